@@ -0,0 +1,56 @@
+//! Store event fan-out throughput
+//!
+//! Measures events/sec deliverable to 100, 500 and 1000 mock connections.
+//! `per_connection` is the previous behaviour, where every connection
+//! serialized each event itself; `shared` reuses one encoding per event.
+//!
+//! Run with `cargo bench --bench fanout`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use universal_connector_server::websocket::fanout::{Encoding, EventFanout};
+use universal_connector_server::DocumentStore;
+
+/// Events published per iteration (below the fan-out channel capacity)
+const EVENTS: usize = 200;
+
+fn deliver(connections: usize, shared: bool) {
+    let store = DocumentStore::new();
+    let fanout = EventFanout::new(&store);
+    let mut receivers: Vec<_> = (0..connections).map(|_| fanout.subscribe()).collect();
+
+    let content = "# Heading\n\nSome paragraph text for the document body.\n".repeat(20);
+    for i in 0..EVENTS {
+        store.upsert(format!("file:///bench/{}.md", i % 10), content.clone(), "markdown".to_string());
+    }
+
+    for receiver in &mut receivers {
+        while let Ok(event) = receiver.try_recv() {
+            let encoded = if shared {
+                event.encoded(Encoding::Json)
+            } else {
+                event.encode_uncached(Encoding::Json)
+            };
+            black_box(encoded.to_message());
+        }
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout");
+    group.sample_size(10);
+
+    for connections in [100, 500, 1000] {
+        group.throughput(Throughput::Elements((EVENTS * connections) as u64));
+        group.bench_with_input(BenchmarkId::new("per_connection", connections), &connections, |b, &n| {
+            b.iter(|| deliver(n, false));
+        });
+        group.bench_with_input(BenchmarkId::new("shared", connections), &connections, |b, &n| {
+            b.iter(|| deliver(n, true));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+//! Histogram recording overhead
+//!
+//! Compares one `observe` on an enabled histogram, on a disabled one and on
+//! one sampling 1 in 10, as set through the registry. A disabled histogram
+//! costs one atomic load and a branch.
+//!
+//! Run with `cargo bench --bench metrics`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use universal_connector_server::monitoring::registry::{Buckets, Registry};
+
+fn observe(c: &mut Criterion) {
+    let registry = Registry::new();
+    let mut group = c.benchmark_group("histogram_observe");
+
+    for (name, enabled, every) in [("enabled", true, 1), ("disabled", false, 1), ("sampled_1_in_10", true, 10)] {
+        let metric = format!("ulc_bench_{name}_seconds");
+        let histogram = registry
+            .histogram_family(&metric, "Benchmark", &["method"], Buckets::latency())
+            .unwrap()
+            .with_labels(&["textDocument/didChange"]);
+        registry.set_enabled(&metric, enabled).unwrap();
+        registry.set_sampling(&metric, every).unwrap();
+        group.bench_function(name, |b| b.iter(|| histogram.observe(black_box(0.0042))));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, observe);
+criterion_main!(benches);
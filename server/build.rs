@@ -0,0 +1,109 @@
+//! Embed build metadata for `build_info`
+//!
+//! Sets `ULC_GIT_HASH`, `ULC_BUILD_TIMESTAMP`, `ULC_RUSTC_VERSION` and
+//! `ULC_FEATURES` for `env!`. Nothing here may fail the build: a source
+//! tarball without git reports its hash as `unknown`.
+//!
+//! With the `grpc` feature it also compiles `proto/ulc.proto` into the tonic
+//! services, parsing it in Rust so that building needs no `protoc`.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=ULC_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git_hash = std::env::var("ULC_GIT_HASH")
+        .ok()
+        .filter(|hash| !hash.is_empty())
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ULC_GIT_HASH={git_hash}");
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()));
+    println!("cargo:rustc-env=ULC_BUILD_TIMESTAMP={}", rfc3339(timestamp));
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ULC_RUSTC_VERSION={rustc_version}");
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=ULC_FEATURES={}", features.join(","));
+
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+/// Generate the gRPC services and the descriptor set served by reflection
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    use prost::Message;
+    use protobuf::Message as _;
+
+    const PROTO: &str = "proto/ulc.proto";
+    println!("cargo:rerun-if-changed={PROTO}");
+    let parsed = protobuf_parse::Parser::new()
+        .pure()
+        .include("proto")
+        .input(PROTO)
+        .file_descriptor_set()
+        .unwrap_or_else(|error| panic!("{PROTO}: {error:#}"));
+    let bytes = parsed.write_to_bytes().expect("encoding the descriptor set");
+    let descriptors = prost_types::FileDescriptorSet::decode(bytes.as_slice()).expect("decoding the descriptor set");
+
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR is set"));
+    std::fs::write(out_dir.join("ulc_descriptor.bin"), &bytes).expect("writing the descriptor set");
+    prost_build::Config::new()
+        .service_generator(tonic_build::configure().service_generator())
+        .compile_fds(descriptors)
+        .unwrap_or_else(|error| panic!("{PROTO}: {error}"));
+}
+
+/// Short hash of the checked-out commit, rebuilding when it moves
+fn git_hash() -> Option<String> {
+    let git_dir = output("git", &["rev-parse", "--git-dir"])?;
+    println!("cargo:rerun-if-changed={git_dir}/HEAD");
+    if let Some(head_ref) = output("git", &["symbolic-ref", "-q", "HEAD"]) {
+        println!("cargo:rerun-if-changed={git_dir}/{head_ref}");
+    }
+    output("git", &["rev-parse", "--short=12", "HEAD"])
+}
+
+/// Trimmed standard output of a successful command
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    let text = text.trim();
+    (output.status.success() && !text.is_empty()).then(|| text.to_string())
+}
+
+/// Format Unix seconds as an RFC 3339 UTC timestamp
+fn rfc3339(secs: u64) -> String {
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
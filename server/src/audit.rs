@@ -0,0 +1,348 @@
+//! Security audit log of authentication and authorization events
+//!
+//! Transports record an [`AuditEvent`] when a token is issued or revoked,
+//! when presented credentials fail validation, when a caller is refused an
+//! operation, and whenever an API key is used. Each event says when it
+//! happened, who the caller was as far as known, where they connected from
+//! and what they asked for, so a compliance review can tell who accessed
+//! which documents.
+//!
+//! Events go to every configured sink as JSON lines: a file appended to, so
+//! external rotation works; stdout; or an HTTP endpoint such as a log
+//! collector, `POSTed` a JSON array per batch. Recording only queues the
+//! event; the [`Auditor::run`] task writes batches in the background. When
+//! the queue is full, events are dropped and counted rather than slowing
+//! requests down. With no sinks, nothing is recorded.
+
+use crate::shutdown::Flushed;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Events that may wait to be written
+const QUEUE_LENGTH: usize = 4096;
+
+/// Most events written to a sink at once
+const BATCH: usize = 256;
+
+/// How long an HTTP sink has to accept a batch
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a drain looks at what is left to write
+const DRAIN_POLL: Duration = Duration::from_millis(20);
+
+/// Where audit events are written
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// Every sink gets every event; none turns auditing off
+    pub sinks: Vec<AuditSinkConfig>,
+}
+
+/// One destination of audit events
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditSinkConfig {
+    /// JSON lines appended to a file
+    File { path: PathBuf },
+    /// JSON lines on stdout, for a container's log collector
+    Stdout,
+    /// Batches `POSTed` as JSON arrays; the URL may carry a token
+    Http { url: String },
+}
+
+impl fmt::Debug for AuditSinkConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File { path } => f.debug_struct("File").field("path", path).finish(),
+            Self::Stdout => f.write_str("Stdout"),
+            Self::Http { url } => {
+                f.debug_struct("Http").field("url", &crate::monitoring::alerts::redact_url(url)).finish()
+            }
+        }
+    }
+}
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    /// An access token, refresh token or API key was issued
+    TokenIssued,
+    /// An API key or refresh token family was revoked
+    TokenRevoked,
+    /// Presented credentials were invalid, expired or revoked
+    AuthenticationFailed,
+    /// A caller lacked the credentials or a scope an operation needs
+    AuthorizationDenied,
+    /// A request was made with an API key
+    ApiKeyUsed,
+    /// A new key was rotated in to sign tokens
+    KeyRotated,
+}
+
+/// One audited event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub event: AuditKind,
+    /// Such as `http` or `websocket`
+    pub transport: String,
+    /// Authenticated subject of the caller; `None` when unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// Name of the caller's client or API key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Client IP address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// What was asked for, such as `DELETE /api/documents/0123` or a WebSocket message type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    /// Why, or what was issued, such as `Requires the write scope`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    /// An event of `kind` happening now over `transport`, with nothing else known yet
+    #[must_use]
+    pub fn new(kind: AuditKind, transport: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event: kind,
+            transport: transport.to_string(),
+            subject: None,
+            client: None,
+            source: None,
+            resource: None,
+            detail: None,
+        }
+    }
+
+    #[must_use]
+    pub fn subject(mut self, subject: Option<impl Into<String>>) -> Self {
+        self.subject = subject.map(Into::into);
+        self
+    }
+
+    #[must_use]
+    pub fn client(mut self, client: Option<impl Into<String>>) -> Self {
+        self.client = client.map(Into::into);
+        self
+    }
+
+    #[must_use]
+    pub fn source(mut self, source: Option<impl ToString>) -> Self {
+        self.source = source.map(|source| source.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn resource(mut self, resource: impl Into<String>) -> Self {
+        self.resource = Some(resource.into());
+        self
+    }
+
+    #[must_use]
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Queues audit events and writes them to the configured sinks
+pub struct Auditor {
+    sinks: Vec<AuditSinkConfig>,
+    queue: Option<mpsc::Sender<AuditEvent>>,
+    receiver: Mutex<Option<mpsc::Receiver<AuditEvent>>>,
+    client: reqwest::Client,
+    /// Events queued and not yet written to every sink
+    pending: AtomicUsize,
+    /// Events dropped because the queue was full
+    dropped: AtomicUsize,
+}
+
+impl Auditor {
+    #[must_use]
+    pub fn new(config: &AuditConfig) -> Self {
+        let (queue, receiver) = if config.sinks.is_empty() {
+            (None, None)
+        } else {
+            let (queue, receiver) = mpsc::channel(QUEUE_LENGTH);
+            (Some(queue), Some(receiver))
+        };
+        Self {
+            sinks: config.sinks.clone(),
+            queue,
+            receiver: Mutex::new(receiver),
+            client: reqwest::Client::new(),
+            pending: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether any sink is configured, so events are recorded at all
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Queue `event` for every sink, dropping it if the queue is full
+    pub fn record(&self, event: AuditEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        if queue.try_send(event).is_err() {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            let dropped = self.dropped.fetch_add(1, Ordering::AcqRel) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Audit queue full: {} events dropped so far", dropped);
+            }
+        }
+    }
+
+    /// Events dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Acquire)
+    }
+
+    /// Events queued and not yet written
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Write queued events to every sink in batches; runs for as long as the server does
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the receiver's lock.
+    pub async fn run(self: Arc<Self>) {
+        let Some(mut receiver) = self.receiver.lock().expect("audit lock poisoned").take() else {
+            return;
+        };
+        let mut batch = Vec::with_capacity(BATCH);
+        while receiver.recv_many(&mut batch, BATCH).await > 0 {
+            for sink in &self.sinks {
+                if let Err(e) = self.write(sink, &batch).await {
+                    warn!("Audit events not written to {:?}: {:#}", sink, e);
+                }
+            }
+            self.pending.fetch_sub(batch.len(), Ordering::AcqRel);
+            batch.clear();
+        }
+    }
+
+    /// Wait for the events queued so far to be written, until `deadline`
+    pub async fn drain(&self, deadline: tokio::time::Instant) -> Flushed {
+        let pending = self.pending();
+        let mut remaining = pending;
+        while remaining > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + DRAIN_POLL)).await;
+            remaining = self.pending().min(remaining);
+        }
+        Flushed::new(pending - remaining, remaining)
+    }
+
+    async fn write(&self, sink: &AuditSinkConfig, batch: &[AuditEvent]) -> Result<()> {
+        match sink {
+            AuditSinkConfig::File { path } => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                file.write_all(json_lines(batch)?.as_bytes()).await?;
+                file.flush().await?;
+            }
+            AuditSinkConfig::Stdout => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(json_lines(batch)?.as_bytes()).await?;
+                stdout.flush().await?;
+            }
+            AuditSinkConfig::Http { url } => {
+                let response = self
+                    .client
+                    .post(url)
+                    .timeout(HTTP_TIMEOUT)
+                    .json(batch)
+                    .send()
+                    .await
+                    .map_err(reqwest::Error::without_url)?;
+                if !response.status().is_success() {
+                    bail!("HTTP {}", response.status().as_u16());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `events` as JSON lines, each ending in a newline
+fn json_lines(events: &[AuditEvent]) -> Result<String> {
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(event)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events_are_appended_as_json_lines() {
+        let dir = std::env::temp_dir().join(format!("ulc-audit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let auditor = Arc::new(Auditor::new(&AuditConfig { sinks: vec![AuditSinkConfig::File { path: path.clone() }] }));
+        tokio::spawn(Arc::clone(&auditor).run());
+
+        auditor.record(
+            AuditEvent::new(AuditKind::AuthorizationDenied, "http")
+                .subject(Some("alice"))
+                .source(Some("192.0.2.7"))
+                .resource("DELETE /api/documents/0123")
+                .detail("Requires the write scope"),
+        );
+        auditor.record(AuditEvent::new(AuditKind::AuthenticationFailed, "websocket").detail("Token expired"));
+        let flushed = auditor.drain(tokio::time::Instant::now() + Duration::from_secs(5)).await;
+        assert_eq!(flushed, Flushed::new(2, 0));
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<AuditEvent> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, AuditKind::AuthorizationDenied);
+        assert_eq!(events[0].resource.as_deref(), Some("DELETE /api/documents/0123"));
+        assert_eq!(events[1].transport, "websocket");
+        assert!(written.lines().next().unwrap().contains(r#""event":"authorization_denied""#));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_without_sinks_nothing_is_queued() {
+        let auditor = Auditor::new(&AuditConfig::default());
+        assert!(!auditor.is_enabled());
+        auditor.record(AuditEvent::new(AuditKind::TokenIssued, "http"));
+        assert_eq!((auditor.pending(), auditor.dropped()), (0, 0));
+
+        let sinks: AuditConfig = toml::from_str(
+            "[[sinks]]\nkind = \"file\"\npath = \"/var/log/audit.jsonl\"\n\n[[sinks]]\nkind = \"http\"\nurl = \"https://logs.example.com/ingest?token=XXXX\"\n",
+        )
+        .unwrap();
+        assert_eq!(sinks.sinks[0], AuditSinkConfig::File { path: PathBuf::from("/var/log/audit.jsonl") });
+        assert!(!format!("{sinks:?}").contains("XXXX"));
+    }
+}
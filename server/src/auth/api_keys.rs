@@ -0,0 +1,331 @@
+//! Revocable API keys
+//!
+//! A key is a random secret shown once, when it is created, and stored only
+//! as its SHA-256 hash, in a sled database under `data_dir` or, without
+//! one, in a temporary database lost at exit. Keys read
+//! `ulc_<id>_<secret>`: the ID finds the record and the secret must hash to
+//! what it holds. Presented as a bearer token or in `X-API-Key`, a key
+//! stands for its subject and scopes as a token does, until it expires or
+//! is revoked.
+//!
+//! When a key was last used is recorded at most once per
+//! [`TOUCH_INTERVAL`], so busy keys do not write on every request.
+
+use super::{Claims, TokenError, AUDIENCE, ISSUER};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+/// How every key starts, telling keys from JWTs
+pub const PREFIX: &str = "ulc_";
+
+/// Header a key may be presented in instead of `Authorization`
+pub const HEADER: &str = "x-api-key";
+
+/// Least time between writes of a key's `last_used_at`
+pub const TOUCH_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Name of the database directory under `data_dir`
+const DIRECTORY: &str = "api_keys";
+
+/// Random bytes in a key ID
+const ID_BYTES: usize = 8;
+
+/// Random bytes in a key secret
+const SECRET_BYTES: usize = 32;
+
+/// An API key, as listed; the secret is never kept
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    /// What the key is for, such as `ci`; reported as the client name in usage
+    pub name: String,
+    /// Subject requests made with the key are accounted to
+    pub subject: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// To within [`TOUCH_INTERVAL`]
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Whether the key is past its expiry
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// The claims a request made with the key carries
+    #[must_use]
+    pub fn claims(&self) -> Claims {
+        let custom = HashMap::from([
+            ("key_name".to_string(), serde_json::json!(self.name)),
+            ("key_id".to_string(), serde_json::json!(self.id)),
+        ]);
+        Claims {
+            sub: self.subject.clone(),
+            iat: self.created_at.timestamp(),
+            exp: self.expires_at.map_or(i64::MAX, |expires_at| expires_at.timestamp()),
+            iss: ISSUER.to_string(),
+            aud: AUDIENCE.to_string(),
+            jti: None,
+            scopes: self.scopes.clone(),
+            custom,
+        }
+    }
+}
+
+/// A key as stored, with the hash of its secret
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// SHA-256 of the secret, base64url
+    hash: String,
+}
+
+/// API keys by ID
+pub struct ApiKeyStore {
+    db: sled::Db,
+    random: SystemRandom,
+}
+
+impl ApiKeyStore {
+    /// Open the store under `data_dir`, creating it if need be
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be opened, as when another process holds
+    /// it.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(DIRECTORY);
+        let db = sled::open(&path).with_context(|| format!("opening API key store {}", path.display()))?;
+        Ok(Self { db, random: SystemRandom::new() })
+    }
+
+    /// A store kept only while the server runs
+    ///
+    /// # Errors
+    ///
+    /// Fails where the temporary store cannot be created.
+    pub fn temporary() -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open().context("opening a temporary API key store")?;
+        Ok(Self { db, random: SystemRandom::new() })
+    }
+
+    /// Create a key, returning its record and the key itself, which cannot be had again
+    ///
+    /// # Errors
+    ///
+    /// Fails where there is no randomness to generate the key with, or it
+    /// cannot be stored.
+    pub fn create(
+        &self,
+        name: String,
+        subject: String,
+        scopes: Vec<String>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiKey, String)> {
+        let mut id = [0u8; ID_BYTES];
+        let mut secret = [0u8; SECRET_BYTES];
+        self.random.fill(&mut id).and_then(|()| self.random.fill(&mut secret)).map_err(|_| {
+            anyhow::anyhow!("no randomness to generate an API key with")
+        })?;
+        let id = id.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+        let secret = URL_SAFE_NO_PAD.encode(secret);
+        let key = ApiKey {
+            id,
+            name,
+            subject,
+            scopes,
+            created_at: Utc::now(),
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+        };
+        self.put(&Stored { key: key.clone(), hash: hash(&secret) })?;
+        let presented = format!("{}{}_{}", PREFIX, key.id, secret);
+        Ok((key, presented))
+    }
+
+    /// Every key, revoked and expired ones included, oldest first
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read, or holds a record that does not
+    /// parse.
+    pub fn list(&self) -> Result<Vec<ApiKey>> {
+        let mut keys = self
+            .db
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice::<Stored>(&value?)?.key))
+            .collect::<Result<Vec<_>>>()?;
+        keys.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(keys)
+    }
+
+    /// The key with ID `id`, or `None` if there is no such key
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read, or the key's record does not
+    /// parse.
+    pub fn get(&self, id: &str) -> Result<Option<ApiKey>> {
+        Ok(self.stored(id)?.map(|stored| stored.key))
+    }
+
+    /// Revoke a key, returning its record, or `None` if there is no such key
+    ///
+    /// Revoking a key again keeps the time it was first revoked.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read or written.
+    pub fn revoke(&self, id: &str) -> Result<Option<ApiKey>> {
+        let Some(mut stored) = self.stored(id)? else { return Ok(None) };
+        if stored.key.revoked_at.is_none() {
+            stored.key.revoked_at = Some(Utc::now());
+            self.put(&stored)?;
+        }
+        Ok(Some(stored.key))
+    }
+
+    /// Check a presented key, recording its use
+    ///
+    /// Unknown keys are malformed, and keys whose secret does not match have a bad signature.
+    ///
+    /// # Errors
+    ///
+    /// [`TokenError::Malformed`] for a key that is not one or is unknown,
+    /// [`TokenError::BadSignature`] where its secret does not match, and
+    /// [`TokenError::Revoked`] or [`TokenError::Expired`] for a key no longer
+    /// valid.
+    pub fn validate(&self, presented: &str) -> Result<ApiKey, TokenError> {
+        let (id, secret) = presented
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or_else(|| TokenError::Malformed("not an API key".to_string()))?;
+        let unknown = || TokenError::Malformed("unknown API key".to_string());
+        let mut stored = self.stored(id).map_err(|_| unknown())?.ok_or_else(unknown)?;
+        if !constant_time_eq(hash(secret).as_bytes(), stored.hash.as_bytes()) {
+            return Err(TokenError::BadSignature);
+        }
+        if stored.key.revoked_at.is_some() {
+            return Err(TokenError::Revoked);
+        }
+        if stored.key.is_expired() {
+            return Err(TokenError::Expired);
+        }
+        let now = Utc::now();
+        let stale = stored.key.last_used_at.is_none_or(|used| (now - used).to_std().is_ok_and(|since| since >= TOUCH_INTERVAL));
+        if stale {
+            stored.key.last_used_at = Some(now);
+            if let Err(e) = self.put(&stored) {
+                tracing::warn!("Last use of API key {} not recorded: {:#}", id, e);
+            }
+        }
+        Ok(stored.key)
+    }
+
+    /// Write pending changes to disk
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be written.
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    fn stored(&self, id: &str) -> Result<Option<Stored>> {
+        self.db.get(id)?.map(|value| Ok(serde_json::from_slice(&value)?)).transpose()
+    }
+
+    fn put(&self, stored: &Stored) -> Result<()> {
+        self.db.insert(stored.key.id.as_bytes(), serde_json::to_vec(stored)?)?;
+        Ok(())
+    }
+}
+
+/// SHA-256 of a secret, base64url, as stored in place of it
+pub(super) fn hash(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, secret.as_bytes()))
+}
+
+/// Compare without returning early, so the time taken says nothing of where the hashes differ
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> ApiKeyStore {
+        ApiKeyStore::temporary().unwrap()
+    }
+
+    #[test]
+    fn test_keys_are_hashed_and_validated() {
+        let store = store();
+        let (key, presented) = store.create("ci".to_string(), "ci-bot".to_string(), vec!["read".to_string()], None).unwrap();
+        assert!(presented.starts_with(&format!("{PREFIX}{}_", key.id)), "{presented}");
+        let secret = presented.rsplit_once('_').unwrap().1;
+        let raw: Vec<u8> = store.db.get(&key.id).unwrap().unwrap().to_vec();
+        assert!(!String::from_utf8(raw).unwrap().contains(secret), "the secret is stored in the clear");
+
+        let validated = store.validate(&presented).unwrap();
+        assert_eq!((validated.subject.as_str(), validated.scopes.as_slice()), ("ci-bot", ["read".to_string()].as_slice()));
+        assert!(validated.last_used_at.is_some());
+        assert_eq!(store.get(&key.id).unwrap().unwrap().last_used_at, validated.last_used_at);
+
+        let forged = format!("{PREFIX}{}_{}", key.id, URL_SAFE_NO_PAD.encode([0u8; SECRET_BYTES]));
+        assert_eq!(store.validate(&forged), Err(TokenError::BadSignature));
+        let unknown = format!("{PREFIX}0000000000000000_{secret}");
+        assert_eq!(store.validate(&unknown), Err(TokenError::Malformed("unknown API key".to_string())));
+        assert!(matches!(store.validate("eyJhbGciOi"), Err(TokenError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_revoked_and_expired_keys_are_refused() {
+        let store = store();
+        let (key, presented) = store.create("ci".to_string(), "ci-bot".to_string(), Vec::new(), None).unwrap();
+        let revoked = store.revoke(&key.id).unwrap().unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert_eq!(store.revoke(&key.id).unwrap().unwrap().revoked_at, revoked.revoked_at);
+        assert_eq!(store.validate(&presented), Err(TokenError::Revoked));
+        assert_eq!(store.revoke("0000000000000000").unwrap(), None);
+
+        let past = Utc::now() - chrono::Duration::minutes(1);
+        let (_, presented) = store.create("old".to_string(), "ci-bot".to_string(), Vec::new(), Some(past)).unwrap();
+        assert_eq!(store.validate(&presented), Err(TokenError::Expired));
+        let names: Vec<_> = store.list().unwrap().into_iter().map(|key| key.name).collect();
+        assert_eq!(names, ["ci", "old"]);
+    }
+
+    #[test]
+    fn test_keys_outlive_the_store() {
+        let dir = std::env::temp_dir().join(format!("ulc-api-keys-{}", uuid::Uuid::new_v4()));
+        let presented = {
+            let store = ApiKeyStore::open(&dir).unwrap();
+            store.create("ci".to_string(), "ci-bot".to_string(), Vec::new(), None).unwrap().1
+        };
+        let store = ApiKeyStore::open(&dir).unwrap();
+        assert_eq!(store.validate(&presented).unwrap().name, "ci");
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
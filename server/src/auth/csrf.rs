@@ -0,0 +1,137 @@
+//! Cross-site request forgery protection for browser-hosted clients
+//!
+//! A browser-hosted editor, such as a web IDE served from the connector's
+//! own site, may keep its access token in a cookie rather than where
+//! scripts can read it. The browser then attaches the token to every
+//! request for the connector, including ones a hostile page makes it send.
+//! With protection on, a request authenticated by that cookie that changes
+//! anything must repeat the value of a second cookie, `ulc_csrf`, in an
+//! `X-CSRF-Token` header: the double-submit pattern. Only scripts of the
+//! connector's site can read the cookie, so a forged request cannot.
+//!
+//! WebSocket upgrades carry no such header. An upgrade made from a page
+//! must come from the connector's own host or an allowed origin instead,
+//! as its `Origin` says.
+
+use super::api_keys::constant_time_eq;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+/// Bytes of a generated CSRF token
+const TOKEN_BYTES: usize = 32;
+
+/// CSRF protection configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsrfConfig {
+    /// Accept access tokens in `token_cookie`, and require the CSRF token of requests authenticated by it
+    pub enabled: bool,
+    /// Cookie carrying the access token
+    pub token_cookie: String,
+    /// Cookie the CSRF token is issued in
+    pub cookie: String,
+    /// Header the CSRF token is repeated in
+    pub header: String,
+    /// Issue the CSRF cookie as `Secure`, sent over HTTPS only
+    pub secure_cookie: bool,
+    /// Origins of pages other than the connector's own that may open `WebSockets`, such as `https://ide.example.com`
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token_cookie: "ulc_token".to_string(),
+            cookie: "ulc_csrf".to_string(),
+            header: "X-CSRF-Token".to_string(),
+            secure_cookie: true,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl CsrfConfig {
+    /// `Set-Cookie` value issuing `token`
+    #[must_use]
+    pub fn set_cookie(&self, token: &str) -> String {
+        let secure = if self.secure_cookie { "; Secure" } else { "" };
+        format!("{}={}; Path=/; SameSite=Strict{}", self.cookie, token, secure)
+    }
+
+    /// Whether a page of `origin` may open a WebSocket to `host`, the upgrade's `Host`
+    ///
+    /// Without an `Origin` the upgrade was not made by a page, and is allowed.
+    #[must_use]
+    pub fn allows_origin(&self, origin: Option<&str>, host: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return true;
+        };
+        let own = origin.split_once("://").map(|(_, authority)| authority);
+        (own.is_some() && own == host) || self.allowed_origins.iter().any(|allowed| allowed.trim_end_matches('/') == origin)
+    }
+}
+
+/// A new random CSRF token
+///
+/// # Errors
+///
+/// Fails where the system has no randomness to draw the token from.
+pub fn generate() -> Result<String> {
+    let mut token = [0u8; TOKEN_BYTES];
+    SystemRandom::new().fill(&mut token).map_err(|_| anyhow!("no randomness"))?;
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Value of the cookie `name` among a request's `Cookie` headers
+pub fn cookie<'a>(headers: impl IntoIterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    headers
+        .into_iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Whether the token repeated in the header is the one in the cookie
+#[must_use]
+pub fn matches(cookie: Option<&str>, header: Option<&str>) -> bool {
+    match (cookie, header) {
+        (Some(cookie), Some(header)) => !cookie.is_empty() && constant_time_eq(cookie.as_bytes(), header.as_bytes()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_submit() {
+        let token = generate().unwrap();
+        assert_ne!(token, generate().unwrap());
+        let header = format!("theme=dark; ulc_csrf={token}; ulc_token=eyJ");
+        assert_eq!(cookie([header.as_str()], "ulc_csrf"), Some(token.as_str()));
+        assert_eq!(cookie(["a=1", "ulc_token=eyJ"], "ulc_token"), Some("eyJ"));
+        assert_eq!(cookie([header.as_str()], "csrf"), None);
+
+        assert!(matches(Some(&token), Some(&token)));
+        assert!(!matches(Some(&token), Some("forged")));
+        assert!(!matches(Some(&token), None));
+        assert!(!matches(None, None));
+        assert!(!matches(Some(""), Some("")));
+    }
+
+    #[test]
+    fn test_websocket_origins() {
+        let config = CsrfConfig { allowed_origins: vec!["https://ide.example.com/".to_string()], ..CsrfConfig::default() };
+        assert!(config.allows_origin(None, Some("connector.example.com")));
+        assert!(config.allows_origin(Some("https://connector.example.com"), Some("connector.example.com")));
+        assert!(config.allows_origin(Some("https://ide.example.com"), Some("connector.example.com")));
+        assert!(!config.allows_origin(Some("https://evil.example"), Some("connector.example.com")));
+        assert!(!config.allows_origin(Some("null"), Some("connector.example.com")));
+        assert!(!config.allows_origin(Some("https://connector.example.com"), None));
+    }
+}
@@ -0,0 +1,88 @@
+//! Logins checked against an LDAP server or Active Directory (ldap feature)
+//!
+//! Each login opens a connection of its own: it binds as the service
+//! account, or not at all, searches `user_base` for the one entry the
+//! filter matches, reads its subject and groups, and then binds as that
+//! entry with the password given. Nothing is cached, so a user disabled in
+//! the directory cannot log in again from that moment; tokens already
+//! issued last until they expire or are revoked.
+
+use super::{Directory, DirectoryUser, LdapConfig, LoginError};
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use tracing::debug;
+
+/// Result code of a bind with a wrong DN or password
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// The directory server `config` names
+pub struct LdapDirectory {
+    config: LdapConfig,
+}
+
+impl LdapDirectory {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    async fn connect(&self) -> Result<Ldap, LoginError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.config.timeout).set_starttls(self.config.start_tls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await.map_err(unavailable)?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                debug!("LDAP connection closed: {}", e);
+            }
+        });
+        Ok(ldap)
+    }
+
+    /// The entry `username` logs in as, found as the service account
+    async fn find(&self, ldap: &mut Ldap, username: &str) -> Result<DirectoryUser, LoginError> {
+        let config = &self.config;
+        if let Some(dn) = &config.bind_dn {
+            let password = config.bind_password.as_deref().unwrap_or_default();
+            let bound = ldap.with_timeout(config.timeout).simple_bind(dn, password).await;
+            bound.and_then(|result| result.success()).map_err(|e| LoginError::Unavailable(format!("service account bind: {}", e)))?;
+        }
+        let attributes = [config.subject_attribute.as_str(), config.group_attribute.as_str()];
+        let filter = config.filter(username);
+        let search = ldap.with_timeout(config.timeout).search(&config.user_base, Scope::Subtree, &filter, attributes);
+        let (entries, _) = search.await.and_then(|result| result.success()).map_err(unavailable)?;
+        // No match and several alike are refused, so a loose filter cannot log anyone in as someone else
+        let [entry] = <[_; 1]>::try_from(entries).map_err(|_| LoginError::InvalidCredentials)?;
+        let mut entry = SearchEntry::construct(entry);
+        let subject = entry
+            .attrs
+            .get(&config.subject_attribute)
+            .and_then(|values| values.first().cloned())
+            .unwrap_or_else(|| username.to_string());
+        let groups = entry.attrs.remove(&config.group_attribute).unwrap_or_default();
+        Ok(DirectoryUser { subject, dn: entry.dn, groups })
+    }
+}
+
+#[tower_lsp::async_trait]
+impl Directory for LdapDirectory {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<DirectoryUser, LoginError> {
+        let mut ldap = self.connect().await?;
+        let found = self.find(&mut ldap, username).await;
+        let verified = match found {
+            Ok(user) => match ldap.with_timeout(self.config.timeout).simple_bind(&user.dn, password).await {
+                Ok(result) if result.rc == 0 => Ok(user),
+                Ok(result) if result.rc == INVALID_CREDENTIALS => Err(LoginError::InvalidCredentials),
+                Ok(result) => Err(LoginError::Unavailable(result.to_string())),
+                Err(e) => Err(unavailable(e)),
+            },
+            Err(e) => Err(e),
+        };
+        let _ = ldap.unbind().await;
+        verified
+    }
+
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+}
+
+fn unavailable(e: LdapError) -> LoginError {
+    LoginError::Unavailable(e.to_string())
+}
@@ -0,0 +1,248 @@
+//! Logins checked against a corporate directory
+//!
+//! With `[ldap]` set, `POST /auth/login` takes a username and password and
+//! checks them against an LDAP server or Active Directory domain
+//! controller: the user's entry is found with the service account, or
+//! anonymously, and then bound to with the password given. A login that
+//! succeeds starts a refresh token family, as an admin would, for the
+//! subject the entry names and the scopes its groups map to.
+//!
+//! The directory is reached through a [`Directory`]: [`ldap`] with the ldap
+//! feature, or any other implementation handed to
+//! [`AuthService::with_directory`](super::AuthService::with_directory).
+
+#[cfg(feature = "ldap")]
+pub mod ldap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Placeholder in [`LdapConfig::user_filter`] for the escaped username
+pub const USERNAME: &str = "{username}";
+
+/// Directory server settings, and how its groups map to scopes
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LdapConfig {
+    /// Server URL, such as `ldaps://dc1.example.com`, or `ldap://` with `start_tls`
+    pub url: String,
+    /// Upgrade an `ldap://` connection with `StartTLS` before binding
+    #[serde(default)]
+    pub start_tls: bool,
+    /// Entry of the service account users are searched for with; anonymous when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_dn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_password: Option<String>,
+    /// Entry under which users are searched for, such as `ou=people,dc=example,dc=com`
+    pub user_base: String,
+    /// Filter finding a user by the name they log in with, [`USERNAME`] standing for it
+    #[serde(default = "default_user_filter")]
+    pub user_filter: String,
+    /// Attribute of the user's entry naming the subject tokens are issued to
+    #[serde(default = "default_subject_attribute")]
+    pub subject_attribute: String,
+    /// Attribute of the user's entry listing their groups
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// Scopes granted by each group, named by its whole DN or its first RDN value, such as `Editors`
+    #[serde(default)]
+    pub group_scopes: BTreeMap<String, Vec<String>>,
+    /// Scopes granted to every user who logs in
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+    /// Time allowed to connect, and for each operation
+    #[serde(default = "default_timeout", with = "crate::config::duration")]
+    pub timeout: Duration,
+}
+
+fn default_user_filter() -> String {
+    format!("(uid={USERNAME})")
+}
+
+fn default_subject_attribute() -> String {
+    "uid".to_string()
+}
+
+fn default_group_attribute() -> String {
+    "memberOf".to_string()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl LdapConfig {
+    /// Search for users under `user_base` on the server at `url`, with the default attributes
+    pub fn new(url: impl Into<String>, user_base: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            start_tls: false,
+            bind_dn: None,
+            bind_password: None,
+            user_base: user_base.into(),
+            user_filter: default_user_filter(),
+            subject_attribute: default_subject_attribute(),
+            group_attribute: default_group_attribute(),
+            group_scopes: BTreeMap::new(),
+            default_scopes: Vec::new(),
+            timeout: default_timeout(),
+        }
+    }
+
+    /// The filter finding `username`, escaped so it cannot widen the search
+    #[must_use]
+    pub fn filter(&self, username: &str) -> String {
+        self.user_filter.replace(USERNAME, &escape(username))
+    }
+
+    /// Scopes of a user in `groups`: the default ones, then those of each group, without repeats
+    #[must_use]
+    pub fn scopes(&self, groups: &[String]) -> Vec<String> {
+        let mut scopes = self.default_scopes.clone();
+        for group in groups {
+            let name = first_rdn_value(group);
+            let granted = self
+                .group_scopes
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(group) || key.eq_ignore_ascii_case(name))
+                .flat_map(|(_, granted)| granted);
+            for scope in granted {
+                if !scopes.contains(scope) {
+                    scopes.push(scope.clone());
+                }
+            }
+        }
+        scopes
+    }
+}
+
+impl fmt::Debug for LdapConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LdapConfig")
+            .field("url", &self.url)
+            .field("start_tls", &self.start_tls)
+            .field("bind_dn", &self.bind_dn)
+            .field("user_base", &self.user_base)
+            .field("user_filter", &self.user_filter)
+            .field("subject_attribute", &self.subject_attribute)
+            .field("group_attribute", &self.group_attribute)
+            .field("group_scopes", &self.group_scopes)
+            .field("default_scopes", &self.default_scopes)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Escape `value` for a search filter, as RFC 4515 asks
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\5c"),
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `Editors` of `CN=Editors,OU=Groups,DC=example,DC=com`; a value that is not a DN as it is
+fn first_rdn_value(dn: &str) -> &str {
+    let rdn = dn.split(',').next().unwrap_or(dn);
+    rdn.split_once('=').map_or(rdn, |(_, value)| value).trim()
+}
+
+/// A user the directory vouched for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryUser {
+    /// Subject tokens are issued to
+    pub subject: String,
+    /// Distinguished name of the user's entry
+    pub dn: String,
+    /// Groups the user's entry lists, usually as DNs
+    pub groups: Vec<String>,
+}
+
+/// Why a login failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginError {
+    /// No such user, or not their password; which is not said
+    InvalidCredentials,
+    /// The directory could not be asked
+    Unavailable(String),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::InvalidCredentials => f.write_str("Invalid username or password"),
+            LoginError::Unavailable(reason) => write!(f, "Directory unavailable: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+/// Where usernames and passwords are checked
+#[tower_lsp::async_trait]
+pub trait Directory: Send + Sync {
+    /// Check `password` is that of `username`, returning who they are
+    ///
+    /// An empty password never gets here: many servers take a bind with
+    /// one as anonymous, and succeed.
+    async fn authenticate(&self, username: &str, password: &str) -> Result<DirectoryUser, LoginError>;
+
+    /// Such as `ldap`, for logs
+    fn name(&self) -> &'static str;
+}
+
+/// The directory `config` names
+///
+/// # Errors
+///
+/// Fails in builds without the ldap feature, which validation reports
+/// before startup.
+pub fn open(config: &LdapConfig) -> Result<Arc<dyn Directory>> {
+    #[cfg(feature = "ldap")]
+    {
+        Ok(Arc::new(self::ldap::LdapDirectory::new(config.clone())))
+    }
+    #[cfg(not(feature = "ldap"))]
+    {
+        let _ = config;
+        anyhow::bail!("LDAP logins need a build with the ldap feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_and_group_scopes() {
+        let mut config = LdapConfig::new("ldaps://dc1.example.com", "ou=people,dc=example,dc=com");
+        assert_eq!(config.filter("alice"), "(uid=alice)");
+        assert_eq!(config.filter("*)(uid=*"), "(uid=\\2a\\29\\28uid=\\2a)");
+        config.user_filter = "(&(objectClass=user)(sAMAccountName={username}))".to_string();
+        assert_eq!(config.filter("bob\\"), "(&(objectClass=user)(sAMAccountName=bob\\5c))");
+
+        config.default_scopes = vec!["read".to_string()];
+        config.group_scopes.insert("editors".to_string(), vec!["read".to_string(), "write".to_string()]);
+        config.group_scopes.insert("CN=Ops,OU=Groups,DC=example,DC=com".to_string(), vec!["role:admin".to_string()]);
+        let groups = ["CN=Editors,OU=Groups,DC=example,DC=com", "cn=ops,ou=groups,dc=example,dc=com", "CN=Staff,DC=example,DC=com"];
+        let groups: Vec<String> = groups.iter().map(ToString::to_string).collect();
+        assert_eq!(config.scopes(&groups), ["read", "write", "role:admin"]);
+        assert_eq!(config.scopes(&[]), ["read"]);
+        assert_eq!(config.scopes(&["Editors".to_string()]), ["read", "write"]);
+
+        config.bind_password = Some("B1ND".to_string());
+        assert!(!format!("{config:?}").contains("B1ND"));
+    }
+}
@@ -0,0 +1,62 @@
+//! Token introspection, as RFC 7662 describes it
+//!
+//! Sidecars and reverse proxies in front of other services can hand the
+//! tokens they receive to `POST /auth/introspect` instead of verifying them
+//! themselves, and learn whether each is active and what it grants. A
+//! token is active if the connector would accept it now: its signature or
+//! key checks out, it has not expired and it has not been revoked. Nothing
+//! else is said of an inactive token, not even why it is inactive.
+
+use super::Claims;
+use serde::{Deserialize, Serialize};
+
+/// What introspection tells of a token
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Introspection {
+    /// Whether the token would be accepted now
+    pub active: bool,
+    /// Scopes granted, roles expanded, separated by spaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Client the token was issued to, when it names one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+impl Introspection {
+    /// A token that would not be accepted, for whatever reason
+    #[must_use]
+    pub fn inactive() -> Self {
+        Self::default()
+    }
+
+    /// A token that would be accepted, with the claims it was accepted with
+    pub fn active(claims: &Claims) -> Self {
+        Self {
+            active: true,
+            scope: Some(claims.scopes.join(" ")),
+            client_id: claims.client_name().map(str::to_string),
+            token_type: Some("Bearer".to_string()),
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            sub: Some(claims.sub.clone()),
+            aud: Some(claims.aud.clone()),
+            iss: Some(claims.iss.clone()),
+            jti: claims.jti.clone(),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Throttling of failed logins, against guessing passwords and tokens
+//!
+//! Failures at `/auth/login` and `/auth/refresh` are counted per client IP
+//! and, for logins, per username. After each one the key must wait before
+//! trying again, the wait doubling with every failure up to `max_backoff`;
+//! a request sent sooner is refused with 429. Once a key reaches its
+//! threshold it is locked out for `lockout`, and refused with 423 however
+//! long it waits. Failures are forgotten `lockout` after the last one, and
+//! a successful login forgets those of its username, though not those of
+//! its IP, so an attacker holding one account cannot wipe the count of an
+//! address guessing others.
+//!
+//! Counts are kept in memory, per instance.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Failures recorded between sweeps of those forgotten
+const SWEEP_EVERY: usize = 1024;
+
+/// Failed login throttling configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// Throttle and lock out after failed logins
+    pub enabled: bool,
+    /// Failures with one username that lock it out
+    pub max_failures: u32,
+    /// Failures from one client IP that lock it out, whatever the usernames tried
+    pub max_failures_per_ip: u32,
+    /// Wait after the first failure, doubled after each one after
+    #[serde(with = "crate::config::duration")]
+    pub backoff: Duration,
+    /// Longest wait between failures short of a lockout
+    #[serde(with = "crate::config::duration")]
+    pub max_backoff: Duration,
+    /// How long a lockout lasts, and how long failures are remembered
+    #[serde(with = "crate::config::duration")]
+    pub lockout: Duration,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            lockout: Duration::from_mins(15),
+        }
+    }
+}
+
+impl LockoutConfig {
+    /// Wait after the `failures`th failure in a row
+    #[must_use]
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+}
+
+/// Why an attempt was refused before its credentials were checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttled {
+    /// Locked out, rather than backing off
+    pub locked: bool,
+    /// Seconds until another attempt is considered
+    pub retry_after: u64,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.locked {
+            write!(f, "Locked out after too many failed attempts; retry in {}s", self.retry_after)
+        } else {
+            write!(f, "Too many failed attempts; retry in {}s", self.retry_after)
+        }
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// What failures are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "client {ip}"),
+            Key::User(user) => write!(f, "user {user}"),
+        }
+    }
+}
+
+/// Recent failures of one key
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    /// Attempts before this are refused
+    blocked_until: Instant,
+    locked: bool,
+}
+
+/// Failed attempts by client IP and username, and the waits they impose
+pub struct LoginThrottle {
+    config: LockoutConfig,
+    failures: DashMap<Key, Failures>,
+    recorded: AtomicUsize,
+}
+
+impl LoginThrottle {
+    #[must_use]
+    pub fn new(config: LockoutConfig) -> Self {
+        Self { config, failures: DashMap::new(), recorded: AtomicUsize::new(0) }
+    }
+
+    pub fn config(&self) -> &LockoutConfig {
+        &self.config
+    }
+
+    /// Refuse an attempt from `ip` as `user` while either must wait, saying for how long
+    ///
+    /// # Errors
+    ///
+    /// [`Throttled`], with the longer of the two waits, while `ip` or `user` is
+    /// locked out.
+    pub fn check(&self, ip: Option<IpAddr>, user: Option<&str>) -> Result<(), Throttled> {
+        self.check_at(ip, user, Instant::now())
+    }
+
+    /// Count a failed attempt from `ip` as `user`
+    pub fn record_failure(&self, ip: Option<IpAddr>, user: Option<&str>) {
+        self.record_failure_at(ip, user, Instant::now());
+    }
+
+    /// Forget the failures of `user`, who has just logged in
+    pub fn record_success(&self, user: &str) {
+        self.failures.remove(&user_key(user));
+    }
+
+    /// Keys with failures remembered
+    pub fn tracked(&self) -> usize {
+        self.failures.len()
+    }
+
+    fn keys(&self, ip: Option<IpAddr>, user: Option<&str>) -> impl Iterator<Item = (Key, u32)> {
+        let ip = ip.map(|ip| (Key::Ip(ip), self.config.max_failures_per_ip));
+        let user = user.map(|user| (user_key(user), self.config.max_failures));
+        ip.into_iter().chain(user)
+    }
+
+    fn check_at(&self, ip: Option<IpAddr>, user: Option<&str>, now: Instant) -> Result<(), Throttled> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let mut refused: Option<Throttled> = None;
+        for (key, _) in self.keys(ip, user) {
+            let Some(failures) = self.failures.get(&key) else { continue };
+            if failures.blocked_until <= now {
+                continue;
+            }
+            let wait = failures.blocked_until - now;
+            // Whole seconds, rounded up, so a client waiting as told is not refused again
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            let locked = failures.locked || refused.is_some_and(|refused| refused.locked);
+            let retry_after = retry_after.max(refused.map_or(0, |refused| refused.retry_after));
+            refused = Some(Throttled { locked, retry_after });
+        }
+        refused.map_or(Ok(()), Err)
+    }
+
+    fn record_failure_at(&self, ip: Option<IpAddr>, user: Option<&str>, now: Instant) {
+        if !self.config.enabled {
+            return;
+        }
+        for (key, threshold) in self.keys(ip, user) {
+            let mut failures = self.failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                blocked_until: now,
+                locked: false,
+            });
+            if now.duration_since(failures.last) >= self.config.lockout {
+                *failures = Failures { count: 0, last: now, blocked_until: now, locked: false };
+            }
+            failures.count = failures.count.saturating_add(1);
+            failures.last = now;
+            if failures.count >= threshold {
+                if !failures.locked {
+                    warn!("Locked out {} for {:?} after {} failed attempts", key, self.config.lockout, failures.count);
+                }
+                failures.locked = true;
+                failures.blocked_until = now + self.config.lockout;
+            } else {
+                failures.blocked_until = now + self.config.backoff(failures.count);
+            }
+        }
+        if self.recorded.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            let lockout = self.config.lockout;
+            self.failures.retain(|_, failures| now.duration_since(failures.last) < lockout);
+        }
+    }
+}
+
+/// Usernames are counted as directories compare them, whatever their case
+fn user_key(user: &str) -> Key {
+    Key::User(user.trim().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_then_lockout() {
+        let throttle = LoginThrottle::new(LockoutConfig { max_failures: 4, max_failures_per_ip: 6, ..LockoutConfig::default() });
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert_eq!(throttle.check_at(Some(ip), Some("alice"), start), Ok(()));
+
+        // Each failure doubles the wait
+        throttle.record_failure_at(Some(ip), Some("alice"), start);
+        assert_eq!(throttle.check_at(Some(ip), Some("Alice"), start), Err(Throttled { locked: false, retry_after: 1 }));
+        assert_eq!(throttle.check_at(Some(ip), Some("alice"), at(1)), Ok(()));
+        throttle.record_failure_at(Some(ip), Some("alice"), at(1));
+        throttle.record_failure_at(Some(ip), Some("alice"), at(3));
+        assert_eq!(throttle.check_at(None, Some("alice"), at(5)), Err(Throttled { locked: false, retry_after: 2 }));
+        assert_eq!(throttle.check_at(None, Some("bob"), at(5)), Ok(()));
+
+        // The fourth failure locks the username out, the sixth from the IP the address
+        throttle.record_failure_at(Some(ip), Some("alice"), at(7));
+        assert_eq!(throttle.check_at(None, Some("alice"), at(60)), Err(Throttled { locked: true, retry_after: 847 }));
+        throttle.record_failure_at(Some(ip), Some("bob"), at(60));
+        assert_eq!(throttle.check_at(Some(ip), Some("carol"), at(60)), Err(Throttled { locked: false, retry_after: 16 }));
+        throttle.record_failure_at(Some(ip), Some("carol"), at(80));
+        assert_eq!(throttle.check_at(Some(ip), Some("carol"), at(80)), Err(Throttled { locked: true, retry_after: 900 }));
+        assert_eq!(throttle.check_at(None, Some("carol"), at(80)), Err(Throttled { locked: false, retry_after: 1 }));
+
+        // Logging in clears the username only; lockouts end, and failures are forgotten
+        throttle.record_success("ALICE");
+        assert_eq!(throttle.check_at(None, Some("alice"), at(80)), Ok(()));
+        assert_eq!(throttle.check_at(Some(ip), None, at(980)), Ok(()));
+        throttle.record_failure_at(Some(ip), None, at(980));
+        assert_eq!(throttle.check_at(Some(ip), None, at(980)), Err(Throttled { locked: false, retry_after: 1 }));
+    }
+
+    #[test]
+    fn test_disabled_and_capped() {
+        let config = LockoutConfig::default();
+        assert_eq!(config.backoff(1), Duration::from_secs(1));
+        assert_eq!(config.backoff(4), Duration::from_secs(8));
+        assert_eq!(config.backoff(500), config.max_backoff);
+
+        let throttle = LoginThrottle::new(LockoutConfig { enabled: false, ..config });
+        for _ in 0..50 {
+            throttle.record_failure(None, Some("alice"));
+        }
+        assert_eq!(throttle.check(None, Some("alice")), Ok(()));
+        assert_eq!(throttle.tracked(), 0);
+    }
+}
@@ -0,0 +1,538 @@
+//! Authentication, authorization and rate limiting as tower layers
+//!
+//! Whatever the transport, a request is checked the same way: its
+//! [`Credentials`] are checked for an [`Authentication`], a caller without
+//! what it needs is given a [`Refusal`], and the audit log records the same
+//! events. Around the HTTP router the checks run as layers, in this order:
+//! [`AuthenticateLayer`] leaves each request's [`Authentication`] in its
+//! extensions, [`RateLimitLayer`] takes a token from the caller's bucket,
+//! and [`EnforcePolicyLayer`] refuses requests lacking a scope `[policy]`
+//! requires of their route. The WebSocket upgrade and gRPC calls are not
+//! served by a tower stack of this `http` version, and check their
+//! [`Credentials`] directly, so a token is accepted or refused alike
+//! everywhere.
+
+use super::mtls::ClientCertificate;
+use super::signing::Signer;
+use super::{api_keys, AuthService, Claims, RateLimitStatus, RateLimitTier};
+use crate::audit::{AuditEvent, AuditKind, Auditor};
+use crate::http::ErrorResponse;
+use crate::ServerState;
+use axum::extract::ConnectInfo;
+use axum::http::{header, Extensions, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::{BoxFuture, Either};
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::debug;
+
+/// The bearer token of a request, or else its API key header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get(api_keys::HEADER).and_then(|value| value.to_str().ok()))
+}
+
+/// Client IP of a request, resolved through the trusted proxies
+pub fn request_source(state: &ServerState, extensions: &Extensions, headers: &HeaderMap) -> Option<IpAddr> {
+    let ConnectInfo(peer) = extensions.get::<ConnectInfo<SocketAddr>>()?;
+    Some(state.config().trusted_proxies.resolve(peer.ip(), headers))
+}
+
+/// An audit event of `kind` about an HTTP request
+pub fn audit_request<B>(state: &ServerState, kind: AuditKind, request: &Request<B>) -> AuditEvent {
+    AuditEvent::new(kind, "http")
+        .source(request_source(state, request.extensions(), request.headers()))
+        .resource(format!("{} {}", request.method(), request.uri().path()))
+}
+
+/// What a caller presented to say who it is
+#[derive(Clone, Copy, Default)]
+pub struct Credentials<'a> {
+    /// Bearer token or API key
+    pub token: Option<&'a str>,
+    /// Key a request was signed with, its signature verified
+    pub signer: Option<&'a Signer>,
+    /// Verified client certificate of the connection
+    pub certificate: Option<&'a ClientCertificate>,
+}
+
+impl<'a> Credentials<'a> {
+    /// Credentials of an HTTP request: its token, and the signer or certificate verified before it came in
+    pub fn of(headers: &'a HeaderMap, extensions: &'a Extensions) -> Self {
+        Self {
+            token: bearer_token(headers),
+            signer: extensions.get::<Arc<Signer>>().map(Arc::as_ref),
+            certificate: extensions.get::<Arc<ClientCertificate>>().map(Arc::as_ref),
+        }
+    }
+
+    /// A token alone
+    #[must_use]
+    pub fn token(token: &'a str) -> Self {
+        Self {
+            token: Some(token),
+            ..Self::default()
+        }
+    }
+
+    /// Check the token, else the signer, else the certificate
+    ///
+    /// A token or signer that does not check out makes the caller
+    /// [`Authentication::Invalid`], even if the certificate would do.
+    pub fn authenticate(&self, auth: &AuthService) -> Authentication {
+        if let Some(token) = self.token {
+            return match auth.validate_token(token) {
+                Ok(claims) => Authentication::Authenticated(claims),
+                Err(e) => Authentication::Invalid(format!("Invalid token: {e}")),
+            };
+        }
+        if let Some(signer) = self.signer {
+            return auth
+                .signer_claims(signer)
+                .map_or_else(|| Authentication::Invalid("Unknown signing key".to_string()), Authentication::Authenticated);
+        }
+        self.certificate
+            .and_then(|certificate| auth.certificate_claims(certificate))
+            .map_or(Authentication::Anonymous, Authentication::Authenticated)
+    }
+}
+
+/// Who a caller is, as its [`Credentials`] say
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    /// Valid credentials, granting these claims
+    Authenticated(Claims),
+    /// No credentials
+    Anonymous,
+    /// Credentials that did not check out, and why
+    Invalid(String),
+}
+
+impl Authentication {
+    /// Claims of valid credentials
+    #[must_use]
+    pub fn claims(&self) -> Option<&Claims> {
+        match self {
+            Self::Authenticated(claims) => Some(claims),
+            _ => None,
+        }
+    }
+
+    /// Claims of valid credentials, or the refusal of a caller without them
+    ///
+    /// # Errors
+    ///
+    /// The [`Refusal`] of the credentials, or of their absence.
+    pub fn require(&self) -> Result<&Claims, Refusal> {
+        match self {
+            Self::Authenticated(claims) => Ok(claims),
+            Self::Anonymous => Err(Refusal::Unauthenticated("Missing bearer token".to_string())),
+            Self::Invalid(detail) => Err(Refusal::Unauthenticated(detail.clone())),
+        }
+    }
+
+    /// Claims of valid credentials, else the `anonymous` claims of a caller without any, or the refusal of a caller with neither
+    ///
+    /// # Errors
+    ///
+    /// The [`Refusal`] of invalid credentials, or of their absence without
+    /// `anonymous` claims.
+    pub fn permit<'a>(&'a self, anonymous: Option<&'a Claims>) -> Result<&'a Claims, Refusal> {
+        match (self, anonymous) {
+            (Self::Anonymous, Some(claims)) => Ok(claims),
+            _ => self.require(),
+        }
+    }
+
+    /// Record credentials refused, or an API key used, as events `event` starts for a kind
+    pub fn audit(&self, auditor: &Auditor, event: impl FnOnce(AuditKind) -> AuditEvent) {
+        match self {
+            Self::Authenticated(claims) if claims.custom.contains_key("key_id") => auditor.record(
+                event(AuditKind::ApiKeyUsed)
+                    .subject(Some(claims.sub.clone()))
+                    .client(claims.client_name()),
+            ),
+            Self::Invalid(detail) => auditor.record(event(AuditKind::AuthenticationFailed).detail(detail)),
+            _ => {}
+        }
+    }
+}
+
+/// Why a request was refused
+#[derive(Debug, Clone)]
+pub enum Refusal {
+    /// Missing or invalid credentials
+    Unauthenticated(String),
+    /// Credentials lacking a scope
+    Forbidden(String),
+    /// The caller's bucket is empty
+    RateLimited(RateLimitStatus),
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthenticated(message) | Self::Forbidden(message) => f.write_str(message),
+            Self::RateLimited(status) => write!(
+                f,
+                "Rate limit of the {} tier exceeded; retry in {}s",
+                status.tier.as_str(),
+                status.retry_after
+            ),
+        }
+    }
+}
+
+impl IntoResponse for Refusal {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let mut response = (status, Json(ErrorResponse { error: self.to_string() })).into_response();
+        if let Self::RateLimited(limited) = &self {
+            rate_limit_headers(response.headers_mut(), limited);
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(limited.retry_after));
+        }
+        response
+    }
+}
+
+/// Describe `status` in the `X-RateLimit-*` headers
+fn rate_limit_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    headers.insert("x-ratelimit-tier", HeaderValue::from_static(status.tier.as_str()));
+    headers.insert("x-ratelimit-limit", HeaderValue::from(status.limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(status.remaining));
+    headers.insert("x-ratelimit-reset", HeaderValue::from(status.reset_at));
+}
+
+/// The [`Authentication`] an [`Authenticate`] layer left on `request`, or else that of its credentials
+fn authentication<B>(auth: &AuthService, request: &Request<B>) -> Authentication {
+    request
+        .extensions()
+        .get::<Authentication>()
+        .cloned()
+        .unwrap_or_else(|| Credentials::of(request.headers(), request.extensions()).authenticate(auth))
+}
+
+/// Layer applying [`Authenticate`]
+#[derive(Clone)]
+pub struct AuthenticateLayer {
+    state: Arc<ServerState>,
+}
+
+impl AuthenticateLayer {
+    pub fn new(state: Arc<ServerState>) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for AuthenticateLayer {
+    type Service = Authenticate<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authenticate {
+            inner,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+/// Leave the [`Authentication`] of each request in its extensions, auditing credentials refused and API keys used
+///
+/// Nothing is refused here: a request without valid credentials goes on,
+/// and refusing it is left to the policy and the handlers that need them.
+/// With authentication disabled every request is anonymous.
+#[derive(Clone)]
+pub struct Authenticate<S> {
+    inner: S,
+    state: Arc<ServerState>,
+}
+
+impl<S, B> Service<Request<B>> for Authenticate<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let authentication = match &self.state.auth_service {
+            Some(auth) => {
+                let authentication = Credentials::of(request.headers(), request.extensions()).authenticate(auth);
+                authentication.audit(&self.state.audit, |kind| audit_request(&self.state, kind, &request));
+                authentication
+            }
+            None => Authentication::Anonymous,
+        };
+        request.extensions_mut().insert(authentication);
+        self.inner.call(request)
+    }
+}
+
+/// Layer applying [`RateLimit`]
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    state: Arc<ServerState>,
+    exempt: &'static [&'static str],
+}
+
+impl RateLimitLayer {
+    pub fn new(state: Arc<ServerState>) -> Self {
+        Self { state, exempt: &[] }
+    }
+
+    /// Never limit requests for these paths
+    #[must_use]
+    pub fn exempt(mut self, paths: &'static [&'static str]) -> Self {
+        self.exempt = paths;
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            state: Arc::clone(&self.state),
+            exempt: self.exempt,
+        }
+    }
+}
+
+/// Take a token from the caller's bucket, refusing the request with 429 when there is none
+///
+/// Callers with valid credentials are limited per subject, in the elevated
+/// tier when they hold the `unlimited` scope; the rest per client IP. Every
+/// response says which limits applied and what is left of them.
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    state: Arc<ServerState>,
+    exempt: &'static [&'static str],
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // The clone may not be ready; the instance polled ready serves this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = Arc::clone(&self.state);
+        if !state.rate_limiter.config().enabled || self.exempt.contains(&request.uri().path()) {
+            return Box::pin(inner.call(request));
+        }
+        let claims = state.auth_service.as_ref().and_then(|auth| authentication(auth, &request).claims().cloned());
+        let tier = RateLimitTier::of(claims.as_ref());
+        let client = match claims {
+            Some(claims) => claims.sub,
+            None => request_source(&state, request.extensions(), request.headers())
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+        };
+        Box::pin(async move {
+            match state.rate_limiter.acquire(tier, &client).await {
+                Ok(status) => {
+                    let mut response = inner.call(request).await?;
+                    rate_limit_headers(response.headers_mut(), &status);
+                    Ok(response)
+                }
+                Err(status) => {
+                    debug!("Rate limited {} client {}", tier.as_str(), client);
+                    Ok(Refusal::RateLimited(status).into_response())
+                }
+            }
+        })
+    }
+}
+
+/// Layer applying [`EnforcePolicy`]
+#[derive(Clone)]
+pub struct EnforcePolicyLayer {
+    state: Arc<ServerState>,
+}
+
+impl EnforcePolicyLayer {
+    pub fn new(state: Arc<ServerState>) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for EnforcePolicyLayer {
+    type Service = EnforcePolicy<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EnforcePolicy {
+            inner,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+/// Refuse requests lacking a scope the configured policy requires of them
+///
+/// Runs before the handler, which then checks the scope the operation
+/// itself needs. Requests no policy rule matches pass untouched, so public
+/// endpoints stay public unless a rule names them.
+#[derive(Clone)]
+pub struct EnforcePolicy<S> {
+    inner: S,
+    state: Arc<ServerState>,
+}
+
+impl<S> EnforcePolicy<S> {
+    fn check<B>(&self, request: &Request<B>) -> Result<(), Refusal> {
+        let Some(auth) = &self.state.auth_service else {
+            return Ok(());
+        };
+        let (method, path) = (request.method().as_str(), request.uri().path());
+        if !auth.policy().covers_route(method, path) {
+            return Ok(());
+        }
+        let authentication = authentication(auth, request);
+        let anonymous = self.state.anonymous_claims();
+        let claims = authentication.permit(anonymous.as_ref()).inspect_err(|_| {
+            // Invalid credentials were recorded as they arrived
+            if let Authentication::Anonymous = authentication {
+                let event = audit_request(&self.state, AuditKind::AuthorizationDenied, request);
+                self.state.audit.record(event.detail("Missing credentials"));
+            }
+        })?;
+        match auth.policy().missing_for_route(method, path, claims) {
+            Some(scope) => {
+                let detail = format!("Requires the {scope} scope");
+                let event = audit_request(&self.state, AuditKind::AuthorizationDenied, request)
+                    .subject(Some(claims.sub.clone()))
+                    .client(claims.client_name())
+                    .detail(&detail);
+                self.state.audit.record(event);
+                Err(Refusal::Forbidden(detail))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for EnforcePolicy<S>
+where
+    S: Service<Request<B>, Response = Response>,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Response, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        match self.check(&request) {
+            Ok(()) => Either::Left(self.inner.call(request)),
+            Err(refusal) => Either::Right(ready(Ok(refusal.into_response()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::policy::ScopePolicy;
+    use crate::auth::{RateLimitConfig, TierLimits};
+    use crate::ServerConfig;
+    use axum::body::Body;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn test_layers_outside_the_router() {
+        let rate_limit = RateLimitConfig {
+            enabled: true,
+            anonymous: TierLimits::new(1, 1),
+            authenticated: TierLimits::new(1, 2),
+            ..RateLimitConfig::default()
+        };
+        let policy = ScopePolicy::default().route("/private", vec!["inventory".to_string()]);
+        let config = ServerConfig { enable_auth: true, rate_limit, policy, ..ServerConfig::default() };
+        let state = Arc::new(ServerState::new(config));
+        let token = state.auth_service.as_ref().unwrap().generate_token("dev".to_string(), Vec::new()).unwrap();
+        let service = ServiceBuilder::new()
+            .layer(AuthenticateLayer::new(Arc::clone(&state)))
+            .layer(RateLimitLayer::new(Arc::clone(&state)))
+            .layer(EnforcePolicyLayer::new(Arc::clone(&state)))
+            .service(service_fn(|request: Request<Body>| async move {
+                let subject = match request.extensions().get::<Authentication>() {
+                    Some(Authentication::Authenticated(claims)) => claims.sub.clone(),
+                    Some(Authentication::Invalid(detail)) => detail.clone(),
+                    _ => "anonymous".to_string(),
+                };
+                Ok::<_, Infallible>(Response::new(Body::from(subject)))
+            }));
+        let peer = SocketAddr::from(([192, 0, 2, 7], 40000));
+        let call = |uri: &str, token: Option<&str>| {
+            let mut request = Request::builder().uri(uri).extension(ConnectInfo(peer));
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            service.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let body = |response: Response| async move {
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        let response = call("/public", Some(&token)).await.unwrap();
+        assert_eq!(response.headers()["x-ratelimit-tier"], "authenticated");
+        assert_eq!(body(response).await, "dev");
+        let refused = call("/private", Some(&token)).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(refused).await, r#"{"error":"Requires the inventory scope"}"#);
+
+        // Invalid credentials reach the service as such, and count against the client IP
+        let forged = call("/public", Some("forged")).await.unwrap();
+        assert!(body(forged).await.starts_with("Invalid token"));
+        let limited = call("/public", None).await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers()[header::RETRY_AFTER], "60");
+    }
+
+    #[test]
+    fn test_credentials_checked_in_order() {
+        let state = ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() });
+        let auth = state.auth_service.as_ref().unwrap();
+        let token = auth.generate_token("dev".to_string(), vec!["read".to_string()]).unwrap();
+
+        let authentication = Credentials::token(&token).authenticate(auth);
+        assert_eq!(authentication.require().unwrap().sub, "dev");
+        assert!(matches!(Credentials::default().authenticate(auth), Authentication::Anonymous));
+        let refusal = Credentials::token("forged").authenticate(auth).require().unwrap_err();
+        assert!(matches!(refusal, Refusal::Unauthenticated(message) if message.starts_with("Invalid token")));
+        let refusal = Credentials::default().authenticate(auth).require().unwrap_err();
+        assert_eq!(refusal.to_string(), "Missing bearer token");
+    }
+}
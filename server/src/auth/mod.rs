@@ -0,0 +1,1226 @@
+//! JWT-based authentication and authorization
+//!
+//! Provides secure authentication for HTTP API and WebSocket connections.
+//! Tokens are JWTs signed with HS256 under the configured secret, or with
+//! RS256 or ES256 under a private key; a token is only trusted once its
+//! signature checks out, and a rejected one says whether it was malformed,
+//! forged or expired, as a [`TokenError`].
+//!
+//! With public-key algorithms the server accepts tokens signed by its own
+//! key and by the public keys of other issuers, such as a central identity
+//! service. The public half of its own key is published as a JWK set, so
+//! clients can check its tokens without sharing a secret. Signing keys are
+//! rotated without ending sessions, the replaced key verifying until its
+//! tokens expire; see [`keyring`].
+//!
+//! Tokens of an external `OpenID` Connect provider are verified against the
+//! keys it publishes; see [`oidc`]. Long-lived API keys are kept in an
+//! [`ApiKeyStore`] instead of being tokens, so they can be revoked.
+//! Editor plugins hold a refresh token instead, exchanged for short-lived
+//! access tokens and rotated on each exchange; see [`refresh`]. A leaked
+//! token is revoked by its `jti` claim before it expires; see
+//! [`revocation`]. Users may log in with their directory credentials for a
+//! refresh token; see [`directory`], or through a SAML identity provider;
+//! see [`saml`]. Repeated failed logins are slowed down
+//! and then locked out; see [`lockout`]. Machine clients may sign each request
+//! with a shared secret instead of holding a token; see [`signing`].
+//! Browser clients keeping their token in a cookie are protected against
+//! forged requests; see [`csrf`].
+//! Whatever the credential, a scope naming a role grants the role's
+//! scopes; see [`roles`]. A scope ending in `*` grants the scopes below
+//! it, as `documents:*` grants `documents:read`; see [`scopes`]. Requests
+//! are limited per caller by a [`RateLimiter`]; see [`rate_limit`]. Each
+//! transport applies these checks alike, through the layers of
+//! [`middleware`].
+
+pub mod api_keys;
+pub mod csrf;
+pub mod directory;
+pub mod introspection;
+pub mod keyring;
+pub mod lockout;
+pub mod middleware;
+pub mod mtls;
+pub mod oidc;
+pub mod policy;
+pub mod rate_limit;
+pub mod refresh;
+pub mod revocation;
+pub mod saml;
+pub mod roles;
+pub mod scopes;
+pub mod signing;
+
+pub use self::rate_limit::{
+    schedule_eviction, RateLimitConfig, RateLimitStatus, RateLimitTier, RateLimiter, TierLimits, UNLIMITED_SCOPE,
+};
+
+use self::api_keys::ApiKeyStore;
+use self::directory::{Directory, LdapConfig, LoginError};
+use self::introspection::Introspection;
+use self::keyring::{KeyInfo, Keyring};
+use self::lockout::{LockoutConfig, LoginThrottle};
+use self::mtls::{ClientCertificate, MtlsConfig};
+use self::oidc::{OidcConfig, OidcProvider};
+use self::policy::ScopePolicy;
+use self::refresh::{RefreshFamily, RefreshTokenStore, TokenLifetimes, TokenPair};
+use self::revocation::{RevocationList, RevokedToken};
+use self::roles::Roles;
+use self::saml::{SamlConfig, ServiceProvider};
+use self::signing::{RequestVerifier, SignatureError, Signer, SigningConfig};
+use crate::ServerConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, EllipticCurveKeyType, Jwk,
+    JwkSet, KeyAlgorithm, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Issuer of every token this server signs
+const ISSUER: &str = "universal-connector";
+/// Audience of every token this server signs
+const AUDIENCE: &str = "universal-connector-api";
+
+/// JWT token claims
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Subject (user ID)
+    pub sub: String,
+    /// Issued at (timestamp)
+    pub iat: i64,
+    /// Expiration time (timestamp)
+    pub exp: i64,
+    /// Issuer
+    pub iss: String,
+    /// Audience
+    pub aud: String,
+    /// Unique ID, by which the token is revoked; absent from API keys and some other issuers' tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Scopes/permissions
+    pub scopes: Vec<String>,
+    /// Custom claims
+    #[serde(flatten)]
+    pub custom: HashMap<String, serde_json::Value>,
+}
+
+impl Claims {
+    /// Create new claims with default expiration (24 hours)
+    pub fn new(user_id: String, scopes: Vec<String>) -> Self {
+        let now = Utc::now();
+        let exp = now + Duration::hours(24);
+
+        Self {
+            sub: user_id,
+            iat: now.timestamp(),
+            exp: exp.timestamp(),
+            iss: ISSUER.to_string(),
+            aud: AUDIENCE.to_string(),
+            jti: Some(uuid::Uuid::new_v4().to_string()),
+            scopes,
+            custom: HashMap::new(),
+        }
+    }
+
+    /// Check if token is expired
+    pub fn is_expired(&self) -> bool {
+        let now = Utc::now().timestamp();
+        now >= self.exp
+    }
+
+    /// Whether any scope held grants `scope`, exactly or under a wildcard; see [`scopes::grants`]
+    #[must_use]
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|held| scopes::grants(held, scope))
+    }
+
+    /// Add custom claim
+    pub fn add_custom(&mut self, key: String, value: serde_json::Value) {
+        self.custom.insert(key, value);
+    }
+
+    /// Name of the client the token was issued to, from a `client_name` or API key `key_name` claim
+    #[must_use]
+    pub fn client_name(&self) -> Option<&str> {
+        ["client_name", "key_name"]
+            .iter()
+            .find_map(|key| self.custom.get(*key).and_then(serde_json::Value::as_str))
+    }
+}
+
+/// Algorithm tokens are signed and verified with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenAlgorithm {
+    /// HMAC-SHA256 under a shared secret
+    #[default]
+    #[serde(rename = "HS256")]
+    Hs256,
+    /// RSA PKCS#1 v1.5 signatures with SHA-256
+    #[serde(rename = "RS256")]
+    Rs256,
+    /// ECDSA over P-256 with SHA-256
+    #[serde(rename = "ES256")]
+    Es256,
+}
+
+impl TokenAlgorithm {
+    /// Name used in configuration and in token headers
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hs256 => "HS256",
+            Self::Rs256 => "RS256",
+            Self::Es256 => "ES256",
+        }
+    }
+
+    fn jwt(self) -> Algorithm {
+        match self {
+            Self::Hs256 => Algorithm::HS256,
+            Self::Rs256 => Algorithm::RS256,
+            Self::Es256 => Algorithm::ES256,
+        }
+    }
+}
+
+/// Claims of callers presenting no credentials, if they may do anything
+///
+/// With authentication disabled they may do everything, or only read with
+/// `read_only`; with it enabled they are refused, or may read with
+/// `read_only`.
+#[must_use]
+pub fn anonymous_claims(enabled: bool, read_only: bool) -> Option<Claims> {
+    let scope = match (enabled, read_only) {
+        (_, true) => roles::READ,
+        (false, false) => "*",
+        (true, false) => return None,
+    };
+    Some(Claims::new("anonymous".to_string(), vec![scope.to_string()]))
+}
+
+/// Authentication middleware configuration
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// JWT secret key, for HS256
+    pub secret: String,
+    /// Algorithm tokens are signed and verified with
+    pub algorithm: TokenAlgorithm,
+    /// PEM private key tokens are signed with, for RS256 and ES256
+    pub private_key: Option<String>,
+    /// PEM public keys of other issuers whose tokens are accepted, for RS256 and ES256
+    pub public_keys: Vec<String>,
+    /// External identity provider whose tokens are accepted too
+    pub oidc: Option<OidcConfig>,
+    /// Client certificates accepted in place of tokens, and who they stand for
+    pub mtls: Option<MtlsConfig>,
+    /// Clients signing their requests in place of tokens, and who they stand for
+    pub signing: Option<SigningConfig>,
+    /// Directory `/auth/login` checks credentials against, and the scopes its groups grant
+    pub ldap: Option<LdapConfig>,
+    /// SAML identity provider whose assertions `/auth/saml/acs` exchanges for tokens
+    pub saml: Option<SamlConfig>,
+    /// Backoff and lockout after failed logins and refresh token exchanges
+    pub lockout: LockoutConfig,
+    /// Token expiration in seconds
+    pub expiration_secs: i64,
+    /// Lifetimes of access and refresh tokens issued by [`AuthService::refresh`]
+    pub lifetimes: TokenLifetimes,
+    /// Scopes granted by each role
+    pub roles: Roles,
+    /// Scopes routes and WebSocket messages require on top of their own
+    pub policy: ScopePolicy,
+    /// Enable authentication
+    pub enabled: bool,
+    /// Let callers presenting no credentials read, and nothing more
+    pub anonymous_read_only: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            secret: std::env::var("JWT_SECRET")
+                .unwrap_or_else(|_| "change-this-secret-in-production".to_string()),
+            algorithm: TokenAlgorithm::default(),
+            private_key: None,
+            public_keys: Vec::new(),
+            oidc: None,
+            mtls: None,
+            signing: None,
+            ldap: None,
+            saml: None,
+            lockout: LockoutConfig::default(),
+            expiration_secs: 86400, // 24 hours
+            lifetimes: TokenLifetimes::default(),
+            roles: Roles::default(),
+            policy: ScopePolicy::default(),
+            enabled: std::env::var("ENABLE_AUTH").unwrap_or_else(|_| "false".to_string()) == "true",
+            anonymous_read_only: false,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// The authentication `config` asks for, with its key files read
+    ///
+    /// A key file that cannot be read is logged and left out, leaving the
+    /// service without that key.
+    #[must_use]
+    pub fn from_server_config(config: &ServerConfig) -> Self {
+        let read = |path: &Path| read_key(path).map_err(|e| warn!("{:#}", e)).ok();
+        Self {
+            secret: config.jwt_secret.clone(),
+            algorithm: config.jwt_algorithm,
+            private_key: config.jwt_private_key_file.as_deref().and_then(read),
+            public_keys: config.jwt_public_key_files.iter().filter_map(|path| read(path)).collect(),
+            oidc: config.oidc.clone(),
+            mtls: config.mtls.clone(),
+            signing: config.request_signing.clone(),
+            ldap: config.ldap.clone(),
+            saml: config.saml.clone(),
+            lockout: config.lockout,
+            expiration_secs: 86400,
+            lifetimes: config.tokens,
+            roles: config.roles.clone(),
+            policy: config.policy.clone(),
+            enabled: config.enable_auth,
+            anonymous_read_only: config.anonymous_read_only,
+        }
+    }
+}
+
+/// Read a PEM key file
+pub(crate) fn read_key(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("reading key file {}", path.display()))
+}
+
+/// A private key for RS256 or ES256, with its public half as a JWK
+pub(crate) struct SigningKey {
+    encoding: EncodingKey,
+    jwk: Jwk,
+}
+
+impl SigningKey {
+    /// Parse a PEM private key: PKCS#8, or PKCS#1 for RSA
+    pub(crate) fn from_pem(algorithm: TokenAlgorithm, pem: &str) -> Result<Self> {
+        let parsed = pem::parse(pem).context("not a PEM file")?;
+        let der = parsed.contents();
+        let (key_algorithm, parameters) = match (algorithm, parsed.tag()) {
+            (TokenAlgorithm::Rs256, tag @ ("PRIVATE KEY" | "RSA PRIVATE KEY")) => {
+                let pair = if tag == "PRIVATE KEY" { RsaKeyPair::from_pkcs8(der) } else { RsaKeyPair::from_der(der) };
+                let pair = pair.map_err(|e| anyhow!("not a usable RSA private key: {e}"))?;
+                let public = ring::rsa::PublicKeyComponents::<Vec<u8>>::from(pair.public());
+                let parameters = RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(public.n),
+                    e: URL_SAFE_NO_PAD.encode(public.e),
+                };
+                (KeyAlgorithm::RS256, AlgorithmParameters::RSA(parameters))
+            }
+            (TokenAlgorithm::Es256, "PRIVATE KEY") => {
+                let rng = ring::rand::SystemRandom::new();
+                let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, der, &rng)
+                    .map_err(|e| anyhow!("not a P-256 private key: {e}"))?;
+                // An uncompressed point: 0x04, then x and y
+                let (x, y) = pair.public_key().as_ref()[1..].split_at(32);
+                let parameters = EllipticCurveKeyParameters {
+                    key_type: EllipticCurveKeyType::EC,
+                    curve: EllipticCurve::P256,
+                    x: URL_SAFE_NO_PAD.encode(x),
+                    y: URL_SAFE_NO_PAD.encode(y),
+                };
+                (KeyAlgorithm::ES256, AlgorithmParameters::EllipticCurve(parameters))
+            }
+            (TokenAlgorithm::Hs256, _) => bail!("HS256 tokens are signed with the secret, not a key"),
+            (algorithm, tag) => bail!("expected a PKCS#8 private key for {}, found {}", algorithm.as_str(), tag),
+        };
+        let encoding = match algorithm {
+            TokenAlgorithm::Rs256 => EncodingKey::from_rsa_pem(pem.as_bytes())?,
+            _ => EncodingKey::from_ec_pem(pem.as_bytes())?,
+        };
+        let common = CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(thumbprint(&parameters)),
+            ..CommonParameters::default()
+        };
+        Ok(Self { encoding, jwk: Jwk { common, algorithm: parameters } })
+    }
+}
+
+/// Parse a PEM public key of another issuer
+pub(crate) fn verifying_key(algorithm: TokenAlgorithm, pem: &str) -> Result<DecodingKey> {
+    Ok(match algorithm {
+        TokenAlgorithm::Rs256 => DecodingKey::from_rsa_pem(pem.as_bytes())?,
+        TokenAlgorithm::Es256 => DecodingKey::from_ec_pem(pem.as_bytes())?,
+        TokenAlgorithm::Hs256 => bail!("HS256 tokens are verified with the secret, not a key"),
+    })
+}
+
+/// The RFC 7638 thumbprint of a public key, used as its key ID
+fn thumbprint(parameters: &AlgorithmParameters) -> String {
+    // The required members only, in lexicographic order
+    let members = match parameters {
+        AlgorithmParameters::RSA(rsa) => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n),
+        AlgorithmParameters::EllipticCurve(ec) => format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, ec.x, ec.y),
+        AlgorithmParameters::OctetKey(_) | AlgorithmParameters::OctetKeyPair(_) => String::new(),
+    };
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, members.as_bytes()))
+}
+
+/// Keys tokens are signed and verified with
+struct Keys {
+    /// With the ID put in token headers, for public-key algorithms
+    signing: Option<(EncodingKey, Option<String>)>,
+    /// Tried in turn until one verifies the signature
+    verifying: Vec<DecodingKey>,
+    /// Public half of the signing key
+    published: Vec<Jwk>,
+}
+
+impl Keys {
+    /// Parse the keys of `config`, logging and leaving out any that are unusable
+    fn load(config: &AuthConfig) -> Self {
+        let mut keys = Self { signing: None, verifying: Vec::new(), published: Vec::new() };
+        if config.algorithm == TokenAlgorithm::Hs256 {
+            if !config.secret.is_empty() {
+                keys.signing = Some((EncodingKey::from_secret(config.secret.as_bytes()), None));
+                keys.verifying.push(DecodingKey::from_secret(config.secret.as_bytes()));
+            }
+            return keys;
+        }
+        if let Some(pem) = &config.private_key {
+            match SigningKey::from_pem(config.algorithm, pem).and_then(|key| {
+                let verifying = DecodingKey::from_jwk(&key.jwk)?;
+                Ok((key, verifying))
+            }) {
+                Ok((key, verifying)) => {
+                    keys.signing = Some((key.encoding, key.jwk.common.key_id.clone()));
+                    keys.verifying.push(verifying);
+                    keys.published.push(key.jwk);
+                }
+                Err(e) => warn!("Token signing key not loaded: {:#}", e),
+            }
+        }
+        for pem in &config.public_keys {
+            match verifying_key(config.algorithm, pem) {
+                Ok(key) => keys.verifying.push(key),
+                Err(e) => warn!("Token verification key not loaded: {:#}", e),
+            }
+        }
+        keys
+    }
+}
+
+/// Why a token was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// Not a JWT, or not one of ours: bad encoding, claims or algorithm
+    Malformed(String),
+    /// Well formed, but not signed with the configured secret
+    BadSignature,
+    /// Signed correctly, but past its `exp`
+    Expired,
+    /// A token, API key or refresh token family that has been revoked
+    Revoked,
+    /// A spent refresh token, presented again; its family is revoked
+    Reused,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed(reason) => write!(f, "Malformed token: {reason}"),
+            TokenError::BadSignature => f.write_str("Token signature does not match"),
+            TokenError::Expired => f.write_str("Token expired"),
+            TokenError::Revoked => f.write_str("Token revoked"),
+            TokenError::Reused => f.write_str("Refresh token already used; every token of its family is revoked"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        match error.kind() {
+            ErrorKind::InvalidSignature => TokenError::BadSignature,
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::InvalidIssuer => TokenError::Malformed("issued by another service".to_string()),
+            ErrorKind::InvalidAudience => TokenError::Malformed("issued for another service".to_string()),
+            _ => TokenError::Malformed(error.to_string()),
+        }
+    }
+}
+
+/// Authentication service
+pub struct AuthService {
+    config: AuthConfig,
+    keys: Keys,
+    oidc: Option<Arc<OidcProvider>>,
+    api_keys: Option<Arc<ApiKeyStore>>,
+    refresh_tokens: Option<Arc<RefreshTokenStore>>,
+    revocations: Arc<RevocationList>,
+    keyring: Arc<Keyring>,
+    signing: Option<RequestVerifier>,
+    directory: Option<Arc<dyn Directory>>,
+    saml: Option<ServiceProvider>,
+    throttle: LoginThrottle,
+}
+
+impl AuthService {
+    /// Create new authentication service
+    ///
+    /// Keys that do not parse are logged and left out; see
+    /// [`AuthService::has_signing_key`] and [`AuthService::has_verifying_key`].
+    pub fn new(config: AuthConfig) -> Self {
+        let keys = Keys::load(&config);
+        let oidc = config.oidc.clone().map(|oidc| Arc::new(OidcProvider::new(oidc)));
+        let revocations = Arc::new(RevocationList::in_memory());
+        let signing = config.signing.clone().map(RequestVerifier::new);
+        let directory = config.ldap.as_ref().and_then(|ldap| directory::open(ldap).map_err(|e| warn!("{:#}", e)).ok());
+        let saml = config.saml.clone().and_then(|saml| ServiceProvider::new(saml).map_err(|e| warn!("SAML sign-on disabled: {:#}", e)).ok());
+        let throttle = LoginThrottle::new(config.lockout);
+        let keyring = Arc::new(Keyring::in_memory());
+        Self { config, keys, oidc, api_keys: None, refresh_tokens: None, revocations, keyring, signing, directory, saml, throttle }
+    }
+
+    /// Accept and create the API keys of `store`
+    #[must_use]
+    pub fn with_api_keys(mut self, store: Arc<ApiKeyStore>) -> Self {
+        self.api_keys = Some(store);
+        self
+    }
+
+    /// Issue and exchange the refresh tokens of `store`
+    #[must_use]
+    pub fn with_refresh_tokens(mut self, store: Arc<RefreshTokenStore>) -> Self {
+        self.refresh_tokens = Some(store);
+        self
+    }
+
+    /// Check `/auth/login` credentials against `directory` rather than the one configured
+    #[must_use]
+    pub fn with_directory(mut self, directory: Arc<dyn Directory>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Refuse the tokens revoked in `list`, and revoke tokens there, in place of a list kept in memory
+    #[must_use]
+    pub fn with_revocations(mut self, list: Arc<RevocationList>) -> Self {
+        self.revocations = list;
+        self
+    }
+
+    /// Sign with the newest key of `keyring`, and verify with its keys, in place of a keyring kept in memory
+    #[must_use]
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    /// Generate JWT token for user
+    pub fn generate_token(&self, user_id: String, scopes: Vec<String>) -> Result<String> {
+        self.sign(&Claims::new(user_id, scopes))
+    }
+
+    /// Sign `claims` under the newest rotated key, or the configured secret or private key, as a bearer token
+    fn sign(&self, claims: &Claims) -> Result<String> {
+        let header = Header::new(self.config.algorithm.jwt());
+        let token = if let Some((key, kid)) = self.keyring.signing(self.config.algorithm) { jsonwebtoken::encode(&Header { kid: Some(kid), ..header }, claims, &key)? } else {
+            let (key, kid) = self.keys.signing.as_ref().ok_or_else(|| anyhow!("No key to sign tokens with is loaded"))?;
+            jsonwebtoken::encode(&Header { kid: kid.clone(), ..header }, claims, key)?
+        };
+        Ok(format!("Bearer {token}"))
+    }
+
+    /// Validate JWT token
+    ///
+    /// The signature is verified before the claims are read, and expiry
+    /// checked after. With public-key algorithms a token signed by any
+    /// configured key is accepted. A token issued by the identity provider,
+    /// if one is configured, is checked against the provider's keys
+    /// instead. An API key is looked up in the key store. Scopes are
+    /// normalized, and roles they name expanded into the scopes they grant.
+    /// Errors are [`TokenError`]s. With authentication disabled any token
+    /// is accepted, with the claims of an anonymous caller.
+    pub fn validate_token(&self, token: &str) -> Result<Claims> {
+        if !self.config.enabled {
+            // If auth is disabled, every caller is anonymous
+            return Ok(self.anonymous_claims().expect("anonymous callers are let in while authentication is disabled"));
+        }
+
+        // Remove "Bearer " prefix if present
+        let mut claims = self.verify(token.strip_prefix("Bearer ").unwrap_or(token))?;
+        self.expand_scopes(&mut claims);
+        Ok(claims)
+    }
+
+    /// Claims of callers presenting no credentials, if they may do anything; see [`anonymous_claims`]
+    pub fn anonymous_claims(&self) -> Option<Claims> {
+        anonymous_claims(self.config.enabled, self.config.anonymous_read_only)
+    }
+
+    /// Whether `token` would be accepted now, and with what claims, for RFC 7662 introspection
+    ///
+    /// Tokens are inactive whatever they hold while authentication is disabled.
+    pub fn introspect(&self, token: &str) -> Introspection {
+        if !self.config.enabled {
+            return Introspection::inactive();
+        }
+        self.validate_token(token).map_or_else(|_| Introspection::inactive(), |claims| Introspection::active(&claims))
+    }
+
+    /// Normalize the scopes of `claims`, then add those the roles they name grant
+    fn expand_scopes(&self, claims: &mut Claims) {
+        scopes::normalize_all(&mut claims.scopes);
+        self.config.roles.expand(&mut claims.scopes);
+    }
+
+    /// Claims of a request made over a connection authenticated with `certificate`
+    ///
+    /// `None` unless client certificates are configured. Roles the scopes
+    /// name are expanded, as for tokens.
+    pub fn certificate_claims(&self, certificate: &ClientCertificate) -> Option<Claims> {
+        if !self.config.enabled {
+            return self.anonymous_claims();
+        }
+        let mut claims = self.config.mtls.as_ref()?.claims(certificate);
+        self.expand_scopes(&mut claims);
+        Some(claims)
+    }
+
+    /// Check the `X-Signature` header of a request for `path` with `method` and `body`
+    ///
+    /// Each signature is accepted once; see [`signing`].
+    ///
+    /// # Errors
+    ///
+    /// A [`SignatureError`] where the header is malformed, names no known key,
+    /// is stale or replayed, or does not match the request.
+    pub fn verify_signature(&self, method: &str, path: &str, header: &str, body: &[u8]) -> Result<Signer, SignatureError> {
+        let verifier = self
+            .signing
+            .as_ref()
+            .ok_or_else(|| SignatureError::Malformed("signed requests are not accepted".to_string()))?;
+        verifier.verify(method, path, header, body)
+    }
+
+    /// Claims of a request whose signature by `signer` was verified
+    ///
+    /// `None` unless signing clients are configured. Roles the scopes name
+    /// are expanded, as for tokens.
+    pub fn signer_claims(&self, signer: &Signer) -> Option<Claims> {
+        if !self.config.enabled {
+            return self.anonymous_claims();
+        }
+        let mut claims = self.signing.as_ref()?.claims(signer)?;
+        self.expand_scopes(&mut claims);
+        Some(claims)
+    }
+
+    /// Claims of a token, as its issuer granted them, unless it has been revoked
+    fn verify(&self, token: &str) -> Result<Claims> {
+        let claims = self.decode(token)?;
+        if claims.jti.as_deref().is_some_and(|jti| self.revocations.is_revoked(jti)) {
+            return Err(TokenError::Revoked.into());
+        }
+        Ok(claims)
+    }
+
+    /// Claims of a token whose signature and expiry check out, revoked or not
+    fn decode(&self, token: &str) -> Result<Claims> {
+        if token.starts_with(api_keys::PREFIX) {
+            let store = self.api_keys.as_ref().ok_or_else(|| TokenError::Malformed("API keys are not accepted".to_string()))?;
+            return Ok(store.validate(token)?.claims());
+        }
+
+        if let Some(provider) = &self.oidc {
+            if oidc::unverified_issuer(token).as_deref() == Some(provider.config().issuer.as_str()) {
+                return Ok(provider.validate(token)?);
+            }
+        }
+
+        let mut validation = Validation::new(self.config.algorithm.jwt());
+        validation.set_issuer(&[ISSUER]);
+        validation.set_audience(&[AUDIENCE]);
+        // Expiry is checked below as `Claims::is_expired` has it, without leeway
+        validation.validate_exp = false;
+        // A token naming a rotated key is verified with that key alone; any other with every key
+        let kid = jsonwebtoken::decode_header(token).ok().and_then(|header| header.kid);
+        let rotated = self.keyring.verifying(self.config.algorithm, kid.as_deref());
+        let named = kid.as_deref().is_some_and(|kid| self.keyring.contains(kid));
+        let configured = if named { &[][..] } else { &self.keys.verifying[..] };
+        let claims = rotated
+            .iter()
+            .chain(configured)
+            .map(|key| jsonwebtoken::decode::<Claims>(token, key, &validation))
+            .find(|decoded| !matches!(decoded, Err(e) if *e.kind() == ErrorKind::InvalidSignature))
+            .ok_or(TokenError::BadSignature)?
+            .map_err(TokenError::from)?
+            .claims;
+
+        // Check expiration
+        if claims.is_expired() {
+            return Err(TokenError::Expired.into());
+        }
+
+        Ok(claims)
+    }
+
+    /// Whether a token signing key is configured
+    pub fn has_signing_key(&self) -> bool {
+        self.keys.signing.is_some() || self.keyring.signing(self.config.algorithm).is_some()
+    }
+
+    /// Whether any key to verify tokens with is configured, counting an identity provider's
+    pub fn has_verifying_key(&self) -> bool {
+        !self.keys.verifying.is_empty() || self.oidc.is_some() || !self.keyring.list().is_empty()
+    }
+
+    /// The identity provider whose tokens are accepted, if one is configured
+    pub fn oidc(&self) -> Option<&Arc<OidcProvider>> {
+        self.oidc.as_ref()
+    }
+
+    /// Where API keys are kept, if anywhere
+    pub fn api_keys(&self) -> Option<&Arc<ApiKeyStore>> {
+        self.api_keys.as_ref()
+    }
+
+    /// Where refresh tokens are kept, if anywhere
+    pub fn refresh_tokens(&self) -> Option<&Arc<RefreshTokenStore>> {
+        self.refresh_tokens.as_ref()
+    }
+
+    /// Tokens revoked before they expire
+    pub fn revocations(&self) -> &Arc<RevocationList> {
+        &self.revocations
+    }
+
+    /// Revoke a token presented to this server, returning its entry in the revocation list
+    ///
+    /// The token must otherwise be valid: its `jti` is only trusted once
+    /// its signature checks out. Revoking a token again succeeds. Tokens
+    /// without a `jti`, such as API keys, are malformed; API keys are
+    /// revoked in their store instead.
+    ///
+    /// # Errors
+    ///
+    /// [`TokenError`]s where the token is not valid or has no `jti`, and an
+    /// error where the revocation cannot be stored.
+    pub fn revoke_token(&self, token: &str) -> Result<RevokedToken> {
+        let claims = self.decode(token.strip_prefix("Bearer ").unwrap_or(token))?;
+        let jti = claims.jti.ok_or_else(|| TokenError::Malformed("no jti claim to revoke it by".to_string()))?;
+        let expires_at = chrono::DateTime::from_timestamp(claims.exp, 0).unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+        self.revocations.revoke(&jti, Some(claims.sub), expires_at)
+    }
+
+    /// Revoke the token `jti` without the token itself, such as one known from the audit log
+    ///
+    /// Its expiry being unknown, the entry is kept for as long as any token
+    /// this server signs lives.
+    ///
+    /// # Errors
+    ///
+    /// Fails without a revocation list, or where the revocation cannot be
+    /// stored.
+    pub fn revoke_jti(&self, jti: &str) -> Result<RevokedToken> {
+        let expires_at = Utc::now().checked_add_signed(self.longest_lifetime()).unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+        self.revocations.revoke(jti, None, expires_at)
+    }
+
+    /// How long the longest-lived token this server signs lives
+    fn longest_lifetime(&self) -> Duration {
+        let access = i64::try_from(self.config.lifetimes.access.as_secs()).unwrap_or(i64::MAX);
+        Duration::seconds(self.config.expiration_secs.max(access))
+    }
+
+    /// Sign new tokens with a new key, generated or given as `pem`, returning it as listed
+    ///
+    /// The key signing so far verifies for as long as the tokens it signed
+    /// live, then retires; see [`keyring`].
+    ///
+    /// # Errors
+    ///
+    /// Fails where `pem` does not parse as a key of the configured algorithm,
+    /// or the keyring cannot be written.
+    pub fn rotate_signing_key(&self, pem: Option<&str>) -> Result<KeyInfo> {
+        self.keyring.rotate(self.config.algorithm, pem, self.longest_lifetime())
+    }
+
+    /// The rotated signing keys, newest first
+    pub fn keyring(&self) -> &Arc<Keyring> {
+        &self.keyring
+    }
+
+    /// Public keys of the tokens this server signs, for `/.well-known/jwks.json`
+    ///
+    /// Empty with HS256, whose secret is never published.
+    pub fn jwks(&self) -> JwkSet {
+        let mut keys = self.keyring.published(self.config.algorithm);
+        keys.extend(self.keys.published.iter().cloned());
+        JwkSet { keys }
+    }
+
+    /// Scopes routes and WebSocket messages require on top of their own
+    pub fn policy(&self) -> &ScopePolicy {
+        &self.config.policy
+    }
+
+    /// Check a token holds every scope the policy requires of a request for `path` with `method`
+    ///
+    /// # Errors
+    ///
+    /// Fails where the token is not valid.
+    pub fn authorize(&self, token: &str, method: &str, path: &str) -> Result<bool> {
+        let claims = self.validate_token(token)?;
+        Ok(self.config.policy.missing_for_route(method, path, &claims).is_none())
+    }
+
+    /// Create an API key valid for a year, returning the key to present
+    ///
+    /// Fails without a key store; see [`AuthService::with_api_keys`].
+    pub fn create_api_key(&self, user_id: String, scopes: Vec<String>, name: String) -> Result<String> {
+        let store = self.api_keys.as_ref().ok_or_else(|| anyhow!("No API key store is open"))?;
+        let expires_at = Utc::now() + Duration::days(365); // 1 year
+        let (_, key) = store.create(name, user_id, scopes, Some(expires_at))?;
+        Ok(key)
+    }
+
+    /// Start a refresh token family for `user_id`, with its first access token
+    ///
+    /// Fails without a refresh token store; see [`AuthService::with_refresh_tokens`].
+    ///
+    /// # Errors
+    ///
+    /// Fails without a refresh token store, or where the family cannot be
+    /// stored or its access token signed.
+    pub fn issue_refresh_token(&self, user_id: String, scopes: Vec<String>, client_name: Option<String>) -> Result<TokenPair> {
+        let store = self.refresh_tokens.as_ref().ok_or_else(|| anyhow!("No refresh token store is open"))?;
+        let (family, refresh_token) = store.issue(user_id, scopes, client_name, self.refresh_expiry())?;
+        self.token_pair(&family, refresh_token)
+    }
+
+    /// Whether `/auth/login` checks credentials against a directory
+    pub fn has_directory(&self) -> bool {
+        self.directory.is_some()
+    }
+
+    /// Failed logins and refresh token exchanges, by client IP and username
+    pub fn throttle(&self) -> &LoginThrottle {
+        &self.throttle
+    }
+
+    /// Check `username` and `password` against the directory, starting a refresh token family for them
+    ///
+    /// The family's scopes are those the user's groups map to when they log
+    /// in.
+    ///
+    /// # Errors
+    ///
+    /// A [`LoginError`] where the directory refuses the user or cannot be
+    /// reached, or an error storing the family.
+    pub async fn login(&self, username: &str, password: &str) -> Result<TokenPair> {
+        let directory = self.directory.as_ref().ok_or_else(|| LoginError::Unavailable("no directory is configured".to_string()))?;
+        if username.is_empty() || password.is_empty() {
+            return Err(LoginError::InvalidCredentials.into());
+        }
+        let user = directory.authenticate(username, password).await?;
+        let scopes = self.config.ldap.as_ref().map(|ldap| ldap.scopes(&user.groups)).unwrap_or_default();
+        self.issue_refresh_token(user.subject, scopes, None)
+    }
+
+    /// Whether `/auth/saml/acs` consumes the assertions of an identity provider
+    pub fn has_saml(&self) -> bool {
+        self.saml.is_some()
+    }
+
+    /// Check the `SAMLResponse` of the identity provider, starting a refresh token family for the user it vouches for
+    ///
+    /// The family's scopes are those the user's groups map to.
+    ///
+    /// # Errors
+    ///
+    /// A [`SamlError`](saml::SamlError) where the response does not check out,
+    /// or an error storing the family.
+    pub fn saml_sign_on(&self, saml_response: &str) -> Result<TokenPair> {
+        let provider = self.saml.as_ref().ok_or_else(|| anyhow!("No SAML identity provider is configured"))?;
+        let user = provider.consume(saml_response)?;
+        let scopes = provider.config().scopes(&user.groups);
+        self.issue_refresh_token(user.subject, scopes, None)
+    }
+
+    /// Exchange a refresh token for an access token and the family's next refresh token
+    ///
+    /// The token presented is spent, and presenting a spent token again
+    /// revokes its family.
+    ///
+    /// # Errors
+    ///
+    /// A [`TokenError`] where the refresh token is unknown, spent, revoked or
+    /// expired, or an error storing the family.
+    pub fn refresh(&self, refresh_token: &str) -> Result<TokenPair> {
+        let store = self
+            .refresh_tokens
+            .as_ref()
+            .ok_or_else(|| TokenError::Malformed("refresh tokens are not accepted".to_string()))?;
+        let (family, refresh_token) = store.rotate(refresh_token, self.refresh_expiry())?;
+        self.token_pair(&family, refresh_token)
+    }
+
+    fn refresh_expiry(&self) -> chrono::DateTime<Utc> {
+        let lifetime = Duration::from_std(self.config.lifetimes.refresh).ok();
+        lifetime.and_then(|lifetime| Utc::now().checked_add_signed(lifetime)).unwrap_or(chrono::DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// An access token for the family's subject, expiring after the configured access lifetime
+    fn token_pair(&self, family: &RefreshFamily, refresh_token: String) -> Result<TokenPair> {
+        let lifetimes = self.config.lifetimes;
+        let mut claims = Claims::new(family.subject.clone(), family.scopes.clone());
+        claims.exp = claims.iat.saturating_add(i64::try_from(lifetimes.access.as_secs()).unwrap_or(i64::MAX));
+        claims.add_custom("refresh_family".to_string(), serde_json::json!(family.id));
+        if let Some(name) = &family.client_name {
+            claims.add_custom("client_name".to_string(), serde_json::json!(name));
+        }
+        let access_token = self.sign(&claims)?;
+        Ok(TokenPair {
+            access_token: access_token.strip_prefix("Bearer ").unwrap_or(&access_token).to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: lifetimes.access.as_secs(),
+            refresh_token,
+            refresh_expires_in: (family.expires_at - Utc::now()).num_seconds().try_into().unwrap_or(0),
+            family: family.id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claims_creation() {
+        let claims = Claims::new("user123".to_string(), vec!["read".to_string()]);
+        assert_eq!(claims.sub, "user123");
+        assert!(claims.has_scope("read"));
+        assert!(!claims.has_scope("write"));
+    }
+
+    #[test]
+    fn test_claims_expiration() {
+        let mut claims = Claims::new("user123".to_string(), vec![]);
+        assert!(!claims.is_expired());
+
+        // Set expiration in the past
+        claims.exp = Utc::now().timestamp() - 3600;
+        assert!(claims.is_expired());
+    }
+
+    #[test]
+    fn test_auth_service_token_generation() {
+        let config = AuthConfig::default();
+        let service = AuthService::new(config);
+
+        let token = service.generate_token("user123".to_string(), vec!["read".to_string()]).unwrap();
+        assert!(token.starts_with("Bearer "));
+    }
+
+    fn service(secret: &str) -> AuthService {
+        AuthService::new(AuthConfig { secret: secret.to_string(), enabled: true, ..AuthConfig::default() })
+    }
+
+    fn token_error(result: Result<Claims>) -> TokenError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let service = service("s3cret");
+        let token = service.generate_token("user123".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(token.split('.').count(), 3);
+
+        let claims = service.validate_token(&token).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.scopes.as_slice()), ("user123", ["read".to_string()].as_slice()));
+        assert_eq!((claims.iss.as_str(), claims.aud.as_str()), (ISSUER, AUDIENCE));
+        // With or without the scheme
+        assert!(service.validate_token(token.strip_prefix("Bearer ").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_revoked_tokens_are_refused() {
+        let service = service("s3cret");
+        let leaked = service.generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        let other = service.generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        let jti = service.validate_token(&leaked).unwrap().jti.unwrap();
+        assert_ne!(service.validate_token(&other).unwrap().jti, Some(jti.clone()));
+
+        let revoked = service.revoke_token(&leaked).unwrap();
+        assert_eq!((revoked.jti.as_str(), revoked.subject.as_deref()), (jti.as_str(), Some("alice")));
+        let error: TokenError = service.validate_token(&leaked).unwrap_err().downcast().unwrap();
+        assert_eq!(error, TokenError::Revoked);
+        assert!(service.validate_token(&other).is_ok());
+        assert_eq!(service.revoke_token(&leaked).unwrap().revoked_at, revoked.revoked_at);
+
+        let forged = AuthService::new(AuthConfig { secret: "another-s3cret".to_string(), ..service.config.clone() });
+        let forged = forged.generate_token("alice".to_string(), Vec::new()).unwrap();
+        assert!(service.revoke_token(&forged).is_err());
+        let mut claims = Claims::new("alice".to_string(), Vec::new());
+        claims.jti = None;
+        let unnamed = service.sign(&claims).unwrap();
+        assert!(matches!(service.revoke_token(&unnamed).unwrap_err().downcast().unwrap(), TokenError::Malformed(_)));
+
+        let other_jti = service.validate_token(&other).unwrap().jti.unwrap();
+        service.revoke_jti(&other_jti).unwrap();
+        assert!(service.validate_token(&other).is_err());
+    }
+
+    #[test]
+    fn test_api_key_carries_its_claims() {
+        let keyless = service("s3cret");
+        assert!(keyless.create_api_key("ci-bot".to_string(), Vec::new(), "ci".to_string()).is_err());
+
+        let service = service("s3cret").with_api_keys(Arc::new(ApiKeyStore::temporary().unwrap()));
+        let key = service.create_api_key("ci-bot".to_string(), vec!["read".to_string()], "ci".to_string()).unwrap();
+        let claims = service.validate_token(&format!("Bearer {key}")).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.client_name()), ("ci-bot", Some("ci")));
+        assert!(claims.has_scope("read"));
+        assert!(claims.exp - claims.iat >= Duration::days(364).num_seconds(), "{claims:?}");
+        assert!(matches!(token_error(keyless.validate_token(&key)), TokenError::Malformed(_)));
+
+        let id = claims.custom["key_id"].as_str().unwrap();
+        service.api_keys().unwrap().revoke(id).unwrap();
+        assert_eq!(token_error(service.validate_token(&key)), TokenError::Revoked);
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        let service = service("s3cret");
+        let reader = service.generate_token("user123".to_string(), vec!["read".to_string()]).unwrap();
+        let admin = service.generate_token("user123".to_string(), vec!["*".to_string()]).unwrap();
+
+        // The claims of one token under the signature of another
+        let [header, _, signature]: [&str; 3] = reader.split('.').collect::<Vec<_>>().try_into().unwrap();
+        let payload = admin.split('.').nth(1).unwrap();
+        let forged = format!("{header}.{payload}.{signature}");
+        assert_eq!(token_error(service.validate_token(&forged)), TokenError::BadSignature);
+    }
+
+    #[test]
+    fn test_wrong_secret_is_rejected() {
+        let token = service("s3cret").generate_token("user123".to_string(), vec!["*".to_string()]).unwrap();
+        let error = token_error(service("another-s3cret").validate_token(&token));
+        assert_eq!(error, TokenError::BadSignature);
+        assert_eq!(error.to_string(), "Token signature does not match");
+    }
+
+    #[test]
+    fn test_expired_and_malformed_tokens() {
+        let service = service("s3cret");
+        let mut claims = Claims::new("user123".to_string(), vec![]);
+        claims.exp = Utc::now().timestamp() - 1;
+        let expired = service.sign(&claims).unwrap();
+        assert_eq!(token_error(service.validate_token(&expired)), TokenError::Expired);
+
+        for token in ["", "not-a-token", "a.b.c", "Bearer x.y"] {
+            assert!(matches!(token_error(service.validate_token(token)), TokenError::Malformed(_)), "{token:?}");
+        }
+    }
+
+    #[test]
+    fn test_roles_grant_their_scopes() {
+        let config = AuthConfig { secret: "s3cret".to_string(), enabled: true, ..AuthConfig::default() };
+        let roles = roles::Roles::default().with("reviewer", vec!["read".to_string(), "comment".to_string()]);
+        let service = AuthService::new(AuthConfig { roles, ..config });
+        let token = service.generate_token("user123".to_string(), vec!["role:reviewer".to_string()]).unwrap();
+        let claims = service.validate_token(&token).unwrap();
+        assert!(claims.has_scope("read") && claims.has_scope("comment") && !claims.has_scope("write"));
+
+        let token = service.generate_token("user123".to_string(), vec!["role:editor".to_string(), "write".to_string()]).unwrap();
+        assert_eq!(service.validate_token(&token).unwrap().scopes, ["role:editor", "write", "read"]);
+    }
+
+    #[test]
+    fn test_hierarchical_scopes_authorize() {
+        let policy = ScopePolicy::default()
+            .route("DELETE /api/documents/:id", vec!["documents:delete".to_string()])
+            .route("/api/formats/*", vec!["formats:*".to_string()]);
+        let service = AuthService::new(AuthConfig { policy, ..service("s3cret").config.clone() });
+        let authorize = |scopes: &[&str], method: &str, path: &str| {
+            let token = service.generate_token("user123".to_string(), scopes.iter().map(ToString::to_string).collect()).unwrap();
+            service.authorize(&token, method, path).unwrap()
+        };
+        assert!(authorize(&["documents:*"], "DELETE", "/api/documents/abc"));
+        assert!(authorize(&["documents : delete"], "DELETE", "/api/documents/abc"));
+        assert!(!authorize(&["documents:read"], "DELETE", "/api/documents/abc"));
+        assert!(!authorize(&["documents"], "DELETE", "/api/documents/abc"));
+        // A wildcard required is held only by that wildcard, or one above it
+        assert!(authorize(&["formats:*"], "GET", "/api/formats/markdown"));
+        assert!(authorize(&["*"], "GET", "/api/formats/markdown"));
+        assert!(!authorize(&["formats:read"], "GET", "/api/formats/markdown"));
+
+        let token = service.generate_token("user123".to_string(), vec![" role: editor ".to_string(), "read".to_string()]).unwrap();
+        assert_eq!(service.validate_token(&token).unwrap().scopes, ["role:editor", "read", "write"]);
+    }
+
+    #[test]
+    fn test_foreign_issuer_or_audience_is_rejected() {
+        let service = service("s3cret");
+        let mut claims = Claims::new("user123".to_string(), vec!["*".to_string()]);
+        claims.iss = "another-service".to_string();
+        let error = token_error(service.validate_token(&service.sign(&claims).unwrap()));
+        assert_eq!(error.to_string(), "Malformed token: issued by another service");
+
+        let mut claims = Claims::new("user123".to_string(), vec!["*".to_string()]);
+        claims.aud = "another-api".to_string();
+        let error = token_error(service.validate_token(&service.sign(&claims).unwrap()));
+        assert_eq!(error.to_string(), "Malformed token: issued for another service");
+    }
+
+    /// A PEM key from `tests/fixtures/keys`, generated for these tests only
+    fn key(name: &str) -> String {
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys").join(name)).unwrap()
+    }
+
+    fn keyed(algorithm: TokenAlgorithm, private_key: Option<&str>, public_keys: &[&str]) -> AuthService {
+        AuthService::new(AuthConfig {
+            algorithm,
+            private_key: private_key.map(key),
+            public_keys: public_keys.iter().map(|name| key(name)).collect(),
+            enabled: true,
+            ..AuthConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_anonymous_claims() {
+        let scopes = |enabled, read_only| anonymous_claims(enabled, read_only).map(|claims| claims.scopes);
+        assert_eq!(scopes(false, false), Some(vec!["*".to_string()]));
+        assert_eq!(scopes(false, true), Some(vec![roles::READ.to_string()]));
+        assert_eq!(scopes(true, true), Some(vec![roles::READ.to_string()]));
+        assert_eq!(scopes(true, false), None);
+
+        // With authentication disabled, whatever token is presented is an anonymous caller's
+        let service = AuthService::new(AuthConfig { enabled: false, anonymous_read_only: true, ..AuthConfig::default() });
+        let claims = service.validate_token("Bearer anything").unwrap();
+        assert_eq!((claims.sub.as_str(), claims.has_scope(roles::WRITE)), ("anonymous", false));
+    }
+
+    #[test]
+    fn test_public_key_tokens_round_trip() {
+        for (algorithm, private_key, kty) in
+            [(TokenAlgorithm::Rs256, "rsa_private.pem", "RSA"), (TokenAlgorithm::Es256, "ec_private.pem", "EC")]
+        {
+            let service = keyed(algorithm, Some(private_key), &[]);
+            let token = service.generate_token("user123".to_string(), vec!["read".to_string()]).unwrap();
+            assert_eq!(service.validate_token(&token).unwrap().sub, "user123");
+
+            // The header names the published key
+            let header = jsonwebtoken::decode_header(token.strip_prefix("Bearer ").unwrap()).unwrap();
+            assert_eq!(header.alg, algorithm.jwt());
+            let jwks = serde_json::to_value(service.jwks()).unwrap();
+            assert_eq!(jwks["keys"].as_array().map(Vec::len), Some(1), "{jwks}");
+            assert_eq!((jwks["keys"][0]["kty"].as_str(), jwks["keys"][0]["alg"].as_str()), (Some(kty), Some(algorithm.as_str())));
+            assert_eq!(jwks["keys"][0]["kid"].as_str(), header.kid.as_deref());
+            assert!(jwks["keys"][0].get("d").is_none(), "{jwks}");
+        }
+        assert!(service("s3cret").jwks().keys.is_empty());
+    }
+
+    #[test]
+    fn test_tokens_of_another_issuer_are_verified_with_its_public_key() {
+        let identity_service = keyed(TokenAlgorithm::Rs256, Some("idp_rsa_private.pem"), &[]);
+        let verifier = keyed(TokenAlgorithm::Rs256, None, &["idp_rsa_public.pem"]);
+        assert!(!verifier.has_signing_key() && verifier.has_verifying_key());
+        assert!(verifier.generate_token("user123".to_string(), vec![]).is_err());
+        assert!(verifier.jwks().keys.is_empty());
+
+        let token = identity_service.generate_token("user123".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(verifier.validate_token(&token).unwrap().scopes, ["read"]);
+
+        // Alongside its own key, and nothing else
+        let both = keyed(TokenAlgorithm::Rs256, Some("rsa_private.pem"), &["idp_rsa_public.pem"]);
+        assert!(both.validate_token(&token).is_ok());
+        let own = both.generate_token("user123".to_string(), vec![]).unwrap();
+        assert!(both.validate_token(&own).is_ok());
+        assert_eq!(token_error(verifier.validate_token(&own)), TokenError::BadSignature);
+        let symmetric = service("s3cret").generate_token("user123".to_string(), vec!["*".to_string()]).unwrap();
+        assert!(matches!(token_error(verifier.validate_token(&symmetric)), TokenError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_rotation_keeps_earlier_tokens_valid() {
+        let kid = |token: &str| jsonwebtoken::decode_header(token.strip_prefix("Bearer ").unwrap()).unwrap().kid;
+        let rotating = service("s3cret");
+        let before = rotating.generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(kid(&before), None);
+
+        let first = rotating.rotate_signing_key(None).unwrap();
+        let after = rotating.generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(kid(&after), Some(first.kid.clone()));
+        let second = rotating.rotate_signing_key(None).unwrap();
+        let latest = rotating.generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(kid(&latest), Some(second.kid.clone()));
+        for token in [&before, &after, &latest] {
+            assert_eq!(rotating.validate_token(token).unwrap().sub, "alice");
+        }
+        assert_eq!(rotating.keyring().list().len(), 2);
+        assert!(rotating.rotate_signing_key(Some(&key("ec_private.pem"))).is_err());
+
+        // A token naming a rotated key is held to that key
+        let forged = service("s3cret").generate_token("alice".to_string(), vec!["*".to_string()]).unwrap();
+        let forged = forged.strip_prefix("Bearer ").unwrap();
+        let header = URL_SAFE_NO_PAD.encode(format!(r#"{{"typ":"JWT","alg":"HS256","kid":"{}"}}"#, first.kid));
+        let relabelled = format!("{}.{}", header, forged.split_once('.').unwrap().1);
+        assert_eq!(token_error(rotating.validate_token(&relabelled)), TokenError::BadSignature);
+
+        // Public keys rotated in are published beside the configured one
+        let keyed = keyed(TokenAlgorithm::Es256, Some("ec_private.pem"), &[]);
+        let rotated = keyed.rotate_signing_key(None).unwrap();
+        let token = keyed.generate_token("alice".to_string(), vec![]).unwrap();
+        assert_eq!(kid(&token), Some(rotated.kid.clone()));
+        let published: Vec<_> = keyed.jwks().keys.into_iter().filter_map(|jwk| jwk.common.key_id).collect();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0], rotated.kid);
+    }
+
+    #[test]
+    fn test_unusable_keys_are_left_out() {
+        assert!(SigningKey::from_pem(TokenAlgorithm::Es256, &key("rsa_private.pem")).is_err());
+        assert!(SigningKey::from_pem(TokenAlgorithm::Rs256, &key("ec_private.pem")).is_err());
+        assert!(SigningKey::from_pem(TokenAlgorithm::Rs256, "not a key").is_err());
+        assert!(verifying_key(TokenAlgorithm::Es256, &key("idp_rsa_public.pem")).is_err());
+
+        let service = keyed(TokenAlgorithm::Es256, Some("rsa_private.pem"), &["idp_rsa_public.pem"]);
+        assert!(!service.has_signing_key() && !service.has_verifying_key());
+        assert!(service.jwks().keys.is_empty());
+    }
+
+    #[test]
+    fn test_client_name() {
+        let mut claims = Claims::new("user123".to_string(), vec![]);
+        assert_eq!(claims.client_name(), None);
+        claims.add_custom("key_name".to_string(), serde_json::json!("ci"));
+        assert_eq!(claims.client_name(), Some("ci"));
+        claims.add_custom("client_name".to_string(), serde_json::json!("vscode"));
+        assert_eq!(claims.client_name(), Some("vscode"));
+    }
+
+    #[test]
+    fn test_wildcard_scope() {
+        let claims = Claims::new("user123".to_string(), vec!["*".to_string()]);
+        assert!(claims.has_scope("*"));
+        assert!(claims.has_scope("read")); // Will fail in real implementation
+    }
+}
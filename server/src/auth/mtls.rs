@@ -0,0 +1,390 @@
+//! Mutual TLS: client certificates in place of tokens
+//!
+//! With `[mtls]` configured, the HTTP and WebSocket listeners serve TLS and
+//! refuse clients without a certificate issued by one of
+//! `client_ca_files`, for deployments that cannot hand out tokens, such as
+//! air-gapped ones. A certificate then stands for an identity as a token
+//! does: `identities` maps its subject, written as in RFC 4514 such as
+//! `CN=alice,OU=Editors,O=Example`, or its common name alone, to a user ID
+//! and scopes. Certificates the authorities issued that `identities` does
+//! not list stand for their common name, with `default_scopes`. A bearer
+//! token or API key sent over the connection takes precedence over the
+//! certificate.
+
+use super::Claims;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client has to complete the TLS handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client certificate settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MtlsConfig {
+    /// PEM certificate chain the listeners present, leaf first
+    pub cert_file: PathBuf,
+    /// PEM private key of that certificate
+    pub key_file: PathBuf,
+    /// PEM certificates of the authorities client certificates must be issued by
+    pub client_ca_files: Vec<PathBuf>,
+    /// Identities by certificate subject, or by common name
+    #[serde(default)]
+    pub identities: BTreeMap<String, CertIdentity>,
+    /// Scopes of certificates `identities` does not list
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+}
+
+impl MtlsConfig {
+    pub fn new(cert_file: impl Into<PathBuf>, key_file: impl Into<PathBuf>, client_ca_files: Vec<PathBuf>) -> Self {
+        Self {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            client_ca_files,
+            identities: BTreeMap::new(),
+            default_scopes: Vec::new(),
+        }
+    }
+
+    /// The claims a request made with `certificate` carries, before roles are expanded
+    #[must_use]
+    pub fn claims(&self, certificate: &ClientCertificate) -> Claims {
+        let identity = self
+            .identities
+            .get(&certificate.subject)
+            .or_else(|| certificate.common_name.as_ref().and_then(|name| self.identities.get(name)));
+        let user = identity
+            .and_then(|identity| identity.user.clone())
+            .or_else(|| certificate.common_name.clone())
+            .unwrap_or_else(|| certificate.subject.clone());
+        let scopes = identity.map_or_else(|| self.default_scopes.clone(), |identity| identity.scopes.clone());
+        let mut claims = Claims::new(user, scopes);
+        claims.exp = certificate.not_after.map_or(i64::MAX, |not_after| not_after.timestamp());
+        claims.add_custom("cert_subject".to_string(), serde_json::json!(certificate.subject));
+        claims
+    }
+}
+
+/// Who a certificate stands for
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertIdentity {
+    /// User ID requests are accounted to; the certificate's common name when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+/// A verified client certificate, as far as identifying its holder goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Subject as in RFC 4514, most specific attribute first
+    pub subject: String,
+    /// The subject's most specific `CN`
+    pub common_name: Option<String>,
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+impl ClientCertificate {
+    /// Read the subject and expiry of a DER X.509 certificate
+    ///
+    /// # Errors
+    ///
+    /// Fails where `der` is not an X.509 certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (certificate, _) = expect(der, SEQUENCE)?;
+        let (tbs, _) = expect(certificate, SEQUENCE)?;
+        let mut rest = tbs;
+        if rest.first() == Some(&EXPLICIT_VERSION) {
+            rest = element(rest).ok_or_else(malformed)?.2;
+        }
+        let (_serial, rest) = expect(rest, INTEGER)?;
+        let (_signature, rest) = expect(rest, SEQUENCE)?;
+        let (_issuer, rest) = expect(rest, SEQUENCE)?;
+        let (validity, rest) = expect(rest, SEQUENCE)?;
+        let (subject, _) = expect(rest, SEQUENCE)?;
+
+        let mut rdns = Vec::new();
+        let mut common_name = None;
+        let mut sets = subject;
+        while !sets.is_empty() {
+            let (set, next) = expect(sets, SET)?;
+            sets = next;
+            let mut attributes = Vec::new();
+            let mut pairs = set;
+            while !pairs.is_empty() {
+                let (pair, next) = expect(pairs, SEQUENCE)?;
+                pairs = next;
+                let (oid, value) = expect(pair, OID)?;
+                let (tag, contents, _) = element(value).ok_or_else(malformed)?;
+                let text = string(tag, contents);
+                let name = attribute_name(oid);
+                if name.as_deref() == Some("CN") {
+                    common_name.clone_from(&text);
+                }
+                let rendered = match (name, text) {
+                    (Some(name), Some(text)) => format!("{}={}", name, escape(&text)),
+                    (name, _) => format!("{}=#{}", name.unwrap_or_else(|| dotted(oid)), hex(value)),
+                };
+                attributes.push(rendered);
+            }
+            rdns.push(attributes.join("+"));
+        }
+        rdns.reverse();
+
+        // notBefore, then notAfter
+        let (_, _, rest) = element(validity).ok_or_else(malformed)?;
+        let not_after = element(rest).and_then(|(tag, contents, _)| time(tag, contents));
+        Ok(Self { subject: rdns.join(","), common_name, not_after })
+    }
+}
+
+/// A TLS acceptor requiring client certificates issued by `client_ca_files`, offering `alpn` protocols
+///
+/// # Errors
+///
+/// Fails where a CA file or the server's certificate or key cannot be read,
+/// or holds no usable certificate.
+pub fn acceptor(config: &MtlsConfig, alpn: &[&str]) -> Result<TlsAcceptor> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    for path in &config.client_ca_files {
+        for certificate in certificates(path)? {
+            roots.add(certificate).with_context(|| format!("adding CA certificate {}", path.display()))?;
+        }
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+        .build()
+        .context("no usable client CA certificates")?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .map_err(|e| anyhow!("reading TLS key file {}: {}", config.key_file.display(), e))?;
+    let mut server = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates(&config.cert_file)?, key)
+        .with_context(|| format!("using TLS certificate {}", config.cert_file.display()))?;
+    server.alpn_protocols = alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Complete the TLS handshake of an accepted connection, returning the stream and its client's certificate
+///
+/// # Errors
+///
+/// Fails where the handshake fails or times out, or the client sends no
+/// certificate.
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<(TlsStream<TcpStream>, ClientCertificate)> {
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")??;
+    let der = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(<[_]>::first)
+        .ok_or_else(|| anyhow!("no client certificate"))?;
+    let certificate = ClientCertificate::from_der(der)?;
+    Ok((stream, certificate))
+}
+
+/// Every certificate in a PEM file
+pub(crate) fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| anyhow!("reading certificate file {}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        bail!("certificate file {} holds no certificates", path.display());
+    }
+    Ok(certificates)
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OID: u8 = 0x06;
+const EXPLICIT_VERSION: u8 = 0xa0;
+
+fn malformed() -> anyhow::Error {
+    anyhow!("malformed certificate")
+}
+
+/// The first DER element of `input`: its tag, its contents, and what follows it
+fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        (bytes.iter().fold(0, |len, &byte| (len << 8) | usize::from(byte)), rest)
+    };
+    (rest.len() >= len).then(|| {
+        let (contents, rest) = rest.split_at(len);
+        (tag, contents, rest)
+    })
+}
+
+/// The contents of the first element of `input`, which must have `tag`, and what follows it
+fn expect(input: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    match element(input) {
+        Some((found, contents, rest)) if found == tag => Ok((contents, rest)),
+        _ => Err(malformed()),
+    }
+}
+
+/// Short name of an attribute type, as OpenSSL writes it in RFC 2253 form
+fn attribute_name(oid: &[u8]) -> Option<String> {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x05] => "serialNumber",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// An object identifier in dotted form, such as `2.5.4.3`
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value: u64 = 0;
+    for &byte in oid {
+        value = (value << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.extend([first, value - first * 40]);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter().map(ToString::to_string).collect::<Vec<_>>().join(".")
+}
+
+/// The text of a directory string, or `None` for types that are not text
+fn string(tag: u8, contents: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, NumericString, PrintableString, IA5String
+        0x0c | 0x12 | 0x13 | 0x16 => String::from_utf8(contents.to_vec()).ok(),
+        // TeletexString, taken as Latin-1 as most encoders mean it
+        0x14 => Some(contents.iter().map(|&byte| char::from(byte)).collect()),
+        // BMPString
+        0x1e => {
+            let units: Vec<u16> = contents.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16(&units).ok()
+        }
+        // UniversalString
+        0x1c => contents
+            .chunks_exact(4)
+            .map(|quad| char::from_u32(u32::from_be_bytes([quad[0], quad[1], quad[2], quad[3]])))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// An attribute value escaped as RFC 4514 requires
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// A `UTCTime` or `GeneralizedTime`
+fn time(tag: u8, contents: &[u8]) -> Option<DateTime<Utc>> {
+    let text = std::str::from_utf8(contents).ok()?;
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(text, format).ok().map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/mtls").join(name)
+    }
+
+    #[test]
+    fn test_subjects_are_read_from_certificates() {
+        let der = certificates(&fixture("alice.pem")).unwrap().remove(0);
+        let certificate = ClientCertificate::from_der(&der).unwrap();
+        assert_eq!(certificate.subject, "CN=alice,OU=Editors,O=Example,C=GB");
+        assert_eq!(certificate.common_name.as_deref(), Some("alice"));
+        assert!(certificate.not_after.unwrap() > Utc::now());
+        assert!(ClientCertificate::from_der(&der[..der.len() / 2]).is_err());
+
+        assert_eq!(escape("Example, Inc. "), "Example\\, Inc.\\ ");
+        assert_eq!(escape("#1"), "\\#1");
+        assert_eq!(dotted(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01]), "1.2.840.113549.1.9.1");
+    }
+
+    #[test]
+    fn test_certificates_map_to_identities() {
+        let mut config = MtlsConfig::new("server.pem", "server_key.pem", Vec::new());
+        config.default_scopes = vec!["read".to_string()];
+        let certificate = |subject: &str, common_name: &str| ClientCertificate {
+            subject: subject.to_string(),
+            common_name: Some(common_name.to_string()),
+            not_after: None,
+        };
+
+        let claims = config.claims(&certificate("CN=bob,O=Example", "bob"));
+        assert_eq!((claims.sub.as_str(), claims.scopes.as_slice()), ("bob", ["read".to_string()].as_slice()));
+        assert_eq!(claims.custom["cert_subject"], "CN=bob,O=Example");
+
+        config.identities.insert("bob".to_string(), CertIdentity { user: None, scopes: vec!["role:editor".to_string()] });
+        let ci = CertIdentity { user: Some("ci".to_string()), scopes: vec!["role:viewer".to_string()] };
+        config.identities.insert("CN=build,OU=Agents,O=Example".to_string(), ci);
+        assert_eq!(config.claims(&certificate("CN=bob,O=Example", "bob")).scopes, ["role:editor"]);
+        let claims = config.claims(&certificate("CN=build,OU=Agents,O=Example", "build"));
+        assert_eq!((claims.sub.as_str(), claims.scopes.as_slice()), ("ci", ["role:viewer".to_string()].as_slice()));
+        // The subject must match in full; otherwise the common name decides
+        assert_eq!(config.claims(&certificate("CN=build,O=Elsewhere", "build")).sub, "build");
+    }
+}
@@ -0,0 +1,488 @@
+//! Tokens issued by an external `OpenID` Connect provider
+//!
+//! With `[oidc]` configured, a bearer token whose `iss` is the provider's
+//! issuer is verified against the keys the provider publishes instead of
+//! the server's own, so users sign in through existing SSO such as
+//! Keycloak, Auth0 or Azure AD. The provider's discovery document names its
+//! JWK set, which is fetched at startup and every `jwks_refresh` after, and
+//! also as soon as a token names a key not seen yet, as happens when the
+//! provider rotates its keys. Those extra fetches are at most one per
+//! [`MIN_REFETCH`].
+//!
+//! Scopes are read from the claim `scope_claim` names, a dotted path such
+//! as `realm_access.roles`, holding a space-separated string or a list.
+//! With `scope_map` set, each provider scope grants the connector scopes it
+//! maps to and unmapped ones grant nothing; otherwise they are taken as
+//! they are.
+
+use super::{Claims, TokenError};
+use crate::scheduler::{Schedule, Scheduler, TaskSpec, OIDC_KEY_REFRESH};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{Jwk, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Least time between fetches made for tokens naming an unknown key
+pub const MIN_REFETCH: Duration = Duration::from_secs(30);
+
+/// How long the provider has to answer a fetch
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// External identity provider settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer exactly as the provider writes it in `iss`, such as `https://sso.example.com/realms/main`
+    pub issuer: String,
+    /// Audience tokens must be issued for, usually the client ID registered with the provider
+    pub audience: String,
+    /// Claim holding the provider's scopes or roles, as a dotted path
+    #[serde(default = "default_scope_claim")]
+    pub scope_claim: String,
+    /// Claim naming the subject tokens are accounted to
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+    /// Connector scopes granted by each provider scope; when empty, provider scopes are used as they are
+    #[serde(default)]
+    pub scope_map: BTreeMap<String, Vec<String>>,
+    /// Time between fetches of the provider's keys
+    #[serde(default = "default_jwks_refresh", with = "crate::config::duration")]
+    pub jwks_refresh: Duration,
+}
+
+fn default_scope_claim() -> String {
+    "scope".to_string()
+}
+
+fn default_subject_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_jwks_refresh() -> Duration {
+    Duration::from_hours(1)
+}
+
+impl OidcConfig {
+    /// Accept tokens of `issuer` issued for `audience`, with the default claims and refresh
+    pub fn new(issuer: impl Into<String>, audience: impl Into<String>) -> Self {
+        Self {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            scope_claim: default_scope_claim(),
+            subject_claim: default_subject_claim(),
+            scope_map: BTreeMap::new(),
+            jwks_refresh: default_jwks_refresh(),
+        }
+    }
+
+    /// Where the provider's discovery document is served
+    #[must_use]
+    pub fn discovery_url(&self) -> String {
+        format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'))
+    }
+}
+
+/// The fields of a discovery document that are used
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    jwks_uri: String,
+}
+
+/// A JWK set read key by key, so one key of an unknown type does not spoil the rest
+#[derive(Deserialize)]
+struct RawJwkSet {
+    keys: Vec<Value>,
+}
+
+/// A signing key of the provider
+struct ProviderKey {
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+/// Verifies the tokens of one identity provider against its published keys
+pub struct OidcProvider {
+    config: OidcConfig,
+    client: reqwest::Client,
+    /// `None` until the first fetch succeeds
+    keys: RwLock<Option<Vec<ProviderKey>>>,
+    /// When a token naming an unknown key last caused a fetch
+    last_refetch: Mutex<Option<Instant>>,
+}
+
+impl OidcProvider {
+    /// A provider with no keys yet; see [`OidcProvider::refresh`]
+    #[must_use]
+    pub fn new(config: OidcConfig) -> Self {
+        Self { config, client: reqwest::Client::new(), keys: RwLock::new(None), last_refetch: Mutex::new(None) }
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    /// Whether the provider's keys have been fetched
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the provider's keys.
+    pub fn is_loaded(&self) -> bool {
+        self.keys.read().expect("provider keys lock poisoned").is_some()
+    }
+
+    /// Fetch the discovery document, then the keys it names, returning how many are usable
+    ///
+    /// Keys for encryption, or of types that cannot verify a signature, are
+    /// left out. On failure the keys already fetched are kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the discovery document or the keys cannot be fetched or
+    /// parsed, keeping the keys fetched before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the provider's keys.
+    pub async fn refresh(&self) -> Result<usize> {
+        let url = self.config.discovery_url();
+        let discovery: Discovery = self.fetch(&url).await?;
+        if discovery.issuer != self.config.issuer {
+            bail!("{} names the issuer {}, not {}", url, discovery.issuer, self.config.issuer);
+        }
+        let set: RawJwkSet = self.fetch(&discovery.jwks_uri).await?;
+        let keys: Vec<ProviderKey> = set
+            .keys
+            .into_iter()
+            .filter_map(|value| {
+                let jwk: Jwk = serde_json::from_value(value).map_err(|e| debug!("Provider key skipped: {}", e)).ok()?;
+                if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+                    return None;
+                }
+                let key = DecodingKey::from_jwk(&jwk).map_err(|e| debug!("Provider key skipped: {}", e)).ok()?;
+                Some(ProviderKey { kid: jwk.common.key_id, key })
+            })
+            .collect();
+        if keys.is_empty() {
+            bail!("{} holds no key that can verify a signature", discovery.jwks_uri);
+        }
+        let count = keys.len();
+        *self.keys.write().expect("provider keys lock poisoned") = Some(keys);
+        Ok(count)
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let fetched = async {
+            let response = self.client.get(url).timeout(FETCH_TIMEOUT).send().await?.error_for_status()?;
+            anyhow::Ok(response.json().await?)
+        };
+        fetched.await.with_context(|| format!("fetching {url}"))
+    }
+
+    /// Verify a token of this provider and translate its claims
+    ///
+    /// Expiry is checked with jsonwebtoken's default leeway of a minute,
+    /// as the provider's clock is not ours.
+    pub(super) fn validate(self: &Arc<Self>, token: &str) -> Result<Claims, TokenError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(TokenError::Malformed("identity provider tokens must be signed with a public key".to_string()));
+        }
+        let keys = self.keys.read().expect("provider keys lock poisoned");
+        let Some(keys) = keys.as_ref() else {
+            self.refetch_soon();
+            return Err(TokenError::Malformed("identity provider keys are not loaded yet".to_string()));
+        };
+        let candidates: Vec<&DecodingKey> = keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .map(|key| &key.key)
+            .collect();
+        if candidates.is_empty() {
+            self.refetch_soon();
+            let kid = header.kid.unwrap_or_default();
+            return Err(TokenError::Malformed(format!("the identity provider has no key with ID {kid}")));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        let claims = candidates
+            .into_iter()
+            .map(|key| jsonwebtoken::decode::<Map<String, Value>>(token, key, &validation))
+            .find(|decoded| !matches!(decoded, Err(e) if *e.kind() == ErrorKind::InvalidSignature))
+            .ok_or(TokenError::BadSignature)??
+            .claims;
+        self.claims(claims)
+    }
+
+    /// Fetch the keys again in the background, unless that was done within [`MIN_REFETCH`]
+    fn refetch_soon(self: &Arc<Self>) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        {
+            let mut last = self.last_refetch.lock().expect("provider refetch lock poisoned");
+            if last.is_some_and(|at| at.elapsed() < MIN_REFETCH) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+        let provider = Arc::clone(self);
+        runtime.spawn(async move {
+            match provider.refresh().await {
+                Ok(count) => info!("Fetched {} keys of identity provider {}", count, provider.config.issuer),
+                Err(e) => warn!("Identity provider keys not fetched: {:#}", e),
+            }
+        });
+    }
+
+    /// The connector claims for the verified claims of a provider token
+    fn claims(&self, mut provided: Map<String, Value>) -> Result<Claims, TokenError> {
+        let subject = &self.config.subject_claim;
+        let sub = lookup(&provided, subject)
+            .and_then(Value::as_str)
+            .ok_or_else(|| TokenError::Malformed(format!("no '{subject}' claim to name the subject")))?
+            .to_string();
+        let scopes = self.scopes(&provided);
+        let exp = provided.get("exp").and_then(Value::as_i64).unwrap_or_default();
+        let iat = provided.get("iat").and_then(Value::as_i64).unwrap_or_else(|| Utc::now().timestamp());
+        let jti = provided.get("jti").and_then(Value::as_str).map(str::to_string);
+        for key in ["sub", "iat", "exp", "iss", "aud", "jti", "scopes"] {
+            provided.remove(key);
+        }
+        // The client the user signed in through, for usage accounting
+        if !provided.contains_key("client_name") {
+            if let Some(client) = ["azp", "client_id"].iter().find_map(|key| provided.get(*key).cloned()) {
+                provided.insert("client_name".to_string(), client);
+            }
+        }
+        Ok(Claims {
+            sub,
+            iat,
+            exp,
+            iss: self.config.issuer.clone(),
+            aud: self.config.audience.clone(),
+            jti,
+            scopes,
+            custom: provided.into_iter().collect(),
+        })
+    }
+
+    fn scopes(&self, provided: &Map<String, Value>) -> Vec<String> {
+        let granted: Vec<&str> = match lookup(provided, &self.config.scope_claim) {
+            Some(Value::String(scopes)) => scopes.split_whitespace().collect(),
+            Some(Value::Array(scopes)) => scopes.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if self.config.scope_map.is_empty() {
+            return granted.into_iter().map(str::to_string).collect();
+        }
+        let mut scopes: Vec<String> = Vec::new();
+        for scope in granted.into_iter().filter_map(|scope| self.config.scope_map.get(scope)).flatten() {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+        scopes
+    }
+}
+
+/// The claim at a dotted `path`
+///
+/// A claim whose own name holds the dots, as the namespaced claims of
+/// Auth0 do, is preferred to a nested one.
+fn lookup<'a>(claims: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    if let Some(value) = claims.get(path) {
+        return Some(value);
+    }
+    let mut parts = path.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
+
+/// The `iss` of a token, read before its signature is checked, to pick the keys to check it with
+pub(super) fn unverified_issuer(token: &str) -> Option<String> {
+    let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+    let claims: Map<String, Value> = serde_json::from_slice(&payload).ok()?;
+    claims.get("iss")?.as_str().map(str::to_string)
+}
+
+/// Fetch the keys of `provider` at once and then every `jwks_refresh`, as the [`OIDC_KEY_REFRESH`] task
+///
+/// # Errors
+///
+/// Fails where the task cannot be registered; a first fetch that fails is
+/// logged, and retried on schedule.
+pub fn schedule_refresh(provider: Arc<OidcProvider>, scheduler: &Scheduler) -> Result<()> {
+    let spec = TaskSpec::new(OIDC_KEY_REFRESH, Schedule::Every(provider.config.jwks_refresh))
+        .jitter(Duration::ZERO)
+        .timeout(2 * FETCH_TIMEOUT);
+    scheduler.register(spec, move || {
+        let provider = Arc::clone(&provider);
+        async move {
+            let count = provider.refresh().await?;
+            debug!("Fetched {} keys of identity provider {}", count, provider.config.issuer);
+            Ok(())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthConfig, AuthService, SigningKey, TokenAlgorithm};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const AUDIENCE: &str = "connector";
+
+    fn key(name: &str) -> SigningKey {
+        let pem = std::fs::read_to_string(format!("{}/tests/fixtures/keys/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let algorithm = if name.starts_with("ec") { TokenAlgorithm::Es256 } else { TokenAlgorithm::Rs256 };
+        SigningKey::from_pem(algorithm, &pem).unwrap()
+    }
+
+    /// An identity provider publishing the keys in `published`, and its issuer
+    async fn identity_provider(published: Arc<Mutex<Vec<Jwk>>>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}/realms/main", listener.local_addr().unwrap());
+        let discovery = json!({"issuer": issuer, "jwks_uri": format!("{issuer}/certs")});
+        let app = Router::new()
+            .route("/realms/main/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+            .route(
+                "/realms/main/certs",
+                get(move || async move { Json(json!({"keys": *published.lock().unwrap()})) }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        issuer
+    }
+
+    fn sign(key: &SigningKey, claims: &Value) -> String {
+        let algorithm = if matches!(key.jwk.algorithm, jsonwebtoken::jwk::AlgorithmParameters::EllipticCurve(_)) {
+            Algorithm::ES256
+        } else {
+            Algorithm::RS256
+        };
+        let header = Header { kid: key.jwk.common.key_id.clone(), ..Header::new(algorithm) };
+        jsonwebtoken::encode(&header, claims, &key.encoding).unwrap()
+    }
+
+    fn service(config: OidcConfig) -> AuthService {
+        AuthService::new(AuthConfig {
+            secret: "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY".to_string(),
+            oidc: Some(config),
+            enabled: true,
+            ..AuthConfig::default()
+        })
+    }
+
+    fn expires() -> i64 {
+        Utc::now().timestamp() + 300
+    }
+
+    #[tokio::test]
+    async fn test_provider_tokens_are_verified_and_mapped() {
+        let idp = key("idp_rsa_private.pem");
+        let issuer = identity_provider(Arc::new(Mutex::new(vec![idp.jwk.clone()]))).await;
+        let mut config = OidcConfig::new(&issuer, AUDIENCE);
+        config.scope_claim = "realm_access.roles".to_string();
+        config.scope_map.insert("editor".to_string(), vec!["read".to_string(), "write".to_string()]);
+        config.scope_map.insert("viewer".to_string(), vec!["read".to_string()]);
+        let auth = service(config);
+        let provider = auth.oidc().unwrap();
+        assert!(!provider.is_loaded());
+        assert_eq!(provider.refresh().await.unwrap(), 1);
+
+        let token = sign(
+            &idp,
+            &json!({
+                "iss": issuer,
+                "aud": [AUDIENCE, "account"],
+                "sub": "f81d4fae",
+                "exp": expires(),
+                "azp": "vscode",
+                "realm_access": {"roles": ["viewer", "editor", "offline_access"]}
+            }),
+        );
+        let claims = auth.validate_token(&format!("Bearer {token}")).unwrap();
+        assert_eq!(claims.sub, "f81d4fae");
+        assert_eq!(claims.scopes, ["read", "write"]);
+        assert_eq!(claims.client_name(), Some("vscode"));
+        assert_eq!((claims.iss.as_str(), claims.aud.as_str()), (issuer.as_str(), AUDIENCE));
+
+        // Tokens the server issues itself are still accepted
+        let own = auth.generate_token("cli".to_string(), vec!["read".to_string()]).unwrap();
+        assert_eq!(auth.validate_token(&own).unwrap().sub, "cli");
+
+        let rejected = |claims: Value, key: &SigningKey| {
+            let error = auth.validate_token(&sign(key, &claims)).unwrap_err();
+            error.downcast::<TokenError>().unwrap()
+        };
+        let other_audience = json!({"iss": issuer, "aud": "account", "sub": "f81d4fae", "exp": expires()});
+        assert_eq!(rejected(other_audience, &idp), TokenError::Malformed("issued for another service".to_string()));
+        let expired = json!({"iss": issuer, "aud": AUDIENCE, "sub": "f81d4fae", "exp": expires() - 3600});
+        assert_eq!(rejected(expired, &idp), TokenError::Expired);
+        let forged = SigningKey { jwk: idp.jwk.clone(), ..key("rsa_private.pem") };
+        let valid = json!({"iss": issuer, "aud": AUDIENCE, "sub": "f81d4fae", "exp": expires()});
+        assert_eq!(rejected(valid, &forged), TokenError::BadSignature);
+
+        // A shared-secret token claiming to be the provider's is not checked against any secret
+        let hs256 = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({"iss": issuer, "aud": AUDIENCE, "sub": "f81d4fae", "exp": expires()}),
+            &EncodingKey::from_secret(b"Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY"),
+        )
+        .unwrap();
+        let error = auth.validate_token(&hs256).unwrap_err().downcast::<TokenError>().unwrap();
+        assert!(matches!(error, TokenError::Malformed(reason) if reason.contains("public key")));
+    }
+
+    #[tokio::test]
+    async fn test_rotated_keys_are_fetched() {
+        let (old, new) = (key("ec_private.pem"), key("idp_rsa_private.pem"));
+        let published = Arc::new(Mutex::new(vec![old.jwk.clone()]));
+        let issuer = identity_provider(Arc::clone(&published)).await;
+        let auth = service(OidcConfig::new(&issuer, AUDIENCE));
+        auth.oidc().unwrap().refresh().await.unwrap();
+
+        let claims = json!({"iss": issuer, "aud": AUDIENCE, "sub": "a", "scope": "read write", "exp": expires()});
+        assert_eq!(auth.validate_token(&sign(&old, &claims)).unwrap().scopes, ["read", "write"]);
+
+        published.lock().unwrap().push(new.jwk.clone());
+        let token = sign(&new, &claims);
+        let error = auth.validate_token(&token).unwrap_err().downcast::<TokenError>().unwrap();
+        assert!(matches!(error, TokenError::Malformed(reason) if reason.contains("no key with ID")));
+        // The unknown key caused a fetch in the background
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while auth.validate_token(&token).is_err() {
+            assert!(Instant::now() < deadline, "the rotated key was never fetched");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_must_name_the_issuer() {
+        let issuer = identity_provider(Arc::new(Mutex::new(Vec::new()))).await;
+        let provider = OidcProvider::new(OidcConfig::new(format!("{issuer}/"), AUDIENCE));
+        let error = provider.refresh().await.unwrap_err();
+        assert!(error.to_string().contains("names the issuer"), "{error:#}");
+
+        let provider = OidcProvider::new(OidcConfig::new(&issuer, AUDIENCE));
+        let error = provider.refresh().await.unwrap_err();
+        assert!(error.to_string().contains("no key"), "{error:#}");
+        assert!(!provider.is_loaded());
+    }
+}
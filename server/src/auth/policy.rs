@@ -0,0 +1,169 @@
+//! Scopes required per route and per WebSocket message, from configuration
+//!
+//! Each operation already needs the scope it is built with, such as `read`
+//! to list documents. `[policy]` adds to those: a route rule names a path,
+//! optionally limited to one method, and the scopes a request matching it
+//! must hold as well; a message rule does the same for a WebSocket message
+//! type. Every matching route rule applies, so a rule for `/api/admin/*`
+//! and one for `DELETE /api/admin/api-keys/:id` both hold for revoking a
+//! key. Rules only ever add scopes, so a policy cannot open up what the
+//! server protects itself.
+//!
+//! Route paths match segment by segment: `:name` matches any one segment
+//! and a trailing `*` matches whatever follows, if anything.
+
+use super::Claims;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Scopes required on top of each operation's own
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScopePolicy {
+    /// Scopes by `"METHOD /path"`, or `"/path"` for any method
+    pub routes: BTreeMap<String, Vec<String>>,
+    /// Scopes by WebSocket message type, such as `Lsp`
+    pub messages: BTreeMap<String, Vec<String>>,
+}
+
+impl ScopePolicy {
+    /// Require `scopes` of requests matching `route`
+    #[must_use]
+    pub fn route(mut self, route: impl Into<String>, scopes: Vec<String>) -> Self {
+        self.routes.insert(route.into(), scopes);
+        self
+    }
+
+    /// Require `scopes` of WebSocket messages of type `message`
+    #[must_use]
+    pub fn message(mut self, message: impl Into<String>, scopes: Vec<String>) -> Self {
+        self.messages.insert(message.into(), scopes);
+        self
+    }
+
+    /// A scope the rules matching an HTTP request need that `claims` lacks
+    ///
+    /// Rules whose route does not parse match nothing; validation reports them.
+    #[must_use]
+    pub fn missing_for_route(&self, method: &str, path: &str, claims: &Claims) -> Option<&str> {
+        let required = self
+            .routes
+            .iter()
+            .filter(|(route, _)| Route::parse(route).is_ok_and(|route| route.matches(method, path)))
+            .flat_map(|(_, scopes)| scopes);
+        missing(required, claims)
+    }
+
+    /// A scope WebSocket messages of type `message` need that `claims` lacks
+    #[must_use]
+    pub fn missing_for_message(&self, message: &str, claims: &Claims) -> Option<&str> {
+        missing(self.messages.get(message).into_iter().flatten(), claims)
+    }
+
+    /// Whether any rule matches an HTTP request, so it must be authenticated
+    #[must_use]
+    pub fn covers_route(&self, method: &str, path: &str) -> bool {
+        self.routes.keys().any(|route| Route::parse(route).is_ok_and(|route| route.matches(method, path)))
+    }
+}
+
+fn missing<'a>(mut required: impl Iterator<Item = &'a String>, claims: &Claims) -> Option<&'a str> {
+    required.find(|scope| !claims.has_scope(scope)).map(String::as_str)
+}
+
+/// The key of a route rule: the method it is limited to, if any, and its path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route<'a> {
+    method: Option<&'a str>,
+    segments: Vec<&'a str>,
+}
+
+impl<'a> Route<'a> {
+    /// Parse `"METHOD /path"` or `"/path"`, saying what is wrong otherwise
+    ///
+    /// # Errors
+    ///
+    /// Says what is wrong with `key`: a method that is not one, a path not
+    /// starting with `/`, or a `*` before the end of the path.
+    pub fn parse(key: &'a str) -> Result<Self, String> {
+        let (method, path) = match key.split_once(' ') {
+            Some((method, path)) => (Some(method), path.trim_start()),
+            None => (None, key),
+        };
+        if let Some(method) = method.filter(|method| method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase())) {
+            return Err(format!("{method:?} is not an HTTP method such as GET"));
+        }
+        let segments: Vec<&str> = path.strip_prefix('/').ok_or("the path does not start with /")?.split('/').collect();
+        if segments.iter().rev().skip(1).any(|segment| *segment == "*") {
+            return Err("* may only end the path".to_string());
+        }
+        Ok(Self { method, segments })
+    }
+
+    /// Whether a request for `path` with `method` matches
+    #[must_use]
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        if self.method.is_some_and(|expected| expected != method) {
+            return false;
+        }
+        let mut parts = path.strip_prefix('/').unwrap_or(path).split('/');
+        for segment in &self.segments {
+            if *segment == "*" {
+                return true;
+            }
+            let matched = parts.next().is_some_and(|part| {
+                if segment.starts_with(':') { !part.is_empty() } else { part == *segment }
+            });
+            if !matched {
+                return false;
+            }
+        }
+        parts.next().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(scopes: &[&str]) -> Claims {
+        Claims::new("someone".to_string(), scopes.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn test_routes_match_by_method_and_pattern() {
+        let route = Route::parse("DELETE /api/documents/:id").unwrap();
+        assert!(route.matches("DELETE", "/api/documents/abc"));
+        assert!(!route.matches("GET", "/api/documents/abc"));
+        assert!(!route.matches("DELETE", "/api/documents"));
+        assert!(!route.matches("DELETE", "/api/documents/abc/more"));
+
+        let route = Route::parse("/api/admin/*").unwrap();
+        assert!(route.matches("GET", "/api/admin/usage"));
+        assert!(route.matches("POST", "/api/admin/tasks/usage_rollup/run"));
+        assert!(!route.matches("GET", "/api/administrators"));
+
+        assert!(Route::parse("api/convert").is_err());
+        assert!(Route::parse("post /api/convert").is_err());
+        assert!(Route::parse("/api/*/convert").is_err());
+    }
+
+    #[test]
+    fn test_every_matching_rule_applies() {
+        let policy = ScopePolicy::default()
+            .route("/api/admin/*", vec!["ops".to_string()])
+            .route("DELETE /api/admin/api-keys/:id", vec!["keys".to_string()])
+            .message("Lsp", vec!["lsp".to_string()]);
+        let path = "/api/admin/api-keys/0123";
+        assert_eq!(policy.missing_for_route("DELETE", path, &claims(&["keys"])), Some("ops"));
+        assert_eq!(policy.missing_for_route("DELETE", path, &claims(&["ops"])), Some("keys"));
+        assert_eq!(policy.missing_for_route("DELETE", path, &claims(&["ops", "keys"])), None);
+        assert_eq!(policy.missing_for_route("GET", path, &claims(&["ops"])), None);
+        assert!(policy.covers_route("GET", "/api/admin/usage"));
+        assert!(!policy.covers_route("GET", "/api/documents"));
+
+        assert_eq!(policy.missing_for_message("Lsp", &claims(&["read"])), Some("lsp"));
+        assert_eq!(policy.missing_for_message("Lsp", &claims(&["*"])), None);
+        assert_eq!(policy.missing_for_message("Subscribe", &claims(&[])), None);
+    }
+}
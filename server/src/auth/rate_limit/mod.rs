@@ -0,0 +1,558 @@
+//! Token bucket rate limiting per caller, in tiers
+//!
+//! Each request takes a token from its caller's bucket, which holds up to a
+//! tier's `burst` and refills at its `requests_per_minute`. Callers without
+//! valid credentials are limited per client IP; authenticated ones per
+//! subject, at higher limits when they hold the [`UNLIMITED_SCOPE`].
+//!
+//! Buckets are kept by a [`RateLimitBackend`]: in memory by default, which
+//! limits each instance on its own, or in Redis with `[rate_limit.redis]`
+//! (redis feature), so instances behind a load balancer share one bucket
+//! per caller. A backend that fails lets the request through rather than
+//! refusing every caller while it is down.
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+use super::Claims;
+use crate::scheduler::{Schedule, Scheduler, TaskSpec, RATE_LIMIT_EVICTION};
+use anyhow::Result;
+use chrono::Utc;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Scope whose holders are limited by the elevated tier instead of the authenticated one
+pub const UNLIMITED_SCOPE: &str = "unlimited";
+
+/// Rate limiting configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Enable rate limiting
+    pub enabled: bool,
+    /// Limits per client IP, for requests without valid credentials
+    pub anonymous: TierLimits,
+    /// Limits per authenticated subject
+    pub authenticated: TierLimits,
+    /// Limits per subject holding the [`UNLIMITED_SCOPE`]
+    pub elevated: TierLimits,
+    /// Keep buckets in Redis, shared by every instance, instead of in memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redis: Option<RedisConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            anonymous: TierLimits::new(60, 10),
+            authenticated: TierLimits::new(600, 100),
+            elevated: TierLimits::new(6000, 1000),
+            redis: None,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Bucket parameters of `tier`
+    #[must_use]
+    pub fn limits(&self, tier: RateLimitTier) -> TierLimits {
+        match tier {
+            RateLimitTier::Anonymous => self.anonymous,
+            RateLimitTier::Authenticated => self.authenticated,
+            RateLimitTier::Elevated => self.elevated,
+        }
+    }
+}
+
+/// Redis server holding the buckets of every instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedisConfig {
+    /// Such as `redis://cache.internal:6379/0`, or `rediss://` for TLS
+    pub url: String,
+    /// Prefix of the keys buckets are stored under
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Time Redis has to answer before the request is let through
+    #[serde(default = "default_redis_timeout", with = "crate::config::duration")]
+    pub timeout: Duration,
+}
+
+fn default_key_prefix() -> String {
+    "ulc:rate_limit:".to_string()
+}
+
+fn default_redis_timeout() -> Duration {
+    Duration::from_millis(100)
+}
+
+impl RedisConfig {
+    /// Keep buckets at `url`, with the default key prefix and timeout
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            key_prefix: default_key_prefix(),
+            timeout: default_redis_timeout(),
+        }
+    }
+}
+
+/// Token bucket parameters of one tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TierLimits {
+    /// Requests per minute
+    pub requests_per_minute: u32,
+    /// Burst size
+    pub burst: u32,
+}
+
+impl TierLimits {
+    #[must_use]
+    pub fn new(requests_per_minute: u32, burst: u32) -> Self {
+        Self { requests_per_minute, burst }
+    }
+
+    /// Tokens added per second
+    #[must_use]
+    pub fn refill_rate(self) -> f64 {
+        f64::from(self.requests_per_minute) / 60.0
+    }
+
+    /// Seconds until a bucket holding `tokens` has `wanted` again
+    #[allow(clippy::cast_possible_truncation)] // Saturating, as casts from floats do
+    fn seconds_until(self, tokens: f64, wanted: f64) -> i64 {
+        if tokens >= wanted {
+            0
+        } else if self.requests_per_minute == 0 {
+            i64::MAX
+        } else {
+            ((wanted - tokens) / self.refill_rate()).ceil() as i64
+        }
+    }
+}
+
+/// Which limits apply to a client, and so what its bucket is keyed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitTier {
+    /// No valid credentials, limited per client IP
+    Anonymous,
+    /// Limited per subject
+    Authenticated,
+    /// Holds the [`UNLIMITED_SCOPE`], limited per subject at higher limits
+    Elevated,
+}
+
+impl RateLimitTier {
+    /// The tier of a caller with `claims`, or of an anonymous one
+    #[must_use]
+    pub fn of(claims: Option<&Claims>) -> Self {
+        match claims {
+            None => Self::Anonymous,
+            Some(claims) if claims.has_scope(UNLIMITED_SCOPE) => Self::Elevated,
+            Some(_) => Self::Authenticated,
+        }
+    }
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Anonymous => "anonymous",
+            Self::Authenticated => "authenticated",
+            Self::Elevated => "elevated",
+        }
+    }
+}
+
+/// A client's bucket, as last updated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    /// Unix seconds
+    pub last_update: i64,
+}
+
+/// Where buckets are kept
+///
+/// A client has a bucket per tier it has been seen in, so logging in does
+/// not inherit an IP's bucket. Backends refill a bucket by the time since
+/// it was last updated before taking from it, and start a new one full.
+#[tower_lsp::async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Take a token from the bucket of `client` in `tier` if it has one, saying whether it did
+    async fn take(&self, tier: RateLimitTier, client: &str, limits: TierLimits) -> Result<(bool, Bucket)>;
+
+    /// The bucket of `client` in `tier`, if it has one
+    async fn peek(&self, tier: RateLimitTier, client: &str) -> Result<Option<Bucket>>;
+
+    /// Drop the buckets idle long enough to have refilled, returning how many
+    ///
+    /// Backends that expire buckets themselves drop none.
+    fn evict_idle(&self, _config: &RateLimitConfig) -> usize {
+        0
+    }
+
+    /// Such as `memory`, for logs
+    fn name(&self) -> &'static str;
+}
+
+/// Buckets in a sharded map, limiting this instance only
+///
+/// HTTP handlers and WebSocket sessions share one through the
+/// [`RateLimiter`] in [`crate::ServerState`] without a global lock; each
+/// check locks only its own client's shard.
+#[derive(Default)]
+pub struct MemoryBackend {
+    buckets: DashMap<(RateLimitTier, String), Bucket>,
+}
+
+impl MemoryBackend {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of clients with a bucket, idle or not
+    #[must_use]
+    pub fn tracked(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn take_now(&self, tier: RateLimitTier, client: &str, limits: TierLimits, now: i64) -> (bool, Bucket) {
+        let burst = f64::from(limits.burst);
+        let mut bucket = self
+            .buckets
+            .entry((tier, client.to_string()))
+            .or_insert(Bucket { tokens: burst, last_update: now });
+
+        // Refill tokens based on time elapsed
+        #[allow(clippy::cast_precision_loss)] // Seconds, far below 2^53
+        let elapsed = (now - bucket.last_update) as f64;
+        bucket.tokens = (bucket.tokens + elapsed * limits.refill_rate()).min(burst);
+        bucket.last_update = now;
+
+        // Check if we have tokens available
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        (allowed, *bucket)
+    }
+}
+
+#[tower_lsp::async_trait]
+impl RateLimitBackend for MemoryBackend {
+    async fn take(&self, tier: RateLimitTier, client: &str, limits: TierLimits) -> Result<(bool, Bucket)> {
+        Ok(self.take_now(tier, client, limits, Utc::now().timestamp()))
+    }
+
+    async fn peek(&self, tier: RateLimitTier, client: &str) -> Result<Option<Bucket>> {
+        Ok(self.buckets.get(&(tier, client.to_string())).map(|bucket| *bucket))
+    }
+
+    fn evict_idle(&self, config: &RateLimitConfig) -> usize {
+        let now = Utc::now().timestamp();
+        let mut evicted = 0;
+        self.buckets.retain(|(tier, _), bucket| {
+            let limits = config.limits(*tier);
+            #[allow(clippy::cast_precision_loss)] // Seconds, far below 2^53
+            let refilled = bucket.tokens + (now - bucket.last_update) as f64 * limits.refill_rate();
+            let keep = refilled < f64::from(limits.burst);
+            evicted += usize::from(!keep);
+            keep
+        });
+        evicted
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+/// Rate limiter using token bucket algorithm, over the configured backend
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimiter {
+    /// Create new rate limiter, keeping buckets where `config` says
+    ///
+    /// Falls back to memory when the Redis backend cannot be built, which
+    /// validation reports before startup.
+    pub fn new(config: RateLimitConfig) -> Self {
+        let backend: Arc<dyn RateLimitBackend> = match &config.redis {
+            #[cfg(feature = "redis")]
+            Some(redis) => match self::redis::RedisBackend::new(redis) {
+                Ok(backend) => Arc::new(backend),
+                Err(e) => {
+                    warn!("Rate limits kept in memory, not Redis: {:#}", e);
+                    Arc::new(MemoryBackend::new())
+                }
+            },
+            #[cfg(not(feature = "redis"))]
+            Some(_) => {
+                warn!("Rate limits kept in memory: Redis needs a build with the redis feature");
+                Arc::new(MemoryBackend::new())
+            }
+            None => Arc::new(MemoryBackend::new()),
+        };
+        Self::with_backend(config, backend)
+    }
+
+    /// Create a rate limiter keeping buckets in `backend`
+    pub fn with_backend(config: RateLimitConfig, backend: Arc<dyn RateLimitBackend>) -> Self {
+        Self { config, backend }
+    }
+
+    #[must_use]
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    #[must_use]
+    pub fn backend(&self) -> &dyn RateLimitBackend {
+        self.backend.as_ref()
+    }
+
+    /// Take a token from the bucket of `client_id` in `tier`, with the status it leaves
+    ///
+    /// Refused when the bucket is empty, with how long until it is not.
+    ///
+    /// # Errors
+    ///
+    /// The status of the empty bucket, saying how long until it has a token
+    /// again.
+    pub async fn acquire(&self, tier: RateLimitTier, client_id: &str) -> Result<RateLimitStatus, RateLimitStatus> {
+        let limits = self.config.limits(tier);
+        if !self.config.enabled {
+            return Ok(RateLimitStatus::full(tier, limits, Utc::now().timestamp()));
+        }
+        match self.backend.take(tier, client_id, limits).await {
+            Ok((true, bucket)) => Ok(RateLimitStatus::of(tier, limits, &bucket)),
+            Ok((false, bucket)) => Err(RateLimitStatus::of(tier, limits, &bucket)),
+            Err(e) => {
+                warn!("Rate limit of {} not checked, {} backend failed: {:#}", client_id, self.backend.name(), e);
+                Ok(RateLimitStatus::full(tier, limits, Utc::now().timestamp()))
+            }
+        }
+    }
+
+    /// Check if request is allowed for client
+    pub async fn check_rate_limit(&self, tier: RateLimitTier, client_id: &str) -> bool {
+        self.acquire(tier, client_id).await.is_ok()
+    }
+
+    /// Drop the buckets of clients idle long enough to have refilled, returning how many
+    ///
+    /// A client seen again starts from a full bucket, as it would have anyway.
+    #[must_use]
+    pub fn evict_idle(&self) -> usize {
+        self.backend.evict_idle(&self.config)
+    }
+
+    /// Get rate limit status for client
+    pub async fn get_status(&self, tier: RateLimitTier, client_id: &str) -> RateLimitStatus {
+        let limits = self.config.limits(tier);
+        match self.backend.peek(tier, client_id).await {
+            Ok(Some(bucket)) => RateLimitStatus::of(tier, limits, &bucket),
+            Ok(None) => RateLimitStatus::full(tier, limits, Utc::now().timestamp()),
+            Err(e) => {
+                warn!("Rate limit of {} not read, {} backend failed: {:#}", client_id, self.backend.name(), e);
+                RateLimitStatus::full(tier, limits, Utc::now().timestamp())
+            }
+        }
+    }
+}
+
+/// Evict the refilled buckets of `limiter` every minute, as the [`RATE_LIMIT_EVICTION`] task
+///
+/// # Errors
+///
+/// Fails where the task cannot be registered, as when its name is taken.
+pub fn schedule_eviction(limiter: Arc<RateLimiter>, scheduler: &Scheduler) -> Result<()> {
+    let spec = TaskSpec::new(RATE_LIMIT_EVICTION, Schedule::Every(std::time::Duration::from_mins(1)));
+    scheduler.register(spec, move || {
+        let evicted = limiter.evict_idle();
+        debug!("Evicted {} idle rate limit buckets", evicted);
+        async { Ok(()) }
+    })
+}
+
+/// Rate limit status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitStatus {
+    /// Tier whose limits applied
+    pub tier: RateLimitTier,
+    pub remaining: u32,
+    pub limit: u32,
+    /// When the bucket is full again, in Unix seconds
+    pub reset_at: i64,
+    /// Seconds until a request would be allowed; 0 when one is now
+    pub retry_after: i64,
+}
+
+impl RateLimitStatus {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // Tokens are between none and the burst size
+    fn of(tier: RateLimitTier, limits: TierLimits, bucket: &Bucket) -> Self {
+        Self {
+            tier,
+            remaining: bucket.tokens.floor() as u32,
+            limit: limits.burst,
+            reset_at: bucket.last_update.saturating_add(limits.seconds_until(bucket.tokens, f64::from(limits.burst))),
+            retry_after: limits.seconds_until(bucket.tokens, 1.0),
+        }
+    }
+
+    fn full(tier: RateLimitTier, limits: TierLimits, now: i64) -> Self {
+        Self {
+            tier,
+            remaining: limits.burst,
+            limit: limits.burst,
+            reset_at: now,
+            retry_after: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let config = RateLimitConfig {
+            enabled: true,
+            authenticated: TierLimits::new(2, 2),
+            ..RateLimitConfig::default()
+        };
+
+        let limiter = RateLimiter::new(config);
+        let tier = RateLimitTier::Authenticated;
+
+        // First two requests should succeed
+        assert!(limiter.check_rate_limit(tier, "client1").await);
+        assert!(limiter.check_rate_limit(tier, "client1").await);
+
+        // Third request should be rate limited
+        let refused = limiter.acquire(tier, "client1").await.unwrap_err();
+        assert_eq!((refused.remaining, refused.retry_after), (0, 30));
+
+        // Different client should not be affected
+        assert!(limiter.check_rate_limit(tier, "client2").await);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_tiers() {
+        let config = RateLimitConfig {
+            enabled: true,
+            anonymous: TierLimits::new(60, 1),
+            authenticated: TierLimits::new(60, 2),
+            elevated: TierLimits::new(60, 5),
+            redis: None,
+        };
+        let limiter = RateLimiter::new(config);
+
+        let editor = Claims::new("alice".to_string(), vec!["read".to_string(), "write".to_string()]);
+        let batch = Claims::new("ci".to_string(), vec!["read".to_string(), UNLIMITED_SCOPE.to_string()]);
+        assert_eq!(RateLimitTier::of(None), RateLimitTier::Anonymous);
+        assert_eq!(RateLimitTier::of(Some(&editor)), RateLimitTier::Authenticated);
+        assert_eq!(RateLimitTier::of(Some(&batch)), RateLimitTier::Elevated);
+
+        let mut allowed = Vec::new();
+        for (tier, client) in [
+            (RateLimitTier::Anonymous, "192.0.2.1"),
+            (RateLimitTier::Authenticated, "alice"),
+            (RateLimitTier::Elevated, "ci"),
+            // A tier's bucket is its own, even for the same client key
+            (RateLimitTier::Elevated, "alice"),
+        ] {
+            let mut count = 0;
+            for _ in 0..10 {
+                count += usize::from(limiter.check_rate_limit(tier, client).await);
+            }
+            allowed.push(count);
+        }
+        assert_eq!(allowed, [1, 2, 5, 5]);
+
+        let status = limiter.get_status(RateLimitTier::Elevated, "ci").await;
+        assert_eq!((status.tier, status.limit, status.remaining), (RateLimitTier::Elevated, 5, 0));
+        assert_eq!(serde_json::to_value(&status).unwrap()["tier"], "elevated");
+
+        let disabled = RateLimiter::new(RateLimitConfig::default());
+        for _ in 0..100 {
+            assert!(disabled.check_rate_limit(RateLimitTier::Anonymous, "192.0.2.1").await);
+        }
+    }
+
+    #[test]
+    fn test_evict_idle_rate_limit_buckets() {
+        let config = RateLimitConfig { enabled: true, anonymous: TierLimits::new(60, 10), ..RateLimitConfig::default() };
+        let limits = config.anonymous;
+        let backend = MemoryBackend::new();
+        let tier = RateLimitTier::Anonymous;
+        let key = |client: &str| (tier, client.to_string());
+        let now = Utc::now().timestamp();
+        assert!(backend.take_now(tier, "idle", limits, now).0);
+        for _ in 0..3 {
+            assert!(backend.take_now(tier, "busy", limits, now).0);
+        }
+        // Nine tokens left, refilled at one a second: ten seconds idle is enough
+        backend.buckets.get_mut(&key("idle")).unwrap().last_update -= 10;
+
+        assert_eq!(backend.evict_idle(&config), 1);
+        assert!(!backend.buckets.contains_key(&key("idle")));
+        assert!(backend.buckets.contains_key(&key("busy")));
+        assert_eq!(backend.tracked(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_is_shared_across_threads() {
+        let limits = TierLimits::new(1, 100);
+        let backend = Arc::new(MemoryBackend::new());
+        let now = Utc::now().timestamp();
+        let allowed: usize = (0..8)
+            .map(|_| {
+                let backend = Arc::clone(&backend);
+                std::thread::spawn(move || {
+                    (0..50).filter(|_| backend.take_now(RateLimitTier::Authenticated, "shared", limits, now).0).count()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+        // Every token was spent exactly once, whichever thread took it
+        assert_eq!(allowed, 100);
+    }
+
+    #[tokio::test]
+    async fn test_failing_backend_lets_requests_through() {
+        struct Down;
+
+        #[tower_lsp::async_trait]
+        impl RateLimitBackend for Down {
+            async fn take(&self, _: RateLimitTier, _: &str, _: TierLimits) -> Result<(bool, Bucket)> {
+                anyhow::bail!("connection refused")
+            }
+
+            async fn peek(&self, _: RateLimitTier, _: &str) -> Result<Option<Bucket>> {
+                anyhow::bail!("connection refused")
+            }
+
+            fn name(&self) -> &'static str {
+                "down"
+            }
+        }
+
+        let config = RateLimitConfig { enabled: true, anonymous: TierLimits::new(1, 1), ..RateLimitConfig::default() };
+        let limiter = RateLimiter::with_backend(config, Arc::new(Down));
+        for _ in 0..3 {
+            let status = limiter.acquire(RateLimitTier::Anonymous, "192.0.2.1").await.unwrap();
+            assert_eq!(status.remaining, 1);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Buckets in Redis, shared by every instance (redis feature)
+//!
+//! Each bucket is a hash of its tokens and when it was last updated, under
+//! `<key_prefix><tier>:<client>`. A Lua script refills and takes from it in
+//! one step, by the Redis server's clock, so instances neither race each
+//! other nor need their clocks in step. Buckets expire once they would
+//! have refilled, which stands in for eviction. Needs Redis 5 or later.
+
+use super::{Bucket, RateLimitBackend, RateLimitTier, RedisConfig, TierLimits};
+use anyhow::{Context, Result};
+use redis::aio::ConnectionManager;
+use redis::{Client, Script};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// Refills and takes from the bucket `KEYS[1]`, given its burst and refill rate per second
+const TAKE: &str = r"
+local burst = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local allowed = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+local ttl = 86400
+if rate > 0 then
+  ttl = math.max(1, math.ceil((burst - tokens) / rate))
+end
+redis.call('EXPIRE', KEYS[1], ttl)
+return {allowed, tostring(tokens), tostring(now)}
+";
+
+/// Buckets in Redis, connected to on first use and reconnected when the connection drops
+pub struct RedisBackend {
+    client: Client,
+    connection: OnceCell<ConnectionManager>,
+    key_prefix: String,
+    timeout: Duration,
+    script: Script,
+}
+
+impl RedisBackend {
+    /// Keep buckets in the Redis server `config` names, failing only if its URL is invalid
+    pub fn new(config: &RedisConfig) -> Result<Self> {
+        Ok(Self {
+            client: Client::open(config.url.as_str()).context("Invalid Redis URL")?,
+            connection: OnceCell::new(),
+            key_prefix: config.key_prefix.clone(),
+            timeout: config.timeout,
+            script: Script::new(TAKE),
+        })
+    }
+
+    /// Key the bucket of `client` in `tier` is stored under
+    pub fn key(&self, tier: RateLimitTier, client: &str) -> String {
+        format!("{}{}:{}", self.key_prefix, tier.as_str(), client)
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .context("Redis unreachable")?;
+        Ok(connection.clone())
+    }
+
+    /// Run `call` on a connection, giving up after the configured timeout
+    async fn with_timeout<T>(&self, call: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+        tokio::time::timeout(self.timeout, call)
+            .await
+            .with_context(|| format!("Redis did not answer within {:?}", self.timeout))?
+    }
+}
+
+/// A bucket as Redis holds it: tokens and Unix time, written as strings to keep their fractions
+fn bucket(tokens: &str, updated: &str) -> Result<Bucket> {
+    Ok(Bucket {
+        tokens: tokens.parse().context("Malformed bucket tokens")?,
+        last_update: updated.parse::<f64>().context("Malformed bucket time")?.floor() as i64,
+    })
+}
+
+#[tower_lsp::async_trait]
+impl RateLimitBackend for RedisBackend {
+    async fn take(&self, tier: RateLimitTier, client: &str, limits: TierLimits) -> Result<(bool, Bucket)> {
+        let key = self.key(tier, client);
+        self.with_timeout(async {
+            let mut connection = self.connection().await?;
+            let (allowed, tokens, updated): (i64, String, String) = self
+                .script
+                .key(key)
+                .arg(limits.burst)
+                .arg(limits.refill_rate())
+                .invoke_async(&mut connection)
+                .await?;
+            Ok((allowed == 1, bucket(&tokens, &updated)?))
+        })
+        .await
+    }
+
+    async fn peek(&self, tier: RateLimitTier, client: &str) -> Result<Option<Bucket>> {
+        let key = self.key(tier, client);
+        self.with_timeout(async {
+            let mut connection = self.connection().await?;
+            let fields: (Option<String>, Option<String>) =
+                redis::cmd("HMGET").arg(key).arg("tokens").arg("updated").query_async(&mut connection).await?;
+            match fields {
+                (Some(tokens), Some(updated)) => bucket(&tokens, &updated).map(Some),
+                _ => Ok(None),
+            }
+        })
+        .await
+    }
+
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{RateLimitConfig, RateLimiter};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_unreachable_redis_lets_requests_through() {
+        let redis = RedisConfig { timeout: Duration::from_secs(2), ..RedisConfig::new("redis://127.0.0.1:1/") };
+        let backend = RedisBackend::new(&redis).unwrap();
+        assert_eq!(backend.key(RateLimitTier::Elevated, "ci"), "ulc:rate_limit:elevated:ci");
+        assert!(backend.take(RateLimitTier::Anonymous, "192.0.2.1", TierLimits::new(1, 1)).await.is_err());
+
+        let config = RateLimitConfig { enabled: true, anonymous: TierLimits::new(1, 1), ..RateLimitConfig::default() };
+        let limiter = RateLimiter::with_backend(config, Arc::new(backend));
+        assert!(limiter.check_rate_limit(RateLimitTier::Anonymous, "192.0.2.1").await);
+        assert!(limiter.check_rate_limit(RateLimitTier::Anonymous, "192.0.2.1").await);
+
+        assert!(RedisBackend::new(&RedisConfig::new("cache.internal:6379")).is_err());
+    }
+}
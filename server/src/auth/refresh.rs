@@ -0,0 +1,407 @@
+//! Refresh tokens and their rotation
+//!
+//! An editor plugin holds a long-lived refresh token and exchanges it at
+//! `POST /auth/refresh` for a short-lived access token, so a leaked access
+//! token is good for minutes rather than days. Every exchange rotates the
+//! refresh token: the one presented is spent and a new one returned with
+//! the access token. The tokens descending from one issue form a family,
+//! and a spent token presented again means two parties hold the family,
+//! one of them a thief, so the whole family is revoked and its holder must
+//! be issued a new one.
+//!
+//! Tokens read `ulr_<family>_<secret>`. Only hashes of secrets are kept, in
+//! a sled database under `data_dir` or, without one, in a temporary
+//! database lost at exit.
+
+use super::api_keys::{constant_time_eq, hash};
+use super::TokenError;
+use crate::scheduler::{Schedule, Scheduler, TaskSpec, REFRESH_TOKEN_PRUNE};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How every refresh token starts, telling them from API keys and JWTs
+pub const PREFIX: &str = "ulr_";
+
+/// Name of the database directory under `data_dir`
+const DIRECTORY: &str = "refresh_tokens";
+
+/// Random bytes in a family ID
+const FAMILY_BYTES: usize = 8;
+
+/// Random bytes in a token secret
+const SECRET_BYTES: usize = 32;
+
+/// Spent tokens remembered per family; one spent longer ago is merely refused
+const SPENT_KEPT: usize = 16;
+
+/// Lifetimes of the tokens issued at `/auth/refresh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct TokenLifetimes {
+    /// Access tokens expire after this long
+    #[serde(with = "crate::config::duration")]
+    pub access: Duration,
+    /// A refresh token not exchanged for this long expires; each exchange starts it again
+    #[serde(with = "crate::config::duration")]
+    pub refresh: Duration,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        Self { access: Duration::from_mins(15), refresh: Duration::from_hours(720) }
+    }
+}
+
+/// An access token and the refresh token to get the next one with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenPair {
+    /// Sent as `Authorization: Bearer <access_token>`
+    pub access_token: String,
+    /// Always `Bearer`
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: u64,
+    /// Spent by exchanging it; present the one returned next time
+    pub refresh_token: String,
+    /// Seconds until the refresh token expires unless exchanged
+    pub refresh_expires_in: u64,
+    /// Family the refresh token belongs to, by which it is listed and revoked
+    pub family: String,
+}
+
+/// A family of refresh tokens, as listed; secrets are never kept
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefreshFamily {
+    pub id: String,
+    /// Subject the family's access tokens are issued to
+    pub subject: String,
+    pub scopes: Vec<String>,
+    /// Carried into the family's access tokens as `client_name`
+    pub client_name: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Exchanges so far
+    pub rotations: u64,
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// When the current token expires unless exchanged
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Why the family was revoked: `revoked` by an admin, or `reused` when a spent token came back
+    pub revoked_reason: Option<String>,
+}
+
+impl RefreshFamily {
+    /// Whether the current token is past its expiry
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+/// A family as stored, with the hashes of its current and spent secrets
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    #[serde(flatten)]
+    family: RefreshFamily,
+    current: String,
+    /// Oldest first
+    spent: VecDeque<String>,
+}
+
+/// Refresh token families by ID
+pub struct RefreshTokenStore {
+    db: sled::Db,
+    random: SystemRandom,
+}
+
+impl RefreshTokenStore {
+    /// Open the store under `data_dir`, creating it if need be
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be opened, as when another process holds
+    /// it.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(DIRECTORY);
+        let db = sled::open(&path).with_context(|| format!("opening refresh token store {}", path.display()))?;
+        Ok(Self { db, random: SystemRandom::new() })
+    }
+
+    /// A store kept only while the server runs
+    ///
+    /// # Errors
+    ///
+    /// Fails where the temporary store cannot be created.
+    pub fn temporary() -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open().context("opening a temporary refresh token store")?;
+        Ok(Self { db, random: SystemRandom::new() })
+    }
+
+    /// Start a family, returning it and its first token
+    ///
+    /// # Errors
+    ///
+    /// Fails where there is no randomness to generate the token with, or the
+    /// family cannot be stored.
+    pub fn issue(
+        &self,
+        subject: String,
+        scopes: Vec<String>,
+        client_name: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(RefreshFamily, String)> {
+        let mut id = [0u8; FAMILY_BYTES];
+        self.random.fill(&mut id).map_err(|_| anyhow::anyhow!("no randomness to generate a refresh token with"))?;
+        let id = id.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+        let secret = self.secret()?;
+        let family = RefreshFamily {
+            id,
+            subject,
+            scopes,
+            client_name,
+            created_at: Utc::now(),
+            rotations: 0,
+            rotated_at: None,
+            expires_at,
+            revoked_at: None,
+            revoked_reason: None,
+        };
+        let stored = Stored { family: family.clone(), current: hash(&secret), spent: VecDeque::new() };
+        self.db.insert(family.id.as_bytes(), serde_json::to_vec(&stored)?)?;
+        let presented = token(&family.id, &secret);
+        Ok((family, presented))
+    }
+
+    /// Spend a presented token for the next one of its family, which expires at `expires_at`
+    ///
+    /// Unknown tokens are malformed, and those matching no current or
+    /// recently spent secret have a bad signature. A spent token revokes
+    /// its family and is [`TokenError::Reused`]. Two exchanges of one token
+    /// racing each other count as reuse too.
+    ///
+    /// # Errors
+    ///
+    /// A [`TokenError`] where the token is unknown, does not match, was spent
+    /// or its family revoked or expired, and errors of the store otherwise.
+    pub fn rotate(&self, presented: &str, expires_at: DateTime<Utc>) -> Result<(RefreshFamily, String)> {
+        let (id, secret) = presented
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or_else(|| TokenError::Malformed("not a refresh token".to_string()))?;
+        let presented = hash(secret);
+        let unknown = || TokenError::Malformed("unknown refresh token".to_string());
+        loop {
+            let Some(old) = self.db.get(id)? else { return Err(unknown().into()) };
+            let mut stored: Stored = serde_json::from_slice(&old)?;
+            let current = constant_time_eq(presented.as_bytes(), stored.current.as_bytes());
+            let spent = !current && stored.spent.iter().any(|spent| constant_time_eq(presented.as_bytes(), spent.as_bytes()));
+            if !current && !spent {
+                return Err(TokenError::BadSignature.into());
+            }
+            if stored.family.revoked_at.is_some() {
+                return Err(TokenError::Revoked.into());
+            }
+            let now = Utc::now();
+            let next = if spent {
+                stored.family.revoked_at = Some(now);
+                stored.family.revoked_reason = Some("reused".to_string());
+                None
+            } else {
+                if stored.family.is_expired() {
+                    return Err(TokenError::Expired.into());
+                }
+                let secret = self.secret()?;
+                stored.spent.push_back(std::mem::replace(&mut stored.current, hash(&secret)));
+                if stored.spent.len() > SPENT_KEPT {
+                    stored.spent.pop_front();
+                }
+                stored.family.rotations += 1;
+                stored.family.rotated_at = Some(now);
+                stored.family.expires_at = expires_at;
+                Some(secret)
+            };
+            if self.db.compare_and_swap(id, Some(old), Some(serde_json::to_vec(&stored)?))?.is_err() {
+                // Changed since it was read, perhaps by the same token racing this exchange
+                continue;
+            }
+            let Some(secret) = next else {
+                warn!("Refresh token of {} reused; revoked family {}", stored.family.subject, id);
+                return Err(TokenError::Reused.into());
+            };
+            let presented = token(id, &secret);
+            return Ok((stored.family, presented));
+        }
+    }
+
+    /// Every family, revoked and expired ones included, oldest first
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read, or holds a family that does not
+    /// parse.
+    pub fn list(&self) -> Result<Vec<RefreshFamily>> {
+        let mut families = self
+            .db
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice::<Stored>(&value?)?.family))
+            .collect::<Result<Vec<_>>>()?;
+        families.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(families)
+    }
+
+    /// Revoke a family, returning it, or `None` if there is no such family
+    ///
+    /// Revoking a family again keeps the time and reason it was first revoked.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read or written.
+    pub fn revoke(&self, id: &str) -> Result<Option<RefreshFamily>> {
+        loop {
+            let Some(old) = self.db.get(id)? else { return Ok(None) };
+            let mut stored: Stored = serde_json::from_slice(&old)?;
+            if stored.family.revoked_at.is_some() {
+                return Ok(Some(stored.family));
+            }
+            stored.family.revoked_at = Some(Utc::now());
+            stored.family.revoked_reason = Some("revoked".to_string());
+            if self.db.compare_and_swap(id, Some(old), Some(serde_json::to_vec(&stored)?))?.is_ok() {
+                return Ok(Some(stored.family));
+            }
+        }
+    }
+
+    /// Remove the families whose current token has expired, returning how many
+    ///
+    /// Their tokens are refused as unknown from then on, as they were refused as expired before.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be read or written.
+    pub fn prune(&self) -> Result<usize> {
+        let mut removed = 0;
+        for entry in self.db.iter() {
+            let (id, value) = entry?;
+            let stored: Stored = serde_json::from_slice(&value)?;
+            if stored.family.is_expired() && self.db.compare_and_swap(&id, Some(value), None::<&[u8]>)?.is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Write pending changes to disk
+    ///
+    /// # Errors
+    ///
+    /// Fails where the store cannot be written.
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    fn secret(&self) -> Result<String> {
+        let mut secret = [0u8; SECRET_BYTES];
+        self.random.fill(&mut secret).map_err(|_| anyhow::anyhow!("no randomness to generate a refresh token with"))?;
+        Ok(URL_SAFE_NO_PAD.encode(secret))
+    }
+}
+
+fn token(family: &str, secret: &str) -> String {
+    format!("{PREFIX}{family}_{secret}")
+}
+
+/// Register the task removing expired families
+///
+/// # Errors
+///
+/// Fails where the task cannot be registered, as when its name is taken.
+pub fn schedule_prune(store: Arc<RefreshTokenStore>, scheduler: &Scheduler) -> Result<()> {
+    scheduler.register(TaskSpec::new(REFRESH_TOKEN_PRUNE, Schedule::Every(Duration::from_hours(1))), move || {
+        let store = Arc::clone(&store);
+        async move {
+            let removed = tokio::task::spawn_blocking(move || store.prune()).await??;
+            debug!("Removed {} expired refresh token families", removed);
+            Ok(())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn later() -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::hours(1)
+    }
+
+    fn token_error(result: Result<(RefreshFamily, String)>) -> TokenError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn test_tokens_rotate() {
+        let store = RefreshTokenStore::temporary().unwrap();
+        let (family, first) = store.issue("alice".to_string(), vec!["read".to_string()], None, later()).unwrap();
+        assert!(first.starts_with(&format!("{PREFIX}{}_", family.id)), "{first}");
+        let raw = store.db.get(&family.id).unwrap().unwrap().to_vec();
+        assert!(!String::from_utf8(raw).unwrap().contains(first.rsplit_once('_').unwrap().1));
+
+        let (rotated, second) = store.rotate(&first, later()).unwrap();
+        assert_ne!(first, second);
+        assert_eq!((rotated.id.as_str(), rotated.subject.as_str(), rotated.rotations), (family.id.as_str(), "alice", 1));
+        let (_, third) = store.rotate(&second, later()).unwrap();
+        assert!(third.starts_with(&format!("{PREFIX}{}_", family.id)));
+
+        let forged = format!("{PREFIX}{}_{}", family.id, URL_SAFE_NO_PAD.encode([0u8; SECRET_BYTES]));
+        assert_eq!(token_error(store.rotate(&forged, later())), TokenError::BadSignature);
+        let unknown = format!("{PREFIX}0000000000000000_{}", URL_SAFE_NO_PAD.encode([0u8; SECRET_BYTES]));
+        assert_eq!(token_error(store.rotate(&unknown, later())), TokenError::Malformed("unknown refresh token".to_string()));
+        assert!(matches!(token_error(store.rotate("ulc_0_0", later())), TokenError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_reuse_revokes_the_family() {
+        let store = RefreshTokenStore::temporary().unwrap();
+        let (family, stolen) = store.issue("alice".to_string(), Vec::new(), None, later()).unwrap();
+        let (_, current) = store.rotate(&stolen, later()).unwrap();
+
+        assert_eq!(token_error(store.rotate(&stolen, later())), TokenError::Reused);
+        assert_eq!(token_error(store.rotate(&current, later())), TokenError::Revoked);
+        let listed = store.list().unwrap();
+        assert_eq!(listed[0].revoked_reason.as_deref(), Some("reused"));
+
+        let (other, _) = store.issue("bob".to_string(), Vec::new(), None, later()).unwrap();
+        assert_eq!(store.revoke(&other.id).unwrap().unwrap().revoked_reason.as_deref(), Some("revoked"));
+        assert_eq!(store.revoke(&family.id).unwrap().unwrap().revoked_reason.as_deref(), Some("reused"));
+        assert_eq!(store.revoke("0000000000000000").unwrap(), None);
+    }
+
+    #[test]
+    fn test_expired_families_are_refused_and_pruned() {
+        let store = RefreshTokenStore::temporary().unwrap();
+        let past = Utc::now() - chrono::Duration::minutes(1);
+        let (_, expired) = store.issue("alice".to_string(), Vec::new(), None, past).unwrap();
+        store.issue("bob".to_string(), Vec::new(), None, later()).unwrap();
+        assert_eq!(token_error(store.rotate(&expired, later())), TokenError::Expired);
+
+        assert_eq!(store.prune().unwrap(), 1);
+        let subjects: Vec<_> = store.list().unwrap().into_iter().map(|family| family.subject).collect();
+        assert_eq!(subjects, ["bob"]);
+        assert!(matches!(token_error(store.rotate(&expired, later())), TokenError::Malformed(_)));
+    }
+}
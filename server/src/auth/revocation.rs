@@ -0,0 +1,178 @@
+//! Tokens revoked before they expire
+//!
+//! Every token this server signs carries a unique `jti` claim. Revoking a
+//! token adds its `jti` to the list, and a token whose `jti` is listed is
+//! refused as [`TokenError::Revoked`](super::TokenError::Revoked) from then
+//! on, even though its signature and expiry check out. An entry is kept
+//! until the token it names would have expired anyway, after which the
+//! `revoked_token_prune` task removes it.
+//!
+//! The list is held in memory, so checking it costs no I/O. With a
+//! `data_dir` it is written through to a sled database there and read back
+//! at startup, so revocations survive a restart; without one it is lost at
+//! exit, along with the tokens it could have refused, whose signing secret
+//! is usually lost too.
+
+use crate::scheduler::{Schedule, Scheduler, TaskSpec, REVOKED_TOKEN_PRUNE};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+/// Name of the database directory under `data_dir`
+const DIRECTORY: &str = "revoked_tokens";
+
+/// A revoked token, as listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevokedToken {
+    /// The token's `jti` claim
+    pub jti: String,
+    /// Subject the token was issued to, when known
+    pub subject: Option<String>,
+    pub revoked_at: DateTime<Utc>,
+    /// When the token expires anyway, and the entry may go
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Revoked tokens by `jti`
+pub struct RevocationList {
+    revoked: DashMap<String, RevokedToken>,
+    /// Where revocations are persisted, if anywhere
+    db: Option<sled::Db>,
+}
+
+impl RevocationList {
+    /// Open the list persisted under `data_dir`, creating it if need be
+    ///
+    /// # Errors
+    ///
+    /// Fails where the list cannot be opened, or holds an entry that does not
+    /// parse.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(DIRECTORY);
+        let db = sled::open(&path).with_context(|| format!("opening revoked token list {}", path.display()))?;
+        let revoked = DashMap::new();
+        for value in db.iter().values() {
+            let entry: RevokedToken = serde_json::from_slice(&value?)?;
+            revoked.insert(entry.jti.clone(), entry);
+        }
+        Ok(Self { revoked, db: Some(db) })
+    }
+
+    /// A list kept only while the server runs
+    #[must_use]
+    pub fn in_memory() -> Self {
+        Self { revoked: DashMap::new(), db: None }
+    }
+
+    /// Revoke the token `jti`, which expires at `expires_at`, returning its entry
+    ///
+    /// Revoking a token again keeps the time it was first revoked.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the entry cannot be stored.
+    pub fn revoke(&self, jti: &str, subject: Option<String>, expires_at: DateTime<Utc>) -> Result<RevokedToken> {
+        let entry = self
+            .revoked
+            .entry(jti.to_string())
+            .or_insert_with(|| RevokedToken { jti: jti.to_string(), subject, revoked_at: Utc::now(), expires_at })
+            .clone();
+        if let Some(db) = &self.db {
+            db.insert(jti.as_bytes(), serde_json::to_vec(&entry)?)?;
+        }
+        Ok(entry)
+    }
+
+    /// Whether the token `jti` has been revoked
+    #[must_use]
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.revoked.contains_key(jti)
+    }
+
+    /// Every revoked token not yet pruned, most recently revoked first
+    #[must_use]
+    pub fn list(&self) -> Vec<RevokedToken> {
+        let mut entries: Vec<RevokedToken> = self.revoked.iter().map(|entry| entry.value().clone()).collect();
+        entries.sort_by(|a, b| b.revoked_at.cmp(&a.revoked_at).then_with(|| a.jti.cmp(&b.jti)));
+        entries
+    }
+
+    /// Remove the entries of tokens that have expired, returning how many
+    ///
+    /// # Errors
+    ///
+    /// Fails where the list cannot be read or written.
+    pub fn prune(&self) -> Result<usize> {
+        let now = Utc::now();
+        let expired: Vec<String> =
+            self.revoked.iter().filter(|entry| entry.expires_at <= now).map(|entry| entry.key().clone()).collect();
+        for jti in &expired {
+            self.revoked.remove(jti);
+            if let Some(db) = &self.db {
+                db.remove(jti.as_bytes())?;
+            }
+        }
+        Ok(expired.len())
+    }
+
+    /// Write pending changes to disk
+    ///
+    /// # Errors
+    ///
+    /// Fails where the list cannot be written.
+    pub async fn flush(&self) -> Result<()> {
+        if let Some(db) = &self.db {
+            db.flush_async().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Register the task removing the entries of expired tokens
+///
+/// # Errors
+///
+/// Fails where the task cannot be registered, as when its name is taken.
+pub fn schedule_prune(list: Arc<RevocationList>, scheduler: &Scheduler) -> Result<()> {
+    scheduler.register(TaskSpec::new(REVOKED_TOKEN_PRUNE, Schedule::Every(Duration::from_hours(1))), move || {
+        let list = Arc::clone(&list);
+        async move {
+            let removed = tokio::task::spawn_blocking(move || list.prune()).await??;
+            debug!("Removed {} expired tokens from the revocation list", removed);
+            Ok(())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revocations_persist_until_expiry() {
+        let dir = std::env::temp_dir().join(format!("ulc-revocation-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let later = Utc::now() + chrono::Duration::hours(1);
+        {
+            let list = RevocationList::open(&dir).unwrap();
+            let first = list.revoke("a1", Some("alice".to_string()), later).unwrap();
+            assert_eq!(list.revoke("a1", None, later).unwrap(), first);
+            list.revoke("b2", None, Utc::now() - chrono::Duration::minutes(1)).unwrap();
+            assert!(list.is_revoked("a1") && list.is_revoked("b2") && !list.is_revoked("c3"));
+        }
+
+        let list = RevocationList::open(&dir).unwrap();
+        assert_eq!(list.list().len(), 2);
+        assert_eq!(list.prune().unwrap(), 1);
+        assert!(list.is_revoked("a1") && !list.is_revoked("b2"));
+        drop(list);
+        let jtis: Vec<_> = RevocationList::open(&dir).unwrap().list().into_iter().map(|entry| entry.jti).collect();
+        assert_eq!(jtis, ["a1"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Roles: named sets of scopes
+//!
+//! Scopes say what a credential may do one class of operation at a time,
+//! which is too fine-grained to hand out to a team. A role names a set of
+//! them, configured under `[roles]`. A credential is given a role by
+//! carrying the scope `role:<name>`, which [`AuthService::validate_token`]
+//! expands into the scopes the role grants, so signed tokens, API keys,
+//! refresh tokens and identity provider scope maps all take roles alike.
+//!
+//! [`AuthService::validate_token`]: super::AuthService::validate_token
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Reading documents, converting and validating, and following changes
+pub const READ: &str = "read";
+/// Changing documents: deleting them, editing collaboratively, and LSP sessions
+pub const WRITE: &str = "write";
+/// The admin endpoints
+pub const ADMIN: &str = "admin";
+/// Asking `/auth/introspect` about other credentials' tokens
+pub const INTROSPECT: &str = "introspect";
+
+/// How a scope naming a role starts
+pub const PREFIX: &str = "role:";
+
+/// Roles by name, each with the scopes it grants
+///
+/// Configuring `[roles]` replaces the default `viewer`, `editor` and
+/// `admin` roles rather than adding to them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Roles(BTreeMap<String, Vec<String>>);
+
+impl Default for Roles {
+    fn default() -> Self {
+        let scopes = |scopes: &[&str]| scopes.iter().map(ToString::to_string).collect();
+        Self(BTreeMap::from([
+            ("viewer".to_string(), scopes(&[READ])),
+            ("editor".to_string(), scopes(&[READ, WRITE])),
+            ("admin".to_string(), scopes(&[READ, WRITE, ADMIN])),
+        ]))
+    }
+}
+
+impl Roles {
+    /// No roles at all
+    #[must_use]
+    pub fn none() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Define `name`, replacing any role of that name
+    #[must_use]
+    pub fn with(mut self, name: impl Into<String>, scopes: Vec<String>) -> Self {
+        self.0.insert(name.into(), scopes);
+        self
+    }
+
+    /// Scopes `name` grants, if there is such a role
+    pub fn get(&self, name: &str) -> Option<&[String]> {
+        self.0.get(name).map(Vec::as_slice)
+    }
+
+    /// Every role with its scopes, by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.0.iter().map(|(name, scopes)| (name.as_str(), scopes.as_slice()))
+    }
+
+    /// Add the scopes granted by the roles `scopes` names
+    ///
+    /// The role scopes themselves are kept. Roles that are not defined
+    /// grant nothing, and roles do not name other roles.
+    pub fn expand(&self, scopes: &mut Vec<String>) {
+        let granted: Vec<String> = scopes
+            .iter()
+            .filter_map(|scope| scope.strip_prefix(PREFIX))
+            .filter_map(|name| self.get(name))
+            .flatten()
+            .filter(|scope| !scope.starts_with(PREFIX))
+            .cloned()
+            .collect();
+        for scope in granted {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_expand_to_their_scopes() {
+        let roles = Roles::default().with("ops", vec![ADMIN.to_string(), "role:editor".to_string()]);
+        let mut scopes = vec!["role:editor".to_string(), READ.to_string(), "role:nobody".to_string()];
+        roles.expand(&mut scopes);
+        assert_eq!(scopes, ["role:editor", READ, "role:nobody", WRITE]);
+
+        // A role naming a role grants only its own scopes
+        let mut scopes = vec!["role:ops".to_string()];
+        roles.expand(&mut scopes);
+        assert_eq!(scopes, ["role:ops", ADMIN]);
+
+        let mut scopes = vec!["role:admin".to_string()];
+        Roles::none().expand(&mut scopes);
+        assert_eq!(scopes, ["role:admin"]);
+    }
+}
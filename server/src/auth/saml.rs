@@ -0,0 +1,791 @@
+//! SAML 2.0 single sign-on
+//!
+//! With `[saml]` set, users sign in through an identity provider that only
+//! speaks SAML. The provider posts its response to `POST /auth/saml/acs`,
+//! the assertion consumer service, through the user's browser (the
+//! HTTP-POST binding), and the connector answers with a refresh token
+//! family as `/auth/login` does. Editor plugins only ever hold the
+//! connector's own tokens.
+//!
+//! An assertion is trusted once its XML signature, or that of the response
+//! around it, checks out against the provider's certificate. Signatures
+//! must be RSA-SHA256 over SHA-256 digests of the exclusive canonical form,
+//! which is what providers sign with by default; encrypted assertions are
+//! not accepted. The assertion must then come from `idp_entity_id`, be
+//! meant for `sp_entity_id`, be within its validity window, give a bearer
+//! confirmation for `acs_url` if that is set, and not have been consumed
+//! before. Values of `group_attribute` map to scopes through
+//! `group_scopes`, as directory groups do.
+//!
+//! Documents with a DTD are refused, so entities cannot be expanded, and
+//! only the signed element is read from, so a forged assertion wrapped
+//! around a signed one is not.
+
+use super::read_key;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use quick_xml::escape::unescape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Namespace of SAML protocol messages, such as `Response`
+const PROTOCOL: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+/// Namespace of assertions
+const ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+/// Namespace of XML signatures
+const DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+/// Namespace the `xml` prefix is bound to without being declared
+const XML: &str = "http://www.w3.org/XML/1998/namespace";
+
+const EXCLUSIVE_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+/// Largest response accepted, in bytes of XML
+const MAX_RESPONSE_BYTES: usize = 256 * 1024;
+
+/// Identity provider settings, and how its attributes map to scopes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SamlConfig {
+    /// Entity ID of the identity provider, as it writes it in `Issuer`
+    pub idp_entity_id: String,
+    /// PEM certificate, or public key, the provider signs with
+    pub idp_certificate_file: PathBuf,
+    /// Entity ID the connector is registered with at the provider, the audience assertions must be meant for
+    pub sp_entity_id: String,
+    /// URL of `/auth/saml/acs` as the provider posts to it; checked against the response when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acs_url: Option<String>,
+    /// Attribute naming the subject tokens are issued to; the assertion's `NameID` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_attribute: Option<String>,
+    /// Attribute listing the user's groups, by `Name` or `FriendlyName`
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// Scopes granted by each group
+    #[serde(default)]
+    pub group_scopes: BTreeMap<String, Vec<String>>,
+    /// Scopes granted to every user who signs in
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+    /// Difference allowed between the provider's clock and the connector's
+    #[serde(default = "default_clock_skew", with = "crate::config::duration")]
+    pub clock_skew: Duration,
+}
+
+fn default_group_attribute() -> String {
+    "groups".to_string()
+}
+
+fn default_clock_skew() -> Duration {
+    Duration::from_mins(2)
+}
+
+impl SamlConfig {
+    /// Accept assertions `idp_entity_id` signs with the key in `idp_certificate_file`, for `sp_entity_id`
+    pub fn new(idp_entity_id: impl Into<String>, idp_certificate_file: impl Into<PathBuf>, sp_entity_id: impl Into<String>) -> Self {
+        Self {
+            idp_entity_id: idp_entity_id.into(),
+            idp_certificate_file: idp_certificate_file.into(),
+            sp_entity_id: sp_entity_id.into(),
+            acs_url: None,
+            subject_attribute: None,
+            group_attribute: default_group_attribute(),
+            group_scopes: BTreeMap::new(),
+            default_scopes: Vec::new(),
+            clock_skew: default_clock_skew(),
+        }
+    }
+
+    /// Scopes of a user in `groups`: the default ones, then those of each group, without repeats
+    #[must_use]
+    pub fn scopes(&self, groups: &[String]) -> Vec<String> {
+        let mut scopes = self.default_scopes.clone();
+        for group in groups {
+            for scope in self.group_scopes.get(group).into_iter().flatten() {
+                if !scopes.contains(scope) {
+                    scopes.push(scope.clone());
+                }
+            }
+        }
+        scopes
+    }
+}
+
+/// Why a SAML response was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SamlError {
+    /// Not a SAML response the connector can read
+    #[error("malformed response: {0}")]
+    Malformed(String),
+    /// Unsigned, or signed by another key or in an unsupported way
+    #[error("bad signature: {0}")]
+    Signature(String),
+    /// Signed, but not an assertion the connector accepts
+    #[error("assertion refused: {0}")]
+    Refused(String),
+}
+
+/// A user the identity provider vouched for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlUser {
+    /// Subject tokens are issued to
+    pub subject: String,
+    /// Values of the group attribute
+    pub groups: Vec<String>,
+}
+
+/// Checks the assertions of the configured identity provider
+pub struct ServiceProvider {
+    config: SamlConfig,
+    key: DecodingKey,
+    /// IDs of assertions consumed, with the time they expire, so none is presented twice
+    consumed: Mutex<HashMap<String, i64>>,
+}
+
+impl ServiceProvider {
+    /// Check assertions as `config` says, reading the provider's certificate
+    ///
+    /// # Errors
+    ///
+    /// Fails where the certificate cannot be read, or holds no RSA public key.
+    pub fn new(config: SamlConfig) -> Result<Self> {
+        let pem = read_key(&config.idp_certificate_file)?;
+        let key = DecodingKey::from_rsa_pem(pem.as_bytes())
+            .with_context(|| format!("{} is not an RSA certificate or public key", config.idp_certificate_file.display()))?;
+        Ok(Self {
+            config,
+            key,
+            consumed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The settings assertions are checked against
+    pub fn config(&self) -> &SamlConfig {
+        &self.config
+    }
+
+    /// The user a base64-encoded `SAMLResponse` vouches for, if it is to be trusted
+    ///
+    /// # Errors
+    ///
+    /// [`SamlError::Malformed`] where the response cannot be read,
+    /// [`SamlError::Signature`] where its signature does not check out, and
+    /// [`SamlError::Refused`] where it is not an assertion to accept.
+    pub fn consume(&self, saml_response: &str) -> Result<SamlUser, SamlError> {
+        let encoded: String = saml_response.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        let xml = STANDARD.decode(encoded).map_err(|_| SamlError::Malformed("SAMLResponse is not base64".to_string()))?;
+        if xml.len() > MAX_RESPONSE_BYTES {
+            return Err(SamlError::Malformed(format!("responses are limited to {MAX_RESPONSE_BYTES} bytes")));
+        }
+        let xml = String::from_utf8(xml).map_err(|_| SamlError::Malformed("not UTF-8".to_string()))?;
+        self.consume_xml(&xml, Utc::now())
+    }
+
+    /// The user the response in `xml` vouches for at `now`
+    fn consume_xml(&self, xml: &str, now: DateTime<Utc>) -> Result<SamlUser, SamlError> {
+        let response = parse(xml)?;
+        if !response.is(PROTOCOL, "Response") {
+            return Err(SamlError::Malformed(format!("expected a Response, not {}", response.name)));
+        }
+        let status = response
+            .child(PROTOCOL, "Status")
+            .and_then(|status| status.child(PROTOCOL, "StatusCode"))
+            .and_then(|code| code.attribute("Value"))
+            .unwrap_or_default();
+        if status != SUCCESS {
+            return Err(SamlError::Refused(format!("the identity provider answered {status}")));
+        }
+        if response.child(ASSERTION, "EncryptedAssertion").is_some() {
+            return Err(SamlError::Refused("encrypted assertions are not supported".to_string()));
+        }
+        let mut assertions = response.children(ASSERTION, "Assertion");
+        let (Some(assertion), None) = (assertions.next(), assertions.next()) else {
+            return Err(SamlError::Malformed("expected exactly one Assertion".to_string()));
+        };
+
+        // The assertion is read only once it, or the response holding it, is known to be signed
+        if assertion.child(DSIG, "Signature").is_some() {
+            self.verify(assertion, &response)?;
+        } else if response.child(DSIG, "Signature").is_some() {
+            self.verify(&response, &response)?;
+        } else {
+            return Err(SamlError::Signature("neither the response nor its assertion is signed".to_string()));
+        }
+        self.check(&response, assertion, now)
+    }
+
+    /// Check the signature `signed` holds covers it, and was made with the provider's key
+    fn verify(&self, signed: &Element, document: &Element) -> Result<(), SamlError> {
+        let invalid = |message: &str| SamlError::Signature(message.to_string());
+        let signature = signed.child(DSIG, "Signature").ok_or_else(|| invalid("missing"))?;
+        let signed_info = signature.child(DSIG, "SignedInfo").ok_or_else(|| invalid("no SignedInfo"))?;
+        let method = signed_info.child(DSIG, "CanonicalizationMethod").ok_or_else(|| invalid("no CanonicalizationMethod"))?;
+        if method.attribute("Algorithm") != Some(EXCLUSIVE_C14N) {
+            return Err(invalid("only exclusive canonicalization without comments is supported"));
+        }
+        let algorithm = signed_info.child(DSIG, "SignatureMethod").and_then(|method| method.attribute("Algorithm"));
+        if algorithm != Some(RSA_SHA256) {
+            return Err(invalid("only RSA-SHA256 signatures are supported"));
+        }
+        let mut references = signed_info.children(DSIG, "Reference");
+        let (Some(reference), None) = (references.next(), references.next()) else {
+            return Err(invalid("expected exactly one Reference"));
+        };
+        let id = signed.attribute("ID").filter(|id| !id.is_empty()).ok_or_else(|| invalid("the signed element has no ID"))?;
+        if reference.attribute("URI").and_then(|uri| uri.strip_prefix('#')) != Some(id) {
+            return Err(invalid("the Reference is not to the signed element"));
+        }
+        // An element with the same ID elsewhere could be the one a careless reader takes as signed
+        if document.count_ids(id) != 1 {
+            return Err(invalid("the signed element's ID is not unique"));
+        }
+
+        let mut canonical = false;
+        let mut prefixes = Vec::new();
+        for transform in reference.child(DSIG, "Transforms").into_iter().flat_map(|transforms| transforms.children(DSIG, "Transform")) {
+            match transform.attribute("Algorithm") {
+                Some(ENVELOPED_SIGNATURE) => {}
+                Some(EXCLUSIVE_C14N) => {
+                    canonical = true;
+                    prefixes = inclusive_prefixes(transform);
+                }
+                _ => return Err(invalid("only the enveloped signature and exclusive canonicalization transforms are supported")),
+            }
+        }
+        if !canonical {
+            return Err(invalid("the Reference is not canonicalized exclusively"));
+        }
+        let digest_method = reference.child(DSIG, "DigestMethod").and_then(|method| method.attribute("Algorithm"));
+        if digest_method != Some(SHA256) {
+            return Err(invalid("only SHA-256 digests are supported"));
+        }
+        let expected = reference.child(DSIG, "DigestValue").map(|value| base64(&value.text())).transpose()?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, canonicalize(signed, Some(signature), &prefixes).as_bytes());
+        if expected.as_deref() != Some(digest.as_ref()) {
+            return Err(invalid("the digest does not match, so the signed element was changed"));
+        }
+
+        let value = signature.child(DSIG, "SignatureValue").ok_or_else(|| invalid("no SignatureValue"))?;
+        let value = URL_SAFE_NO_PAD.encode(base64(&value.text())?);
+        let message = canonicalize(signed_info, None, &inclusive_prefixes(method));
+        match jsonwebtoken::crypto::verify(&value, message.as_bytes(), &self.key, Algorithm::RS256) {
+            Ok(true) => Ok(()),
+            _ => Err(invalid("not made with the identity provider's key")),
+        }
+    }
+
+    /// Check what the signed `assertion` says, and that it was not consumed before, for the user it names
+    fn check(&self, response: &Element, assertion: &Element, now: DateTime<Utc>) -> Result<SamlUser, SamlError> {
+        let refused = |message: String| SamlError::Refused(message);
+        let config = &self.config;
+        let skew = chrono::Duration::from_std(config.clock_skew).unwrap_or_default();
+        let issuer = assertion.child(ASSERTION, "Issuer").map(Element::text).unwrap_or_default();
+        if issuer != config.idp_entity_id {
+            return Err(refused(format!("issued by {:?}, not {:?}", issuer, config.idp_entity_id)));
+        }
+        if let (Some(acs_url), Some(destination)) = (&config.acs_url, response.attribute("Destination")) {
+            if destination != acs_url {
+                return Err(refused(format!("sent to {destination}, not {acs_url}")));
+            }
+        }
+
+        let conditions = assertion.child(ASSERTION, "Conditions").ok_or_else(|| refused("it has no Conditions".to_string()))?;
+        if let Some(not_before) = conditions.attribute("NotBefore").map(timestamp).transpose()? {
+            if now + skew < not_before {
+                return Err(refused(format!("not valid before {}", not_before.to_rfc3339())));
+            }
+        }
+        let mut expires = conditions.attribute("NotOnOrAfter").map(timestamp).transpose()?;
+        if expires.is_some_and(|expires| now - skew >= expires) {
+            return Err(refused("expired".to_string()));
+        }
+        let mut restrictions = conditions.children(ASSERTION, "AudienceRestriction").peekable();
+        if restrictions.peek().is_none() {
+            return Err(refused("it is not restricted to an audience".to_string()));
+        }
+        for restriction in restrictions {
+            if !restriction.children(ASSERTION, "Audience").any(|audience| audience.text() == config.sp_entity_id) {
+                return Err(refused(format!("not meant for {:?}", config.sp_entity_id)));
+            }
+        }
+
+        let subject = assertion.child(ASSERTION, "Subject").ok_or_else(|| refused("it has no Subject".to_string()))?;
+        let confirmed = subject
+            .children(ASSERTION, "SubjectConfirmation")
+            .filter(|confirmation| confirmation.attribute("Method") == Some(BEARER))
+            .filter_map(|confirmation| confirmation.child(ASSERTION, "SubjectConfirmationData"))
+            .find_map(|data| {
+                let until = data.attribute("NotOnOrAfter").and_then(|until| timestamp(until).ok())?;
+                let recipient = data.attribute("Recipient");
+                let meant = config.acs_url.as_deref().is_none_or(|acs_url| recipient == Some(acs_url));
+                (now - skew < until && meant).then_some(until)
+            })
+            .ok_or_else(|| refused("it has no current bearer confirmation for this service".to_string()))?;
+        expires = Some(expires.map_or(confirmed, |expires| expires.min(confirmed)));
+
+        let values = |name: &str| -> Vec<String> {
+            assertion
+                .children(ASSERTION, "AttributeStatement")
+                .flat_map(|statement| statement.children(ASSERTION, "Attribute"))
+                .filter(|attribute| attribute.attribute("Name") == Some(name) || attribute.attribute("FriendlyName") == Some(name))
+                .flat_map(|attribute| attribute.children(ASSERTION, "AttributeValue"))
+                .map(Element::text)
+                .collect()
+        };
+        let subject = match &config.subject_attribute {
+            Some(attribute) => values(attribute).into_iter().next(),
+            None => subject.child(ASSERTION, "NameID").map(Element::text),
+        };
+        let subject = subject.filter(|subject| !subject.is_empty()).ok_or_else(|| refused("it names no subject".to_string()))?;
+
+        let id = assertion.attribute("ID").unwrap_or_default();
+        let mut consumed = self.consumed.lock().expect("SAML replay cache lock poisoned");
+        consumed.retain(|_, until| *until > (now - skew).timestamp());
+        let until = expires.map_or(i64::MAX, |expires| (expires + skew).timestamp());
+        if consumed.insert(id.to_string(), until).is_some() {
+            return Err(refused(format!("assertion {id} was already used")));
+        }
+        Ok(SamlUser {
+            subject,
+            groups: values(&config.group_attribute),
+        })
+    }
+}
+
+/// Decode base64 that may be wrapped over lines
+fn base64(text: &str) -> Result<Vec<u8>, SamlError> {
+    let encoded: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD
+        .decode(encoded)
+        .map_err(|_| SamlError::Signature("a digest or signature is not base64".to_string()))
+}
+
+/// An `xs:dateTime` of the assertion
+fn timestamp(text: &str) -> Result<DateTime<Utc>, SamlError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| SamlError::Malformed(format!("{text:?} is not a timestamp")))
+}
+
+/// Prefixes canonicalized inclusively, listed by the `InclusiveNamespaces` of a method or transform
+fn inclusive_prefixes(method: &Element) -> Vec<String> {
+    method
+        .child(EXCLUSIVE_C14N, "InclusiveNamespaces")
+        .and_then(|inclusive| inclusive.attribute("PrefixList"))
+        .map(|list| {
+            list.split_ascii_whitespace()
+                .map(|prefix| if prefix == "#default" { String::new() } else { prefix.to_string() })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// An element of a parsed document, with the namespaces in scope at it
+struct Element {
+    /// Qualified name, as written
+    name: String,
+    prefix: String,
+    local: String,
+    namespace: String,
+    /// Attributes other than namespace declarations
+    attributes: Vec<Attribute>,
+    /// Namespaces in scope, by prefix, the default one under `""`
+    scope: BTreeMap<String, String>,
+    children: Vec<Node>,
+}
+
+struct Attribute {
+    name: String,
+    prefix: String,
+    local: String,
+    namespace: String,
+    value: String,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    /// Read a start tag, in the scope of its parent's namespaces
+    fn new(start: &BytesStart<'_>, parent: Option<&Element>) -> Result<Self, SamlError> {
+        let malformed = |e: &dyn std::fmt::Display| SamlError::Malformed(e.to_string());
+        let mut scope = parent.map(|parent| parent.scope.clone()).unwrap_or_default();
+        let mut raw = Vec::new();
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(|e| malformed(&e))?;
+            let name = std::str::from_utf8(attribute.key.as_ref()).map_err(|e| malformed(&e))?.to_string();
+            let value = std::str::from_utf8(&attribute.value).map_err(|e| malformed(&e))?;
+            // Whitespace is normalized as an XML processor would, before references are resolved
+            let value = unescape(&value.replace(['\t', '\n', '\r'], " ")).map_err(|e| malformed(&e))?.into_owned();
+            if name == "xmlns" {
+                scope.insert(String::new(), value);
+            } else if let Some(prefix) = name.strip_prefix("xmlns:") {
+                scope.insert(prefix.to_string(), value);
+            } else {
+                raw.push((name, value));
+            }
+        }
+        let resolve = |name: &str, default: bool| -> Result<(String, String, String), SamlError> {
+            let (prefix, local) = name.split_once(':').unwrap_or(("", name));
+            let namespace = match prefix {
+                "xml" => XML.to_string(),
+                "" if !default => String::new(),
+                _ => match scope.get(prefix) {
+                    Some(namespace) => namespace.clone(),
+                    None if prefix.is_empty() => String::new(),
+                    None => return Err(SamlError::Malformed(format!("prefix {prefix} is not declared"))),
+                },
+            };
+            Ok((prefix.to_string(), local.to_string(), namespace))
+        };
+        let name = std::str::from_utf8(start.name().as_ref()).map_err(|e| malformed(&e))?.to_string();
+        let (prefix, local, namespace) = resolve(&name, true)?;
+        let attributes = raw
+            .into_iter()
+            .map(|(name, value)| {
+                let (prefix, local, namespace) = resolve(&name, false)?;
+                Ok(Attribute { name, prefix, local, namespace, value })
+            })
+            .collect::<Result<_, SamlError>>()?;
+        Ok(Self {
+            name,
+            prefix,
+            local,
+            namespace,
+            attributes,
+            scope,
+            children: Vec::new(),
+        })
+    }
+
+    fn is(&self, namespace: &str, local: &str) -> bool {
+        self.namespace == namespace && self.local == local
+    }
+
+    fn children<'a>(&'a self, namespace: &'a str, local: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter_map(move |child| match child {
+            Node::Element(element) if element.is(namespace, local) => Some(element),
+            _ => None,
+        })
+    }
+
+    fn child(&self, namespace: &str, local: &str) -> Option<&Element> {
+        self.children.iter().find_map(|child| match child {
+            Node::Element(element) if element.is(namespace, local) => Some(element),
+            _ => None,
+        })
+    }
+
+    /// Value of the unqualified attribute `name`
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.namespace.is_empty() && attribute.local == name)
+            .map(|attribute| attribute.value.as_str())
+    }
+
+    /// Text directly inside the element, trimmed
+    fn text(&self) -> String {
+        let text: String = self
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect();
+        text.trim().to_string()
+    }
+
+    /// Number of elements here and below with the `ID` given
+    fn count_ids(&self, id: &str) -> usize {
+        let own = usize::from(self.attribute("ID") == Some(id));
+        own + self
+            .children
+            .iter()
+            .map(|child| match child {
+                Node::Element(element) => element.count_ids(id),
+                Node::Text(_) => 0,
+            })
+            .sum::<usize>()
+    }
+}
+
+/// Parse a document into its root element, refusing DTDs
+fn parse(xml: &str) -> Result<Element, SamlError> {
+    let malformed = |e: &dyn std::fmt::Display| SamlError::Malformed(e.to_string());
+    let mut reader = Reader::from_str(xml);
+    let mut open: Vec<Element> = Vec::new();
+    let mut root = None;
+    let mut close = |element: Element, open: &mut Vec<Element>| match open.last_mut() {
+        Some(parent) => {
+            parent.children.push(Node::Element(element));
+            Ok(())
+        }
+        None if root.is_none() => {
+            root = Some(element);
+            Ok(())
+        }
+        None => Err(SamlError::Malformed("more than one root element".to_string())),
+    };
+    loop {
+        match reader.read_event().map_err(|e| malformed(&e))? {
+            Event::Start(start) => {
+                let element = Element::new(&start, open.last())?;
+                open.push(element);
+            }
+            Event::Empty(start) => {
+                let element = Element::new(&start, open.last())?;
+                close(element, &mut open)?;
+            }
+            Event::End(_) => {
+                let element = open.pop().ok_or_else(|| SamlError::Malformed("unbalanced end tag".to_string()))?;
+                close(element, &mut open)?;
+            }
+            Event::Text(text) => {
+                let raw = std::str::from_utf8(&text).map_err(|e| malformed(&e))?;
+                let text = unescape(&raw.replace("\r\n", "\n").replace('\r', "\n")).map_err(|e| malformed(&e))?.into_owned();
+                match open.last_mut() {
+                    Some(parent) => parent.children.push(Node::Text(text)),
+                    None if text.trim().is_empty() => {}
+                    None => return Err(SamlError::Malformed("text outside the root element".to_string())),
+                }
+            }
+            Event::CData(data) => {
+                let text = std::str::from_utf8(&data).map_err(|e| malformed(&e))?.replace("\r\n", "\n");
+                if let Some(parent) = open.last_mut() {
+                    parent.children.push(Node::Text(text));
+                }
+            }
+            Event::DocType(_) => return Err(SamlError::Malformed("documents with a DTD are not accepted".to_string())),
+            Event::Decl(_) | Event::Comment(_) | Event::PI(_) => {}
+            Event::Eof => break,
+        }
+    }
+    if !open.is_empty() {
+        return Err(SamlError::Malformed("unclosed element".to_string()));
+    }
+    root.ok_or_else(|| SamlError::Malformed("no root element".to_string()))
+}
+
+/// The exclusive canonical form of `element` without comments, leaving out `skip`, as XML signatures digest it
+///
+/// Namespaces are declared where first used rather than where written;
+/// those named in `inclusive` are declared where in scope, used or not.
+fn canonicalize(element: &Element, skip: Option<&Element>, inclusive: &[String]) -> String {
+    let mut out = String::new();
+    write_canonical(element, &BTreeMap::new(), skip, inclusive, &mut out);
+    out
+}
+
+fn write_canonical(element: &Element, rendered: &BTreeMap<String, String>, skip: Option<&Element>, inclusive: &[String], out: &mut String) {
+    let mut used: BTreeSet<&str> = BTreeSet::new();
+    used.insert(&element.prefix);
+    used.extend(element.attributes.iter().map(|attribute| attribute.prefix.as_str()).filter(|prefix| !prefix.is_empty()));
+    used.extend(inclusive.iter().map(String::as_str).filter(|prefix| element.scope.contains_key(*prefix)));
+    used.remove("xml");
+
+    let mut scope = rendered.clone();
+    out.push('<');
+    out.push_str(&element.name);
+    for prefix in used {
+        let namespace = element.scope.get(prefix).map_or("", String::as_str);
+        if rendered.get(prefix).map_or("", String::as_str) == namespace {
+            continue;
+        }
+        if prefix.is_empty() {
+            out.push_str(" xmlns=\"");
+        } else {
+            out.push_str(" xmlns:");
+            out.push_str(prefix);
+            out.push_str("=\"");
+        }
+        escape_attribute(namespace, out);
+        out.push('"');
+        scope.insert(prefix.to_string(), namespace.to_string());
+    }
+    let mut attributes: Vec<&Attribute> = element.attributes.iter().collect();
+    attributes.sort_by(|a, b| (&a.namespace, &a.local).cmp(&(&b.namespace, &b.local)));
+    for attribute in attributes {
+        out.push(' ');
+        out.push_str(&attribute.name);
+        out.push_str("=\"");
+        escape_attribute(&attribute.value, out);
+        out.push('"');
+    }
+    out.push('>');
+    for child in &element.children {
+        match child {
+            Node::Element(child) if skip.is_some_and(|skip| std::ptr::eq(child, skip)) => {}
+            Node::Element(child) => write_canonical(child, &scope, skip, inclusive, out),
+            Node::Text(text) => escape_text(text, out),
+        }
+    }
+    out.push_str("</");
+    out.push_str(&element.name);
+    out.push('>');
+}
+
+fn escape_attribute(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_text(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SecondsFormat;
+    use jsonwebtoken::EncodingKey;
+    use std::path::Path;
+
+    const IDP: &str = "https://idp.example.com/saml";
+    const SP: &str = "urn:universal-connector";
+    const ACS: &str = "https://connector.example.com/auth/saml/acs";
+
+    fn key_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys").join(name)
+    }
+
+    fn provider() -> ServiceProvider {
+        let mut config = SamlConfig::new(IDP, key_file("idp_rsa_cert.pem"), SP);
+        config.acs_url = Some(ACS.to_string());
+        config.group_scopes.insert("editors".to_string(), vec!["role:editor".to_string()]);
+        ServiceProvider::new(config).unwrap()
+    }
+
+    fn assertion(id: &str, now: DateTime<Utc>) -> String {
+        let later = (now + chrono::Duration::minutes(5)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let now = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+        format!(
+            r#"<saml:Assertion xmlns:saml="{ASSERTION}" xmlns:xs="http://www.w3.org/2001/XMLSchema" ID="{id}" Version="2.0" IssueInstant="{now}">
+  <saml:Issuer>{IDP}</saml:Issuer>
+  <saml:Subject>
+    <saml:NameID>alice@example.com</saml:NameID>
+    <saml:SubjectConfirmation Method="{BEARER}"><saml:SubjectConfirmationData NotOnOrAfter="{later}" Recipient="{ACS}"/></saml:SubjectConfirmation>
+  </saml:Subject>
+  <saml:Conditions NotBefore="{now}" NotOnOrAfter="{later}">
+    <saml:AudienceRestriction><saml:Audience>{SP}</saml:Audience></saml:AudienceRestriction>
+  </saml:Conditions>
+  <saml:AttributeStatement>
+    <saml:Attribute Name="groups">
+      <saml:AttributeValue xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="xs:string">editors</saml:AttributeValue>
+      <saml:AttributeValue>staff &amp; contractors</saml:AttributeValue>
+    </saml:Attribute>
+  </saml:AttributeStatement>
+</saml:Assertion>"#
+        )
+    }
+
+    fn response(assertion: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<samlp:Response xmlns:samlp="{PROTOCOL}" xmlns:saml="{ASSERTION}" ID="_response" Version="2.0" Destination="{ACS}"><saml:Issuer>{IDP}</saml:Issuer><samlp:Status><samlp:StatusCode Value="{SUCCESS}"/></samlp:Status>{assertion}</samlp:Response>"#
+        )
+    }
+
+    /// `xml` with an enveloped signature of its root, made with the key in `key`, after the root's Issuer
+    fn sign(xml: &str, key: &str) -> String {
+        let root = parse(xml).unwrap();
+        let id = root.attribute("ID").unwrap();
+        let digest = STANDARD.encode(ring::digest::digest(&ring::digest::SHA256, canonicalize(&root, None, &[]).as_bytes()));
+        let signed_info = format!(
+            r##"<ds:SignedInfo><ds:CanonicalizationMethod Algorithm="{EXCLUSIVE_C14N}"/><ds:SignatureMethod Algorithm="{RSA_SHA256}"/><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{ENVELOPED_SIGNATURE}"/><ds:Transform Algorithm="{EXCLUSIVE_C14N}"/></ds:Transforms><ds:DigestMethod Algorithm="{SHA256}"/><ds:DigestValue>{digest}</ds:DigestValue></ds:Reference></ds:SignedInfo>"##
+        );
+        let declared = signed_info.replacen("<ds:SignedInfo>", &format!(r#"<ds:SignedInfo xmlns:ds="{DSIG}">"#), 1);
+        let message = canonicalize(&parse(&declared).unwrap(), None, &[]);
+        let key = EncodingKey::from_rsa_pem(&std::fs::read(key_file(key)).unwrap()).unwrap();
+        let value = jsonwebtoken::crypto::sign(message.as_bytes(), &key, Algorithm::RS256).unwrap();
+        let value = STANDARD.encode(URL_SAFE_NO_PAD.decode(value).unwrap());
+        let signature = format!(r#"<ds:Signature xmlns:ds="{DSIG}">{signed_info}<ds:SignatureValue>{value}</ds:SignatureValue></ds:Signature>"#);
+        let end = xml.find("</saml:Issuer>").unwrap() + "</saml:Issuer>".len();
+        format!("{}{}{}", &xml[..end], signature, &xml[end..])
+    }
+
+    #[test]
+    fn test_exclusive_canonical_form() {
+        // As xmllint --exc-c14n writes them
+        let xml = r#"<saml:Assertion xmlns:saml="urn:a" xmlns:xs="urn:xs" ID="a" xmlns:unused="urn:u"><saml:Issuer>idp</saml:Issuer><saml:AttributeValue xmlns:xsi="urn:xsi" xsi:type="xs:string" b="1 &amp; 2" a='x"y'>A &lt; B &gt; C</saml:AttributeValue><empty/></saml:Assertion>"#;
+        let root = parse(xml).unwrap();
+        assert_eq!(
+            canonicalize(&root, None, &[]),
+            r#"<saml:Assertion xmlns:saml="urn:a" ID="a"><saml:Issuer>idp</saml:Issuer><saml:AttributeValue xmlns:xsi="urn:xsi" a="x&quot;y" b="1 &amp; 2" xsi:type="xs:string">A &lt; B &gt; C</saml:AttributeValue><empty></empty></saml:Assertion>"#
+        );
+        assert!(canonicalize(&root, None, &["xs".to_string()]).starts_with(r#"<saml:Assertion xmlns:saml="urn:a" xmlns:xs="urn:xs" ID="a">"#));
+        let undeclared = parse(r#"<a xmlns="urn:d"><b xmlns=""><c/></b></a>"#).unwrap();
+        assert_eq!(canonicalize(&undeclared, None, &[]), r#"<a xmlns="urn:d"><b xmlns=""><c></c></b></a>"#);
+
+        assert!(matches!(parse(r#"<!DOCTYPE a [<!ENTITY x "y">]><a>&x;</a>"#), Err(SamlError::Malformed(_))));
+        assert!(matches!(parse("<p:a/>"), Err(SamlError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_signed_assertions() {
+        let provider = provider();
+        let now = Utc::now();
+        let signed = response(&sign(&assertion("_a1", now), "idp_rsa_private.pem"));
+        let encoded = STANDARD.encode(&signed);
+        let user = provider.consume(&encoded).unwrap();
+        assert_eq!(user.subject, "alice@example.com");
+        assert_eq!(user.groups, ["editors", "staff & contractors"]);
+        assert_eq!(provider.config().scopes(&user.groups), ["role:editor"]);
+        assert!(matches!(provider.consume(&encoded), Err(SamlError::Refused(message)) if message.contains("already used")));
+
+        // A response signed around an unsigned assertion vouches for it as well
+        let whole = sign(&response(&assertion("_a2", now)), "idp_rsa_private.pem");
+        assert_eq!(provider.consume_xml(&whole, now).unwrap().subject, "alice@example.com");
+
+        let signature = |result: Result<SamlUser, SamlError>| matches!(result, Err(SamlError::Signature(_)));
+        let tampered = response(&sign(&assertion("_a3", now), "idp_rsa_private.pem")).replace("alice@", "mallory@");
+        assert!(signature(provider.consume_xml(&tampered, now)));
+        assert!(signature(provider.consume_xml(&response(&sign(&assertion("_a4", now), "rsa_private.pem")), now)));
+        assert!(signature(provider.consume_xml(&response(&assertion("_a5", now)), now)));
+        // A forged assertion beside the signed one is not read in its place
+        let wrapped = response(&format!("{}{}", assertion("_a6", now), sign(&assertion("_a7", now), "idp_rsa_private.pem")));
+        assert!(matches!(provider.consume_xml(&wrapped, now), Err(SamlError::Malformed(_))));
+
+        let refused = |result: Result<SamlUser, SamlError>| matches!(result, Err(SamlError::Refused(_)));
+        let later = response(&sign(&assertion("_a8", now), "idp_rsa_private.pem"));
+        assert!(refused(provider.consume_xml(&later, now + chrono::Duration::minutes(10))));
+        assert!(refused(provider.consume_xml(&later, now - chrono::Duration::minutes(10))));
+        let mut elsewhere = provider.config().clone();
+        elsewhere.sp_entity_id = "urn:another-service".to_string();
+        assert!(refused(ServiceProvider::new(elsewhere).unwrap().consume_xml(&later, now)));
+        let failed = later.replace(SUCCESS, "urn:oasis:names:tc:SAML:2.0:status:Requester");
+        assert!(refused(provider.consume_xml(&failed, now)));
+    }
+}
@@ -0,0 +1,107 @@
+//! Scope matching: exact, or hierarchical under a trailing wildcard
+//!
+//! A scope is a path of segments separated by `:`, such as
+//! `documents:read`. A scope whose last segment is `*` grants every scope
+//! below it: `documents:*` grants `documents:read` and
+//! `documents:read:own`, but neither `documents` itself nor
+//! `formats:read`. `*` alone grants every scope. A `*` anywhere else, as
+//! in `documents:*:own` or `doc*`, is matched as written.
+//!
+//! Scopes are compared segment by segment as [`normalize`] would write
+//! them, with whitespace around segments and empty segments ignored, so
+//! ` documents: read` and `documents::read` are both `documents:read`.
+//! Case is kept: scopes are case-sensitive, as in OAuth.
+
+/// Separates the segments of a scope
+pub const SEPARATOR: char = ':';
+
+/// A last segment granting every scope below the ones before it
+pub const WILDCARD: &str = "*";
+
+/// The segments of `scope`, trimmed, without empty ones
+fn segments(scope: &str) -> impl Iterator<Item = &str> {
+    scope.split(SEPARATOR).map(str::trim).filter(|segment| !segment.is_empty())
+}
+
+/// Whether holding `held` grants `required`
+#[must_use]
+pub fn grants(held: &str, required: &str) -> bool {
+    let mut held = segments(held).peekable();
+    let mut required = segments(required);
+    loop {
+        match (held.next(), required.next()) {
+            // A wildcard stands for one or more segments, never for none
+            (Some(WILDCARD), Some(_)) if held.peek().is_none() => return true,
+            (Some(held), Some(required)) if held == required => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// `scope` with its segments trimmed and empty ones dropped
+#[must_use]
+pub fn normalize(scope: &str) -> String {
+    segments(scope).collect::<Vec<_>>().join(":")
+}
+
+/// Normalize each of `scopes`, dropping those left empty and repeats
+pub fn normalize_all(scopes: &mut Vec<String>) {
+    let mut normalized: Vec<String> = Vec::with_capacity(scopes.len());
+    for scope in scopes.drain(..) {
+        let scope = normalize(&scope);
+        if !scope.is_empty() && !normalized.contains(&scope) {
+            normalized.push(scope);
+        }
+    }
+    *scopes = normalized;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards_grant_the_scopes_below_them() {
+        for (held, required) in [
+            ("read", "read"),
+            ("*", "read"),
+            ("*", "documents:read"),
+            ("*", "*"),
+            ("documents:*", "documents:read"),
+            ("documents:*", "documents:read:own"),
+            ("documents:*", "documents:*"),
+            ("documents:*", "documents:read:*"),
+            ("documents:read", " documents : read "),
+            ("documents::read", "documents:read"),
+        ] {
+            assert!(grants(held, required), "{held} should grant {required}");
+        }
+        for (held, required) in [
+            ("read", "write"),
+            ("read", "read:own"),
+            ("documents:read", "documents"),
+            ("documents:*", "documents"),
+            ("documents:*", "formats:read"),
+            ("documents:*", "*"),
+            ("documents:read", "documents:*"),
+            ("documents:*:own", "documents:read:own"),
+            ("doc*", "documents"),
+            ("Read", "read"),
+            ("", "read"),
+        ] {
+            assert!(!grants(held, required), "{held} should not grant {required}");
+        }
+    }
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(normalize(" documents : read "), "documents:read");
+        assert_eq!(normalize("formats::*:"), "formats:*");
+        assert_eq!(normalize("role:editor"), "role:editor");
+
+        let mut scopes = ["read", " read", "", " : ", "documents: *", "documents:*"].map(String::from).to_vec();
+        normalize_all(&mut scopes);
+        assert_eq!(scopes, ["read", "documents:*"]);
+    }
+}
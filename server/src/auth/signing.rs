@@ -0,0 +1,298 @@
+//! HMAC-signed requests, for machine-to-machine clients
+//!
+//! Some clients, such as CI integrations, cannot safely hold a bearer
+//! token but can hold a shared secret and sign each request with it. A
+//! signed request carries an `X-Signature` header:
+//!
+//! ```text
+//! X-Signature: key=ci, timestamp=1760608364, nonce=5f0c9a2e, digest=<hex SHA-256 of the body>, signature=<base64>
+//! ```
+//!
+//! `signature` is the HMAC-SHA256, under the secret of `key`, of the
+//! method, the path with its query, the timestamp, the nonce and the
+//! digest, each on a line of its own. The server checks the digest against
+//! the body, the timestamp against its clock, and the signature; and it
+//! remembers each nonce for as long as its timestamp is acceptable, so a
+//! captured request cannot be replayed. A request so verified stands for
+//! the client's subject and scopes, as a token would.
+
+use super::Claims;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use dashmap::DashMap;
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Header a signed request carries its signature in
+pub const HEADER: &str = "x-signature";
+
+/// Longest nonce accepted, in characters
+const MAX_NONCE_CHARS: usize = 128;
+
+/// Nonces remembered between sweeps of those that can no longer be replayed
+const SWEEP_EVERY: usize = 1024;
+
+/// Clients that sign their requests, and how far their clocks may drift
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    /// Clients by the key ID their signatures name
+    pub clients: BTreeMap<String, SigningClient>,
+    /// How far a request's timestamp may be from the server's clock, either way
+    #[serde(with = "crate::config::duration")]
+    pub max_skew: Duration,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self { clients: BTreeMap::new(), max_skew: Duration::from_mins(5) }
+    }
+}
+
+impl fmt::Debug for SigningConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningConfig")
+            .field("clients", &self.clients.keys().collect::<Vec<_>>())
+            .field("max_skew", &self.max_skew)
+            .finish()
+    }
+}
+
+/// A client signing its requests with a shared secret
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SigningClient {
+    /// Secret shared with the client
+    pub secret: String,
+    /// Subject its requests stand for; the key ID by default
+    pub user: Option<String>,
+    /// Scopes its requests carry, roles included
+    pub scopes: Vec<String>,
+}
+
+impl fmt::Debug for SigningClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningClient").field("user", &self.user).field("scopes", &self.scopes).finish_non_exhaustive()
+    }
+}
+
+/// The client a request was verified to be signed by, left in the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signer {
+    /// Key ID the signature named
+    pub key: String,
+}
+
+/// Why a signature was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// Not a signature header this server reads
+    Malformed(String),
+    /// Names a key no client is configured with
+    UnknownKey,
+    /// Timestamped further from the server's clock than allowed
+    Stale,
+    /// The body does not hash to the digest signed
+    DigestMismatch,
+    /// Not signed with the key's secret
+    BadSignature,
+    /// A nonce seen before, within the time its timestamp is good for
+    Replayed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Malformed(reason) => write!(f, "Malformed signature: {reason}"),
+            SignatureError::UnknownKey => f.write_str("Unknown signing key"),
+            SignatureError::Stale => f.write_str("Signature timestamp outside the allowed clock skew"),
+            SignatureError::DigestMismatch => f.write_str("Body does not match the signed digest"),
+            SignatureError::BadSignature => f.write_str("Signature does not match"),
+            SignatureError::Replayed => f.write_str("Nonce already used"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Checks signed requests against the configured clients, remembering their nonces
+pub struct RequestVerifier {
+    config: SigningConfig,
+    /// Nonces by key, with the Unix time after which they can no longer be replayed
+    nonces: DashMap<(String, String), i64>,
+    inserted: AtomicUsize,
+}
+
+impl RequestVerifier {
+    #[must_use]
+    pub fn new(config: SigningConfig) -> Self {
+        Self { config, nonces: DashMap::new(), inserted: AtomicUsize::new(0) }
+    }
+
+    /// The client of the key ID `key`, if one is configured
+    pub fn client(&self, key: &str) -> Option<&SigningClient> {
+        self.config.clients.get(key)
+    }
+
+    /// Check the signature `header` of a request for `path` with `method` and `body`
+    ///
+    /// # Errors
+    ///
+    /// A [`SignatureError`] where `header` is malformed, names no known key,
+    /// falls outside the allowed clock skew, was seen before, or does not match
+    /// the request.
+    pub fn verify(&self, method: &str, path: &str, header: &str, body: &[u8]) -> Result<Signer, SignatureError> {
+        self.verify_at(method, path, header, body, Utc::now().timestamp())
+    }
+
+    fn verify_at(&self, method: &str, path: &str, header: &str, body: &[u8], now: i64) -> Result<Signer, SignatureError> {
+        let fields = Fields::parse(header)?;
+        let client = self.client(fields.key).ok_or(SignatureError::UnknownKey)?;
+        let skew = i64::try_from(self.config.max_skew.as_secs()).unwrap_or(i64::MAX);
+        if fields.timestamp.abs_diff(now) > skew.unsigned_abs() {
+            return Err(SignatureError::Stale);
+        }
+        if !fields.digest.eq_ignore_ascii_case(&hex_digest(body)) {
+            return Err(SignatureError::DigestMismatch);
+        }
+        let signed = canonical(method, path, fields.timestamp, fields.nonce, &fields.digest.to_ascii_lowercase());
+        let key = hmac::Key::new(hmac::HMAC_SHA256, client.secret.as_bytes());
+        hmac::verify(&key, signed.as_bytes(), &fields.signature).map_err(|_| SignatureError::BadSignature)?;
+
+        // Only a verified nonce is remembered, so forgeries cannot use up a client's nonces
+        let nonce = (fields.key.to_string(), fields.nonce.to_string());
+        if self.nonces.insert(nonce, fields.timestamp.saturating_add(skew)).is_some() {
+            return Err(SignatureError::Replayed);
+        }
+        if self.inserted.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.nonces.retain(|_, good_until| *good_until >= now);
+        }
+        Ok(Signer { key: fields.key.to_string() })
+    }
+
+    /// The claims a request signed by `signer` carries, before roles are expanded
+    pub fn claims(&self, signer: &Signer) -> Option<Claims> {
+        let client = self.client(&signer.key)?;
+        let mut claims = Claims::new(client.user.clone().unwrap_or_else(|| signer.key.clone()), client.scopes.clone());
+        // Each request is signed afresh, so there is no token to revoke
+        claims.jti = None;
+        claims.add_custom("client_name".to_string(), serde_json::json!(signer.key));
+        Some(claims)
+    }
+
+    /// Nonces remembered
+    pub fn nonces(&self) -> usize {
+        self.nonces.len()
+    }
+}
+
+/// The fields of a signature header
+struct Fields<'a> {
+    key: &'a str,
+    timestamp: i64,
+    nonce: &'a str,
+    digest: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Fields<'a> {
+    fn parse(header: &'a str) -> Result<Self, SignatureError> {
+        let mut fields: BTreeMap<&str, &str> = BTreeMap::new();
+        for field in header.split(',') {
+            let (name, value) = field
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| SignatureError::Malformed(format!("{} is not name=value", field.trim())))?;
+            fields.insert(name.trim(), value.trim());
+        }
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .copied()
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| SignatureError::Malformed(format!("no {name}")))
+        };
+        let nonce = field("nonce")?;
+        if nonce.chars().count() > MAX_NONCE_CHARS {
+            return Err(SignatureError::Malformed(format!("nonce longer than {MAX_NONCE_CHARS} characters")));
+        }
+        Ok(Self {
+            key: field("key")?,
+            timestamp: field("timestamp")?
+                .parse()
+                .map_err(|_| SignatureError::Malformed("timestamp is not Unix seconds".to_string()))?,
+            nonce,
+            digest: field("digest")?,
+            signature: STANDARD
+                .decode(field("signature")?)
+                .map_err(|_| SignatureError::Malformed("signature is not base64".to_string()))?,
+        })
+    }
+}
+
+/// Lowercase hex SHA-256 of `body`
+#[must_use]
+pub fn hex_digest(body: &[u8]) -> String {
+    digest::digest(&digest::SHA256, body).as_ref().iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// What is signed: the request line, timestamp, nonce and body digest, a line each
+fn canonical(method: &str, path: &str, timestamp: i64, nonce: &str, digest: &str) -> String {
+    format!("{}\n{}\n{}\n{}\n{}", method.to_ascii_uppercase(), path, timestamp, nonce, digest)
+}
+
+/// The `X-Signature` header of a request, as a client signs it
+#[must_use]
+pub fn sign(key: &str, secret: &str, method: &str, path: &str, body: &[u8], timestamp: i64, nonce: &str) -> String {
+    let digest = hex_digest(body);
+    let signed = canonical(method, path, timestamp, nonce, &digest);
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), signed.as_bytes());
+    let signature = STANDARD.encode(tag.as_ref());
+    format!("key={key}, timestamp={timestamp}, nonce={nonce}, digest={digest}, signature={signature}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier() -> RequestVerifier {
+        let client = SigningClient { secret: "ci-shared-secret".to_string(), ..SigningClient::default() };
+        RequestVerifier::new(SigningConfig { clients: BTreeMap::from([("ci".to_string(), client)]), ..SigningConfig::default() })
+    }
+
+    #[test]
+    fn test_signed_requests_verify_once() {
+        let verifier = verifier();
+        let now = 1_760_608_364;
+        let body = br##"{"content": "# Notes"}"##;
+        let header = sign("ci", "ci-shared-secret", "POST", "/api/convert?to=html", body, now, "n1");
+        let signer = verifier.verify_at("POST", "/api/convert?to=html", &header, body, now + 10).unwrap();
+        assert_eq!(signer, Signer { key: "ci".to_string() });
+        assert_eq!(verifier.verify_at("POST", "/api/convert?to=html", &header, body, now + 11), Err(SignatureError::Replayed));
+
+        let fresh = |nonce: &str| sign("ci", "ci-shared-secret", "POST", "/api/convert?to=html", body, now, nonce);
+        let check = |header: &str, method: &str, body: &[u8], at: i64| verifier.verify_at(method, "/api/convert?to=html", header, body, at);
+        assert_eq!(check(&fresh("n2"), "POST", b"{}", now), Err(SignatureError::DigestMismatch));
+        assert_eq!(check(&fresh("n3"), "PUT", body, now), Err(SignatureError::BadSignature));
+        assert_eq!(check(&fresh("n4"), "POST", body, now + 301), Err(SignatureError::Stale));
+        assert_eq!(check(&fresh("n5"), "POST", body, now - 301), Err(SignatureError::Stale));
+        let forged = sign("ci", "guessed", "POST", "/api/convert?to=html", body, now, "n6");
+        assert_eq!(check(&forged, "POST", body, now), Err(SignatureError::BadSignature));
+        let unknown = sign("cd", "ci-shared-secret", "POST", "/api/convert?to=html", body, now, "n7");
+        assert_eq!(check(&unknown, "POST", body, now), Err(SignatureError::UnknownKey));
+        assert!(matches!(check("key=ci, nonce=n8", "POST", body, now), Err(SignatureError::Malformed(_))));
+        // Rejected requests leave their nonces usable
+        assert!(check(&fresh("n2"), "POST", body, now).is_ok());
+        assert_eq!(verifier.nonces(), 2);
+        assert!(!format!("{:?}", verifier.config).contains("ci-shared-secret"));
+    }
+}
@@ -0,0 +1,341 @@
+//! Stdio-to-WebSocket LSP bridge
+//!
+//! Lets editors that can only spawn stdio language servers use a shared
+//! connector instance. LSP messages read from stdin are forwarded as `Lsp`
+//! WebSocket messages to a language server hosted by the remote connector,
+//! and its messages are written back to stdout with Content-Length framing.
+//!
+//! The bridge opens a resumable session so responses survive a dropped
+//! connection. If the connection cannot be restored, the editor is told via
+//! `window/showMessage` and the bridge exits, which editors treat as a
+//! server shutdown and answer by restarting it.
+//!
+//! Forwarded messages carry a `traceparent` so the connector's handling
+//! joins the bridge's trace.
+
+use crate::build_info;
+use crate::clients::ClientInfo;
+use crate::lsp::{read_message, write_message};
+use crate::telemetry;
+use crate::websocket::{Capability, WsMessage, PROTOCOL_VERSION};
+use anyhow::{anyhow, bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{info, info_span, warn};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Bridge configuration
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// WebSocket URL of the remote connector
+    pub url: String,
+    /// Bearer token sent with the WebSocket upgrade request
+    pub token: Option<String>,
+    /// Reconnection attempts before the bridge gives up
+    pub reconnect_attempts: u32,
+    /// Delay between reconnection attempts
+    pub reconnect_delay: Duration,
+}
+
+impl BridgeConfig {
+    /// Create a configuration for `url` with default reconnection settings
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            token: None,
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Read `BRIDGE_URL` and `BRIDGE_TOKEN`; `None` unless bridge mode is requested
+    #[must_use]
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("BRIDGE_URL").ok().filter(|url| !url.is_empty())?;
+        Some(Self {
+            token: std::env::var("BRIDGE_TOKEN").ok(),
+            ..Self::new(url)
+        })
+    }
+}
+
+/// Run the bridge on stdin/stdout
+///
+/// # Errors
+///
+/// Fails where the connector cannot be reached, or stdin or stdout fail.
+pub async fn run_bridge(config: BridgeConfig) -> Result<()> {
+    run_bridge_with(config, tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Run the bridge on any pair of Content-Length framed streams
+///
+/// # Errors
+///
+/// Fails where the connector cannot be reached, or `input` or `output`
+/// fail.
+pub async fn run_bridge_with<R, W>(config: BridgeConfig, input: R, mut output: W) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    // Read the editor side on its own task: a partially read message must
+    // not be lost when a socket event wins the race below
+    let (input_tx, mut input_rx) = mpsc::channel::<Result<Value>>(64);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(input);
+        loop {
+            let message = read_message(&mut reader).await.transpose();
+            let done = !matches!(message, Some(Ok(_)));
+            if let Some(message) = message {
+                if input_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+    });
+
+    let result = match Link::open(&config).await {
+        Ok(mut link) => link.run(&config, &mut input_rx, &mut output).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = &result {
+        warn!("Bridge stopped: {:#}", e);
+        let _ = write_message(&mut output, &connection_lost(e)).await;
+    }
+    result
+}
+
+/// `window/showMessage` notification telling the editor why the server is going away
+fn connection_lost(error: &anyhow::Error) -> Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "window/showMessage",
+        "params": {
+            "type": 1,
+            "message": format!("Lost connection to the Universal Connector: {error:#}"),
+        },
+    })
+}
+
+/// Connection to the remote connector, with the session it is attached to
+struct Link {
+    socket: Socket,
+    session_id: String,
+    /// Highest delivery ID already written to the editor
+    last_delivery_id: u64,
+    heartbeat: Duration,
+}
+
+impl Link {
+    /// Connect and open a new resumable session
+    async fn open(config: &BridgeConfig) -> Result<Self> {
+        let (mut socket, heartbeat) = connect(config).await?;
+        send(&mut socket, &WsMessage::OpenSession).await?;
+        loop {
+            if let WsMessage::SessionOpened { session_id } = next_message(&mut socket).await? {
+                info!("Bridge session {} opened on {}", session_id, config.url);
+                return Ok(Self {
+                    socket,
+                    session_id,
+                    last_delivery_id: 0,
+                    heartbeat,
+                });
+            }
+        }
+    }
+
+    /// Reconnect and resume the session, retrying per the configuration
+    async fn reconnect(&mut self, config: &BridgeConfig) -> Result<()> {
+        let mut last_error = anyhow!("no reconnection attempts configured");
+        for attempt in 1..=config.reconnect_attempts {
+            tokio::time::sleep(config.reconnect_delay).await;
+            warn!("Reconnecting to {} (attempt {})", config.url, attempt);
+
+            let resumed = match connect(config).await {
+                Ok((mut socket, heartbeat)) => self.resume(&mut socket).await.map(|lost| (socket, heartbeat, lost)),
+                Err(e) => Err(e),
+            };
+            match resumed {
+                Ok((socket, heartbeat, None)) => {
+                    self.socket = socket;
+                    self.heartbeat = heartbeat;
+                    return Ok(());
+                }
+                // The hosted language server is gone with the session
+                Ok((_, _, Some(reason))) => bail!("Session lost: {reason}"),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error.context(format!("Gave up after {} reconnection attempts", config.reconnect_attempts)))
+    }
+
+    /// Resume the session on a new socket, returning the reason if it no longer exists
+    async fn resume(&self, socket: &mut Socket) -> Result<Option<String>> {
+        let resume = WsMessage::ResumeSession {
+            session_id: self.session_id.clone(),
+            last_delivery_id: self.last_delivery_id,
+        };
+        send(socket, &resume).await?;
+        loop {
+            match next_message(socket).await? {
+                WsMessage::SessionResumed { redelivered, .. } => {
+                    info!("Bridge session resumed, {} messages redelivered", redelivered);
+                    return Ok(None);
+                }
+                WsMessage::ResyncRequired { reason } => return Ok(Some(reason)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Forward messages both ways until the editor exits
+    async fn run<W>(
+        &mut self,
+        config: &BridgeConfig,
+        input: &mut mpsc::Receiver<Result<Value>>,
+        output: &mut W,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut heartbeat = tokio::time::interval(self.heartbeat);
+        loop {
+            tokio::select! {
+                message = input.recv() => {
+                    // End of input means the editor went away
+                    let Some(message) = message.transpose()? else {
+                        return Ok(());
+                    };
+                    let method = message.get("method").and_then(Value::as_str);
+                    let exit = method == Some("exit");
+                    // The connector continues this span's trace for the message
+                    let span = info_span!("bridge.forward", rpc.method = method.unwrap_or("response"));
+                    let message = WsMessage::Lsp {
+                        message,
+                        traceparent: span.in_scope(telemetry::current_traceparent),
+                    };
+                    if send(&mut self.socket, &message).await.is_err() {
+                        self.reconnect(config).await?;
+                        send(&mut self.socket, &message).await?;
+                    }
+                    if exit {
+                        let _ = self.socket.close(None).await;
+                        return Ok(());
+                    }
+                }
+                frame = self.socket.next() => match frame {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<WsMessage>(&text) {
+                            Ok(message) => self.receive(message, output).await?,
+                            Err(e) => warn!("Ignoring malformed message from connector: {}", e),
+                        }
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => self.reconnect(config).await?,
+                    Some(Ok(_)) => {}
+                },
+                _ = heartbeat.tick() => {
+                    if send(&mut self.socket, &WsMessage::Ping).await.is_err() {
+                        self.reconnect(config).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle one message from the connector
+    async fn receive<W>(&mut self, message: WsMessage, output: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match message {
+            WsMessage::Reliable { delivery_id, message } => {
+                // Redelivered IDs were already written; only acknowledge them again
+                if delivery_id > self.last_delivery_id {
+                    if let WsMessage::Lsp { message, .. } = *message {
+                        write_message(output, &message).await?;
+                    }
+                    self.last_delivery_id = delivery_id;
+                }
+                send(&mut self.socket, &WsMessage::Ack { delivery_id }).await?;
+            }
+            WsMessage::Lsp { message, .. } => write_message(output, &message).await?,
+            WsMessage::ResyncRequired { reason } => bail!("Session lost: {reason}"),
+            WsMessage::Error { message } | WsMessage::ProtocolViolation { message } => {
+                warn!("Connector reported an error: {}", message);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Connect and negotiate the capabilities the bridge needs
+///
+/// Returns the socket and the heartbeat interval requested by the server.
+async fn connect(config: &BridgeConfig) -> Result<(Socket, Duration)> {
+    let mut request = config.url.as_str().into_client_request()?;
+    if let Some(token) = &config.token {
+        request
+            .headers_mut()
+            .insert("Authorization", HeaderValue::from_str(&format!("Bearer {token}"))?);
+    }
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+
+    let hello = WsMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: [Capability::Lsp, Capability::ResumableSessions]
+            .iter()
+            .map(|c| c.as_str().to_string())
+            .collect(),
+        client: Some(ClientInfo {
+            name: "universal-connector-bridge".to_string(),
+            version: Some(build_info::VERSION.to_string()),
+        }),
+    };
+    send(&mut socket, &hello).await?;
+
+    let WsMessage::Welcome(negotiated) = next_message(&mut socket).await? else {
+        bail!("Connector did not answer the handshake");
+    };
+    for capability in [Capability::Lsp, Capability::ResumableSessions] {
+        if !negotiated.has(capability) {
+            bail!("Connector does not support {}", capability.as_str());
+        }
+    }
+    Ok((socket, Duration::from_secs(negotiated.limits.heartbeat_interval_secs.max(1))))
+}
+
+async fn send(socket: &mut Socket, message: &WsMessage) -> Result<()> {
+    socket.send(Message::Text(serde_json::to_string(message)?)).await?;
+    Ok(())
+}
+
+/// Next protocol message, skipping control frames
+async fn next_message(socket: &mut Socket) -> Result<WsMessage> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Close(frame))) => {
+                let reason = frame.map(|f| f.reason.to_string()).unwrap_or_default();
+                bail!("Connection closed by connector: {reason}");
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => bail!("Connection closed by connector"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Build metadata embedded at compile time
+//!
+//! Filled in by the build script, so every replica can report which build
+//! it runs: over `GET /api/version`, the `ulc_build_info` metric, the LSP
+//! `serverInfo` and the WebSocket `Welcome`.
+
+use serde::{Deserialize, Serialize};
+
+/// Crate version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of the commit built, or `unknown` outside a git checkout
+pub const GIT_HASH: &str = env!("ULC_GIT_HASH");
+/// When the build ran, as RFC 3339 UTC
+pub const BUILD_TIMESTAMP: &str = env!("ULC_BUILD_TIMESTAMP");
+/// `rustc --version` of the compiler used
+pub const RUSTC_VERSION: &str = env!("ULC_RUSTC_VERSION");
+/// Enabled Cargo features, comma-separated
+const FEATURES: &str = env!("ULC_FEATURES");
+
+/// What was built, when and how
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub build_timestamp: String,
+    pub rustc_version: String,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    /// Metric labels, in the order of [`BuildInfo::label_values`]
+    pub const LABELS: [&'static str; 5] = ["version", "git_hash", "build_timestamp", "rustc_version", "features"];
+
+    /// Metadata of the running binary
+    pub fn current() -> Self {
+        Self {
+            version: VERSION.to_string(),
+            git_hash: GIT_HASH.to_string(),
+            build_timestamp: BUILD_TIMESTAMP.to_string(),
+            rustc_version: RUSTC_VERSION.to_string(),
+            features: FEATURES.split(',').filter(|f| !f.is_empty()).map(str::to_string).collect(),
+        }
+    }
+
+    /// Values for [`BuildInfo::LABELS`]
+    #[must_use]
+    pub fn label_values(&self) -> [String; 5] {
+        [
+            self.version.clone(),
+            self.git_hash.clone(),
+            self.build_timestamp.clone(),
+            self.rustc_version.clone(),
+            self.features.join(","),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert!(chrono::DateTime::parse_from_rfc3339(&info.build_timestamp).is_ok());
+        assert!(info.rustc_version.starts_with("rustc ") || info.rustc_version == "unknown");
+        assert_eq!(
+            info.features.contains(&"counting-allocator".to_string()),
+            cfg!(feature = "counting-allocator")
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! What this server instance supports
+//!
+//! The [`CapabilityRegistry`] holds a tree of named capabilities, each with
+//! a version, parameters such as size limits, and capabilities nested under
+//! it. [`CapabilityRegistry::from_config`] fills it at startup from the
+//! configuration and compiled features; embedders add their own with
+//! [`CapabilityRegistry::register`].
+//!
+//! `GET /api/capabilities`, the `experimental` section of the LSP
+//! `initialize` result and the WebSocket `Welcome` all render the registry
+//! with [`CapabilityRegistry::to_value`], so they cannot disagree. Paths
+//! join names with dots: `formats.markdown`.
+
+use crate::build_info;
+use crate::core::Format;
+use crate::formats::FormatLimits;
+use crate::{websocket, ServerConfig};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// Version of the HTTP API, the document formats and collaborative editing
+const API_VERSION: &str = "1";
+/// LSP version the server implements
+const LSP_VERSION: &str = "3.17";
+
+/// One capability: its version, parameters and nested capabilities
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityInfo {
+    /// Version of the capability, such as `3.17` for LSP
+    pub version: String,
+    /// Settings clients may need, such as `max_input_bytes`
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub parameters: Map<String, Value>,
+    /// Capabilities nested under this one, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub children: BTreeMap<String, CapabilityInfo>,
+}
+
+impl CapabilityInfo {
+    /// A capability at `version`, with no parameters
+    pub fn new(version: impl Into<String>) -> Self {
+        Self {
+            version: version.into(),
+            parameters: Map::new(),
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// Add a parameter, such as a size limit
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` does not serialize to JSON, as a map with keys that
+    /// are not strings does not.
+    #[must_use]
+    pub fn parameter(mut self, name: &str, value: impl Serialize) -> Self {
+        self.parameters.insert(name.to_string(), serde_json::to_value(value).expect("parameter serializes"));
+        self
+    }
+
+    /// Nest a capability under this one
+    #[must_use]
+    pub fn child(mut self, name: &str, child: CapabilityInfo) -> Self {
+        self.children.insert(name.to_string(), child);
+        self
+    }
+}
+
+/// Capabilities of this server instance, open to registration at runtime
+#[derive(Debug, Default)]
+pub struct CapabilityRegistry {
+    tree: RwLock<BTreeMap<String, CapabilityInfo>>,
+}
+
+impl CapabilityRegistry {
+    /// An empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in capabilities enabled by `config` and the compiled features
+    #[must_use]
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let mut tree = BTreeMap::new();
+        tree.insert(
+            "build".to_string(),
+            CapabilityInfo::new(build_info::VERSION).parameter("features", build_info::BuildInfo::current().features),
+        );
+        tree.insert("formats".to_string(), formats(&config.format_limits));
+        tree.insert("lsp".to_string(), CapabilityInfo::new(LSP_VERSION).parameter("stdio", config.enable_lsp));
+        tree.insert("collab".to_string(), CapabilityInfo::new(API_VERSION));
+        if config.enable_http {
+            tree.insert("http".to_string(), CapabilityInfo::new(API_VERSION));
+        }
+        if config.enable_websocket {
+            tree.insert("websocket".to_string(), websocket::capability());
+        }
+        if config.enable_grpc && cfg!(feature = "grpc") {
+            tree.insert("grpc".to_string(), CapabilityInfo::new(API_VERSION).parameter("reflection", true));
+        }
+        if config.enable_auth {
+            let mut auth = CapabilityInfo::new(API_VERSION)
+                .parameter("scheme", "bearer")
+                .parameter("algorithm", config.jwt_algorithm.as_str());
+            // Where clients sign users in to get tokens the server accepts
+            if let Some(oidc) = &config.oidc {
+                auth = auth.parameter("oidc_issuer", oidc.issuer.as_str());
+            }
+            tree.insert("auth".to_string(), auth);
+        }
+        Self { tree: RwLock::new(tree) }
+    }
+
+    /// Add or replace the capability at `path`, under an existing parent
+    ///
+    /// # Errors
+    ///
+    /// Fails where there is no capability at the parent of `path`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the capability tree.
+    pub fn register(&self, path: &str, capability: CapabilityInfo) -> Result<()> {
+        let mut tree = self.tree.write().expect("capability lock poisoned");
+        let (siblings, name) = match path.rsplit_once('.') {
+            Some((parent, name)) => {
+                let parent =
+                    find(&mut tree, parent).ok_or_else(|| anyhow!("No capability {parent} to add {name} under"))?;
+                (&mut parent.children, name)
+            }
+            None => (&mut *tree, path),
+        };
+        if name.is_empty() {
+            bail!("Capability path {path:?} has an empty name");
+        }
+        siblings.insert(name.to_string(), capability);
+        Ok(())
+    }
+
+    /// Remove the capability at `path` with everything under it
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the capability tree.
+    pub fn remove(&self, path: &str) -> Option<CapabilityInfo> {
+        let mut tree = self.tree.write().expect("capability lock poisoned");
+        match path.rsplit_once('.') {
+            Some((parent, name)) => find(&mut tree, parent)?.children.remove(name),
+            None => tree.remove(path),
+        }
+    }
+
+    /// Set one parameter of the capability at `path`
+    ///
+    /// # Errors
+    ///
+    /// Fails where there is no capability at `path`, or `value` does not
+    /// serialize.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the capability tree.
+    pub fn set_parameter(&self, path: &str, name: &str, value: impl Serialize) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut tree = self.tree.write().expect("capability lock poisoned");
+        let capability = find(&mut tree, path).ok_or_else(|| anyhow!("No capability {path}"))?;
+        capability.parameters.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// The capability at `path`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the capability tree.
+    pub fn get(&self, path: &str) -> Option<CapabilityInfo> {
+        let tree = self.tree.read().expect("capability lock poisoned");
+        let mut names = path.split('.');
+        let mut capability = tree.get(names.next()?)?;
+        for name in names {
+            capability = capability.children.get(name)?;
+        }
+        Some(capability.clone())
+    }
+
+    /// Whether the capability at `path` is registered
+    pub fn contains(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// Names of the capabilities nested under `path`, in order
+    pub fn names(&self, path: &str) -> Vec<String> {
+        self.get(path).map(|capability| capability.children.into_keys().collect()).unwrap_or_default()
+    }
+
+    /// The whole tree, as every surface exposes it
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the capability tree.
+    pub fn to_value(&self) -> Value {
+        let tree = self.tree.read().expect("capability lock poisoned");
+        serde_json::to_value(&*tree).expect("capabilities serialize")
+    }
+
+    /// Formats listed in the registry, in [`Format::ALL`] order
+    pub fn formats(&self) -> Vec<Format> {
+        Format::ALL
+            .into_iter()
+            .filter(|format| self.contains(&format!("formats.{}", format.name())))
+            .collect()
+    }
+}
+
+/// Describe the built-in formats, each convertible to every other
+fn formats(limits: &FormatLimits) -> CapabilityInfo {
+    let mut formats = CapabilityInfo::new(API_VERSION)
+        .parameter("max_input_bytes", limits.max_input_bytes)
+        .parameter("max_output_bytes", limits.max_output_bytes);
+    for format in Format::ALL {
+        let targets: Vec<&str> = Format::ALL.iter().filter(|to| **to != format).map(Format::name).collect();
+        let capability = CapabilityInfo::new(API_VERSION).parameter("converts_to", targets);
+        formats = formats.child(format.name(), capability.parameter("validates", true));
+    }
+    formats
+}
+
+fn find<'a>(tree: &'a mut BTreeMap<String, CapabilityInfo>, path: &str) -> Option<&'a mut CapabilityInfo> {
+    let mut names = path.split('.');
+    let mut capability = tree.get_mut(names.next()?)?;
+    for name in names {
+        capability = capability.children.get_mut(name)?;
+    }
+    Some(capability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_built_in_capabilities_follow_config() {
+        let registry = CapabilityRegistry::from_config(&ServerConfig::default());
+        assert_eq!(
+            registry.names("formats"),
+            ["cbor", "csv", "dotenv", "html", "ini", "json", "json5", "jsonc", "markdown", "msgpack", "ndjson", "plist", "toml", "tsv", "xml", "yaml"]
+        );
+        assert_eq!(registry.formats(), Format::ALL);
+        assert!(registry.contains("websocket.collab"));
+        assert!(!registry.contains("auth"));
+        let markdown = registry.get("formats.markdown").unwrap();
+        assert_eq!(markdown.parameters["converts_to"], serde_json::json!(["html", "json", "yaml", "xml", "toml", "csv", "tsv", "ini", "json5", "jsonc", "ndjson", "msgpack", "cbor", "dotenv", "plist"]));
+
+        let config = ServerConfig::builder().disable_websocket().format_limits(|limits| limits.max_input_bytes(1024));
+        let registry = CapabilityRegistry::from_config(&config.build().unwrap());
+        assert!(!registry.contains("websocket"));
+        assert_eq!(registry.get("formats").unwrap().parameters["max_input_bytes"], 1024);
+    }
+
+    #[test]
+    fn test_registration() {
+        let registry = CapabilityRegistry::from_config(&ServerConfig::default());
+        registry.register("formats.asciidoc", CapabilityInfo::new("1").parameter("validates", false)).unwrap();
+        registry.register("jobs", CapabilityInfo::new("2").child("cancel", CapabilityInfo::new("1"))).unwrap();
+        assert!(registry.contains("formats.asciidoc"));
+        assert_eq!(registry.to_value()["jobs"]["children"]["cancel"]["version"], "1");
+        // Only formats the server converts are listed as formats
+        assert_eq!(registry.formats(), Format::ALL);
+
+        assert!(registry.register("missing.child", CapabilityInfo::new("1")).is_err());
+        assert!(registry.register("formats.", CapabilityInfo::new("1")).is_err());
+        registry.set_parameter("formats", "max_input_bytes", 16).unwrap();
+        assert_eq!(registry.get("formats").unwrap().parameters["max_input_bytes"], 16);
+
+        assert!(registry.remove("formats.json").is_some());
+        assert!(!registry.formats().contains(&Format::Json));
+        assert!(registry.remove("formats.json").is_none());
+    }
+}
@@ -0,0 +1,605 @@
+//! Client for the HTTP and WebSocket APIs
+//!
+//! For companion tools written in Rust. [`UlcClient`] wraps the HTTP
+//! endpoints in typed methods, and [`UlcClient::websocket`] opens a
+//! [`UlcSocket`] that performs the handshake, follows subscriptions and
+//! resumes its session after a dropped connection. Requests, responses and
+//! WebSocket messages are the server's own types, re-exported here, so the
+//! two sides cannot drift apart.
+//!
+//! A client is given a bearer token, or a function fetching one. With a
+//! function, the token is fetched before the first request and fetched
+//! again when the server refuses it, and the refused request is retried
+//! once with the new token.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), universal_connector_server::client::ClientError> {
+//! use universal_connector_server::client::UlcClient;
+//!
+//! let client = UlcClient::new("http://localhost:8080")?.with_websocket_url("ws://localhost:8081");
+//! let converted = client.convert("# Title", "markdown", "html").await?.content;
+//! println!("{}", converted);
+//! let mut socket = client.websocket().await?;
+//! socket.subscribe_pattern("file:///notes/**", false).await?;
+//! loop {
+//!     println!("{:?}", socket.next().await?);
+//! }
+//! # }
+//! ```
+
+mod websocket;
+
+pub use self::websocket::{SocketOptions, Subscription, UlcSocket};
+pub use crate::auth::refresh::TokenPair;
+pub use crate::document_store::Document;
+pub use crate::formats::diff::{Change, ChangeKind};
+pub use crate::formats::FormatOptions;
+pub use crate::http::{
+    ConvertRequest, ConvertResponse, DiffDocument, DiffRequest, DiffResponse, DocumentListResponse, ErrorResponse,
+    FormatRequest, FormatResponse, ServerStats, ValidateRequest, ValidateResponse, VersionResponse,
+};
+pub use crate::monitoring::HealthStatus;
+pub use crate::scheduler::TaskStatus;
+pub use crate::websocket::{Capability, Negotiated, WsMessage};
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+/// Why a call failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// 400: the request was malformed, e.g. named an unknown format
+    BadRequest(String),
+    /// 401: no token, or one the server does not accept
+    Unauthorized(String),
+    /// 403: the token lacks a scope the endpoint needs
+    Forbidden(String),
+    /// 404
+    NotFound(String),
+    /// 409: e.g. a task that is already running
+    Conflict(String),
+    /// Any other status the server answered with
+    Status { status: u16, message: String },
+    /// The token function failed
+    Token(anyhow::Error),
+    /// The server could not be reached, or its answer not read
+    Http(reqwest::Error),
+    /// The WebSocket connection failed
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    /// The server broke the protocol, or refused the handshake
+    Protocol(String),
+}
+
+impl ClientError {
+    /// The error for a refused request, from its status and body
+    ///
+    /// The message is the `error` of the server's JSON body, or the
+    /// `detail` or `title` of an RFC 9457 `application/problem+json` body,
+    /// falling back to the body itself and then to the status.
+    #[must_use]
+    pub fn from_response(status: u16, body: &str) -> Self {
+        let message = serde_json::from_str::<ErrorBody>(body)
+            .ok()
+            .and_then(|body| body.error.or(body.detail).or(body.title))
+            .or_else(|| Some(body.trim().to_string()).filter(|body| !body.is_empty()))
+            .unwrap_or_else(|| {
+                let reason = StatusCode::from_u16(status).ok().and_then(|status| status.canonical_reason());
+                reason.unwrap_or("Request failed").to_string()
+            });
+        match status {
+            400 => Self::BadRequest(message),
+            401 => Self::Unauthorized(message),
+            403 => Self::Forbidden(message),
+            404 => Self::NotFound(message),
+            409 => Self::Conflict(message),
+            status => Self::Status { status, message },
+        }
+    }
+
+    /// HTTP status the server refused the request with, if it did
+    #[must_use]
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::BadRequest(_) => Some(400),
+            Self::Unauthorized(_) => Some(401),
+            Self::Forbidden(_) => Some(403),
+            Self::NotFound(_) => Some(404),
+            Self::Conflict(_) => Some(409),
+            Self::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadRequest(message) => write!(f, "Bad request: {message}"),
+            Self::Unauthorized(message) => write!(f, "Unauthorized: {message}"),
+            Self::Forbidden(message) => write!(f, "Forbidden: {message}"),
+            Self::NotFound(message) => write!(f, "Not found: {message}"),
+            Self::Conflict(message) => write!(f, "Conflict: {message}"),
+            Self::Status { status, message } => write!(f, "Server answered {status}: {message}"),
+            Self::Token(e) => write!(f, "Failed to get a token: {e:#}"),
+            Self::Http(e) => write!(f, "HTTP request failed: {e}"),
+            Self::WebSocket(e) => write!(f, "WebSocket failed: {e}"),
+            Self::Protocol(message) => write!(f, "Protocol error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Token(e) => Some(e.as_ref()),
+            Self::Http(e) => Some(e),
+            Self::WebSocket(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for ClientError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(e))
+    }
+}
+
+/// Error body of the server, or of a problem+json response from a proxy in front of it
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: Option<String>,
+    detail: Option<String>,
+    title: Option<String>,
+}
+
+type Result<T, E = ClientError> = std::result::Result<T, E>;
+
+/// Fetches a new bearer token
+type Refresh = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<String>> + Send + Sync>;
+
+/// The token sent with requests, and how to replace it
+#[derive(Default)]
+struct Credentials {
+    token: RwLock<Option<String>>,
+    refresh: Option<Refresh>,
+    /// Held while refreshing, so concurrent refusals fetch one token
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl Credentials {
+    fn current(&self) -> Option<String> {
+        self.token.read().expect("token lock poisoned").clone()
+    }
+
+    /// The token to send, fetched first if there is none yet
+    async fn token(&self) -> Result<Option<String>> {
+        match self.current() {
+            Some(token) => Ok(Some(token)),
+            None if self.refresh.is_some() => self.refresh(None).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace `stale`, unless another caller already has
+    async fn refresh(&self, stale: Option<&str>) -> Result<String> {
+        let Some(refresh) = &self.refresh else {
+            return Err(ClientError::Unauthorized("No token to refresh".to_string()));
+        };
+        let _refreshing = self.refreshing.lock().await;
+        if let Some(current) = self.current().filter(|current| Some(current.as_str()) != stale) {
+            return Ok(current);
+        }
+        let token = refresh().await.map_err(ClientError::Token)?;
+        *self.token.write().expect("token lock poisoned") = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Whether a refusal of `sent` may be retried with a new token
+    fn can_refresh(&self) -> bool {
+        self.refresh.is_some()
+    }
+}
+
+/// `Authorization` header value for `token`, which may carry the `Bearer` prefix already
+fn bearer(token: &str) -> String {
+    format!("Bearer {}", token.strip_prefix("Bearer ").unwrap_or(token))
+}
+
+/// Client of one server
+///
+/// Cheap to clone; clones share the connection pool and the token.
+#[derive(Clone)]
+pub struct UlcClient {
+    http: reqwest::Client,
+    base_url: String,
+    websocket_url: Option<String>,
+    credentials: Arc<Credentials>,
+}
+
+impl fmt::Debug for UlcClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UlcClient")
+            .field("base_url", &self.base_url)
+            .field("websocket_url", &self.websocket_url)
+            .field("refreshing", &self.credentials.can_refresh())
+            .finish_non_exhaustive()
+    }
+}
+
+impl UlcClient {
+    /// Client of the HTTP API at `base_url`, such as `http://localhost:8080`
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Protocol`] where `base_url` is not an HTTP URL.
+    pub fn new(base_url: impl Into<String>) -> Result<Self> {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(ClientError::Protocol(format!("Not an HTTP URL: {base_url}")));
+        }
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base_url,
+            websocket_url: None,
+            credentials: Arc::new(Credentials::default()),
+        })
+    }
+
+    /// Send `token` with every request and WebSocket handshake
+    #[must_use]
+    pub fn with_token(self, token: impl Into<String>) -> Self {
+        let credentials = Credentials {
+            token: RwLock::new(Some(token.into())),
+            refresh: self.credentials.refresh.clone(),
+            refreshing: tokio::sync::Mutex::new(()),
+        };
+        Self { credentials: Arc::new(credentials), ..self }
+    }
+
+    /// Fetch tokens with `refresh`: before the first request, and whenever the server refuses one
+    #[must_use]
+    pub fn with_token_refresh<F, Fut>(self, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<String>> + Send + 'static,
+    {
+        let credentials = Credentials {
+            token: RwLock::new(self.credentials.current()),
+            refresh: Some(Arc::new(move || refresh().boxed())),
+            refreshing: tokio::sync::Mutex::new(()),
+        };
+        Self { credentials: Arc::new(credentials), ..self }
+    }
+
+    /// Open WebSocket connections to `url`, such as `ws://localhost:8081`
+    #[must_use]
+    pub fn with_websocket_url(self, url: impl Into<String>) -> Self {
+        Self { websocket_url: Some(url.into()), ..self }
+    }
+
+    #[must_use]
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The token currently sent, if any
+    #[must_use]
+    pub fn token(&self) -> Option<String> {
+        self.credentials.current()
+    }
+
+    /// Exchange a refresh token for an access token and the next refresh token
+    ///
+    /// The token presented is spent, so keep the one returned; presenting
+    /// it again revokes every token descending from it. Sent without this
+    /// client's own token, as the refresh token is the credential.
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Unauthorized`] where the token is unknown, spent or
+    /// revoked, and the other [`ClientError`]s of a request that fails.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<TokenPair> {
+        let body = serde_json::json!({ "refresh_token": refresh_token });
+        Ok(self.attempt(Method::POST, "/auth/refresh", Some(&body), None).await?.json().await?)
+    }
+
+    /// Convert `content` between two formats, built in or provided by plugins
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where a format is unknown or `content` does
+    /// not convert, and the other [`ClientError`]s of a request that fails.
+    pub async fn convert(&self, content: &str, from: &str, to: &str) -> Result<ConvertResponse> {
+        let request = ConvertRequest {
+            content: content.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            original: None,
+            datetimes: crate::formats::datetime::DateTimeStyle::default(),
+        };
+        self.call(Method::POST, "/api/convert", Some(&request)).await
+    }
+
+    /// Convert `content` back to `to`, keeping the comments and key order of `original`, the document it came from
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where a format is unknown or `content` does
+    /// not convert; `original` not parsing is a warning of the response
+    /// instead.
+    pub async fn convert_onto(&self, content: &str, from: &str, to: &str, original: &str) -> Result<ConvertResponse> {
+        let request = ConvertRequest {
+            content: content.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            original: Some(original.to_string()),
+            datetimes: crate::formats::datetime::DateTimeStyle::default(),
+        };
+        self.call(Method::POST, "/api/convert", Some(&request)).await
+    }
+
+    /// Validate `content` as `format`; problems found are diagnostics, not errors
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where `format` is unknown, and the other
+    /// [`ClientError`]s of a request that fails.
+    pub async fn validate(&self, content: &str, format: &str) -> Result<ValidateResponse> {
+        let request = ValidateRequest { content: content.to_string(), format: format.to_string(), schema: None };
+        self.call(Method::POST, "/api/validate", Some(&request)).await
+    }
+
+    /// Validate `content` as `format` and against `schema`, the name of a schema on the server or a schema itself
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where `format` is unknown, no schema on the
+    /// server has the name given, or `schema` does not compile.
+    pub async fn validate_schema(&self, content: &str, format: &str, schema: serde_json::Value) -> Result<ValidateResponse> {
+        let request = ValidateRequest { content: content.to_string(), format: format.to_string(), schema: Some(schema) };
+        self.call(Method::POST, "/api/validate", Some(&request)).await
+    }
+
+    /// Lay out `content`, a JSON, NDJSON, YAML or TOML document, as `options` say
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where `content` does not parse as `format`,
+    /// or `format` cannot be laid out.
+    pub async fn format(&self, content: &str, format: &str, options: FormatOptions) -> Result<FormatResponse> {
+        let request = FormatRequest { content: content.to_string(), format: format.to_string(), options };
+        self.call(Method::POST, "/api/format", Some(&request)).await
+    }
+
+    /// The changes from `before` to `after`, documents of the formats named
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::BadRequest`] where a format is unknown or either document
+    /// does not parse.
+    pub async fn diff(&self, before: &str, before_format: &str, after: &str, after_format: &str) -> Result<DiffResponse> {
+        let request = DiffRequest {
+            before: DiffDocument { content: before.to_string(), format: before_format.to_string() },
+            after: DiffDocument { content: after.to_string(), format: after_format.to_string() },
+        };
+        self.call(Method::POST, "/api/diff", Some(&request)).await
+    }
+
+    /// Every document open on the server
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached or refuses the
+    /// request.
+    pub async fn documents(&self) -> Result<Vec<Document>> {
+        let list: DocumentListResponse = self.call(Method::GET, "/api/documents", None::<&()>).await?;
+        Ok(list.documents)
+    }
+
+    /// The open document with ID `id`
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::NotFound`] where no document has the ID `id`.
+    pub async fn document(&self, id: &str) -> Result<Document> {
+        self.call(Method::GET, &format!("/api/documents/{}", segment(id)), None::<&()>).await
+    }
+
+    /// Close the open document with ID `id`
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::NotFound`] where no document has the ID `id`.
+    pub async fn delete_document(&self, id: &str) -> Result<()> {
+        self.send(Method::DELETE, &format!("/api/documents/{}", segment(id)), None::<&()>).await?;
+        Ok(())
+    }
+
+    /// The number of open documents, with the server's uptime and version
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached or refuses the
+    /// request.
+    pub async fn stats(&self) -> Result<ServerStats> {
+        self.call(Method::GET, "/api/stats", None::<&()>).await
+    }
+
+    /// The server's build and uptime
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached or its answer read.
+    pub async fn version(&self) -> Result<VersionResponse> {
+        self.call(Method::GET, "/api/version", None::<&()>).await
+    }
+
+    /// The server's capability tree, as `GET /api/capabilities` returns it
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached or its answer read.
+    pub async fn capabilities(&self) -> Result<serde_json::Value> {
+        self.call(Method::GET, "/api/capabilities", None::<&()>).await
+    }
+
+    /// Results of every health check
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached or its answer read.
+    pub async fn health(&self) -> Result<HealthStatus> {
+        self.call(Method::GET, "/api/health/detailed", None::<&()>).await
+    }
+
+    /// Whether the server is ready for traffic, as its readiness probe says
+    ///
+    /// # Errors
+    ///
+    /// A [`ClientError`] where the server cannot be reached; a server that is
+    /// not ready answers `false`.
+    pub async fn ready(&self) -> Result<bool> {
+        match self.send(Method::GET, "/readyz", None::<&()>).await {
+            Ok(_) => Ok(true),
+            Err(ClientError::Status { status: 503, .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Scheduled maintenance tasks; needs the `admin` scope when authentication is enabled
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Forbidden`] without the `admin` scope, and the other
+    /// [`ClientError`]s of a request that fails.
+    pub async fn tasks(&self) -> Result<Vec<TaskStatus>> {
+        self.call(Method::GET, "/api/admin/tasks", None::<&()>).await
+    }
+
+    /// Run the task `name` now, outside its schedule
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Conflict`] where the task is already running, and
+    /// [`ClientError::Forbidden`] without the `admin` scope.
+    pub async fn run_task(&self, name: &str) -> Result<Option<TaskStatus>> {
+        self.call(Method::POST, &format!("/api/admin/tasks/{}/run", segment(name)), None::<&()>).await
+    }
+
+    /// Open a WebSocket connection, negotiating resumable sessions
+    ///
+    /// # Errors
+    ///
+    /// As [`UlcClient::websocket_with`] does.
+    pub async fn websocket(&self) -> Result<UlcSocket> {
+        self.websocket_with(SocketOptions::default()).await
+    }
+
+    /// Open a WebSocket connection as `options` say
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Protocol`] without a WebSocket URL or where the server
+    /// refuses the handshake, and [`ClientError::WebSocket`] where the
+    /// connection fails.
+    pub async fn websocket_with(&self, options: SocketOptions) -> Result<UlcSocket> {
+        let url = self
+            .websocket_url
+            .clone()
+            .ok_or_else(|| ClientError::Protocol("No WebSocket URL configured".to_string()))?;
+        UlcSocket::connect(self.clone(), url, options).await
+    }
+
+    /// Send a request and decode its JSON answer
+    async fn call<T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<&impl Serialize>) -> Result<T> {
+        Ok(self.send(method, path, body).await?.json().await?)
+    }
+
+    /// Send a request, retrying it once with a new token if the server refuses the one sent
+    async fn send(&self, method: Method, path: &str, body: Option<&impl Serialize>) -> Result<reqwest::Response> {
+        let token = self.credentials.token().await?;
+        match self.attempt(method.clone(), path, body, token.as_deref()).await {
+            Err(ClientError::Unauthorized(_)) if self.credentials.can_refresh() => {
+                let token = self.credentials.refresh(token.as_deref()).await?;
+                self.attempt(method, path, body, Some(&token)).await
+            }
+            result => result,
+        }
+    }
+
+    async fn attempt(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+        token: Option<&str>,
+    ) -> Result<reqwest::Response> {
+        let mut request = self.http.request(method, format!("{}{}", self.base_url, path));
+        if let Some(token) = token {
+            request = request.header(reqwest::header::AUTHORIZATION, bearer(token));
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap_or_default();
+        Err(ClientError::from_response(status, &body))
+    }
+}
+
+/// `value` escaped for use as one path segment
+fn segment(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(char::from(byte));
+        } else {
+            let _ = write!(escaped, "%{byte:02X}");
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_bodies() {
+        let error = ClientError::from_response(404, r#"{"error": "Document not found: x"}"#);
+        assert!(matches!(&error, ClientError::NotFound(message) if message == "Document not found: x"));
+
+        let problem = r#"{"type": "about:blank", "title": "Too Many Requests", "detail": "Retry in 5s"}"#;
+        let error = ClientError::from_response(429, problem);
+        assert!(matches!(&error, ClientError::Status { status: 429, message } if message == "Retry in 5s"));
+        assert_eq!(error.status(), Some(429));
+
+        assert!(matches!(ClientError::from_response(502, ""), ClientError::Status { message, .. } if message == "Bad Gateway"));
+        assert!(matches!(ClientError::from_response(401, "denied"), ClientError::Unauthorized(message) if message == "denied"));
+    }
+
+    #[test]
+    fn test_segment_escaping() {
+        assert_eq!(segment("daily-report"), "daily-report");
+        assert_eq!(segment("a b/c"), "a%20b%2Fc");
+        assert_eq!(bearer("Bearer abc"), "Bearer abc");
+        assert_eq!(bearer("abc"), "Bearer abc");
+    }
+}
@@ -0,0 +1,451 @@
+//! WebSocket connection of a [`UlcClient`]
+//!
+//! [`UlcSocket`] says `Hello`, keeps the connection alive with `Ping`, and
+//! hands out the server's notifications, unwrapping `Reliable` and `Batch`
+//! frames and acknowledging each delivery once it is read. When the
+//! connection drops it reconnects: a session opened with
+//! [`UlcSocket::open_session`] is resumed, so deliveries missed in between
+//! arrive with their original IDs and duplicates are skipped; without one,
+//! or when the server no longer has it, subscriptions are made again and a
+//! `ResyncRequired` is handed out, as notifications may have been lost.
+
+use super::{bearer, ClientError, Result, UlcClient};
+use crate::build_info;
+use crate::clients::ClientInfo;
+use crate::websocket::{decode_frame, Capability, Negotiated, WsMessage, PROTOCOL_VERSION};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// What a [`UlcSocket`] negotiates, and how it reconnects
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// Capabilities asked for in `Hello`
+    pub capabilities: Vec<Capability>,
+    /// How the connection is listed in `GET /api/admin/clients`
+    pub client: ClientInfo,
+    /// Reconnection attempts before a dropped connection is reported
+    pub reconnect_attempts: u32,
+    /// Delay before each reconnection attempt
+    pub reconnect_delay: Duration,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            capabilities: vec![Capability::ResumableSessions],
+            client: ClientInfo {
+                name: "universal-connector-client".to_string(),
+                version: Some(build_info::VERSION.to_string()),
+            },
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What to follow: one document, or every document whose URI matches a pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subscription {
+    Document(String),
+    /// URI glob or prefix, as in `Subscribe`
+    Pattern(String),
+}
+
+impl Subscription {
+    fn subscribe(&self, ack: bool) -> WsMessage {
+        let (document_id, pattern) = self.fields();
+        WsMessage::Subscribe { document_id, pattern, ack }
+    }
+
+    fn unsubscribe(&self) -> WsMessage {
+        let (document_id, pattern) = self.fields();
+        WsMessage::Unsubscribe { document_id, pattern }
+    }
+
+    fn fields(&self) -> (String, Option<String>) {
+        match self {
+            Self::Document(id) => (id.clone(), None),
+            Self::Pattern(pattern) => (String::new(), Some(pattern.clone())),
+        }
+    }
+}
+
+/// A WebSocket connection to the server
+pub struct UlcSocket {
+    client: UlcClient,
+    url: String,
+    options: SocketOptions,
+    socket: Socket,
+    negotiated: Negotiated,
+    heartbeat: Interval,
+    /// Session resumed after a reconnect, if one was opened
+    session_id: Option<String>,
+    /// Highest delivery ID handed out
+    last_delivery_id: u64,
+    /// Subscriptions to make again on a connection without the session
+    subscriptions: Vec<(Subscription, bool)>,
+    /// Messages read but not yet handed out
+    pending: VecDeque<WsMessage>,
+}
+
+impl std::fmt::Debug for UlcSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UlcSocket")
+            .field("url", &self.url)
+            .field("negotiated", &self.negotiated)
+            .field("session_id", &self.session_id)
+            .field("last_delivery_id", &self.last_delivery_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UlcSocket {
+    pub(super) async fn connect(client: UlcClient, url: String, options: SocketOptions) -> Result<Self> {
+        let (socket, negotiated) = handshake(&client, &url, &options).await?;
+        let heartbeat = heartbeat(&negotiated);
+        Ok(Self {
+            client,
+            url,
+            options,
+            socket,
+            negotiated,
+            heartbeat,
+            session_id: None,
+            last_delivery_id: 0,
+            subscriptions: Vec::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// What the handshake agreed
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    /// The session resumed after a reconnect, once opened
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Make this connection's session resumable, returning its ID
+    ///
+    /// Needs the `resumable_sessions` capability.
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Protocol`] where the server lacks the capability or does
+    /// not answer with a session.
+    pub async fn open_session(&mut self) -> Result<String> {
+        self.require(Capability::ResumableSessions)?;
+        let session_id = open_session(&mut self.socket, &mut self.pending).await?;
+        info!("WebSocket session {} opened on {}", session_id, self.url);
+        self.session_id = Some(session_id.clone());
+        Ok(session_id)
+    }
+
+    /// Follow the document with ID `document_id`
+    ///
+    /// # Errors
+    ///
+    /// As [`UlcSocket::follow`] does.
+    pub async fn subscribe(&mut self, document_id: &str, ack: bool) -> Result<()> {
+        self.follow(Subscription::Document(document_id.to_string()), ack).await
+    }
+
+    /// Follow every document whose URI matches `pattern`
+    ///
+    /// # Errors
+    ///
+    /// As [`UlcSocket::follow`] does.
+    pub async fn subscribe_pattern(&mut self, pattern: &str, ack: bool) -> Result<()> {
+        self.follow(Subscription::Pattern(pattern.to_string()), ack).await
+    }
+
+    /// Follow `subscription`; with `ack`, its notifications are redelivered after a reconnect
+    ///
+    /// Acknowledged subscriptions need an open session. The server answers
+    /// a subscription it refuses with an `Error` message.
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Protocol`] for an acknowledged subscription without a
+    /// session, and [`ClientError::WebSocket`] where the message cannot be
+    /// sent.
+    pub async fn follow(&mut self, subscription: Subscription, ack: bool) -> Result<()> {
+        if ack && self.session_id.is_none() {
+            return Err(ClientError::Protocol("Acknowledged subscriptions need an open session".to_string()));
+        }
+        self.send(&subscription.subscribe(ack)).await?;
+        self.subscriptions.retain(|(existing, _)| *existing != subscription);
+        self.subscriptions.push((subscription, ack));
+        Ok(())
+    }
+
+    /// Stop following `subscription`
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::WebSocket`] where the message cannot be sent.
+    pub async fn unfollow(&mut self, subscription: Subscription) -> Result<()> {
+        self.send(&subscription.unsubscribe()).await?;
+        self.subscriptions.retain(|(existing, _)| *existing != subscription);
+        Ok(())
+    }
+
+    /// Send any message, such as `GetMetrics` or a collab operation
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::WebSocket`] where the message cannot be sent.
+    pub async fn send(&mut self, message: &WsMessage) -> Result<()> {
+        send(&mut self.socket, message).await
+    }
+
+    /// Next message from the server
+    ///
+    /// Reconnects when the connection drops, failing once reconnecting
+    /// does. `Pong`s and session replies are consumed here; deliveries are
+    /// acknowledged as they are read.
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::Protocol`] where the server closes the connection for
+    /// good, and the error of the last reconnection attempt where none
+    /// succeeds.
+    pub async fn next(&mut self) -> Result<WsMessage> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            tokio::select! {
+                frame = self.socket.next() => match frame {
+                    Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => match decode(&frame) {
+                        Ok(message) => self.receive(message).await?,
+                        Err(e) => warn!("Ignoring malformed message from the server: {}", e),
+                    },
+                    Some(Ok(Message::Close(frame))) => {
+                        // A displaced or refused connection is not to be reconnected
+                        if let Some(frame) = frame.filter(|frame| u16::from(frame.code) >= 4000) {
+                            return Err(ClientError::Protocol(format!("Connection closed: {}", frame.reason)));
+                        }
+                        self.reconnect().await?;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => self.reconnect().await?,
+                },
+                _ = self.heartbeat.tick() => {
+                    if self.send(&WsMessage::Ping).await.is_err() {
+                        self.reconnect().await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Next message, or `None` if none arrives within `timeout`
+    ///
+    /// # Errors
+    ///
+    /// As [`UlcSocket::next`] does.
+    pub async fn next_within(&mut self, timeout: Duration) -> Result<Option<WsMessage>> {
+        tokio::time::timeout(timeout, self.next()).await.ok().transpose()
+    }
+
+    /// Close the connection; the session, if open, stays resumable until it expires
+    ///
+    /// # Errors
+    ///
+    /// [`ClientError::WebSocket`] where the close frame cannot be sent.
+    pub async fn close(mut self) -> Result<()> {
+        self.socket.close(None).await?;
+        Ok(())
+    }
+
+    /// Drop the connection and connect again, resuming the session if one is open
+    ///
+    /// # Errors
+    ///
+    /// The error of the last attempt where every one fails, as the options
+    /// allow.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let _ = self.socket.close(None).await;
+        let mut last_error = ClientError::Protocol("No reconnection attempts configured".to_string());
+        for attempt in 1..=self.options.reconnect_attempts {
+            tokio::time::sleep(self.options.reconnect_delay).await;
+            warn!("Reconnecting to {} (attempt {})", self.url, attempt);
+            match handshake(&self.client, &self.url, &self.options).await {
+                Ok((socket, negotiated)) => {
+                    self.socket = socket;
+                    self.heartbeat = heartbeat(&negotiated);
+                    self.negotiated = negotiated;
+                    return self.restore().await;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Resume the session on the new connection, or subscribe again without it
+    async fn restore(&mut self) -> Result<()> {
+        let lost = match self.session_id.clone() {
+            Some(session_id) => {
+                let resume = WsMessage::ResumeSession { session_id, last_delivery_id: self.last_delivery_id };
+                send(&mut self.socket, &resume).await?;
+                loop {
+                    match next_message(&mut self.socket).await? {
+                        WsMessage::SessionResumed { redelivered, .. } => {
+                            info!("WebSocket session resumed, {} messages redelivered", redelivered);
+                            return Ok(());
+                        }
+                        WsMessage::ResyncRequired { reason } => break reason,
+                        message => self.pending.push_back(message),
+                    }
+                }
+            }
+            None => "Reconnected without a session".to_string(),
+        };
+
+        // A new session numbers its deliveries from the start
+        if self.session_id.is_some() {
+            self.session_id = Some(open_session(&mut self.socket, &mut self.pending).await?);
+            self.last_delivery_id = 0;
+        }
+        for (subscription, ack) in self.subscriptions.clone() {
+            send(&mut self.socket, &subscription.subscribe(ack)).await?;
+        }
+        self.pending.push_back(WsMessage::ResyncRequired { reason: lost });
+        Ok(())
+    }
+
+    /// Queue what `message` holds for the caller, acknowledging deliveries
+    async fn receive(&mut self, message: WsMessage) -> Result<()> {
+        match message {
+            WsMessage::Reliable { delivery_id, message } => {
+                // Redelivered IDs were already handed out; only acknowledge them again
+                if delivery_id > self.last_delivery_id {
+                    self.last_delivery_id = delivery_id;
+                    Box::pin(self.receive(*message)).await?;
+                }
+                self.send(&WsMessage::Ack { delivery_id }).await?;
+            }
+            WsMessage::Batch { messages } => {
+                for message in messages {
+                    Box::pin(self.receive(message)).await?;
+                }
+            }
+            WsMessage::Pong => {}
+            message => self.pending.push_back(message),
+        }
+        Ok(())
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.negotiated.has(capability) {
+            Ok(())
+        } else {
+            Err(ClientError::Protocol(format!("Capability not negotiated: {}", capability.as_str())))
+        }
+    }
+}
+
+/// Connect and say `Hello`, fetching a new token once if the server refuses the one sent
+async fn handshake(client: &UlcClient, url: &str, options: &SocketOptions) -> Result<(Socket, Negotiated)> {
+    let token = client.credentials.token().await?;
+    let socket = match upgrade(url, token.as_deref()).await {
+        Err(ClientError::Unauthorized(_)) if client.credentials.can_refresh() => {
+            let token = client.credentials.refresh(token.as_deref()).await?;
+            upgrade(url, Some(&token)).await?
+        }
+        result => result?,
+    };
+    let mut socket = socket;
+
+    let hello = WsMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: options.capabilities.iter().map(|capability| capability.as_str().to_string()).collect(),
+        client: Some(options.client.clone()),
+    };
+    send(&mut socket, &hello).await?;
+    match next_message(&mut socket).await? {
+        WsMessage::Welcome(negotiated) => Ok((socket, negotiated)),
+        WsMessage::ProtocolViolation { message } | WsMessage::Error { message } => {
+            Err(ClientError::Protocol(format!("Handshake refused: {message}")))
+        }
+        _ => Err(ClientError::Protocol("Server did not answer the handshake".to_string())),
+    }
+}
+
+/// Open the connection, mapping a refused upgrade to the error of its status
+async fn upgrade(url: &str, token: Option<&str>) -> Result<Socket> {
+    let mut request = url.into_client_request()?;
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&bearer(token))
+            .map_err(|_| ClientError::Protocol("Token is not a valid header value".to_string()))?;
+        request.headers_mut().insert("Authorization", value);
+    }
+    match tokio_tungstenite::connect_async(request).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(tungstenite::Error::Http(response)) => {
+            let body = response.body().as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+            Err(ClientError::from_response(response.status().as_u16(), &body))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Ask for a session, keeping whatever arrives before the reply
+async fn open_session(socket: &mut Socket, pending: &mut VecDeque<WsMessage>) -> Result<String> {
+    send(socket, &WsMessage::OpenSession).await?;
+    loop {
+        match next_message(socket).await? {
+            WsMessage::SessionOpened { session_id } => return Ok(session_id),
+            WsMessage::ProtocolViolation { message } => return Err(ClientError::Protocol(message)),
+            message => pending.push_back(message),
+        }
+    }
+}
+
+/// Pings at the interval the server asked for, the first one interval from now
+fn heartbeat(negotiated: &Negotiated) -> Interval {
+    let period = Duration::from_secs(negotiated.limits.heartbeat_interval_secs.max(1));
+    let mut heartbeat = tokio::time::interval_at(Instant::now() + period, period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    heartbeat
+}
+
+async fn send(socket: &mut Socket, message: &WsMessage) -> Result<()> {
+    let text = serde_json::to_string(message).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    socket.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+fn decode(frame: &Message) -> Result<WsMessage> {
+    decode_frame(frame).map_err(ClientError::Protocol)
+}
+
+/// Next protocol message, skipping control frames
+async fn next_message(socket: &mut Socket) -> Result<WsMessage> {
+    loop {
+        match socket.next().await {
+            Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => return decode(&frame),
+            Some(Ok(Message::Close(frame))) => {
+                let reason = frame.map(|frame| frame.reason.to_string()).unwrap_or_default();
+                return Err(ClientError::Protocol(format!("Connection closed by the server: {reason}")));
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(ClientError::Protocol("Connection closed by the server".to_string())),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Client address resolution behind reverse proxies
+//!
+//! A connection's peer address is the proxy's when the server sits behind a
+//! load balancer. `X-Forwarded-For` carries the real client address, but any
+//! client can send that header, so it is only believed when the peer is a
+//! configured trusted proxy.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+
+/// A network in CIDR notation, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    fn parse(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().with_context(|| format!("Invalid proxy address: {s}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in {s}"))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+
+    fn max_len(&self) -> u8 {
+        if self.addr.is_ipv4() {
+            32
+        } else {
+            128
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 peers (dual-stack listeners) match IPv4 networks
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix_len == self.max_len() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix_len)
+        }
+    }
+}
+
+/// Proxies whose `X-Forwarded-For` headers are believed
+///
+/// Empty by default: with no trusted proxies the peer address is always
+/// the client address. Serialized as a list of addresses and networks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Parse a comma-separated list of addresses and CIDR networks
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first entry that is neither an address nor a CIDR network.
+    pub fn parse(list: &str) -> Result<Self> {
+        let networks = list
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Network::parse)
+            .collect::<Result<_>>()?;
+        Ok(Self { networks })
+    }
+
+    /// Whether `ip` belongs to a trusted proxy
+    #[must_use]
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Resolve the client address of a request received from `peer`
+    ///
+    /// Walks `X-Forwarded-For` from the nearest hop outwards and returns the
+    /// first address that is not a trusted proxy. Entries further out were
+    /// written by the client itself and are ignored. A malformed entry stops
+    /// the walk at the last address known to be genuine.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.is_trusted(peer) {
+            return client;
+        }
+
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        for hop in hops.iter().rev() {
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl Serialize for TrustedProxies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.networks.iter().map(ToString::to_string))
+    }
+}
+
+impl<'de> Deserialize<'de> for TrustedProxies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let list = Vec::<String>::deserialize(deserializer)?;
+        let networks = list
+            .iter()
+            .map(|network| Network::parse(network))
+            .collect::<Result<_>>()
+            .map_err(serde::de::Error::custom)?;
+        Ok(Self { networks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_header() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+        let headers = forwarded("203.0.113.7");
+        assert_eq!(proxies.resolve(ip("198.51.100.1"), &headers), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn test_trusted_chain_resolves_first_untrusted_hop() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 192.168.1.1").unwrap();
+        // The client spoofed 1.2.3.4; the real client is 203.0.113.7
+        let headers = forwarded("1.2.3.4, 203.0.113.7, 192.168.1.1");
+        assert_eq!(proxies.resolve(ip("10.1.2.3"), &headers), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_malformed_hop_stops_walk() {
+        let proxies = TrustedProxies::parse("10.0.0.1").unwrap();
+        let headers = forwarded("203.0.113.7, garbage");
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_network_matching() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8,fd00::/8").unwrap();
+        assert!(proxies.is_trusted(ip("10.255.0.1")));
+        assert!(proxies.is_trusted(ip("::ffff:10.0.0.1")));
+        assert!(proxies.is_trusted(ip("fd12::1")));
+        assert!(!proxies.is_trusted(ip("11.0.0.1")));
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_serialized_as_list() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 192.168.1.1, fd00::/8").unwrap();
+        let value = serde_json::to_value(&proxies).unwrap();
+        assert_eq!(value, serde_json::json!(["10.0.0.0/8", "192.168.1.1", "fd00::/8"]));
+        assert_eq!(serde_json::from_value::<TrustedProxies>(value).unwrap(), proxies);
+        assert!(serde_json::from_value::<TrustedProxies>(serde_json::json!(["10.0.0.0/40"])).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Who is connected, over which transport, and what they support
+//!
+//! Each transport registers a client with the [`ClientRegistry`] when it
+//! connects and keeps the [`RegisteredClient`] it gets back until the client
+//! goes; dropping it removes the record, so the registry holds connected
+//! clients only. LSP sessions fill in the name, version and capabilities
+//! from `initialize`, WebSocket connections from `Hello`, and HTTP
+//! connections the subject of each request's bearer token.
+//!
+//! `GET /api/admin/clients` lists the records. Features that vary per client,
+//! such as snippet completions, read the client's record through
+//! [`RegisteredClient::read`].
+
+use crate::monitoring::connections::Transport;
+use crate::monitoring::usage::Client;
+use chrono::{DateTime, SubsecRound, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Name and version a client gives for itself, as in LSP `clientInfo`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// How the columns of LSP positions are counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionEncoding {
+    /// UTF-16 code units, which every LSP client supports
+    #[default]
+    #[serde(rename = "utf-16")]
+    Utf16,
+    /// Bytes of UTF-8
+    #[serde(rename = "utf-8")]
+    Utf8,
+}
+
+impl PositionEncoding {
+    /// Name used in LSP `positionEncoding`
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Utf16 => "utf-16",
+            Self::Utf8 => "utf-8",
+        }
+    }
+
+    /// The UTF-16 column of `column`, counted in this encoding, on `line`
+    ///
+    /// Columns past the end of the line stay past it by the same amount.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // A character is at most four bytes
+    pub fn to_utf16(self, line: &str, column: u32) -> u32 {
+        if self == Self::Utf16 {
+            return column;
+        }
+        let (mut bytes, mut units) = (0, 0);
+        for c in line.chars() {
+            if bytes >= column {
+                return units;
+            }
+            bytes += c.len_utf8() as u32;
+            units += c.len_utf16() as u32;
+        }
+        units + column.saturating_sub(bytes)
+    }
+
+    /// The column in this encoding of UTF-16 `column` on `line`
+    ///
+    /// Columns past the end of the line stay past it by the same amount.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // A character is at most four bytes
+    pub fn from_utf16(self, line: &str, column: u32) -> u32 {
+        if self == Self::Utf16 {
+            return column;
+        }
+        let (mut bytes, mut units) = (0, 0);
+        for c in line.chars() {
+            if units >= column {
+                return bytes;
+            }
+            bytes += c.len_utf8() as u32;
+            units += c.len_utf16() as u32;
+        }
+        bytes + column.saturating_sub(units)
+    }
+}
+
+/// A connected client, as `GET /api/admin/clients` lists it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub id: String,
+    /// Such as `websocket` or `lsp_stdio`
+    pub transport: String,
+    /// Name the client gave, or the `client_name` claim of its token
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// As negotiated: LSP client capabilities, or the names of WebSocket capabilities agreed
+    pub capabilities: Value,
+    /// Encoding of LSP positions, once agreed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_encoding: Option<PositionEncoding>,
+    /// Authenticated subject; `None` is anonymous
+    pub subject: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// When the client last sent anything
+    pub last_active: DateTime<Utc>,
+}
+
+impl ClientRecord {
+    /// Whether the client accepts snippets in completions, as an LSP client declares
+    pub fn supports_snippets(&self) -> bool {
+        self.capabilities
+            .pointer("/textDocument/completion/completionItem/snippetSupport")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Everything but the last activity, which changes too often to take the lock
+    record: RwLock<ClientRecord>,
+    /// Milliseconds since the Unix epoch
+    last_active: AtomicI64,
+}
+
+impl Entry {
+    fn snapshot(&self) -> ClientRecord {
+        let mut record = self.record.read().expect("client record lock poisoned").clone();
+        let millis = self.last_active.load(Ordering::Relaxed);
+        record.last_active = DateTime::from_timestamp_millis(millis).unwrap_or(record.last_active);
+        record
+    }
+}
+
+type Entries = Arc<DashMap<String, Arc<Entry>>>;
+
+/// Clients connected over any transport
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    clients: Entries,
+}
+
+impl ClientRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a client connected over `transport`, accounted to `client`
+    ///
+    /// The record lasts as long as the returned guard.
+    #[must_use]
+    pub fn register(&self, transport: Transport, client: &Client) -> RegisteredClient {
+        let id = uuid::Uuid::new_v4().to_string();
+        // Activity is kept to the millisecond; connected_at must not sort after it
+        let now = Utc::now().trunc_subsecs(3);
+        let entry = Arc::new(Entry {
+            record: RwLock::new(ClientRecord {
+                id: id.clone(),
+                transport: transport.as_str().to_string(),
+                name: client.name.clone(),
+                version: None,
+                capabilities: Value::Null,
+                position_encoding: None,
+                subject: client.subject.clone(),
+                connected_at: now,
+                last_active: now,
+            }),
+            last_active: AtomicI64::new(now.timestamp_millis()),
+        });
+        self.clients.insert(id.clone(), Arc::clone(&entry));
+        RegisteredClient { id, entry, clients: Arc::clone(&self.clients) }
+    }
+
+    /// Connected clients, longest connected first
+    #[must_use]
+    pub fn list(&self) -> Vec<ClientRecord> {
+        let mut records: Vec<ClientRecord> = self.clients.iter().map(|entry| entry.snapshot()).collect();
+        records.sort_by(|a, b| a.connected_at.cmp(&b.connected_at).then_with(|| a.id.cmp(&b.id)));
+        records
+    }
+
+    /// The connected client with `id`
+    #[must_use]
+    pub fn get(&self, id: &str) -> Option<ClientRecord> {
+        self.clients.get(id).map(|entry| entry.snapshot())
+    }
+
+    /// Number of connected clients
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// A client's place in the [`ClientRegistry`], removed when dropped
+#[derive(Debug)]
+pub struct RegisteredClient {
+    id: String,
+    entry: Arc<Entry>,
+    clients: Entries,
+}
+
+impl RegisteredClient {
+    /// ID of the record
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Note that the client sent something
+    pub fn touch(&self) {
+        self.entry.last_active.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Change the record, such as once the client has said what it supports
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the record's lock.
+    pub fn update(&self, change: impl FnOnce(&mut ClientRecord)) {
+        change(&mut self.entry.record.write().expect("client record lock poisoned"));
+    }
+
+    /// Read the record, without copying it
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the record's lock.
+    pub fn read<T>(&self, read: impl FnOnce(&ClientRecord) -> T) -> T {
+        read(&self.entry.record.read().expect("client record lock poisoned"))
+    }
+
+    /// The record as it stands, last activity included
+    #[must_use]
+    pub fn record(&self) -> ClientRecord {
+        self.entry.snapshot()
+    }
+}
+
+impl Drop for RegisteredClient {
+    fn drop(&mut self) {
+        self.clients.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_records_last_only_while_registered() {
+        let registry = ClientRegistry::new();
+        let anonymous = registry.register(Transport::LspStdio, &Client::anonymous());
+        let client = Client { subject: Some("alice".to_string()), name: Some("vim-plugin".to_string()) };
+        let authenticated = registry.register(Transport::WebSocket, &client);
+        authenticated.update(|record| record.capabilities = json!(["collab"]));
+
+        let records = registry.list();
+        assert_eq!(records.len(), 2);
+        let record = registry.get(authenticated.id()).unwrap();
+        assert_eq!(record.transport, "websocket");
+        assert_eq!(record.subject.as_deref(), Some("alice"));
+        assert_eq!(record.name.as_deref(), Some("vim-plugin"));
+        assert_eq!(record.capabilities, json!(["collab"]));
+        assert_eq!(registry.get(anonymous.id()).unwrap().subject, None);
+
+        drop(anonymous);
+        assert_eq!(registry.len(), 1);
+        drop(authenticated);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_touch_moves_last_activity() {
+        let registry = ClientRegistry::new();
+        let client = registry.register(Transport::Http, &Client::anonymous());
+        client.entry.last_active.store(0, Ordering::Relaxed);
+        assert_eq!(client.record().last_active.timestamp(), 0);
+        client.touch();
+        assert!(client.record().last_active >= client.record().connected_at);
+    }
+
+    #[test]
+    fn test_snippet_support() {
+        let registry = ClientRegistry::new();
+        let client = registry.register(Transport::LspTcp, &Client::anonymous());
+        assert!(!client.read(ClientRecord::supports_snippets));
+        client.update(|record| {
+            let completion = json!({ "completionItem": { "snippetSupport": true } });
+            record.capabilities = json!({ "textDocument": { "completion": completion } });
+        });
+        assert!(client.read(ClientRecord::supports_snippets));
+    }
+
+    #[test]
+    fn test_position_encoding_columns() {
+        // "é" is two bytes and one UTF-16 unit, "😀" four bytes and two units
+        let line = "aé😀b";
+        for (utf8, utf16) in [(0, 0), (1, 1), (3, 2), (7, 4), (8, 5), (10, 7)] {
+            assert_eq!(PositionEncoding::Utf8.to_utf16(line, utf8), utf16);
+            assert_eq!(PositionEncoding::Utf8.from_utf16(line, utf16), utf8);
+        }
+        assert_eq!(PositionEncoding::Utf16.to_utf16(line, 3), 3);
+        assert_eq!(PositionEncoding::Utf16.from_utf16(line, 3), 3);
+        assert_eq!(serde_json::to_value(PositionEncoding::Utf8).unwrap(), json!("utf-8"));
+    }
+}
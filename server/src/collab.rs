@@ -0,0 +1,956 @@
+//! Concurrent editing for shared documents using operational transformation
+//!
+//! Documents are opt-in: once collaboration is enabled for a URI the server keeps
+//! an authoritative revision counter and an operation log. Clients submit
+//! operations tagged with the last server revision they have seen; the server
+//! transforms them against everything that happened since, applies them, and
+//! rebroadcasts with authoritative ordering. The converged content is written
+//! back to the [`DocumentStore`] after every operation.
+//!
+//! LSP editors take part as any other client does: each session keeps a
+//! [`CollabClient`] per document as its editor's copy, sends the editor's
+//! edits against the revision that copy is at, and pushes the operations of
+//! others to the editor as workspace edits.
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::document_store::DocumentStore;
+
+/// Maximum number of operations retained per document for transformation
+const MAX_HISTORY: usize = 1000;
+
+/// Language recorded for collaborative documents that did not exist in the store
+const DEFAULT_LANGUAGE: &str = "plaintext";
+
+/// A single component of a text operation
+///
+/// Lengths are measured in Unicode scalar values (Rust `char`s).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpComponent {
+    /// Skip over characters, leaving them unchanged
+    Retain(usize),
+    /// Insert text at the current position
+    Insert(String),
+    /// Delete characters at the current position
+    Delete(usize),
+}
+
+/// A text operation spanning an entire document
+///
+/// Operations are sequences of retain/insert/delete components that together
+/// cover every character of the document they apply to.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<OpComponent>", into = "Vec<OpComponent>")]
+pub struct TextOperation {
+    ops: Vec<OpComponent>,
+    base_len: usize,
+    target_len: usize,
+}
+
+impl From<Vec<OpComponent>> for TextOperation {
+    fn from(components: Vec<OpComponent>) -> Self {
+        let mut op = Self::new();
+        for component in components {
+            match component {
+                OpComponent::Retain(n) => op.retain(n),
+                OpComponent::Insert(s) => op.insert(&s),
+                OpComponent::Delete(n) => op.delete(n),
+            };
+        }
+        op
+    }
+}
+
+impl From<TextOperation> for Vec<OpComponent> {
+    fn from(op: TextOperation) -> Self {
+        op.ops
+    }
+}
+
+impl TextOperation {
+    /// Create an empty operation
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Components of this operation
+    #[must_use]
+    pub fn components(&self) -> &[OpComponent] {
+        &self.ops
+    }
+
+    /// Length of the document this operation applies to
+    #[must_use]
+    pub fn base_len(&self) -> usize {
+        self.base_len
+    }
+
+    /// Length of the document after applying this operation
+    #[must_use]
+    pub fn target_len(&self) -> usize {
+        self.target_len
+    }
+
+    /// Check whether the operation leaves the document unchanged
+    #[must_use]
+    pub fn is_noop(&self) -> bool {
+        self.ops.iter().all(|c| matches!(c, OpComponent::Retain(_)))
+    }
+
+    /// Retain `n` characters
+    pub fn retain(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        self.target_len += n;
+        if let Some(OpComponent::Retain(last)) = self.ops.last_mut() {
+            *last += n;
+        } else {
+            self.ops.push(OpComponent::Retain(n));
+        }
+        self
+    }
+
+    /// Insert `text` at the current position
+    pub fn insert(&mut self, text: &str) -> &mut Self {
+        if text.is_empty() {
+            return self;
+        }
+        self.target_len += text.chars().count();
+
+        // Keep inserts ahead of deletes so equivalent operations compare equal
+        let mut at = self.ops.len();
+        if let Some(OpComponent::Delete(_)) = self.ops.last() {
+            at -= 1;
+        }
+        if at > 0 {
+            if let OpComponent::Insert(prev) = &mut self.ops[at - 1] {
+                prev.push_str(text);
+                return self;
+            }
+        }
+        self.ops.insert(at, OpComponent::Insert(text.to_string()));
+        self
+    }
+
+    /// Delete `n` characters
+    pub fn delete(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        if let Some(OpComponent::Delete(last)) = self.ops.last_mut() {
+            *last += n;
+        } else {
+            self.ops.push(OpComponent::Delete(n));
+        }
+        self
+    }
+
+    /// Apply the operation to a document
+    ///
+    /// # Errors
+    ///
+    /// Fails where the document is not the length the operation applies to.
+    pub fn apply(&self, text: &str) -> Result<String> {
+        let mut chars = text.chars();
+        let mut consumed = 0;
+        let mut output = String::with_capacity(text.len());
+
+        for component in &self.ops {
+            match component {
+                OpComponent::Retain(n) => {
+                    for _ in 0..*n {
+                        output.push(chars.next().ok_or_else(|| anyhow!("Operation retains past end of document"))?);
+                    }
+                    consumed += n;
+                }
+                OpComponent::Insert(s) => output.push_str(s),
+                OpComponent::Delete(n) => {
+                    for _ in 0..*n {
+                        chars.next().ok_or_else(|| anyhow!("Operation deletes past end of document"))?;
+                    }
+                    consumed += n;
+                }
+            }
+        }
+
+        if consumed != self.base_len || chars.next().is_some() {
+            return Err(anyhow!(
+                "Operation base length {} does not match document length {}",
+                self.base_len,
+                text.chars().count()
+            ));
+        }
+
+        Ok(output)
+    }
+
+    /// Compose two consecutive operations into one with the same effect
+    ///
+    /// # Errors
+    ///
+    /// Fails where `other` does not apply to the document this one leaves.
+    pub fn compose(&self, other: &TextOperation) -> Result<TextOperation> {
+        if self.target_len != other.base_len {
+            return Err(anyhow!(
+                "Cannot compose: first target length {} != second base length {}",
+                self.target_len,
+                other.base_len
+            ));
+        }
+
+        let mut result = TextOperation::new();
+        let mut first = self.ops.iter().cloned();
+        let mut second = other.ops.iter().cloned();
+        let mut op1 = first.next();
+        let mut op2 = second.next();
+
+        loop {
+            match (op1.take(), op2.take()) {
+                (None, None) => break,
+                (Some(OpComponent::Delete(n)), b) => {
+                    result.delete(n);
+                    op1 = first.next();
+                    op2 = b;
+                }
+                (a, Some(OpComponent::Insert(s))) => {
+                    result.insert(&s);
+                    op1 = a;
+                    op2 = second.next();
+                }
+                (None, _) | (_, None) => return Err(anyhow!("Cannot compose: operations have mismatched lengths")),
+                (Some(OpComponent::Retain(a)), Some(OpComponent::Retain(b))) => {
+                    result.retain(a.min(b));
+                    (op1, op2) = split_lengths(a, b, OpComponent::Retain, OpComponent::Retain, &mut first, &mut second);
+                }
+                (Some(OpComponent::Insert(s)), Some(OpComponent::Delete(b))) => {
+                    let a = s.chars().count();
+                    (op1, op2) = match a.cmp(&b) {
+                        std::cmp::Ordering::Greater => (Some(OpComponent::Insert(s.chars().skip(b).collect())), second.next()),
+                        std::cmp::Ordering::Equal => (first.next(), second.next()),
+                        std::cmp::Ordering::Less => (first.next(), Some(OpComponent::Delete(b - a))),
+                    };
+                }
+                (Some(OpComponent::Insert(s)), Some(OpComponent::Retain(b))) => {
+                    let a = s.chars().count();
+                    (op1, op2) = match a.cmp(&b) {
+                        std::cmp::Ordering::Greater => {
+                            result.insert(&s.chars().take(b).collect::<String>());
+                            (Some(OpComponent::Insert(s.chars().skip(b).collect())), second.next())
+                        }
+                        std::cmp::Ordering::Equal => {
+                            result.insert(&s);
+                            (first.next(), second.next())
+                        }
+                        std::cmp::Ordering::Less => {
+                            result.insert(&s);
+                            (first.next(), Some(OpComponent::Retain(b - a)))
+                        }
+                    };
+                }
+                (Some(OpComponent::Retain(a)), Some(OpComponent::Delete(b))) => {
+                    result.delete(a.min(b));
+                    (op1, op2) = split_lengths(a, b, OpComponent::Retain, OpComponent::Delete, &mut first, &mut second);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Transform two concurrent operations against each other
+    ///
+    /// Returns `(a', b')` such that applying `a` then `b'` yields the same
+    /// document as applying `b` then `a'`. When both insert at the same
+    /// position, the insert from `a` is placed first.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the two do not apply to documents of the same length.
+    pub fn transform(a: &TextOperation, b: &TextOperation) -> Result<(TextOperation, TextOperation)> {
+        if a.base_len != b.base_len {
+            return Err(anyhow!(
+                "Cannot transform: base lengths differ ({} vs {})",
+                a.base_len,
+                b.base_len
+            ));
+        }
+
+        let mut a_prime = TextOperation::new();
+        let mut b_prime = TextOperation::new();
+        let mut first = a.ops.iter().cloned();
+        let mut second = b.ops.iter().cloned();
+        let mut op1 = first.next();
+        let mut op2 = second.next();
+
+        loop {
+            match (op1.take(), op2.take()) {
+                (None, None) => break,
+                (Some(OpComponent::Insert(s)), other) => {
+                    b_prime.retain(s.chars().count());
+                    a_prime.insert(&s);
+                    op1 = first.next();
+                    op2 = other;
+                }
+                (other, Some(OpComponent::Insert(s))) => {
+                    a_prime.retain(s.chars().count());
+                    b_prime.insert(&s);
+                    op1 = other;
+                    op2 = second.next();
+                }
+                (None, _) | (_, None) => return Err(anyhow!("Cannot transform: operations have mismatched lengths")),
+                (Some(OpComponent::Retain(x)), Some(OpComponent::Retain(y))) => {
+                    a_prime.retain(x.min(y));
+                    b_prime.retain(x.min(y));
+                    (op1, op2) = split_lengths(x, y, OpComponent::Retain, OpComponent::Retain, &mut first, &mut second);
+                }
+                (Some(OpComponent::Delete(x)), Some(OpComponent::Delete(y))) => {
+                    // Both sides deleted the same range; nothing left to do
+                    (op1, op2) = split_lengths(x, y, OpComponent::Delete, OpComponent::Delete, &mut first, &mut second);
+                }
+                (Some(OpComponent::Delete(x)), Some(OpComponent::Retain(y))) => {
+                    a_prime.delete(x.min(y));
+                    (op1, op2) = split_lengths(x, y, OpComponent::Delete, OpComponent::Retain, &mut first, &mut second);
+                }
+                (Some(OpComponent::Retain(x)), Some(OpComponent::Delete(y))) => {
+                    b_prime.delete(x.min(y));
+                    (op1, op2) = split_lengths(x, y, OpComponent::Retain, OpComponent::Delete, &mut first, &mut second);
+                }
+            }
+        }
+
+        Ok((a_prime, b_prime))
+    }
+
+    /// Build the operation turning `old` into `new` by trimming the common prefix and suffix
+    #[must_use]
+    pub fn from_diff(old: &str, new: &str) -> TextOperation {
+        let (range, replacement) = changed_range(old, new);
+        let mut op = TextOperation::new();
+        op.retain(range.start);
+        op.insert(&replacement);
+        op.delete(range.len());
+        op.retain(old.chars().count() - range.end);
+        op
+    }
+
+    /// Build the operation replacing a line/column range of `text` with `replacement`
+    ///
+    /// Columns are UTF-16 code units, as used by LSP `Position`s.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the range lies past the end of `text`, or ends before it starts.
+    pub fn from_range_edit(
+        text: &str,
+        start: (u32, u32),
+        end: (u32, u32),
+        replacement: &str,
+    ) -> Result<TextOperation> {
+        let start = position_to_offset(text, start.0, start.1)?;
+        let end = position_to_offset(text, end.0, end.1)?;
+        if end < start {
+            return Err(anyhow!("Edit range end precedes start"));
+        }
+
+        let total = text.chars().count();
+        let mut op = TextOperation::new();
+        op.retain(start);
+        op.insert(replacement);
+        op.delete(end - start);
+        op.retain(total - end);
+        Ok(op)
+    }
+}
+
+/// Advance both component streams past `min(a, b)` units, carrying the remainder forward
+fn split_lengths<I1, I2>(
+    a: usize,
+    b: usize,
+    make_a: fn(usize) -> OpComponent,
+    make_b: fn(usize) -> OpComponent,
+    first: &mut I1,
+    second: &mut I2,
+) -> (Option<OpComponent>, Option<OpComponent>)
+where
+    I1: Iterator<Item = OpComponent>,
+    I2: Iterator<Item = OpComponent>,
+{
+    match a.cmp(&b) {
+        std::cmp::Ordering::Greater => (Some(make_a(a - b)), second.next()),
+        std::cmp::Ordering::Equal => (first.next(), second.next()),
+        std::cmp::Ordering::Less => (first.next(), Some(make_b(b - a))),
+    }
+}
+
+/// The characters of `old` between the prefix and suffix it shares with `new`, and what `new` has instead
+#[must_use]
+pub fn changed_range(old: &str, new: &str) -> (std::ops::Range<usize>, String) {
+    let old_chars: Vec<char> = old.chars().collect();
+    let new_chars: Vec<char> = new.chars().collect();
+
+    let prefix = old_chars
+        .iter()
+        .zip(&new_chars)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_chars[prefix..]
+        .iter()
+        .rev()
+        .zip(new_chars[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let replacement = new_chars[prefix..new_chars.len() - suffix].iter().collect();
+    (prefix..old_chars.len() - suffix, replacement)
+}
+
+/// Convert a character offset into an LSP-style line/UTF-16 column position
+///
+/// Offsets past the end of `text` give its end.
+#[must_use]
+pub fn offset_to_position(text: &str, offset: usize) -> (u32, u32) {
+    let (mut line, mut character) = (0, 0);
+    for c in text.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += utf16_len(c);
+        }
+    }
+    (line, character)
+}
+
+/// The UTF-16 code units `c` takes, one or two
+#[allow(clippy::cast_possible_truncation)]
+fn utf16_len(c: char) -> u32 {
+    c.len_utf16() as u32
+}
+
+/// Convert an LSP-style line/UTF-16 column position into a character offset
+///
+/// Columns past the end of the line give its end.
+///
+/// # Errors
+///
+/// Fails where `line` lies past the end of `text`.
+pub fn position_to_offset(text: &str, line: u32, character: u32) -> Result<usize> {
+    let mut offset = 0;
+    let mut current_line = 0;
+    let mut chars = text.chars().peekable();
+
+    while current_line < line {
+        match chars.next() {
+            Some('\n') => current_line += 1,
+            Some(_) => {}
+            None => return Err(anyhow!("Line {line} is past end of document")),
+        }
+        offset += 1;
+    }
+
+    let mut units = 0;
+    while units < character {
+        match chars.peek() {
+            Some('\n') | None => break,
+            Some(c) => {
+                units += utf16_len(*c);
+                offset += 1;
+                chars.next();
+            }
+        }
+    }
+
+    Ok(offset)
+}
+
+/// Result of applying a client operation on the server
+#[derive(Debug, Clone)]
+pub struct AppliedOperation {
+    /// Server revision after applying the operation
+    pub revision: u64,
+    /// The operation as transformed onto the server history
+    pub operation: TextOperation,
+}
+
+/// Broadcast notification for an operation accepted by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollabEvent {
+    /// Document URI
+    pub uri: String,
+    /// Server revision after this operation
+    pub revision: u64,
+    /// Transformed operation to apply on top of `revision - 1`
+    pub operation: TextOperation,
+    /// Identifier of the client that submitted the operation
+    pub origin: String,
+}
+
+/// Server-side state of one collaborative document
+struct CollabDocument {
+    content: String,
+    revision: u64,
+    history: VecDeque<TextOperation>,
+}
+
+impl CollabDocument {
+    fn new(content: String) -> Self {
+        Self {
+            content,
+            revision: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Transform an operation based on `base_revision` onto the head and apply it
+    fn receive(&mut self, base_revision: u64, mut operation: TextOperation) -> Result<AppliedOperation> {
+        if base_revision > self.revision {
+            return Err(anyhow!(
+                "Revision {} is ahead of server revision {}",
+                base_revision,
+                self.revision
+            ));
+        }
+
+        let oldest = self.revision - self.history.len() as u64;
+        if base_revision < oldest {
+            return Err(anyhow!(
+                "Revision {base_revision} is too old to transform (oldest retained is {oldest}); resynchronize"
+            ));
+        }
+
+        let skip = usize::try_from(base_revision - oldest)?;
+        for concurrent in self.history.iter().skip(skip) {
+            operation = TextOperation::transform(&operation, concurrent)?.0;
+        }
+
+        self.content = operation.apply(&self.content)?;
+        self.revision += 1;
+        self.history.push_back(operation.clone());
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+
+        Ok(AppliedOperation {
+            revision: self.revision,
+            operation,
+        })
+    }
+}
+
+/// Manager for all documents in concurrent editing mode
+pub struct CollabManager {
+    documents: Arc<DocumentStore>,
+    sessions: DashMap<String, Mutex<CollabDocument>>,
+    events: broadcast::Sender<CollabEvent>,
+}
+
+impl CollabManager {
+    /// Create a manager writing converged content to `documents`
+    pub fn new(documents: Arc<DocumentStore>) -> Self {
+        let (events, _) = broadcast::channel(1024);
+        Self {
+            documents,
+            sessions: DashMap::new(),
+            events,
+        }
+    }
+
+    /// Enable concurrent editing for a document, returning its current revision and content
+    ///
+    /// The document is seeded from the store; enabling an already-enabled
+    /// document simply returns its state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the document's lock.
+    #[must_use]
+    pub fn enable(&self, uri: &str) -> (u64, String) {
+        let entry = self.sessions.entry(uri.to_string()).or_insert_with(|| {
+            let content = self.documents.get(uri).map(|d| d.content).unwrap_or_default();
+            Mutex::new(CollabDocument::new(content))
+        });
+        let doc = entry.lock().expect("collab document lock poisoned");
+        (doc.revision, doc.content.clone())
+    }
+
+    /// Leave concurrent editing mode for a document
+    pub fn disable(&self, uri: &str) {
+        self.sessions.remove(uri);
+    }
+
+    /// Check whether a document is in concurrent editing mode
+    #[must_use]
+    pub fn is_enabled(&self, uri: &str) -> bool {
+        self.sessions.contains_key(uri)
+    }
+
+    /// Current revision and content of a collaborative document
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the document's lock.
+    #[must_use]
+    pub fn snapshot(&self, uri: &str) -> Option<(u64, String)> {
+        self.sessions.get(uri).map(|entry| {
+            let doc = entry.lock().expect("collab document lock poisoned");
+            (doc.revision, doc.content.clone())
+        })
+    }
+
+    /// Subscribe to accepted operations across all collaborative documents
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<CollabEvent> {
+        self.events.subscribe()
+    }
+
+    /// Submit an operation from `origin` based on `base_revision`
+    ///
+    /// The operation is transformed, applied, written to the document store,
+    /// and broadcast to subscribers.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the document is not in concurrent editing mode, or the
+    /// operation does not apply to it as of `base_revision`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the document's lock.
+    pub fn submit(
+        &self,
+        uri: &str,
+        origin: &str,
+        base_revision: u64,
+        operation: TextOperation,
+    ) -> Result<AppliedOperation> {
+        let entry = self
+            .sessions
+            .get(uri)
+            .ok_or_else(|| anyhow!("Concurrent editing is not enabled for {uri}"))?;
+
+        // Hold the document lock across the store write so store content always
+        // matches the revision order seen by subscribers.
+        let mut doc = entry.lock().expect("collab document lock poisoned");
+        let applied = doc.receive(base_revision, operation)?;
+
+        let language = self
+            .documents
+            .get(uri)
+            .map_or_else(|| DEFAULT_LANGUAGE.to_string(), |d| d.language);
+        self.documents.upsert(uri.to_string(), doc.content.clone(), language);
+
+        // Ignore send errors (no subscribers)
+        let _ = self.events.send(CollabEvent {
+            uri: uri.to_string(),
+            revision: applied.revision,
+            operation: applied.operation.clone(),
+            origin: origin.to_string(),
+        });
+
+        Ok(applied)
+    }
+}
+
+/// Client-side synchronization state for one collaborative document
+///
+/// Implements the usual three-state client: synchronized, awaiting an
+/// acknowledgement, or awaiting an acknowledgement with further local edits
+/// buffered. Used by LSP sessions for their editors, by tests and by Rust
+/// clients of the WebSocket protocol.
+#[derive(Debug, Clone)]
+pub struct CollabClient {
+    /// Local document content
+    pub content: String,
+    /// Last server revision this client has seen
+    pub revision: u64,
+    outstanding: Option<TextOperation>,
+    buffer: Option<TextOperation>,
+}
+
+impl CollabClient {
+    /// Create a client from a server snapshot
+    #[must_use]
+    pub fn new(revision: u64, content: String) -> Self {
+        Self {
+            content,
+            revision,
+            outstanding: None,
+            buffer: None,
+        }
+    }
+
+    /// Apply a local edit; returns the operation to send if nothing is in flight
+    ///
+    /// # Errors
+    ///
+    /// Fails where the edit does not apply to the content.
+    pub fn apply_local(&mut self, operation: TextOperation) -> Result<Option<(u64, TextOperation)>> {
+        self.content = operation.apply(&self.content)?;
+        if self.outstanding.is_none() {
+            self.outstanding = Some(operation.clone());
+            return Ok(Some((self.revision, operation)));
+        }
+        self.buffer = Some(match self.buffer.take() {
+            Some(buffer) => buffer.compose(&operation)?,
+            None => operation,
+        });
+        Ok(None)
+    }
+
+    /// Handle the server acknowledging our outstanding operation
+    ///
+    /// Returns the buffered operation to send next, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails where no operation is outstanding.
+    pub fn server_ack(&mut self) -> Result<Option<(u64, TextOperation)>> {
+        if self.outstanding.take().is_none() {
+            return Err(anyhow!("Received acknowledgement with no outstanding operation"));
+        }
+        self.revision += 1;
+        self.outstanding = self.buffer.take();
+        Ok(self.outstanding.clone().map(|op| (self.revision, op)))
+    }
+
+    /// Apply an operation from another client, transforming it past local edits
+    ///
+    /// # Errors
+    ///
+    /// Fails where the operation does not apply to the content before them.
+    pub fn apply_server(&mut self, operation: TextOperation) -> Result<()> {
+        let mut operation = operation;
+        if let Some(outstanding) = self.outstanding.take() {
+            let (outstanding, op) = TextOperation::transform(&outstanding, &operation)?;
+            self.outstanding = Some(outstanding);
+            operation = op;
+        }
+        if let Some(buffer) = self.buffer.take() {
+            let (buffer, op) = TextOperation::transform(&buffer, &operation)?;
+            self.buffer = Some(buffer);
+            operation = op;
+        }
+        self.content = operation.apply(&self.content)?;
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// Check whether all local edits have been acknowledged
+    #[must_use]
+    pub fn is_synchronized(&self) -> bool {
+        self.outstanding.is_none() && self.buffer.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(components: Vec<OpComponent>) -> TextOperation {
+        components.into()
+    }
+
+    #[test]
+    fn test_apply_operation() {
+        let operation = op(vec![
+            OpComponent::Retain(6),
+            OpComponent::Insert("brave new ".to_string()),
+            OpComponent::Retain(5),
+        ]);
+        assert_eq!(operation.apply("Hello world").unwrap(), "Hello brave new world");
+        assert!(operation.apply("Hello").is_err());
+    }
+
+    #[test]
+    fn test_transform_concurrent_inserts() {
+        let doc = "abc";
+        let a = op(vec![OpComponent::Retain(1), OpComponent::Insert("X".to_string()), OpComponent::Retain(2)]);
+        let b = op(vec![OpComponent::Retain(1), OpComponent::Insert("Y".to_string()), OpComponent::Retain(2)]);
+
+        let (a_prime, b_prime) = TextOperation::transform(&a, &b).unwrap();
+        let left = b_prime.apply(&a.apply(doc).unwrap()).unwrap();
+        let right = a_prime.apply(&b.apply(doc).unwrap()).unwrap();
+        assert_eq!(left, right);
+        assert_eq!(left, "aXYbc");
+    }
+
+    #[test]
+    fn test_compose_matches_sequential_apply() {
+        let doc = "hello";
+        let a = TextOperation::from_diff(doc, "help");
+        let b = TextOperation::from_diff("help", "helpful!");
+        let composed = a.compose(&b).unwrap();
+        assert_eq!(composed.apply(doc).unwrap(), "helpful!");
+    }
+
+    #[test]
+    fn test_range_edit_utf16_columns() {
+        // "😀" is two UTF-16 code units but a single char
+        let text = "a😀b\nsecond";
+        let operation = TextOperation::from_range_edit(text, (0, 3), (1, 3), "-").unwrap();
+        assert_eq!(operation.apply(text).unwrap(), "a😀-ond");
+        assert_eq!(offset_to_position(text, 3), (0, 4));
+        assert_eq!(offset_to_position(text, 7), (1, 3));
+        assert_eq!(position_to_offset(text, 1, 3).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_operation_serialization() {
+        let operation = op(vec![OpComponent::Retain(2), OpComponent::Delete(1), OpComponent::Insert("z".to_string())]);
+        let json = serde_json::to_string(&operation).unwrap();
+        assert_eq!(json, r#"[{"retain":2},{"insert":"z"},{"delete":1}]"#);
+        let parsed: TextOperation = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, operation);
+    }
+
+    #[test]
+    fn test_stale_revision_is_transformed() {
+        let store = Arc::new(DocumentStore::new());
+        store.upsert("file:///shared.txt".to_string(), "base".to_string(), "plaintext".to_string());
+        let manager = CollabManager::new(Arc::clone(&store));
+        assert_eq!(manager.enable("file:///shared.txt"), (0, "base".to_string()));
+
+        manager
+            .submit("file:///shared.txt", "a", 0, TextOperation::from_diff("base", "base!"))
+            .unwrap();
+        let applied = manager
+            .submit("file:///shared.txt", "b", 0, TextOperation::from_diff("base", ">base"))
+            .unwrap();
+
+        assert_eq!(applied.revision, 2);
+        assert_eq!(store.get("file:///shared.txt").unwrap().content, ">base!");
+    }
+
+    /// Deterministic xorshift generator so failures are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            usize::try_from(self.next() % n as u64).unwrap()
+        }
+    }
+
+    fn random_edit(rng: &mut Rng, content: &str) -> TextOperation {
+        let len = content.chars().count();
+        let start = rng.below(len + 1);
+        let delete = rng.below(len - start + 1).min(3);
+        let insert: String = (0..rng.below(4))
+            .map(|_| ['a', 'b', 'é', '😀', '\n'][rng.below(5)])
+            .collect();
+
+        let mut operation = TextOperation::new();
+        operation.retain(start);
+        operation.insert(&insert);
+        operation.delete(delete);
+        operation.retain(len - start - delete);
+        operation
+    }
+
+    #[test]
+    fn test_randomized_convergence() {
+        const CLIENTS: usize = 4;
+        let uri = "file:///converge.txt";
+
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            let store = Arc::new(DocumentStore::new());
+            store.upsert(uri.to_string(), "shared text".to_string(), "plaintext".to_string());
+            let manager = CollabManager::new(Arc::clone(&store));
+            let (revision, content) = manager.enable(uri);
+            let mut events = manager.subscribe();
+
+            let mut clients: Vec<CollabClient> =
+                (0..CLIENTS).map(|_| CollabClient::new(revision, content.clone())).collect();
+            // Operations in flight to the server, and events not yet delivered to each client
+            let mut to_server: VecDeque<(usize, u64, TextOperation)> = VecDeque::new();
+            let mut to_client: Vec<VecDeque<CollabEvent>> = vec![VecDeque::new(); CLIENTS];
+
+            for _ in 0..300 {
+                match rng.below(3) {
+                    0 => {
+                        let c = rng.below(CLIENTS);
+                        let edit = random_edit(&mut rng, &clients[c].content);
+                        if let Some((rev, operation)) = clients[c].apply_local(edit).unwrap() {
+                            to_server.push_back((c, rev, operation));
+                        }
+                    }
+                    1 => {
+                        if let Some((c, rev, operation)) = to_server.pop_front() {
+                            manager.submit(uri, &c.to_string(), rev, operation).unwrap();
+                            while let Ok(event) = events.try_recv() {
+                                for queue in &mut to_client {
+                                    queue.push_back(event.clone());
+                                }
+                            }
+                        }
+                    }
+                    _ => {
+                        let c = rng.below(CLIENTS);
+                        if let Some(event) = to_client[c].pop_front() {
+                            if event.origin == c.to_string() {
+                                if let Some((rev, operation)) = clients[c].server_ack().unwrap() {
+                                    to_server.push_back((c, rev, operation));
+                                }
+                            } else {
+                                clients[c].apply_server(event.operation).unwrap();
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Drain everything still in flight
+            loop {
+                let mut progressed = false;
+                while let Some((c, rev, operation)) = to_server.pop_front() {
+                    manager.submit(uri, &c.to_string(), rev, operation).unwrap();
+                    progressed = true;
+                }
+                while let Ok(event) = events.try_recv() {
+                    for queue in &mut to_client {
+                        queue.push_back(event.clone());
+                    }
+                }
+                for (c, queue) in to_client.iter_mut().enumerate() {
+                    while let Some(event) = queue.pop_front() {
+                        progressed = true;
+                        if event.origin == c.to_string() {
+                            if let Some((rev, operation)) = clients[c].server_ack().unwrap() {
+                                to_server.push_back((c, rev, operation));
+                            }
+                        } else {
+                            clients[c].apply_server(event.operation).unwrap();
+                        }
+                    }
+                }
+                if !progressed {
+                    break;
+                }
+            }
+
+            let (_, server_content) = manager.snapshot(uri).unwrap();
+            for client in &clients {
+                assert!(client.is_synchronized());
+                assert_eq!(client.content, server_content, "seed {seed}");
+            }
+            assert_eq!(store.get(uri).unwrap().content, server_content);
+        }
+    }
+}
@@ -0,0 +1,878 @@
+//! Building a configuration in code
+//!
+//! Embedders build a [`ServerConfig`] through [`ServerConfig::builder`]
+//! rather than its fields, which are `#[non_exhaustive]` so that new
+//! settings do not break them. Related settings are set together through a
+//! group builder, and [`ServerConfigBuilder::build`] refuses the same
+//! problems startup does.
+//!
+//! ```
+//! use universal_connector_server::ServerConfig;
+//!
+//! let config = ServerConfig::builder()
+//!     .http(|http| http.addr("127.0.0.1:9000"))
+//!     .auth(|auth| auth.enabled(true).secret("Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY"))
+//!     .disable_websocket()
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(config.http_addr, "127.0.0.1:9000");
+//! assert!(!config.enable_websocket);
+//! ```
+
+use super::ConfigError;
+use crate::audit::AuditConfig;
+use crate::auth::csrf::CsrfConfig;
+use crate::auth::directory::LdapConfig;
+use crate::auth::lockout::LockoutConfig;
+use crate::auth::mtls::MtlsConfig;
+use crate::auth::oidc::OidcConfig;
+use crate::auth::policy::ScopePolicy;
+use crate::auth::refresh::TokenLifetimes;
+use crate::auth::roles::Roles;
+use crate::auth::saml::SamlConfig;
+use crate::auth::signing::SigningConfig;
+use crate::auth::{RateLimitConfig, TokenAlgorithm};
+use crate::formats::plugins::PluginConfig;
+use crate::formats::schema::SchemaConfig;
+use crate::jobs::JobsConfig;
+use crate::logging::{LogFileConfig, LogFormat, LoggingConfig};
+use crate::monitoring::alerts::SinkConfig;
+use crate::monitoring::rules::Rule;
+use crate::monitoring::slow_ops::{OpKind, SlowOpConfig};
+use crate::monitoring::statsd::StatsdConfig;
+use crate::monitoring::{AlertsConfig, LifecycleThresholds, MetricsConfig, UsageConfig};
+use crate::proxy::DownstreamConfig;
+use crate::scheduler::TasksConfig;
+use crate::{ConnectionLimits, FormatLimits, ServerConfig, TracingConfig, TrustedProxies};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings refused by [`ServerConfigBuilder::build`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidConfig {
+    /// Every error found; warnings are not refused
+    pub errors: Vec<ConfigError>,
+}
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self.errors.iter().map(ToString::to_string).collect();
+        f.write_str(&errors.join("\n"))
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// A [`ServerConfig`] under construction, from the defaults or an existing one
+///
+/// Starting from an existing configuration changes only what is set:
+///
+/// ```
+/// use universal_connector_server::config::ServerConfigBuilder;
+/// use universal_connector_server::ServerConfig;
+///
+/// let running = ServerConfig::default();
+/// let changed = ServerConfigBuilder::from(running.clone())
+///     .websocket(|ws| ws.max_connections(100))
+///     .build()
+///     .unwrap();
+/// assert_eq!(changed.ws_connection_limits.max_total, 100);
+/// assert_eq!(changed.http_addr, running.http_addr);
+/// ```
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfig {
+    /// Build a configuration starting from the defaults
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+}
+
+impl From<ServerConfig> for ServerConfigBuilder {
+    fn from(config: ServerConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ServerConfigBuilder {
+    /// The HTTP API listener
+    pub fn http(mut self, build: impl FnOnce(HttpBuilder) -> HttpBuilder) -> Self {
+        let http = build(HttpBuilder {
+            addr: std::mem::take(&mut self.config.http_addr),
+            enabled: self.config.enable_http,
+        });
+        self.config.http_addr = http.addr;
+        self.config.enable_http = http.enabled;
+        self
+    }
+
+    /// The WebSocket listener and its connection limits
+    pub fn websocket(mut self, build: impl FnOnce(WebSocketBuilder) -> WebSocketBuilder) -> Self {
+        let ws = build(WebSocketBuilder {
+            addr: std::mem::take(&mut self.config.ws_addr),
+            enabled: self.config.enable_websocket,
+            limits: self.config.ws_connection_limits.clone(),
+        });
+        self.config.ws_addr = ws.addr;
+        self.config.enable_websocket = ws.enabled;
+        self.config.ws_connection_limits = ws.limits;
+        self
+    }
+
+    /// The gRPC listener, served by builds with the `grpc` feature
+    pub fn grpc(mut self, build: impl FnOnce(GrpcBuilder) -> GrpcBuilder) -> Self {
+        let grpc = build(GrpcBuilder {
+            addr: std::mem::take(&mut self.config.grpc_addr),
+            enabled: self.config.enable_grpc,
+        });
+        self.config.grpc_addr = grpc.addr;
+        self.config.enable_grpc = grpc.enabled;
+        self
+    }
+
+    /// Token authentication
+    pub fn auth(mut self, build: impl FnOnce(AuthBuilder) -> AuthBuilder) -> Self {
+        let auth = build(AuthBuilder {
+            enabled: self.config.enable_auth,
+            anonymous_read_only: self.config.anonymous_read_only,
+            secret: std::mem::take(&mut self.config.jwt_secret),
+            algorithm: self.config.jwt_algorithm,
+            private_key_file: self.config.jwt_private_key_file.take(),
+            public_key_files: std::mem::take(&mut self.config.jwt_public_key_files),
+            oidc: self.config.oidc.take(),
+            mtls: self.config.mtls.take(),
+            request_signing: self.config.request_signing.take(),
+            ldap: self.config.ldap.take(),
+            saml: self.config.saml.take(),
+            lifetimes: self.config.tokens,
+            roles: std::mem::take(&mut self.config.roles),
+            policy: std::mem::take(&mut self.config.policy),
+        });
+        self.config.enable_auth = auth.enabled;
+        self.config.anonymous_read_only = auth.anonymous_read_only;
+        self.config.jwt_secret = auth.secret;
+        self.config.jwt_algorithm = auth.algorithm;
+        self.config.jwt_private_key_file = auth.private_key_file;
+        self.config.jwt_public_key_files = auth.public_key_files;
+        self.config.oidc = auth.oidc;
+        self.config.mtls = auth.mtls;
+        self.config.request_signing = auth.request_signing;
+        self.config.ldap = auth.ldap;
+        self.config.saml = auth.saml;
+        self.config.tokens = auth.lifetimes;
+        self.config.roles = auth.roles;
+        self.config.policy = auth.policy;
+        self
+    }
+
+    /// Do not serve LSP over stdio
+    pub fn disable_lsp(mut self) -> Self {
+        self.config.enable_lsp = false;
+        self
+    }
+
+    /// Do not serve the HTTP API
+    pub fn disable_http(mut self) -> Self {
+        self.config.enable_http = false;
+        self
+    }
+
+    /// Do not serve WebSocket clients
+    pub fn disable_websocket(mut self) -> Self {
+        self.config.enable_websocket = false;
+        self
+    }
+
+    /// Reverse proxies trusted to report client addresses
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.config.trusted_proxies = proxies;
+        self
+    }
+
+    /// Size caps on converted and validated documents
+    pub fn format_limits(mut self, build: impl FnOnce(FormatLimitsBuilder) -> FormatLimitsBuilder) -> Self {
+        self.config.format_limits = build(FormatLimitsBuilder(self.config.format_limits.clone())).0;
+        self
+    }
+
+    /// Directory for persisted state
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Format plugins and their sandbox limits
+    pub fn plugins(mut self, build: impl FnOnce(PluginsBuilder) -> PluginsBuilder) -> Self {
+        self.config.plugins = build(PluginsBuilder(self.config.plugins.clone())).0;
+        self
+    }
+
+    /// Lifecycle thresholds and the webhook told of transitions
+    pub fn lifecycle(mut self, build: impl FnOnce(LifecycleBuilder) -> LifecycleBuilder) -> Self {
+        let lifecycle = build(LifecycleBuilder {
+            thresholds: self.config.lifecycle_thresholds,
+            webhook: self.config.lifecycle_webhook.take(),
+        });
+        self.config.lifecycle_thresholds = lifecycle.thresholds;
+        self.config.lifecycle_webhook = lifecycle.webhook;
+        self
+    }
+
+    /// Alert rules and where their alerts go
+    pub fn alerting(mut self, build: impl FnOnce(AlertingBuilder) -> AlertingBuilder) -> Self {
+        let alerting = build(AlertingBuilder {
+            rules: std::mem::take(&mut self.config.alert_rules),
+            webhook: self.config.alert_webhook.take(),
+            alerts: std::mem::take(&mut self.config.alerts),
+        });
+        self.config.alert_rules = alerting.rules;
+        self.config.alert_webhook = alerting.webhook;
+        self.config.alerts = alerting.alerts;
+        self
+    }
+
+    /// Slow operation thresholds and logging
+    pub fn slow_ops(mut self, build: impl FnOnce(SlowOpsBuilder) -> SlowOpsBuilder) -> Self {
+        self.config.slow_ops = build(SlowOpsBuilder(self.config.slow_ops.clone())).0;
+        self
+    }
+
+    /// Log format, filter and destination
+    pub fn logging(mut self, build: impl FnOnce(LoggingBuilder) -> LoggingBuilder) -> Self {
+        self.config.logging = build(LoggingBuilder(std::mem::take(&mut self.config.logging))).0;
+        self
+    }
+
+    /// HTTP request limits per client IP, per subject, and for subjects holding `unlimited`
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.config.rate_limit = rate_limit;
+        self
+    }
+
+    /// Backoff and lockout after failed logins, per client IP and per username
+    pub fn lockout(mut self, lockout: LockoutConfig) -> Self {
+        self.config.lockout = lockout;
+        self
+    }
+
+    /// Access tokens in cookies for browser clients, and the CSRF protection they need
+    pub fn csrf(mut self, csrf: CsrfConfig) -> Self {
+        self.config.csrf = csrf;
+        self
+    }
+
+    /// Sinks of the security audit log
+    pub fn audit(mut self, audit: AuditConfig) -> Self {
+        self.config.audit = audit;
+        self
+    }
+
+    /// Per-client usage accounting
+    pub fn usage(mut self, usage: UsageConfig) -> Self {
+        self.config.usage = usage;
+        self
+    }
+
+    /// Metrics left unrecorded or sampled
+    pub fn metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// `StatsD` agent to push metrics to
+    pub fn statsd(mut self, statsd: StatsdConfig) -> Self {
+        self.config.statsd = Some(statsd);
+        self
+    }
+
+    /// OpenTelemetry trace export
+    pub fn tracing(mut self, tracing: TracingConfig) -> Self {
+        self.config.tracing = tracing;
+        self
+    }
+
+    /// Workers and per-class limits of the background job queue
+    pub fn jobs(mut self, jobs: JobsConfig) -> Self {
+        self.config.jobs = jobs;
+        self
+    }
+
+    /// Document expiry and schedule overrides of the built-in maintenance tasks
+    pub fn tasks(mut self, tasks: TasksConfig) -> Self {
+        self.config.tasks = tasks;
+        self
+    }
+
+    /// A language server to forward LSP requests about the documents it matches to
+    pub fn downstream(mut self, downstream: DownstreamConfig) -> Self {
+        self.config.downstreams.push(downstream);
+        self
+    }
+
+    /// A JSON Schema to load at startup
+    pub fn schema(mut self, schema: SchemaConfig) -> Self {
+        self.config.schemas.push(schema);
+        self
+    }
+
+    /// The configuration, unless it has errors [`ServerConfig::validate`] reports
+    ///
+    /// Warnings are not refused; [`ServerConfig::validate`] still lists them.
+    ///
+    /// ```
+    /// use universal_connector_server::ServerConfig;
+    ///
+    /// let error = ServerConfig::builder()
+    ///     .http(|http| http.addr("0.0.0.0:8080"))
+    ///     .websocket(|ws| ws.addr("0.0.0.0:8080"))
+    ///     .build()
+    ///     .unwrap_err();
+    /// assert_eq!(error.errors[0].path, "ws_addr");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// [`InvalidConfig`] with every error [`ServerConfig::validate`] finds.
+    pub fn build(self) -> Result<ServerConfig, InvalidConfig> {
+        match self.config.validate() {
+            Ok(()) => Ok(self.config),
+            Err(problems) => {
+                let errors: Vec<ConfigError> = problems.into_iter().filter(|problem| !problem.warning).collect();
+                if errors.is_empty() {
+                    Ok(self.config)
+                } else {
+                    Err(InvalidConfig { errors })
+                }
+            }
+        }
+    }
+}
+
+/// HTTP API settings, for [`ServerConfigBuilder::http`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct HttpBuilder {
+    addr: String,
+    enabled: bool,
+}
+
+impl HttpBuilder {
+    /// Bind address, such as `127.0.0.1:8080`
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Whether the HTTP API is served
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+}
+
+/// gRPC settings, for [`ServerConfigBuilder::grpc`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct GrpcBuilder {
+    addr: String,
+    enabled: bool,
+}
+
+impl GrpcBuilder {
+    /// Bind address, such as `127.0.0.1:50051`
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Whether gRPC clients are served
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+}
+
+/// WebSocket settings, for [`ServerConfigBuilder::websocket`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct WebSocketBuilder {
+    addr: String,
+    enabled: bool,
+    limits: ConnectionLimits,
+}
+
+impl WebSocketBuilder {
+    /// Bind address, such as `127.0.0.1:8081`
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Whether WebSocket clients are served
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Connections across all clients
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_total = max;
+        self
+    }
+
+    /// Connections per authenticated subject
+    pub fn max_per_subject(mut self, max: usize) -> Self {
+        self.limits.max_per_subject = max;
+        self
+    }
+
+    /// Connections per client IP
+    pub fn max_per_ip(mut self, max: usize) -> Self {
+        self.limits.max_per_ip = max;
+        self
+    }
+
+    /// Displace a subject's longest idle connection instead of refusing a new one
+    pub fn displace_idle(mut self, displace: bool) -> Self {
+        self.limits.displace_idle = displace;
+        self
+    }
+
+    /// Delay suggested to refused clients
+    pub fn retry_after(mut self, delay: Duration) -> Self {
+        self.limits.retry_after = delay;
+        self
+    }
+}
+
+/// Authentication settings, for [`ServerConfigBuilder::auth`]
+#[derive(Clone)]
+#[must_use]
+pub struct AuthBuilder {
+    enabled: bool,
+    anonymous_read_only: bool,
+    secret: String,
+    algorithm: TokenAlgorithm,
+    private_key_file: Option<PathBuf>,
+    public_key_files: Vec<PathBuf>,
+    oidc: Option<OidcConfig>,
+    mtls: Option<MtlsConfig>,
+    request_signing: Option<SigningConfig>,
+    ldap: Option<LdapConfig>,
+    saml: Option<SamlConfig>,
+    lifetimes: TokenLifetimes,
+    roles: Roles,
+    policy: ScopePolicy,
+}
+
+impl AuthBuilder {
+    /// Whether requests need a token
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Let callers without credentials read, and nothing more, whether requests need a token or not
+    pub fn anonymous_read_only(mut self, read_only: bool) -> Self {
+        self.anonymous_read_only = read_only;
+        self
+    }
+
+    /// Secret tokens are signed with; at least 32 random characters
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.secret = secret.into();
+        self
+    }
+
+    /// Algorithm tokens are signed and verified with; the secret is used only with HS256
+    pub fn algorithm(mut self, algorithm: TokenAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// PEM file of the private key RS256 or ES256 tokens are signed with
+    pub fn private_key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.private_key_file = Some(path.into());
+        self
+    }
+
+    /// Also accept RS256 or ES256 tokens signed by the owner of the PEM public key in `path`
+    pub fn public_key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.public_key_files.push(path.into());
+        self
+    }
+
+    /// Also accept the tokens of an `OpenID` Connect provider
+    pub fn oidc(mut self, oidc: OidcConfig) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    /// Serve HTTP and WebSocket over TLS, accepting client certificates in place of tokens
+    pub fn mtls(mut self, mtls: MtlsConfig) -> Self {
+        self.mtls = Some(mtls);
+        self
+    }
+
+    /// Also accept requests signed by the clients of `signing`, for machines that cannot hold tokens
+    pub fn request_signing(mut self, signing: SigningConfig) -> Self {
+        self.request_signing = Some(signing);
+        self
+    }
+
+    /// Serve `/auth/login`, checking usernames and passwords against the directory of `ldap`
+    pub fn ldap(mut self, ldap: LdapConfig) -> Self {
+        self.ldap = Some(ldap);
+        self
+    }
+
+    /// Serve `/auth/saml/acs`, signing in the users whose assertions the identity provider of `saml` signs
+    pub fn saml(mut self, saml: SamlConfig) -> Self {
+        self.saml = Some(saml);
+        self
+    }
+
+    /// How long access tokens issued at `/auth/refresh` last, and refresh tokens left unexchanged
+    pub fn token_lifetimes(mut self, access: Duration, refresh: Duration) -> Self {
+        self.lifetimes.access = access;
+        self.lifetimes.refresh = refresh;
+        self
+    }
+
+    /// Define a role granting `scopes` to credentials holding `role:<name>`, alongside the default roles
+    pub fn role(mut self, name: impl Into<String>, scopes: &[&str]) -> Self {
+        self.roles = self.roles.with(name, scopes.iter().map(ToString::to_string).collect());
+        self
+    }
+
+    /// Require `scopes`, on top of their own, of requests matching `route`, such as `DELETE /api/documents/:id`
+    pub fn require_for_route(mut self, route: impl Into<String>, scopes: &[&str]) -> Self {
+        self.policy = self.policy.route(route, scopes.iter().map(ToString::to_string).collect());
+        self
+    }
+
+    /// Require `scopes`, on top of their own, of WebSocket messages of type `message`, such as `Lsp`
+    pub fn require_for_message(mut self, message: impl Into<String>, scopes: &[&str]) -> Self {
+        self.policy = self.policy.message(message, scopes.iter().map(ToString::to_string).collect());
+        self
+    }
+}
+
+impl fmt::Debug for AuthBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthBuilder")
+            .field("enabled", &self.enabled)
+            .field("anonymous_read_only", &self.anonymous_read_only)
+            .field("algorithm", &self.algorithm)
+            .field("private_key_file", &self.private_key_file)
+            .field("public_key_files", &self.public_key_files)
+            .field("oidc", &self.oidc)
+            .field("mtls", &self.mtls)
+            .field("request_signing", &self.request_signing)
+            .field("ldap", &self.ldap)
+            .field("saml", &self.saml)
+            .field("lifetimes", &self.lifetimes)
+            .field("roles", &self.roles)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Document size caps, for [`ServerConfigBuilder::format_limits`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct FormatLimitsBuilder(FormatLimits);
+
+impl FormatLimitsBuilder {
+    /// Largest document accepted, in bytes
+    pub fn max_input_bytes(mut self, max: usize) -> Self {
+        self.0.max_input_bytes = max;
+        self
+    }
+
+    /// Largest converted document returned, in bytes
+    pub fn max_output_bytes(mut self, max: usize) -> Self {
+        self.0.max_output_bytes = max;
+        self
+    }
+}
+
+/// Format plugin settings, for [`ServerConfigBuilder::plugins`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct PluginsBuilder(PluginConfig);
+
+impl PluginsBuilder {
+    /// Directory whose `*.wasm` plugins are loaded at startup
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.0.dir = Some(dir.into());
+        self
+    }
+
+    /// Largest memory a plugin may use, in bytes
+    pub fn max_memory_bytes(mut self, max: usize) -> Self {
+        self.0.max_memory_bytes = max;
+        self
+    }
+
+    /// Instructions a plugin may execute per call, as wasmtime fuel
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.0.fuel = fuel;
+        self
+    }
+}
+
+/// Lifecycle settings, for [`ServerConfigBuilder::lifecycle`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct LifecycleBuilder {
+    thresholds: LifecycleThresholds,
+    webhook: Option<String>,
+}
+
+impl LifecycleBuilder {
+    /// Failing evaluations that move Ready to Degraded
+    pub fn degrade_after(mut self, evaluations: u32) -> Self {
+        self.thresholds.degrade_after = evaluations;
+        self
+    }
+
+    /// Healthy evaluations that move Degraded back to Ready
+    pub fn recover_after(mut self, evaluations: u32) -> Self {
+        self.thresholds.recover_after = evaluations;
+        self
+    }
+
+    /// Unhealthy evaluations after which a Degraded server stops being ready
+    pub fn unready_after(mut self, evaluations: u32) -> Self {
+        self.thresholds.unready_after = evaluations;
+        self
+    }
+
+    /// Unhealthy evaluations after which a Degraded server stops being live
+    pub fn restart_after(mut self, evaluations: u32) -> Self {
+        self.thresholds.restart_after = evaluations;
+        self
+    }
+
+    /// URL notified of every transition
+    pub fn webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+}
+
+/// Alert settings, for [`ServerConfigBuilder::alerting`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct AlertingBuilder {
+    rules: Vec<Rule>,
+    webhook: Option<String>,
+    alerts: AlertsConfig,
+}
+
+impl AlertingBuilder {
+    /// Add a threshold rule
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// URL notified when a rule fires or resolves
+    pub fn webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+
+    /// Add a sink notified of firing and resolved alerts
+    pub fn sink(mut self, sink: SinkConfig) -> Self {
+        self.alerts.sinks.push(sink);
+        self
+    }
+
+    /// Time after which a rule still firing is notified again; 0 never
+    pub fn renotify_secs(mut self, secs: u64) -> Self {
+        self.alerts.renotify_secs = secs;
+        self
+    }
+
+    /// Attempts at each delivery, the first included
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.alerts.max_attempts = attempts;
+        self
+    }
+
+    /// Wait before the first retry, doubled for each one after
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.alerts.retry_backoff_ms = u64::try_from(backoff.as_millis()).unwrap_or(u64::MAX);
+        self
+    }
+}
+
+/// Slow operation settings, for [`ServerConfigBuilder::slow_ops`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct SlowOpsBuilder(SlowOpConfig);
+
+impl SlowOpsBuilder {
+    /// Duration from which an operation of `kind` counts as slow
+    pub fn threshold(mut self, kind: OpKind, threshold: Duration) -> Self {
+        self.0.thresholds.set(kind, threshold);
+        self
+    }
+
+    /// Slow operations kept for the admin endpoint
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.0.capacity = capacity;
+        self
+    }
+
+    /// Records logged per kind per minute; zero logs none
+    pub fn max_logs_per_minute(mut self, max: u32) -> Self {
+        self.0.max_logs_per_minute = max;
+        self
+    }
+}
+
+/// Logging settings, for [`ServerConfigBuilder::logging`]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct LoggingBuilder(LoggingConfig);
+
+impl LoggingBuilder {
+    /// Record layout
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.0.format = format;
+        self
+    }
+
+    /// Default level, such as `info`
+    pub fn level(mut self, level: impl Into<String>) -> Self {
+        self.0.level = level.into();
+        self
+    }
+
+    /// Add a per-module override, such as `tower_http=debug`
+    pub fn directive(mut self, directive: impl Into<String>) -> Self {
+        self.0.directives.push(directive.into());
+        self
+    }
+
+    /// Write to a rotated file instead of stderr
+    pub fn file(mut self, file: LogFileConfig) -> Self {
+        self.0.file = Some(file);
+        self
+    }
+
+    /// Include the fields of enclosing spans in JSON records
+    pub fn include_spans(mut self, include: bool) -> Self {
+        self.0.include_spans = include;
+        self
+    }
+
+    /// File holding the filter to apply on SIGHUP
+    pub fn filter_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.0.filter_file = Some(path.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY";
+
+    fn paths(error: &InvalidConfig) -> Vec<&str> {
+        error.errors.iter().map(|error| error.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_defaults_build() {
+        assert_eq!(ServerConfig::builder().build().unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn test_groups_set_their_settings() {
+        let config = ServerConfig::builder()
+            .http(|http| http.addr("127.0.0.1:9000"))
+            .websocket(|ws| ws.addr("127.0.0.1:9001").max_per_ip(4).displace_idle(true))
+            .auth(|auth| auth.enabled(true).secret(SECRET))
+            .format_limits(|limits| limits.max_input_bytes(1024))
+            .lifecycle(|lifecycle| lifecycle.degrade_after(5).webhook("https://hooks.example.com/lifecycle"))
+            .alerting(|alerting| alerting.renotify_secs(0).retry_backoff(Duration::from_millis(250)))
+            .slow_ops(|slow| slow.threshold(OpKind::Store, Duration::ZERO).capacity(5))
+            .logging(|logging| logging.format(LogFormat::Json).level("debug").directive("hyper=warn"))
+            .disable_lsp()
+            .build()
+            .unwrap();
+
+        // Groups set later keep what earlier ones set
+        assert_eq!((config.http_addr.as_str(), config.ws_addr.as_str()), ("127.0.0.1:9000", "127.0.0.1:9001"));
+        assert!(config.enable_http && config.enable_websocket && !config.enable_lsp);
+        assert_eq!(config.ws_connection_limits.max_per_ip, 4);
+        assert!(config.ws_connection_limits.displace_idle);
+        assert_eq!(config.ws_connection_limits.max_total, ConnectionLimits::default().max_total);
+        assert!(config.enable_auth);
+        assert_eq!(config.jwt_secret, SECRET);
+        assert_eq!(config.format_limits.max_input_bytes, 1024);
+        assert_eq!(config.lifecycle_thresholds.degrade_after, 5);
+        assert!(config.lifecycle_webhook.is_some());
+        assert_eq!((config.alerts.renotify_secs, config.alerts.retry_backoff_ms), (0, 250));
+        assert_eq!(config.slow_ops.thresholds.get(OpKind::Store), Duration::ZERO);
+        assert_eq!(config.slow_ops.capacity, 5);
+        assert_eq!(config.logging.filter_spec(), "debug,hyper=warn");
+    }
+
+    #[test]
+    fn test_rebuild_changes_only_what_is_set() {
+        let running = ServerConfig::builder()
+            .http(|http| http.addr("127.0.0.1:9000"))
+            .disable_websocket()
+            .build()
+            .unwrap();
+        let changed = ServerConfigBuilder::from(running.clone())
+            .http(|http| http.enabled(false))
+            .build()
+            .unwrap();
+        assert_eq!(changed.http_addr, "127.0.0.1:9000");
+        assert!(!changed.enable_http && !changed.enable_websocket);
+        assert_eq!(ServerConfigBuilder::from(running.clone()).build().unwrap(), running);
+    }
+
+    #[test]
+    fn test_invalid_combinations_refused() {
+        let clash = ServerConfig::builder()
+            .http(|http| http.addr("0.0.0.0:9000"))
+            .websocket(|ws| ws.addr("127.0.0.1:9000"))
+            .build()
+            .unwrap_err();
+        assert_eq!(paths(&clash), ["ws_addr"]);
+
+        // Auth on with the development secret, and a limit of nothing
+        let error = ServerConfig::builder()
+            .auth(|auth| auth.enabled(true))
+            .websocket(|ws| ws.max_per_subject(0))
+            .build()
+            .unwrap_err();
+        assert_eq!(paths(&error), ["jwt_secret", "ws_connection_limits.max_per_subject"]);
+        assert!(error.to_string().contains('\n'), "{error}");
+        assert!(!error.to_string().contains("dev-secret"), "{error}");
+
+        let error = ServerConfig::builder().logging(|logging| logging.directive("hyper=loud")).build().unwrap_err();
+        assert_eq!(paths(&error), ["logging"]);
+        let error = ServerConfig::builder().alerting(|alerting| alerting.max_attempts(0)).build().unwrap_err();
+        assert_eq!(paths(&error), ["alerts"]);
+    }
+
+    #[test]
+    fn test_warnings_do_not_fail_the_build() {
+        let config = ServerConfig::builder().disable_lsp().disable_http().disable_websocket().build().unwrap();
+        assert!(config.validate().unwrap_err().iter().all(|problem| problem.warning));
+    }
+}
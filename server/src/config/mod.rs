@@ -0,0 +1,1261 @@
+//! Configuration files
+//!
+//! [`ServerConfig::from_file`] reads the whole server configuration from a
+//! TOML or YAML file, picked by its extension. Every setting has a default,
+//! so a file only needs what differs from it. Keys the server does not know
+//! are reported as warnings with their path, such as `logging.levle`,
+//! rather than refused. Syntax and type errors give the line and column.
+//!
+//! Durations are written as a number and a unit: `250ms`, `5s`, `10m` or
+//! `1h`. [`ServerConfig::example_toml`] writes a commented reference file
+//! with every setting at its default.
+//!
+//! [`ServerConfig::load`] layers `ULC_` environment variables over the file,
+//! so one setting can change without editing it: `ULC_HTTP_ADDR` sets
+//! `http_addr` and `ULC_WS_CONNECTION_LIMITS__MAX_TOTAL` sets
+//! `ws_connection_limits.max_total`.
+//!
+//! Secret settings may name where they are kept instead, as `env:NAME`,
+//! `file:/path` or `vault:path#field`; see [`secrets`].
+//!
+//! A running server reads its configuration again on SIGHUP; [`Reload`]
+//! sorts the changes into those applied at once and those needing a restart.
+//!
+//! Code embedding the server builds its configuration with
+//! [`ServerConfig::builder`], which refuses what startup would.
+
+mod builder;
+pub mod reload;
+pub mod secrets;
+mod validate;
+
+pub use self::builder::{
+    AlertingBuilder, AuthBuilder, FormatLimitsBuilder, GrpcBuilder, HttpBuilder, InvalidConfig, LifecycleBuilder,
+    LoggingBuilder, PluginsBuilder, ServerConfigBuilder, SlowOpsBuilder, WebSocketBuilder,
+};
+pub use self::reload::Reload;
+pub use self::secrets::{SecretReader, SecretSource};
+pub use self::validate::ConfigError;
+pub(crate) use self::validate::is_development_secret;
+
+use crate::auth::TierLimits;
+use crate::formats::{self, ExtendedFormat};
+use crate::monitoring::alerts::redact_url;
+use crate::ServerConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Prefix of the environment variables overriding settings
+pub const ENV_PREFIX: &str = "ULC_";
+
+/// Settings that may instead be read from the file a `_FILE` variable names
+const SECRETS: &[&str] = &["jwt_secret"];
+
+/// Stands in for secrets in [`ServerConfig::redacted`]
+const REDACTED: &str = "<redacted>";
+
+/// A configuration read from text, with the keys it did not use
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: ServerConfig,
+    /// Paths of unknown keys, such as `logging.levle` or `alert_rules[0].treshold`
+    pub unknown_keys: Vec<String>,
+    /// `ULC_` variables naming no setting
+    pub unknown_variables: Vec<String>,
+    /// The layer each setting came from, by path
+    pub sources: Vec<(String, Source)>,
+    /// Problems that do not stop the server, unless it is strict
+    pub warnings: Vec<ConfigError>,
+}
+
+/// A command-line flag setting one setting, above every other layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flag {
+    /// The flag as given, such as `--http-addr`
+    pub name: String,
+    /// Path of the setting, such as `http_addr` or `logging.level`
+    pub setting: String,
+    pub value: String,
+}
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    /// The variable setting it, or the section holding it
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => f.write_str("default"),
+            Self::File => f.write_str("file"),
+            Self::Env(name) | Self::Flag(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A `ULC_` variable or flag, with the setting it overrides
+#[derive(Debug, Clone)]
+struct Override {
+    source: Source,
+    path: Vec<String>,
+    value: String,
+}
+
+impl From<&Flag> for Override {
+    fn from(flag: &Flag) -> Self {
+        Self {
+            source: Source::Flag(flag.name.clone()),
+            path: flag.setting.split('.').map(str::to_string).collect(),
+            value: flag.value.clone(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read the configuration from a `.toml`, `.yaml` or `.yml` file, warning of unknown keys
+    ///
+    /// # Errors
+    ///
+    /// As [`ServerConfig::load_file`] does.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let loaded = Self::load_file(path)?;
+        for key in &loaded.unknown_keys {
+            warn!("Unknown configuration key {} in {}", key, path.display());
+        }
+        Ok(loaded.config)
+    }
+
+    /// Read the configuration from a file, returning unknown keys rather than logging them
+    ///
+    /// # Errors
+    ///
+    /// Fails where the file cannot be read, does not parse, or holds a setting
+    /// of the wrong type.
+    pub fn load_file(path: &Path) -> Result<LoadedConfig> {
+        Self::layered(Some(path), std::iter::empty(), &[])
+    }
+
+    /// Layer the defaults, the configuration file, the `ULC_` variables and `flags`
+    ///
+    /// The file is `file` when given, otherwise the one `CONFIG_FILE` names.
+    ///
+    /// # Errors
+    ///
+    /// As [`ServerConfig::layered`] does.
+    pub fn load(file: Option<&Path>, flags: &[Flag]) -> Result<LoadedConfig> {
+        let file = file.map(Path::to_path_buf).or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from));
+        Self::layered(file.as_deref(), std::env::vars(), flags)
+    }
+
+    /// Layer the variables in `vars` starting with `ULC_`, then `flags`, over `file` or the defaults
+    ///
+    /// `ULC_LOGGING__LEVEL` sets `logging.level`: `__` separates sections.
+    /// Values take the type of the setting, lists are comma-separated, and
+    /// sections and lists of sections are JSON. `ULC_JWT_SECRET_FILE` reads
+    /// the secret from a file instead. Secrets given as `env:` references
+    /// are read from `vars` too.
+    ///
+    /// # Errors
+    ///
+    /// Fails where `file` cannot be read or parsed, a variable or flag does not
+    /// parse as its setting, or a secret reference cannot be read.
+    pub fn layered(
+        file: Option<&Path>,
+        vars: impl IntoIterator<Item = (String, String)>,
+        flags: &[Flag],
+    ) -> Result<LoadedConfig> {
+        let (config, raw) = match file {
+            Some(path) => Self::read(path)?,
+            None => (Self::default(), Value::Null),
+        };
+        let vars: Vec<_> = vars.into_iter().collect();
+        let secrets = SecretReader::new(&vars);
+        let mut overrides = overrides(&vars, &secrets)?;
+        overrides.extend(flags.iter().map(Override::from));
+        layer(config, &raw, &overrides, &secrets)
+    }
+
+    /// Apply `flags` to a configuration built otherwise, reporting its other settings as defaults
+    ///
+    /// # Errors
+    ///
+    /// Fails where a flag does not parse as its setting, or a secret reference
+    /// cannot be read.
+    pub fn with_flags(self, flags: &[Flag]) -> Result<LoadedConfig> {
+        let overrides: Vec<_> = flags.iter().map(Override::from).collect();
+        let vars: Vec<_> = std::env::vars().collect();
+        layer(self, &Value::Null, &overrides, &SecretReader::new(&vars))
+    }
+
+    /// A copy safe to print, with the JWT and request signing secrets and webhook, audit, Redis and LDAP credentials hidden
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.jwt_secret = REDACTED.to_string();
+        if let Some(signing) = &mut config.request_signing {
+            for client in signing.clients.values_mut() {
+                client.secret = REDACTED.to_string();
+            }
+        }
+        if let Some(ldap) = &mut config.ldap {
+            ldap.bind_password = ldap.bind_password.as_ref().map(|_| REDACTED.to_string());
+        }
+        config.lifecycle_webhook = config.lifecycle_webhook.as_deref().map(redact_url);
+        config.alert_webhook = config.alert_webhook.as_deref().map(redact_url);
+        for sink in &mut config.alerts.sinks {
+            sink.url = redact_url(&sink.url);
+            if let Some(key) = &mut sink.routing_key {
+                *key = REDACTED.to_string();
+            }
+        }
+        if let Some(redis) = &mut config.rate_limit.redis {
+            redis.url = redact_url(&redis.url);
+        }
+        for sink in &mut config.audit.sinks {
+            if let crate::audit::AuditSinkConfig::Http { url } = sink {
+                *url = redact_url(url);
+            }
+        }
+        for downstream in &mut config.downstreams {
+            if let crate::proxy::DownstreamTransport::WebSocket(url) = &mut downstream.transport {
+                *url = redact_url(url);
+            }
+        }
+        config
+    }
+
+    /// Parse a TOML or YAML configuration and check it
+    ///
+    /// # Errors
+    ///
+    /// Fails where `text` does not parse, holds a setting of the wrong type, or
+    /// refers to a secret that cannot be read.
+    pub fn parse(text: &str, format: ExtendedFormat) -> Result<LoadedConfig> {
+        let (config, raw) = Self::parse_raw(text, format)?;
+        let vars: Vec<_> = std::env::vars().collect();
+        layer(config, &raw, &[], &SecretReader::new(&vars))
+    }
+
+    /// Parse a file, keeping the tree as written to find unknown keys
+    fn read(path: &Path) -> Result<(Self, Value)> {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        let Ok(format @ (ExtendedFormat::Toml | ExtendedFormat::Yaml)) = ExtendedFormat::from_str(extension) else {
+            bail!("Configuration file {} must end in .toml, .yaml or .yml", path.display());
+        };
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading configuration from {}", path.display()))?;
+        Self::parse_raw(&text, format).with_context(|| path.display().to_string())
+    }
+
+    fn parse_raw(text: &str, format: ExtendedFormat) -> Result<(Self, Value)> {
+        match format {
+            ExtendedFormat::Toml => {
+                let config = toml::from_str(text).map_err(|e| toml_error(text, &e))?;
+                let raw = toml::from_str::<toml::Table>(text).ok().and_then(|raw| serde_json::to_value(raw).ok());
+                Ok((config, raw.unwrap_or_default()))
+            }
+            ExtendedFormat::Yaml => {
+                let raw: serde_yaml::Value = serde_yaml::from_str(text).map_err(|e| yaml_error(&e))?;
+                // A file of comments only is an empty document
+                let config = if raw.is_null() {
+                    Self::default()
+                } else {
+                    serde_yaml::from_str(text).map_err(|e| yaml_error(&e))?
+                };
+                Ok((config, serde_json::to_value(raw).unwrap_or_default()))
+            }
+            ExtendedFormat::Xml => bail!("Configuration files are TOML or YAML, not XML"),
+        }
+    }
+
+    /// A commented reference configuration, with every setting at its default
+    #[must_use]
+    #[allow(clippy::too_many_lines)] // A line or two per setting
+    pub fn example_toml() -> String {
+        let defaults = Self::default();
+        let string = |value: &str| toml::Value::String(value.to_string()).to_string();
+        let tier = |limits: TierLimits| {
+            format!("{{ requests_per_minute = {}, burst = {} }}", limits.requests_per_minute, limits.burst)
+        };
+        let limits = &defaults.ws_connection_limits;
+        let lifecycle = &defaults.lifecycle_thresholds;
+        let slow = &defaults.slow_ops;
+        let logging = &defaults.logging;
+        let tracing = &defaults.tracing;
+        let roles = defaults.roles.iter().fold(String::new(), |mut roles, (name, scopes)| {
+            let _ = writeln!(roles, "{name} = {}", toml::Value::from(scopes.to_vec()));
+            roles
+        });
+        format!(
+            r#"# Universal Language Connector server configuration
+#
+# Every setting is shown at its default; delete the ones you do not change.
+# Durations are a number and a unit: 250ms, 5s, 10m or 1h.
+
+# HTTP API bind address
+http_addr = {http_addr}
+# WebSocket bind address
+ws_addr = {ws_addr}
+# Serve LSP over stdio
+enable_lsp = {enable_lsp}
+# Serve the HTTP API
+enable_http = {enable_http}
+# Serve WebSocket clients
+enable_websocket = {enable_websocket}
+# gRPC bind address
+grpc_addr = {grpc_addr}
+# Serve gRPC clients; needs a build with the grpc feature
+enable_grpc = {enable_grpc}
+# Require bearer tokens signed with jwt_secret
+enable_auth = {enable_auth}
+# Let clients without credentials read, and nothing more, as a public demo server should
+anonymous_read_only = {anonymous_read_only}
+# Change this whenever enable_auth is true; secrets may name where they are kept instead,
+# as env:NAME, file:/run/secrets/jwt_secret or vault:secret/data/connector#jwt_secret
+jwt_secret = {jwt_secret}
+# HS256 signs with jwt_secret; RS256 and ES256 sign and verify with key files
+jwt_algorithm = {jwt_algorithm}
+# PEM private key RS256 or ES256 tokens are signed with, published at /.well-known/jwks.json
+# jwt_private_key_file = "/etc/universal-connector/token-key.pem"
+# PEM public keys of other issuers, such as an identity service, whose tokens are accepted
+jwt_public_key_files = []
+# Proxies whose X-Forwarded-For is believed: addresses and CIDR networks
+trusted_proxies = []
+# Directory for persisted state, checked for writability and free space
+# data_dir = "/var/lib/universal-connector"
+# URL notified of every lifecycle transition
+# lifecycle_webhook = "https://hooks.example.com/lifecycle"
+# URL notified when an alert rule fires or resolves
+# alert_webhook = "https://hooks.example.com/alerts"
+# Threshold rules over metrics; see [[alert_rules]] at the end
+alert_rules = []
+# Language servers fronted for the documents they match; see [[downstreams]] at the end
+downstreams = []
+# JSON Schemas to validate documents against; see [[schemas]] at the end
+schemas = []
+
+[tokens]
+# Access tokens issued at /auth/refresh expire after this long
+access = {access_lifetime}
+# Refresh tokens not exchanged for this long expire
+refresh = {refresh_lifetime}
+
+[roles]
+# Scopes each role grants to credentials holding the scope role:<name>; replaces these when set
+{roles}
+[policy]
+# Scopes required on top of each operation's own, by "METHOD /path" or "/path" for any method;
+# :name matches one path segment and a trailing * the rest, such as {{ "/api/admin/*" = ["ops"] }}
+routes = {{}}
+# Scopes required on top of their own by WebSocket message type, such as {{ Lsp = ["lsp"] }}
+messages = {{}}
+
+[rate_limit]
+# Refuse HTTP requests beyond these limits with 429; probes and /metrics are never limited
+enabled = {rate_limit_enabled}
+# Per client IP, for requests without valid credentials
+anonymous = {rate_limit_anonymous}
+# Per authenticated subject
+authenticated = {rate_limit_authenticated}
+# Per subject holding the unlimited scope
+elevated = {rate_limit_elevated}
+
+# Keep buckets in Redis, so every instance shares them (redis feature)
+# [rate_limit.redis]
+# url = "redis://cache.internal:6379/0"
+# key_prefix = "ulc:rate_limit:"
+# timeout = "100ms"
+
+[lockout]
+# Slow down, then lock out, clients failing at /auth/login and /auth/refresh, with 429 and 423
+enabled = {lockout_enabled}
+# Failures with one username that lock it out
+max_failures = {lockout_max_failures}
+# Failures from one client IP that lock it out, whatever the usernames tried
+max_failures_per_ip = {lockout_max_failures_per_ip}
+# Wait after the first failure, doubled after each one after
+backoff = {lockout_backoff}
+# Longest wait between failures short of a lockout
+max_backoff = {lockout_max_backoff}
+# How long a lockout lasts, and how long failures are remembered
+lockout = {lockout_lockout}
+
+[csrf]
+# Accept access tokens in a cookie, for browser-hosted editors, and require requests
+# authenticated by it that change anything to repeat the CSRF cookie in a header
+enabled = {csrf_enabled}
+token_cookie = {csrf_token_cookie}
+cookie = {csrf_cookie}
+header = {csrf_header}
+# Issue the CSRF cookie over HTTPS only
+secure_cookie = {csrf_secure_cookie}
+# Pages of other origins allowed to open WebSockets; the connector's own always are
+allowed_origins = []
+
+[audit]
+# Where authentication and authorization events are written; none turns auditing off.
+# See [[audit.sinks]] at the end
+sinks = []
+
+[ws_connection_limits]
+# Connections across all clients
+max_total = {max_total}
+# Connections per authenticated subject
+max_per_subject = {max_per_subject}
+# Connections per client IP
+max_per_ip = {max_per_ip}
+# Displace a subject's longest idle connection instead of refusing a new one
+displace_idle = {displace_idle}
+# Delay suggested to refused clients in Retry-After
+retry_after = {retry_after}
+
+[format_limits]
+# Largest document converted or validated, in bytes
+max_input_bytes = {max_input_bytes}
+# Largest conversion result returned, in bytes
+max_output_bytes = {max_output_bytes}
+
+[lint]
+# Severity of lint rules by ID: error, warning, info, hint or off, such as {{ "toml/prefer-dotted-keys" = "hint" }}
+rules = {{}}
+# Severities of their own for the documents of a workspace; see [[lint.workspaces]] at the end
+workspaces = []
+
+[plugins]
+# Directory whose *.wasm format plugins are loaded at startup (wasm-plugins feature)
+# dir = "/usr/lib/universal-connector/plugins"
+# Largest memory a plugin may use per call, in bytes
+max_memory_bytes = {plugin_memory}
+# Instructions a plugin may execute per call
+fuel = {plugin_fuel}
+
+[lifecycle_thresholds]
+# Failing health evaluations that move ready to degraded
+degrade_after = {degrade_after}
+# Healthy evaluations that move degraded back to ready
+recover_after = {recover_after}
+# Unhealthy evaluations after which a degraded server is no longer ready
+unready_after = {unready_after}
+# Unhealthy evaluations after which a degraded server is no longer live
+restart_after = {restart_after}
+
+[slow_ops]
+# Slow operations kept for /api/admin/slow-ops
+capacity = {slow_capacity}
+# Slow operations logged per kind per minute; 0 logs none
+max_logs_per_minute = {max_logs_per_minute}
+
+[slow_ops.thresholds]
+# Duration from which an operation counts as slow; 0s counts every one
+http = {slow_http}
+lsp = {slow_lsp}
+conversion = {slow_conversion}
+validation = {slow_validation}
+store = {slow_store}
+
+[usage]
+# Count usage per authenticated subject; when false, all traffic is anonymous
+per_subject = {per_subject}
+# Subjects tracked per 15-minute slot
+capacity = {usage_capacity}
+# JSON lines file daily usage is appended to
+# rollup_file = "/var/lib/universal-connector/usage.jsonl"
+
+[metrics]
+# Metrics or groups (lsp, websocket, formats) neither recorded nor exported
+disabled = []
+# Histograms or groups recording one observation in N, such as {{ lsp = 10 }}
+sampling = {{}}
+
+[alerts]
+# Time after which a rule still firing is notified again; 0 never
+renotify_secs = {renotify_secs}
+# Attempts at each delivery, the first included
+max_attempts = {max_attempts}
+# Wait before the first retry, doubled for each one after
+retry_backoff_ms = {retry_backoff_ms}
+# Webhooks notified of alerts; see [[alerts.sinks]] at the end
+sinks = []
+
+[logging]
+# pretty, compact or json
+format = {log_format}
+# Default level
+level = {log_level}
+# Per-module overrides, such as "universal_connector_server::lsp=debug"
+directives = []
+# Include the fields of enclosing spans in JSON records
+include_spans = {include_spans}
+# File holding the filter applied on SIGHUP
+# filter_file = "/etc/universal-connector/log-filter"
+
+# Write logs to a size-rotated file instead of stderr
+# [logging.file]
+# path = "/var/log/universal-connector/server.log"
+# max_bytes = 10485760
+# retain = 5
+
+[tracing]
+# OTLP gRPC endpoint traces are exported to
+# otlp_endpoint = "http://localhost:4317"
+service_name = {service_name}
+service_version = {service_version}
+# Fraction of new traces recorded
+sampling_ratio = {sampling_ratio:?}
+
+[jobs]
+# Tasks running background jobs, such as webhook deliveries and diagnostics
+workers = {job_workers}
+# Limits by class, such as {{ webhook = {{ concurrency = 8, max_queued = 100 }} }}
+classes = {{}}
+
+[jobs.default_limits]
+# Jobs of a class running at once
+concurrency = {job_concurrency}
+# Jobs of a class waiting to run, beyond which new ones are refused
+max_queued = {job_max_queued}
+
+[tasks]
+# Documents untouched for this long are removed; 0s keeps them
+document_ttl = {document_ttl}
+# Schedules replacing the built-in ones by task, such as {{ usage_rollup = "5m", document_ttl_sweep = "*/10 * * * *" }}
+schedules = {{}}
+
+# Accept the tokens of an OpenID Connect provider, such as Keycloak, Auth0 or Azure AD
+# [oidc]
+# issuer = "https://sso.example.com/realms/main"
+# audience = "universal-connector"
+# scope_claim = "scope"
+# subject_claim = "sub"
+# jwks_refresh = "1h"
+# [oidc.scope_map]
+# editor = ["read", "write"]
+
+# Serve HTTP and WebSocket over TLS, requiring client certificates in place of tokens
+# [mtls]
+# cert_file = "/etc/universal-connector/server.pem"
+# key_file = "/etc/universal-connector/server-key.pem"
+# client_ca_files = ["/etc/universal-connector/clients-ca.pem"]
+# default_scopes = []
+# [mtls.identities]
+# "CN=build-agent,OU=CI,O=Example" = {{ user = "ci", scopes = ["role:viewer"] }}
+# alice = {{ scopes = ["role:editor"] }}
+
+# Check /auth/login usernames and passwords against an LDAP server or Active Directory (ldap feature)
+# [ldap]
+# url = "ldaps://dc1.example.com"
+# start_tls = false
+# bind_dn = "cn=connector,ou=services,dc=example,dc=com"
+# bind_password = "service account password"
+# user_base = "ou=people,dc=example,dc=com"
+# user_filter = "(uid={{username}})"
+# subject_attribute = "uid"
+# group_attribute = "memberOf"
+# default_scopes = []
+# timeout = "5s"
+# [ldap.group_scopes]
+# Editors = ["role:editor"]
+# "CN=Ops,OU=Groups,DC=example,DC=com" = ["role:admin"]
+
+# Sign users in at /auth/saml/acs with the SAML 2.0 assertions of an identity provider
+# [saml]
+# idp_entity_id = "https://idp.example.com/metadata"
+# idp_certificate_file = "/etc/universal-connector/idp-signing.pem"
+# sp_entity_id = "https://connector.example.com"
+# acs_url = "https://connector.example.com/auth/saml/acs"
+# subject_attribute = "email"
+# group_attribute = "groups"
+# default_scopes = []
+# clock_skew = "2m"
+# [saml.group_scopes]
+# Editors = ["role:editor"]
+
+# Accept requests signed with a shared secret, for clients such as CI that cannot hold tokens
+# [request_signing]
+# max_skew = "5m"
+# [request_signing.clients.ci]
+# secret = "output of openssl rand -base64 48"
+# user = "ci"
+# scopes = ["read", "write"]
+
+# Push metrics to a StatsD agent, at addr (UDP) or socket (Unix datagram)
+# [statsd]
+# addr = "127.0.0.1:8125"
+# prefix = "connector."
+# interval = "10s"
+# tags = true
+# histograms = "timing"
+# max_packet_bytes = 1432
+
+# [[alert_rules]]
+# name = "http_5xx_ratio"
+# window_secs = 300
+# comparison = "above"
+# threshold = 0.05
+# severity = "critical"
+# query = {{ kind = "ratio", numerator = {{ metric = "ulc_http_request_duration_seconds", labels = {{ status = "5xx" }} }}, denominator = {{ metric = "ulc_http_request_duration_seconds" }} }}
+
+# kind = "file" with path, "stdout", or "http" with url
+# [[audit.sinks]]
+# kind = "file"
+# path = "/var/log/universal-connector/audit.jsonl"
+
+# [[alerts.sinks]]
+# name = "ops"
+# url = "https://hooks.example.com/alerts"
+# template = "generic"
+# min_severity = "warning"
+
+# Checked on request as {{"schema": "deploy"}}, and by editors on the documents matching its globs
+# [[schemas]]
+# name = "deploy"
+# path = "/etc/universal-connector/schemas/deploy.json"
+# documents = ["**/deploy/*.yaml"]
+
+# Rule severities for the documents of one workspace, over those of [lint]
+# [[lint.workspaces]]
+# documents = ["/home/me/legacy/**"]
+# rules = {{ "json/no-duplicate-keys" = "off" }}
+
+# Run by each LSP session on demand; or tcp = "host:port", or websocket = "ws://..."
+# [[downstreams]]
+# name = "rust-analyzer"
+# command = ["rust-analyzer"]
+# languages = ["rust"]
+# patterns = ["*.rs", "**/Cargo.toml"]
+# idle_timeout = "10m"
+# restart_delay = "1s"
+# max_restarts = 5
+# request_timeout = "30s"
+# [downstreams.uri_map]
+# local = "file:///home/me/project"
+# remote = "file:///workspace"
+"#,
+            http_addr = string(&defaults.http_addr),
+            ws_addr = string(&defaults.ws_addr),
+            enable_lsp = defaults.enable_lsp,
+            enable_http = defaults.enable_http,
+            enable_websocket = defaults.enable_websocket,
+            grpc_addr = string(&defaults.grpc_addr),
+            enable_grpc = defaults.enable_grpc,
+            enable_auth = defaults.enable_auth,
+            anonymous_read_only = defaults.anonymous_read_only,
+            jwt_secret = string(&defaults.jwt_secret),
+            jwt_algorithm = string(defaults.jwt_algorithm.as_str()),
+            access_lifetime = string(&duration::format(defaults.tokens.access)),
+            refresh_lifetime = string(&duration::format(defaults.tokens.refresh)),
+            rate_limit_enabled = defaults.rate_limit.enabled,
+            lockout_enabled = defaults.lockout.enabled,
+            lockout_max_failures = defaults.lockout.max_failures,
+            lockout_max_failures_per_ip = defaults.lockout.max_failures_per_ip,
+            lockout_backoff = string(&duration::format(defaults.lockout.backoff)),
+            lockout_max_backoff = string(&duration::format(defaults.lockout.max_backoff)),
+            lockout_lockout = string(&duration::format(defaults.lockout.lockout)),
+            csrf_enabled = defaults.csrf.enabled,
+            csrf_token_cookie = string(&defaults.csrf.token_cookie),
+            csrf_cookie = string(&defaults.csrf.cookie),
+            csrf_header = string(&defaults.csrf.header),
+            csrf_secure_cookie = defaults.csrf.secure_cookie,
+            rate_limit_anonymous = tier(defaults.rate_limit.anonymous),
+            rate_limit_authenticated = tier(defaults.rate_limit.authenticated),
+            rate_limit_elevated = tier(defaults.rate_limit.elevated),
+            max_total = limits.max_total,
+            max_per_subject = limits.max_per_subject,
+            max_per_ip = limits.max_per_ip,
+            displace_idle = limits.displace_idle,
+            retry_after = string(&duration::format(limits.retry_after)),
+            max_input_bytes = defaults.format_limits.max_input_bytes,
+            max_output_bytes = defaults.format_limits.max_output_bytes,
+            plugin_memory = defaults.plugins.max_memory_bytes,
+            plugin_fuel = defaults.plugins.fuel,
+            degrade_after = lifecycle.degrade_after,
+            recover_after = lifecycle.recover_after,
+            unready_after = lifecycle.unready_after,
+            restart_after = lifecycle.restart_after,
+            slow_capacity = slow.capacity,
+            max_logs_per_minute = slow.max_logs_per_minute,
+            slow_http = string(&duration::format(slow.thresholds.http)),
+            slow_lsp = string(&duration::format(slow.thresholds.lsp)),
+            slow_conversion = string(&duration::format(slow.thresholds.conversion)),
+            slow_validation = string(&duration::format(slow.thresholds.validation)),
+            slow_store = string(&duration::format(slow.thresholds.store)),
+            per_subject = defaults.usage.per_subject,
+            usage_capacity = defaults.usage.capacity,
+            renotify_secs = defaults.alerts.renotify_secs,
+            max_attempts = defaults.alerts.max_attempts,
+            retry_backoff_ms = defaults.alerts.retry_backoff_ms,
+            log_format = string(logging.format.as_str()),
+            log_level = string(&logging.level),
+            include_spans = logging.include_spans,
+            service_name = string(&tracing.service_name),
+            service_version = string(&tracing.service_version),
+            sampling_ratio = tracing.sampling_ratio,
+            job_workers = defaults.jobs.workers,
+            job_concurrency = defaults.jobs.default_limits.concurrency,
+            job_max_queued = defaults.jobs.default_limits.max_queued,
+            document_ttl = string(&duration::format(defaults.tasks.document_ttl)),
+        )
+    }
+}
+
+fn toml_error(text: &str, e: &toml::de::Error) -> anyhow::Error {
+    anyhow!(formats::toml::error_message(text, e))
+}
+
+fn yaml_error(e: &serde_yaml::Error) -> anyhow::Error {
+    anyhow!(formats::yaml::error_message(e))
+}
+
+/// Collect the paths of keys in `raw` that deserializing left unused
+///
+/// `known` is the parsed configuration serialized again, so it holds every
+/// key the configuration has, including those left at their default.
+fn collect_unknown(raw: &Value, known: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (raw, known) {
+        (Value::Object(raw), Value::Object(known)) => {
+            for (key, value) in raw {
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                match known.get(key) {
+                    Some(known) => collect_unknown(value, known, &path, unknown),
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(known)) => {
+            for (i, (raw, known)) in raw.iter().zip(known).enumerate() {
+                collect_unknown(raw, known, &format!("{path}[{i}]"), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Apply `overrides` to a configuration read from `raw`, read the secrets it refers to, then check it
+fn layer(config: ServerConfig, raw: &Value, overrides: &[Override], secrets: &SecretReader) -> Result<LoadedConfig> {
+    let mut tree = serde_json::to_value(&config)?;
+    let mut unknown_keys = Vec::new();
+    collect_unknown(raw, &tree, "", &mut unknown_keys);
+
+    // Applied one at a time, so an error names its variable
+    let mut config = config;
+    for each in overrides {
+        set(&mut tree, &each.path, &each.value).with_context(|| each.source.to_string())?;
+        config = serde_json::from_value(tree.clone()).with_context(|| each.source.to_string())?;
+    }
+    secrets.resolve(&mut config)?;
+    let warnings = match config.validate() {
+        Ok(()) => Vec::new(),
+        Err(problems) => {
+            let (warnings, errors): (Vec<_>, Vec<_>) = problems.into_iter().partition(|problem| problem.warning);
+            if !errors.is_empty() {
+                bail!(errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"));
+            }
+            warnings
+        }
+    };
+
+    let known = serde_json::to_value(&config)?;
+    let mut unused = Vec::new();
+    collect_unknown(&tree, &known, "", &mut unused);
+    let unknown_variables = overrides
+        .iter()
+        .filter(|each| unused.iter().any(|key| within(&each.path.join("."), key)))
+        .map(|each| each.source.to_string())
+        .collect();
+
+    let mut settings = Vec::new();
+    leaves(&known, "", &mut settings);
+    let sources = settings
+        .into_iter()
+        .map(|path| {
+            let source = match overrides.iter().rev().find(|each| within(&path, &each.path.join("."))) {
+                Some(each) => each.source.clone(),
+                None if path.split('.').try_fold(raw, |value, key| value.get(key)).is_some() => Source::File,
+                None => Source::Default,
+            };
+            (path, source)
+        })
+        .collect();
+    Ok(LoadedConfig { config, unknown_keys, unknown_variables, sources, warnings })
+}
+
+/// The `ULC_` variables in `vars` by name, reading secrets given as files
+fn overrides(vars: &[(String, String)], secrets: &SecretReader) -> Result<Vec<Override>> {
+    let mut vars: Vec<_> = vars.iter().filter(|(name, _)| name.starts_with(ENV_PREFIX)).cloned().collect();
+    vars.sort();
+    let mut overrides: Vec<Override> = Vec::new();
+    for (variable, value) in vars {
+        let Some(setting) = variable.strip_prefix(ENV_PREFIX) else { continue };
+        let setting = setting.to_ascii_lowercase();
+        let (setting, value) = match setting.strip_suffix("_file").filter(|secret| SECRETS.contains(secret)) {
+            Some(secret) => {
+                let text = secrets.read(&SecretSource::File(PathBuf::from(&value))).with_context(|| variable.clone())?;
+                (secret.to_string(), text)
+            }
+            None => (setting, value),
+        };
+        let path = setting.split("__").map(str::to_string).collect();
+        overrides.push(Override { source: Source::Env(variable), path, value });
+    }
+    if let Some(pair) = overrides.windows(2).find(|pair| pair[0].path == pair[1].path) {
+        bail!("Set {} or {}, not both", pair[0].source, pair[1].source);
+    }
+    Ok(overrides)
+}
+
+/// Set the setting at `path` in `tree` from a variable's text
+fn set(tree: &mut Value, path: &[String], text: &str) -> Result<()> {
+    let Some((key, sections)) = path.split_last() else { return Ok(()) };
+    let mut node = tree;
+    for (depth, section) in sections.iter().enumerate() {
+        if node.is_null() {
+            *node = Value::Object(Map::new());
+        }
+        node = match node {
+            Value::Object(map) => map.entry(section.clone()).or_insert(Value::Null),
+            _ => bail!("{} is not a section", path[..depth].join(".")),
+        };
+    }
+    if node.is_null() {
+        *node = Value::Object(Map::new());
+    }
+    let Value::Object(map) = node else {
+        bail!("{} is not a section", sections.join("."));
+    };
+    let value = coerce(map.get(key), text)?;
+    map.insert(key.clone(), value);
+    Ok(())
+}
+
+/// A variable's text as a value of the type `current` has
+fn coerce(current: Option<&Value>, text: &str) -> Result<Value> {
+    let trimmed = text.trim();
+    Ok(match current {
+        Some(Value::String(_)) => Value::String(text.to_string()),
+        Some(Value::Bool(_)) => match trimmed.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Value::Bool(true),
+            "false" | "0" | "no" | "off" => Value::Bool(false),
+            _ => bail!("expected true or false, not {text:?}"),
+        },
+        Some(Value::Number(_)) => match serde_json::from_str(trimmed) {
+            Ok(number @ Value::Number(_)) => number,
+            _ => bail!("expected a number, not {text:?}"),
+        },
+        Some(Value::Array(_)) if !trimmed.starts_with('[') => Value::Array(
+            trimmed
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        ),
+        Some(Value::Array(_) | Value::Object(_)) => {
+            serde_json::from_str(trimmed).with_context(|| format!("expected JSON, not {text:?}"))?
+        }
+        // Unset, or not a setting: JSON when it parses, otherwise a string
+        None | Some(Value::Null) => serde_json::from_str(trimmed).unwrap_or_else(|_| Value::String(text.to_string())),
+    })
+}
+
+/// Whether `path` is `section` or a setting within it
+fn within(path: &str, section: &str) -> bool {
+    path.strip_prefix(section).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Paths of every setting in `value`, sections being walked into
+fn leaves(value: &Value, path: &str, found: &mut Vec<String>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                leaves(value, &path, found);
+            }
+        }
+        _ => found.push(path.to_string()),
+    }
+}
+
+/// Durations as a number and a unit, for `#[serde(with = "crate::config::duration")]`
+pub mod duration {
+    use anyhow::{anyhow, bail, Result};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    /// Write `duration` as [`format`] does
+    ///
+    /// # Errors
+    ///
+    /// The error of `serializer`.
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(*duration))
+    }
+
+    /// Read a duration as [`parse`] does
+    ///
+    /// # Errors
+    ///
+    /// Fails where the value is not a string [`parse`] reads.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        parse(&text).map_err(serde::de::Error::custom)
+    }
+
+    /// `duration` in the largest unit that holds it exactly, to the millisecond
+    #[must_use]
+    pub fn format(duration: Duration) -> String {
+        let millis = duration.as_millis();
+        let secs = millis / 1000;
+        if !millis.is_multiple_of(1000) {
+            format!("{millis}ms")
+        } else if secs > 0 && secs.is_multiple_of(3600) {
+            format!("{}h", secs / 3600)
+        } else if secs > 0 && secs.is_multiple_of(60) {
+            format!("{}m", secs / 60)
+        } else {
+            format!("{secs}s")
+        }
+    }
+
+    /// Parse a whole number followed by `ms`, `s`, `m` or `h`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `text` has no number, an unknown unit, or is too long to
+    /// hold.
+    pub fn parse(text: &str) -> Result<Duration> {
+        let text = text.trim();
+        let (number, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()));
+        let number: u64 = number
+            .parse()
+            .map_err(|_| anyhow!("Invalid duration {text:?}: expected a number and a unit, such as 5s"))?;
+        let secs = |per_unit: u64| {
+            number
+                .checked_mul(per_unit)
+                .map(Duration::from_secs)
+                .ok_or_else(|| anyhow!("Duration {text:?} is too long"))
+        };
+        match unit.trim() {
+            "ms" => Ok(Duration::from_millis(number)),
+            "s" => secs(1),
+            "m" => secs(60),
+            "h" => secs(3600),
+            _ => bail!("Invalid duration {text:?}: the unit must be ms, s, m or h"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::rate_limit::RedisConfig;
+    use std::time::Duration;
+
+    fn toml(text: &str) -> Result<LoadedConfig> {
+        ServerConfig::parse(text, ExtendedFormat::Toml)
+    }
+
+    /// Paths of every setting in `value`, with whether it is unset
+    fn settings(value: &Value, path: &str, found: &mut Vec<(String, bool)>) {
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                    settings(value, &path, found);
+                }
+            }
+            _ => found.push((path.to_string(), value.is_null())),
+        }
+    }
+
+    #[test]
+    fn test_example_round_trips() {
+        let example = ServerConfig::example_toml();
+        let loaded = toml(&example).unwrap();
+        assert!(loaded.unknown_keys.is_empty(), "{:?}", loaded.unknown_keys);
+        assert_eq!(loaded.config, ServerConfig::default());
+
+        let serialized = ::toml::to_string(&loaded.config).unwrap();
+        assert_eq!(toml(&serialized).unwrap().config, loaded.config);
+    }
+
+    #[test]
+    fn test_example_is_complete() {
+        let example = ServerConfig::example_toml();
+        let raw = serde_json::to_value(::toml::from_str::<::toml::Table>(&example).unwrap()).unwrap();
+        let mut found = Vec::new();
+        settings(&serde_json::to_value(ServerConfig::default()).unwrap(), "", &mut found);
+        for (path, unset) in found {
+            if unset {
+                // Optional settings are shown commented out
+                let key = path.rsplit('.').next().unwrap();
+                let shown = example.contains(&format!("# {key} = ")) || example.contains(&format!("# [{path}]"));
+                assert!(shown, "{path} is not in the example");
+            } else {
+                let present = path.split('.').try_fold(&raw, |value, key| value.get(key));
+                assert!(present.is_some(), "{path} is not in the example");
+            }
+        }
+    }
+
+    #[test]
+    fn test_minimal_files() {
+        let loaded = toml("enable_auth = true\njwt_secret = \"aG9zdC1zZWNyZXQtZm9yLXRoZS1taW5pbWFs\"\n").unwrap();
+        assert!(loaded.config.enable_auth);
+        assert_eq!(loaded.config.http_addr, ServerConfig::default().http_addr);
+        assert_eq!(toml("").unwrap().config, ServerConfig::default());
+
+        let yaml = "
+ws_connection_limits:
+  max_per_ip: 8
+  retry_after: 30s
+slow_ops:
+  thresholds: { store: 250ms }
+trusted_proxies: [10.0.0.0/8]
+statsd: { addr: '127.0.0.1:8125', interval: 1m }
+";
+        let config = ServerConfig::parse(yaml, ExtendedFormat::Yaml).unwrap().config;
+        assert_eq!(config.ws_connection_limits.max_per_ip, 8);
+        assert_eq!(config.ws_connection_limits.max_total, 10_000);
+        assert_eq!(config.ws_connection_limits.retry_after, Duration::from_secs(30));
+        assert_eq!(config.slow_ops.thresholds.store, Duration::from_millis(250));
+        assert!(config.trusted_proxies.is_trusted("10.1.2.3".parse().unwrap()));
+        let statsd = config.statsd.unwrap();
+        assert_eq!(statsd.interval, Duration::from_mins(1));
+        assert_eq!(statsd.max_packet_bytes, 1432);
+        let empty = ServerConfig::parse("# nothing yet\n", ExtendedFormat::Yaml).unwrap();
+        assert_eq!(empty.config, ServerConfig::default());
+    }
+
+    #[test]
+    fn test_unknown_keys_reported_with_path() {
+        let loaded = toml(
+            r#"
+http_adr = "0.0.0.0:9000"
+
+[logging]
+levle = "debug"
+
+[[alert_rules]]
+name = "errors"
+query = { kind = "rate", metric = "ulc_errors_total" }
+window_secs = 60
+comparison = "above"
+threshold = 1
+treshold = 2
+"#,
+        )
+        .unwrap();
+        assert_eq!(loaded.unknown_keys, ["alert_rules[0].treshold", "http_adr", "logging.levle"]);
+        assert_eq!(loaded.config.alert_rules.len(), 1);
+
+        let yaml = "usage:\n  per_subjekt: false\nmetrics:\n  sampling: { lsp: 10 }\n";
+        let loaded = ServerConfig::parse(yaml, ExtendedFormat::Yaml).unwrap();
+        assert_eq!(loaded.unknown_keys, ["usage.per_subjekt"]);
+    }
+
+    #[test]
+    fn test_errors_give_line_and_column() {
+        let error = toml("http_addr = \"0.0.0.0:80\"\n\n[ws_connection_limits]\nmax_total = \"many\"\n").unwrap_err();
+        assert!(format!("{error:#}").starts_with("line 4, column 13: invalid type: string"), "{error:#}");
+
+        let error = ServerConfig::parse("usage:\n  capacity: lots\n", ExtendedFormat::Yaml).unwrap_err();
+        assert!(format!("{error:#}").starts_with("line 2, column 13: "), "{error:#}");
+
+        let error = toml("[slow_ops.thresholds]\nhttp = \"2 weeks\"\n").unwrap_err();
+        assert!(format!("{error:#}").contains("line 2"), "{error:#}");
+
+        // Checked beyond their type
+        assert!(toml("[logging]\nlevel = \"loud\"\n").is_err());
+        assert!(toml("[tracing]\nsampling_ratio = 2.0\n").is_err());
+        assert!(toml("[metrics]\ndisabled = [\"ulc_nope\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = std::env::temp_dir().join(format!("ulc-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.yml");
+        std::fs::write(&path, "enable_lsp: false\n").unwrap();
+        assert!(!ServerConfig::from_file(&path).unwrap().enable_lsp);
+
+        let path = dir.join("server.toml");
+        std::fs::write(&path, "enable_lsp = 3\n").unwrap();
+        let error = format!("{:#}", ServerConfig::from_file(&path).unwrap_err());
+        assert!(error.starts_with(&format!("{}: line 1", path.display())), "{error}");
+
+        let path = dir.join("server.xml");
+        std::fs::write(&path, "<config/>").unwrap();
+        assert!(ServerConfig::from_file(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| ((*name).to_string(), (*value).to_string())).collect()
+    }
+
+    fn flag(name: &str, setting: &str, value: &str) -> Flag {
+        Flag { name: name.to_string(), setting: setting.to_string(), value: value.to_string() }
+    }
+
+    fn source<'a>(loaded: &'a LoadedConfig, path: &str) -> &'a Source {
+        &loaded.sources.iter().find(|(setting, _)| setting == path).unwrap().1
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let loaded = ServerConfig::layered(
+            None,
+            vars(&[
+                ("ULC_HTTP_ADDR", "127.0.0.1:9000"),
+                ("ULC_ENABLE_AUTH", "yes"),
+                ("ULC_JWT_SECRET", "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY"),
+                ("ULC_WS_CONNECTION_LIMITS__MAX_PER_IP", "8"),
+                ("ULC_WS_CONNECTION_LIMITS__RETRY_AFTER", "30s"),
+                ("ULC_TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1"),
+                ("ULC_LOGGING__DIRECTIVES", "tower=warn,hyper=error"),
+                ("ULC_STATSD__ADDR", "127.0.0.1:8125"),
+                ("ULC_STATSD__TAGS", "false"),
+                ("ULC_METRICS__SAMPLING__LSP", "10"),
+                ("ULC_TRACING__SAMPLING_RATIO", "0.25"),
+                ("ULC_SERVCE_NAME", "typo"),
+                ("HTTP_ADDR", "0.0.0.0:1"),
+            ]),
+            &[],
+        )
+        .unwrap();
+        let config = &loaded.config;
+        assert_eq!(config.http_addr, "127.0.0.1:9000");
+        assert!(config.enable_auth);
+        assert_eq!(config.ws_connection_limits.max_per_ip, 8);
+        assert_eq!(config.ws_connection_limits.retry_after, Duration::from_secs(30));
+        assert!(config.trusted_proxies.is_trusted("127.0.0.1".parse().unwrap()));
+        assert_eq!(config.logging.directives, ["tower=warn", "hyper=error"]);
+        assert!(!config.statsd.as_ref().unwrap().tags);
+        assert_eq!(config.metrics.sampling["lsp"], 10);
+        assert!((config.tracing.sampling_ratio - 0.25).abs() < f64::EPSILON);
+        assert_eq!(loaded.unknown_variables, ["ULC_SERVCE_NAME"]);
+
+        assert_eq!(source(&loaded, "http_addr"), &Source::Env("ULC_HTTP_ADDR".to_string()));
+        assert_eq!(source(&loaded, "statsd.addr"), &Source::Env("ULC_STATSD__ADDR".to_string()));
+        assert_eq!(source(&loaded, "statsd.interval"), &Source::Default);
+        assert_eq!(source(&loaded, "ws_addr"), &Source::Default);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let dir = std::env::temp_dir().join(format!("ulc-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        std::fs::write(&path, "http_addr = \"0.0.0.0:9000\"\n\n[logging]\nlevel = \"warn\"\n").unwrap();
+        let secret = dir.join("jwt_secret");
+        std::fs::write(&secret, "s3cret\n").unwrap();
+        let rollup = dir.join("usage.jsonl");
+
+        let loaded = ServerConfig::layered(
+            Some(&path),
+            vars(&[
+                ("ULC_LOGGING__LEVEL", "debug"),
+                ("ULC_JWT_SECRET_FILE", secret.to_str().unwrap()),
+                ("ULC_USAGE__ROLLUP_FILE", rollup.to_str().unwrap()),
+                ("ULC_WS_ADDR", "0.0.0.0:9001"),
+            ]),
+            &[flag("--ws-addr", "ws_addr", "127.0.0.1:9001"), flag("--no-lsp", "enable_lsp", "false")],
+        )
+        .unwrap();
+        assert_eq!(loaded.config.http_addr, "0.0.0.0:9000");
+        assert_eq!(loaded.config.ws_addr, "127.0.0.1:9001");
+        assert!(!loaded.config.enable_lsp);
+        assert_eq!(loaded.config.logging.level, "debug");
+        assert_eq!(loaded.config.jwt_secret, "s3cret");
+        assert_eq!(loaded.config.usage.rollup_file.as_deref(), Some(rollup.as_path()));
+        assert_eq!(source(&loaded, "http_addr"), &Source::File);
+        assert_eq!(source(&loaded, "logging.level"), &Source::Env("ULC_LOGGING__LEVEL".to_string()));
+        assert_eq!(source(&loaded, "jwt_secret"), &Source::Env("ULC_JWT_SECRET_FILE".to_string()));
+        assert_eq!(source(&loaded, "logging.format"), &Source::Default);
+        assert_eq!(source(&loaded, "ws_addr"), &Source::Flag("--ws-addr".to_string()));
+
+        let both = vars(&[("ULC_JWT_SECRET", "plain"), ("ULC_JWT_SECRET_FILE", secret.to_str().unwrap())]);
+        assert!(ServerConfig::layered(Some(&path), both, &[]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_errors_name_the_variable() {
+        for (variable, value, expected) in [
+            ("ULC_WS_CONNECTION_LIMITS__MAX_TOTAL", "many", "expected a number"),
+            ("ULC_ENABLE_LSP", "maybe", "expected true or false"),
+            ("ULC_USAGE__CAPACITY", "-1", "invalid value"),
+            ("ULC_SLOW_OPS__THRESHOLDS__HTTP", "2 weeks", "Invalid duration"),
+            ("ULC_HTTP_ADDR__PORT", "80", "http_addr is not a section"),
+            ("ULC_JWT_SECRET_FILE", "/nonexistent/secret", "reading /nonexistent/secret"),
+        ] {
+            let error = ServerConfig::layered(None, vars(&[(variable, value)]), &[]).unwrap_err();
+            let error = format!("{error:#}");
+            assert!(error.starts_with(&format!("{variable}: ")), "{error}");
+            assert!(error.contains(expected), "{error}");
+        }
+        // Checked beyond their type, as in a file
+        assert!(ServerConfig::layered(None, vars(&[("ULC_LOGGING__LEVEL", "loud")]), &[]).is_err());
+        let error = ServerConfig::default().with_flags(&[flag("--log-level", "logging.level", "loud")]).unwrap_err();
+        assert!(format!("{error:#}").starts_with("logging: "), "{error:#}");
+    }
+
+    #[test]
+    fn test_redacted() {
+        let mut config = ServerConfig {
+            jwt_secret: "s3cret".to_string(),
+            alert_webhook: Some("https://hooks.example.com/services/T000/XXXX".to_string()),
+            ..ServerConfig::default()
+        };
+        let sinks = r#"
+[[alerts.sinks]]
+name = "pager"
+url = "https://events.example.com/v2/XXXX"
+template = "pagerduty"
+routing_key = "R0UT1NG"
+"#;
+        config.alerts.sinks = toml(sinks).unwrap().config.alerts.sinks;
+        config.rate_limit.redis = Some(RedisConfig::new("redis://:hunter2@cache.internal:6379/0"));
+        config.audit.sinks.push(crate::audit::AuditSinkConfig::Http { url: "https://logs.example.com/ingest/T0K3N".to_string() });
+        let mut signing = crate::auth::signing::SigningConfig::default();
+        signing.clients.insert("ci".to_string(), crate::auth::signing::SigningClient { secret: "SH4R3D".to_string(), ..Default::default() });
+        config.request_signing = Some(signing);
+        let mut ldap = crate::auth::directory::LdapConfig::new("ldaps://dc1.example.com", "dc=example,dc=com");
+        ldap.bind_password = Some("B1ND".to_string());
+        config.ldap = Some(ldap);
+        let printed = ::toml::to_string(&config.redacted()).unwrap();
+        for secret in ["s3cret", "XXXX", "R0UT1NG", "hunter2", "T0K3N", "SH4R3D", "B1ND"] {
+            assert!(!printed.contains(secret), "{printed}");
+        }
+        assert!(printed.contains("https://hooks.example.com/<redacted>"), "{printed}");
+    }
+
+    #[test]
+    fn test_durations() {
+        for (text, duration) in [
+            ("250ms", Duration::from_millis(250)),
+            ("5s", Duration::from_secs(5)),
+            ("10m", Duration::from_mins(10)),
+            ("2h", Duration::from_hours(2)),
+            ("0s", Duration::ZERO),
+        ] {
+            assert_eq!(duration::parse(text).unwrap(), duration);
+            assert_eq!(duration::format(duration), text);
+        }
+        assert_eq!(duration::format(Duration::from_secs(90)), "90s");
+        assert_eq!(duration::format(Duration::from_millis(1500)), "1500ms");
+        assert!(duration::parse("5").is_err());
+        assert!(duration::parse("5 days").is_err());
+        assert!(duration::parse("-1s").is_err());
+        assert!(duration::parse(&format!("{}h", u64::MAX)).is_err());
+    }
+}
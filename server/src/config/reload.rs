@@ -0,0 +1,157 @@
+//! Reloading the configuration of a running server
+//!
+//! A reload reads the configuration again, as at startup, and compares it
+//! with the one in force. Changed settings under a [`RELOADABLE`] section
+//! take effect at once; any other change, such as a bind address, needs a
+//! restart and is refused, the running value being kept until then.
+
+use super::within;
+use crate::ServerConfig;
+use serde_json::Value;
+
+/// Sections the running server picks up without a restart
+pub const RELOADABLE: &[&str] = &[
+    "logging.level",
+    "logging.directives",
+    "ws_connection_limits",
+    "trusted_proxies",
+    "format_limits",
+    "lint",
+    "slow_ops",
+    "metrics",
+    "alerts",
+    "downstreams",
+];
+
+/// A reloaded configuration, as compared with the running one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reload {
+    /// The running configuration with the reloadable changes made
+    pub effective: ServerConfig,
+    /// Changed settings now in force
+    pub applied: Vec<String>,
+    /// Changed settings left at their running value until a restart
+    pub rejected: Vec<String>,
+}
+
+impl Reload {
+    /// Compare `loaded` with the `running` configuration
+    #[must_use]
+    pub fn plan(running: &ServerConfig, loaded: ServerConfig) -> Self {
+        let (applied, rejected) = changed(running, &loaded).into_iter().partition(|setting| reloadable(setting));
+        let mut effective = running.clone();
+        effective.logging.level = loaded.logging.level;
+        effective.logging.directives = loaded.logging.directives;
+        effective.ws_connection_limits = loaded.ws_connection_limits;
+        effective.trusted_proxies = loaded.trusted_proxies;
+        effective.format_limits = loaded.format_limits;
+        effective.lint = loaded.lint;
+        effective.slow_ops = loaded.slow_ops;
+        effective.metrics = loaded.metrics;
+        effective.alerts = loaded.alerts;
+        effective.downstreams = loaded.downstreams;
+        Self { effective, applied, rejected }
+    }
+
+    /// Whether an applied change lies within `section`
+    #[must_use]
+    pub fn applies(&self, section: &str) -> bool {
+        self.applied.iter().any(|setting| within(setting, section))
+    }
+
+    /// Whether any setting changed
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.rejected.is_empty()
+    }
+}
+
+/// Whether `setting` takes effect without a restart
+#[must_use]
+pub fn reloadable(setting: &str) -> bool {
+    RELOADABLE.iter().any(|section| within(setting, section))
+}
+
+/// Paths of the settings that differ between `old` and `new`
+///
+/// Lists compare as a whole, so a changed sink is reported as `alerts.sinks`.
+///
+/// # Panics
+///
+/// Panics if a configuration does not serialize to JSON, which none does.
+#[must_use]
+pub fn changed(old: &ServerConfig, new: &ServerConfig) -> Vec<String> {
+    let old = serde_json::to_value(old).expect("configuration serializes");
+    let new = serde_json::to_value(new).expect("configuration serializes");
+    let mut found = Vec::new();
+    diff(&old, &new, "", &mut found);
+    found
+}
+
+fn diff(old: &Value, new: &Value, path: &str, found: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                diff(old.get(key).unwrap_or(&Value::Null), new.get(key).unwrap_or(&Value::Null), &path, found);
+            }
+        }
+        _ if old != new => found.push(path.to_string()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::alerts::SinkConfig;
+
+    #[test]
+    fn test_changed_settings() {
+        let running = ServerConfig::default();
+        assert!(changed(&running, &running).is_empty());
+
+        let mut loaded = running.clone();
+        loaded.format_limits.max_input_bytes = 1024;
+        loaded.logging.directives = vec!["hyper=warn".to_string()];
+        loaded.statsd = Some(serde_json::from_str(r#"{"addr": "127.0.0.1:8125"}"#).unwrap());
+        loaded.alerts.sinks.push(SinkConfig {
+            name: "ops".to_string(),
+            url: "https://hooks.example.com/T000/B000/secret".to_string(),
+            template: crate::monitoring::alerts::Template::Slack,
+            min_severity: crate::monitoring::rules::Severity::Warning,
+            routing_key: None,
+        });
+        assert_eq!(
+            changed(&running, &loaded),
+            ["alerts.sinks", "format_limits.max_input_bytes", "logging.directives", "statsd"]
+        );
+    }
+
+    #[test]
+    fn test_plan_keeps_settings_needing_a_restart() {
+        let running = ServerConfig::default();
+        let mut loaded = running.clone();
+        loaded.http_addr = "127.0.0.1:9000".to_string();
+        loaded.data_dir = Some("/srv/ulc".into());
+        loaded.logging.level = "debug".to_string();
+        loaded.logging.format = crate::logging::LogFormat::Json;
+        loaded.ws_connection_limits.max_per_ip = 4;
+        loaded.trusted_proxies = crate::TrustedProxies::parse("10.0.0.0/8").unwrap();
+
+        let reload = Reload::plan(&running, loaded.clone());
+        assert_eq!(reload.applied, ["logging.level", "trusted_proxies", "ws_connection_limits.max_per_ip"]);
+        assert_eq!(reload.rejected, ["data_dir", "http_addr", "logging.format"]);
+        assert_eq!(reload.effective.http_addr, running.http_addr);
+        assert_eq!(reload.effective.logging.level, "debug");
+        assert_eq!(reload.effective.ws_connection_limits.max_per_ip, 4);
+
+        // What still differs is exactly what was refused
+        assert_eq!(changed(&reload.effective, &loaded), reload.rejected);
+        assert!(reload.rejected.iter().all(|setting| !reloadable(setting)));
+        assert!(Reload::plan(&running, running.clone()).is_empty());
+    }
+}
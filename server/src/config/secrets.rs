@@ -0,0 +1,296 @@
+//! Secrets read from where they are kept, rather than written into the configuration
+//!
+//! A secret setting may hold a reference in place of its value:
+//!
+//! - `env:NAME` reads the environment variable `NAME`
+//! - `file:/run/secrets/jwt_secret` reads a file without its trailing
+//!   newline, as Kubernetes and Docker mount secrets
+//! - `vault:secret/data/connector#jwt_secret` reads a field of a `HashiCorp`
+//!   Vault secret, from the server at `VAULT_ADDR` with `VAULT_TOKEN`
+//!
+//! Any other value is the secret itself. References are resolved as the
+//! configuration is loaded, before it is checked, so a secret that cannot
+//! be read stops startup, naming its setting, and is checked like one
+//! written out. Reloads resolve them again.
+
+use crate::ServerConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Time allowed for each request to Vault
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the value of a secret setting is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// The value as written
+    Inline(String),
+    /// An environment variable, `env:NAME`
+    Env(String),
+    /// A file, `file:/path`
+    File(PathBuf),
+    /// A field of a Vault secret, `vault:path#field`
+    Vault { path: String, field: String },
+}
+
+impl SecretSource {
+    /// The source a setting's value names, or the value itself
+    ///
+    /// # Errors
+    ///
+    /// Fails where an `env:` or `file:` reference names nothing, or a `vault:`
+    /// one is not `vault:<path>#<field>`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((scheme, rest)) = value.split_once(':') else { return Ok(Self::Inline(value.to_string())) };
+        Ok(match scheme {
+            "env" if !rest.is_empty() => Self::Env(rest.to_string()),
+            "file" if !rest.is_empty() => Self::File(PathBuf::from(rest)),
+            "vault" => match rest.rsplit_once('#') {
+                Some((path, field)) if !path.is_empty() && !field.is_empty() => {
+                    Self::Vault { path: path.trim_matches('/').to_string(), field: field.to_string() }
+                }
+                _ => bail!("{value:?} is not vault:<path>#<field>"),
+            },
+            "env" | "file" => bail!("{value:?} names no {scheme}"),
+            _ => Self::Inline(value.to_string()),
+        })
+    }
+}
+
+/// Where secrets kept outside the configuration are fetched from
+pub trait SecretStore: Send + Sync {
+    /// The `field` of the secret at `path`
+    ///
+    /// # Errors
+    ///
+    /// Fails where the secret or its field cannot be read.
+    fn read(&self, path: &str, field: &str) -> Result<String>;
+}
+
+/// Reads the secrets settings refer to, from the variables given and the store they name
+pub struct SecretReader<'a> {
+    vars: &'a [(String, String)],
+    store: Option<&'a dyn SecretStore>,
+}
+
+impl<'a> SecretReader<'a> {
+    /// Read `env:` references from `vars`, and `vault:` ones from the server their `VAULT_` variables name
+    #[must_use]
+    pub fn new(vars: &'a [(String, String)]) -> Self {
+        Self { vars, store: None }
+    }
+
+    /// Read `vault:` references from `store` instead
+    #[must_use]
+    pub fn with_store(mut self, store: &'a dyn SecretStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    fn var(&self, name: &str) -> Option<&'a str> {
+        self.vars.iter().find(|(each, _)| each == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The value of the secret `source` names
+    ///
+    /// # Errors
+    ///
+    /// Fails where the variable is not set, the file cannot be read, or the
+    /// Vault server cannot be reached or has no such secret.
+    pub fn read(&self, source: &SecretSource) -> Result<String> {
+        match source {
+            SecretSource::Inline(value) => Ok(value.clone()),
+            SecretSource::Env(name) => self.var(name).map(str::to_string).ok_or_else(|| anyhow!("{name} is not set")),
+            SecretSource::File(path) => {
+                let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+                Ok(text.trim_end_matches(['\r', '\n']).to_string())
+            }
+            SecretSource::Vault { path, field } => match self.store {
+                Some(store) => store.read(path, field),
+                None => Vault::from_vars(self)?.read(path, field),
+            },
+        }
+    }
+
+    /// Replace the references in the secret settings of `config` with the secrets they name
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first secret that cannot be read, naming its setting.
+    pub fn resolve(&self, config: &mut ServerConfig) -> Result<()> {
+        let mut secrets: Vec<(String, &mut String)> = vec![("jwt_secret".to_string(), &mut config.jwt_secret)];
+        if let Some(password) = config.ldap.as_mut().and_then(|ldap| ldap.bind_password.as_mut()) {
+            secrets.push(("ldap.bind_password".to_string(), password));
+        }
+        if let Some(signing) = &mut config.request_signing {
+            for (key, client) in &mut signing.clients {
+                secrets.push((format!("request_signing.clients.{key}.secret"), &mut client.secret));
+            }
+        }
+        for (i, sink) in config.alerts.sinks.iter_mut().enumerate() {
+            if let Some(key) = &mut sink.routing_key {
+                secrets.push((format!("alerts.sinks[{i}].routing_key"), key));
+            }
+        }
+        for (path, value) in secrets {
+            let source = SecretSource::parse(value).with_context(|| path.clone())?;
+            if !matches!(source, SecretSource::Inline(_)) {
+                *value = self.read(&source).with_context(|| path.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `HashiCorp` Vault server, read through its HTTP API
+///
+/// Secrets of the KV engine are read at `/v1/<path>`; for version 2 the
+/// path includes `data/`, as in `secret/data/connector`.
+pub struct Vault {
+    addr: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl Vault {
+    pub fn new(addr: impl Into<String>, token: impl Into<String>) -> Self {
+        Self { addr: addr.into(), token: token.into(), namespace: None }
+    }
+
+    /// The server `VAULT_ADDR` names, with `VAULT_TOKEN` and any `VAULT_NAMESPACE`, as the Vault CLI reads them
+    fn from_vars(reader: &SecretReader<'_>) -> Result<Self> {
+        let addr = reader.var("VAULT_ADDR").ok_or_else(|| anyhow!("vault: references need VAULT_ADDR"))?;
+        let token = reader.var("VAULT_TOKEN").ok_or_else(|| anyhow!("vault: references need VAULT_TOKEN"))?;
+        let namespace = reader.var("VAULT_NAMESPACE").map(str::to_string);
+        Ok(Self { namespace, ..Self::new(addr, token) })
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Value> {
+        let client = reqwest::Client::builder().timeout(VAULT_TIMEOUT).build()?;
+        let mut request = client.get(url).header("X-Vault-Token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        Ok(request.send().await?.error_for_status()?.json().await?)
+    }
+}
+
+impl SecretStore for Vault {
+    fn read(&self, path: &str, field: &str) -> Result<String> {
+        let url = format!("{}/v1/{}", self.addr.trim_end_matches('/'), path);
+        // Configuration is loaded outside any runtime serving requests, or
+        // blocking one, so the request gets a runtime of its own
+        let body = std::thread::scope(|scope| {
+            scope
+                .spawn(|| tokio::runtime::Builder::new_current_thread().enable_all().build()?.block_on(self.fetch(&url)))
+                .join()
+                .map_err(|_| anyhow!("reading {path} from Vault panicked"))?
+        })
+        .with_context(|| format!("reading {path} from Vault"))?;
+        // Version 2 of the KV engine keeps the fields under data.data, version 1 under data
+        let data = &body["data"];
+        let fields = if data["metadata"].is_object() { &data["data"] } else { data };
+        fields[field].as_str().map(str::to_string).ok_or_else(|| anyhow!("Vault secret {path} has no field {field}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::directory::LdapConfig;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_references() {
+        struct Fixed;
+        impl SecretStore for Fixed {
+            fn read(&self, path: &str, field: &str) -> Result<String> {
+                Ok(format!("{path}/{field}"))
+            }
+        }
+
+        let parse = |value: &str| SecretSource::parse(value).unwrap();
+        assert_eq!(parse("s3cret"), SecretSource::Inline("s3cret".to_string()));
+        assert_eq!(parse("Zm9v:YmFy"), SecretSource::Inline("Zm9v:YmFy".to_string()));
+        assert_eq!(parse("env:JWT_SECRET"), SecretSource::Env("JWT_SECRET".to_string()));
+        assert_eq!(parse("file:/run/secrets/jwt"), SecretSource::File(PathBuf::from("/run/secrets/jwt")));
+        let vault = SecretSource::Vault { path: "secret/data/connector".to_string(), field: "jwt".to_string() };
+        assert_eq!(parse("vault:/secret/data/connector#jwt"), vault);
+        for broken in ["env:", "file:", "vault:secret/data/connector", "vault:#jwt"] {
+            assert!(SecretSource::parse(broken).is_err(), "{broken}");
+        }
+
+        let dir = std::env::temp_dir().join(format!("ulc-secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("bind"), "B1ND\r\n").unwrap();
+        let mut config = ServerConfig {
+            jwt_secret: "env:JWT_SECRET".to_string(),
+            ldap: Some(LdapConfig {
+                bind_password: Some(format!("file:{}", dir.join("bind").display())),
+                ..LdapConfig::new("ldaps://dc1.example.com", "dc=example,dc=com")
+            }),
+            ..ServerConfig::default()
+        };
+        config.alerts.sinks = serde_json::from_str(
+            r#"[{"name": "pager", "url": "https://events.pagerduty.com/v2/enqueue", "template": "pagerduty",
+                "routing_key": "vault:secret/data/pagerduty#key"}]"#,
+        )
+        .unwrap();
+        let vars = [("JWT_SECRET".to_string(), "from the environment".to_string())];
+        SecretReader::new(&vars).with_store(&Fixed).resolve(&mut config).unwrap();
+        assert_eq!(config.jwt_secret, "from the environment");
+        assert_eq!(config.ldap.as_ref().unwrap().bind_password.as_deref(), Some("B1ND"));
+        assert_eq!(config.alerts.sinks[0].routing_key.as_deref(), Some("secret/data/pagerduty/key"));
+
+        // A reference that cannot be read names its setting
+        let mut unset = ServerConfig { jwt_secret: "env:NOT_SET".to_string(), ..ServerConfig::default() };
+        let error = SecretReader::new(&[]).resolve(&mut unset).unwrap_err();
+        assert_eq!(format!("{error:#}"), "jwt_secret: NOT_SET is not set");
+        let mut vaulted = ServerConfig { jwt_secret: "vault:secret/data/connector#jwt".to_string(), ..ServerConfig::default() };
+        let error = SecretReader::new(&[]).resolve(&mut vaulted).unwrap_err();
+        assert_eq!(format!("{error:#}"), "jwt_secret: vault: references need VAULT_ADDR");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_vault_kv() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for body in [
+                r#"{"data": {"data": {"jwt": "s3cret"}, "metadata": {"version": 3}}}"#,
+                r#"{"data": {"jwt": "v1-s3cret"}}"#,
+            ] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    head.push(line.trim().to_ascii_lowercase());
+                }
+                requests.push(head);
+                let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let vars = [("VAULT_ADDR".to_string(), addr), ("VAULT_TOKEN".to_string(), "hvs.T0K3N".to_string())];
+        let reader = SecretReader::new(&vars);
+        let read = |path: &str| reader.read(&SecretSource::parse(path).unwrap());
+        assert_eq!(read("vault:secret/data/connector#jwt").unwrap(), "s3cret");
+        assert_eq!(read("vault:kv/connector#jwt").unwrap(), "v1-s3cret");
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0][0], "get /v1/secret/data/connector http/1.1");
+        assert!(requests[0].contains(&"x-vault-token: hvs.t0k3n".to_string()), "{:?}", requests[0]);
+        assert_eq!(requests[1][0], "get /v1/kv/connector http/1.1");
+    }
+}
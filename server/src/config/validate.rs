@@ -0,0 +1,1603 @@
+//! Checks on settings whose type alone does not make them valid
+//!
+//! They run while the configuration loads, before any listener binds. Each
+//! problem names the setting at fault and a fix. Errors stop the server;
+//! warnings are logged, and stop it too when it is started with `--strict`.
+
+use super::duration;
+use crate::audit::AuditSinkConfig;
+use crate::auth::directory;
+use crate::auth::mtls;
+use crate::auth::policy::Route;
+use crate::auth::roles;
+use crate::auth::{self, RateLimitTier, SigningKey, TokenAlgorithm};
+use crate::formats::lint;
+use crate::formats::schema::Schema;
+use crate::proxy::DownstreamTransport;
+use crate::scheduler;
+use crate::websocket::CLIENT_MESSAGES;
+use crate::ServerConfig;
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Secrets shipped as development defaults, refused when auth is on
+const DEFAULT_SECRETS: &[&str] = &["dev-secret-change-in-production", "change-this-secret-in-production"];
+
+/// Shortest JWT secret accepted, in characters
+const MIN_SECRET_CHARS: usize = 32;
+
+/// Estimated entropy under which a JWT secret draws a warning, in bits
+const MIN_SECRET_BITS: f64 = 96.0;
+
+/// Largest UDP payload, and so the largest `StatsD` packet
+const MAX_DATAGRAM_BYTES: usize = 65_507;
+
+/// A problem with one setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Path of the setting, such as `ws_addr` or `ws_connection_limits.max_total`
+    pub path: String,
+    pub message: String,
+    /// What to change
+    pub fix: String,
+    /// Logged rather than refused, unless startup is strict
+    pub warning: bool,
+}
+
+impl ConfigError {
+    fn error(path: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { path: path.to_string(), message: message.into(), fix: fix.into(), warning: false }
+    }
+
+    fn warning(path: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { warning: true, ..Self::error(path, message, fix) }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}; {}", self.path, self.message, self.fix)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Check the settings whose type alone does not make them valid, returning every problem
+    ///
+    /// # Errors
+    ///
+    /// Every [`ConfigError`] found, those only warned of among them.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut problems = Vec::new();
+        check_components(self, &mut problems);
+        check_secret(self, &mut problems);
+        check_oidc(self, &mut problems);
+        check_mtls(self, &mut problems);
+        check_signing(self, &mut problems);
+        check_ldap(self, &mut problems);
+        check_saml(self, &mut problems);
+        check_token_lifetimes(self, &mut problems);
+        check_lockout(self, &mut problems);
+        check_csrf(self, &mut problems);
+        check_roles(self, &mut problems);
+        check_policy(self, &mut problems);
+        check_rate_limit(self, &mut problems);
+        check_audit(self, &mut problems);
+        check_listeners(self, &mut problems);
+        check_urls(self, &mut problems);
+        check_directories(self, &mut problems);
+        check_limits(self, &mut problems);
+        check_downstreams(self, &mut problems);
+        check_schemas(self, &mut problems);
+        check_lint(self, &mut problems);
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// Settings the modules using them check
+fn check_components(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    if let Err(e) = config.logging.filter() {
+        problems.push(ConfigError::error(
+            "logging",
+            format!("{e:#}"),
+            "use a level such as info, and directives such as tower_http=debug",
+        ));
+    }
+    let ratio = config.tracing.sampling_ratio;
+    if !(0.0..=1.0).contains(&ratio) {
+        problems.push(ConfigError::error(
+            "tracing.sampling_ratio",
+            format!("{ratio} is not between 0 and 1"),
+            "use a fraction, such as 0.1 to sample one trace in ten",
+        ));
+    }
+    if let Err(e) = crate::monitoring::rules::validate(&config.alert_rules) {
+        problems.push(ConfigError::error("alert_rules", format!("{e:#}"), "correct the rule or remove it"));
+    }
+    if let Err(e) = config.alerts.validate() {
+        problems.push(ConfigError::error("alerts", format!("{e:#}"), "correct the sink or remove it"));
+    }
+    if let Err(e) = config.metrics.validate() {
+        problems.push(ConfigError::error(
+            "metrics",
+            format!("{e:#}"),
+            "use metric or group names listed by GET /api/admin/metrics/controls",
+        ));
+    }
+}
+
+fn check_secret(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    if !config.enable_auth {
+        return;
+    }
+    if config.jwt_algorithm != TokenAlgorithm::Hs256 {
+        check_keys(config, problems);
+        return;
+    }
+    let secret = &config.jwt_secret;
+    let fix = "set it to a random value, such as the output of `openssl rand -base64 48`";
+    let chars = secret.chars().count();
+    if is_development_secret(secret) {
+        problems.push(ConfigError::error("jwt_secret", "is the development default, with auth enabled", fix));
+    } else if chars < MIN_SECRET_CHARS {
+        problems.push(ConfigError::error(
+            "jwt_secret",
+            format!("has {chars} characters; at least {MIN_SECRET_CHARS} are needed"),
+            fix,
+        ));
+    } else if entropy_bits(secret) < MIN_SECRET_BITS {
+        problems.push(ConfigError::warning("jwt_secret", "repeats too few characters to be hard to guess", fix));
+    }
+}
+
+/// Whether `secret` is one shipped as a development default
+pub(crate) fn is_development_secret(secret: &str) -> bool {
+    DEFAULT_SECRETS.contains(&secret)
+}
+
+/// Check the key files of a public-key algorithm parse, and that tokens can be verified at all
+fn check_keys(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let algorithm = config.jwt_algorithm;
+    let fix = format!("name a PEM file holding a PKCS#8 {} key", algorithm.as_str());
+    let unkeyed = config.jwt_private_key_file.is_none() && config.jwt_public_key_files.is_empty();
+    if unkeyed && config.oidc.is_none() && config.mtls.is_none() {
+        problems.push(ConfigError::error(
+            "jwt_private_key_file",
+            format!("is unset, as is jwt_public_key_files, so no {} token can be verified", algorithm.as_str()),
+            "set either or both, or configure [oidc] or [mtls]",
+        ));
+    }
+    if let Some(path) = &config.jwt_private_key_file {
+        if let Err(e) = auth::read_key(path).and_then(|pem| SigningKey::from_pem(algorithm, &pem)) {
+            problems.push(ConfigError::error("jwt_private_key_file", format!("{e:#}"), &fix));
+        }
+    }
+    for (i, path) in config.jwt_public_key_files.iter().enumerate() {
+        if let Err(e) = auth::read_key(path).and_then(|pem| auth::verifying_key(algorithm, &pem)) {
+            problems.push(ConfigError::error(&format!("jwt_public_key_files[{i}]"), format!("{e:#}"), &fix));
+        }
+    }
+}
+
+/// Check the identity provider can be reached safely and its tokens mapped
+fn check_oidc(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let Some(oidc) = &config.oidc else { return };
+    if !config.enable_auth {
+        problems.push(ConfigError::warning("oidc", "is set, but enable_auth is false", "set enable_auth = true"));
+    }
+    let fix = "use the issuer URL the provider puts in its tokens, such as https://sso.example.com/realms/main";
+    match reqwest::Url::parse(&oidc.issuer) {
+        Ok(url) if url.scheme() == "https" => {}
+        Ok(url) if url.scheme() == "http" && is_local(&url) => {}
+        // Keys fetched over plain http could be swapped in transit, and any token forged
+        Ok(url) if url.scheme() == "http" => {
+            problems.push(ConfigError::error("oidc.issuer", "is plain http, so its keys could be forged", fix));
+        }
+        _ => problems.push(ConfigError::error("oidc.issuer", "is not an http or https URL", fix)),
+    }
+    if oidc.audience.is_empty() {
+        problems.push(ConfigError::error("oidc.audience", "is empty", "use the client ID registered with the provider"));
+    }
+    for (path, claim) in [("oidc.scope_claim", &oidc.scope_claim), ("oidc.subject_claim", &oidc.subject_claim)] {
+        if claim.is_empty() {
+            problems.push(ConfigError::error(path, "is empty", "name a claim, or remove the setting for the default"));
+        }
+    }
+    if oidc.jwks_refresh.is_zero() {
+        problems.push(ConfigError::error("oidc.jwks_refresh", "is 0", "use 1m or more"));
+    }
+}
+
+/// Check the listeners' certificate and the client authorities load, and certificates map to identities
+fn check_mtls(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let Some(mtls) = &config.mtls else { return };
+    if !config.enable_auth {
+        let message = "is set, but enable_auth is false, so any certificate the authorities issued may do anything";
+        problems.push(ConfigError::warning("mtls", message, "set enable_auth = true"));
+    }
+    if config.enable_grpc {
+        let message = "serves clients without certificates, though [mtls] is set";
+        problems.push(ConfigError::warning("enable_grpc", message, "set enable_grpc = false, or require tokens of gRPC clients"));
+    }
+    let found = problems.len();
+    if mtls.client_ca_files.is_empty() {
+        let message = "is empty, so no client certificate is accepted";
+        problems.push(ConfigError::error("mtls.client_ca_files", message, "name the PEM certificate of the authority issuing client certificates"));
+    }
+    for (i, path) in mtls.client_ca_files.iter().enumerate() {
+        if let Err(e) = mtls::certificates(path) {
+            problems.push(ConfigError::error(&format!("mtls.client_ca_files[{i}]"), format!("{e:#}"), "name a PEM certificate file"));
+        }
+    }
+    if let Err(e) = mtls::certificates(&mtls.cert_file) {
+        problems.push(ConfigError::error("mtls.cert_file", format!("{e:#}"), "name the PEM certificate chain of this server"));
+    }
+    // With the certificates readable, what is left to fail is the key
+    if problems.len() == found {
+        if let Err(e) = mtls::acceptor(mtls, &[]) {
+            problems.push(ConfigError::error("mtls.key_file", format!("{e:#}"), "name the PEM private key of mtls.cert_file"));
+        }
+    }
+    for (subject, identity) in &mtls.identities {
+        let path = format!("mtls.identities.{subject}");
+        if subject.is_empty() {
+            problems.push(ConfigError::error(&path, "names no subject", "use a subject such as CN=alice,O=Example, or a common name"));
+        } else if identity.scopes.is_empty() {
+            problems.push(ConfigError::warning(&path, "grants no scopes", "list scopes such as role:editor, or remove the identity"));
+        }
+    }
+}
+
+/// Check request signing clients have secrets as strong as a JWT secret, and something to do
+fn check_signing(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let Some(signing) = &config.request_signing else { return };
+    if !config.enable_auth {
+        let message = "is set, but enable_auth is false, so signatures are not checked";
+        problems.push(ConfigError::warning("request_signing", message, "set enable_auth = true"));
+    }
+    if signing.max_skew.is_zero() {
+        problems.push(ConfigError::error("request_signing.max_skew", "is 0, so no signature is ever fresh", "use 5m"));
+    }
+    for (key, client) in &signing.clients {
+        let path = format!("request_signing.clients.{key}");
+        if key.is_empty() || key.contains(|c: char| c == ',' || c == '=' || c.is_whitespace()) {
+            let fix = "use a name without spaces, commas or =, such as ci";
+            problems.push(ConfigError::error(&path, "is not a key ID a signature can name", fix));
+        }
+        let chars = client.secret.chars().count();
+        if chars < MIN_SECRET_CHARS {
+            problems.push(ConfigError::error(
+                &format!("{path}.secret"),
+                format!("has {chars} characters; at least {MIN_SECRET_CHARS} are needed"),
+                "set it to a random value, such as the output of `openssl rand -base64 48`",
+            ));
+        }
+        if client.scopes.is_empty() {
+            let message = "is empty, so its requests may do nothing";
+            problems.push(ConfigError::warning(&format!("{path}.scopes"), message, "list scopes such as read"));
+        }
+    }
+}
+
+/// Check the directory is reached over TLS, users can be found in it, and logging in grants something
+fn check_ldap(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let Some(ldap) = &config.ldap else { return };
+    if !config.enable_auth {
+        let message = "is set, but enable_auth is false, so /auth/login is not served";
+        problems.push(ConfigError::warning("ldap", message, "set enable_auth = true"));
+    }
+    if !cfg!(feature = "ldap") {
+        problems.push(ConfigError::error("ldap", "is set, but this build has no ldap feature", "build with --features ldap, or remove it"));
+    }
+    let fix = "use ldaps://host, or ldap://host with start_tls = true";
+    match reqwest::Url::parse(&ldap.url) {
+        Ok(url) if url.scheme() == "ldaps" && ldap.start_tls => {
+            problems.push(ConfigError::error("ldap.start_tls", "is set, but the URL is already ldaps://", "set start_tls = false"));
+        }
+        Ok(url) if url.scheme() == "ldaps" || (url.scheme() == "ldap" && (ldap.start_tls || is_local(&url))) => {}
+        // Passwords bound with over plain LDAP could be read in transit
+        Ok(url) if url.scheme() == "ldap" => {
+            let message = "is plain ldap without start_tls, so passwords cross the network in the clear";
+            problems.push(ConfigError::error("ldap.url", message, fix));
+        }
+        _ => problems.push(ConfigError::error("ldap.url", "is not an ldap or ldaps URL", fix)),
+    }
+    if ldap.bind_dn.is_some() != ldap.bind_password.is_some() {
+        let message = "is set without the other of bind_dn and bind_password";
+        let path = if ldap.bind_dn.is_some() { "ldap.bind_dn" } else { "ldap.bind_password" };
+        problems.push(ConfigError::error(path, message, "set both to search as a service account, or neither to search anonymously"));
+    }
+    if ldap.user_base.trim().is_empty() {
+        let fix = "name the entry users are under, such as ou=people,dc=example,dc=com";
+        problems.push(ConfigError::error("ldap.user_base", "is empty", fix));
+    }
+    if !ldap.user_filter.contains(directory::USERNAME) {
+        let message = format!("does not contain {}, so it finds the same entry whoever logs in", directory::USERNAME);
+        let fix = "use (uid={username}), or (sAMAccountName={username}) for Active Directory";
+        problems.push(ConfigError::error("ldap.user_filter", message, fix));
+    }
+    for (path, attribute) in [("ldap.subject_attribute", &ldap.subject_attribute), ("ldap.group_attribute", &ldap.group_attribute)] {
+        if attribute.is_empty() {
+            problems.push(ConfigError::error(path, "is empty", "name an attribute, or remove the setting for the default"));
+        }
+    }
+    if ldap.timeout.is_zero() {
+        problems.push(ConfigError::error("ldap.timeout", "is 0", "use 5s"));
+    }
+    if ldap.default_scopes.is_empty() && ldap.group_scopes.values().all(Vec::is_empty) {
+        let message = "is empty, as is ldap.default_scopes, so users who log in may do nothing";
+        problems.push(ConfigError::warning("ldap.group_scopes", message, "map groups to scopes, such as Editors = [\"read\", \"write\"]"));
+    }
+}
+
+/// Check assertions can be verified and are meant for the connector, and signing in grants something
+fn check_saml(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let Some(saml) = &config.saml else { return };
+    if !config.enable_auth {
+        let message = "is set, but enable_auth is false, so /auth/saml/acs is not served";
+        problems.push(ConfigError::warning("saml", message, "set enable_auth = true"));
+    }
+    for (path, entity_id) in [("saml.idp_entity_id", &saml.idp_entity_id), ("saml.sp_entity_id", &saml.sp_entity_id)] {
+        if entity_id.trim().is_empty() {
+            problems.push(ConfigError::error(path, "is empty", "copy the entity ID from the identity provider's metadata"));
+        }
+    }
+    let certificate = auth::read_key(&saml.idp_certificate_file).and_then(|pem| auth::verifying_key(TokenAlgorithm::Rs256, &pem));
+    if let Err(e) = certificate {
+        let fix = "use the PEM signing certificate from the identity provider's metadata";
+        problems.push(ConfigError::error("saml.idp_certificate_file", format!("{e:#}"), fix));
+    }
+    if let Some(acs_url) = &saml.acs_url {
+        let fix = "use the https:// URL the identity provider posts to, ending in /auth/saml/acs";
+        match reqwest::Url::parse(acs_url) {
+            Ok(url) if url.scheme() == "https" || (url.scheme() == "http" && is_local(&url)) => {}
+            // Bearer assertions posted over plain HTTP could be read and replayed
+            Ok(url) if url.scheme() == "http" => {
+                problems.push(ConfigError::error("saml.acs_url", "is plain http, so assertions cross the network in the clear", fix));
+            }
+            _ => problems.push(ConfigError::error("saml.acs_url", "is not an http or https URL", fix)),
+        }
+    }
+    if saml.subject_attribute.as_deref() == Some("") || saml.group_attribute.is_empty() {
+        let path = if saml.group_attribute.is_empty() { "saml.group_attribute" } else { "saml.subject_attribute" };
+        problems.push(ConfigError::error(path, "is empty", "name an attribute, or remove the setting for the default"));
+    }
+    if saml.clock_skew > Duration::from_mins(10) {
+        let message = format!("is {}, so expired assertions are accepted for that long", duration::format(saml.clock_skew));
+        problems.push(ConfigError::warning("saml.clock_skew", message, "use 2m, and keep both clocks in sync"));
+    }
+    if saml.default_scopes.is_empty() && saml.group_scopes.values().all(Vec::is_empty) {
+        let message = "is empty, as is saml.default_scopes, so users who sign in may do nothing";
+        problems.push(ConfigError::warning("saml.group_scopes", message, "map groups to scopes, such as Editors = [\"read\", \"write\"]"));
+    }
+}
+
+/// Check refresh tokens outlast the access tokens they renew, which stay short
+fn check_token_lifetimes(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let tokens = &config.tokens;
+    if tokens.access.is_zero() {
+        problems.push(ConfigError::error("tokens.access", "is 0, so every access token is expired when issued", "use 15m"));
+    } else if tokens.access > Duration::from_hours(1) {
+        // Access tokens cannot be revoked, so a leaked one is good until it expires
+        let message = format!("is {}, so a leaked access token is good for that long", duration::format(tokens.access));
+        problems.push(ConfigError::warning("tokens.access", message, "use 1h or less; clients refresh as often as they need"));
+    }
+    if tokens.refresh <= tokens.access {
+        let message = "is no longer than tokens.access, so refresh tokens expire before the access tokens they renew";
+        problems.push(ConfigError::error("tokens.refresh", message, "use days or weeks, such as 720h"));
+    }
+}
+
+/// Check failed logins are throttled, and locked out only after more than one
+fn check_lockout(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let lockout = &config.lockout;
+    if !lockout.enabled {
+        if config.ldap.is_some() {
+            let message = "is false, so directory passwords can be guessed at /auth/login as fast as requests are served";
+            problems.push(ConfigError::warning("lockout.enabled", message, "set it to true"));
+        }
+        return;
+    }
+    for (path, threshold) in [("lockout.max_failures", lockout.max_failures), ("lockout.max_failures_per_ip", lockout.max_failures_per_ip)] {
+        if threshold == 0 {
+            problems.push(ConfigError::error(path, "is 0, so the first failure locks out", "use 5 per username and 20 per IP"));
+        }
+    }
+    if lockout.lockout.is_zero() {
+        problems.push(ConfigError::error("lockout.lockout", "is 0, so failures are forgotten at once and nothing is locked out", "use 15m"));
+    }
+    if lockout.max_backoff < lockout.backoff {
+        let message = format!("is shorter than lockout.backoff, so every wait is {}", duration::format(lockout.max_backoff));
+        problems.push(ConfigError::warning("lockout.max_backoff", message, "make it longer than lockout.backoff, such as 30s"));
+    }
+}
+
+/// Check CSRF cookies and header are usable names, and allowed origins are bare origins
+fn check_csrf(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let csrf = &config.csrf;
+    if !csrf.enabled {
+        return;
+    }
+    if !config.enable_auth {
+        problems.push(ConfigError::warning("csrf.enabled", "is true, but enable_auth is false, so no token is taken from a cookie", "set enable_auth = true"));
+    }
+    for (path, name) in [("csrf.token_cookie", &csrf.token_cookie), ("csrf.cookie", &csrf.cookie)] {
+        if name.is_empty() || name.contains(|c: char| !c.is_ascii_graphic() || "()<>@,;:\\\"/[]?={}".contains(c)) {
+            problems.push(ConfigError::error(path, "is not a usable cookie name", "use letters, digits, - and _, such as ulc_csrf"));
+        }
+    }
+    if csrf.token_cookie == csrf.cookie {
+        let message = "is also csrf.token_cookie, so the CSRF token would overwrite the access token";
+        problems.push(ConfigError::error("csrf.cookie", message, "use another name, such as ulc_csrf"));
+    }
+    if axum::http::HeaderName::try_from(csrf.header.as_str()).is_err() {
+        problems.push(ConfigError::error("csrf.header", "is not a usable header name", "use X-CSRF-Token"));
+    }
+    for (i, origin) in csrf.allowed_origins.iter().enumerate() {
+        let bare = reqwest::Url::parse(origin)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.path() == "/" && url.query().is_none() && url.username().is_empty());
+        if !bare {
+            let message = "is not an origin, which browsers send as a scheme, host and port only";
+            problems.push(ConfigError::error(&format!("csrf.allowed_origins[{i}]"), message, "write it as https://ide.example.com"));
+        }
+    }
+}
+
+/// Check roles grant scopes rather than other roles, and the roles the identity provider and directory groups map to exist
+fn check_roles(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    for (name, scopes) in config.roles.iter() {
+        let path = format!("roles.{name}");
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            problems.push(ConfigError::error(&path, "is not a usable role name", "use a name without spaces, such as editor"));
+        }
+        if let Some(nested) = scopes.iter().find(|scope| scope.starts_with(roles::PREFIX)) {
+            let message = format!("names {nested}, and roles do not grant other roles");
+            problems.push(ConfigError::error(&path, message, "list the scopes of that role instead"));
+        } else if scopes.is_empty() {
+            problems.push(ConfigError::warning(&path, "grants no scopes", "list scopes such as read, or remove the role"));
+        }
+    }
+    let mapped = config.oidc.iter().flat_map(|oidc| oidc.scope_map.iter().map(|(value, scopes)| (format!("oidc.scope_map.{value}"), scopes)));
+    let grouped = config.ldap.iter().flat_map(|ldap| ldap.group_scopes.iter().map(|(group, scopes)| (format!("ldap.group_scopes.{group}"), scopes)));
+    let asserted = config.saml.iter().flat_map(|saml| saml.group_scopes.iter().map(|(group, scopes)| (format!("saml.group_scopes.{group}"), scopes)));
+    for (path, scopes) in mapped.chain(grouped).chain(asserted) {
+        let undefined = scopes
+            .iter()
+            .filter_map(|scope| scope.strip_prefix(roles::PREFIX))
+            .find(|name| config.roles.get(name).is_none());
+        if let Some(name) = undefined {
+            let message = format!("maps to role {name}, which is not defined, so it grants nothing");
+            problems.push(ConfigError::warning(&path, message, "define it under [roles]"));
+        }
+    }
+}
+
+/// Check policy rules name routes that parse and message types clients send, and require something
+fn check_policy(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let policy = &config.policy;
+    if !config.enable_auth && (!policy.routes.is_empty() || !policy.messages.is_empty()) {
+        problems.push(ConfigError::warning("policy", "is set, but enable_auth is false", "set enable_auth = true"));
+    }
+    for (route, scopes) in &policy.routes {
+        let path = format!("policy.routes.{route}");
+        if let Err(e) = Route::parse(route) {
+            problems.push(ConfigError::error(&path, e, "write it as \"METHOD /path\" or \"/path\", such as \"DELETE /api/documents/:id\""));
+        } else if scopes.is_empty() {
+            problems.push(ConfigError::warning(&path, "requires no scopes", "list scopes such as admin, or remove the rule"));
+        }
+    }
+    for (message, scopes) in &policy.messages {
+        let path = format!("policy.messages.{message}");
+        if !CLIENT_MESSAGES.contains(&message.as_str()) {
+            let message = format!("is not a message clients send, so the rule never applies; they send {}", CLIENT_MESSAGES.join(", "));
+            problems.push(ConfigError::error(&path, message, "use one of those message types"));
+        } else if scopes.is_empty() {
+            problems.push(ConfigError::warning(&path, "requires no scopes", "list scopes such as write, or remove the rule"));
+        }
+    }
+}
+
+fn check_rate_limit(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let rate_limit = &config.rate_limit;
+    if let Some(redis) = &rate_limit.redis {
+        if !cfg!(feature = "redis") {
+            let message = "is set, but this build has no redis feature, so limits are kept in memory";
+            problems.push(ConfigError::error("rate_limit.redis", message, "build with --features redis, or remove it"));
+        } else if let Err(e) = redis_url(&redis.url) {
+            problems.push(ConfigError::error("rate_limit.redis.url", e, "write it as redis://host:port/db, or rediss:// for TLS"));
+        }
+        if redis.timeout.is_zero() {
+            problems.push(ConfigError::error("rate_limit.redis.timeout", "is 0, so Redis is never waited for", "raise it, such as to 100ms"));
+        }
+        if !rate_limit.enabled {
+            problems.push(ConfigError::warning("rate_limit.redis", "is set, but rate_limit.enabled is false", "set rate_limit.enabled = true"));
+        }
+    }
+    if !rate_limit.enabled {
+        return;
+    }
+    for tier in [RateLimitTier::Anonymous, RateLimitTier::Authenticated, RateLimitTier::Elevated] {
+        let limits = rate_limit.limits(tier);
+        let path = format!("rate_limit.{}", tier.as_str());
+        if limits.burst == 0 {
+            let message = format!("has burst = 0, so every {} request is refused", tier.as_str());
+            problems.push(ConfigError::error(&path, message, "raise burst, or set rate_limit.enabled = false"));
+        } else if limits.requests_per_minute == 0 {
+            let message = "has requests_per_minute = 0, so a spent bucket never refills";
+            problems.push(ConfigError::warning(&path, message, "raise requests_per_minute"));
+        }
+    }
+}
+
+fn check_audit(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    for (i, sink) in config.audit.sinks.iter().enumerate() {
+        let path = format!("audit.sinks[{i}]");
+        match sink {
+            AuditSinkConfig::File { path: file } => {
+                // The file is created on the first event; its directory is not
+                let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                check_writable(&format!("{path}.path"), dir, problems);
+            }
+            AuditSinkConfig::Stdout if config.enable_lsp => {
+                let message = "writes to stdout, which carries LSP traffic while enable_lsp is true";
+                problems.push(ConfigError::error(&path, message, "use a file sink, or set enable_lsp = false"));
+            }
+            AuditSinkConfig::Stdout => {}
+            // The URL itself is left out, as it may carry a token
+            AuditSinkConfig::Http { url } => match reqwest::Url::parse(url) {
+                Ok(url) if url.scheme() == "https" => {}
+                Ok(url) if url.scheme() == "http" => {
+                    if !is_local(&url) {
+                        problems.push(ConfigError::warning(
+                            &format!("{path}.url"),
+                            "is sent over plain http",
+                            "use an https URL, so audit events cannot be read in transit",
+                        ));
+                    }
+                }
+                _ => problems.push(ConfigError::error(
+                    &format!("{path}.url"),
+                    "is not an http or https URL",
+                    "use a full URL, such as https://logs.example.com/ingest",
+                )),
+            },
+        }
+    }
+}
+
+/// Why `url` is not a Redis URL, if it is not
+#[cfg(feature = "redis")]
+fn redis_url(url: &str) -> Result<(), String> {
+    redis::Client::open(url).map(drop).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "redis"))]
+#[allow(clippy::unnecessary_wraps)] // As the Redis build's
+fn redis_url(_url: &str) -> Result<(), String> {
+    Ok(())
+}
+
+/// Whether `url` names this host
+fn is_local(url: &reqwest::Url) -> bool {
+    url.host_str().is_some_and(|host| host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()))
+}
+
+/// Entropy of `text` estimated from how often each of its characters occurs
+#[allow(clippy::cast_precision_loss)]
+fn entropy_bits(text: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in text.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let len = text.chars().count() as f64;
+    let per_char: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    per_char * len
+}
+
+fn check_listeners(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let mut bound: Vec<(&str, &str, u16)> = Vec::new();
+    for (enabled, path, addr, default_port) in [
+        (config.enable_http, "http_addr", &config.http_addr, 8080),
+        (config.enable_websocket, "ws_addr", &config.ws_addr, 8081),
+        (config.enable_grpc, "grpc_addr", &config.grpc_addr, 50051),
+    ] {
+        if !enabled {
+            continue;
+        }
+        let Some((host, port)) = host_and_port(addr) else {
+            problems.push(ConfigError::error(
+                path,
+                format!("{addr:?} is not a host and port"),
+                format!("write it as host:port, such as 0.0.0.0:{default_port}"),
+            ));
+            continue;
+        };
+        let any = |host: &str| host.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified());
+        let clash = bound.iter().find(|(_, other_host, other_port)| {
+            let same_interface = host.eq_ignore_ascii_case(other_host) || any(host) || any(other_host);
+            port == *other_port && port != 0 && same_interface
+        });
+        match clash {
+            Some((other, _, _)) => problems.push(ConfigError::error(
+                path,
+                format!("uses port {port}, as {other} does"),
+                format!("give {path} a port of its own, such as {default_port}"),
+            )),
+            None => bound.push((path, host, port)),
+        }
+    }
+    if config.enable_grpc && !cfg!(feature = "grpc") {
+        problems.push(ConfigError::error(
+            "enable_grpc",
+            "this build has no gRPC interface",
+            "build with --features grpc, or set enable_grpc = false",
+        ));
+    }
+    if !(config.enable_lsp || config.enable_http || config.enable_websocket || config.enable_grpc) {
+        problems.push(ConfigError::warning(
+            "enable_lsp",
+            "LSP, HTTP, WebSocket and gRPC are all disabled, so nothing is served",
+            "enable at least one of enable_lsp, enable_http, enable_websocket and enable_grpc",
+        ));
+    }
+}
+
+/// The host and port of a bind address such as `0.0.0.0:8080` or `[::1]:8080`
+fn host_and_port(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']')?,
+        // An IPv6 address needs brackets to be told from its port
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+fn check_urls(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let urls = [
+        ("lifecycle_webhook", config.lifecycle_webhook.as_deref(), true),
+        ("alert_webhook", config.alert_webhook.as_deref(), true),
+        // Collectors commonly listen without TLS inside the cluster
+        ("tracing.otlp_endpoint", config.tracing.otlp_endpoint.as_deref(), false),
+    ];
+    for (path, url, private) in urls {
+        let Some(url) = url else { continue };
+        // The URL itself is left out, as it may carry a token
+        match reqwest::Url::parse(url) {
+            Ok(url) if url.scheme() == "https" => {}
+            Ok(url) if url.scheme() == "http" => {
+                if private && !is_local(&url) {
+                    problems.push(ConfigError::warning(
+                        path,
+                        "is sent over plain http",
+                        "use an https URL, so notifications cannot be read in transit",
+                    ));
+                }
+            }
+            _ => problems.push(ConfigError::error(
+                path,
+                "is not an http or https URL",
+                "use a full URL, such as https://hooks.example.com/notify",
+            )),
+        }
+    }
+}
+
+fn check_directories(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    if let Some(dir) = &config.data_dir {
+        check_writable("data_dir", dir, problems);
+    }
+    if let Some(dir) = &config.plugins.dir {
+        if !dir.is_dir() {
+            problems.push(ConfigError::error(
+                "plugins.dir",
+                format!("{} is not a directory", dir.display()),
+                "create it, or name an existing directory",
+            ));
+        } else if !cfg!(feature = "wasm-plugins") {
+            problems.push(ConfigError::warning(
+                "plugins.dir",
+                "is set, but this build has no wasm-plugins feature, so no plugin is loaded",
+                "build with --features wasm-plugins, or remove it",
+            ));
+        }
+    }
+    // The log file's directory is created when logging starts; the rollup's is not
+    if let Some(dir) = config.usage.rollup_file.as_deref().and_then(Path::parent) {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        check_writable("usage.rollup_file", dir, problems);
+    }
+}
+
+/// Check that files can be created in `dir`, by creating one
+fn check_writable(path: &str, dir: &Path, problems: &mut Vec<ConfigError>) {
+    if !dir.is_dir() {
+        problems.push(ConfigError::error(
+            path,
+            format!("{} is not a directory", dir.display()),
+            "create it, or name an existing directory",
+        ));
+        return;
+    }
+    let probe = dir.join(format!(".ulc-write-check-{}", uuid::Uuid::new_v4()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+        }
+        Err(e) => problems.push(ConfigError::error(
+            path,
+            format!("{} is not writable: {e}", dir.display()),
+            "give the server's user write access to it",
+        )),
+    }
+}
+
+#[allow(clippy::too_many_lines)] // A check or two per limit
+fn check_limits(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let limits = &config.ws_connection_limits;
+    for (path, value) in [
+        ("ws_connection_limits.max_total", limits.max_total),
+        ("ws_connection_limits.max_per_subject", limits.max_per_subject),
+        ("ws_connection_limits.max_per_ip", limits.max_per_ip),
+    ] {
+        if value == 0 {
+            problems.push(ConfigError::error(
+                path,
+                "is 0, so every WebSocket connection is refused",
+                "raise it, or set enable_websocket = false",
+            ));
+        } else if value > limits.max_total {
+            problems.push(ConfigError::warning(
+                path,
+                format!("is above max_total ({}), so it is never reached", limits.max_total),
+                "lower it to max_total or below",
+            ));
+        }
+    }
+
+    let formats = &config.format_limits;
+    for (path, value) in [
+        ("format_limits.max_input_bytes", formats.max_input_bytes),
+        ("format_limits.max_output_bytes", formats.max_output_bytes),
+    ] {
+        if value == 0 {
+            problems.push(ConfigError::error(path, "is 0, so every document is refused", "raise it"));
+        }
+    }
+    if formats.max_output_bytes < formats.max_input_bytes {
+        problems.push(ConfigError::warning(
+            "format_limits.max_output_bytes",
+            "is below max_input_bytes, so the largest documents accepted cannot be converted",
+            "raise it to max_input_bytes or above",
+        ));
+    }
+
+    if config.plugins.max_memory_bytes == 0 {
+        problems.push(ConfigError::error("plugins.max_memory_bytes", "is 0, so no plugin can run", "raise it"));
+    }
+    if config.plugins.fuel == 0 {
+        problems.push(ConfigError::error("plugins.fuel", "is 0, so every plugin call fails", "raise it"));
+    }
+
+    let lifecycle = &config.lifecycle_thresholds;
+    for (path, value) in [
+        ("lifecycle_thresholds.degrade_after", lifecycle.degrade_after),
+        ("lifecycle_thresholds.recover_after", lifecycle.recover_after),
+        ("lifecycle_thresholds.unready_after", lifecycle.unready_after),
+        ("lifecycle_thresholds.restart_after", lifecycle.restart_after),
+    ] {
+        if value == 0 {
+            problems.push(ConfigError::error(path, "is 0", "use 1 to act on the first evaluation"));
+        }
+    }
+    if lifecycle.restart_after < lifecycle.unready_after {
+        problems.push(ConfigError::warning(
+            "lifecycle_thresholds.restart_after",
+            "is below unready_after, so the server is restarted before traffic is moved away",
+            "raise it to unready_after or above",
+        ));
+    }
+
+    if config.usage.per_subject && config.usage.capacity == 0 {
+        problems.push(ConfigError::error(
+            "usage.capacity",
+            "is 0, so no subject is counted",
+            "raise it, or set usage.per_subject = false",
+        ));
+    }
+
+    if config.jobs.workers == 0 {
+        problems.push(ConfigError::error("jobs.workers", "is 0, so no job runs", "use 1 or more"));
+    }
+    let default_limits = ("jobs.default_limits".to_string(), &config.jobs.default_limits);
+    let classes = config.jobs.classes.iter().map(|(class, limits)| (format!("jobs.classes.{class}"), limits));
+    for (path, limits) in std::iter::once(default_limits).chain(classes) {
+        if limits.concurrency == 0 {
+            let message = "is 0, so no job runs";
+            problems.push(ConfigError::error(&format!("{path}.concurrency"), message, "use 1 or more"));
+        }
+        if limits.max_queued == 0 {
+            problems.push(ConfigError::error(
+                &format!("{path}.max_queued"),
+                "is 0, so every job is refused",
+                "use 1 or more",
+            ));
+        }
+    }
+
+    for name in config.tasks.schedules.keys().filter(|name| !scheduler::BUILT_IN.contains(&name.as_str())) {
+        problems.push(ConfigError::warning(
+            &format!("tasks.schedules.{name}"),
+            "names no built-in task, so it is ignored",
+            format!("use one of {}", scheduler::BUILT_IN.join(", ")),
+        ));
+    }
+
+    if let Some(statsd) = &config.statsd {
+        if statsd.interval.is_zero() {
+            problems.push(ConfigError::error("statsd.interval", "is 0", "use 1s or more"));
+        }
+        if !(64..=MAX_DATAGRAM_BYTES).contains(&statsd.max_packet_bytes) {
+            problems.push(ConfigError::error(
+                "statsd.max_packet_bytes",
+                format!("{} is not between 64 and {MAX_DATAGRAM_BYTES}", statsd.max_packet_bytes),
+                "use 1432 on Ethernet, or 8932 with jumbo frames",
+            ));
+        }
+    }
+}
+
+fn check_downstreams(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let mut names = HashSet::new();
+    for (i, downstream) in config.downstreams.iter().enumerate() {
+        let path = |key: &str| format!("downstreams[{i}].{key}");
+        if downstream.name.trim().is_empty() {
+            problems.push(ConfigError::error(&path("name"), "is empty", "name the server, such as rust-analyzer"));
+        } else if !names.insert(downstream.name.as_str()) {
+            problems.push(ConfigError::error(
+                &path("name"),
+                format!("{} names an earlier downstream too", downstream.name),
+                "give each downstream its own name",
+            ));
+        }
+        match &downstream.transport {
+            DownstreamTransport::Stdio(command) if command.first().map_or("", String::as_str).is_empty() => {
+                problems.push(ConfigError::error(
+                    &path("command"),
+                    "has no program",
+                    "list the program and its arguments, such as [\"rust-analyzer\"]",
+                ));
+            }
+            DownstreamTransport::Tcp(addr)
+                if !addr.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) =>
+            {
+                problems.push(ConfigError::error(
+                    &path("tcp"),
+                    format!("{addr} is not a host and port"),
+                    "use host:port, such as 127.0.0.1:9257",
+                ));
+            }
+            DownstreamTransport::WebSocket(url) if !(url.starts_with("ws://") || url.starts_with("wss://")) => {
+                problems.push(ConfigError::error(
+                    &path("websocket"),
+                    format!("{url} is not a WebSocket URL"),
+                    "use a ws:// or wss:// URL",
+                ));
+            }
+            _ => {}
+        }
+        if downstream.languages.is_empty() && downstream.patterns.is_empty() {
+            problems.push(ConfigError::warning(
+                &path("languages"),
+                "and patterns are both empty, so no document is routed to it",
+                "list the language IDs or file patterns it serves",
+            ));
+        }
+        if downstream.request_timeout.is_zero() {
+            let message = "is 0, so every request fails";
+            problems.push(ConfigError::error(&path("request_timeout"), message, "use 1s or more"));
+        }
+    }
+}
+
+fn check_schemas(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let mut names = HashSet::new();
+    for (i, schema) in config.schemas.iter().enumerate() {
+        let path = |key: &str| format!("schemas[{i}].{key}");
+        if schema.name.trim().is_empty() {
+            problems.push(ConfigError::error(&path("name"), "is empty", "name the schema, such as deploy"));
+        } else if !names.insert(schema.name.as_str()) {
+            problems.push(ConfigError::error(
+                &path("name"),
+                format!("{} names an earlier schema too", schema.name),
+                "give each schema its own name",
+            ));
+        }
+        if let Err(e) = Schema::load(&schema.path) {
+            problems.push(ConfigError::error(
+                &path("path"),
+                format!("{e:#}"),
+                "point it at a draft 2020-12 JSON Schema in JSON, YAML or TOML",
+            ));
+        }
+    }
+}
+
+fn check_lint(config: &ServerConfig, problems: &mut Vec<ConfigError>) {
+    let mut rules = vec![("lint.rules".to_string(), &config.lint.rules)];
+    for (i, workspace) in config.lint.workspaces.iter().enumerate() {
+        if workspace.documents.is_empty() {
+            let message = "is empty, so the workspace has no documents";
+            problems.push(ConfigError::warning(&format!("lint.workspaces[{i}].documents"), message, "list globs such as /home/me/project/**"));
+        }
+        rules.push((format!("lint.workspaces[{i}].rules"), &workspace.rules));
+    }
+    for (path, severities) in rules {
+        for id in severities.keys().filter(|id| lint::rule(id).is_none()) {
+            problems.push(ConfigError::warning(
+                &format!("{path}.{id}"),
+                "names no rule, so it changes nothing",
+                "use a rule ID such as yaml/no-duplicate-keys",
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::directory::LdapConfig;
+    use crate::auth::mtls::{CertIdentity, MtlsConfig};
+    use crate::auth::oidc::OidcConfig;
+    use crate::auth::policy::ScopePolicy;
+    use crate::auth::rate_limit::RedisConfig;
+    use crate::auth::saml::SamlConfig;
+    use crate::auth::{RateLimitConfig, TierLimits};
+    use crate::auth::lockout::LockoutConfig;
+    use crate::auth::csrf::CsrfConfig;
+    use crate::jobs::ClassLimits;
+    use crate::monitoring::statsd::{StatsdConfig, StatsdTarget};
+    use crate::auth::refresh::TokenLifetimes;
+    use crate::auth::roles::Roles;
+    use crate::auth::signing::{SigningClient, SigningConfig};
+    use crate::proxy::DownstreamConfig;
+    use crate::formats::lint::{Severity, WorkspaceRules};
+    use crate::formats::schema::SchemaConfig;
+
+    /// Paths of the problems found, with whether each is a warning
+    fn problems(config: &ServerConfig) -> Vec<(String, bool)> {
+        config
+            .validate()
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|problem| (problem.path, problem.warning))
+            .collect()
+    }
+
+    fn error(path: &str) -> Vec<(String, bool)> {
+        vec![(path.to_string(), false)]
+    }
+
+    fn warning(path: &str) -> Vec<(String, bool)> {
+        vec![(path.to_string(), true)]
+    }
+
+    fn scratch_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ulc-validate-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_defaults_pass() {
+        assert_eq!(ServerConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_component_settings() {
+        let mut config = ServerConfig::default();
+        config.logging.level = "loud".to_string();
+        assert_eq!(problems(&config), error("logging"));
+
+        let mut config = ServerConfig::default();
+        config.tracing.sampling_ratio = 1.5;
+        assert_eq!(problems(&config), error("tracing.sampling_ratio"));
+        config.tracing.sampling_ratio = 0.0;
+        assert!(problems(&config).is_empty());
+
+        let mut config = ServerConfig::default();
+        config.metrics.disabled = vec!["ulc_nope".to_string()];
+        assert_eq!(problems(&config), error("metrics"));
+
+        let mut config = ServerConfig::default();
+        config.alerts.max_attempts = 0;
+        assert_eq!(problems(&config), error("alerts"));
+    }
+
+    #[test]
+    fn test_secret_strength() {
+        let with_secret = |secret: &str| ServerConfig {
+            enable_auth: true,
+            jwt_secret: secret.to_string(),
+            ..ServerConfig::default()
+        };
+        assert_eq!(problems(&with_secret("dev-secret-change-in-production")), error("jwt_secret"));
+        assert_eq!(problems(&with_secret("short-but-not-default")), error("jwt_secret"));
+        assert_eq!(problems(&with_secret(&"a".repeat(64))), warning("jwt_secret"));
+        assert_eq!(problems(&with_secret(&"password".repeat(4))), warning("jwt_secret"));
+        assert!(problems(&with_secret("Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY")).is_empty());
+
+        // Only checked when tokens are checked
+        assert!(problems(&ServerConfig { enable_auth: false, ..with_secret("short") }).is_empty());
+        let error = ServerConfig { enable_auth: true, ..ServerConfig::default() }.validate().unwrap_err();
+        assert_eq!(
+            error[0].to_string(),
+            "jwt_secret: is the development default, with auth enabled; \
+             set it to a random value, such as the output of `openssl rand -base64 48`"
+        );
+    }
+
+    #[test]
+    fn test_token_keys() {
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let keyed = |algorithm, private_key: Option<&str>, public_keys: &[&str]| ServerConfig {
+            enable_auth: true,
+            jwt_algorithm: algorithm,
+            jwt_private_key_file: private_key.map(|name| keys.join(name)),
+            jwt_public_key_files: public_keys.iter().map(|name| keys.join(name)).collect(),
+            ..ServerConfig::default()
+        };
+        // The secret is not used, so the development default is no problem
+        assert!(problems(&keyed(TokenAlgorithm::Rs256, Some("rsa_private.pem"), &[])).is_empty());
+        assert!(problems(&keyed(TokenAlgorithm::Rs256, None, &["idp_rsa_public.pem"])).is_empty());
+        assert_eq!(
+            problems(&keyed(TokenAlgorithm::Es256, Some("ec_private.pem"), &["idp_rsa_public.pem"])),
+            error("jwt_public_key_files[0]")
+        );
+        assert_eq!(problems(&keyed(TokenAlgorithm::Es256, None, &[])), error("jwt_private_key_file"));
+        assert_eq!(problems(&keyed(TokenAlgorithm::Es256, Some("rsa_private.pem"), &[])), error("jwt_private_key_file"));
+        assert_eq!(
+            problems(&keyed(TokenAlgorithm::Rs256, Some("rsa_private.pem"), &["idp_rsa_public.pem", "missing.pem"])),
+            error("jwt_public_key_files[1]")
+        );
+    }
+
+    #[test]
+    fn test_oidc() {
+        let with_oidc = |oidc: OidcConfig| ServerConfig {
+            enable_auth: true,
+            jwt_algorithm: TokenAlgorithm::Rs256,
+            oidc: Some(oidc),
+            ..ServerConfig::default()
+        };
+        // The provider's keys are enough to verify tokens with
+        let oidc = OidcConfig::new("https://sso.example.com/realms/main", "connector");
+        assert!(problems(&with_oidc(oidc.clone())).is_empty());
+        assert!(problems(&with_oidc(OidcConfig::new("http://127.0.0.1:8180/realms/dev", "connector"))).is_empty());
+        assert_eq!(problems(&with_oidc(OidcConfig::new("http://sso.example.com", "connector"))), error("oidc.issuer"));
+        assert_eq!(problems(&with_oidc(OidcConfig::new("sso.example.com", "connector"))), error("oidc.issuer"));
+        assert_eq!(problems(&with_oidc(OidcConfig::new(&oidc.issuer, ""))), error("oidc.audience"));
+
+        let mut unmapped = oidc.clone();
+        unmapped.scope_claim = String::new();
+        unmapped.jwks_refresh = Duration::ZERO;
+        let found: Vec<_> = problems(&with_oidc(unmapped)).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(found, ["oidc.scope_claim", "oidc.jwks_refresh"]);
+
+        let disabled = ServerConfig { oidc: Some(oidc), ..ServerConfig::default() };
+        assert_eq!(problems(&disabled), warning("oidc"));
+    }
+
+    #[test]
+    fn test_mtls() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/mtls");
+        let mtls = |cert: &str, key: &str, cas: &[&str]| {
+            MtlsConfig::new(fixtures.join(cert), fixtures.join(key), cas.iter().map(|name| fixtures.join(name)).collect())
+        };
+        // Certificates are enough to verify clients with
+        let with_mtls = |mtls: MtlsConfig| ServerConfig {
+            enable_auth: true,
+            jwt_algorithm: TokenAlgorithm::Rs256,
+            mtls: Some(mtls),
+            ..ServerConfig::default()
+        };
+        assert!(problems(&with_mtls(mtls("server.pem", "server_key.pem", &["ca.pem"]))).is_empty());
+        assert_eq!(problems(&with_mtls(mtls("server.pem", "server_key.pem", &[]))), error("mtls.client_ca_files"));
+        assert_eq!(problems(&with_mtls(mtls("server.pem", "server_key.pem", &["missing.pem"]))), error("mtls.client_ca_files[0]"));
+        assert_eq!(problems(&with_mtls(mtls("server_key.pem", "server_key.pem", &["ca.pem"]))), error("mtls.cert_file"));
+        assert_eq!(problems(&with_mtls(mtls("server.pem", "alice_key.pem", &["ca.pem"]))), error("mtls.key_file"));
+
+        let mut unscoped = mtls("server.pem", "server_key.pem", &["ca.pem"]);
+        unscoped.identities.insert("alice".to_string(), CertIdentity::default());
+        assert_eq!(problems(&with_mtls(unscoped.clone())), warning("mtls.identities.alice"));
+        unscoped.identities.clear();
+        let disabled = ServerConfig { mtls: Some(unscoped.clone()), ..ServerConfig::default() };
+        assert_eq!(problems(&disabled), warning("mtls"));
+        // Builds without the grpc feature refuse enable_grpc as well
+        let grpc = problems(&ServerConfig { enable_grpc: true, ..with_mtls(unscoped) });
+        assert!(grpc.contains(&("enable_grpc".to_string(), true)), "{grpc:?}");
+    }
+
+    #[test]
+    fn test_request_signing() {
+        let client = |secret: &str, scopes: &[&str]| SigningClient {
+            secret: secret.to_string(),
+            scopes: scopes.iter().map(ToString::to_string).collect(),
+            ..SigningClient::default()
+        };
+        let with_clients = |clients: &[(&str, SigningClient)]| ServerConfig {
+            request_signing: Some(SigningConfig {
+                clients: clients.iter().map(|(key, client)| (key.to_string(), client.clone())).collect(),
+                ..SigningConfig::default()
+            }),
+            ..ServerConfig::default()
+        };
+        let secret = "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY";
+        let signed = |config: ServerConfig| ServerConfig { enable_auth: true, jwt_secret: secret.to_string(), ..config };
+        assert!(problems(&signed(with_clients(&[("ci", client(secret, &["read"]))]))).is_empty());
+        assert_eq!(problems(&signed(with_clients(&[("ci", client("short", &["read"]))]))), error("request_signing.clients.ci.secret"));
+        assert_eq!(problems(&signed(with_clients(&[("c i", client(secret, &["read"]))]))), error("request_signing.clients.c i"));
+        assert_eq!(problems(&signed(with_clients(&[("ci", client(secret, &[]))]))), warning("request_signing.clients.ci.scopes"));
+        assert_eq!(problems(&with_clients(&[])), warning("request_signing"));
+
+        let mut stale = signed(with_clients(&[]));
+        stale.request_signing.as_mut().unwrap().max_skew = Duration::ZERO;
+        assert_eq!(problems(&stale), error("request_signing.max_skew"));
+    }
+
+    #[test]
+    fn test_ldap() {
+        let ldap = LdapConfig {
+            default_scopes: vec![roles::READ.to_string()],
+            ..LdapConfig::new("ldaps://dc1.example.com", "ou=people,dc=example,dc=com")
+        };
+        let with_ldap = |ldap: LdapConfig| ServerConfig {
+            enable_auth: true,
+            jwt_secret: "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY".to_string(),
+            ldap: Some(ldap),
+            ..ServerConfig::default()
+        };
+        // Builds without the ldap feature refuse [ldap] whatever it says
+        let found = |ldap: LdapConfig| {
+            let mut found = problems(&with_ldap(ldap));
+            if !cfg!(feature = "ldap") {
+                assert_eq!(found.remove(0), ("ldap".to_string(), false));
+            }
+            found
+        };
+        assert!(found(ldap.clone()).is_empty());
+        assert!(found(LdapConfig { url: "ldap://127.0.0.1:3389".to_string(), ..ldap.clone() }).is_empty());
+        assert!(found(LdapConfig { url: "ldap://dc1.example.com".to_string(), start_tls: true, ..ldap.clone() }).is_empty());
+        assert_eq!(found(LdapConfig { url: "ldap://dc1.example.com".to_string(), ..ldap.clone() }), error("ldap.url"));
+        assert_eq!(found(LdapConfig { url: "dc1.example.com".to_string(), ..ldap.clone() }), error("ldap.url"));
+        assert_eq!(found(LdapConfig { start_tls: true, ..ldap.clone() }), error("ldap.start_tls"));
+        assert_eq!(found(LdapConfig { bind_dn: Some("cn=svc,dc=example,dc=com".to_string()), ..ldap.clone() }), error("ldap.bind_dn"));
+        assert_eq!(found(LdapConfig { user_filter: "(uid=alice)".to_string(), ..ldap.clone() }), error("ldap.user_filter"));
+        assert_eq!(found(LdapConfig { timeout: Duration::ZERO, ..ldap.clone() }), error("ldap.timeout"));
+        assert_eq!(found(LdapConfig { default_scopes: Vec::new(), ..ldap.clone() }), warning("ldap.group_scopes"));
+        let mut grouped = ldap.clone();
+        grouped.group_scopes.insert("Auditors".to_string(), vec!["role:auditor".to_string()]);
+        assert_eq!(found(grouped), warning("ldap.group_scopes.Auditors"));
+
+        let disabled = problems(&ServerConfig { enable_auth: false, ..with_ldap(ldap) });
+        assert!(disabled.contains(&("ldap".to_string(), true)), "{disabled:?}");
+    }
+
+    #[test]
+    fn test_saml() {
+        let saml = SamlConfig {
+            default_scopes: vec![roles::READ.to_string()],
+            ..SamlConfig::new("https://idp.example.com", "tests/fixtures/keys/idp_rsa_cert.pem", "https://connector.example.com")
+        };
+        let with_saml = |saml: SamlConfig| ServerConfig {
+            enable_auth: true,
+            jwt_secret: "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY".to_string(),
+            saml: Some(saml),
+            ..ServerConfig::default()
+        };
+        let found = |saml: SamlConfig| problems(&with_saml(saml));
+        assert!(found(saml.clone()).is_empty());
+        let acs = |url: &str| SamlConfig { acs_url: Some(url.to_string()), ..saml.clone() };
+        assert!(found(acs("https://connector.example.com/auth/saml/acs")).is_empty());
+        assert!(found(acs("http://localhost:8080/auth/saml/acs")).is_empty());
+        assert_eq!(found(acs("http://connector.example.com/auth/saml/acs")), error("saml.acs_url"));
+        assert_eq!(found(acs("connector.example.com")), error("saml.acs_url"));
+        assert_eq!(found(SamlConfig { idp_entity_id: " ".to_string(), ..saml.clone() }), error("saml.idp_entity_id"));
+        assert_eq!(found(SamlConfig { sp_entity_id: String::new(), ..saml.clone() }), error("saml.sp_entity_id"));
+        let certificate = |path: &str| SamlConfig { idp_certificate_file: path.into(), ..saml.clone() };
+        assert_eq!(found(certificate("tests/fixtures/keys/missing.pem")), error("saml.idp_certificate_file"));
+        assert_eq!(found(certificate("tests/fixtures/keys/ec_private.pem")), error("saml.idp_certificate_file"));
+        assert_eq!(found(SamlConfig { group_attribute: String::new(), ..saml.clone() }), error("saml.group_attribute"));
+        assert_eq!(found(SamlConfig { clock_skew: Duration::from_hours(1), ..saml.clone() }), warning("saml.clock_skew"));
+        assert_eq!(found(SamlConfig { default_scopes: Vec::new(), ..saml.clone() }), warning("saml.group_scopes"));
+        let mut grouped = saml.clone();
+        grouped.group_scopes.insert("Auditors".to_string(), vec!["role:auditor".to_string()]);
+        assert_eq!(found(grouped), warning("saml.group_scopes.Auditors"));
+
+        let disabled = problems(&ServerConfig { enable_auth: false, ..with_saml(saml) });
+        assert!(disabled.contains(&("saml".to_string(), true)), "{disabled:?}");
+    }
+
+    #[test]
+    fn test_lockout() {
+        let defaults = LockoutConfig::default();
+        let with_lockout = |lockout: LockoutConfig| ServerConfig { lockout, ..ServerConfig::default() };
+        assert!(problems(&with_lockout(defaults)).is_empty());
+        assert_eq!(problems(&with_lockout(LockoutConfig { max_failures: 0, ..defaults })), error("lockout.max_failures"));
+        assert_eq!(problems(&with_lockout(LockoutConfig { max_failures_per_ip: 0, ..defaults })), error("lockout.max_failures_per_ip"));
+        assert_eq!(problems(&with_lockout(LockoutConfig { lockout: Duration::ZERO, ..defaults })), error("lockout.lockout"));
+        let capped = LockoutConfig { backoff: Duration::from_mins(1), ..defaults };
+        assert_eq!(problems(&with_lockout(capped)), warning("lockout.max_backoff"));
+
+        // Turned off, nothing is checked, though it is worth a warning where passwords are taken
+        let disabled = LockoutConfig { enabled: false, max_failures: 0, ..defaults };
+        assert!(problems(&with_lockout(disabled)).is_empty());
+        let ldap = LdapConfig::new("ldaps://dc1.example.com", "ou=people,dc=example,dc=com");
+        let found = problems(&ServerConfig { ldap: Some(ldap), ..with_lockout(disabled) });
+        assert!(found.contains(&("lockout.enabled".to_string(), true)), "{found:?}");
+    }
+
+    #[test]
+    fn test_csrf() {
+        let enabled = CsrfConfig { enabled: true, ..CsrfConfig::default() };
+        let with_csrf = |csrf: CsrfConfig| ServerConfig { enable_auth: true, jwt_secret: "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY".to_string(), csrf, ..ServerConfig::default() };
+        assert!(problems(&with_csrf(enabled.clone())).is_empty());
+        let origins = vec!["https://ide.example.com".to_string(), "http://localhost:3000/".to_string()];
+        assert!(problems(&with_csrf(CsrfConfig { allowed_origins: origins, ..enabled.clone() })).is_empty());
+        assert_eq!(problems(&with_csrf(CsrfConfig { cookie: "ulc csrf".to_string(), ..enabled.clone() })), error("csrf.cookie"));
+        assert_eq!(problems(&with_csrf(CsrfConfig { token_cookie: "ulc_csrf".to_string(), ..enabled.clone() })), error("csrf.cookie"));
+        assert_eq!(problems(&with_csrf(CsrfConfig { header: "X CSRF".to_string(), ..enabled.clone() })), error("csrf.header"));
+        let paths = CsrfConfig { allowed_origins: vec!["https://ide.example.com/app".to_string()], ..enabled.clone() };
+        assert_eq!(problems(&with_csrf(paths)), error("csrf.allowed_origins[0]"));
+        let unauthenticated = ServerConfig { csrf: enabled, ..ServerConfig::default() };
+        assert_eq!(problems(&unauthenticated), warning("csrf.enabled"));
+    }
+
+    #[test]
+    fn test_token_lifetimes() {
+        let with_lifetimes = |access: u64, refresh: u64| ServerConfig {
+            tokens: TokenLifetimes { access: Duration::from_secs(access), refresh: Duration::from_secs(refresh) },
+            ..ServerConfig::default()
+        };
+        assert!(problems(&with_lifetimes(900, 86400)).is_empty());
+        assert_eq!(problems(&with_lifetimes(0, 86400)), error("tokens.access"));
+        assert_eq!(problems(&with_lifetimes(2 * 3600, 86400)), warning("tokens.access"));
+        assert_eq!(problems(&with_lifetimes(900, 900)), error("tokens.refresh"));
+    }
+
+    #[test]
+    fn test_roles() {
+        let with_roles = |roles: Roles| ServerConfig { roles, ..ServerConfig::default() };
+        assert!(problems(&ServerConfig::default()).is_empty());
+        assert_eq!(problems(&with_roles(Roles::none().with("ops", vec!["role:admin".to_string()]))), error("roles.ops"));
+        assert_eq!(problems(&with_roles(Roles::none().with("on call", vec![roles::ADMIN.to_string()]))), error("roles.on call"));
+        assert_eq!(problems(&with_roles(Roles::none().with("idle", Vec::new()))), warning("roles.idle"));
+
+        let mut oidc = OidcConfig::new("https://sso.example.com", "connector");
+        oidc.scope_map.insert("engineering".to_string(), vec!["role:editor".to_string()]);
+        oidc.scope_map.insert("support".to_string(), vec!["role:support".to_string()]);
+        let config = ServerConfig { enable_auth: true, jwt_algorithm: TokenAlgorithm::Rs256, oidc: Some(oidc), ..ServerConfig::default() };
+        assert_eq!(problems(&config), warning("oidc.scope_map.support"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let with_policy = |policy: ScopePolicy| ServerConfig {
+            enable_auth: true,
+            jwt_secret: "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY".to_string(),
+            policy,
+            ..ServerConfig::default()
+        };
+        let admin = || vec![roles::ADMIN.to_string()];
+        let valid = ScopePolicy::default().route("/api/admin/*", admin()).message("Lsp", vec!["lsp".to_string()]);
+        assert!(problems(&with_policy(valid.clone())).is_empty());
+        assert_eq!(problems(&ServerConfig { enable_auth: false, ..with_policy(valid) }), warning("policy"));
+        assert_eq!(problems(&with_policy(ScopePolicy::default().route("api/admin", admin()))), error("policy.routes.api/admin"));
+        assert_eq!(problems(&with_policy(ScopePolicy::default().route("/healthz", Vec::new()))), warning("policy.routes./healthz"));
+        assert_eq!(problems(&with_policy(ScopePolicy::default().message("lsp", admin()))), error("policy.messages.lsp"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let with_limits = |anonymous: TierLimits| ServerConfig {
+            rate_limit: RateLimitConfig { enabled: true, anonymous, ..RateLimitConfig::default() },
+            ..ServerConfig::default()
+        };
+        assert!(problems(&with_limits(TierLimits::new(60, 10))).is_empty());
+        assert_eq!(problems(&with_limits(TierLimits::new(60, 0))), error("rate_limit.anonymous"));
+        assert_eq!(problems(&with_limits(TierLimits::new(0, 10))), warning("rate_limit.anonymous"));
+        let mut disabled = with_limits(TierLimits::new(60, 0));
+        disabled.rate_limit.enabled = false;
+        assert!(problems(&disabled).is_empty());
+
+        let mut shared = with_limits(TierLimits::new(60, 10));
+        shared.rate_limit.redis = Some(RedisConfig::new("redis://cache.internal:6379/0"));
+        if cfg!(feature = "redis") {
+            assert!(problems(&shared).is_empty());
+            shared.rate_limit.redis = Some(RedisConfig::new("cache.internal:6379"));
+            assert_eq!(problems(&shared), error("rate_limit.redis.url"));
+        } else {
+            assert_eq!(problems(&shared), error("rate_limit.redis"));
+        }
+    }
+
+    #[test]
+    fn test_audit() {
+        let with_sink = |sink: AuditSinkConfig| ServerConfig {
+            audit: crate::audit::AuditConfig { sinks: vec![sink] },
+            ..ServerConfig::default()
+        };
+        let dir = scratch_dir();
+        assert!(problems(&with_sink(AuditSinkConfig::File { path: dir.join("audit.jsonl") })).is_empty());
+        let missing = AuditSinkConfig::File { path: dir.join("missing").join("audit.jsonl") };
+        assert_eq!(problems(&with_sink(missing)), error("audit.sinks[0].path"));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(problems(&with_sink(AuditSinkConfig::Stdout)), error("audit.sinks[0]"));
+        let config = ServerConfig { enable_lsp: false, ..with_sink(AuditSinkConfig::Stdout) };
+        assert!(problems(&config).is_empty());
+
+        let http = |url: &str| with_sink(AuditSinkConfig::Http { url: url.to_string() });
+        assert!(problems(&http("https://logs.example.com/ingest?token=T0K3N")).is_empty());
+        assert!(problems(&http("http://127.0.0.1:8088/ingest")).is_empty());
+        assert_eq!(problems(&http("http://logs.example.com/ingest")), warning("audit.sinks[0].url"));
+        assert_eq!(problems(&http("logs.example.com/ingest")), error("audit.sinks[0].url"));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let with_addrs = |http: &str, ws: &str| ServerConfig {
+            http_addr: http.to_string(),
+            ws_addr: ws.to_string(),
+            ..ServerConfig::default()
+        };
+        assert_eq!(problems(&with_addrs("0.0.0.0:8080", "0.0.0.0:8080")), error("ws_addr"));
+        assert_eq!(problems(&with_addrs("127.0.0.1:8080", "0.0.0.0:8080")), error("ws_addr"));
+        assert_eq!(problems(&with_addrs("[::1]:9000", "[::1]:9000")), error("ws_addr"));
+        assert!(problems(&with_addrs("127.0.0.1:8080", "127.0.0.2:8080")).is_empty());
+        assert!(problems(&with_addrs("127.0.0.1:0", "127.0.0.1:0")).is_empty());
+        assert!(problems(&with_addrs("localhost:8080", "[::1]:8081")).is_empty());
+
+        assert_eq!(problems(&with_addrs("8080", "0.0.0.0:8081")), error("http_addr"));
+        assert_eq!(problems(&with_addrs("0.0.0.0:8080", "0.0.0.0:http")), error("ws_addr"));
+        assert_eq!(problems(&with_addrs("0.0.0.0:8080", "::1:8081")), error("ws_addr"));
+        assert_eq!(problems(&with_addrs("0.0.0.0:8080", ":8081")), error("ws_addr"));
+
+        // Disabled listeners are neither parsed nor compared
+        let config = ServerConfig { enable_websocket: false, ..with_addrs("0.0.0.0:8080", "0.0.0.0:8080") };
+        assert!(problems(&config).is_empty());
+
+        let grpc = |addr: &str| ServerConfig { enable_grpc: true, grpc_addr: addr.to_string(), ..ServerConfig::default() };
+        let unbuilt = if cfg!(feature = "grpc") { vec![] } else { error("enable_grpc") };
+        assert_eq!(problems(&grpc("0.0.0.0:50051")), unbuilt);
+        assert_eq!(problems(&grpc("127.0.0.1:8081")), [error("grpc_addr"), unbuilt.clone()].concat());
+        assert_eq!(problems(&grpc("50051")), [error("grpc_addr"), unbuilt].concat());
+
+        let config = ServerConfig {
+            enable_lsp: false,
+            enable_http: false,
+            enable_websocket: false,
+            ..ServerConfig::default()
+        };
+        assert_eq!(problems(&config), warning("enable_lsp"));
+    }
+
+    #[test]
+    fn test_urls() {
+        let with_webhook = |url: &str| ServerConfig { alert_webhook: Some(url.to_string()), ..ServerConfig::default() };
+        assert!(problems(&with_webhook("https://hooks.example.com/alerts/T0K3N")).is_empty());
+        assert!(problems(&with_webhook("http://127.0.0.1:9093/alerts")).is_empty());
+        assert!(problems(&with_webhook("http://localhost/alerts")).is_empty());
+        assert_eq!(problems(&with_webhook("http://hooks.example.com/alerts")), warning("alert_webhook"));
+        assert_eq!(problems(&with_webhook("hooks.example.com/alerts")), error("alert_webhook"));
+        assert_eq!(problems(&with_webhook("ftp://hooks.example.com/alerts")), error("alert_webhook"));
+
+        let errors = with_webhook("not a url/T0K3N").validate().unwrap_err();
+        assert!(!errors[0].to_string().contains("T0K3N"), "{}", errors[0]);
+
+        let config = ServerConfig { lifecycle_webhook: Some("webhook".to_string()), ..ServerConfig::default() };
+        assert_eq!(problems(&config), error("lifecycle_webhook"));
+
+        let mut config = ServerConfig::default();
+        config.tracing.otlp_endpoint = Some("http://otel-collector:4317".to_string());
+        assert!(problems(&config).is_empty());
+        config.tracing.otlp_endpoint = Some("otel-collector:4317".to_string());
+        assert_eq!(problems(&config), error("tracing.otlp_endpoint"));
+    }
+
+    #[test]
+    fn test_directories() {
+        let dir = scratch_dir();
+        let config = ServerConfig { data_dir: Some(dir.clone()), ..ServerConfig::default() };
+        assert!(problems(&config).is_empty());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0, "the write check left a file behind");
+
+        let config = ServerConfig { data_dir: Some(dir.join("missing")), ..ServerConfig::default() };
+        assert_eq!(problems(&config), error("data_dir"));
+
+        std::fs::write(dir.join("file"), "").unwrap();
+        let config = ServerConfig { data_dir: Some(dir.join("file")), ..ServerConfig::default() };
+        assert_eq!(problems(&config), error("data_dir"));
+
+        let mut config = ServerConfig::default();
+        config.usage.rollup_file = Some(dir.join("usage.jsonl"));
+        assert!(problems(&config).is_empty());
+        config.usage.rollup_file = Some(dir.join("missing").join("usage.jsonl"));
+        assert_eq!(problems(&config), error("usage.rollup_file"));
+
+        let mut config = ServerConfig::default();
+        config.logging.file = Some(crate::logging::LogFileConfig::new(dir.join("missing").join("server.log")));
+        assert!(problems(&config).is_empty());
+
+        let mut config = ServerConfig::default();
+        config.plugins.dir = Some(dir.clone());
+        let expected = if cfg!(feature = "wasm-plugins") { Vec::new() } else { warning("plugins.dir") };
+        assert_eq!(problems(&config), expected);
+        config.plugins.dir = Some(dir.join("missing"));
+        assert_eq!(problems(&config), error("plugins.dir"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.join("locked");
+            std::fs::create_dir(&locked).unwrap();
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o500)).unwrap();
+            // Root writes regardless of permissions
+            if std::fs::File::create(locked.join("probe")).is_err() {
+                let config = ServerConfig { data_dir: Some(locked.clone()), ..ServerConfig::default() };
+                assert_eq!(problems(&config), error("data_dir"));
+            }
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut config = ServerConfig::default();
+        config.ws_connection_limits.max_total = 0;
+        let found = problems(&config);
+        assert_eq!(found[0], error("ws_connection_limits.max_total")[0]);
+        // The other limits are then above it too
+        assert!(found[1..].iter().all(|(_, warning)| *warning), "{found:?}");
+
+        let mut config = ServerConfig::default();
+        config.ws_connection_limits.max_per_ip = 0;
+        assert_eq!(problems(&config), error("ws_connection_limits.max_per_ip"));
+        config.ws_connection_limits.max_per_ip = config.ws_connection_limits.max_total + 1;
+        assert_eq!(problems(&config), warning("ws_connection_limits.max_per_ip"));
+
+        let mut config = ServerConfig::default();
+        config.format_limits.max_input_bytes = 0;
+        assert_eq!(problems(&config), error("format_limits.max_input_bytes"));
+        config.format_limits.max_input_bytes = config.format_limits.max_output_bytes + 1;
+        assert_eq!(problems(&config), warning("format_limits.max_output_bytes"));
+
+        let mut config = ServerConfig::default();
+        config.lifecycle_thresholds.degrade_after = 0;
+        assert_eq!(problems(&config), error("lifecycle_thresholds.degrade_after"));
+        let mut config = ServerConfig::default();
+        config.lifecycle_thresholds.restart_after = 1;
+        assert_eq!(problems(&config), warning("lifecycle_thresholds.restart_after"));
+
+        let mut config = ServerConfig::default();
+        config.usage.capacity = 0;
+        assert_eq!(problems(&config), error("usage.capacity"));
+        config.usage.per_subject = false;
+        assert!(problems(&config).is_empty());
+
+        let mut config = ServerConfig::default();
+        config.jobs.workers = 0;
+        assert_eq!(problems(&config), error("jobs.workers"));
+        let mut config = ServerConfig::default();
+        config.jobs.default_limits.max_queued = 0;
+        config.jobs.classes.insert("webhook".to_string(), ClassLimits::new(0, 10));
+        let found: Vec<_> = problems(&config).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(found, ["jobs.default_limits.max_queued", "jobs.classes.webhook.concurrency"]);
+
+        let mut config = ServerConfig::default();
+        config.tasks.schedules.insert(scheduler::USAGE_ROLLUP.to_string(), "5m".parse().unwrap());
+        assert!(problems(&config).is_empty());
+        config.tasks.schedules.insert("usage_rolup".to_string(), "5m".parse().unwrap());
+        assert_eq!(problems(&config), warning("tasks.schedules.usage_rolup"));
+
+        let mut config = ServerConfig::default();
+        config.plugins.fuel = 0;
+        assert_eq!(problems(&config), error("plugins.fuel"));
+
+        let mut statsd = StatsdConfig::new(StatsdTarget::Udp("127.0.0.1:8125".to_string()));
+        let mut config = ServerConfig { statsd: Some(statsd.clone()), ..ServerConfig::default() };
+        assert!(problems(&config).is_empty());
+        statsd.interval = Duration::ZERO;
+        statsd.max_packet_bytes = 100_000;
+        config.statsd = Some(statsd);
+        let found: Vec<_> = problems(&config).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(found, ["statsd.interval", "statsd.max_packet_bytes"]);
+    }
+
+    #[test]
+    fn test_downstreams() {
+        let mut server = DownstreamConfig::new("pyright", DownstreamTransport::Tcp("127.0.0.1:9257".to_string()));
+        server.languages = vec!["python".to_string()];
+        let mut config = ServerConfig { downstreams: vec![server.clone()], ..ServerConfig::default() };
+        assert!(problems(&config).is_empty());
+
+        config.downstreams.push(DownstreamConfig::new("pyright", DownstreamTransport::Stdio(Vec::new())));
+        config.downstreams.push(DownstreamConfig::new("remote", DownstreamTransport::Tcp("localhost".to_string())));
+        config.downstreams.push(DownstreamConfig::new("ws", DownstreamTransport::WebSocket("http://x".to_string())));
+        server.name = "slow".to_string();
+        server.request_timeout = Duration::ZERO;
+        config.downstreams.push(server);
+        assert_eq!(
+            problems(&config),
+            [
+                ("downstreams[1].name".to_string(), false),
+                ("downstreams[1].command".to_string(), false),
+                ("downstreams[1].languages".to_string(), true),
+                ("downstreams[2].tcp".to_string(), false),
+                ("downstreams[2].languages".to_string(), true),
+                ("downstreams[3].websocket".to_string(), false),
+                ("downstreams[3].languages".to_string(), true),
+                ("downstreams[4].request_timeout".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schemas() {
+        let dir = std::env::temp_dir().join(format!("ulc-schemas-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("deploy.yaml"), "type: object\nrequired: [name]\n").unwrap();
+        std::fs::write(dir.join("broken.json"), r#"{"pattern": "("}"#).unwrap();
+
+        let mut config = ServerConfig { schemas: vec![SchemaConfig::new("deploy", dir.join("deploy.yaml"))], ..ServerConfig::default() };
+        assert!(problems(&config).is_empty());
+
+        config.schemas.push(SchemaConfig::new("deploy", dir.join("deploy.yaml")));
+        config.schemas.push(SchemaConfig::new("", dir.join("broken.json")));
+        config.schemas.push(SchemaConfig::new("missing", dir.join("missing.json")));
+        assert_eq!(
+            problems(&config),
+            [
+                ("schemas[1].name".to_string(), false),
+                ("schemas[2].name".to_string(), false),
+                ("schemas[2].path".to_string(), false),
+                ("schemas[3].path".to_string(), false),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut config = ServerConfig::default();
+        config.lint.rules.insert("yaml/no-duplicate-keys".to_string(), Severity::Warning);
+        assert!(problems(&config).is_empty());
+
+        config.lint.rules.insert("yaml/no-tabs".to_string(), Severity::Error);
+        let rules = [("json/no-duplicate-keys".to_string(), Severity::Off)].into();
+        config.lint.workspaces.push(WorkspaceRules { documents: Vec::new(), rules });
+        assert_eq!(
+            problems(&config),
+            [("lint.workspaces[0].documents".to_string(), true), ("lint.rules.yaml/no-tabs".to_string(), true)]
+        );
+    }
+}
@@ -2,6 +2,16 @@
 //!
 //! Provides bidirectional conversion between formats:
 //! - Markdown ↔ HTML ↔ JSON ↔ YAML ↔ XML ↔ TOML (Platinum RSR)
+//! - CSV and TSV tables ↔ JSON, and through JSON every other format
+//! - INI ↔ JSON, and through JSON every other format
+//! - dotenv ↔ JSON objects, and through JSON every other format
+//! - JSON5 and JSONC → JSON, and through JSON every other format
+//! - NDJSON ↔ JSON arrays, and through JSON every other format
+//! - `MessagePack` and CBOR, as base64 text, ↔ JSON, and through JSON every other format
+//! - Property lists, XML or binary as base64 text, ↔ JSON, and through JSON every other format
+//!
+//! Apart from Markdown ↔ HTML, every conversion reads the document into the
+//! model of [`formats::model`] and writes it in the other format.
 
 use anyhow::{anyhow, Result};
 use pulldown_cmark::{html, Parser};
@@ -9,7 +19,8 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::formats::{self, ExtendedFormat};
+use crate::formats;
+use crate::formats::datetime::DateTimeStyle;
 
 /// Supported conversion formats
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -21,10 +32,64 @@ pub enum Format {
     Yaml,  // Platinum RSR
     Xml,   // Platinum RSR
     Toml,  // Platinum RSR
+    Csv,
+    Tsv,
+    Ini,
+    Json5,
+    Jsonc,
+    Ndjson,
+    Msgpack,
+    Cbor,
+    Dotenv,
+    Plist,
 }
 
 impl Format {
+    /// Every format, in the order they are listed to clients
+    pub const ALL: [Self; 16] = [
+        Self::Markdown,
+        Self::Html,
+        Self::Json,
+        Self::Yaml,
+        Self::Xml,
+        Self::Toml,
+        Self::Csv,
+        Self::Tsv,
+        Self::Ini,
+        Self::Json5,
+        Self::Jsonc,
+        Self::Ndjson,
+        Self::Msgpack,
+        Self::Cbor,
+        Self::Dotenv,
+        Self::Plist,
+    ];
+
+    /// Name used on the wire, as serialized
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Markdown => "markdown",
+            Self::Html => "html",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Xml => "xml",
+            Self::Toml => "toml",
+            Self::Csv => "csv",
+            Self::Tsv => "tsv",
+            Self::Ini => "ini",
+            Self::Json5 => "json5",
+            Self::Jsonc => "jsonc",
+            Self::Ndjson => "ndjson",
+            Self::Msgpack => "msgpack",
+            Self::Cbor => "cbor",
+            Self::Dotenv => "dotenv",
+            Self::Plist => "plist",
+        }
+    }
+
     /// Parse format from string
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
             "markdown" | "md" => Ok(Self::Markdown),
@@ -33,6 +98,16 @@ pub fn from_str(s: &str) -> Result<Self> {
             "yaml" | "yml" => Ok(Self::Yaml),
             "xml" => Ok(Self::Xml),
             "toml" => Ok(Self::Toml),
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            "ini" | "cfg" => Ok(Self::Ini),
+            "json5" => Ok(Self::Json5),
+            "jsonc" => Ok(Self::Jsonc),
+            "ndjson" | "jsonl" => Ok(Self::Ndjson),
+            "msgpack" | "messagepack" => Ok(Self::Msgpack),
+            "cbor" => Ok(Self::Cbor),
+            "dotenv" | "env" => Ok(Self::Dotenv),
+            "plist" => Ok(Self::Plist),
             _ => Err(anyhow!("Unsupported format: {}", s)),
         }
     }
@@ -46,6 +121,16 @@ pub fn extension(&self) -> &'static str {
             Self::Yaml => "yaml",
             Self::Xml => "xml",
             Self::Toml => "toml",
+            Self::Csv => "csv",
+            Self::Tsv => "tsv",
+            Self::Ini => "ini",
+            Self::Json5 => "json5",
+            Self::Jsonc => "jsonc",
+            Self::Ndjson => "ndjson",
+            Self::Msgpack => "msgpack",
+            Self::Cbor => "cbor",
+            Self::Dotenv => "env",
+            Self::Plist => "plist",
         }
     }
 }
@@ -58,6 +143,16 @@ pub struct ConversionRequest {
     pub to: Format,
 }
 
+/// How a conversion writes what the target format lays out more than one way
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversionOptions {
+    /// An earlier version of the result, whose layout is kept, as [`ConversionCore::convert_onto`] describes
+    pub original: Option<String>,
+    /// How date-times are written
+    pub datetimes: DateTimeStyle,
+}
+
 /// Conversion response
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConversionResponse {
@@ -73,127 +168,60 @@ pub struct ConversionResponse {
 impl ConversionCore {
     /// Convert document between formats
     pub fn convert(request: ConversionRequest) -> Result<ConversionResponse> {
+        Self::convert_with(request, &ConversionOptions::default())
+    }
+
+    /// Convert a document, laying the result out as `original`, an earlier version of it
+    ///
+    /// The comments, blank lines and key order of a YAML or TOML `original`
+    /// are kept, as [`formats::model::write_onto`] describes; one that does
+    /// not parse is passed over with a warning.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the content does not parse as its format, or has no form in
+    /// the other.
+    pub fn convert_onto(request: ConversionRequest, original: &str) -> Result<ConversionResponse> {
+        Self::convert_with(request, &ConversionOptions { original: Some(original.to_string()), ..Default::default() })
+    }
+
+    /// Convert a document, writing it as `options` choose
+    ///
+    /// Date-times are written in the style `options` give, as
+    /// [`formats::model::DocumentValue::with_datetimes`] describes.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the content does not parse as its format, or has no form in
+    /// the other.
+    pub fn convert_with(request: ConversionRequest, options: &ConversionOptions) -> Result<ConversionResponse> {
         let mut warnings = Vec::new();
 
         let content = match (request.from, request.to) {
             // Markdown → HTML
             (Format::Markdown, Format::Html) => Self::markdown_to_html(&request.content),
 
-            // Markdown → JSON
-            (Format::Markdown, Format::Json) => Self::markdown_to_json(&request.content)?,
-
             // HTML → Markdown
             (Format::Html, Format::Markdown) => {
                 warnings.push("HTML to Markdown conversion may lose some formatting".to_string());
                 Self::html_to_markdown(&request.content)?
             }
 
-            // HTML → JSON
-            (Format::Html, Format::Json) => Self::html_to_json(&request.content)?,
-
-            // JSON → Markdown
-            (Format::Json, Format::Markdown) => Self::json_to_markdown(&request.content)?,
-
-            // JSON → HTML
-            (Format::Json, Format::Html) => Self::json_to_html(&request.content)?,
-
-            // YAML ↔ JSON (Platinum RSR)
-            (Format::Yaml, Format::Json) => formats::yaml::yaml_to_json(&request.content)?,
-            (Format::Json, Format::Yaml) => formats::yaml::json_to_yaml(&request.content)?,
-
-            // XML ↔ JSON (Platinum RSR)
-            (Format::Xml, Format::Json) => formats::xml::xml_to_json(&request.content)?,
-            (Format::Json, Format::Xml) => formats::xml::json_to_xml(&request.content)?,
-
-            // TOML ↔ JSON (Platinum RSR)
-            (Format::Toml, Format::Json) => formats::toml::toml_to_json(&request.content)?,
-            (Format::Json, Format::Toml) => formats::toml::json_to_toml(&request.content)?,
-
-            // Extended formats → Markdown/HTML (via JSON intermediate)
-            (Format::Yaml, Format::Markdown) => {
-                let json = formats::yaml::yaml_to_json(&request.content)?;
-                Self::json_to_markdown(&json)?
-            }
-            (Format::Yaml, Format::Html) => {
-                let json = formats::yaml::yaml_to_json(&request.content)?;
-                Self::json_to_html(&json)?
-            }
-            (Format::Xml, Format::Markdown) => {
-                let json = formats::xml::xml_to_json(&request.content)?;
-                Self::json_to_markdown(&json)?
-            }
-            (Format::Xml, Format::Html) => {
-                let json = formats::xml::xml_to_json(&request.content)?;
-                Self::json_to_html(&json)?
-            }
-            (Format::Toml, Format::Markdown) => {
-                let json = formats::toml::toml_to_json(&request.content)?;
-                Self::json_to_markdown(&json)?
-            }
-            (Format::Toml, Format::Html) => {
-                let json = formats::toml::toml_to_json(&request.content)?;
-                Self::json_to_html(&json)?
-            }
-
-            // Markdown/HTML → Extended formats (via JSON intermediate)
-            (Format::Markdown, Format::Yaml) => {
-                let json = Self::markdown_to_json(&request.content)?;
-                formats::yaml::json_to_yaml(&json)?
-            }
-            (Format::Markdown, Format::Xml) => {
-                let json = Self::markdown_to_json(&request.content)?;
-                formats::xml::json_to_xml(&json)?
-            }
-            (Format::Markdown, Format::Toml) => {
-                let json = Self::markdown_to_json(&request.content)?;
-                formats::toml::json_to_toml(&json)?
-            }
-            (Format::Html, Format::Yaml) => {
-                let json = Self::html_to_json(&request.content)?;
-                formats::yaml::json_to_yaml(&json)?
-            }
-            (Format::Html, Format::Xml) => {
-                let json = Self::html_to_json(&request.content)?;
-                formats::xml::json_to_xml(&json)?
-            }
-            (Format::Html, Format::Toml) => {
-                let json = Self::html_to_json(&request.content)?;
-                formats::toml::json_to_toml(&json)?
-            }
-
-            // Extended format cross-conversions (via JSON intermediate)
-            (Format::Yaml, Format::Xml) => {
-                let json = formats::yaml::yaml_to_json(&request.content)?;
-                formats::xml::json_to_xml(&json)?
-            }
-            (Format::Yaml, Format::Toml) => {
-                let json = formats::yaml::yaml_to_json(&request.content)?;
-                formats::toml::json_to_toml(&json)?
-            }
-            (Format::Xml, Format::Yaml) => {
-                let json = formats::xml::xml_to_json(&request.content)?;
-                formats::yaml::json_to_yaml(&json)?
-            }
-            (Format::Xml, Format::Toml) => {
-                let json = formats::xml::xml_to_json(&request.content)?;
-                formats::toml::json_to_toml(&json)?
-            }
-            (Format::Toml, Format::Yaml) => {
-                let json = formats::toml::toml_to_json(&request.content)?;
-                formats::yaml::json_to_yaml(&json)?
-            }
-            (Format::Toml, Format::Xml) => {
-                let json = formats::toml::toml_to_json(&request.content)?;
-                formats::xml::json_to_xml(&json)?
-            }
-
             // Same format - no conversion needed
-            (Format::Markdown, Format::Markdown) |
-            (Format::Html, Format::Html) |
-            (Format::Json, Format::Json) |
-            (Format::Yaml, Format::Yaml) |
-            (Format::Xml, Format::Xml) |
-            (Format::Toml, Format::Toml) => request.content,
+            (from, to) if from == to => request.content,
+
+            // Every other pair, through the document model
+            (from, to) => {
+                let value = formats::model::read(&request.content, from, &mut warnings)?.with_datetimes(options.datetimes);
+                match options.original.as_deref().map(|original| formats::model::write_onto(&value, to, original)) {
+                    Some(Ok(content)) => content,
+                    Some(Err(e)) => {
+                        warnings.push(format!("The original {} could not be read, so its layout is not kept: {:#}", to.name(), e));
+                        formats::model::write(&value, to)?
+                    }
+                    None => formats::model::write(&value, to)?,
+                }
+            }
         };
 
         Ok(ConversionResponse {
@@ -204,16 +232,16 @@ pub fn convert(request: ConversionRequest) -> Result<ConversionResponse> {
         })
     }
 
-    /// Convert Markdown to HTML using pulldown-cmark
+    /// Convert Markdown to HTML using pulldown-cmark, leaving out any front matter
     fn markdown_to_html(markdown: &str) -> String {
-        let parser = Parser::new(markdown);
+        let parser = Parser::new(formats::markdown::body(markdown));
         let mut html_output = String::new();
         html::push_html(&mut html_output, parser);
         html_output
     }
 
     /// Convert Markdown to JSON (structured representation)
-    fn markdown_to_json(markdown: &str) -> Result<String> {
+    pub(crate) fn markdown_to_json(markdown: &str) -> Result<String> {
         let html = Self::markdown_to_html(markdown);
         Self::html_to_json(&html)
     }
@@ -255,8 +283,8 @@ fn html_to_markdown(html_content: &str) -> Result<String> {
             for element in document.select(&code_selector) {
                 let text = element.text().collect::<String>();
                 if element.value().name() == "code"
-                    && element.parent().map_or(false, |p| {
-                        p.value().as_element().map_or(false, |e| e.name() == "pre")
+                    && element.parent().is_some_and(|p| {
+                        p.value().as_element().is_some_and(|e| e.name() == "pre")
                     })
                 {
                     markdown.push_str(&format!("```\n{}\n```\n\n", text.trim()));
@@ -280,7 +308,7 @@ fn html_to_markdown(html_content: &str) -> Result<String> {
     }
 
     /// Convert HTML to JSON (DOM structure)
-    fn html_to_json(html_content: &str) -> Result<String> {
+    pub(crate) fn html_to_json(html_content: &str) -> Result<String> {
         let document = Html::parse_document(html_content);
 
         let mut data = HashMap::new();
@@ -345,21 +373,35 @@ pub fn json_to_markdown(json_content: &str) -> Result<String> {
     }
 
     /// Convert JSON to HTML
-    fn json_to_html(json_content: &str) -> Result<String> {
+    pub(crate) fn json_to_html(json_content: &str) -> Result<String> {
         let markdown = Self::json_to_markdown(json_content)?;
         Ok(Self::markdown_to_html(&markdown))
     }
 
     /// Validate document format
+    ///
+    /// Findings of the lint rules at their default severities follow the
+    /// syntax problems where they are warnings or errors.
     pub fn validate(content: &str, format: Format) -> Result<Vec<String>> {
+        let mut diagnostics = Self::validate_syntax(content, format)?;
+        let findings = formats::lint::lint(content, format, &formats::lint::LintConfig::default(), None);
+        let reported = findings.into_iter().filter(|finding| finding.severity >= formats::lint::Severity::Warning);
+        diagnostics.extend(reported.map(|finding| finding.to_string()));
+        Ok(diagnostics)
+    }
+
+    /// Check a document parses, returning its syntax problems
+    ///
+    /// # Errors
+    ///
+    /// Fails where a check cannot run at all; problems the document has are
+    /// returned instead.
+    pub fn validate_syntax(content: &str, format: Format) -> Result<Vec<String>> {
         let mut diagnostics = Vec::new();
 
         match format {
             Format::Markdown => {
-                // Basic Markdown validation
-                if content.is_empty() {
-                    diagnostics.push("Document is empty".to_string());
-                }
+                diagnostics.extend(formats::markdown::validate_front_matter(content)?);
             }
             Format::Html => {
                 // HTML validation - check if it parses
@@ -390,6 +432,39 @@ pub fn validate(content: &str, format: Format) -> Result<Vec<String>> {
                 // TOML validation (Platinum RSR)
                 diagnostics.extend(formats::toml::validate_toml(content)?);
             }
+            Format::Csv => {
+                diagnostics.extend(formats::csv::validate_csv(content)?);
+            }
+            Format::Tsv => {
+                diagnostics.extend(formats::csv::validate_tsv(content)?);
+            }
+            Format::Ini => {
+                diagnostics.extend(formats::ini::validate_ini(content)?);
+            }
+            Format::Dotenv => {
+                diagnostics.extend(formats::dotenv::validate_dotenv(content)?);
+            }
+            Format::Json5 => {
+                diagnostics.extend(formats::json5::validate_json5(content)?);
+            }
+            Format::Jsonc => {
+                diagnostics.extend(formats::json5::validate_jsonc(content)?);
+            }
+            Format::Ndjson => {
+                diagnostics.extend(formats::ndjson::validate_ndjson(content)?);
+            }
+            Format::Msgpack => match formats::msgpack::decode_text(content) {
+                Ok(bytes) => diagnostics.extend(formats::msgpack::validate_msgpack(&bytes)?),
+                Err(e) => diagnostics.push(e.to_string()),
+            },
+            Format::Cbor => match formats::cbor::decode_text(content) {
+                Ok(bytes) => diagnostics.extend(formats::cbor::validate_cbor(&bytes)?),
+                Err(e) => diagnostics.push(e.to_string()),
+            },
+            Format::Plist => match formats::plist::decode_text(content) {
+                Ok(bytes) => diagnostics.extend(formats::plist::validate_plist(&bytes)?),
+                Err(e) => diagnostics.push(e.to_string()),
+            },
         }
 
         Ok(diagnostics)
@@ -407,6 +482,10 @@ fn test_markdown_to_html() {
         assert!(html.contains("<h1>"));
         assert!(html.contains("Hello World"));
         assert!(html.contains("<strong>"));
+
+        // Front matter is metadata, not content
+        let html = ConversionCore::markdown_to_html("---\ntitle: Hello\n---\n# Body\n");
+        assert_eq!(html, "<h1>Body</h1>\n");
     }
 
     #[test]
@@ -1,12 +1,33 @@
 //! Concurrent document storage using lock-free DashMap
 //!
-//! Provides thread-safe document management with minimal contention.
+//! Provides thread-safe document management with minimal contention. With a
+//! data directory configured, [`Snapshots`] persist the documents to a file
+//! there and load them back at startup.
 
+use crate::monitoring::slow_ops::{OpKind, Operation, SlowOps};
+use crate::monitoring::StoreMetricsRecorder;
+use crate::telemetry;
+use dashmap::mapref::entry::Entry;
+use anyhow::{Context, Result};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use tracing::info_span;
 use uuid::Uuid;
 
+/// Capacity of the store event channel before slow subscribers start lagging
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// URI of the sentinel document written by [`DocumentStore::probe`]
+const PROBE_URI: &str = "health://probe";
+
+/// File under the data directory the documents are persisted to
+pub const SNAPSHOT_FILE: &str = "documents.json";
+
 /// Document metadata and content
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
@@ -68,28 +89,245 @@ pub struct DocumentStats {
     pub version: i32,
 }
 
+/// Kind of change recorded by a [`DocumentEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentEventKind {
+    Created,
+    Updated,
+    Removed,
+}
+
+/// Change notification published by the document store
+#[derive(Debug, Clone)]
+pub struct DocumentEvent {
+    pub kind: DocumentEventKind,
+    /// Document state after the change (before removal for `Removed`)
+    pub document: Arc<Document>,
+}
+
+/// URI pattern used to query and subscribe to documents
+///
+/// Patterns containing `*` or `?` are globs: `**` matches any sequence of
+/// characters, `*` matches within a single path segment, and `?` matches one
+/// character other than `/`. Anything else is a URI prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriPattern {
+    Prefix(String),
+    Glob(String),
+}
+
+impl UriPattern {
+    /// Parse a pattern string
+    #[must_use]
+    pub fn parse(pattern: &str) -> Self {
+        let normalized = normalize_uri(pattern);
+        if normalized.contains(['*', '?']) {
+            Self::Glob(normalized)
+        } else {
+            Self::Prefix(normalized)
+        }
+    }
+
+    /// Check whether a URI matches this pattern
+    #[must_use]
+    pub fn matches(&self, uri: &str) -> bool {
+        let uri = normalize_uri(uri);
+        match self {
+            Self::Prefix(prefix) => uri.starts_with(prefix.as_str()),
+            Self::Glob(glob) => glob_matches(glob.as_bytes(), uri.as_bytes()),
+        }
+    }
+}
+
+/// Normalize a URI for matching
+///
+/// Lowercases the scheme, converts backslashes to forward slashes, and decodes
+/// percent-escapes so `file:///my%20docs` and `file:///my docs` compare equal.
+#[must_use]
+pub fn normalize_uri(uri: &str) -> String {
+    let uri = uri.trim().replace('\\', "/");
+    let (scheme, rest) = match uri.find("://") {
+        Some(idx) => (uri[..idx].to_ascii_lowercase(), &uri[idx..]),
+        None => (String::new(), uri.as_str()),
+    };
+
+    let bytes = rest.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    scheme + &String::from_utf8_lossy(&decoded)
+}
+
+/// Match a glob pattern against a normalized URI
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let mut memo = vec![None; (pattern.len() + 1) * (text.len() + 1)];
+    glob_matches_at(pattern, text, 0, 0, &mut memo)
+}
+
+fn glob_matches_at(p: &[u8], t: &[u8], pi: usize, ti: usize, memo: &mut [Option<bool>]) -> bool {
+    let key = pi * (t.len() + 1) + ti;
+    if let Some(result) = memo[key] {
+        return result;
+    }
+
+    let result = if pi == p.len() {
+        ti == t.len()
+    } else if p[pi] == b'*' && p.get(pi + 1) == Some(&b'*') {
+        // `**/` may also match zero directories
+        let next = pi + 2;
+        (p.get(next) == Some(&b'/') && glob_matches_at(p, t, next + 1, ti, memo))
+            || glob_matches_at(p, t, next, ti, memo)
+            || (ti < t.len() && glob_matches_at(p, t, pi, ti + 1, memo))
+    } else if p[pi] == b'*' {
+        glob_matches_at(p, t, pi + 1, ti, memo)
+            || (ti < t.len() && t[ti] != b'/' && glob_matches_at(p, t, pi, ti + 1, memo))
+    } else if ti < t.len() && (p[pi] == t[ti] || (p[pi] == b'?' && t[ti] != b'/')) {
+        glob_matches_at(p, t, pi + 1, ti + 1, memo)
+    } else {
+        false
+    };
+
+    memo[key] = Some(result);
+    result
+}
+
+/// Listener invoked synchronously for every store event
+type Observer = Box<dyn Fn(&DocumentEvent) + Send + Sync>;
+
 /// Thread-safe document store using lock-free concurrent HashMap
 pub struct DocumentStore {
     /// Documents indexed by URI
     documents: DashMap<String, Document>,
+    /// Change notifications for subscribers
+    events: broadcast::Sender<DocumentEvent>,
+    /// Synchronous listeners, called before the event is broadcast
+    observers: RwLock<Vec<Observer>>,
+    /// Where writes slower than their threshold are reported
+    slow_ops: OnceLock<Arc<SlowOps>>,
+    /// Where document counts and sizes are reported
+    metrics: OnceLock<Arc<dyn StoreMetricsRecorder>>,
 }
 
 impl DocumentStore {
     /// Create a new empty document store
     pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         Self {
             documents: DashMap::new(),
+            events,
+            observers: RwLock::new(Vec::new()),
+            slow_ops: OnceLock::new(),
+            metrics: OnceLock::new(),
+        }
+    }
+
+    /// Report every change to `recorder`
+    ///
+    /// Documents already stored are reported as added. Only the first
+    /// recorder set is used.
+    pub fn record_metrics(&self, recorder: Arc<dyn StoreMetricsRecorder>) {
+        if self.metrics.set(recorder).is_ok() {
+            let Some(recorder) = self.metrics.get() else {
+                return;
+            };
+            for entry in &self.documents {
+                recorder.document_added(&entry.value().language, entry.value().content.len());
+            }
+        }
+    }
+
+    /// Report writes slower than their threshold to `slow_ops`
+    ///
+    /// Writes include the synchronous observers, which are what usually
+    /// makes one slow. Only the first detector set is used.
+    pub fn report_slow_ops(&self, slow_ops: Arc<SlowOps>) {
+        let _ = self.slow_ops.set(slow_ops);
+    }
+
+    /// Subscribe to document change events
+    pub fn subscribe(&self) -> broadcast::Receiver<DocumentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Register a listener called for every change, on the writer's thread
+    ///
+    /// Observers see events in publication order before any subscriber does,
+    /// so they must be cheap. They suit components that re-broadcast events
+    /// in their own form without adding a task hop.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the observer list.
+    pub fn observe(&self, observer: impl Fn(&DocumentEvent) + Send + Sync + 'static) {
+        self.observers
+            .write()
+            .expect("observers lock poisoned")
+            .push(Box::new(observer));
+    }
+
+    /// Publish an event if anyone is listening
+    fn publish(&self, kind: DocumentEventKind, document: &Document) {
+        let observers = self.observers.read().expect("observers lock poisoned");
+        if observers.is_empty() && self.events.receiver_count() == 0 {
+            return;
+        }
+
+        let event = DocumentEvent {
+            kind,
+            document: Arc::new(document.clone()),
+        };
+        for observer in observers.iter() {
+            observer(&event);
+        }
+        let _ = self.events.send(event);
+    }
+
+    fn report_slow(&self, operation: Operation, start: Instant) {
+        if let Some(slow_ops) = self.slow_ops.get() {
+            slow_ops.record(operation, start.elapsed());
         }
     }
 
     /// Insert or update a document
     pub fn upsert(&self, uri: String, content: String, language: String) -> Arc<Document> {
-        self.documents
-            .entry(uri.clone())
-            .and_modify(|doc| doc.update_content(content.clone()))
-            .or_insert_with(|| Document::new(uri, content, language))
-            .clone()
-            .into()
+        let _span = info_span!("store.upsert", size = telemetry::size_bucket(content.len())).entered();
+        let start = Instant::now();
+        let operation = Operation::new(OpKind::Store, "upsert").size(content.len()).format(language.as_str());
+        let (document, kind) = match self.documents.entry(uri.clone()) {
+            Entry::Occupied(mut entry) => {
+                let (old_bytes, new_bytes) = (entry.get().content.len(), content.len());
+                entry.get_mut().update_content(content);
+                if let Some(metrics) = self.metrics.get() {
+                    metrics.document_resized(old_bytes, new_bytes);
+                }
+                (entry.get().clone(), DocumentEventKind::Updated)
+            }
+            Entry::Vacant(entry) => {
+                let document = Document::new(uri, content, language);
+                if let Some(metrics) = self.metrics.get() {
+                    metrics.document_added(&document.language, document.content.len());
+                }
+                entry.insert(document.clone());
+                (document, DocumentEventKind::Created)
+            }
+        };
+
+        self.publish(kind, &document);
+        self.report_slow(operation, start);
+        Arc::new(document)
     }
 
     /// Get a document by URI
@@ -107,7 +345,51 @@ pub fn get_by_id(&self, id: &str) -> Option<Document> {
 
     /// Remove a document by URI
     pub fn remove(&self, uri: &str) -> Option<Document> {
-        self.documents.remove(uri).map(|(_, doc)| doc)
+        let _span = info_span!("store.remove").entered();
+        let start = Instant::now();
+        let removed = self.documents.remove(uri).map(|(_, doc)| doc);
+        if let Some(document) = &removed {
+            self.removed(document, start);
+        }
+        removed
+    }
+
+    /// Remove the documents last modified before `cutoff`, returning how many
+    ///
+    /// Each is reported as removed and counted as expired. A document
+    /// written again during the sweep is kept.
+    pub fn expire(&self, cutoff: chrono::DateTime<chrono::Utc>) -> usize {
+        let _span = info_span!("store.expire").entered();
+        let stale: Vec<String> = self
+            .documents
+            .iter()
+            .filter(|entry| entry.value().modified_at < cutoff)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut expired = 0;
+        for uri in stale {
+            let start = Instant::now();
+            if let Some((_, document)) = self.documents.remove_if(&uri, |_, doc| doc.modified_at < cutoff) {
+                self.removed(&document, start);
+                if let Some(metrics) = self.metrics.get() {
+                    metrics.document_expired();
+                }
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    /// Report a removal begun at `start`
+    fn removed(&self, document: &Document, start: Instant) {
+        if let Some(metrics) = self.metrics.get() {
+            metrics.document_removed(&document.language, document.content.len());
+        }
+        self.publish(DocumentEventKind::Removed, document);
+        let operation = Operation::new(OpKind::Store, "remove")
+            .size(document.content.len())
+            .format(document.language.as_str());
+        self.report_slow(operation, start);
     }
 
     /// List all documents
@@ -118,6 +400,15 @@ pub fn list(&self) -> Vec<Document> {
             .collect()
     }
 
+    /// List documents whose URI matches a pattern
+    pub fn query(&self, pattern: &UriPattern) -> Vec<Document> {
+        self.documents
+            .iter()
+            .filter(|entry| pattern.matches(entry.key()))
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
     /// Get document count
     pub fn count(&self) -> usize {
         self.documents.len()
@@ -125,25 +416,171 @@ pub fn count(&self) -> usize {
 
     /// Clear all documents
     pub fn clear(&self) {
-        self.documents.clear();
+        let uris: Vec<String> = self.documents.iter().map(|entry| entry.key().clone()).collect();
+        for uri in uris {
+            self.remove(&uri);
+        }
     }
 
     /// Check if a document exists
     pub fn contains(&self, uri: &str) -> bool {
         self.documents.contains_key(uri)
     }
+
+    /// Put back documents persisted by an earlier run, as they were
+    ///
+    /// Ids, versions and timestamps are kept, and documents stored since
+    /// are replaced. Counted as added, but publishes no events: nothing
+    /// changed as far as subscribers are concerned.
+    pub fn restore(&self, documents: Vec<Document>) {
+        for document in documents {
+            let metrics = self.metrics.get();
+            if let Some(metrics) = metrics {
+                metrics.document_added(&document.language, document.content.len());
+            }
+            if let Some(replaced) = self.documents.insert(document.uri.clone(), document) {
+                if let Some(metrics) = metrics {
+                    metrics.document_removed(&replaced.language, replaced.content.len());
+                }
+            }
+        }
+    }
+
+    /// Write, read back and remove a sentinel document, returning whether
+    /// the read saw the write
+    ///
+    /// Goes through the same map and shard locks as real documents but
+    /// publishes no events. The sentinel is visible to concurrent listings
+    /// for the duration of the probe.
+    pub fn probe(&self) -> bool {
+        let sentinel = Document::new(PROBE_URI.to_string(), String::new(), "plaintext".to_string());
+        let id = sentinel.id.clone();
+        self.documents.insert(PROBE_URI.to_string(), sentinel);
+        let seen = self.documents.get(PROBE_URI).is_some_and(|doc| doc.id == id);
+        self.documents.remove(PROBE_URI);
+        seen
+    }
 }
 
+
 impl Default for DocumentStore {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// The documents of a store persisted to [`SNAPSHOT_FILE`] in a directory
+///
+/// The whole store is written at once, and only when a document changed
+/// since the last write. Writes go to a temporary file renamed over the
+/// snapshot, so a crash mid-write leaves the previous one whole.
+pub struct Snapshots {
+    store: Arc<DocumentStore>,
+    path: PathBuf,
+    /// Set by every change, cleared by a write
+    dirty: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for Snapshots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshots").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+impl Snapshots {
+    /// Persist `store` to a snapshot in `dir`, following its changes from now on
+    pub fn new(store: Arc<DocumentStore>, dir: &Path) -> Self {
+        let dirty = Arc::new(AtomicBool::new(false));
+        let changed = Arc::clone(&dirty);
+        store.observe(move |_| changed.store(true, Ordering::Release));
+        Self { store, path: dir.join(SNAPSHOT_FILE), dirty }
+    }
+
+    /// Where the snapshot is written
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Restore the documents of the snapshot, if there is one, returning how many
+    ///
+    /// Reads synchronously, as it is meant for startup.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the snapshot cannot be read or does not parse.
+    pub fn load(&self) -> Result<usize> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+        };
+        let documents: Vec<Document> =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", self.path.display()))?;
+        let restored = documents.len();
+        self.store.restore(documents);
+        Ok(restored)
+    }
+
+    /// Write the snapshot if a document changed since the last write, returning the documents written
+    ///
+    /// # Errors
+    ///
+    /// Fails where the snapshot cannot be written; it is then written on the
+    /// next flush.
+    pub async fn flush(&self) -> Result<usize> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(0);
+        }
+        let documents = self.store.list();
+        let written = self.write(&documents).await;
+        if written.is_err() {
+            // Kept for the next attempt
+            self.dirty.store(true, Ordering::Release);
+        }
+        written.map(|()| documents.len())
+    }
+
+    async fn write(&self, documents: &[Document]) -> Result<()> {
+        let json = serde_json::to_vec(documents)?;
+        let temporary = self.path.with_extension("json.tmp");
+        let written = async {
+            tokio::fs::write(&temporary, &json).await?;
+            tokio::fs::rename(&temporary, &self.path).await
+        }
+        .await;
+        written.with_context(|| format!("writing {}", self.path.display()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn test_snapshots_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ulc-snapshots-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = Arc::new(DocumentStore::new());
+        let snapshots = Snapshots::new(Arc::clone(&store), &dir);
+        assert_eq!(snapshots.flush().await.unwrap(), 0);
+
+        store.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+        let b = store.upsert("file:///b.md".to_string(), "# B".to_string(), "markdown".to_string());
+        store.upsert("file:///b.md".to_string(), "# B!".to_string(), "markdown".to_string());
+        assert_eq!(snapshots.flush().await.unwrap(), 2);
+        // Nothing changed since
+        assert_eq!(snapshots.flush().await.unwrap(), 0);
+
+        let restarted = Arc::new(DocumentStore::new());
+        let mut events = restarted.subscribe();
+        assert_eq!(Snapshots::new(Arc::clone(&restarted), &dir).load().unwrap(), 2);
+        let restored = restarted.get("file:///b.md").unwrap();
+        assert_eq!((restored.id.as_str(), restored.version, restored.content.as_str()), (b.id.as_str(), 2, "# B!"));
+        assert!(events.try_recv().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_document_creation() {
         let doc = Document::new(
@@ -212,6 +649,83 @@ fn test_store_operations() {
         assert_eq!(store.count(), 0);
     }
 
+    #[test]
+    fn test_uri_patterns() {
+        let glob = UriPattern::parse("file:///ws/**/*.yaml");
+        assert!(glob.matches("file:///ws/deploy.yaml"));
+        assert!(glob.matches("FILE:///ws/k8s/base/service.yaml"));
+        assert!(!glob.matches("file:///ws/k8s/service.yml"));
+        assert!(!glob.matches("file:///other/service.yaml"));
+
+        let single = UriPattern::parse("file:///ws/*.toml");
+        assert!(single.matches("file:///ws/Cargo.toml"));
+        assert!(!single.matches("file:///ws/crate/Cargo.toml"));
+
+        let prefix = UriPattern::parse("file:///my%20docs/");
+        assert!(prefix.matches("file:///my docs/notes.md"));
+        assert!(!prefix.matches("file:///my-docs/notes.md"));
+    }
+
+    #[test]
+    fn test_query_and_events() {
+        let store = DocumentStore::new();
+        let mut events = store.subscribe();
+
+        store.upsert("file:///ws/a.yaml".to_string(), "a: 1".to_string(), "yaml".to_string());
+        store.upsert("file:///ws/b.json".to_string(), "{}".to_string(), "json".to_string());
+        store.upsert("file:///ws/a.yaml".to_string(), "a: 2".to_string(), "yaml".to_string());
+        store.remove("file:///ws/b.json");
+
+        let matched = store.query(&UriPattern::parse("file:///ws/**/*.yaml"));
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].content, "a: 2");
+
+        let kinds: Vec<DocumentEventKind> =
+            std::iter::from_fn(|| events.try_recv().ok()).map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DocumentEventKind::Created,
+                DocumentEventKind::Created,
+                DocumentEventKind::Updated,
+                DocumentEventKind::Removed,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observers() {
+        let store = DocumentStore::new();
+        let seen = Arc::new(RwLock::new(Vec::new()));
+        let observed = Arc::clone(&seen);
+        store.observe(move |event| observed.write().unwrap().push(event.document.content.clone()));
+
+        // Observers are called even when nobody subscribed
+        store.upsert("file:///a.md".to_string(), "one".to_string(), "markdown".to_string());
+        store.upsert("file:///a.md".to_string(), "two".to_string(), "markdown".to_string());
+        assert_eq!(*seen.read().unwrap(), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let store = DocumentStore::new();
+        let mut events = store.subscribe();
+        store.upsert("file:///old.md".to_string(), "old".to_string(), "markdown".to_string());
+        store.upsert("file:///new.md".to_string(), "new".to_string(), "markdown".to_string());
+        let hour_ago = chrono::Utc::now() - chrono::Duration::hours(1);
+        store.documents.get_mut("file:///old.md").unwrap().modified_at = hour_ago;
+
+        assert_eq!(store.expire(hour_ago + chrono::Duration::minutes(1)), 1);
+        assert!(!store.contains("file:///old.md"));
+        assert!(store.contains("file:///new.md"));
+        let removed: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|e| e.kind == DocumentEventKind::Removed)
+            .map(|e| e.document.uri.clone())
+            .collect();
+        assert_eq!(removed, ["file:///old.md"]);
+        assert_eq!(store.expire(hour_ago), 0);
+    }
+
     #[test]
     fn test_concurrent_access() {
         use std::sync::Arc;
@@ -0,0 +1,1212 @@
+//! Apache Avro support for document conversion
+//!
+//! Avro data is read with a [`Schema`], given with it or registered by name
+//! in the [`AvroSchemas`] of [`Formats`](super::Formats); an object
+//! container file carries its own. Data converts to plain JSON: records
+//! become objects, enums their symbols, unions the value of their branch,
+//! and bytes and fixed values base64 strings; `NaN` and the infinities
+//! become strings. Writing picks the first branch of a union that the value
+//! fits, and fills missing fields from their defaults.
+//!
+//! The binary encoding is read and written as single datums, and in object
+//! container files with the `null` codec. The JSON encoding of the Avro
+//! specification, where a union value is wrapped as `{"string": "a"}` and
+//! bytes are strings of code points up to U+00FF, converts to and from
+//! plain JSON. Logical types are read as their underlying types, and a
+//! schema is used as given, without resolving it against a reader's.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Number, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Values nested inside one another before reading gives up
+const MAX_DEPTH: usize = 128;
+
+/// First bytes of an object container file
+const MAGIC: &[u8; 4] = b"Obj\x01";
+
+/// A parsed Avro schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    json: Value,
+    root: Type,
+    named: Vec<Named>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    /// A record, enum or fixed type, by its index in the schema
+    Named(usize),
+    Array(Box<Type>),
+    Map(Box<Type>),
+    Union(Vec<Type>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Named {
+    /// Full name, with its namespace
+    name: String,
+    kind: NamedKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NamedKind {
+    Record(Vec<Field>),
+    Enum(Vec<String>),
+    Fixed(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    kind: Type,
+    /// The default as plain JSON
+    default: Option<Value>,
+}
+
+impl Schema {
+    /// Parse a schema from its JSON
+    ///
+    /// # Errors
+    ///
+    /// Fails where `json` is not a schema: an unknown type, a name defined
+    /// twice or never, or a default that does not match its field.
+    pub fn new(json: Value) -> Result<Self> {
+        let mut parser = Parser::default();
+        let root = parser.parse(&json, "")?;
+        let mut schema = Self { json, root, named: parser.named };
+        schema.read_defaults()?;
+        Ok(schema)
+    }
+
+    /// Parse a schema from JSON text, such as the contents of an `.avsc` file
+    ///
+    /// # Errors
+    ///
+    /// Fails where `text` is not JSON, or not a schema as [`Schema::new`] reads
+    /// one.
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::new(serde_json::from_str(text).context("Invalid Avro schema")?)
+    }
+
+    /// Full name of the schema's type when it is a record, enum or fixed type
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        match self.root {
+            Type::Named(index) => Some(&self.named[index].name),
+            _ => None,
+        }
+    }
+
+    /// The schema's JSON
+    #[must_use]
+    pub fn json(&self) -> &Value {
+        &self.json
+    }
+
+    /// Turn field defaults, written in the Avro JSON encoding for the first branch of a union, into plain JSON
+    fn read_defaults(&mut self) -> Result<()> {
+        for index in 0..self.named.len() {
+            let NamedKind::Record(fields) = &self.named[index].kind else {
+                continue;
+            };
+            let mut defaults = Vec::new();
+            for field in fields {
+                let default = field.default.as_ref().map(|default| {
+                    // A union's default is a value of its first branch, not wrapped
+                    let kind = match &field.kind {
+                        Type::Union(branches) => branches.first().unwrap_or(&Type::Null),
+                        kind => kind,
+                    };
+                    let mut diagnostics = Vec::new();
+                    let plain = self.read_avro_json(kind, default, &field.name, 0, &mut diagnostics);
+                    match diagnostics.first() {
+                        Some(diagnostic) => Err(anyhow!("Default of {}: {}", self.named[index].name, diagnostic)),
+                        None => Ok(plain),
+                    }
+                });
+                defaults.push(default.transpose()?);
+            }
+            if let NamedKind::Record(fields) = &mut self.named[index].kind {
+                for (field, default) in fields.iter_mut().zip(defaults) {
+                    field.default = default;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of a union branch of type `kind`, as the JSON encoding wraps values in
+    fn branch_name<'a>(&'a self, kind: &Type) -> &'a str {
+        match kind {
+            Type::Null => "null",
+            Type::Boolean => "boolean",
+            Type::Int => "int",
+            Type::Long => "long",
+            Type::Float => "float",
+            Type::Double => "double",
+            Type::Bytes => "bytes",
+            Type::String => "string",
+            Type::Named(index) => &self.named[*index].name,
+            Type::Array(_) => "array",
+            Type::Map(_) => "map",
+            Type::Union(_) => "union",
+        }
+    }
+
+    /// Whether plain JSON `value` can be written as `kind`
+    fn fits(&self, kind: &Type, value: &Value) -> bool {
+        let mut writer = Writer { schema: self, out: Vec::new(), diagnostics: Vec::new() };
+        writer.write(kind, value, "");
+        writer.diagnostics.is_empty()
+    }
+
+    /// The first branch of `branches` that `value` fits
+    fn branch<'a>(&self, branches: &'a [Type], value: &Value) -> Option<(usize, &'a Type)> {
+        branches.iter().enumerate().find(|(_, branch)| self.fits(branch, value))
+    }
+
+    /// Plain JSON of a value in the Avro JSON encoding, reporting what does not fit
+    fn read_avro_json(&self, kind: &Type, value: &Value, path: &str, depth: usize, diagnostics: &mut Vec<AvroDiagnostic>) -> Value {
+        let mut report = |message: String| diagnostics.push(AvroDiagnostic { path: path.to_string(), message });
+        if depth > MAX_DEPTH {
+            report("values nested too deeply".to_string());
+            return Value::Null;
+        }
+        match (kind, value) {
+            (Type::Union(branches), Value::Null) if branches.contains(&Type::Null) => Value::Null,
+            (Type::Union(branches), Value::Object(wrapped)) if wrapped.len() == 1 => {
+                let (name, inner) = wrapped.iter().next().expect("one entry");
+                if let Some(branch) = branches.iter().find(|branch| self.branch_name(branch) == name) { self.read_avro_json(branch, inner, path, depth + 1, diagnostics) } else {
+                    report(format!("the union has no branch {name}"));
+                    Value::Null
+                }
+            }
+            (Type::Union(branches), _) => {
+                let names: Vec<&str> = branches.iter().map(|branch| self.branch_name(branch)).collect();
+                report(format!("expected a union value wrapped in its branch, one of {}", names.join(", ")));
+                Value::Null
+            }
+            (Type::Bytes, Value::String(text)) => if let Some(bytes) = code_points(text) { Value::String(STANDARD.encode(bytes)) } else {
+                report("bytes are written as code points up to U+00FF".to_string());
+                Value::Null
+            },
+            (Type::Named(index), _) => match (&self.named[*index].kind, value) {
+                (NamedKind::Fixed(size), Value::String(text)) => match code_points(text) {
+                    Some(bytes) if bytes.len() == *size => Value::String(STANDARD.encode(bytes)),
+                    Some(bytes) => {
+                        report(format!("{} holds {} bytes, not {}", self.named[*index].name, size, bytes.len()));
+                        Value::Null
+                    }
+                    None => {
+                        report("bytes are written as code points up to U+00FF".to_string());
+                        Value::Null
+                    }
+                },
+                (NamedKind::Record(fields), Value::Object(object)) => {
+                    let mut record = Map::new();
+                    for field in fields {
+                        let path = join(path, &field.name);
+                        match (object.get(&field.name), &field.default) {
+                            (Some(inner), _) => {
+                                record.insert(field.name.clone(), self.read_avro_json(&field.kind, inner, &path, depth + 1, diagnostics));
+                            }
+                            (None, Some(default)) => {
+                                record.insert(field.name.clone(), default.clone());
+                            }
+                            (None, None) => diagnostics.push(AvroDiagnostic { path, message: "missing field".to_string() }),
+                        }
+                    }
+                    for key in object.keys().filter(|key| !fields.iter().any(|field| &field.name == *key)) {
+                        let message = format!("{} has no field {}", self.named[*index].name, key);
+                        diagnostics.push(AvroDiagnostic { path: join(path, key), message });
+                    }
+                    Value::Object(record)
+                }
+                _ => self.check_plain(kind, value, path, diagnostics),
+            },
+            (Type::Array(items), Value::Array(values)) => Value::Array(
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| self.read_avro_json(items, item, &format!("{path}[{index}]"), depth + 1, diagnostics))
+                    .collect(),
+            ),
+            (Type::Map(values), Value::Object(entries)) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, item)| (key.clone(), self.read_avro_json(values, item, &join(path, key), depth + 1, diagnostics)))
+                    .collect(),
+            ),
+            _ => self.check_plain(kind, value, path, diagnostics),
+        }
+    }
+
+    /// `value` as it is, where plain JSON and the Avro JSON encoding agree, reporting it if it does not fit
+    fn check_plain(&self, kind: &Type, value: &Value, path: &str, diagnostics: &mut Vec<AvroDiagnostic>) -> Value {
+        let mut writer = Writer { schema: self, out: Vec::new(), diagnostics: Vec::new() };
+        writer.write(kind, value, path);
+        diagnostics.append(&mut writer.diagnostics);
+        value.clone()
+    }
+
+    /// The Avro JSON encoding of plain JSON `value`, which fits `kind`
+    fn write_avro_json(&self, kind: &Type, value: &Value) -> Value {
+        match (kind, value) {
+            (Type::Union(branches), value) => match self.branch(branches, value) {
+                Some((_, Type::Null)) | None => Value::Null,
+                Some((_, branch)) => {
+                    let mut wrapped = Map::new();
+                    wrapped.insert(self.branch_name(branch).to_string(), self.write_avro_json(branch, value));
+                    Value::Object(wrapped)
+                }
+            },
+            (Type::Bytes, Value::String(text)) => Value::String(STANDARD.decode(text).unwrap_or_default().into_iter().map(char::from).collect()),
+            (Type::Named(index), _) => match (&self.named[*index].kind, value) {
+                (NamedKind::Fixed(_), Value::String(text)) => {
+                    Value::String(STANDARD.decode(text).unwrap_or_default().into_iter().map(char::from).collect())
+                }
+                (NamedKind::Record(fields), Value::Object(object)) => Value::Object(
+                    fields
+                        .iter()
+                        .filter_map(|field| {
+                            let inner = object.get(&field.name).or(field.default.as_ref())?;
+                            Some((field.name.clone(), self.write_avro_json(&field.kind, inner)))
+                        })
+                        .collect(),
+                ),
+                _ => value.clone(),
+            },
+            (Type::Array(items), Value::Array(values)) => Value::Array(values.iter().map(|item| self.write_avro_json(items, item)).collect()),
+            (Type::Map(values), Value::Object(entries)) => {
+                Value::Object(entries.iter().map(|(key, item)| (key.clone(), self.write_avro_json(values, item))).collect())
+            }
+            _ => value.clone(),
+        }
+    }
+}
+
+/// Named types defined so far, while parsing a schema
+#[derive(Default)]
+struct Parser {
+    named: Vec<Named>,
+    names: HashMap<String, usize>,
+}
+
+impl Parser {
+    fn parse(&mut self, value: &Value, namespace: &str) -> Result<Type> {
+        match value {
+            Value::String(name) => self.reference(name, namespace),
+            Value::Array(branches) => {
+                let mut kinds: Vec<Type> = Vec::new();
+                for branch in branches {
+                    let kind = self.parse(branch, namespace)?;
+                    if matches!(kind, Type::Union(_)) {
+                        bail!("A union cannot hold another union directly");
+                    }
+                    if kinds.iter().any(|other| self.same_branch(other, &kind)) {
+                        bail!("A union holds the same type twice: {branch}");
+                    }
+                    kinds.push(kind);
+                }
+                Ok(Type::Union(kinds))
+            }
+            Value::Object(object) => {
+                let kind = object.get("type").ok_or_else(|| anyhow!("A schema object needs a type: {value}"))?;
+                match kind.as_str() {
+                    Some("record" | "error") => {
+                        let (index, inner) = self.define(object, namespace, NamedKind::Record(Vec::new()))?;
+                        let Some(Value::Array(declared)) = object.get("fields") else {
+                            bail!("Record {} needs an array of fields", self.named[index].name);
+                        };
+                        let mut fields: Vec<Field> = Vec::new();
+                        for field in declared {
+                            let name = field.get("name").and_then(Value::as_str).ok_or_else(|| anyhow!("Fields of {} need a name", self.named[index].name))?;
+                            if fields.iter().any(|other| other.name == name) {
+                                bail!("Record {} has two fields called {}", self.named[index].name, name);
+                            }
+                            let declared = field.get("type").ok_or_else(|| anyhow!("Field {name} needs a type"))?;
+                            let kind = self.parse(declared, &inner)?;
+                            fields.push(Field { name: name.to_string(), kind, default: field.get("default").cloned() });
+                        }
+                        self.named[index].kind = NamedKind::Record(fields);
+                        Ok(Type::Named(index))
+                    }
+                    Some("enum") => {
+                        let symbols = object
+                            .get("symbols")
+                            .and_then(Value::as_array)
+                            .and_then(|symbols| symbols.iter().map(|symbol| symbol.as_str().map(str::to_string)).collect::<Option<Vec<_>>>())
+                            .ok_or_else(|| anyhow!("An enum needs an array of symbols: {value}"))?;
+                        let (index, _) = self.define(object, namespace, NamedKind::Enum(symbols))?;
+                        Ok(Type::Named(index))
+                    }
+                    Some("fixed") => {
+                        let size = object.get("size").and_then(Value::as_u64).ok_or_else(|| anyhow!("A fixed type needs a size: {value}"))?;
+                        let (index, _) = self.define(object, namespace, NamedKind::Fixed(usize::try_from(size)?))?;
+                        Ok(Type::Named(index))
+                    }
+                    Some("array") => {
+                        let items = object.get("items").ok_or_else(|| anyhow!("An array needs items: {value}"))?;
+                        Ok(Type::Array(Box::new(self.parse(items, namespace)?)))
+                    }
+                    Some("map") => {
+                        let values = object.get("values").ok_or_else(|| anyhow!("A map needs values: {value}"))?;
+                        Ok(Type::Map(Box::new(self.parse(values, namespace)?)))
+                    }
+                    // A primitive with attributes, such as a logical type
+                    _ => self.parse(kind, namespace),
+                }
+            }
+            _ => bail!("A schema is a type name, an array of union branches or an object, not {value}"),
+        }
+    }
+
+    fn reference(&self, name: &str, namespace: &str) -> Result<Type> {
+        Ok(match name {
+            "null" => Type::Null,
+            "boolean" => Type::Boolean,
+            "int" => Type::Int,
+            "long" => Type::Long,
+            "float" => Type::Float,
+            "double" => Type::Double,
+            "bytes" => Type::Bytes,
+            "string" => Type::String,
+            name => {
+                let index = self.names.get(&full_name(name, namespace)).or_else(|| self.names.get(name));
+                Type::Named(*index.ok_or_else(|| anyhow!("Unknown type {name}"))?)
+            }
+        })
+    }
+
+    /// Add a named type, returning its index and the namespace of the types inside it
+    fn define(&mut self, object: &Map<String, Value>, namespace: &str, kind: NamedKind) -> Result<(usize, String)> {
+        let name = object.get("name").and_then(Value::as_str).ok_or_else(|| anyhow!("Records, enums and fixed types need a name"))?;
+        let namespace = object.get("namespace").and_then(Value::as_str).unwrap_or(namespace);
+        let name = full_name(name, namespace);
+        if self.names.contains_key(&name) {
+            bail!("{name} is defined twice");
+        }
+        let index = self.named.len();
+        let inner = name.rsplit_once('.').map_or("", |(namespace, _)| namespace).to_string();
+        self.names.insert(name.clone(), index);
+        self.named.push(Named { name, kind });
+        Ok((index, inner))
+    }
+
+    /// Whether two union branches would be wrapped under the same name
+    fn same_branch(&self, a: &Type, b: &Type) -> bool {
+        match (a, b) {
+            (Type::Named(a), Type::Named(b)) => self.named[*a].name == self.named[*b].name,
+            (Type::Array(_), Type::Array(_)) | (Type::Map(_), Type::Map(_)) => true,
+            (a, b) => a == b,
+        }
+    }
+}
+
+fn full_name(name: &str, namespace: &str) -> String {
+    if name.contains('.') || namespace.is_empty() {
+        name.to_string()
+    } else {
+        format!("{namespace}.{name}")
+    }
+}
+
+/// Schemas by name, registered at startup or while running
+#[derive(Default)]
+pub struct AvroSchemas {
+    schemas: RwLock<BTreeMap<String, Arc<Schema>>>,
+}
+
+impl AvroSchemas {
+    /// An empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `schema` as `name`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is empty or already registered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn register(&self, name: &str, schema: Schema) -> Result<()> {
+        if name.is_empty() {
+            bail!("Avro schemas need a name");
+        }
+        let mut schemas = self.schemas.write().expect("avro schema registry lock poisoned");
+        if schemas.contains_key(name) {
+            bail!("An Avro schema called {name} is already registered");
+        }
+        schemas.insert(name.to_string(), Arc::new(schema));
+        Ok(())
+    }
+
+    /// Remove the schema called `name`, returning whether there was one
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn unregister(&self, name: &str) -> bool {
+        self.schemas.write().expect("avro schema registry lock poisoned").remove(name).is_some()
+    }
+
+    /// The schema called `name`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn get(&self, name: &str) -> Option<Arc<Schema>> {
+        self.schemas.read().expect("avro schema registry lock poisoned").get(name).cloned()
+    }
+
+    /// Names of the registered schemas, in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn names(&self) -> Vec<String> {
+        self.schemas.read().expect("avro schema registry lock poisoned").keys().cloned().collect()
+    }
+}
+
+/// A value that does not match its schema, or data that cannot be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvroDiagnostic {
+    /// Path to the value, such as `readings[2].unit`, empty for the whole datum
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for AvroDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Convert a binary datum of `schema` to JSON
+///
+/// # Errors
+///
+/// Fails where `bytes` are not a datum of `schema`, or leave bytes over.
+pub fn binary_to_json(schema: &Schema, bytes: &[u8]) -> Result<String> {
+    let value = read_datum(schema, bytes).map_err(|diagnostic| anyhow!("Invalid Avro: {diagnostic}"))?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Convert JSON to a binary datum of `schema`
+///
+/// # Errors
+///
+/// Fails where the JSON does not parse, or does not match `schema`.
+pub fn json_to_binary(schema: &Schema, json: &str) -> Result<Vec<u8>> {
+    let value: Value = serde_json::from_str(json)?;
+    let mut writer = Writer { schema, out: Vec::new(), diagnostics: Vec::new() };
+    writer.write(&schema.root, &value, "");
+    writer.finish()
+}
+
+/// Convert the Avro JSON encoding of a datum to plain JSON
+///
+/// # Errors
+///
+/// Fails where `avro_json` does not parse, or is not the encoding of a
+/// datum of `schema`.
+pub fn avro_json_to_json(schema: &Schema, avro_json: &str) -> Result<String> {
+    let value: Value = serde_json::from_str(avro_json)?;
+    let mut diagnostics = Vec::new();
+    let plain = schema.read_avro_json(&schema.root, &value, "", 0, &mut diagnostics);
+    if let Some(diagnostic) = diagnostics.first() {
+        bail!("Invalid Avro JSON: {diagnostic}");
+    }
+    Ok(serde_json::to_string_pretty(&plain)?)
+}
+
+/// Convert plain JSON to the Avro JSON encoding of `schema`
+///
+/// # Errors
+///
+/// Fails where the JSON does not parse, or does not match `schema`.
+pub fn json_write_avro_json(schema: &Schema, json: &str) -> Result<String> {
+    let value: Value = serde_json::from_str(json)?;
+    if let Some(diagnostic) = check(schema, &value).first() {
+        bail!("Invalid Avro: {diagnostic}");
+    }
+    Ok(serde_json::to_string_pretty(&schema.write_avro_json(&schema.root, &value))?)
+}
+
+/// The schema an object container file carries
+///
+/// # Errors
+///
+/// Fails where `bytes` do not start with a container header holding a
+/// schema.
+pub fn container_schema(bytes: &[u8]) -> Result<Schema> {
+    let mut reader = Reader { bytes, position: 0 };
+    header(&mut reader).map(|(schema, _)| schema).map_err(|e| anyhow!("Invalid Avro container: {e}"))
+}
+
+/// Convert an object container file to a JSON array of its records, read with the schema it carries
+///
+/// # Errors
+///
+/// Fails where `bytes` are not an object container file, or a record does
+/// not match its schema.
+pub fn container_to_json(bytes: &[u8]) -> Result<String> {
+    let records = read_container(bytes).map_err(|diagnostic| anyhow!("Invalid Avro container: {diagnostic}"))?;
+    Ok(serde_json::to_string_pretty(&records)?)
+}
+
+/// Convert a JSON array of records to an object container file carrying `schema`
+///
+/// # Errors
+///
+/// Fails where the JSON is not an array, or a record does not match
+/// `schema`.
+pub fn json_to_container(schema: &Schema, json: &str) -> Result<Vec<u8>> {
+    let Value::Array(records) = serde_json::from_str(json)? else {
+        bail!("An Avro container is written from a JSON array of records");
+    };
+    let mut writer = Writer { schema, out: Vec::new(), diagnostics: Vec::new() };
+    for (index, record) in records.iter().enumerate() {
+        writer.write(&schema.root, record, &format!("[{index}]"));
+    }
+    let data = writer.finish()?;
+
+    let sync = uuid::Uuid::new_v4().into_bytes();
+    let mut out = MAGIC.to_vec();
+    write_long(&mut out, 2);
+    write_bytes(&mut out, b"avro.schema");
+    write_bytes(&mut out, schema.json.to_string().as_bytes());
+    write_bytes(&mut out, b"avro.codec");
+    write_bytes(&mut out, b"null");
+    write_long(&mut out, 0);
+    out.extend_from_slice(&sync);
+    if !records.is_empty() {
+        write_count(&mut out, records.len());
+        write_count(&mut out, data.len());
+        out.extend_from_slice(&data);
+        out.extend_from_slice(&sync);
+    }
+    Ok(out)
+}
+
+/// Every value of plain JSON that does not match `schema`
+///
+/// # Errors
+///
+/// Fails where the JSON does not parse.
+pub fn validate_json(schema: &Schema, json: &str) -> Result<Vec<AvroDiagnostic>> {
+    Ok(check(schema, &serde_json::from_str(json)?))
+}
+
+/// Every value of a datum in the Avro JSON encoding that does not match `schema`
+///
+/// # Errors
+///
+/// Fails where `avro_json` does not parse.
+pub fn validate_avro_json(schema: &Schema, avro_json: &str) -> Result<Vec<AvroDiagnostic>> {
+    let mut diagnostics = Vec::new();
+    schema.read_avro_json(&schema.root, &serde_json::from_str(avro_json)?, "", 0, &mut diagnostics);
+    Ok(diagnostics)
+}
+
+/// The problem that stops a binary datum of `schema` being read, if any
+#[must_use]
+pub fn validate_binary(schema: &Schema, bytes: &[u8]) -> Vec<AvroDiagnostic> {
+    read_datum(schema, bytes).err().into_iter().collect()
+}
+
+/// The problem that stops an object container file being read, if any
+#[must_use]
+pub fn validate_container(bytes: &[u8]) -> Vec<AvroDiagnostic> {
+    read_container(bytes).err().into_iter().collect()
+}
+
+fn check(schema: &Schema, value: &Value) -> Vec<AvroDiagnostic> {
+    let mut writer = Writer { schema, out: Vec::new(), diagnostics: Vec::new() };
+    writer.write(&schema.root, value, "");
+    writer.diagnostics
+}
+
+fn read_datum(schema: &Schema, bytes: &[u8]) -> std::result::Result<Value, AvroDiagnostic> {
+    let mut reader = Reader { bytes, position: 0 };
+    let value = reader.read(schema, &schema.root, "", 0)?;
+    if reader.position < bytes.len() {
+        return Err(reader.error("", &format!("{} bytes after the datum", bytes.len() - reader.position)));
+    }
+    Ok(value)
+}
+
+fn read_container(bytes: &[u8]) -> std::result::Result<Vec<Value>, AvroDiagnostic> {
+    let mut reader = Reader { bytes, position: 0 };
+    let (schema, sync) = header(&mut reader).map_err(|message| AvroDiagnostic { path: String::new(), message })?;
+    let mut records = Vec::new();
+    while reader.position < bytes.len() {
+        let path = format!("[{}]", records.len());
+        let count = reader.long().map_err(|message| reader.error(&path, &message))?;
+        let size = reader.long().map_err(|message| reader.error(&path, &message))?;
+        let (Ok(count), Ok(size)) = (usize::try_from(count), usize::try_from(size)) else {
+            return Err(reader.error(&path, "negative block count or size"));
+        };
+        let end = reader.position.saturating_add(size);
+        for _ in 0..count {
+            let path = format!("[{}]", records.len());
+            records.push(reader.read(&schema, &schema.root, &path, 0)?);
+            if reader.position > end {
+                return Err(reader.error(&path, "record runs past the end of its block"));
+            }
+        }
+        if reader.position != end {
+            return Err(reader.error("", "block is longer than its records"));
+        }
+        let marker = reader.take(16).map_err(|message| reader.error("", &message))?;
+        if marker != sync {
+            return Err(reader.error("", "sync marker does not match the header's"));
+        }
+    }
+    Ok(records)
+}
+
+/// The schema and sync marker of an object container file
+fn header(reader: &mut Reader) -> std::result::Result<(Schema, [u8; 16]), String> {
+    if reader.take(4).ok() != Some(&MAGIC[..]) {
+        return Err("not an object container file".to_string());
+    }
+    let mut metadata = HashMap::new();
+    loop {
+        let count = reader.long()?;
+        if count == 0 {
+            break;
+        }
+        if count < 0 {
+            reader.long()?;
+        }
+        for _ in 0..count.unsigned_abs() {
+            let key = String::from_utf8(reader.sized()?.to_vec()).map_err(|_| "metadata key is not UTF-8".to_string())?;
+            metadata.insert(key, reader.sized()?.to_vec());
+        }
+    }
+    match metadata.get("avro.codec").map(Vec::as_slice) {
+        None | Some(b"null") => {}
+        Some(codec) => return Err(format!("codec {} is not supported, only null", String::from_utf8_lossy(codec))),
+    }
+    let schema = metadata.get("avro.schema").ok_or_else(|| "no avro.schema in the header".to_string())?;
+    let schema = std::str::from_utf8(schema)
+        .map_err(|e| e.to_string())
+        .and_then(|schema| Schema::parse(schema).map_err(|e| format!("{e:#}")))?;
+    let mut sync = [0; 16];
+    sync.copy_from_slice(reader.take(16)?);
+    Ok((schema, sync))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, path: &str, message: &str) -> AvroDiagnostic {
+        AvroDiagnostic { path: path.to_string(), message: format!("byte {}: {message}", self.position) }
+    }
+
+    fn take(&mut self, length: usize) -> std::result::Result<&'a [u8], String> {
+        let end = self.position.checked_add(length).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| "unexpected end of data".to_string())?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    /// A zigzag varint
+    fn long(&mut self) -> std::result::Result<i64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..70).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1).cast_signed() ^ -(value & 1).cast_signed());
+            }
+        }
+        Err("varint longer than 10 bytes".to_string())
+    }
+
+    /// Bytes preceded by their length
+    fn sized(&mut self) -> std::result::Result<&'a [u8], String> {
+        let length = usize::try_from(self.long()?).map_err(|_| "negative length".to_string())?;
+        self.take(length)
+    }
+
+    /// Item counts of the blocks of an array or map, until the empty block ending it
+    fn block(&mut self) -> std::result::Result<usize, String> {
+        let count = self.long()?;
+        if count < 0 {
+            // A negative count is followed by the block's size in bytes
+            self.long()?;
+        }
+        let count = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+        if count > self.bytes.len() - self.position + 1 {
+            return Err(format!("block of {count} items is longer than the data"));
+        }
+        Ok(count)
+    }
+
+    fn read(&mut self, schema: &Schema, kind: &Type, path: &str, depth: usize) -> std::result::Result<Value, AvroDiagnostic> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(path, "values nested too deeply"));
+        }
+        let start = self.position;
+        let fail = |reader: &mut Self, message: String| {
+            reader.position = start;
+            reader.error(path, &message)
+        };
+        Ok(match kind {
+            Type::Null => Value::Null,
+            Type::Boolean => match self.take(1).map_err(|message| fail(self, message))?[0] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                other => return Err(fail(self, format!("{other} is not a boolean"))),
+            },
+            Type::Int => {
+                let long = self.long().map_err(|message| fail(self, message))?;
+                let int = i32::try_from(long).map_err(|_| fail(self, format!("{long} is out of range for int")))?;
+                Value::from(int)
+            }
+            Type::Long => Value::from(self.long().map_err(|message| fail(self, message))?),
+            Type::Float => {
+                let bytes = self.take(4).map_err(|message| fail(self, message))?;
+                float_to_json(f64::from(f32::from_le_bytes(bytes.try_into().expect("four bytes"))))
+            }
+            Type::Double => {
+                let bytes = self.take(8).map_err(|message| fail(self, message))?;
+                float_to_json(f64::from_le_bytes(bytes.try_into().expect("eight bytes")))
+            }
+            Type::Bytes => Value::String(STANDARD.encode(self.sized().map_err(|message| fail(self, message))?)),
+            Type::String => {
+                let bytes = self.sized().map_err(|message| fail(self, message))?;
+                Value::String(std::str::from_utf8(bytes).map_err(|_| fail(self, "string is not UTF-8".to_string()))?.to_string())
+            }
+            Type::Named(index) => match &schema.named[*index].kind {
+                NamedKind::Record(fields) => {
+                    let mut record = Map::new();
+                    for field in fields {
+                        record.insert(field.name.clone(), self.read(schema, &field.kind, &join(path, &field.name), depth + 1)?);
+                    }
+                    Value::Object(record)
+                }
+                NamedKind::Enum(symbols) => {
+                    let index = self.long().map_err(|message| fail(self, message))?;
+                    let symbol = usize::try_from(index).ok().and_then(|index| symbols.get(index));
+                    Value::String(symbol.ok_or_else(|| fail(self, format!("enum has no symbol {index}")))?.clone())
+                }
+                NamedKind::Fixed(size) => Value::String(STANDARD.encode(self.take(*size).map_err(|message| fail(self, message))?)),
+            },
+            Type::Array(items) => {
+                let mut values = Vec::new();
+                loop {
+                    let count = self.block().map_err(|message| fail(self, message))?;
+                    if count == 0 {
+                        break;
+                    }
+                    for _ in 0..count {
+                        let path = format!("{}[{}]", path, values.len());
+                        values.push(self.read(schema, items, &path, depth + 1)?);
+                    }
+                }
+                Value::Array(values)
+            }
+            Type::Map(values) => {
+                let mut entries = Map::new();
+                loop {
+                    let count = self.block().map_err(|message| fail(self, message))?;
+                    if count == 0 {
+                        break;
+                    }
+                    for _ in 0..count {
+                        let key = self.sized().map_err(|message| fail(self, message))?;
+                        let key = std::str::from_utf8(key).map_err(|_| fail(self, "map key is not UTF-8".to_string()))?.to_string();
+                        let value = self.read(schema, values, &join(path, &key), depth + 1)?;
+                        entries.insert(key, value);
+                    }
+                }
+                Value::Object(entries)
+            }
+            Type::Union(branches) => {
+                let index = self.long().map_err(|message| fail(self, message))?;
+                let branch = usize::try_from(index).ok().and_then(|index| branches.get(index));
+                let branch = branch.ok_or_else(|| fail(self, format!("union has no branch {index}")))?;
+                self.read(schema, branch, path, depth + 1)?
+            }
+        })
+    }
+}
+
+/// Writes plain JSON in the binary encoding, reporting what does not fit
+struct Writer<'s> {
+    schema: &'s Schema,
+    out: Vec<u8>,
+    diagnostics: Vec<AvroDiagnostic>,
+}
+
+impl Writer<'_> {
+    fn finish(self) -> Result<Vec<u8>> {
+        match self.diagnostics.first() {
+            Some(diagnostic) => Err(anyhow!("Invalid Avro: {diagnostic}")),
+            None => Ok(self.out),
+        }
+    }
+
+    fn report(&mut self, path: &str, message: String) {
+        self.diagnostics.push(AvroDiagnostic { path: path.to_string(), message });
+    }
+
+    fn write(&mut self, kind: &Type, value: &Value, path: &str) {
+        let schema = self.schema;
+        match (kind, value) {
+            (Type::Null, Value::Null) => {}
+            (Type::Boolean, Value::Bool(b)) => self.out.push(u8::from(*b)),
+            (Type::Int, Value::Number(n)) => match n.as_i64().and_then(|n| i32::try_from(n).ok()) {
+                Some(int) => write_long(&mut self.out, i64::from(int)),
+                None => self.report(path, format!("{n} is not an int")),
+            },
+            (Type::Long, Value::Number(n)) => match n.as_i64() {
+                Some(long) => write_long(&mut self.out, long),
+                None => self.report(path, format!("{n} is not a long")),
+            },
+            (Type::Float | Type::Double, value) if float(value).is_some() => {
+                let f = float(value).unwrap_or_default();
+                match kind {
+                    #[allow(clippy::cast_possible_truncation)] // Avro floats are single precision
+                    Type::Float => self.out.extend_from_slice(&(f as f32).to_le_bytes()),
+                    _ => self.out.extend_from_slice(&f.to_le_bytes()),
+                }
+            }
+            (Type::Bytes, Value::String(text)) => match STANDARD.decode(text) {
+                Ok(bytes) => write_bytes(&mut self.out, &bytes),
+                Err(e) => self.report(path, format!("invalid base64: {e}")),
+            },
+            (Type::String, Value::String(text)) => write_bytes(&mut self.out, text.as_bytes()),
+            (Type::Named(index), value) => {
+                let named = &schema.named[*index];
+                match (&named.kind, value) {
+                    (NamedKind::Record(fields), Value::Object(object)) => {
+                        for field in fields {
+                            let path = join(path, &field.name);
+                            match object.get(&field.name).or(field.default.as_ref()) {
+                                Some(inner) => self.write(&field.kind, inner, &path),
+                                None => self.report(&path, "missing field".to_string()),
+                            }
+                        }
+                        for key in object.keys().filter(|key| !fields.iter().any(|field| &field.name == *key)) {
+                            self.report(&join(path, key), format!("{} has no field {}", named.name, key));
+                        }
+                    }
+                    (NamedKind::Enum(symbols), Value::String(symbol)) => match symbols.iter().position(|other| other == symbol) {
+                        Some(index) => write_count(&mut self.out, index),
+                        None => self.report(path, format!("{} has no symbol {}", named.name, symbol)),
+                    },
+                    (NamedKind::Fixed(size), Value::String(text)) => match STANDARD.decode(text) {
+                        Ok(bytes) if bytes.len() == *size => self.out.extend_from_slice(&bytes),
+                        Ok(bytes) => self.report(path, format!("{} holds {} bytes, not {}", named.name, size, bytes.len())),
+                        Err(e) => self.report(path, format!("invalid base64: {e}")),
+                    },
+                    (kind, value) => {
+                        let expected = match kind {
+                            NamedKind::Record(_) => "an object",
+                            NamedKind::Enum(_) => "a symbol",
+                            NamedKind::Fixed(_) => "base64 bytes",
+                        };
+                        self.report(path, format!("expected {} for {}, found {}", expected, named.name, self::kind(value)));
+                    }
+                }
+            }
+            (Type::Array(items), Value::Array(values)) => {
+                if !values.is_empty() {
+                    write_count(&mut self.out, values.len());
+                    for (index, item) in values.iter().enumerate() {
+                        self.write(items, item, &format!("{path}[{index}]"));
+                    }
+                }
+                write_long(&mut self.out, 0);
+            }
+            (Type::Map(kind), Value::Object(entries)) => {
+                if !entries.is_empty() {
+                    write_count(&mut self.out, entries.len());
+                    for (key, item) in entries {
+                        write_bytes(&mut self.out, key.as_bytes());
+                        self.write(kind, item, &join(path, key));
+                    }
+                }
+                write_long(&mut self.out, 0);
+            }
+            (Type::Union(branches), value) => if let Some((index, branch)) = schema.branch(branches, value) {
+                write_count(&mut self.out, index);
+                self.write(branch, value, path);
+            } else {
+                let names: Vec<&str> = branches.iter().map(|branch| schema.branch_name(branch)).collect();
+                self.report(path, format!("{} fits no branch of the union of {}", self::kind(value), names.join(", ")));
+            },
+            (kind, value) => {
+                let expected = schema.branch_name(kind);
+                self.report(path, format!("expected {}, found {}", expected, self::kind(value)));
+            }
+        }
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)] // Seven bits at a time
+fn write_long(out: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)).cast_unsigned();
+    while zigzag >= 0x80 {
+        out.push((zigzag as u8 & 0x7f) | 0x80);
+        zigzag >>= 7;
+    }
+    out.push(zigzag as u8);
+}
+
+/// A count, length or index, which Avro writes as a long
+fn write_count(out: &mut Vec<u8>, count: usize) {
+    write_long(out, i64::try_from(count).unwrap_or(i64::MAX));
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_count(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+/// A float written as a number, `NaN` or an infinity
+fn float(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(text) => match text.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A float, or the string JSON writes infinities and NaN as
+fn float_to_json(f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => Value::Number(n),
+        None if f.is_nan() => Value::String("NaN".to_string()),
+        None if f > 0.0 => Value::String("Infinity".to_string()),
+        None => Value::String("-Infinity".to_string()),
+    }
+}
+
+/// The bytes a string of code points up to U+00FF stands for
+fn code_points(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(u32::from(c)).ok()).collect()
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reading() -> Schema {
+        Schema::new(json!({
+            "type": "record",
+            "name": "Reading",
+            "namespace": "telemetry",
+            "fields": [
+                {"name": "sensor", "type": "string"},
+                {"name": "value", "type": "double"},
+                {"name": "unit", "type": {"type": "enum", "name": "Unit", "symbols": ["CELSIUS", "PERCENT"]}, "default": "CELSIUS"},
+                {"name": "taken_at", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+                {"name": "note", "type": ["null", "string"], "default": null},
+                {"name": "tags", "type": {"type": "map", "values": "int"}, "default": {}},
+                {"name": "checksum", "type": {"type": "fixed", "name": "Checksum", "size": 2}},
+                {"name": "previous", "type": ["null", "Reading"], "default": null}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn value(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = reading();
+        assert_eq!(schema.name(), Some("telemetry.Reading"));
+        assert_eq!(Schema::parse("\"string\"").unwrap().name(), None);
+
+        let problems = [
+            (json!("Missing"), "Unknown type Missing"),
+            (json!(["null", ["int"]]), "A union cannot hold another union directly"),
+            (json!(["int", "int"]), "A union holds the same type twice: \"int\""),
+            (json!({"type": "enum", "name": "E"}), "An enum needs an array of symbols"),
+            (json!({"type": "record", "name": "R", "fields": [{"name": "a", "type": "int", "default": "x"}]}), "Default of R: a: expected int, found a string"),
+        ];
+        for (json, message) in problems {
+            let error = format!("{:#}", Schema::new(json).unwrap_err());
+            assert!(error.starts_with(message), "{error}");
+        }
+
+        let registry = AvroSchemas::new();
+        registry.register("reading", schema).unwrap();
+        assert!(registry.register("reading", reading()).is_err());
+        assert_eq!(registry.get("reading").unwrap().name(), Some("telemetry.Reading"));
+        assert!(registry.unregister("reading"));
+        assert_eq!(registry.names(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let schema = reading();
+        let plain = json!({
+            "sensor": "t1",
+            "value": 21.5,
+            "unit": "PERCENT",
+            "taken_at": 1_700_000_000_000i64,
+            "note": "roof",
+            "tags": {"floor": 3},
+            "checksum": "q80=",
+            "previous": {"sensor": "t0", "value": "NaN", "taken_at": -1, "checksum": "AAA="}
+        });
+        let bytes = json_to_binary(&schema, &plain.to_string()).unwrap();
+        let mut expected = plain.clone();
+        // Missing fields are written with their defaults
+        expected["previous"].as_object_mut().unwrap().extend([
+            ("unit".to_string(), json!("CELSIUS")),
+            ("note".to_string(), Value::Null),
+            ("tags".to_string(), json!({})),
+            ("previous".to_string(), Value::Null),
+        ]);
+        assert_eq!(value(&binary_to_json(&schema, &bytes).unwrap()), expected);
+
+        // The specification's example: the long -64 and the string "foo"
+        let pair = Schema::parse(r#"{"type": "record", "name": "P", "fields": [{"name": "a", "type": "long"}, {"name": "b", "type": "string"}]}"#).unwrap();
+        assert_eq!(json_to_binary(&pair, r#"{"a": -64, "b": "foo"}"#).unwrap(), [0x7f, 0x06, b'f', b'o', b'o']);
+        assert_eq!(value(&binary_to_json(&pair, &[0x7f, 0x06, b'f', b'o', b'o']).unwrap()), json!({"a": -64, "b": "foo"}));
+    }
+
+    #[test]
+    fn test_avro_json_encoding() {
+        let schema = reading();
+        let avro = json!({
+            "sensor": "t1",
+            "value": 1.0,
+            "taken_at": 5,
+            "note": {"string": "roof"},
+            "checksum": "\u{00ab}\u{00cd}",
+            "previous": {"telemetry.Reading": {"sensor": "t0", "value": 2.0, "taken_at": 4, "checksum": "\u{0000}\u{0000}"}}
+        });
+        let plain = value(&avro_json_to_json(&schema, &avro.to_string()).unwrap());
+        assert_eq!(plain["note"], "roof");
+        assert_eq!(plain["checksum"], "q80=");
+        assert_eq!(plain["unit"], "CELSIUS");
+        assert_eq!(plain["previous"]["sensor"], "t0");
+
+        let back = value(&json_write_avro_json(&schema, &plain.to_string()).unwrap());
+        assert_eq!(back["note"], json!({"string": "roof"}));
+        assert_eq!(back["previous"]["telemetry.Reading"]["note"], Value::Null);
+        assert_eq!(back["checksum"], "\u{00ab}\u{00cd}");
+
+        let diagnostics = validate_avro_json(&schema, &json!({"sensor": "t1", "value": 1, "taken_at": 1, "note": "roof", "checksum": "\u{0100}\u{0000}"}).to_string()).unwrap();
+        let found: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+        assert_eq!(found, ["note: expected a union value wrapped in its branch, one of null, string", "checksum: bytes are written as code points up to U+00FF"]);
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let schema = reading();
+        let records = json!([
+            {"sensor": "a", "value": 1, "taken_at": 1, "checksum": "AAA="},
+            {"sensor": "b", "value": 2, "taken_at": 2, "checksum": "AAE=", "unit": "PERCENT"}
+        ]);
+        let file = json_to_container(&schema, &records.to_string()).unwrap();
+        assert!(file.starts_with(b"Obj\x01"));
+        assert_eq!(container_schema(&file).unwrap(), schema);
+        let read = value(&container_to_json(&file).unwrap());
+        assert_eq!(read.as_array().unwrap().len(), 2);
+        assert_eq!(read[1]["unit"], "PERCENT");
+        assert_eq!(validate_container(&file), []);
+
+        // A damaged sync marker, and a record cut short
+        let mut damaged = file.clone();
+        *damaged.last_mut().unwrap() ^= 0xff;
+        assert!(validate_container(&damaged)[0].message.contains("sync marker"));
+        let cut = &file[..file.len() - 20];
+        assert!(validate_container(cut)[0].message.contains("unexpected end of data"));
+        assert_eq!(validate_container(b"PAR1")[0].message, "not an object container file");
+        assert_eq!(value(&container_to_json(&json_to_container(&schema, "[]").unwrap()).unwrap()), json!([]));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let schema = reading();
+        let diagnostics = validate_json(
+            &schema,
+            &json!({"sensor": 1, "value": "warm", "unit": "KELVIN", "taken_at": 1.5, "note": 3, "tags": {"a": "b"}, "checksum": "AAAA", "colour": "red"}).to_string(),
+        )
+        .unwrap();
+        let found: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            found,
+            [
+                "sensor: expected string, found a number",
+                "value: expected double, found a string",
+                "unit: telemetry.Unit has no symbol KELVIN",
+                "taken_at: 1.5 is not a long",
+                "note: a number fits no branch of the union of null, string",
+                "tags.a: expected int, found a string",
+                "checksum: telemetry.Checksum holds 2 bytes, not 3",
+                "colour: telemetry.Reading has no field colour",
+            ]
+        );
+        assert_eq!(validate_json(&schema, r#"{"value": 1}"#).unwrap()[0].to_string(), "sensor: missing field");
+
+        let pair = Schema::parse(r#"{"type": "record", "name": "P", "fields": [{"name": "a", "type": "int"}, {"name": "b", "type": "boolean"}]}"#).unwrap();
+        assert_eq!(validate_binary(&pair, &[0x02, 0x01]), []);
+        assert_eq!(validate_binary(&pair, &[0x02, 0x07])[0].to_string(), "b: byte 1: 7 is not a boolean");
+        assert_eq!(validate_binary(&pair, &[0x02])[0].to_string(), "b: byte 1: unexpected end of data");
+        assert_eq!(validate_binary(&pair, &[0x02, 0x00, 0x00])[0].to_string(), "byte 2: 1 bytes after the datum");
+        assert!(binary_to_json(&pair, &[0xff, 0xff, 0xff, 0xff, 0x7f, 0x00]).unwrap_err().to_string().contains("out of range for int"));
+    }
+}
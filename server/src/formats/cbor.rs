@@ -0,0 +1,340 @@
+//! CBOR format support for document conversion
+//!
+//! A CBOR (RFC 8949) document is a single data item, converted to and from
+//! JSON, and through JSON to YAML. Byte strings become base64 strings, and
+//! map keys that are integers, booleans, floats or null become their JSON
+//! text, such as `"1"`; undefined reads as null. Keys that are arrays or
+//! maps, integers beyond 64 bits and floats that are not finite have no
+//! JSON form. Tags, such as 1 for an epoch time, are handled as
+//! [`CborOptions`] choose.
+//!
+//! The transports carry documents as text, so there a CBOR document is its
+//! bytes in base64, which [`decode_text`] and [`encode_text`] read and
+//! write; the functions taking bytes are for code embedding the server.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ciborium::value::{Integer, Value as Cbor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// Keys of the object a kept tag becomes
+const TAG_KEY: &str = "tag";
+const TAG_VALUE_KEY: &str = "value";
+
+/// What becomes of tagged data items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tags {
+    /// Drop the tag, keeping the item it wraps
+    #[default]
+    Unwrap,
+    /// Keep the tag as `{"tag": 1, "value": ...}`, and write such objects back as tags
+    Object,
+    /// Refuse any tag
+    Reject,
+}
+
+/// How CBOR maps onto JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CborOptions {
+    /// What becomes of tagged data items
+    pub tags: Tags,
+}
+
+/// Convert CBOR bytes to JSON
+///
+/// # Errors
+///
+/// Fails where `bytes` are not one CBOR item, or hold a value JSON has no
+/// form for.
+pub fn cbor_to_json(bytes: &[u8]) -> Result<String> {
+    cbor_to_json_with(bytes, &CborOptions::default())
+}
+
+/// Convert CBOR bytes to JSON, handling tags as `options` choose
+///
+/// # Errors
+///
+/// Fails where `bytes` are not one CBOR item, or hold a value JSON has no
+/// form for.
+pub fn cbor_to_json_with(bytes: &[u8], options: &CborOptions) -> Result<String> {
+    let value = read(bytes, options).map_err(|diagnostic| anyhow!("Invalid CBOR: {diagnostic}"))?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Convert JSON to CBOR bytes
+///
+/// # Errors
+///
+/// Fails where `json` does not parse.
+pub fn json_to_cbor(json: &str) -> Result<Vec<u8>> {
+    json_to_cbor_with(json, &CborOptions::default())
+}
+
+/// Convert JSON to CBOR bytes, writing kept tags back when `options` keep them
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or a kept tag is malformed.
+pub fn json_to_cbor_with(json: &str, options: &CborOptions) -> Result<Vec<u8>> {
+    let value: Value = serde_json::from_str(json)?;
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&from_json(value, options), &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Convert CBOR bytes to YAML
+///
+/// # Errors
+///
+/// As [`cbor_to_json`] does.
+pub fn cbor_to_yaml(bytes: &[u8]) -> Result<String> {
+    super::yaml::json_to_yaml(&cbor_to_json(bytes)?)
+}
+
+/// Convert YAML to CBOR bytes
+///
+/// # Errors
+///
+/// Fails where `yaml` does not parse, or has a key JSON cannot hold.
+pub fn yaml_to_cbor(yaml: &str) -> Result<Vec<u8>> {
+    json_to_cbor(&super::yaml::yaml_to_json(yaml)?)
+}
+
+/// Validate CBOR bytes, returning any errors
+///
+/// # Errors
+///
+/// Never; problems with `bytes` are the diagnostics returned.
+pub fn validate_cbor(bytes: &[u8]) -> Result<Vec<String>> {
+    Ok(diagnostics(bytes, &CborOptions::default())
+        .into_iter()
+        .map(|diagnostic| format!("Invalid CBOR: {diagnostic}"))
+        .collect())
+}
+
+/// The bytes of a CBOR document written as base64, ignoring whitespace
+///
+/// # Errors
+///
+/// Fails where `text` is not base64.
+pub fn decode_text(text: &str) -> Result<Vec<u8>> {
+    let compact: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD.decode(compact).map_err(|e| anyhow!("CBOR is sent as base64: {e}"))
+}
+
+/// A CBOR document written as base64
+#[must_use]
+pub fn encode_text(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+/// Why CBOR bytes cannot be read as JSON, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CborDiagnostic {
+    /// Bytes before the problem, or before the item holding it
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CborDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+/// The first problem with `bytes`, or none when they hold one item JSON can hold
+#[must_use]
+pub fn diagnostics(bytes: &[u8], options: &CborOptions) -> Vec<CborDiagnostic> {
+    read(bytes, options).err().into_iter().collect()
+}
+
+fn read(bytes: &[u8], options: &CborOptions) -> std::result::Result<Value, CborDiagnostic> {
+    let mut rest = bytes;
+    let value: Cbor = ciborium::de::from_reader(&mut rest).map_err(|e| {
+        let read = bytes.len() - rest.len();
+        let (offset, message) = match e {
+            ciborium::de::Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                (read, "unexpected end of data".to_string())
+            }
+            ciborium::de::Error::Io(e) => (read, e.to_string()),
+            ciborium::de::Error::Syntax(offset) => (offset, "malformed data item".to_string()),
+            ciborium::de::Error::Semantic(offset, message) => (offset.unwrap_or(read), message),
+            ciborium::de::Error::RecursionLimitExceeded => (read, "items nested too deeply".to_string()),
+        };
+        CborDiagnostic { offset, message }
+    })?;
+    if !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        return Err(CborDiagnostic { offset, message: format!("{} bytes after the data item", rest.len()) });
+    }
+    // Decoding keeps no positions, so problems with the item are placed at its start
+    to_json(value, options).map_err(|message| CborDiagnostic { offset: 0, message })
+}
+
+fn to_json(value: Cbor, options: &CborOptions) -> std::result::Result<Value, String> {
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Integer(i) => integer(i)?,
+        Cbor::Float(f) => Number::from_f64(f).map(Value::Number).ok_or_else(|| format!("{f} has no JSON form"))?,
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bytes(bytes) => Value::String(STANDARD.encode(bytes)),
+        Cbor::Array(items) => {
+            Value::Array(items.into_iter().map(|item| to_json(item, options)).collect::<std::result::Result<_, _>>()?)
+        }
+        Cbor::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                map.insert(key_text(key, options)?, to_json(value, options)?);
+            }
+            Value::Object(map)
+        }
+        // Bignums that fit are read as integers; these are the ones that do not
+        Cbor::Tag(2 | 3, _) if options.tags != Tags::Object => return Err("integer beyond 64 bits".to_string()),
+        Cbor::Tag(tag, item) => match options.tags {
+            Tags::Unwrap => to_json(*item, options)?,
+            Tags::Object => {
+                let mut map = Map::new();
+                map.insert(TAG_KEY.to_string(), Value::from(tag));
+                map.insert(TAG_VALUE_KEY.to_string(), to_json(*item, options)?);
+                Value::Object(map)
+            }
+            Tags::Reject => return Err(format!("tag {tag} is not accepted")),
+        },
+        _ => return Err("unknown data item".to_string()),
+    })
+}
+
+fn integer(i: Integer) -> std::result::Result<Value, String> {
+    let wide = i128::from(i);
+    if let Ok(u) = u64::try_from(wide) {
+        Ok(Value::from(u))
+    } else if let Ok(i) = i64::try_from(wide) {
+        Ok(Value::from(i))
+    } else {
+        Err(format!("integer {wide} beyond 64 bits"))
+    }
+}
+
+/// The JSON key of a map key
+fn key_text(key: Cbor, options: &CborOptions) -> std::result::Result<String, String> {
+    match key {
+        Cbor::Text(text) => Ok(text),
+        Cbor::Bytes(bytes) => Ok(STANDARD.encode(bytes)),
+        Cbor::Tag(_, item) if options.tags == Tags::Unwrap => key_text(*item, options),
+        Cbor::Array(_) | Cbor::Map(_) | Cbor::Tag(..) => {
+            Err("a map key that is an array, map or tag has no JSON form".to_string())
+        }
+        scalar => Ok(to_json(scalar, options)?.to_string()),
+    }
+}
+
+fn from_json(value: Value, options: &CborOptions) -> Cbor {
+    match value {
+        Value::Null => Cbor::Null,
+        Value::Bool(b) => Cbor::Bool(b),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => Cbor::from(u),
+            (_, Some(i)) => Cbor::from(i),
+            _ => Cbor::Float(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(text) => Cbor::Text(text),
+        Value::Array(items) => Cbor::Array(items.into_iter().map(|item| from_json(item, options)).collect()),
+        Value::Object(mut map) => {
+            if options.tags == Tags::Object && map.len() == 2 && map.contains_key(TAG_VALUE_KEY) {
+                if let Some(tag) = map.get(TAG_KEY).and_then(Value::as_u64) {
+                    let item = map.remove(TAG_VALUE_KEY).unwrap_or_default();
+                    return Cbor::Tag(tag, Box::new(from_json(item, options)));
+                }
+            }
+            Cbor::Map(map.into_iter().map(|(key, value)| (Cbor::Text(key), from_json(value, options))).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(bytes: &[u8], tags: Tags) -> std::result::Result<Value, String> {
+        cbor_to_json_with(bytes, &CborOptions { tags })
+            .map(|json| serde_json::from_str(&json).unwrap())
+            .map_err(|e| e.to_string())
+    }
+
+    fn encode(value: &Cbor) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({"name": "sensor", "reading": 21.5, "tags": ["a", null, true], "big": u64::MAX, "neg": -3});
+        let bytes = json_to_cbor(&value.to_string()).unwrap();
+        assert_eq!(to_value(&bytes, Tags::Unwrap).unwrap(), value);
+
+        // RFC 8949 appendix A: [1, [2, 3], [4, 5]]
+        assert_eq!(json_to_cbor("[1, [2, 3], [4, 5]]").unwrap(), [0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05]);
+
+        let yaml = cbor_to_yaml(&bytes).unwrap();
+        assert!(yaml.contains("name: sensor"), "{yaml}");
+        assert_eq!(yaml_to_cbor(&yaml).unwrap(), bytes);
+
+        assert_eq!(decode_text(&format!(" {}\n", encode_text(&bytes))).unwrap(), bytes);
+        assert!(decode_text("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_tags() {
+        // RFC 8949 appendix A: 1(1363896240), an epoch time
+        let epoch = [0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0];
+        assert_eq!(to_value(&epoch, Tags::Unwrap).unwrap(), json!(1_363_896_240));
+        let kept = to_value(&epoch, Tags::Object).unwrap();
+        assert_eq!(kept, json!({"tag": 1, "value": 1_363_896_240}));
+        assert!(to_value(&epoch, Tags::Reject).unwrap_err().contains("byte 0: tag 1 is not accepted"));
+
+        // Kept tags are written back as tags, and only then
+        let options = CborOptions { tags: Tags::Object };
+        assert_eq!(json_to_cbor_with(&kept.to_string(), &options).unwrap(), epoch);
+        assert_ne!(json_to_cbor(&kept.to_string()).unwrap(), epoch);
+
+        // A bignum that fits is an integer, and one that does not is refused
+        assert_eq!(to_value(&[0xc2, 0x41, 0x2a], Tags::Reject).unwrap(), json!(42));
+        let huge = encode(&Cbor::Tag(2, Box::new(Cbor::Bytes(vec![1; 17]))));
+        assert!(to_value(&huge, Tags::Unwrap).unwrap_err().contains("integer beyond 64 bits"));
+    }
+
+    #[test]
+    fn test_values_without_json_form() {
+        // Byte strings are base64, scalar keys their JSON text, and undefined null
+        let map = Cbor::Map(vec![(Cbor::from(1), Cbor::Bytes(vec![0, 1, 2])), (Cbor::Bool(true), Cbor::Null)]);
+        assert_eq!(to_value(&encode(&map), Tags::Unwrap).unwrap(), json!({"1": "AAEC", "true": null}));
+        assert_eq!(to_value(&[0xf7], Tags::Unwrap).unwrap(), Value::Null);
+
+        let problem = |value: Cbor| diagnostics(&encode(&value), &CborOptions::default()).remove(0).message;
+        assert_eq!(problem(Cbor::Float(f64::INFINITY)), "inf has no JSON form");
+        assert_eq!(problem(Cbor::Integer(Integer::try_from(-(1i128 << 64)).unwrap())), "integer -18446744073709551616 beyond 64 bits");
+        assert_eq!(
+            problem(Cbor::Map(vec![(Cbor::Array(vec![]), Cbor::Null)])),
+            "a map key that is an array, map or tag has no JSON form"
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        // An array of two items holding one
+        assert_eq!(validate_cbor(&[0x82, 0x01]).unwrap(), ["Invalid CBOR: byte 2: unexpected end of data"]);
+        assert_eq!(validate_cbor(&[0x01, 0x02, 0x03]).unwrap(), ["Invalid CBOR: byte 1: 2 bytes after the data item"]);
+        // Additional information 28 is reserved
+        assert!(validate_cbor(&[0x1c]).unwrap()[0].starts_with("Invalid CBOR: byte 0"));
+        assert_eq!(validate_cbor(&[0x80]).unwrap(), Vec::<String>::new());
+        assert!(cbor_to_json(&[]).unwrap_err().to_string().starts_with("Invalid CBOR: byte 0"));
+    }
+}
@@ -0,0 +1,555 @@
+//! CSV and TSV format support for document conversion
+//!
+//! A table becomes a JSON array with an object per row, keyed by the
+//! header, or by `column1`, `column2`, ... when the first row is data.
+//! Whether it is a header is inferred unless [`CsvOptions`] say, as is the
+//! delimiter of CSV; TSV is always tab-separated. A name the header repeats
+//! is numbered, as `name_2`, so each field keeps a key of its own. A column whose fields all
+//! read as numbers, or all as booleans, is typed, and otherwise every field
+//! of it is a string; empty fields are null. Each object keeps the order of
+//! the columns.
+//!
+//! JSON is written from an array of objects, or a single object, with a
+//! column for every key any of them has, in the order they first appear.
+//! Nested objects and arrays are flattened into dotted columns such as
+//! `owner.name` and `tags.0`; reading the table back keeps those columns as
+//! written.
+
+use anyhow::{anyhow, Result};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Number, Value};
+use std::fmt;
+
+use super::model::{DocumentValue, Node};
+
+/// Delimiters tried, in order of preference, when CSV does not give one
+const DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+/// Lines looked at to detect the delimiter
+const SAMPLE_LINES: usize = 10;
+
+/// How a table maps onto JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvOptions {
+    /// Field delimiter, an ASCII character; detected from the first lines when unset
+    pub delimiter: Option<char>,
+    /// Whether the first row names the columns; inferred when unset
+    pub header: Option<bool>,
+    /// Read columns of fields such as `42` and `-1.5` as numbers
+    pub numbers: bool,
+    /// Read columns of `true` and `false` as booleans
+    pub booleans: bool,
+    /// Read empty fields as null, and write null as an empty field either way
+    pub nulls: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self { delimiter: None, header: None, numbers: true, booleans: true, nulls: true }
+    }
+}
+
+impl CsvOptions {
+    /// Options of TSV, otherwise the defaults
+    #[must_use]
+    pub fn tsv() -> Self {
+        Self { delimiter: Some('\t'), ..Self::default() }
+    }
+
+    fn delimiter(&self) -> Result<Option<u8>> {
+        match self.delimiter {
+            Some(delimiter) if !delimiter.is_ascii() || matches!(delimiter, '"' | '\n' | '\r') => {
+                Err(anyhow!("The delimiter {delimiter:?} cannot separate fields; use an ASCII character other than a quote"))
+            }
+            delimiter => Ok(delimiter.map(|delimiter| delimiter as u8)),
+        }
+    }
+}
+
+/// Convert CSV to JSON, detecting its delimiter
+///
+/// # Errors
+///
+/// As [`csv_to_json_with`] does.
+pub fn csv_to_json(csv: &str) -> Result<String> {
+    csv_to_json_with(csv, &CsvOptions::default())
+}
+
+/// Convert TSV to JSON
+///
+/// # Errors
+///
+/// As [`csv_to_json_with`] does.
+pub fn tsv_to_json(tsv: &str) -> Result<String> {
+    csv_to_json_with(tsv, &CsvOptions::tsv())
+}
+
+/// Convert a table to JSON as `options` choose
+///
+/// # Errors
+///
+/// Fails where a row cannot be read, a row is ragged, or the delimiter
+/// `options` give cannot separate fields.
+pub fn csv_to_json_with(csv: &str, options: &CsvOptions) -> Result<String> {
+    let table = read(csv, options)?;
+    if let Some(diagnostic) = ragged(&table).into_iter().next() {
+        return Err(anyhow!("Invalid CSV: {diagnostic}"));
+    }
+    let types: Vec<Type> = (0..table.width).map(|i| column_type(table.rows.iter().map(|row| row.fields[i].as_str()), options)).collect();
+    let records: Vec<Record> = table
+        .rows
+        .iter()
+        .map(|row| Record {
+            columns: &table.columns,
+            values: row.fields.iter().zip(&types).map(|(field, kind)| kind.read(field, options)).collect(),
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&records)?)
+}
+
+/// A row as a JSON object, its fields in the order of its columns
+struct Record<'a> {
+    columns: &'a [String],
+    values: Vec<Value>,
+}
+
+impl Serialize for Record<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.values.len()))?;
+        for (column, value) in self.columns.iter().zip(&self.values) {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Convert JSON to CSV
+///
+/// # Errors
+///
+/// As [`json_to_csv_with`] does.
+pub fn json_to_csv(json: &str) -> Result<String> {
+    json_to_csv_with(json, &CsvOptions::default())
+}
+
+/// Convert JSON to TSV
+///
+/// # Errors
+///
+/// As [`json_to_csv_with`] does.
+pub fn json_to_tsv(json: &str) -> Result<String> {
+    json_to_csv_with(json, &CsvOptions::tsv())
+}
+
+/// Convert JSON to a table as `options` choose, comma-separated unless they give a delimiter
+///
+/// The header is written unless `options.header` is `Some(false)`.
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or the table cannot be written as
+/// [`write`] says.
+pub fn json_to_csv_with(json: &str, options: &CsvOptions) -> Result<String> {
+    write(&serde_json::from_str(json)?, options)
+}
+
+/// Write `value` as a table as `options` choose, keeping the order of its keys
+///
+/// # Errors
+///
+/// Fails where `value` is not an object or an array of objects, or the
+/// delimiter `options` give cannot separate fields.
+pub fn write(value: &DocumentValue, options: &CsvOptions) -> Result<String> {
+    let delimiter = options.delimiter()?.unwrap_or(b',');
+    let rows = match &value.node {
+        Node::Array(items) => items.iter().collect(),
+        Node::Map(_) => vec![value],
+        other => return Err(anyhow!("A table is written from a JSON array of objects, not {}", kind(other))),
+    };
+
+    let mut columns: Vec<String> = Vec::new();
+    let mut flat = Vec::with_capacity(rows.len());
+    for (i, row) in rows.into_iter().enumerate() {
+        if !matches!(row.node, Node::Map(_)) {
+            return Err(anyhow!("Row {} is {}; each row of a table is a JSON object", i, kind(&row.node)));
+        }
+        let mut fields = Vec::new();
+        flatten(row, "", &mut fields);
+        for (key, _) in &fields {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+        flat.push(fields);
+    }
+
+    let mut writer = ::csv::WriterBuilder::new().delimiter(delimiter).from_writer(Vec::new());
+    // An empty table has no columns, and a header of none would read back as one empty field
+    if options.header != Some(false) && !columns.is_empty() {
+        writer.write_record(&columns)?;
+    }
+    for fields in &flat {
+        let field = |column: &String| fields.iter().find(|(key, _)| key == column).map(|(_, value)| field(value));
+        writer.write_record(columns.iter().map(|column| field(column).unwrap_or_default()))?;
+    }
+    Ok(String::from_utf8(writer.into_inner().map_err(|e| anyhow!("{}", e.error()))?)?)
+}
+
+/// Validate CSV, detecting its delimiter
+///
+/// # Errors
+///
+/// Fails where the delimiter cannot separate fields, or a row cannot be
+/// read at all.
+pub fn validate_csv(csv: &str) -> Result<Vec<String>> {
+    validate_with(csv, &CsvOptions::default())
+}
+
+/// Validate TSV
+///
+/// # Errors
+///
+/// Fails where a row cannot be read at all.
+pub fn validate_tsv(tsv: &str) -> Result<Vec<String>> {
+    validate_with(tsv, &CsvOptions::tsv())
+}
+
+/// Ragged rows are left to the `csv/consistent-columns` lint rule
+fn validate_with(text: &str, options: &CsvOptions) -> Result<Vec<String>> {
+    read(text, options)?;
+    Ok(Vec::new())
+}
+
+/// A row whose number of fields differs from the header's, or the first row's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvDiagnostic {
+    /// Line the row starts on, from 1
+    pub line: usize,
+    /// Fields in the row
+    pub fields: usize,
+    /// Fields in the header, or the first row
+    pub expected: usize,
+}
+
+impl fmt::Display for CsvDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {} fields, where the first row has {}", self.line, self.fields, self.expected)
+    }
+}
+
+/// Every ragged row of `text`, empty when all have the same number of fields
+///
+/// # Errors
+///
+/// Fails where the delimiter cannot separate fields, or a row cannot be
+/// read at all.
+pub fn diagnostics(text: &str, options: &CsvOptions) -> Result<Vec<CsvDiagnostic>> {
+    Ok(ragged(&read(text, options)?))
+}
+
+/// The delimiter `text` most likely uses: the one found the same number of
+/// times, and most often, on each of its first lines; a comma when none is
+#[must_use]
+pub fn detect_delimiter(text: &str) -> char {
+    let lines: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).take(SAMPLE_LINES).collect();
+    let counts = |delimiter: u8| lines.iter().map(|line| unquoted(line, delimiter)).collect::<Vec<_>>();
+    let consistent = DELIMITERS
+        .into_iter()
+        .filter_map(|delimiter| {
+            let counts = counts(delimiter);
+            let first = *counts.first()?;
+            (first > 0 && counts.iter().all(|count| *count == first)).then_some((delimiter, first))
+        })
+        // The first of those found most often, as `max_by_key` keeps the last
+        .rev()
+        .max_by_key(|(_, count)| *count);
+    consistent.map_or(',', |(delimiter, _)| delimiter as char)
+}
+
+/// Occurrences of `delimiter` in `line` outside quotes
+fn unquoted(line: &str, delimiter: u8) -> usize {
+    let mut quoted = false;
+    line.bytes()
+        .filter(|byte| {
+            if *byte == b'"' {
+                quoted = !quoted;
+            }
+            !quoted && *byte == delimiter
+        })
+        .count()
+}
+
+/// A table as read, before its fields are typed
+struct Table {
+    columns: Vec<String>,
+    /// Fields in the header, or the first row without one
+    width: usize,
+    rows: Vec<Row>,
+}
+
+struct Row {
+    line: usize,
+    fields: Vec<String>,
+}
+
+fn read(text: &str, options: &CsvOptions) -> Result<Table> {
+    let delimiter = options.delimiter()?.unwrap_or_else(|| detect_delimiter(text) as u8);
+    let mut reader = ::csv::ReaderBuilder::new().delimiter(delimiter).has_headers(false).flexible(true).from_reader(text.as_bytes());
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| anyhow!("Invalid CSV: {e}"))?;
+        let line = record.position().map_or(0, |position| usize::try_from(position.line()).unwrap_or(usize::MAX));
+        rows.push(Row { line, fields: record.iter().map(str::to_string).collect() });
+    }
+
+    let header = options.header.unwrap_or_else(|| has_header(&rows, options));
+    let width = rows.first().map_or(0, |row| row.fields.len());
+    let columns = if header && !rows.is_empty() {
+        distinct(rows.remove(0).fields)
+    } else {
+        (1..=width).map(|i| format!("column{i}")).collect()
+    };
+    Ok(Table { columns, width, rows })
+}
+
+/// Whether the first of `rows` looks like a header: names, none empty or typed
+fn has_header(rows: &[Row], options: &CsvOptions) -> bool {
+    rows.first().is_some_and(|first| {
+        first.fields.iter().all(|name| matches!(typed(name, options), Value::String(ref text) if !text.is_empty()))
+    })
+}
+
+/// `names` with each repeat numbered, as `name_2`, past the names before it
+fn distinct(names: Vec<String>) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::with_capacity(names.len());
+    for name in names {
+        let mut column = name.clone();
+        let mut n = 1;
+        while columns.contains(&column) {
+            n += 1;
+            column = format!("{name}_{n}");
+        }
+        columns.push(column);
+    }
+    columns
+}
+
+fn ragged(table: &Table) -> Vec<CsvDiagnostic> {
+    table
+        .rows
+        .iter()
+        .filter(|row| row.fields.len() != table.width)
+        .map(|row| CsvDiagnostic { line: row.line, fields: row.fields.len(), expected: table.width })
+        .collect()
+}
+
+/// What the fields of a column are read as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Number,
+    Bool,
+    String,
+}
+
+impl Type {
+    /// The JSON value of `field`, a field of a column of this type
+    fn read(self, field: &str, options: &CsvOptions) -> Value {
+        match typed(field, options) {
+            Value::Null => Value::Null,
+            Value::Number(number) if self == Type::Number => Value::Number(number),
+            Value::Bool(b) if self == Type::Bool => Value::Bool(b),
+            _ => Value::String(field.to_string()),
+        }
+    }
+}
+
+/// The one type all of a column's non-null `fields` read as, a string if they differ
+fn column_type<'a>(fields: impl Iterator<Item = &'a str>, options: &CsvOptions) -> Type {
+    let mut found = None;
+    for field in fields {
+        let kind = match typed(field, options) {
+            Value::Null => continue,
+            Value::Number(_) => Type::Number,
+            Value::Bool(_) => Type::Bool,
+            _ => return Type::String,
+        };
+        if found.is_some_and(|found| found != kind) {
+            return Type::String;
+        }
+        found = Some(kind);
+    }
+    found.unwrap_or(Type::String)
+}
+
+/// The JSON value of `field` alone, typed as far as `options` allow
+fn typed(field: &str, options: &CsvOptions) -> Value {
+    if field.is_empty() && options.nulls {
+        return Value::Null;
+    }
+    if options.booleans {
+        match field {
+            "true" | "TRUE" | "True" => return Value::Bool(true),
+            "false" | "FALSE" | "False" => return Value::Bool(false),
+            _ => {}
+        }
+    }
+    if options.numbers {
+        // JSON's grammar keeps codes such as 007 and +1 as text
+        if let Ok(number) = serde_json::from_str::<Number>(field) {
+            let integral = !field.contains(['.', 'e', 'E']);
+            // Integers too long for 64 bits would lose digits as floats
+            if !integral || number.is_i64() || number.is_u64() {
+                return Value::Number(number);
+            }
+        }
+    }
+    Value::String(field.to_string())
+}
+
+/// Add the leaves of `value` to `fields` in order, under dotted keys starting with `prefix`
+fn flatten<'a>(value: &'a DocumentValue, prefix: &str, fields: &mut Vec<(String, &'a DocumentValue)>) {
+    let key = |name: &str| if prefix.is_empty() { name.to_string() } else { format!("{prefix}.{name}") };
+    match &value.node {
+        Node::Map(entries) if !entries.is_empty() => {
+            for (name, value) in entries {
+                flatten(value, &key(name), fields);
+            }
+        }
+        Node::Array(items) if !items.is_empty() => {
+            for (i, value) in items.iter().enumerate() {
+                flatten(value, &key(&i.to_string()), fields);
+            }
+        }
+        _ => fields.push((prefix.to_string(), value)),
+    }
+}
+
+/// The text of a flattened leaf
+fn field(value: &DocumentValue) -> String {
+    match &value.node {
+        Node::Null => String::new(),
+        Node::String(text) => text.clone(),
+        Node::DateTime(datetime) => datetime.text.clone(),
+        // Empty objects and arrays have no leaves to spread over columns
+        _ => value.to_json().to_string(),
+    }
+}
+
+fn kind(node: &Node) -> &'static str {
+    match node {
+        Node::Null => "null",
+        Node::Bool(_) => "a boolean",
+        Node::Integer(_) | Node::Unsigned(_) | Node::Float(_) => "a number",
+        Node::String(_) | Node::DateTime(_) => "a string",
+        Node::Array(_) => "an array",
+        Node::Map(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(csv: &str, options: &CsvOptions) -> Value {
+        serde_json::from_str(&csv_to_json_with(csv, options).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_csv_to_json_types_fields() {
+        let csv = "name,port,tls,zip,weight,owner\nweb,80,false,007,0.5,\napi,443,true,10001,1e3,ops\n";
+        assert_eq!(
+            to_value(csv, &CsvOptions::default()),
+            json!([
+                {"name": "web", "port": 80, "tls": false, "zip": "007", "weight": 0.5, "owner": null},
+                {"name": "api", "port": 443, "tls": true, "zip": "10001", "weight": 1000.0, "owner": "ops"},
+            ])
+        );
+
+        let strings = CsvOptions { numbers: false, booleans: false, nulls: false, header: Some(true), ..CsvOptions::default() };
+        assert_eq!(
+            to_value("a,b,c\n1,true,\n", &strings),
+            json!([{"a": "1", "b": "true", "c": ""}])
+        );
+        assert_eq!(to_value("id\n123456789012345678901234\n", &CsvOptions::default()), json!([{"id": "123456789012345678901234"}]));
+
+        // A column is typed only when all its fields read as the same type
+        assert_eq!(
+            to_value("flag,n\ntrue,1\nno,2.5\n,x\n", &CsvOptions::default()),
+            json!([{"flag": "true", "n": "1"}, {"flag": "no", "n": "2.5"}, {"flag": null, "n": "x"}])
+        );
+    }
+
+    #[test]
+    fn test_keeps_column_order() {
+        let json = csv_to_json("zone,app,count\neu,web,2\n").unwrap();
+        let keys: Vec<usize> = ["zone", "app", "count"].iter().map(|key| json.find(&format!("\"{key}\"")).unwrap()).collect();
+        assert!(keys.is_sorted(), "{json}");
+        assert_eq!(json_to_csv(&json).unwrap(), "zone,app,count\neu,web,2\n");
+    }
+
+    #[test]
+    fn test_headers_and_delimiters() {
+        // A first row of numbers is data
+        assert_eq!(to_value("1;2\n3;4\n", &CsvOptions::default()), json!([{"column1": 1, "column2": 2}, {"column1": 3, "column2": 4}]));
+        assert_eq!(to_value("name|city\nada|london\n", &CsvOptions::default()), json!([{"name": "ada", "city": "london"}]));
+        assert_eq!(to_value("a\tb\n1\t2\n", &CsvOptions::tsv()), json!([{"a": 1, "b": 2}]));
+        let no_header = CsvOptions { header: Some(false), ..CsvOptions::default() };
+        assert_eq!(to_value("a,b\n", &no_header), json!([{"column1": "a", "column2": "b"}]));
+
+        // Repeated names are numbered rather than taking the row for data
+        assert_eq!(
+            to_value("a,b,a,a_2\n1,2,3,4\n", &CsvOptions::default()),
+            json!([{"a": 1, "b": 2, "a_2": 3, "a_2_2": 4}])
+        );
+
+        assert_eq!(detect_delimiter("a,b;c\n1,2;3\n"), ',');
+        assert_eq!(detect_delimiter("a;b;c\n1;\"2;5\";3\n"), ';');
+        assert_eq!(detect_delimiter("one column\n"), ',');
+        let quote = CsvOptions { delimiter: Some('"'), ..CsvOptions::default() };
+        assert!(csv_to_json_with("a", &quote).is_err());
+    }
+
+    #[test]
+    fn test_json_to_csv_flattens() {
+        let json = r#"[
+            {"name": "web", "owner": {"team": "ops"}, "tags": ["a", "b"], "note": "x, \"y\""},
+            {"name": "api", "port": 443, "owner": null, "empty": []}
+        ]"#;
+        assert_eq!(
+            json_to_csv(json).unwrap(),
+            "name,owner.team,tags.0,tags.1,note,port,owner,empty\n\
+             web,ops,a,b,\"x, \"\"y\"\"\",,,\n\
+             api,,,,,443,,[]\n"
+        );
+        assert_eq!(json_to_tsv(r#"{"a": 1, "b": true}"#).unwrap(), "a\tb\n1\ttrue\n");
+        assert!(json_to_csv("[1]").unwrap_err().to_string().contains("Row 0 is a number"));
+        assert!(json_to_csv("\"text\"").is_err());
+
+        for rows in [r#"[{"name": "web", "port": 80}, {"name": "api", "port": null}]"#, "[]"] {
+            let back = to_value(&json_to_csv(rows).unwrap(), &CsvOptions::default());
+            assert_eq!(back, serde_json::from_str::<Value>(rows).unwrap());
+        }
+        assert_eq!(json_to_csv("[]").unwrap(), "");
+    }
+
+    #[test]
+    fn test_validate_ragged_rows() {
+        let ragged = "a,b\n1,2,3\n4\n\"multi\nline\",5\n6,7,8\n";
+        let found = diagnostics(ragged, &CsvOptions::default()).unwrap();
+        assert_eq!(
+            found.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "line 2: 3 fields, where the first row has 2",
+                "line 3: 1 fields, where the first row has 2",
+                "line 6: 3 fields, where the first row has 2",
+            ]
+        );
+        assert!(csv_to_json("a,b\n1\n").unwrap_err().to_string().contains("line 2"));
+        // Ragged rows and empty documents are the lint rules'
+        assert!(validate_csv(ragged).unwrap().is_empty());
+        assert!(validate_tsv("  ").unwrap().is_empty());
+    }
+}
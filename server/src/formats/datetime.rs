@@ -0,0 +1,298 @@
+//! Dates and times across formats
+//!
+//! TOML date-times, YAML timestamps and XML values typed `xs:dateTime`,
+//! `xs:date` or `xs:time` are read into the document model as [`DateTime`]s
+//! rather than strings, each keeping the text it was written as. Written to
+//! another format, [`DateTimeStyle`] chooses their form: the text as it was
+//! read, by default; RFC 3339, which lays out the date, time and offset one
+//! way whatever format they came from; or seconds since the Unix epoch.
+//!
+//! A date-time names an instant only with a date, a time and an offset.
+//! Written as epoch seconds, local ones, without an offset, and dates or
+//! times alone stay RFC 3339 text rather than be put in a zone they do not
+//! give.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Number;
+use std::fmt;
+
+/// How date-times are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DateTimeStyle {
+    /// The text the date-time was read from
+    #[default]
+    Preserve,
+    /// RFC 3339 text, such as `2024-01-31T12:00:00+01:00`
+    Rfc3339,
+    /// Seconds since 1970-01-01T00:00:00Z, for date-times that name an instant
+    Epoch,
+}
+
+/// A date and time, either of which may be missing, at an offset from UTC or local
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub date: Option<Date>,
+    pub time: Option<Time>,
+    /// Minutes east of UTC, `None` where local
+    pub offset: Option<i32>,
+    /// The text it is written as, first that it was read from
+    pub text: String,
+}
+
+/// A day of the proleptic Gregorian calendar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+/// A time of day
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Time {
+    pub hour: u32,
+    pub minute: u32,
+    /// Up to 60, for a leap second
+    pub second: u32,
+    /// Digits of the fraction of a second, as written
+    pub fraction: String,
+}
+
+impl DateTime {
+    /// The date-time `text` writes, as RFC 3339, TOML, YAML timestamps and XML Schema do, if any
+    ///
+    /// As YAML allows, the date and time may be separated by spaces, months,
+    /// days and hours before a time may have one digit, and the offset may
+    /// be an hour alone, after spaces. A date alone is written in full,
+    /// `YYYY-MM-DD`, and may be followed by an offset, as in XML Schema.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut cursor = Cursor { bytes: text.as_bytes(), at: 0 };
+        let date = cursor.date();
+        let time = match date {
+            None => Some(cursor.time()?),
+            Some(_) if cursor.separator() => Some(cursor.time()?),
+            Some(_) if cursor.at == "YYYY-MM-DD".len() => None,
+            Some(_) => return None,
+        };
+        let offset = cursor.offset(time.is_some()).ok()?;
+        (cursor.at == text.len()).then(|| Self { date, time, offset, text: text.to_string() })
+    }
+
+    /// Whether this names an instant, with a date, a time and an offset
+    #[must_use]
+    pub fn is_instant(&self) -> bool {
+        self.date.is_some() && self.time.is_some() && self.offset.is_some()
+    }
+
+    /// Seconds since the Unix epoch, whole where the fraction is zero, for an instant
+    #[must_use]
+    pub fn epoch(&self) -> Option<Number> {
+        let (Some(date), Some(time), Some(offset)) = (self.date, &self.time, self.offset) else {
+            return None;
+        };
+        let days = days_from_civil(i64::from(date.year), date.month, date.day);
+        let seconds = days * 86_400 + i64::from(time.hour * 3600 + time.minute * 60 + time.second) - i64::from(offset) * 60;
+        if time.fraction.bytes().all(|digit| digit == b'0') {
+            return Some(Number::from(seconds));
+        }
+        let fraction: f64 = format!("0.{}", time.fraction).parse().ok()?;
+        #[allow(clippy::cast_precision_loss)] // Within 2^53 seconds of 1970, as any year of four digits is
+        Number::from_f64(seconds as f64 + fraction)
+    }
+
+    /// As RFC 3339 lays it out: `T` between date and time, `Z` for UTC, and
+    /// the offset as hours and minutes
+    #[must_use]
+    pub fn rfc3339(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(date) = self.date {
+            write!(f, "{:04}-{:02}-{:02}", date.year, date.month, date.day)?;
+            if self.time.is_some() {
+                f.write_str("T")?;
+            }
+        }
+        if let Some(time) = &self.time {
+            write!(f, "{:02}:{:02}:{:02}", time.hour, time.minute, time.second)?;
+            if !time.fraction.is_empty() {
+                write!(f, ".{}", time.fraction)?;
+            }
+        }
+        match self.offset {
+            None => Ok(()),
+            Some(0) => f.write_str("Z"),
+            Some(offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                write!(f, "{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60)
+            }
+        }
+    }
+}
+
+/// Days from 1970-01-01 to a day of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // Years counted from March, so the leap day ends them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = i64::from((153 * ((month + 9) % 12) + 2) / 5 + day - 1);
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Reads the parts of a date-time in turn
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Cursor<'_> {
+    /// A number of `min` to `max` digits
+    fn number(&mut self, min: usize, max: usize) -> Option<u32> {
+        let digits = self.bytes[self.at..].iter().take(max).take_while(|b| b.is_ascii_digit()).count();
+        if digits < min {
+            return None;
+        }
+        let text = std::str::from_utf8(&self.bytes[self.at..self.at + digits]).ok()?;
+        self.at += digits;
+        text.parse().ok()
+    }
+
+    fn byte(&mut self, expected: u8) -> Option<()> {
+        (self.bytes.get(self.at) == Some(&expected)).then(|| self.at += 1)
+    }
+
+    /// A date, leaving the cursor where it was if there is none
+    fn date(&mut self) -> Option<Date> {
+        let start = self.at;
+        let date = (|| {
+            let year = i32::try_from(self.number(4, 4)?).ok()?;
+            self.byte(b'-')?;
+            let month = self.number(1, 2)?;
+            self.byte(b'-')?;
+            let day = self.number(1, 2)?;
+            ((1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)).then_some(Date { year, month, day })
+        })();
+        if date.is_none() {
+            self.at = start;
+        }
+        date
+    }
+
+    /// What separates a date from its time: `T`, or spaces before a digit
+    fn separator(&mut self) -> bool {
+        match self.bytes.get(self.at) {
+            Some(b'T' | b't') => {
+                self.at += 1;
+                true
+            }
+            Some(b' ' | b'\t') => {
+                let spaces = self.bytes[self.at..].iter().take_while(|b| matches!(b, b' ' | b'\t')).count();
+                let digit = self.bytes.get(self.at + spaces).is_some_and(u8::is_ascii_digit);
+                if digit {
+                    self.at += spaces;
+                }
+                digit
+            }
+            _ => false,
+        }
+    }
+
+    fn time(&mut self) -> Option<Time> {
+        let hour = self.number(1, 2)?;
+        self.byte(b':')?;
+        let minute = self.number(2, 2)?;
+        self.byte(b':')?;
+        let second = self.number(2, 2)?;
+        let mut fraction = String::new();
+        if self.byte(b'.').is_some() {
+            let digits = self.bytes[self.at..].iter().take_while(|b| b.is_ascii_digit()).count();
+            fraction = String::from_utf8_lossy(&self.bytes[self.at..self.at + digits]).into_owned();
+            self.at += digits;
+        }
+        (hour < 24 && minute < 60 && second <= 60).then_some(Time { hour, minute, second, fraction })
+    }
+
+    /// An offset in minutes, if any; spaces may precede one after a time
+    fn offset(&mut self, after_time: bool) -> Result<Option<i32>, Malformed> {
+        let spaces = if after_time { self.bytes[self.at..].iter().take_while(|b| matches!(b, b' ' | b'\t')).count() } else { 0 };
+        let sign = match self.bytes.get(self.at + spaces) {
+            Some(b'Z' | b'z') => {
+                self.at += spaces + 1;
+                return Ok(Some(0));
+            }
+            Some(b'+') => 1,
+            Some(b'-') => -1,
+            _ => return Ok(None),
+        };
+        self.at += spaces + 1;
+        let hours = self.number(1, 2).ok_or(Malformed)?;
+        let colon = self.byte(b':').is_some();
+        let minutes = match self.number(2, 2) {
+            Some(minutes) => minutes,
+            None if colon => return Err(Malformed),
+            None => 0,
+        };
+        if hours >= 24 || minutes >= 60 {
+            return Err(Malformed);
+        }
+        Ok(Some(sign * i32::try_from(hours * 60 + minutes).unwrap_or_default()))
+    }
+}
+
+/// A part of a date-time begun but not written as it must be
+struct Malformed;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parsed = DateTime::parse("1979-05-27T07:32:00.999-07:00").unwrap();
+        assert_eq!(parsed.date, Some(Date { year: 1979, month: 5, day: 27 }));
+        assert_eq!(parsed.time, Some(Time { hour: 7, minute: 32, second: 0, fraction: "999".to_string() }));
+        assert_eq!(parsed.offset, Some(-420));
+
+        // TOML, YAML and XML Schema layouts, in RFC 3339's
+        let rfc3339 = |text: &str| DateTime::parse(text).map(|parsed| parsed.rfc3339());
+        assert_eq!(rfc3339("1979-05-27 07:32:00z").unwrap(), "1979-05-27T07:32:00Z");
+        assert_eq!(rfc3339("2001-12-14 21:59:43.10 -5").unwrap(), "2001-12-14T21:59:43.10-05:00");
+        assert_eq!(rfc3339("2001-1-2 3:04:05+0530").unwrap(), "2001-01-02T03:04:05+05:30");
+        assert_eq!(rfc3339("2002-09-24-06:00").unwrap(), "2002-09-24-06:00");
+        assert_eq!(rfc3339("13:20:00Z").unwrap(), "13:20:00Z");
+        assert_eq!(rfc3339("1979-05-27").unwrap(), "1979-05-27");
+
+        for text in ["2024-02-30", "2023-13-01", "24:00:00", "2024-01-01 noon", "2024-01-01T10:00", "1.5", "2024", "2024-01-01 +01:", "2024-1-1", "2024-01-1Z"] {
+            assert_eq!(DateTime::parse(text), None, "{text}");
+        }
+        assert!(DateTime::parse("2024-02-29").is_some());
+    }
+
+    #[test]
+    fn test_epoch() {
+        let epoch = |text: &str| DateTime::parse(text).unwrap().epoch();
+        assert_eq!(epoch("1970-01-01T00:00:00Z"), Some(Number::from(0)));
+        assert_eq!(epoch("2024-01-31T13:00:00+01:00"), Some(Number::from(1_706_702_400)));
+        assert_eq!(epoch("1969-12-31T23:59:59.5Z"), Number::from_f64(-0.5));
+        assert_eq!(epoch("2000-03-01T00:00:00.000Z"), Some(Number::from(951_868_800)));
+        // Local date-times and dates alone name no instant
+        assert_eq!(epoch("2024-01-31T12:00:00"), None);
+        assert_eq!(epoch("2024-01-31"), None);
+    }
+}
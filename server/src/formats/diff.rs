@@ -0,0 +1,268 @@
+//! Structural diff of documents
+//!
+//! Two documents, of the same format or not, are compared as their
+//! [`model`] values, so a YAML file and the JSON it was converted to have no
+//! differences. Each [`Change`] names a value by its JSON pointer:
+//! a key or item only one side has is added or removed, and a value both
+//! have that differs is changed, unless both are maps or arrays, whose
+//! contents are compared instead. Numbers compare by value, so `1` and
+//! `1.0` are the same, and the order of map keys is not a difference.
+//!
+//! Arrays are matched on their longest common run of equal items, so an
+//! item inserted at the front is one addition rather than a change to every
+//! item after it. Between matched items, removed and added items pair up
+//! as changes when both are maps, both arrays or both scalars. Removed
+//! values are named by their index in the document before, every other
+//! value by its index in the document after. Arrays too long to match are
+//! compared item by item.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::model::{self, DocumentValue, Node};
+use super::schema::escape;
+use crate::core::Format;
+
+/// Arrays whose lengths multiply past this are compared item by item
+const MAX_MATCHED: usize = 1 << 20;
+
+/// What happened to a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A difference between two documents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// JSON pointer to the value, empty for the whole document
+    pub path: String,
+    pub kind: ChangeKind,
+    /// The value before, unless added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<Value>,
+    /// The value after, unless removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Value>,
+}
+
+impl Change {
+    fn added(path: String, after: &DocumentValue) -> Self {
+        Self { path, kind: ChangeKind::Added, before: None, after: Some(after.to_json()) }
+    }
+
+    fn removed(path: String, before: &DocumentValue) -> Self {
+        Self { path, kind: ChangeKind::Removed, before: Some(before.to_json()), after: None }
+    }
+
+    fn changed(path: String, before: &DocumentValue, after: &DocumentValue) -> Self {
+        Self { path, kind: ChangeKind::Changed, before: Some(before.to_json()), after: Some(after.to_json()) }
+    }
+}
+
+/// The changes from `before` to `after`, documents of the formats given
+///
+/// # Errors
+///
+/// Fails where either document does not parse as its format.
+pub fn diff_documents(before: &str, before_format: Format, after: &str, after_format: Format) -> Result<Vec<Change>> {
+    let before = model::read(before, before_format, &mut Vec::new())?;
+    let after = model::read(after, after_format, &mut Vec::new())?;
+    Ok(diff(&before, &after))
+}
+
+/// The changes from `before` to `after`: those within a map in the order of its keys before, then added keys in their order after
+#[must_use]
+pub fn diff(before: &DocumentValue, after: &DocumentValue) -> Vec<Change> {
+    let mut changes = Vec::new();
+    compare(String::new(), before, after, &mut changes);
+    changes
+}
+
+fn compare(path: String, before: &DocumentValue, after: &DocumentValue, changes: &mut Vec<Change>) {
+    match (&before.node, &after.node) {
+        (Node::Map(old), Node::Map(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, escape(key));
+                match after.get(key) {
+                    Some(new_value) => compare(path, old_value, new_value, changes),
+                    None => changes.push(Change::removed(path, old_value)),
+                }
+            }
+            for (key, new_value) in new.iter().filter(|(key, _)| before.get(key).is_none()) {
+                changes.push(Change::added(format!("{}/{}", path, escape(key)), new_value));
+            }
+        }
+        (Node::Array(old), Node::Array(new)) => compare_arrays(&path, old, new, changes),
+        _ if same(before, after) => {}
+        _ => changes.push(Change::changed(path, before, after)),
+    }
+}
+
+fn compare_arrays(path: &str, old: &[DocumentValue], new: &[DocumentValue], changes: &mut Vec<Change>) {
+    // Items equal at either end match without the table
+    let prefix = old.iter().zip(new).take_while(|(x, y)| same(x, y)).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(x, y)| same(x, y)).count();
+    let (old_rest, new_rest) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let matched = if old_rest.len().saturating_mul(new_rest.len()) > MAX_MATCHED {
+        Vec::new()
+    } else {
+        common_run(old_rest, new_rest)
+    };
+    // Each matched pair, then the ends of both arrays, close a stretch of unmatched items
+    let (mut i, mut j) = (0, 0);
+    for (end_i, end_j) in matched.into_iter().chain([(old_rest.len(), new_rest.len())]) {
+        while i < end_i || j < end_j {
+            let (removed, added) = (end_i - i, end_j - j);
+            // Items pair up when they are alike, or when both sides have as many left
+            let paired = removed > 0 && added > 0 && (removed == added || alike(&old_rest[i], &new_rest[j]));
+            if paired {
+                compare(format!("{}/{}", path, prefix + j), &old_rest[i], &new_rest[j], changes);
+                (i, j) = (i + 1, j + 1);
+            } else if removed > added {
+                changes.push(Change::removed(format!("{}/{}", path, prefix + i), &old_rest[i]));
+                i += 1;
+            } else {
+                changes.push(Change::added(format!("{}/{}", path, prefix + j), &new_rest[j]));
+                j += 1;
+            }
+        }
+        (i, j) = (end_i + 1, end_j + 1);
+    }
+}
+
+/// Whether `a` and `b` hold the same data, whatever their key order, spans and comments
+#[allow(clippy::float_cmp)] // Numbers written alike are equal, `1` and `1.0` among them
+fn same(a: &DocumentValue, b: &DocumentValue) -> bool {
+    match (&a.node, &b.node) {
+        (Node::Integer(x), Node::Integer(y)) => x == y,
+        (Node::Unsigned(x), Node::Unsigned(y)) => x == y,
+        (Node::Integer(_) | Node::Unsigned(_) | Node::Float(_), Node::Integer(_) | Node::Unsigned(_) | Node::Float(_)) => {
+            number(&a.node) == number(&b.node)
+        }
+        (Node::Array(x), Node::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same(x, y)),
+        (Node::Map(x), Node::Map(y)) => {
+            x.len() == y.len() && x.iter().all(|(key, x)| b.get(key).is_some_and(|y| same(x, y)))
+        }
+        (x, y) => x == y,
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn number(node: &Node) -> f64 {
+    match node {
+        Node::Integer(i) => *i as f64,
+        Node::Unsigned(u) => *u as f64,
+        Node::Float(f) => *f,
+        _ => f64::NAN,
+    }
+}
+
+/// Whether `a` and `b` are both maps, both arrays or both scalars
+fn alike(a: &DocumentValue, b: &DocumentValue) -> bool {
+    match (&a.node, &b.node) {
+        (Node::Map(_), Node::Map(_)) | (Node::Array(_), Node::Array(_)) => true,
+        (Node::Map(_) | Node::Array(_), _) | (_, Node::Map(_) | Node::Array(_)) => false,
+        _ => true,
+    }
+}
+
+/// Indices of the items of the longest common subsequence of `old` and `new`
+fn common_run(old: &[DocumentValue], new: &[DocumentValue]) -> Vec<(usize, usize)> {
+    let width = new.len() + 1;
+    // lengths[i * width + j]: the longest common run of old[i..] and new[j..]
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if same(&old[i], &new[j]) {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut pairs) = (0, 0, Vec::new());
+    while i < old.len() && j < new.len() {
+        if same(&old[i], &new[j]) {
+            pairs.push((i, j));
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn json_diff(before: &str, after: &str) -> Vec<Change> {
+        diff_documents(before, Format::Json, after, Format::Json).unwrap()
+    }
+
+    fn kinds(changes: &[Change]) -> Vec<(&str, ChangeKind)> {
+        changes.iter().map(|change| (change.path.as_str(), change.kind)).collect()
+    }
+
+    #[test]
+    fn test_maps() {
+        let before = r#"{"name": "app", "port": 80, "tls": {"on": false}, "a/b": 1, "ratio": 1}"#;
+        let after = r#"{"port": 8080, "name": "app", "tls": {"on": true, "cert": "x"}, "ratio": 1.0, "debug": true}"#;
+        let changes = json_diff(before, after);
+        assert_eq!(
+            kinds(&changes),
+            [
+                ("/port", ChangeKind::Changed),
+                ("/tls/on", ChangeKind::Changed),
+                ("/tls/cert", ChangeKind::Added),
+                ("/a~1b", ChangeKind::Removed),
+                ("/debug", ChangeKind::Added),
+            ]
+        );
+        assert_eq!((&changes[0].before, &changes[0].after), (&Some(json!(80)), &Some(json!(8080))));
+        assert_eq!((&changes[3].before, &changes[3].after), (&Some(json!(1)), &None));
+        assert!(json_diff(before, before).is_empty());
+        assert_eq!(kinds(&json_diff(r#"{"a": [1]}"#, r#"{"a": {"0": 1}}"#)), [("/a", ChangeKind::Changed)]);
+        assert_eq!(kinds(&json_diff("1", r#""1""#)), [("", ChangeKind::Changed)]);
+    }
+
+    #[test]
+    fn test_arrays() {
+        // One item inserted at the front, one removed and one edited
+        let changes = json_diff(r#"["b", "c", {"d": 1}, "e"]"#, r#"["a", "b", {"d": 2}, "e"]"#);
+        assert_eq!(
+            kinds(&changes),
+            [("/0", ChangeKind::Added), ("/1", ChangeKind::Removed), ("/2/d", ChangeKind::Changed)]
+        );
+        assert_eq!(changes[1].before, Some(json!("c")));
+
+        assert_eq!(kinds(&json_diff("[1, 2, 3]", "[1, 3]")), [("/1", ChangeKind::Removed)]);
+        assert_eq!(kinds(&json_diff("[1, 2]", "[1, 2, 3]")), [("/2", ChangeKind::Added)]);
+        assert_eq!(kinds(&json_diff("[1, 2]", "[3, 4]")), [("/0", ChangeKind::Changed), ("/1", ChangeKind::Changed)]);
+    }
+
+    #[test]
+    fn test_across_formats() {
+        let yaml = "name: app\nports:\n  - 80\n  - 443\n";
+        let toml = "name = \"app\"\nports = [80, 8443]\n";
+        let changes = diff_documents(yaml, Format::Yaml, toml, Format::Toml).unwrap();
+        let expected = Change {
+            path: "/ports/1".to_string(),
+            kind: ChangeKind::Changed,
+            before: Some(json!(443)),
+            after: Some(json!(8443)),
+        };
+        assert_eq!(changes, [expected]);
+        assert!(diff_documents(yaml, Format::Yaml, r#"{"ports": [80, 443], "name": "app"}"#, Format::Json).unwrap().is_empty());
+        assert!(diff_documents("{", Format::Json, toml, Format::Toml).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+//! dotenv (`.env`) format support for document conversion
+//!
+//! A dotenv file sets one variable per `KEY=VALUE` line, optionally
+//! prefixed with `export`, and becomes a flat JSON object of strings. Lines
+//! starting with `#` are comments, as is the rest of an unquoted value
+//! after ` #`. Single-quoted values are taken as written; double-quoted
+//! values take `\n`, `\r`, `\t`, `\"`, `\\` and `\$` escapes. Either kind of
+//! quote may run over several lines.
+//!
+//! References such as `$HOME` or `${HOME:-/root}` in unquoted and
+//! double-quoted values are kept as written, not expanded; [`references`]
+//! lists them. A key set twice takes its last value, and unquoted values
+//! holding whitespace, quotes or shell metacharacters are read as written;
+//! both are reported as warnings, which do not stop a conversion.
+//!
+//! JSON is written from an object whose values are scalars. Values are
+//! quoted when they would otherwise read back differently, in single
+//! quotes unless they hold one or a line break, so that `$` stays literal.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Characters that an unquoted value should not hold
+const SPECIAL: &[char] = &['"', '\'', '`', '\\', ';', '&', '|', '<', '>', '(', ')'];
+
+/// Convert dotenv to JSON
+///
+/// # Errors
+///
+/// Fails on the first line that is not an assignment, or a value whose
+/// quotes are not closed.
+pub fn dotenv_to_json(dotenv: &str) -> Result<String> {
+    let document = read(dotenv);
+    if let Some(diagnostic) = document.problems.into_iter().find(|diagnostic| !diagnostic.warning) {
+        return Err(anyhow!("Invalid dotenv: {diagnostic}"));
+    }
+    Ok(serde_json::to_string_pretty(&Value::Object(document.variables))?)
+}
+
+/// Convert JSON to dotenv
+///
+/// # Errors
+///
+/// Fails where `json` is not an object, or a key is not a variable name.
+pub fn json_to_dotenv(json: &str) -> Result<String> {
+    let Value::Object(variables) = serde_json::from_str(json)? else {
+        return Err(anyhow!("dotenv files are written from a JSON object"));
+    };
+
+    let mut dotenv = String::new();
+    for (key, value) in &variables {
+        if !valid_key(key) {
+            return Err(anyhow!("{key:?} cannot be a dotenv variable name"));
+        }
+        let written = match value {
+            Value::Null => String::new(),
+            Value::Bool(_) | Value::Number(_) => value.to_string(),
+            Value::String(text) => quoted(text),
+            Value::Array(_) | Value::Object(_) => {
+                return Err(anyhow!("dotenv values are scalars; `{}` is {}", key, kind(value)));
+            }
+        };
+        let _ = writeln!(dotenv, "{key}={written}");
+    }
+    Ok(dotenv)
+}
+
+/// Validate dotenv, returning every problem and warning found
+///
+/// # Errors
+///
+/// Never; problems are returned, warnings among them, rather than failing.
+pub fn validate_dotenv(dotenv: &str) -> Result<Vec<String>> {
+    Ok(diagnostics(dotenv)
+        .into_iter()
+        .map(|diagnostic| {
+            if diagnostic.warning {
+                format!("dotenv warning: {diagnostic}")
+            } else {
+                format!("Invalid dotenv: {diagnostic}")
+            }
+        })
+        .collect())
+}
+
+/// A problem on one line of a dotenv file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotenvDiagnostic {
+    /// Line the problem is on, from 1
+    pub line: usize,
+    pub message: String,
+    /// Whether the line is still read, such as a duplicate key
+    pub warning: bool,
+}
+
+impl fmt::Display for DotenvDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Every malformed line, duplicate key and unquoted special character of `dotenv`, in order
+#[must_use]
+pub fn diagnostics(dotenv: &str) -> Vec<DotenvDiagnostic> {
+    read(dotenv).problems
+}
+
+/// Variables each key's value refers to, by key, in the order they appear
+#[must_use]
+pub fn references(dotenv: &str) -> BTreeMap<String, Vec<String>> {
+    read(dotenv).references
+}
+
+/// A file as read, with the problems found along the way
+struct Document {
+    variables: Map<String, Value>,
+    references: BTreeMap<String, Vec<String>>,
+    problems: Vec<DotenvDiagnostic>,
+}
+
+fn read(dotenv: &str) -> Document {
+    let mut variables = Map::new();
+    let mut references = BTreeMap::new();
+    let mut problems = Vec::new();
+    // Lines keys were first set on
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut lines = dotenv.lines().enumerate();
+
+    while let Some((i, raw)) = lines.next() {
+        let line = i + 1;
+        let mut problem = |message: String, warning: bool| problems.push(DotenvDiagnostic { line, message, warning });
+        let text = raw.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let text = text.strip_prefix("export").filter(|rest| rest.starts_with([' ', '\t'])).map_or(text, str::trim_start);
+        let Some((key, value)) = text.split_once('=') else {
+            problem(format!("expected `KEY=VALUE`, found {text:?}"), false);
+            continue;
+        };
+        let key = key.trim();
+        if !valid_key(key) {
+            problem(format!("{key:?} is not a valid variable name"), false);
+            continue;
+        }
+
+        let value = value.trim_start();
+        let read = if let Some(quote @ ('"' | '\'')) = value.chars().next() {
+            // A quoted value runs to its closing quote, over later lines if need be
+            let mut body = value[1..].to_string();
+            loop {
+                if let Some(end) = closing(&body, quote) {
+                    let rest = body[end + 1..].trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        problem(format!("unexpected {rest:?} after the closing quote"), false);
+                        break None;
+                    }
+                    body.truncate(end);
+                    break Some(if quote == '"' { unescape(&body) } else { Ok((body, Vec::new())) });
+                }
+                if let Some((_, next)) = lines.next() {
+                    body.push('\n');
+                    body.push_str(next);
+                } else {
+                    problem("unterminated quoted value".to_string(), false);
+                    break None;
+                }
+            }
+        } else {
+            let value = match value.find(" #").or_else(|| value.find("\t#")) {
+                Some(comment) => &value[..comment],
+                None => value,
+            }
+            .trim_end();
+            if let Some(c) = value.chars().find(|c| c.is_whitespace() || SPECIAL.contains(c)) {
+                problem(format!("unquoted value of `{key}` holds {c:?}; quote it"), true);
+            }
+            Some(Ok((value.to_string(), names(value))))
+        };
+        let (value, names) = match read {
+            Some(Ok(read)) => read,
+            Some(Err(message)) => {
+                problem(message, false);
+                continue;
+            }
+            None => continue,
+        };
+
+        if let Some(first) = seen.get(key) {
+            problem(format!("duplicate key `{key}`, first set on line {first}; the last value is used"), true);
+        }
+        seen.entry(key.to_string()).or_insert(line);
+        if names.is_empty() {
+            references.remove(key);
+        } else {
+            references.insert(key.to_string(), names);
+        }
+        variables.insert(key.to_string(), Value::String(value));
+    }
+
+    Document { variables, references, problems }
+}
+
+/// Whether `key` is a variable name shells accept
+fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Position of the quote closing a value opened with `quote`
+fn closing(body: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (position, c) in body.char_indices() {
+        match c {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            c if c == quote && !escaped => return Some(position),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// The value of a double-quoted body, and the variables it refers to
+fn unescape(body: &str) -> std::result::Result<(String, Vec<String>), String> {
+    let mut unescaped = String::with_capacity(body.len());
+    let mut references = Vec::new();
+    let mut chars = body.char_indices();
+    while let Some((position, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                Some('t') => unescaped.push('\t'),
+                Some(c @ ('"' | '\\' | '$')) => unescaped.push(c),
+                Some(other) => return Err(format!("unknown escape `\\{other}` in a quoted value")),
+                None => return Err("unterminated quoted value".to_string()),
+            },
+            '$' => {
+                references.extend(reference(&body[position..]));
+                unescaped.push('$');
+            }
+            c => unescaped.push(c),
+        }
+    }
+    Ok((unescaped, references))
+}
+
+/// Variables referred to in an unquoted value
+fn names(value: &str) -> Vec<String> {
+    value.match_indices('$').filter_map(|(position, _)| reference(&value[position..])).collect()
+}
+
+/// The variable named by a reference starting at `$`, as `$NAME` or `${NAME}`, `${NAME:-default}` and the like
+fn reference(text: &str) -> Option<String> {
+    let name = match text[1..].strip_prefix('{') {
+        Some(braced) => braced.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).next()?,
+        None => text[1..].split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).next()?,
+    };
+    valid_key(name).then(|| name.to_string())
+}
+
+/// A string value as written, quoted when it would otherwise read back differently
+fn quoted(text: &str) -> String {
+    let plain = text.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:@%+,=".contains(c));
+    if plain {
+        text.to_string()
+    } else if !text.contains(['\'', '\n', '\r']) {
+        format!("'{text}'")
+    } else {
+        let escaped = text
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+            .replace('\t', "\\t");
+        format!("\"{escaped}\"")
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(dotenv: &str) -> Value {
+        serde_json::from_str(&dotenv_to_json(dotenv).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_dotenv_to_json() {
+        let dotenv = "# database\nexport DB_HOST=localhost\nDB_PORT = 5432 # default\nPASSWORD='p@ss $word'\nGREETING=\"hello\\n\\\"world\\\"\"\nCERT=\"-----BEGIN-----\nabc\n-----END-----\"\nEMPTY=\nURL=postgres://$DB_HOST:${DB_PORT:-5432}/app\nPRICE=\"\\$5\"\n";
+        assert_eq!(
+            to_value(dotenv),
+            json!({
+                "DB_HOST": "localhost",
+                "DB_PORT": "5432",
+                "PASSWORD": "p@ss $word",
+                "GREETING": "hello\n\"world\"",
+                "CERT": "-----BEGIN-----\nabc\n-----END-----",
+                "EMPTY": "",
+                "URL": "postgres://$DB_HOST:${DB_PORT:-5432}/app",
+                "PRICE": "$5",
+            })
+        );
+
+        // Single quotes keep references literal, and escaped dollars are not references
+        let found = references(dotenv);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found["URL"], ["DB_HOST", "DB_PORT"]);
+        assert_eq!(references("A=\"${B}-$C\"\n")["A"], ["B", "C"]);
+    }
+
+    #[test]
+    fn test_json_to_dotenv_round_trip() {
+        let value = json!({
+            "NAME": "app",
+            "DEBUG": true,
+            "PORT": 8080,
+            "GREETING": "hello world",
+            "PRICE": "$5",
+            "QUOTE": "it's\nfine",
+            "GONE": null,
+        });
+        let dotenv = json_to_dotenv(&value.to_string()).unwrap();
+        assert_eq!(
+            dotenv,
+            "DEBUG=true\nGONE=\nGREETING='hello world'\nNAME=app\nPORT=8080\nPRICE='$5'\nQUOTE=\"it's\\nfine\"\n"
+        );
+        // Every value reads back as a string
+        assert_eq!(
+            to_value(&dotenv),
+            json!({"DEBUG": "true", "GONE": "", "GREETING": "hello world", "NAME": "app", "PORT": "8080", "PRICE": "$5", "QUOTE": "it's\nfine"})
+        );
+        assert!(diagnostics(&dotenv).is_empty());
+
+        assert!(json_to_dotenv("[1]").is_err());
+        assert!(json_to_dotenv(r#"{"A": {"B": 1}}"#).unwrap_err().to_string().contains("`A` is an object"));
+        assert!(json_to_dotenv(r#"{"1A": "x"}"#).is_err());
+        assert!(json_to_dotenv(r#"{"A-B": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let dotenv = "A=1\nA=2\njust text\n9LIVES=cat\nB=hello world\nC=a;b\nD=\"bad\\q\"\nE='x' y\nF=\"open\n";
+        let found: Vec<String> = validate_dotenv(dotenv).unwrap();
+        assert_eq!(
+            found,
+            [
+                "dotenv warning: line 2: duplicate key `A`, first set on line 1; the last value is used",
+                "Invalid dotenv: line 3: expected `KEY=VALUE`, found \"just text\"",
+                "Invalid dotenv: line 4: \"9LIVES\" is not a valid variable name",
+                "dotenv warning: line 5: unquoted value of `B` holds ' '; quote it",
+                "dotenv warning: line 6: unquoted value of `C` holds ';'; quote it",
+                "Invalid dotenv: line 7: unknown escape `\\q` in a quoted value",
+                "Invalid dotenv: line 8: unexpected \"y\" after the closing quote",
+                "Invalid dotenv: line 9: unterminated quoted value",
+            ]
+        );
+
+        // Warnings do not stop a conversion, and the last value of a key is used
+        assert_eq!(to_value("A=1\nA=2\nB=a b\n"), json!({"A": "2", "B": "a b"}));
+        assert_eq!(dotenv_to_json("A\n").unwrap_err().to_string(), "Invalid dotenv: line 1: expected `KEY=VALUE`, found \"A\"");
+        assert!(diagnostics("exported=1\nexport X=1\n").is_empty());
+    }
+}
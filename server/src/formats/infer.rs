@@ -0,0 +1,237 @@
+//! JSON Schema inference from sample documents
+//!
+//! Sample documents, read as their [`model`] values, are merged into one
+//! shape per position: the types seen there, the keys of the maps and the
+//! items of the arrays. The schema gives each position its `type`, a list
+//! where several were seen, with integers widened to `number` where floats
+//! were seen too. Keys every map at a position has are `required`; the
+//! items of every array at a position share one `items` schema.
+//!
+//! Strings at a position repeating among at most
+//! [`max_enum`](InferOptions::max_enum) distinct values are offered as an
+//! `enum`, with `null` where that was seen too. A position only ever seen
+//! once, or seen with each string once, gets no `enum`, as one sample says
+//! nothing of the values allowed. The schema is of draft 2020-12, and every
+//! sample it was inferred from is valid against it.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use super::model::{DocumentValue, Node};
+
+/// Draft of the schemas inferred
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// How schemas are inferred
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InferOptions {
+    /// Most distinct strings offered as an `enum`, none with 0
+    pub max_enum: usize,
+}
+
+impl Default for InferOptions {
+    fn default() -> Self {
+        Self { max_enum: 10 }
+    }
+}
+
+/// The schema all of `samples` are valid against
+#[must_use]
+pub fn infer(samples: &[DocumentValue], options: &InferOptions) -> Value {
+    let mut shape = Shape::default();
+    for sample in samples {
+        shape.add(sample, options);
+    }
+    let mut schema = Map::new();
+    schema.insert("$schema".to_string(), json!(DRAFT));
+    schema.extend(shape.schema());
+    Value::Object(schema)
+}
+
+/// The values seen at one position of the samples
+#[derive(Debug, Default)]
+#[allow(clippy::struct_excessive_bools)] // One per scalar type
+struct Shape {
+    null: bool,
+    boolean: bool,
+    integer: bool,
+    number: bool,
+    /// Strings seen, counted, until more than `max_enum` are distinct
+    strings: BTreeMap<String, usize>,
+    string_count: usize,
+    /// Whether too many distinct strings were seen for an `enum`
+    open: bool,
+    /// Shapes of the keys of the maps seen, with how many maps had each
+    properties: BTreeMap<String, (Shape, usize)>,
+    maps: usize,
+    items: Option<Box<Shape>>,
+    arrays: usize,
+}
+
+impl Shape {
+    fn add(&mut self, value: &DocumentValue, options: &InferOptions) {
+        match &value.node {
+            Node::Null => self.null = true,
+            Node::Bool(_) => self.boolean = true,
+            Node::Integer(_) | Node::Unsigned(_) => self.integer = true,
+            Node::Float(_) => self.number = true,
+            Node::String(s) => {
+                self.string_count += 1;
+                if !self.open {
+                    *self.strings.entry(s.clone()).or_default() += 1;
+                    if self.strings.len() > options.max_enum {
+                        self.open = true;
+                        self.strings.clear();
+                    }
+                }
+            }
+            // As the RFC 3339 string it is in JSON
+            Node::DateTime(datetime) => self.add(&DocumentValue::new(Node::String(datetime.rfc3339())), options),
+            Node::Array(items) => {
+                self.arrays += 1;
+                let shape = self.items.get_or_insert_with(Box::default);
+                for item in items {
+                    shape.add(item, options);
+                }
+            }
+            Node::Map(entries) => {
+                self.maps += 1;
+                // A key given twice is read as its last value, and counts once
+                let mut keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+                keys.sort_unstable();
+                keys.dedup();
+                for key in keys {
+                    let (shape, count) = self.properties.entry(key.to_string()).or_default();
+                    if let Some(value) = value.get(key) {
+                        shape.add(value, options);
+                    }
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    /// The keywords of the schema for this position, none if nothing was seen
+    fn schema(&self) -> Map<String, Value> {
+        let mut types = Vec::new();
+        if self.maps > 0 {
+            types.push("object");
+        }
+        if self.arrays > 0 {
+            types.push("array");
+        }
+        if self.string_count > 0 {
+            types.push("string");
+        }
+        if self.number {
+            types.push("number");
+        } else if self.integer {
+            types.push("integer");
+        }
+        if self.boolean {
+            types.push("boolean");
+        }
+        if self.null {
+            types.push("null");
+        }
+
+        let mut schema = Map::new();
+        match types.as_slice() {
+            [] => return schema,
+            [only] => schema.insert("type".to_string(), json!(only)),
+            _ => schema.insert("type".to_string(), json!(types)),
+        };
+        if self.maps > 0 {
+            let properties: Map<String, Value> = self
+                .properties
+                .iter()
+                .map(|(key, (shape, _))| (key.clone(), Value::Object(shape.schema())))
+                .collect();
+            let required: Vec<&String> =
+                self.properties.iter().filter(|(_, (_, count))| *count == self.maps).map(|(key, _)| key).collect();
+            schema.insert("properties".to_string(), Value::Object(properties));
+            if !required.is_empty() {
+                schema.insert("required".to_string(), json!(required));
+            }
+        }
+        if let Some(items) = self.items.as_ref().filter(|items| !items.is_empty()) {
+            schema.insert("items".to_string(), Value::Object(items.schema()));
+        }
+        if let Some(values) = self.enum_values() {
+            schema.insert("enum".to_string(), Value::Array(values));
+        }
+        schema
+    }
+
+    /// The strings seen, where they make an `enum`: only strings, or null, seen, and some seen more than once
+    fn enum_values(&self) -> Option<Vec<Value>> {
+        let only_strings = !(self.boolean || self.integer || self.number) && self.maps == 0 && self.arrays == 0;
+        let repeated = self.string_count > self.strings.len();
+        if self.open || self.strings.is_empty() || !only_strings || !repeated {
+            return None;
+        }
+        let mut values: Vec<Value> = self.strings.keys().map(|s| json!(s)).collect();
+        if self.null {
+            values.push(Value::Null);
+        }
+        Some(values)
+    }
+
+    fn is_empty(&self) -> bool {
+        !(self.null || self.boolean || self.integer || self.number) && self.string_count == 0 && self.maps == 0 && self.arrays == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Format;
+    use crate::formats::{model, schema};
+
+    #[test]
+    fn test_infer() {
+        let samples = [
+            ("name: api\nreplicas: 2\ntier: web\nports: [80, 443]\n", Format::Yaml),
+            (r#"{"name": "db", "replicas": 1.5, "tier": "data", "ports": [], "tls": null}"#, Format::Json),
+            ("name = \"cache\"\nreplicas = 1\ntier = \"web\"\nports = [6379]\ntls = true\n", Format::Toml),
+        ];
+        let values: Vec<_> = samples.iter().map(|(content, format)| model::read(content, *format, &mut Vec::new()).unwrap()).collect();
+        let inferred = infer(&values, &InferOptions::default());
+        let expected = json!({
+            "$schema": DRAFT,
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "ports": {"type": "array", "items": {"type": "integer"}},
+                "replicas": {"type": "number"},
+                "tier": {"type": "string", "enum": ["data", "web"]},
+                "tls": {"type": ["boolean", "null"]},
+            },
+            "required": ["name", "ports", "replicas", "tier"],
+        });
+        assert_eq!(inferred, expected);
+
+        // Every sample is valid against the schema inferred from it
+        let compiled = schema::Schema::new(inferred).unwrap();
+        for (content, format) in samples {
+            assert!(schema::validate(content, format, &compiled).unwrap().is_empty(), "{content}");
+        }
+        assert!(!schema::validate("name: api\nreplicas: 2\ntier: edge\nports: []\n", Format::Yaml, &compiled).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_enum_candidates() {
+        let samples: Vec<_> = ["a", "b", "a", "c"].iter().map(|s| DocumentValue::from_json(json!({"kind": s}))).collect();
+        let kind = |options: &InferOptions| infer(&samples, options)["properties"]["kind"].clone();
+        assert_eq!(kind(&InferOptions::default()), json!({"type": "string", "enum": ["a", "b", "c"]}));
+        assert_eq!(kind(&InferOptions { max_enum: 2 }), json!({"type": "string"}));
+        assert_eq!(kind(&InferOptions { max_enum: 0 }), json!({"type": "string"}));
+
+        let once = infer(&[DocumentValue::from_json(json!(["x", "y", 1]))], &InferOptions::default());
+        assert_eq!(once["items"], json!({"type": ["string", "integer"]}));
+        assert_eq!(infer(&[DocumentValue::from_json(json!([]))], &InferOptions::default())["type"], "array");
+        assert_eq!(infer(&[], &InferOptions::default()), json!({"$schema": DRAFT}));
+    }
+}
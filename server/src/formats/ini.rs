@@ -0,0 +1,366 @@
+//! INI format support for document conversion
+//!
+//! An INI document becomes a JSON object: keys before the first section at
+//! its top level, and each `[section]` an object of its keys. Keys are set
+//! with `=` or `:`, and lines starting with `;` or `#` are comments. A
+//! section may be opened again, adding to its keys, but a key may be set
+//! only once in each. Values that read as numbers or as `true` or `false`
+//! are typed, and quotes keep a value a string: `"42"`, or `'a ; b'`, taken
+//! as written. Double-quoted values take `\"`, `\\`, `\n`, `\r` and `\t`
+//! escapes. Keys under a malformed section header are checked but dropped.
+//!
+//! JSON is written from an object whose values are scalars, or objects of
+//! scalars, which become sections. Null is written as an empty value, and
+//! read back as an empty string.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Convert INI to JSON
+///
+/// # Errors
+///
+/// Fails on the first line that is neither a section, a key and value, nor
+/// a comment.
+pub fn ini_to_json(ini: &str) -> Result<String> {
+    let document = read(ini);
+    if let Some(diagnostic) = document.problems.into_iter().next() {
+        return Err(anyhow!("Invalid INI: {diagnostic}"));
+    }
+    Ok(serde_json::to_string_pretty(&Value::Object(document.root))?)
+}
+
+/// Convert JSON to INI
+///
+/// # Errors
+///
+/// Fails where `json` is not an object of scalars and sections of scalars.
+pub fn json_to_ini(json: &str) -> Result<String> {
+    let Value::Object(root) = serde_json::from_str(json)? else {
+        return Err(anyhow!("INI documents are written from a JSON object"));
+    };
+
+    let mut ini = String::new();
+    let mut sections = Vec::new();
+    for (key, value) in &root {
+        match value {
+            Value::Object(keys) => sections.push((key, keys)),
+            value => ini.push_str(&entry(key, value, None)?),
+        }
+    }
+    for (name, keys) in sections {
+        if name.is_empty() || name.contains([']', '\n', '\r']) {
+            return Err(anyhow!("{name:?} cannot name an INI section"));
+        }
+        if !ini.is_empty() {
+            ini.push('\n');
+        }
+        let _ = writeln!(ini, "[{name}]");
+        for (key, value) in keys {
+            ini.push_str(&entry(key, value, Some(name))?);
+        }
+    }
+    Ok(ini)
+}
+
+/// Validate INI, returning every problem found
+///
+/// # Errors
+///
+/// Never; problems are returned for each line they are found on.
+pub fn validate_ini(ini: &str) -> Result<Vec<String>> {
+    Ok(diagnostics(ini).into_iter().map(|diagnostic| format!("Invalid INI: {diagnostic}")).collect())
+}
+
+/// A problem on one line of an INI document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IniDiagnostic {
+    /// Line the problem is on, from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for IniDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Every malformed line and duplicate key of `ini`, in order
+#[must_use]
+pub fn diagnostics(ini: &str) -> Vec<IniDiagnostic> {
+    read(ini).problems
+}
+
+/// A document as read, with the problems found along the way
+struct Document {
+    root: Map<String, Value>,
+    problems: Vec<IniDiagnostic>,
+}
+
+fn read(ini: &str) -> Document {
+    let mut root = Map::new();
+    let mut problems = Vec::new();
+    // Lines keys were first set on, by section, and sections first opened on, at the top level
+    let mut seen: HashMap<(Option<String>, String), usize> = HashMap::new();
+    let mut section: Option<String> = None;
+    // Under a malformed section header, whose keys belong nowhere
+    let mut orphaned = false;
+
+    for (i, raw) in ini.lines().enumerate() {
+        let line = i + 1;
+        let text = raw.trim();
+        let mut problem = |message: String| problems.push(IniDiagnostic { line, message });
+        if text.is_empty() || text.starts_with([';', '#']) {
+            continue;
+        }
+
+        if let Some(header) = text.strip_prefix('[') {
+            let Some((name, rest)) = header.split_once(']') else {
+                problem("malformed section header: missing `]`".to_string());
+                orphaned = true;
+                continue;
+            };
+            let name = name.trim();
+            if name.is_empty() {
+                problem("malformed section header: the name is empty".to_string());
+                orphaned = true;
+                continue;
+            }
+            let rest = rest.trim();
+            if !rest.is_empty() && !rest.starts_with([';', '#']) {
+                problem(format!("malformed section header: {rest:?} after `]`"));
+            }
+            match root.get(name) {
+                Some(Value::Object(_)) => {}
+                Some(_) => {
+                    let first = seen[&(None, name.to_string())];
+                    problem(format!("section `{name}` has the name of the key set on line {first}"));
+                    orphaned = true;
+                    continue;
+                }
+                None => {
+                    root.insert(name.to_string(), Value::Object(Map::new()));
+                    seen.insert((None, name.to_string()), line);
+                }
+            }
+            section = Some(name.to_string());
+            orphaned = false;
+            continue;
+        }
+
+        let Some(split) = text.find(['=', ':']) else {
+            problem(format!("expected `key = value` or a `[section]`, found {text:?}"));
+            continue;
+        };
+        let key = text[..split].trim();
+        if key.is_empty() {
+            problem("the key is empty".to_string());
+            continue;
+        }
+        let value = match typed(text[split + 1..].trim()) {
+            Ok(value) => value,
+            Err(message) => {
+                problem(message);
+                continue;
+            }
+        };
+
+        if orphaned {
+            continue;
+        }
+        if let Some(first) = seen.get(&(section.clone(), key.to_string())) {
+            let place = section.as_ref().map_or(String::new(), |name| format!(" in section `{name}`"));
+            problem(format!("duplicate key `{key}`{place}, first set on line {first}"));
+            continue;
+        }
+        seen.insert((section.clone(), key.to_string()), line);
+        let keys = match &section {
+            Some(name) => match root.get_mut(name) {
+                Some(Value::Object(keys)) => keys,
+                _ => continue,
+            },
+            None => &mut root,
+        };
+        keys.insert(key.to_string(), value);
+    }
+
+    Document { root, problems }
+}
+
+/// The JSON value of a value as written
+fn typed(value: &str) -> std::result::Result<Value, String> {
+    if value.starts_with('"') {
+        if value.len() < 2 || !value.ends_with('"') {
+            return Err("unterminated quoted value".to_string());
+        }
+        return unescape(&value[1..value.len() - 1]).map(Value::String);
+    }
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return Ok(Value::String(value[1..value.len() - 1].to_string()));
+    }
+    match value {
+        "true" | "TRUE" | "True" => return Ok(Value::Bool(true)),
+        "false" | "FALSE" | "False" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    // JSON's grammar keeps codes such as 007 and +1 as text
+    if let Ok(number) = serde_json::from_str::<Number>(value) {
+        let integral = !value.contains(['.', 'e', 'E']);
+        // Integers too long for 64 bits would lose digits as floats
+        if !integral || number.is_i64() || number.is_u64() {
+            return Ok(Value::Number(number));
+        }
+    }
+    Ok(Value::String(value.to_string()))
+}
+
+fn unescape(text: &str) -> std::result::Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err("unescaped `\"` inside a quoted value".to_string());
+        }
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => return Err(format!("unknown escape `\\{other}` in a quoted value")),
+            None => return Err("unterminated quoted value".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// The line setting `key` to `value`, in `section` for errors
+fn entry(key: &str, value: &Value, section: Option<&str>) -> Result<String> {
+    let path = section.map_or(key.to_string(), |section| format!("{section}.{key}"));
+    if key.trim() != key || key.is_empty() || key.contains(['=', ':', '\n', '\r']) || key.starts_with([';', '#', '[']) {
+        return Err(anyhow!("{path:?} cannot be an INI key"));
+    }
+    let written = match value {
+        Value::Null => String::new(),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        Value::String(text) => {
+            // Quote what would otherwise read back as something else
+            let plain = !text.is_empty()
+                && text.trim() == text
+                && !text.contains(['\n', '\r', '\t'])
+                && !text.starts_with(['"', '\''])
+                && typed(text) == Ok(Value::String(text.clone()));
+            if plain {
+                text.clone()
+            } else {
+                let escaped = text
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r")
+                    .replace('\t', "\\t");
+                format!("\"{escaped}\"")
+            }
+        }
+        Value::Array(_) | Value::Object(_) => {
+            return Err(anyhow!("INI values are scalars; `{}` is {}", path, kind(value)));
+        }
+    };
+    Ok(if written.is_empty() { format!("{key} =\n") } else { format!("{key} = {written}\n") })
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(ini: &str) -> Value {
+        serde_json::from_str(&ini_to_json(ini).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_ini_to_json() {
+        let ini = "; generated\nname = app\n\n[server]\nport = 8080\nhost: localhost\ntls = false\nzip = 007\n\n# again\n[paths]\nroot = \"/srv/app\"\nversion = \"42\"\nnote = 'a ; b'\nempty =\n";
+        assert_eq!(
+            to_value(ini),
+            json!({
+                "name": "app",
+                "server": {"port": 8080, "host": "localhost", "tls": false, "zip": "007"},
+                "paths": {"root": "/srv/app", "version": "42", "note": "a ; b", "empty": ""},
+            })
+        );
+
+        // Opening a section again adds to it
+        assert_eq!(to_value("[a]\nx = 1\n[b]\n[a]\ny = 2\n"), json!({"a": {"x": 1, "y": 2}, "b": {}}));
+        assert_eq!(to_value("s = \"tab\\there \\\"q\\\"\"\n"), json!({"s": "tab\there \"q\""}));
+    }
+
+    #[test]
+    fn test_json_to_ini_round_trip() {
+        let value = json!({
+            "name": "app",
+            "debug": true,
+            "server": {"port": 8080, "host": " padded ", "version": "42", "motd": "line\nbreak", "path": "C:\\app"},
+            "empty": {},
+        });
+        let ini = json_to_ini(&value.to_string()).unwrap();
+        assert_eq!(
+            ini,
+            "debug = true\nname = app\n\n[empty]\n\n[server]\nhost = \" padded \"\nmotd = \"line\\nbreak\"\npath = C:\\app\nport = 8080\nversion = \"42\"\n"
+        );
+        assert_eq!(to_value(&ini), value);
+
+        assert_eq!(json_to_ini(r#"{"gone": null}"#).unwrap(), "gone =\n");
+        assert!(json_to_ini("[1]").is_err());
+        assert!(json_to_ini(r#"{"a": {"b": {"c": 1}}}"#).unwrap_err().to_string().contains("`a.b` is an object"));
+        assert!(json_to_ini(r#"{"tags": [1]}"#).is_err());
+        assert!(json_to_ini(r#"{"a=b": 1}"#).is_err());
+        assert!(json_to_ini(r#"{"a]": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let ini = "a = 1\na = 2\n[server\n[]\n[ok] trailing\nport = 1\nport = 2\njust text\n= 3\nq = \"open\n[a]\n";
+        let found: Vec<String> = diagnostics(ini).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            found,
+            [
+                "line 2: duplicate key `a`, first set on line 1",
+                "line 3: malformed section header: missing `]`",
+                "line 4: malformed section header: the name is empty",
+                "line 5: malformed section header: \"trailing\" after `]`",
+                "line 7: duplicate key `port` in section `ok`, first set on line 6",
+                "line 8: expected `key = value` or a `[section]`, found \"just text\"",
+                "line 9: the key is empty",
+                "line 10: unterminated quoted value",
+                "line 11: section `a` has the name of the key set on line 1",
+            ]
+        );
+
+        assert_eq!(validate_ini("[a]\nx = 1\n[b]\nx = 1\n").unwrap(), Vec::<String>::new());
+        assert_eq!(validate_ini("x = 1\nx = 1\n").unwrap(), ["Invalid INI: line 2: duplicate key `x`, first set on line 1"]);
+        assert_eq!(
+            ini_to_json("[a\n").unwrap_err().to_string(),
+            "Invalid INI: line 1: malformed section header: missing `]`"
+        );
+    }
+}
@@ -0,0 +1,478 @@
+//! JSON5 and JSONC format support for document conversion
+//!
+//! JSONC is JSON with `//` and `/* */` comments and trailing commas, as
+//! VS Code reads its settings and `tsconfig.json`. JSON5 adds to that keys
+//! that are identifiers, single-quoted strings, more escapes and line
+//! continuations in strings, hexadecimal numbers, numbers with a leading or
+//! trailing decimal point or a `+` sign, and more whitespace. Both read as
+//! strict JSON, except `Infinity` and `NaN`, which JSON cannot hold.
+//!
+//! Errors are placed by byte offset, and by line and column. JSON is
+//! written to either as formatted JSON, which both read; comments do not
+//! survive the round trip.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// Arrays and objects opened inside one another before a document is refused
+const MAX_DEPTH: usize = 128;
+
+/// Which relaxation of JSON a document is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Json5,
+    Jsonc,
+}
+
+impl Dialect {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Json5 => "JSON5",
+            Self::Jsonc => "JSONC",
+        }
+    }
+}
+
+/// Convert JSON5 to JSON
+///
+/// # Errors
+///
+/// Fails where `json5` does not parse as JSON5.
+pub fn json5_to_json(json5: &str) -> Result<String> {
+    to_json(json5, Dialect::Json5)
+}
+
+/// Convert JSONC to JSON
+///
+/// # Errors
+///
+/// Fails where `jsonc` does not parse as JSONC.
+pub fn jsonc_to_json(jsonc: &str) -> Result<String> {
+    to_json(jsonc, Dialect::Jsonc)
+}
+
+fn to_json(text: &str, dialect: Dialect) -> Result<String> {
+    let value = parse(text, dialect).map_err(|diagnostic| anyhow!("Invalid {}: {}", dialect.name(), diagnostic))?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Convert JSON to JSON5
+///
+/// # Errors
+///
+/// Fails where `json` does not parse.
+pub fn json_to_json5(json: &str) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&serde_json::from_str::<Value>(json)?)?)
+}
+
+/// Convert JSON to JSONC
+///
+/// # Errors
+///
+/// Fails where `json` does not parse.
+pub fn json_to_jsonc(json: &str) -> Result<String> {
+    json_to_json5(json)
+}
+
+/// Validate JSON5, returning any errors
+///
+/// # Errors
+///
+/// Never; a problem is returned as a diagnostic.
+pub fn validate_json5(json5: &str) -> Result<Vec<String>> {
+    Ok(validate(json5, Dialect::Json5))
+}
+
+/// Validate JSONC, returning any errors
+///
+/// # Errors
+///
+/// Never; a problem is returned as a diagnostic.
+pub fn validate_jsonc(jsonc: &str) -> Result<Vec<String>> {
+    Ok(validate(jsonc, Dialect::Jsonc))
+}
+
+fn validate(text: &str, dialect: Dialect) -> Vec<String> {
+    diagnostics(text, dialect).into_iter().map(|diagnostic| format!("Invalid {}: {}", dialect.name(), diagnostic)).collect()
+}
+
+/// Where and why a document fails to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json5Diagnostic {
+    pub message: String,
+    /// Byte offset of the problem in the text
+    pub offset: usize,
+    /// Line and column of `offset`, both from 1, the column counted in characters
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Json5Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// The first error in `text`, or none when it parses
+#[must_use]
+pub fn diagnostics(text: &str, dialect: Dialect) -> Vec<Json5Diagnostic> {
+    parse(text, dialect).err().into_iter().collect()
+}
+
+/// Parse `text`, written in `dialect`, as a JSON value
+///
+/// # Errors
+///
+/// The first [`Json5Diagnostic`] found, with its line and column.
+pub fn parse(text: &str, dialect: Dialect) -> std::result::Result<Value, Json5Diagnostic> {
+    let mut parser = Parser { text, pos: 0, dialect };
+    parser.skip()?;
+    let value = parser.value(0)?;
+    parser.skip()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => Err(parser.error(parser.pos, format!("unexpected `{c}` after the value"))),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    dialect: Dialect,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, offset: usize, message: String) -> Json5Diagnostic {
+        let (line, column) = super::line_column(self.text, offset);
+        Json5Diagnostic { message, offset, line, column }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn json5(&self) -> bool {
+        self.dialect == Dialect::Json5
+    }
+
+    /// Skip whitespace and comments
+    fn skip(&mut self) -> std::result::Result<(), Json5Diagnostic> {
+        loop {
+            let rest = &self.text[self.pos..];
+            if rest.starts_with("//") {
+                self.pos += rest.find(['\n', '\r']).unwrap_or(rest.len());
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                let end = comment.find("*/").ok_or_else(|| self.error(self.pos, "unterminated comment".to_string()))?;
+                self.pos += end + 4;
+            } else {
+                match self.peek() {
+                    Some(' ' | '\t' | '\n' | '\r' | '\u{feff}') => {}
+                    Some(c) if self.json5() && c.is_whitespace() => {}
+                    _ => return Ok(()),
+                }
+                self.bump();
+            }
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> std::result::Result<Value, Json5Diagnostic> {
+        let start = self.pos;
+        match self.peek() {
+            Some('{' | '[') if depth == MAX_DEPTH => {
+                Err(self.error(start, format!("nested more than {MAX_DEPTH} deep")))
+            }
+            Some('{') => self.object(depth),
+            Some('[') => self.array(depth),
+            Some('"') => self.string().map(Value::String),
+            Some('\'') if self.json5() => self.string().map(Value::String),
+            Some(c) if c.is_ascii_digit() || matches!(c, '-' | '+' | '.') => self.number(),
+            Some(c) if c.is_alphabetic() => {
+                let word = self.word();
+                match word {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    "Infinity" | "NaN" if self.json5() => {
+                        Err(self.error(start, format!("`{word}` cannot be written as JSON")))
+                    }
+                    _ => Err(self.error(start, format!("unexpected `{word}`, expected a value"))),
+                }
+            }
+            Some(c) => Err(self.error(start, format!("unexpected `{c}`, expected a value"))),
+            None => Err(self.error(start, "unexpected end of input, expected a value".to_string())),
+        }
+    }
+
+    /// The run of identifier characters at the current position
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '$' | '\u{200c}' | '\u{200d}')) {
+            self.bump();
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn object(&mut self, depth: usize) -> std::result::Result<Value, Json5Diagnostic> {
+        self.bump();
+        let mut map = Map::new();
+        loop {
+            self.skip()?;
+            let start = self.pos;
+            let key = match self.peek() {
+                Some('}') => {
+                    self.bump();
+                    return Ok(Value::Object(map));
+                }
+                Some('"') => self.string()?,
+                Some('\'') if self.json5() => self.string()?,
+                Some(c) if self.json5() && (c.is_alphabetic() || matches!(c, '_' | '$')) => self.word().to_string(),
+                Some(c) => return Err(self.error(start, format!("unexpected `{c}`, expected a key"))),
+                None => return Err(self.error(start, "unterminated object".to_string())),
+            };
+            self.skip()?;
+            if self.peek() != Some(':') {
+                return Err(self.error(self.pos, format!("expected `:` after the key {key:?}")));
+            }
+            self.bump();
+            self.skip()?;
+            map.insert(key, self.value(depth + 1)?);
+            self.skip()?;
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some('}') => {}
+                Some(c) => return Err(self.error(self.pos, format!("unexpected `{c}`, expected `,` or `}}`"))),
+                None => return Err(self.error(self.pos, "unterminated object".to_string())),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> std::result::Result<Value, Json5Diagnostic> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip()?;
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            if self.peek() == Some(',') {
+                return Err(self.error(self.pos, "unexpected `,`, expected a value".to_string()));
+            }
+            if self.peek().is_none() {
+                return Err(self.error(self.pos, "unterminated array".to_string()));
+            }
+            items.push(self.value(depth + 1)?);
+            self.skip()?;
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                Some(c) => return Err(self.error(self.pos, format!("unexpected `{c}`, expected `,` or `]`"))),
+                None => return Err(self.error(self.pos, "unterminated array".to_string())),
+            }
+        }
+    }
+
+    fn string(&mut self) -> std::result::Result<String, Json5Diagnostic> {
+        let start = self.pos;
+        let quote = self.bump();
+        let mut string = String::new();
+        loop {
+            let at = self.pos;
+            match self.bump() {
+                None => return Err(self.error(start, "unterminated string".to_string())),
+                Some(c) if Some(c) == quote => return Ok(string),
+                Some('\n' | '\r') => return Err(self.error(at, "line break in a string".to_string())),
+                Some(c) if c < ' ' && !self.json5() => {
+                    return Err(self.error(at, format!("unescaped control character {c:?} in a string")));
+                }
+                Some('\\') => self.escape(at, &mut string)?,
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    /// Read the escape after the `\` at `at` into `string`
+    fn escape(&mut self, at: usize, string: &mut String) -> std::result::Result<(), Json5Diagnostic> {
+        let unknown = |parser: &Self, c: char| Err(parser.error(at, format!("unknown escape `\\{c}`")));
+        match self.bump() {
+            Some('"') => string.push('"'),
+            Some('\\') => string.push('\\'),
+            Some('/') => string.push('/'),
+            Some('b') => string.push('\u{8}'),
+            Some('f') => string.push('\u{c}'),
+            Some('n') => string.push('\n'),
+            Some('r') => string.push('\r'),
+            Some('t') => string.push('\t'),
+            Some('u') => {
+                let unit = self.hex(4, at)?;
+                let c = if (0xd800..0xdc00).contains(&unit) && self.text[self.pos..].starts_with("\\u") {
+                    self.pos += 2;
+                    let low = self.hex(4, at)?;
+                    char::from_u32(0x10000 + ((unit - 0xd800) << 10) + low.wrapping_sub(0xdc00))
+                        .filter(|_| (0xdc00..0xe000).contains(&low))
+                } else {
+                    char::from_u32(unit)
+                };
+                string.push(c.unwrap_or('\u{fffd}'));
+            }
+            Some(c) if !self.json5() => return unknown(self, c),
+            Some('\'') => string.push('\''),
+            Some('v') => string.push('\u{b}'),
+            Some('0') if !self.peek().is_some_and(|c| c.is_ascii_digit()) => string.push('\0'),
+            Some('x') => {
+                let byte = self.hex(2, at)?;
+                string.push(char::from_u32(byte).unwrap_or('\u{fffd}'));
+            }
+            // Line continuations
+            Some('\r') => {
+                if self.peek() == Some('\n') {
+                    self.bump();
+                }
+            }
+            Some('\n' | '\u{2028}' | '\u{2029}') => {}
+            Some(c) if c.is_ascii_digit() => return unknown(self, c),
+            Some(c) => string.push(c),
+            None => return Err(self.error(at, "unterminated string".to_string())),
+        }
+        Ok(())
+    }
+
+    fn hex(&mut self, digits: usize, at: usize) -> std::result::Result<u32, Json5Diagnostic> {
+        let text = self.text.get(self.pos..self.pos + digits).filter(|text| text.chars().all(|c| c.is_ascii_hexdigit()));
+        let Some(text) = text else {
+            return Err(self.error(at, format!("expected {digits} hexadecimal digits in the escape")));
+        };
+        self.pos += digits;
+        Ok(u32::from_str_radix(text, 16).unwrap_or_default())
+    }
+
+    fn number(&mut self) -> std::result::Result<Value, Json5Diagnostic> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-')) {
+            self.bump();
+        }
+        let token = &self.text[start..self.pos];
+        let invalid = || self.error(start, format!("invalid number `{token}`"));
+        if !self.json5() {
+            return serde_json::from_str::<Number>(token).map(Value::Number).map_err(|_| invalid());
+        }
+
+        let (negative, unsigned) = match token.as_bytes().first() {
+            Some(b'-') => (true, &token[1..]),
+            Some(b'+') => (false, &token[1..]),
+            _ => (false, token),
+        };
+        if unsigned == "Infinity" || unsigned == "NaN" {
+            return Err(self.error(start, format!("`{token}` cannot be written as JSON")));
+        }
+        if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+            let magnitude = u64::from_str_radix(hex, 16).map_err(|_| invalid())?;
+            let number = if negative {
+                i64::try_from(magnitude).ok().and_then(i64::checked_neg).map(Number::from).ok_or_else(invalid)?
+            } else {
+                Number::from(magnitude)
+            };
+            return Ok(Value::Number(number));
+        }
+
+        // A leading or trailing point is JSON once it has a digit on each side
+        let mut decimal = if unsigned.starts_with('.') { format!("0{unsigned}") } else { unsigned.to_string() };
+        if let Some(point) = decimal.find('.') {
+            if !decimal[point + 1..].starts_with(|c: char| c.is_ascii_digit()) {
+                decimal.insert(point + 1, '0');
+            }
+        }
+        if negative {
+            decimal.insert(0, '-');
+        }
+        serde_json::from_str::<Number>(&decimal).map(Value::Number).map_err(|_| invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(text: &str, dialect: Dialect) -> Value {
+        parse(text, dialect).unwrap()
+    }
+
+    #[test]
+    fn test_jsonc() {
+        let settings = r#"{
+            // Editor
+            "editor.tabSize": 4, /* spaces */
+            "files.exclude": {"**/.git": true,},
+            "list": [1, 2,],
+        }"#;
+        assert_eq!(
+            to_value(settings, Dialect::Jsonc),
+            json!({"editor.tabSize": 4, "files.exclude": {"**/.git": true}, "list": [1, 2]})
+        );
+        assert_eq!(to_value("\u{feff}// only a comment before\n[]", Dialect::Jsonc), json!([]));
+
+        // JSON5 additions are not JSONC
+        for text in ["{a: 1}", "['a']", "0x10", "+1", ".5", "[\"\\v\"]"] {
+            assert!(parse(text, Dialect::Jsonc).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_json5() {
+        let text = "// config\n{\n  unquoted: 'single \"quoted\"',\n  $id_1: 0x1F,\n  neg: -0xff,\n  lead: .5, trail: 5., plus: +1, exp: 2.e1,\n  'esc': '\\x41\\v\\0\\'\\q line\\\n continued',\n  list: [null, true, false,],\n}\n";
+        assert_eq!(
+            to_value(text, Dialect::Json5),
+            json!({
+                "unquoted": "single \"quoted\"",
+                "$id_1": 31,
+                "neg": -255,
+                "lead": 0.5,
+                "trail": 5.0,
+                "plus": 1,
+                "exp": 20.0,
+                "esc": "A\u{b}\0'q line continued",
+                "list": [null, true, false],
+            })
+        );
+        assert_eq!(to_value(r#""\ud83d\ude00""#, Dialect::Json5), json!("😀"));
+        assert_eq!(to_value("\u{a0}1\u{2028}", Dialect::Json5), json!(1));
+
+        let json: Value = serde_json::from_str(&json5_to_json("{a: [1,],}").unwrap()).unwrap();
+        assert_eq!(json, json!({"a": [1]}));
+        assert_eq!(json_to_json5(r#"{"a":1}"#).unwrap(), "{\n  \"a\": 1\n}");
+    }
+
+    #[test]
+    fn test_errors_are_placed() {
+        let error = |text: &str, dialect| {
+            let diagnostic = parse(text, dialect).unwrap_err();
+            (diagnostic.line, diagnostic.column, diagnostic.message)
+        };
+        assert_eq!(error("{\n  \"a\": 1\n  \"b\": 2\n}", Dialect::Jsonc), (3, 3, "unexpected `\"`, expected `,` or `}`".to_string()));
+        assert_eq!(error("[1, /* open", Dialect::Jsonc), (1, 5, "unterminated comment".to_string()));
+        assert_eq!(error("{a: Infinity}", Dialect::Json5), (1, 5, "`Infinity` cannot be written as JSON".to_string()));
+        assert_eq!(error("[-NaN]", Dialect::Json5), (1, 2, "`-NaN` cannot be written as JSON".to_string()));
+        assert_eq!(error("{'é': 'x\n'}", Dialect::Json5), (1, 9, "line break in a string".to_string()));
+        assert_eq!(error("[01]", Dialect::Json5), (1, 2, "invalid number `01`".to_string()));
+        assert_eq!(error("[1,,2]", Dialect::Json5), (1, 4, "unexpected `,`, expected a value".to_string()));
+        assert_eq!(error("{} x", Dialect::Json5), (1, 4, "unexpected `x` after the value".to_string()));
+        assert_eq!(error("", Dialect::Json5).2, "unexpected end of input, expected a value");
+        assert_eq!(error(&"[".repeat(MAX_DEPTH + 1), Dialect::Json5).2, format!("nested more than {MAX_DEPTH} deep"));
+
+        assert_eq!(validate_jsonc("{\"a\": 1,}").unwrap(), Vec::<String>::new());
+        assert_eq!(validate_jsonc("{\"a\" 1}").unwrap(), ["Invalid JSONC: line 1, column 6: expected `:` after the key \"a\""]);
+        assert!(jsonc_to_json("{,}").unwrap_err().to_string().starts_with("Invalid JSONC: line 1, column 2"));
+    }
+}
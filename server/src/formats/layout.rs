@@ -0,0 +1,134 @@
+//! Layout of converted JSON
+//!
+//! Converters lay out their JSON as they like. [`OutputOptions`] lay it out
+//! again: indented by a chosen width, with object keys sorted, or in the
+//! canonical form, compact with sorted keys, so that equal documents give
+//! equal bytes. Re-indenting keeps the order of keys, as it works on the
+//! text rather than on a parsed value.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// How converted JSON is laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputOptions {
+    /// Spaces per level, or 0 for compact output; `None` keeps the converter's layout
+    pub indent: Option<usize>,
+    /// Order the keys of every object
+    pub sort_keys: bool,
+    /// Compact, with the keys of every object sorted
+    pub canonical: bool,
+}
+
+impl OutputOptions {
+    /// Whether any option is set
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Lay out `json` as the options say
+    ///
+    /// # Errors
+    ///
+    /// Fails where `json` does not parse, or canonical output is asked to be
+    /// indented.
+    pub fn apply(&self, json: &str) -> Result<String> {
+        if self.is_default() {
+            return Ok(json.to_string());
+        }
+        if self.canonical && self.indent.is_some_and(|indent| indent > 0) {
+            bail!("Canonical output is compact and cannot be indented");
+        }
+        let indent = if self.canonical { Some(0) } else { self.indent };
+        if self.sort_keys || self.canonical {
+            // Without `preserve_order`, serde_json keeps object keys sorted
+            let value: serde_json::Value = serde_json::from_str(json).context("Output is not JSON")?;
+            let sorted = serde_json::to_string(&value)?;
+            return Ok(reindent(&sorted, indent.unwrap_or(2)));
+        }
+        serde_json::from_str::<serde::de::IgnoredAny>(json).context("Output is not JSON")?;
+        Ok(reindent(json, indent.unwrap_or(2)))
+    }
+}
+
+/// Lay out valid JSON with `indent` spaces per level, or compactly for 0
+fn reindent(json: &str, indent: usize) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut depth = 0;
+    let newline = |out: &mut String, depth: usize| {
+        if indent > 0 {
+            out.push('\n');
+            out.extend(std::iter::repeat_n(' ', depth * indent));
+        }
+    };
+    let mut chars = json.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                out.push(c);
+                while let Some(c) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '{' | '[' => {
+                out.push(c);
+                while chars.peek().is_some_and(char::is_ascii_whitespace) {
+                    chars.next();
+                }
+                if matches!(chars.peek(), Some('}' | ']')) {
+                    out.extend(chars.next());
+                } else {
+                    depth += 1;
+                    newline(&mut out, depth);
+                }
+            }
+            '}' | ']' => {
+                depth -= 1;
+                newline(&mut out, depth);
+                out.push(c);
+            }
+            ',' => {
+                out.push(c);
+                newline(&mut out, depth);
+            }
+            ':' => out.push_str(if indent > 0 { ": " } else { ":" }),
+            c if c.is_ascii_whitespace() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{"b": [1, {"z": "a, \"b\": c"}, []], "a": {}}"#;
+
+    #[test]
+    fn test_reindent_keeps_key_order() {
+        let options = OutputOptions { indent: Some(4), ..OutputOptions::default() };
+        let expected = "{\n    \"b\": [\n        1,\n        {\n            \"z\": \"a, \\\"b\\\": c\"\n        },\n        []\n    ],\n    \"a\": {}\n}";
+        assert_eq!(options.apply(JSON).unwrap(), expected);
+        assert_eq!(OutputOptions::default().apply(JSON).unwrap(), JSON);
+    }
+
+    #[test]
+    fn test_sorted_and_canonical() {
+        let sorted = OutputOptions { indent: Some(0), sort_keys: true, canonical: false };
+        assert_eq!(sorted.apply(JSON).unwrap(), r#"{"a":{},"b":[1,{"z":"a, \"b\": c"},[]]}"#);
+        let canonical = OutputOptions { canonical: true, ..OutputOptions::default() };
+        assert_eq!(canonical.apply(JSON).unwrap(), sorted.apply(JSON).unwrap());
+
+        let indented = OutputOptions { indent: Some(2), canonical: true, ..OutputOptions::default() };
+        assert!(indented.apply(JSON).is_err());
+        assert!(canonical.apply("# not json").is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! Lint rules over documents
+//!
+//! Beyond their syntax, documents are checked by named rules, such as
+//! `yaml/no-duplicate-keys` or `toml/prefer-dotted-keys`, each listed in
+//! [`RULES`] with the formats it applies to and its default [`Severity`].
+//! A [`LintConfig`] changes severities, or turns rules off, for every
+//! document and for the documents of workspaces, matched by globs over URI
+//! paths as the documents of a schema are. Each [`Finding`] names its rule,
+//! so editors can offer to disable it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+use super::schema::LineColumn;
+use super::{csv, yaml};
+use crate::core::Format;
+
+/// How much a finding matters, from a rule turned off to an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Off,
+    Hint,
+    Info,
+    Warning,
+    Error,
+}
+
+/// A problem a rule found, before its severity is known
+struct Found {
+    message: String,
+    /// Bytes of the document the problem lies in, `None` for the whole document
+    span: Option<Range<usize>>,
+}
+
+impl Found {
+    fn document(message: impl Into<String>) -> Self {
+        Self { message: message.into(), span: None }
+    }
+
+    fn at(span: Range<usize>, message: impl Into<String>) -> Self {
+        Self { message: message.into(), span: Some(span) }
+    }
+}
+
+/// A named check of the documents of some formats
+#[derive(Debug, Clone, Serialize)]
+pub struct Rule {
+    /// Name configuration and findings give it, the format first, such as `yaml/no-duplicate-keys`
+    pub id: &'static str,
+    /// Formats whose documents it checks
+    #[serde(serialize_with = "format_names")]
+    pub formats: &'static [Format],
+    /// Severity unless configured otherwise
+    pub severity: Severity,
+    pub description: &'static str,
+    #[serde(skip)]
+    check: fn(&str, Format) -> Vec<Found>,
+}
+
+fn format_names<S: serde::Serializer>(formats: &&'static [Format], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(formats.iter().map(Format::extension))
+}
+
+/// Every rule, in the order their findings are reported
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "markdown/no-empty-document",
+        formats: &[Format::Markdown],
+        severity: Severity::Warning,
+        description: "The document has no content",
+        check: |content, _| empty(content.is_empty(), "Document is empty"),
+    },
+    Rule {
+        id: "yaml/no-empty-document",
+        formats: &[Format::Yaml],
+        severity: Severity::Warning,
+        description: "The document holds nothing but whitespace",
+        check: |content, _| empty(content.trim().is_empty(), "YAML document is empty"),
+    },
+    Rule {
+        id: "yaml/no-duplicate-keys",
+        formats: &[Format::Yaml],
+        severity: Severity::Error,
+        description: "A mapping gives the same key twice, which YAML forbids and conversion refuses",
+        check: duplicate_yaml_keys,
+    },
+    Rule {
+        id: "json/no-duplicate-keys",
+        formats: &[Format::Json],
+        severity: Severity::Warning,
+        description: "An object gives the same key twice, of which only the last value is read",
+        check: duplicate_json_keys,
+    },
+    Rule {
+        id: "toml/no-empty-document",
+        formats: &[Format::Toml],
+        severity: Severity::Warning,
+        description: "The document holds nothing but whitespace",
+        check: |content, _| empty(content.trim().is_empty(), "TOML document is empty"),
+    },
+    Rule {
+        id: "toml/prefer-dotted-keys",
+        formats: &[Format::Toml],
+        severity: Severity::Off,
+        description: "A table holds a single value, which a dotted key gives in one line",
+        check: single_value_tables,
+    },
+    Rule {
+        id: "xml/no-empty-document",
+        formats: &[Format::Xml],
+        severity: Severity::Warning,
+        description: "The document holds nothing but whitespace",
+        check: |content, _| empty(content.trim().is_empty(), "XML document is empty"),
+    },
+    Rule {
+        id: "xml/require-declaration",
+        formats: &[Format::Xml],
+        severity: Severity::Warning,
+        description: "The document does not open with an XML declaration",
+        check: |content, _| empty(!content.trim().is_empty() && !content.starts_with("<?xml"), "Missing XML declaration"),
+    },
+    Rule {
+        id: "csv/no-empty-document",
+        formats: &[Format::Csv, Format::Tsv],
+        severity: Severity::Warning,
+        description: "The document holds nothing but whitespace",
+        check: |content, _| empty(content.trim().is_empty(), "CSV document is empty"),
+    },
+    Rule {
+        id: "csv/consistent-columns",
+        formats: &[Format::Csv, Format::Tsv],
+        severity: Severity::Warning,
+        description: "A row has more or fewer fields than the header, or the first row",
+        check: ragged_rows,
+    },
+];
+
+/// The rule called `id`
+#[must_use]
+pub fn rule(id: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|rule| rule.id == id)
+}
+
+/// Severities of rules, by ID, overriding their defaults
+pub type Severities = BTreeMap<String, Severity>;
+
+/// Rule severities for every document, and for the documents of workspaces
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LintConfig {
+    /// Severities for every document
+    pub rules: Severities,
+    /// Severities for the documents of each workspace, over those for every document; later workspaces win
+    pub workspaces: Vec<WorkspaceRules>,
+}
+
+/// Rule severities for the documents of one workspace
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceRules {
+    /// Globs over URI paths of the workspace's documents; one without `/` matches the file name
+    pub documents: Vec<String>,
+    pub rules: Severities,
+}
+
+impl LintConfig {
+    /// The severity of `rule` for the document at `uri`, or for any document when there is none
+    #[must_use]
+    pub fn severity(&self, rule: &Rule, uri: Option<&str>) -> Severity {
+        let workspaces = self
+            .workspaces
+            .iter()
+            .filter(|workspace| uri.is_some_and(|uri| crate::proxy::matches_patterns(&workspace.documents, uri)));
+        workspaces
+            .rev()
+            .find_map(|workspace| workspace.rules.get(rule.id))
+            .or_else(|| self.rules.get(rule.id))
+            .copied()
+            .unwrap_or(rule.severity)
+    }
+}
+
+/// A problem a rule found in a document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    /// ID of the rule, such as `yaml/no-duplicate-keys`
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    /// Bytes the problem lies in, left out when it is the whole document's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Range<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<LineColumn>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<LineColumn>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(start) = self.start {
+            write!(f, "line {}, column {}: ", start.line, start.column)?;
+        }
+        write!(f, "{} [{}]", self.message, self.rule)
+    }
+}
+
+/// Check `content`, a document of `format`, with every rule `config` leaves on for the document at `uri`
+#[must_use]
+pub fn lint(content: &str, format: Format, config: &LintConfig, uri: Option<&str>) -> Vec<Finding> {
+    let position = |offset| {
+        let (line, column) = super::line_column(content, offset);
+        LineColumn { line, column }
+    };
+    let mut findings = Vec::new();
+    for rule in RULES.iter().filter(|rule| rule.formats.contains(&format)) {
+        let severity = config.severity(rule, uri);
+        if severity == Severity::Off {
+            continue;
+        }
+        findings.extend((rule.check)(content, format).into_iter().map(|found| Finding {
+            rule: rule.id.to_string(),
+            severity,
+            message: found.message,
+            start: found.span.as_ref().map(|span| position(span.start)),
+            end: found.span.as_ref().map(|span| position(span.end)),
+            span: found.span,
+        }));
+    }
+    findings
+}
+
+/// A finding for the whole document when `found`
+fn empty(found: bool, message: &str) -> Vec<Found> {
+    if found {
+        vec![Found::document(message)]
+    } else {
+        Vec::new()
+    }
+}
+
+/// The key the YAML parser found twice; it stops there, so there is at most one
+fn duplicate_yaml_keys(content: &str, _: Format) -> Vec<Found> {
+    yaml::diagnostics(content)
+        .into_iter()
+        .filter(yaml::YamlDiagnostic::is_duplicate_key)
+        .map(|diagnostic| match diagnostic.span {
+            Some((start, end)) => Found::at(offset(content, start)..offset(content, end), diagnostic.message),
+            None => Found::document(diagnostic.message),
+        })
+        .collect()
+}
+
+/// The byte at a YAML position
+fn offset(content: &str, position: yaml::Position) -> usize {
+    let line_start: usize = content.split_inclusive('\n').take(position.line - 1).map(str::len).sum();
+    let line = content[line_start..].lines().next().unwrap_or_default();
+    line_start + line.char_indices().nth(position.column - 1).map_or(line.len(), |(index, _)| index)
+}
+
+/// Each key a JSON object gives again, at the repeat
+fn duplicate_json_keys(content: &str, _: Format) -> Vec<Found> {
+    if serde_json::from_str::<serde::de::IgnoredAny>(content).is_err() {
+        return Vec::new();
+    }
+    let bytes = content.as_bytes();
+    // The keys of each object open, or `None` for an array
+    let mut open: Vec<Option<HashSet<String>>> = Vec::new();
+    let mut key_next = false;
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' => {
+                open.push(Some(HashSet::new()));
+                key_next = true;
+            }
+            b'[' => open.push(None),
+            b'}' | b']' => {
+                open.pop();
+            }
+            b',' => key_next = matches!(open.last(), Some(Some(_))),
+            b'"' => {
+                let end = string_end(bytes, i);
+                if let (true, Some(Some(keys))) = (key_next, open.last_mut()) {
+                    let key: String = serde_json::from_str(&content[i..end]).unwrap_or_default();
+                    if !keys.insert(key.clone()) {
+                        found.push(Found::at(i..end, format!("Key {key:?} is given again; only its last value is read")));
+                    }
+                }
+                key_next = false;
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    found
+}
+
+/// Where the JSON string opening at `start` ends
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Each table with a header holding a single value and nothing else
+fn single_value_tables(content: &str, _: Format) -> Vec<Found> {
+    let Ok(document) = toml_edit::ImDocument::parse(content) else {
+        return Vec::new();
+    };
+    let mut found = Vec::new();
+    single_value_tables_in(document.as_table(), &mut Vec::new(), &mut found);
+    found
+}
+
+fn single_value_tables_in(table: &toml_edit::Table, path: &mut Vec<String>, found: &mut Vec<Found>) {
+    for (key, item) in table {
+        let Some(child) = item.as_table() else {
+            continue;
+        };
+        path.push(key_repr(table, key));
+        let mut entries = child.iter();
+        if let (Some((only, value)), None, Some(span)) = (entries.next(), entries.next(), child.span()) {
+            if value.is_value() && !child.is_implicit() && !child.is_dotted() {
+                let (table, only) = (path.join("."), key_repr(child, only));
+                let message = format!("Table [{table}] holds only {only}; give it as the dotted key {table}.{only}");
+                found.push(Found::at(span, message));
+            }
+        }
+        single_value_tables_in(child, path, found);
+        path.pop();
+    }
+}
+
+/// `key` of `table` as the document writes it, quoted where it needs to be
+fn key_repr(table: &toml_edit::Table, key: &str) -> String {
+    table.key(key).map_or_else(|| key.to_string(), |key| key.display_repr().into_owned())
+}
+
+/// Each row with more or fewer fields than the first, at its line
+fn ragged_rows(content: &str, format: Format) -> Vec<Found> {
+    let options = if format == Format::Tsv { csv::CsvOptions::tsv() } else { csv::CsvOptions::default() };
+    let Ok(ragged) = csv::diagnostics(content, &options) else {
+        return Vec::new();
+    };
+    let lines: Vec<Range<usize>> = content
+        .split_inclusive('\n')
+        .scan(0, |start, line| {
+            let range = *start..*start + line.trim_end_matches(['\n', '\r']).len();
+            *start += line.len();
+            Some(range)
+        })
+        .collect();
+    ragged
+        .into_iter()
+        .map(|row| {
+            let message = format!("{} fields, where the first row has {}", row.fields, row.expected);
+            match lines.get(row.line - 1) {
+                Some(line) => Found::at(line.clone(), message),
+                None => Found::document(message),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(findings: &[Finding]) -> Vec<(&str, Severity)> {
+        findings.iter().map(|finding| (finding.rule.as_str(), finding.severity)).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = LintConfig::default();
+        let findings = lint("a: 1\nb: 2\na: 3\n", Format::Yaml, &config, None);
+        assert_eq!(rules(&findings), [("yaml/no-duplicate-keys", Severity::Error)]);
+        // The YAML parser places a duplicate key at the start of its mapping
+        assert_eq!(findings[0].start, Some(LineColumn { line: 1, column: 1 }));
+        assert_eq!(rules(&lint("  ", Format::Yaml, &config, None)), [("yaml/no-empty-document", Severity::Warning)]);
+
+        let findings = lint(r#"{"a": {"b": 1, "b": 2}, "c": [{"b": 3}], "a": 4}"#, Format::Json, &config, None);
+        let spans: Vec<_> = findings.iter().map(|finding| finding.span.clone().unwrap()).collect();
+        assert_eq!(spans, [15..18, 41..44]);
+        assert_eq!(findings[0].to_string(), "line 1, column 16: Key \"b\" is given again; only its last value is read [json/no-duplicate-keys]");
+
+        let findings = lint("a,b\n1,2,3\n4,5\n", Format::Csv, &config, None);
+        assert_eq!(findings[0].span, Some(4..9));
+        assert_eq!(findings[0].message, "3 fields, where the first row has 2");
+        assert_eq!(rules(&lint("<a/>", Format::Xml, &config, None)), [("xml/require-declaration", Severity::Warning)]);
+        assert!(lint("[a]\nb = 1\n", Format::Toml, &config, None).is_empty());
+        assert!(lint("{", Format::Json, &config, None).is_empty());
+    }
+
+    #[test]
+    fn test_prefer_dotted_keys() {
+        let config = LintConfig { rules: [("toml/prefer-dotted-keys".to_string(), Severity::Hint)].into(), ..LintConfig::default() };
+        let toml = "[server]\nport = 80\n\n[client.tls]\ncert = \"a\"\nkey = \"b\"\n\n[log.\"file name\"]\npath = \"x\"\n";
+        let findings = lint(toml, Format::Toml, &config, None);
+        let messages: Vec<_> = findings.iter().map(|finding| finding.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Table [server] holds only port; give it as the dotted key server.port",
+                "Table [log.\"file name\"] holds only path; give it as the dotted key log.\"file name\".path",
+            ]
+        );
+        assert_eq!(findings[0].span, Some(0..18));
+    }
+
+    #[test]
+    fn test_workspace_severities() {
+        let config: LintConfig = ::toml::from_str(
+            r#"
+            rules = { "json/no-duplicate-keys" = "error", "markdown/no-empty-document" = "off" }
+
+            [[workspaces]]
+            documents = ["/work/**"]
+            rules = { "json/no-duplicate-keys" = "hint" }
+
+            [[workspaces]]
+            documents = ["/work/legacy/**"]
+            rules = { "json/no-duplicate-keys" = "off" }
+            "#,
+        )
+        .unwrap();
+        let duplicated = r#"{"a": 1, "a": 2}"#;
+        let severity = |uri| rules(&lint(duplicated, Format::Json, &config, uri)).first().map(|(_, severity)| *severity);
+        assert_eq!(severity(None), Some(Severity::Error));
+        assert_eq!(severity(Some("file:///elsewhere/a.json")), Some(Severity::Error));
+        assert_eq!(severity(Some("file:///work/legacy/a.json")), None);
+        assert_eq!(severity(Some("file:///work/new/a.json")), Some(Severity::Hint));
+        assert!(lint("", Format::Markdown, &config, None).is_empty());
+    }
+}
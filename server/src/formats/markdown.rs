@@ -0,0 +1,349 @@
+//! Markdown front matter
+//!
+//! Static site generators read a page's metadata from the top of its
+//! Markdown: YAML between `---` lines, the closing one also `...`, or TOML
+//! between `+++` lines. [`parse`] splits a document into its
+//! [`FrontMatter`], read as JSON, and its body, and [`render`] writes one
+//! back, so that [`document_to_json`] and [`json_to_document`] move pages
+//! between files and structured data. The body is kept byte for byte.
+//!
+//! Front matter must be a mapping; an empty block reads as an empty one.
+//! A leading `---` with no closing line is a thematic break, not front
+//! matter. [`validate`] checks the front matter against a [`Schema`],
+//! placing each violation in the whole document, and checks a document
+//! without any as an empty mapping.
+
+use super::schema::{self, LineColumn, Schema, SchemaDiagnostic};
+use crate::core::Format;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// How front matter is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontMatterFormat {
+    /// Between `---` lines
+    Yaml,
+    /// Between `+++` lines
+    Toml,
+}
+
+impl FrontMatterFormat {
+    /// The line opening and closing the block
+    #[must_use]
+    pub fn delimiter(&self) -> &'static str {
+        match self {
+            Self::Yaml => "---",
+            Self::Toml => "+++",
+        }
+    }
+
+    /// The format the block is written in
+    #[must_use]
+    pub fn format(&self) -> Format {
+        match self {
+            Self::Yaml => Format::Yaml,
+            Self::Toml => Format::Toml,
+        }
+    }
+}
+
+/// The metadata at the top of a document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrontMatter {
+    pub format: FrontMatterFormat,
+    /// The mapping it holds, as JSON
+    pub data: Value,
+}
+
+/// A Markdown document split into its front matter and body
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    #[serde(default)]
+    pub front_matter: Option<FrontMatter>,
+    /// Everything after the closing line, or the whole document when there is no front matter
+    #[serde(default)]
+    pub body: String,
+}
+
+/// Front matter as found in the text, before it is parsed
+struct Block<'a> {
+    format: FrontMatterFormat,
+    /// Between the delimiter lines
+    text: &'a str,
+    /// Byte offset of `text` in the document
+    offset: usize,
+    body: &'a str,
+}
+
+/// Find the front matter block opening `text`, if there is one
+fn split(text: &str) -> Option<Block<'_>> {
+    let first = text.split_inclusive('\n').next()?;
+    let format = match first.trim_end() {
+        "---" => FrontMatterFormat::Yaml,
+        "+++" => FrontMatterFormat::Toml,
+        _ => return None,
+    };
+    let offset = first.len();
+    let mut end = offset;
+    for line in text[offset..].split_inclusive('\n') {
+        let closing = line.trim_end();
+        if closing == format.delimiter() || (format == FrontMatterFormat::Yaml && closing == "...") {
+            return Some(Block { format, text: &text[offset..end], offset, body: &text[end + line.len()..] });
+        }
+        end += line.len();
+    }
+    None
+}
+
+/// The body of `text`, without its front matter
+#[must_use]
+pub fn body(text: &str) -> &str {
+    split(text).map_or(text, |block| block.body)
+}
+
+/// Split `text` into its front matter, parsed, and its body
+///
+/// # Errors
+///
+/// Fails where the front matter does not parse as YAML or TOML.
+pub fn parse(text: &str) -> Result<Document> {
+    let Some(block) = split(text) else {
+        return Ok(Document { front_matter: None, body: text.to_string() });
+    };
+    let data = read(&block).map_err(|message| anyhow!("Invalid front matter: {message}"))?;
+    Ok(Document { front_matter: Some(FrontMatter { format: block.format, data }), body: block.body.to_string() })
+}
+
+/// Parse a block's mapping, or describe why it cannot be, at lines of the whole document
+fn read(block: &Block<'_>) -> std::result::Result<Value, String> {
+    // The block's line 1 is the document's line 2, after the opening delimiter
+    let json = match block.format {
+        FrontMatterFormat::Yaml => super::yaml::yaml_to_json(block.text).map_err(|e| {
+            super::yaml::diagnostics(block.text)
+                .into_iter()
+                .next().map_or_else(|| e.to_string(), |found| match found.span {
+                    Some((start, _)) => format!("line {}, column {}: {}", start.line + 1, start.column, found.message),
+                    None => found.message,
+                })
+        })?,
+        FrontMatterFormat::Toml => super::toml::toml_to_json(block.text).map_err(|e| {
+            super::toml::diagnostics(block.text)
+                .into_iter()
+                .next().map_or_else(|| e.to_string(), |found| match found.position {
+                    Some((line, column)) => format!("line {}, column {}: {}", line + 1, column, found.message),
+                    None => found.message,
+                })
+        })?,
+    };
+    match serde_json::from_str(&json).map_err(|e| e.to_string())? {
+        Value::Null => Ok(Value::Object(Map::new())),
+        Value::Object(map) => Ok(Value::Object(map)),
+        other => Err(format!("front matter must be a mapping, not {}", kind(&other))),
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Write `document` back as Markdown, its front matter first
+///
+/// # Errors
+///
+/// Fails where the front matter cannot be written in its format, as with a
+/// TOML null.
+pub fn render(document: &Document) -> Result<String> {
+    let Some(front_matter) = &document.front_matter else {
+        return Ok(document.body.clone());
+    };
+    let data = match &front_matter.data {
+        Value::Object(map) if map.is_empty() => String::new(),
+        Value::Object(_) => {
+            let json = front_matter.data.to_string();
+            let mut data = match front_matter.format {
+                FrontMatterFormat::Yaml => super::yaml::json_to_yaml(&json)?,
+                FrontMatterFormat::Toml => super::toml::json_to_toml(&json)?,
+            };
+            if !data.ends_with('\n') {
+                data.push('\n');
+            }
+            data
+        }
+        other => return Err(anyhow!("Front matter must be a mapping, not {}", kind(other))),
+    };
+    let delimiter = front_matter.format.delimiter();
+    Ok(format!("{delimiter}\n{data}{delimiter}\n{}", document.body))
+}
+
+/// Convert Markdown to JSON holding its front matter and body
+///
+/// # Errors
+///
+/// Fails where the front matter does not parse.
+pub fn document_to_json(markdown: &str) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&parse(markdown)?)?)
+}
+
+/// Convert JSON holding front matter and a body to Markdown
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or its front matter cannot be
+/// written.
+pub fn json_to_document(json: &str) -> Result<String> {
+    let document: Document = serde_json::from_str(json).map_err(|e| anyhow!("Invalid document: {e}"))?;
+    render(&document)
+}
+
+/// Validate front matter syntax, returning any errors
+///
+/// # Errors
+///
+/// Never; a front matter that does not parse is returned as the one
+/// problem.
+pub fn validate_front_matter(markdown: &str) -> Result<Vec<String>> {
+    Ok(parse(markdown).err().into_iter().map(|e| e.to_string()).collect())
+}
+
+/// Check the front matter of `markdown` against `schema`, placing violations in the whole document
+///
+/// # Errors
+///
+/// Fails where the front matter does not parse.
+pub fn validate(markdown: &str, schema: &Schema) -> Result<Vec<SchemaDiagnostic>> {
+    let position = |offset| {
+        let (line, column) = super::line_column(markdown, offset);
+        LineColumn { line, column }
+    };
+    // Without front matter, or with an empty block, violations sit where it starts
+    let empty = |offset: usize| -> Vec<SchemaDiagnostic> {
+        schema
+            .validate(&Value::Object(Map::new()))
+            .into_iter()
+            .map(|violation| SchemaDiagnostic { violation, span: offset..offset, start: position(offset), end: position(offset) })
+            .collect()
+    };
+    let Some(block) = split(markdown) else {
+        return Ok(empty(0));
+    };
+    parse(markdown)?;
+    if block.text.trim().is_empty() {
+        return Ok(empty(block.offset));
+    }
+    let found = schema::validate(block.text, block.format.format(), schema)?;
+    Ok(found
+        .into_iter()
+        .map(|found| {
+            let span = found.span.start + block.offset..found.span.end + block.offset;
+            SchemaDiagnostic { start: position(span.start), end: position(span.end), span, violation: found.violation }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_front_matter() {
+        let yaml = "---\ntitle: Hello\ntags: [a, b]\n---\n# Hello\n";
+        let document = parse(yaml).unwrap();
+        assert_eq!(
+            document.front_matter,
+            Some(FrontMatter { format: FrontMatterFormat::Yaml, data: json!({"title": "Hello", "tags": ["a", "b"]}) })
+        );
+        assert_eq!(document.body, "# Hello\n");
+        assert_eq!(body(yaml), "# Hello\n");
+
+        let toml = "+++\ntitle = \"Hello\"\ndraft = true\n+++\nBody";
+        let document = parse(toml).unwrap();
+        assert_eq!(document.front_matter.unwrap().data, json!({"title": "Hello", "draft": true}));
+        assert_eq!(document.body, "Body");
+
+        // YAML may close with `...`, and an empty block is an empty mapping
+        assert_eq!(parse("---\na: 1\n...\nx").unwrap().body, "x");
+        assert_eq!(parse("---\n---\nx").unwrap().front_matter.unwrap().data, json!({}));
+
+        // A thematic break with nothing closing it is body
+        for text in ["---\n# Not front matter\n", "# Title\n---\na: 1\n---\n", ""] {
+            assert_eq!(parse(text).unwrap(), Document { front_matter: None, body: text.to_string() });
+        }
+    }
+
+    #[test]
+    fn test_invalid_front_matter() {
+        let error = parse("---\ntitle: Hello\n  bad: [\n---\n").unwrap_err().to_string();
+        assert!(error.starts_with("Invalid front matter: line 3"), "{error}");
+
+        let error = parse("+++\ntitle = \"Hello\"\ntitle = \"Again\"\n+++\n").unwrap_err().to_string();
+        assert!(error.starts_with("Invalid front matter: line 3"), "{error}");
+
+        let error = parse("---\n- a\n- b\n---\n").unwrap_err().to_string();
+        assert_eq!(error, "Invalid front matter: front matter must be a mapping, not an array");
+
+        assert_eq!(validate_front_matter("---\na: 1\n---\n").unwrap(), Vec::<String>::new());
+        assert_eq!(validate_front_matter("---\n- a\n---\n").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        for text in ["---\ntitle: Hello\n---\n\n# Hello\n", "+++\ntitle = \"Hello\"\n+++\nBody", "No front matter\n", "---\n---\nx"] {
+            assert_eq!(render(&parse(text).unwrap()).unwrap(), text);
+        }
+
+        let json = document_to_json("---\ntitle: Hello\n---\nBody\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&json).unwrap(),
+            json!({"front_matter": {"format": "yaml", "data": {"title": "Hello"}}, "body": "Body\n"})
+        );
+        assert_eq!(json_to_document(&json).unwrap(), "---\ntitle: Hello\n---\nBody\n");
+
+        let toml = json!({"front_matter": {"format": "toml", "data": {"title": "Post", "weight": 2}}, "body": "Text\n"});
+        assert_eq!(json_to_document(&toml.to_string()).unwrap(), "+++\ntitle = \"Post\"\nweight = 2\n+++\nText\n");
+        assert_eq!(json_to_document(r#"{"body": "Only"}"#).unwrap(), "Only");
+
+        let scalar = json!({"front_matter": {"format": "yaml", "data": 1}, "body": ""});
+        assert!(json_to_document(&scalar.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validate_against_schema() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "required": ["title"],
+            "properties": {"draft": {"type": "boolean"}},
+        }))
+        .unwrap();
+
+        let text = "---\ntitle: Hello\ndraft: yes please\n---\nBody\n";
+        let found = validate(text, &schema).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].violation.instance_path, "/draft");
+        assert_eq!((found[0].start.line, found[0].start.column), (3, 8));
+
+        let toml = "+++\ndraft = 1\n+++\n";
+        let found = validate(toml, &schema).unwrap();
+        let keywords: Vec<_> = found.iter().map(|found| found.violation.keyword.as_str()).collect();
+        assert_eq!(keywords, ["type", "required"]);
+        assert_eq!(&toml[found[0].span.clone()], "1");
+
+        // Without front matter, the document is checked as an empty mapping
+        let found = validate("# Intro\n", &schema).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].span, 0..0);
+
+        assert!(validate("---\n- a\n---\n", &schema).is_err());
+        assert!(schema::supports(Format::Markdown));
+        assert_eq!(schema::validate("---\ntitle: x\n---\n", Format::Markdown, &schema).unwrap(), Vec::new());
+    }
+}
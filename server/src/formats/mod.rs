@@ -1,12 +1,72 @@
 //! Extended format support
 //!
-//! Provides conversion support for YAML, XML, and TOML formats.
+//! Provides conversion support for YAML, XML, and TOML formats, and the
+//! [`Formats`] entry point through which every transport converts and
+//! validates documents, including formats added by [`plugins`] and checks
+//! against JSON Schemas in [`schema`]. [`markdown`] reads and writes the
+//! front matter of Markdown documents, and [`protobuf`] converts messages
+//! with the descriptors registered in [`Formats::descriptors`], as [`avro`]
+//! reads records with the schemas in [`Formats::avro_schemas`]. Built-in
+//! formats convert through the document model of [`model`], and [`pretty`]
+//! lays out documents for [`Formats::format_document`]. [`diff`] compares
+//! documents through the same model for [`Formats::diff`], and [`stream`]
+//! converts documents too large to hold a record at a time. Validation
+//! checks documents with the rules of [`lint`] beyond their syntax, and
+//! [`infer`] drafts a schema from sample documents for
+//! [`Formats::infer_schema`]. [`query`] evaluates `JSONPath` and `JMESPath`
+//! over the model, and `XPath` over XML, for [`Formats::query`].
+//! [`datetime`] gives the model's date-times, and the forms they are
+//! written in.
 
 pub mod yaml;
 pub mod xml;
 pub mod toml;
+pub mod csv;
+pub mod ini;
+pub mod dotenv;
+pub mod json5;
+pub mod msgpack;
+pub mod cbor;
+pub mod plist;
+pub mod protobuf;
+pub mod avro;
+pub mod ndjson;
+pub mod markdown;
+pub mod model;
+pub mod datetime;
+pub mod pretty;
+pub mod query;
+pub mod diff;
+pub mod infer;
+pub mod lint;
+pub mod stream;
+pub mod layout;
+pub mod plugins;
+pub mod schema;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
 
-use anyhow::Result;
+use crate::core::{ConversionCore, ConversionOptions, ConversionRequest, ConversionResponse, Format};
+use crate::monitoring::slow_ops::{OpKind, Operation, SlowOps};
+use crate::telemetry;
+use self::avro::AvroSchemas;
+use self::diff::Change;
+use self::infer::InferOptions;
+use self::lint::{Finding, LintConfig};
+use self::plugins::{FormatPlugin, FormatRegistry};
+use self::protobuf::DescriptorRegistry;
+use self::query::{QueryLanguage, QueryResult};
+use self::schema::{Schema, SchemaDiagnostic, SchemaRegistry};
+use self::stream::{RecordTooLarge, Streamed};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info_span, Instrument};
+
+pub use self::layout::OutputOptions;
+pub use self::pretty::FormatOptions;
 
 /// Extended format enumeration
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,6 +78,7 @@ pub enum ExtendedFormat {
 
 impl ExtendedFormat {
     /// Parse format from string
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
             "yaml" | "yml" => Ok(Self::Yaml),
@@ -44,11 +105,776 @@ pub fn validate(&self, content: &str) -> Result<Vec<String>> {
             Self::Toml => toml::validate_toml(content),
         }
     }
+
+    /// Convert a document of this format to JSON
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` does not parse as this format, or holds a value
+    /// JSON cannot.
+    pub fn to_json(&self, content: &str) -> Result<String> {
+        match self {
+            Self::Yaml => yaml::yaml_to_json(content),
+            Self::Xml => xml::xml_to_json(content),
+            Self::Toml => toml::toml_to_json(content),
+        }
+    }
+
+    /// Convert JSON to a document of this format
+    ///
+    /// # Errors
+    ///
+    /// Fails where `json` does not parse, or has no form in this format.
+    pub fn from_json(&self, json: &str) -> Result<String> {
+        match self {
+            Self::Yaml => yaml::json_to_yaml(json),
+            Self::Xml => xml::json_to_xml(json),
+            Self::Toml => toml::json_to_toml(json),
+        }
+    }
+}
+
+/// Line and column, both counted from 1, of the byte at `offset` in `text`
+pub(crate) fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = text.get(..offset).unwrap_or(text);
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Caps on the documents accepted by [`Formats`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct FormatLimits {
+    /// Largest document accepted for conversion or validation, in bytes
+    pub max_input_bytes: usize,
+    /// Largest conversion result returned, in bytes
+    pub max_output_bytes: usize,
+}
+
+impl Default for FormatLimits {
+    fn default() -> Self {
+        Self {
+            max_input_bytes: 16 * 1024 * 1024,
+            max_output_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Which of the [`FormatLimits`] refused a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    InputSize,
+    OutputSize,
+}
+
+impl LimitKind {
+    /// Label value used in metrics
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InputSize => "input_size",
+            Self::OutputSize => "output_size",
+        }
+    }
+}
+
+/// Result of a validation run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationOutcome {
+    /// No diagnostics
+    Clean,
+    /// The document was checked and diagnostics were reported
+    Warnings,
+    /// The document could not be checked
+    Errors,
+}
+
+impl ValidationOutcome {
+    /// Label value used in metrics
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Clean => "clean",
+            Self::Warnings => "warnings",
+            Self::Errors => "errors",
+        }
+    }
+}
+
+/// Hook told about every conversion and validation run through [`Formats`]
+pub trait FormatObserver: Send + Sync {
+    /// A conversion finished, successfully or not
+    fn conversion(&self, from: Format, to: Format, elapsed: Duration, result: &Result<ConversionResponse>);
+
+    /// A streamed conversion finished, successfully or not
+    fn streamed_conversion(&self, from: Format, to: Format, elapsed: Duration, result: &Result<Streamed>);
+
+    /// A validation finished
+    fn validation(&self, format: Format, elapsed: Duration, outcome: ValidationOutcome);
+
+    /// A document was refused by a limit
+    fn limit_exceeded(&self, limit: LimitKind);
+}
+
+/// Observer of [`Formats::standalone`], which reports nowhere
+struct Unobserved;
+
+impl FormatObserver for Unobserved {
+    fn conversion(&self, _: Format, _: Format, _: Duration, _: &Result<ConversionResponse>) {}
+
+    fn streamed_conversion(&self, _: Format, _: Format, _: Duration, _: &Result<Streamed>) {}
+
+    fn validation(&self, _: Format, _: Duration, _: ValidationOutcome) {}
+
+    fn limit_exceeded(&self, _: LimitKind) {}
+}
+
+/// Guess the format of `content` from what it looks like
+///
+/// JSON is recognized by an opening brace, or an array that parses, TOML by
+/// parsing, HTML and XML by their opening tag, and YAML by its document
+/// marker or a leading `key:` line. Anything else is taken for Markdown.
+#[must_use]
+pub fn detect(content: &str) -> Format {
+    let trimmed = content.trim_start();
+    // Markdown may open with a link, but not with a brace: broken JSON is still JSON
+    if trimmed.starts_with('{')
+        || (trimmed.starts_with('[') && serde_json::from_str::<serde::de::IgnoredAny>(trimmed).is_ok())
+    {
+        return Format::Json;
+    }
+    if trimmed.starts_with('<') {
+        let head = trimmed.get(..trimmed.len().min(64)).unwrap_or(trimmed).to_ascii_lowercase();
+        return if head.starts_with("<!doctype html") || head.starts_with("<html") { Format::Html } else { Format::Xml };
+    }
+    if trimmed.starts_with("---") {
+        return Format::Yaml;
+    }
+    let first = trimmed.lines().next().unwrap_or_default();
+    if !first.starts_with('#') && ::toml::from_str::<::toml::Table>(content).is_ok_and(|table| !table.is_empty()) {
+        return Format::Toml;
+    }
+    let key = first.split_once(':').map(|(key, _)| key);
+    if key.is_some_and(|key| !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || "_-".contains(c))) {
+        return Format::Yaml;
+    }
+    Format::Markdown
+}
+
+/// A format named on the wire: built in, or provided by a plugin
+#[derive(Clone)]
+pub enum FormatRef {
+    BuiltIn(Format),
+    Plugin(Arc<dyn FormatPlugin>),
+}
+
+impl FormatRef {
+    /// Name used on the wire
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::BuiltIn(format) => format.name(),
+            Self::Plugin(plugin) => plugin.name(),
+        }
+    }
+}
+
+impl std::fmt::Debug for FormatRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BuiltIn(format) => f.debug_tuple("BuiltIn").field(format).finish(),
+            Self::Plugin(plugin) => f.debug_tuple("Plugin").field(&plugin.name()).finish(),
+        }
+    }
+}
+
+/// Conversion and validation entry point shared by HTTP, LSP and WebSocket
+///
+/// Each call is reported to the observer exactly once. Conversions chained
+/// internally through [`ConversionCore`] are part of the outer call and are
+/// not reported separately. A document over the input limit is refused
+/// before any work is done and is reported only as a limit rejection.
+/// Conversions to or from a plugin format report their built-in leg, if any.
+#[derive(Clone)]
+pub struct Formats {
+    /// Shared by clones, so new limits reach every one
+    limits: Arc<RwLock<FormatLimits>>,
+    observer: Arc<dyn FormatObserver>,
+    slow_ops: Option<Arc<SlowOps>>,
+    /// Shared by clones, so every one sees plugins registered later
+    plugins: Arc<FormatRegistry>,
+    /// Shared by clones, like the plugins
+    schemas: Arc<SchemaRegistry>,
+    /// Shared by clones, like the plugins
+    descriptors: Arc<DescriptorRegistry>,
+    /// Shared by clones, like the plugins
+    avro_schemas: Arc<AvroSchemas>,
+    /// Shared by clones, like the limits
+    lint: Arc<RwLock<LintConfig>>,
+}
+
+impl Formats {
+    /// Create the entry point, reporting to `observer`
+    pub fn new(limits: FormatLimits, observer: Arc<dyn FormatObserver>) -> Self {
+        Self {
+            limits: Arc::new(RwLock::new(limits)),
+            observer,
+            slow_ops: None,
+            plugins: Arc::new(FormatRegistry::new()),
+            schemas: Arc::new(SchemaRegistry::new()),
+            descriptors: Arc::new(DescriptorRegistry::new()),
+            avro_schemas: Arc::new(AvroSchemas::new()),
+            lint: Arc::new(RwLock::new(LintConfig::default())),
+        }
+    }
+
+    /// Create the entry point outside a server, reporting to nothing
+    #[must_use]
+    pub fn standalone(limits: FormatLimits) -> Self {
+        Self::new(limits, Arc::new(Unobserved))
+    }
+
+    /// Also report calls slower than their threshold to `slow_ops`
+    #[must_use]
+    pub fn with_slow_ops(mut self, slow_ops: Arc<SlowOps>) -> Self {
+        self.slow_ops = Some(slow_ops);
+        self
+    }
+
+    /// Limits applied to every document
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the limits' lock.
+    #[must_use]
+    pub fn limits(&self) -> FormatLimits {
+        self.limits.read().expect("format limits lock poisoned").clone()
+    }
+
+    /// Apply `limits` to every document from now on
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the limits' lock.
+    pub fn set_limits(&self, limits: FormatLimits) {
+        *self.limits.write().expect("format limits lock poisoned") = limits;
+    }
+
+    /// Severities of the lint rules documents are checked with
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the lint settings' lock.
+    #[must_use]
+    pub fn lint_config(&self) -> LintConfig {
+        self.lint.read().expect("lint config lock poisoned").clone()
+    }
+
+    /// Check every document with the rule severities of `config` from now on
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the lint settings' lock.
+    pub fn set_lint_config(&self, config: LintConfig) {
+        *self.lint.write().expect("lint config lock poisoned") = config;
+    }
+
+    /// Formats added by plugins
+    #[must_use]
+    pub fn plugins(&self) -> &FormatRegistry {
+        &self.plugins
+    }
+
+    /// JSON Schemas documents are checked against
+    #[must_use]
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+
+    /// Protobuf descriptors messages are converted and validated with
+    #[must_use]
+    pub fn descriptors(&self) -> &DescriptorRegistry {
+        &self.descriptors
+    }
+
+    /// Avro schemas records are converted and validated with
+    #[must_use]
+    pub fn avro_schemas(&self) -> &AvroSchemas {
+        &self.avro_schemas
+    }
+
+    /// The built-in or plugin format called `name`
+    ///
+    /// # Errors
+    ///
+    /// Fails where no built-in format or plugin is called `name`.
+    pub fn resolve(&self, name: &str) -> Result<FormatRef> {
+        if let Ok(format) = Format::from_str(name) {
+            return Ok(FormatRef::BuiltIn(format));
+        }
+        self.plugins.get(name).map(FormatRef::Plugin).ok_or_else(|| anyhow!("Unsupported format: {name}"))
+    }
+
+    /// Convert a document between any two formats, built-in or plugin
+    ///
+    /// Plugin formats go through canonical JSON: the source is converted to
+    /// JSON, and JSON to the target.
+    ///
+    /// # Errors
+    ///
+    /// Fails where either side is past the size limits, or the conversion or
+    /// plugin fails.
+    pub fn convert_any(&self, content: &str, from: &FormatRef, to: &FormatRef) -> Result<String> {
+        if let (FormatRef::BuiltIn(from), FormatRef::BuiltIn(to)) = (from, to) {
+            return Ok(self.convert(ConversionRequest { content: content.to_string(), from: *from, to: *to })?.content);
+        }
+        let _span = info_span!(
+            "format.convert_plugin",
+            format.from = from.name(),
+            format.to = to.name(),
+            size = telemetry::size_bucket(content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, content.len())?;
+
+        let canonical = match from {
+            FormatRef::BuiltIn(Format::Json) => content.to_string(),
+            FormatRef::BuiltIn(format) => {
+                let request = ConversionRequest { content: content.to_string(), from: *format, to: Format::Json };
+                self.convert(request)?.content
+            }
+            FormatRef::Plugin(plugin) => {
+                let canonical = text(plugin.name(), plugin.to_canonical(content.as_bytes())?)?;
+                serde_json::from_str::<serde_json::Value>(&canonical)
+                    .with_context(|| format!("Plugin {} produced invalid JSON", plugin.name()))?;
+                canonical
+            }
+        };
+        let output = match to {
+            FormatRef::BuiltIn(Format::Json) => canonical,
+            FormatRef::BuiltIn(format) => {
+                self.convert(ConversionRequest { content: canonical, from: Format::Json, to: *format })?.content
+            }
+            FormatRef::Plugin(plugin) => text(plugin.name(), plugin.from_canonical(canonical.as_bytes())?)?,
+        };
+        self.check(LimitKind::OutputSize, output.len())?;
+        Ok(output)
+    }
+
+    /// Validate a document of any format, built-in or plugin
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` is past the input limit, or the plugin fails.
+    pub fn validate_any(&self, content: &str, format: &FormatRef) -> Result<Vec<String>> {
+        match format {
+            FormatRef::BuiltIn(format) => self.validate(content, *format),
+            FormatRef::Plugin(plugin) => {
+                let _span = info_span!(
+                    "format.validate_plugin",
+                    format = plugin.name(),
+                    size = telemetry::size_bucket(content.len()),
+                )
+                .entered();
+                self.check(LimitKind::InputSize, content.len())?;
+                plugin.validate(content.as_bytes())
+            }
+        }
+    }
+
+    /// Convert a document between formats
+    ///
+    /// # Errors
+    ///
+    /// As [`Formats::convert_with`] does.
+    pub fn convert(&self, request: ConversionRequest) -> Result<ConversionResponse> {
+        self.convert_with(request, &ConversionOptions::default())
+    }
+
+    /// Convert a document, keeping the comments, blank lines and key order of `original`, an earlier version of the result
+    ///
+    /// # Errors
+    ///
+    /// As [`Formats::convert_with`] does.
+    pub fn convert_onto(&self, request: ConversionRequest, original: &str) -> Result<ConversionResponse> {
+        self.convert_with(request, &ConversionOptions { original: Some(original.to_string()), ..Default::default() })
+    }
+
+    /// Convert a document, writing it as `options` choose
+    ///
+    /// # Errors
+    ///
+    /// Fails where the content or result is past the size limits, or the
+    /// content does not convert.
+    pub fn convert_with(&self, request: ConversionRequest, options: &ConversionOptions) -> Result<ConversionResponse> {
+        let _span = info_span!(
+            "format.convert",
+            format.from = request.from.extension(),
+            format.to = request.to.extension(),
+            size = telemetry::size_bucket(request.content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, request.content.len())?;
+
+        let (from, to, size) = (request.from, request.to, request.content.len());
+        let start = Instant::now();
+        let mut result = ConversionCore::convert_with(request, options);
+        if let Ok(response) = &result {
+            if let Err(e) = self.check(LimitKind::OutputSize, response.content.len()) {
+                result = Err(e);
+            }
+        }
+        let elapsed = start.elapsed();
+        self.observer.conversion(from, to, elapsed, &result);
+        self.report_slow(
+            Operation::new(OpKind::Conversion, "convert")
+                .size(size)
+                .format(from.extension())
+                .option("from", from.extension())
+                .option("to", to.extension()),
+            elapsed,
+        );
+        result
+    }
+
+    /// Convert the records of `reader`, a document of `from`, to `writer` as `to`, a record at a time
+    ///
+    /// As only a record is held, the input limit applies to each record
+    /// rather than the whole document, and there is no output limit.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the pair does not stream, a record is invalid or over the
+    /// input limit, or reading or writing fails.
+    pub async fn convert_stream<R, W>(&self, reader: R, from: Format, to: Format, writer: W) -> Result<Streamed>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let span = info_span!("format.convert_stream", format.from = from.extension(), format.to = to.extension());
+        let start = Instant::now();
+        let result = stream::convert_async(reader, from, to, writer, self.limits().max_input_bytes).instrument(span).await;
+        let elapsed = start.elapsed();
+        if result.as_ref().is_err_and(anyhow::Error::is::<RecordTooLarge>) {
+            self.observer.limit_exceeded(LimitKind::InputSize);
+        }
+        self.observer.streamed_conversion(from, to, elapsed, &result);
+        self.report_slow(
+            Operation::new(OpKind::Conversion, "convert_stream")
+                .size(result.as_ref().map_or(0, |streamed| streamed.read))
+                .format(from.extension())
+                .option("from", from.extension())
+                .option("to", to.extension()),
+            elapsed,
+        );
+        result
+    }
+
+    /// Validate a document, returning its diagnostics
+    ///
+    /// Findings of the lint rules at warning or error, as configured for
+    /// every document, follow the syntax problems, naming their rule.
+    ///
+    /// # Errors
+    ///
+    /// As [`Formats::validate_with_lint`] does.
+    pub fn validate(&self, content: &str, format: Format) -> Result<Vec<String>> {
+        let (mut diagnostics, findings) = self.validate_with_lint(content, format, None)?;
+        let reported = findings.into_iter().filter(|finding| finding.severity >= lint::Severity::Warning);
+        diagnostics.extend(reported.map(|finding| finding.to_string()));
+        Ok(diagnostics)
+    }
+
+    /// Validate a document, returning its syntax problems and, apart, the findings of the lint rules in force for the document at `uri`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` is past the input limit, or a check cannot run at
+    /// all.
+    pub fn validate_with_lint(&self, content: &str, format: Format, uri: Option<&str>) -> Result<(Vec<String>, Vec<Finding>)> {
+        let _span = info_span!(
+            "format.validate",
+            format = format.extension(),
+            size = telemetry::size_bucket(content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, content.len())?;
+
+        let start = Instant::now();
+        let result = ConversionCore::validate_syntax(content, format)
+            .map(|diagnostics| (diagnostics, lint::lint(content, format, &self.lint_config(), uri)));
+        let outcome = match &result {
+            Ok((diagnostics, findings)) if diagnostics.is_empty() && findings.is_empty() => ValidationOutcome::Clean,
+            Ok(_) => ValidationOutcome::Warnings,
+            Err(_) => ValidationOutcome::Errors,
+        };
+        let elapsed = start.elapsed();
+        self.observer.validation(format, elapsed, outcome);
+        self.report_slow(
+            Operation::new(OpKind::Validation, "validate")
+                .size(content.len())
+                .format(format.extension()),
+            elapsed,
+        );
+        result
+    }
+
+    /// Check a JSON, YAML or TOML document, or Markdown front matter, against `schema`, returning its violations
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` is past the input limit, or not a document the
+    /// schema applies to.
+    pub fn validate_schema(&self, content: &str, format: Format, schema: &Schema) -> Result<Vec<SchemaDiagnostic>> {
+        let _span = info_span!(
+            "format.validate_schema",
+            format = format.extension(),
+            size = telemetry::size_bucket(content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, content.len())?;
+        schema::validate(content, format, schema)
+    }
+
+    /// Lay out a JSON, NDJSON, YAML or TOML document as `options` say
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` or the result is past the size limits, or
+    /// `content` does not parse.
+    pub fn format_document(&self, format: Format, content: &str, options: &FormatOptions) -> Result<String> {
+        let _span = info_span!(
+            "format.format_document",
+            format = format.extension(),
+            size = telemetry::size_bucket(content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, content.len())?;
+        let formatted = pretty::format_document(format, content, options)?;
+        self.check(LimitKind::OutputSize, formatted.len())?;
+        Ok(formatted)
+    }
+
+    /// The changes from `before` to `after`, documents of any formats, built-in or plugin
+    ///
+    /// # Errors
+    ///
+    /// Fails where either document is past the input limit or does not parse as
+    /// its format.
+    pub fn diff(&self, before: &str, before_format: &FormatRef, after: &str, after_format: &FormatRef) -> Result<Vec<Change>> {
+        let _span = info_span!(
+            "format.diff",
+            format.before = before_format.name(),
+            format.after = after_format.name(),
+            size = telemetry::size_bucket(before.len().max(after.len())),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, before.len())?;
+        self.check(LimitKind::InputSize, after.len())?;
+        Ok(diff::diff(&document_model(before, before_format)?, &document_model(after, after_format)?))
+    }
+
+    /// A schema every one of `samples`, documents of any formats, built-in or plugin, is valid against
+    ///
+    /// # Errors
+    ///
+    /// Fails where a sample is past the input limit or does not parse as its
+    /// format, or there are none.
+    pub fn infer_schema(&self, samples: &[(&str, &FormatRef)], options: &InferOptions) -> Result<serde_json::Value> {
+        let _span = info_span!(
+            "format.infer_schema",
+            samples = samples.len(),
+            size = telemetry::size_bucket(samples.iter().map(|(content, _)| content.len()).sum()),
+        )
+        .entered();
+        let mut values = Vec::with_capacity(samples.len());
+        for (i, (content, format)) in samples.iter().enumerate() {
+            self.check(LimitKind::InputSize, content.len())?;
+            values.push(document_model(content, format).with_context(|| format!("In sample {}", i + 1))?);
+        }
+        Ok(infer::infer(&values, options))
+    }
+
+    /// Evaluate `expression`, of `language`, against a document of any format, built-in or plugin, or `XPath` against XML
+    ///
+    /// # Errors
+    ///
+    /// Fails where `content` is past the input limit or does not parse, or
+    /// `expression` does not.
+    pub fn query(&self, content: &str, format: &FormatRef, language: QueryLanguage, expression: &str) -> Result<QueryResult> {
+        let _span = info_span!(
+            "format.query",
+            format = format.name(),
+            language = language.name(),
+            size = telemetry::size_bucket(content.len()),
+        )
+        .entered();
+        self.check(LimitKind::InputSize, content.len())?;
+        match (language, format) {
+            (QueryLanguage::XPath, FormatRef::BuiltIn(Format::Xml)) => query::xpath(content, expression),
+            (QueryLanguage::XPath, format) => Err(anyhow!("XPath queries XML documents, not {}", format.name())),
+            _ => query::query(&document_model(content, format)?.to_json(), language, expression),
+        }
+    }
+
+    fn report_slow(&self, operation: Operation, elapsed: Duration) {
+        if let Some(slow_ops) = &self.slow_ops {
+            slow_ops.record(operation, elapsed);
+        }
+    }
+
+    fn check(&self, limit: LimitKind, len: usize) -> Result<()> {
+        let limits = self.limits();
+        let max = match limit {
+            LimitKind::InputSize => limits.max_input_bytes,
+            LimitKind::OutputSize => limits.max_output_bytes,
+        };
+        if len > max {
+            self.observer.limit_exceeded(limit);
+            return Err(anyhow!("Document of {} bytes exceeds the {} limit of {} bytes", len, limit.as_str(), max));
+        }
+        Ok(())
+    }
+}
+
+/// `content` as a document model; plugin formats through canonical JSON
+fn document_model(content: &str, format: &FormatRef) -> Result<model::DocumentValue> {
+    match format {
+        FormatRef::BuiltIn(format) => model::read(content, *format, &mut Vec::new()),
+        FormatRef::Plugin(plugin) => {
+            let canonical = text(plugin.name(), plugin.to_canonical(content.as_bytes())?)?;
+            model::read(&canonical, Format::Json, &mut Vec::new())
+                .with_context(|| format!("Plugin {} produced invalid JSON", plugin.name()))
+        }
+    }
+}
+
+/// A plugin's output as text
+fn text(plugin: &str, output: Vec<u8>) -> Result<String> {
+    String::from_utf8(output).with_context(|| format!("Plugin {plugin} produced invalid UTF-8"))
+}
+
+impl std::fmt::Debug for Formats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Formats")
+            .field("limits", &self.limits())
+            .field("plugins", &self.plugins.names())
+            .field("schemas", &self.schemas.names())
+            .field("descriptors", &self.descriptors.names())
+            .field("avro_schemas", &self.avro_schemas.names())
+            .finish_non_exhaustive()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+
+    /// Records every report as a readable line
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Recorder {
+        fn events(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl FormatObserver for Recorder {
+        fn conversion(&self, from: Format, to: Format, _: Duration, result: &Result<ConversionResponse>) {
+            let outcome = if result.is_ok() { "ok" } else { "failed" };
+            self.0.lock().unwrap().push(format!("convert {}->{} {}", from.extension(), to.extension(), outcome));
+        }
+
+        fn streamed_conversion(&self, from: Format, to: Format, _: Duration, result: &Result<Streamed>) {
+            let outcome = match result {
+                Ok(streamed) => format!("{} records", streamed.records),
+                Err(_) => "failed".to_string(),
+            };
+            self.0.lock().unwrap().push(format!("stream {}->{} {}", from.extension(), to.extension(), outcome));
+        }
+
+        fn validation(&self, format: Format, _: Duration, outcome: ValidationOutcome) {
+            self.0.lock().unwrap().push(format!("validate {} {}", format.extension(), outcome.as_str()));
+        }
+
+        fn limit_exceeded(&self, limit: LimitKind) {
+            self.0.lock().unwrap().push(format!("limit {}", limit.as_str()));
+        }
+    }
+
+    fn formats(limits: FormatLimits) -> (Formats, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        (Formats::new(limits, recorder.clone()), recorder)
+    }
+
+    fn request(content: &str, from: Format, to: Format) -> ConversionRequest {
+        ConversionRequest { content: content.to_string(), from, to }
+    }
+
+    #[test]
+    fn test_chained_conversion_reported_once() {
+        let (formats, recorder) = formats(FormatLimits::default());
+
+        // YAML → HTML goes through a JSON intermediate inside the core
+        formats.convert(request("title: Notes", Format::Yaml, Format::Html)).unwrap();
+        assert!(formats.convert(request("{ not json", Format::Json, Format::Html)).is_err());
+
+        assert_eq!(recorder.events(), vec!["convert yaml->html ok", "convert json->html failed"]);
+    }
+
+    #[test]
+    fn test_validation_outcomes() {
+        let (formats, recorder) = formats(FormatLimits::default());
+
+        formats.validate(r#"{"key": "value"}"#, Format::Json).unwrap();
+        formats.validate("{ invalid json }", Format::Json).unwrap();
+
+        assert_eq!(recorder.events(), vec!["validate json clean", "validate json warnings"]);
+    }
+
+    #[test]
+    fn test_limits_refuse_documents() {
+        let (formats, recorder) = formats(FormatLimits { max_input_bytes: 16, max_output_bytes: 16 });
+
+        assert!(formats.convert(request("# A heading that is too long", Format::Markdown, Format::Html)).is_err());
+        assert!(formats.validate("# A heading that is too long", Format::Markdown).is_err());
+        // Fits going in, but the rendered HTML does not fit coming out
+        assert!(formats.convert(request("# Heading", Format::Markdown, Format::Html)).is_err());
+
+        assert_eq!(
+            recorder.events(),
+            vec!["limit input_size", "limit input_size", "limit output_size", "convert md->html failed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_conversions_reported() {
+        let (formats, recorder) = formats(FormatLimits { max_input_bytes: 16, max_output_bytes: 16 });
+
+        // The limits apply a record at a time, so a long stream of small records converts
+        let ndjson = "{\"n\":1}\n".repeat(10);
+        let streamed = formats.convert_stream(std::io::Cursor::new(ndjson.clone()), Format::Ndjson, Format::Yaml, tokio::io::sink()).await.unwrap();
+        assert_eq!((streamed.records, streamed.read), (10, ndjson.len()));
+        let yaml = "items:\n- a long item\n- another\n";
+        assert!(formats.convert_stream(yaml.as_bytes(), Format::Yaml, Format::Json, tokio::io::sink()).await.is_err());
+
+        assert_eq!(recorder.events(), vec!["stream ndjson->yaml 10 records", "limit input_size", "stream yaml->json failed"]);
+    }
+
+    #[test]
+    fn test_new_limits_reach_clones() {
+        let (formats, _) = formats(FormatLimits::default());
+        let shared = formats.clone();
+        assert!(shared.validate("# A heading that is too long", Format::Markdown).is_ok());
+
+        formats.set_limits(FormatLimits { max_input_bytes: 16, max_output_bytes: 16 });
+        assert_eq!(shared.limits().max_input_bytes, 16);
+        assert!(shared.validate("# A heading that is too long", Format::Markdown).is_err());
+    }
 
     #[test]
     fn test_format_from_str() {
@@ -58,10 +884,99 @@ fn test_format_from_str() {
         assert_eq!(ExtendedFormat::from_str("toml").unwrap(), ExtendedFormat::Toml);
     }
 
+    #[test]
+    fn test_extended_format_json_dispatch() {
+        for (format, content) in [
+            (ExtendedFormat::Yaml, "key: value\n"),
+            (ExtendedFormat::Xml, "<key>value</key>"),
+            (ExtendedFormat::Toml, "key = \"value\""),
+        ] {
+            let json = format.to_json(content).unwrap();
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap(), serde_json::json!({"key": "value"}));
+            assert_eq!(format.to_json(&format.from_json(&json).unwrap()).unwrap(), json, "{format:?}");
+        }
+    }
+
     #[test]
     fn test_format_extension() {
         assert_eq!(ExtendedFormat::Yaml.extension(), "yaml");
         assert_eq!(ExtendedFormat::Xml.extension(), "xml");
         assert_eq!(ExtendedFormat::Toml.extension(), "toml");
     }
+
+    /// One JSON string per line
+    struct Lines;
+
+    impl FormatPlugin for Lines {
+        fn name(&self) -> &'static str {
+            "lines"
+        }
+
+        fn to_canonical(&self, input: &[u8]) -> Result<Vec<u8>> {
+            let lines: Vec<&str> = std::str::from_utf8(input)?.lines().collect();
+            Ok(serde_json::to_vec(&lines)?)
+        }
+
+        fn from_canonical(&self, canonical: &[u8]) -> Result<Vec<u8>> {
+            let lines: Vec<String> = serde_json::from_slice(canonical)?;
+            Ok(lines.join("\n").into_bytes())
+        }
+
+        fn validate(&self, input: &[u8]) -> Result<Vec<String>> {
+            Ok(input.is_empty().then(|| "Empty".to_string()).into_iter().collect())
+        }
+    }
+
+    #[test]
+    fn test_plugin_formats_convert_through_json() {
+        let (formats, recorder) = formats(FormatLimits::default());
+        formats.plugins().register(Arc::new(Lines)).unwrap();
+        let lines = formats.resolve("lines").unwrap();
+        let json = formats.resolve("json").unwrap();
+        let yaml = formats.resolve("yml").unwrap();
+        assert_eq!(yaml.name(), "yaml");
+        assert!(formats.resolve("properties").is_err());
+
+        assert_eq!(formats.convert_any("a\nb", &lines, &json).unwrap(), r#"["a","b"]"#);
+        assert_eq!(formats.convert_any(r#"["c", "d"]"#, &json, &lines).unwrap(), "c\nd");
+        formats.convert_any("a\nb", &lines, &yaml).unwrap();
+        assert!(formats.convert_any(r#"{"not": "a list"}"#, &json, &lines).is_err());
+        assert_eq!(formats.validate_any("", &lines).unwrap(), ["Empty"]);
+        let changes = formats.diff("a\nb", &lines, "[\"a\", \"c\"]", &json).unwrap();
+        assert_eq!(changes.iter().map(|change| change.path.as_str()).collect::<Vec<_>>(), ["/1"]);
+
+        // Only the built-in leg is reported
+        assert_eq!(recorder.events(), vec!["convert json->yaml ok"]);
+    }
+
+    #[test]
+    fn test_limits_apply_to_plugin_formats() {
+        let (formats, recorder) = formats(FormatLimits { max_input_bytes: 16, max_output_bytes: 8 });
+        formats.plugins().register(Arc::new(Lines)).unwrap();
+        let (lines, json) = (formats.resolve("lines").unwrap(), formats.resolve("json").unwrap());
+
+        assert!(formats.convert_any("a line that is too long", &lines, &json).is_err());
+        assert!(formats.validate_any("a line that is too long", &lines).is_err());
+        assert!(formats.convert_any("a\nb\nc", &lines, &json).is_err());
+        assert_eq!(recorder.events(), vec!["limit input_size", "limit input_size", "limit output_size"]);
+    }
+
+    #[test]
+    fn test_detect() {
+        for (content, format) in [
+            ("{\"a\": 1}", Format::Json),
+            ("{\"unterminated\": ", Format::Json),
+            ("  [1, 2]", Format::Json),
+            ("<!DOCTYPE html><p>hi</p>", Format::Html),
+            ("<?xml version=\"1.0\"?><a/>", Format::Xml),
+            ("---\nkey: value", Format::Yaml),
+            ("name: deploy\nreplicas: 3", Format::Yaml),
+            ("[server]\nport = 8080", Format::Toml),
+            ("title = \"x\"", Format::Toml),
+            ("# Heading\n\nSome text: here", Format::Markdown),
+            ("[link](https://example.com)", Format::Markdown),
+        ] {
+            assert_eq!(detect(content), format, "{content}");
+        }
+    }
 }
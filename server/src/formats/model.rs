@@ -0,0 +1,1118 @@
+//! Intermediate document model
+//!
+//! Every built-in format is read into a [`DocumentValue`] by [`read`] and
+//! written from one by [`write`], so converting between any two formats
+//! takes one reader and one writer rather than a function for each pair.
+//! Markdown and HTML take part as the structured JSON they convert to; only
+//! Markdown ↔ HTML, which keeps the document's text, bypasses the model.
+//!
+//! Values read from JSON carry the bytes they span in the source. YAML and
+//! TOML keys and tables carry the comments written above them, which are
+//! written above them again when the value is written as TOML; YAML values
+//! also carry the blank line above them and the comment ending their line.
+//! Other formats drop comments. Maps read from JSON and block YAML keep
+//! the order of their entries, and rows read from CSV that of its columns.
+//!
+//! TOML date-times, YAML timestamps and XML elements typed `xs:dateTime`,
+//! `xs:date` or `xs:time` with `xsi:type` are read as [`Node::DateTime`],
+//! and written in the style [`DocumentValue::with_datetimes`] gives them,
+//! as they were read unless it changes them. YAML writes them as
+//! timestamps and TOML as date-times, quoting strings that would read back
+//! as one; other formats write them as strings.
+//!
+//! [`write_onto`] is the way back for a document edited in another format:
+//! given the version it was converted from, it keeps that version's
+//! comments, blank lines and entry order, so a YAML → JSON → YAML or
+//! TOML → JSON → TOML round trip only changes what was edited.
+
+use anyhow::{anyhow, bail, Result};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use super::datetime::{DateTime, DateTimeStyle};
+use crate::core::{ConversionCore, Format};
+
+/// A value of a document, with where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentValue {
+    pub node: Node,
+    /// Bytes of the value in the document it was read from, when the format gives them
+    pub span: Option<Range<usize>>,
+    /// Comments above the value, one per line and without their markers; a document's own are those below its last entry
+    pub comments: Vec<String>,
+    /// Whether a blank line separates the value, and its comments, from what is above
+    pub blank_line: bool,
+    /// Comment ending the value's line, without its marker
+    pub trailing: Option<String>,
+}
+
+/// What a [`DocumentValue`] holds
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    /// Integers above `i64::MAX`
+    Unsigned(u64),
+    Float(f64),
+    String(String),
+    /// A date, time or both, as the format types them
+    DateTime(DateTime),
+    Array(Vec<DocumentValue>),
+    Map(Vec<(String, DocumentValue)>),
+}
+
+impl DocumentValue {
+    /// A value with no span or comments
+    #[must_use]
+    pub fn new(node: Node) -> Self {
+        Self { node, span: None, comments: Vec::new(), blank_line: false, trailing: None }
+    }
+
+    /// The value of a parsed JSON value
+    pub fn from_json(value: Value) -> Self {
+        Self::new(match value {
+            Value::Null => Node::Null,
+            Value::Bool(b) => Node::Bool(b),
+            Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(i), _) => Node::Integer(i),
+                (_, Some(u)) => Node::Unsigned(u),
+                _ => Node::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::String(s) => Node::String(s),
+            Value::Array(items) => Node::Array(items.into_iter().map(Self::from_json).collect()),
+            Value::Object(object) => Node::Map(object.into_iter().map(|(key, value)| (key, Self::from_json(value))).collect()),
+        })
+    }
+
+    /// The value as JSON, where floats that are not finite become null
+    pub fn to_json(&self) -> Value {
+        match &self.node {
+            Node::Null => Value::Null,
+            Node::Bool(b) => Value::Bool(*b),
+            Node::Integer(i) => Value::from(*i),
+            Node::Unsigned(u) => Value::from(*u),
+            Node::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+            Node::String(s) => Value::String(s.clone()),
+            Node::DateTime(datetime) => Value::String(datetime.text.clone()),
+            Node::Array(items) => Value::Array(items.iter().map(Self::to_json).collect()),
+            Node::Map(entries) => Value::Object(entries.iter().map(|(key, value)| (key.clone(), value.to_json())).collect::<Map<_, _>>()),
+        }
+    }
+
+    /// The entry `key` of a map
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&DocumentValue> {
+        match &self.node {
+            Node::Map(entries) => entries.iter().rev().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The value `path` leads to, by key and index
+    fn find_mut(&mut self, path: &[String]) -> Option<&mut DocumentValue> {
+        let Some((step, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        let next = match &mut self.node {
+            Node::Map(entries) => entries.iter_mut().rev().find(|(name, _)| name == step).map(|(_, value)| value),
+            Node::Array(items) => step.parse().ok().and_then(|index: usize| items.get_mut(index)),
+            _ => None,
+        };
+        next?.find_mut(rest)
+    }
+
+    /// `self` with its date-times as `style` writes them
+    ///
+    /// They stay date-times, written as the text they were read from or as
+    /// RFC 3339. Epoch seconds are numbers, for date-times naming an
+    /// instant; others are written as RFC 3339.
+    #[must_use]
+    pub fn with_datetimes(mut self, style: DateTimeStyle) -> Self {
+        self.node = match self.node {
+            Node::DateTime(datetime) => match (style, datetime.epoch()) {
+                (DateTimeStyle::Preserve, _) => Node::DateTime(datetime),
+                (DateTimeStyle::Epoch, Some(seconds)) => DocumentValue::from_json(Value::Number(seconds)).node,
+                (DateTimeStyle::Rfc3339 | DateTimeStyle::Epoch, _) => Node::DateTime(DateTime { text: datetime.rfc3339(), ..datetime }),
+            },
+            Node::Array(items) => Node::Array(items.into_iter().map(|item| item.with_datetimes(style)).collect()),
+            Node::Map(entries) => Node::Map(entries.into_iter().map(|(key, value)| (key, value.with_datetimes(style))).collect()),
+            node => node,
+        };
+        self
+    }
+
+    /// Add the paths of the date-times within to `found`, each after `path`, the path to `self`
+    fn find_datetimes(&self, path: &mut Vec<String>, found: &mut Vec<Vec<String>>) {
+        match &self.node {
+            Node::DateTime(_) => found.push(path.clone()),
+            Node::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    path.push(i.to_string());
+                    item.find_datetimes(path, found);
+                    path.pop();
+                }
+            }
+            Node::Map(entries) => {
+                for (key, value) in entries {
+                    path.push(key.clone());
+                    value.find_datetimes(path, found);
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Deserializing keeps the order of map entries, which [`Value`] sorts; a
+/// key given twice keeps its first place and its last value. Scalar keys,
+/// as YAML allows, become their text.
+impl<'de> Deserialize<'de> for DocumentValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(NodeVisitor).map(Self::new)
+    }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a document value")
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Node, E> {
+        Ok(Node::Null)
+    }
+
+    fn visit_none<E>(self) -> std::result::Result<Node, E> {
+        Ok(Node::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<Node, D::Error> {
+        DocumentValue::deserialize(deserializer).map(|value| value.node)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> std::result::Result<Node, E> {
+        Ok(Node::Bool(b))
+    }
+
+    fn visit_i64<E>(self, i: i64) -> std::result::Result<Node, E> {
+        Ok(Node::Integer(i))
+    }
+
+    fn visit_u64<E>(self, u: u64) -> std::result::Result<Node, E> {
+        Ok(i64::try_from(u).map_or(Node::Unsigned(u), Node::Integer))
+    }
+
+    fn visit_f64<E>(self, f: f64) -> std::result::Result<Node, E> {
+        Ok(Node::Float(f))
+    }
+
+    fn visit_str<E>(self, s: &str) -> std::result::Result<Node, E> {
+        Ok(Node::String(s.to_string()))
+    }
+
+    fn visit_string<E>(self, s: String) -> std::result::Result<Node, E> {
+        Ok(Node::String(s))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Node, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Node::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Node, A::Error> {
+        let mut entries: Vec<(String, DocumentValue)> = Vec::new();
+        while let Some(key) = map.next_key::<DocumentValue>()? {
+            let key = match key.node {
+                Node::String(s) => s,
+                Node::Null => "null".to_string(),
+                Node::Bool(b) => b.to_string(),
+                Node::Integer(i) => i.to_string(),
+                Node::Unsigned(u) => u.to_string(),
+                Node::Float(f) => f.to_string(),
+                Node::DateTime(datetime) => datetime.text,
+                Node::Array(_) | Node::Map(_) => return Err(de::Error::custom("map keys must be scalars")),
+            };
+            let value = map.next_value()?;
+            match entries.iter_mut().find(|(name, _)| *name == key) {
+                Some((_, entry)) => *entry = value,
+                None => entries.push((key, value)),
+            }
+        }
+        Ok(Node::Map(entries))
+    }
+}
+
+/// Read `content`, a document of `format`, adding what the format warns about to `warnings`
+///
+/// # Errors
+///
+/// Fails where `content` does not parse as `format`, or `format` has no
+/// document model.
+pub fn read(content: &str, format: Format, warnings: &mut Vec<String>) -> Result<DocumentValue> {
+    let json = match format {
+        Format::Json => {
+            let mut value: DocumentValue = serde_json::from_str(content)?;
+            json_spans(&mut value, content, skip_space(content.as_bytes(), 0));
+            return Ok(value);
+        }
+        Format::Toml => {
+            let mut value = DocumentValue::from_json(serde_json::from_str(&super::toml::toml_to_json(content)?)?);
+            if let Ok(document) = content.parse::<toml_edit::DocumentMut>() {
+                read_toml_comments(&mut value, document.as_table());
+                read_toml_datetimes(&mut value, document.as_table());
+            }
+            return Ok(value);
+        }
+        Format::Dotenv => {
+            let json = super::dotenv::dotenv_to_json(content)?;
+            let found = super::dotenv::diagnostics(content);
+            warnings.extend(found.iter().filter(|diagnostic| diagnostic.warning).map(|diagnostic| format!("dotenv: {diagnostic}")));
+            json
+        }
+        Format::Markdown => ConversionCore::markdown_to_json(content)?,
+        Format::Html => ConversionCore::html_to_json(content)?,
+        Format::Yaml => {
+            let mut value = DocumentValue::from_json(serde_json::from_str(&super::yaml::yaml_to_json(content)?)?);
+            attach_yaml_layout(&mut value, content);
+            for path in super::yaml::timestamps(content) {
+                if let Some(timestamp) = value.find_mut(&path) {
+                    read_datetime(timestamp);
+                }
+            }
+            return Ok(value);
+        }
+        Format::Xml => {
+            let mut value = DocumentValue::from_json(serde_json::from_str(&super::xml::xml_to_json(content)?)?);
+            read_xml_datetimes(&mut value);
+            return Ok(value);
+        }
+        // Read as the model, which keeps the order of the columns
+        Format::Csv => return Ok(serde_json::from_str(&super::csv::csv_to_json(content)?)?),
+        Format::Tsv => return Ok(serde_json::from_str(&super::csv::tsv_to_json(content)?)?),
+        Format::Ini => super::ini::ini_to_json(content)?,
+        Format::Json5 => super::json5::json5_to_json(content)?,
+        Format::Jsonc => super::json5::jsonc_to_json(content)?,
+        Format::Ndjson => super::ndjson::ndjson_to_json(content)?,
+        Format::Msgpack => super::msgpack::msgpack_to_json(&super::msgpack::decode_text(content)?)?,
+        Format::Cbor => super::cbor::cbor_to_json(&super::cbor::decode_text(content)?)?,
+        Format::Plist => super::plist::plist_to_json(&super::plist::decode_text(content)?)?,
+    };
+    Ok(DocumentValue::from_json(serde_json::from_str(&json)?))
+}
+
+/// Write `value` as a document of `format`
+///
+/// # Errors
+///
+/// Fails where `value` has no form in `format`, such as null in TOML.
+pub fn write(value: &DocumentValue, format: Format) -> Result<String> {
+    let json = serde_json::to_string_pretty(&value.to_json())?;
+    Ok(match format {
+        Format::Json => json,
+        Format::Toml => {
+            let toml = super::toml::json_to_toml(&json)?;
+            let datetimes = !datetime_paths(value).is_empty();
+            match toml.parse::<toml_edit::DocumentMut>() {
+                Ok(mut document) if has_comments(value) || datetimes => {
+                    write_toml_comments(value, document.as_table_mut());
+                    write_toml_datetimes(value, document.as_table_mut());
+                    document.to_string()
+                }
+                _ => toml,
+            }
+        }
+        Format::Markdown => ConversionCore::json_to_markdown(&json)?,
+        Format::Html => ConversionCore::json_to_html(&json)?,
+        Format::Yaml => super::yaml::json_to_yaml_with_timestamps(&json, &datetime_paths(value))?,
+        Format::Xml => super::xml::json_to_xml(&json)?,
+        Format::Csv => super::csv::write(value, &super::csv::CsvOptions::default())?,
+        Format::Tsv => super::csv::write(value, &super::csv::CsvOptions::tsv())?,
+        Format::Ini => super::ini::json_to_ini(&json)?,
+        Format::Dotenv => super::dotenv::json_to_dotenv(&json)?,
+        Format::Json5 => super::json5::json_to_json5(&json)?,
+        Format::Jsonc => super::json5::json_to_jsonc(&json)?,
+        Format::Ndjson => super::ndjson::json_to_ndjson(&json)?,
+        Format::Msgpack => super::msgpack::encode_text(&super::msgpack::json_to_msgpack(&json)?),
+        Format::Cbor => super::cbor::encode_text(&super::cbor::json_to_cbor(&json)?),
+        Format::Plist => super::plist::json_to_plist(&json)?,
+    })
+}
+
+/// Write `value` as a document of `format`, laid out as `original`, an earlier version of it in that format
+///
+/// YAML keeps the comments, blank lines and entry order of `original`, and
+/// TOML is updated in place, so what was not edited keeps its layout too.
+/// Entries new to `value` follow those of `original`. Other formats are
+/// written as by [`write`].
+///
+/// # Errors
+///
+/// As [`write`] does; an `original` that does not parse is written over
+/// instead.
+pub fn write_onto(value: &DocumentValue, format: Format, original: &str) -> Result<String> {
+    match format {
+        Format::Yaml => {
+            let before = read(original, Format::Yaml, &mut Vec::new())?;
+            super::pretty::write_yaml(&restyle(value.clone(), &before), &super::FormatOptions::default())
+        }
+        Format::Toml => {
+            let mut document: toml_edit::DocumentMut = original.parse()?;
+            let before = read(original, Format::Toml, &mut Vec::new())?;
+            let Node::Map(entries) = &value.node else {
+                bail!("A TOML document is a table");
+            };
+            let mut next = last_position(document.as_table()) + 1;
+            update_table(document.as_table_mut(), entries, &before, &mut next)?;
+            Ok(document.to_string())
+        }
+        _ => write(value, format),
+    }
+}
+
+/// `edited` with the comments, blank lines and entry order of `original`, an earlier version of it
+#[must_use]
+pub fn restyle(mut edited: DocumentValue, original: &DocumentValue) -> DocumentValue {
+    edited.comments.clone_from(&original.comments);
+    edited.blank_line = original.blank_line;
+    edited.trailing.clone_from(&original.trailing);
+    match (&mut edited.node, &original.node) {
+        (Node::Map(entries), Node::Map(before)) => {
+            // Entries new to the edit keep their order, after the others
+            entries.sort_by_key(|(key, _)| before.iter().position(|(name, _)| name == key).unwrap_or(usize::MAX));
+            for (key, entry) in entries.iter_mut() {
+                if let Some(old) = original.get(key) {
+                    *entry = restyle(std::mem::replace(entry, DocumentValue::new(Node::Null)), old);
+                }
+            }
+        }
+        (Node::Array(items), Node::Array(before)) => {
+            for (item, old) in items.iter_mut().zip(before) {
+                *item = restyle(std::mem::replace(item, DocumentValue::new(Node::Null)), old);
+            }
+        }
+        _ => {}
+    }
+    edited
+}
+
+/// The last position of a table header in `table` and the tables in it
+fn last_position(table: &toml_edit::Table) -> usize {
+    table
+        .iter()
+        .map(|(_, item)| match item {
+            toml_edit::Item::Table(inner) => inner.position().unwrap_or_default().max(last_position(inner)),
+            toml_edit::Item::ArrayOfTables(tables) => {
+                tables.iter().map(|inner| inner.position().unwrap_or_default().max(last_position(inner))).max().unwrap_or_default()
+            }
+            _ => 0,
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Place the tables of `item`, new to a document, after those already in it, from `next`
+fn place(item: &mut toml_edit::Item, next: &mut usize) {
+    let tables: Vec<&mut toml_edit::Table> = match item {
+        toml_edit::Item::Table(table) => vec![table],
+        toml_edit::Item::ArrayOfTables(tables) => tables.iter_mut().collect(),
+        _ => return,
+    };
+    for table in tables {
+        if !table.is_dotted() {
+            table.set_position(*next);
+            table.decor_mut().set_prefix("\n");
+            *next += 1;
+        }
+        for (_, inner) in table.iter_mut() {
+            place(inner, next);
+        }
+    }
+}
+
+/// Make `table`, read as `before`, hold `entries`, leaving what did not change as it is
+fn update_table(table: &mut toml_edit::Table, entries: &[(String, DocumentValue)], before: &DocumentValue, next: &mut usize) -> Result<()> {
+    let removed: Vec<String> = table
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !entries.iter().any(|(name, _)| name == key))
+        .collect();
+    for key in removed {
+        table.remove(&key);
+    }
+    // New keys go after the others, whatever their order in `entries`
+    let (kept, added): (Vec<_>, Vec<_>) = entries.iter().partition(|(key, _)| table.contains_key(key));
+    for (key, entry) in kept.into_iter().chain(added) {
+        let old = before.get(key);
+        if old.is_some_and(|old| old.to_json() == entry.to_json()) {
+            continue;
+        }
+        match (table.get_mut(key), &entry.node, old) {
+            (Some(toml_edit::Item::Table(inner)), Node::Map(entries), Some(old)) => update_table(inner, entries, old, next)?,
+            (Some(toml_edit::Item::ArrayOfTables(tables)), Node::Array(items), Some(old))
+                if items.iter().all(|item| matches!(item.node, Node::Map(_))) =>
+            {
+                let Node::Array(old_items) = &old.node else {
+                    bail!("`{key}` was an array of tables");
+                };
+                while tables.len() > items.len() {
+                    tables.remove(tables.len() - 1);
+                }
+                for (index, item) in items.iter().enumerate() {
+                    let Node::Map(entries) = &item.node else { continue };
+                    if let (Some(inner), Some(old)) = (tables.get_mut(index), old_items.get(index)) { update_table(inner, entries, old, next)? } else {
+                        let mut table = toml_edit::Table::new();
+                        table.set_position(*next);
+                        table.decor_mut().set_prefix("\n");
+                        *next += 1;
+                        update_table(&mut table, entries, &DocumentValue::new(Node::Map(Vec::new())), next)?;
+                        tables.push(table);
+                    }
+                }
+            }
+            (Some(toml_edit::Item::Value(value)), _, _) => {
+                let mut item = toml_item(key, entry)?;
+                place(&mut item, next);
+                let mut edited = item.into_value().map_err(|_| anyhow!("`{key}` has no inline TOML form"))?;
+                *edited.decor_mut() = value.decor().clone();
+                *value = edited;
+            }
+            (Some(slot), _, _) => {
+                let mut item = toml_item(key, entry)?;
+                place(&mut item, next);
+                *slot = item;
+            }
+            (None, _, _) => {
+                let mut item = toml_item(key, entry)?;
+                place(&mut item, next);
+                table.insert(key, item);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `value` as the TOML item of `key`, written as [`write`] would
+fn toml_item(key: &str, value: &DocumentValue) -> Result<toml_edit::Item> {
+    let json = serde_json::json!({ key: value.to_json() });
+    let mut document: toml_edit::DocumentMut = super::toml::json_to_toml(&json.to_string())?.parse()?;
+    document.remove(key).ok_or_else(|| anyhow!("TOML has no form for `{key}`"))
+}
+
+/// Where a line of block YAML puts a value, and what surrounds it
+#[derive(Debug, Default)]
+struct Layout {
+    line: usize,
+    comments: Vec<String>,
+    blank_line: bool,
+    trailing: Option<String>,
+}
+
+/// A block collection of YAML open at a column
+struct Frame {
+    column: usize,
+    path: Vec<String>,
+    sequence: bool,
+    next: usize,
+}
+
+/// Give the values of `value`, read from block YAML `content`, the comments and blank lines around them, and their maps the order of their entries
+///
+/// Values are found by the keys and items that start lines, by their
+/// indentation as in [`schema`](super::schema); flow collections are not
+/// entered, and comments the lines do not place go to the next value.
+pub(crate) fn attach_yaml_layout(value: &mut DocumentValue, content: &str) {
+    let (mut layouts, footer) = yaml_layout(content);
+    attach(value, &mut Vec::new(), &mut layouts);
+    value.comments = footer;
+}
+
+fn attach(value: &mut DocumentValue, path: &mut Vec<String>, layouts: &mut HashMap<Vec<String>, Layout>) {
+    if let Some(layout) = layouts.remove(path.as_slice()) {
+        value.comments = layout.comments;
+        value.blank_line = layout.blank_line;
+        value.trailing = layout.trailing;
+    }
+    match &mut value.node {
+        Node::Map(entries) => {
+            entries.sort_by_key(|(key, _)| {
+                path.push(key.clone());
+                let line = layouts.get(path.as_slice()).map_or(usize::MAX, |layout| layout.line);
+                path.pop();
+                line
+            });
+            for (key, entry) in entries {
+                path.push(key.clone());
+                attach(entry, path, layouts);
+                path.pop();
+            }
+        }
+        Node::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                path.push(index.to_string());
+                attach(item, path, layouts);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The layout of each key and item starting a line, by path, and the comments after the last
+fn yaml_layout(content: &str) -> (HashMap<Vec<String>, Layout>, Vec<String>) {
+    let mut layouts: HashMap<Vec<String>, Layout> = HashMap::new();
+    let mut frames: Vec<Frame> = Vec::new();
+    // The key or item last read, whose value a more indented line holds
+    let mut last: Vec<String> = Vec::new();
+    let (mut comments, mut blank_line) = (Vec::new(), false);
+    // Lines of a block scalar are indented past the column of its key or item
+    let mut block: Option<usize> = None;
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        let mut column = line.len() - line.trim_start_matches(' ').len();
+        if let Some(parent) = block {
+            if line.is_empty() || column > parent {
+                continue;
+            }
+            block = None;
+        }
+        let mut rest = &line[column..];
+        if rest.is_empty() {
+            blank_line |= comments.is_empty() && number > 0;
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix('#') {
+            comments.push(comment.strip_prefix(' ').unwrap_or(comment).to_string());
+            continue;
+        }
+        if rest == "---" || rest == "..." || rest.starts_with("--- ") || rest.starts_with('%') {
+            continue;
+        }
+
+        let mut placed: Option<Vec<String>> = None;
+        loop {
+            let item = rest == "-" || rest.starts_with("- ");
+            let key = if item { None } else { yaml_line_key(rest) };
+            if !item && key.is_none() {
+                break;
+            }
+            while frames.last().is_some_and(|frame| frame.column > column || (frame.column == column && frame.sequence && !item)) {
+                frames.pop();
+            }
+            if !frames.last().is_some_and(|frame| frame.column == column && frame.sequence == item) {
+                frames.push(Frame { column, path: last.clone(), sequence: item, next: 0 });
+            }
+            let Some(frame) = frames.last_mut() else { break };
+            let mut path = frame.path.clone();
+            let after = if let Some((key, after)) = key {
+                path.push(key);
+                after
+            } else {
+                path.push(frame.next.to_string());
+                frame.next += 1;
+                &rest[1..]
+            };
+            let layout = layouts.entry(path.clone()).or_default();
+            layout.line = number;
+            if placed.is_none() {
+                layout.comments = std::mem::take(&mut comments);
+                layout.blank_line = std::mem::take(&mut blank_line);
+            }
+            placed = Some(path.clone());
+            last = path;
+
+            let value = after.trim_start();
+            if value.starts_with(['|', '>']) {
+                block = Some(column);
+            }
+            if value.is_empty() || value.starts_with('#') || !(value == "-" || value.starts_with("- ") || yaml_line_key(value).is_some()) {
+                rest = value;
+                break;
+            }
+            column = line.len() - value.len();
+            rest = value;
+        }
+        if let (Some(path), Some(start)) = (placed, comment_start(rest)) {
+            let comment = &rest[start + 1..];
+            if let Some(layout) = layouts.get_mut(&path) {
+                layout.trailing = Some(comment.strip_prefix(' ').unwrap_or(comment).trim_end().to_string());
+            }
+        }
+    }
+    (layouts, comments)
+}
+
+/// The key a line of block YAML starts with, and the rest of the line after its colon
+fn yaml_line_key(rest: &str) -> Option<(String, &str)> {
+    let (key, after) = match rest.chars().next()? {
+        '"' => {
+            let end = string_end(rest.as_bytes(), 0)?;
+            (serde_json::from_str(&rest[..end]).ok()?, &rest[end..])
+        }
+        '\'' => {
+            let mut end = 1;
+            loop {
+                end += rest[end..].find('\'')?;
+                if rest[end + 1..].starts_with('\'') {
+                    end += 2;
+                } else {
+                    break;
+                }
+            }
+            (rest[1..end].replace("''", "'"), &rest[end + 1..])
+        }
+        '#' | '[' | '{' | '&' | '*' | '!' | '|' | '>' | '?' => return None,
+        _ => {
+            let end = rest.find(": ").or_else(|| rest.strip_suffix(':').map(str::len))?;
+            (rest[..end].trim_end().to_string(), &rest[end..])
+        }
+    };
+    let after = after.trim_start_matches([' ', '\t']).strip_prefix(':')?;
+    (after.is_empty() || after.starts_with([' ', '\t'])).then_some((key, after))
+}
+
+/// Where a comment starts in a line of YAML: a `#` after whitespace, outside quotes
+pub(crate) fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if previous.is_whitespace() => return Some(index),
+            (None, '\'' | '"') if previous.is_whitespace() || ":-[{,".contains(previous) => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            _ => {}
+        }
+        previous = c;
+    }
+    None
+}
+
+fn skip_space(bytes: &[u8], mut i: usize) -> usize {
+    while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+        i += 1;
+    }
+    i
+}
+
+/// Give `value`, starting at `start` in `text`, and every value inside it their spans, returning where it ends
+fn json_spans(value: &mut DocumentValue, text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let end = match &mut value.node {
+        Node::Map(entries) => {
+            let mut i = skip_space(bytes, start + 1);
+            while bytes.get(i) == Some(&b'"') {
+                let key_end = string_end(bytes, i)?;
+                let key: String = serde_json::from_str(&text[i..key_end]).ok()?;
+                i = skip_space(bytes, skip_space(bytes, key_end) + 1);
+                // A key given twice holds its last value, so the last one read places it
+                let (_, entry) = entries.iter_mut().find(|(name, _)| *name == key)?;
+                i = skip_space(bytes, json_spans(entry, text, i)?);
+                if bytes.get(i) == Some(&b',') {
+                    i = skip_space(bytes, i + 1);
+                }
+            }
+            i + 1
+        }
+        Node::Array(items) => {
+            let mut i = skip_space(bytes, start + 1);
+            for item in items.iter_mut() {
+                i = skip_space(bytes, json_spans(item, text, i)?);
+                if bytes.get(i) == Some(&b',') {
+                    i = skip_space(bytes, i + 1);
+                }
+            }
+            i + 1
+        }
+        Node::String(_) => string_end(bytes, start)?,
+        _ => {
+            let mut i = start;
+            while bytes.get(i).is_some_and(|b| !b",}] \t\r\n".contains(b)) {
+                i += 1;
+            }
+            i
+        }
+    };
+    value.span = Some(start..end);
+    Some(end)
+}
+
+/// Where the JSON string opening at `start` ends
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// Comments in the whitespace before a TOML key or table header
+fn comment_lines(prefix: Option<&toml_edit::RawString>) -> Vec<String> {
+    let text = prefix.and_then(toml_edit::RawString::as_str).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix('#'))
+        .map(|comment| comment.strip_prefix(' ').unwrap_or(comment).to_string())
+        .collect()
+}
+
+fn read_toml_comments(value: &mut DocumentValue, table: &toml_edit::Table) {
+    let Node::Map(entries) = &mut value.node else {
+        return;
+    };
+    for (key, entry) in entries {
+        match table.get(key) {
+            Some(toml_edit::Item::Table(inner)) => {
+                entry.comments = comment_lines(inner.decor().prefix());
+                read_toml_comments(entry, inner);
+            }
+            Some(_) => entry.comments = comment_lines(table.key(key).and_then(|key| key.leaf_decor().prefix())),
+            None => {}
+        }
+    }
+}
+
+/// Make `value` a date-time, where it is a string that reads as one
+fn read_datetime(value: &mut DocumentValue) {
+    if let Node::String(text) = &value.node {
+        if let Some(datetime) = DateTime::parse(text) {
+            value.node = Node::DateTime(datetime);
+        }
+    }
+}
+
+/// Make the strings of `value`, read from `table`, that TOML gives as date-times into them, as written
+fn read_toml_datetimes(value: &mut DocumentValue, table: &toml_edit::Table) {
+    let Node::Map(entries) = &mut value.node else {
+        return;
+    };
+    for (key, entry) in entries {
+        match (table.get(key), &mut entry.node) {
+            (Some(toml_edit::Item::Table(inner)), _) => read_toml_datetimes(entry, inner),
+            (Some(toml_edit::Item::ArrayOfTables(tables)), Node::Array(items)) => {
+                for (item, inner) in items.iter_mut().zip(tables.iter()) {
+                    read_toml_datetimes(item, inner);
+                }
+            }
+            (Some(toml_edit::Item::Value(inner)), _) => read_toml_value_datetimes(entry, inner),
+            _ => {}
+        }
+    }
+}
+
+fn read_toml_value_datetimes(value: &mut DocumentValue, inner: &toml_edit::Value) {
+    match (&mut value.node, inner) {
+        (Node::String(_), toml_edit::Value::Datetime(datetime)) => {
+            if let Some(datetime) = DateTime::parse(&datetime.display_repr()) {
+                value.node = Node::DateTime(datetime);
+            }
+        }
+        (Node::Array(items), toml_edit::Value::Array(array)) => {
+            for (entry, inner) in items.iter_mut().zip(array.iter()) {
+                read_toml_value_datetimes(entry, inner);
+            }
+        }
+        (Node::Map(entries), toml_edit::Value::InlineTable(table)) => {
+            for (key, entry) in entries {
+                if let Some(inner) = table.get(key) {
+                    read_toml_value_datetimes(entry, inner);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Make the text of the elements of `value` typed as XML Schema dates and times into date-times
+fn read_xml_datetimes(value: &mut DocumentValue) {
+    match &mut value.node {
+        Node::Map(entries) => {
+            let typed = entries.iter().any(|(key, entry)| {
+                let Node::String(kind) = &entry.node else {
+                    return false;
+                };
+                let kind = kind.rsplit(':').next().unwrap_or_default();
+                key.starts_with('@') && key.ends_with(":type") && matches!(kind, "dateTime" | "date" | "time")
+            });
+            for (key, entry) in entries {
+                if typed && key == "#text" {
+                    read_datetime(entry);
+                } else {
+                    read_xml_datetimes(entry);
+                }
+            }
+        }
+        Node::Array(items) => items.iter_mut().for_each(read_xml_datetimes),
+        _ => {}
+    }
+}
+
+/// The paths, by key and index, of the date-times in `value`
+fn datetime_paths(value: &DocumentValue) -> Vec<Vec<String>> {
+    let mut found = Vec::new();
+    value.find_datetimes(&mut Vec::new(), &mut found);
+    found
+}
+
+fn has_comments(value: &DocumentValue) -> bool {
+    !value.comments.is_empty()
+        || match &value.node {
+            Node::Map(entries) => entries.iter().any(|(_, entry)| has_comments(entry)),
+            Node::Array(items) => items.iter().any(has_comments),
+            _ => false,
+        }
+}
+
+fn write_toml_comments(value: &DocumentValue, table: &mut toml_edit::Table) {
+    let Node::Map(entries) = &value.node else {
+        return;
+    };
+    for (key, entry) in entries {
+        let prefix: String = entry
+            .comments
+            .iter()
+            .map(|comment| if comment.is_empty() { "#\n".to_string() } else { format!("# {comment}\n") })
+            .collect();
+        if let Some(toml_edit::Item::Table(inner)) = table.get_mut(key) {
+            if !prefix.is_empty() {
+                inner.decor_mut().set_prefix(format!("\n{prefix}"));
+            }
+            write_toml_comments(entry, inner);
+        } else if let (false, Some(mut key)) = (prefix.is_empty(), table.key_mut(key)) {
+            key.leaf_decor_mut().set_prefix(prefix);
+        }
+    }
+}
+
+/// Make the strings of `table`, written from `value`, that are date-times in it into TOML date-times
+///
+/// Text TOML does not read as a date-time is written as RFC 3339 instead,
+/// and a date-time TOML has no form for, such as a time with an offset,
+/// stays a string.
+fn write_toml_datetimes(value: &DocumentValue, table: &mut toml_edit::Table) {
+    let Node::Map(entries) = &value.node else {
+        return;
+    };
+    for (key, entry) in entries {
+        match (table.get_mut(key), &entry.node) {
+            (Some(toml_edit::Item::Table(inner)), _) => write_toml_datetimes(entry, inner),
+            (Some(toml_edit::Item::ArrayOfTables(tables)), Node::Array(items)) => {
+                for (item, inner) in items.iter().zip(tables.iter_mut()) {
+                    write_toml_datetimes(item, inner);
+                }
+            }
+            (Some(toml_edit::Item::Value(inner)), _) => write_toml_value_datetimes(entry, inner),
+            _ => {}
+        }
+    }
+}
+
+fn write_toml_value_datetimes(value: &DocumentValue, inner: &mut toml_edit::Value) {
+    match (&value.node, inner) {
+        (Node::DateTime(datetime), inner) => {
+            let parsed = datetime.text.parse::<toml_edit::Datetime>().or_else(|_| datetime.rfc3339().parse());
+            if let Ok(parsed) = parsed {
+                let decor = inner.decor().clone();
+                *inner = toml_edit::Value::from(parsed);
+                *inner.decor_mut() = decor;
+            }
+        }
+        (Node::Array(items), toml_edit::Value::Array(array)) => {
+            for (entry, inner) in items.iter().zip(array.iter_mut()) {
+                write_toml_value_datetimes(entry, inner);
+            }
+        }
+        (Node::Map(entries), toml_edit::Value::InlineTable(table)) => {
+            for (key, entry) in entries {
+                if let Some(inner) = table.get_mut(key) {
+                    write_toml_value_datetimes(entry, inner);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_spans() {
+        let json = "{\n  \"name\": \"app\",\n  \"ports\": [80, 443],\n  \"tls\": {\"on\": true}\n}";
+        let value = read(json, Format::Json, &mut Vec::new()).unwrap();
+        let text = |value: &DocumentValue| &json[value.span.clone().unwrap()];
+        assert_eq!(text(&value), json);
+        assert_eq!(text(value.get("name").unwrap()), "\"app\"");
+        assert_eq!(text(value.get("ports").unwrap()), "[80, 443]");
+        let Node::Array(ports) = &value.get("ports").unwrap().node else { panic!() };
+        assert_eq!(text(&ports[1]), "443");
+        assert_eq!(text(value.get("tls").unwrap().get("on").unwrap()), "true");
+
+        // Other formats give no spans
+        assert_eq!(read("name: app\n", Format::Yaml, &mut Vec::new()).unwrap().span, None);
+    }
+
+    #[test]
+    fn test_toml_comments_round_trip() {
+        let toml = "# the app\nname = \"app\"\n\n# where it listens\n[server]\n# default port\nport = 8080\n";
+        let value = read(toml, Format::Toml, &mut Vec::new()).unwrap();
+        assert_eq!(value.get("name").unwrap().comments, ["the app"]);
+        assert_eq!(value.get("server").unwrap().comments, ["where it listens"]);
+        assert_eq!(value.get("server").unwrap().get("port").unwrap().comments, ["default port"]);
+
+        assert_eq!(write(&value, Format::Toml).unwrap(), toml);
+        // Formats without comments drop them
+        assert_eq!(write(&value, Format::Json).unwrap(), "{\n  \"name\": \"app\",\n  \"server\": {\n    \"port\": 8080\n  }\n}");
+    }
+
+    /// Convert `content` to JSON, edit it with `edit` and convert it back onto `content`
+    fn round_trip(content: &str, format: Format, edit: impl FnOnce(&mut Value)) -> String {
+        let mut json = read(content, format, &mut Vec::new()).unwrap().to_json();
+        edit(&mut json);
+        let edited = read(&json.to_string(), Format::Json, &mut Vec::new()).unwrap();
+        write_onto(&edited, format, content).unwrap()
+    }
+
+    #[test]
+    fn test_yaml_round_trip() {
+        let yaml = "# service settings\nname: app\n\n# where it listens\nserver:\n  port: 8080 # default\n  hosts:\n    - a\n    - b # backup\n  notes: |\n    # not a comment\n    text\nzeta: 1\nalpha: 2\n# end\n";
+        assert_eq!(round_trip(yaml, Format::Yaml, |_| {}), yaml);
+
+        let edited = round_trip(yaml, Format::Yaml, |json| {
+            json["server"]["port"] = 9090.into();
+            json["debug"] = true.into();
+            json.as_object_mut().unwrap().remove("zeta");
+        });
+        let expected = "# service settings\nname: app\n\n# where it listens\nserver:\n  port: 9090 # default\n  hosts:\n    - a\n    - b # backup\n  notes: |\n    # not a comment\n    text\nalpha: 2\ndebug: true\n# end\n";
+        assert_eq!(edited, expected);
+
+        let items = "- name: a # first\n  size: 1\n\n# the other\n- name: b\n";
+        let value = read(items, Format::Yaml, &mut Vec::new()).unwrap();
+        let Node::Array(read_items) = &value.node else { panic!() };
+        assert_eq!((read_items[1].blank_line, &read_items[1].comments), (true, &vec!["the other".to_string()]));
+        assert_eq!(read_items[0].get("name").unwrap().trailing.as_deref(), Some("first"));
+        assert_eq!(round_trip(items, Format::Yaml, |_| {}), items);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let toml = "# the app\nname = 'app'\nreleased = 1979-05-27\n\n[server]\nport = 8080 # default\nhosts = [ \"a\", \"b\" ]\n\n[[plugins]]\nname = \"x\"\n";
+        assert_eq!(round_trip(toml, Format::Toml, |_| {}), toml);
+
+        let edited = round_trip(toml, Format::Toml, |json| {
+            json["server"]["port"] = 9090.into();
+            json["server"]["tls"] = true.into();
+            json["plugins"].as_array_mut().unwrap().push(serde_json::json!({"name": "y"}));
+            json["logging"] = serde_json::json!({"level": "info"});
+        });
+        let expected = "# the app\nname = 'app'\nreleased = 1979-05-27\n\n[server]\nport = 9090 # default\nhosts = [ \"a\", \"b\" ]\ntls = true\n\n[[plugins]]\nname = \"x\"\n\n[[plugins]]\nname = \"y\"\n\n[logging]\nlevel = \"info\"\n";
+        assert_eq!(edited, expected);
+
+        // Without a readable original the result is written afresh
+        assert!(write_onto(&read("a = 1", Format::Toml, &mut Vec::new()).unwrap(), Format::Toml, "a = ").is_err());
+    }
+
+    #[test]
+    fn test_any_to_any() {
+        let mut warnings = Vec::new();
+        let value = read("A=1\nA=2\n", Format::Dotenv, &mut warnings).unwrap();
+        assert_eq!(warnings, ["dotenv: line 2: duplicate key `A`, first set on line 1; the last value is used"]);
+        assert_eq!(write(&value, Format::Yaml).unwrap(), "A: '2'\n");
+
+        let value = read("a,b\n1,x\n", Format::Csv, &mut warnings).unwrap();
+        let cbor = write(&value, Format::Cbor).unwrap();
+        assert_eq!(read(&cbor, Format::Cbor, &mut warnings).unwrap().to_json(), value.to_json());
+        assert_eq!(DocumentValue::from_json(value.to_json()), value);
+        assert_eq!(DocumentValue::from_json(Value::from(u64::MAX)).node, Node::Unsigned(u64::MAX));
+    }
+
+    #[test]
+    fn test_datetimes() {
+        let datetime = |value: &DocumentValue| match &value.node {
+            Node::DateTime(datetime) => datetime.text.clone(),
+            node => panic!("{node:?}"),
+        };
+        let toml = "a = 1979-05-27 07:32:00Z\nb = [1979-05-27]\nc = \"1979-05-27\"\n\n[t]\nd = 07:32:00\ne = { f = 2024-01-31T12:00:00.5+01:00 }\n\n[[x]]\ng = 2024-01-31T12:00:00\n";
+        let value = read(toml, Format::Toml, &mut Vec::new()).unwrap();
+        assert_eq!(datetime(value.get("a").unwrap()), "1979-05-27 07:32:00Z");
+        assert_eq!(value.to_json()["a"], "1979-05-27 07:32:00Z");
+        let Node::Array(b) = &value.get("b").unwrap().node else { panic!() };
+        assert_eq!(datetime(&b[0]), "1979-05-27");
+        assert_eq!(value.get("c").unwrap().node, Node::String("1979-05-27".to_string()));
+        let t = value.get("t").unwrap();
+        assert_eq!(datetime(t.get("d").unwrap()), "07:32:00");
+        assert_eq!(datetime(t.get("e").unwrap().get("f").unwrap()), "2024-01-31T12:00:00.5+01:00");
+        assert_eq!(value.to_json()["x"][0]["g"], "2024-01-31T12:00:00");
+
+        let epoch = value.clone().with_datetimes(DateTimeStyle::Epoch).to_json();
+        assert_eq!(epoch["a"], 296_638_320);
+        assert_eq!(epoch["t"]["e"]["f"], 1_706_698_800.5);
+        // With no offset, or no time, there is no instant to count to
+        assert_eq!((&epoch["b"][0], &epoch["x"][0]["g"]), (&Value::from("1979-05-27"), &Value::from("2024-01-31T12:00:00")));
+
+        let yaml = "when: 2001-12-14 21:59:43.10 -5\nquoted: '2001-12-14'\n";
+        let value = read(yaml, Format::Yaml, &mut Vec::new()).unwrap();
+        assert_eq!(datetime(value.get("when").unwrap()), "2001-12-14 21:59:43.10 -5");
+        assert_eq!(value.get("quoted").unwrap().node, Node::String("2001-12-14".to_string()));
+        let yaml = "base: &base {when: 2001-12-14}\njob:\n  <<: *base\n  when: '2001-12-15'\n";
+        let job = read(yaml, Format::Yaml, &mut Vec::new()).unwrap().get("job").unwrap().clone();
+        assert_eq!(job.get("when").unwrap().node, Node::String("2001-12-15".to_string()));
+        let toml = write(&value.clone().with_datetimes(DateTimeStyle::Rfc3339), Format::Toml).unwrap();
+        assert!(toml.contains("when = 2001-12-14T21:59:43.1-05:00\n"), "{toml}");
+        // TOML has no date-time written as YAML's, which RFC 3339 keeps one
+        assert_eq!(write(&value.clone().with_datetimes(DateTimeStyle::Preserve), Format::Toml).unwrap(), toml);
+        let json = write(&value.with_datetimes(DateTimeStyle::Preserve), Format::Json).unwrap();
+        assert!(json.contains("\"when\": \"2001-12-14 21:59:43.10 -5\""), "{json}");
+
+        let xml = "<e xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"><at xsi:type=\"xs:dateTime\">2024-01-31T12:00:00Z</at><on>2024-01-31</on></e>";
+        let value = read(xml, Format::Xml, &mut Vec::new()).unwrap();
+        let e = value.get("e").unwrap();
+        assert_eq!(datetime(e.get("at").unwrap().get("#text").unwrap()), "2024-01-31T12:00:00Z");
+        assert_eq!(e.get("on").unwrap().node, Node::String("2024-01-31".to_string()));
+    }
+
+    #[test]
+    fn test_datetime_round_trips() {
+        let convert = |content: &str, from: Format, to: Format, style: DateTimeStyle| {
+            write(&read(content, from, &mut Vec::new()).unwrap().with_datetimes(style), to).unwrap()
+        };
+
+        // A string that would read back as a timestamp is quoted, so it stays one
+        let yaml = convert("{\"s\": \"2024-01-31 12:00:00Z\"}", Format::Json, Format::Yaml, DateTimeStyle::Preserve);
+        assert_eq!(yaml, "s: '2024-01-31 12:00:00Z'\n");
+        for style in [DateTimeStyle::Preserve, DateTimeStyle::Rfc3339, DateTimeStyle::Epoch] {
+            let json = convert(&yaml, Format::Yaml, Format::Json, style);
+            assert_eq!(serde_json::from_str::<Value>(&json).unwrap()["s"], "2024-01-31 12:00:00Z", "{style:?}");
+        }
+
+        // A date alone is a timestamp only with two-digit months and days
+        let yaml = "d: 2024-1-1\nt: 2024-1-1 9:05:00Z\n";
+        let json: Value = serde_json::from_str(&convert(yaml, Format::Yaml, Format::Json, DateTimeStyle::Rfc3339)).unwrap();
+        assert_eq!((&json["d"], &json["t"]), (&Value::from("2024-1-1"), &Value::from("2024-01-01T09:05:00Z")));
+
+        // By default timestamps keep their text, and stay timestamps
+        let yaml = "when: 2001-12-14 21:59:43.10 -5\n";
+        let value = read(yaml, Format::Yaml, &mut Vec::new()).unwrap().with_datetimes(DateTimeStyle::default());
+        assert_eq!(write(&value, Format::Yaml).unwrap(), yaml);
+        assert_eq!(crate::formats::pretty::write_yaml(&value, &crate::formats::FormatOptions::default()).unwrap(), yaml);
+        let toml = convert(yaml, Format::Yaml, Format::Toml, DateTimeStyle::default());
+        assert_eq!(toml, "when = 2001-12-14T21:59:43.1-05:00\n");
+        assert_eq!(convert(&toml, Format::Toml, Format::Yaml, DateTimeStyle::default()), "when: 2001-12-14T21:59:43.1-05:00\n");
+
+        // TOML date-times stay date-times through JSON
+        let toml = "d = 1979-05-27\nt = 12:30:00\n";
+        assert_eq!(convert(&convert(toml, Format::Toml, Format::Json, DateTimeStyle::default()), Format::Json, Format::Toml, DateTimeStyle::default()), toml);
+    }
+}
@@ -0,0 +1,260 @@
+//! `MessagePack` format support for document conversion
+//!
+//! A `MessagePack` document is a single value, converted to and from JSON,
+//! and through JSON to YAML. Binary data becomes a base64 string, and map
+//! keys that are integers, booleans, floats or nil become their JSON text,
+//! such as `"1"`; keys that are arrays or maps, extension types, strings
+//! that are not UTF-8, and floats that are not finite have no JSON form.
+//! JSON integers are written in the smallest encoding that holds them.
+//! Arrays and maps nested more than 128 deep are refused.
+//!
+//! The transports carry documents as text, so there a `MessagePack` document
+//! is its bytes in base64, which [`decode_text`] and [`encode_text`] read
+//! and write; the functions taking bytes are for code embedding the server.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rmpv::Value as Msgpack;
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// Arrays and maps opened inside one another before a document is refused
+const MAX_DEPTH: usize = 128;
+
+/// Convert `MessagePack` bytes to JSON
+///
+/// # Errors
+///
+/// Fails where `bytes` are not one `MessagePack` value, or hold one JSON
+/// has no form for.
+pub fn msgpack_to_json(bytes: &[u8]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&read(bytes).map_err(|diagnostic| anyhow!("Invalid MessagePack: {diagnostic}"))?)?)
+}
+
+/// Convert JSON to `MessagePack` bytes
+///
+/// # Errors
+///
+/// Fails where `json` does not parse.
+pub fn json_to_msgpack(json: &str) -> Result<Vec<u8>> {
+    let value: Value = serde_json::from_str(json)?;
+    Ok(rmp_serde::to_vec(&value)?)
+}
+
+/// Convert `MessagePack` bytes to YAML
+///
+/// # Errors
+///
+/// As [`msgpack_to_json`] does.
+pub fn msgpack_to_yaml(bytes: &[u8]) -> Result<String> {
+    super::yaml::json_to_yaml(&msgpack_to_json(bytes)?)
+}
+
+/// Convert YAML to `MessagePack` bytes
+///
+/// # Errors
+///
+/// Fails where `yaml` does not parse, or has a key JSON cannot hold.
+pub fn yaml_to_msgpack(yaml: &str) -> Result<Vec<u8>> {
+    json_to_msgpack(&super::yaml::yaml_to_json(yaml)?)
+}
+
+/// Validate `MessagePack` bytes, returning any errors
+///
+/// # Errors
+///
+/// Never; problems with `bytes` are the diagnostics returned.
+pub fn validate_msgpack(bytes: &[u8]) -> Result<Vec<String>> {
+    Ok(diagnostics(bytes).into_iter().map(|diagnostic| format!("Invalid MessagePack: {diagnostic}")).collect())
+}
+
+/// The bytes of a `MessagePack` document written as base64, ignoring whitespace
+///
+/// # Errors
+///
+/// Fails where `text` is not base64.
+pub fn decode_text(text: &str) -> Result<Vec<u8>> {
+    let compact: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD.decode(compact).map_err(|e| anyhow!("MessagePack is sent as base64: {e}"))
+}
+
+/// A `MessagePack` document written as base64
+#[must_use]
+pub fn encode_text(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+/// Why `MessagePack` bytes cannot be read as JSON, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MsgpackDiagnostic {
+    /// Bytes read before the problem was found
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MsgpackDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+/// The first problem with `bytes`, or none when they hold one value JSON can hold
+#[must_use]
+pub fn diagnostics(bytes: &[u8]) -> Vec<MsgpackDiagnostic> {
+    read(bytes).err().into_iter().collect()
+}
+
+fn read(bytes: &[u8]) -> std::result::Result<Value, MsgpackDiagnostic> {
+    let mut rest = bytes;
+    // The decoder spends two levels on each array or map and up to three on what lies
+    // inside the last, so it stops runaway nesting here and `to_json` keeps the exact limit
+    let value = rmpv::decode::read_value_with_max_depth(&mut rest, 2 * MAX_DEPTH + 3).map_err(|e| MsgpackDiagnostic {
+        offset: bytes.len() - rest.len(),
+        message: match e {
+            rmpv::decode::Error::InvalidMarkerRead(e) | rmpv::decode::Error::InvalidDataRead(e)
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                "unexpected end of data".to_string()
+            }
+            rmpv::decode::Error::DepthLimitExceeded => "items nested too deeply".to_string(),
+            e => e.to_string(),
+        },
+    })?;
+    if !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        return Err(MsgpackDiagnostic { offset, message: format!("{} bytes after the value", rest.len()) });
+    }
+    // Decoding keeps no positions, so problems with the value are placed at its start
+    to_json(value, 0).map_err(|message| MsgpackDiagnostic { offset: 0, message })
+}
+
+/// `value` as JSON, where it lies inside `depth` arrays and maps
+fn to_json(value: Msgpack, depth: usize) -> std::result::Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err("items nested too deeply".to_string());
+    }
+    Ok(match value {
+        Msgpack::Nil => Value::Null,
+        Msgpack::Boolean(b) => Value::Bool(b),
+        Msgpack::Integer(i) => match (i.as_u64(), i.as_i64()) {
+            (Some(u), _) => Value::from(u),
+            (_, Some(i)) => Value::from(i),
+            _ => return Err(format!("integer {i} out of range")),
+        },
+        Msgpack::F32(f) => float(f64::from(f))?,
+        Msgpack::F64(f) => float(f)?,
+        Msgpack::String(s) => match s.into_str() {
+            Some(s) => Value::String(s),
+            None => return Err("string is not UTF-8".to_string()),
+        },
+        Msgpack::Binary(bytes) => Value::String(STANDARD.encode(bytes)),
+        Msgpack::Array(items) => {
+            Value::Array(items.into_iter().map(|item| to_json(item, depth + 1)).collect::<std::result::Result<_, _>>()?)
+        }
+        Msgpack::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                map.insert(key_text(key)?, to_json(value, depth + 1)?);
+            }
+            Value::Object(map)
+        }
+        Msgpack::Ext(kind, _) => return Err(format!("extension type {kind} has no JSON form")),
+    })
+}
+
+fn float(f: f64) -> std::result::Result<Value, String> {
+    Number::from_f64(f).map(Value::Number).ok_or_else(|| format!("{f} has no JSON form"))
+}
+
+/// The JSON key of a map key
+fn key_text(key: Msgpack) -> std::result::Result<String, String> {
+    match key {
+        Msgpack::String(s) => s.into_str().ok_or_else(|| "string is not UTF-8".to_string()),
+        Msgpack::Binary(bytes) => Ok(STANDARD.encode(bytes)),
+        Msgpack::Nil | Msgpack::Boolean(_) | Msgpack::Integer(_) | Msgpack::F32(_) | Msgpack::F64(_) => {
+            Ok(to_json(key, 0)?.to_string())
+        }
+        Msgpack::Array(_) | Msgpack::Map(_) => Err("a map key that is an array or map has no JSON form".to_string()),
+        Msgpack::Ext(kind, _) => Err(format!("extension type {kind} has no JSON form")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(bytes: &[u8]) -> Value {
+        serde_json::from_str(&msgpack_to_json(bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({"name": "connector", "port": 8080, "ratio": 0.5, "tags": ["a", null, true], "big": u64::MAX, "neg": -3});
+        let bytes = json_to_msgpack(&value.to_string()).unwrap();
+        assert_eq!(to_value(&bytes), value);
+
+        // Integers take the smallest encoding
+        assert_eq!(json_to_msgpack("[1, -1, 300]").unwrap(), [0x93, 0x01, 0xff, 0xcd, 0x01, 0x2c]);
+
+        let yaml = msgpack_to_yaml(&bytes).unwrap();
+        assert!(yaml.contains("name: connector"), "{yaml}");
+        assert_eq!(yaml_to_msgpack(&yaml).unwrap(), bytes);
+
+        assert_eq!(decode_text(&format!(" {}\n", encode_text(&bytes))).unwrap(), bytes);
+        assert!(decode_text("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_values_without_json_form() {
+        // Binary data is base64, and scalar keys their JSON text
+        let mut bytes = Vec::new();
+        let map = Msgpack::Map(vec![
+            (Msgpack::from(1), Msgpack::Binary(vec![0, 1, 2])),
+            (Msgpack::Boolean(true), Msgpack::Nil),
+        ]);
+        rmpv::encode::write_value(&mut bytes, &map).unwrap();
+        assert_eq!(to_value(&bytes), json!({"1": "AAEC", "true": null}));
+
+        let problem = |value: Msgpack| {
+            let mut bytes = Vec::new();
+            rmpv::encode::write_value(&mut bytes, &value).unwrap();
+            diagnostics(&bytes).into_iter().next().unwrap().message
+        };
+        assert_eq!(problem(Msgpack::Ext(5, vec![1])), "extension type 5 has no JSON form");
+        assert_eq!(problem(Msgpack::F64(f64::NAN)), "NaN has no JSON form");
+        assert_eq!(problem(Msgpack::Map(vec![(Msgpack::Array(vec![]), Msgpack::Nil)])), "a map key that is an array or map has no JSON form");
+        // A string of one byte, 0xff
+        assert_eq!(diagnostics(&[0xa1, 0xff])[0].message, "string is not UTF-8");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        // An array of two items holding one
+        assert_eq!(
+            validate_msgpack(&[0x92, 0x01]).unwrap(),
+            ["Invalid MessagePack: byte 2: unexpected end of data"]
+        );
+        assert_eq!(validate_msgpack(&[0x01, 0x02, 0x03]).unwrap(), ["Invalid MessagePack: byte 1: 2 bytes after the value"]);
+        assert_eq!(validate_msgpack(&[0x90]).unwrap(), Vec::<String>::new());
+        assert!(msgpack_to_json(&[]).unwrap_err().to_string().starts_with("Invalid MessagePack: byte 0"));
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        // Arrays of one item, around nil
+        let nested = |depth: usize| [vec![0x91; depth], vec![0xc0]].concat();
+        let deepest = "[".repeat(MAX_DEPTH) + "null" + &"]".repeat(MAX_DEPTH);
+        assert_eq!(msgpack_to_json(&nested(MAX_DEPTH)).unwrap().split_whitespace().collect::<String>(), deepest);
+        // A string inside the deepest array, under a map key
+        let string = [vec![0x81, 0xa1, b'k'], vec![0x91; MAX_DEPTH - 1], vec![0xa1, b's']].concat();
+        assert!(msgpack_to_json(&string).is_ok());
+        for depth in [MAX_DEPTH + 1, 500] {
+            let found = diagnostics(&nested(depth));
+            assert_eq!(found.len(), 1, "{found:?}");
+            assert_eq!(found[0].message, "items nested too deeply");
+            assert!(msgpack_to_json(&nested(depth)).is_err());
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! NDJSON (JSON Lines) format support
+//!
+//! An NDJSON document holds one JSON value, a record, on each line. The
+//! functions taking a reader go through it a line at a time, and
+//! [`array_to_ndjson`] through a JSON array a record at a time, so memory
+//! stays at the size of the largest record however long the input. The
+//! functions taking a string are for documents already in memory, as the
+//! transports hold them.
+//!
+//! Blank lines are skipped, lines may end in `\r\n`, and a byte order mark
+//! before the first record is ignored. As JSON, a document is an array of
+//! its records.
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::{DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde_json::Value;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Invalid lines reported before validation stops reading
+pub const MAX_DIAGNOSTICS: usize = 100;
+
+/// A line that does not hold a JSON value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdjsonDiagnostic {
+    /// Line of the document, from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for NdjsonDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A record and the line it was read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub line: usize,
+    pub value: Value,
+}
+
+/// The records of an NDJSON stream, read a line at a time
+///
+/// An invalid line is an error naming it, after which reading goes on with
+/// the next line; failing to read ends the iteration after its error.
+pub struct Records<R> {
+    reader: R,
+    line: usize,
+    buffer: String,
+    done: bool,
+}
+
+/// Read the records of `reader` one line at a time
+pub fn records<R: BufRead>(reader: R) -> Records<R> {
+    Records { reader, line: 0, buffer: String::new(), done: false }
+}
+
+impl<R: BufRead> Records<R> {
+    /// Where the next line with anything on it lies in the buffer, or `None` at the end
+    fn next_line(&mut self) -> Result<Option<std::ops::Range<usize>>> {
+        loop {
+            self.buffer.clear();
+            let read = self.reader.read_line(&mut self.buffer).with_context(|| format!("reading line {}", self.line + 1))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            let end = self.buffer.trim_end_matches(['\n', '\r']).len();
+            let start = if self.line == 1 && self.buffer.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
+            if !self.buffer[start.min(end)..end].trim().is_empty() {
+                return Ok(Some(start..end));
+            }
+        }
+    }
+
+    /// Parse the next line, telling a failure to read from an invalid line
+    fn read(&mut self) -> Result<Option<std::result::Result<Record, NdjsonDiagnostic>>> {
+        let Some(text) = self.next_line()? else {
+            return Ok(None);
+        };
+        let parsed = serde_json::from_str(&self.buffer[text]);
+        let line = self.line;
+        Ok(Some(parsed.map(|value| Record { line, value }).map_err(|e| NdjsonDiagnostic { line, message: e.to_string() })))
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read() {
+            Ok(Some(record)) => Some(record.map_err(|diagnostic| anyhow!("Invalid NDJSON: {diagnostic}"))),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Every invalid line of `reader`, up to [`MAX_DIAGNOSTICS`]
+///
+/// # Errors
+///
+/// Fails where `reader` does, or a line is longer than a record may be.
+pub fn validate_reader<R: BufRead>(reader: R) -> Result<Vec<NdjsonDiagnostic>> {
+    let mut records = records(reader);
+    let mut diagnostics = Vec::new();
+    while diagnostics.len() < MAX_DIAGNOSTICS {
+        match records.read()? {
+            Some(Ok(_)) => {}
+            Some(Err(diagnostic)) => diagnostics.push(diagnostic),
+            None => break,
+        }
+    }
+    Ok(diagnostics)
+}
+
+/// Write the records of `reader` to `writer` as a JSON array, one record a line,
+/// returning how many there were; the first invalid line fails it
+///
+/// # Errors
+///
+/// Fails on the first invalid line, or where `reader` or `writer` do.
+pub fn ndjson_to_array<R: BufRead, W: Write>(reader: R, mut writer: W) -> Result<usize> {
+    let mut count = 0;
+    writer.write_all(b"[")?;
+    for record in records(reader) {
+        writer.write_all(if count == 0 { b"\n" } else { b",\n" })?;
+        serde_json::to_writer(&mut writer, &record?.value)?;
+        count += 1;
+    }
+    writer.write_all(if count == 0 { b"]\n" } else { b"\n]\n" })?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Write the items of the JSON array in `reader` to `writer` as NDJSON, returning how many there were
+///
+/// # Errors
+///
+/// Fails where `reader` does not hold a JSON array, or `reader` or `writer`
+/// fail.
+pub fn array_to_ndjson<R: Read, W: Write>(reader: R, mut writer: W) -> Result<usize> {
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let count = Items { writer: &mut writer }.deserialize(&mut deserializer).map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+    deserializer.end().map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Writes each item of an array as it is read, rather than collecting them
+struct Items<'a, W> {
+    writer: &'a mut W,
+}
+
+impl<'de, W: Write> DeserializeSeed<'de> for Items<'_, W> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, W: Write> Visitor<'de> for Items<'_, W> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON array, whose items become NDJSON records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut items: A) -> std::result::Result<usize, A::Error> {
+        let mut count = 0;
+        while let Some(item) = items.next_element::<Value>()? {
+            serde_json::to_writer(&mut *self.writer, &item).map_err(serde::de::Error::custom)?;
+            self.writer.write_all(b"\n").map_err(serde::de::Error::custom)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Convert NDJSON to a JSON array
+///
+/// # Errors
+///
+/// Fails on the first invalid line.
+pub fn ndjson_to_json(ndjson: &str) -> Result<String> {
+    let values = records(ndjson.as_bytes()).map(|record| record.map(|record| record.value)).collect::<Result<Vec<_>>>()?;
+    Ok(serde_json::to_string_pretty(&values)?)
+}
+
+/// Convert a JSON array to NDJSON
+///
+/// # Errors
+///
+/// Fails where `json` is not an array.
+pub fn json_to_ndjson(json: &str) -> Result<String> {
+    let mut ndjson = Vec::new();
+    array_to_ndjson(json.as_bytes(), &mut ndjson)?;
+    Ok(String::from_utf8(ndjson)?)
+}
+
+/// Validate NDJSON, returning an error for each invalid line, up to [`MAX_DIAGNOSTICS`]
+///
+/// # Errors
+///
+/// Fails where a line is longer than a record may be.
+pub fn validate_ndjson(ndjson: &str) -> Result<Vec<String>> {
+    let diagnostics = validate_reader(ndjson.as_bytes())?;
+    Ok(diagnostics.into_iter().map(|diagnostic| format!("Invalid NDJSON: {diagnostic}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_records() {
+        let ndjson = "\u{feff}{\"a\": 1}\r\n\n  \n[1, 2]\nnot json\n\"last\"";
+        let read: Vec<_> = records(ndjson.as_bytes()).map(|record| record.map_err(|e| e.to_string())).collect();
+        assert_eq!(
+            read,
+            [
+                Ok(Record { line: 1, value: json!({"a": 1}) }),
+                Ok(Record { line: 4, value: json!([1, 2]) }),
+                Err("Invalid NDJSON: line 5: expected ident at line 1 column 2".to_string()),
+                Ok(Record { line: 6, value: json!("last") }),
+            ]
+        );
+
+        let diagnostics = validate_reader(ndjson.as_bytes()).unwrap();
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.line).collect::<Vec<_>>(), [5]);
+        assert_eq!(validate_ndjson("{}\n{}\n").unwrap(), Vec::<String>::new());
+
+        // Reading stops at the cap rather than going through everything
+        let invalid = "x\n".repeat(MAX_DIAGNOSTICS + 5);
+        assert_eq!(validate_reader(invalid.as_bytes()).unwrap().len(), MAX_DIAGNOSTICS);
+    }
+
+    #[test]
+    fn test_array_round_trip() {
+        let mut array = Vec::new();
+        assert_eq!(ndjson_to_array("{\"a\":1}\n\n[true]\n".as_bytes(), &mut array).unwrap(), 2);
+        assert_eq!(String::from_utf8(array.clone()).unwrap(), "[\n{\"a\":1},\n[true]\n]\n");
+
+        let mut ndjson = Vec::new();
+        assert_eq!(array_to_ndjson(Cursor::new(array), &mut ndjson).unwrap(), 2);
+        assert_eq!(String::from_utf8(ndjson).unwrap(), "{\"a\":1}\n[true]\n");
+
+        let mut empty = Vec::new();
+        ndjson_to_array("".as_bytes(), &mut empty).unwrap();
+        assert_eq!(empty, b"[]\n");
+
+        assert!(ndjson_to_array("{}\n{\n".as_bytes(), Vec::new()).unwrap_err().to_string().contains("line 2"));
+        assert!(array_to_ndjson(r#"{"a": 1}"#.as_bytes(), Vec::new()).unwrap_err().to_string().contains("expected a JSON array"));
+        assert!(array_to_ndjson("[1] [2]".as_bytes(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_string_conversions() {
+        let json = ndjson_to_json("{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), json!([{"id": 1}, {"id": 2}]));
+        assert_eq!(json_to_ndjson(&json).unwrap(), "{\"id\":1}\n{\"id\":2}\n");
+        assert_eq!(
+            validate_ndjson("{}\n{oops}\n").unwrap(),
+            ["Invalid NDJSON: line 2: key must be a string at line 1 column 2"]
+        );
+    }
+}
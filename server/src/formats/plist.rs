@@ -0,0 +1,324 @@
+//! Apple property list support for document conversion
+//!
+//! Property lists are read in their XML, binary (`bplist00`) and old ASCII
+//! forms, and converted to and from JSON: dictionaries become objects,
+//! data a base64 string, dates their ISO 8601 text, such as
+//! `2024-01-31T12:00:00Z`, and UIDs of keyed archives `{"CF$UID": n}`
+//! objects. JSON is written as an XML property list, as `Info.plist` files
+//! are kept in source control, or as a binary one for code embedding the
+//! server; JSON strings stay strings, so dates and data read back as text.
+//! Null and floats that are not finite have no property list form, and
+//! arrays and dictionaries nested more than 128 deep are refused both ways.
+//!
+//! The transports carry documents as text, so there a binary property list
+//! is its bytes in base64, which [`decode_text`] tells from XML and ASCII
+//! and [`encode_text`] writes.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use plist::Value as Plist;
+use serde_json::{Map, Number, Value};
+use std::fmt;
+use std::io::Cursor;
+
+/// Key of the object a UID becomes, as in keyed archives
+const UID_KEY: &str = "CF$UID";
+
+/// Arrays and dictionaries opened inside one another before a document is refused
+const MAX_DEPTH: usize = 128;
+
+/// Convert a property list, in any of its forms, to JSON
+///
+/// # Errors
+///
+/// Fails where `bytes` are not a property list, or hold a value JSON has no
+/// form for.
+pub fn plist_to_json(bytes: &[u8]) -> Result<String> {
+    let value = read(bytes).map_err(|diagnostic| anyhow!("Invalid property list: {diagnostic}"))?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Convert JSON to an XML property list
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or holds null, a float that is not
+/// finite, or a UID.
+pub fn json_to_plist(json: &str) -> Result<String> {
+    let mut xml = Vec::new();
+    let options = plist::XmlWriteOptions::default().indent(b'\t', 1);
+    from_json(&serde_json::from_str(json)?, "", 0)?.to_writer_xml_with_options(&mut xml, &options)?;
+    let mut xml = String::from_utf8(xml)?;
+    xml.push('\n');
+    Ok(xml)
+}
+
+/// Convert JSON to a binary property list
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or holds null or a float that is not
+/// finite.
+pub fn json_to_binary_plist(json: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    from_json(&serde_json::from_str(json)?, "", 0)?.to_writer_binary(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Validate a property list, returning any errors
+///
+/// # Errors
+///
+/// Never; problems with `bytes` are the diagnostics returned.
+pub fn validate_plist(bytes: &[u8]) -> Result<Vec<String>> {
+    Ok(diagnostics(bytes).into_iter().map(|diagnostic| format!("Invalid property list: {diagnostic}")).collect())
+}
+
+/// The bytes of a property list sent as text: base64 for a binary one, else the text itself
+///
+/// # Errors
+///
+/// Fails where `text` starts as base64 of a binary property list but is not
+/// base64.
+pub fn decode_text(text: &str) -> Result<Vec<u8>> {
+    // "bplist" in base64
+    if !text.trim_start().starts_with("YnBsaXN0") {
+        return Ok(text.as_bytes().to_vec());
+    }
+    let compact: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD.decode(compact).map_err(|e| anyhow!("Binary property lists are sent as base64: {e}"))
+}
+
+/// A property list as text: a binary one as base64, else its text
+#[must_use]
+pub fn encode_text(bytes: &[u8]) -> String {
+    if bytes.starts_with(b"bplist") {
+        STANDARD.encode(bytes)
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Why a property list cannot be read as JSON, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlistDiagnostic {
+    /// Bytes read before the problem was found, when known
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for PlistDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "byte {}: {}", offset, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// The first problem with `bytes`, or none when they hold a property list JSON can hold
+#[must_use]
+pub fn diagnostics(bytes: &[u8]) -> Vec<PlistDiagnostic> {
+    read(bytes).err().into_iter().collect()
+}
+
+fn read(bytes: &[u8]) -> std::result::Result<Value, PlistDiagnostic> {
+    let value = Plist::from_reader(Cursor::new(bytes)).map_err(|e| describe(&e))?;
+    // Reading keeps no positions, so problems with the value are placed nowhere
+    to_json(value, 0).map_err(|message| PlistDiagnostic { offset: None, message })
+}
+
+/// A diagnostic in words from the error's kind, which the crate only shows in its debug form
+fn describe(error: &plist::Error) -> PlistDiagnostic {
+    if let Some(io) = error.as_io() {
+        return PlistDiagnostic { offset: None, message: io.to_string() };
+    }
+    let text = error.to_string();
+    let kind = text.split([' ', '{', '(']).next().unwrap_or_default();
+    let mut message = String::new();
+    for c in kind.chars() {
+        if c.is_ascii_uppercase() && !message.is_empty() {
+            message.push(' ');
+        }
+        message.push(c.to_ascii_lowercase());
+    }
+    let offset = text.rsplit_once("(offset ").and_then(|(_, rest)| rest.trim_end_matches(')').parse().ok());
+    PlistDiagnostic { offset, message }
+}
+
+/// `value` as JSON, where it lies inside `depth` arrays and dictionaries
+fn to_json(value: Plist, depth: usize) -> std::result::Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("arrays and dictionaries nested more than {MAX_DEPTH} deep"));
+    }
+    Ok(match value {
+        Plist::Boolean(b) => Value::Bool(b),
+        Plist::Integer(i) => match (i.as_unsigned(), i.as_signed()) {
+            (Some(u), _) => Value::from(u),
+            (_, Some(i)) => Value::from(i),
+            _ => return Err(format!("integer {i} out of range")),
+        },
+        Plist::Real(f) => Number::from_f64(f).map(Value::Number).ok_or_else(|| format!("{f} has no JSON form"))?,
+        Plist::String(s) => Value::String(s),
+        Plist::Data(bytes) => Value::String(STANDARD.encode(bytes)),
+        Plist::Date(date) => Value::String(date.to_xml_format()),
+        Plist::Uid(uid) => {
+            let mut object = Map::new();
+            object.insert(UID_KEY.to_string(), Value::from(uid.get()));
+            Value::Object(object)
+        }
+        Plist::Array(items) => {
+            Value::Array(items.into_iter().map(|item| to_json(item, depth + 1)).collect::<std::result::Result<_, _>>()?)
+        }
+        Plist::Dictionary(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                map.insert(key, to_json(value, depth + 1)?);
+            }
+            Value::Object(map)
+        }
+        value => return Err(format!("{value:?} has no JSON form")),
+    })
+}
+
+/// The property list of `value`, at `path` for errors, inside `depth` arrays and objects
+fn from_json(value: &Value, path: &str, depth: usize) -> Result<Plist> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Property lists nest at most {MAX_DEPTH} deep; `{path}` is deeper"));
+    }
+    Ok(match value {
+        Value::Null => return Err(anyhow!("Property lists have no null; `{}` is null", if path.is_empty() { "." } else { path })),
+        Value::Bool(b) => Plist::Boolean(*b),
+        Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => Plist::Integer(u.into()),
+            (_, Some(i), _) => Plist::Integer(i.into()),
+            (_, _, Some(f)) => Plist::Real(f),
+            _ => return Err(anyhow!("{n} has no property list form")),
+        },
+        Value::String(s) => Plist::String(s.clone()),
+        Value::Array(items) => Plist::Array(
+            items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| from_json(item, &format!("{path}[{index}]"), depth + 1))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(object) => if let (1, Some(uid)) = (object.len(), object.get(UID_KEY).and_then(Value::as_u64)) { Plist::Uid(plist::Uid::new(uid)) } else {
+            let mut dictionary = plist::Dictionary::new();
+            for (key, item) in object {
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                dictionary.insert(key.clone(), from_json(item, &path, depth + 1)?);
+            }
+            Plist::Dictionary(dictionary)
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const INFO: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>CFBundleIdentifier</key>
+	<string>com.example.app</string>
+	<key>CFBundleVersion</key>
+	<integer>42</integer>
+	<key>LSRequiresIPhoneOS</key>
+	<true/>
+	<key>Released</key>
+	<date>2024-01-31T12:00:00Z</date>
+	<key>Icon</key>
+	<data>AAEC</data>
+	<key>UIRequiredDeviceCapabilities</key>
+	<array>
+		<string>arm64</string>
+	</array>
+	<key>Scale</key>
+	<real>1.5</real>
+</dict>
+</plist>
+"#;
+
+    fn to_value(bytes: &[u8]) -> Value {
+        serde_json::from_str(&plist_to_json(bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_plist_to_json() {
+        let expected = json!({
+            "CFBundleIdentifier": "com.example.app",
+            "CFBundleVersion": 42,
+            "LSRequiresIPhoneOS": true,
+            "Released": "2024-01-31T12:00:00Z",
+            "Icon": "AAEC",
+            "UIRequiredDeviceCapabilities": ["arm64"],
+            "Scale": 1.5,
+        });
+        assert_eq!(to_value(INFO.as_bytes()), expected);
+
+        // The old ASCII form, as in some Xcode project files
+        assert_eq!(to_value(b"{ name = app; tags = (a, b); }"), json!({"name": "app", "tags": ["a", "b"]}));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({"name": "app", "build": 7, "debug": false, "ratio": 0.25, "nested": {"list": [1, "two"]}, "big": u64::MAX, "neg": -3});
+        let xml = json_to_plist(&value.to_string()).unwrap();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE plist"), "{xml}");
+        assert!(xml.contains("\t<key>build</key>\n\t<integer>7</integer>\n"), "{xml}");
+        assert_eq!(to_value(xml.as_bytes()), value);
+
+        let binary = json_to_binary_plist(&value.to_string()).unwrap();
+        assert!(binary.starts_with(b"bplist00"));
+        assert_eq!(to_value(&binary), value);
+        assert_eq!(decode_text(&format!(" {}\n", encode_text(&binary))).unwrap(), binary);
+        assert_eq!(encode_text(INFO.as_bytes()), INFO);
+        assert_eq!(decode_text(INFO).unwrap(), INFO.as_bytes());
+
+        // UIDs only have a binary form
+        let archive = json_to_binary_plist(r#"{"$top": {"root": {"CF$UID": 1}}}"#).unwrap();
+        assert_eq!(to_value(&archive), json!({"$top": {"root": {"CF$UID": 1}}}));
+        assert!(json_to_plist(r#"{"root": {"CF$UID": 1}}"#).is_err());
+
+        assert_eq!(json_to_plist(r#"{"a": [1, null]}"#).unwrap_err().to_string(), "Property lists have no null; `a[1]` is null");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert_eq!(validate_plist(INFO.as_bytes()).unwrap(), Vec::<String>::new());
+
+        let unclosed = "<plist version=\"1.0\"><dict><key>a</key><string>b</string></plist>";
+        let found = diagnostics(unclosed.as_bytes());
+        assert_eq!(found.len(), 1);
+        assert!(found[0].offset.is_some(), "{found:?}");
+
+        let unknown = validate_plist(b"<plist version=\"1.0\"><dict><key>a</key><colour>red</colour></dict></plist>").unwrap();
+        assert!(unknown[0].starts_with("Invalid property list: byte "), "{unknown:?}");
+        assert!(unknown[0].ends_with("unknown xml element"), "{unknown:?}");
+
+        let binary = json_to_binary_plist(r#"{"a": [1, 2, 3]}"#).unwrap();
+        assert!(!diagnostics(&binary[..binary.len() - 10]).is_empty());
+        assert!(decode_text("YnBsaXN0 not base64!").is_err());
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let nested = |depth: usize| format!("<plist version=\"1.0\">{}{}</plist>", "<array>".repeat(depth), "</array>".repeat(depth));
+        assert!(plist_to_json(nested(MAX_DEPTH + 1).as_bytes()).is_ok());
+        let found = diagnostics(nested(2000).as_bytes());
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert_eq!(found[0].message, format!("arrays and dictionaries nested more than {MAX_DEPTH} deep"));
+        assert!(plist_to_json(nested(2000).as_bytes()).is_err());
+
+        // JSON text nests at most 128 deep, so a deeper value is built directly
+        let deep = (0..500).fold(json!(1), |inner, _| json!([inner]));
+        let error = from_json(&deep, "", 0).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("Property lists nest at most {MAX_DEPTH} deep; `[0][0]")), "{error}");
+        assert!(from_json(&(0..MAX_DEPTH).fold(json!(1), |inner, _| json!([inner])), "", 0).is_ok());
+    }
+}
@@ -0,0 +1,272 @@
+//! Formats added at runtime
+//!
+//! A [`FormatPlugin`] converts one format to and from canonical JSON and
+//! validates it, all on byte buffers. Conversions between a plugin format
+//! and any other format go through canonical JSON, so a plugin only needs
+//! to know its own format. Plugins are held by the [`FormatRegistry`] of
+//! [`Formats`](super::Formats); embedders register native Rust plugins
+//! there directly.
+//!
+//! With the `wasm-plugins` feature, [`load`] also instantiates every
+//! `*.wasm` module in the configured directory as a sandboxed
+//! [`WasmPlugin`](super::wasm::WasmPlugin), named after its file.
+
+use crate::capabilities::{CapabilityInfo, CapabilityRegistry};
+use crate::core::Format;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// Version of the interface plugins implement, as `ulc_abi_version` must return it
+pub const ABI_VERSION: i32 = 1;
+
+/// Where plugins are loaded from and what each call may use
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct PluginConfig {
+    /// Directory whose `*.wasm` modules are loaded at startup
+    pub dir: Option<PathBuf>,
+    /// Largest linear memory a plugin may grow to, in bytes
+    pub max_memory_bytes: usize,
+    /// Instructions a plugin may execute per call, as wasmtime fuel
+    pub fuel: u64,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_memory_bytes: 64 * 1024 * 1024,
+            fuel: 100_000_000,
+        }
+    }
+}
+
+/// A format converted through canonical JSON
+pub trait FormatPlugin: Send + Sync {
+    /// Format name on the wire, such as `properties`
+    fn name(&self) -> &str;
+
+    /// Convert a document of this format to canonical JSON
+    ///
+    /// # Errors
+    ///
+    /// Fails where `input` is not a document of this format.
+    fn to_canonical(&self, input: &[u8]) -> Result<Vec<u8>>;
+
+    /// Convert canonical JSON to a document of this format
+    ///
+    /// # Errors
+    ///
+    /// Fails where `canonical` has no form in this format.
+    #[allow(clippy::wrong_self_convention)]
+    fn from_canonical(&self, canonical: &[u8]) -> Result<Vec<u8>>;
+
+    /// Problems found in a document of this format
+    ///
+    /// # Errors
+    ///
+    /// Fails where the plugin cannot check `input` at all.
+    fn validate(&self, input: &[u8]) -> Result<Vec<String>>;
+}
+
+/// Plugin formats by name
+#[derive(Default)]
+pub struct FormatRegistry {
+    plugins: RwLock<BTreeMap<String, Arc<dyn FormatPlugin>>>,
+}
+
+impl FormatRegistry {
+    /// An empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `plugin`, unless its name is taken by a built-in format or another plugin
+    ///
+    /// # Errors
+    ///
+    /// Fails where the plugin's name is that of a built-in format or of another
+    /// plugin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the plugin list.
+    pub fn register(&self, plugin: Arc<dyn FormatPlugin>) -> Result<()> {
+        let name = plugin.name().to_string();
+        if name.is_empty() {
+            bail!("Format plugins need a name");
+        }
+        if Format::from_str(&name).is_ok() {
+            bail!("{name} is a built-in format");
+        }
+        let mut plugins = self.plugins.write().expect("format plugin lock poisoned");
+        if plugins.contains_key(&name) {
+            bail!("A plugin for {name} is already registered");
+        }
+        plugins.insert(name, plugin);
+        Ok(())
+    }
+
+    /// The plugin for `name`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the plugin list.
+    pub fn get(&self, name: &str) -> Option<Arc<dyn FormatPlugin>> {
+        self.plugins.read().expect("format plugin lock poisoned").get(name).cloned()
+    }
+
+    /// Names of the registered plugins, in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the plugin list.
+    pub fn names(&self) -> Vec<String> {
+        self.plugins.read().expect("format plugin lock poisoned").keys().cloned().collect()
+    }
+}
+
+/// Describe a plugin format, convertible to every built-in one
+pub fn capability() -> CapabilityInfo {
+    let targets: Vec<&str> = Format::ALL.iter().map(Format::name).collect();
+    CapabilityInfo::new(ABI_VERSION.to_string())
+        .parameter("converts_to", targets)
+        .parameter("validates", true)
+        .parameter("plugin", true)
+}
+
+/// Load the plugins `config` names into `registry`, listing each in `capabilities`
+///
+/// A plugin that fails to load or register is logged and skipped; the
+/// server starts with the others.
+pub fn install(config: &PluginConfig, registry: &FormatRegistry, capabilities: &CapabilityRegistry) {
+    for plugin in load(config) {
+        let name = plugin.name().to_string();
+        if let Err(e) = registry.register(plugin) {
+            warn!("Format plugin {} not registered: {:#}", name, e);
+            continue;
+        }
+        if let Err(e) = capabilities.register(&format!("formats.{name}"), capability()) {
+            warn!("Format plugin {} not listed in the capabilities: {:#}", name, e);
+        }
+        info!("Format plugin {} registered", name);
+    }
+}
+
+/// Instantiate the `*.wasm` modules in the configured directory, in name order
+#[cfg(feature = "wasm-plugins")]
+pub fn load(config: &PluginConfig) -> Vec<Arc<dyn FormatPlugin>> {
+    let Some(dir) = &config.dir else {
+        return Vec::new();
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Format plugins in {} not loaded: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "wasm"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| match super::wasm::WasmPlugin::load(&path, config) {
+            Ok(plugin) => Some(Arc::new(plugin) as Arc<dyn FormatPlugin>),
+            Err(e) => {
+                warn!("Format plugin {} not loaded: {:#}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Without the `wasm-plugins` feature there is nothing to load plugins with
+#[cfg(not(feature = "wasm-plugins"))]
+pub fn load(config: &PluginConfig) -> Vec<Arc<dyn FormatPlugin>> {
+    if let Some(dir) = &config.dir {
+        warn!("Built without the wasm-plugins feature; format plugins in {} not loaded", dir.display());
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Upper-cases on the way out of canonical JSON
+    struct Shout;
+
+    impl FormatPlugin for Shout {
+        fn name(&self) -> &'static str {
+            "shout"
+        }
+
+        fn to_canonical(&self, input: &[u8]) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(&String::from_utf8_lossy(input).to_lowercase())?)
+        }
+
+        fn from_canonical(&self, canonical: &[u8]) -> Result<Vec<u8>> {
+            let text: String = serde_json::from_slice(canonical)?;
+            Ok(text.to_uppercase().into_bytes())
+        }
+
+        fn validate(&self, _input: &[u8]) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_registration() {
+        let registry = FormatRegistry::new();
+        registry.register(Arc::new(Shout)).unwrap();
+        assert_eq!(registry.names(), ["shout"]);
+        assert!(registry.get("shout").is_some());
+        assert!(registry.get("whisper").is_none());
+        assert!(registry.register(Arc::new(Shout)).is_err());
+    }
+
+    #[test]
+    fn test_built_in_names_are_refused() {
+        struct Impostor;
+
+        impl FormatPlugin for Impostor {
+            fn name(&self) -> &'static str {
+                "yml"
+            }
+
+            fn to_canonical(&self, input: &[u8]) -> Result<Vec<u8>> {
+                Ok(input.to_vec())
+            }
+
+            fn from_canonical(&self, canonical: &[u8]) -> Result<Vec<u8>> {
+                Ok(canonical.to_vec())
+            }
+
+            fn validate(&self, _input: &[u8]) -> Result<Vec<String>> {
+                Ok(Vec::new())
+            }
+        }
+
+        let error = FormatRegistry::new().register(Arc::new(Impostor)).unwrap_err();
+        assert!(error.to_string().contains("built-in"), "{error}");
+    }
+
+    #[cfg(not(feature = "wasm-plugins"))]
+    #[test]
+    fn test_nothing_loads_without_the_feature() {
+        let config = PluginConfig {
+            dir: Some(std::env::temp_dir()),
+            ..PluginConfig::default()
+        };
+        assert!(load(&config).is_empty());
+    }
+}
@@ -0,0 +1,693 @@
+//! Formatting documents in a chosen style
+//!
+//! [`format_document`] rewrites a JSON, NDJSON, YAML or TOML document in the
+//! layout [`FormatOptions`] describe, keeping its values and, unless keys
+//! are sorted, their order. It serves the LSP `textDocument/formatting`
+//! request and the HTTP `/api/format` endpoint.
+//!
+//! JSON and YAML are written afresh from the [`model`](super::model), so
+//! YAML documents with comments, which the model does not keep, are
+//! refused rather than stripped, and anchors are written out in full. TOML
+//! is tidied in place, keeping its comments, dates and multi-line strings.
+//! JSON strings keep the double quotes JSON requires, and NDJSON records
+//! stay one to a line.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+
+use super::model::{self, DocumentValue, Node};
+use crate::core::Format;
+
+/// Quotes around strings that need them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuoteStyle {
+    #[default]
+    Double,
+    /// Single quotes, for strings that can be written with them
+    Single,
+}
+
+/// When an array of scalars is written one item per line
+///
+/// Arrays holding arrays or maps always are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrayWrap {
+    /// When it does not fit within the line width; YAML keeps its block sequences
+    #[default]
+    Auto,
+    Always,
+    /// Never, whatever its length
+    Never,
+}
+
+/// How a document is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FormatOptions {
+    /// Spaces per level, or 0 for JSON on one line
+    pub indent: usize,
+    /// Order the keys of every map
+    pub sort_keys: bool,
+    pub quote_style: QuoteStyle,
+    pub array_wrap: ArrayWrap,
+    /// Columns an array may reach before [`ArrayWrap::Auto`] wraps it
+    pub line_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent: 2,
+            sort_keys: false,
+            quote_style: QuoteStyle::default(),
+            array_wrap: ArrayWrap::default(),
+            line_width: 80,
+        }
+    }
+}
+
+/// Whether [`format_document`] formats documents of `format`
+#[must_use]
+pub fn supports(format: Format) -> bool {
+    matches!(format, Format::Json | Format::Ndjson | Format::Yaml | Format::Toml)
+}
+
+/// `content`, a document of `format`, laid out as `options` say
+///
+/// # Errors
+///
+/// Fails where `content` does not parse as `format`, or `format` is not one
+/// laid out here.
+pub fn format_document(format: Format, content: &str, options: &FormatOptions) -> Result<String> {
+    match format {
+        Format::Json => {
+            let mut value: DocumentValue = serde_json::from_str(content).map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+            if options.sort_keys {
+                sort(&mut value);
+            }
+            let mut printer = Printer::new(options);
+            printer.json(&value, 0);
+            printer.out.push('\n');
+            Ok(printer.out)
+        }
+        Format::Ndjson => {
+            let compact = FormatOptions { indent: 0, ..*options };
+            let mut out = String::new();
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim_start_matches('\u{feff}');
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut value: DocumentValue =
+                    serde_json::from_str(line).map_err(|e| anyhow!("Invalid NDJSON: line {}: {}", number + 1, e))?;
+                if options.sort_keys {
+                    sort(&mut value);
+                }
+                let mut printer = Printer::new(&compact);
+                printer.json(&value, 0);
+                out.push_str(&printer.out);
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        Format::Yaml => {
+            let mut value: DocumentValue = serde_yaml::from_str(content).map_err(|e| anyhow!("Invalid YAML: {e}"))?;
+            model::attach_yaml_layout(&mut value, content);
+            if options.sort_keys {
+                sort(&mut value);
+            }
+            let yaml = write_yaml(&value, options)?;
+            let start = content.lines().find(|line| !(line.trim().is_empty() || line.starts_with('#')));
+            Ok(if start.is_some_and(|line| line.trim_end() == "---") { format!("---\n{yaml}") } else { yaml })
+        }
+        Format::Toml => format_toml(content, options),
+        _ => bail!("{} documents cannot be formatted", format.name()),
+    }
+}
+
+/// `value` as block YAML, with the comments and blank lines it carries
+pub(crate) fn write_yaml(value: &DocumentValue, options: &FormatOptions) -> Result<String> {
+    if options.indent == 0 {
+        bail!("YAML needs an indent of at least one space");
+    }
+    let mut printer = Printer::new(options);
+    match &value.node {
+        Node::Map(entries) if !entries.is_empty() => printer.yaml_map(entries, "", 0),
+        Node::Array(items) if !items.is_empty() => printer.yaml_sequence(items, "", 0),
+        _ => printer.yaml_value("", value, 0),
+    }
+    // A document's own comments follow its last entry
+    printer.yaml_comments(false, &value.comments, 0);
+    Ok(printer.out)
+}
+
+fn sort(value: &mut DocumentValue) {
+    match &mut value.node {
+        Node::Map(entries) => {
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (_, entry) in entries.iter_mut() {
+                sort(entry);
+            }
+        }
+        Node::Array(items) => items.iter_mut().for_each(sort),
+        _ => {}
+    }
+}
+
+fn is_scalar(value: &DocumentValue) -> bool {
+    !matches!(value.node, Node::Array(_) | Node::Map(_))
+}
+
+/// The text of a number, with a point or exponent for floats
+fn number(node: &Node) -> Option<String> {
+    Some(match node {
+        Node::Integer(i) => i.to_string(),
+        Node::Unsigned(u) => u.to_string(),
+        Node::Float(f) => serde_json::Number::from_f64(*f)?.to_string(),
+        _ => return None,
+    })
+}
+
+/// Writes documents into a string
+struct Printer<'a> {
+    options: &'a FormatOptions,
+    out: String,
+}
+
+impl<'a> Printer<'a> {
+    fn new(options: &'a FormatOptions) -> Self {
+        Self { options, out: String::new() }
+    }
+
+    /// Characters on the line being written
+    fn column(&self) -> usize {
+        self.out.rsplit('\n').next().unwrap_or_default().chars().count()
+    }
+
+    fn newline(&mut self, depth: usize) {
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(depth * self.options.indent));
+    }
+
+    fn json(&mut self, value: &DocumentValue, depth: usize) {
+        let compact = self.options.indent == 0;
+        match &value.node {
+            Node::Map(entries) if !entries.is_empty() => {
+                self.out.push('{');
+                for (index, (key, entry)) in entries.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push(',');
+                    }
+                    if !compact {
+                        self.newline(depth + 1);
+                    }
+                    self.out.push_str(&json_string(key));
+                    self.out.push_str(if compact { ":" } else { ": " });
+                    self.json(entry, depth + 1);
+                }
+                if !compact {
+                    self.newline(depth);
+                }
+                self.out.push('}');
+            }
+            Node::Array(items) if !items.is_empty() => {
+                let inline = items.iter().all(is_scalar).then(|| {
+                    let separator = if compact { "," } else { ", " };
+                    let texts: Vec<_> = items.iter().map(json_scalar).collect();
+                    format!("[{}]", texts.join(separator))
+                });
+                match inline {
+                    Some(inline) if compact || self.fits(&inline) => self.out.push_str(&inline),
+                    _ => {
+                        self.out.push('[');
+                        for (index, item) in items.iter().enumerate() {
+                            if index > 0 {
+                                self.out.push(',');
+                            }
+                            if !compact {
+                                self.newline(depth + 1);
+                            }
+                            self.json(item, depth + 1);
+                        }
+                        if !compact {
+                            self.newline(depth);
+                        }
+                        self.out.push(']');
+                    }
+                }
+            }
+            _ => {
+                let text = json_scalar(value);
+                self.out.push_str(&text);
+            }
+        }
+    }
+
+    /// Whether an array of scalars written as `inline` stays on one line
+    fn fits(&self, inline: &str) -> bool {
+        match self.options.array_wrap {
+            ArrayWrap::Auto => self.column() + inline.chars().count() <= self.options.line_width,
+            ArrayWrap::Always => false,
+            ArrayWrap::Never => true,
+        }
+    }
+
+    /// A blank line if `blank_line`, then `comments` at column `pad`
+    fn yaml_comments(&mut self, blank_line: bool, comments: &[String], pad: usize) {
+        if blank_line && !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+        for comment in comments {
+            self.out.push_str(&" ".repeat(pad));
+            self.out.push('#');
+            if !comment.is_empty() {
+                self.out.push(' ');
+                self.out.push_str(comment);
+            }
+            self.out.push('\n');
+        }
+    }
+
+    /// End a line, with the comment ending the line of `value`
+    fn yaml_end_line(&mut self, value: &DocumentValue) {
+        if let Some(comment) = &value.trailing {
+            self.out.push_str(" #");
+            if !comment.is_empty() {
+                self.out.push(' ');
+                self.out.push_str(comment);
+            }
+        }
+        self.out.push('\n');
+    }
+
+    /// Entries of a block map, the first after `first` and the others at column `pad`
+    fn yaml_map(&mut self, entries: &[(String, DocumentValue)], first: &str, pad: usize) {
+        for (index, (key, entry)) in entries.iter().enumerate() {
+            // An entry starting the line of a sequence item has its comments above the item
+            if index > 0 || first.trim().is_empty() {
+                self.yaml_comments(entry.blank_line, &entry.comments, pad);
+            }
+            let lead = if index == 0 { first.to_string() } else { " ".repeat(pad) };
+            let head = format!("{}{}:", lead, self.yaml_string(key, false));
+            let inner = pad + self.options.indent;
+            match &entry.node {
+                Node::Map(entries) if !entries.is_empty() => {
+                    self.out.push_str(&head);
+                    self.yaml_end_line(entry);
+                    self.yaml_map(entries, &" ".repeat(inner), inner);
+                }
+                Node::Array(items) if !items.is_empty() && !self.yaml_flow(items) => {
+                    self.out.push_str(&head);
+                    self.yaml_end_line(entry);
+                    self.yaml_sequence(items, &" ".repeat(inner), inner);
+                }
+                _ => self.yaml_value(&head, entry, inner),
+            }
+        }
+    }
+
+    /// Items of a block sequence, the first after `first` and the others at column `pad`
+    fn yaml_sequence(&mut self, items: &[DocumentValue], first: &str, pad: usize) {
+        for (index, item) in items.iter().enumerate() {
+            if index > 0 || first.trim().is_empty() {
+                self.yaml_comments(item.blank_line, &item.comments, pad);
+            }
+            let lead = if index == 0 { first.to_string() } else { " ".repeat(pad) };
+            let head = format!("{lead}- ");
+            match &item.node {
+                Node::Map(entries) if !entries.is_empty() => self.yaml_map(entries, &head, pad + 2),
+                Node::Array(items) if !items.is_empty() && !self.yaml_flow(items) => {
+                    self.yaml_sequence(items, &head, pad + 2);
+                }
+                _ => self.yaml_value(head.trim_end(), item, pad + self.options.indent),
+            }
+        }
+    }
+
+    /// Whether a sequence is written in flow style, as `[a, b]`
+    fn yaml_flow(&self, items: &[DocumentValue]) -> bool {
+        self.options.array_wrap == ArrayWrap::Never && items.iter().all(is_scalar)
+    }
+
+    /// A line of `head` and a value on one line, or a block scalar indented to `pad`
+    fn yaml_value(&mut self, head: &str, value: &DocumentValue, pad: usize) {
+        let text = match &value.node {
+            Node::String(s) => match block_literal(s) {
+                Some((indicator, body)) => {
+                    self.out.push_str(head);
+                    self.out.push_str(if head.is_empty() { "" } else { " " });
+                    self.out.push_str(indicator);
+                    if let Some(comment) = &value.trailing {
+                        let _ = write!(self.out, " # {comment}");
+                    }
+                    for line in body.split('\n') {
+                        self.out.push('\n');
+                        if !line.is_empty() {
+                            self.out.push_str(&" ".repeat(pad));
+                            self.out.push_str(line);
+                        }
+                    }
+                    self.out.push('\n');
+                    return;
+                }
+                None => self.yaml_string(s, false),
+            },
+            Node::Array(items) if !items.is_empty() => {
+                let texts: Vec<_> = items.iter().map(|item| self.yaml_scalar(item, true)).collect();
+                format!("[{}]", texts.join(", "))
+            }
+            Node::Array(_) => "[]".to_string(),
+            Node::Map(_) => "{}".to_string(),
+            _ => self.yaml_scalar(value, false),
+        };
+        self.out.push_str(head);
+        if !head.is_empty() {
+            self.out.push(' ');
+        }
+        self.out.push_str(&text);
+        self.yaml_end_line(value);
+    }
+
+    fn yaml_scalar(&self, value: &DocumentValue, flow: bool) -> String {
+        match &value.node {
+            Node::Null => "null".to_string(),
+            Node::Bool(b) => b.to_string(),
+            Node::Float(f) if f.is_nan() => ".nan".to_string(),
+            Node::Float(f) if f.is_infinite() => if *f > 0.0 { ".inf" } else { "-.inf" }.to_string(),
+            Node::String(s) => self.yaml_string(s, flow),
+            // Plain, as a timestamp
+            Node::DateTime(datetime) => datetime.text.clone(),
+            node => number(node).unwrap_or_default(),
+        }
+    }
+
+    /// A string plain where YAML reads it back as the same string, else quoted
+    fn yaml_string(&self, s: &str, flow: bool) -> String {
+        if plain_yaml(s, flow) {
+            s.to_string()
+        } else if self.options.quote_style == QuoteStyle::Single && !s.chars().any(char::is_control) {
+            format!("'{}'", s.replace('\'', "''"))
+        } else {
+            json_string(s)
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+fn json_scalar(value: &DocumentValue) -> String {
+    match &value.node {
+        Node::Null => "null".to_string(),
+        Node::Bool(b) => b.to_string(),
+        Node::String(s) => json_string(s),
+        Node::DateTime(datetime) => json_string(&datetime.text),
+        // Only empty ones are written as scalars
+        Node::Array(_) => "[]".to_string(),
+        Node::Map(_) => "{}".to_string(),
+        node => number(node).unwrap_or_else(|| "null".to_string()),
+    }
+}
+
+/// Words other YAML readers take for booleans
+const YAML_BOOLEANS: [&str; 6] = ["y", "n", "yes", "no", "on", "off"];
+
+fn plain_yaml(s: &str, flow: bool) -> bool {
+    let mut chars = s.chars();
+    let (Some(first), second) = (chars.next(), chars.next()) else {
+        return false;
+    };
+    let indicator = match first {
+        '-' | '?' | ':' => second.is_none_or(char::is_whitespace),
+        _ => ",[]{}#&*!|>'\"%@`".contains(first),
+    };
+    !indicator
+        && s.trim() == s
+        && !s.chars().any(char::is_control)
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.ends_with(':')
+        && !(flow && s.contains([',', '[', ']', '{', '}']))
+        && !YAML_BOOLEANS.contains(&s.to_ascii_lowercase().as_str())
+        && !super::yaml::is_plain_timestamp(s)
+        && matches!(serde_yaml::from_str(s), Ok(serde_yaml::Value::String(read)) if read == s)
+}
+
+/// The indicator and text of `s` as a literal block scalar, when it has lines and can be one
+fn block_literal(s: &str) -> Option<(&'static str, &str)> {
+    let (indicator, body) = match s.strip_suffix('\n') {
+        Some(body) => ("|", body),
+        None => ("|-", s),
+    };
+    let fits = body.contains('\n')
+        && !body.ends_with('\n')
+        && !body.starts_with([' ', '\n'])
+        && !body.chars().any(|c| c.is_control() && c != '\n');
+    fits.then_some((indicator, body))
+}
+
+fn format_toml(content: &str, options: &FormatOptions) -> Result<String> {
+    let mut document: toml_edit::DocumentMut = content.parse().map_err(|e| anyhow!("Invalid TOML: {e}"))?;
+    if options.sort_keys {
+        let mut position = 0;
+        sort_toml(document.as_table_mut(), &mut position);
+    }
+    tidy_table(document.as_table_mut(), options);
+    let trailing = tidy_prefix(document.trailing().as_str().unwrap_or_default());
+    document.set_trailing(trailing);
+
+    let text = document.to_string();
+    let mut text = text.trim_start_matches('\n').trim_end().to_string();
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Sort the keys of `table` and the tables in it, numbering the tables in their new order from `position`
+fn sort_toml(table: &mut toml_edit::Table, position: &mut usize) {
+    table.sort_values();
+    for (_, item) in table.iter_mut() {
+        match item {
+            toml_edit::Item::Table(inner) => {
+                if !inner.is_dotted() {
+                    inner.set_position(*position);
+                    *position += 1;
+                }
+                sort_toml(inner, position);
+            }
+            toml_edit::Item::ArrayOfTables(tables) => {
+                for inner in tables.iter_mut() {
+                    inner.set_position(*position);
+                    *position += 1;
+                    sort_toml(inner, position);
+                }
+            }
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(inline)) => inline.sort_values(),
+            _ => {}
+        }
+    }
+}
+
+fn tidy_table(table: &mut toml_edit::Table, options: &FormatOptions) {
+    let dotted = table.is_dotted();
+    for (mut key, item) in table.iter_mut() {
+        if !dotted {
+            let prefix = tidy_prefix(key.leaf_decor().prefix().and_then(toml_edit::RawString::as_str).unwrap_or_default());
+            key.leaf_decor_mut().set_prefix(prefix);
+        }
+        let column = key.get().chars().count() + 3;
+        match item {
+            toml_edit::Item::Table(inner) => {
+                if !inner.is_dotted() {
+                    tidy_header(inner.decor_mut());
+                } else if !dotted {
+                    key.leaf_decor_mut().set_suffix("");
+                }
+                tidy_table(inner, options);
+            }
+            toml_edit::Item::ArrayOfTables(tables) => {
+                for inner in tables.iter_mut() {
+                    tidy_header(inner.decor_mut());
+                    tidy_table(inner, options);
+                }
+            }
+            toml_edit::Item::Value(value) => {
+                if !dotted {
+                    key.leaf_decor_mut().set_suffix(" ");
+                }
+                tidy_value(value, options, column, 0, false);
+                let suffix = tidy_suffix(value.decor().suffix().and_then(toml_edit::RawString::as_str).unwrap_or_default());
+                value.decor_mut().set_prefix(" ");
+                value.decor_mut().set_suffix(suffix);
+            }
+            toml_edit::Item::None => {}
+        }
+    }
+}
+
+/// A table header one blank line below what comes before, with its comments
+fn tidy_header(decor: &mut toml_edit::Decor) {
+    let prefix = tidy_prefix(decor.prefix().and_then(toml_edit::RawString::as_str).unwrap_or_default());
+    let suffix = tidy_suffix(decor.suffix().and_then(toml_edit::RawString::as_str).unwrap_or_default());
+    decor.set_prefix(if prefix.starts_with('\n') { prefix } else { format!("\n{prefix}") });
+    decor.set_suffix(suffix);
+}
+
+/// The comment lines of the whitespace before a line, unindented, with at most one blank line in a row
+fn tidy_prefix(prefix: &str) -> String {
+    let mut tidy = String::new();
+    let mut lines: Vec<&str> = prefix.split('\n').collect();
+    // What follows the last newline is the indent of the line itself
+    lines.pop();
+    for line in lines {
+        let line = line.trim();
+        if !line.is_empty() || !(tidy == "\n" || tidy.ends_with("\n\n")) {
+            tidy.push_str(line);
+            tidy.push('\n');
+        }
+    }
+    tidy
+}
+
+/// A comment ending a line, one space after what it follows
+fn tidy_suffix(suffix: &str) -> String {
+    match suffix.trim() {
+        "" => String::new(),
+        comment => format!(" {comment}"),
+    }
+}
+
+fn has_comment(decor: &toml_edit::Decor) -> bool {
+    [decor.prefix(), decor.suffix()]
+        .into_iter()
+        .flatten()
+        .any(|raw| raw.as_str().unwrap_or_default().contains('#'))
+}
+
+/// Requote the strings in `value` and lay out its arrays, which start at `column`, `depth` arrays deep
+fn tidy_value(value: &mut toml_edit::Value, options: &FormatOptions, column: usize, depth: usize, inline: bool) {
+    match value {
+        toml_edit::Value::String(s) => {
+            let raw = s.as_repr().and_then(|repr| repr.as_raw().as_str()).unwrap_or_default();
+            // Multi-line strings keep their layout
+            if raw.starts_with("\"\"\"") || raw.starts_with("'''") {
+                return;
+            }
+            let text = s.value();
+            let literal = options.quote_style == QuoteStyle::Single
+                && !text.contains('\'')
+                && !text.chars().any(|c| c.is_control() && c != '\t');
+            let quoted = if literal { format!("'{text}'") } else { json_string(text) };
+            if let Ok(mut requoted) = quoted.parse::<toml_edit::Value>() {
+                *requoted.decor_mut() = s.decor().clone();
+                *value = requoted;
+            }
+        }
+        toml_edit::Value::InlineTable(table) => {
+            table.fmt();
+            for (_, inner) in table.iter_mut() {
+                tidy_value(inner, options, column, depth, true);
+            }
+        }
+        toml_edit::Value::Array(array) => {
+            let commented = array.iter().any(|item| has_comment(item.decor())) || array.trailing().as_str().unwrap_or_default().contains('#');
+            let item_column = (depth + 1) * options.indent;
+            for item in array.iter_mut() {
+                tidy_value(item, options, item_column, depth + 1, inline);
+            }
+            if commented || array.is_empty() {
+                return;
+            }
+            let scalars = array.iter().all(|item| !matches!(item, toml_edit::Value::Array(_) | toml_edit::Value::InlineTable(_)));
+            array.fmt();
+            let width = array.to_string().trim().chars().count();
+            let wrap = !inline
+                && match options.array_wrap {
+                    _ if !scalars => true,
+                    ArrayWrap::Auto => column + width > options.line_width,
+                    ArrayWrap::Always => true,
+                    ArrayWrap::Never => false,
+                };
+            if wrap {
+                for item in array.iter_mut() {
+                    item.decor_mut().set_prefix(format!("\n{}", " ".repeat(item_column)));
+                    item.decor_mut().set_suffix("");
+                }
+                array.set_trailing_comma(true);
+                array.set_trailing(format!("\n{}", " ".repeat(depth * options.indent)));
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(format: Format, content: &str, options: FormatOptions) -> String {
+        format_document(format, content, &options).unwrap()
+    }
+
+    #[test]
+    fn test_json() {
+        let json = r#"{"name":"app","ports":[80,443],"tls":{"on":true,"ciphers":[]},"hosts":[{"a":1}]}"#;
+        let expected = "{\n  \"name\": \"app\",\n  \"ports\": [80, 443],\n  \"tls\": {\n    \"on\": true,\n    \"ciphers\": []\n  },\n  \"hosts\": [\n    {\n      \"a\": 1\n    }\n  ]\n}\n";
+        assert_eq!(format(Format::Json, json, FormatOptions::default()), expected);
+
+        let sorted = FormatOptions { indent: 4, sort_keys: true, array_wrap: ArrayWrap::Always, ..FormatOptions::default() };
+        let expected = "{\n    \"hosts\": [\n        {\n            \"a\": 1\n        }\n    ],\n    \"name\": \"app\",\n    \"ports\": [\n        80,\n        443\n    ],\n    \"tls\": {\n        \"ciphers\": [],\n        \"on\": true\n    }\n}\n";
+        assert_eq!(format(Format::Json, json, sorted), expected);
+
+        let compact = FormatOptions { indent: 0, ..FormatOptions::default() };
+        assert_eq!(format(Format::Json, &format(Format::Json, json, sorted), compact), r#"{"hosts":[{"a":1}],"name":"app","ports":[80,443],"tls":{"ciphers":[],"on":true}}"#.to_string() + "\n");
+        assert_eq!(format(Format::Json, json, compact), format!("{json}\n"));
+
+        // Arrays wrap at the line width
+        let narrow = FormatOptions { line_width: 17, ..FormatOptions::default() };
+        assert_eq!(format(Format::Json, "{\"ab\": [1, 2, 3]}", narrow), "{\n  \"ab\": [1, 2, 3]\n}\n");
+        assert_eq!(format(Format::Json, "{\"abc\": [1, 2, 3]}", narrow), "{\n  \"abc\": [\n    1,\n    2,\n    3\n  ]\n}\n");
+
+        assert_eq!(format(Format::Ndjson, "{\"b\": 1, \"a\": [1, 2]}\n\n{}\n", sorted), "{\"a\":[1,2],\"b\":1}\n{}\n");
+        assert!(format_document(Format::Json, "{", &FormatOptions::default()).unwrap_err().to_string().starts_with("Invalid JSON"));
+        assert!(format_document(Format::Csv, "a,b", &FormatOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_yaml() {
+        let yaml = "name:   app\nversion: '1.0'\nargs: [--port, \"80\", 'a b']\nservers:\n- host: a\n  tags: [x, z]\n-   - 1\n    - 2\nnotes: |\n  line one\n  line two\nempty: {}\nenabled: yes\n";
+        let expected = "name: app\nversion: \"1.0\"\nargs:\n  - --port\n  - \"80\"\n  - a b\nservers:\n  - host: a\n    tags:\n      - x\n      - z\n  - - 1\n    - 2\nnotes: |\n  line one\n  line two\nempty: {}\nenabled: \"yes\"\n";
+        let formatted = format(Format::Yaml, yaml, FormatOptions::default());
+        assert_eq!(formatted, expected);
+        let read = |text: &str| serde_yaml::from_str::<serde_yaml::Value>(text).unwrap();
+        assert_eq!(read(&formatted), read(yaml));
+
+        let flow = FormatOptions { indent: 4, quote_style: QuoteStyle::Single, array_wrap: ArrayWrap::Never, sort_keys: true, ..FormatOptions::default() };
+        let yaml = "---\nb: {d: ~, c: ['1', \"it's\", x]}\na: '#1'\n";
+        assert_eq!(format(Format::Yaml, yaml, flow), "---\na: '#1'\nb:\n    c: ['1', it's, x]\n    d: null\n");
+
+        // Comments and single blank lines are kept
+        let commented = "a:   1 # one\n\n\n# two\nb: [x]\n";
+        assert_eq!(format(Format::Yaml, commented, FormatOptions::default()), "a: 1 # one\n\n# two\nb:\n  - x\n");
+        assert!(format_document(Format::Yaml, "a: 1\n", &FormatOptions { indent: 0, ..FormatOptions::default() }).is_err());
+    }
+
+    #[test]
+    fn test_toml() {
+        let toml = "# the app\nname=\"app\"   # its name\nports = [ 80,443 ]\n\n\n\n[server]\n  # bind\n  host =  'localhost'\n[[plugins]]\nname = \"a\"\ntags = [\n  \"x\", # first\n]\n";
+        let expected = "# the app\nname = \"app\" # its name\nports = [80, 443]\n\n[server]\n# bind\nhost = \"localhost\"\n\n[[plugins]]\nname = \"a\"\ntags = [\n  \"x\", # first\n]\n";
+        assert_eq!(format(Format::Toml, toml, FormatOptions::default()), expected);
+        assert_eq!(format(Format::Toml, expected, FormatOptions::default()), expected);
+
+        let options = FormatOptions { indent: 4, sort_keys: true, quote_style: QuoteStyle::Single, line_width: 12, ..FormatOptions::default() };
+        let toml = "z = \"it's\"\na = [\"x\", \"y\"]\n[b]\ny = 1\nx = 'q'\n[a2]\nk = 1\n";
+        let expected = "a = [\n    'x',\n    'y',\n]\nz = \"it's\"\n\n[a2]\nk = 1\n\n[b]\nx = 'q'\ny = 1\n";
+        assert_eq!(format(Format::Toml, toml, options), expected);
+        assert!(format_document(Format::Toml, "a = ", &options).unwrap_err().to_string().starts_with("Invalid TOML"));
+    }
+}
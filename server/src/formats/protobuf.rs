@@ -0,0 +1,749 @@
+//! Protocol Buffers support for document conversion
+//!
+//! A protobuf message means nothing without its schema, so every conversion
+//! takes the [`MessageDescriptor`] of the message it reads or writes.
+//! [`Descriptors`] load them from `.proto` files, parsed in Rust so that no
+//! `protoc` is needed, or from a `FileDescriptorSet` such as `protoc
+//! --descriptor_set_out` or `buf build` writes. A [`DescriptorRegistry`]
+//! holds them by name, and finds messages by their full name, such as
+//! `telemetry.Reading`, across everything registered.
+//!
+//! JSON follows the proto3 JSON mapping: fields are keyed by their JSON
+//! name, in lowerCamelCase, and read by either name; 64-bit integers are
+//! strings, bytes base64 and enums the names of their values, and fields
+//! holding their default are left out. Well-known types such as
+//! `google.protobuf.Timestamp` are written as the messages they are, not in
+//! their special JSON forms. Unknown fields of a binary payload are dropped.
+//!
+//! The text format is the one `protoc --decode` prints.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use base64::Engine;
+use protobuf::reflect::{
+    FileDescriptor, MessageDescriptor, ReflectFieldRef, ReflectValueBox, ReflectValueRef, RuntimeFieldType,
+    RuntimeType,
+};
+use protobuf::{Message, MessageDyn};
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Messages nested inside one another before reading gives up
+const MAX_DEPTH: usize = 100;
+
+/// The messages of a set of `.proto` files, and of the files they import
+pub struct Descriptors {
+    files: Vec<FileDescriptor>,
+    /// Every message by full name, nested ones included
+    messages: BTreeMap<String, MessageDescriptor>,
+}
+
+impl Descriptors {
+    /// Parse the `.proto` files `inputs`, finding them and their imports under `includes`
+    ///
+    /// # Errors
+    ///
+    /// Fails where a file cannot be found, read or parsed, or does not
+    /// typecheck.
+    pub fn load(includes: &[impl AsRef<Path>], inputs: &[impl AsRef<Path>]) -> Result<Self> {
+        let parsed = protobuf_parse::Parser::new()
+            .pure()
+            .includes(includes.iter().map(AsRef::as_ref))
+            .inputs(inputs.iter().map(AsRef::as_ref))
+            .parse_and_typecheck()
+            .map_err(|e| anyhow!("{e:#}"))?;
+        Self::new(parsed.file_descriptors)
+    }
+
+    /// Read a serialized `FileDescriptorSet`, which must hold every file its files import
+    ///
+    /// # Errors
+    ///
+    /// Fails where `set` is not a `FileDescriptorSet`, or lacks a file one of
+    /// its files imports.
+    pub fn decode(set: &[u8]) -> Result<Self> {
+        let set = protobuf::descriptor::FileDescriptorSet::parse_from_bytes(set).context("Invalid FileDescriptorSet")?;
+        Self::new(set.file)
+    }
+
+    fn new(protos: Vec<protobuf::descriptor::FileDescriptorProto>) -> Result<Self> {
+        let files = FileDescriptor::new_dynamic_fds(protos, &[])?;
+        let mut messages = BTreeMap::new();
+        let mut pending: Vec<MessageDescriptor> = files.iter().flat_map(FileDescriptor::messages).collect();
+        while let Some(message) = pending.pop() {
+            pending.extend(message.nested_messages().filter(|nested| !nested.is_map_entry()));
+            messages.insert(message.full_name().to_string(), message);
+        }
+        Ok(Self { files, messages })
+    }
+
+    /// The message called `name` in full, such as `telemetry.Reading`
+    #[must_use]
+    pub fn message(&self, name: &str) -> Option<MessageDescriptor> {
+        self.messages.get(name.strip_prefix('.').unwrap_or(name)).cloned()
+    }
+
+    /// Full names of the messages, in order
+    #[must_use]
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.keys().cloned().collect()
+    }
+
+    /// Names of the files, imports included
+    #[must_use]
+    pub fn files(&self) -> Vec<String> {
+        self.files.iter().map(|file| file.proto().name().to_string()).collect()
+    }
+}
+
+/// Descriptors by name, registered at startup or while running
+#[derive(Default)]
+pub struct DescriptorRegistry {
+    descriptors: RwLock<BTreeMap<String, Arc<Descriptors>>>,
+}
+
+impl DescriptorRegistry {
+    /// An empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `descriptors` as `name`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is empty or already registered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn register(&self, name: &str, descriptors: Descriptors) -> Result<()> {
+        if name.is_empty() {
+            bail!("Descriptors need a name");
+        }
+        let mut registered = self.descriptors.write().expect("descriptor registry lock poisoned");
+        if registered.contains_key(name) {
+            bail!("Descriptors called {name} are already registered");
+        }
+        registered.insert(name.to_string(), Arc::new(descriptors));
+        Ok(())
+    }
+
+    /// Remove the descriptors called `name`, returning whether there were any
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn unregister(&self, name: &str) -> bool {
+        self.descriptors.write().expect("descriptor registry lock poisoned").remove(name).is_some()
+    }
+
+    /// The descriptors called `name`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn get(&self, name: &str) -> Option<Arc<Descriptors>> {
+        self.descriptors.read().expect("descriptor registry lock poisoned").get(name).cloned()
+    }
+
+    /// Names of the registered descriptors, in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn names(&self) -> Vec<String> {
+        self.descriptors.read().expect("descriptor registry lock poisoned").keys().cloned().collect()
+    }
+
+    /// The message called `name` in full, from the first descriptors, by name, that have it
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn message(&self, name: &str) -> Option<MessageDescriptor> {
+        self.descriptors.read().expect("descriptor registry lock poisoned").values().find_map(|descriptors| descriptors.message(name))
+    }
+}
+
+/// A problem with a message, at a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtobufDiagnostic {
+    /// Path to the field, such as `readings[2].unit`, empty for the whole message
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ProtobufDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Convert a binary payload of `message` to JSON
+///
+/// # Errors
+///
+/// Fails where `bytes` are not a payload of `message`.
+pub fn binary_to_json(message: &MessageDescriptor, bytes: &[u8]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&to_json(&*parse_binary(message, bytes)?))?)
+}
+
+/// Convert JSON to a binary payload of `message`
+///
+/// # Errors
+///
+/// Fails where the JSON does not parse, or does not match `message`.
+pub fn json_to_binary(message: &MessageDescriptor, json: &str) -> Result<Vec<u8>> {
+    Ok(parse_json(message, json)?.write_to_bytes_dyn()?)
+}
+
+/// Convert the text format of `message` to JSON
+///
+/// # Errors
+///
+/// Fails where `text` is not the text format of `message`.
+pub fn text_to_json(message: &MessageDescriptor, text: &str) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&to_json(&*parse_text(message, text)?))?)
+}
+
+/// Convert JSON to the text format of `message`
+///
+/// # Errors
+///
+/// Fails where the JSON does not parse, or does not match `message`.
+pub fn json_to_text(message: &MessageDescriptor, json: &str) -> Result<String> {
+    Ok(protobuf::text_format::print_to_string_pretty(&*parse_json(message, json)?))
+}
+
+/// Convert a binary payload of `message` to the text format
+///
+/// # Errors
+///
+/// Fails where `bytes` are not a payload of `message`.
+pub fn binary_to_text(message: &MessageDescriptor, bytes: &[u8]) -> Result<String> {
+    Ok(protobuf::text_format::print_to_string_pretty(&*parse_binary(message, bytes)?))
+}
+
+/// Convert the text format of `message` to a binary payload
+///
+/// # Errors
+///
+/// Fails where `text` is not the text format of `message`.
+pub fn text_to_binary(message: &MessageDescriptor, text: &str) -> Result<Vec<u8>> {
+    Ok(parse_text(message, text)?.write_to_bytes_dyn()?)
+}
+
+/// Problems with a binary payload of `message`: failing to decode, missing
+/// required fields, and fields the schema does not have
+#[must_use]
+pub fn validate_binary(message: &MessageDescriptor, bytes: &[u8]) -> Vec<ProtobufDiagnostic> {
+    match message.parse_from_bytes(bytes) {
+        Ok(parsed) => {
+            let mut diagnostics = Vec::new();
+            check(&*parsed, "", true, &mut diagnostics);
+            diagnostics
+        }
+        Err(e) => vec![ProtobufDiagnostic { path: String::new(), message: e.to_string() }],
+    }
+}
+
+/// Problems with JSON as `message`: every field that is not in the schema
+/// or holds the wrong type, and missing required fields
+///
+/// # Errors
+///
+/// Fails where `json` does not parse.
+pub fn validate_json(message: &MessageDescriptor, json: &str) -> Result<Vec<ProtobufDiagnostic>> {
+    let value: Value = serde_json::from_str(json)?;
+    let mut diagnostics = Vec::new();
+    let parsed = from_json(message, &value, "", 0, &mut diagnostics);
+    check(&*parsed, "", false, &mut diagnostics);
+    Ok(diagnostics)
+}
+
+/// Problems with the text format of `message`: the first that stops it
+/// parsing, with its line and column, else missing required fields
+#[must_use]
+pub fn validate_text(message: &MessageDescriptor, text: &str) -> Vec<ProtobufDiagnostic> {
+    let mut parsed = message.new_instance();
+    if let Err(e) = protobuf::text_format::merge_from_str(&mut *parsed, text) {
+        return vec![ProtobufDiagnostic { path: String::new(), message: e.to_string() }];
+    }
+    let mut diagnostics = Vec::new();
+    check(&*parsed, "", false, &mut diagnostics);
+    diagnostics
+}
+
+fn parse_binary(message: &MessageDescriptor, bytes: &[u8]) -> Result<Box<dyn MessageDyn>> {
+    message.parse_from_bytes(bytes).map_err(|e| anyhow!("Invalid {} payload: {}", message.full_name(), e))
+}
+
+fn parse_text(message: &MessageDescriptor, text: &str) -> Result<Box<dyn MessageDyn>> {
+    let mut parsed = message.new_instance();
+    protobuf::text_format::merge_from_str(&mut *parsed, text)
+        .map_err(|e| anyhow!("Invalid {} text: {}", message.full_name(), e))?;
+    Ok(parsed)
+}
+
+fn parse_json(message: &MessageDescriptor, json: &str) -> Result<Box<dyn MessageDyn>> {
+    let value: Value = serde_json::from_str(json)?;
+    let mut diagnostics = Vec::new();
+    let parsed = from_json(message, &value, "", 0, &mut diagnostics);
+    check(&*parsed, "", false, &mut diagnostics);
+    match diagnostics.first() {
+        Some(diagnostic) => Err(anyhow!("Invalid {} JSON: {}", message.full_name(), diagnostic)),
+        None => Ok(parsed),
+    }
+}
+
+/// Report required fields `message` lacks, and with `unknown` fields the schema does not have
+fn check(message: &dyn MessageDyn, path: &str, unknown: bool, diagnostics: &mut Vec<ProtobufDiagnostic>) {
+    let descriptor = message.descriptor_dyn();
+    if unknown {
+        for (number, _) in message.unknown_fields_dyn() {
+            diagnostics.push(ProtobufDiagnostic { path: path.to_string(), message: format!("unknown field number {number}") });
+        }
+    }
+    for field in descriptor.fields() {
+        let path = join(path, field.name());
+        match field.get_reflect(message) {
+            ReflectFieldRef::Optional(value) => match value.value() {
+                Some(ReflectValueRef::Message(nested)) => check(&*nested, &path, unknown, diagnostics),
+                None if field.is_required() => {
+                    diagnostics.push(ProtobufDiagnostic { path, message: "missing required field".to_string() });
+                }
+                Some(_) | None => {}
+            },
+            ReflectFieldRef::Repeated(items) => {
+                for (index, item) in items.into_iter().enumerate() {
+                    if let ReflectValueRef::Message(nested) = item {
+                        check(&*nested, &format!("{path}[{index}]"), unknown, diagnostics);
+                    }
+                }
+            }
+            ReflectFieldRef::Map(map) => {
+                for (key, value) in &map {
+                    if let ReflectValueRef::Message(nested) = value {
+                        check(&*nested, &format!("{}[{}]", path, map_key(&key)), unknown, diagnostics);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+fn to_json(message: &dyn MessageDyn) -> Value {
+    let mut object = Map::new();
+    for field in message.descriptor_dyn().fields() {
+        let value = match field.get_reflect(message) {
+            ReflectFieldRef::Optional(value) => match value.value() {
+                Some(value) => value_to_json(&value),
+                None => continue,
+            },
+            ReflectFieldRef::Repeated(items) if items.is_empty() => continue,
+            ReflectFieldRef::Repeated(items) => Value::Array(items.into_iter().map(|item| value_to_json(&item)).collect()),
+            ReflectFieldRef::Map(map) if map.is_empty() => continue,
+            ReflectFieldRef::Map(map) => Value::Object((&map).into_iter().map(|(key, value)| (map_key(&key), value_to_json(&value))).collect()),
+        };
+        object.insert(field.json_name().to_string(), value);
+    }
+    Value::Object(object)
+}
+
+fn value_to_json(value: &ReflectValueRef) -> Value {
+    match value {
+        ReflectValueRef::U32(n) => Value::from(*n),
+        ReflectValueRef::I32(n) => Value::from(*n),
+        ReflectValueRef::U64(n) => Value::String(n.to_string()),
+        ReflectValueRef::I64(n) => Value::String(n.to_string()),
+        ReflectValueRef::F32(f) => float_to_json(f64::from(*f)),
+        ReflectValueRef::F64(f) => float_to_json(*f),
+        ReflectValueRef::Bool(b) => Value::Bool(*b),
+        ReflectValueRef::String(s) => Value::String(s.to_string()),
+        ReflectValueRef::Bytes(bytes) => Value::String(STANDARD.encode(bytes)),
+        ReflectValueRef::Enum(descriptor, number) => match descriptor.value_by_number(*number) {
+            Some(value) => Value::String(value.name().to_string()),
+            None => Value::from(*number),
+        },
+        ReflectValueRef::Message(nested) => to_json(&**nested),
+    }
+}
+
+/// A float, or the string JSON writes infinities and NaN as
+fn float_to_json(f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => Value::Number(n),
+        None if f.is_nan() => Value::String("NaN".to_string()),
+        None if f > 0.0 => Value::String("Infinity".to_string()),
+        None => Value::String("-Infinity".to_string()),
+    }
+}
+
+fn map_key(key: &ReflectValueRef) -> String {
+    match key {
+        ReflectValueRef::String(s) => s.to_string(),
+        key => value_to_json(key).to_string().trim_matches('"').to_string(),
+    }
+}
+
+/// The message `value` holds, reporting what does not fit the schema and leaving it out
+fn from_json(
+    message: &MessageDescriptor,
+    value: &Value,
+    path: &str,
+    depth: usize,
+    diagnostics: &mut Vec<ProtobufDiagnostic>,
+) -> Box<dyn MessageDyn> {
+    let mut parsed = message.new_instance();
+    let report = |diagnostics: &mut Vec<ProtobufDiagnostic>, path: String, message: String| {
+        diagnostics.push(ProtobufDiagnostic { path, message });
+    };
+    if depth > MAX_DEPTH {
+        report(diagnostics, path.to_string(), "messages nested too deeply".to_string());
+        return parsed;
+    }
+    let Value::Object(object) = value else {
+        report(diagnostics, path.to_string(), format!("expected {} object, found {}", message.name(), kind(value)));
+        return parsed;
+    };
+    for (key, value) in object {
+        let path = join(path, key);
+        let Some(field) = message.field_by_name_or_json_name(key) else {
+            report(diagnostics, path, format!("{} has no field {}", message.full_name(), key));
+            continue;
+        };
+        if value.is_null() {
+            continue;
+        }
+        match field.runtime_field_type() {
+            RuntimeFieldType::Singular(kind) => {
+                match value_from_json(&kind, value, &path, depth, diagnostics) {
+                    Ok(Some(value)) => field.set_singular_field(&mut *parsed, value),
+                    Ok(None) => {}
+                    Err(message) => report(diagnostics, path, message),
+                }
+            }
+            RuntimeFieldType::Repeated(kind) => {
+                let Value::Array(items) = value else {
+                    report(diagnostics, path, format!("expected an array, found {}", self::kind(value)));
+                    continue;
+                };
+                for (index, item) in items.iter().enumerate() {
+                    let path = format!("{path}[{index}]");
+                    match value_from_json(&kind, item, &path, depth, diagnostics) {
+                        Ok(Some(item)) => field.mut_repeated(&mut *parsed).push(item),
+                        Ok(None) => {}
+                        Err(message) => report(diagnostics, path, message),
+                    }
+                }
+            }
+            RuntimeFieldType::Map(key_kind, value_kind) => {
+                let Value::Object(entries) = value else {
+                    report(diagnostics, path, format!("expected an object, found {}", kind(value)));
+                    continue;
+                };
+                for (key, value) in entries {
+                    let path = format!("{path}[{key}]");
+                    let key = match key_from_json(&key_kind, key) {
+                        Ok(key) => key,
+                        Err(message) => {
+                            report(diagnostics, path, message);
+                            continue;
+                        }
+                    };
+                    match value_from_json(&value_kind, value, &path, depth, diagnostics) {
+                        Ok(Some(value)) => field.mut_map(&mut *parsed).insert(key, value),
+                        Ok(None) => {}
+                        Err(message) => report(diagnostics, path, message),
+                    }
+                }
+            }
+        }
+    }
+    parsed
+}
+
+/// The value of type `kind` that `value` holds; `None` when a nested
+/// message has reported its own problems
+fn value_from_json(
+    kind: &RuntimeType,
+    value: &Value,
+    path: &str,
+    depth: usize,
+    diagnostics: &mut Vec<ProtobufDiagnostic>,
+) -> std::result::Result<Option<ReflectValueBox>, String> {
+    let expected = |name: &str| format!("expected {}, found {}", name, self::kind(value));
+    Ok(Some(match kind {
+        RuntimeType::I32 => ReflectValueBox::I32(integer(value, "int32")?),
+        RuntimeType::I64 => ReflectValueBox::I64(integer(value, "int64")?),
+        RuntimeType::U32 => ReflectValueBox::U32(integer(value, "uint32")?),
+        RuntimeType::U64 => ReflectValueBox::U64(integer(value, "uint64")?),
+        #[allow(clippy::cast_possible_truncation)] // As a float field is
+        RuntimeType::F32 => ReflectValueBox::F32(float(value)? as f32),
+        RuntimeType::F64 => ReflectValueBox::F64(float(value)?),
+        RuntimeType::Bool => ReflectValueBox::Bool(value.as_bool().ok_or_else(|| expected("a boolean"))?),
+        RuntimeType::String => ReflectValueBox::String(value.as_str().ok_or_else(|| expected("a string"))?.to_string()),
+        RuntimeType::VecU8 => {
+            let text = value.as_str().ok_or_else(|| expected("base64 bytes"))?;
+            let bytes = STANDARD.decode(text).or_else(|_| URL_SAFE.decode(text)).map_err(|e| format!("invalid base64: {e}"))?;
+            ReflectValueBox::Bytes(bytes)
+        }
+        RuntimeType::Enum(descriptor) => {
+            let found = match value {
+                Value::String(name) => descriptor.value_by_name(name),
+                Value::Number(_) => integer::<i32>(value, "enum").ok().and_then(|number| descriptor.value_by_number(number)),
+                _ => return Err(expected(&format!("a {} name", descriptor.name()))),
+            };
+            let found = found.ok_or_else(|| format!("{} has no value {}", descriptor.full_name(), value))?;
+            ReflectValueBox::Enum(descriptor.clone(), found.value())
+        }
+        RuntimeType::Message(descriptor) => {
+            let before = diagnostics.len();
+            let nested = from_json(descriptor, value, path, depth + 1, diagnostics);
+            if diagnostics.len() > before {
+                return Ok(None);
+            }
+            ReflectValueBox::Message(nested)
+        }
+    }))
+}
+
+fn key_from_json(kind: &RuntimeType, key: &str) -> std::result::Result<ReflectValueBox, String> {
+    let key = Value::String(key.to_string());
+    Ok(match kind {
+        RuntimeType::I32 => ReflectValueBox::I32(integer(&key, "int32")?),
+        RuntimeType::I64 => ReflectValueBox::I64(integer(&key, "int64")?),
+        RuntimeType::U32 => ReflectValueBox::U32(integer(&key, "uint32")?),
+        RuntimeType::U64 => ReflectValueBox::U64(integer(&key, "uint64")?),
+        RuntimeType::Bool => match key.as_str() {
+            Some("true") => ReflectValueBox::Bool(true),
+            Some("false") => ReflectValueBox::Bool(false),
+            _ => return Err(format!("expected a boolean key, found {key}")),
+        },
+        _ => ReflectValueBox::String(key.as_str().unwrap_or_default().to_string()),
+    })
+}
+
+/// An integer written as a number, or as a string of one, that fits `name`
+fn integer<T: TryFrom<i128>>(value: &Value, name: &str) -> std::result::Result<T, String> {
+    let wide = match value {
+        Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), ..) => Some(i128::from(i)),
+            (_, Some(u), _) => Some(i128::from(u)),
+            #[allow(clippy::cast_possible_truncation)] // Whole, and within an i128
+            (.., Some(f)) if f.fract() == 0.0 && f.abs() < 1e38 => Some(f as i128),
+            _ => None,
+        },
+        Value::String(text) => text.trim().parse::<i128>().ok(),
+        _ => None,
+    };
+    let wide = wide.ok_or_else(|| format!("expected {}, found {}", name, kind(value)))?;
+    T::try_from(wide).map_err(|_| format!("{wide} is out of range for {name}"))
+}
+
+/// A float written as a number, or as a string of one, `NaN` or an infinity
+fn float(value: &Value) -> std::result::Result<f64, String> {
+    match value {
+        Value::Number(n) => n.as_f64().ok_or_else(|| format!("{n} is not a float")),
+        Value::String(text) => match text.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "Infinity" => Ok(f64::INFINITY),
+            "-Infinity" => Ok(f64::NEG_INFINITY),
+            text => text.parse().map_err(|_| format!("expected a float, found \"{text}\"")),
+        },
+        _ => Err(format!("expected a float, found {}", kind(value))),
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TELEMETRY: &str = r#"
+syntax = "proto3";
+package telemetry;
+
+import "units.proto";
+
+message Reading {
+  string sensor_id = 1;
+  double value = 2;
+  units.Unit unit = 3;
+  int64 taken_at = 4;
+  repeated string tags = 5;
+  map<string, Reading> related = 6;
+  bytes raw = 7;
+
+  message Calibration {
+    float offset = 1;
+  }
+  Calibration calibration = 8;
+}
+"#;
+
+    const UNITS: &str = r#"
+syntax = "proto3";
+package units;
+
+enum Unit {
+  UNIT_UNSPECIFIED = 0;
+  CELSIUS = 1;
+  PERCENT = 2;
+}
+"#;
+
+    const LEGACY: &str = r#"
+syntax = "proto2";
+package legacy;
+
+message Device {
+  required string serial = 1;
+  optional uint32 port = 2;
+}
+"#;
+
+    fn descriptors() -> Descriptors {
+        let dir = std::env::temp_dir().join(format!("ulc-protobuf-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, source) in [("telemetry.proto", TELEMETRY), ("units.proto", UNITS), ("legacy.proto", LEGACY)] {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+        let loaded = Descriptors::load(&[&dir], &[dir.join("telemetry.proto"), dir.join("legacy.proto")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap()
+    }
+
+    fn reading() -> MessageDescriptor {
+        descriptors().message("telemetry.Reading").unwrap()
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let descriptors = descriptors();
+        assert_eq!(descriptors.messages(), ["legacy.Device", "telemetry.Reading", "telemetry.Reading.Calibration"]);
+        assert_eq!(descriptors.files().len(), 3);
+        assert!(descriptors.message(".telemetry.Reading").is_some());
+
+        // A compiled set reads the same, and must hold the files it imports
+        let mut set = protobuf::descriptor::FileDescriptorSet::new();
+        set.file = descriptors.files.iter().map(|file| file.proto().clone()).collect();
+        let decoded = Descriptors::decode(&set.write_to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.messages(), descriptors.messages());
+        set.file.retain(|file| file.name() != "units.proto");
+        assert!(Descriptors::decode(&set.write_to_bytes().unwrap()).is_err());
+
+        let registry = DescriptorRegistry::new();
+        registry.register("telemetry", descriptors).unwrap();
+        assert!(registry.register("telemetry", decoded).is_err());
+        assert_eq!(registry.names(), ["telemetry"]);
+        assert_eq!(registry.message("legacy.Device").unwrap().name(), "Device");
+        assert!(registry.message("legacy.Missing").is_none());
+        assert!(registry.unregister("telemetry"));
+        assert!(registry.message("legacy.Device").is_none());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let reading = reading();
+        let value = json!({
+            "sensorId": "t1",
+            "value": 21.5,
+            "unit": "CELSIUS",
+            "takenAt": "1700000000000",
+            "tags": ["roof"],
+            "related": {"t2": {"sensorId": "t2", "value": "NaN"}},
+            "raw": "AAEC",
+            "calibration": {"offset": -0.5}
+        });
+        let bytes = json_to_binary(&reading, &value.to_string()).unwrap();
+        let json: Value = serde_json::from_str(&binary_to_json(&reading, &bytes).unwrap()).unwrap();
+        assert_eq!(json, value);
+
+        // Field names and numbers are read as well as JSON names and strings
+        let bytes = json_to_binary(&reading, r#"{"sensor_id": "t1", "taken_at": 5, "unit": 2}"#).unwrap();
+        let json: Value = serde_json::from_str(&binary_to_json(&reading, &bytes).unwrap()).unwrap();
+        assert_eq!(json, json!({"sensorId": "t1", "takenAt": "5", "unit": "PERCENT"}));
+
+        let text = binary_to_text(&reading, &bytes).unwrap();
+        assert!(text.contains("sensor_id: \"t1\""), "{text}");
+        assert_eq!(text_to_binary(&reading, &text).unwrap(), bytes);
+        let json: Value = serde_json::from_str(&text_to_json(&reading, "sensor_id: \"t3\" unit: CELSIUS").unwrap()).unwrap();
+        assert_eq!(json, json!({"sensorId": "t3", "unit": "CELSIUS"}));
+        assert!(json_to_text(&reading, r#"{"tags": ["a", "b"]}"#).unwrap().contains("tags: \"b\""));
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        let reading = reading();
+        let diagnostics = validate_json(
+            &reading,
+            r#"{"sensorId": 7, "unit": "KELVIN", "colour": "red", "tags": "roof", "related": {"t2": {"value": true}}, "calibration": {"offset": 1}}"#,
+        )
+        .unwrap();
+        let found: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            found,
+            [
+                "colour: telemetry.Reading has no field colour",
+                "related[t2].value: expected a float, found a boolean",
+                "sensorId: expected a string, found a number",
+                "tags: expected an array, found a string",
+                "unit: units.Unit has no value \"KELVIN\"",
+            ]
+        );
+        assert!(validate_json(&reading, r#"{"takenAt": "1e400"}"#).unwrap()[0].message.contains("expected int64"));
+        let error = json_to_binary(&reading, r#"{"value": "warm"}"#).unwrap_err().to_string();
+        assert_eq!(error, "Invalid telemetry.Reading JSON: value: expected a float, found \"warm\"");
+
+        let device = descriptors().message("legacy.Device").unwrap();
+        assert_eq!(validate_json(&device, r#"{"port": -1}"#).unwrap().iter().map(ToString::to_string).collect::<Vec<_>>(), [
+            "port: -1 is out of range for uint32",
+            "serial: missing required field",
+        ]);
+    }
+
+    #[test]
+    fn test_binary_and_text_diagnostics() {
+        let reading = reading();
+        // Field 1, length 5, holding two bytes
+        assert_eq!(validate_binary(&reading, &[0x0a, 0x05, b'a', b'b']).len(), 1);
+        // Field 15 as a varint, which the schema does not have
+        assert_eq!(validate_binary(&reading, &[0x78, 0x01])[0].to_string(), "unknown field number 15");
+        assert_eq!(validate_binary(&reading, &[]), []);
+        assert!(binary_to_json(&reading, &[0x0a, 0x05]).unwrap_err().to_string().starts_with("Invalid telemetry.Reading payload"));
+
+        let problems = validate_text(&reading, "sensor_id: \"t1\"\ncolour: 1");
+        assert!(problems[0].message.contains("Unknown field"), "{problems:?}");
+        let device = descriptors().message("legacy.Device").unwrap();
+        assert_eq!(validate_text(&device, "port: 80")[0].to_string(), "serial: missing required field");
+    }
+}
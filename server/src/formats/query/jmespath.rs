@@ -0,0 +1,939 @@
+//! `JMESPath`, as its specification gives it
+//!
+//! An expression is evaluated against a value and gives one value back,
+//! `null` where nothing matches. Besides fields, indices and slices, it
+//! projects over arrays with `[*]`, objects with `*` and flattened arrays
+//! with `[]`, filters projections with `[?expression]`, builds lists with
+//! `[a, b]` and objects with `{name: a}`, pipes with `|`, and compares and
+//! combines with `==`, `<`, `&&`, `||` and `!`. Literals are JSON between
+//! backticks, or raw strings between single quotes. Every built-in function
+//! of the specification is supported, those taking an expression, such as
+//! `sort_by(people, &age)`, included.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+
+use super::equal;
+use super::jsonpath::slice;
+
+/// Expressions nested within one another before one is refused
+const MAX_DEPTH: usize = 64;
+
+/// The value `expression` gives for `value`
+///
+/// # Errors
+///
+/// Fails where `expression` does not parse, or a function is called with
+/// arguments it does not take.
+pub fn search(value: &Value, expression: &str) -> Result<Value> {
+    let tokens = lex(expression)?;
+    let mut parser = Parser { tokens, pos: 0, depth: 0 };
+    let ast = parser.expression(0)?;
+    if let Some((token, at)) = parser.tokens.get(parser.pos) {
+        bail!("Invalid JMESPath at column {}: unexpected {}", at + 1, token.describe());
+    }
+    evaluate(&ast, value)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    QuotedIdentifier(String),
+    Literal(Value),
+    Number(i64),
+    Dot,
+    Star,
+    Flatten,
+    Filter,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    Comma,
+    Colon,
+    Pipe,
+    Or,
+    And,
+    Not,
+    Ampersand,
+    At,
+    Compare(Comparison),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Token {
+    /// How tightly the token binds what is on its left
+    fn binding_power(&self) -> usize {
+        match self {
+            Self::Pipe => 1,
+            Self::Or => 2,
+            Self::And => 3,
+            Self::Compare(_) => 5,
+            Self::Flatten => 9,
+            Self::Star => 20,
+            Self::Filter => 21,
+            Self::Dot => 40,
+            Self::Not => 45,
+            Self::LeftBrace => 50,
+            Self::LeftBracket => 55,
+            Self::LeftParen => 60,
+            _ => 0,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Identifier(name) | Self::QuotedIdentifier(name) => format!("identifier {name}"),
+            Self::Literal(value) => format!("literal {value}"),
+            Self::Number(n) => format!("number {n}"),
+            token => format!("`{}`", token.symbol()),
+        }
+    }
+
+    /// The text of a punctuation token
+    fn symbol(&self) -> &'static str {
+        match self {
+            Self::Dot => ".",
+            Self::Star => "*",
+            Self::Flatten => "[]",
+            Self::Filter => "[?",
+            Self::LeftBracket => "[",
+            Self::RightBracket => "]",
+            Self::LeftBrace => "{",
+            Self::RightBrace => "}",
+            Self::LeftParen => "(",
+            Self::RightParen => ")",
+            Self::Comma => ",",
+            Self::Colon => ":",
+            Self::Pipe => "|",
+            Self::Or => "||",
+            Self::And => "&&",
+            Self::Not => "!",
+            Self::Ampersand => "&",
+            Self::At => "@",
+            Self::Compare(Comparison::Eq) => "==",
+            Self::Compare(Comparison::Ne) => "!=",
+            Self::Compare(Comparison::Lt) => "<",
+            Self::Compare(Comparison::Le) => "<=",
+            Self::Compare(Comparison::Gt) => ">",
+            Self::Compare(Comparison::Ge) => ">=",
+            Self::Identifier(_) | Self::QuotedIdentifier(_) | Self::Literal(_) | Self::Number(_) => "",
+        }
+    }
+}
+
+/// The tokens of `expression`, each with the character it starts at
+fn lex(expression: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let error = |at: usize, message: &str| anyhow!("Invalid JMESPath at column {}: {}", at + 1, message);
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let token = match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                i += 1;
+                continue;
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                while chars.get(i).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    i += 1;
+                }
+                tokens.push((Token::Identifier(chars[start..i].iter().collect()), start));
+                continue;
+            }
+            '-' | '0'..='9' => {
+                i += 1;
+                while chars.get(i).is_some_and(char::is_ascii_digit) {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let number = digits.parse().map_err(|_| error(start, "expected a number"))?;
+                tokens.push((Token::Number(number), start));
+                continue;
+            }
+            '"' => {
+                let end = closing(&chars, i, '"').ok_or_else(|| error(start, "unterminated quoted identifier"))?;
+                let quoted: String = chars[i..=end].iter().collect();
+                let name = serde_json::from_str(&quoted).map_err(|_| error(start, "invalid quoted identifier"))?;
+                i = end + 1;
+                tokens.push((Token::QuotedIdentifier(name), start));
+                continue;
+            }
+            '\'' => {
+                let end = closing(&chars, i, '\'').ok_or_else(|| error(start, "unterminated raw string"))?;
+                let raw: String = chars[i + 1..end].iter().collect();
+                i = end + 1;
+                tokens.push((Token::Literal(Value::String(raw.replace("\\'", "'"))), start));
+                continue;
+            }
+            '`' => {
+                let end = closing(&chars, i, '`').ok_or_else(|| error(start, "unterminated literal"))?;
+                let json: String = chars[i + 1..end].iter().collect();
+                let value = serde_json::from_str(json.replace("\\`", "`").trim()).map_err(|_| error(start, "invalid JSON literal"))?;
+                i = end + 1;
+                tokens.push((Token::Literal(value), start));
+                continue;
+            }
+            '[' if next == Some(']') => Token::Flatten,
+            '[' if next == Some('?') => Token::Filter,
+            '|' if next == Some('|') => Token::Or,
+            '&' if next == Some('&') => Token::And,
+            '=' if next == Some('=') => Token::Compare(Comparison::Eq),
+            '!' if next == Some('=') => Token::Compare(Comparison::Ne),
+            '<' if next == Some('=') => Token::Compare(Comparison::Le),
+            '>' if next == Some('=') => Token::Compare(Comparison::Ge),
+            '.' => Token::Dot,
+            '*' => Token::Star,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '|' => Token::Pipe,
+            '!' => Token::Not,
+            '&' => Token::Ampersand,
+            '@' => Token::At,
+            '<' => Token::Compare(Comparison::Lt),
+            '>' => Token::Compare(Comparison::Gt),
+            _ => return Err(error(start, &format!("unexpected character {c:?}"))),
+        };
+        let two = matches!(token, Token::Flatten | Token::Filter | Token::Or | Token::And)
+            || matches!(token, Token::Compare(Comparison::Eq | Comparison::Ne | Comparison::Le | Comparison::Ge));
+        i += if two { 2 } else { 1 };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+/// Index of the `quote` closing the one at `open`, skipping escaped characters
+fn closing(chars: &[char], open: usize, quote: char) -> Option<usize> {
+    let mut i = open + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+enum Ast {
+    Current,
+    Field(String),
+    Subexpression(Box<Ast>, Box<Ast>),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>, Option<i64>),
+    /// Each item of the array on the left through the expression on the right
+    Projection(Box<Ast>, Box<Ast>),
+    /// Each value of the object on the left through the expression on the right
+    ValueProjection(Box<Ast>, Box<Ast>),
+    /// The items on the left the condition holds for, through the expression on the right
+    FilterProjection(Box<Ast>, Box<Ast>, Box<Ast>),
+    Flatten(Box<Ast>),
+    List(Vec<Ast>),
+    Hash(Vec<(String, Ast)>),
+    Or(Box<Ast>, Box<Ast>),
+    And(Box<Ast>, Box<Ast>),
+    Not(Box<Ast>),
+    Compare(Comparison, Box<Ast>, Box<Ast>),
+    Literal(Value),
+    Pipe(Box<Ast>, Box<Ast>),
+    Function(String, Vec<Ast>),
+    Reference(Box<Ast>),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self, ahead: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + ahead).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos) {
+            Some((token, at)) => anyhow!("Invalid JMESPath at column {}: {}, found {}", at + 1, message, token.describe()),
+            None => anyhow!("Invalid JMESPath: {message}, found the end of the expression"),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.peek(0).cloned().ok_or_else(|| self.error("expected an expression"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &Token, what: &str) -> Result<()> {
+        if self.peek(0) == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {what}")))
+        }
+    }
+
+    fn next_binding_power(&self) -> usize {
+        self.peek(0).map_or(0, Token::binding_power)
+    }
+
+    fn expression(&mut self, binding_power: usize) -> Result<Ast> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(&format!("nested more than {MAX_DEPTH} deep")));
+        }
+        let token = self.next()?;
+        let mut left = self.prefix(token)?;
+        while binding_power < self.next_binding_power() {
+            let token = self.next()?;
+            left = self.infix(token, left)?;
+        }
+        self.depth -= 1;
+        Ok(left)
+    }
+
+    fn prefix(&mut self, token: Token) -> Result<Ast> {
+        Ok(match token {
+            Token::Literal(value) => Ast::Literal(value),
+            Token::Identifier(name) => Ast::Field(name),
+            Token::QuotedIdentifier(name) => {
+                if self.peek(0) == Some(&Token::LeftParen) {
+                    return Err(self.error("functions are called by unquoted names"));
+                }
+                Ast::Field(name)
+            }
+            Token::Star => Ast::ValueProjection(Box::new(Ast::Current), Box::new(self.projected(Token::Star.binding_power())?)),
+            Token::LeftBracket => match self.peek(0) {
+                Some(Token::Number(_) | Token::Colon) => {
+                    let index = self.index()?;
+                    self.project_slice(Ast::Current, index)?
+                }
+                Some(Token::Star) if self.peek(1) == Some(&Token::RightBracket) => {
+                    self.pos += 2;
+                    Ast::Projection(Box::new(Ast::Current), Box::new(self.projected(Token::Star.binding_power())?))
+                }
+                _ => self.list()?,
+            },
+            Token::Filter => self.filter(Ast::Current)?,
+            Token::LeftBrace => self.hash()?,
+            Token::Flatten => {
+                let flattened = Ast::Flatten(Box::new(Ast::Current));
+                Ast::Projection(Box::new(flattened), Box::new(self.projected(Token::Flatten.binding_power())?))
+            }
+            Token::LeftParen => {
+                let inner = self.expression(0)?;
+                self.expect(&Token::RightParen, ")")?;
+                inner
+            }
+            Token::Not => Ast::Not(Box::new(self.expression(Token::Not.binding_power())?)),
+            Token::Ampersand => Ast::Reference(Box::new(self.expression(0)?)),
+            Token::At => Ast::Current,
+            token => {
+                self.pos -= 1;
+                return Err(self.error(&format!("unexpected {}", token.describe())));
+            }
+        })
+    }
+
+    fn infix(&mut self, token: Token, left: Ast) -> Result<Ast> {
+        let left = Box::new(left);
+        Ok(match token {
+            Token::Dot => {
+                if self.peek(0) == Some(&Token::Star) {
+                    self.pos += 1;
+                    Ast::ValueProjection(left, Box::new(self.projected(Token::Dot.binding_power())?))
+                } else {
+                    Ast::Subexpression(left, Box::new(self.after_dot(Token::Dot.binding_power())?))
+                }
+            }
+            Token::Pipe => Ast::Pipe(left, Box::new(self.expression(Token::Pipe.binding_power())?)),
+            Token::Or => Ast::Or(left, Box::new(self.expression(Token::Or.binding_power())?)),
+            Token::And => Ast::And(left, Box::new(self.expression(Token::And.binding_power())?)),
+            Token::Compare(comparison) => {
+                Ast::Compare(comparison, left, Box::new(self.expression(Token::Compare(comparison).binding_power())?))
+            }
+            Token::LeftParen => {
+                let Ast::Field(name) = *left else {
+                    return Err(self.error("only a function name can be called"));
+                };
+                let mut arguments = Vec::new();
+                while self.peek(0) != Some(&Token::RightParen) {
+                    arguments.push(self.expression(0)?);
+                    if self.peek(0) == Some(&Token::Comma) {
+                        self.pos += 1;
+                    } else if self.peek(0) != Some(&Token::RightParen) {
+                        return Err(self.error("expected , or )"));
+                    }
+                }
+                self.pos += 1;
+                Ast::Function(name, arguments)
+            }
+            Token::Filter => self.filter(*left)?,
+            Token::Flatten => {
+                let flattened = Ast::Flatten(left);
+                Ast::Projection(Box::new(flattened), Box::new(self.projected(Token::Flatten.binding_power())?))
+            }
+            Token::LeftBracket => if let Some(Token::Number(_) | Token::Colon) = self.peek(0) {
+                let index = self.index()?;
+                self.project_slice(*left, index)?
+            } else {
+                self.expect(&Token::Star, "*, an index or a slice")?;
+                self.expect(&Token::RightBracket, "]")?;
+                Ast::Projection(left, Box::new(self.projected(Token::Star.binding_power())?))
+            },
+            token => {
+                self.pos -= 1;
+                return Err(self.error(&format!("unexpected {}", token.describe())));
+            }
+        })
+    }
+
+    /// An index or slice, after its `[`
+    fn index(&mut self) -> Result<Ast> {
+        let mut parts = [None; 3];
+        let mut part = 0;
+        loop {
+            match self.next()? {
+                Token::Number(n) if parts[part].is_none() => parts[part] = Some(n),
+                Token::Colon if part < 2 => part += 1,
+                Token::RightBracket => break,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected a number, : or ]"));
+                }
+            }
+        }
+        match (part, parts) {
+            (0, [Some(index), ..]) => Ok(Ast::Index(index)),
+            (0, _) => Err(self.error("expected an index")),
+            (_, [start, end, step]) => {
+                if step == Some(0) {
+                    return Err(self.error("a slice step cannot be 0"));
+                }
+                Ok(Ast::Slice(start, end, step))
+            }
+        }
+    }
+
+    /// `index` applied to `left`, projecting what follows over the items of a slice
+    fn project_slice(&mut self, left: Ast, index: Ast) -> Result<Ast> {
+        let is_slice = matches!(index, Ast::Slice(..));
+        let indexed = Ast::Subexpression(Box::new(left), Box::new(index));
+        if is_slice {
+            Ok(Ast::Projection(Box::new(indexed), Box::new(self.projected(Token::Star.binding_power())?)))
+        } else {
+            Ok(indexed)
+        }
+    }
+
+    /// A filter projection over `left`, after its `[?`
+    fn filter(&mut self, left: Ast) -> Result<Ast> {
+        let condition = self.expression(0)?;
+        self.expect(&Token::RightBracket, "]")?;
+        let right = if self.peek(0) == Some(&Token::Flatten) { Ast::Current } else { self.projected(Token::Filter.binding_power())? };
+        Ok(Ast::FilterProjection(Box::new(left), Box::new(right), Box::new(condition)))
+    }
+
+    /// What a projection applies to each of its items
+    fn projected(&mut self, binding_power: usize) -> Result<Ast> {
+        match self.peek(0) {
+            None => Ok(Ast::Current),
+            Some(token) if token.binding_power() < 10 => Ok(Ast::Current),
+            Some(Token::LeftBracket | Token::Filter) => self.expression(binding_power),
+            Some(Token::Dot) => {
+                self.pos += 1;
+                self.after_dot(binding_power)
+            }
+            Some(_) => Err(self.error("expected ., [ or [? after a projection")),
+        }
+    }
+
+    fn after_dot(&mut self, binding_power: usize) -> Result<Ast> {
+        match self.peek(0) {
+            Some(Token::Identifier(_) | Token::QuotedIdentifier(_) | Token::Star) => self.expression(binding_power),
+            Some(Token::LeftBracket) => {
+                self.pos += 1;
+                self.list()
+            }
+            Some(Token::LeftBrace) => {
+                self.pos += 1;
+                self.hash()
+            }
+            _ => Err(self.error("expected a field, *, [ or { after .")),
+        }
+    }
+
+    /// A multi-select list, after its `[`
+    fn list(&mut self) -> Result<Ast> {
+        let mut items = Vec::new();
+        loop {
+            items.push(self.expression(0)?);
+            match self.next()? {
+                Token::Comma => {}
+                Token::RightBracket => return Ok(Ast::List(items)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected , or ]"));
+                }
+            }
+        }
+    }
+
+    /// A multi-select hash, after its `{`
+    fn hash(&mut self) -> Result<Ast> {
+        let mut entries = Vec::new();
+        loop {
+            let (Token::Identifier(key) | Token::QuotedIdentifier(key)) = self.next()? else {
+                self.pos -= 1;
+                return Err(self.error("expected a key"));
+            };
+            self.expect(&Token::Colon, ":")?;
+            entries.push((key, self.expression(0)?));
+            match self.next()? {
+                Token::Comma => {}
+                Token::RightBrace => return Ok(Ast::Hash(entries)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected , or }"));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `value` counts as true: anything but `false`, `null` and empty strings, arrays and objects
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => false,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+        _ => true,
+    }
+}
+
+fn evaluate(ast: &Ast, current: &Value) -> Result<Value> {
+    Ok(match ast {
+        Ast::Current => current.clone(),
+        Ast::Field(name) => current.get(name).cloned().unwrap_or(Value::Null),
+        Ast::Subexpression(left, right) | Ast::Pipe(left, right) => evaluate(right, &evaluate(left, current)?)?,
+        Ast::Index(index) => match current {
+            Value::Array(items) => {
+                let len = i64::try_from(items.len()).unwrap_or(i64::MAX);
+                let at = if *index < 0 { len + index } else { *index };
+                usize::try_from(at).ok().and_then(|at| items.get(at)).cloned().unwrap_or(Value::Null)
+            }
+            _ => Value::Null,
+        },
+        Ast::Slice(start, end, step) => match current {
+            Value::Array(items) => Value::Array(slice(items.len(), *start, *end, *step).into_iter().map(|i| items[i].clone()).collect()),
+            _ => Value::Null,
+        },
+        Ast::Projection(left, right) => match evaluate(left, current)? {
+            Value::Array(items) => project(&items, right)?,
+            _ => Value::Null,
+        },
+        Ast::ValueProjection(left, right) => match evaluate(left, current)? {
+            Value::Object(map) => project(&map.into_iter().map(|(_, value)| value).collect::<Vec<_>>(), right)?,
+            _ => Value::Null,
+        },
+        Ast::FilterProjection(left, right, condition) => match evaluate(left, current)? {
+            Value::Array(items) => {
+                let mut kept = Vec::new();
+                for item in items {
+                    if truthy(&evaluate(condition, &item)?) {
+                        kept.push(item);
+                    }
+                }
+                project(&kept, right)?
+            }
+            _ => Value::Null,
+        },
+        Ast::Flatten(inner) => match evaluate(inner, current)? {
+            Value::Array(items) => Value::Array(
+                items
+                    .into_iter()
+                    .flat_map(|item| match item {
+                        Value::Array(inner) => inner,
+                        item => vec![item],
+                    })
+                    .collect(),
+            ),
+            _ => Value::Null,
+        },
+        Ast::List(items) if !current.is_null() => {
+            Value::Array(items.iter().map(|item| evaluate(item, current)).collect::<Result<_>>()?)
+        }
+        Ast::Hash(entries) if !current.is_null() => Value::Object(
+            entries.iter().map(|(key, value)| Ok((key.clone(), evaluate(value, current)?))).collect::<Result<Map<_, _>>>()?,
+        ),
+        Ast::List(_) | Ast::Hash(_) => Value::Null,
+        Ast::Or(left, right) => {
+            let left = evaluate(left, current)?;
+            if truthy(&left) {
+                left
+            } else {
+                evaluate(right, current)?
+            }
+        }
+        Ast::And(left, right) => {
+            let left = evaluate(left, current)?;
+            if truthy(&left) {
+                evaluate(right, current)?
+            } else {
+                left
+            }
+        }
+        Ast::Not(inner) => Value::Bool(!truthy(&evaluate(inner, current)?)),
+        Ast::Compare(comparison, left, right) => compare(*comparison, &evaluate(left, current)?, &evaluate(right, current)?),
+        Ast::Literal(value) => value.clone(),
+        Ast::Function(name, arguments) => call(name, arguments, current)?,
+        Ast::Reference(_) => bail!("An expression reference, &, is only an argument of sort_by, max_by, min_by or map"),
+    })
+}
+
+/// `right` applied to each of `items`, leaving out the nulls it gives
+fn project(items: &[Value], right: &Ast) -> Result<Value> {
+    let mut projected = Vec::new();
+    for item in items {
+        let value = evaluate(right, item)?;
+        if !value.is_null() {
+            projected.push(value);
+        }
+    }
+    Ok(Value::Array(projected))
+}
+
+/// Any values compare equal or not, numbers by value; ordering anything but numbers gives null
+fn compare(comparison: Comparison, left: &Value, right: &Value) -> Value {
+    match comparison {
+        Comparison::Eq => return Value::Bool(equal(left, right)),
+        Comparison::Ne => return Value::Bool(!equal(left, right)),
+        _ => {}
+    }
+    let (Some(a), Some(b)) = (left.as_f64(), right.as_f64()) else {
+        return Value::Null;
+    };
+    Value::Bool(match comparison {
+        Comparison::Lt => a < b,
+        Comparison::Le => a <= b,
+        Comparison::Gt => a > b,
+        _ => a >= b,
+    })
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)] // Whole, and well within an i64
+fn number(n: f64) -> Value {
+    // Whole results, such as the sum of integers, stay integers
+    if n.fract() == 0.0 && n.abs() < 9.0e15 {
+        Value::from(n as i64)
+    } else {
+        Number::from_f64(n).map_or(Value::Null, Value::Number)
+    }
+}
+
+/// Values of the same type ordered: numbers by value, strings by code point
+fn order(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal),
+    }
+}
+
+/// Whether `items` are all numbers or all strings, as sorting and extremes need
+fn sortable(items: &[Value]) -> bool {
+    items.iter().all(Value::is_number) || items.iter().all(Value::is_string)
+}
+
+#[allow(clippy::too_many_lines)] // An arm per function
+fn call(name: &str, arguments: &[Ast], current: &Value) -> Result<Value> {
+    let arity = |count: usize| {
+        if arguments.len() == count {
+            Ok(())
+        } else {
+            Err(anyhow!("{}() takes {} argument{}, not {}", name, count, if count == 1 { "" } else { "s" }, arguments.len()))
+        }
+    };
+    let invalid = |value: &Value, expected: &str| anyhow!("{}() expects {}, not {}", name, expected, type_name(value));
+    // Arguments taken as expression references, evaluated against each item
+    let reference = |index: usize| match &arguments[index] {
+        Ast::Reference(expression) => Ok(expression.as_ref()),
+        _ => Err(anyhow!("{}() expects an expression reference, &expression, as argument {}", name, index + 1)),
+    };
+    let value = |index: usize| evaluate(&arguments[index], current);
+    let array = |index: usize| match value(index)? {
+        Value::Array(items) => Ok(items),
+        other => Err(invalid(&other, "an array")),
+    };
+
+    Ok(match name {
+        "abs" | "ceil" | "floor" => {
+            arity(1)?;
+            let argument = value(0)?;
+            let n = argument.as_f64().ok_or_else(|| invalid(&argument, "a number"))?;
+            number(match name {
+                "abs" => n.abs(),
+                "ceil" => n.ceil(),
+                _ => n.floor(),
+            })
+        }
+        "avg" | "sum" => {
+            arity(1)?;
+            let items = array(0)?;
+            let mut total = 0.0;
+            for item in &items {
+                total += item.as_f64().ok_or_else(|| invalid(item, "an array of numbers"))?;
+            }
+            match name {
+                "sum" => number(total),
+                _ if items.is_empty() => Value::Null,
+                #[allow(clippy::cast_precision_loss)] // Arrays are far shorter than 2^53
+                _ => number(total / items.len() as f64),
+            }
+        }
+        "contains" => {
+            arity(2)?;
+            let needle = value(1)?;
+            match value(0)? {
+                Value::Array(items) => Value::Bool(items.iter().any(|item| equal(item, &needle))),
+                Value::String(s) => Value::Bool(needle.as_str().is_some_and(|needle| s.contains(needle))),
+                other => return Err(invalid(&other, "an array or a string")),
+            }
+        }
+        "starts_with" | "ends_with" => {
+            arity(2)?;
+            let (subject, affix) = (value(0)?, value(1)?);
+            let (Some(subject), Some(affix)) = (subject.as_str(), affix.as_str()) else {
+                return Err(invalid(if subject.is_string() { &affix } else { &subject }, "strings"));
+            };
+            Value::Bool(if name == "starts_with" { subject.starts_with(affix) } else { subject.ends_with(affix) })
+        }
+        "join" => {
+            arity(2)?;
+            let separator = value(0)?;
+            let separator = separator.as_str().ok_or_else(|| invalid(&separator, "a string separator"))?;
+            let items = array(1)?;
+            let parts: Option<Vec<&str>> = items.iter().map(Value::as_str).collect();
+            Value::String(parts.ok_or_else(|| anyhow!("join() expects an array of strings"))?.join(separator))
+        }
+        "keys" | "values" => {
+            arity(1)?;
+            match value(0)? {
+                Value::Object(map) if name == "keys" => Value::Array(map.keys().cloned().map(Value::String).collect()),
+                Value::Object(map) => Value::Array(map.into_iter().map(|(_, value)| value).collect()),
+                other => return Err(invalid(&other, "an object")),
+            }
+        }
+        "length" => {
+            arity(1)?;
+            match value(0)? {
+                Value::String(s) => Value::from(s.chars().count()),
+                Value::Array(items) => Value::from(items.len()),
+                Value::Object(map) => Value::from(map.len()),
+                other => return Err(invalid(&other, "a string, an array or an object")),
+            }
+        }
+        "map" => {
+            arity(2)?;
+            let expression = reference(0)?;
+            let items = array(1)?;
+            Value::Array(items.iter().map(|item| evaluate(expression, item)).collect::<Result<_>>()?)
+        }
+        "max" | "min" => {
+            arity(1)?;
+            let items = array(0)?;
+            if !sortable(&items) {
+                bail!("{name}() expects an array of numbers or of strings");
+            }
+            let extreme = if name == "max" { items.iter().max_by(|a, b| order(a, b)) } else { items.iter().min_by(|a, b| order(a, b)) };
+            extreme.cloned().unwrap_or(Value::Null)
+        }
+        "max_by" | "min_by" | "sort_by" => {
+            arity(2)?;
+            let items = array(0)?;
+            let expression = reference(1)?;
+            let mut keyed = Vec::with_capacity(items.len());
+            for item in items {
+                keyed.push((evaluate(expression, &item)?, item));
+            }
+            let keys: Vec<Value> = keyed.iter().map(|(key, _)| key.clone()).collect();
+            if !sortable(&keys) {
+                bail!("{name}() expects the expression to give numbers or strings");
+            }
+            match name {
+                "sort_by" => {
+                    keyed.sort_by(|(a, _), (b, _)| order(a, b));
+                    Value::Array(keyed.into_iter().map(|(_, item)| item).collect())
+                }
+                "max_by" => keyed.into_iter().max_by(|(a, _), (b, _)| order(a, b)).map_or(Value::Null, |(_, item)| item),
+                _ => keyed.into_iter().min_by(|(a, _), (b, _)| order(a, b)).map_or(Value::Null, |(_, item)| item),
+            }
+        }
+        "merge" => {
+            let mut merged = Map::new();
+            for index in 0..arguments.len() {
+                match value(index)? {
+                    Value::Object(map) => merged.extend(map),
+                    other => return Err(invalid(&other, "objects")),
+                }
+            }
+            Value::Object(merged)
+        }
+        "not_null" => {
+            if arguments.is_empty() {
+                bail!("not_null() takes at least one argument");
+            }
+            for index in 0..arguments.len() {
+                let value = value(index)?;
+                if !value.is_null() {
+                    return Ok(value);
+                }
+            }
+            Value::Null
+        }
+        "reverse" => {
+            arity(1)?;
+            match value(0)? {
+                Value::String(s) => Value::String(s.chars().rev().collect()),
+                Value::Array(mut items) => {
+                    items.reverse();
+                    Value::Array(items)
+                }
+                other => return Err(invalid(&other, "an array or a string")),
+            }
+        }
+        "sort" => {
+            arity(1)?;
+            let mut items = array(0)?;
+            if !sortable(&items) {
+                bail!("sort() expects an array of numbers or of strings");
+            }
+            items.sort_by(order);
+            Value::Array(items)
+        }
+        "to_array" => {
+            arity(1)?;
+            match value(0)? {
+                Value::Array(items) => Value::Array(items),
+                other => Value::Array(vec![other]),
+            }
+        }
+        "to_string" => {
+            arity(1)?;
+            match value(0)? {
+                Value::String(s) => Value::String(s),
+                other => Value::String(other.to_string()),
+            }
+        }
+        "to_number" => {
+            arity(1)?;
+            match value(0)? {
+                Value::Number(n) => Value::Number(n),
+                Value::String(s) => s.parse::<Number>().map_or(Value::Null, Value::Number),
+                _ => Value::Null,
+            }
+        }
+        "type" => {
+            arity(1)?;
+            Value::String(type_name(&value(0)?).to_string())
+        }
+        _ => bail!("Unknown function {name}()"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_expressions() {
+        let data = json!({
+            "people": [
+                {"name": "ann", "age": 31, "tags": ["a", "b"]},
+                {"name": "bob", "age": 25, "tags": ["c"]},
+                {"name": "cy", "age": 40},
+            ],
+            "env": {"prod": {"replicas": 3}, "dev": {"replicas": 1}},
+        });
+        let search = |expression: &str| search(&data, expression).unwrap();
+        assert_eq!(search("people[0].name"), json!("ann"));
+        assert_eq!(search("people[-1].age"), json!(40));
+        assert_eq!(search("people[*].name"), json!(["ann", "bob", "cy"]));
+        assert_eq!(search("people[?age > `30`].name"), json!(["ann", "cy"]));
+        assert_eq!(search("people[?name == 'bob'] | [0].age"), json!(25));
+        assert_eq!(search("people[].tags[]"), json!(["a", "b", "c"]));
+        assert_eq!(search("people[:2].name"), json!(["ann", "bob"]));
+        assert_eq!(search("people[::-1].name | [0]"), json!("cy"));
+        assert_eq!(search("env.*.replicas"), json!([1, 3]));
+        assert_eq!(search("people[0].{n: name, t: length(tags)}"), json!({"n": "ann", "t": 2}));
+        assert_eq!(search("people[1].[name, age]"), json!(["bob", 25]));
+        assert_eq!(search("missing || 'default'"), json!("default"));
+        assert_eq!(search("!(people[2].tags) && `true`"), json!(true));
+        assert_eq!(search("\"people\"[0].\"name\""), json!("ann"));
+        assert_eq!(search("people.name"), Value::Null);
+    }
+
+    #[test]
+    fn test_functions() {
+        let data = json!({"people": [{"name": "ann", "age": 31}, {"name": "bob", "age": 25}], "n": [3, 1.5, 2]});
+        let search = |expression: &str| search(&data, expression).unwrap();
+        assert_eq!(search("sort_by(people, &age)[0].name"), json!("bob"));
+        assert_eq!(search("max_by(people, &age).name"), json!("ann"));
+        assert_eq!(search("map(&name, people)"), json!(["ann", "bob"]));
+        assert_eq!(search("join(', ', people[*].name)"), json!("ann, bob"));
+        assert_eq!(search("sum(n)"), json!(6.5));
+        assert_eq!(search("sort(n)"), json!([1.5, 2, 3]));
+        assert_eq!(search("avg(people[*].age)"), json!(28));
+        assert_eq!(search("contains(people[*].name, 'bob')"), json!(true));
+        assert_eq!(search("keys(people[0])"), json!(["age", "name"]));
+        assert_eq!(search("not_null(missing, people[1].name)"), json!("bob"));
+        assert_eq!(search("to_number('12') > `10`"), json!(true));
+        assert_eq!(search("type(merge(people[0], `{\"x\": 1}`))"), json!("object"));
+
+        for (expression, message) in [
+            ("length(`1`)", "length() expects a string, an array or an object, not number"),
+            ("sort_by(people, age)", "expression reference"),
+            ("people[", "found the end of the expression"),
+            ("people[0]]", "column 10: unexpected `]`"),
+            ("nope(`1`)", "Unknown function nope()"),
+            ("people[0:1:0]", "step cannot be 0"),
+        ] {
+            let error = super::search(&data, expression).unwrap_err().to_string();
+            assert!(error.contains(message), "{expression}: {error}");
+        }
+    }
+}
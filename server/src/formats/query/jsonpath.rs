@@ -0,0 +1,611 @@
+//! `JSONPath`, as RFC 9535 gives it
+//!
+//! A query starts at the root, `$`, and selects nodes segment by segment:
+//! members by name, as `.name` or `['name']`, array items by index,
+//! negative from the end, slices as `[start:end:step]`, every child with
+//! `*`, several selectors at once as `[0, 'name']`, and each of those
+//! among all descendants with `..`. Filters, `[?@.price < 10 && @.tags]`,
+//! keep the children for which comparisons of literals and singular
+//! queries, relative to the child with `@` or to the root with `$`, and
+//! tests that a query selects anything, hold. The function extensions of
+//! the RFC, such as `length()` and `match()`, are not supported.
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use super::equal;
+use crate::formats::schema::escape;
+
+/// Filters and brackets nested within one another before a query is refused
+const MAX_DEPTH: usize = 64;
+
+/// The nodes of `value` that `expression` selects, each with its JSON pointer, in the order the RFC gives
+///
+/// # Errors
+///
+/// Fails where `expression` does not parse, or calls a function wrongly.
+pub fn select<'a>(value: &'a Value, expression: &str) -> Result<Vec<(String, &'a Value)>> {
+    let mut parser = Parser { text: expression, pos: 0, depth: 0 };
+    parser.skip();
+    if !parser.eat("$") {
+        return Err(parser.error("a query starts at the root, $"));
+    }
+    let query = parser.query(false)?;
+    parser.skip();
+    if parser.pos < expression.len() {
+        return Err(parser.error("expected a segment, . or ["));
+    }
+    Ok(query.apply(value, (String::new(), value)))
+}
+
+/// Segments from the root, or from the node a filter tests
+#[derive(Debug)]
+struct Query {
+    relative: bool,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Child(Vec<Selector>),
+    Descendant(Vec<Selector>),
+}
+
+#[derive(Debug)]
+enum Selector {
+    Name(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>, Option<i64>),
+    Wildcard,
+    Filter(Filter),
+}
+
+#[derive(Debug)]
+enum Filter {
+    Or(Box<Filter>, Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Exists(Query),
+    Compare(Comparable, Comparison, Comparable),
+}
+
+#[derive(Debug)]
+enum Comparable {
+    Literal(Value),
+    Query(Query),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+type Selected<'a> = (String, &'a Value);
+
+impl Query {
+    fn apply<'a>(&self, root: &'a Value, start: Selected<'a>) -> Vec<Selected<'a>> {
+        let mut nodes = vec![start];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            for node in nodes {
+                match segment {
+                    Segment::Child(selectors) => {
+                        for selector in selectors {
+                            selector.select(root, &node, &mut next);
+                        }
+                    }
+                    Segment::Descendant(selectors) => {
+                        let mut descendants = Vec::new();
+                        descendants_of(node, &mut descendants);
+                        for descendant in &descendants {
+                            for selector in selectors {
+                                selector.select(root, descendant, &mut next);
+                            }
+                        }
+                    }
+                }
+            }
+            nodes = next;
+        }
+        nodes
+    }
+
+    /// Whether the query selects at most one node whatever the document, as comparisons need
+    fn is_singular(&self) -> bool {
+        self.segments.iter().all(|segment| {
+            matches!(segment, Segment::Child(selectors) if matches!(selectors.as_slice(), [Selector::Name(_) | Selector::Index(_)]))
+        })
+    }
+}
+
+/// `node` and every node within it, each before its children
+fn descendants_of<'a>(node: Selected<'a>, found: &mut Vec<Selected<'a>>) {
+    let (path, value) = node;
+    found.push((path.clone(), value));
+    for child in children(&path, value) {
+        descendants_of(child, found);
+    }
+}
+
+fn children<'a>(path: &str, value: &'a Value) -> Vec<Selected<'a>> {
+    match value {
+        Value::Array(items) => items.iter().enumerate().map(|(i, item)| (format!("{path}/{i}"), item)).collect(),
+        Value::Object(map) => map.iter().map(|(key, value)| (format!("{}/{}", path, escape(key)), value)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Selector {
+    fn select<'a>(&self, root: &'a Value, (path, value): &Selected<'a>, found: &mut Vec<Selected<'a>>) {
+        match (self, value) {
+            (Self::Name(name), Value::Object(map)) => {
+                if let Some(child) = map.get(name) {
+                    found.push((format!("{}/{}", path, escape(name)), child));
+                }
+            }
+            (Self::Index(index), Value::Array(items)) => {
+                let len = i64::try_from(items.len()).unwrap_or(i64::MAX);
+                let at = if *index < 0 { len + index } else { *index };
+                if let Some(item) = usize::try_from(at).ok().and_then(|at| items.get(at)) {
+                    found.push((format!("{path}/{at}"), item));
+                }
+            }
+            (Self::Slice(start, end, step), Value::Array(items)) => {
+                for i in slice(items.len(), *start, *end, *step) {
+                    found.push((format!("{path}/{i}"), &items[i]));
+                }
+            }
+            (Self::Wildcard, _) => found.extend(children(path, value)),
+            (Self::Filter(filter), _) => {
+                found.extend(children(path, value).into_iter().filter(|(_, child)| filter.test(root, child)));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Indices of an array of `len` items a slice selects, in the order it selects them
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // Indices are clamped within the array
+pub(super) fn slice(len: usize, start: Option<i64>, end: Option<i64>, step: Option<i64>) -> Vec<usize> {
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let step = step.unwrap_or(1);
+    let normalize = |i: i64| if i >= 0 { i } else { len + i };
+    let mut indices = Vec::new();
+    if step > 0 {
+        let lower = normalize(start.unwrap_or(0)).clamp(0, len);
+        let upper = normalize(end.unwrap_or(len)).clamp(0, len);
+        let mut i = lower;
+        while i < upper {
+            indices.push(i as usize);
+            i += step;
+        }
+    } else if step < 0 {
+        let upper = normalize(start.unwrap_or(len - 1)).clamp(-1, len - 1);
+        let lower = normalize(end.unwrap_or(-len - 1)).clamp(-1, len - 1);
+        let mut i = upper;
+        while lower < i {
+            indices.push(i as usize);
+            i += step;
+        }
+    }
+    indices
+}
+
+impl Filter {
+    fn test(&self, root: &Value, current: &Value) -> bool {
+        match self {
+            Self::Or(a, b) => a.test(root, current) || b.test(root, current),
+            Self::And(a, b) => a.test(root, current) && b.test(root, current),
+            Self::Not(filter) => !filter.test(root, current),
+            Self::Exists(query) => !run(query, root, current).is_empty(),
+            Self::Compare(left, comparison, right) => {
+                let (left, right) = (left.value(root, current), right.value(root, current));
+                compare(left.as_ref(), *comparison, right.as_ref())
+            }
+        }
+    }
+}
+
+fn run<'a>(query: &Query, root: &'a Value, current: &'a Value) -> Vec<Selected<'a>> {
+    let start = if query.relative { current } else { root };
+    query.apply(root, (String::new(), start))
+}
+
+impl Comparable {
+    /// The value compared, `None` where a query selects nothing
+    fn value(&self, root: &Value, current: &Value) -> Option<Value> {
+        match self {
+            Self::Literal(value) => Some(value.clone()),
+            Self::Query(query) => run(query, root, current).first().map(|(_, value)| (*value).clone()),
+        }
+    }
+}
+
+fn compare(left: Option<&Value>, comparison: Comparison, right: Option<&Value>) -> bool {
+    let equals = match (left, right) {
+        (None, None) => true,
+        (Some(a), Some(b)) => equal(a, b),
+        _ => false,
+    };
+    let less = |a: Option<&Value>, b: Option<&Value>| match (a, b) {
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a.as_f64() < b.as_f64(),
+        (Some(Value::String(a)), Some(Value::String(b))) => a < b,
+        _ => false,
+    };
+    match comparison {
+        Comparison::Eq => equals,
+        Comparison::Ne => !equals,
+        Comparison::Lt => less(left, right),
+        Comparison::Le => less(left, right) || equals,
+        Comparison::Gt => less(right, left),
+        Comparison::Ge => less(right, left) || equals,
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> anyhow::Error {
+        let column = self.text[..self.pos].chars().count() + 1;
+        anyhow!("Invalid JSONPath at column {column}: {message}")
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.text[self.pos..].starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {token}")))
+        }
+    }
+
+    fn skip(&mut self) {
+        while self.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn nest(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(&format!("nested more than {MAX_DEPTH} deep")));
+        }
+        Ok(())
+    }
+
+    /// The segments after `$` or `@`
+    fn query(&mut self, relative: bool) -> Result<Query> {
+        let mut segments = Vec::new();
+        loop {
+            if self.eat("..") {
+                let selectors = match self.peek() {
+                    Some('[') => self.bracket()?,
+                    Some('*') => {
+                        self.pos += 1;
+                        vec![Selector::Wildcard]
+                    }
+                    _ => vec![Selector::Name(self.name()?)],
+                };
+                segments.push(Segment::Descendant(selectors));
+            } else if self.eat(".") {
+                let selector = if self.eat("*") { Selector::Wildcard } else { Selector::Name(self.name()?) };
+                segments.push(Segment::Child(vec![selector]));
+            } else if self.peek() == Some('[') {
+                segments.push(Segment::Child(self.bracket()?));
+            } else {
+                return Ok(Query { relative, segments });
+            }
+        }
+    }
+
+    /// A member name written without quotes
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        let first = |c: char| c.is_ascii_alphabetic() || c == '_' || !c.is_ascii();
+        if !self.peek().is_some_and(first) {
+            return Err(self.error("expected a member name or *"));
+        }
+        while self.peek().is_some_and(|c| first(c) || c.is_ascii_digit()) {
+            self.bump();
+        }
+        Ok(self.text[start..self.pos].to_string())
+    }
+
+    fn bracket(&mut self) -> Result<Vec<Selector>> {
+        self.expect("[")?;
+        self.nest()?;
+        let mut selectors = Vec::new();
+        loop {
+            self.skip();
+            selectors.push(self.selector()?);
+            self.skip();
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect("]")?;
+        self.depth -= 1;
+        Ok(selectors)
+    }
+
+    fn selector(&mut self) -> Result<Selector> {
+        match self.peek() {
+            Some('\'' | '"') => Ok(Selector::Name(self.string()?)),
+            Some('*') => {
+                self.pos += 1;
+                Ok(Selector::Wildcard)
+            }
+            Some('?') => {
+                self.pos += 1;
+                Ok(Selector::Filter(self.or()?))
+            }
+            _ => {
+                let start = self.integer()?;
+                self.skip();
+                if !self.eat(":") {
+                    return start.map(Selector::Index).ok_or_else(|| self.error("expected a selector"));
+                }
+                self.skip();
+                let end = self.integer()?;
+                self.skip();
+                let step = if self.eat(":") {
+                    self.skip();
+                    self.integer()?
+                } else {
+                    None
+                };
+                Ok(Selector::Slice(start, end, step))
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<Option<i64>> {
+        let start = self.pos;
+        self.eat("-");
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        match &self.text[start..self.pos] {
+            "" => Ok(None),
+            digits => digits.parse().map(Some).map_err(|_| self.error("expected an integer")),
+        }
+    }
+
+    /// A string literal in single or double quotes
+    fn string(&mut self) -> Result<String> {
+        let quote = self.bump().unwrap_or('\'');
+        let mut string = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error("unterminated string")),
+                Some(c) if c == quote => return Ok(string),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode()?,
+                        Some(c @ ('\\' | '/' | '\'' | '"')) => c,
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    string.push(c);
+                }
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, reading the low surrogate after a high one
+    fn unicode(&mut self) -> Result<char> {
+        let high = self.code_unit()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.eat("\\u") {
+                return Err(self.error("expected the low surrogate of a pair"));
+            }
+            let low = self.code_unit()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("not a character"))
+    }
+
+    /// The UTF-16 code unit of four hex digits
+    fn code_unit(&mut self) -> Result<u32> {
+        let unit = self.text.get(self.pos..self.pos + 4).and_then(|hex| u32::from_str_radix(hex, 16).ok());
+        let unit = unit.ok_or_else(|| self.error("expected four hex digits"))?;
+        self.pos += 4;
+        Ok(unit)
+    }
+
+    fn or(&mut self) -> Result<Filter> {
+        let mut filter = self.and()?;
+        loop {
+            self.skip();
+            if !self.eat("||") {
+                return Ok(filter);
+            }
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+    }
+
+    fn and(&mut self) -> Result<Filter> {
+        let mut filter = self.basic()?;
+        loop {
+            self.skip();
+            if !self.eat("&&") {
+                return Ok(filter);
+            }
+            filter = Filter::And(Box::new(filter), Box::new(self.basic()?));
+        }
+    }
+
+    fn basic(&mut self) -> Result<Filter> {
+        self.skip();
+        if self.eat("!") {
+            self.skip();
+            self.nest()?;
+            let negated = if self.peek() == Some('(') { self.parenthesized()? } else { Filter::Exists(self.test_query()?) };
+            self.depth -= 1;
+            return Ok(Filter::Not(Box::new(negated)));
+        }
+        if self.peek() == Some('(') {
+            return self.parenthesized();
+        }
+        let left = self.comparable()?;
+        self.skip();
+        let Some(comparison) = self.comparison() else {
+            return match left {
+                Comparable::Query(query) => Ok(Filter::Exists(query)),
+                Comparable::Literal(_) => Err(self.error("expected a comparison after the literal")),
+            };
+        };
+        self.skip();
+        let right = self.comparable()?;
+        for side in [&left, &right] {
+            if let Comparable::Query(query) = side {
+                if !query.is_singular() {
+                    return Err(self.error("queries compared must select a single node, by names and indices"));
+                }
+            }
+        }
+        Ok(Filter::Compare(left, comparison, right))
+    }
+
+    fn parenthesized(&mut self) -> Result<Filter> {
+        self.expect("(")?;
+        self.nest()?;
+        let filter = self.or()?;
+        self.skip();
+        self.expect(")")?;
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn test_query(&mut self) -> Result<Query> {
+        match self.comparable()? {
+            Comparable::Query(query) => Ok(query),
+            Comparable::Literal(_) => Err(self.error("expected a query, @ or $")),
+        }
+    }
+
+    fn comparison(&mut self) -> Option<Comparison> {
+        let operators =
+            [("==", Comparison::Eq), ("!=", Comparison::Ne), ("<=", Comparison::Le), (">=", Comparison::Ge), ("<", Comparison::Lt), (">", Comparison::Gt)];
+        operators.into_iter().find(|(token, _)| self.eat(token)).map(|(_, comparison)| comparison)
+    }
+
+    fn comparable(&mut self) -> Result<Comparable> {
+        if self.eat("@") {
+            return Ok(Comparable::Query(self.query(true)?));
+        }
+        if self.eat("$") {
+            return Ok(Comparable::Query(self.query(false)?));
+        }
+        if matches!(self.peek(), Some('\'' | '"')) {
+            return Ok(Comparable::Literal(Value::String(self.string()?)));
+        }
+        for (word, value) in [("true", Value::Bool(true)), ("false", Value::Bool(false)), ("null", Value::Null)] {
+            if self.eat(word) {
+                return Ok(Comparable::Literal(value));
+            }
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            if self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                return Err(self.error("function extensions are not supported"));
+            }
+            return Err(self.error("expected a query or a literal"));
+        }
+        serde_json::from_str::<serde_json::Number>(&self.text[start..self.pos])
+            .map(|number| Comparable::Literal(Value::Number(number)))
+            .map_err(|_| self.error("invalid number"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn paths(value: &Value, expression: &str) -> Vec<String> {
+        select(value, expression).unwrap().into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn test_segments() {
+        let store = json!({"store": {"book": [
+            {"title": "A", "price": 8, "tags": ["x"]},
+            {"title": "B", "price": 12.5},
+            {"title": "C", "price": 22, "isbn": "0-1"},
+        ], "bicycle": {"price": 19}}});
+        assert_eq!(paths(&store, "$.store.book[0].title"), ["/store/book/0/title"]);
+        assert_eq!(paths(&store, "$['store'][\"book\"][-1]"), ["/store/book/2"]);
+        assert_eq!(paths(&store, "$.store.book[::-2].title"), ["/store/book/2/title", "/store/book/0/title"]);
+        assert_eq!(paths(&store, "$.store.book[0:2, 2]").len(), 3);
+        assert_eq!(paths(&store, "$..price"), ["/store/bicycle/price", "/store/book/0/price", "/store/book/1/price", "/store/book/2/price"]);
+        assert_eq!(paths(&store, "$.store.*").len(), 2);
+        assert_eq!(paths(&store, "$"), [""]);
+        assert!(paths(&store, "$.missing[0]").is_empty());
+    }
+
+    #[test]
+    fn test_filters() {
+        let store = json!({"book": [
+            {"title": "A", "price": 8, "tags": ["x"]},
+            {"title": "B", "price": 12.5},
+            {"title": "C", "price": 22, "isbn": "0-1"},
+        ], "max": 20});
+        let titles = |expression: &str| -> Vec<Value> {
+            select(&store, expression).unwrap().into_iter().map(|(_, value)| value.clone()).collect()
+        };
+        assert_eq!(titles("$.book[?@.price < 10].title"), [json!("A")]);
+        assert_eq!(titles("$.book[?(@.price > $.max || @.tags) && @.title != 'A'].title"), [json!("C")]);
+        assert_eq!(titles("$.book[?@.isbn].title"), [json!("C")]);
+        assert_eq!(titles("$.book[?!@.isbn].title"), [json!("A"), json!("B")]);
+        assert_eq!(titles("$.book[?@.price == 12.50].title"), [json!("B")]);
+        assert_eq!(titles("$..[?@ == 'x']"), [json!("x")]);
+
+        for (expression, message) in [
+            ("book", "column 1: a query starts at the root"),
+            ("$.book[", "expected a selector"),
+            ("$.book[?@..price < 1]", "must select a single node"),
+            ("$.book[?length(@) > 1]", "function extensions"),
+            ("$.book]", "column 7: expected a segment"),
+        ] {
+            let error = select(&store, expression).unwrap_err().to_string();
+            assert!(error.contains(message), "{expression}: {error}");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Queries over documents
+//!
+//! Documents are queried as their [`model`](super::model) values, so a
+//! YAML or TOML document answers as the JSON it converts to would.
+//! [`jsonpath`] selects the nodes matching a path, each with its JSON
+//! pointer; [`jmespath`] computes a single value from the document. Both
+//! compare numbers by value, so `1` equals `1.0`. XML documents are also
+//! queried with `XPath`, by [`xpath`], on their text rather than their
+//! values, so that each node selected comes with where it lies.
+
+pub mod jmespath;
+pub mod jsonpath;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::ops::Range;
+
+use super::schema::LineColumn;
+use super::xml::xpath::{self, XPathValue};
+
+/// The language of a query
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryLanguage {
+    #[default]
+    JsonPath,
+    JmesPath,
+    /// `XPath` 1.0, for XML documents only
+    XPath,
+}
+
+impl QueryLanguage {
+    /// The language called `name`, `jsonpath`, `jmespath` or `xpath`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is none of the three.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "jsonpath" => Ok(Self::JsonPath),
+            "jmespath" => Ok(Self::JmesPath),
+            "xpath" => Ok(Self::XPath),
+            _ => bail!("Unknown query language {name:?}: expected jsonpath, jmespath or xpath"),
+        }
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::JsonPath => "JSONPath",
+            Self::JmesPath => "JMESPath",
+            Self::XPath => "XPath",
+        }
+    }
+}
+
+/// What a query gave
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryResult {
+    /// The values a `JSONPath` query or `XPath` selected, in order, or the value of a `JMESPath` or `XPath` expression
+    pub result: Value,
+    /// JSON pointers of the values a `JSONPath` query selected, or location paths of the nodes an `XPath` selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+    /// Where the nodes an `XPath` selected lie in the document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranges: Option<Vec<SourceRange>>,
+}
+
+/// Where a node lies in the text of a document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceRange {
+    pub span: Range<usize>,
+    pub start: LineColumn,
+    pub end: LineColumn,
+}
+
+/// Evaluate `expression`, of `language`, against `value`
+///
+/// # Errors
+///
+/// Fails where `expression` does not parse, or is `XPath`, which needs XML
+/// text rather than a value.
+pub fn query(value: &Value, language: QueryLanguage, expression: &str) -> Result<QueryResult> {
+    Ok(match language {
+        QueryLanguage::JsonPath => {
+            let (paths, values) = jsonpath::select(value, expression)?.into_iter().map(|(path, value)| (path, value.clone())).unzip();
+            QueryResult { result: Value::Array(values), paths: Some(paths), ranges: None }
+        }
+        QueryLanguage::JmesPath => QueryResult { result: jmespath::search(value, expression)?, paths: None, ranges: None },
+        QueryLanguage::XPath => bail!("XPath queries the text of XML documents, not their values"),
+    })
+}
+
+/// Evaluate the `XPath` `expression` against `xml`, giving the nodes selected with their ranges, or the value computed
+///
+/// # Errors
+///
+/// Fails where `xml` does not parse, or `expression` is not an `XPath`
+/// expression.
+pub fn xpath(xml: &str, expression: &str) -> Result<QueryResult> {
+    let result = match xpath::evaluate(xml, expression)? {
+        XPathValue::Nodes(nodes) => {
+            let position = |offset| {
+                let (line, column) = super::line_column(xml, offset);
+                LineColumn { line, column }
+            };
+            let ranges = nodes
+                .iter()
+                .map(|node| SourceRange { span: node.span.clone(), start: position(node.span.start), end: position(node.span.end) })
+                .collect();
+            let (values, paths) = nodes.into_iter().map(|node| (node.value, node.path)).unzip();
+            return Ok(QueryResult { result: Value::Array(values), paths: Some(paths), ranges: Some(ranges) });
+        }
+        XPathValue::Boolean(b) => Value::Bool(b),
+        // Whole numbers, as counts are, without a fraction
+        #[allow(clippy::cast_possible_truncation)] // Whole, and within 2^53
+        XPathValue::Number(n) if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 => json!(n as i64),
+        XPathValue::Number(n) => json!(n),
+        XPathValue::String(s) => Value::String(s),
+    };
+    Ok(QueryResult { result, paths: None, ranges: None })
+}
+
+/// Whether `a` and `b` are the same value, numbers compared by value
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => match (x.as_i64(), y.as_i64()) {
+            (Some(x), Some(y)) => x == y,
+            _ => x.as_f64() == y.as_f64(),
+        },
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| equal(x, y)),
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(key, x)| y.get(key).is_some_and(|y| equal(x, y)))
+        }
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query() {
+        let value = json!({"items": [{"n": 1}, {"n": 2.0}]});
+        let selected = query(&value, QueryLanguage::JsonPath, "$.items[?@.n == 2].n").unwrap();
+        assert_eq!(selected, QueryResult { result: json!([2.0]), paths: Some(vec!["/items/1/n".to_string()]), ranges: None });
+        let searched = query(&value, QueryLanguage::JmesPath, "items[?n == `1`] | length(@)").unwrap();
+        assert_eq!(serde_json::to_value(searched).unwrap(), json!({"result": 1}));
+        assert_eq!(QueryLanguage::from_name("JMESPath").unwrap(), QueryLanguage::JmesPath);
+        assert!(QueryLanguage::from_name("xquery").is_err());
+        assert!(query(&value, QueryLanguage::XPath, "/items").is_err());
+    }
+
+    #[test]
+    fn test_xpath() {
+        let xml = "<items>\n  <item n=\"1\"/>\n  <item n=\"2\">two</item>\n</items>";
+        let selected = xpath(xml, "//item[@n = 2]").unwrap();
+        assert_eq!(selected.result, json!([{"item": {"@n": "2", "#text": "two"}}]));
+        assert_eq!(selected.paths, Some(vec!["/items[1]/item[2]".to_string()]));
+        let range = &selected.ranges.unwrap()[0];
+        assert_eq!(&xml[range.span.clone()], "<item n=\"2\">two</item>");
+        assert_eq!((range.start, range.end), (LineColumn { line: 3, column: 3 }, LineColumn { line: 3, column: 25 }));
+        assert_eq!(serde_json::to_value(xpath(xml, "count(//item)").unwrap()).unwrap(), json!({"result": 2}));
+        assert_eq!(xpath(xml, "sum(//@n) div 4").unwrap().result, json!(0.75));
+    }
+}
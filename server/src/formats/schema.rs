@@ -0,0 +1,1214 @@
+//! JSON Schema validation
+//!
+//! A [`Schema`] checks a JSON value against a JSON Schema of draft 2020-12,
+//! giving a [`Violation`] for each keyword that fails. [`validate`] checks a
+//! JSON, YAML or TOML document, read as JSON first, and places each
+//! violation in the document's text as a [`SchemaDiagnostic`]: exactly for
+//! JSON and TOML, and by key and indentation for YAML, falling back to the
+//! nearest enclosing value that can be found. A Markdown document is
+//! checked by its front matter, as [`super::markdown::validate`] does.
+//!
+//! References resolve within the schema, to JSON pointers, `$anchor`s and
+//! subschemas with an `$id`. Nothing is fetched, so a schema referring to
+//! another document is refused. `format` is an annotation, as the draft
+//! makes it by default, and `pattern` is a Rust regular expression, which
+//! covers the ECMA-262 syntax of schemas in practice but not lookaround or
+//! backreferences.
+//!
+//! Schemas are registered by name in the [`SchemaRegistry`] of
+//! [`Formats`](super::Formats), with globs choosing the documents editors
+//! check against each; [`install`] loads the configured ones.
+
+use crate::core::Format;
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// References followed inside one another before a check gives up, taking them for a loop
+const MAX_DEPTH: usize = 64;
+
+/// A schema loaded at startup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaConfig {
+    /// Name requests give for it, such as `deploy`
+    pub name: String,
+    /// JSON, YAML or TOML file holding the schema
+    pub path: PathBuf,
+    /// Globs over URI paths of the documents editors check against it; one without `/` matches the file name
+    #[serde(default)]
+    pub documents: Vec<String>,
+}
+
+impl SchemaConfig {
+    /// The schema in `path`, called `name`, checking no document in editors
+    pub fn new(name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self { name: name.into(), path: path.into(), documents: Vec::new() }
+    }
+}
+
+/// A keyword a value fails
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// The keyword, such as `required`
+    pub keyword: String,
+    /// JSON pointer to the value, empty for the whole document
+    pub instance_path: String,
+    /// JSON pointer to the keyword in the schema
+    pub schema_path: String,
+    pub message: String,
+}
+
+/// A line and column, both from 1, the column counted in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A [`Violation`] placed in the document's text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaDiagnostic {
+    #[serde(flatten)]
+    pub violation: Violation,
+    /// Bytes of the value in the document, or of the nearest enclosing value found
+    pub span: Range<usize>,
+    pub start: LineColumn,
+    pub end: LineColumn,
+}
+
+/// Whether documents of `format` can be checked against a schema
+#[must_use]
+pub fn supports(format: Format) -> bool {
+    matches!(format, Format::Json | Format::Yaml | Format::Toml | Format::Markdown)
+}
+
+/// Check `content`, a document of `format`, against `schema`
+///
+/// # Errors
+///
+/// Fails where `content` does not parse as `format`, or is a format schemas
+/// do not apply to.
+pub fn validate(content: &str, format: Format, schema: &Schema) -> Result<Vec<SchemaDiagnostic>> {
+    let instance: Value = match format {
+        Format::Json => serde_json::from_str(content).map_err(|e| anyhow!("Invalid JSON: {e}"))?,
+        Format::Yaml => serde_json::from_str(&super::yaml::yaml_to_json(content)?)?,
+        Format::Toml => serde_json::from_str(&super::toml::toml_to_json(content)?)?,
+        Format::Markdown => return super::markdown::validate(content, schema),
+        other => bail!("Schemas check JSON, YAML, TOML or Markdown documents, not {}", other.name()),
+    };
+    let diagnostics = schema
+        .validate(&instance)
+        .into_iter()
+        .map(|violation| {
+            let span = locate(content, format, &violation.instance_path);
+            let position = |offset| {
+                let (line, column) = super::line_column(content, offset);
+                LineColumn { line, column }
+            };
+            SchemaDiagnostic { start: position(span.start), end: position(span.end), span, violation }
+        })
+        .collect();
+    Ok(diagnostics)
+}
+
+/// A compiled JSON Schema
+#[derive(Debug, Clone)]
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+    /// JSON pointers of the subschemas with an `$anchor`, by anchor
+    anchors: HashMap<String, String>,
+    /// JSON pointers of the subschemas with an `$id`, by ID
+    ids: HashMap<String, String>,
+}
+
+impl Schema {
+    /// Compile `root`, refusing other drafts, invalid patterns and references that do not resolve
+    ///
+    /// # Errors
+    ///
+    /// Fails where `root` is of another draft, has an invalid pattern, or a
+    /// reference that does not resolve.
+    pub fn new(root: Value) -> Result<Self> {
+        if !matches!(root, Value::Object(_) | Value::Bool(_)) {
+            bail!("A schema is an object or a boolean, not {}", type_name(&root));
+        }
+        if let Some(dialect) = root.get("$schema") {
+            let dialect = dialect.as_str().unwrap_or_default();
+            if !dialect.contains("2020-12") {
+                bail!("Only draft 2020-12 schemas are supported, not {dialect}");
+            }
+        }
+        let mut schema = Self { root: Value::Null, patterns: HashMap::new(), anchors: HashMap::new(), ids: HashMap::new() };
+        let mut references = Vec::new();
+        schema.index(&root, "", &mut references)?;
+        schema.root = root;
+        for (reference, at) in references {
+            if schema.resolve(&reference).is_none() {
+                bail!("{reference} at {at} does not resolve within the schema");
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Compile the schema in `path`, read as YAML or TOML by its extension and as JSON otherwise
+    ///
+    /// # Errors
+    ///
+    /// Fails where the file cannot be read or parsed, or holds no schema
+    /// [`Schema::new`] compiles.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let json = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => super::yaml::yaml_to_json(&text)?,
+            Some("toml") => super::toml::toml_to_json(&text)?,
+            _ => text,
+        };
+        let root = serde_json::from_str(&json).with_context(|| format!("parsing {}", path.display()))?;
+        Self::new(root).with_context(|| format!("compiling {}", path.display()))
+    }
+
+    /// The keywords `instance` fails
+    #[must_use]
+    pub fn validate(&self, instance: &Value) -> Vec<Violation> {
+        let mut check = Check { schema: self, violations: Vec::new(), depth: 0 };
+        check.check(&self.root, "", instance, "");
+        check.violations
+    }
+
+    /// Record the anchors, IDs and patterns of `schema`, found at the pointer `at`, and its references
+    fn index(&mut self, schema: &Value, at: &str, references: &mut Vec<(String, String)>) -> Result<()> {
+        let Value::Object(map) = schema else {
+            return Ok(());
+        };
+        if let Some(id) = map.get("$id").and_then(Value::as_str) {
+            self.ids.insert(id.trim_end_matches('#').to_string(), at.to_string());
+        }
+        for keyword in ["$anchor", "$dynamicAnchor"] {
+            if let Some(anchor) = map.get(keyword).and_then(Value::as_str) {
+                self.anchors.insert(anchor.to_string(), at.to_string());
+            }
+        }
+        for (keyword, value) in map {
+            let at = format!("{}/{}", at, escape(keyword));
+            match (keyword.as_str(), value) {
+                ("$ref" | "$dynamicRef", Value::String(reference)) => references.push((reference.clone(), at)),
+                ("pattern", Value::String(pattern)) => self.compile(pattern, &at)?,
+                (
+                    "additionalProperties" | "propertyNames" | "items" | "contains" | "not" | "if" | "then" | "else"
+                    | "unevaluatedItems" | "unevaluatedProperties",
+                    schema,
+                ) => self.index(schema, &at, references)?,
+                ("allOf" | "anyOf" | "oneOf" | "prefixItems", Value::Array(schemas)) => {
+                    for (i, schema) in schemas.iter().enumerate() {
+                        self.index(schema, &format!("{at}/{i}"), references)?;
+                    }
+                }
+                ("properties" | "patternProperties" | "dependentSchemas" | "$defs" | "definitions", Value::Object(schemas)) => {
+                    for (name, schema) in schemas {
+                        if keyword == "patternProperties" {
+                            self.compile(name, &at)?;
+                        }
+                        self.index(schema, &format!("{}/{}", at, escape(name)), references)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(&mut self, pattern: &str, at: &str) -> Result<()> {
+        let regex = Regex::new(pattern).map_err(|e| anyhow!("Invalid pattern {pattern:?} at {at}: {e}"))?;
+        self.patterns.insert(pattern.to_string(), regex);
+        Ok(())
+    }
+
+    /// The subschema `reference` names
+    fn resolve(&self, reference: &str) -> Option<&Value> {
+        let (base, fragment) = reference.split_once('#').unwrap_or((reference, ""));
+        let resource = if base.is_empty() {
+            ""
+        } else {
+            // A relative reference names the last segments of an ID
+            let relative = format!("/{base}");
+            self.ids.iter().find(|(id, _)| *id == base || id.ends_with(&relative)).map(|(_, at)| at.as_str())?
+        };
+        if fragment.is_empty() {
+            self.root.pointer(resource)
+        } else if fragment.starts_with('/') {
+            self.root.pointer(&format!("{}{}", resource, percent_decode(fragment)))
+        } else {
+            self.anchors.get(fragment).and_then(|at| self.root.pointer(at))
+        }
+    }
+}
+
+/// Properties and items of a value that a schema and its subschemas looked at
+#[derive(Default)]
+struct Evaluated {
+    properties: HashSet<String>,
+    items: HashSet<usize>,
+    all_items: bool,
+}
+
+impl Evaluated {
+    fn merge(&mut self, other: Evaluated) {
+        self.properties.extend(other.properties);
+        self.items.extend(other.items);
+        self.all_items |= other.all_items;
+    }
+}
+
+/// One run of [`Schema::validate`]
+struct Check<'s> {
+    schema: &'s Schema,
+    violations: Vec<Violation>,
+    /// References followed to get here
+    depth: usize,
+}
+
+impl<'s> Check<'s> {
+    fn fail(&mut self, keyword: &str, at: &str, path: &str, message: String) {
+        self.violations.push(Violation {
+            keyword: keyword.to_string(),
+            instance_path: path.to_string(),
+            schema_path: format!("{at}/{keyword}"),
+            message,
+        });
+    }
+
+    /// What `schema` evaluated of `instance`, if `instance` passes it; failures are not recorded
+    fn probe(&mut self, schema: &'s Value, at: &str, instance: &Value, path: &str) -> Option<Evaluated> {
+        let before = self.violations.len();
+        let evaluated = self.check(schema, at, instance, path);
+        let passed = self.violations.len() == before;
+        self.violations.truncate(before);
+        passed.then_some(evaluated)
+    }
+
+    /// Check `instance`, at the pointer `path`, against `schema`, at the pointer `at`
+    #[allow(clippy::too_many_lines)] // A step per group of keywords
+    fn check(&mut self, schema: &'s Value, at: &str, instance: &Value, path: &str) -> Evaluated {
+        let mut evaluated = Evaluated::default();
+        let keywords = match schema {
+            Value::Object(keywords) => keywords,
+            Value::Bool(false) => {
+                self.violations.push(Violation {
+                    keyword: "false".to_string(),
+                    instance_path: path.to_string(),
+                    schema_path: at.to_string(),
+                    message: "No value is allowed here".to_string(),
+                });
+                return evaluated;
+            }
+            _ => return evaluated,
+        };
+        let keyword = |name: &str| keywords.get(name);
+        let here = |name: &str| format!("{at}/{name}");
+
+        for name in ["$ref", "$dynamicRef"] {
+            if let Some(reference) = keyword(name).and_then(Value::as_str) {
+                evaluated.merge(self.reference(reference, &here(name), instance, path));
+            }
+        }
+
+        if let Some(types) = keyword("type") {
+            let types: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.is_empty() && !types.iter().any(|name| is_type(instance, name)) {
+                self.fail("type", at, path, format!("Expected {}, found {}", types.join(" or "), type_name(instance)));
+            }
+        }
+        if let Some(Value::Array(values)) = keyword("enum") {
+            if !values.iter().any(|value| equal(value, instance)) {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                self.fail("enum", at, path, format!("Must be one of {}", values.join(", ")));
+            }
+        }
+        if let Some(value) = keyword("const") {
+            if !equal(value, instance) {
+                self.fail("const", at, path, format!("Must be {value}"));
+            }
+        }
+
+        match instance {
+            Value::Number(number) => self.number(keywords, at, number, path),
+            Value::String(text) => self.string(keywords, at, text, path),
+            Value::Array(items) => evaluated.merge(self.array(keywords, at, items, path)),
+            Value::Object(properties) => evaluated.merge(self.object(keywords, at, properties, path)),
+            _ => {}
+        }
+
+        if let Some(Value::Array(schemas)) = keyword("allOf") {
+            for (i, schema) in schemas.iter().enumerate() {
+                evaluated.merge(self.check(schema, &format!("{}/{}", here("allOf"), i), instance, path));
+            }
+        }
+        if let Some(Value::Array(schemas)) = keyword("anyOf") {
+            let mut matched = false;
+            for (i, schema) in schemas.iter().enumerate() {
+                if let Some(branch) = self.probe(schema, &format!("{}/{}", here("anyOf"), i), instance, path) {
+                    evaluated.merge(branch);
+                    matched = true;
+                }
+            }
+            if !matched {
+                self.fail("anyOf", at, path, "Matches none of the anyOf schemas".to_string());
+            }
+        }
+        if let Some(Value::Array(schemas)) = keyword("oneOf") {
+            let mut matched = Vec::new();
+            for (i, schema) in schemas.iter().enumerate() {
+                if let Some(branch) = self.probe(schema, &format!("{}/{}", here("oneOf"), i), instance, path) {
+                    matched.push(i);
+                    evaluated.merge(branch);
+                }
+            }
+            match matched.as_slice() {
+                [_] => {}
+                [] => self.fail("oneOf", at, path, "Matches none of the oneOf schemas".to_string()),
+                _ => {
+                    let matched: Vec<String> = matched.iter().map(usize::to_string).collect();
+                    self.fail("oneOf", at, path, format!("Matches oneOf schemas {}, not one only", matched.join(", ")));
+                }
+            }
+        }
+        if let Some(schema) = keyword("not") {
+            if self.probe(schema, &here("not"), instance, path).is_some() {
+                self.fail("not", at, path, "Must not match the not schema".to_string());
+            }
+        }
+        if let Some(condition) = keyword("if") {
+            let (branch, name) = match self.probe(condition, &here("if"), instance, path) {
+                Some(passed) => {
+                    evaluated.merge(passed);
+                    (keyword("then"), "then")
+                }
+                None => (keyword("else"), "else"),
+            };
+            if let Some(branch) = branch {
+                evaluated.merge(self.check(branch, &here(name), instance, path));
+            }
+        }
+
+        self.unevaluated(keywords, at, instance, path, &mut evaluated);
+        evaluated
+    }
+
+    fn reference(&mut self, reference: &str, at: &str, instance: &Value, path: &str) -> Evaluated {
+        let Some(target) = self.schema.resolve(reference) else {
+            self.violations.push(Violation {
+                keyword: "$ref".to_string(),
+                instance_path: path.to_string(),
+                schema_path: at.to_string(),
+                message: format!("{reference} does not resolve"),
+            });
+            return Evaluated::default();
+        };
+        if self.depth >= MAX_DEPTH {
+            self.violations.push(Violation {
+                keyword: "$ref".to_string(),
+                instance_path: path.to_string(),
+                schema_path: at.to_string(),
+                message: format!("References nest more than {MAX_DEPTH} deep, likely in a loop"),
+            });
+            return Evaluated::default();
+        }
+        self.depth += 1;
+        let evaluated = self.check(target, at, instance, path);
+        self.depth -= 1;
+        evaluated
+    }
+
+    fn number(&mut self, keywords: &'s Map<String, Value>, at: &str, number: &serde_json::Number, path: &str) {
+        let value = number.as_f64().unwrap_or(f64::NAN);
+        let limit = |name: &str| keywords.get(name).and_then(Value::as_f64);
+        if let Some(divisor) = limit("multipleOf").filter(|divisor| *divisor > 0.0) {
+            let quotient = value / divisor;
+            if (quotient - quotient.round()).abs() > 1e-9 * quotient.abs().max(1.0) {
+                self.fail("multipleOf", at, path, format!("{number} is not a multiple of {divisor}"));
+            }
+        }
+        if let Some(maximum) = limit("maximum").filter(|maximum| value > *maximum) {
+            self.fail("maximum", at, path, format!("{number} is above the maximum of {maximum}"));
+        }
+        if let Some(maximum) = limit("exclusiveMaximum").filter(|maximum| value >= *maximum) {
+            self.fail("exclusiveMaximum", at, path, format!("{number} is not below {maximum}"));
+        }
+        if let Some(minimum) = limit("minimum").filter(|minimum| value < *minimum) {
+            self.fail("minimum", at, path, format!("{number} is below the minimum of {minimum}"));
+        }
+        if let Some(minimum) = limit("exclusiveMinimum").filter(|minimum| value <= *minimum) {
+            self.fail("exclusiveMinimum", at, path, format!("{number} is not above {minimum}"));
+        }
+    }
+
+    fn string(&mut self, keywords: &'s Map<String, Value>, at: &str, text: &str, path: &str) {
+        let length = text.chars().count() as u64;
+        if let Some(maximum) = keywords.get("maxLength").and_then(Value::as_u64).filter(|maximum| length > *maximum) {
+            self.fail("maxLength", at, path, format!("Longer than {maximum} characters"));
+        }
+        if let Some(minimum) = keywords.get("minLength").and_then(Value::as_u64).filter(|minimum| length < *minimum) {
+            self.fail("minLength", at, path, format!("Shorter than {minimum} characters"));
+        }
+        if let Some(pattern) = keywords.get("pattern").and_then(Value::as_str) {
+            if !self.matches(pattern, text) {
+                self.fail("pattern", at, path, format!("Does not match the pattern {pattern}"));
+            }
+        }
+    }
+
+    fn array(&mut self, keywords: &'s Map<String, Value>, at: &str, items: &[Value], path: &str) -> Evaluated {
+        let mut evaluated = Evaluated::default();
+        let here = |name: &str| format!("{at}/{name}");
+        let item_path = |i: usize| format!("{path}/{i}");
+
+        let prefix = match keywords.get("prefixItems") {
+            Some(Value::Array(schemas)) => {
+                for (i, (schema, item)) in schemas.iter().zip(items).enumerate() {
+                    self.check(schema, &format!("{}/{}", here("prefixItems"), i), item, &item_path(i));
+                    evaluated.items.insert(i);
+                }
+                schemas.len()
+            }
+            _ => 0,
+        };
+        if let Some(schema) = keywords.get("items") {
+            for (i, item) in items.iter().enumerate().skip(prefix) {
+                if schema == &Value::Bool(false) {
+                    self.fail("items", at, &item_path(i), format!("Item {i} is not allowed"));
+                } else {
+                    self.check(schema, &here("items"), item, &item_path(i));
+                }
+            }
+            evaluated.all_items = true;
+        }
+        if let Some(schema) = keywords.get("contains") {
+            let mut matched = 0;
+            for (i, item) in items.iter().enumerate() {
+                if self.probe(schema, &here("contains"), item, &item_path(i)).is_some() {
+                    evaluated.items.insert(i);
+                    matched += 1;
+                }
+            }
+            let minimum = keywords.get("minContains").and_then(Value::as_u64).unwrap_or(1);
+            if matched < minimum {
+                self.fail("contains", at, path, format!("{matched} items match contains, at least {minimum} must"));
+            }
+            if let Some(maximum) = keywords.get("maxContains").and_then(Value::as_u64).filter(|maximum| matched > *maximum) {
+                self.fail("maxContains", at, path, format!("{matched} items match contains, at most {maximum} may"));
+            }
+        }
+
+        let count = items.len() as u64;
+        if let Some(maximum) = keywords.get("maxItems").and_then(Value::as_u64).filter(|maximum| count > *maximum) {
+            self.fail("maxItems", at, path, format!("More than {maximum} items"));
+        }
+        if let Some(minimum) = keywords.get("minItems").and_then(Value::as_u64).filter(|minimum| count < *minimum) {
+            self.fail("minItems", at, path, format!("Fewer than {minimum} items"));
+        }
+        if keywords.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let repeat = (0..items.len()).find_map(|j| (0..j).find(|&i| equal(&items[i], &items[j])).map(|i| (i, j)));
+            if let Some((i, j)) = repeat {
+                self.fail("uniqueItems", at, path, format!("Items {i} and {j} are equal"));
+            }
+        }
+        evaluated
+    }
+
+    fn object(&mut self, keywords: &'s Map<String, Value>, at: &str, properties: &Map<String, Value>, path: &str) -> Evaluated {
+        let mut evaluated = Evaluated::default();
+        let here = |name: &str| format!("{at}/{name}");
+        let property_path = |name: &str| format!("{}/{}", path, escape(name));
+
+        if let Some(Value::Object(schemas)) = keywords.get("properties") {
+            for (name, schema) in schemas {
+                if let Some(value) = properties.get(name) {
+                    self.check(schema, &format!("{}/{}", here("properties"), escape(name)), value, &property_path(name));
+                    evaluated.properties.insert(name.clone());
+                }
+            }
+        }
+        if let Some(Value::Object(schemas)) = keywords.get("patternProperties") {
+            for (pattern, schema) in schemas {
+                let at = format!("{}/{}", here("patternProperties"), escape(pattern));
+                let matching: Vec<_> = properties.iter().filter(|(name, _)| self.matches(pattern, name)).collect();
+                for (name, value) in matching {
+                    self.check(schema, &at, value, &property_path(name));
+                    evaluated.properties.insert(name.clone());
+                }
+            }
+        }
+        if let Some(schema) = keywords.get("additionalProperties") {
+            let additional: Vec<&String> = properties.keys().filter(|name| !evaluated.properties.contains(*name)).collect();
+            for name in additional {
+                if schema == &Value::Bool(false) {
+                    self.fail("additionalProperties", at, &property_path(name), format!("Property '{name}' is not allowed"));
+                } else {
+                    self.check(schema, &here("additionalProperties"), &properties[name], &property_path(name));
+                }
+                evaluated.properties.insert(name.clone());
+            }
+        }
+        if let Some(schema) = keywords.get("propertyNames") {
+            for name in properties.keys() {
+                let key = Value::String(name.clone());
+                if self.probe(schema, &here("propertyNames"), &key, &property_path(name)).is_none() {
+                    let message = format!("Property name '{name}' does not match propertyNames");
+                    self.fail("propertyNames", at, &property_path(name), message);
+                }
+            }
+        }
+
+        if let Some(Value::Array(required)) = keywords.get("required") {
+            for name in required.iter().filter_map(Value::as_str).filter(|name| !properties.contains_key(*name)) {
+                self.fail("required", at, path, format!("Missing required property '{name}'"));
+            }
+        }
+        if let Some(Value::Object(dependencies)) = keywords.get("dependentRequired") {
+            for (name, required) in dependencies.iter().filter(|(name, _)| properties.contains_key(*name)) {
+                let missing = required.as_array().into_iter().flatten().filter_map(Value::as_str);
+                for other in missing.filter(|other| !properties.contains_key(*other)) {
+                    self.fail("dependentRequired", at, path, format!("Property '{name}' requires '{other}'"));
+                }
+            }
+        }
+        if let Some(Value::Object(schemas)) = keywords.get("dependentSchemas") {
+            let instance = Value::Object(properties.clone());
+            for (name, schema) in schemas.iter().filter(|(name, _)| properties.contains_key(*name)) {
+                evaluated.merge(self.check(schema, &format!("{}/{}", here("dependentSchemas"), escape(name)), &instance, path));
+            }
+        }
+
+        let count = properties.len() as u64;
+        if let Some(maximum) = keywords.get("maxProperties").and_then(Value::as_u64).filter(|maximum| count > *maximum) {
+            self.fail("maxProperties", at, path, format!("More than {maximum} properties"));
+        }
+        if let Some(minimum) = keywords.get("minProperties").and_then(Value::as_u64).filter(|minimum| count < *minimum) {
+            self.fail("minProperties", at, path, format!("Fewer than {minimum} properties"));
+        }
+        evaluated
+    }
+
+    /// Apply `unevaluatedItems` and `unevaluatedProperties` to what no other keyword looked at
+    fn unevaluated(&mut self, keywords: &'s Map<String, Value>, at: &str, instance: &Value, path: &str, evaluated: &mut Evaluated) {
+        match instance {
+            Value::Array(items) if !evaluated.all_items => {
+                let Some(schema) = keywords.get("unevaluatedItems") else {
+                    return;
+                };
+                for (i, item) in items.iter().enumerate().filter(|(i, _)| !evaluated.items.contains(i)) {
+                    let item_path = format!("{path}/{i}");
+                    if schema == &Value::Bool(false) {
+                        self.fail("unevaluatedItems", at, &item_path, format!("Item {i} is not allowed"));
+                    } else {
+                        self.check(schema, &format!("{at}/unevaluatedItems"), item, &item_path);
+                    }
+                }
+                evaluated.all_items = true;
+            }
+            Value::Object(properties) => {
+                let Some(schema) = keywords.get("unevaluatedProperties") else {
+                    return;
+                };
+                for (name, value) in properties.iter().filter(|(name, _)| !evaluated.properties.contains(*name)) {
+                    let property_path = format!("{}/{}", path, escape(name));
+                    if schema == &Value::Bool(false) {
+                        let message = format!("Property '{name}' is not allowed");
+                        self.fail("unevaluatedProperties", at, &property_path, message);
+                    } else {
+                        self.check(schema, &format!("{at}/unevaluatedProperties"), value, &property_path);
+                    }
+                }
+                evaluated.properties.extend(properties.keys().cloned());
+            }
+            _ => {}
+        }
+    }
+
+    fn matches(&self, pattern: &str, text: &str) -> bool {
+        match self.schema.patterns.get(pattern) {
+            Some(regex) => regex.is_match(text),
+            // Reached only through a reference into an unknown keyword; an invalid pattern matches anything
+            None => Regex::new(pattern).map_or(true, |regex| regex.is_match(text)),
+        }
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Equality as JSON Schema has it, where `1` and `1.0` are the same number
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => match (x.as_i64(), y.as_i64()) {
+            (Some(x), Some(y)) => x == y,
+            _ => x.as_f64() == y.as_f64(),
+        },
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| equal(x, y)),
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(key, x)| y.get(key).is_some_and(|y| equal(x, y)))
+        }
+        _ => a == b,
+    }
+}
+
+/// `name` as a JSON pointer segment
+pub(crate) fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+/// Decode the `%XX` escapes of a URI fragment
+fn percent_decode(fragment: &str) -> String {
+    let bytes = fragment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%').then(|| fragment.get(i + 1..i + 3)).flatten();
+        if let Some(byte) = escaped.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Bytes of the value at `pointer` in `content`, or of the nearest enclosing value found
+fn locate(content: &str, format: Format, pointer: &str) -> Range<usize> {
+    let segments: Vec<String> = pointer.split('/').skip(1).map(unescape).collect();
+    let found = match format {
+        Format::Json => json_span(content, &segments),
+        Format::Toml => toml_span(content, &segments),
+        Format::Yaml => yaml_span(content, &segments),
+        _ => None,
+    };
+    found.unwrap_or_else(|| {
+        let start = content.len() - content.trim_start().len();
+        start..content.trim_end().len().max(start)
+    })
+}
+
+fn json_span(text: &str, segments: &[String]) -> Option<Range<usize>> {
+    let bytes = text.as_bytes();
+    let mut start = skip_space(bytes, 0);
+    for segment in segments {
+        let next = match bytes.get(start)? {
+            b'{' => json_member(text, start, segment),
+            b'[' => segment.parse().ok().and_then(|index| json_item(bytes, start, index)),
+            _ => None,
+        };
+        match next {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    Some(start..json_end(bytes, start)?)
+}
+
+fn skip_space(bytes: &[u8], mut i: usize) -> usize {
+    while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+        i += 1;
+    }
+    i
+}
+
+/// Where the JSON value starting at `start` ends
+fn json_end(bytes: &[u8], start: usize) -> Option<usize> {
+    match bytes.get(start)? {
+        b'"' => {
+            let mut i = start + 1;
+            loop {
+                match bytes.get(i)? {
+                    b'\\' => i += 2,
+                    b'"' => return Some(i + 1),
+                    _ => i += 1,
+                }
+            }
+        }
+        b'{' | b'[' => {
+            let (mut i, mut depth) = (start, 0);
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = json_end(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        _ => {
+            let mut i = start;
+            while bytes.get(i).is_some_and(|b| !b",}] \t\r\n".contains(b)) {
+                i += 1;
+            }
+            Some(i)
+        }
+    }
+}
+
+/// Where the value of the member `name` of the object opening at `open` starts
+fn json_member(text: &str, open: usize, name: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut i = skip_space(bytes, open + 1);
+    while bytes.get(i) == Some(&b'"') {
+        let key_end = json_end(bytes, i)?;
+        let key: String = serde_json::from_str(&text[i..key_end]).ok()?;
+        i = skip_space(bytes, key_end);
+        if bytes.get(i) != Some(&b':') {
+            return None;
+        }
+        let value = skip_space(bytes, i + 1);
+        if key == name {
+            return Some(value);
+        }
+        i = skip_space(bytes, json_end(bytes, value)?);
+        if bytes.get(i) == Some(&b',') {
+            i = skip_space(bytes, i + 1);
+        }
+    }
+    None
+}
+
+/// Where item `index` of the array opening at `open` starts
+fn json_item(bytes: &[u8], open: usize, index: usize) -> Option<usize> {
+    let mut i = skip_space(bytes, open + 1);
+    for _ in 0..index {
+        i = skip_space(bytes, json_end(bytes, i)?);
+        if bytes.get(i) != Some(&b',') {
+            return None;
+        }
+        i = skip_space(bytes, i + 1);
+    }
+    bytes.get(i).filter(|b| **b != b']').map(|_| i)
+}
+
+/// A value in a TOML document, as `toml_edit` holds it
+enum TomlNode<'a> {
+    Table(&'a toml_edit::Table),
+    Tables(&'a toml_edit::ArrayOfTables),
+    Value(&'a toml_edit::Value),
+}
+
+impl<'a> TomlNode<'a> {
+    fn of(item: &'a toml_edit::Item) -> Option<Self> {
+        match item {
+            toml_edit::Item::Table(table) => Some(Self::Table(table)),
+            toml_edit::Item::ArrayOfTables(tables) => Some(Self::Tables(tables)),
+            toml_edit::Item::Value(value) => Some(Self::Value(value)),
+            toml_edit::Item::None => None,
+        }
+    }
+
+    fn child(&self, segment: &str) -> Option<Self> {
+        match self {
+            Self::Table(table) => table.get(segment).and_then(Self::of),
+            Self::Tables(tables) => tables.get(segment.parse().ok()?).map(Self::Table),
+            Self::Value(toml_edit::Value::InlineTable(table)) => table.get(segment).map(Self::Value),
+            Self::Value(toml_edit::Value::Array(items)) => items.get(segment.parse().ok()?).map(Self::Value),
+            Self::Value(_) => None,
+        }
+    }
+
+    fn span(&self) -> Option<Range<usize>> {
+        match self {
+            Self::Table(table) => table.span(),
+            Self::Tables(tables) => tables.span(),
+            Self::Value(value) => value.span(),
+        }
+    }
+}
+
+fn toml_span(text: &str, segments: &[String]) -> Option<Range<usize>> {
+    let document = toml_edit::ImDocument::parse(text).ok()?;
+    let mut node = TomlNode::Table(document.as_table());
+    let mut span = None;
+    for segment in segments {
+        let Some(child) = node.child(segment) else {
+            break;
+        };
+        // Tables made by dotted keys or headers of their children have no span of their own
+        span = child.span().or(span);
+        node = child;
+    }
+    span
+}
+
+/// Find the value at `segments` in block-style YAML by its keys and the indentation of its items
+///
+/// Flow collections are not entered; the span is then the collection's.
+#[allow(clippy::cast_possible_wrap)] // Indents are far below isize::MAX
+fn yaml_span(text: &str, segments: &[String]) -> Option<Range<usize>> {
+    let mut starts = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut offset = 0;
+    for line in text.split('\n') {
+        starts.push(offset);
+        offset += line.len() + 1;
+        lines.push(line.trim_end_matches('\r').to_string());
+    }
+    let indent = |line: &str| line.len() - line.trim_start_matches(' ').len();
+    let content = |line: &str| {
+        let trimmed = line.trim();
+        !(trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---" || trimmed == "...")
+    };
+    let is_item = |rest: &str| rest == "-" || rest.starts_with("- ");
+
+    // Children of the node found so far lie in lines `from..to`, indented past `parent`
+    let (mut from, mut to, mut parent) = (0, lines.len(), -1isize);
+    let mut found = None;
+    for segment in segments {
+        let Some(first) = (from..to).find(|&i| content(&lines[i]) && indent(&lines[i]) as isize > parent) else {
+            break;
+        };
+        let column = indent(&lines[first]);
+        let at_column = |lines: &[String], i: usize| content(&lines[i]) && indent(&lines[i]) == column;
+        let hit = if is_item(&lines[first][column..]) {
+            let Ok(index) = segment.parse::<usize>() else {
+                break;
+            };
+            let Some(line) = (from..to).filter(|&i| at_column(&lines, i) && is_item(&lines[i][column..])).nth(index) else {
+                break;
+            };
+            // The item's own keys line up with those on the lines after it
+            lines[line].replace_range(column..=column, " ");
+            let value = column + 1 + lines[line][column + 1..].len() - lines[line][column + 1..].trim_start().len();
+            to = (line + 1..to).find(|&i| content(&lines[i]) && indent(&lines[i]) <= column).unwrap_or(to);
+            from = line;
+            parent = column as isize;
+            (line, value)
+        } else {
+            let key = |rest: &str| yaml_key(rest, segment);
+            let Some(line) = (from..to).find(|&i| at_column(&lines, i) && key(&lines[i][column..]).is_some()) else {
+                break;
+            };
+            let value = column + key(&lines[line][column..]).unwrap_or_default();
+            let next = (line + 1..to).find(|&i| content(&lines[i]));
+            // A sequence under a key may start at the key's own indentation
+            let sequence = next.is_some_and(|i| at_column(&lines, i) && is_item(&lines[i][column..]));
+            let ends = |i: usize| {
+                let line_indent = indent(&lines[i]);
+                content(&lines[i])
+                    && (line_indent < column || (line_indent == column && !(sequence && is_item(&lines[i][column..]))))
+            };
+            to = (line + 1..to).find(|&i| ends(i)).unwrap_or(to);
+            from = line + 1;
+            parent = column as isize - isize::from(sequence);
+            (line, value)
+        };
+        found = Some(hit);
+    }
+
+    let Some((line, column)) = found else {
+        return json_span(text, &[]).filter(|_| text.trim_start().starts_with(['{', '[']));
+    };
+    if lines[line].trim_end().len() > column {
+        return Some(starts[line] + column..starts[line] + lines[line].trim_end().len());
+    }
+    // A block value runs from its first child to its last
+    let first = (from..to).find(|&i| i > line && content(&lines[i]));
+    let last = (from..to).rev().find(|&i| i > line && content(&lines[i]));
+    match (first, last) {
+        (Some(first), Some(last)) => Some(starts[first] + indent(&lines[first])..starts[last] + lines[last].trim_end().len()),
+        _ => Some(starts[line] + column..starts[line] + column),
+    }
+}
+
+/// Where the value starts in `rest`, a line from its indentation on, if it holds the key `key`
+fn yaml_key(rest: &str, key: &str) -> Option<usize> {
+    let quoted = serde_json::to_string(key).unwrap_or_default();
+    let forms = [key.to_string(), quoted, format!("'{}'", key.replace('\'', "''"))];
+    forms.iter().find_map(|form| {
+        let after = rest.strip_prefix(form.as_str())?.strip_prefix(':')?;
+        (after.is_empty() || after.starts_with([' ', '\t'])).then(|| rest.len() - after.trim_start().len())
+    })
+}
+
+/// Schemas by name, with the documents each applies to in editors
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: RwLock<BTreeMap<String, Registered>>,
+}
+
+struct Registered {
+    schema: Arc<Schema>,
+    /// Globs over the URIs of the documents it applies to
+    documents: Vec<String>,
+}
+
+impl SchemaRegistry {
+    /// An empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `schema` as `name`, applied in editors to the documents whose URI matches one of the globs `documents`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is empty or a glob of `documents` does not parse.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn register(&self, name: &str, schema: Schema, documents: Vec<String>) -> Result<()> {
+        if name.is_empty() {
+            bail!("Schemas need a name");
+        }
+        let mut schemas = self.schemas.write().expect("schema registry lock poisoned");
+        if schemas.contains_key(name) {
+            bail!("A schema called {name} is already registered");
+        }
+        schemas.insert(name.to_string(), Registered { schema: Arc::new(schema), documents });
+        Ok(())
+    }
+
+    /// The schema called `name`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn get(&self, name: &str) -> Option<Arc<Schema>> {
+        self.schemas.read().expect("schema registry lock poisoned").get(name).map(|registered| Arc::clone(&registered.schema))
+    }
+
+    /// Names of the registered schemas, in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn names(&self) -> Vec<String> {
+        self.schemas.read().expect("schema registry lock poisoned").keys().cloned().collect()
+    }
+
+    /// The first schema, by name, whose documents include the one at `uri`, with its name
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn for_document(&self, uri: &str) -> Option<(String, Arc<Schema>)> {
+        let schemas = self.schemas.read().expect("schema registry lock poisoned");
+        schemas
+            .iter()
+            .find(|(_, registered)| crate::proxy::matches_patterns(&registered.documents, uri))
+            .map(|(name, registered)| (name.clone(), Arc::clone(&registered.schema)))
+    }
+}
+
+/// Load the schemas `configs` name into `registry`
+///
+/// A schema that fails to load or register is logged and skipped; the
+/// server starts with the others.
+pub fn install(configs: &[SchemaConfig], registry: &SchemaRegistry) {
+    for config in configs {
+        let registered = Schema::load(&config.path).and_then(|schema| registry.register(&config.name, schema, config.documents.clone()));
+        match registered {
+            Ok(()) => info!("Schema {} registered from {}", config.name, config.path.display()),
+            Err(e) => warn!("Schema {} not registered: {:#}", config.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn keywords(schema: Value, instance: &Value) -> Vec<(String, String)> {
+        let schema = Schema::new(schema).unwrap();
+        schema.validate(instance).into_iter().map(|violation| (violation.keyword, violation.instance_path)).collect()
+    }
+
+    fn one(keyword: &str, path: &str) -> Vec<(String, String)> {
+        vec![(keyword.to_string(), path.to_string())]
+    }
+
+    #[test]
+    fn test_keywords() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "replicas"],
+            "properties": {
+                "name": {"type": "string", "pattern": "^[a-z-]+$", "maxLength": 12},
+                "replicas": {"type": "integer", "minimum": 1, "multipleOf": 1},
+                "ratio": {"exclusiveMaximum": 1},
+                "tier": {"enum": ["web", "worker"]},
+                "ports": {"type": "array", "items": {"type": "integer"}, "uniqueItems": true, "maxItems": 3},
+                "version": {"const": 2}
+            },
+            "additionalProperties": false
+        });
+        let valid = json!({"name": "deploy", "replicas": 3.0, "tier": "web", "ports": [80, 443], "version": 2.0});
+        assert_eq!(keywords(schema.clone(), &valid), []);
+
+        let cases = [
+            (json!({"name": "deploy"}), one("required", "")),
+            (json!({"name": "Deploy", "replicas": 1}), one("pattern", "/name")),
+            (json!({"name": "a-very-long-name", "replicas": 1}), one("maxLength", "/name")),
+            (json!({"name": "a", "replicas": 0}), one("minimum", "/replicas")),
+            (json!({"name": "a", "replicas": 1.5}), vec![("type".to_string(), "/replicas".to_string()), ("multipleOf".to_string(), "/replicas".to_string())]),
+            (json!({"name": "a", "replicas": 1, "ratio": 1}), one("exclusiveMaximum", "/ratio")),
+            (json!({"name": "a", "replicas": 1, "tier": "db"}), one("enum", "/tier")),
+            (json!({"name": "a", "replicas": 1, "ports": [80, "443"]}), one("type", "/ports/1")),
+            (json!({"name": "a", "replicas": 1, "ports": [80, 80]}), one("uniqueItems", "/ports")),
+            (json!({"name": "a", "replicas": 1, "ports": [1, 2, 3, 4]}), one("maxItems", "/ports")),
+            (json!({"name": "a", "replicas": 1, "version": 3}), one("const", "/version")),
+            (json!({"name": "a", "replicas": 1, "extra": true}), one("additionalProperties", "/extra")),
+            (json!([]), vec![("type".to_string(), String::new())]),
+        ];
+        for (instance, expected) in cases {
+            assert_eq!(keywords(schema.clone(), &instance), expected, "{instance}");
+        }
+    }
+
+    #[test]
+    fn test_combinators_and_conditionals() {
+        let schema = json!({
+            "oneOf": [{"type": "string"}, {"type": "integer"}, {"type": "number", "maximum": 0}],
+            "not": {"const": "forbidden"}
+        });
+        assert_eq!(keywords(schema.clone(), &json!("text")), []);
+        assert_eq!(keywords(schema.clone(), &json!(-1)), one("oneOf", ""));
+        assert_eq!(keywords(schema.clone(), &json!(true)), one("oneOf", ""));
+        assert_eq!(keywords(schema, &json!("forbidden")), one("not", ""));
+
+        let schema = json!({
+            "if": {"properties": {"kind": {"const": "tcp"}}, "required": ["kind"]},
+            "then": {"required": ["port"]},
+            "else": {"required": ["path"]},
+            "anyOf": [{"required": ["host"]}, {"required": ["socket"]}],
+            "dependentRequired": {"tls": ["certificate"]}
+        });
+        assert_eq!(keywords(schema.clone(), &json!({"kind": "tcp", "port": 1, "host": "h"})), []);
+        assert_eq!(keywords(schema.clone(), &json!({"kind": "tcp", "host": "h"})), one("required", ""));
+        assert_eq!(keywords(schema.clone(), &json!({"path": "/", "socket": "s"})), []);
+        assert_eq!(keywords(schema.clone(), &json!({"path": "/"})), one("anyOf", ""));
+        assert_eq!(keywords(schema, &json!({"path": "/", "host": "h", "tls": true})), one("dependentRequired", ""));
+
+        let schema = json!({"type": "array", "prefixItems": [{"type": "string"}], "contains": {"type": "integer"}, "maxContains": 1});
+        assert_eq!(keywords(schema.clone(), &json!(["a", 1])), []);
+        assert_eq!(keywords(schema.clone(), &json!(["a", "b"])), one("contains", ""));
+        assert_eq!(keywords(schema, &json!(["a", 1, 2])), one("maxContains", ""));
+    }
+
+    #[test]
+    fn test_references_and_unevaluated() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "$defs": {
+                "port": {"$anchor": "port", "type": "integer", "maximum": 65535},
+                "node": {"type": "object", "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}}}
+            },
+            "properties": {"port": {"$ref": "#port"}, "tree": {"$ref": "#/$defs/node"}},
+            "allOf": [{"properties": {"name": true}}],
+            "unevaluatedProperties": false
+        });
+        let instance = json!({"port": 80, "name": "a", "tree": {"children": [{"children": []}]}});
+        assert_eq!(keywords(schema.clone(), &instance), []);
+        assert_eq!(keywords(schema.clone(), &json!({"port": 70000})), one("maximum", "/port"));
+        assert_eq!(keywords(schema.clone(), &json!({"tree": {"children": [{"children": 1}]}})), one("type", "/tree/children/0/children"));
+        assert_eq!(keywords(schema, &json!({"other": 1})), one("unevaluatedProperties", "/other"));
+
+        assert!(Schema::new(json!({"$ref": "https://example.com/other.json"})).is_err());
+        assert!(Schema::new(json!({"pattern": "("})).is_err());
+        assert!(Schema::new(json!({"$schema": "http://json-schema.org/draft-07/schema#"})).is_err());
+        assert!(Schema::new(json!(1)).is_err());
+
+        // A reference to itself is a loop
+        let schema = Schema::new(json!({"$defs": {"a": {"$ref": "#/$defs/a"}}, "$ref": "#/$defs/a"})).unwrap();
+        assert_eq!(schema.validate(&json!(1))[0].keyword, "$ref");
+    }
+
+    fn spans(content: &str, format: Format, schema: Value) -> Vec<(String, &str)> {
+        let schema = Schema::new(schema).unwrap();
+        validate(content, format, &schema).unwrap().into_iter().map(|d| (d.violation.keyword, &content[d.span])).collect()
+    }
+
+    #[test]
+    fn test_spans() {
+        let schema = json!({
+            "properties": {
+                "service": {"properties": {"name": {"type": "string"}, "ports": {"items": {"type": "integer"}}}},
+                "tags": {"maxItems": 1}
+            },
+            "required": ["owner"]
+        });
+
+        let json = "{\n  \"service\": {\"name\": 7, \"ports\": [80, \"x\"]},\n  \"tags\": [\"a\", \"b\"]\n}";
+        let expected = [("type", "7"), ("type", "\"x\""), ("maxItems", "[\"a\", \"b\"]"), ("required", json)];
+        assert_eq!(spans(json, Format::Json, schema.clone()), expected.map(|(keyword, text)| (keyword.to_string(), text)));
+
+        let yaml = "# deploy\nservice:\n  name: 7\n  ports:\n  - 80\n  - x\ntags:\n  - a\n  - b\n";
+        let expected = [("type", "7"), ("type", "x"), ("maxItems", "- a\n  - b"), ("required", yaml.trim())];
+        assert_eq!(spans(yaml, Format::Yaml, schema.clone()), expected.map(|(keyword, text)| (keyword.to_string(), text)));
+
+        let toml = "tags = [\"a\", \"b\"]\n\n[service]\nname = 7\nports = [80, \"x\"]\n";
+        let found = spans(toml, Format::Toml, schema.clone());
+        let expected = [("type", "7"), ("type", "\"x\""), ("maxItems", "[\"a\", \"b\"]")];
+        assert_eq!(found[..3], expected.map(|(keyword, text)| (keyword.to_string(), text)));
+
+        let schema = Schema::new(schema).unwrap();
+        let found = validate("a: 1\nb:\n  c: 2\n", Format::Yaml, &schema).unwrap();
+        assert_eq!((found[0].start, found[0].end), (LineColumn { line: 1, column: 1 }, LineColumn { line: 3, column: 7 }));
+        assert!(validate("<a/>", Format::Xml, &schema).is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = SchemaRegistry::new();
+        let schema = || Schema::new(json!({"type": "object"})).unwrap();
+        registry.register("deploy", schema(), vec!["**/deploy/*.yaml".to_string(), "Chart.yaml".to_string()]).unwrap();
+        registry.register("any", schema(), Vec::new()).unwrap();
+        assert!(registry.register("deploy", schema(), Vec::new()).is_err());
+        assert_eq!(registry.names(), ["any", "deploy"]);
+
+        let name = |uri: &str| registry.for_document(uri).map(|(name, _)| name);
+        assert_eq!(name("file:///work/deploy/web.yaml").as_deref(), Some("deploy"));
+        assert_eq!(name("file:///work/charts/Chart.yaml").as_deref(), Some("deploy"));
+        assert_eq!(name("file:///work/values.yaml"), None);
+        assert!(registry.get("any").is_some());
+    }
+}
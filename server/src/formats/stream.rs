@@ -0,0 +1,396 @@
+//! Streaming conversion of documents too large to hold in memory
+//!
+//! JSON, NDJSON and YAML convert a record at a time, taken as collections
+//! of records the way [`Stream::Array`](yaml::Stream::Array) reads them:
+//! the items of a JSON array, the lines of NDJSON and the documents of a
+//! YAML stream. Only the record being converted is held, so memory stays at
+//! the size of the largest record however long the document. JSON is
+//! written as an array with a record a line, NDJSON with a record a line,
+//! and YAML with a document a record.
+//!
+//! YAML is split into documents at its `---` and `...` markers before it is
+//! parsed, so a stream of many documents, such as an export of Kubernetes
+//! manifests, streams; a single document is read whole. Empty documents are
+//! left out, as they are from a stream read as an array.
+//!
+//! A record may take at most the bytes it is given to [`convert`]: reading
+//! stops once a record runs past them, failing with [`RecordTooLarge`]
+//! rather than holding the rest, so a single large YAML document is refused
+//! while it is read. [`Formats::convert_stream`](super::Formats::convert_stream)
+//! gives the input limit of its [`FormatLimits`](super::FormatLimits).
+//!
+//! [`convert`] reads and writes blocking I/O, and [`convert_async`] Tokio's,
+//! running [`convert`] on a blocking thread.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::{DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde_json::Value;
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::SyncIoBridge;
+
+use super::ndjson;
+use super::yaml;
+use crate::core::Format;
+
+/// Whether documents of `from` convert to `to` a record at a time
+#[must_use]
+pub fn supports(from: Format, to: Format) -> bool {
+    streams(from) && streams(to)
+}
+
+fn streams(format: Format) -> bool {
+    matches!(format, Format::Json | Format::Ndjson | Format::Yaml)
+}
+
+/// What a streamed conversion went through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Streamed {
+    /// Records converted
+    pub records: usize,
+    /// Bytes read
+    pub read: usize,
+    /// Bytes written
+    pub written: usize,
+}
+
+/// A record ran past the bytes a streamed conversion allows it
+#[derive(Debug)]
+pub struct RecordTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for RecordTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A record exceeds the input_size limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for RecordTooLarge {}
+
+/// Convert the records of `reader`, a document of `from`, to `writer` as `to`
+///
+/// # Errors
+///
+/// Fails where the pair does not stream, a record is invalid, or reading
+/// or writing fails, and with [`RecordTooLarge`] once a record runs past
+/// `max_record_bytes`.
+pub fn convert<R: BufRead, W: Write>(reader: R, from: Format, to: Format, writer: W, max_record_bytes: usize) -> Result<Streamed> {
+    if !supports(from, to) {
+        bail!("Only JSON, NDJSON and YAML stream, not {} to {}", from.name(), to.name());
+    }
+    let consumed = Rc::new(Consumed::default());
+    let reader = Capped { inner: reader, limit: max_record_bytes, consumed: Rc::clone(&consumed) };
+    let mut sink = Sink { format: to, writer: Counted { inner: writer, written: 0 }, count: 0, consumed: Rc::clone(&consumed) };
+    let converted = records(reader, from, &mut sink);
+    if converted.is_err() && consumed.record.get() > max_record_bytes {
+        return Err(RecordTooLarge { limit: max_record_bytes }.into());
+    }
+    converted?;
+    let records = sink.finish()?;
+    Ok(Streamed { records, read: consumed.total.get(), written: sink.writer.written })
+}
+
+/// Hand each record of `reader`, a document of `from`, to `sink`
+fn records<R: BufRead, W: Write>(reader: R, from: Format, sink: &mut Sink<W>) -> Result<()> {
+    match from {
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_reader(reader);
+            Items { sink }.deserialize(&mut deserializer).map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+            deserializer.end().map_err(|e| anyhow!("Invalid JSON: {e}"))?;
+        }
+        Format::Ndjson => {
+            for record in ndjson::records(reader) {
+                sink.write(record?.value)?;
+            }
+        }
+        _ => {
+            for document in yaml_documents(reader) {
+                let (line, text) = document?;
+                let records = yaml::non_empty_documents(&text).with_context(|| format!("In the document on line {line}"))?;
+                for record in records {
+                    sink.write(record)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// [`convert`] for Tokio readers and writers, shutting `writer` down once done
+///
+/// Output still buffered when the conversion fails is dropped, so a
+/// document failing early writes nothing.
+///
+/// # Errors
+///
+/// Fails as [`convert`] does.
+pub async fn convert_async<R, W>(reader: R, from: Format, to: Format, writer: W, max_record_bytes: usize) -> Result<Streamed>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut writer = SyncIoBridge::new(writer);
+        let mut buffered = BufWriter::new(&mut writer);
+        let converted = convert(BufReader::new(SyncIoBridge::new(reader)), from, to, &mut buffered, max_record_bytes);
+        if converted.is_err() {
+            drop(buffered.into_parts());
+        } else {
+            drop(buffered);
+            writer.shutdown()?;
+        }
+        converted
+    })
+    .await?
+}
+
+/// Bytes read by a [`Capped`] reader, in all and since the last record was written
+#[derive(Default)]
+struct Consumed {
+    total: Cell<usize>,
+    record: Cell<usize>,
+}
+
+/// Reads through to `inner`, failing once the record being read runs past `limit` bytes
+struct Capped<R> {
+    inner: R,
+    limit: usize,
+    consumed: Rc<Consumed>,
+}
+
+impl<R: BufRead> BufRead for Capped<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed.record.get() > self.limit {
+            return Err(io::Error::other(RecordTooLarge { limit: self.limit }));
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed.total.set(self.consumed.total.get() + amount);
+        self.consumed.record.set(self.consumed.record.get() + amount);
+        self.inner.consume(amount);
+    }
+}
+
+impl<R: BufRead> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+/// Writes through to `inner`, counting the bytes written
+struct Counted<W> {
+    inner: W,
+    written: usize,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes records as a document of `format`
+struct Sink<W> {
+    format: Format,
+    writer: Counted<W>,
+    count: usize,
+    /// Reset as each record is written, so the next is counted from its start
+    consumed: Rc<Consumed>,
+}
+
+impl<W: Write> Sink<W> {
+    fn write(&mut self, record: Value) -> Result<()> {
+        self.consumed.record.set(0);
+        match self.format {
+            Format::Json => {
+                self.writer.write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut self.writer, &record)?;
+            }
+            Format::Ndjson => {
+                serde_json::to_writer(&mut self.writer, &record)?;
+                self.writer.write_all(b"\n")?;
+            }
+            _ => {
+                if self.count > 0 {
+                    self.writer.write_all(b"---\n")?;
+                }
+                serde_yaml::to_writer(&mut self.writer, &yaml::from_json(record))?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<usize> {
+        if self.format == Format::Json {
+            self.writer.write_all(if self.count == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Hands each item of a JSON array to the sink as it is read, rather than collecting them
+struct Items<'a, W> {
+    sink: &'a mut Sink<W>,
+}
+
+impl<'de, W: Write> DeserializeSeed<'de> for Items<'_, W> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, W: Write> Visitor<'de> for Items<'_, W> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON array, whose items are the records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut items: A) -> std::result::Result<(), A::Error> {
+        while let Some(item) = items.next_element::<Value>()? {
+            self.sink.write(item).map_err(serde::de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// The text of each document of the YAML stream in `reader`, with the line it starts on
+fn yaml_documents<R: BufRead>(reader: R) -> impl Iterator<Item = Result<(usize, String)>> {
+    let mut lines = reader.lines();
+    let (mut document, mut start, mut number) = (String::new(), 1, 0);
+    // Whether the document so far holds anything but directives, comments and blank lines
+    let mut content = false;
+    let mut done = false;
+    std::iter::from_fn(move || {
+        while !done {
+            let Some(line) = lines.next() else {
+                done = true;
+                break;
+            };
+            let line = match line.with_context(|| format!("reading line {}", number + 1)) {
+                Ok(line) => line,
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            };
+            number += 1;
+            let marker = |text: &str| line == text || line.strip_prefix(text).is_some_and(|rest| rest.starts_with([' ', '\t']));
+            // A document ends where the next starts, or at its end marker
+            if content && (marker("---") || marker("...")) {
+                let ended = std::mem::take(&mut document);
+                let at = std::mem::replace(&mut start, number);
+                content = false;
+                if marker("---") {
+                    document.push_str(&line);
+                    document.push('\n');
+                    content = true;
+                }
+                return Some(Ok((at, ended)));
+            }
+            let trimmed = line.trim_start();
+            content |= !(trimmed.is_empty() || trimmed.starts_with('#') || line.starts_with('%') || marker("..."));
+            if document.is_empty() {
+                start = number;
+            }
+            document.push_str(&line);
+            document.push('\n');
+        }
+        let rest = std::mem::take(&mut document);
+        (content && !rest.is_empty()).then(|| {
+            content = false;
+            Ok((start, rest))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(content: &str, from: Format, to: Format) -> Result<String> {
+        let mut out = Vec::new();
+        convert(content.as_bytes(), from, to, &mut out, usize::MAX)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_yaml_stream() {
+        let yaml = "# manifests\nkind: A\nspec: {replicas: 1}\n---\n--- |\n  text\n...\n%YAML 1.2\n---\n- 1\n- 2\n";
+        assert_eq!(stream(yaml, Format::Yaml, Format::Ndjson).unwrap(), "{\"kind\":\"A\",\"spec\":{\"replicas\":1}}\n\"text\\n\"\n[1,2]\n");
+        assert_eq!(stream(yaml, Format::Yaml, Format::Json).unwrap(), "[\n{\"kind\":\"A\",\"spec\":{\"replicas\":1}},\n\"text\\n\",\n[1,2]\n]\n");
+
+        let error = stream("a: 1\n---\nb: [\n", Format::Yaml, Format::Json).unwrap_err();
+        assert!(format!("{error:#}").starts_with("In the document on line 2: Invalid YAML"), "{error:#}");
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let ndjson = "{\"a\":1}\n\n{\"b\":[true,null]}\n";
+        let yaml = stream(ndjson, Format::Ndjson, Format::Yaml).unwrap();
+        assert_eq!(yaml, "a: 1\n---\nb:\n- true\n- null\n");
+        let json = stream(&yaml, Format::Yaml, Format::Json).unwrap();
+        assert_eq!(stream(&json, Format::Json, Format::Ndjson).unwrap(), "{\"a\":1}\n{\"b\":[true,null]}\n");
+        assert_eq!(stream("[]", Format::Json, Format::Json).unwrap(), "[]\n");
+        assert_eq!(stream("", Format::Yaml, Format::Ndjson).unwrap(), "");
+
+        assert!(stream("{\"a\": 1}", Format::Json, Format::Ndjson).is_err());
+        assert!(stream("[1, 2", Format::Json, Format::Ndjson).is_err());
+        assert!(stream("a,b\n1,2", Format::Csv, Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_records_over_the_limit() {
+        let convert = |content: &str, from| convert(content.as_bytes(), from, Format::Ndjson, io::sink(), 16);
+        let streamed = convert("{\"a\":1}\n{\"b\":2}\n", Format::Ndjson).unwrap();
+        assert_eq!((streamed.records, streamed.read), (2, 16));
+        assert!(convert("[1, 2, 3, 4, 5, 6, 7, 8]", Format::Json).is_ok());
+
+        // One document is refused part way through, not read whole first
+        let document = format!("a: 1\n---\nitems:\n{}", "- item\n".repeat(100_000));
+        let error = convert(&document, Format::Yaml).unwrap_err();
+        assert!(error.is::<RecordTooLarge>(), "{error:#}");
+        let error = convert("[1, \"a string longer than the limit\"]", Format::Json).unwrap_err();
+        assert_eq!(error.to_string(), "A record exceeds the input_size limit of 16 bytes");
+    }
+
+    #[tokio::test]
+    async fn test_async() {
+        let (input, mut feed) = tokio::io::duplex(64);
+        let (output, mut result) = tokio::io::duplex(64);
+        let converting = tokio::spawn(convert_async(input, Format::Ndjson, Format::Json, output, usize::MAX));
+        let writing = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for i in 0..100 {
+                feed.write_all(format!("{{\"n\":{i}}}\n").as_bytes()).await.unwrap();
+            }
+        });
+        let mut json = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut result, &mut json).await.unwrap();
+        writing.await.unwrap();
+        let streamed = converting.await.unwrap().unwrap();
+        assert_eq!((streamed.records, streamed.written), (100, json.len()));
+        let records: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(records[99], serde_json::json!({"n": 99}));
+    }
+}
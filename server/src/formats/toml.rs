@@ -1,54 +1,245 @@
 //! TOML format support for document conversion
+//!
+//! Tables map to JSON objects and arrays, including arrays of tables, to
+//! arrays. Date-times, which JSON has no type for, become strings, and
+//! strings that read as TOML date-times become date-times again going back.
+//! JSON `null` has no TOML form and is refused, as are integers beyond the
+//! 64-bit signed range and a document that is not an object at the top.
 
-use anyhow::Result;
-use serde_json::Value;
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Number, Value};
+use std::fmt;
+use std::ops::Range;
 
 /// Convert TOML to JSON
 pub fn toml_to_json(toml: &str) -> Result<String> {
-    // Placeholder: use toml crate in production
-    let value: Value = serde_json::json!({
-        "toml_content": toml
-    });
-    Ok(serde_json::to_string_pretty(&value)?)
+    let table = parse(toml).map_err(|diagnostic| anyhow!("Invalid TOML: {diagnostic}"))?;
+    let json = Value::Object(table.into_iter().map(|(key, value)| (key, to_json(value))).collect());
+    Ok(serde_json::to_string_pretty(&json)?)
 }
 
 /// Convert JSON to TOML
 pub fn json_to_toml(json: &str) -> Result<String> {
     let value: Value = serde_json::from_str(json)?;
-    // Placeholder: use toml crate for proper serialization
-    Ok(format!("# TOML\n[data]\ncontent = '''{}'''", value))
+    let Value::Object(map) = value else {
+        return Err(anyhow!("TOML documents must be a table, not {}", kind(&value)));
+    };
+    let table = from_json_map(map, "")?;
+    Ok(::toml::to_string_pretty(&table)?)
 }
 
 /// Validate TOML syntax
 pub fn validate_toml(toml: &str) -> Result<Vec<String>> {
-    let mut diagnostics = Vec::new();
+    Ok(self::diagnostics(toml).into_iter().map(|diagnostic| format!("Invalid TOML: {diagnostic}")).collect())
+}
+
+/// A problem the TOML parser found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TomlDiagnostic {
+    pub message: String,
+    /// Bytes of the text the problem lies in, where the parser gives them
+    pub span: Option<Range<usize>>,
+    /// Line and column where `span` starts, both from 1, the column counted in characters
+    pub position: Option<(usize, usize)>,
+}
 
-    if toml.trim().is_empty() {
-        diagnostics.push("TOML document is empty".to_string());
+impl fmt::Display for TomlDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "line {}, column {}: {}", line, column, self.message),
+            None => f.write_str(&self.message),
+        }
     }
+}
+
+/// Problems parsing `toml`, empty when it is well formed
+///
+/// The parser stops at its first error, so there is at most one.
+#[must_use]
+pub fn diagnostics(toml: &str) -> Vec<TomlDiagnostic> {
+    parse(toml).err().into_iter().collect()
+}
+
+/// Describe a parse error, leading with its line and column where the parser gives a position
+pub(crate) fn error_message(text: &str, e: &::toml::de::Error) -> String {
+    diagnostic(text, e).to_string()
+}
+
+fn diagnostic(text: &str, e: &::toml::de::Error) -> TomlDiagnostic {
+    let span = e.span();
+    let position = span.as_ref().map(|span| super::line_column(text, span.start));
+    TomlDiagnostic { message: e.message().trim_end().to_string(), span, position }
+}
+
+fn parse(toml: &str) -> Result<::toml::Table, TomlDiagnostic> {
+    ::toml::from_str(toml).map_err(|e| diagnostic(toml, &e))
+}
 
-    // Check for common TOML issues
-    if toml.contains('\t') && !toml.contains("'''") {
-        diagnostics.push("TOML should use spaces for indentation outside of strings".to_string());
+fn to_json(value: ::toml::Value) -> Value {
+    match value {
+        ::toml::Value::String(s) => Value::String(s),
+        ::toml::Value::Integer(i) => Value::from(i),
+        // JSON has no infinities or NaN
+        ::toml::Value::Float(f) => Number::from_f64(f).map_or_else(|| Value::String(f.to_string()), Value::Number),
+        ::toml::Value::Boolean(b) => Value::Bool(b),
+        ::toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        ::toml::Value::Array(items) => Value::Array(items.into_iter().map(to_json).collect()),
+        ::toml::Value::Table(table) => Value::Object(table.into_iter().map(|(key, value)| (key, to_json(value))).collect()),
     }
+}
+
+/// `path` is the dotted key of `map`, for errors
+fn from_json_map(map: Map<String, Value>, path: &str) -> Result<::toml::Table> {
+    map.into_iter()
+        .map(|(key, value)| {
+            let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+            Ok((key, from_json(value, &path)?))
+        })
+        .collect()
+}
 
-    Ok(diagnostics)
+fn from_json(value: Value, path: &str) -> Result<::toml::Value> {
+    Ok(match value {
+        Value::Null => return Err(anyhow!("TOML has no null value, found one at '{path}'")),
+        Value::Bool(b) => ::toml::Value::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => ::toml::Value::Integer(i),
+            None if n.is_u64() => return Err(anyhow!("TOML integers are 64-bit signed, '{path}' holds {n}")),
+            None => ::toml::Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => match s.parse::<::toml::value::Datetime>() {
+            Ok(datetime) => ::toml::Value::Datetime(datetime),
+            Err(_) => ::toml::Value::String(s),
+        },
+        Value::Array(items) => {
+            let items = items.into_iter().enumerate().map(|(i, item)| from_json(item, &format!("{path}[{i}]")));
+            ::toml::Value::Array(items.collect::<Result<_>>()?)
+        }
+        Value::Object(map) => ::toml::Value::Table(from_json_map(map, path)?),
+    })
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    fn round_trip(toml: &str) -> Value {
+        let json: Value = serde_json::from_str(&toml_to_json(toml).unwrap()).unwrap();
+        let again: Value = serde_json::from_str(&toml_to_json(&json_to_toml(&json.to_string()).unwrap()).unwrap()).unwrap();
+        assert_eq!(again, json);
+        json
+    }
 
     #[test]
     fn test_toml_to_json() {
         let toml = "key = \"value\"";
-        let result = toml_to_json(toml);
-        assert!(result.is_ok());
+        assert_eq!(round_trip(toml), json!({"key": "value"}));
+    }
+
+    #[test]
+    fn test_round_trip_nested_tables_and_arrays() {
+        let toml = r#"
+            title = "Settings"
+            released = 1979-05-27T07:32:00Z
+
+            [editor]
+            tab_size = 4
+            ratio = 0.5
+            rulers = [80, 120]
+
+            [editor.font]
+            family = "Mono"
+            ligatures = true
+
+            [[plugins]]
+            name = "git"
+            args = ["--quiet"]
+
+            [[plugins]]
+            name = "lint"
+            levels = [[1, 2], ["a"]]
+        "#;
+        assert_eq!(
+            round_trip(toml),
+            json!({
+                "title": "Settings",
+                "released": "1979-05-27T07:32:00Z",
+                "editor": {
+                    "tab_size": 4,
+                    "ratio": 0.5,
+                    "rulers": [80, 120],
+                    "font": {"family": "Mono", "ligatures": true}
+                },
+                "plugins": [
+                    {"name": "git", "args": ["--quiet"]},
+                    {"name": "lint", "levels": [[1, 2], ["a"]]}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_to_toml_keeps_types() {
+        let toml = json_to_toml(r#"{"released": "1979-05-27T07:32:00Z", "day": "1979-05-27", "note": "May 1979", "n": 7}"#).unwrap();
+        assert!(toml.contains("released = 1979-05-27T07:32:00Z\n"), "{toml}");
+        assert!(toml.contains("day = 1979-05-27\n"), "{toml}");
+        assert!(toml.contains("note = \"May 1979\"\n"), "{toml}");
+        assert!(toml.contains("n = 7\n"), "{toml}");
+
+        let toml = json_to_toml(r#"{"plugins": [{"name": "git"}, {"name": "lint"}]}"#).unwrap();
+        assert_eq!(toml.matches("[[plugins]]").count(), 2, "{toml}");
+    }
+
+    #[test]
+    fn test_datetimes_round_trip_through_json() {
+        let toml = "at = 07:32:00\nday = 1979-05-27\nlocal = 1979-05-27T07:32:00\nreleased = 1979-05-27T00:32:00.999999-07:00\n";
+        let json = toml_to_json(toml).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap()["day"], "1979-05-27");
+        assert_eq!(json_to_toml(&json).unwrap(), toml);
+    }
+
+    #[test]
+    fn test_json_without_toml_form() {
+        let error = json_to_toml(r#"{"a": {"b": [1, null]}}"#).unwrap_err();
+        assert!(error.to_string().contains("'a.b[1]'"), "{error}");
+        let error = json_to_toml(r#"{"big": 18446744073709551615}"#).unwrap_err();
+        assert!(error.to_string().contains("'big'"), "{error}");
+        assert!(json_to_toml("[1, 2]").unwrap_err().to_string().contains("an array"));
     }
 
     #[test]
     fn test_validate_toml_empty() {
-        let diagnostics = validate_toml("").unwrap();
-        assert!(!diagnostics.is_empty());
+        // The `toml/no-empty-document` lint rule reports it
+        assert!(validate_toml("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_toml_reports_position() {
+        let diagnostics = validate_toml("a = 1\nb = = 2\n").unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert!(diagnostics[0].starts_with("Invalid TOML: line 2, column 5:"), "{}", diagnostics[0]);
+        assert!(validate_toml("a = 1").unwrap().is_empty());
+
+        let found = super::diagnostics("a = 1\nb = = 2\n");
+        assert_eq!(found[0].span.as_ref().map(|span| span.start), Some(10), "{found:?}");
+        assert_eq!(found[0].position, Some((2, 5)));
+    }
+
+    #[test]
+    fn test_validate_toml_tabs() {
+        // Tabs are whitespace to TOML, inside strings or out
+        assert!(validate_toml("[a]\n\tb = \"x\ty\"\n").unwrap().is_empty());
     }
 }
@@ -0,0 +1,170 @@
+//! Format plugins compiled to WebAssembly, run in a wasmtime sandbox
+//!
+//! Built with the `wasm-plugins` feature. A plugin is a core wasm module
+//! with no imports, so it has no WASI, filesystem or network access, that
+//! exports:
+//!
+//! - `memory`: its linear memory
+//! - `ulc_abi_version() -> i32`: [`ABI_VERSION`]
+//! - `ulc_alloc(len: i32) -> i32`: a buffer of `len` bytes for the input
+//! - `ulc_to_canonical(ptr: i32, len: i32) -> i64`
+//! - `ulc_from_canonical(ptr: i32, len: i32) -> i64`
+//! - `ulc_validate(ptr: i32, len: i32) -> i64`
+//!
+//! The host writes the input into a buffer from `ulc_alloc` and calls one
+//! of the three functions with it. The result packs the address of the
+//! output in its upper 32 bits and its length in the lower 32. The output
+//! starts with a status byte: 0 for success, followed by the converted
+//! document or, from `ulc_validate`, one diagnostic per line; anything
+//! else for failure, followed by a UTF-8 message.
+//!
+//! Every call runs in a fresh instance whose memory is capped at
+//! [`PluginConfig::max_memory_bytes`] and whose execution is capped at
+//! [`PluginConfig::fuel`]. A trap, or a call over either cap, fails that
+//! conversion and nothing else.
+
+use super::plugins::{FormatPlugin, PluginConfig, ABI_VERSION};
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+use wasmtime::{Engine, Instance, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+
+/// Functions every plugin exports besides `memory`
+const EXPORTS: [&str; 5] = ["ulc_abi_version", "ulc_alloc", "ulc_to_canonical", "ulc_from_canonical", "ulc_validate"];
+
+/// A sandboxed format plugin
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: InstancePre<StoreLimits>,
+    max_memory_bytes: usize,
+    fuel: u64,
+}
+
+impl WasmPlugin {
+    /// Load the plugin at `path`, named after the file
+    pub fn load(path: &Path, config: &PluginConfig) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("{} has no usable file name", path.display()))?;
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::new(name, &bytes, config)
+    }
+
+    /// Compile a plugin called `name` from a wasm binary
+    pub fn new(name: &str, wasm: &[u8], config: &PluginConfig) -> Result<Self> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::new(&engine, wasm).context("Not a valid wasm module")?;
+        if let Some(import) = module.imports().next() {
+            bail!("Imports {}::{}, but plugins are given no host functions", import.module(), import.name());
+        }
+        for export in std::iter::once("memory").chain(EXPORTS) {
+            if module.get_export(export).is_none() {
+                bail!("Does not export {}", export);
+            }
+        }
+        let plugin = Self {
+            name: name.to_string(),
+            module: Linker::new(&engine).instantiate_pre(&module)?,
+            engine,
+            max_memory_bytes: config.max_memory_bytes,
+            fuel: config.fuel,
+        };
+
+        let (mut store, instance) = plugin.instantiate()?;
+        let version = instance.get_typed_func::<(), i32>(&mut store, "ulc_abi_version")?;
+        let version = version.call(&mut store, ()).map_err(|e| plugin.failure(e))?;
+        if version != ABI_VERSION {
+            bail!("Implements plugin ABI {}, but the server speaks {}", version, ABI_VERSION);
+        }
+        Ok(plugin)
+    }
+
+    /// A fresh instance, within the limits
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, Instance)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory_bytes)
+            .instances(1)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.module.instantiate(&mut store).map_err(|e| self.failure(e))?;
+        Ok((store, instance))
+    }
+
+    /// Run `function` on `input`, returning its output after the status byte
+    fn call(&self, function: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let (mut store, instance) = self.instantiate()?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("Plugin {} exports no memory", self.name))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "ulc_alloc")?;
+        let function = instance.get_typed_func::<(i32, i32), i64>(&mut store, function)?;
+
+        let len = i32::try_from(input.len()).context("Document too large for a plugin")?;
+        let ptr = alloc.call(&mut store, len).map_err(|e| self.failure(e))?;
+        let offset = usize::try_from(ptr).map_err(|_| anyhow!("Plugin {} allocated at {}", self.name, ptr))?;
+        memory
+            .write(&mut store, offset, input)
+            .with_context(|| format!("Plugin {} allocated outside its memory", self.name))?;
+
+        let packed = function.call(&mut store, (ptr, len)).map_err(|e| self.failure(e))?;
+        let packed = u64::from_ne_bytes(packed.to_ne_bytes());
+        let (offset, len) = (usize::try_from(packed >> 32)?, usize::try_from(packed & 0xffff_ffff)?);
+        let mut output = vec![0; len];
+        memory
+            .read(&store, offset, &mut output)
+            .with_context(|| format!("Plugin {} returned a buffer outside its memory", self.name))?;
+        match output.split_first() {
+            Some((0, document)) => Ok(document.to_vec()),
+            Some((_, message)) => {
+                Err(anyhow!("Plugin {} refused the document: {}", self.name, String::from_utf8_lossy(message)))
+            }
+            None => Err(anyhow!("Plugin {} returned nothing", self.name)),
+        }
+    }
+
+    /// Describe an error raised while running plugin code
+    fn failure(&self, error: anyhow::Error) -> anyhow::Error {
+        match error.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => anyhow!("Plugin {} exceeded its limit of {} fuel", self.name, self.fuel),
+            Some(trap) => anyhow!("Plugin {} trapped: {}", self.name, trap),
+            // Including growing memory past the cap, which the store limits make fail
+            None => error.context(format!("Plugin {} failed", self.name)),
+        }
+    }
+}
+
+impl FormatPlugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_canonical(&self, input: &[u8]) -> Result<Vec<u8>> {
+        self.call("ulc_to_canonical", input)
+    }
+
+    fn from_canonical(&self, canonical: &[u8]) -> Result<Vec<u8>> {
+        self.call("ulc_from_canonical", canonical)
+    }
+
+    fn validate(&self, input: &[u8]) -> Result<Vec<String>> {
+        let output = String::from_utf8(self.call("ulc_validate", input)?)
+            .with_context(|| format!("Plugin {} produced invalid UTF-8", self.name))?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("name", &self.name)
+            .field("max_memory_bytes", &self.max_memory_bytes)
+            .field("fuel", &self.fuel)
+            .finish_non_exhaustive()
+    }
+}
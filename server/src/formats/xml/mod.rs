@@ -0,0 +1,534 @@
+//! XML format support for document conversion
+//!
+//! A document becomes a JSON object with one key, the root element. An
+//! element holding only text becomes that string, and an empty one `null`.
+//! Otherwise it becomes an object: attributes under their name prefixed
+//! with `@`, text under `#text`, and child elements under their name, as an
+//! array where the name repeats. Comments, processing instructions and the
+//! declaration are dropped. [`XmlOptions`] choose other keys for attributes
+//! and text, and whether names keep their namespace prefix, lose it, or name
+//! their namespace in full as `{uri}local`. Elements nested more than 128
+//! deep are refused, as the value they make is too deep to handle.
+//!
+//! Going back, a JSON object with any other number of keys, or whose single
+//! key holds an array, is wrapped in a `<root>` element. Names written as
+//! `{uri}local` are given the namespace declarations they need.
+//!
+//! [`xpath`] evaluates `XPath` 1.0 expressions against a document, giving
+//! the nodes selected as JSON, each with the bytes it spans.
+
+pub mod xpath;
+
+use anyhow::{anyhow, Result};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::{QName, ResolveResult};
+use quick_xml::reader::NsReader;
+use quick_xml::Writer;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Root element wrapped around JSON that does not name one
+const ROOT: &str = "root";
+
+/// Elements opened inside one another before a document is refused
+const MAX_DEPTH: usize = 128;
+
+/// Namespace of the `xml` prefix, bound without a declaration
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// How element and attribute names are written as JSON keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Namespaces {
+    /// As written, `ed:theme`, with `xmlns` declarations kept as attributes
+    #[default]
+    Prefixed,
+    /// Local names only, `theme`, dropping `xmlns` declarations
+    Local,
+    /// With the namespace in full, `{urn:editor}theme`, dropping `xmlns` declarations
+    Expanded,
+}
+
+/// How XML maps onto JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct XmlOptions {
+    /// Prefix of attribute keys
+    pub attribute_prefix: String,
+    /// Key of an element's text when it also has attributes or children
+    pub text_key: String,
+    /// How names with a namespace are written
+    pub namespaces: Namespaces,
+}
+
+impl Default for XmlOptions {
+    fn default() -> Self {
+        Self { attribute_prefix: "@".to_string(), text_key: "#text".to_string(), namespaces: Namespaces::Prefixed }
+    }
+}
+
+impl XmlOptions {
+    fn check(&self) -> Result<()> {
+        if self.attribute_prefix.is_empty() {
+            return Err(anyhow!("The attribute prefix cannot be empty, or attributes could not be told from elements"));
+        }
+        if self.text_key.is_empty() || self.text_key.starts_with(&self.attribute_prefix) {
+            return Err(anyhow!("The text key '{}' must be set and not look like an attribute", self.text_key));
+        }
+        Ok(())
+    }
+}
+
+/// Convert XML to JSON
+pub fn xml_to_json(xml: &str) -> Result<String> {
+    xml_to_json_with(xml, &XmlOptions::default())
+}
+
+/// Convert XML to JSON with the keys `options` choose
+///
+/// # Errors
+///
+/// Fails where `xml` is not well formed, or nests more than 128 elements
+/// deep.
+pub fn xml_to_json_with(xml: &str, options: &XmlOptions) -> Result<String> {
+    options.check()?;
+    let value = parse(xml, options).map_err(|diagnostic| anyhow!("Invalid XML: {diagnostic}"))?;
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Convert JSON to XML
+pub fn json_to_xml(json: &str) -> Result<String> {
+    json_to_xml_with(json, &XmlOptions::default())
+}
+
+/// Convert JSON to XML, reading attributes and text under the keys `options` choose
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or is not an object with one key, the
+/// root element.
+pub fn json_to_xml_with(json: &str, options: &XmlOptions) -> Result<String> {
+    options.check()?;
+    let value: Value = serde_json::from_str(json)?;
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    match &value {
+        Value::Object(map) if map.len() == 1 && map.values().all(|value| !value.is_array()) => {
+            for (name, value) in map {
+                write_element(&mut writer, options, name, value, "")?;
+            }
+        }
+        value => write_element(&mut writer, options, ROOT, value, "")?,
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Validate XML syntax
+pub fn validate_xml(xml: &str) -> Result<Vec<String>> {
+    // An empty document is left to the `xml/no-empty-document` lint rule
+    if xml.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(self::diagnostics(xml).into_iter().map(|diagnostic| format!("Invalid XML: {diagnostic}")).collect())
+}
+
+/// A way in which an XML text is not well formed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlDiagnostic {
+    pub message: String,
+    /// Byte offset of the problem in the text
+    pub offset: usize,
+    /// Line and column of `offset`, both from 1, the column counted in characters
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for XmlDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Ways in which `xml` is not well formed, empty when it is
+///
+/// Reading stops at the first problem, so there is at most one. Prefixes
+/// must be declared, as namespaces in XML require.
+#[must_use]
+pub fn diagnostics(xml: &str) -> Vec<XmlDiagnostic> {
+    parse(xml, &XmlOptions::default()).err().into_iter().collect()
+}
+
+/// An element whose end tag has not been read yet
+struct Open {
+    name: String,
+    /// Attributes and children read so far
+    fields: Map<String, Value>,
+    text: String,
+    /// Where the start tag was, for an element never closed
+    offset: usize,
+}
+
+impl Open {
+    fn new(reader: &NsReader<&[u8]>, options: &XmlOptions, start: &BytesStart<'_>, offset: usize) -> Result<Self, String> {
+        let mut fields = Map::new();
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(|e| e.to_string())?;
+            let raw = attribute.key.as_ref();
+            let declaration = raw == b"xmlns" || raw.starts_with(b"xmlns:");
+            if declaration && options.namespaces != Namespaces::Prefixed {
+                continue;
+            }
+            let name = if declaration { String::from_utf8_lossy(raw).into_owned() } else { key(reader, options, attribute.key, true)? };
+            let value = attribute.unescape_value().map_err(|e| e.to_string())?.into_owned();
+            if fields.insert(format!("{}{}", options.attribute_prefix, name), Value::String(value)).is_some() {
+                return Err(format!("attribute '{name}' appears twice once namespaces are applied"));
+            }
+        }
+        let name = key(reader, options, start.name(), false)?;
+        Ok(Self { name, fields, text: String::new(), offset })
+    }
+
+    fn into_value(self, options: &XmlOptions) -> (String, Value) {
+        let Self { name, mut fields, text, .. } = self;
+        let value = match (fields.is_empty(), text.is_empty()) {
+            (true, true) => Value::Null,
+            (true, false) => Value::String(text),
+            (false, empty) => {
+                if !empty {
+                    fields.insert(options.text_key.clone(), Value::String(text));
+                }
+                Value::Object(fields)
+            }
+        };
+        (name, value)
+    }
+
+    /// Add a child, making an array of children with the same name
+    fn push(&mut self, name: String, value: Value) {
+        match self.fields.get_mut(&name) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                self.fields.insert(name, value);
+            }
+        }
+    }
+}
+
+/// The JSON key of an element or attribute name, as `options` write namespaces
+fn key(reader: &NsReader<&[u8]>, options: &XmlOptions, name: QName<'_>, attribute: bool) -> Result<String, String> {
+    let (namespace, local) = reader.resolve(name, attribute);
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    match (namespace, options.namespaces) {
+        (ResolveResult::Unknown(prefix), _) => Err(format!("namespace prefix '{}' is not declared", text(&prefix))),
+        (_, Namespaces::Prefixed) => Ok(text(name.as_ref())),
+        (ResolveResult::Bound(namespace), Namespaces::Expanded) => {
+            Ok(format!("{{{}}}{}", text(namespace.as_ref()), text(local.as_ref())))
+        }
+        _ => Ok(text(local.as_ref())),
+    }
+}
+
+fn parse(xml: &str, options: &XmlOptions) -> Result<Value, XmlDiagnostic> {
+    let at = |offset: usize, message: String| {
+        let (line, column) = super::line_column(xml, offset);
+        XmlDiagnostic { message, offset, line, column }
+    };
+    let mut reader = NsReader::from_str(xml);
+    reader.trim_text(true);
+    let mut open: Vec<Open> = Vec::new();
+    let mut root = None;
+    loop {
+        // Where the next event starts, past the whitespace the reader trims
+        let rest = xml.get(reader.buffer_position()..).unwrap_or_default();
+        let offset = xml.len() - rest.trim_start().len();
+        let event = reader.read_event().map_err(|e| at(reader.buffer_position(), e.to_string()))?;
+        let invalid = |message: String| at(offset, message);
+        let text = match event {
+            Event::Start(start) | Event::Empty(start) if open.is_empty() && root.is_some() => {
+                let name = String::from_utf8_lossy(start.name().as_ref()).into_owned();
+                return Err(at(offset, format!("second root element <{name}>")));
+            }
+            Event::Start(_) if open.len() == MAX_DEPTH => {
+                return Err(at(offset, format!("elements nested more than {MAX_DEPTH} deep")));
+            }
+            Event::Start(start) => {
+                open.push(Open::new(&reader, options, &start, offset).map_err(invalid)?);
+                continue;
+            }
+            Event::Empty(start) => {
+                let (name, value) = Open::new(&reader, options, &start, offset).map_err(invalid)?.into_value(options);
+                match open.last_mut() {
+                    Some(parent) => parent.push(name, value),
+                    None => root = Some((name, value)),
+                }
+                continue;
+            }
+            Event::End(_) => {
+                // The reader has checked the end tag matches
+                let element = open.pop().ok_or_else(|| at(offset, "unexpected end tag".to_string()))?;
+                let (name, value) = element.into_value(options);
+                match open.last_mut() {
+                    Some(parent) => parent.push(name, value),
+                    None => root = Some((name, value)),
+                }
+                continue;
+            }
+            Event::Text(text) => text.unescape().map_err(|e| invalid(e.to_string()))?.into_owned(),
+            Event::CData(data) => String::from_utf8_lossy(&data.into_inner()).into_owned(),
+            Event::Comment(_) | Event::Decl(_) | Event::PI(_) | Event::DocType(_) => continue,
+            Event::Eof => break,
+        };
+        match open.last_mut() {
+            Some(element) => element.text.push_str(&text),
+            None => return Err(at(offset, "text outside the root element".to_string())),
+        }
+    }
+    if let Some(element) = open.pop() {
+        return Err(at(element.offset, format!("element <{}> is never closed", element.name)));
+    }
+    let (name, value) = root.ok_or_else(|| at(xml.len(), "no root element".to_string()))?;
+    Ok(Value::Object(Map::from_iter([(name, value)])))
+}
+
+/// The namespace and local name of a `{uri}local` key
+fn expanded(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix('{')?.split_once('}')
+}
+
+/// `default` is the default namespace the element is in, where [`expanded`] names have set one
+fn write_element(writer: &mut Writer<Vec<u8>>, options: &XmlOptions, key: &str, value: &Value, default: &str) -> Result<()> {
+    if let Value::Array(items) = value {
+        for item in items {
+            write_element(writer, options, key, item, default)?;
+        }
+        return Ok(());
+    }
+    let (name, namespace) = match expanded(key) {
+        Some((namespace, local)) => (local, namespace),
+        // A name written as is stays out of any namespace set for its parent
+        None if key.contains(':') => (key, default),
+        None => (key, ""),
+    };
+    check_name(name)?;
+    let mut start = BytesStart::new(name);
+    if namespace != default {
+        start.push_attribute(("xmlns", namespace));
+    }
+    let Value::Object(fields) = value else {
+        if value.is_null() {
+            writer.write_event(Event::Empty(start))?;
+        } else {
+            writer.write_event(Event::Start(start))?;
+            writer.write_event(Event::Text(BytesText::new(&scalar(key, value)?)))?;
+            writer.write_event(Event::End(BytesEnd::new(name)))?;
+        }
+        return Ok(());
+    };
+
+    let mut text = None;
+    let mut children = Vec::new();
+    let mut declared: Vec<&str> = Vec::new();
+    for (field, value) in fields {
+        if let Some(attribute) = field.strip_prefix(options.attribute_prefix.as_str()) {
+            let value = scalar(field, value)?;
+            if let Some((namespace, local)) = expanded(attribute) {
+                check_name(local)?;
+                let prefix = if namespace == XML_NAMESPACE {
+                    "xml".to_string()
+                } else {
+                    let index = declared.iter().position(|uri| *uri == namespace).unwrap_or_else(|| {
+                        declared.push(namespace);
+                        start.push_attribute((format!("xmlns:ns{}", declared.len() - 1).as_str(), namespace));
+                        declared.len() - 1
+                    });
+                    format!("ns{index}")
+                };
+                start.push_attribute((format!("{prefix}:{local}").as_str(), value.as_str()));
+            } else {
+                check_name(attribute)?;
+                start.push_attribute((attribute, value.as_str()));
+            }
+        } else if *field == options.text_key {
+            text = Some(scalar(field, value)?);
+        } else {
+            children.push((field, value));
+        }
+    }
+    if text.is_none() && children.is_empty() {
+        writer.write_event(Event::Empty(start))?;
+        return Ok(());
+    }
+    writer.write_event(Event::Start(start))?;
+    if let Some(text) = text {
+        writer.write_event(Event::Text(BytesText::new(&text)))?;
+    }
+    for (field, value) in children {
+        write_element(writer, options, field, value, namespace)?;
+    }
+    writer.write_event(Event::End(BytesEnd::new(name)))?;
+    Ok(())
+}
+
+/// The text of a value written as an attribute or text
+fn scalar(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Null => Ok(String::new()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(_) | Value::Object(_) => Err(anyhow!("'{key}' must hold text, not a list or object")),
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || "_-.:".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("'{name}' is not a valid XML name"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip(xml: &str) -> Value {
+        let json: Value = serde_json::from_str(&xml_to_json(xml).unwrap()).unwrap();
+        let again: Value = serde_json::from_str(&xml_to_json(&json_to_xml(&json.to_string()).unwrap()).unwrap()).unwrap();
+        assert_eq!(again, json);
+        json
+    }
+
+    #[test]
+    fn test_round_trip_attributes() {
+        let xml = r#"<?xml version="1.0"?>
+            <!-- editor settings -->
+            <settings version="2" xmlns:ed="urn:editor">
+                <font size="12" family="Mono &amp; Sans">Body</font>
+                <ruler>80</ruler>
+                <ruler>120</ruler>
+                <ed:theme dark="true"/>
+                <empty/>
+                <script><![CDATA[a < b]]></script>
+            </settings>"#;
+        assert_eq!(
+            round_trip(xml),
+            json!({
+                "settings": {
+                    "@version": "2",
+                    "@xmlns:ed": "urn:editor",
+                    "font": {"@size": "12", "@family": "Mono & Sans", "#text": "Body"},
+                    "ruler": ["80", "120"],
+                    "ed:theme": {"@dark": "true"},
+                    "empty": null,
+                    "script": "a < b"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_to_xml_wraps_unnamed_roots() {
+        let xml = json_to_xml(r#"{"a": 1, "b": [true, "x"]}"#).unwrap();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"), "{xml}");
+        let json: Value = serde_json::from_str(&xml_to_json(&xml).unwrap()).unwrap();
+        assert_eq!(json, json!({"root": {"a": "1", "b": ["true", "x"]}}));
+        assert!(json_to_xml(r#"{"1st": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_attribute_prefix_and_text_key() {
+        let options = XmlOptions { attribute_prefix: "-".to_string(), text_key: "_".to_string(), ..XmlOptions::default() };
+        let json = xml_to_json_with(r#"<a id="1">text<b/></a>"#, &options).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, json!({"a": {"-id": "1", "_": "text", "b": null}}));
+        let xml = json_to_xml_with(&json, &options).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&xml_to_json_with(&xml, &options).unwrap()).unwrap(), value);
+
+        let options = XmlOptions { attribute_prefix: String::new(), ..XmlOptions::default() };
+        assert!(xml_to_json_with("<a/>", &options).is_err());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let xml = r#"<feed xmlns="urn:atom" xmlns:ed="urn:editor">
+                <entry ed:id="7" xml:lang="en"><ed:theme>dark</ed:theme><title>Notes</title></entry>
+            </feed>"#;
+        let convert = |namespaces| {
+            let options = XmlOptions { namespaces, ..XmlOptions::default() };
+            serde_json::from_str::<Value>(&xml_to_json_with(xml, &options).unwrap()).unwrap()
+        };
+        assert_eq!(
+            convert(Namespaces::Local),
+            json!({"feed": {"entry": {"@id": "7", "@lang": "en", "theme": "dark", "title": "Notes"}}})
+        );
+        let expanded = convert(Namespaces::Expanded);
+        assert_eq!(
+            expanded,
+            json!({"{urn:atom}feed": {"{urn:atom}entry": {
+                "@{urn:editor}id": "7",
+                "@{http://www.w3.org/XML/1998/namespace}lang": "en",
+                "{urn:editor}theme": "dark",
+                "{urn:atom}title": "Notes",
+            }}})
+        );
+        // Writing expanded names declares their namespaces
+        let options = XmlOptions { namespaces: Namespaces::Expanded, ..XmlOptions::default() };
+        let written = json_to_xml_with(&expanded.to_string(), &options).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&xml_to_json_with(&written, &options).unwrap()).unwrap(), expanded);
+        let written = json_to_xml(r#"{"{urn:atom}feed": {"plain": 1}}"#).unwrap();
+        let value: Value = serde_json::from_str(&xml_to_json_with(&written, &options).unwrap()).unwrap();
+        assert_eq!(value, json!({"{urn:atom}feed": {"plain": "1"}}));
+
+        let diagnostics = validate_xml("<?xml version=\"1.0\"?>\n<a>\n  <x:b/>\n</a>").unwrap();
+        assert_eq!(diagnostics, ["Invalid XML: line 3, column 3: namespace prefix 'x' is not declared"]);
+    }
+
+    #[test]
+    fn test_diagnostics_give_byte_offsets() {
+        let xml = "<a>\n  <b x=\"1\" x=\"2\"/>\n</a>";
+        let found = diagnostics(xml);
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert_eq!((found[0].offset, found[0].line, found[0].column), (6, 2, 3), "{found:?}");
+
+        let xml = "<a>é</a><b/>";
+        assert_eq!(diagnostics(xml)[0].offset, 9);
+        assert_eq!(diagnostics(xml)[0].column, 9);
+        assert!(diagnostics("<a/>").is_empty());
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let deep = |depth: usize| "<a>".repeat(depth) + &"</a>".repeat(depth);
+        assert!(xml_to_json(&deep(MAX_DEPTH)).is_ok());
+
+        let found = diagnostics(&deep(10_000));
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert_eq!(found[0].message, format!("elements nested more than {MAX_DEPTH} deep"));
+        assert_eq!(found[0].offset, MAX_DEPTH * 3);
+        assert!(xml_to_json(&deep(10_000)).is_err());
+    }
+
+    #[test]
+    fn test_validate_xml_leaves_rules_to_lint() {
+        // Empty documents and missing declarations are lint rules'
+        assert!(validate_xml("").unwrap().is_empty());
+        assert!(validate_xml("<root></root>").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_xml_reports_position() {
+        let diagnostics = validate_xml("<?xml version=\"1.0\"?>\n<a>\n  <b></c>\n</a>").unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert!(diagnostics[0].starts_with("Invalid XML: line 3, column "), "{}", diagnostics[0]);
+
+        let diagnostics = validate_xml("<?xml version=\"1.0\"?>\n<a>\n  <b>").unwrap();
+        assert_eq!(diagnostics, ["Invalid XML: line 3, column 3: element <b> is never closed"]);
+        assert!(validate_xml("<?xml version=\"1.0\"?><a x=\"1\">text</a>").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1517 @@
+//! `XPath` 1.0 over XML documents
+//!
+//! A document is read into the `XPath` data model: a root node holding the
+//! root element, and comments and processing instructions, elements holding
+//! their attributes and children. Adjacent text and CDATA sections make one
+//! text node, and whitespace between elements is text too, as `XPath` has it.
+//! Every node keeps the bytes it spans in the document: an element from its
+//! start tag to the end of its end tag, an attribute from its name to its
+//! closing quote.
+//!
+//! Every axis but `namespace`, abbreviations such as `//`, `..` and `@`,
+//! predicates and the whole core function library are supported. Nothing
+//! binds variables, and `id()` finds elements by their `xml:id` attribute,
+//! as no DTD declares IDs. Prefixes in an expression are those the document
+//! declares, the first declaration of each where it declares one twice. As
+//! `XPath` 1.0 has it, a name without a prefix is in no namespace, so an
+//! element in a default namespace is found with `*[local-name() = 'name']`.
+
+use anyhow::{anyhow, bail, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{LocalName, QName, ResolveResult};
+use quick_xml::reader::NsReader;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::ops::Range;
+
+use super::{Open, XmlOptions, XML_NAMESPACE};
+
+/// Operators and brackets nested within one another before an expression is refused
+const MAX_DEPTH: usize = 128;
+
+/// The core function library, with the fewest and most arguments each takes
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("last", 0, 0),
+    ("position", 0, 0),
+    ("count", 1, 1),
+    ("id", 1, 1),
+    ("local-name", 0, 1),
+    ("namespace-uri", 0, 1),
+    ("name", 0, 1),
+    ("string", 0, 1),
+    ("concat", 2, usize::MAX),
+    ("starts-with", 2, 2),
+    ("contains", 2, 2),
+    ("substring-before", 2, 2),
+    ("substring-after", 2, 2),
+    ("substring", 2, 3),
+    ("string-length", 0, 1),
+    ("normalize-space", 0, 1),
+    ("translate", 3, 3),
+    ("boolean", 1, 1),
+    ("not", 1, 1),
+    ("true", 0, 0),
+    ("false", 0, 0),
+    ("lang", 1, 1),
+    ("number", 0, 1),
+    ("sum", 1, 1),
+    ("floor", 1, 1),
+    ("ceiling", 1, 1),
+    ("round", 1, 1),
+];
+
+/// Names that test a node's type rather than its name where `(` follows them
+const NODE_TYPES: &[&str] = &["node", "text", "comment", "processing-instruction"];
+
+/// A node an expression selected
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPathMatch {
+    /// An element, or the root, as [`xml_to_json`](super::xml_to_json) gives it; any other node as its string value
+    pub value: Value,
+    /// A location path selecting only this node, such as `/config[1]/server[2]/@port`
+    pub path: String,
+    /// Bytes of the node in the document
+    pub span: Range<usize>,
+}
+
+/// What an expression gave
+#[derive(Debug, Clone, PartialEq)]
+pub enum XPathValue {
+    /// The nodes selected, in document order
+    Nodes(Vec<XPathMatch>),
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+/// The value of `expression` with the root of `xml` as its context node
+///
+/// # Errors
+///
+/// Fails where `xml` does not parse, or `expression` is not an `XPath`
+/// expression.
+pub fn evaluate(xml: &str, expression: &str) -> Result<XPathValue> {
+    let document = Document::read(xml)?;
+    let tokens = lex(expression)?;
+    let mut parser = Parser { tokens, pos: 0, depth: 0, prefixes: &document.prefixes };
+    let ast = parser.expression()?;
+    if let Some((token, at)) = parser.tokens.get(parser.pos) {
+        bail!("Invalid XPath at column {}: unexpected {}", at + 1, token.describe());
+    }
+    Ok(match document.eval(&ast, Context { node: 0, position: 1, size: 1 })? {
+        Object::Nodes(nodes) => XPathValue::Nodes(
+            nodes
+                .into_iter()
+                .map(|id| XPathMatch { value: document.fragment(id), path: document.path(id), span: document.nodes[id].span.clone() })
+                .collect(),
+        ),
+        Object::Boolean(b) => XPathValue::Boolean(b),
+        Object::Number(n) => XPathValue::Number(n),
+        Object::String(s) => XPathValue::String(s),
+    })
+}
+
+/// A name as written, with its local part and namespace
+#[derive(Debug)]
+struct Name {
+    qualified: String,
+    local: String,
+    namespace: Option<String>,
+}
+
+impl Name {
+    fn new(name: QName<'_>, namespace: &ResolveResult<'_>, local: LocalName<'_>) -> Self {
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let namespace = match namespace {
+            ResolveResult::Bound(namespace) => Some(text(namespace.as_ref())),
+            _ => None,
+        };
+        Self { qualified: text(name.as_ref()), local: text(local.as_ref()), namespace }
+    }
+}
+
+#[derive(Debug)]
+enum Kind {
+    Root,
+    Element(Name),
+    Attribute(Name, String),
+    Text(String),
+    Comment(String),
+    Instruction { target: String, data: String },
+}
+
+#[derive(Debug)]
+struct NodeData {
+    kind: Kind,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    attributes: Vec<usize>,
+    /// Namespace declarations, which are no attributes in `XPath` but are in the JSON of an element
+    declarations: Vec<(String, String)>,
+    span: Range<usize>,
+    /// The first node after this one's descendants
+    end: usize,
+}
+
+/// A document's nodes, each numbered by its place in document order
+///
+/// An element's attributes follow it, then its descendants, so a node's
+/// descendants are the nodes numbered from it to its `end`.
+struct Document {
+    nodes: Vec<NodeData>,
+    /// Namespaces of the prefixes the document declares
+    prefixes: HashMap<String, String>,
+}
+
+impl Document {
+    fn read(xml: &str) -> Result<Self> {
+        if let Some(diagnostic) = super::diagnostics(xml).into_iter().next() {
+            bail!("Invalid XML: {diagnostic}");
+        }
+        let mut document = Self { nodes: Vec::new(), prefixes: HashMap::from([("xml".to_string(), XML_NAMESPACE.to_string())]) };
+        document.node(Kind::Root, None, 0..xml.len());
+        let mut reader = NsReader::from_str(xml);
+        let mut open = vec![0];
+        loop {
+            let offset = reader.buffer_position();
+            let event = reader.read_event()?;
+            let span = offset..reader.buffer_position();
+            let parent = open.last().copied().unwrap_or_default();
+            match event {
+                Event::Start(start) => open.push(document.element(&reader, &start, parent, &xml[span.clone()], span)?),
+                Event::Empty(start) => {
+                    let id = document.element(&reader, &start, parent, &xml[span.clone()], span)?;
+                    document.nodes[id].end = document.nodes.len();
+                }
+                Event::End(_) => {
+                    if let Some(id) = open.pop() {
+                        let end = document.nodes.len();
+                        let element = &mut document.nodes[id];
+                        element.span.end = span.end;
+                        element.end = end;
+                    }
+                }
+                Event::Text(text) => document.text(parent, &text.unescape()?, span),
+                Event::CData(data) => document.text(parent, &String::from_utf8_lossy(&data.into_inner()), span),
+                Event::Comment(comment) => {
+                    let text = String::from_utf8_lossy(&comment.into_inner()).into_owned();
+                    document.add(Kind::Comment(text), parent, span);
+                }
+                Event::PI(instruction) => {
+                    let content = String::from_utf8_lossy(&instruction.into_inner()).into_owned();
+                    let (target, data) = content.split_once(|c: char| c.is_ascii_whitespace()).unwrap_or((content.as_str(), ""));
+                    let kind = Kind::Instruction { target: target.to_string(), data: data.trim_start().to_string() };
+                    document.add(kind, parent, span);
+                }
+                Event::Decl(_) | Event::DocType(_) => {}
+                Event::Eof => break,
+            }
+        }
+        document.nodes[0].end = document.nodes.len();
+        Ok(document)
+    }
+
+    fn node(&mut self, kind: Kind, parent: Option<usize>, span: Range<usize>) -> usize {
+        let id = self.nodes.len();
+        let node = NodeData { kind, parent, children: Vec::new(), attributes: Vec::new(), declarations: Vec::new(), span, end: id + 1 };
+        self.nodes.push(node);
+        id
+    }
+
+    /// Add a child to `parent`
+    fn add(&mut self, kind: Kind, parent: usize, span: Range<usize>) -> usize {
+        let id = self.node(kind, Some(parent), span);
+        self.nodes[parent].children.push(id);
+        id
+    }
+
+    /// Add the element `start` opens, and its attributes; `tag` is its start tag
+    fn element(
+        &mut self,
+        reader: &NsReader<&[u8]>,
+        start: &BytesStart<'_>,
+        parent: usize,
+        tag: &str,
+        span: Range<usize>,
+    ) -> Result<usize> {
+        let (namespace, local) = reader.resolve_element(start.name());
+        let id = self.add(Kind::Element(Name::new(start.name(), &namespace, local)), parent, span.clone());
+        for (attribute, bytes) in start.attributes().zip(attribute_spans(tag)) {
+            let attribute = attribute?;
+            let value = attribute.unescape_value()?.into_owned();
+            let raw = attribute.key.as_ref();
+            if raw == b"xmlns" || raw.starts_with(b"xmlns:") {
+                let declaration = String::from_utf8_lossy(raw).into_owned();
+                if let Some(prefix) = declaration.strip_prefix("xmlns:") {
+                    self.prefixes.entry(prefix.to_string()).or_insert_with(|| value.clone());
+                }
+                self.nodes[id].declarations.push((declaration, value));
+                continue;
+            }
+            let (namespace, local) = reader.resolve_attribute(attribute.key);
+            let kind = Kind::Attribute(Name::new(attribute.key, &namespace, local), value);
+            let attribute = self.node(kind, Some(id), span.start + bytes.start..span.start + bytes.end);
+            self.nodes[id].attributes.push(attribute);
+        }
+        Ok(id)
+    }
+
+    /// Add text to `parent`, joining the text node it ends with
+    fn text(&mut self, parent: usize, text: &str, span: Range<usize>) {
+        // Only whitespace lies outside the root element, which is no node
+        if parent == 0 || text.is_empty() {
+            return;
+        }
+        if let Some(&last) = self.nodes[parent].children.last() {
+            if let Kind::Text(existing) = &mut self.nodes[last].kind {
+                existing.push_str(text);
+                self.nodes[last].span.end = span.end;
+                return;
+            }
+        }
+        self.add(Kind::Text(text.to_string()), parent, span);
+    }
+
+    fn is_attribute(&self, id: usize) -> bool {
+        matches!(self.nodes[id].kind, Kind::Attribute(..))
+    }
+
+    fn string_value(&self, id: usize) -> String {
+        match &self.nodes[id].kind {
+            Kind::Root | Kind::Element(_) => (id + 1..self.nodes[id].end)
+                .filter_map(|descendant| match &self.nodes[descendant].kind {
+                    Kind::Text(text) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            Kind::Attribute(_, value) | Kind::Text(value) | Kind::Comment(value) | Kind::Instruction { data: value, .. } => {
+                value.clone()
+            }
+        }
+    }
+
+    fn name(&self, id: usize) -> Option<&Name> {
+        match &self.nodes[id].kind {
+            Kind::Element(name) | Kind::Attribute(name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The nodes on `axis` from `id`, nearest first
+    fn axis(&self, id: usize, axis: Axis) -> Vec<usize> {
+        let node = &self.nodes[id];
+        let siblings = || match node.parent {
+            Some(parent) if !self.is_attribute(id) => self.nodes[parent].children.as_slice(),
+            _ => &[],
+        };
+        match axis {
+            Axis::Child => node.children.clone(),
+            Axis::Attribute => node.attributes.clone(),
+            Axis::SelfNode => vec![id],
+            Axis::Parent => node.parent.into_iter().collect(),
+            Axis::Ancestor | Axis::AncestorOrSelf => {
+                let mut nodes = if axis == Axis::AncestorOrSelf { vec![id] } else { Vec::new() };
+                let mut parent = node.parent;
+                while let Some(ancestor) = parent {
+                    nodes.push(ancestor);
+                    parent = self.nodes[ancestor].parent;
+                }
+                nodes
+            }
+            Axis::Descendant => (id + 1..node.end).filter(|n| !self.is_attribute(*n)).collect(),
+            Axis::DescendantOrSelf => std::iter::once(id).chain((id + 1..node.end).filter(|n| !self.is_attribute(*n))).collect(),
+            Axis::Following => (node.end..self.nodes.len()).filter(|n| !self.is_attribute(*n)).collect(),
+            Axis::FollowingSibling => siblings().iter().copied().filter(|sibling| *sibling > id).collect(),
+            Axis::PrecedingSibling => siblings().iter().copied().filter(|sibling| *sibling < id).rev().collect(),
+            Axis::Preceding => {
+                let ancestors = self.axis(id, Axis::Ancestor);
+                (0..id).rev().filter(|n| !self.is_attribute(*n) && !ancestors.contains(n)).collect()
+            }
+        }
+    }
+
+    fn matches(&self, id: usize, axis: Axis, test: &NodeTest) -> bool {
+        // Names test the attributes on the attribute axis, and elements on any other
+        let principal = match (&self.nodes[id].kind, axis) {
+            (Kind::Attribute(name, _), Axis::Attribute) => Some(name),
+            (Kind::Element(name), axis) if axis != Axis::Attribute => Some(name),
+            _ => None,
+        };
+        match (test, &self.nodes[id].kind) {
+            (NodeTest::Node, _) | (NodeTest::Text, Kind::Text(_)) | (NodeTest::Comment, Kind::Comment(_)) => true,
+            (NodeTest::Instruction(expected), Kind::Instruction { target, .. }) => expected.as_ref().is_none_or(|expected| expected == target),
+            (NodeTest::Any, _) => principal.is_some(),
+            (NodeTest::Namespace(namespace), _) => principal.is_some_and(|name| name.namespace.as_ref() == Some(namespace)),
+            (NodeTest::Name { namespace, local }, _) => principal.is_some_and(|name| name.local == *local && name.namespace == *namespace),
+            _ => false,
+        }
+    }
+
+    fn eval(&self, expr: &Expr, context: Context) -> Result<Object> {
+        Ok(match expr {
+            Expr::Binary(Operator::Or, a, b) => Object::Boolean(self.eval(a, context)?.boolean() || self.eval(b, context)?.boolean()),
+            Expr::Binary(Operator::And, a, b) => Object::Boolean(self.eval(a, context)?.boolean() && self.eval(b, context)?.boolean()),
+            Expr::Binary(Operator::Compare(comparison), a, b) => {
+                Object::Boolean(self.compare(*comparison, &self.eval(a, context)?, &self.eval(b, context)?))
+            }
+            Expr::Binary(operator, a, b) => {
+                let (a, b) = (self.number(&self.eval(a, context)?), self.number(&self.eval(b, context)?));
+                Object::Number(operator.apply(a, b))
+            }
+            Expr::Negate(expr) => Object::Number(-self.number(&self.eval(expr, context)?)),
+            Expr::Union(exprs) => {
+                let mut nodes = Vec::new();
+                for expr in exprs {
+                    nodes.extend(self.eval(expr, context)?.into_nodes("Both sides of | need node-sets")?);
+                }
+                nodes.sort_unstable();
+                nodes.dedup();
+                Object::Nodes(nodes)
+            }
+            Expr::Literal(s) => Object::String(s.clone()),
+            Expr::Number(n) => Object::Number(*n),
+            Expr::Call(name, arguments) => self.call(name, arguments, context)?,
+            Expr::Filter(primary, predicates) => {
+                let mut nodes = self.eval(primary, context)?.into_nodes("Predicates filter node-sets")?;
+                for predicate in predicates {
+                    nodes = self.filter(nodes, predicate)?;
+                }
+                Object::Nodes(nodes)
+            }
+            Expr::Path(start, steps) => {
+                let mut nodes = match start {
+                    Start::Root => vec![0],
+                    Start::Context => vec![context.node],
+                    Start::Nodes(expr) => self.eval(expr, context)?.into_nodes("A path goes on from a node-set")?,
+                };
+                for step in steps {
+                    nodes = self.step(&nodes, step)?;
+                }
+                Object::Nodes(nodes)
+            }
+        })
+    }
+
+    /// The nodes `step` selects from each of `nodes`, in document order
+    fn step(&self, nodes: &[usize], step: &Step) -> Result<Vec<usize>> {
+        let mut selected = Vec::new();
+        for &node in nodes {
+            let mut candidates: Vec<usize> =
+                self.axis(node, step.axis).into_iter().filter(|id| self.matches(*id, step.axis, &step.test)).collect();
+            for predicate in &step.predicates {
+                candidates = self.filter(candidates, predicate)?;
+            }
+            selected.extend(candidates);
+        }
+        selected.sort_unstable();
+        selected.dedup();
+        Ok(selected)
+    }
+
+    /// The nodes `predicate` holds for, each at its position among `nodes`
+    #[allow(clippy::cast_precision_loss, clippy::float_cmp)] // Positions are far below 2^53, and whole
+    fn filter(&self, nodes: Vec<usize>, predicate: &Expr) -> Result<Vec<usize>> {
+        let size = nodes.len();
+        let mut kept = Vec::new();
+        for (index, node) in nodes.into_iter().enumerate() {
+            let context = Context { node, position: index + 1, size };
+            let keep = match self.eval(predicate, context)? {
+                // A number holds at that position
+                Object::Number(n) => n == (index + 1) as f64,
+                other => other.boolean(),
+            };
+            if keep {
+                kept.push(node);
+            }
+        }
+        Ok(kept)
+    }
+
+    fn number(&self, object: &Object) -> f64 {
+        match object {
+            Object::Nodes(_) => number_of(&self.string(object)),
+            Object::Boolean(b) => f64::from(u8::from(*b)),
+            Object::Number(n) => *n,
+            Object::String(s) => number_of(s),
+        }
+    }
+
+    fn string(&self, object: &Object) -> String {
+        match object {
+            Object::Nodes(nodes) => nodes.first().map(|id| self.string_value(*id)).unwrap_or_default(),
+            Object::Boolean(b) => b.to_string(),
+            Object::Number(n) => format_number(*n),
+            Object::String(s) => s.clone(),
+        }
+    }
+
+    /// Whether `comparison` holds, for some node where either side is a node-set
+    fn compare(&self, comparison: Comparison, a: &Object, b: &Object) -> bool {
+        match (a, b) {
+            (Object::Nodes(a), Object::Nodes(b)) => {
+                let right: Vec<Object> = b.iter().map(|id| Object::String(self.string_value(*id))).collect();
+                a.iter().any(|id| {
+                    let left = Object::String(self.string_value(*id));
+                    right.iter().any(|right| self.compare_values(comparison, &left, right))
+                })
+            }
+            (Object::Nodes(_), Object::Boolean(_)) => self.compare_values(comparison, &Object::Boolean(a.boolean()), b),
+            (Object::Nodes(nodes), _) => nodes.iter().any(|id| {
+                let value = self.string_value(*id);
+                let left = if matches!(b, Object::Number(_)) { Object::Number(number_of(&value)) } else { Object::String(value) };
+                self.compare_values(comparison, &left, b)
+            }),
+            (_, Object::Nodes(_)) => self.compare(comparison.reversed(), b, a),
+            _ => self.compare_values(comparison, a, b),
+        }
+    }
+
+    /// Whether `comparison` holds between values that are not node-sets
+    #[allow(clippy::float_cmp)] // As XPath compares numbers
+    fn compare_values(&self, comparison: Comparison, a: &Object, b: &Object) -> bool {
+        let equal = || match (a, b) {
+            (Object::Boolean(_), _) | (_, Object::Boolean(_)) => a.boolean() == b.boolean(),
+            (Object::Number(_), _) | (_, Object::Number(_)) => self.number(a) == self.number(b),
+            _ => self.string(a) == self.string(b),
+        };
+        match comparison {
+            Comparison::Eq => equal(),
+            Comparison::Ne => !equal(),
+            Comparison::Lt => self.number(a) < self.number(b),
+            Comparison::Le => self.number(a) <= self.number(b),
+            Comparison::Gt => self.number(a) > self.number(b),
+            Comparison::Ge => self.number(a) >= self.number(b),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)] // Counts and lengths are far below 2^53
+    fn call(&self, name: &str, arguments: &[Expr], context: Context) -> Result<Object> {
+        let value = |i: usize| self.eval(&arguments[i], context);
+        let string = |i: usize| -> Result<String> { Ok(self.string(&value(i)?)) };
+        let number = |i: usize| -> Result<f64> { Ok(self.number(&value(i)?)) };
+        // The node-set of the first argument, or the context node
+        let nodes = || -> Result<Vec<usize>> {
+            match arguments.first() {
+                Some(_) => value(0)?.into_nodes(&format!("{name}() needs a node-set")),
+                None => Ok(vec![context.node]),
+            }
+        };
+        // The first argument as a string, or the context node's string value
+        let text = || -> Result<String> {
+            match arguments.first() {
+                Some(_) => string(0),
+                None => Ok(self.string_value(context.node)),
+            }
+        };
+        Ok(match name {
+            "last" => Object::Number(context.size as f64),
+            "position" => Object::Number(context.position as f64),
+            "count" => Object::Number(value(0)?.into_nodes("count() needs a node-set")?.len() as f64),
+            "id" => {
+                let ids: Vec<String> = match value(0)? {
+                    Object::Nodes(nodes) => nodes.iter().flat_map(|id| tokens(&self.string_value(*id))).collect(),
+                    other => tokens(&self.string(&other)),
+                };
+                Object::Nodes(self.ids(&ids))
+            }
+            "local-name" => Object::String(nodes()?.first().map(|id| self.local_name(*id)).unwrap_or_default()),
+            "namespace-uri" => {
+                let namespace = nodes()?.first().and_then(|id| self.name(*id)).and_then(|name| name.namespace.clone());
+                Object::String(namespace.unwrap_or_default())
+            }
+            "name" => Object::String(nodes()?.first().map(|id| self.qualified_name(*id)).unwrap_or_default()),
+            "string" => Object::String(text()?),
+            "concat" => Object::String((0..arguments.len()).map(&string).collect::<Result<String>>()?),
+            "starts-with" => Object::Boolean(string(0)?.starts_with(&string(1)?)),
+            "contains" => Object::Boolean(string(0)?.contains(&string(1)?)),
+            "substring-before" => {
+                let (s, pattern) = (string(0)?, string(1)?);
+                Object::String(s.find(&pattern).map(|i| s[..i].to_string()).unwrap_or_default())
+            }
+            "substring-after" => {
+                let (s, pattern) = (string(0)?, string(1)?);
+                Object::String(s.find(&pattern).map(|i| s[i + pattern.len()..].to_string()).unwrap_or_default())
+            }
+            "substring" => {
+                let length = if arguments.len() > 2 { Some(number(2)?) } else { None };
+                Object::String(substring(&string(0)?, number(1)?, length))
+            }
+            "string-length" => Object::Number(text()?.chars().count() as f64),
+            "normalize-space" => Object::String(tokens(&text()?).join(" ")),
+            "translate" => {
+                let (s, from, to): (String, Vec<char>, Vec<char>) = (string(0)?, string(1)?.chars().collect(), string(2)?.chars().collect());
+                // Characters of `from` past the end of `to` are removed
+                Object::String(
+                    s.chars()
+                        .filter_map(|c| match from.iter().position(|f| *f == c) {
+                            Some(i) => to.get(i).copied(),
+                            None => Some(c),
+                        })
+                        .collect(),
+                )
+            }
+            "boolean" => Object::Boolean(value(0)?.boolean()),
+            "not" => Object::Boolean(!value(0)?.boolean()),
+            "true" => Object::Boolean(true),
+            "false" => Object::Boolean(false),
+            "lang" => Object::Boolean(self.lang(context.node, &string(0)?)),
+            "number" => Object::Number(match arguments.first() {
+                Some(_) => number(0)?,
+                None => number_of(&self.string_value(context.node)),
+            }),
+            "sum" => Object::Number(
+                value(0)?.into_nodes("sum() needs a node-set")?.iter().map(|id| number_of(&self.string_value(*id))).sum(),
+            ),
+            "floor" => Object::Number(number(0)?.floor()),
+            "ceiling" => Object::Number(number(0)?.ceil()),
+            "round" => Object::Number(round(number(0)?)),
+            _ => bail!("Unknown XPath function {name}()"),
+        })
+    }
+
+    fn local_name(&self, id: usize) -> String {
+        match &self.nodes[id].kind {
+            Kind::Instruction { target, .. } => target.clone(),
+            _ => self.name(id).map(|name| name.local.clone()).unwrap_or_default(),
+        }
+    }
+
+    fn qualified_name(&self, id: usize) -> String {
+        match &self.nodes[id].kind {
+            Kind::Instruction { target, .. } => target.clone(),
+            _ => self.name(id).map(|name| name.qualified.clone()).unwrap_or_default(),
+        }
+    }
+
+    /// Elements whose `xml:id` is one of `ids`
+    fn ids(&self, ids: &[String]) -> Vec<usize> {
+        let has_id = |attribute: &usize| match &self.nodes[*attribute].kind {
+            Kind::Attribute(name, value) => name.local == "id" && name.namespace.as_deref() == Some(XML_NAMESPACE) && ids.contains(value),
+            _ => false,
+        };
+        (0..self.nodes.len()).filter(|id| self.nodes[*id].attributes.iter().any(has_id)).collect()
+    }
+
+    /// Whether the `xml:lang` nearest `id` is `language` or one of its variants
+    fn lang(&self, id: usize, language: &str) -> bool {
+        let language = language.to_lowercase();
+        let mut node = Some(id);
+        while let Some(id) = node {
+            let declared = self.nodes[id].attributes.iter().find_map(|attribute| match &self.nodes[*attribute].kind {
+                Kind::Attribute(name, value) if name.local == "lang" && name.namespace.as_deref() == Some(XML_NAMESPACE) => Some(value),
+                _ => None,
+            });
+            if let Some(declared) = declared {
+                let declared = declared.to_lowercase();
+                return declared.strip_prefix(&language).is_some_and(|rest| rest.is_empty() || rest.starts_with('-'));
+            }
+            node = self.nodes[id].parent;
+        }
+        false
+    }
+
+    /// The node as JSON: an element, or the root, as `xml_to_json` gives it, and any other node its string value
+    fn fragment(&self, id: usize) -> Value {
+        match &self.nodes[id].kind {
+            Kind::Root => Value::Object(self.nodes[id].children.iter().filter_map(|child| self.entry(*child)).collect()),
+            Kind::Element(_) => Value::Object(self.entry(id).into_iter().collect()),
+            _ => Value::String(self.string_value(id)),
+        }
+    }
+
+    /// An element's name and value, as `xml_to_json` gives them
+    fn entry(&self, id: usize) -> Option<(String, Value)> {
+        let node = &self.nodes[id];
+        let Kind::Element(name) = &node.kind else {
+            return None;
+        };
+        let options = XmlOptions::default();
+        let mut element = Open { name: name.qualified.clone(), fields: Map::new(), text: String::new(), offset: node.span.start };
+        let attributes = node.attributes.iter().filter_map(|attribute| match &self.nodes[*attribute].kind {
+            Kind::Attribute(name, value) => Some((&name.qualified, value)),
+            _ => None,
+        });
+        for (name, value) in node.declarations.iter().map(|(name, value)| (name, value)).chain(attributes) {
+            element.fields.insert(format!("{}{}", options.attribute_prefix, name), Value::String(value.clone()));
+        }
+        for child in &node.children {
+            match &self.nodes[*child].kind {
+                // As the reader trims each text when converting
+                Kind::Text(text) => element.text.push_str(text.trim()),
+                Kind::Element(_) => {
+                    if let Some((name, value)) = self.entry(*child) {
+                        element.push(name, value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(element.into_value(&options))
+    }
+
+    /// A location path selecting only `id`
+    fn path(&self, id: usize) -> String {
+        let mut steps = Vec::new();
+        let mut node = id;
+        while let Some(parent) = self.nodes[node].parent {
+            let step = if let Kind::Attribute(name, _) = &self.nodes[node].kind { format!("@{}", name.qualified) } else {
+                let test = self.node_test(node);
+                let earlier = self.nodes[parent].children.iter().take_while(|child| **child != node);
+                let position = earlier.filter(|child| self.node_test(**child) == test).count() + 1;
+                format!("{test}[{position}]")
+            };
+            steps.push(step);
+            node = parent;
+        }
+        steps.reverse();
+        format!("/{}", steps.join("/"))
+    }
+
+    /// The node test of a step to a child like `id`
+    fn node_test(&self, id: usize) -> String {
+        match &self.nodes[id].kind {
+            Kind::Element(name) => name.qualified.clone(),
+            Kind::Text(_) => "text()".to_string(),
+            Kind::Comment(_) => "comment()".to_string(),
+            Kind::Instruction { target, .. } => format!("processing-instruction('{target}')"),
+            Kind::Root | Kind::Attribute(..) => String::new(),
+        }
+    }
+}
+
+/// Bytes of each attribute in a start tag, from its name to its closing quote
+fn attribute_spans(tag: &str) -> Vec<Range<usize>> {
+    let bytes = tag.as_bytes();
+    let mut spans = Vec::new();
+    // Past `<` and the element's name
+    let mut i = bytes.iter().position(|b| b.is_ascii_whitespace() || *b == b'/' || *b == b'>').unwrap_or(bytes.len());
+    loop {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if matches!(bytes.get(i), None | Some(b'/' | b'>')) {
+            return spans;
+        }
+        let start = i;
+        // Names hold no quotes, and values no quote of the kind around them
+        let Some(open) = bytes[i..].iter().position(|b| *b == b'"' || *b == b'\'').map(|at| i + at) else {
+            return spans;
+        };
+        let Some(close) = bytes[open + 1..].iter().position(|b| *b == bytes[open]).map(|at| open + 1 + at) else {
+            return spans;
+        };
+        i = close + 1;
+        spans.push(start..i);
+    }
+}
+
+/// An `XPath` string as a number: a decimal, perhaps negative, between whitespace, or NaN
+fn number_of(s: &str) -> f64 {
+    let s = s.trim_matches(is_whitespace);
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let decimal = digits.chars().any(|c| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && digits.matches('.').count() <= 1;
+    if decimal {
+        s.parse().unwrap_or(f64::NAN)
+    } else {
+        f64::NAN
+    }
+}
+
+/// A number as `XPath` writes it, without an exponent and with no `.0` on integers
+fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        let infinity = if n > 0.0 { "Infinity" } else { "-Infinity" };
+        infinity.to_string()
+    } else if n == 0.0 {
+        // Negative zero too
+        "0".to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// The integer nearest `n`, halves rounded up, as `round()` gives it
+fn round(n: f64) -> f64 {
+    if n.is_nan() || n.is_infinite() {
+        n
+    } else if (-0.5..0.0).contains(&n) {
+        -0.0
+    } else {
+        (n + 0.5).floor()
+    }
+}
+
+/// The characters of `s` from position `start`, counted from 1, for `length` of them or to the end
+#[allow(clippy::cast_precision_loss)] // Positions are far below 2^53
+fn substring(s: &str, start: f64, length: Option<f64>) -> String {
+    let first = round(start);
+    let end = length.map_or(f64::INFINITY, |length| first + round(length));
+    s.chars()
+        .enumerate()
+        .filter(|(i, _)| {
+            let position = (i + 1) as f64;
+            position >= first && position < end
+        })
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n')
+}
+
+/// The parts of `s` between whitespace
+fn tokens(s: &str) -> Vec<String> {
+    s.split(is_whitespace).filter(|part| !part.is_empty()).map(str::to_string).collect()
+}
+
+/// The value of an expression
+#[derive(Debug, Clone)]
+enum Object {
+    /// Nodes in document order
+    Nodes(Vec<usize>),
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Object {
+    fn boolean(&self) -> bool {
+        match self {
+            Self::Nodes(nodes) => !nodes.is_empty(),
+            Self::Boolean(b) => *b,
+            Self::Number(n) => *n != 0.0 && !n.is_nan(),
+            Self::String(s) => !s.is_empty(),
+        }
+    }
+
+    /// The nodes of a node-set, or an error that `need` one
+    fn into_nodes(self, need: &str) -> Result<Vec<usize>> {
+        let kind = match self {
+            Self::Nodes(nodes) => return Ok(nodes),
+            Self::Boolean(_) => "boolean",
+            Self::Number(_) => "number",
+            Self::String(_) => "string",
+        };
+        Err(anyhow!("{need}, not a {kind}"))
+    }
+}
+
+/// The node an expression is evaluated at, and its position among the nodes being filtered
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    node: usize,
+    position: usize,
+    size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    Ancestor,
+    AncestorOrSelf,
+    Attribute,
+    Child,
+    Descendant,
+    DescendantOrSelf,
+    Following,
+    FollowingSibling,
+    Parent,
+    Preceding,
+    PrecedingSibling,
+    SelfNode,
+}
+
+impl Axis {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "ancestor" => Self::Ancestor,
+            "ancestor-or-self" => Self::AncestorOrSelf,
+            "attribute" => Self::Attribute,
+            "child" => Self::Child,
+            "descendant" => Self::Descendant,
+            "descendant-or-self" => Self::DescendantOrSelf,
+            "following" => Self::Following,
+            "following-sibling" => Self::FollowingSibling,
+            "parent" => Self::Parent,
+            "preceding" => Self::Preceding,
+            "preceding-sibling" => Self::PrecedingSibling,
+            "self" => Self::SelfNode,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeTest {
+    /// `*`
+    Any,
+    /// `prefix:*`, by the namespace of the prefix
+    Namespace(String),
+    Name { namespace: Option<String>, local: String },
+    Node,
+    Text,
+    Comment,
+    /// `processing-instruction()`, with the target given, if any
+    Instruction(Option<String>),
+}
+
+#[derive(Debug)]
+struct Step {
+    axis: Axis,
+    test: NodeTest,
+    predicates: Vec<Expr>,
+}
+
+impl Step {
+    /// Every node on `axis`, as `.`, `..` and `//` abbreviate
+    fn any(axis: Axis) -> Self {
+        Self { axis, test: NodeTest::Node, predicates: Vec::new() }
+    }
+}
+
+/// Where a location path starts
+#[derive(Debug)]
+enum Start {
+    Root,
+    Context,
+    /// The nodes of a filter expression, as in `id('a')/b`
+    Nodes(Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// The comparison with its sides swapped
+    fn reversed(self) -> Self {
+        match self {
+            Self::Lt => Self::Gt,
+            Self::Le => Self::Ge,
+            Self::Gt => Self::Lt,
+            Self::Ge => Self::Le,
+            comparison => comparison,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Or,
+    And,
+    Compare(Comparison),
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl Operator {
+    /// The operator a token is where one is expected
+    fn of(token: &Token) -> Option<Self> {
+        Some(match token {
+            Token::Name(name) if name == "or" => Self::Or,
+            Token::Name(name) if name == "and" => Self::And,
+            Token::Name(name) if name == "div" => Self::Divide,
+            Token::Name(name) if name == "mod" => Self::Modulo,
+            Token::Compare(comparison) => Self::Compare(*comparison),
+            Token::Plus => Self::Add,
+            Token::Minus => Self::Subtract,
+            Token::Star => Self::Multiply,
+            _ => return None,
+        })
+    }
+
+    fn precedence(self) -> usize {
+        match self {
+            Self::Or => 1,
+            Self::And => 2,
+            Self::Compare(Comparison::Eq | Comparison::Ne) => 3,
+            Self::Compare(_) => 4,
+            Self::Add | Self::Subtract => 5,
+            Self::Multiply | Self::Divide | Self::Modulo => 6,
+        }
+    }
+
+    /// The arithmetic of an arithmetic operator, NaN for the others
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Self::Add => a + b,
+            Self::Subtract => a - b,
+            Self::Multiply => a * b,
+            Self::Divide => a / b,
+            Self::Modulo => a % b,
+            Self::Or | Self::And | Self::Compare(_) => f64::NAN,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Binary(Operator, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Union(Vec<Expr>),
+    Literal(String),
+    Number(f64),
+    Call(String, Vec<Expr>),
+    /// A primary expression filtered by predicates, in document order
+    Filter(Box<Expr>, Vec<Expr>),
+    Path(Start, Vec<Step>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A name, `local`, `prefix:local` or `prefix:*`, which the parser tells from an operator by where it is
+    Name(String),
+    Literal(String),
+    Number(f64),
+    Variable(String),
+    Star,
+    Slash,
+    DoubleSlash,
+    Dot,
+    DotDot,
+    At,
+    Comma,
+    ColonColon,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Pipe,
+    Plus,
+    Minus,
+    Compare(Comparison),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Self::Name(name) => format!("name {name}"),
+            Self::Literal(s) => format!("literal '{s}'"),
+            Self::Number(n) => format!("number {}", format_number(*n)),
+            Self::Variable(name) => format!("variable ${name}"),
+            token => format!("`{}`", token.symbol()),
+        }
+    }
+
+    /// The text of a punctuation token
+    fn symbol(&self) -> &'static str {
+        match self {
+            Self::Star => "*",
+            Self::Slash => "/",
+            Self::DoubleSlash => "//",
+            Self::Dot => ".",
+            Self::DotDot => "..",
+            Self::At => "@",
+            Self::Comma => ",",
+            Self::ColonColon => "::",
+            Self::LeftParen => "(",
+            Self::RightParen => ")",
+            Self::LeftBracket => "[",
+            Self::RightBracket => "]",
+            Self::Pipe => "|",
+            Self::Plus => "+",
+            Self::Minus => "-",
+            Self::Compare(Comparison::Eq) => "=",
+            Self::Compare(Comparison::Ne) => "!=",
+            Self::Compare(Comparison::Lt) => "<",
+            Self::Compare(Comparison::Le) => "<=",
+            Self::Compare(Comparison::Gt) => ">",
+            Self::Compare(Comparison::Ge) => ">=",
+            Self::Name(_) | Self::Literal(_) | Self::Number(_) | Self::Variable(_) => "",
+        }
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '\u{B7}')
+}
+
+/// The name at `chars[*i]`, `local`, `prefix:local` or `prefix:*`, moving past it
+fn name_at(chars: &[char], i: &mut usize) -> Option<String> {
+    let start = *i;
+    if !chars.get(start).copied().is_some_and(is_name_start) {
+        return None;
+    }
+    let local = |i: &mut usize| {
+        while chars.get(*i).copied().is_some_and(is_name_char) {
+            *i += 1;
+        }
+    };
+    local(i);
+    if chars.get(*i) == Some(&':') {
+        match chars.get(*i + 1).copied() {
+            Some('*') => *i += 2,
+            Some(c) if is_name_start(c) => {
+                *i += 1;
+                local(i);
+            }
+            _ => {}
+        }
+    }
+    Some(chars[start..*i].iter().collect())
+}
+
+/// The tokens of `expression`, each with the character it starts at
+fn lex(expression: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let error = |at: usize, message: &str| anyhow!("Invalid XPath at column {}: {}", at + 1, message);
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let token = match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                i += 1;
+                continue;
+            }
+            '0'..='9' | '.' if c != '.' || next.is_some_and(|c| c.is_ascii_digit()) => {
+                while chars.get(i).is_some_and(char::is_ascii_digit) {
+                    i += 1;
+                }
+                if chars.get(i) == Some(&'.') {
+                    i += 1;
+                    while chars.get(i).is_some_and(char::is_ascii_digit) {
+                        i += 1;
+                    }
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let number = digits.parse().map_err(|_| error(start, "expected a number"))?;
+                tokens.push((Token::Number(number), start));
+                continue;
+            }
+            '"' | '\'' => {
+                let end = chars[i + 1..].iter().position(|d| *d == c).ok_or_else(|| error(start, "unterminated literal"))? + i + 1;
+                tokens.push((Token::Literal(chars[i + 1..end].iter().collect()), start));
+                i = end + 1;
+                continue;
+            }
+            '$' => {
+                i += 1;
+                let name = name_at(&chars, &mut i).ok_or_else(|| error(start, "expected a variable name"))?;
+                tokens.push((Token::Variable(name), start));
+                continue;
+            }
+            c if is_name_start(c) => {
+                if let Some(name) = name_at(&chars, &mut i) {
+                    tokens.push((Token::Name(name), start));
+                }
+                continue;
+            }
+            '/' if next == Some('/') => Token::DoubleSlash,
+            '.' if next == Some('.') => Token::DotDot,
+            ':' if next == Some(':') => Token::ColonColon,
+            '!' if next == Some('=') => Token::Compare(Comparison::Ne),
+            '<' if next == Some('=') => Token::Compare(Comparison::Le),
+            '>' if next == Some('=') => Token::Compare(Comparison::Ge),
+            '/' => Token::Slash,
+            '.' => Token::Dot,
+            '@' => Token::At,
+            ',' => Token::Comma,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '|' => Token::Pipe,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '=' => Token::Compare(Comparison::Eq),
+            '<' => Token::Compare(Comparison::Lt),
+            '>' => Token::Compare(Comparison::Gt),
+            _ => return Err(error(start, &format!("unexpected character {c:?}"))),
+        };
+        let two = matches!(token, Token::DoubleSlash | Token::DotDot | Token::ColonColon)
+            || matches!(token, Token::Compare(Comparison::Ne | Comparison::Le | Comparison::Ge));
+        i += if two { 2 } else { 1 };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+    prefixes: &'a HashMap<String, String>,
+}
+
+impl Parser<'_> {
+    fn peek(&self, ahead: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + ahead).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos) {
+            Some((token, at)) => anyhow!("Invalid XPath at column {}: {}, found {}", at + 1, message, token.describe()),
+            None => anyhow!("Invalid XPath: {message}, found the end of the expression"),
+        }
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek(0) == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &Token, what: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {what}")))
+        }
+    }
+
+    fn nest(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(&format!("nested more than {MAX_DEPTH} deep")));
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<Expr> {
+        let depth = self.depth;
+        self.nest()?;
+        let expr = self.binary(1)?;
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    /// Operands joined by operators binding at least as tightly as `precedence`, the left first
+    fn binary(&mut self, precedence: usize) -> Result<Expr> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        while let Some(operator) = self.peek(0).and_then(Operator::of).filter(|operator| operator.precedence() >= precedence) {
+            self.pos += 1;
+            self.nest()?;
+            let right = self.binary(operator.precedence() + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let depth = self.depth;
+        let mut negations = 0;
+        while self.eat(&Token::Minus) {
+            self.nest()?;
+            negations += 1;
+        }
+        let mut expr = self.union()?;
+        for _ in 0..negations {
+            expr = Expr::Negate(Box::new(expr));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn union(&mut self) -> Result<Expr> {
+        let mut paths = vec![self.path()?];
+        while self.eat(&Token::Pipe) {
+            paths.push(self.path()?);
+        }
+        Ok(if paths.len() == 1 { paths.remove(0) } else { Expr::Union(paths) })
+    }
+
+    fn path(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Slash) {
+            // `/` alone is the root
+            let steps = if self.starts_step() { self.relative()? } else { Vec::new() };
+            return Ok(Expr::Path(Start::Root, steps));
+        }
+        if self.eat(&Token::DoubleSlash) {
+            return Ok(Expr::Path(Start::Root, self.descendants()?));
+        }
+        if !self.starts_primary() {
+            return Ok(Expr::Path(Start::Context, self.relative()?));
+        }
+        let mut filter = self.primary()?;
+        let mut predicates = Vec::new();
+        while self.peek(0) == Some(&Token::LeftBracket) {
+            predicates.push(self.predicate()?);
+        }
+        if !predicates.is_empty() {
+            filter = Expr::Filter(Box::new(filter), predicates);
+        }
+        if self.eat(&Token::Slash) {
+            Ok(Expr::Path(Start::Nodes(Box::new(filter)), self.relative()?))
+        } else if self.eat(&Token::DoubleSlash) {
+            Ok(Expr::Path(Start::Nodes(Box::new(filter)), self.descendants()?))
+        } else {
+            Ok(filter)
+        }
+    }
+
+    fn starts_step(&self) -> bool {
+        matches!(self.peek(0), Some(Token::Dot | Token::DotDot | Token::At | Token::Star | Token::Name(_)))
+    }
+
+    fn starts_primary(&self) -> bool {
+        match self.peek(0) {
+            Some(Token::Variable(_) | Token::LeftParen | Token::Literal(_) | Token::Number(_)) => true,
+            Some(Token::Name(name)) => self.peek(1) == Some(&Token::LeftParen) && !NODE_TYPES.contains(&name.as_str()),
+            _ => false,
+        }
+    }
+
+    /// The steps after `//`, which stands for a descendant-or-self step
+    fn descendants(&mut self) -> Result<Vec<Step>> {
+        let mut steps = vec![Step::any(Axis::DescendantOrSelf)];
+        steps.extend(self.relative()?);
+        Ok(steps)
+    }
+
+    fn relative(&mut self) -> Result<Vec<Step>> {
+        let mut steps = vec![self.step()?];
+        loop {
+            if self.eat(&Token::Slash) {
+                steps.push(self.step()?);
+            } else if self.eat(&Token::DoubleSlash) {
+                steps.push(Step::any(Axis::DescendantOrSelf));
+                steps.push(self.step()?);
+            } else {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Step> {
+        if self.eat(&Token::Dot) {
+            return Ok(Step::any(Axis::SelfNode));
+        }
+        if self.eat(&Token::DotDot) {
+            return Ok(Step::any(Axis::Parent));
+        }
+        let axis = if self.eat(&Token::At) {
+            Axis::Attribute
+        } else if let (Some(Token::Name(name)), Some(Token::ColonColon)) = (self.peek(0), self.peek(1)) {
+            let axis = match Axis::from_name(name) {
+                Some(axis) => axis,
+                None if name == "namespace" => return Err(self.error("the namespace axis is not supported")),
+                None => return Err(self.error("expected an axis")),
+            };
+            self.pos += 2;
+            axis
+        } else {
+            Axis::Child
+        };
+        let test = self.node_test()?;
+        let mut predicates = Vec::new();
+        while self.peek(0) == Some(&Token::LeftBracket) {
+            predicates.push(self.predicate()?);
+        }
+        Ok(Step { axis, test, predicates })
+    }
+
+    fn node_test(&mut self) -> Result<NodeTest> {
+        let test = match self.peek(0) {
+            Some(Token::Star) => NodeTest::Any,
+            Some(Token::Name(name)) if self.peek(1) == Some(&Token::LeftParen) => {
+                let test = match name.as_str() {
+                    "node" => NodeTest::Node,
+                    "text" => NodeTest::Text,
+                    "comment" => NodeTest::Comment,
+                    "processing-instruction" => NodeTest::Instruction(None),
+                    _ => return Err(self.error("expected a node test")),
+                };
+                self.pos += 2;
+                let test = match (test, self.peek(0)) {
+                    (NodeTest::Instruction(_), Some(Token::Literal(target))) => {
+                        let target = target.clone();
+                        self.pos += 1;
+                        NodeTest::Instruction(Some(target))
+                    }
+                    (test, _) => test,
+                };
+                self.expect(&Token::RightParen, ")")?;
+                return Ok(test);
+            }
+            Some(Token::Name(name)) => match name.split_once(':') {
+                Some((prefix, "*")) => NodeTest::Namespace(self.namespace(prefix)?),
+                Some((prefix, local)) => NodeTest::Name { namespace: Some(self.namespace(prefix)?), local: local.to_string() },
+                None => NodeTest::Name { namespace: None, local: name.clone() },
+            },
+            _ => return Err(self.error("expected a node test")),
+        };
+        self.pos += 1;
+        Ok(test)
+    }
+
+    fn namespace(&self, prefix: &str) -> Result<String> {
+        self.prefixes
+            .get(prefix)
+            .cloned()
+            .ok_or_else(|| self.error(&format!("namespace prefix '{prefix}' is not declared in the document")))
+    }
+
+    fn predicate(&mut self) -> Result<Expr> {
+        self.expect(&Token::LeftBracket, "[")?;
+        let predicate = self.expression()?;
+        self.expect(&Token::RightBracket, "]")?;
+        Ok(predicate)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = self.peek(0).cloned().ok_or_else(|| self.error("expected an expression"))?;
+        let expr = match token {
+            Token::Variable(name) => return Err(self.error(&format!("variable ${name} is not bound"))),
+            Token::LeftParen => {
+                self.pos += 1;
+                let expr = self.expression()?;
+                self.expect(&Token::RightParen, ")")?;
+                return Ok(expr);
+            }
+            Token::Literal(s) => Expr::Literal(s),
+            Token::Number(n) => Expr::Number(n),
+            Token::Name(name) => return self.call(name),
+            _ => return Err(self.error("expected an expression")),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr> {
+        let Some(&(_, fewest, most)) = FUNCTIONS.iter().find(|(function, ..)| *function == name) else {
+            return Err(self.error("unknown function"));
+        };
+        self.pos += 2;
+        let mut arguments = Vec::new();
+        if !self.eat(&Token::RightParen) {
+            loop {
+                arguments.push(self.expression()?);
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(&Token::RightParen, ", or )")?;
+        }
+        if arguments.len() < fewest || arguments.len() > most {
+            let takes = match (fewest, most) {
+                (0, 0) => "no arguments".to_string(),
+                (1, 1) => "1 argument".to_string(),
+                (fewest, usize::MAX) => format!("at least {fewest} arguments"),
+                (fewest, most) if fewest == most => format!("{fewest} arguments"),
+                (fewest, most) => format!("{fewest} to {most} arguments"),
+            };
+            bail!("Invalid XPath: {}() takes {}, not {}", name, takes, arguments.len());
+        }
+        Ok(Expr::Call(name, arguments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CONFIG: &str = r#"<?xml version="1.0"?>
+<config xmlns:ed="urn:editor" xml:lang="en-GB">
+  <!-- servers -->
+  <server name="api" port="80"><host>a.example</host></server>
+  <server name="db" port="5432" xml:id="primary"><host>b.example</host><ed:note>main</ed:note></server>
+  <?render fast?>
+  <limit>2.5</limit>
+</config>"#;
+
+    fn nodes(expression: &str) -> Vec<XPathMatch> {
+        match evaluate(CONFIG, expression).unwrap() {
+            XPathValue::Nodes(nodes) => nodes,
+            other => panic!("{expression} gave {other:?}"),
+        }
+    }
+
+    fn values(expression: &str) -> Vec<Value> {
+        nodes(expression).into_iter().map(|found| found.value).collect()
+    }
+
+    fn scalar(expression: &str) -> XPathValue {
+        evaluate(CONFIG, expression).unwrap()
+    }
+
+    #[test]
+    fn test_location_paths() {
+        let ports = nodes("/config/server/@port");
+        assert_eq!(ports.iter().map(|found| found.value.clone()).collect::<Vec<_>>(), [json!("80"), json!("5432")]);
+        assert_eq!(ports[1].path, "/config[1]/server[2]/@port");
+        assert_eq!(&CONFIG[ports[1].span.clone()], r#"port="5432""#);
+
+        let db = nodes("//server[@port > 100]");
+        assert_eq!(db.len(), 1);
+        assert!(CONFIG[db[0].span.clone()].starts_with("<server name=\"db\"") && CONFIG[db[0].span.clone()].ends_with("</server>"));
+        assert_eq!(
+            db[0].value,
+            json!({"server": {"@name": "db", "@port": "5432", "@xml:id": "primary", "host": "b.example", "ed:note": "main"}})
+        );
+
+        assert_eq!(values("//server[last()]/host/text()"), [json!("b.example")]);
+        assert_eq!(values("//host[. = 'a.example']/../@name"), [json!("api")]);
+        assert_eq!(values("//ed:note/ancestor::*/@name"), [json!("db")]);
+        assert_eq!(values("//ed:*"), [json!({"ed:note": "main"})]);
+        assert_eq!(values("//server[1]/following-sibling::*[1]/@name"), [json!("db")]);
+        assert_eq!(values("//limit/preceding-sibling::server[1]/@name"), [json!("db")]);
+        assert_eq!(values("(//server)[2]/@name | //server[1]/@name"), [json!("api"), json!("db")]);
+        assert_eq!(values("id('primary')/host"), [json!({"host": "b.example"})]);
+        assert_eq!(values("/config/comment()"), [json!(" servers ")]);
+        assert_eq!(values("//processing-instruction('render')"), [json!("fast")]);
+        assert_eq!(nodes("//processing-instruction()")[0].path, "/config[1]/processing-instruction('render')[1]");
+        assert_eq!(nodes("/")[0].span, 0..CONFIG.len());
+        assert!(nodes("//host")[1].value.is_object());
+        assert_eq!(nodes("//host/text()")[1].path, "/config[1]/server[2]/host[1]/text()[1]");
+        assert!(nodes("//server[@port = 1]").is_empty());
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(scalar("count(//server)"), XPathValue::Number(2.0));
+        assert_eq!(scalar("sum(//server/@port) div 2"), XPathValue::Number(2756.0));
+        assert_eq!(scalar("-//limit * 2 mod 3"), XPathValue::Number(-2.0));
+        assert_eq!(scalar("string(//limit + 1)"), XPathValue::String("3.5".to_string()));
+        assert_eq!(scalar("string(1 div 0)"), XPathValue::String("Infinity".to_string()));
+        assert_eq!(scalar("concat(name(//ed:note), ' ', local-name(//ed:note), ' ', namespace-uri(//ed:note))"), XPathValue::String("ed:note note urn:editor".to_string()));
+        assert_eq!(scalar("substring('12345', 1.5, 2.6)"), XPathValue::String("234".to_string()));
+        assert_eq!(scalar("substring-after(//server[2]/host, '.')"), XPathValue::String("example".to_string()));
+        assert_eq!(scalar("translate(normalize-space('  a  b '), 'ab', 'B')"), XPathValue::String("B ".to_string()));
+        assert_eq!(scalar("round(-0.5) = 0 and round(2.5) = 3 and floor(-1.5) = -2"), XPathValue::Boolean(true));
+        assert_eq!(scalar("boolean(//host[lang('en')]) and not(//host[lang('fr')])"), XPathValue::Boolean(true));
+        assert_eq!(scalar("//server/@port = '80' and //server/@port != 80"), XPathValue::Boolean(true));
+        assert_eq!(scalar("number('x') = number('x')"), XPathValue::Boolean(false));
+        assert_eq!(scalar("boolean(//missing) or starts-with(//host, 'a.')"), XPathValue::Boolean(true));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |expression: &str| evaluate(CONFIG, expression).unwrap_err().to_string();
+        assert_eq!(error("//server["), "Invalid XPath: expected a node test, found the end of the expression");
+        assert_eq!(error("//x:server"), "Invalid XPath at column 3: namespace prefix 'x' is not declared in the document, found name x:server");
+        assert_eq!(error("namespace::*"), "Invalid XPath at column 1: the namespace axis is not supported, found name namespace");
+        assert_eq!(error("$port"), "Invalid XPath at column 1: variable $port is not bound, found variable $port");
+        assert_eq!(error("frobnicate()"), "Invalid XPath at column 1: unknown function, found name frobnicate");
+        assert_eq!(error("concat('a')"), "Invalid XPath: concat() takes at least 2 arguments, not 1");
+        assert_eq!(error("count('a')"), "count() needs a node-set, not a string");
+        assert_eq!(error("/config]"), "Invalid XPath at column 8: unexpected `]`");
+        assert!(error(&format!("{}1{}", "(".repeat(200), ")".repeat(200))).contains("nested more than"));
+        assert!(evaluate("<a><b></a>", "/").unwrap_err().to_string().starts_with("Invalid XML"));
+    }
+}
@@ -0,0 +1,130 @@
+//! The events of libyaml, the parser beneath `serde_yaml`
+//!
+//! `serde_yaml` reads a document into values, losing where its aliases lie
+//! and what they name, and how each scalar was written. [`Parser`] reads
+//! the text again as libyaml's events, which keep both.
+
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+
+use super::Position;
+
+/// What a parser event is, with the anchor it gives a node
+pub(super) enum Kind {
+    StreamStart,
+    StreamEnd,
+    DocumentStart,
+    DocumentEnd,
+    Alias(String),
+    Scalar {
+        anchor: Option<String>,
+        value: String,
+        /// Whether the scalar was written plain, without quotes or a block indicator
+        plain: bool,
+        /// The tag given, its handle resolved, as `tag:yaml.org,2002:str` for `!!str`
+        tag: Option<String>,
+    },
+    SequenceStart(Option<String>),
+    SequenceEnd,
+    MappingStart(Option<String>),
+    MappingEnd,
+}
+
+/// An event of the parser, and where in the text it lies
+pub(super) struct Event {
+    pub kind: Kind,
+    pub span: Range<usize>,
+    pub start: Position,
+}
+
+/// libyaml's parser, reading `'a` text
+pub(super) struct Parser<'a> {
+    /// Boxed, as libyaml keeps pointers into it
+    sys: Box<MaybeUninit<unsafe_libyaml::yaml_parser_t>>,
+    text: PhantomData<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(text: &'a str) -> Option<Self> {
+        let mut sys = Box::new(MaybeUninit::<unsafe_libyaml::yaml_parser_t>::uninit());
+        let parser = sys.as_mut_ptr();
+        // SAFETY: the parser is initialized in place, and reads `text`, which outlives it
+        unsafe {
+            if unsafe_libyaml::yaml_parser_initialize(parser).fail {
+                return None;
+            }
+            unsafe_libyaml::yaml_parser_set_encoding(parser, unsafe_libyaml::YAML_UTF8_ENCODING);
+            unsafe_libyaml::yaml_parser_set_input_string(parser, text.as_ptr(), text.len() as u64);
+        }
+        Some(Self { sys, text: PhantomData })
+    }
+
+    /// The next event, `None` where the text fails to parse, after which there are none
+    #[allow(clippy::cast_possible_truncation)] // libyaml's sizes are this platform's
+    pub fn next(&mut self) -> Option<Event> {
+        let parser = self.sys.as_mut_ptr();
+        let mut event = MaybeUninit::<unsafe_libyaml::yaml_event_t>::uninit();
+        // SAFETY: the parser was initialized, and fills in the event unless it fails; the
+        // strings read are copied before the event is deleted
+        unsafe {
+            if unsafe_libyaml::yaml_parser_parse(parser, event.as_mut_ptr()).fail {
+                return None;
+            }
+            let event = event.as_mut_ptr();
+            let data = &(*event).data;
+            let kind = match (*event).type_ {
+                unsafe_libyaml::YAML_STREAM_START_EVENT => Kind::StreamStart,
+                unsafe_libyaml::YAML_DOCUMENT_START_EVENT => Kind::DocumentStart,
+                unsafe_libyaml::YAML_DOCUMENT_END_EVENT => Kind::DocumentEnd,
+                unsafe_libyaml::YAML_ALIAS_EVENT => Kind::Alias(string(data.alias.anchor).unwrap_or_default()),
+                unsafe_libyaml::YAML_SCALAR_EVENT => {
+                    let scalar = &data.scalar;
+                    let value = match scalar.length {
+                        0 => &[][..],
+                        length => std::slice::from_raw_parts(scalar.value, length as usize),
+                    };
+                    Kind::Scalar {
+                        anchor: string(scalar.anchor),
+                        value: String::from_utf8_lossy(value).into_owned(),
+                        plain: scalar.style == unsafe_libyaml::YAML_PLAIN_SCALAR_STYLE,
+                        tag: string(scalar.tag),
+                    }
+                }
+                unsafe_libyaml::YAML_SEQUENCE_START_EVENT => Kind::SequenceStart(string(data.sequence_start.anchor)),
+                unsafe_libyaml::YAML_SEQUENCE_END_EVENT => Kind::SequenceEnd,
+                unsafe_libyaml::YAML_MAPPING_START_EVENT => Kind::MappingStart(string(data.mapping_start.anchor)),
+                unsafe_libyaml::YAML_MAPPING_END_EVENT => Kind::MappingEnd,
+                _ => Kind::StreamEnd,
+            };
+            let (start, end) = ((*event).start_mark, (*event).end_mark);
+            unsafe_libyaml::yaml_event_delete(event);
+            Some(Event {
+                kind,
+                span: start.index as usize..end.index as usize,
+                start: Position { line: start.line as usize + 1, column: start.column as usize + 1 },
+            })
+        }
+    }
+}
+
+/// The string `text` points to, if any
+///
+/// # Safety
+///
+/// `text` must be null or point to a string ending in a null byte.
+unsafe fn string(text: *const u8) -> Option<String> {
+    if text.is_null() {
+        return None;
+    }
+    // SAFETY: as the caller ensures
+    Some(unsafe { CStr::from_ptr(text.cast()) }.to_string_lossy().into_owned())
+}
+
+impl Drop for Parser<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the parser was initialized, and is not used again
+        unsafe { unsafe_libyaml::yaml_parser_delete(self.sys.as_mut_ptr()) }
+    }
+}
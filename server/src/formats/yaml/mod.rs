@@ -0,0 +1,749 @@
+//! YAML format support for document conversion
+//!
+//! Mappings become JSON objects and sequences arrays. Keys that are other
+//! scalars, such as `1` or `true`, become the strings they read as; a key
+//! that is itself a mapping or sequence has no JSON form and is refused.
+//! Merge keys (`<<`) are applied and tags dropped, keeping the tagged value.
+//! Aliases are expanded into copies of the nodes their anchors name, and an
+//! alias within the node it names, which no copy could hold, is reported
+//! where it lies. [`References::Symbolic`] keeps them as JSON references
+//! instead, written back as anchors and aliases.
+//! Infinities and NaN, which JSON cannot hold, become the strings `.inf`,
+//! `-.inf` and `.nan`. Timestamps, plain scalars such as `2001-12-14
+//! 21:59:43.10 -5` or those tagged `!!timestamp`, become strings too, and
+//! [`timestamps`] finds them for the document model to read as date-times.
+//! Written from JSON, strings that would read back as timestamps are
+//! quoted, so they stay strings.
+//!
+//! A stream of several documents, such as a file of Kubernetes manifests,
+//! becomes a JSON array or NDJSON as [`YamlOptions`] choose, and either
+//! splits back into a stream.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use serde_yaml::Value as Yaml;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use super::datetime::DateTime;
+
+mod events;
+mod references;
+
+/// How a YAML stream of several documents maps onto JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    /// One document; a stream of several is refused, and a JSON array stays one document
+    #[default]
+    Single,
+    /// A JSON array with an item per document, split into a document per item
+    Array,
+    /// NDJSON, a line per document, split into a document per line
+    Ndjson,
+}
+
+/// How anchors, aliases and merge keys map onto JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum References {
+    /// Aliases become copies of the nodes they name, and merge keys are applied
+    #[default]
+    Expand,
+    /// Aliases become `{"$ref": "#/pointer"}` and merge keys stay, which become anchors and aliases again
+    Symbolic,
+}
+
+/// How YAML maps onto JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct YamlOptions {
+    /// How streams of several documents are read and written
+    pub stream: Stream,
+    /// How anchors and aliases are read and written
+    pub references: References,
+}
+
+/// Convert YAML to JSON
+///
+/// The text must hold a single document.
+pub fn yaml_to_json(yaml: &str) -> Result<String> {
+    yaml_to_json_with(yaml, &YamlOptions::default())
+}
+
+/// Convert YAML to JSON, reading a stream of documents as `options` choose
+///
+/// As a JSON array or NDJSON, empty documents, such as one after a
+/// trailing `---`, are left out.
+///
+/// # Errors
+///
+/// Fails where `yaml` does not parse, or has a key or value JSON has no
+/// form for.
+pub fn yaml_to_json_with(yaml: &str, options: &YamlOptions) -> Result<String> {
+    let documents = match options.references {
+        References::Expand => parse(yaml),
+        References::Symbolic => references::parse_symbolic(yaml),
+    };
+    let mut documents = documents.map_err(|diagnostic| anyhow!("Invalid YAML: {diagnostic}"))?;
+    if options.stream == Stream::Single {
+        if documents.len() > 1 {
+            return Err(anyhow!("JSON holds a single document, the YAML holds {}; convert it as a stream", documents.len()));
+        }
+        let document = documents.pop().unwrap_or(Yaml::Null);
+        return Ok(serde_json::to_string_pretty(&to_json(document, "")?)?);
+    }
+
+    let documents = documents
+        .into_iter()
+        .filter(|document| !document.is_null())
+        .enumerate()
+        .map(|(i, document)| to_json(document, &format!("[{i}]")))
+        .collect::<Result<Vec<_>>>()?;
+    if options.stream == Stream::Ndjson {
+        let lines = documents.iter().map(serde_json::to_string).collect::<Result<Vec<_>, _>>()?;
+        return Ok(lines.join("\n"));
+    }
+    Ok(serde_json::to_string_pretty(&Value::Array(documents))?)
+}
+
+/// Convert JSON to YAML
+pub fn json_to_yaml(json: &str) -> Result<String> {
+    json_to_yaml_with(json, &YamlOptions::default())
+}
+
+/// Convert JSON to YAML, splitting it into a stream of documents as `options` choose
+///
+/// With [`References::Symbolic`], the references of each document become
+/// anchors and aliases of the nodes they name.
+///
+/// # Errors
+///
+/// Fails where `json` does not parse, or is not a stream of documents as
+/// `options` say.
+pub fn json_to_yaml_with(json: &str, options: &YamlOptions) -> Result<String> {
+    let documents = match options.stream {
+        Stream::Single => vec![serde_json::from_str(json)?],
+        Stream::Array => match serde_json::from_str(json)? {
+            Value::Array(items) => items,
+            other => return Err(anyhow!("Only a JSON array splits into a YAML stream, not {}", json_kind(&other))),
+        },
+        Stream::Ndjson => json
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).map_err(|e| anyhow!("Invalid NDJSON: line {}: {}", i + 1, e)))
+            .collect::<Result<_>>()?,
+    };
+    let documents = documents.into_iter().map(|document| {
+        let yaml = match options.references {
+            References::Expand => serde_yaml::to_string(&from_json(document))?,
+            References::Symbolic => references::write(&document)?,
+        };
+        Ok(quote_timestamps(&yaml, &[]))
+    });
+    Ok(documents.collect::<Result<Vec<_>>>()?.join("---\n"))
+}
+
+/// Convert JSON to YAML, writing the strings at `timestamps`, by key and index, as timestamps
+pub(crate) fn json_to_yaml_with_timestamps(json: &str, timestamps: &[Vec<String>]) -> Result<String> {
+    let yaml = serde_yaml::to_string(&from_json(serde_json::from_str(json)?))?;
+    Ok(quote_timestamps(&yaml, timestamps))
+}
+
+/// `yaml`, written by `serde_yaml`, with the plain scalars that read as timestamps quoted, but for those at `keep`
+///
+/// `serde_yaml` quotes strings that would read back as numbers or booleans,
+/// but not those that would read as timestamps, which it has no type for.
+fn quote_timestamps(yaml: &str, keep: &[Vec<String>]) -> String {
+    let mut quoted = yaml.to_string();
+    for found in find_timestamps(yaml).into_iter().rev() {
+        if let Some(span) = found.span.filter(|_| !keep.contains(&found.path)) {
+            quoted.insert(span.end, '\'');
+            quoted.insert(span.start, '\'');
+        }
+    }
+    quoted
+}
+
+/// Whether `text`, written as a plain scalar, reads as a timestamp
+pub(crate) fn is_plain_timestamp(text: &str) -> bool {
+    is_timestamp(text, true, None)
+}
+
+/// Convert YAML to Markdown
+pub fn yaml_to_markdown(yaml: &str) -> Result<String> {
+    let json = yaml_to_json(yaml)?;
+    crate::core::ConversionCore::json_to_markdown(&json)
+}
+
+/// Convert Markdown to YAML
+pub fn markdown_to_yaml(markdown: &str) -> Result<String> {
+    let json = crate::core::ConversionRequest {
+        content: markdown.to_string(),
+        from: crate::core::Format::Markdown,
+        to: crate::core::Format::Json,
+    };
+    let response = crate::core::ConversionCore::convert(json)?;
+    json_to_yaml(&response.content)
+}
+
+/// Validate YAML syntax
+pub fn validate_yaml(yaml: &str) -> Result<Vec<String>> {
+    let mut diagnostics = Vec::new();
+
+    let found = self::diagnostics(yaml).into_iter().filter(|diagnostic| !diagnostic.is_duplicate_key());
+    diagnostics.extend(found.map(|diagnostic| format!("Invalid YAML: {diagnostic}")));
+
+    Ok(diagnostics)
+}
+
+/// A line and column in a YAML text, both from 1, the column counted in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A problem the YAML parser found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YamlDiagnostic {
+    /// The parser's description, without its position
+    pub message: String,
+    /// Where the problem lies, from the parser's position to the end of its line
+    /// and inclusive of both ends; `None` where the parser gives no position
+    pub span: Option<(Position, Position)>,
+}
+
+impl YamlDiagnostic {
+    /// Whether a mapping gives the same key twice, which the `yaml/no-duplicate-keys` rule reports
+    #[must_use]
+    pub fn is_duplicate_key(&self) -> bool {
+        self.message.starts_with("duplicate entry")
+    }
+}
+
+impl fmt::Display for YamlDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some((start, _)) => write!(f, "line {}, column {}: {}", start.line, start.column, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Problems parsing every document of `yaml`, empty when it is well formed
+///
+/// The parser stops at its first error, so there is at most one.
+#[must_use]
+pub fn diagnostics(yaml: &str) -> Vec<YamlDiagnostic> {
+    parse(yaml).err().into_iter().collect()
+}
+
+/// Describe a parse error, leading with its line and column where the parser gives a position
+pub(crate) fn error_message(e: &serde_yaml::Error) -> String {
+    diagnostic(None, e).to_string()
+}
+
+/// `text`, where known, is what was parsed, to find where the error's line ends
+fn diagnostic(text: Option<&str>, e: &serde_yaml::Error) -> YamlDiagnostic {
+    let message = e.to_string();
+    let Some(location) = e.location() else {
+        return YamlDiagnostic { message, span: None };
+    };
+    let start = Position { line: location.line(), column: location.column() };
+    // The message gives the location, given in the span instead
+    let message = message.replacen(&format!(" at line {} column {}", start.line, start.column), "", 1);
+    let length = text.and_then(|text| text.lines().nth(start.line - 1)).map_or(0, |line| line.chars().count());
+    let end = Position { column: length.max(start.column), ..start };
+    YamlDiagnostic { message, span: Some((start, end)) }
+}
+
+/// Every document of `yaml`, with aliases expanded and merge keys applied
+fn parse(yaml: &str) -> Result<Vec<Yaml>, YamlDiagnostic> {
+    let documents: Result<Vec<_>, YamlDiagnostic> = serde_yaml::Deserializer::from_str(yaml)
+        .map(|document| {
+            let mut value = Yaml::deserialize(document).map_err(|e| diagnostic(Some(yaml), &e))?;
+            value.apply_merge().map_err(|e| diagnostic(Some(yaml), &e))?;
+            Ok(value)
+        })
+        .collect();
+    // serde_yaml expands an alias within the node it names until it nests too deep
+    documents.map_err(|diagnostic| {
+        if diagnostic.message.starts_with("recursion limit exceeded") {
+            references::cycle(yaml).unwrap_or(diagnostic)
+        } else {
+            diagnostic
+        }
+    })
+}
+
+/// The documents of `yaml` that are not empty, as JSON, as [`Stream::Array`] reads them
+pub(crate) fn non_empty_documents(yaml: &str) -> Result<Vec<Value>> {
+    let documents = parse(yaml).map_err(|diagnostic| anyhow!("Invalid YAML: {diagnostic}"))?;
+    documents.into_iter().filter(|document| !document.is_null()).map(|document| to_json(document, "")).collect()
+}
+
+/// A collection being read by [`timestamps`]
+struct Open {
+    mapping: bool,
+    /// Nodes read within it so far, keys and values alike in a mapping
+    children: usize,
+    /// The text of the key of the entry being read, in a mapping
+    key: String,
+    /// The anchor it is given
+    anchor: Option<String>,
+    /// How many timestamps were found before it
+    first: usize,
+    /// The keys of its entries, in a mapping, apart from merge keys
+    explicit: Vec<String>,
+    /// The keys of the entries merge keys bring in, in a mapping
+    merged: Vec<String>,
+}
+
+impl Open {
+    fn new(mapping: bool, anchor: Option<String>, first: usize) -> Self {
+        Self { mapping, children: 0, key: String::new(), anchor, first, explicit: Vec::new(), merged: Vec::new() }
+    }
+}
+
+/// A timestamp found by [`find_timestamps`]
+struct Found {
+    path: Vec<String>,
+    /// Where it lies in the text, when it is a plain scalar rather than an alias of one
+    span: Option<Range<usize>>,
+    /// The depths of the open mappings a merge key brings it into, which their own entries override
+    merged_into: Vec<usize>,
+}
+
+/// What [`find_timestamps`] keeps of an anchored node for its aliases
+#[derive(Default)]
+struct Anchored {
+    /// The paths of its timestamps within it
+    within: Vec<Vec<String>>,
+    /// Its text, if a scalar
+    text: Option<String>,
+    /// The keys of its entries, if a mapping
+    keys: Vec<String>,
+}
+
+/// The paths, by key and index, of the timestamps in the first document of `yaml`
+///
+/// A timestamp is a date, with a time or not, written plain and untagged,
+/// or tagged `!!timestamp`, which `serde_yaml` reads as a string. Aliases of
+/// timestamps, or of collections holding them, give their own paths, and
+/// so do those a merge key (`<<`) brings in, unless the mapping gives the
+/// key itself or an earlier mapping merged brings it in first.
+pub(crate) fn timestamps(yaml: &str) -> Vec<Vec<String>> {
+    find_timestamps(yaml).into_iter().map(|found| found.path).collect()
+}
+
+fn find_timestamps(yaml: &str) -> Vec<Found> {
+    let mut found: Vec<Found> = Vec::new();
+    let Some(mut parser) = events::Parser::new(yaml) else {
+        return found;
+    };
+    let mut anchors: HashMap<String, Anchored> = HashMap::new();
+    let mut open: Vec<Open> = Vec::new();
+    while let Some(event) = parser.next() {
+        let in_key = open.last().is_some_and(|collection| collection.mapping && collection.children % 2 == 0);
+        // Where a node starting here lies; the entries a merge key brings in lie in the mapping holding it
+        let path = |open: &[Open]| {
+            let mut path = Vec::new();
+            for (depth, collection) in open.iter().enumerate() {
+                let merging = depth > 0 && open[depth - 1].mapping && open[depth - 1].key == "<<";
+                if collection.mapping && collection.key == "<<" || !collection.mapping && merging {
+                    continue;
+                }
+                path.push(if collection.mapping { collection.key.clone() } else { collection.children.to_string() });
+            }
+            path
+        };
+        // The mappings a merge key brings a node starting here into
+        let merged_into = |open: &[Open]| (0..open.len()).filter(|&depth| open[depth].mapping && open[depth].key == "<<").collect();
+        // The mapping a node starting here is merged into, if it is one merged whole
+        let merge_target = |open: &[Open]| match open {
+            [.., target, last] if !last.mapping && target.mapping && target.key == "<<" => Some(open.len() - 2),
+            [.., last] if last.mapping && last.key == "<<" => Some(open.len() - 1),
+            _ => None,
+        };
+        match event.kind {
+            events::Kind::DocumentEnd | events::Kind::StreamEnd => break,
+            events::Kind::Scalar { anchor, value, .. } if in_key => {
+                if let Some(anchor) = anchor {
+                    anchors.insert(anchor, Anchored { text: Some(value.clone()), ..Default::default() });
+                }
+                if let Some(collection) = open.last_mut() {
+                    collection.key = value;
+                }
+            }
+            events::Kind::Scalar { anchor, value, plain, tag } => {
+                let timestamp = is_timestamp(&value, plain, tag.as_deref());
+                if timestamp {
+                    let span = (plain && tag.is_none()).then_some(event.span);
+                    found.push(Found { path: path(&open), span, merged_into: merged_into(&open) });
+                }
+                if let Some(anchor) = anchor {
+                    let within = if timestamp { vec![Vec::new()] } else { Vec::new() };
+                    anchors.insert(anchor, Anchored { within, text: Some(value), keys: Vec::new() });
+                }
+            }
+            events::Kind::Alias(name) => {
+                let Some(anchored) = anchors.get(&name) else { continue };
+                if in_key {
+                    if let (Some(collection), Some(text)) = (open.last_mut(), &anchored.text) {
+                        collection.key.clone_from(text);
+                    }
+                } else {
+                    let (path, merged_into, start) = (path(&open), merged_into(&open), found.len());
+                    found.extend(anchored.within.iter().map(|inner| Found {
+                        path: path.iter().chain(inner).cloned().collect(),
+                        span: None,
+                        merged_into: merged_into.clone(),
+                    }));
+                    if let Some(target) = merge_target(&open) {
+                        let keys = anchored.keys.clone();
+                        merge(&mut open, target, keys, &mut found, start, &path);
+                    }
+                }
+            }
+            events::Kind::SequenceStart(anchor) => {
+                open.push(Open::new(false, anchor, found.len()));
+                continue;
+            }
+            events::Kind::MappingStart(anchor) => {
+                open.push(Open::new(true, anchor, found.len()));
+                continue;
+            }
+            events::Kind::SequenceEnd | events::Kind::MappingEnd => {
+                let Some(mut collection) = open.pop() else { break };
+                let path = path(&open);
+                let depth = open.len();
+                override_merged(&collection, depth, &path, &mut found);
+                let mut keys = std::mem::take(&mut collection.explicit);
+                let merged: Vec<String> = collection.merged.into_iter().filter(|key| !keys.contains(key)).collect();
+                keys.extend(merged);
+                if let Some(anchor) = collection.anchor {
+                    let within = found[collection.first..].iter().map(|inner| inner.path.iter().skip(path.len()).cloned().collect()).collect();
+                    anchors.insert(anchor, Anchored { within, text: None, keys: keys.clone() });
+                }
+                if let Some(target) = merge_target(&open).filter(|_| collection.mapping) {
+                    merge(&mut open, target, keys, &mut found, collection.first, &path);
+                }
+            }
+            events::Kind::StreamStart | events::Kind::DocumentStart => continue,
+        }
+        if let Some(parent) = open.last_mut() {
+            if parent.mapping && parent.children % 2 == 0 && parent.key != "<<" && !parent.explicit.contains(&parent.key) {
+                parent.explicit.push(parent.key.clone());
+            }
+            parent.children += 1;
+        }
+    }
+    found
+}
+
+/// Drop the timestamps merged into `collection`, open at `depth` and lying at `path`, under keys it gives itself
+///
+/// A mapping's own entries override those a merge key brings in, wherever
+/// they lie in it.
+fn override_merged(collection: &Open, depth: usize, path: &[String], found: &mut Vec<Found>) {
+    let within = found.split_off(collection.first);
+    found.extend(within.into_iter().filter_map(|mut inner| {
+        let merged = inner.merged_into.last() == Some(&depth);
+        if merged {
+            inner.merged_into.pop();
+        }
+        let overridden = merged && inner.path.get(path.len()).is_some_and(|key| collection.explicit.contains(key));
+        (!overridden).then_some(inner)
+    }));
+}
+
+/// Bring a mapping with `keys`, whose timestamps are those from `start` on, into the mapping open at `target` through its merge key
+///
+/// The timestamps under keys an earlier mapping merged brought in already
+/// are dropped, as the first mapping merged gives each key.
+fn merge(open: &mut [Open], target: usize, keys: Vec<String>, found: &mut Vec<Found>, start: usize, path: &[String]) {
+    let merged = &mut open[target].merged;
+    let brought = found.split_off(start);
+    found.extend(brought.into_iter().filter(|inner| !inner.path.get(path.len()).is_some_and(|key| merged.contains(key))));
+    for key in keys {
+        if !merged.contains(&key) {
+            merged.push(key);
+        }
+    }
+}
+
+/// Whether a scalar is a YAML timestamp: a date, with a time and offset or neither
+fn is_timestamp(value: &str, plain: bool, tag: Option<&str>) -> bool {
+    let typed = match tag {
+        None => plain,
+        Some(tag) => tag == "tag:yaml.org,2002:timestamp",
+    };
+    typed && DateTime::parse(value).is_some_and(|datetime| datetime.date.is_some() && (datetime.time.is_some() || datetime.offset.is_none()))
+}
+
+/// `path` locates `value` in the document, for errors
+fn to_json(value: Yaml, path: &str) -> Result<Value> {
+    Ok(match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Value::from(i),
+            (None, Some(u)) => Value::from(u),
+            // JSON has no infinities or NaN
+            (None, None) => n.as_f64().and_then(Number::from_f64).map_or_else(|| Value::String(n.to_string()), Value::Number),
+        },
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(items) => {
+            let items = items.into_iter().enumerate().map(|(i, item)| to_json(item, &format!("{path}[{i}]")));
+            Value::Array(items.collect::<Result<_>>()?)
+        }
+        Yaml::Mapping(mapping) => {
+            let mut map = Map::new();
+            for (key, value) in mapping {
+                let key = key_string(key, path)?;
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                let value = to_json(value, &path)?;
+                if map.insert(key, value).is_some() {
+                    return Err(anyhow!("Key '{path}' appears twice once keys are strings"));
+                }
+            }
+            Value::Object(map)
+        }
+        Yaml::Tagged(tagged) => to_json(tagged.value, path)?,
+    })
+}
+
+/// The JSON object key for `key`, a key of the mapping at `path`
+fn key_string(key: Yaml, path: &str) -> Result<String> {
+    Ok(match key {
+        Yaml::Null => "null".to_string(),
+        Yaml::Bool(b) => b.to_string(),
+        Yaml::Number(n) => n.to_string(),
+        Yaml::String(s) => s,
+        Yaml::Tagged(tagged) => key_string(tagged.value, path)?,
+        Yaml::Sequence(_) | Yaml::Mapping(_) => {
+            let at = if path.is_empty() { "the top level".to_string() } else { format!("'{path}'") };
+            return Err(anyhow!("JSON keys are strings, found a {} key at {}", kind(&key), at));
+        }
+    })
+}
+
+pub(crate) fn from_json(value: Value) -> Yaml {
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Bool(b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Yaml::from(i),
+            (None, Some(u)) => Yaml::from(u),
+            (None, None) => Yaml::from(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => Yaml::String(s),
+        Value::Array(items) => Yaml::Sequence(items.into_iter().map(from_json).collect()),
+        Value::Object(map) => Yaml::Mapping(map.into_iter().map(|(key, value)| (Yaml::String(key), from_json(value))).collect()),
+    }
+}
+
+fn json_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn kind(value: &Yaml) -> &'static str {
+    match value {
+        Yaml::Sequence(_) => "sequence",
+        Yaml::Mapping(_) => "mapping",
+        _ => "scalar",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_value(yaml: &str) -> Value {
+        serde_json::from_str(&yaml_to_json(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_yaml_to_json() {
+        let yaml = "key: value";
+        assert_eq!(to_value(yaml), json!({"key": "value"}));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let yaml = "labels:\n  canary: false\n  tier: web\nname: deploy\nports:\n- 80\n- 443\nreplicas: 3\n";
+        let json = yaml_to_json(yaml).unwrap();
+        assert_eq!(json_to_yaml(&json).unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_nested_maps_sequences_and_scalars() {
+        let yaml = "\
+service:
+  name: web
+  ports:
+    - 80
+    - port: 443
+      tls: true
+  weight: 0.25
+  owner: ~
+  big: 18446744073709551615
+  limit: .inf
+  quoted: '42'
+";
+        assert_eq!(
+            to_value(yaml),
+            json!({"service": {
+                "name": "web",
+                "ports": [80, {"port": 443, "tls": true}],
+                "weight": 0.25,
+                "owner": null,
+                "big": 18_446_744_073_709_551_615u64,
+                "limit": ".inf",
+                "quoted": "42",
+            }})
+        );
+    }
+
+    #[test]
+    fn test_keys_merges_and_tags() {
+        let yaml = "\
+base: &base
+  retries: 3
+job:
+  <<: *base
+  1: one
+  true: yes
+  stamp: !timestamp 2024-01-01
+";
+        assert_eq!(
+            to_value(yaml),
+            json!({
+                "base": {"retries": 3},
+                "job": {"retries": 3, "1": "one", "true": "yes", "stamp": "2024-01-01"},
+            })
+        );
+
+        let error = yaml_to_json("outer:\n  ? [a, b]\n  : pair\n").unwrap_err().to_string();
+        assert!(error.contains("sequence key at 'outer'"), "{error}");
+        let error = yaml_to_json("1: a\n'1': b\n").unwrap_err().to_string();
+        assert!(error.contains("'1' appears twice"), "{error}");
+        assert!(yaml_to_json("a: 1\n---\nb: 2\n").unwrap_err().to_string().contains("holds 2"));
+    }
+
+    #[test]
+    fn test_streams() {
+        let yaml = "---\nkind: Service\nmetadata: {name: web}\n---\nkind: Deployment\n---\n";
+        let options = |stream| YamlOptions { stream, ..YamlOptions::default() };
+
+        let array = yaml_to_json_with(yaml, &options(Stream::Array)).unwrap();
+        let value: Value = serde_json::from_str(&array).unwrap();
+        assert_eq!(value, json!([{"kind": "Service", "metadata": {"name": "web"}}, {"kind": "Deployment"}]));
+        let ndjson = yaml_to_json_with(yaml, &options(Stream::Ndjson)).unwrap();
+        assert_eq!(ndjson, "{\"kind\":\"Service\",\"metadata\":{\"name\":\"web\"}}\n{\"kind\":\"Deployment\"}");
+        let error = yaml_to_json(yaml).unwrap_err().to_string();
+        assert!(error.contains("holds 3; convert it as a stream"), "{error}");
+
+        // Either splits back into the same stream
+        let stream = "kind: Service\nmetadata:\n  name: web\n---\nkind: Deployment\n";
+        assert_eq!(json_to_yaml_with(&array, &options(Stream::Array)).unwrap(), stream);
+        assert_eq!(json_to_yaml_with(&ndjson, &options(Stream::Ndjson)).unwrap(), stream);
+        assert_eq!(json_to_yaml("[1, 2]").unwrap(), "- 1\n- 2\n");
+        assert!(json_to_yaml_with("{}", &options(Stream::Array)).unwrap_err().to_string().contains("not an object"));
+        let error = json_to_yaml_with("{}\n{", &options(Stream::Ndjson)).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid NDJSON: line 2"), "{error}");
+
+        // Errors name the document they are in
+        let error = yaml_to_json_with("a: 1\n---\n? [b]\n: c\n", &options(Stream::Array)).unwrap_err().to_string();
+        assert!(error.contains("key at '[1]'"), "{error}");
+    }
+
+    #[test]
+    fn test_references() {
+        let yaml = "base: &base\n  retries: 3\njob:\n  <<: *base\n  name: build\n";
+        assert_eq!(to_value(yaml)["job"], json!({"retries": 3, "name": "build"}));
+        let symbolic = YamlOptions { references: References::Symbolic, ..YamlOptions::default() };
+        let json = yaml_to_json_with(yaml, &symbolic).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, json!({"base": {"retries": 3}, "job": {"<<": {"$ref": "#/base"}, "name": "build"}}));
+        assert_eq!(json_to_yaml_with(&json, &symbolic).unwrap(), yaml);
+        assert_eq!(json_to_yaml(&json).unwrap(), "base:\n  retries: 3\njob:\n  <<:\n    $ref: '#/base'\n  name: build\n");
+
+        // An alias within the node it names has no expansion
+        let error = yaml_to_json("a: &a\n  b: [*a]\n").unwrap_err().to_string();
+        assert_eq!(error, "Invalid YAML: line 2, column 7: alias *a lies within the node anchored &a, which cannot contain itself");
+        assert_eq!(validate_yaml("- &a {b: *a}\n").unwrap().len(), 1);
+        assert!(yaml_to_json_with("a: &a\n  b: [*a]\n", &symbolic).is_ok());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let yaml = "a: 2001-12-14 21:59:43.10 -5\nb: '2001-12-14'\nc: [!!timestamp 2002-12-14, 12:30:00, 2002-12-14-05:00]\n\
+                    d: &d 2024-01-31\ne: *d\nbase: &base {f: 2024-01-01}\njob:\n  <<: *base\n---\ng: 2024-01-01\n";
+        let path = |path: &[&str]| path.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            timestamps(yaml),
+            [path(&["a"]), path(&["c", "0"]), path(&["d"]), path(&["e"]), path(&["base", "f"]), path(&["job", "f"])]
+        );
+        assert!(timestamps("a: [1\n").is_empty());
+
+        // The mapping's own entries, and those of the first mapping merged, override those merged in
+        let yaml = "base: &base {f: 2024-01-01, g: 2024-01-02}\nother: &other {g: text}\n\
+                    a:\n  <<: *base\n  f: '2024-01-01'\nb:\n  f: 1\n  <<: [*other, *base]\nc:\n  <<: {h: 2024-01-01}\n  h: [2024-01-01]\n";
+        assert_eq!(
+            timestamps(yaml),
+            [path(&["base", "f"]), path(&["base", "g"]), path(&["a", "g"]), path(&["c", "h", "0"])]
+        );
+    }
+
+    #[test]
+    fn test_validate_yaml_reports_position() {
+        let diagnostics = validate_yaml("key: value\nlist: [1, 2\n").unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert!(diagnostics[0].starts_with("Invalid YAML: line "), "{}", diagnostics[0]);
+        assert!(validate_yaml("key: value").unwrap().is_empty());
+
+        let diagnostics = super::diagnostics("a: 1\nb: [c\nd: 2\n");
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        let (start, end) = diagnostics[0].span.unwrap();
+        assert_eq!((start.line, end.line), (3, 3));
+        assert!(start.column <= end.column && end.column == 4, "{diagnostics:?}");
+        assert!(!diagnostics[0].message.contains("at line 3 column 2"), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_validate_yaml_tabs() {
+        // Tabs may not indent, but may separate a value from its key
+        let diagnostics = validate_yaml("key:\n\t- value\n").unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert!(diagnostics[0].starts_with("Invalid YAML: line 2, column 1: "), "{}", diagnostics[0]);
+        assert!(validate_yaml("key:\tvalue").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_yaml_leaves_rules_to_lint() {
+        // Empty documents and duplicate keys are lint rules'
+        assert!(validate_yaml("   ").unwrap().is_empty());
+        assert!(validate_yaml("a: 1\na: 2\n").unwrap().is_empty());
+        assert!(diagnostics("a: 1\na: 2\n")[0].is_duplicate_key());
+    }
+
+    #[test]
+    fn test_validate_yaml_checks_every_document() {
+        assert!(validate_yaml("a: 1\n---\nb: 2\n").unwrap().is_empty());
+        let diagnostics = validate_yaml("a: 1\n---\nb: [2\n").unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    }
+}
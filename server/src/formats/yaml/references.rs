@@ -0,0 +1,528 @@
+//! Anchors and aliases kept as references
+//!
+//! `serde_yaml` expands aliases as it reads, so where they lie is read here
+//! from the [`events`](super::events) of libyaml, the parser beneath it. Read
+//! [`Symbolic`](super::References::Symbolic)ally, an alias becomes a JSON
+//! reference, `{"$ref": "#/base"}`, to the JSON pointer of the node its
+//! anchor names within the same document, and merge keys are left
+//! unapplied, under the key `<<`. Written back, each node a reference names
+//! is anchored where it first appears and aliased wherever else it does. A
+//! reference that comes before the node it names is written as that node,
+//! which reads back in its place, with a reference where the node was.
+//!
+//! An alias within the node its anchor names, as in `a: &a [*a]`, makes a
+//! node that contains itself. Kept as a reference it reads as any other,
+//! but it cannot be expanded, and [`cycle`] says where it lies.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use serde_yaml::{Mapping, Value as Yaml};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::ops::Range;
+
+use super::events::{Kind, Parser};
+use super::{from_json, Position, YamlDiagnostic};
+
+/// Starts the strings aliases are replaced by while `serde_yaml` reads a document, followed by the alias's index
+const MARKER: &str = "\0*";
+
+/// A step from a collection to one of its nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// The item of a sequence at an index
+    Item(usize),
+    /// The value of the entry of a mapping at an index
+    Entry(usize),
+}
+
+/// A node given an anchor
+#[derive(Debug)]
+struct Anchor {
+    document: usize,
+    path: Vec<Step>,
+    /// Whether the node is a key, or within one, which has no JSON pointer
+    in_key: bool,
+}
+
+/// An alias of an anchored node
+#[derive(Debug)]
+struct Alias {
+    name: String,
+    /// Index of the anchor among those read, `None` where no node before it has the name
+    anchor: Option<usize>,
+    span: Range<usize>,
+    start: Position,
+    in_key: bool,
+    /// Whether the node anchored contains the alias
+    cycle: bool,
+}
+
+/// The anchors and aliases of every document of a stream, in the order they appear
+#[derive(Debug, Default)]
+struct References {
+    anchors: Vec<Anchor>,
+    aliases: Vec<Alias>,
+}
+
+/// Where an alias lies within the node its anchor names, which would have to contain itself to expand
+pub(super) fn cycle(yaml: &str) -> Option<YamlDiagnostic> {
+    let references = read(yaml)?;
+    let alias = references.aliases.into_iter().find(|alias| alias.cycle)?;
+    let end = Position { column: alias.start.column + alias.name.chars().count(), ..alias.start };
+    let message = format!("alias *{0} lies within the node anchored &{0}, which cannot contain itself", alias.name);
+    Some(YamlDiagnostic { message, span: Some((alias.start, end)) })
+}
+
+/// Every document of `yaml`, its aliases as references and its merge keys unapplied
+pub(super) fn parse_symbolic(yaml: &str) -> Result<Vec<Yaml>, YamlDiagnostic> {
+    // Where libyaml fails, serde_yaml fails alike, and says why
+    let Some(references) = read(yaml) else {
+        return super::parse(yaml);
+    };
+    let mut text = String::with_capacity(yaml.len());
+    let mut last = 0;
+    for (i, alias) in references.aliases.iter().enumerate() {
+        let anchor = alias.anchor.map(|anchor| &references.anchors[anchor]);
+        // An alias in a key, or of one, stays to be expanded: JSON keys are strings
+        if alias.in_key || anchor.is_none_or(|anchor| anchor.in_key) {
+            continue;
+        }
+        text.push_str(&yaml[last..alias.span.start]);
+        let _ = write!(text, "\"\\0*{i}\"");
+        last = alias.span.end;
+    }
+    text.push_str(&yaml[last..]);
+
+    let mut documents = serde_yaml::Deserializer::from_str(&text)
+        .map(|document| Yaml::deserialize(document).map_err(|e| super::diagnostic(Some(&text), &e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let pointers: Vec<Option<String>> = references
+        .anchors
+        .iter()
+        .map(|anchor| documents.get(anchor.document).and_then(|document| pointer(document, &anchor.path)))
+        .collect();
+    for document in &mut documents {
+        replace_markers(document, &|i| references.aliases[i].anchor.and_then(|anchor| pointers[anchor].clone()));
+    }
+    Ok(documents)
+}
+
+/// Replace the markers standing for aliases within `value` by references to the pointers `target` gives them
+fn replace_markers(value: &mut Yaml, target: &dyn Fn(usize) -> Option<String>) {
+    match value {
+        Yaml::String(s) => {
+            let Some(pointer) = s.strip_prefix(MARKER).and_then(|i| i.parse().ok()).and_then(target) else {
+                return;
+            };
+            let mut reference = Mapping::new();
+            reference.insert(Yaml::String("$ref".to_string()), Yaml::String(format!("#{pointer}")));
+            *value = Yaml::Mapping(reference);
+        }
+        Yaml::Sequence(items) => items.iter_mut().for_each(|item| replace_markers(item, target)),
+        Yaml::Mapping(mapping) => mapping.values_mut().for_each(|value| replace_markers(value, target)),
+        Yaml::Tagged(tagged) => replace_markers(&mut tagged.value, target),
+        Yaml::Null | Yaml::Bool(_) | Yaml::Number(_) => {}
+    }
+}
+
+/// The JSON pointer of the node `path` leads to from `document`
+fn pointer(document: &Yaml, path: &[Step]) -> Option<String> {
+    let mut node = document;
+    let mut pointer = String::new();
+    for step in path {
+        while let Yaml::Tagged(tagged) = node {
+            node = &tagged.value;
+        }
+        node = match (step, node) {
+            (Step::Item(i), Yaml::Sequence(items)) => {
+                let _ = write!(pointer, "/{i}");
+                items.get(*i)?
+            }
+            (Step::Entry(i), Yaml::Mapping(mapping)) => {
+                let (key, value) = mapping.iter().nth(*i)?;
+                let key = super::key_string(key.clone(), "").ok()?;
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                value
+            }
+            _ => return None,
+        };
+    }
+    Some(pointer)
+}
+
+/// A collection being read
+struct Open {
+    mapping: bool,
+    /// Nodes read within it so far, keys and values alike in a mapping
+    children: usize,
+    anchor: Option<usize>,
+}
+
+/// The anchors and aliases of `yaml`, `None` where it does not parse
+fn read(yaml: &str) -> Option<References> {
+    let mut parser = Parser::new(yaml)?;
+    let mut references = References::default();
+    let mut document = 0;
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    let mut open: Vec<Open> = Vec::new();
+    loop {
+        let event = parser.next()?;
+        // Where a node starting here lies: the steps to it, and whether it is in a key
+        let path = || {
+            let steps = open.iter().map(|collection| {
+                if collection.mapping {
+                    Step::Entry(collection.children / 2)
+                } else {
+                    Step::Item(collection.children)
+                }
+            });
+            let in_key = open.iter().any(|collection| collection.mapping && collection.children % 2 == 0);
+            (steps.collect::<Vec<_>>(), in_key)
+        };
+        let mut anchor = |name: Option<String>, references: &mut References| {
+            let name = name?;
+            let (path, in_key) = path();
+            references.anchors.push(Anchor { document, path, in_key });
+            defined.insert(name, references.anchors.len() - 1);
+            Some(references.anchors.len() - 1)
+        };
+        let ended = match event.kind {
+            Kind::StreamEnd => return Some(references),
+            Kind::DocumentStart => {
+                defined.clear();
+                false
+            }
+            Kind::DocumentEnd => {
+                document += 1;
+                false
+            }
+            Kind::Alias(name) => {
+                let (_, in_key) = path();
+                let anchor = defined.get(&name).copied();
+                let cycle = anchor.is_some() && open.iter().any(|collection| collection.anchor == anchor);
+                references.aliases.push(Alias { name, anchor, span: event.span, start: event.start, in_key, cycle });
+                true
+            }
+            Kind::Scalar { anchor: name, .. } => {
+                anchor(name, &mut references);
+                true
+            }
+            Kind::SequenceStart(name) => {
+                let anchor = anchor(name, &mut references);
+                open.push(Open { mapping: false, children: 0, anchor });
+                false
+            }
+            Kind::MappingStart(name) => {
+                let anchor = anchor(name, &mut references);
+                open.push(Open { mapping: true, children: 0, anchor });
+                false
+            }
+            Kind::SequenceEnd | Kind::MappingEnd => {
+                open.pop();
+                true
+            }
+            Kind::StreamStart => false,
+        };
+        if let Some(parent) = open.last_mut().filter(|_| ended) {
+            parent.children += 1;
+        }
+    }
+}
+
+/// `document` as YAML, its references aliases of the nodes they name
+pub(super) fn write(document: &Value) -> Result<String> {
+    let mut targets = HashSet::new();
+    find_targets(document, document, &mut targets)?;
+    if targets.is_empty() {
+        return Ok(serde_yaml::to_string(&from_json(document.clone()))?);
+    }
+    let mut writer = Writer { root: document, targets, anchors: HashMap::new(), names: HashSet::new(), out: String::new() };
+    match writer.visit(document, String::new())? {
+        Visited::Node { value, pointer, anchor } if is_collection(value) => {
+            if let Some(anchor) = anchor {
+                let _ = writeln!(writer.out, "&{anchor}");
+            }
+            writer.block(value, &pointer, 0, false)?;
+        }
+        Visited::Node { value, anchor, .. } => {
+            let anchor = anchor.map(|anchor| format!("&{anchor} ")).unwrap_or_default();
+            let _ = writeln!(writer.out, "{}{}", anchor, scalar(value)?);
+        }
+        Visited::Alias(_) => unreachable!("nothing is anchored before the document"),
+    }
+    Ok(writer.out)
+}
+
+/// The pointer `value` refers to, where it is a reference: an object of only a `$ref` within the document
+fn reference(value: &Value) -> Option<&str> {
+    let Value::Object(map) = value else {
+        return None;
+    };
+    match map.get("$ref") {
+        Some(Value::String(target)) if map.len() == 1 => target.strip_prefix('#'),
+        _ => None,
+    }
+}
+
+/// The node `pointer` names in `root`, and its pointer, following references to references
+fn resolve<'a>(root: &'a Value, pointer: &str) -> Result<(&'a Value, String)> {
+    let mut followed = vec![pointer.to_string()];
+    loop {
+        let pointer = followed.last().expect("a pointer is followed");
+        let value = root.pointer(pointer).ok_or_else(|| anyhow!("Reference to #{pointer} names nothing in the document"))?;
+        let Some(next) = reference(value) else {
+            return Ok((value, pointer.clone()));
+        };
+        if followed.iter().any(|pointer| pointer == next) {
+            bail!("References to #{} only name each other, never a node", followed.join(", #"));
+        }
+        followed.push(next.to_string());
+    }
+}
+
+/// Add the pointers of the nodes the references within `value` name to `targets`
+fn find_targets(root: &Value, value: &Value, targets: &mut HashSet<String>) -> Result<()> {
+    if let Some(pointer) = reference(value) {
+        targets.insert(resolve(root, pointer)?.1);
+        return Ok(());
+    }
+    match value {
+        Value::Array(items) => items.iter().try_for_each(|item| find_targets(root, item, targets)),
+        Value::Object(map) => map.values().try_for_each(|value| find_targets(root, value, targets)),
+        _ => Ok(()),
+    }
+}
+
+/// What is written where a node of the document lies
+enum Visited<'a> {
+    /// An alias of the node anchored with the name, written before
+    Alias(String),
+    /// The node, with its pointer and the name to anchor it with, where references name it
+    Node { value: &'a Value, pointer: String, anchor: Option<String> },
+}
+
+/// Writes a document in block style, as `serde_yaml` does, with anchors and aliases
+struct Writer<'a> {
+    root: &'a Value,
+    /// Pointers of the nodes references name
+    targets: HashSet<String>,
+    /// Names of the nodes anchored so far, by pointer
+    anchors: HashMap<String, String>,
+    names: HashSet<String>,
+    out: String,
+}
+
+impl<'a> Writer<'a> {
+    /// What to write for `value`, at `pointer`: the node a reference names, where it appears first, or an alias of it
+    fn visit(&mut self, value: &'a Value, pointer: String) -> Result<Visited<'a>> {
+        let (value, pointer) = match reference(value) {
+            Some(target) => resolve(self.root, target)?,
+            None => (value, pointer),
+        };
+        if let Some(name) = self.anchors.get(&pointer) {
+            return Ok(Visited::Alias(name.clone()));
+        }
+        let anchor = self.targets.contains(&pointer).then(|| self.name(&pointer));
+        Ok(Visited::Node { value, pointer, anchor })
+    }
+
+    /// An anchor for the node at `pointer`, after its key or index, unique in the document
+    fn name(&mut self, pointer: &str) -> String {
+        let segment = pointer.rsplit('/').next().filter(|segment| !segment.is_empty()).unwrap_or("root");
+        let base: String = segment
+            .replace("~1", "/")
+            .replace("~0", "~")
+            .chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let mut name = base.clone();
+        for n in 2.. {
+            if self.names.insert(name.clone()) {
+                break;
+            }
+            name = format!("{base}_{n}");
+        }
+        self.anchors.insert(pointer.to_string(), name.clone());
+        name
+    }
+
+    /// Write the entries or items of a collection that is not empty, each on a line
+    /// indented by `indent`, but for the first, when `inline`, which follows a `-`
+    fn block(&mut self, value: &'a Value, pointer: &str, indent: usize, inline: bool) -> Result<()> {
+        let children: Vec<(Option<&String>, &Value)> = match value {
+            Value::Object(map) => map.iter().map(|(key, value)| (Some(key), value)).collect(),
+            Value::Array(items) => items.iter().map(|item| (None, item)).collect(),
+            _ => unreachable!("only collections are written as blocks"),
+        };
+        for (i, (key, child)) in children.into_iter().enumerate() {
+            if i > 0 || !inline {
+                self.out.push_str(&" ".repeat(indent));
+            }
+            if let Some(key) = key {
+                self.out.push_str(&scalar(&Value::String(key.clone()))?);
+                self.out.push(':');
+                let pointer = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+                self.entry_value(child, pointer, indent)?;
+            } else {
+                self.out.push('-');
+                self.item(child, format!("{pointer}/{i}"), indent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what follows the key of an entry indented by `indent`
+    fn entry_value(&mut self, value: &'a Value, pointer: String, indent: usize) -> Result<()> {
+        let (value, pointer, anchor) = match self.visit(value, pointer)? {
+            Visited::Alias(name) => {
+                let _ = writeln!(self.out, " *{name}");
+                return Ok(());
+            }
+            Visited::Node { value, pointer, anchor } => (value, pointer, anchor),
+        };
+        if let Some(anchor) = anchor {
+            let _ = write!(self.out, " &{anchor}");
+        }
+        match value {
+            // A sequence in a mapping is indented as its key is
+            Value::Array(items) if !items.is_empty() => {
+                self.out.push('\n');
+                self.block(value, &pointer, indent, false)
+            }
+            Value::Object(map) if !map.is_empty() => {
+                self.out.push('\n');
+                self.block(value, &pointer, indent + 2, false)
+            }
+            _ => {
+                let _ = writeln!(self.out, " {}", scalar(value)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write what follows the `-` of an item indented by `indent`
+    fn item(&mut self, value: &'a Value, pointer: String, indent: usize) -> Result<()> {
+        match self.visit(value, pointer)? {
+            Visited::Alias(name) => {
+                let _ = writeln!(self.out, " *{name}");
+            }
+            Visited::Node { value, pointer, anchor: Some(anchor) } if is_collection(value) => {
+                let _ = writeln!(self.out, " &{anchor}");
+                self.block(value, &pointer, indent + 2, false)?;
+            }
+            Visited::Node { value, pointer, anchor: None } if is_collection(value) => {
+                self.out.push(' ');
+                self.block(value, &pointer, indent + 2, true)?;
+            }
+            Visited::Node { value, anchor, .. } => {
+                let anchor = anchor.map(|anchor| format!("&{anchor} ")).unwrap_or_default();
+                let _ = writeln!(self.out, " {}{}", anchor, scalar(value)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `value` is written as a block, a collection that is not empty
+fn is_collection(value: &Value) -> bool {
+    match value {
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+        _ => false,
+    }
+}
+
+/// `value`, a scalar or an empty collection, as `serde_yaml` writes it, double quoted where it spans lines
+fn scalar(value: &Value) -> Result<String> {
+    let written = serde_yaml::to_string(&from_json(value.clone()))?;
+    let written = written.trim_end_matches('\n');
+    if written.contains('\n') {
+        return Ok(serde_json::to_string(value)?);
+    }
+    Ok(written.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn symbolic(yaml: &str) -> Value {
+        let documents = parse_symbolic(yaml).unwrap();
+        super::super::to_json(documents.into_iter().next().unwrap(), "").unwrap()
+    }
+
+    #[test]
+    fn test_read_references() {
+        let yaml = "\
+defaults: &defaults
+  retries: 3
+  hosts: [&primary a.example, b.example]
+jobs:
+- name: build
+  <<: *defaults
+- name: deploy
+  host: *primary
+  'key: *not': *defaults
+";
+        let expected = json!({
+            "defaults": {"retries": 3, "hosts": ["a.example", "b.example"]},
+            "jobs": [
+                {"name": "build", "<<": {"$ref": "#/defaults"}},
+                {"name": "deploy", "host": {"$ref": "#/defaults/hosts/0"}, "key: *not": {"$ref": "#/defaults"}},
+            ],
+        });
+        assert_eq!(symbolic(yaml), expected);
+
+        // A node containing itself reads as any other, but is a cycle to expand
+        assert_eq!(symbolic("a: &a\n  b: [1, *a]\n"), json!({"a": {"b": [1, {"$ref": "#/a"}]}}));
+        let diagnostic = cycle("x: 1\na: &a\n  b: [1, *a]\n").unwrap();
+        let (start, end) = diagnostic.span.unwrap();
+        assert_eq!((start, end), (Position { line: 3, column: 10 }, Position { line: 3, column: 11 }));
+        assert!(diagnostic.message.starts_with("alias *a lies within the node anchored &a"), "{diagnostic:?}");
+        assert!(cycle("a: &a 1\nb: *a\n").is_none());
+
+        // Anchors belong to their document, and keys are expanded
+        let documents = parse_symbolic("a: &a 1\n---\n- &a 2\n- *a\n---\n&k key: 1\n*k : 2\n").unwrap_err();
+        assert!(documents.message.contains("duplicate entry"), "{documents:?}");
+        let documents = parse_symbolic("a: &a 1\n---\n- &a 2\n- *a\n").unwrap();
+        assert_eq!(super::super::to_json(documents[1].clone(), "").unwrap(), json!([2, {"$ref": "#/0"}]));
+        assert!(parse_symbolic("a: *missing\n").unwrap_err().message.contains("unknown anchor"));
+    }
+
+    #[test]
+    fn test_write_references() {
+        let document = json!({
+            "base": {"retries": 3, "hosts": ["a", "b"]},
+            "jobs": [{"<<": {"$ref": "#/base"}, "host": {"$ref": "#/base/hosts/0"}}, {"$ref": "#/base/hosts"}],
+        });
+        let yaml = write(&document).unwrap();
+        let expected = "\
+base: &base
+  hosts: &hosts
+  - &0 a
+  - b
+  retries: 3
+jobs:
+- <<: *base
+  host: *0
+- *hosts
+";
+        assert_eq!(yaml, expected);
+        assert_eq!(symbolic(&yaml), document);
+
+        // A node named before it appears is written where it is named first
+        let document = json!({"a": {"$ref": "#/z"}, "self": {"loop": {"$ref": "#/self"}}, "z": [1, "two\nlines"]});
+        let yaml = write(&document).unwrap();
+        assert_eq!(yaml, "a: &z\n- 1\n- \"two\\nlines\"\nself: &self\n  loop: *self\nz: *z\n");
+        let moved = json!({"a": [1, "two\nlines"], "self": {"loop": {"$ref": "#/self"}}, "z": {"$ref": "#/a"}});
+        assert_eq!(symbolic(&yaml), moved);
+        assert_eq!(write(&json!({"$ref": "#/0"})).unwrap_err().to_string(), "Reference to #/0 names nothing in the document");
+        let error = write(&json!({"a": {"$ref": "#/b"}, "b": {"$ref": "#/a"}})).unwrap_err().to_string();
+        assert_eq!(error, "References to #/b, #/a only name each other, never a node");
+        assert_eq!(write(&json!({"$ref": "other.json#/a"})).unwrap(), "$ref: other.json#/a\n");
+    }
+}
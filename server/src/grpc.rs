@@ -0,0 +1,318 @@
+//! gRPC interface, built with the `grpc` feature
+//!
+//! Serves the services of `proto/ulc.proto` on `grpc_addr`: the documents
+//! and their changes, and conversion and validation. Each call is a thin
+//! adapter over what the HTTP API and the WebSocket server do, so all three
+//! answer alike, down to the error messages; [`ApiError`]s map to status
+//! codes. The reflection service is served too, so tools such as `grpcurl`
+//! need no copy of the proto file.
+//!
+//! With authentication enabled every call but reflection needs a valid
+//! token in its `authorization` metadata, with or without `Bearer `,
+//! checked as the HTTP and WebSocket servers check theirs.
+//!
+//! `Subscribe` reads document events from the channel WebSocket sessions
+//! read, and holds subscriptions under the same limit. A stream that falls
+//! behind is sent `resync_required` instead of the events it missed, as a
+//! WebSocket client is sent `ResyncRequired`.
+
+use crate::audit::AuditEvent;
+use crate::auth::middleware::{Credentials, Refusal};
+use crate::auth::{roles, Claims};
+use crate::document_store::{Document, DocumentEvent, DocumentEventKind};
+use crate::http::{self, ApiError, ConvertRequest};
+use crate::monitoring::usage::Client;
+use crate::websocket::{self, Subscriptions};
+use crate::ServerState;
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::warn;
+
+/// Messages and services generated from `proto/ulc.proto`
+#[allow(clippy::all, clippy::pedantic)]
+pub mod proto {
+    tonic::include_proto!("ulc.v1");
+
+    /// Encoded descriptors of the proto file, for the reflection service
+    pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ulc_descriptor.bin"));
+}
+
+use self::proto::documents_server::{Documents, DocumentsServer};
+use self::proto::formats_server::{Formats, FormatsServer};
+use self::proto::{document_event, subscribe_request};
+
+/// Events queued for a subscriber before the store events back up behind it
+const SUBSCRIBER_QUEUE: usize = 32;
+
+/// Documents and their changes
+pub struct DocumentsService {
+    state: Arc<ServerState>,
+    /// Set when the server stops, ending every subscription so the connections can drain
+    stopping: watch::Receiver<bool>,
+}
+
+/// Conversion and validation
+pub struct FormatsService {
+    state: Arc<ServerState>,
+}
+
+impl From<ApiError> for Status {
+    fn from(error: ApiError) -> Self {
+        match error {
+            ApiError::NotFound(message) => Status::not_found(message),
+            ApiError::BadRequest(message) => Status::invalid_argument(message),
+            ApiError::Unauthorized(message) => Status::unauthenticated(message),
+            ApiError::Forbidden(message) => Status::permission_denied(message),
+            ApiError::Conflict(message) => Status::already_exists(message),
+            ApiError::TooManyRequests(message) => Status::resource_exhausted(message),
+            ApiError::Unavailable(message) => Status::unavailable(message),
+            ApiError::Throttled(throttled) => Status::resource_exhausted(throttled.to_string()),
+            ApiError::Internal(message) => Status::internal(message),
+        }
+    }
+}
+
+impl From<Refusal> for Status {
+    fn from(refusal: Refusal) -> Self {
+        ApiError::from(refusal).into()
+    }
+}
+
+impl From<Document> for proto::Document {
+    fn from(document: Document) -> Self {
+        Self {
+            id: document.id,
+            uri: document.uri,
+            content: document.content,
+            language: document.language,
+            version: document.version,
+            created_at: document.created_at.to_rfc3339(),
+            modified_at: document.modified_at.to_rfc3339(),
+        }
+    }
+}
+
+/// The subscriber message for a store event
+fn event_message(event: &DocumentEvent) -> document_event::Event {
+    let document = &event.document;
+    match event.kind {
+        DocumentEventKind::Created | DocumentEventKind::Updated => {
+            document_event::Event::Updated(Document::clone(document).into())
+        }
+        DocumentEventKind::Removed => document_event::Event::Removed(proto::DocumentRemoved {
+            document_id: document.id.clone(),
+            uri: document.uri.clone(),
+        }),
+    }
+}
+
+/// Apply one subscription change, or say why it was refused
+fn change_subscription(subscriptions: &mut Subscriptions, request: proto::SubscribeRequest) -> Result<(), String> {
+    let (document_id, pattern) = match request.target {
+        Some(subscribe_request::Target::DocumentId(id)) => (id, None),
+        Some(subscribe_request::Target::Pattern(pattern)) => (String::new(), Some(pattern)),
+        None => return Err("Subscribe requires a document_id or pattern".to_string()),
+    };
+    if request.unsubscribe {
+        subscriptions.unsubscribe(&document_id, pattern.as_deref());
+        Ok(())
+    } else {
+        subscriptions.subscribe(document_id, pattern, false)
+    }
+}
+
+/// The client a call is made for, as the interceptor identified it
+fn client<T>(request: &Request<T>) -> Client {
+    request.extensions().get::<Client>().cloned().unwrap_or_else(Client::anonymous)
+}
+
+/// Refuse a call whose claims lack `scope`, those of its token or else the anonymous claims
+#[allow(clippy::result_large_err)]
+fn require<T>(request: &Request<T>, scope: &str) -> Result<(), Status> {
+    match request.extensions().get::<Claims>() {
+        Some(claims) if !claims.has_scope(scope) => Err(Status::permission_denied(format!("Requires the {} scope", scope))),
+        _ => Ok(()),
+    }
+}
+
+#[tonic::async_trait]
+impl Documents for DocumentsService {
+    type SubscribeStream = ReceiverStream<Result<proto::DocumentEvent, Status>>;
+
+    async fn list_documents(
+        &self,
+        request: Request<proto::ListDocumentsRequest>,
+    ) -> Result<Response<proto::ListDocumentsResponse>, Status> {
+        require(&request, roles::READ)?;
+        let documents = self.state.documents.list().into_iter().map(Into::into).collect();
+        Ok(Response::new(proto::ListDocumentsResponse { documents }))
+    }
+
+    async fn get_document(&self, request: Request<proto::GetDocumentRequest>) -> Result<Response<proto::Document>, Status> {
+        require(&request, roles::READ)?;
+        let document = http::find_document(&self.state, &request.into_inner().id)?;
+        Ok(Response::new(document.into()))
+    }
+
+    async fn delete_document(
+        &self,
+        request: Request<proto::DeleteDocumentRequest>,
+    ) -> Result<Response<proto::DeleteDocumentResponse>, Status> {
+        require(&request, roles::WRITE)?;
+        http::remove_document(&self.state, &request.into_inner().id)?;
+        Ok(Response::new(proto::DeleteDocumentResponse {}))
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<Streaming<proto::SubscribeRequest>>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        require(&request, roles::READ)?;
+        let mut requests = request.into_inner();
+        let mut events = self.state.ws_sessions.events();
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+        let mut stopping = self.stopping.clone();
+        tokio::spawn(async move {
+            let mut subscriptions = Subscriptions::default();
+            loop {
+                // Biased so a subscription is in place before the events after it are matched
+                let event = tokio::select! {
+                    biased;
+                    () = tx.closed() => break,
+                    _ = stopping.wait_for(|stopping| *stopping) => break,
+                    request = requests.message() => match request {
+                        Ok(Some(request)) => match change_subscription(&mut subscriptions, request) {
+                            Ok(()) => continue,
+                            Err(message) => document_event::Event::Error(message),
+                        },
+                        Ok(None) | Err(_) => break,
+                    },
+                    event = events.recv() => match event {
+                        Ok(shared) if subscriptions.matches(shared.event()).is_some() => event_message(shared.event()),
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Document event stream lagged by {} events for a gRPC subscriber", skipped);
+                            document_event::Event::ResyncRequired(proto::ResyncRequired {
+                                reason: websocket::lag_reason(skipped),
+                            })
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+                // Waiting here lets the store events back up, until the subscriber lags
+                let sent = tokio::select! {
+                    sent = tx.send(Ok(proto::DocumentEvent { event: Some(event) })) => sent.is_ok(),
+                    _ = stopping.wait_for(|stopping| *stopping) => false,
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[tonic::async_trait]
+impl Formats for FormatsService {
+    async fn convert(&self, request: Request<proto::ConvertRequest>) -> Result<Response<proto::ConvertResponse>, Status> {
+        require(&request, roles::READ)?;
+        let client = client(&request);
+        let proto::ConvertRequest { content, from, to } = request.into_inner();
+        let request = ConvertRequest { content, from, to, original: None, datetimes: Default::default() };
+        let converted = http::convert(&self.state, &client, request)?;
+        Ok(Response::new(proto::ConvertResponse {
+            content: converted.content,
+            from: converted.from,
+            to: converted.to,
+            warnings: converted.warnings,
+        }))
+    }
+
+    async fn validate(&self, request: Request<proto::ValidateRequest>) -> Result<Response<proto::ValidateResponse>, Status> {
+        require(&request, roles::READ)?;
+        let request = request.into_inner();
+        let validated = http::validate(&self.state, &request.content, &request.format, None)?;
+        Ok(Response::new(proto::ValidateResponse {
+            valid: validated.valid,
+            diagnostics: validated.diagnostics,
+        }))
+    }
+}
+
+/// Check the token of a call, when authentication is enabled, and identify its client
+#[derive(Clone)]
+pub struct Authenticate {
+    state: Arc<ServerState>,
+}
+
+impl tonic::service::Interceptor for Authenticate {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let anonymous = self.state.anonymous_claims();
+        let (client, claims) = match &self.state.auth_service {
+            Some(auth) => {
+                let token = request.metadata().get("authorization").and_then(|value| value.to_str().ok());
+                let authentication = Credentials { token, ..Credentials::default() }.authenticate(auth);
+                let source = request.remote_addr().map(|addr| addr.ip());
+                authentication.audit(&self.state.audit, |kind| AuditEvent::new(kind, "grpc").source(source));
+                let claims = authentication.permit(anonymous.as_ref())?.clone();
+                let client = authentication.claims().map_or_else(Client::anonymous, Client::from_claims);
+                (client, Some(claims))
+            }
+            None => (Client::anonymous(), anonymous),
+        };
+        if let Some(claims) = claims {
+            request.extensions_mut().insert(claims);
+        }
+        request.extensions_mut().insert(client);
+        Ok(request)
+    }
+}
+
+/// The documents service, behind authentication, ending its subscriptions once `stopping` is set
+pub fn documents_service(
+    state: Arc<ServerState>,
+    stopping: watch::Receiver<bool>,
+) -> InterceptedService<DocumentsServer<DocumentsService>, Authenticate> {
+    let auth = Authenticate { state: Arc::clone(&state) };
+    DocumentsServer::with_interceptor(DocumentsService { state, stopping }, auth)
+}
+
+/// The formats service, behind authentication
+pub fn formats_service(state: Arc<ServerState>) -> InterceptedService<FormatsServer<FormatsService>, Authenticate> {
+    let auth = Authenticate { state: Arc::clone(&state) };
+    FormatsServer::with_interceptor(FormatsService { state }, auth)
+}
+
+/// Serve the gRPC services on a bound listener until `stop` completes
+pub async fn serve_grpc_until<F>(state: Arc<ServerState>, listener: TcpListener, stop: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .build()?;
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+    let (stopping, stopped) = watch::channel(false);
+    let mut draining = stopping.subscribe();
+    tokio::spawn(async move {
+        stop.await;
+        stopping.send_replace(true);
+    });
+    tonic::transport::Server::builder()
+        .add_service(documents_service(Arc::clone(&state), stopped))
+        .add_service(formats_service(state))
+        .add_service(reflection)
+        .serve_with_incoming_shutdown(incoming, async move {
+            let _ = draining.wait_for(|stopping| *stopping).await;
+        })
+        .await?;
+    Ok(())
+}
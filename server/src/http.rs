@@ -2,26 +2,90 @@
 //!
 //! Provides HTTP endpoints for web integration and non-LSP clients.
 
-use crate::core::{ConversionCore, ConversionRequest, Format};
+use crate::audit::{AuditEvent, AuditKind};
+use crate::auth::api_keys::{ApiKey, ApiKeyStore};
+use crate::auth::csrf;
+use crate::auth::directory::LoginError;
+use crate::auth::keyring::KeyInfo;
+use crate::auth::lockout::Throttled;
+use crate::auth::middleware::{
+    audit_request, bearer_token, request_source, AuthenticateLayer, Authentication, Credentials, EnforcePolicyLayer, RateLimitLayer,
+    Refusal,
+};
+use crate::auth::refresh::{RefreshFamily, RefreshTokenStore, TokenPair};
+use crate::auth::revocation::RevokedToken;
+use crate::auth::saml::SamlError;
+use crate::auth::signing;
+use crate::auth::roles;
+use crate::auth::mtls::{self, ClientCertificate};
+use crate::auth::{AuthService, Claims, TokenError};
+use crate::build_info::{self, BuildInfo};
+use crate::clients::{ClientRecord, ClientRegistry, RegisteredClient};
+use crate::core::{ConversionOptions, ConversionRequest, Format};
 use crate::document_store::Document;
+use crate::formats::datetime::DateTimeStyle;
+use crate::formats::diff::Change;
+use crate::formats::infer::InferOptions;
+use crate::formats::pretty;
+use crate::formats::query::{QueryLanguage, QueryResult};
+use crate::formats::schema::{self, Schema, SchemaDiagnostic};
+use crate::formats::stream;
+use crate::formats::{FormatOptions, FormatRef};
+use crate::monitoring::connections::{ConnectionMetrics, ConnectionState, DisconnectReason, OpenConnection, Transport};
+use crate::monitoring::slow_ops::{self, OpKind, Operation, SlowOp};
+use crate::monitoring::usage::{self, Client, Counts, UsageReport};
+use crate::monitoring::alerts::AlertsSummary;
+use crate::monitoring::{ConnectionsSummary, MetricControl, MetricsConfig};
+use crate::scheduler::{RunNowError, TaskStatus};
+use crate::telemetry;
 use crate::ServerState;
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts, MatchedPath, Path, Query, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{delete, get, post},
-    Json, Router,
+    serve::IncomingStream,
+    Extension, Form, Json, Router,
 };
+use futures_util::{StreamExt, TryStreamExt};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower::{Service, ServiceExt};
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{error, info};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+/// Header carrying the request ID
+const REQUEST_ID_HEADER: &str = "x-request-id";
 
 /// HTTP API error response
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    error: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl From<Refusal> for ApiError {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Unauthenticated(message) => ApiError::Unauthorized(message),
+            Refusal::Forbidden(message) => ApiError::Forbidden(message),
+            Refusal::RateLimited(_) => ApiError::TooManyRequests(refusal.to_string()),
+        }
+    }
 }
 
 impl IntoResponse for ApiError {
@@ -29,6 +93,17 @@ fn into_response(self) -> Response {
         let (status, message) = match self {
             ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            ApiError::Unavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+            ApiError::Throttled(throttled) => {
+                let status = if throttled.locked { StatusCode::LOCKED } else { StatusCode::TOO_MANY_REQUESTS };
+                let mut response = (status, Json(ErrorResponse { error: throttled.to_string() })).into_response();
+                response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(throttled.retry_after));
+                return response;
+            }
             ApiError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
         };
 
@@ -38,33 +113,160 @@ fn into_response(self) -> Response {
 
 /// API error types
 #[derive(Debug)]
-enum ApiError {
+pub(crate) enum ApiError {
     NotFound(String),
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    TooManyRequests(String),
+    /// A service the request depends on could not be reached
+    Unavailable(String),
+    /// Too many failed logins: 429 while backing off, 423 once locked out
+    Throttled(Throttled),
     Internal(String),
 }
 
 /// Convert document request
-#[derive(Debug, Deserialize)]
-struct ConvertRequest {
-    content: String,
-    from: String,
-    to: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConvertRequest {
+    pub content: String,
+    /// Format name, built in or provided by a plugin
+    pub from: String,
+    pub to: String,
+    /// An earlier version of the result, whose comments, blank lines and key order a YAML or TOML result keeps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    /// How date-times are written, for built-in formats
+    #[serde(default)]
+    pub datetimes: DateTimeStyle,
+}
+
+/// Converted document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConvertResponse {
+    pub content: String,
+    pub from: String,
+    pub to: String,
+    pub warnings: Vec<String>,
+}
+
+/// Validate document request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidateRequest {
+    pub content: String,
+    pub format: String,
+    /// JSON Schema to check the document against: the name of a configured one, or the schema itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+}
+
+/// Validation result; diagnostics do not make the request fail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidateResponse {
+    pub valid: bool,
+    pub diagnostics: Vec<String>,
+    /// Keywords of the requested schema the document fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<SchemaDiagnostic>,
+}
+
+/// Format document request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormatRequest {
+    pub content: String,
+    pub format: String,
+    /// Layout; options left out take their defaults
+    #[serde(default)]
+    pub options: FormatOptions,
+}
+
+/// Formatted document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormatResponse {
+    pub content: String,
+    /// Whether the layout differs from the document sent
+    pub changed: bool,
+}
+
+/// One side of a diff, or a schema sample: a document and its format, built in or provided by a plugin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffDocument {
+    pub content: String,
+    pub format: String,
+}
+
+/// Diff documents request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffRequest {
+    pub before: DiffDocument,
+    pub after: DiffDocument,
+}
+
+/// Differences between two documents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffResponse {
+    pub changes: Vec<Change>,
+}
+
+/// Infer schema request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InferSchemaRequest {
+    /// Samples of the documents the schema is for
+    pub documents: Vec<DiffDocument>,
+    #[serde(default)]
+    pub options: InferOptions,
+}
+
+/// Schema inferred from sample documents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InferSchemaResponse {
+    pub schema: serde_json::Value,
+}
+
+/// Query a stored document request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryRequest {
+    pub expression: String,
+    /// `JSONPath` unless given
+    #[serde(default)]
+    pub language: QueryLanguage,
 }
 
 /// Document list response
-#[derive(Debug, Serialize)]
-struct DocumentListResponse {
-    documents: Vec<Document>,
-    count: usize,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentListResponse {
+    pub documents: Vec<Document>,
+    pub count: usize,
 }
 
 /// Server statistics
-#[derive(Debug, Serialize)]
-struct ServerStats {
-    document_count: usize,
-    uptime_seconds: u64,
-    version: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub document_count: usize,
+    pub uptime_seconds: u64,
+    pub version: String,
+}
+
+/// Build metadata and uptime
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionResponse {
+    #[serde(flatten)]
+    pub build: BuildInfo,
+    pub uptime_seconds: u64,
+}
+
+/// Log filter in `EnvFilter` syntax
+#[derive(Debug, Serialize, Deserialize)]
+struct LogFilter {
+    filter: String,
+}
+
+/// Formats of a streamed conversion
+#[derive(Debug, Deserialize)]
+struct StreamFormats {
+    from: String,
+    to: String,
 }
 
 /// Health check response (deprecated - use /api/health/detailed)
@@ -77,29 +279,68 @@ struct HealthResponse {
 /// Convert document handler
 async fn convert_document(
     State(state): State<Arc<ServerState>>,
+    Extension(client): Extension<Client>,
+    caller: Caller,
     Json(payload): Json<ConvertRequest>,
-) -> Result<Json<serde_json::Value>, ApiError> {
+) -> Result<Json<ConvertResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    convert(&state, &client, payload).map(Json)
+}
+
+/// Convert between two formats, built in or from plugins, accounting the conversion to `client`
+///
+/// Shared by every transport that offers conversion, so they report the
+/// same errors and count usage the same way.
+pub(crate) fn convert(state: &ServerState, client: &Client, payload: ConvertRequest) -> Result<ConvertResponse, ApiError> {
     info!("Converting document: {} → {}", payload.from, payload.to);
 
-    let from_format = Format::from_str(&payload.from)
+    let from = state
+        .formats
+        .resolve(&payload.from)
         .map_err(|e| ApiError::BadRequest(format!("Invalid 'from' format: {}", e)))?;
 
-    let to_format = Format::from_str(&payload.to)
+    let to = state
+        .formats
+        .resolve(&payload.to)
         .map_err(|e| ApiError::BadRequest(format!("Invalid 'to' format: {}", e)))?;
 
+    // Plugin formats convert through canonical JSON, without warnings
+    let (FormatRef::BuiltIn(from_format), FormatRef::BuiltIn(to_format)) = (&from, &to) else {
+        return match state.formats.convert_any(&payload.content, &from, &to) {
+            Ok(content) => {
+                state.usage.record(client, Counts::conversion());
+                Ok(ConvertResponse {
+                    content,
+                    from: from.name().to_string(),
+                    to: to.name().to_string(),
+                    warnings: Vec::new(),
+                })
+            }
+            Err(e) => {
+                error!("Conversion failed: {:#}", e);
+                Err(ApiError::Internal(format!("Conversion failed: {e:#}")))
+            }
+        };
+    };
+    let (from_format, to_format) = (*from_format, *to_format);
+
     let request = ConversionRequest {
         content: payload.content,
         from: from_format,
         to: to_format,
     };
 
-    match ConversionCore::convert(request) {
-        Ok(response) => Ok(Json(serde_json::json!({
-            "content": response.content,
-            "from": response.from,
-            "to": response.to,
-            "warnings": response.warnings,
-        }))),
+    let options = ConversionOptions { original: payload.original, datetimes: payload.datetimes };
+    match state.formats.convert_with(request, &options) {
+        Ok(response) => {
+            state.usage.record(client, Counts::conversion());
+            Ok(ConvertResponse {
+                content: response.content,
+                from: response.from.name().to_string(),
+                to: response.to.name().to_string(),
+                warnings: response.warnings,
+            })
+        }
         Err(e) => {
             error!("Conversion failed: {}", e);
             Err(ApiError::Internal(format!("Conversion failed: {}", e)))
@@ -107,50 +348,223 @@ async fn convert_document(
     }
 }
 
+/// Bytes of converted output sent at a time by [`convert_stream`]
+const STREAM_CHUNK: usize = 64 * 1024;
+
+/// Streamed conversion handler: the request body is converted as it arrives and sent back chunked
+///
+/// Failures before any output is sent are answered with a status; later
+/// ones can only cut the response short.
+async fn convert_stream(
+    State(state): State<Arc<ServerState>>,
+    Extension(client): Extension<Client>,
+    caller: Caller,
+    Query(formats): Query<StreamFormats>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    let builtin = |name: &str, side: &str| match state.formats.resolve(name) {
+        Ok(FormatRef::BuiltIn(format)) => Ok(format),
+        Ok(FormatRef::Plugin(_)) => Err(ApiError::BadRequest(format!("Invalid '{side}' format: plugin formats do not stream"))),
+        Err(e) => Err(ApiError::BadRequest(format!("Invalid '{side}' format: {e}"))),
+    };
+    let (from, to) = (builtin(&formats.from, "from")?, builtin(&formats.to, "to")?);
+    if !stream::supports(from, to) {
+        return Err(ApiError::BadRequest(format!("Cannot stream {} to {}: only JSON, NDJSON and YAML stream", from.name(), to.name())));
+    }
+    info!("Streaming conversion: {} → {}", from.name(), to.name());
+
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let (writer, output) = tokio::io::duplex(STREAM_CHUNK);
+    let formats = state.formats.clone();
+    let converting = tokio::spawn(async move { formats.convert_stream(reader, from, to, writer).await });
+    let mut chunks = ReaderStream::with_capacity(output, STREAM_CHUNK);
+    let first = chunks.next().await;
+    // Once the output ends, the conversion has succeeded or failed
+    let finish = async move {
+        let converted = converting.await.map_err(anyhow::Error::from).and_then(|converted| converted);
+        if converted.is_ok() {
+            state.usage.record(&client, Counts::conversion());
+        }
+        converted
+    };
+    let content_type = match to {
+        Format::Json => "application/json",
+        Format::Ndjson => "application/x-ndjson",
+        _ => "application/yaml",
+    };
+    let Some(first) = first else {
+        finish.await.map_err(|e| ApiError::BadRequest(format!("Conversion failed: {e:#}")))?;
+        return Ok(([(header::CONTENT_TYPE, content_type)], Body::empty()).into_response());
+    };
+    let first = first.map_err(|e| ApiError::Internal(format!("Conversion failed: {e}")))?;
+    // A failure after the first chunk ends the body with an error, which aborts the response
+    let failure = futures_util::stream::once(finish).filter_map(|finished| async move {
+        finished.err().map(|e| {
+            error!("Streamed conversion failed: {:#}", e);
+            Err(std::io::Error::other(format!("{e:#}")))
+        })
+    });
+    let body = futures_util::stream::once(ready(Ok(first))).chain(chunks).chain(failure);
+    Ok(([(header::CONTENT_TYPE, content_type)], Body::from_stream(body)).into_response())
+}
+
 /// List all documents handler
 async fn list_documents(
     State(state): State<Arc<ServerState>>,
-) -> Json<DocumentListResponse> {
+    caller: Caller,
+) -> Result<Json<DocumentListResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
     let documents = state.documents.list();
     let count = documents.len();
 
-    Json(DocumentListResponse { documents, count })
+    Ok(Json(DocumentListResponse { documents, count }))
 }
 
 /// Get document by ID handler
 async fn get_document(
     State(state): State<Arc<ServerState>>,
+    caller: Caller,
     Path(id): Path<String>,
 ) -> Result<Json<Document>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    find_document(&state, &id).map(Json)
+}
+
+/// The document with this ID
+pub(crate) fn find_document(state: &ServerState, id: &str) -> Result<Document, ApiError> {
     state
+        .documents
+        .get_by_id(id)
+        .ok_or_else(|| ApiError::NotFound(format!("Document not found: {}", id)))
+}
+
+/// Query document handler, finding the document by ID or URI
+async fn query_document(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Json(payload): Json<QueryRequest>,
+) -> Result<Json<QueryResult>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    let document = state
         .documents
         .get_by_id(&id)
+        .or_else(|| state.documents.get(&id))
+        .ok_or_else(|| ApiError::NotFound(format!("Document not found: {id}")))?;
+    // Languages that are no format are read as Markdown, as conversions read them
+    let format = state.formats.resolve(&document.language).unwrap_or(FormatRef::BuiltIn(Format::Markdown));
+    state
+        .formats
+        .query(&document.content, &format, payload.language, &payload.expression)
         .map(Json)
-        .ok_or_else(|| ApiError::NotFound(format!("Document not found: {}", id)))
+        .map_err(|e| ApiError::BadRequest(format!("Query failed: {e:#}")))
 }
 
 /// Delete document handler
 async fn delete_document(
     State(state): State<Arc<ServerState>>,
+    caller: Caller,
     Path(id): Path<String>,
 ) -> Result<StatusCode, ApiError> {
+    require_scope(&state, &caller, roles::WRITE)?;
+    remove_document(&state, &id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Remove the document with this ID
+pub(crate) fn remove_document(state: &ServerState, id: &str) -> Result<(), ApiError> {
     // Find document by ID
-    let doc = state
-        .documents
-        .get_by_id(&id)
-        .ok_or_else(|| ApiError::NotFound(format!("Document not found: {}", id)))?;
+    let doc = find_document(state, id)?;
 
     // Remove by URI
     state.documents.remove(&doc.uri);
+    Ok(())
+}
 
-    Ok(StatusCode::NO_CONTENT)
+/// Format document handler
+async fn format_document(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(payload): Json<FormatRequest>,
+) -> Result<Json<FormatResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    let format = match state.formats.resolve(&payload.format) {
+        Ok(FormatRef::BuiltIn(format)) if pretty::supports(format) => format,
+        Ok(format) => {
+            let message = format!("Formatting lays out JSON, NDJSON, YAML or TOML documents, not {}", format.name());
+            return Err(ApiError::BadRequest(message));
+        }
+        Err(e) => return Err(ApiError::BadRequest(format!("Invalid format: {e}"))),
+    };
+    // The document is the caller's to fix, so one that does not parse is a bad request
+    let content = state
+        .formats
+        .format_document(format, &payload.content, &payload.options)
+        .map_err(|e| ApiError::BadRequest(format!("Formatting failed: {e:#}")))?;
+    Ok(Json(FormatResponse {
+        changed: content != payload.content,
+        content,
+    }))
+}
+
+/// Diff documents handler
+async fn diff_documents(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(payload): Json<DiffRequest>,
+) -> Result<Json<DiffResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    let resolve = |document: &DiffDocument| {
+        state
+            .formats
+            .resolve(&document.format)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid format: {e}")))
+    };
+    let (before, after) = (resolve(&payload.before)?, resolve(&payload.after)?);
+    // Either document is the caller's to fix, so one that does not parse is a bad request
+    let changes = state
+        .formats
+        .diff(&payload.before.content, &before, &payload.after.content, &after)
+        .map_err(|e| ApiError::BadRequest(format!("Diff failed: {e:#}")))?;
+    Ok(Json(DiffResponse { changes }))
+}
+
+/// Infer a schema from sample documents handler
+async fn infer_schema(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(payload): Json<InferSchemaRequest>,
+) -> Result<Json<InferSchemaResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
+    if payload.documents.is_empty() {
+        return Err(ApiError::BadRequest("At least one document is needed to infer a schema".to_string()));
+    }
+    let formats = payload
+        .documents
+        .iter()
+        .map(|document| {
+            state
+                .formats
+                .resolve(&document.format)
+                .map_err(|e| ApiError::BadRequest(format!("Invalid format: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let samples: Vec<_> = payload.documents.iter().zip(&formats).map(|(document, format)| (document.content.as_str(), format)).collect();
+    let schema = state
+        .formats
+        .infer_schema(&samples, &payload.options)
+        .map_err(|e| ApiError::BadRequest(format!("Inference failed: {e:#}")))?;
+    Ok(Json(InferSchemaResponse { schema }))
 }
 
 /// Validate document handler
 async fn validate_document(
-    State(_state): State<Arc<ServerState>>,
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
     Json(payload): Json<serde_json::Value>,
-) -> Result<Json<serde_json::Value>, ApiError> {
+) -> Result<Json<ValidateResponse>, ApiError> {
+    require_scope(&state, &caller, roles::READ)?;
     let content = payload
         .get("content")
         .and_then(|v| v.as_str())
@@ -161,15 +575,58 @@ async fn validate_document(
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::BadRequest("Missing 'format' field".to_string()))?;
 
-    let format = Format::from_str(format_str)
+    validate(&state, content, format_str, payload.get("schema")).map(Json)
+}
+
+/// Validate `content` as the named format, and against `schema` if given; diagnostics do not make it fail
+pub(crate) fn validate(
+    state: &ServerState,
+    content: &str,
+    format: &str,
+    schema: Option<&serde_json::Value>,
+) -> Result<ValidateResponse, ApiError> {
+    let format = state
+        .formats
+        .resolve(format)
         .map_err(|e| ApiError::BadRequest(format!("Invalid format: {}", e)))?;
+    let schema = schema.map(|schema| request_schema(state, schema)).transpose()?;
+    let checked = match &format {
+        FormatRef::BuiltIn(format) if schema::supports(*format) => Some(*format),
+        _ => None,
+    };
+    if schema.is_some() && checked.is_none() {
+        let message = format!("Schemas check JSON, YAML, TOML or Markdown documents, not {}", format.name());
+        return Err(ApiError::BadRequest(message));
+    }
 
-    match ConversionCore::validate(content, format) {
-        Ok(diagnostics) => Ok(Json(serde_json::json!({
-            "valid": diagnostics.is_empty(),
-            "diagnostics": diagnostics,
-        }))),
-        Err(e) => Err(ApiError::Internal(format!("Validation failed: {}", e))),
+    let diagnostics = state
+        .formats
+        .validate_any(content, &format)
+        .map_err(|e| ApiError::Internal(format!("Validation failed: {e:#}")))?;
+    let violations = match (schema, checked) {
+        // A document that does not parse has nothing to check
+        (Some(schema), Some(format)) if diagnostics.is_empty() => state
+            .formats
+            .validate_schema(content, format, &schema)
+            .map_err(|e| ApiError::Internal(format!("Validation failed: {e:#}")))?,
+        _ => Vec::new(),
+    };
+    Ok(ValidateResponse {
+        valid: diagnostics.is_empty() && violations.is_empty(),
+        diagnostics,
+        violations,
+    })
+}
+
+/// The schema a request names, or gives inline
+fn request_schema(state: &ServerState, schema: &serde_json::Value) -> Result<Arc<Schema>, ApiError> {
+    match schema {
+        serde_json::Value::String(name) => {
+            state.formats.schemas().get(name).ok_or_else(|| ApiError::BadRequest(format!("Unknown schema: {name}")))
+        }
+        schema => Schema::new(schema.clone())
+            .map(Arc::new)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid schema: {e:#}"))),
     }
 }
 
@@ -177,16 +634,37 @@ async fn validate_document(
 async fn get_stats(State(state): State<Arc<ServerState>>) -> Json<ServerStats> {
     Json(ServerStats {
         document_count: state.documents.count(),
-        uptime_seconds: 0, // TODO: Track actual uptime
-        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_seconds: state.metrics.uptime().as_secs(),
+        version: build_info::VERSION.to_string(),
+    })
+}
+
+/// Version handler
+async fn get_version(State(state): State<Arc<ServerState>>) -> Json<VersionResponse> {
+    Json(VersionResponse {
+        build: BuildInfo::current(),
+        uptime_seconds: state.metrics.uptime().as_secs(),
     })
 }
 
+/// Everything this server supports, as the LSP and WebSocket handshakes list it
+async fn get_capabilities(State(state): State<Arc<ServerState>>) -> Json<serde_json::Value> {
+    Json(state.capabilities.to_value())
+}
+
+/// Public keys of the tokens this server signs, for clients to check them with
+///
+/// Empty unless authentication is on with RS256 or ES256 and a private key.
+async fn get_jwks(State(state): State<Arc<ServerState>>) -> Json<jsonwebtoken::jwk::JwkSet> {
+    let keys = state.auth_service.as_ref().map_or_else(Vec::new, |auth| auth.jwks().keys);
+    Json(jsonwebtoken::jwk::JwkSet { keys })
+}
+
 /// Health check handler (basic)
 async fn health_check() -> Json<HealthResponse> {
     Json(HealthResponse {
         status: "healthy".to_string(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
+        version: build_info::VERSION.to_string(),
     })
 }
 
@@ -194,10 +672,165 @@ async fn health_check() -> Json<HealthResponse> {
 async fn detailed_health_check(
     State(state): State<Arc<ServerState>>,
 ) -> Json<crate::monitoring::HealthStatus> {
-    let health = state.health_checker.check(&state.metrics).await;
+    let health = state.health_checker.check().await;
     Json(health)
 }
 
+/// Liveness probe: 503 once a restart would help
+async fn liveness_probe(State(state): State<Arc<ServerState>>) -> (StatusCode, Json<crate::monitoring::HealthStatus>) {
+    let health = state.health_checker.check().await;
+    probe_response(health.lifecycle.live, health)
+}
+
+/// Readiness probe: 503 while the server should not receive traffic
+async fn readiness_probe(State(state): State<Arc<ServerState>>) -> (StatusCode, Json<crate::monitoring::HealthStatus>) {
+    let health = state.health_checker.check().await;
+    probe_response(health.lifecycle.ready, health)
+}
+
+/// Startup probe: 503 until startup completes
+async fn startup_probe(State(state): State<Arc<ServerState>>) -> (StatusCode, Json<crate::monitoring::HealthStatus>) {
+    let health = state.health_checker.check().await;
+    probe_response(health.lifecycle.started, health)
+}
+
+fn probe_response(
+    passing: bool,
+    health: crate::monitoring::HealthStatus,
+) -> (StatusCode, Json<crate::monitoring::HealthStatus>) {
+    let status = if passing { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+/// Credentials of a request: its headers, and the extensions holding its verified signature or certificate, if any
+///
+/// Also where the request came from and what it asked for, for the audit log.
+struct Caller {
+    headers: HeaderMap,
+    extensions: Extensions,
+    source: Option<IpAddr>,
+    resource: String,
+}
+
+impl Caller {
+    /// An audit event of `kind` about this request
+    fn audit(&self, kind: AuditKind) -> AuditEvent {
+        AuditEvent::new(kind, "http").source(self.source).resource(self.resource.clone())
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<ServerState>> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<ServerState>) -> Result<Self, Infallible> {
+        Ok(Self {
+            headers: parts.headers.clone(),
+            extensions: parts.extensions.clone(),
+            source: request_source(state, &parts.extensions, &parts.headers),
+            resource: format!("{} {}", parts.method, parts.uri.path()),
+        })
+    }
+}
+
+/// Who a caller is, as the [`Authenticate`](crate::auth::middleware::Authenticate) layer found
+///
+/// Requests that did not pass the layer have their credentials checked here.
+fn caller_authentication(auth: &AuthService, headers: &HeaderMap, extensions: &Extensions) -> Authentication {
+    extensions
+        .get::<Authentication>()
+        .cloned()
+        .unwrap_or_else(|| Credentials::of(headers, extensions).authenticate(auth))
+}
+
+/// Claims of a caller's bearer token or API key, or else of its verified signature or client certificate
+fn caller_claims(auth: &AuthService, headers: &HeaderMap, extensions: &Extensions) -> Result<Claims, ApiError> {
+    let authentication = caller_authentication(auth, headers, extensions);
+    authentication.require().cloned().map_err(ApiError::from)
+}
+
+/// Require a bearer token with the admin scope when authentication is enabled
+fn require_admin(state: &ServerState, caller: &Caller) -> Result<(), ApiError> {
+    require_scope(state, caller, roles::ADMIN)
+}
+
+/// Require a bearer token or client certificate with `scope`, directly or through a role
+///
+/// A caller without credentials acts with the anonymous claims, which grant
+/// everything with authentication disabled unless `anonymous_read_only` is set.
+fn require_scope(state: &ServerState, caller: &Caller, scope: &str) -> Result<(), ApiError> {
+    let authentication = match &state.auth_service {
+        Some(auth) => caller_authentication(auth, &caller.headers, &caller.extensions),
+        None => Authentication::Anonymous,
+    };
+    let claims = match authentication {
+        Authentication::Authenticated(claims) => claims,
+        // Invalid credentials are recorded as they arrive, by the Authenticate layer
+        Authentication::Invalid(detail) => return Err(ApiError::Unauthorized(detail)),
+        Authentication::Anonymous => state.anonymous_claims().ok_or_else(|| {
+            state.audit.record(caller.audit(AuditKind::AuthorizationDenied).detail("Missing credentials"));
+            ApiError::Unauthorized("Missing bearer token".to_string())
+        })?,
+    };
+    if claims.has_scope(scope) {
+        Ok(())
+    } else {
+        let detail = format!("Requires the {scope} scope");
+        let client = claims.client_name().map(str::to_string);
+        state.audit.record(
+            caller.audit(AuditKind::AuthorizationDenied).subject(Some(claims.sub)).client(client).detail(&detail),
+        );
+        Err(ApiError::Forbidden(detail))
+    }
+}
+
+/// Subject and client name of `caller`, if it presented valid credentials
+fn caller_identity(state: &ServerState, caller: &Caller) -> (Option<String>, Option<String>) {
+    let claims = state
+        .auth_service
+        .as_ref()
+        .and_then(|auth| caller_claims(auth, &caller.headers, &caller.extensions).ok());
+    match claims {
+        Some(claims) => {
+            let client = claims.client_name().map(str::to_string);
+            (Some(claims.sub), client)
+        }
+        None => (None, None),
+    }
+}
+
+/// Active log filter handler
+async fn get_log_filter(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<LogFilter>, ApiError> {
+    require_admin(&state, &caller)?;
+    let logging = log_handle(&state)?;
+    Ok(Json(LogFilter { filter: logging.filter() }))
+}
+
+/// Replace the log filter without a restart
+async fn set_log_filter(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<LogFilter>,
+) -> Result<Json<LogFilter>, ApiError> {
+    require_admin(&state, &caller)?;
+    let logging = log_handle(&state)?;
+    logging
+        .set_filter(&request.filter)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    info!("Log filter changed to {}", request.filter);
+    Ok(Json(LogFilter { filter: logging.filter() }))
+}
+
+fn log_handle(state: &ServerState) -> Result<&crate::LogHandle, ApiError> {
+    state
+        .logging
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Runtime log control is not available".to_string()))
+}
+
 /// Metrics snapshot handler (Platinum RSR)
 async fn get_metrics(
     State(state): State<Arc<ServerState>>,
@@ -206,103 +839,1398 @@ async fn get_metrics(
     Json(snapshot)
 }
 
-/// Create HTTP router
-fn create_router(state: Arc<ServerState>) -> Router {
-    Router::new()
-        .route("/api/convert", post(convert_document))
-        .route("/api/documents", get(list_documents))
-        .route("/api/documents/:id", get(get_document))
-        .route("/api/documents/:id", delete(delete_document))
-        .route("/api/validate", post(validate_document))
-        .route("/api/stats", get(get_stats))
-        .route("/api/health", get(health_check))
-        .route("/api/health/detailed", get(detailed_health_check))  // Platinum RSR
-        .route("/api/metrics", get(get_metrics))  // Platinum RSR
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        )
-        .with_state(state)
+/// Metrics snapshot handler for admin tooling
+async fn get_admin_metrics(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<crate::monitoring::MetricsSnapshot>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(state.metrics.snapshot()))
 }
 
-/// Run HTTP server
-pub async fn run_http_server(state: Arc<ServerState>, addr: &str) -> Result<()> {
-    let app = create_router(state);
+/// Empty every rate window, for admin tooling
+async fn reset_rate_windows(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<StatusCode, ApiError> {
+    require_admin(&state, &caller)?;
+    state.metrics.reset_windows();
+    info!("Rate windows reset");
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    info!("HTTP server listening on {}", addr);
+/// Whether each metric is recorded, and how often histograms sample
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetricControlsResponse {
+    pub metrics: Vec<MetricControl>,
+}
 
-    axum::serve(listener, app).await?;
+/// Metric recording settings handler for admin tooling
+async fn get_metric_controls(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<MetricControlsResponse>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(MetricControlsResponse {
+        metrics: state.metrics.controls(),
+    }))
+}
 
-    Ok(())
+/// Replace the metric recording settings without a restart
+async fn set_metric_controls(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<MetricsConfig>,
+) -> Result<Json<MetricControlsResponse>, ApiError> {
+    require_admin(&state, &caller)?;
+    state
+        .metrics
+        .apply(&request)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    info!("Metric settings changed: disabled {:?}, sampling {:?}", request.disabled, request.sampling);
+    Ok(Json(MetricControlsResponse {
+        metrics: state.metrics.controls(),
+    }))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ServerConfig;
-    use axum::body::Body;
-    use axum::http::{Request, StatusCode};
-    use tower::ServiceExt;
+/// Recent slow operations, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SlowOpsResponse {
+    pub operations: Vec<SlowOp>,
+}
 
-    fn create_test_state() -> Arc<ServerState> {
-        Arc::new(ServerState::new(ServerConfig::default()))
-    }
+/// Slow operation ring handler for admin tooling
+async fn get_slow_ops(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<SlowOpsResponse>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(SlowOpsResponse {
+        operations: state.slow_ops.recent(),
+    }))
+}
 
-    #[tokio::test]
-    async fn test_health_check() {
-        let state = create_test_state();
-        let app = create_router(state);
+/// Connection summary handler for admin tooling
+///
+/// Authenticated subjects are counted, never listed.
+async fn get_connections(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<ConnectionsSummary>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(ConnectionsSummary {
+        authenticated_subjects: state.ws_admission.subjects() as u64,
+        ..state.metrics.connections.summary()
+    }))
+}
 
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/health")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// Connected clients handler for admin tooling
+async fn get_clients(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<ClientRecord>>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(state.clients.list()))
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
-    }
+/// Maintenance task status handler for admin tooling
+async fn get_tasks(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<TaskStatus>>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(state.scheduler.list()))
+}
 
-    #[tokio::test]
-    async fn test_convert_document() {
-        let state = create_test_state();
-        let app = create_router(state);
+/// Run a maintenance task now, outside its schedule
+///
+/// Refused while the task is already running and may not overlap.
+async fn run_task(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Option<TaskStatus>>), ApiError> {
+    require_admin(&state, &caller)?;
+    state.scheduler.run_now(&name).map_err(|e| match e {
+        RunNowError::Unknown(_) => ApiError::NotFound(e.to_string()),
+        RunNowError::AlreadyRunning(_) | RunNowError::ShuttingDown => ApiError::Conflict(e.to_string()),
+    })?;
+    Ok((StatusCode::ACCEPTED, Json(state.scheduler.get(&name))))
+}
 
-        let payload = serde_json::json!({
-            "content": "# Hello World",
-            "from": "markdown",
-            "to": "html"
-        });
+/// Firing alerts and sink delivery handler for admin tooling
+///
+/// Sink URLs are reduced to their host.
+async fn get_alerts(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<AlertsSummary>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(state.alerts.summary()))
+}
 
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .method("POST")
-                    .uri("/api/convert")
-                    .header("content-type", "application/json")
-                    .body(Body::from(serde_json::to_string(&payload).unwrap()))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// API key to create
+#[derive(Debug, Deserialize)]
+struct CreateApiKey {
+    name: String,
+    /// Subject the key's requests are accounted to; the name if unset
+    subject: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
-    }
+/// A created API key, with the key itself, which is not shown again
+#[derive(Debug, Serialize)]
+struct CreatedApiKey {
+    api_key: String,
+    #[serde(flatten)]
+    record: ApiKey,
+}
 
-    #[tokio::test]
-    async fn test_list_documents() {
-        let state = create_test_state();
+fn api_key_store(state: &ServerState) -> Result<&Arc<ApiKeyStore>, ApiError> {
+    state
+        .auth_service
+        .as_ref()
+        .and_then(|auth| auth.api_keys())
+        .ok_or_else(|| ApiError::NotFound("API keys are kept only with authentication enabled".to_string()))
+}
 
-        // Add a test document
-        state.documents.upsert(
-            "file:///test.md".to_string(),
-            "# Test".to_string(),
+/// API keys handler for admin tooling, revoked and expired ones included
+async fn list_api_keys(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    require_admin(&state, &caller)?;
+    let keys = api_key_store(&state)?.list().map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    Ok(Json(keys))
+}
+
+/// Create an API key
+async fn create_api_key(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<CreateApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ApiError> {
+    require_admin(&state, &caller)?;
+    let store = api_key_store(&state)?;
+    if request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("An API key needs a name".to_string()));
+    }
+    if request.expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now()) {
+        return Err(ApiError::BadRequest("expires_at is in the past".to_string()));
+    }
+    let subject = request.subject.unwrap_or_else(|| request.name.clone());
+    let (record, api_key) = store
+        .create(request.name, subject, request.scopes, request.expires_at)
+        .map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    info!("Created API key {} ({}) for {}", record.id, record.name, record.subject);
+    audit_admin(&state, &caller, AuditKind::TokenIssued, format!("API key {} ({}) for {}", record.id, record.name, record.subject));
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { api_key, record })))
+}
+
+/// Revoke an API key; requests made with it are refused from then on
+async fn revoke_api_key(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<Json<ApiKey>, ApiError> {
+    require_admin(&state, &caller)?;
+    let revoked = api_key_store(&state)?.revoke(&id).map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    let revoked = revoked.ok_or_else(|| ApiError::NotFound(format!("No API key {id}")))?;
+    info!("Revoked API key {} ({})", revoked.id, revoked.name);
+    audit_admin(&state, &caller, AuditKind::TokenRevoked, format!("API key {} ({}) of {}", revoked.id, revoked.name, revoked.subject));
+    Ok(Json(revoked))
+}
+
+/// Refresh token to exchange
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Exchange a refresh token for an access token and the next refresh token
+///
+/// Needs no bearer token: the refresh token is the credential. Clients
+/// presenting invalid ones are slowed down, then locked out.
+async fn refresh_token(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<TokenPair>, ApiError> {
+    let (auth, _) = refresh_tokens(&state)?;
+    auth.throttle().check(caller.source, None).map_err(ApiError::Throttled)?;
+    let pair = auth.refresh(&request.refresh_token).map_err(|e| match e.downcast_ref::<TokenError>() {
+        Some(_) => {
+            auth.throttle().record_failure(caller.source, None);
+            state.audit.record(caller.audit(AuditKind::AuthenticationFailed).detail(format!("Invalid refresh token: {e}")));
+            ApiError::Unauthorized(format!("Invalid refresh token: {e}"))
+        }
+        None => ApiError::Internal(format!("{e:#}")),
+    })?;
+    let subject = auth.validate_token(&pair.access_token).ok().map(|claims| claims.sub);
+    let detail = format!("Access token from refresh token family {}", pair.family);
+    state.audit.record(caller.audit(AuditKind::TokenIssued).subject(subject).detail(detail));
+    Ok(Json(pair))
+}
+
+/// Directory credentials to log in with; deliberately not `Debug`, so the password is never logged
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// Log in with directory credentials, for an access token and a refresh token
+///
+/// Needs no bearer token: the username and password are the credential.
+/// Failures are throttled per client IP and per username.
+async fn login(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<LoginRequest>,
+) -> Result<Json<TokenPair>, ApiError> {
+    let (auth, _) = refresh_tokens(&state)?;
+    if !auth.has_directory() {
+        return Err(ApiError::NotFound("Logins need a directory, configured under [ldap]".to_string()));
+    }
+    // Refused before the directory is asked, so a locked out password cannot be confirmed either
+    auth.throttle().check(caller.source, Some(&request.username)).map_err(|throttled| {
+        let detail = format!("Directory login as {} refused: {}", request.username, throttled);
+        state.audit.record(caller.audit(AuditKind::AuthenticationFailed).subject(Some(&request.username)).detail(detail));
+        ApiError::Throttled(throttled)
+    })?;
+    let pair = auth.login(&request.username, &request.password).await.map_err(|e| match e.downcast_ref::<LoginError>() {
+        Some(LoginError::InvalidCredentials) => {
+            auth.throttle().record_failure(caller.source, Some(&request.username));
+            let detail = format!("Directory login as {}", request.username);
+            state.audit.record(caller.audit(AuditKind::AuthenticationFailed).subject(Some(&request.username)).detail(detail));
+            ApiError::Unauthorized(LoginError::InvalidCredentials.to_string())
+        }
+        Some(error @ LoginError::Unavailable(_)) => {
+            warn!("Directory login as {} not checked: {}", request.username, error);
+            ApiError::Unavailable(error.to_string())
+        }
+        None => ApiError::Internal(format!("{e:#}")),
+    })?;
+    auth.throttle().record_success(&request.username);
+    info!("{} logged in with directory credentials", request.username);
+    let subject = auth.validate_token(&pair.access_token).ok().map(|claims| claims.sub);
+    let detail = format!("Refresh token family {} from a directory login", pair.family);
+    state.audit.record(caller.audit(AuditKind::TokenIssued).subject(subject).detail(detail));
+    Ok(Json(pair))
+}
+
+/// Response an identity provider posts with the HTTP-POST binding
+#[derive(Deserialize)]
+struct SamlPost {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+}
+
+/// Sign in with a SAML 2.0 assertion, for an access token and a refresh token
+///
+/// Needs no bearer token: the assertion, signed by the identity provider, is the credential.
+async fn consume_saml_assertion(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Form(post): Form<SamlPost>,
+) -> Result<Json<TokenPair>, ApiError> {
+    let (auth, _) = refresh_tokens(&state)?;
+    if !auth.has_saml() {
+        return Err(ApiError::NotFound("SAML sign-on needs an identity provider, configured under [saml]".to_string()));
+    }
+    let pair = auth.saml_sign_on(&post.saml_response).map_err(|e| match e.downcast_ref::<SamlError>() {
+        Some(error) => {
+            let detail = format!("SAML sign-on refused: {error}");
+            state.audit.record(caller.audit(AuditKind::AuthenticationFailed).detail(detail));
+            ApiError::Unauthorized(format!("Invalid SAML response: {error}"))
+        }
+        None => ApiError::Internal(format!("{e:#}")),
+    })?;
+    let subject = auth.validate_token(&pair.access_token).ok().map(|claims| claims.sub);
+    info!("{} signed in with a SAML assertion", subject.as_deref().unwrap_or("unknown user"));
+    let detail = format!("Refresh token family {} from a SAML assertion", pair.family);
+    state.audit.record(caller.audit(AuditKind::TokenIssued).subject(subject).detail(detail));
+    Ok(Json(pair))
+}
+
+/// Token to introspect, form-encoded as RFC 7662 asks
+///
+/// A `token_type_hint` may be sent too, and is ignored: every kind of token is tried.
+#[derive(Deserialize)]
+struct IntrospectRequest {
+    token: String,
+}
+
+/// Say whether a token is active and what it grants, for sidecars and proxies (RFC 7662)
+///
+/// The caller needs the introspect scope, so tokens cannot be probed anonymously.
+async fn introspect_token(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Form(request): Form<IntrospectRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let auth = state
+        .auth_service
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Tokens are introspected only with authentication enabled".to_string()))?;
+    require_scope(&state, &caller, roles::INTROSPECT)?;
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(auth.introspect(&request.token))))
+}
+
+/// Refresh token family to start
+#[derive(Debug, Deserialize)]
+struct IssueRefreshToken {
+    subject: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// Carried into the family's access tokens
+    client_name: Option<String>,
+}
+
+fn refresh_tokens(state: &ServerState) -> Result<(&Arc<AuthService>, &Arc<RefreshTokenStore>), ApiError> {
+    state
+        .auth_service
+        .as_ref()
+        .and_then(|auth| Some((auth, auth.refresh_tokens()?)))
+        .ok_or_else(|| ApiError::NotFound("Refresh tokens are issued only with authentication enabled".to_string()))
+}
+
+/// Record an event of `kind` about a credential an admin managed
+fn audit_admin(state: &ServerState, caller: &Caller, kind: AuditKind, detail: String) {
+    let (subject, client) = caller_identity(state, caller);
+    state.audit.record(caller.audit(kind).subject(subject).client(client).detail(detail));
+}
+
+/// Refresh token families handler for admin tooling, revoked and expired ones included
+async fn list_refresh_tokens(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<RefreshFamily>>, ApiError> {
+    require_admin(&state, &caller)?;
+    let families = refresh_tokens(&state)?.1.list().map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    Ok(Json(families))
+}
+
+/// Issue a refresh token, such as for an editor plugin to be set up with
+async fn issue_refresh_token(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<IssueRefreshToken>,
+) -> Result<(StatusCode, Json<TokenPair>), ApiError> {
+    require_admin(&state, &caller)?;
+    let (auth, _) = refresh_tokens(&state)?;
+    if request.subject.trim().is_empty() {
+        return Err(ApiError::BadRequest("A refresh token needs a subject".to_string()));
+    }
+    let pair = auth
+        .issue_refresh_token(request.subject.clone(), request.scopes, request.client_name)
+        .map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    info!("Issued refresh token family {} for {}", pair.family, request.subject);
+    audit_admin(&state, &caller, AuditKind::TokenIssued, format!("Refresh token family {} for {}", pair.family, request.subject));
+    Ok((StatusCode::CREATED, Json(pair)))
+}
+
+/// Revoke a refresh token family; its refresh tokens are refused from then on
+///
+/// Access tokens already issued to the family stay good until they expire.
+async fn revoke_refresh_token(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<Json<RefreshFamily>, ApiError> {
+    require_admin(&state, &caller)?;
+    let revoked = refresh_tokens(&state)?.1.revoke(&id).map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    let revoked = revoked.ok_or_else(|| ApiError::NotFound(format!("No refresh token family {id}")))?;
+    info!("Revoked refresh token family {} of {}", revoked.id, revoked.subject);
+    audit_admin(&state, &caller, AuditKind::TokenRevoked, format!("Refresh token family {} of {}", revoked.id, revoked.subject));
+    Ok(Json(revoked))
+}
+
+/// Token to revoke: the token itself or, when only that is known, its `jti`
+#[derive(Debug, Deserialize)]
+struct RevokeToken {
+    token: Option<String>,
+    jti: Option<String>,
+}
+
+fn auth_service(state: &ServerState) -> Result<&Arc<AuthService>, ApiError> {
+    state
+        .auth_service
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Tokens are revoked only with authentication enabled".to_string()))
+}
+
+/// Revoked tokens handler for admin tooling, until they expire
+async fn list_revoked_tokens(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<RevokedToken>>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(auth_service(&state)?.revocations().list()))
+}
+
+/// Revoke a token before it expires; requests made with it are refused from then on
+async fn revoke_token(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<RevokeToken>,
+) -> Result<Json<RevokedToken>, ApiError> {
+    require_admin(&state, &caller)?;
+    let auth = auth_service(&state)?;
+    let revoked = match (request.token, request.jti) {
+        (Some(token), None) => auth.revoke_token(&token).map_err(|e| match e.downcast_ref::<TokenError>() {
+            Some(_) => ApiError::BadRequest(format!("Invalid token: {e}")),
+            None => ApiError::Internal(format!("{e:#}")),
+        })?,
+        (None, Some(jti)) if !jti.trim().is_empty() => {
+            auth.revoke_jti(&jti).map_err(|e| ApiError::Internal(format!("{e:#}")))?
+        }
+        _ => return Err(ApiError::BadRequest("Give either a token or its jti".to_string())),
+    };
+    let subject = revoked.subject.as_deref().unwrap_or("an unknown subject");
+    info!("Revoked token {} of {}", revoked.jti, subject);
+    audit_admin(&state, &caller, AuditKind::TokenRevoked, format!("Token {} of {}", revoked.jti, subject));
+    Ok(Json(revoked))
+}
+
+/// Key to rotate in: generated unless a PEM private key is given
+#[derive(Debug, Deserialize)]
+struct RotateKey {
+    private_key: Option<String>,
+}
+
+/// Signing keys rotated in, newest first
+async fn list_signing_keys(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+) -> Result<Json<Vec<KeyInfo>>, ApiError> {
+    require_admin(&state, &caller)?;
+    Ok(Json(auth_service(&state)?.keyring().list()))
+}
+
+/// Sign new tokens with a new key; tokens signed with the previous one stay valid until they expire
+async fn rotate_signing_key(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Json(request): Json<RotateKey>,
+) -> Result<(StatusCode, Json<KeyInfo>), ApiError> {
+    require_admin(&state, &caller)?;
+    let key = auth_service(&state)?
+        .rotate_signing_key(request.private_key.as_deref())
+        .map_err(|e| ApiError::BadRequest(format!("Key not rotated in: {e:#}")))?;
+    info!("Rotated in {} signing key {}", key.algorithm.as_str(), key.kid);
+    audit_admin(&state, &caller, AuditKind::KeyRotated, format!("{} signing key {}", key.algorithm.as_str(), key.kid));
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// Window and length of a usage report
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    /// Such as `15m`, `1h` or `1d`
+    window: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Most active subjects handler for admin tooling
+async fn get_usage(
+    State(state): State<Arc<ServerState>>,
+    caller: Caller,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageReport>, ApiError> {
+    require_admin(&state, &caller)?;
+    let window = match query.window.as_deref() {
+        Some(window) => usage::parse_window(window).map_err(|e| ApiError::BadRequest(e.to_string()))?,
+        None => Duration::from_hours(1),
+    };
+    Ok(Json(state.usage.top(window, query.limit.unwrap_or(20))))
+}
+
+/// Prometheus scrape handler
+async fn get_prometheus_metrics(State(state): State<Arc<ServerState>>) -> Response {
+    let body = crate::monitoring::prometheus::encode(&state.metrics.gather());
+    ([(header::CONTENT_TYPE, crate::monitoring::prometheus::CONTENT_TYPE)], body).into_response()
+}
+
+/// Status codes reported exactly; others are reported by class
+const EXACT_STATUS_CODES: [u16; 7] = [401, 403, 404, 409, 412, 413, 429];
+
+/// Metrics label for a response status: the code if allowlisted, else its class
+fn status_label(status: StatusCode) -> String {
+    let code = status.as_u16();
+    if EXACT_STATUS_CODES.contains(&code) {
+        code.to_string()
+    } else {
+        format!("{}xx", code / 100)
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+}
+
+/// Account each request to the subject the [`Authenticate`](crate::auth::middleware::Authenticate) layer found, if any
+///
+/// The subject is left in the request extensions for handlers that account
+/// further usage, and in the connection's client record. Requests without
+/// valid credentials are anonymous; refusing them is left to the handlers
+/// that require them.
+async fn account_usage(State(state): State<Arc<ServerState>>, mut request: Request, next: Next) -> Response {
+    let claims = request.extensions().get::<Authentication>().and_then(Authentication::claims);
+    let client = claims.map_or_else(Client::anonymous, Client::from_claims);
+    let size = content_length(request.headers()).unwrap_or(0);
+    state.usage.record(&client, Counts::request(size as u64));
+    if let Some(registered) = request.extensions().get::<Arc<RegisteredClient>>() {
+        registered.touch();
+        if !registered.read(|record| record.subject == client.subject && record.name == client.name) {
+            registered.update(|record| {
+                record.subject.clone_from(&client.subject);
+                record.name.clone_from(&client.name);
+            });
+        }
+    }
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+/// Paths never rate limited, so probes and scrapes from one address keep working
+const UNLIMITED_PATHS: &[&str] = &["/healthz", "/readyz", "/startupz", "/metrics"];
+
+/// Largest body of a signed request, in bytes, matching the default limit of the JSON extractor
+const SIGNED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Verify the `X-Signature` header of a signed request, leaving its [`Signer`] in the request extensions
+///
+/// The body is read in full to check its digest, then handed on unchanged.
+async fn verify_signature(State(state): State<Arc<ServerState>>, request: Request, next: Next) -> Response {
+    let Some(auth) = state.auth_service.as_ref() else {
+        return next.run(request).await;
+    };
+    let Some(header) = request.headers().get(signing::HEADER).cloned() else {
+        return next.run(request).await;
+    };
+    let event = audit_request(&state, AuditKind::AuthenticationFailed, &request);
+    let (mut parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, SIGNED_BODY_LIMIT).await else {
+        return ApiError::BadRequest(format!("Signed request bodies are limited to {SIGNED_BODY_LIMIT} bytes")).into_response();
+    };
+    let path = parts.uri.path_and_query().map_or_else(|| parts.uri.path(), |path| path.as_str());
+    let verified = header
+        .to_str()
+        .map_err(|_| signing::SignatureError::Malformed("not ASCII".to_string()))
+        .and_then(|header| auth.verify_signature(parts.method.as_str(), path, header, &bytes));
+    match verified {
+        Ok(signer) => {
+            parts.extensions.insert(Arc::new(signer));
+            next.run(Request::from_parts(parts, Body::from(bytes))).await
+        }
+        Err(e) => {
+            state.audit.record(event.detail(e.to_string()));
+            ApiError::Unauthorized(format!("Invalid signature: {e}")).into_response()
+        }
+    }
+}
+
+/// Take the access token of a browser client from its cookie, refusing requests that change anything without the CSRF token
+///
+/// A request presenting a token in a header is left alone, as a forged
+/// request cannot set headers. A response to a client without a CSRF
+/// token issues one in a cookie.
+async fn protect_csrf(State(state): State<Arc<ServerState>>, mut request: Request, next: Next) -> Response {
+    let config = state.config();
+    let csrf = &config.csrf;
+    if !csrf.enabled {
+        return next.run(request).await;
+    }
+    let headers = request.headers();
+    let cookies = || headers.get_all(header::COOKIE).iter().filter_map(|value| value.to_str().ok());
+    let issued = csrf::cookie(cookies(), &csrf.cookie).map(str::to_string);
+    let token = csrf::cookie(cookies(), &csrf.token_cookie).filter(|_| bearer_token(headers).is_none());
+    if let Some(token) = token {
+        let safe = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        let repeated = headers.get(csrf.header.as_str()).and_then(|value| value.to_str().ok());
+        if !safe && !csrf::matches(issued.as_deref(), repeated) {
+            let detail = format!("Missing or mismatched {} header", csrf.header);
+            state.audit.record(audit_request(&state, AuditKind::AuthorizationDenied, &request).detail(&detail));
+            return ApiError::Forbidden(detail).into_response();
+        }
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {token}")) {
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+    }
+
+    let mut response = next.run(request).await;
+    if issued.is_none() {
+        match csrf::generate() {
+            Ok(token) => {
+                if let Ok(cookie) = HeaderValue::from_str(&csrf.set_cookie(&token)) {
+                    response.headers_mut().append(header::SET_COOKIE, cookie);
+                }
+            }
+            Err(e) => warn!("CSRF cookie not issued: {:#}", e),
+        }
+    }
+    response
+}
+
+/// Record request latency by route pattern, method and status
+///
+/// The route pattern rather than the raw path keeps document IDs out of
+/// the labels, and requests matching no route share `unmatched`.
+async fn record_latency(
+    State(state): State<Arc<ServerState>>,
+    route: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = route.map_or_else(|| "unmatched".to_string(), |route| route.as_str().to_string());
+    let method = request.method().clone();
+    let size = content_length(request.headers());
+    let start = Instant::now();
+
+    let in_flight = state.metrics.connections.request();
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    let elapsed = start.elapsed();
+    let status = status_label(response.status());
+    state
+        .metrics
+        .http_request_duration
+        .with_labels(&[&route, method.as_str(), &status])
+        .observe_duration(elapsed);
+    let mut operation = Operation::new(OpKind::Http, format!("{method} {route}")).option("status", status);
+    if let Some(size) = size {
+        operation = operation.size(size);
+    }
+    state.slow_ops.record(operation, elapsed);
+    response
+}
+
+/// Run each request in a span, continuing the caller's trace from its `traceparent` header
+///
+/// The span carries a request ID, taken from the caller's `x-request-id`
+/// header when it sends a usable one, and returned in the same header.
+async fn trace_request(route: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let span = info_span!(
+        "http.request",
+        request_id = %request_id,
+        http.method = %request.method(),
+        http.route = route.as_ref().map_or("unmatched", MatchedPath::as_str),
+        http.status_code = field::Empty,
+    );
+    let traceparent = request.headers().get(telemetry::TRACEPARENT).and_then(|v| v.to_str().ok());
+    telemetry::set_parent(&span, traceparent);
+
+    let mut response = slow_ops::with_request_id(request_id.clone(), next.run(request).instrument(span.clone())).await;
+    span.record("http.status_code", response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Create HTTP router
+pub fn create_router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/api/convert", post(convert_document))
+        .route("/api/convert/stream", post(convert_stream))
+        .route("/api/documents", get(list_documents))
+        .route("/api/documents/:id", get(get_document))
+        .route("/api/documents/:id", delete(delete_document))
+        .route("/api/documents/:id/query", post(query_document))
+        .route("/api/validate", post(validate_document))
+        .route("/api/format", post(format_document))
+        .route("/api/diff", post(diff_documents))
+        .route("/api/schema/infer", post(infer_schema))
+        .route("/api/stats", get(get_stats))
+        .route("/api/version", get(get_version))
+        .route("/api/capabilities", get(get_capabilities))
+        .route("/.well-known/jwks.json", get(get_jwks))
+        .route("/auth/login", post(login))
+        .route("/auth/saml/acs", post(consume_saml_assertion))
+        .route("/auth/refresh", post(refresh_token))
+        .route("/auth/introspect", post(introspect_token))
+        .route("/api/health", get(health_check))
+        .route("/api/health/detailed", get(detailed_health_check))  // Platinum RSR
+        .route("/healthz", get(liveness_probe))
+        .route("/readyz", get(readiness_probe))
+        .route("/startupz", get(startup_probe))
+        .route("/api/metrics", get(get_metrics))  // Platinum RSR
+        .route("/metrics", get(get_prometheus_metrics))
+        .route("/api/admin/logging", get(get_log_filter).put(set_log_filter))
+        .route("/api/admin/metrics", get(get_admin_metrics))
+        .route("/api/admin/metrics/reset-windows", post(reset_rate_windows))
+        .route("/api/admin/metrics/controls", get(get_metric_controls).put(set_metric_controls))
+        .route("/api/admin/slow-ops", get(get_slow_ops))
+        .route("/api/admin/connections", get(get_connections))
+        .route("/api/admin/clients", get(get_clients))
+        .route("/api/admin/tasks", get(get_tasks))
+        .route("/api/admin/tasks/:name/run", post(run_task))
+        .route("/api/admin/usage", get(get_usage))
+        .route("/api/admin/alerts", get(get_alerts))
+        .route("/api/admin/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api/admin/api-keys/:id", delete(revoke_api_key))
+        .route("/api/admin/refresh-tokens", get(list_refresh_tokens).post(issue_refresh_token))
+        .route("/api/admin/refresh-tokens/:id", delete(revoke_refresh_token))
+        .route("/api/admin/tokens/revoked", get(list_revoked_tokens))
+        .route("/api/admin/tokens/revoke", post(revoke_token))
+        .route("/api/admin/signing-keys", get(list_signing_keys))
+        .route("/api/admin/signing-keys/rotate", post(rotate_signing_key))
+        .layer(EnforcePolicyLayer::new(Arc::clone(&state)))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state), account_usage))
+        .layer(RateLimitLayer::new(Arc::clone(&state)).exempt(UNLIMITED_PATHS))
+        .layer(AuthenticateLayer::new(Arc::clone(&state)))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state), verify_signature))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state), record_latency))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state), protect_csrf))
+        .layer(middleware::from_fn(trace_request))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods(Any)
+                .allow_headers(Any),
+        )
+        .with_state(state)
+}
+
+/// Serves the router on each accepted connection, counted and registered while the connection is open
+#[derive(Clone)]
+struct CountConnections {
+    router: Router,
+    connections: ConnectionMetrics,
+    clients: Arc<ClientRegistry>,
+}
+
+impl CountConnections {
+    /// Count and register a connection from `peer`, opened by the holder of `certificate`, if any
+    fn open(&self, peer: SocketAddr, certificate: Option<Arc<ClientCertificate>>) -> Counted {
+        let mut connection = self.connections.open(Transport::Http, ConnectionState::Active);
+        // hyper does not say how a connection ended
+        connection.set_reason(DisconnectReason::Closed);
+        Counted {
+            router: self.router.clone(),
+            _connection: Arc::new(connection),
+            client: Arc::new(self.clients.register(Transport::Http, &Client::anonymous())),
+            peer,
+            certificate,
+        }
+    }
+}
+
+impl Service<IncomingStream<'_>> for CountConnections {
+    type Response = Counted;
+    type Error = Infallible;
+    type Future = Ready<Result<Counted, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: IncomingStream<'_>) -> Self::Future {
+        ready(Ok(self.open(stream.remote_addr(), None)))
+    }
+}
+
+/// The router serving one connection, which is uncounted and deregistered once every clone is dropped
+#[derive(Clone)]
+struct Counted {
+    router: Router,
+    _connection: Arc<OpenConnection>,
+    /// Left in each request's extensions for [`account_usage`] to fill in
+    client: Arc<RegisteredClient>,
+    /// Address the connection came from, left in each request's extensions as [`ConnectInfo`]
+    peer: SocketAddr,
+    /// Client certificate the connection was authenticated with, left in each request's extensions
+    certificate: Option<Arc<ClientCertificate>>,
+}
+
+impl Service<Request> for Counted {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = <Router as Service<Request>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Service::<Request>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        request.extensions_mut().insert(Arc::clone(&self.client));
+        request.extensions_mut().insert(ConnectInfo(self.peer));
+        if let Some(certificate) = &self.certificate {
+            request.extensions_mut().insert(Arc::clone(certificate));
+        }
+        self.router.call(request)
+    }
+}
+
+/// Run HTTP server
+pub async fn run_http_server(state: Arc<ServerState>, addr: &str) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("HTTP server listening on {}", addr);
+
+    serve_http(state, listener).await
+}
+
+/// Serve the API on an already bound listener
+///
+/// # Errors
+///
+/// As [`serve_http_until`] does.
+pub async fn serve_http(state: Arc<ServerState>, listener: tokio::net::TcpListener) -> Result<()> {
+    serve_http_until(state, listener, std::future::pending()).await
+}
+
+/// Serve the API until `stop` completes, then finish the requests in flight
+///
+/// With `[mtls]` configured, connections are TLS and need a client certificate.
+///
+/// # Errors
+///
+/// Fails where the TLS settings of `[mtls]` cannot be loaded, or accepting
+/// connections fails.
+pub async fn serve_http_until<F>(state: Arc<ServerState>, listener: tokio::net::TcpListener, stop: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mtls = state.config().mtls.clone();
+    let connections = state.metrics.connections.clone();
+    let clients = Arc::clone(&state.clients);
+    let app = CountConnections {
+        router: create_router(state),
+        connections,
+        clients,
+    };
+
+    match mtls {
+        Some(mtls) => serve_tls(listener, mtls::acceptor(&mtls, &["h2", "http/1.1"])?, app, stop).await,
+        None => axum::serve(listener, app).with_graceful_shutdown(stop).await?,
+    }
+
+    Ok(())
+}
+
+/// Serve connections from clients with certificates, as [`serve_http_until`] does the rest
+async fn serve_tls<F>(listener: tokio::net::TcpListener, acceptor: TlsAcceptor, app: CountConnections, stop: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(stop);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept HTTP connection: {}", e);
+                    continue;
+                }
+            },
+            () = &mut stop => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let (stream, certificate) = match mtls::accept(&acceptor, stream).await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Refused TLS connection from {}: {:#}", peer, e);
+                    return;
+                }
+            };
+            let service = app.open(peer, Some(Arc::new(certificate))).map_request(|request: hyper::Request<Incoming>| request.map(Body::new));
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("HTTP connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::api_keys;
+    use crate::audit::{AuditConfig, AuditSinkConfig};
+    use crate::auth::directory::{Directory, DirectoryUser, LdapConfig};
+    use crate::auth::lockout::LockoutConfig;
+    use crate::auth::AuthConfig;
+    use crate::auth::policy::ScopePolicy;
+    use crate::auth::saml::SamlConfig;
+    use crate::auth::{RateLimitConfig, TierLimits};
+    use crate::formats::FormatLimits;
+    use crate::scheduler::{Schedule, TaskSpec};
+    use crate::ServerConfig;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use tower::ServiceExt;
+
+    fn create_test_state() -> Arc<ServerState> {
+        Arc::new(ServerState::new(ServerConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let state = create_test_state();
+        let app = create_router(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let state = create_test_state();
+        let app = create_router(Arc::clone(&state));
+        let probe = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        // Alive but not ready while starting
+        assert_eq!(probe("/healthz").await.0, StatusCode::OK);
+        assert_eq!(probe("/readyz").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        let (status, health) = probe("/startupz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health["lifecycle"]["state"], "starting");
+
+        state.health_checker.mark_started();
+        for uri in ["/healthz", "/readyz", "/startupz"] {
+            let (status, health) = probe(uri).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+            assert_eq!(health["checks"]["document_store"]["status"], "healthy");
+        }
+
+        // Draining: still alive, no longer ready
+        state.health_checker.begin_shutdown();
+        assert_eq!(probe("/healthz").await.0, StatusCode::OK);
+        let (status, health) = probe("/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health["lifecycle"]["state"], "draining");
+        assert!((state.metrics.lifecycle_state.with_labels(&["draining"]).get() - 1.0).abs() < f64::EPSILON);
+
+        state.health_checker.mark_stopped();
+        assert_eq!(probe("/healthz").await.0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_convert_document() {
+        let state = create_test_state();
+        let app = create_router(state);
+
+        let payload = serde_json::json!({
+            "content": "# Hello World",
+            "from": "markdown",
+            "to": "html"
+        });
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/convert")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_validate_against_schema() {
+        let state = create_test_state();
+        let schema = Schema::new(serde_json::json!({"required": ["name"]})).unwrap();
+        state.formats.schemas().register("named", schema, Vec::new()).unwrap();
+        let app = create_router(state);
+        let validate = |payload: serde_json::Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/validate")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let inline = serde_json::json!({"properties": {"port": {"maximum": 65535}}});
+        let (status, body) = validate(serde_json::json!({"content": "port = 70000\n", "format": "toml", "schema": inline})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["valid"], false);
+        let violation = &body["violations"][0];
+        assert_eq!((violation["keyword"].as_str(), violation["instance_path"].as_str()), (Some("maximum"), Some("/port")));
+        assert_eq!(violation["span"], serde_json::json!({"start": 7, "end": 12}));
+        assert_eq!(violation["start"], serde_json::json!({"line": 1, "column": 8}));
+
+        let (_, body) = validate(serde_json::json!({"content": "name: a\n", "format": "yaml", "schema": "named"})).await;
+        assert_eq!(body["valid"], true);
+        assert!(body.get("violations").is_none());
+        let (status, _) = validate(serde_json::json!({"content": "{}", "format": "json", "schema": "missing"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = validate(serde_json::json!({"content": "{}", "format": "json", "schema": {"pattern": "("}})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = validate(serde_json::json!({"content": "<p>A</p>", "format": "html", "schema": "named"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Markdown is checked by its front matter
+        let (_, body) = validate(serde_json::json!({"content": "---\nname: a\n---\n# A", "format": "markdown", "schema": "named"})).await;
+        assert_eq!(body["valid"], true);
+        let (_, body) = validate(serde_json::json!({"content": "# A", "format": "markdown", "schema": "named"})).await;
+        assert_eq!(body["violations"][0]["keyword"], "required");
+    }
+
+    #[tokio::test]
+    async fn test_format_document() {
+        let app = create_router(create_test_state());
+        let format = |payload: serde_json::Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/format")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let options = serde_json::json!({"indent": 4, "sort_keys": true, "quote_style": "single"});
+        let (status, body) = format(serde_json::json!({"content": "b: 1\na: x y\n", "format": "yml", "options": options})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"content": "a: x y\nb: 1\n", "changed": true}));
+        let (_, body) = format(serde_json::json!({"content": "a = 1\n", "format": "toml"})).await;
+        assert_eq!(body["changed"], false);
+
+        let (status, body) = format(serde_json::json!({"content": "{", "format": "json"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Formatting failed: Invalid JSON"), "{body}");
+        let (status, _) = format(serde_json::json!({"content": "# A", "format": "markdown"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = format(serde_json::json!({"content": "{}", "format": "json", "options": {"array_wrap": "sometimes"}})).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_diff_documents() {
+        let app = create_router(create_test_state());
+        let diff = |payload: serde_json::Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/diff")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let before = serde_json::json!({"content": "name: app\nport: 80\n", "format": "yaml"});
+        let after = serde_json::json!({"content": "name = \"app\"\nport = 8080\ndebug = true\n", "format": "toml"});
+        let (status, body) = diff(serde_json::json!({"before": before, "after": after})).await;
+        assert_eq!(status, StatusCode::OK);
+        let expected = serde_json::json!([
+            {"path": "/port", "kind": "changed", "before": 80, "after": 8080},
+            {"path": "/debug", "kind": "added", "after": true},
+        ]);
+        assert_eq!(body["changes"], expected);
+
+        let broken = serde_json::json!({"content": "{", "format": "json"});
+        let (status, body) = diff(serde_json::json!({"before": before, "after": broken})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Diff failed"), "{body}");
+        let unknown = serde_json::json!({"content": "", "format": "nonsense"});
+        let (status, _) = diff(serde_json::json!({"before": unknown, "after": before})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_infer_schema() {
+        let app = create_router(create_test_state());
+        let infer = |payload: serde_json::Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/schema/infer")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let documents = serde_json::json!([
+            {"content": "name: api\nport: 80\n", "format": "yaml"},
+            {"content": "{\"name\": \"db\", \"port\": 5432, \"tls\": true}", "format": "json"},
+        ]);
+        let (status, body) = infer(serde_json::json!({"documents": documents})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["schema"]["required"], serde_json::json!(["name", "port"]));
+        assert_eq!(body["schema"]["properties"]["tls"], serde_json::json!({"type": "boolean"}));
+
+        let broken = serde_json::json!([{"content": "a: [", "format": "yaml"}]);
+        let (status, body) = infer(serde_json::json!({"documents": broken})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Inference failed: In sample 1"), "{body}");
+        let (status, _) = infer(serde_json::json!({"documents": []})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_query_document() {
+        let state = create_test_state();
+        let document = state.documents.upsert(
+            "file:///work/deploy.yaml".to_string(),
+            "services:\n  - name: api\n    port: 80\n  - name: db\n    port: 5432\n".to_string(),
+            "yaml".to_string(),
+        );
+        let xml = state.documents.upsert(
+            "file:///work/deploy.xml".to_string(),
+            "<services>\n  <service name=\"api\" port=\"80\"/>\n</services>\n".to_string(),
+            "xml".to_string(),
+        );
+        let app = create_router(state);
+        let query = |id: &str, payload: serde_json::Value| {
+            let request = Request::builder()
+                .method("POST")
+                .uri(format!("/api/documents/{id}/query"))
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, body) = query(&document.id, serde_json::json!({"expression": "$.services[?@.port > 100].name"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"result": ["db"], "paths": ["/services/1/name"]}));
+        let by_uri = "file%3A%2F%2F%2Fwork%2Fdeploy.yaml";
+        let (_, body) = query(by_uri, serde_json::json!({"expression": "max_by(services, &port).name", "language": "jmespath"})).await;
+        assert_eq!(body, serde_json::json!({"result": "db"}));
+        let (_, body) = query(&xml.id, serde_json::json!({"expression": "//service/@port", "language": "xpath"})).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "result": ["80"],
+                "paths": ["/services[1]/service[1]/@port"],
+                "ranges": [{"span": {"start": 33, "end": 42}, "start": {"line": 2, "column": 23}, "end": {"line": 2, "column": 32}}],
+            })
+        );
+        let (status, body) = query(&document.id, serde_json::json!({"expression": "//service", "language": "xpath"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Query failed: XPath queries XML documents, not yaml");
+
+        let (status, body) = query(&document.id, serde_json::json!({"expression": "services"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Query failed: Invalid JSONPath"), "{body}");
+        let (status, _) = query("missing", serde_json::json!({"expression": "$"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_convert_stream() {
+        let state = create_test_state();
+        let app = create_router(Arc::clone(&state));
+        let stream = |query: &str, body: String| {
+            let request = Request::builder()
+                .method("POST")
+                .uri(format!("/api/convert/stream?{query}"))
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+                (status, content_type, body.map(|body| String::from_utf8(body.to_vec()).unwrap()))
+            }
+        };
+
+        let ndjson = (0..5000).map(|i| format!("{{\"id\":{i},\"name\":\"record {i}\"}}\n")).collect::<Vec<_>>().concat();
+        let (status, content_type, body) = stream("from=ndjson&to=yaml", ndjson.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.unwrap(), "application/yaml");
+        let yaml = body.unwrap();
+        assert!(yaml.starts_with("id: 0\nname: record 0\n---\nid: 1\n"), "{}", &yaml[..40]);
+        let (_, _, body) = stream("from=yaml&to=ndjson", yaml).await;
+        assert_eq!(body.unwrap(), ndjson);
+
+        // Early failures are a status, later ones cut the body short
+        let (status, _, _) = stream("from=json&to=ndjson", "{\"a\": 1}".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _, _) = stream("from=csv&to=json", "a,b\n1,2\n".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _, body) = stream("from=ndjson&to=json", format!("{ndjson}{{broken\n")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_err());
+
+        // The input limit applies to each record, refusing a large one as it is read
+        state.formats.set_limits(FormatLimits { max_input_bytes: 1024, ..Default::default() });
+        let (status, _, body) = stream("from=ndjson&to=yaml", ndjson.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_ok());
+        let (status, _, _) = stream("from=yaml&to=json", format!("items:\n{}", "- item\n".repeat(1000))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_latency_histograms_populate() {
+        let state = create_test_state();
+        let app = create_router(Arc::clone(&state));
+
+        for _ in 0..3 {
+            let payload = serde_json::json!({ "content": "# Hi", "from": "markdown", "to": "html" });
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/api/convert")
+                        .header("content-type", "application/json")
+                        .body(Body::from(payload.to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let payload = serde_json::json!({ "content": "{}", "format": "json" });
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/validate")
+                    .header("content-type", "application/json")
+                    .body(Body::from(payload.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let missing = Request::builder().uri("/api/documents/nope").body(Body::empty()).unwrap();
+        app.clone().oneshot(missing).await.unwrap();
+
+        let metrics = &state.metrics;
+        let convert = metrics
+            .http_request_duration
+            .with_labels(&["/api/convert", "POST", "2xx"])
+            .snapshot();
+        assert_eq!(convert.count, 3);
+        assert!(convert.sum > 0.0 && convert.sum < 10.0);
+        let not_found = metrics
+            .http_request_duration
+            .with_labels(&["/api/documents/:id", "GET", "404"])
+            .snapshot();
+        assert_eq!(not_found.count, 1);
+        assert_eq!(metrics.conversion_duration.with_labels(&["md", "html"]).snapshot().count, 3);
+        assert_eq!(metrics.validation_duration.with_labels(&["json"]).snapshot().count, 1);
+
+        let response = app
+            .oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains(
+            "ulc_http_request_duration_seconds_count{route=\"/api/convert\",method=\"POST\",status=\"2xx\"} 3"
+        ));
+        assert!(text.contains("ulc_conversion_duration_seconds_bucket{from=\"md\",to=\"html\",le=\"+Inf\"} 3"));
+        assert!(metrics.snapshot().latency.contains_key("ulc_conversion_duration_seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_format_counters_count_each_request_once() {
+        let state = create_test_state();
+        let app = create_router(Arc::clone(&state));
+
+        // YAML → HTML is chained through JSON inside the conversion core
+        let payload = serde_json::json!({ "content": "title: Notes", "from": "yaml", "to": "html" });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/convert")
+                    .header("content-type", "application/json")
+                    .body(Body::from(payload.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let conversions = state.metrics.conversions.children();
+        assert_eq!(conversions.len(), 1);
+        assert_eq!(conversions[0].0, vec!["yaml", "html", "succeeded"]);
+        assert_eq!(conversions[0].1.get(), 1);
+        let top = &state.metrics.snapshot().formats.top_conversions;
+        assert_eq!((top.len(), top[0].attempted), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_list_documents() {
+        let state = create_test_state();
+
+        // Add a test document
+        state.documents.upsert(
+            "file:///test.md".to_string(),
+            "# Test".to_string(),
             "markdown".to_string(),
         );
 
@@ -320,4 +2248,956 @@ async fn test_list_documents() {
 
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        let app = create_router(create_test_state());
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/api/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let generated = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+
+        // A caller's ID is kept so logs on both sides line up
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/health")
+                    .header(REQUEST_ID_HEADER, "edge-1234")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "edge-1234");
+    }
+
+    #[tokio::test]
+    async fn test_admin_log_filter() {
+        let (_subscriber, handle) = crate::logging::subscriber(
+            &crate::LoggingConfig::default(),
+            tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::sink),
+        )
+        .unwrap();
+        let mut state = ServerState::new(ServerConfig {
+            enable_auth: true,
+            ..ServerConfig::default()
+        });
+        state.logging = Some(handle.clone());
+        let auth = state.auth_service.clone().unwrap();
+        let admin = auth.generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]).unwrap();
+        let reader = auth.generate_token("dev".to_string(), vec!["read".to_string()]).unwrap();
+        let app = create_router(Arc::new(state));
+
+        let put = |token: &str, filter: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri("/api/admin/logging")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::json!({ "filter": filter }).to_string()))
+                .unwrap()
+        };
+
+        let anonymous = Request::builder().uri("/api/admin/logging").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(anonymous).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.clone().oneshot(put(&reader, "debug")).await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(handle.filter(), "info");
+
+        let response = app.clone().oneshot(put(&admin, "debug,hyper=warn")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(handle.filter(), "debug,hyper=warn");
+
+        let response = app.clone().oneshot(put(&admin, "debug,hyper=loud")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handle.filter(), "debug,hyper=warn");
+
+        let current = Request::builder()
+            .uri("/api/admin/logging")
+            .header(header::AUTHORIZATION, format!("Bearer {admin}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(current).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["filter"], "debug,hyper=warn");
+    }
+
+    #[tokio::test]
+    async fn test_jwks_verifies_issued_tokens() {
+        let keys = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let state = Arc::new(ServerState::new(ServerConfig {
+            enable_auth: true,
+            jwt_algorithm: crate::TokenAlgorithm::Es256,
+            jwt_private_key_file: Some(keys.join("ec_private.pem")),
+            ..ServerConfig::default()
+        }));
+        let token = state.auth_service.as_ref().unwrap().generate_token("ops".to_string(), vec![]).unwrap();
+        let token = token.strip_prefix("Bearer ").unwrap();
+        let jwks = |state| async {
+            let request = Request::builder().uri("/.well-known/jwks.json").body(Body::empty()).unwrap();
+            let response = create_router(state).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<jsonwebtoken::jwk::JwkSet>(&body).unwrap()
+        };
+
+        // A client holding only the published key can check the token
+        let published = jwks(Arc::clone(&state)).await;
+        let kid = jsonwebtoken::decode_header(token).unwrap().kid.unwrap();
+        let key = jsonwebtoken::DecodingKey::from_jwk(published.find(&kid).unwrap()).unwrap();
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+        validation.set_audience(&["universal-connector-api"]);
+        let claims = jsonwebtoken::decode::<crate::auth::Claims>(token, &key, &validation).unwrap().claims;
+        assert_eq!(claims.sub, "ops");
+
+        assert!(jwks(Arc::new(ServerState::new(ServerConfig::default()))).await.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_admin() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let admin = state.auth_service.as_ref().unwrap().generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]);
+        let admin = admin.unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: &str, credential: (&'static str, String), body: serde_json::Value| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(credential.0, credential.1)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let bearer = || ("authorization", format!("Bearer {admin}"));
+
+        let body = serde_json::json!({"name": "ci", "scopes": ["admin"]});
+        let (status, created) = call("POST", "/api/admin/api-keys", bearer(), body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((created["subject"].as_str(), created["revoked_at"].as_null()), (Some("ci"), Some(())));
+        let (key, id) = (created["api_key"].as_str().unwrap().to_string(), created["id"].as_str().unwrap());
+
+        // The key is accepted in its own header, and listed without its secret
+        let (status, listed) = call("GET", "/api/admin/api-keys", (api_keys::HEADER, key.clone()), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(listed.as_array().map(Vec::len), Some(1));
+        assert!(listed[0]["last_used_at"].is_string(), "{listed}");
+        assert!(listed[0].get("api_key").is_none() && listed[0].get("hash").is_none(), "{listed}");
+
+        let revoke = format!("/api/admin/api-keys/{id}");
+        let (status, revoked) = call("DELETE", &revoke, bearer(), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(revoked["revoked_at"].is_string());
+        let (status, error) = call("GET", "/api/admin/api-keys", ("authorization", format!("Bearer {key}")), serde_json::Value::Null).await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("Invalid token: Token revoked")));
+
+        let (status, _) = call("DELETE", "/api/admin/api-keys/0000000000000000", bearer(), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call("POST", "/api/admin/api-keys", bearer(), serde_json::json!({"name": " "})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_directory_login() {
+        struct Staff;
+
+        #[tower_lsp::async_trait]
+        impl Directory for Staff {
+            async fn authenticate(&self, username: &str, password: &str) -> Result<DirectoryUser, LoginError> {
+                let groups = vec!["CN=Editors,OU=Groups,DC=example,DC=com".to_string()];
+                match (username, password) {
+                    ("alice", "correct horse") | ("bob", _) => {
+                        Ok(DirectoryUser { subject: username.to_string(), dn: format!("uid={username},dc=example,dc=com"), groups })
+                    }
+                    ("carol", _) => Err(LoginError::Unavailable("connection refused".to_string())),
+                    _ => Err(LoginError::InvalidCredentials),
+                }
+            }
+
+            fn name(&self) -> &'static str {
+                "staff"
+            }
+        }
+
+        let mut ldap = LdapConfig::new("ldaps://dc1.example.com", "dc=example,dc=com");
+        ldap.group_scopes.insert("Editors".to_string(), vec!["role:editor".to_string()]);
+        let config = ServerConfig { enable_auth: true, ldap: Some(ldap), ..ServerConfig::default() };
+        let mut state = ServerState::new(config.clone());
+        let store = Arc::clone(state.auth_service.as_ref().unwrap().refresh_tokens().unwrap());
+        let auth = AuthService::new(AuthConfig::from_server_config(&config)).with_refresh_tokens(store).with_directory(Arc::new(Staff));
+        let auth = Arc::new(auth);
+        state.auth_service = Some(Arc::clone(&auth));
+        let app = create_router(Arc::new(state));
+        let login = |username: &str, password: &str| {
+            let body = serde_json::json!({"username": username, "password": password});
+            let request = Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let (status, pair) = login("alice", "correct horse").await;
+        assert_eq!(status, StatusCode::OK);
+        let token = format!("Bearer {}", pair["access_token"].as_str().unwrap());
+        let request = Request::builder().method("DELETE").uri("/api/documents/missing").header("authorization", token).body(Body::empty());
+        // The Editors group grants the editor role, and so write
+        assert_eq!(app.clone().oneshot(request.unwrap()).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert!(pair["refresh_token"].is_string());
+
+        let (status, error) = login("alice", "wrong").await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("Invalid username or password")));
+        // An empty password would be an anonymous bind, which many servers accept
+        assert_eq!(login("bob", "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(login("carol", "secret").await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        // A failure makes the username wait, even with the right password, and enough lock it out
+        let (status, error) = login("alice", "correct horse").await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::TOO_MANY_REQUESTS, Some("Too many failed attempts; retry in 1s")));
+        for _ in 0..LockoutConfig::default().max_failures {
+            auth.throttle().record_failure(None, Some("dave"));
+        }
+        let body = serde_json::json!({"username": "Dave", "password": "correct horse"}).to_string();
+        let request = Request::builder().method("POST").uri("/auth/login").header("content-type", "application/json").body(Body::from(body));
+        let response = app.clone().oneshot(request.unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).and_then(|value| value.to_str().ok()), Some("900"));
+
+        let app = create_router(Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() })));
+        let body = serde_json::json!({"username": "alice", "password": "correct horse"}).to_string();
+        let request = Request::builder().method("POST").uri("/auth/login").header("content-type", "application/json").body(Body::from(body));
+        assert_eq!(app.oneshot(request.unwrap()).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_clients_read_only() {
+        for enable_auth in [false, true] {
+            let config = ServerConfig { enable_auth, anonymous_read_only: true, ..ServerConfig::default() };
+            let state = Arc::new(ServerState::new(config));
+            let document = state.documents.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+            let uri = format!("/api/documents/{}", document.id);
+            let app = create_router(Arc::clone(&state));
+            let call = |method: &str, uri: &str, token: Option<String>| {
+                let mut request = Request::builder().method(method).uri(uri);
+                if let Some(token) = token {
+                    request = request.header("authorization", token);
+                }
+                app.clone().oneshot(request.body(Body::empty()).unwrap())
+            };
+            assert_eq!(call("GET", &uri, None).await.unwrap().status(), StatusCode::OK);
+            assert_eq!(call("DELETE", &uri, None).await.unwrap().status(), StatusCode::FORBIDDEN);
+            assert_eq!(call("GET", "/api/admin/tasks", None).await.unwrap().status(), StatusCode::FORBIDDEN);
+            // With authentication enabled, a token still grants what it holds
+            if let Some(auth) = &state.auth_service {
+                let token = auth.generate_token("alice".to_string(), vec!["role:editor".to_string()]).unwrap();
+                assert_eq!(call("DELETE", &uri, Some(token)).await.unwrap().status(), StatusCode::NO_CONTENT);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_saml_sign_on_is_refused_unsigned() {
+        let saml = SamlConfig::new("https://idp.example.com", "tests/fixtures/keys/idp_rsa_cert.pem", "https://connector.example.com");
+        let post = |config: ServerConfig, response: &str| {
+            let app = create_router(Arc::new(ServerState::new(config)));
+            let body = format!("SAMLResponse={}", response.replace('+', "%2B").replace('/', "%2F").replace('=', "%3D"));
+            let request = Request::builder()
+                .method("POST")
+                .uri("/auth/saml/acs")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(body))
+                .unwrap();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let unsigned = STANDARD.encode(r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" ID="r1" Version="2.0"/>"#);
+        let config = ServerConfig { enable_auth: true, saml: Some(saml), ..ServerConfig::default() };
+        let (status, error) = post(config.clone(), &unsigned).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(error["error"].as_str().unwrap().starts_with("Invalid SAML response: "), "{error}");
+        assert_eq!(post(config, "not base64").await.0, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = post(ServerConfig { enable_auth: true, ..ServerConfig::default() }, &unsigned).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_token_introspection() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let auth = state.auth_service.as_ref().unwrap();
+        let token = |subject: &str, scopes: &[&str]| {
+            let token = auth.generate_token(subject.to_string(), scopes.iter().map(ToString::to_string).collect()).unwrap();
+            token.strip_prefix("Bearer ").unwrap().to_string()
+        };
+        let sidecar = format!("Bearer {}", token("gateway", &[roles::INTROSPECT]));
+        let reader = token("alice", &["role:viewer"]);
+        let revoked = token("bob", &[roles::READ]);
+        auth.revoke_token(&revoked).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let introspect = |authorization: &str, token: &str| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/auth/introspect")
+                .header("authorization", authorization)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(format!("token={token}&token_type_hint=access_token")))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let cache = response.headers().get(header::CACHE_CONTROL).cloned();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, cache, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let (status, cache, active) = introspect(&sidecar, &reader).await;
+        assert_eq!((status, cache.as_ref().and_then(|value| value.to_str().ok())), (StatusCode::OK, Some("no-store")));
+        assert_eq!((active["active"].as_bool(), active["sub"].as_str()), (Some(true), Some("alice")));
+        assert_eq!(active["scope"], "role:viewer read");
+        assert!(active["exp"].is_i64() && active["jti"].is_string());
+
+        for inactive in [revoked.as_str(), "not-a-token", ""] {
+            let (status, _, body) = introspect(&sidecar, inactive).await;
+            assert_eq!((status, body), (StatusCode::OK, serde_json::json!({"active": false})));
+        }
+        // Introspecting needs the scope for it, so tokens cannot be probed by anyone
+        assert_eq!(introspect(&format!("Bearer {reader}"), &reader).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(introspect("", &reader).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_cookie_tokens_need_the_csrf_token() {
+        let csrf = csrf::CsrfConfig { enabled: true, ..csrf::CsrfConfig::default() };
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, csrf, ..ServerConfig::default() }));
+        let auth = state.auth_service.as_ref().unwrap();
+        let token = auth.generate_token("alice".to_string(), vec![roles::READ.to_string(), roles::WRITE.to_string()]).unwrap();
+        let token = token.strip_prefix("Bearer ").unwrap().to_string();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, cookie: String, headers: &[(&str, &str)]| {
+            let mut request = Request::builder().method(method).uri("/api/documents/0123").header("cookie", cookie);
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            let app = app.clone();
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+
+        // A client without a CSRF token is issued one, and reads with its cookie alone
+        let response = call("GET", format!("ulc_token={token}"), &[]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let issued = response.headers()[header::SET_COOKIE].to_str().unwrap().to_string();
+        assert!(issued.ends_with("; Path=/; SameSite=Strict; Secure"), "{issued}");
+        let csrf_token = issued.strip_prefix("ulc_csrf=").unwrap().split(';').next().unwrap().to_string();
+        let cookies = format!("ulc_token={token}; ulc_csrf={csrf_token}");
+
+        // Changing anything takes the token repeated in the header
+        let response = call("DELETE", cookies.clone(), &[]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = call("DELETE", cookies.clone(), &[("x-csrf-token", "forged")]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = call("DELETE", cookies.clone(), &[("x-csrf-token", &csrf_token)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        // A token in a header is not the browser's doing
+        let bearer = format!("Bearer {token}");
+        let response = call("DELETE", String::new(), &[("authorization", &bearer)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = call("DELETE", format!("ulc_token=forged; ulc_csrf={csrf_token}"), &[("x-csrf-token", &csrf_token)]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_token_revocation() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let auth = state.auth_service.as_ref().unwrap();
+        let admin = auth.generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]).unwrap();
+        let leaked = auth.generate_token("alice".to_string(), vec![roles::READ.to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: &str, token: &str, body: serde_json::Value| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", token)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        assert_eq!(call("GET", "/api/documents", &leaked, serde_json::Value::Null).await.0, StatusCode::OK);
+
+        let body = serde_json::json!({"token": leaked});
+        let (status, _) = call("POST", "/api/admin/tokens/revoke", &leaked, body.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, revoked) = call("POST", "/api/admin/tokens/revoke", &admin, body).await;
+        assert_eq!((status, revoked["subject"].as_str()), (StatusCode::OK, Some("alice")));
+        let (status, error) = call("GET", "/api/documents", &leaked, serde_json::Value::Null).await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("Invalid token: Token revoked")));
+
+        let (status, listed) = call("GET", "/api/admin/tokens/revoked", &admin, serde_json::Value::Null).await;
+        assert_eq!((status, listed[0]["jti"].clone()), (StatusCode::OK, revoked["jti"].clone()));
+        let (status, _) = call("POST", "/api/admin/tokens/revoke", &admin, serde_json::json!({"token": "forged"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call("POST", "/api/admin/tokens/revoke", &admin, serde_json::json!({})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call("POST", "/api/admin/tokens/revoke", &admin, serde_json::json!({"jti": "0123"})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_rotation() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let auth = state.auth_service.as_ref().unwrap();
+        let admin = auth.generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]).unwrap();
+        let before = auth.generate_token("alice".to_string(), vec![roles::READ.to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: &str, token: &str, body: serde_json::Value| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", token)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, _) = call("POST", "/api/admin/signing-keys/rotate", &before, serde_json::json!({})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, rotated) = call("POST", "/api/admin/signing-keys/rotate", &admin, serde_json::json!({})).await;
+        assert_eq!((status, rotated["algorithm"].as_str(), rotated["signing"].as_bool()), (StatusCode::CREATED, Some("HS256"), Some(true)));
+        let (status, listed) = call("GET", "/api/admin/signing-keys", &admin, serde_json::Value::Null).await;
+        assert_eq!((status, listed[0]["kid"].clone()), (StatusCode::OK, rotated["kid"].clone()));
+
+        // Sessions outlive the rotation, and new tokens name the new key
+        assert_eq!(call("GET", "/api/documents", &before, serde_json::Value::Null).await.0, StatusCode::OK);
+        let after = auth.generate_token("alice".to_string(), vec![roles::READ.to_string()]).unwrap();
+        let header = jsonwebtoken::decode_header(after.strip_prefix("Bearer ").unwrap()).unwrap();
+        assert_eq!(header.kid.as_deref(), rotated["kid"].as_str());
+        assert_eq!(call("GET", "/api/documents", &after, serde_json::Value::Null).await.0, StatusCode::OK);
+
+        let body = serde_json::json!({"private_key": "not a key"});
+        let (status, _) = call("POST", "/api/admin/signing-keys/rotate", &admin, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_signed_requests() {
+        let secret = "Y2ktc2hhcmVkLXNlY3JldC1mb3ItdGhlLWJ1aWxkLWFnZW50";
+        let client = signing::SigningClient { secret: secret.to_string(), scopes: vec![roles::READ.to_string()], ..Default::default() };
+        let mut request_signing = signing::SigningConfig::default();
+        request_signing.clients.insert("ci".to_string(), client);
+        let config = ServerConfig { enable_auth: true, request_signing: Some(request_signing), ..ServerConfig::default() };
+        let app = create_router(Arc::new(ServerState::new(config)));
+        let call = |method: &str, uri: &str, signature: &str, body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(signing::HEADER, signature)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        let body = serde_json::json!({"content": "# Hello", "from": "markdown", "to": "html"}).to_string();
+        let signature = signing::sign("ci", secret, "POST", "/api/convert", body.as_bytes(), now, "n1");
+        assert_eq!(call("POST", "/api/convert", &signature, &body).await.0, StatusCode::OK);
+        let (status, error) = call("POST", "/api/convert", &signature, &body).await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("Invalid signature: Nonce already used")));
+
+        let signature = signing::sign("ci", secret, "POST", "/api/convert", body.as_bytes(), now, "n2");
+        let tampered = body.replace("html", "text");
+        assert_eq!(call("POST", "/api/convert", &signature, &tampered).await.0, StatusCode::UNAUTHORIZED);
+        let forged = signing::sign("ci", "guessed", "POST", "/api/convert", body.as_bytes(), now, "n3");
+        assert_eq!(call("POST", "/api/convert", &forged, &body).await.0, StatusCode::UNAUTHORIZED);
+
+        // A signature stands for the client's scopes, and no more
+        let signature = signing::sign("ci", secret, "GET", "/api/documents?limit=5", b"", now, "n4");
+        assert_eq!(call("GET", "/api/documents?limit=5", &signature, "").await.0, StatusCode::OK);
+        let signature = signing::sign("ci", secret, "DELETE", "/api/documents/abc", b"", now, "n5");
+        assert_eq!(call("DELETE", "/api/documents/abc", &signature, "").await.0, StatusCode::FORBIDDEN);
+        let signature = signing::sign("ci", secret, "GET", "/api/documents", b"", now, "n6");
+        assert_eq!(call("GET", "/api/documents?limit=5", &signature, "").await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = std::env::temp_dir().join(format!("ulc-audit-http-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let config = ServerConfig {
+            enable_auth: true,
+            audit: AuditConfig { sinks: vec![AuditSinkConfig::File { path: path.clone() }] },
+            ..ServerConfig::default()
+        };
+        let state = Arc::new(ServerState::new(config));
+        tokio::spawn(Arc::clone(&state.audit).run());
+        let auth = state.auth_service.as_ref().unwrap();
+        let admin = auth.generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]).unwrap();
+        let reader = auth.generate_token("alice".to_string(), vec![roles::READ.to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: &str, credential: (&'static str, String)| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(credential.0, credential.1)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::json!({"name": "ci", "scopes": ["read"]}).to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, created) = call("POST", "/api/admin/api-keys", ("authorization", format!("Bearer {admin}"))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = created["api_key"].as_str().unwrap().to_string();
+        assert_eq!(call("GET", "/api/documents", (api_keys::HEADER, key)).await.0, StatusCode::OK);
+        let (status, _) = call("GET", "/api/admin/api-keys", ("authorization", format!("Bearer {reader}"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call("GET", "/api/admin/api-keys", ("authorization", "Bearer forged".to_string())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let flushed = state.audit.drain(tokio::time::Instant::now() + Duration::from_secs(5)).await;
+        assert_eq!(flushed.abandoned, 0);
+        let events: Vec<AuditEvent> =
+            std::fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let kinds: Vec<AuditKind> = events.iter().map(|event| event.event).collect();
+        assert_eq!(
+            kinds,
+            [AuditKind::TokenIssued, AuditKind::ApiKeyUsed, AuditKind::AuthorizationDenied, AuditKind::AuthenticationFailed]
+        );
+        assert_eq!(events[0].subject.as_deref(), Some("ops"));
+        assert_eq!((events[1].client.as_deref(), events[1].resource.as_deref()), (Some("ci"), Some("GET /api/documents")));
+        assert_eq!(events[2].subject.as_deref(), Some("alice"));
+        assert_eq!(events[2].detail.as_deref(), Some("Requires the admin scope"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_exchange() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let admin = state.auth_service.as_ref().unwrap().generate_token("ops".to_string(), vec![roles::ADMIN.to_string()]);
+        let admin = format!("Bearer {}", admin.unwrap());
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: &str, authorization: Option<String>, body: serde_json::Value| {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let request = request.body(Body::from(body.to_string())).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let body = serde_json::json!({"subject": "alice", "scopes": ["admin"], "client_name": "vscode"});
+        let (status, issued) = call("POST", "/api/admin/refresh-tokens", Some(admin.clone()), body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((issued["token_type"].as_str(), issued["expires_in"].as_u64()), (Some("Bearer"), Some(900)));
+        let (status, _) = call("POST", "/api/admin/refresh-tokens", None, serde_json::json!({"subject": "alice"})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Exchanged without a bearer token, for an access token carrying the family's scopes and client
+        let first = issued["refresh_token"].clone();
+        let (status, pair) = call("POST", "/auth/refresh", None, serde_json::json!({"refresh_token": first})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(pair["refresh_token"], first);
+        let claims = state.auth_service.as_ref().unwrap().validate_token(pair["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.client_name()), ("alice", Some("vscode")));
+        assert!(claims.exp - claims.iat <= 900);
+        let access = Some(format!("Bearer {}", pair["access_token"].as_str().unwrap()));
+        let (status, listed) = call("GET", "/api/admin/refresh-tokens", access, serde_json::Value::Null).await;
+        assert_eq!((status, listed[0]["rotations"].as_u64()), (StatusCode::OK, Some(1)));
+
+        let (status, error) = call("POST", "/auth/refresh", None, serde_json::json!({"refresh_token": first})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(error["error"].as_str().unwrap().starts_with("Invalid refresh token: Refresh token already used"), "{error}");
+        let (status, error) = call("POST", "/auth/refresh", None, serde_json::json!({"refresh_token": pair["refresh_token"]})).await;
+        assert_eq!((status, error["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("Invalid refresh token: Token revoked")));
+
+        let revoke = format!("/api/admin/refresh-tokens/{}", issued["family"].as_str().unwrap());
+        let (status, revoked) = call("DELETE", &revoke, Some(admin.clone()), serde_json::Value::Null).await;
+        assert_eq!((status, revoked["revoked_reason"].as_str()), (StatusCode::OK, Some("reused")));
+        let (status, _) = call("DELETE", "/api/admin/refresh-tokens/0000000000000000", Some(admin), serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_document_endpoints_need_scopes() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let document = state.documents.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+        let auth = state.auth_service.as_ref().unwrap();
+        let viewer = auth.generate_token("dev".to_string(), vec!["role:viewer".to_string()]).unwrap();
+        let editor = auth.generate_token("dev".to_string(), vec!["role:editor".to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: String, token: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                request = request.header("authorization", token);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let uri = format!("/api/documents/{}", document.id);
+        assert_eq!(call("GET", "/api/documents".to_string(), None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("GET", uri.clone(), Some(&viewer)).await.unwrap().status(), StatusCode::OK);
+        let refused = call("DELETE", uri.clone(), Some(&viewer)).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        let body = axum::body::to_bytes(refused.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"], "Requires the write scope");
+        assert_eq!(call("DELETE", uri, Some(&editor)).await.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_policy_adds_route_scopes() {
+        let policy = ScopePolicy::default()
+            .route("DELETE /api/documents/:id", vec!["curate".to_string()])
+            .route("/api/version", vec!["inventory".to_string()]);
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, policy, ..ServerConfig::default() }));
+        let document = state.documents.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+        let auth = state.auth_service.as_ref().unwrap();
+        let editor = auth.generate_token("dev".to_string(), vec!["role:editor".to_string()]).unwrap();
+        let curator = auth.generate_token("dev".to_string(), vec!["role:editor".to_string(), "curate".to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let call = |method: &str, uri: String, token: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                request = request.header("authorization", token);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // A public endpoint named by a rule needs a token holding its scopes
+        assert_eq!(call("GET", "/api/version".to_string(), None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("GET", "/api/stats".to_string(), None).await.unwrap().status(), StatusCode::OK);
+
+        let uri = format!("/api/documents/{}", document.id);
+        assert_eq!(call("GET", uri.clone(), Some(&editor)).await.unwrap().status(), StatusCode::OK);
+        let refused = call("DELETE", uri.clone(), Some(&editor)).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        let body = axum::body::to_bytes(refused.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"], "Requires the curate scope");
+        assert_eq!(call("DELETE", uri, Some(&curator)).await.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_tiers() {
+        let rate_limit = RateLimitConfig {
+            enabled: true,
+            anonymous: TierLimits::new(1, 1),
+            authenticated: TierLimits::new(1, 2),
+            elevated: TierLimits::new(1, 3),
+            redis: None,
+        };
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, rate_limit, ..ServerConfig::default() }));
+        let auth = state.auth_service.as_ref().unwrap();
+        let editor = auth.generate_token("alice".to_string(), vec!["role:editor".to_string()]).unwrap();
+        let batch = auth.generate_token("ci".to_string(), vec!["read".to_string(), "unlimited".to_string()]).unwrap();
+        let app = create_router(Arc::clone(&state));
+        let peer = SocketAddr::from(([192, 0, 2, 7], 40000));
+        let call = |uri: &str, token: Option<&str>| {
+            let mut request = Request::builder().uri(uri).extension(ConnectInfo(peer));
+            if let Some(token) = token {
+                request = request.header("authorization", token);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let statuses = |responses: Vec<Response>| responses.iter().map(Response::status).collect::<Vec<_>>();
+
+        let first = call("/api/stats", None).await.unwrap();
+        assert_eq!(first.headers()["x-ratelimit-tier"], "anonymous");
+        assert_eq!(first.headers()["x-ratelimit-remaining"], "0");
+        let refused = call("/api/stats", None).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()[header::RETRY_AFTER], "60");
+        // Probes are never limited
+        assert_eq!(call("/healthz", None).await.unwrap().status(), StatusCode::OK);
+
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            responses.push(call("/api/stats", Some(&editor)).await.unwrap());
+        }
+        assert_eq!(responses[0].headers()["x-ratelimit-tier"], "authenticated");
+        assert_eq!(statuses(responses), [StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+
+        let mut responses = Vec::new();
+        for _ in 0..4 {
+            responses.push(call("/api/stats", Some(&batch)).await.unwrap());
+        }
+        assert_eq!(responses[0].headers()["x-ratelimit-tier"], "elevated");
+        assert_eq!(responses[0].headers()["x-ratelimit-limit"], "3");
+        assert_eq!(statuses(responses), [StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_metrics() {
+        let state = Arc::new(ServerState::new(ServerConfig {
+            enable_auth: true,
+            ..ServerConfig::default()
+        }));
+        let admin = state
+            .auth_service
+            .as_ref()
+            .unwrap()
+            .generate_token("ops".to_string(), vec![roles::ADMIN.to_string()])
+            .unwrap();
+        state.metrics.errors.inc();
+        let app = create_router(Arc::clone(&state));
+
+        let anonymous = Request::builder().uri("/api/admin/metrics").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(anonymous).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/api/admin/metrics")
+            .header(header::AUTHORIZATION, format!("Bearer {admin}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let snapshot: crate::monitoring::MetricsSnapshot = serde_json::from_slice(&body).unwrap();
+        assert_eq!(snapshot.total_errors, 1);
+        assert!((snapshot.values["ulc_errors_total"][0].value - 1.0).abs() < f64::EPSILON);
+        assert!(snapshot.values.contains_key("ulc_uptime_seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_metric_controls() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        state.metrics.lsp_request_duration.with_labels(&["textDocument/didChange"]).observe(0.01);
+        let app = create_router(Arc::clone(&state));
+        let put = |settings: serde_json::Value| {
+            Request::builder()
+                .method("PUT")
+                .uri("/api/admin/metrics/controls")
+                .header("content-type", "application/json")
+                .body(Body::from(settings.to_string()))
+                .unwrap()
+        };
+        let scrape = || async {
+            let request = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        assert!(scrape().await.contains("ulc_lsp_request_duration_seconds_count"));
+
+        let settings = serde_json::json!({ "disabled": ["lsp"], "sampling": { "ulc_http_request_duration_seconds": 10 } });
+        let response = app.clone().oneshot(put(settings)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let controls: MetricControlsResponse = serde_json::from_slice(&body).unwrap();
+        let http = controls
+            .metrics
+            .iter()
+            .find(|control| control.name == "ulc_http_request_duration_seconds")
+            .unwrap();
+        assert_eq!(http.sample_every, 10);
+        // Disabled metrics are left out of the scrape, not reported as zero
+        let scraped = scrape().await;
+        assert!(!scraped.contains("ulc_lsp_request_duration_seconds"), "{scraped}");
+        assert!(scraped.contains("ulc_errors_total"));
+
+        let unknown = serde_json::json!({ "disabled": ["ulc_nope"] });
+        assert_eq!(app.clone().oneshot(put(unknown)).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert!(!scrape().await.contains("ulc_lsp_request_duration_seconds"));
+
+        // Re-enabled without a restart, with what was recorded before
+        let response = app.clone().oneshot(put(serde_json::json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(scrape().await.contains("ulc_lsp_request_duration_seconds_count{method=\"textDocument/didChange\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_reset_rate_windows() {
+        let state = Arc::new(ServerState::new(ServerConfig {
+            enable_auth: true,
+            ..ServerConfig::default()
+        }));
+        let admin = state
+            .auth_service
+            .as_ref()
+            .unwrap()
+            .generate_token("ops".to_string(), vec![roles::ADMIN.to_string()])
+            .unwrap();
+        state.metrics.errors.inc_by(30);
+        assert!(state.metrics.snapshot().rates["ulc_errors_total"].m1.unwrap() > 0.0);
+        let app = create_router(Arc::clone(&state));
+        let reset = |token: Option<&str>| {
+            let mut request = Request::builder().method("POST").uri("/api/admin/metrics/reset-windows");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(reset(None)).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.oneshot(reset(Some(&admin))).await.unwrap().status(), StatusCode::NO_CONTENT);
+        // The rate starts again; the counter does not
+        assert!(state.metrics.snapshot().rates["ulc_errors_total"].m1.unwrap_or(0.0).abs() < f64::EPSILON);
+        assert_eq!(state.metrics.errors.get(), 30);
+    }
+
+    #[tokio::test]
+    async fn test_usage_report() {
+        let state = create_test_state();
+        let alice = Client {
+            subject: Some("alice".to_string()),
+            name: Some("vscode".to_string()),
+        };
+        for _ in 0..3 {
+            state.usage.record(&alice, Counts::request(0));
+        }
+        let app = create_router(Arc::clone(&state));
+        let payload = serde_json::json!({ "content": "# Hi", "from": "markdown", "to": "html" }).to_string();
+        let convert = Request::builder()
+            .method("POST")
+            .uri("/api/convert")
+            .header("content-type", "application/json")
+            .header(header::CONTENT_LENGTH, payload.len())
+            .body(Body::from(payload.clone()))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(convert).await.unwrap().status(), StatusCode::OK);
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(get("/api/admin/usage?window=1h&limit=5")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: UsageReport = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report.window_secs, 3600);
+        assert_eq!(report.clients.len(), 1);
+        assert_eq!(report.clients[0].subject, "alice");
+        assert_eq!(report.clients[0].client.as_deref(), Some("vscode"));
+        assert_eq!(report.clients[0].counts.requests, 3);
+        // Without authentication every request is anonymous, this one included
+        assert_eq!(report.anonymous.requests, 2);
+        assert_eq!(report.anonymous.conversions, 1);
+        assert_eq!(report.anonymous.bytes, payload.len() as u64);
+
+        let response = app.oneshot(get("/api/admin/usage?window=1w")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_admin_tasks() {
+        let state = create_test_state();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let gate = Arc::clone(&release);
+        state
+            .scheduler
+            .register(TaskSpec::new("reindex", Schedule::Every(Duration::from_hours(1))), move || {
+                let gate = Arc::clone(&gate);
+                async move {
+                    gate.notified().await;
+                    anyhow::Ok(())
+                }
+            })
+            .unwrap();
+        let app = create_router(Arc::clone(&state));
+        let run = |name: &str| {
+            Request::builder().method("POST").uri(format!("/api/admin/tasks/{name}/run")).body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(run("reindex")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let status: TaskStatus = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status.running, 1);
+        assert_eq!(app.clone().oneshot(run("reindex")).await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(app.clone().oneshot(run("vacuum")).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let response =
+            app.oneshot(Request::builder().uri("/api/admin/tasks").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let tasks: Vec<TaskStatus> = serde_json::from_slice(&body).unwrap();
+        let names: Vec<&str> = tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, ["rate_limit_eviction", "reindex"]);
+        assert_eq!(tasks[1].schedule, "1h");
+        release.notify_one();
+    }
+
+    #[test]
+    fn test_status_label() {
+        assert_eq!(status_label(StatusCode::OK), "2xx");
+        assert_eq!(status_label(StatusCode::NOT_FOUND), "404");
+        assert_eq!(status_label(StatusCode::TOO_MANY_REQUESTS), "429");
+        assert_eq!(status_label(StatusCode::IM_A_TEAPOT), "4xx");
+        assert_eq!(status_label(StatusCode::BAD_GATEWAY), "5xx");
+    }
+
+    #[tokio::test]
+    async fn test_random_paths_keep_series_bounded() {
+        let state = create_test_state();
+        let app = create_router(Arc::clone(&state));
+
+        for i in 0..300 {
+            let id = uuid::Uuid::new_v4();
+            let uri = match i % 3 {
+                0 => format!("/api/documents/{id}"),
+                1 => format!("/api/documents/file%3A%2F%2F%2Ftmp%2F{id}.md"),
+                _ => format!("/random/{id}/path"),
+            };
+            let method = if i % 2 == 0 { "GET" } else { "DELETE" };
+            let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let series = state.metrics.http_request_duration.children();
+        assert!(series.len() <= 6, "{:?}", series.iter().map(|(labels, _)| labels).collect::<Vec<_>>());
+        assert!(series.len() < state.metrics.registry().series_limit());
+        for (labels, _) in &series {
+            assert!(["/api/documents/:id", "unmatched"].contains(&labels[0].as_str()), "{labels:?}");
+        }
+    }
 }
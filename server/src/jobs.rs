@@ -0,0 +1,741 @@
+//! Background jobs on a bounded pool of workers
+//!
+//! Features that would otherwise spawn a task per event, such as webhook
+//! deliveries and LSP diagnostics, submit a job to the shared [`JobQueue`]
+//! instead. Each job belongs to a class, such as [`WEBHOOK`], whose
+//! [`ClassLimits`] cap how many of its jobs run at once and how many may
+//! wait. A class whose queue is full sheds load: [`JobQueue::submit`] fails
+//! with [`Refused::Full`] rather than buffering without bound.
+//!
+//! The [`JobHandle`] of a job awaits its result, watches its progress and
+//! cancels it; dropping the handle leaves the job to run. A job that panics
+//! fails on its own, and the worker running it goes on to the next. Workers
+//! start with the first submission, on the runtime it is made from.
+//!
+//! At shutdown the queue is closed to new jobs and drained: those already
+//! submitted get until a deadline to finish, and the ones still waiting then
+//! are dropped.
+
+use crate::monitoring::registry::{Buckets, Counter, Family, Gauge, Histogram, Registry};
+use crate::shutdown::Flushed;
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::sync::{oneshot, watch, Notify};
+use tracing::warn;
+
+/// Class of webhook deliveries
+pub const WEBHOOK: &str = "webhook";
+/// Class of diagnostics computed for LSP clients
+pub const DIAGNOSTICS: &str = "diagnostics";
+
+/// Limits on one class of jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ClassLimits {
+    /// Jobs of the class running at once
+    pub concurrency: usize,
+    /// Jobs of the class waiting to run, beyond which submissions are refused
+    pub max_queued: usize,
+}
+
+impl ClassLimits {
+    #[must_use]
+    pub fn new(concurrency: usize, max_queued: usize) -> Self {
+        Self { concurrency, max_queued }
+    }
+}
+
+impl Default for ClassLimits {
+    fn default() -> Self {
+        Self { concurrency: 4, max_queued: 1000 }
+    }
+}
+
+/// Job queue settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct JobsConfig {
+    /// Worker tasks, shared by every class
+    pub workers: usize,
+    /// Limits of the classes not listed in `classes`
+    pub default_limits: ClassLimits,
+    /// Limits by class, such as `webhook` or `diagnostics`
+    pub classes: BTreeMap<String, ClassLimits>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { workers: 4, default_limits: ClassLimits::default(), classes: BTreeMap::new() }
+    }
+}
+
+impl JobsConfig {
+    /// Limits in force for `class`
+    #[must_use]
+    pub fn limits(&self, class: &str) -> ClassLimits {
+        self.classes.get(class).copied().unwrap_or(self.default_limits)
+    }
+}
+
+/// Progress a job reports through [`JobContext::report`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Units of work done
+    pub done: u64,
+    /// Units of work in all, if known
+    pub total: Option<u64>,
+    pub message: Option<String>,
+}
+
+/// A submission refused because its class already has as many jobs waiting as it may
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueFull {
+    pub class: String,
+    pub max_queued: usize,
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} job queue is full ({} waiting)", self.class, self.max_queued)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Why a submission was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refused {
+    /// The class already has as many jobs waiting as it may
+    Full(QueueFull),
+    /// The queue was closed for shutdown
+    Closed,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(full) => full.fmt(f),
+            Self::Closed => write!(f, "job queue is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Why a job has no result
+#[derive(Debug)]
+pub enum JobError {
+    /// Cancelled through its handle before it finished
+    Cancelled,
+    /// The job panicked, with the panic message
+    Panicked(String),
+    /// The job returned an error
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "job cancelled"),
+            Self::Panicked(message) => write!(f, "job panicked: {message}"),
+            Self::Failed(e) => write!(f, "job failed: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// How a job ended, as counted in `ulc_jobs_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Succeeded,
+    Failed,
+    Panicked,
+    Cancelled,
+    /// Refused with [`Refused`]
+    Rejected,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Panicked => "panicked",
+            Self::Cancelled => "cancelled",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+/// Queue depth, wait and run time, and outcomes, by job class
+#[derive(Debug, Clone)]
+pub struct JobMetrics {
+    pub queued: Family<Gauge>,
+    pub running: Family<Gauge>,
+    pub wait_duration: Family<Histogram>,
+    pub run_duration: Family<Histogram>,
+    pub jobs: Family<Counter>,
+}
+
+impl JobMetrics {
+    /// Register the job metrics
+    ///
+    /// # Errors
+    ///
+    /// Fails where a metric of the same name is already registered.
+    pub fn register(registry: &Registry) -> Result<Self> {
+        Ok(Self {
+            queued: registry.gauge_family("ulc_jobs_queued", "Jobs waiting for a worker", &["class"])?,
+            running: registry.gauge_family("ulc_jobs_running", "Jobs running", &["class"])?,
+            wait_duration: registry.histogram_family(
+                "ulc_job_wait_seconds",
+                "Time jobs waited for a worker",
+                &["class"],
+                Buckets::latency(),
+            )?,
+            run_duration: registry.histogram_family(
+                "ulc_job_run_seconds",
+                "Time jobs ran",
+                &["class"],
+                Buckets::latency(),
+            )?,
+            jobs: registry.counter_family("ulc_jobs_total", "Jobs by class and outcome", &["class", "outcome"])?,
+        })
+    }
+
+    fn count(&self, class: &str, outcome: Outcome) {
+        self.jobs.with_labels(&[class, outcome.as_str()]).inc();
+    }
+}
+
+/// What a running job is given
+#[derive(Debug)]
+pub struct JobContext {
+    progress: watch::Sender<Progress>,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl JobContext {
+    /// Publish the job's progress to the watchers of its handle
+    pub fn report(&self, progress: Progress) {
+        self.progress.send_replace(progress);
+    }
+
+    /// Whether the job has been cancelled, for jobs that do long stretches of work between awaits
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+}
+
+/// A submitted job: its result, progress and cancellation
+///
+/// Awaiting the handle gives the job's result.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    id: u64,
+    class: String,
+    result: oneshot::Receiver<Result<T, JobError>>,
+    progress: watch::Receiver<Progress>,
+    cancel: Arc<watch::Sender<bool>>,
+    queue: Weak<JobQueue>,
+}
+
+impl<T> JobHandle<T> {
+    #[must_use]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Follow the progress the job reports
+    #[must_use]
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.progress.clone()
+    }
+
+    /// Cancel the job
+    ///
+    /// A waiting job is removed from the queue. A running one is dropped at
+    /// its next await, unless it finishes first.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+        if let Some(queue) = self.queue.upgrade() {
+            queue.withdraw(&self.class, self.id);
+        }
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T, JobError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The result is only ever dropped unsent for a job withdrawn from the queue
+        Pin::new(&mut self.result).poll(cx).map(|result| result.unwrap_or(Err(JobError::Cancelled)))
+    }
+}
+
+/// A job waiting for a worker
+struct Queued {
+    id: u64,
+    enqueued: Instant,
+    task: BoxFuture<'static, Outcome>,
+}
+
+#[derive(Default)]
+struct Class {
+    queued: VecDeque<Queued>,
+    running: usize,
+}
+
+/// Jobs waiting for and run by a fixed number of workers
+pub struct JobQueue {
+    config: JobsConfig,
+    metrics: JobMetrics,
+    classes: Mutex<HashMap<String, Class>>,
+    /// Signalled when a job is queued or a running one ends
+    ready: Notify,
+    /// Signalled to every drain when a job ends or leaves the queue
+    settled: Notify,
+    started: AtomicBool,
+    closed: AtomicBool,
+    next_id: AtomicU64,
+}
+
+impl fmt::Debug for JobQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobQueue").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl JobQueue {
+    #[must_use]
+    pub fn new(config: JobsConfig, metrics: JobMetrics) -> Self {
+        Self {
+            config,
+            metrics,
+            classes: Mutex::new(HashMap::new()),
+            ready: Notify::new(),
+            settled: Notify::new(),
+            started: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Queue `job` in `class`, or refuse it if the class has as many jobs waiting as it may
+    /// or the queue is closed
+    ///
+    /// Must be called within a Tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// [`Refused::Full`] where `class` has as many jobs waiting as it may, and
+    /// [`Refused::Closed`] once the queue is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the queue's lock, or outside a
+    /// Tokio runtime.
+    pub fn submit<T, F, Fut>(self: &Arc<Self>, class: &str, job: F) -> Result<JobHandle<T>, Refused>
+    where
+        T: Send + 'static,
+        F: FnOnce(JobContext) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let limits = self.config.limits(class);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (result_tx, result_rx) = oneshot::channel();
+        let (progress_tx, progress_rx) = watch::channel(Progress::default());
+        let cancel = Arc::new(watch::Sender::new(false));
+        let context = JobContext { progress: progress_tx, cancelled: cancel.subscribe() };
+
+        let task_cancel = Arc::clone(&cancel);
+        let task_class = class.to_string();
+        let task = async move {
+            let mut cancelled = task_cancel.subscribe();
+            let run = AssertUnwindSafe(async move { job(context).await }).catch_unwind();
+            let (outcome, result) = tokio::select! {
+                biased;
+                _ = cancelled.wait_for(|cancelled| *cancelled) => (Outcome::Cancelled, Err(JobError::Cancelled)),
+                finished = run => match finished {
+                    Ok(Ok(value)) => (Outcome::Succeeded, Ok(value)),
+                    Ok(Err(e)) => (Outcome::Failed, Err(JobError::Failed(e))),
+                    Err(panic) => {
+                        let message = panic_message(&*panic);
+                        warn!(class = %task_class, "Job panicked: {}", message);
+                        (Outcome::Panicked, Err(JobError::Panicked(message)))
+                    }
+                },
+            };
+            let _ = result_tx.send(result);
+            outcome
+        }
+        .boxed();
+
+        {
+            let mut classes = self.classes.lock().expect("job queue lock poisoned");
+            // Checked under the lock so that a drain counts every job admitted
+            if self.closed.load(Ordering::Acquire) {
+                self.metrics.count(class, Outcome::Rejected);
+                return Err(Refused::Closed);
+            }
+            let queue = classes.entry(class.to_string()).or_default();
+            if queue.queued.len() >= limits.max_queued {
+                self.metrics.count(class, Outcome::Rejected);
+                return Err(Refused::Full(QueueFull { class: class.to_string(), max_queued: limits.max_queued }));
+            }
+            queue.queued.push_back(Queued { id, enqueued: Instant::now(), task });
+            self.metrics.queued.with_labels(&[class]).inc();
+        }
+        self.start();
+        self.ready.notify_one();
+
+        Ok(JobHandle {
+            id,
+            class: class.to_string(),
+            result: result_rx,
+            progress: progress_rx,
+            cancel,
+            queue: Arc::downgrade(self),
+        })
+    }
+
+    /// Jobs waiting in `class`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the queue's lock.
+    pub fn queued(&self, class: &str) -> usize {
+        let classes = self.classes.lock().expect("job queue lock poisoned");
+        classes.get(class).map_or(0, |queue| queue.queued.len())
+    }
+
+    /// Jobs running in `class`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the queue's lock.
+    pub fn running(&self, class: &str) -> usize {
+        let classes = self.classes.lock().expect("job queue lock poisoned");
+        classes.get(class).map_or(0, |queue| queue.running)
+    }
+
+    /// Refuse every submission from now on
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the queue's lock.
+    pub fn close(&self) {
+        let _classes = self.classes.lock().expect("job queue lock poisoned");
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Whether the queue has been closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Wait for the jobs submitted so far to end, until `deadline`
+    ///
+    /// Jobs still waiting at the deadline are dropped, and their handles
+    /// resolve as cancelled; those still running are left to the runtime.
+    /// Both are counted as abandoned. Meant to follow [`close`](Self::close),
+    /// as jobs submitted meanwhile are waited for too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the queue's lock.
+    pub async fn drain(&self, deadline: tokio::time::Instant) -> Flushed {
+        let pending = self.pending();
+        loop {
+            let settled = self.settled.notified();
+            tokio::pin!(settled);
+            settled.as_mut().enable();
+            if self.pending() == 0 {
+                return Flushed::new(pending, 0);
+            }
+            tokio::select! {
+                () = settled => {}
+                () = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        let mut classes = self.classes.lock().expect("job queue lock poisoned");
+        let mut abandoned = 0;
+        for (class, queue) in classes.iter_mut() {
+            let queued = self.metrics.queued.with_labels(&[class]);
+            for _ in queue.queued.drain(..) {
+                queued.dec();
+                self.metrics.count(class, Outcome::Cancelled);
+                abandoned += 1;
+            }
+            abandoned += queue.running;
+        }
+        Flushed::new(pending.saturating_sub(abandoned), abandoned)
+    }
+
+    /// Jobs waiting or running, in every class
+    fn pending(&self) -> usize {
+        let classes = self.classes.lock().expect("job queue lock poisoned");
+        classes.values().map(|queue| queue.queued.len() + queue.running).sum()
+    }
+
+    fn start(self: &Arc<Self>) {
+        if self.started.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        for _ in 0..self.config.workers.max(1) {
+            tokio::spawn(Arc::clone(self).work());
+        }
+    }
+
+    /// Run jobs for as long as the runtime does
+    async fn work(self: Arc<Self>) {
+        loop {
+            let Some((class, queued)) = self.next() else {
+                self.ready.notified().await;
+                continue;
+            };
+            self.metrics.wait_duration.with_labels(&[&class]).observe_duration(queued.enqueued.elapsed());
+            let running = self.metrics.running.with_labels(&[&class]);
+            running.inc();
+            let started = Instant::now();
+            let outcome = queued.task.await;
+            self.metrics.run_duration.with_labels(&[&class]).observe_duration(started.elapsed());
+            running.dec();
+            self.metrics.count(&class, outcome);
+
+            if let Some(queue) = self.classes.lock().expect("job queue lock poisoned").get_mut(&class) {
+                queue.running -= 1;
+            }
+            // A job of this class may have been waiting for the slot
+            self.ready.notify_one();
+            self.settled.notify_waiters();
+        }
+    }
+
+    /// Take the longest waiting job of a class below its concurrency limit
+    fn next(&self) -> Option<(String, Queued)> {
+        let mut classes = self.classes.lock().expect("job queue lock poisoned");
+        let (class, queue) = classes
+            .iter_mut()
+            .filter(|(class, queue)| queue.running < self.config.limits(class).concurrency)
+            .filter_map(|(class, queue)| Some((queue.queued.front()?.enqueued, class, queue)))
+            .min_by_key(|(enqueued, _, _)| *enqueued)
+            .map(|(_, class, queue)| (class.clone(), queue))?;
+        let queued = queue.queued.pop_front()?;
+        queue.running += 1;
+        self.metrics.queued.with_labels(&[&class]).dec();
+        // Another worker may take a job of another class meanwhile
+        if queue.queued.front().is_some() {
+            self.ready.notify_one();
+        }
+        Some((class, queued))
+    }
+
+    /// Remove a job from the queue before it runs
+    fn withdraw(&self, class: &str, id: u64) {
+        let mut classes = self.classes.lock().expect("job queue lock poisoned");
+        let Some(queue) = classes.get_mut(class) else {
+            return;
+        };
+        if let Some(position) = queue.queued.iter().position(|queued| queued.id == id) {
+            queue.queued.remove(position);
+            self.metrics.queued.with_labels(&[class]).dec();
+            self.metrics.count(class, Outcome::Cancelled);
+            self.settled.notify_waiters();
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::Metrics;
+    use std::time::Duration;
+
+    fn queue(workers: usize, limits: ClassLimits) -> Arc<JobQueue> {
+        let config = JobsConfig { workers, default_limits: limits, classes: BTreeMap::new() };
+        Arc::new(JobQueue::new(config, Metrics::new().jobs))
+    }
+
+    /// Wait until `class` has `running` jobs running
+    async fn settle(queue: &JobQueue, class: &str, running: usize) {
+        for _ in 0..100 {
+            if queue.running(class) == running {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{class} never had {running} jobs running");
+    }
+
+    #[tokio::test]
+    async fn test_results_and_progress() {
+        let queue = queue(2, ClassLimits::default());
+        let handle = queue
+            .submit("sum", |context| async move {
+                context.report(Progress { done: 1, total: Some(2), message: None });
+                Ok(40 + 2)
+            })
+            .unwrap();
+        let progress = handle.progress();
+        assert_eq!(handle.await.unwrap(), 42);
+        assert_eq!(progress.borrow().done, 1);
+
+        let failed = queue.submit("sum", |_| async { Err::<(), _>(anyhow::anyhow!("no")) }).unwrap();
+        assert!(matches!(failed.await, Err(JobError::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_per_class() {
+        let queue = queue(4, ClassLimits::new(1, 10));
+        let (release, released) = watch::channel(false);
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let mut released = released.clone();
+            handles.push(
+                queue
+                    .submit("narrow", move |_| async move {
+                        let _ = released.wait_for(|released| *released).await;
+                        Ok(())
+                    })
+                    .unwrap(),
+            );
+        }
+        // Other classes are not held up by the narrow one
+        let other = queue.submit("other", |_| async { Ok("done") }).unwrap();
+        assert_eq!(other.await.unwrap(), "done");
+
+        settle(&queue, "narrow", 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(queue.running("narrow"), 1);
+        assert_eq!(queue.queued("narrow"), 2);
+
+        release.send_replace(true);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(queue.running("narrow"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_mid_run_and_queued() {
+        let queue = queue(1, ClassLimits::default());
+        let running = queue
+            .submit("slow", |context| async move {
+                context.report(Progress { done: 1, total: None, message: Some("started".to_string()) });
+                std::future::pending::<()>().await;
+                Ok(())
+            })
+            .unwrap();
+        let waiting = queue.submit("slow", |_| async { Ok(()) }).unwrap();
+        settle(&queue, "slow", 1).await;
+        assert_eq!(queue.queued("slow"), 1);
+
+        waiting.cancel();
+        assert_eq!(queue.queued("slow"), 0);
+        assert!(matches!(waiting.await, Err(JobError::Cancelled)));
+        running.cancel();
+        assert!(matches!(running.await, Err(JobError::Cancelled)));
+
+        // The worker is free for the next job
+        assert_eq!(queue.submit("slow", |_| async { Ok(1) }).unwrap().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sheds_load_when_saturated() {
+        let queue = queue(1, ClassLimits::new(1, 2));
+        let (release, released) = watch::channel(false);
+        let blocking = |mut released: watch::Receiver<bool>| {
+            move |_: JobContext| async move {
+                let _ = released.wait_for(|released| *released).await;
+                anyhow::Ok(())
+            }
+        };
+        let first = queue.submit("busy", blocking(released.clone())).unwrap();
+        settle(&queue, "busy", 1).await;
+        let queued: Vec<_> = (0..2).map(|_| queue.submit("busy", blocking(released.clone())).unwrap()).collect();
+
+        let refused = queue.submit("busy", blocking(released.clone())).unwrap_err();
+        assert_eq!(refused, Refused::Full(QueueFull { class: "busy".to_string(), max_queued: 2 }));
+        assert_eq!(queue.queued("busy"), 2);
+
+        release.send_replace(true);
+        first.await.unwrap();
+        for handle in queued {
+            handle.await.unwrap();
+        }
+        queue.submit("busy", blocking(released)).unwrap().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_and_drain() {
+        let queue = queue(1, ClassLimits::default());
+        let quick = queue.submit("mixed", |_| async { Ok(()) }).unwrap();
+        quick.await.unwrap();
+        let finishing = queue
+            .submit("mixed", |_| async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(())
+            })
+            .unwrap();
+        queue.close();
+        assert!(queue.is_closed());
+        assert_eq!(queue.submit("mixed", |_| async { Ok(()) }).unwrap_err(), Refused::Closed);
+
+        let drained = queue.drain(tokio::time::Instant::now() + Duration::from_secs(5)).await;
+        assert_eq!(drained, Flushed::new(1, 0));
+        finishing.await.unwrap();
+
+        // Past the deadline, waiting jobs are dropped and running ones left behind
+        let queue = self::queue(1, ClassLimits::default());
+        let stuck = queue.submit("stuck", |_| std::future::pending::<Result<()>>()).unwrap();
+        let waiting = queue.submit("stuck", |_| async { Ok(()) }).unwrap();
+        settle(&queue, "stuck", 1).await;
+        queue.close();
+        let drained = queue.drain(tokio::time::Instant::now() + Duration::from_millis(20)).await;
+        assert_eq!(drained, Flushed::new(0, 2));
+        assert_eq!(queue.queued("stuck"), 0);
+        assert!(matches!(waiting.await, Err(JobError::Cancelled)));
+        stuck.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_panic_fails_only_its_job() {
+        fn boom() -> Result<()> {
+            panic!("boom")
+        }
+        let queue = queue(1, ClassLimits::default());
+        let panicked = queue.submit("fragile", |_| async { boom() }).unwrap();
+        match panicked.await {
+            Err(JobError::Panicked(message)) => assert_eq!(message, "boom"),
+            other => panic!("expected a panic, got {other:?}"),
+        }
+        // The only worker survived
+        assert_eq!(queue.submit("fragile", |_| async { Ok("after") }).unwrap().await.unwrap(), "after");
+    }
+}
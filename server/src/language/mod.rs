@@ -0,0 +1,529 @@
+//! Document intelligence per language
+//!
+//! A [`LanguageProvider`] claims languages by ID and answers the editor
+//! features the LSP backend serves: diagnostics, completion, hover,
+//! document symbols, formatting and code actions. Every hook is optional. The backend
+//! asks the [`ProviderRegistry`] for every request, so documents reach the
+//! built-in [`FormatProvider`] and embedder providers the same way, whether
+//! the language server is on stdio, TCP or behind a WebSocket `Lsp` session.
+//!
+//! Providers claiming a document's language are consulted in order: those
+//! registered [`ProviderOrder::BeforeBuiltIn`], then the built-in ones, then
+//! those registered [`ProviderOrder::AfterBuiltIn`]. Diagnostics,
+//! completions and code actions from every provider are concatenated in that order; for
+//! hover, symbols and formatting the first provider with an answer wins.
+//! A document in a language nobody claims goes to the built-in providers.
+
+pub mod ulcignore;
+
+use crate::capabilities::{CapabilityInfo, CapabilityRegistry};
+use crate::core::Format;
+use crate::document_store::Document;
+use crate::formats::lint::Severity;
+use crate::formats::{pretty, schema, FormatOptions, Formats};
+use anyhow::Result;
+use std::sync::{Arc, RwLock};
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, Command, CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, DocumentSymbol,
+    FormattingOptions, FormattingProperty, Hover, HoverContents, MarkupContent, MarkupKind, NumberOrString, Position, Range, TextEdit,
+};
+
+/// Conversion commands, with the label and format of their result, in the order offered
+const CONVERSIONS: [(&str, &str, Format); 3] = [
+    ("convert.toHtml", "HTML", Format::Html),
+    ("convert.toMarkdown", "Markdown", Format::Markdown),
+    ("convert.toJson", "JSON", Format::Json),
+];
+
+/// Command inferring a JSON Schema from stored documents, given by URI, as `POST /api/schema/infer` does
+pub const INFER_SCHEMA_COMMAND: &str = "schema.infer";
+
+/// Version of the `languages` capabilities
+const LANGUAGES_VERSION: &str = "1";
+
+/// Editor features for the documents of some languages
+///
+/// Each hook gets a snapshot of the document as stored when the request
+/// arrived. Hooks a provider does not implement answer nothing.
+#[tower_lsp::async_trait]
+pub trait LanguageProvider: Send + Sync {
+    /// Name reported in the capabilities, such as `ulcignore`
+    fn name(&self) -> &str;
+
+    /// Language IDs this provider claims, as editors send them in `didOpen`
+    fn languages(&self) -> &[&str];
+
+    /// Problems found in `document`
+    async fn diagnostics(&self, _document: &Document) -> Vec<Diagnostic> {
+        Vec::new()
+    }
+
+    /// Completions offered at `position`
+    async fn completion(&self, _document: &Document, _position: Position) -> Vec<CompletionItem> {
+        Vec::new()
+    }
+
+    /// Hover shown at `position`
+    async fn hover(&self, _document: &Document, _position: Position) -> Option<Hover> {
+        None
+    }
+
+    /// Outline of `document`
+    async fn document_symbols(&self, _document: &Document) -> Option<Vec<DocumentSymbol>> {
+        None
+    }
+
+    /// Edits formatting the whole of `document`
+    async fn formatting(&self, _document: &Document, _options: &FormattingOptions) -> Option<Vec<TextEdit>> {
+        None
+    }
+
+    /// Actions offered on `range`
+    async fn code_actions(&self, _document: &Document, _range: Range) -> Vec<CodeActionOrCommand> {
+        Vec::new()
+    }
+}
+
+/// Where a provider is consulted relative to the built-in providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderOrder {
+    /// Ahead of the built-in providers, so its answers take precedence
+    BeforeBuiltIn,
+    /// After the built-in providers, adding to their answers
+    AfterBuiltIn,
+}
+
+/// Language providers consulted by the LSP backend, open to registration at runtime
+pub struct ProviderRegistry {
+    before: RwLock<Vec<Arc<dyn LanguageProvider>>>,
+    built_in: Vec<Arc<dyn LanguageProvider>>,
+    after: RwLock<Vec<Arc<dyn LanguageProvider>>>,
+    capabilities: Arc<CapabilityRegistry>,
+}
+
+impl ProviderRegistry {
+    /// A registry consulting `built_in` for every language no other provider claims
+    ///
+    /// Languages claimed by providers registered later are listed in
+    /// `capabilities` under `languages`.
+    pub fn new(built_in: Vec<Arc<dyn LanguageProvider>>, capabilities: Arc<CapabilityRegistry>) -> Self {
+        Self {
+            before: RwLock::new(Vec::new()),
+            built_in,
+            after: RwLock::new(Vec::new()),
+            capabilities,
+        }
+    }
+
+    /// Consult `provider` for the languages it claims, at `order`
+    ///
+    /// # Errors
+    ///
+    /// Fails where another provider already claims one of the languages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the provider list.
+    pub fn register(&self, provider: Arc<dyn LanguageProvider>, order: ProviderOrder) -> Result<()> {
+        if !self.capabilities.contains("languages") {
+            self.capabilities.register("languages", CapabilityInfo::new(LANGUAGES_VERSION))?;
+        }
+        for language in provider.languages() {
+            let capability = CapabilityInfo::new(LANGUAGES_VERSION).parameter("provider", provider.name());
+            self.capabilities.register(&format!("languages.{language}"), capability)?;
+        }
+        let providers = match order {
+            ProviderOrder::BeforeBuiltIn => &self.before,
+            ProviderOrder::AfterBuiltIn => &self.after,
+        };
+        providers.write().expect("provider lock poisoned").push(provider);
+        Ok(())
+    }
+
+    /// Providers for `language`, in the order they are consulted
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the provider list.
+    pub fn providers_for(&self, language: &str) -> Vec<Arc<dyn LanguageProvider>> {
+        let before = self.before.read().expect("provider lock poisoned");
+        let after = self.after.read().expect("provider lock poisoned");
+        let claiming: Vec<_> = before
+            .iter()
+            .chain(&self.built_in)
+            .chain(after.iter())
+            .filter(|provider| provider.languages().contains(&language))
+            .cloned()
+            .collect();
+        if claiming.is_empty() {
+            self.built_in.clone()
+        } else {
+            claiming
+        }
+    }
+
+    /// Diagnostics from every provider, in order
+    pub async fn diagnostics(&self, document: &Document) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for provider in self.providers_for(&document.language) {
+            diagnostics.extend(provider.diagnostics(document).await);
+        }
+        diagnostics
+    }
+
+    /// Completions from every provider, in order
+    pub async fn completion(&self, document: &Document, position: Position) -> Vec<CompletionItem> {
+        let mut items = Vec::new();
+        for provider in self.providers_for(&document.language) {
+            items.extend(provider.completion(document, position).await);
+        }
+        items
+    }
+
+    /// Hover from the first provider with one
+    pub async fn hover(&self, document: &Document, position: Position) -> Option<Hover> {
+        for provider in self.providers_for(&document.language) {
+            if let Some(hover) = provider.hover(document, position).await {
+                return Some(hover);
+            }
+        }
+        None
+    }
+
+    /// Symbols from the first provider with an outline
+    pub async fn document_symbols(&self, document: &Document) -> Option<Vec<DocumentSymbol>> {
+        for provider in self.providers_for(&document.language) {
+            if let Some(symbols) = provider.document_symbols(document).await {
+                return Some(symbols);
+            }
+        }
+        None
+    }
+
+    /// Code actions from every provider, in order
+    pub async fn code_actions(&self, document: &Document, range: Range) -> Vec<CodeActionOrCommand> {
+        let mut actions = Vec::new();
+        for provider in self.providers_for(&document.language) {
+            actions.extend(provider.code_actions(document, range).await);
+        }
+        actions
+    }
+
+    /// Formatting edits from the first provider that formats the document
+    pub async fn formatting(&self, document: &Document, options: &FormattingOptions) -> Option<Vec<TextEdit>> {
+        for provider in self.providers_for(&document.language) {
+            if let Some(edits) = provider.formatting(document, options).await {
+                return Some(edits);
+            }
+        }
+        None
+    }
+}
+
+/// The built-in formats: validation, conversion completions, document statistics and formatting
+///
+/// Documents in a language that is not a [`Format`] are treated as Markdown.
+pub struct FormatProvider {
+    formats: Formats,
+    capabilities: Arc<CapabilityRegistry>,
+}
+
+impl FormatProvider {
+    /// Validate through `formats`, offering conversions to the formats `capabilities` lists
+    pub fn new(formats: Formats, capabilities: Arc<CapabilityRegistry>) -> Self {
+        Self { formats, capabilities }
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageProvider for FormatProvider {
+    fn name(&self) -> &'static str {
+        "formats"
+    }
+
+    fn languages(&self) -> &[&str] {
+        &[
+            "markdown", "md", "html", "htm", "json", "yaml", "yml", "xml", "toml", "csv", "tsv", "ini", "cfg", "json5",
+            "jsonc", "ndjson", "jsonl", "msgpack", "cbor", "dotenv", "env", "plist",
+        ]
+    }
+
+    async fn diagnostics(&self, document: &Document) -> Vec<Diagnostic> {
+        let format = Format::from_str(&document.language).unwrap_or(Format::Markdown);
+        let Ok((issues, findings)) = self.formats.validate_with_lint(&document.content, format, Some(&document.uri)) else {
+            return Vec::new();
+        };
+        let mut diagnostics: Vec<Diagnostic> = issues
+            .iter()
+            .map(|message| Diagnostic {
+                range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                severity: Some(DiagnosticSeverity::WARNING),
+                message: message.clone(),
+                source: Some("universal-connector".to_string()),
+                ..Default::default()
+            })
+            .collect();
+        // Findings carry their rule as the code, for editors to offer turning it off
+        diagnostics.extend(findings.into_iter().map(|finding| {
+            let range = finding.span.as_ref().map_or(Range::default(), |span| {
+                Range::new(position(&document.content, span.start), position(&document.content, span.end))
+            });
+            Diagnostic {
+                range,
+                severity: Some(match finding.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                    Severity::Info => DiagnosticSeverity::INFORMATION,
+                    Severity::Hint | Severity::Off => DiagnosticSeverity::HINT,
+                }),
+                code: Some(NumberOrString::String(finding.rule)),
+                message: finding.message,
+                source: Some("universal-connector".to_string()),
+                ..Default::default()
+            }
+        }));
+
+        // A document that does not parse has nothing for a schema to check
+        let schema = self.formats.schemas().for_document(&document.uri).filter(|_| issues.is_empty());
+        if let Some((name, schema)) = schema.filter(|_| schema::supports(format)) {
+            let violations = self.formats.validate_schema(&document.content, format, &schema).unwrap_or_default();
+            diagnostics.extend(violations.into_iter().map(|found| Diagnostic {
+                range: Range::new(position(&document.content, found.span.start), position(&document.content, found.span.end)),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(found.violation.keyword)),
+                message: found.violation.message,
+                source: Some(format!("schema {name}")),
+                ..Default::default()
+            }));
+        }
+        diagnostics
+    }
+
+    async fn completion(&self, document: &Document, _position: Position) -> Vec<CompletionItem> {
+        // Offer a conversion to each format the server lists
+        conversions(&self.capabilities)
+            .into_iter()
+            .map(|(command, label, _)| CompletionItem {
+                label: format!("Convert to {label}"),
+                kind: Some(CompletionItemKind::TEXT),
+                detail: Some(format!("Convert current document to {label}")),
+                command: Some(Command {
+                    title: format!("Convert to {label}"),
+                    command: command.to_string(),
+                    arguments: Some(vec![serde_json::json!(document.uri)]),
+                }),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    async fn hover(&self, document: &Document, _position: Position) -> Option<Hover> {
+        let stats = document.stats();
+        let content = format!(
+            "**Document Statistics**\n\n\
+            - Lines: {}\n\
+            - Words: {}\n\
+            - Characters: {}\n\
+            - Version: {}\n\
+            - Format: {}",
+            stats.lines, stats.words, stats.characters, stats.version, document.language
+        );
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: content,
+            }),
+            range: None,
+        })
+    }
+
+    async fn formatting(&self, document: &Document, options: &FormattingOptions) -> Option<Vec<TextEdit>> {
+        let format = Format::from_str(&document.language).ok().filter(|format| pretty::supports(*format))?;
+        let formatted = self.formats.format_document(format, &document.content, &format_options(options)).ok()?;
+        if formatted == document.content {
+            return Some(Vec::new());
+        }
+        let whole = Range::new(Position::new(0, 0), position(&document.content, document.content.len()));
+        Some(vec![TextEdit::new(whole, formatted)])
+    }
+
+    async fn code_actions(&self, document: &Document, _range: Range) -> Vec<CodeActionOrCommand> {
+        // Schemas check JSON, YAML and TOML documents, so those are the ones to draft one from
+        let format = Format::from_str(&document.language).unwrap_or(Format::Markdown);
+        if !matches!(format, Format::Json | Format::Yaml | Format::Toml) {
+            return Vec::new();
+        }
+        let title = "Generate schema from this document".to_string();
+        vec![CodeActionOrCommand::CodeAction(CodeAction {
+            title: title.clone(),
+            kind: Some(CodeActionKind::SOURCE),
+            command: Some(Command {
+                title,
+                command: INFER_SCHEMA_COMMAND.to_string(),
+                arguments: Some(vec![serde_json::json!(document.uri)]),
+            }),
+            ..Default::default()
+        })]
+    }
+}
+
+/// The layout asked for: the indent from the tab size, the rest from `sortKeys`, `quoteStyle`, `arrayWrap` and `lineWidth` properties
+fn format_options(options: &FormattingOptions) -> FormatOptions {
+    let mut format = FormatOptions { indent: options.tab_size as usize, ..FormatOptions::default() };
+    if let Some(FormattingProperty::Bool(sort_keys)) = options.properties.get("sortKeys") {
+        format.sort_keys = *sort_keys;
+    }
+    if let Some(quote_style) = named(options, "quoteStyle") {
+        format.quote_style = quote_style;
+    }
+    if let Some(array_wrap) = named(options, "arrayWrap") {
+        format.array_wrap = array_wrap;
+    }
+    if let Some(FormattingProperty::Number(line_width)) = options.properties.get("lineWidth") {
+        format.line_width = usize::try_from(*line_width).unwrap_or(format.line_width);
+    }
+    format
+}
+
+/// The option a string property names, such as `single` for a [`QuoteStyle`](pretty::QuoteStyle)
+fn named<T: serde::de::DeserializeOwned>(options: &FormattingOptions, property: &str) -> Option<T> {
+    match options.properties.get(property) {
+        Some(FormattingProperty::String(name)) => serde_json::from_value(serde_json::Value::String(name.clone())).ok(),
+        _ => None,
+    }
+}
+
+/// The LSP position, in UTF-16, of the byte at `offset` in `text`
+fn position(text: &str, offset: usize) -> Position {
+    let before = text.get(..offset).unwrap_or(text);
+    let line = before.rsplit('\n').next().unwrap_or_default();
+    let (line, character) = (before.matches('\n').count(), line.encode_utf16().count());
+    Position::new(u32::try_from(line).unwrap_or(u32::MAX), u32::try_from(character).unwrap_or(u32::MAX))
+}
+
+/// Conversion commands to the formats `capabilities` lists
+pub(crate) fn conversions(capabilities: &CapabilityRegistry) -> Vec<(&'static str, &'static str, Format)> {
+    let formats = capabilities.formats();
+    CONVERSIONS.into_iter().filter(|(_, _, format)| formats.contains(format)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServerConfig;
+
+    /// Claims `languages`, answering every hook with its name
+    struct Named(&'static str, &'static [&'static str]);
+
+    #[tower_lsp::async_trait]
+    impl LanguageProvider for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn languages(&self) -> &[&str] {
+            self.1
+        }
+
+        async fn diagnostics(&self, _document: &Document) -> Vec<Diagnostic> {
+            vec![Diagnostic {
+                message: self.0.to_string(),
+                ..Default::default()
+            }]
+        }
+
+        async fn formatting(&self, _document: &Document, _options: &FormattingOptions) -> Option<Vec<TextEdit>> {
+            Some(vec![TextEdit::new(Range::default(), self.0.to_string())])
+        }
+    }
+
+    fn registry() -> ProviderRegistry {
+        let capabilities = Arc::new(CapabilityRegistry::from_config(&ServerConfig::default()));
+        let built_in: Arc<dyn LanguageProvider> = Arc::new(Named("built-in", &["markdown"]));
+        ProviderRegistry::new(vec![built_in], capabilities)
+    }
+
+    fn names(providers: &[Arc<dyn LanguageProvider>]) -> Vec<&str> {
+        providers.iter().map(|provider| provider.name()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_merge_order() {
+        let registry = registry();
+        registry.register(Arc::new(Named("after", &["markdown"])), ProviderOrder::AfterBuiltIn).unwrap();
+        registry.register(Arc::new(Named("before", &["markdown"])), ProviderOrder::BeforeBuiltIn).unwrap();
+        assert_eq!(names(&registry.providers_for("markdown")), ["before", "built-in", "after"]);
+
+        let document = Document::new("file:///a.md".to_string(), String::new(), "markdown".to_string());
+        let messages: Vec<_> = registry.diagnostics(&document).await.into_iter().map(|d| d.message).collect();
+        assert_eq!(messages, ["before", "built-in", "after"]);
+        let edits = registry.formatting(&document, &FormattingOptions::default()).await.unwrap();
+        assert_eq!(edits[0].new_text, "before");
+        assert!(registry.hover(&document, Position::default()).await.is_none());
+    }
+
+    #[test]
+    fn test_unclaimed_languages_go_to_built_in_providers() {
+        let registry = registry();
+        registry.register(Arc::new(Named("ignore", &["ulcignore"])), ProviderOrder::AfterBuiltIn).unwrap();
+        assert_eq!(names(&registry.providers_for("ulcignore")), ["ignore"]);
+        assert_eq!(names(&registry.providers_for("plaintext")), ["built-in"]);
+
+        let language = registry.capabilities.get("languages.ulcignore").unwrap();
+        assert_eq!(language.parameters["provider"], "ignore");
+    }
+
+    #[tokio::test]
+    async fn test_schema_diagnostics() {
+        let formats = Formats::standalone(crate::formats::FormatLimits::default());
+        let schema = schema::Schema::new(serde_json::json!({"properties": {"port": {"type": "integer"}}})).unwrap();
+        formats.schemas().register("service", schema, vec!["service.yaml".to_string()]).unwrap();
+        let capabilities = Arc::new(CapabilityRegistry::from_config(&ServerConfig::default()));
+        let provider = FormatProvider::new(formats, capabilities);
+
+        let content = "name: café\nport: eighty\n".to_string();
+        let document = Document::new("file:///work/service.yaml".to_string(), content.clone(), "yaml".to_string());
+        let diagnostics = provider.diagnostics(&document).await;
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert_eq!(diagnostics[0].range, Range::new(Position::new(1, 6), Position::new(1, 12)));
+        assert_eq!(diagnostics[0].code, Some(NumberOrString::String("type".to_string())));
+
+        let other = Document::new("file:///work/other.yaml".to_string(), content, "yaml".to_string());
+        assert!(provider.diagnostics(&other).await.is_empty());
+        assert_eq!(position("é\n😀x", "é\n😀x".len()), Position::new(1, 3));
+    }
+
+    #[tokio::test]
+    async fn test_lint_diagnostics() {
+        let capabilities = Arc::new(CapabilityRegistry::from_config(&ServerConfig::default()));
+        let provider = FormatProvider::new(Formats::standalone(crate::formats::FormatLimits::default()), capabilities);
+        let document = Document::new("file:///work/a.json".to_string(), "{\"a\": 1,\n \"a\": 2}".to_string(), "json".to_string());
+        let diagnostics = provider.diagnostics(&document).await;
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert_eq!(diagnostics[0].code, Some(NumberOrString::String("json/no-duplicate-keys".to_string())));
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostics[0].range.start, Position::new(1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_formatting() {
+        let capabilities = Arc::new(CapabilityRegistry::from_config(&ServerConfig::default()));
+        let provider = FormatProvider::new(Formats::standalone(crate::formats::FormatLimits::default()), capabilities);
+        let mut options = FormattingOptions { tab_size: 4, ..Default::default() };
+        options.properties.insert("sortKeys".to_string(), FormattingProperty::Bool(true));
+        options.properties.insert("arrayWrap".to_string(), FormattingProperty::String("always".to_string()));
+
+        let document = Document::new("file:///a.json".to_string(), "{\"b\": [1], \"a\": \"é\"}".to_string(), "json".to_string());
+        let edits = provider.formatting(&document, &options).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, Range::new(Position::new(0, 0), Position::new(0, 20)));
+        assert_eq!(edits[0].new_text, "{\n    \"a\": \"é\",\n    \"b\": [\n        1\n    ]\n}\n");
+
+        let formatted = Document::new("file:///a.json".to_string(), edits[0].new_text.clone(), "json".to_string());
+        assert_eq!(provider.formatting(&formatted, &options).await, Some(Vec::new()));
+        let invalid = Document::new("file:///a.json".to_string(), "{".to_string(), "json".to_string());
+        assert_eq!(provider.formatting(&invalid, &options).await, None);
+        let markdown = Document::new("file:///a.md".to_string(), "#  Title".to_string(), "markdown".to_string());
+        assert_eq!(provider.formatting(&markdown, &options).await, None);
+    }
+}
@@ -0,0 +1,232 @@
+//! `.ulcignore`: documents the connector leaves alone
+//!
+//! An example [`LanguageProvider`], small enough to read in one sitting.
+//! Each line of a `.ulcignore` file is a glob pattern of document URIs to
+//! ignore, `!pattern` includes documents again, and lines starting with
+//! `#` are comments:
+//!
+//! ```text
+//! # Build output
+//! **/target/**
+//! !**/target/doc/**
+//! ```
+//!
+//! Register it for documents opened with the `ulcignore` language ID:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use universal_connector_server::language::{ulcignore::UlcIgnoreProvider, ProviderOrder};
+//! use universal_connector_server::{ServerConfig, ServerState};
+//!
+//! let state = ServerState::new(ServerConfig::default());
+//! state.providers.register(Arc::new(UlcIgnoreProvider), ProviderOrder::BeforeBuiltIn).unwrap();
+//! assert!(state.capabilities.contains("languages.ulcignore"));
+//! ```
+
+use super::LanguageProvider;
+use crate::core::Format;
+use crate::document_store::Document;
+use std::collections::HashSet;
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, DocumentSymbol, FormattingOptions, Hover,
+    HoverContents, MarkupContent, MarkupKind, Position, Range, SymbolKind, TextEdit,
+};
+
+/// Source of the diagnostics this provider reports
+const SOURCE: &str = "ulcignore";
+
+/// Provider for the `ulcignore` language
+pub struct UlcIgnoreProvider;
+
+/// One line of a `.ulcignore` file
+#[derive(Debug, PartialEq, Eq)]
+enum Line<'a> {
+    Blank,
+    Comment(&'a str),
+    Ignore(&'a str),
+    Include(&'a str),
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Self {
+        let line = line.trim();
+        if line.is_empty() {
+            Self::Blank
+        } else if let Some(comment) = line.strip_prefix('#') {
+            Self::Comment(comment.trim())
+        } else if let Some(pattern) = line.strip_prefix('!') {
+            Self::Include(pattern.trim())
+        } else {
+            Self::Ignore(line)
+        }
+    }
+
+    fn pattern(&self) -> Option<&'a str> {
+        match self {
+            Self::Ignore(pattern) | Self::Include(pattern) => Some(pattern),
+            Self::Blank | Self::Comment(_) => None,
+        }
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageProvider for UlcIgnoreProvider {
+    fn name(&self) -> &'static str {
+        "ulcignore"
+    }
+
+    fn languages(&self) -> &[&str] {
+        &["ulcignore"]
+    }
+
+    async fn diagnostics(&self, document: &Document) -> Vec<Diagnostic> {
+        let mut seen = HashSet::new();
+        let mut diagnostics = Vec::new();
+        for (number, text) in document.content.lines().enumerate() {
+            let line = Line::parse(text);
+            let Some(pattern) = line.pattern() else {
+                continue;
+            };
+            let problem = if pattern.is_empty() {
+                Some((DiagnosticSeverity::ERROR, "Missing pattern after `!`".to_string()))
+            } else if pattern.matches('[').count() != pattern.matches(']').count() {
+                Some((DiagnosticSeverity::ERROR, format!("Unbalanced brackets in `{pattern}`")))
+            } else if !seen.insert(pattern) {
+                Some((DiagnosticSeverity::WARNING, format!("`{pattern}` is already listed")))
+            } else {
+                None
+            };
+            if let Some((severity, message)) = problem {
+                diagnostics.push(Diagnostic {
+                    range: line_range(number, text),
+                    severity: Some(severity),
+                    message,
+                    source: Some(SOURCE.to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+        diagnostics
+    }
+
+    async fn completion(&self, _document: &Document, _position: Position) -> Vec<CompletionItem> {
+        // Ignore every document of a format
+        Format::ALL
+            .into_iter()
+            .map(|format| CompletionItem {
+                label: format!("**/*.{}", format.extension()),
+                kind: Some(CompletionItemKind::FILE),
+                detail: Some(format!("Ignore every {} document", format.name())),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    async fn hover(&self, document: &Document, position: Position) -> Option<Hover> {
+        let text = document.content.lines().nth(position.line as usize)?;
+        let value = match Line::parse(text) {
+            Line::Blank | Line::Comment(_) => return None,
+            Line::Ignore(pattern) => format!("Documents matching `{pattern}` are ignored"),
+            Line::Include(pattern) => format!("Documents matching `{pattern}` are included again"),
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(line_range(position.line as usize, text)),
+        })
+    }
+
+    #[allow(deprecated)]
+    async fn document_symbols(&self, document: &Document) -> Option<Vec<DocumentSymbol>> {
+        let symbols = document
+            .content
+            .lines()
+            .enumerate()
+            .filter_map(|(number, text)| {
+                let line = Line::parse(text);
+                let detail = match line {
+                    Line::Ignore(_) => "ignore",
+                    Line::Include(_) => "include",
+                    Line::Blank | Line::Comment(_) => return None,
+                };
+                let name = line.pattern().filter(|pattern| !pattern.is_empty())?;
+                Some(DocumentSymbol {
+                    name: name.to_string(),
+                    detail: Some(detail.to_string()),
+                    kind: SymbolKind::FILE,
+                    tags: None,
+                    deprecated: None,
+                    range: line_range(number, text),
+                    selection_range: line_range(number, text),
+                    children: None,
+                })
+            })
+            .collect();
+        Some(symbols)
+    }
+
+    async fn formatting(&self, document: &Document, _options: &FormattingOptions) -> Option<Vec<TextEdit>> {
+        // One pattern per line with no stray whitespace, `!` against its pattern
+        let edits = document
+            .content
+            .lines()
+            .enumerate()
+            .filter_map(|(number, text)| {
+                let formatted = match Line::parse(text) {
+                    Line::Blank => String::new(),
+                    Line::Comment(comment) => format!("# {comment}").trim_end().to_string(),
+                    Line::Ignore(pattern) => pattern.to_string(),
+                    Line::Include(pattern) => format!("!{pattern}"),
+                };
+                (formatted != text).then(|| TextEdit::new(line_range(number, text), formatted))
+            })
+            .collect();
+        Some(edits)
+    }
+}
+
+/// The whole of line `number`, whose text is `text`
+fn line_range(number: usize, text: &str) -> Range {
+    let line = u32::try_from(number).unwrap_or(u32::MAX);
+    let end = u32::try_from(text.encode_utf16().count()).unwrap_or(u32::MAX);
+    Range::new(Position::new(line, 0), Position::new(line, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(content: &str) -> Document {
+        Document::new("file:///.ulcignore".to_string(), content.to_string(), "ulcignore".to_string())
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        assert_eq!(Line::parse("  "), Line::Blank);
+        assert_eq!(Line::parse("# build output"), Line::Comment("build output"));
+        assert_eq!(Line::parse(" **/target/** "), Line::Ignore("**/target/**"));
+        assert_eq!(Line::parse("! docs/*.md"), Line::Include("docs/*.md"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let diagnostics = UlcIgnoreProvider.diagnostics(&document("*.md\n!\n[ab\n# *.md\n*.md")).await;
+        let found: Vec<_> = diagnostics.iter().map(|d| (d.range.start.line, d.severity.unwrap())).collect();
+        assert_eq!(
+            found,
+            [(1, DiagnosticSeverity::ERROR), (2, DiagnosticSeverity::ERROR), (4, DiagnosticSeverity::WARNING)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_formatting_touches_only_untidy_lines() {
+        let edits = UlcIgnoreProvider
+            .formatting(&document("*.md\n  *.html  \n#notes\n! *.json"), &FormattingOptions::default())
+            .await
+            .unwrap();
+        let edited: Vec<_> = edits.iter().map(|edit| (edit.range.start.line, edit.new_text.as_str())).collect();
+        assert_eq!(edited, [(1, "*.html"), (2, "# notes"), (3, "!*.json")]);
+    }
+}
@@ -7,23 +7,94 @@
 #![deny(clippy::all)]
 #![warn(clippy::pedantic)]
 
+pub mod audit;
 pub mod auth;
+pub mod bridge;
+pub mod build_info;
+pub mod capabilities;
+pub mod client;
+pub mod client_ip;
+pub mod clients;
+pub mod collab;
+pub mod config;
 pub mod core;
 pub mod document_store;
 pub mod formats;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod http;
+pub mod jobs;
+pub mod language;
+pub mod logging;
 pub mod lsp;
 pub mod monitoring;
+pub mod oneshot;
+pub mod proxy;
+pub mod scheduler;
+pub mod server;
+pub mod shutdown;
+pub mod systemd;
+pub mod telemetry;
 pub mod websocket;
 
+use crate::audit::{AuditConfig, Auditor};
+use crate::auth::api_keys::ApiKeyStore;
+use crate::auth::csrf::CsrfConfig;
+use crate::auth::directory::LdapConfig;
+use crate::auth::lockout::LockoutConfig;
+use crate::auth::keyring::Keyring;
+use crate::auth::mtls::MtlsConfig;
+use crate::auth::oidc::OidcConfig;
+use crate::auth::policy::ScopePolicy;
+use crate::auth::refresh::{RefreshTokenStore, TokenLifetimes};
+use crate::auth::revocation::RevocationList;
+use crate::auth::roles::Roles;
+use crate::auth::saml::SamlConfig;
+use crate::auth::signing::SigningConfig;
+use crate::auth::{Claims, RateLimiter};
+use crate::config::Reload;
+use crate::document_store::Snapshots;
+use crate::formats::lint::LintConfig;
+use crate::formats::plugins::{self, PluginConfig};
+use crate::formats::schema::{self, SchemaConfig};
+use crate::monitoring::checks;
+use crate::monitoring::rules::{Rule, RuleEngine, RulesCheck};
+use crate::monitoring::statsd::StatsdConfig;
+use crate::monitoring::{Alerter, AlertsConfig, MetricsConfig, SlowOpConfig, SlowOps, Usage, UsageConfig};
+use crate::proxy::{DownstreamConfig, KnownCapabilities};
+use crate::scheduler::{OnShutdown, Schedule, TaskSpec};
+use crate::shutdown::{Flushed, Phase, ShutdownHooks, ShutdownReport};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
 
-pub use crate::auth::{AuthConfig, AuthService};
+pub use crate::auth::{AuthConfig, AuthService, RateLimitConfig, TokenAlgorithm};
+pub use crate::build_info::BuildInfo;
+pub use crate::capabilities::{CapabilityInfo, CapabilityRegistry};
+pub use crate::client_ip::TrustedProxies;
+pub use crate::clients::ClientRegistry;
+pub use crate::collab::CollabManager;
 pub use crate::document_store::DocumentStore;
-pub use crate::monitoring::{HealthChecker, Metrics};
+pub use crate::formats::{FormatLimits, Formats};
+pub use crate::jobs::{JobQueue, JobsConfig};
+pub use crate::language::{LanguageProvider, ProviderOrder, ProviderRegistry};
+pub use crate::logging::{LogHandle, LoggingConfig};
+pub use crate::monitoring::{CheckPolicy, HealthCheck, HealthChecker, LifecycleThresholds, Metrics};
+pub use crate::scheduler::{Scheduler, TasksConfig};
+pub use crate::server::{Server, ServerHandle};
+pub use crate::telemetry::TracingConfig;
+pub use crate::websocket::admission::ConnectionLimits;
+pub use crate::websocket::{Capability, Negotiated, ServerLimits};
 
 /// Main server configuration
-#[derive(Debug, Clone)]
+///
+/// Read from a file with [`ServerConfig::from_file`]; every field has a default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
 pub struct ServerConfig {
     /// HTTP server bind address
     pub http_addr: String,
@@ -35,10 +106,89 @@ pub struct ServerConfig {
     pub enable_http: bool,
     /// Enable WebSocket server
     pub enable_websocket: bool,
+    /// gRPC server bind address
+    pub grpc_addr: String,
+    /// Enable gRPC server; needs a build with the `grpc` feature
+    pub enable_grpc: bool,
     /// JWT secret for authentication (Platinum RSR)
     pub jwt_secret: String,
+    /// Algorithm tokens are signed and verified with: HS256 with `jwt_secret`, RS256 or ES256 with key files
+    pub jwt_algorithm: TokenAlgorithm,
+    /// PEM private key RS256 or ES256 tokens are signed with, published at `/.well-known/jwks.json`
+    pub jwt_private_key_file: Option<PathBuf>,
+    /// PEM public keys of other issuers whose RS256 or ES256 tokens are accepted
+    pub jwt_public_key_files: Vec<PathBuf>,
+    /// `OpenID` Connect provider whose tokens are accepted, with how its claims map to scopes
+    pub oidc: Option<OidcConfig>,
+    /// Serve HTTP and WebSocket over TLS, requiring client certificates that stand for identities
+    pub mtls: Option<MtlsConfig>,
+    /// HTTP clients signing each request with a shared secret, in place of a token
+    pub request_signing: Option<SigningConfig>,
+    /// LDAP or Active Directory server `/auth/login` checks usernames and passwords against
+    pub ldap: Option<LdapConfig>,
+    /// SAML identity provider whose signed assertions `/auth/saml/acs` exchanges for tokens
+    pub saml: Option<SamlConfig>,
+    /// Lifetimes of the access and refresh tokens issued at `/auth/refresh`
+    pub tokens: TokenLifetimes,
+    /// Scopes granted by each role, for credentials holding `role:<name>`
+    pub roles: Roles,
+    /// Scopes routes and WebSocket messages require on top of their own
+    pub policy: ScopePolicy,
+    /// HTTP request limits per client IP, per subject, and for subjects holding `unlimited`
+    pub rate_limit: RateLimitConfig,
+    /// Backoff and lockout after failed logins, per client IP and per username
+    pub lockout: LockoutConfig,
+    /// Access tokens in cookies for browser clients, and the CSRF protection they need
+    pub csrf: CsrfConfig,
+    /// Sinks of the security audit log of authentication and authorization events
+    pub audit: AuditConfig,
     /// Enable authentication (Platinum RSR)
     pub enable_auth: bool,
+    /// Let clients presenting no credentials read, and nothing more, rather than do everything with authentication disabled, or nothing with it enabled
+    pub anonymous_read_only: bool,
+    /// Caps on concurrent WebSocket connections
+    pub ws_connection_limits: ConnectionLimits,
+    /// Reverse proxies trusted to report client addresses
+    pub trusted_proxies: TrustedProxies,
+    /// Size caps on converted and validated documents
+    pub format_limits: FormatLimits,
+    /// Directory for persisted state, checked for writability and free space;
+    /// documents are kept there across restarts
+    pub data_dir: Option<PathBuf>,
+    /// Format plugins loaded at startup, and their sandbox limits
+    pub plugins: PluginConfig,
+    /// JSON Schemas loaded at startup, for validation requests and the documents they apply to
+    pub schemas: Vec<SchemaConfig>,
+    /// Severities of the lint rules documents are checked with, for every document and per workspace
+    pub lint: LintConfig,
+    /// Health evaluations needed to change lifecycle state
+    pub lifecycle_thresholds: LifecycleThresholds,
+    /// URL notified of every lifecycle transition
+    pub lifecycle_webhook: Option<String>,
+    /// Threshold rules over metrics, feeding health and alerts
+    pub alert_rules: Vec<Rule>,
+    /// URL notified when a rule fires or resolves
+    pub alert_webhook: Option<String>,
+    /// Sinks notified of firing and resolved alerts
+    pub alerts: AlertsConfig,
+    /// Thresholds and log sampling for slow operations
+    pub slow_ops: SlowOpConfig,
+    /// Per-client usage accounting and its daily rollup
+    pub usage: UsageConfig,
+    /// Metrics left unrecorded or sampled, changeable at runtime
+    pub metrics: MetricsConfig,
+    /// `StatsD` agent to push metrics to, alongside `/metrics`
+    pub statsd: Option<StatsdConfig>,
+    /// Log format, filter and destination
+    pub logging: LoggingConfig,
+    /// OpenTelemetry trace export
+    pub tracing: TracingConfig,
+    /// Language servers LSP requests about the documents they match are forwarded to
+    pub downstreams: Vec<DownstreamConfig>,
+    /// Workers and per-class limits of the background job queue
+    pub jobs: JobsConfig,
+    /// Document expiry and the schedules of maintenance tasks
+    pub tasks: TasksConfig,
 }
 
 impl Default for ServerConfig {
@@ -49,8 +199,47 @@ fn default() -> Self {
             enable_lsp: true,
             enable_http: true,
             enable_websocket: true,
+            grpc_addr: "0.0.0.0:50051".to_string(),
+            enable_grpc: false,
             jwt_secret: "dev-secret-change-in-production".to_string(),
+            jwt_algorithm: TokenAlgorithm::default(),
+            jwt_private_key_file: None,
+            jwt_public_key_files: Vec::new(),
+            oidc: None,
+            mtls: None,
+            request_signing: None,
+            ldap: None,
+            saml: None,
+            tokens: TokenLifetimes::default(),
+            roles: Roles::default(),
+            policy: ScopePolicy::default(),
+            rate_limit: RateLimitConfig::default(),
+            lockout: LockoutConfig::default(),
+            csrf: CsrfConfig::default(),
+            audit: AuditConfig::default(),
             enable_auth: false, // Disabled by default for development
+            anonymous_read_only: false,
+            ws_connection_limits: ConnectionLimits::default(),
+            trusted_proxies: TrustedProxies::default(),
+            format_limits: FormatLimits::default(),
+            data_dir: None,
+            plugins: PluginConfig::default(),
+            schemas: Vec::new(),
+            lint: LintConfig::default(),
+            lifecycle_thresholds: LifecycleThresholds::default(),
+            lifecycle_webhook: None,
+            alert_rules: Vec::new(),
+            alert_webhook: None,
+            alerts: AlertsConfig::default(),
+            slow_ops: SlowOpConfig::default(),
+            usage: UsageConfig::default(),
+            metrics: MetricsConfig::default(),
+            statsd: None,
+            logging: LoggingConfig::default(),
+            tracing: TracingConfig::default(),
+            downstreams: Vec::new(),
+            jobs: JobsConfig::default(),
+            tasks: TasksConfig::default(),
         }
     }
 }
@@ -59,38 +248,405 @@ fn default() -> Self {
 pub struct ServerState {
     /// Document store (thread-safe, lock-free)
     pub documents: Arc<DocumentStore>,
-    /// Server configuration
-    pub config: ServerConfig,
+    /// Configuration in force, replaced by [`ServerState::reload`]
+    config: watch::Sender<Arc<ServerConfig>>,
     /// Metrics collector (Platinum RSR)
     pub metrics: Arc<Metrics>,
+    /// Conversion and validation, reported to the metrics collector
+    pub formats: Formats,
+    /// What this instance supports, as every transport reports it
+    pub capabilities: Arc<CapabilityRegistry>,
+    /// Editor features per language, consulted by the LSP backend
+    pub providers: Arc<ProviderRegistry>,
     /// Health checker (Platinum RSR)
     pub health_checker: Arc<HealthChecker>,
     /// Authentication service (Platinum RSR)
     pub auth_service: Option<Arc<AuthService>>,
+    /// Concurrent editing sessions for shared documents
+    pub collab: Arc<CollabManager>,
+    /// Resumable WebSocket sessions
+    pub ws_sessions: Arc<websocket::SessionRegistry>,
+    /// WebSocket connection admission control
+    pub ws_admission: Arc<websocket::admission::Admission>,
+    /// Runtime control of the installed log filter, if the binary installed one
+    pub logging: Option<LogHandle>,
+    /// Threshold rules, evaluated by [`RuleEngine::run`]
+    pub rules: Arc<RuleEngine>,
+    /// Recent operations slower than their configured threshold
+    pub slow_ops: Arc<SlowOps>,
+    /// Usage per authenticated subject
+    pub usage: Arc<Usage>,
+    /// Alert notifications to the configured sinks, delivered by [`Alerter::run`]
+    pub alerts: Arc<Alerter>,
+    /// Capabilities of the downstream language servers, as they last reported them
+    pub downstream_capabilities: Arc<KnownCapabilities>,
+    /// Connected clients over every transport, with what they said about themselves
+    pub clients: Arc<ClientRegistry>,
+    /// Background work such as webhook deliveries and LSP diagnostics
+    pub jobs: Arc<JobQueue>,
+    /// Recurring maintenance, run by [`Scheduler::run`]
+    pub scheduler: Arc<Scheduler>,
+    /// Token buckets per client, shared by every transport and pruned by the scheduler
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Security audit log, written to its sinks by [`Auditor::run`]
+    pub audit: Arc<Auditor>,
+    /// What [`ServerState::shutdown`] flushes, registered by each subsystem
+    pub shutdown_hooks: ShutdownHooks,
+}
+
+/// Register the built-in maintenance tasks the configuration calls for
+fn schedule_maintenance(
+    scheduler: &Scheduler,
+    config: &ServerConfig,
+    documents: &Arc<DocumentStore>,
+    snapshots: Option<&Arc<Snapshots>>,
+    usage: &Arc<Usage>,
+) {
+    let unique = "built-in task names are unique";
+    let minutely = || Schedule::Every(Duration::from_mins(1));
+    let ttl = config.tasks.document_ttl;
+    if !ttl.is_zero() {
+        let documents = Arc::clone(documents);
+        let spec = TaskSpec::new(scheduler::DOCUMENT_TTL_SWEEP, minutely());
+        let sweep = move || {
+            let expired = chrono::Duration::from_std(ttl).map(|ttl| documents.expire(chrono::Utc::now() - ttl));
+            async move {
+                let expired = expired?;
+                if expired > 0 {
+                    info!("Expired {} documents unchanged for {}", expired, config::duration::format(ttl));
+                }
+                anyhow::Ok(())
+            }
+        };
+        scheduler.register(spec, sweep).expect(unique);
+    }
+    if config.usage.rollup_file.is_some() {
+        let usage = Arc::clone(usage);
+        let spec = TaskSpec::new(scheduler::USAGE_ROLLUP, minutely()).on_shutdown(OnShutdown::Wait);
+        let rollup = move || {
+            let usage = Arc::clone(&usage);
+            async move { usage.rollup().await }
+        };
+        scheduler.register(spec, rollup).expect(unique);
+    }
+    if let Some(snapshots) = snapshots {
+        let snapshots = Arc::clone(snapshots);
+        let spec = TaskSpec::new(scheduler::DOCUMENT_SNAPSHOT, minutely()).on_shutdown(OnShutdown::Wait);
+        let snapshot = move || {
+            let snapshots = Arc::clone(&snapshots);
+            async move { snapshots.flush().await.map(|_| ()) }
+        };
+        scheduler.register(spec, snapshot).expect(unique);
+    }
+}
+
+/// A credential store under `data_dir`, or a temporary one without it
+///
+/// A store that cannot be opened is logged, and the `what` it keeps are then refused.
+fn open_store<T>(
+    config: &ServerConfig,
+    what: &str,
+    open: impl FnOnce(&std::path::Path) -> anyhow::Result<T>,
+    temporary: impl FnOnce() -> anyhow::Result<T>,
+) -> Option<Arc<T>> {
+    let opened = match &config.data_dir {
+        Some(dir) => open(dir),
+        None => temporary(),
+    };
+    opened.map(Arc::new).map_err(|e| warn!("{} not accepted: {:#}", what, e)).ok()
+}
+
+/// Register the flush hooks of the subsystems [`ServerState::shutdown`] settles
+fn register_shutdown_hooks(
+    hooks: &ShutdownHooks,
+    config: &ServerConfig,
+    jobs: &Arc<JobQueue>,
+    scheduler: &Arc<Scheduler>,
+    snapshots: Option<&Arc<Snapshots>>,
+    usage: &Arc<Usage>,
+    alerts: &Arc<Alerter>,
+) {
+    let jobs = Arc::clone(jobs);
+    hooks.register("jobs", Phase::Jobs, move |deadline| async move {
+        jobs.close();
+        Ok(jobs.drain(deadline).await)
+    });
+    let scheduler = Arc::clone(scheduler);
+    hooks.register("scheduler", Phase::Jobs, move |_| async move { Ok(scheduler.shutdown().await) });
+    if let Some(snapshots) = snapshots {
+        let snapshots = Arc::clone(snapshots);
+        hooks.register("documents", Phase::Persistence, move |_| async move {
+            Ok(Flushed::new(snapshots.flush().await?, 0))
+        });
+    }
+    // Roll up the day so far, so a restart loses no usage
+    let usage = Arc::clone(usage);
+    hooks.register("usage", Phase::Buffers, move |_| async move { Ok(Flushed::new(usage.flush().await?, 0)) });
+    let alerts = Arc::clone(alerts);
+    hooks.register("alerts", Phase::Buffers, move |deadline| async move { Ok(alerts.drain(deadline).await) });
+    if config.tracing.otlp_endpoint.is_some() {
+        hooks.register("tracing", Phase::Exporters, |_| async {
+            telemetry::shutdown().await;
+            Ok(Flushed::default())
+        });
+    }
 }
 
 impl ServerState {
     /// Create new server state
     pub fn new(config: ServerConfig) -> Self {
         // Create auth service if enabled
+        let (api_keys, refresh_tokens, revocations) = if config.enable_auth {
+            (
+                open_store(&config, "API keys", ApiKeyStore::open, ApiKeyStore::temporary),
+                open_store(&config, "Refresh tokens", RefreshTokenStore::open, RefreshTokenStore::temporary),
+                open_store(&config, "Token revocations", RevocationList::open, || Ok(RevocationList::in_memory())),
+            )
+        } else {
+            (None, None, None)
+        };
         let auth_service = if config.enable_auth {
-            let auth_config = AuthConfig {
-                secret: config.jwt_secret.clone(),
-                expiration_secs: 86400, // 24 hours
-                required_scopes: std::collections::HashMap::new(),
-                enabled: true,
-            };
-            Some(Arc::new(AuthService::new(auth_config)))
+            let mut auth = AuthService::new(AuthConfig::from_server_config(&config));
+            if let Some(store) = &api_keys {
+                auth = auth.with_api_keys(Arc::clone(store));
+            }
+            if let Some(store) = &refresh_tokens {
+                auth = auth.with_refresh_tokens(Arc::clone(store));
+            }
+            if let Some(list) = &revocations {
+                auth = auth.with_revocations(Arc::clone(list));
+            }
+            if let Some(keyring) = open_store(&config, "Signing keys", Keyring::open, || Ok(Keyring::in_memory())) {
+                auth = auth.with_keyring(keyring);
+            }
+            Some(Arc::new(auth))
         } else {
             None
         };
 
+        let slow_ops = Arc::new(SlowOps::new(config.slow_ops.clone()));
+        let metrics = Arc::new(Metrics::new());
+        if let Err(e) = metrics.apply(&config.metrics) {
+            tracing::warn!("Metric settings not applied, recording every metric: {:#}", e);
+        }
+        let documents = Arc::new(DocumentStore::new());
+        documents.report_slow_ops(Arc::clone(&slow_ops));
+        documents.record_metrics(Arc::new(metrics.store.clone()));
+        let jobs = Arc::new(JobQueue::new(config.jobs.clone(), metrics.jobs.clone()));
+
+        let health_checker = HealthChecker::with_thresholds(config.lifecycle_thresholds);
+        metrics.record_lifecycle(health_checker.state(), chrono::Utc::now());
+        let lifecycle_metrics = Arc::clone(&metrics);
+        health_checker.observe(move |transition| lifecycle_metrics.record_lifecycle(transition.to, transition.at));
+        if let Some(url) = &config.lifecycle_webhook {
+            health_checker.observe(monitoring::lifecycle::webhook(url.clone(), Arc::clone(&jobs)));
+        }
+        let policy = CheckPolicy::default();
+        health_checker.register(checks::DocumentStoreCheck::new(Arc::clone(&documents)), policy);
+        health_checker.register(checks::AuthCheck::new(auth_service.clone()), policy);
+        health_checker.register(checks::EventLoopLagCheck::new(Arc::clone(&metrics)), policy);
+        health_checker.register(checks::ErrorRateCheck::new(Arc::clone(&metrics)), policy);
+        if let Some(dir) = &config.data_dir {
+            health_checker.register(checks::WritableCheck::new(dir), policy);
+            health_checker.register(checks::DiskSpaceCheck::new(dir), policy);
+        }
+
+        let rules = Arc::new(RuleEngine::new(config.alert_rules.clone()));
+        if let Some(url) = &config.alert_webhook {
+            rules.observe(monitoring::rules::webhook(url.clone(), Arc::clone(&jobs)));
+        }
+        health_checker.register(RulesCheck::new(Arc::clone(&rules)), policy);
+        let alerts = Arc::new(Alerter::new(config.alerts.clone(), &metrics.alerts));
+        let alerter = Arc::clone(&alerts);
+        rules.observe_evaluations(move |events, statuses| alerter.notify(events, statuses));
+
+        let formats =
+            Formats::new(config.format_limits.clone(), metrics.clone()).with_slow_ops(Arc::clone(&slow_ops));
+        let capabilities = Arc::new(CapabilityRegistry::from_config(&config));
+        plugins::install(&config.plugins, formats.plugins(), &capabilities);
+        schema::install(&config.schemas, formats.schemas());
+        formats.set_lint_config(config.lint.clone());
+        let built_in: Arc<dyn LanguageProvider> =
+            Arc::new(language::FormatProvider::new(formats.clone(), Arc::clone(&capabilities)));
+        let providers = ProviderRegistry::new(vec![built_in], Arc::clone(&capabilities));
+
+        let scheduler = Arc::new(Scheduler::new(config.tasks.clone(), metrics.tasks.clone()));
+        let usage = Arc::new(Usage::new(config.usage.clone()));
+        let snapshots = config.data_dir.as_deref().map(|dir| {
+            let snapshots = Snapshots::new(Arc::clone(&documents), dir);
+            match snapshots.load() {
+                Ok(0) => {}
+                Ok(restored) => info!("Restored {} documents from {}", restored, snapshots.path().display()),
+                Err(e) => warn!("Documents not restored: {:#}", e),
+            }
+            Arc::new(snapshots)
+        });
+        schedule_maintenance(&scheduler, &config, &documents, snapshots.as_ref(), &usage);
+        if let Some(provider) = auth_service.as_ref().and_then(|auth| auth.oidc()) {
+            auth::oidc::schedule_refresh(Arc::clone(provider), &scheduler).expect("built-in task names are unique");
+        }
+        if let Some(store) = &refresh_tokens {
+            auth::refresh::schedule_prune(Arc::clone(store), &scheduler).expect("built-in task names are unique");
+        }
+        if let Some(list) = &revocations {
+            auth::revocation::schedule_prune(Arc::clone(list), &scheduler).expect("built-in task names are unique");
+        }
+        let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
+        if config.rate_limit.enabled {
+            info!("Rate limit buckets kept in {}", rate_limiter.backend().name());
+        }
+        auth::schedule_eviction(Arc::clone(&rate_limiter), &scheduler).expect("built-in task names are unique");
+        let audit = Arc::new(Auditor::new(&config.audit));
+        let shutdown_hooks = ShutdownHooks::new();
+        register_shutdown_hooks(&shutdown_hooks, &config, &jobs, &scheduler, snapshots.as_ref(), &usage, &alerts);
+        if audit.is_enabled() {
+            let audit = Arc::clone(&audit);
+            shutdown_hooks.register("audit", Phase::Buffers, move |deadline| async move { Ok(audit.drain(deadline).await) });
+        }
+        if let Some(store) = api_keys {
+            shutdown_hooks.register("api_keys", Phase::Persistence, move |_| async move {
+                store.flush().await?;
+                Ok(Flushed::default())
+            });
+        }
+        if let Some(store) = refresh_tokens {
+            shutdown_hooks.register("refresh_tokens", Phase::Persistence, move |_| async move {
+                store.flush().await?;
+                Ok(Flushed::default())
+            });
+        }
+        if let Some(list) = revocations {
+            shutdown_hooks.register("revoked_tokens", Phase::Persistence, move |_| async move {
+                list.flush().await?;
+                Ok(Flushed::default())
+            });
+        }
+
         Self {
-            documents: Arc::new(DocumentStore::new()),
-            metrics: Arc::new(Metrics::new()),
-            health_checker: Arc::new(HealthChecker::new()),
+            collab: Arc::new(CollabManager::new(Arc::clone(&documents))),
+            ws_sessions: Arc::new(websocket::SessionRegistry::new(
+                &documents,
+                metrics.connections.ws_sessions_retained.clone(),
+            )),
+            ws_admission: Arc::new(websocket::admission::Admission::new(
+                config.ws_connection_limits.clone(),
+                Arc::clone(&metrics),
+            )),
+            formats,
+            capabilities,
+            providers: Arc::new(providers),
+            documents,
+            metrics,
+            health_checker: Arc::new(health_checker),
             auth_service,
-            config,
+            logging: None,
+            rules,
+            slow_ops,
+            usage,
+            alerts,
+            downstream_capabilities: Arc::new(KnownCapabilities::default()),
+            clients: Arc::new(ClientRegistry::new()),
+            jobs,
+            scheduler,
+            rate_limiter,
+            audit,
+            shutdown_hooks,
+            config: watch::channel(Arc::new(config)).0,
+        }
+    }
+
+    /// Settle background work and flush what is buffered, within `timeout` in all
+    ///
+    /// Runs the [`shutdown_hooks`](Self::shutdown_hooks) by phase: the job
+    /// queue stops taking jobs and drains, maintenance runs settle, the
+    /// documents are written to the data directory, usage and alert
+    /// buffers are flushed, and exporters are closed. Transports call this
+    /// once they have drained. Hooks run once; a second call reports nothing.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.shutdown_hooks.run(timeout).await
+    }
+
+    /// Configuration in force
+    pub fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config.borrow())
+    }
+
+    /// Claims of clients presenting no credentials, if they may do anything
+    pub fn anonymous_claims(&self) -> Option<Claims> {
+        let config = self.config();
+        auth::anonymous_claims(config.enable_auth, config.anonymous_read_only)
+    }
+
+    /// Follow the configuration in force, which changes on every reload that applies a setting
+    pub fn subscribe_config(&self) -> watch::Receiver<Arc<ServerConfig>> {
+        self.config.subscribe()
+    }
+
+    /// Apply the settings of a reloaded, already validated configuration
+    ///
+    /// Changes to [`config::reload::RELOADABLE`] settings take effect at
+    /// once. Any other change is logged at `warn` and left for a restart.
+    /// Either way each changed setting is counted in `ulc_config_changes_total`.
+    pub fn reload(&self, loaded: ServerConfig) -> Reload {
+        let reload = Reload::plan(&self.config(), loaded);
+        let effective = &reload.effective;
+        if reload.applies("ws_connection_limits") {
+            self.ws_admission.set_limits(effective.ws_connection_limits.clone());
+        }
+        if reload.applies("format_limits") {
+            self.formats.set_limits(effective.format_limits.clone());
+            let limits = &effective.format_limits;
+            for (name, value) in [
+                ("max_input_bytes", limits.max_input_bytes),
+                ("max_output_bytes", limits.max_output_bytes),
+            ] {
+                if let Err(e) = self.capabilities.set_parameter("formats", name, value) {
+                    warn!("Format limits not listed in the capabilities: {:#}", e);
+                }
+            }
+        }
+        if reload.applies("lint") {
+            self.formats.set_lint_config(effective.lint.clone());
+        }
+        if reload.applies("slow_ops") {
+            self.slow_ops.set_config(effective.slow_ops.clone());
+        }
+        if reload.applies("metrics") {
+            if let Err(e) = self.metrics.apply(&effective.metrics) {
+                warn!("Metric settings not reloaded: {:#}", e);
+            }
+        }
+        if reload.applies("alerts") {
+            self.alerts.reconfigure(effective.alerts.clone());
+        }
+        if reload.applies("logging") {
+            let configured = self.logging.as_ref().map(|handle| handle.configure(&effective.logging.filter_spec()));
+            if let Some(Err(e)) = configured {
+                warn!("Log filter not reloaded: {:#}", e);
+            }
+        }
+        if !reload.applied.is_empty() {
+            self.config.send_replace(Arc::new(effective.clone()));
+        }
+
+        let outcome = if reload.is_empty() { "unchanged" } else { "changed" };
+        self.metrics.config_reloads.with_labels(&[outcome]).inc();
+        for (settings, outcome) in [(&reload.applied, "applied"), (&reload.rejected, "rejected")] {
+            for setting in settings {
+                self.metrics.config_changes.with_labels(&[setting.as_str(), outcome]).inc();
+            }
+        }
+        if reload.is_empty() {
+            info!("Configuration reloaded, unchanged");
+        } else if !reload.applied.is_empty() {
+            info!(changed = %reload.applied.join(","), "Configuration reloaded");
+        }
+        if !reload.rejected.is_empty() {
+            warn!(
+                settings = %reload.rejected.join(","),
+                "Configuration changes not applied; they take effect after a restart"
+            );
         }
+        reload
     }
 }
@@ -0,0 +1,746 @@
+//! Log output configuration
+//!
+//! Logs go to stderr, as stdout carries LSP traffic, or to a size-rotated
+//! file. The filter can be replaced while the server runs, through
+//! [`LogHandle`], from the admin API or on SIGHUP.
+//!
+//! JSON records are one object per line with flat fields:
+//!
+//! - `timestamp`, `level`, `target` and `message`, always
+//! - the event's own fields
+//! - `span`, the innermost span, and `request_id`, when in one
+//! - `trace_id` and `span_id`, when spans are exported
+//! - the fields of every enclosing span, when span fields are included
+
+use crate::telemetry::{self, TracingConfig};
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::{Context, Layered, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// Span field kept in JSON records even when span fields are left out
+const REQUEST_ID: &str = "request_id";
+
+/// Log record layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line, human-oriented
+    Pretty,
+    /// One line per record
+    Compact,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    /// Parse `pretty`, `compact` or `json`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `s` is none of the three.
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown log format: {s}")),
+        }
+    }
+
+    /// Name accepted by [`LogFormat::parse`]
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pretty => "pretty",
+            Self::Compact => "compact",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Log file rotated by size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogFileConfig {
+    /// File written to; rotated files get `.1`, `.2`, … appended
+    pub path: PathBuf,
+    /// Size after which the file is rotated
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files kept; older ones are deleted
+    #[serde(default = "default_retain")]
+    pub retain: usize,
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_retain() -> usize {
+    5
+}
+
+impl LogFileConfig {
+    /// Rotate `path` at 10 MiB, keeping five old files
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: default_max_bytes(),
+            retain: default_retain(),
+        }
+    }
+}
+
+/// Logging configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct LoggingConfig {
+    /// Record layout
+    pub format: LogFormat,
+    /// Default level, such as `info`
+    pub level: String,
+    /// Per-module overrides in `EnvFilter` syntax, such as
+    /// `universal_connector_server::lsp=debug`
+    pub directives: Vec<String>,
+    /// Write to this file instead of stderr
+    pub file: Option<LogFileConfig>,
+    /// Include the fields of enclosing spans in each record
+    ///
+    /// Applies to JSON; text formats always show the span scope.
+    pub include_spans: bool,
+    /// File holding the filter to apply on SIGHUP; without one, SIGHUP
+    /// restores the configured filter
+    pub filter_file: Option<PathBuf>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Compact,
+            level: "info".to_string(),
+            directives: Vec::new(),
+            file: None,
+            include_spans: true,
+            filter_file: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Read `LOG_FORMAT`, `LOG_LEVEL`, `LOG_DIRECTIVES` (comma-separated),
+    /// `LOG_FILE`, `LOG_FILE_MAX_BYTES`, `LOG_FILE_RETAIN`, `LOG_SPANS` and
+    /// `LOG_FILTER_FILE`
+    ///
+    /// # Errors
+    ///
+    /// Fails where a variable is set to a value that does not parse as its
+    /// setting.
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        let env = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+
+        let file = env("LOG_FILE").map(|path| {
+            let defaults = LogFileConfig::new(path);
+            LogFileConfig {
+                max_bytes: env("LOG_FILE_MAX_BYTES").and_then(|v| v.parse().ok()).unwrap_or(defaults.max_bytes),
+                retain: env("LOG_FILE_RETAIN").and_then(|v| v.parse().ok()).unwrap_or(defaults.retain),
+                ..defaults
+            }
+        });
+        let config = Self {
+            format: env("LOG_FORMAT").map_or(Ok(defaults.format), |v| LogFormat::parse(&v))?,
+            level: env("LOG_LEVEL").unwrap_or(defaults.level),
+            directives: env("LOG_DIRECTIVES")
+                .map(|v| v.split(',').map(str::trim).filter(|d| !d.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            file,
+            include_spans: env("LOG_SPANS").map_or(defaults.include_spans, |v| v == "true"),
+            filter_file: env("LOG_FILTER_FILE").map(PathBuf::from),
+        };
+        config.filter()?;
+        Ok(config)
+    }
+
+    /// Filter in `EnvFilter` syntax: the level, then the directives
+    pub fn filter_spec(&self) -> String {
+        std::iter::once(self.level.as_str())
+            .chain(self.directives.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub(crate) fn filter(&self) -> Result<EnvFilter> {
+        // A bare word would parse as a target directive
+        self.level
+            .parse::<LevelFilter>()
+            .map_err(|_| anyhow!("Unknown log level: {}", self.level))?;
+        parse_filter(&self.filter_spec())
+    }
+}
+
+fn parse_filter(spec: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(spec).map_err(|e| anyhow!("Invalid log filter {spec:?}: {e}"))
+}
+
+/// Changes the active log filter
+#[derive(Clone)]
+pub struct LogHandle {
+    reload: reload::Handle<EnvFilter, Registry>,
+    configured: Arc<Mutex<String>>,
+    current: Arc<Mutex<String>>,
+    filter_file: Option<PathBuf>,
+}
+
+impl LogHandle {
+    /// Active filter in `EnvFilter` syntax
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the filter's lock.
+    #[must_use]
+    pub fn filter(&self) -> String {
+        self.current.lock().expect("log filter lock poisoned").clone()
+    }
+
+    /// Replace the active filter; an invalid one leaves it unchanged
+    ///
+    /// # Errors
+    ///
+    /// Fails where `spec` is not a valid filter, or the subscriber has gone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the filter's lock.
+    pub fn set_filter(&self, spec: &str) -> Result<()> {
+        let filter = parse_filter(spec)?;
+        let mut current = self.current.lock().expect("log filter lock poisoned");
+        self.reload
+            .reload(filter)
+            .map_err(|e| anyhow!("Cannot replace log filter: {e}"))?;
+        *current = spec.to_string();
+        Ok(())
+    }
+
+    /// Make `spec` the configured filter, applying it unless a filter file overrides it
+    ///
+    /// # Errors
+    ///
+    /// As [`LogHandle::set_filter`] does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the filter's lock.
+    pub fn configure(&self, spec: &str) -> Result<()> {
+        parse_filter(spec)?;
+        *self.configured.lock().expect("log filter lock poisoned") = spec.to_string();
+        if self.filter_file.is_none() {
+            self.set_filter(spec)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the filter in the filter file, or restore the configured one
+    ///
+    /// Returns the filter now active.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the filter file cannot be read, or as
+    /// [`LogHandle::set_filter`] does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the filter's lock.
+    pub fn reload(&self) -> Result<String> {
+        let spec = match &self.filter_file {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Cannot read log filter from {}", path.display()))?
+                .trim()
+                .to_string(),
+            None => self.configured.lock().expect("log filter lock poisoned").clone(),
+        };
+        self.set_filter(&spec)?;
+        Ok(spec)
+    }
+}
+
+impl fmt::Debug for LogHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogHandle").field("filter", &self.filter()).finish_non_exhaustive()
+    }
+}
+
+/// Subscriber without trace export
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+/// Build the subscriber described by `config`, writing to `writer`
+///
+/// [`init_logging`] installs it with the writer the configuration names;
+/// this is for callers that supply their own.
+///
+/// # Errors
+///
+/// Fails where the filter is not valid, or spans are exported and the
+/// exporter cannot be built.
+pub fn subscriber(
+    config: &LoggingConfig,
+    writer: BoxMakeWriter,
+) -> Result<(impl Subscriber + for<'span> LookupSpan<'span> + Send + Sync, LogHandle)> {
+    let (filter, reload) = reload::Layer::new(config.filter()?);
+    let output: Box<dyn Layer<Filtered> + Send + Sync> = match config.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(config.file.is_none())
+            .pretty()
+            .boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(config.file.is_none())
+            .with_target(false)
+            .compact()
+            .boxed(),
+        LogFormat::Json => SpanFieldsLayer
+            .and_then(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .event_format(JsonFormat {
+                        include_spans: config.include_spans,
+                    }),
+            )
+            .boxed(),
+    };
+
+    let handle = LogHandle {
+        reload,
+        configured: Arc::new(Mutex::new(config.filter_spec())),
+        current: Arc::new(Mutex::new(config.filter_spec())),
+        filter_file: config.filter_file.clone(),
+    };
+    Ok((tracing_subscriber::registry().with(filter).with(output), handle))
+}
+
+/// Install the global subscriber, exporting spans as `tracing` configures
+///
+/// Must be called inside the Tokio runtime when spans are exported.
+///
+/// # Errors
+///
+/// As [`subscriber`] does, and where the log file cannot be opened or a
+/// subscriber is already installed.
+pub fn init_logging(config: &LoggingConfig, tracing: &TracingConfig) -> Result<LogHandle> {
+    let writer = match &config.file {
+        Some(file) => BoxMakeWriter::new(RotatingFile::open(file.clone())?),
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    let (subscriber, handle) = subscriber(config, writer)?;
+    subscriber.with(telemetry::layer(tracing)?).try_init()?;
+    Ok(handle)
+}
+
+/// Apply [`LogHandle::reload`] on every SIGHUP
+///
+/// # Errors
+///
+/// Fails where the SIGHUP handler cannot be installed.
+#[cfg(unix)]
+pub fn reload_on_hangup(handle: LogHandle) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match handle.reload() {
+                Ok(filter) => tracing::info!("Log filter reloaded: {}", filter),
+                Err(e) => tracing::warn!("Log filter not reloaded: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Log file that moves aside once it reaches [`LogFileConfig::max_bytes`]
+pub struct RotatingFile {
+    config: LogFileConfig,
+    /// Open file and its size
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    /// Open `config.path` for appending, creating it and its directory if needed
+    ///
+    /// # Errors
+    ///
+    /// Fails where the file or its directory cannot be created or opened.
+    pub fn open(config: LogFileConfig) -> Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Cannot create log directory {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("Cannot open log file {}", config.path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and start a new file
+    fn rotate(&self, file: &mut (File, u64)) -> io::Result<()> {
+        file.0.flush()?;
+        let retain = self.config.retain;
+        remove_if_exists(&self.rotated(retain.max(1)))?;
+        for n in (1..retain).rev() {
+            rename_if_exists(&self.rotated(n), &self.rotated(n + 1))?;
+        }
+        if retain > 0 {
+            fs::rename(&self.config.path, self.rotated(1))?;
+        }
+        *file = (File::create(&self.config.path)?, 0);
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().expect("log file lock poisoned");
+        // Each record arrives in one write, so records are never split
+        if file.1 > 0 && file.1 + buf.len() as u64 > self.config.max_bytes {
+            self.rotate(&mut file)?;
+        }
+        let written = file.0.write(buf)?;
+        file.1 += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().expect("log file lock poisoned").0.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = &'a RotatingFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+/// Fields of a span as JSON values, kept in its extensions
+struct SpanFields(Map<String, Value>);
+
+/// Records span fields for [`JsonFormat`]
+struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut JsonVisitor(&mut fields.0));
+            }
+        }
+    }
+}
+
+/// Collects fields into a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{value:?}")));
+    }
+}
+
+/// One flat JSON object per event
+struct JsonFormat {
+    include_spans: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut record = Map::new();
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if self.include_spans {
+                        record.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    } else if let Some(request_id) = fields.get(REQUEST_ID) {
+                        record.insert(REQUEST_ID.to_string(), request_id.clone());
+                    }
+                }
+                if let Some((trace_id, span_id)) = telemetry::span_ids(&span) {
+                    record.insert("trace_id".to_string(), Value::from(trace_id));
+                    record.insert("span_id".to_string(), Value::from(span_id));
+                }
+                record.insert("span".to_string(), Value::from(span.name()));
+            }
+        }
+
+        event.record(&mut JsonVisitor(&mut record));
+
+        let metadata = event.metadata();
+        record.insert(
+            "timestamp".to_string(),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)),
+        );
+        record.insert("level".to_string(), Value::from(metadata.level().to_string()));
+        record.insert("target".to_string(), Value::from(metadata.target()));
+        record.entry("message").or_insert(Value::from(""));
+
+        writeln!(writer, "{}", Value::Object(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, info_span, warn};
+
+    /// Writer collecting everything logged
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self {
+            self.clone()
+        }
+    }
+
+    fn capture(config: &LoggingConfig, emit: impl FnOnce()) -> String {
+        let captured = Captured::default();
+        let (subscriber, _handle) = subscriber(config, BoxMakeWriter::new(captured.clone())).unwrap();
+        tracing::subscriber::with_default(subscriber, emit);
+        captured.take()
+    }
+
+    fn converted() {
+        let span = info_span!("http.request", request_id = "req-7", http.route = "/api/convert");
+        span.in_scope(|| info!(format = "md", bytes = 42_u64, "Converted document"));
+    }
+
+    fn json(format: LogFormat, include_spans: bool) -> Map<String, Value> {
+        let config = LoggingConfig {
+            format,
+            include_spans,
+            ..LoggingConfig::default()
+        };
+        let output = capture(&config, converted);
+        let mut lines = output.lines();
+        let record = serde_json::from_str::<Value>(lines.next().unwrap()).unwrap();
+        assert_eq!(lines.next(), None);
+        record.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let record = json(LogFormat::Json, true);
+        let mut keys: Vec<&str> = record.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            ["bytes", "format", "http.route", "level", "message", "request_id", "span", "target", "timestamp"]
+        );
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], module_path!());
+        assert_eq!(record["message"], "Converted document");
+        assert_eq!(record["request_id"], "req-7");
+        assert_eq!(record["span"], "http.request");
+        assert_eq!(record["bytes"], 42);
+        assert!(chrono::DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_json_without_span_fields_keeps_request_id() {
+        let record = json(LogFormat::Json, false);
+        assert_eq!(record["request_id"], "req-7");
+        assert_eq!(record["span"], "http.request");
+        assert!(!record.contains_key("http.route"));
+    }
+
+    #[test]
+    fn test_text_formats() {
+        for format in [LogFormat::Compact, LogFormat::Pretty] {
+            let config = LoggingConfig {
+                format,
+                ..LoggingConfig::default()
+            };
+            let output = capture(&config, converted);
+            assert!(output.contains("Converted document"), "{format:?}: {output}");
+            assert!(output.contains("INFO"), "{format:?}: {output}");
+            assert!(output.contains("req-7"), "{format:?}: {output}");
+        }
+    }
+
+    #[test]
+    fn test_filter_reload() {
+        let captured = Captured::default();
+        let config = LoggingConfig {
+            format: LogFormat::Json,
+            directives: vec!["hyper=warn".to_string()],
+            ..LoggingConfig::default()
+        };
+        let (subscriber, handle) = subscriber(&config, BoxMakeWriter::new(captured.clone())).unwrap();
+        assert_eq!(handle.filter(), "info,hyper=warn");
+
+        tracing::subscriber::with_default(subscriber, || {
+            handle.set_filter("warn").unwrap();
+            info!("hidden");
+            warn!("shown");
+            assert_eq!(captured.take().lines().count(), 1);
+
+            // A bad filter is rejected and the old one stays
+            assert!(handle.set_filter("info,hyper=loud").is_err());
+            assert_eq!(handle.filter(), "warn");
+
+            // Without a filter file, reloading restores the configuration
+            assert_eq!(handle.reload().unwrap(), "info,hyper=warn");
+            info!("shown again");
+            assert_eq!(captured.take().lines().count(), 1);
+
+            // A new configured filter applies now and on later reloads
+            handle.configure("error").unwrap();
+            warn!("hidden");
+            assert!(captured.take().is_empty());
+            handle.set_filter("info").unwrap();
+            assert_eq!(handle.reload().unwrap(), "error");
+            assert!(handle.configure("info,hyper=loud").is_err());
+            assert_eq!(handle.filter(), "error");
+        });
+    }
+
+    #[test]
+    fn test_config_rejects_bad_values() {
+        assert!(LogFormat::parse("xml").is_err());
+        assert_eq!(LogFormat::parse("JSON").unwrap(), LogFormat::Json);
+        let config = LoggingConfig {
+            level: "loud".to_string(),
+            ..LoggingConfig::default()
+        };
+        assert!(config.filter().is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("ulc-log-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("server.log");
+        let file = RotatingFile::open(LogFileConfig {
+            path: path.clone(),
+            max_bytes: 100,
+            retain: 2,
+        })
+        .unwrap();
+
+        // One write per record, as the formatter makes
+        for i in 0..10 {
+            (&file).write_all(format!("record {i:02} {}\n", "x".repeat(30)).as_bytes()).unwrap();
+        }
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["server.log", "server.log.1", "server.log.2"]);
+
+        // Records are never split and the newest are in the live file
+        let live = fs::read_to_string(&path).unwrap();
+        assert!(live.ends_with(&format!("record 09 {}\n", "x".repeat(30))));
+        assert!(fs::metadata(&path).unwrap().len() <= 100);
+        assert!(fs::read_to_string(dir.join("server.log.2")).unwrap().starts_with("record 04"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
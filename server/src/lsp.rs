@@ -2,15 +2,52 @@
 //!
 //! Provides Language Server Protocol 3.17 compliant server for editor integration.
 
-use crate::core::{ConversionCore, ConversionRequest, Format};
+use crate::build_info;
+use crate::clients::{ClientRecord, PositionEncoding, RegisteredClient};
+use crate::collab::{self, CollabClient, CollabEvent, TextOperation};
+use crate::core::{ConversionRequest, Format};
+use crate::document_store::Document;
+use crate::formats::FormatRef;
+use crate::jobs;
+use crate::formats::infer::InferOptions;
+use crate::formats::query::QueryLanguage;
+use crate::language::{self, ProviderRegistry, INFER_SCHEMA_COMMAND};
+use crate::monitoring::connections::{ConnectionState, DisconnectReason, Transport};
+use crate::monitoring::slow_ops::{self, OpKind, Operation};
+use crate::monitoring::usage;
+use crate::monitoring::{Metrics, MetricsSnapshot, SlowOps};
+use crate::proxy::{self, Proxy};
 use crate::ServerState;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
 use serde_json::Value;
-use std::sync::Arc;
-use tower_lsp::jsonrpc::Result as LspResult;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tower::Service;
+use tower_lsp::jsonrpc::{ErrorCode, Request, Response, Result as LspResult};
 use tower_lsp::lsp_types::*;
-use tower_lsp::{Client, LanguageServer, LspService, Server};
-use tracing::{error, info};
+use tower_lsp::{Client, ExitedError, LanguageServer, LspService, Server};
+use tracing::{error, info, info_span, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Origin recorded for collaborative operations arriving over LSP, before the session's client ID
+const LSP_ORIGIN: &str = "lsp";
+
+/// Custom request returning the metrics snapshot, e.g. for a status panel
+pub const METRICS_METHOD: &str = "universal/metrics";
+
+/// Command comparing two stored documents, given by URI, as `POST /api/diff` does
+pub const DIFF_COMMAND: &str = "document.diff";
+
+/// Command querying a stored document, given by URI, with `JSONPath`, `JMESPath` or, for XML, `XPath`
+pub const QUERY_COMMAND: &str = "document.query";
+
+/// Buffer size of the in-memory pipes connecting an [`LspHost`] to its server
+const PIPE_CAPACITY: usize = 64 * 1024;
 
 /// Universal Language Connector LSP backend
 pub struct UniversalConnectorBackend {
@@ -18,12 +55,165 @@ pub struct UniversalConnectorBackend {
     client: Client,
     /// Shared server state
     state: Arc<ServerState>,
+    /// Downstream language servers this session's documents may be routed to
+    proxy: Proxy,
+    /// This session's record in the client registry, filled in by `initialize`
+    registered: Arc<RegisteredClient>,
+    /// The documents the editor has open, as it holds them
+    editors: Editors,
+    /// Pushes the operations of other collaborators to the editor while the session lasts
+    collab_events: tokio::task::AbortHandle,
+}
+
+impl Drop for UniversalConnectorBackend {
+    fn drop(&mut self) {
+        self.collab_events.abort();
+    }
 }
 
 impl UniversalConnectorBackend {
-    /// Create a new LSP backend
-    fn new(client: Client, state: Arc<ServerState>) -> Self {
-        Self { client, state }
+    /// Create a new LSP backend for the client registered as `registered`
+    fn new(client: Client, state: Arc<ServerState>, registered: Arc<RegisteredClient>) -> Self {
+        let proxy = Proxy::new(
+            client.clone(),
+            state.config().downstreams.clone(),
+            Arc::clone(&state.documents),
+            Arc::clone(&state.downstream_capabilities),
+        );
+        let editors = Editors {
+            copies: Arc::default(),
+            client: client.clone(),
+            state: Arc::clone(&state),
+            registered: Arc::clone(&registered),
+            origin: format!("{}:{}", LSP_ORIGIN, registered.id()),
+        };
+        // Subscribed before any document joins, so none of its operations is missed
+        let events = state.collab.subscribe();
+        let collab_events = tokio::spawn(editors.clone().forward(events)).abort_handle();
+        Self {
+            client,
+            state,
+            proxy,
+            registered,
+            editors,
+            collab_events,
+        }
+    }
+
+    /// Columns of the positions exchanged with this client, counted as it agreed
+    fn columns<'a>(&self, text: &'a str) -> Columns<'a> {
+        let encoding = self.registered.read(|record| record.position_encoding.unwrap_or_default());
+        Columns { encoding, text }
+    }
+
+    /// UTF-8 positions if the client offers them and every downstream server agreed to them too
+    fn position_encoding(params: &InitializeParams, downstream: &[Value]) -> PositionEncoding {
+        let offered = params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_ref())
+            .is_some_and(|encodings| encodings.contains(&PositionEncodingKind::UTF8));
+        let downstream_agrees = downstream
+            .iter()
+            .all(|capabilities| capabilities["positionEncoding"] == PositionEncoding::Utf8.as_str());
+        if offered && downstream_agrees {
+            PositionEncoding::Utf8
+        } else {
+            PositionEncoding::Utf16
+        }
+    }
+
+    /// Handle [`METRICS_METHOD`]
+    #[allow(clippy::unused_async)] // Custom methods are async
+    async fn metrics(&self) -> LspResult<MetricsSnapshot> {
+        Ok(self.state.metrics.snapshot())
+    }
+
+    /// Conversion commands to the formats the capability registry lists
+    fn conversions(&self) -> Vec<(&'static str, &'static str, Format)> {
+        language::conversions(&self.state.capabilities)
+    }
+
+    /// Snapshot of the stored document at `uri`
+    fn document(&self, uri: &Url) -> Option<Document> {
+        self.state.documents.get(uri.as_str())
+    }
+
+    /// The changes from the document at the first URI of `arguments` to the one at the second
+    fn diff(&self, arguments: &[Value]) -> LspResult<Value> {
+        let [before, after] = [0, 1].map(|index| {
+            let uri = arguments
+                .get(index)
+                .and_then(Value::as_str)
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing URI argument"))?;
+            let document = self
+                .state
+                .documents
+                .get(uri)
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Document not found"))?;
+            // Languages that are no format are read as Markdown, as conversions read them
+            let format = self.state.formats.resolve(&document.language).unwrap_or(FormatRef::BuiltIn(Format::Markdown));
+            Ok((document, format))
+        });
+        let ((before, before_format), (after, after_format)) = (before?, after?);
+        let changes = self
+            .state
+            .formats
+            .diff(&before.content, &before_format, &after.content, &after_format)
+            .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(format!("Diff failed: {e:#}")))?;
+        Ok(serde_json::json!({ "changes": changes }))
+    }
+
+    /// The result of the expression, second of `arguments`, on the document at the first, in the language named third or `JSONPath`
+    fn query(&self, arguments: &[Value]) -> LspResult<Value> {
+        let (Some(uri), Some(expression)) = (arguments.first().and_then(Value::as_str), arguments.get(1).and_then(Value::as_str))
+        else {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params("Expected a URI and an expression"));
+        };
+        let language = match arguments.get(2).and_then(Value::as_str) {
+            Some(name) => QueryLanguage::from_name(name).map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(e.to_string()))?,
+            None => QueryLanguage::default(),
+        };
+        let document = self
+            .state
+            .documents
+            .get(uri)
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Document not found"))?;
+        let format = self.state.formats.resolve(&document.language).unwrap_or(FormatRef::BuiltIn(Format::Markdown));
+        let result = self
+            .state
+            .formats
+            .query(&document.content, &format, language, expression)
+            .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(format!("Query failed: {e:#}")))?;
+        Ok(serde_json::to_value(result).unwrap_or_default())
+    }
+
+    /// A schema for the documents at the URIs of `arguments`, as `POST /api/schema/infer` infers it
+    fn infer_schema(&self, arguments: &[Value]) -> LspResult<Value> {
+        if arguments.is_empty() {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params("Missing URI argument"));
+        }
+        let mut documents = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            let uri = argument
+                .as_str()
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("URI arguments must be strings"))?;
+            let document = self
+                .state
+                .documents
+                .get(uri)
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Document not found"))?;
+            let format = self.state.formats.resolve(&document.language).unwrap_or(FormatRef::BuiltIn(Format::Markdown));
+            documents.push((document, format));
+        }
+        let samples: Vec<_> = documents.iter().map(|(document, format)| (document.content.as_str(), format)).collect();
+        let schema = self
+            .state
+            .formats
+            .infer_schema(&samples, &InferOptions::default())
+            .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(format!("Inference failed: {e:#}")))?;
+        Ok(serde_json::json!({ "schema": schema }))
     }
 
     /// Convert URI to format
@@ -43,11 +233,25 @@ fn uri_to_format(uri: &Url) -> Format {
 
 #[tower_lsp::async_trait]
 impl LanguageServer for UniversalConnectorBackend {
-    async fn initialize(&self, _params: InitializeParams) -> LspResult<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> LspResult<InitializeResult> {
         info!("LSP client initializing...");
+        let downstream = self.proxy.initialize(serde_json::to_value(&params).unwrap_or_default()).await;
+        let encoding = Self::position_encoding(&params, &downstream);
+        self.registered.update(|record| {
+            if let Some(info) = params.client_info {
+                record.name = Some(info.name);
+                record.version = info.version;
+            }
+            record.capabilities = serde_json::to_value(&params.capabilities).unwrap_or_default();
+            record.position_encoding = Some(encoding);
+        });
 
         Ok(InitializeResult {
-            capabilities: ServerCapabilities {
+            capabilities: proxy::merge_capabilities(ServerCapabilities {
+                position_encoding: Some(match encoding {
+                    PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+                    PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+                }),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::INCREMENTAL,
                 )),
@@ -57,12 +261,17 @@ async fn initialize(&self, _params: InitializeParams) -> LspResult<InitializeRes
                 }),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
                 definition_provider: Some(OneOf::Left(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
                 execute_command_provider: Some(ExecuteCommandOptions {
-                    commands: vec![
-                        "convert.toMarkdown".to_string(),
-                        "convert.toHtml".to_string(),
-                        "convert.toJson".to_string(),
-                    ],
+                    commands: self
+                        .conversions()
+                        .into_iter()
+                        .map(|(command, _, _)| command)
+                        .chain([DIFF_COMMAND, QUERY_COMMAND, INFER_SCHEMA_COMMAND])
+                        .map(str::to_string)
+                        .collect(),
                     ..Default::default()
                 }),
                 diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
@@ -73,11 +282,12 @@ async fn initialize(&self, _params: InitializeParams) -> LspResult<InitializeRes
                         ..Default::default()
                     },
                 )),
+                experimental: Some(self.state.capabilities.to_value()),
                 ..Default::default()
-            },
+            }, &downstream),
             server_info: Some(ServerInfo {
                 name: "Universal Language Connector".to_string(),
-                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                version: Some(build_info::VERSION.to_string()),
             }),
         })
     }
@@ -91,6 +301,7 @@ async fn initialized(&self, _params: InitializedParams) {
 
     async fn shutdown(&self) -> LspResult<()> {
         info!("LSP server shutting down");
+        self.proxy.shutdown().await;
         Ok(())
     }
 
@@ -101,119 +312,171 @@ async fn did_open(&self, params: DidOpenTextDocumentParams) {
 
         info!("Document opened: {}", uri);
 
-        self.state
-            .documents
-            .upsert(uri.clone(), content.clone(), language);
+        // A collaborative document keeps its content, which is pushed to the editor instead
+        if !self.editors.open(&uri, content.clone(), params.text_document.version) {
+            self.state.documents.upsert(uri.clone(), content, language.clone());
+        }
+        self.proxy.did_open(&uri, &language).await;
 
         // Send diagnostics
-        self.send_diagnostics(&params.text_document.uri, &content)
-            .await;
+        self.send_diagnostics(&params.text_document.uri);
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
 
-        for change in params.content_changes {
-            let text = change.text;
-            let format = Self::uri_to_format(&params.text_document.uri);
-            self.state.documents.upsert(
-                uri.clone(),
-                text.clone(),
-                format.extension().to_string(),
-            );
-
-            // Send updated diagnostics
-            self.send_diagnostics(&params.text_document.uri, &text)
-                .await;
+        // Documents in concurrent editing mode go through the operation log so
+        // LSP edits merge with WebSocket collaborators instead of overwriting them
+        match self.editors.change(&uri, params.text_document.version, params.content_changes) {
+            Ok(Some(content)) => {
+                // Keep the language the document was opened with, so it stays with its providers
+                let language = self.document(&params.text_document.uri).map_or_else(
+                    || Self::uri_to_format(&params.text_document.uri).extension().to_string(),
+                    |doc| doc.language,
+                );
+                self.state.documents.upsert(uri.clone(), content, language);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to apply edit to {}: {}", uri, e),
         }
+        self.proxy.did_change(&uri).await;
+
+        // Send updated diagnostics
+        self.send_diagnostics(&params.text_document.uri);
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
         info!("Document saved: {}", params.text_document.uri);
+        self.proxy.did_save(params.text_document.uri.as_str()).await;
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
         info!("Document closed: {}", uri);
+        self.editors.close(&uri);
+        self.proxy.did_close(&uri).await;
         // Note: We keep documents in store for potential HTTP/WS access
     }
 
     async fn completion(&self, params: CompletionParams) -> LspResult<Option<CompletionResponse>> {
-        let uri = params.text_document_position.text_document.uri.to_string();
-
-        // Provide format-specific completions
-        let completions = vec![
-            CompletionItem {
-                label: "Convert to HTML".to_string(),
-                kind: Some(CompletionItemKind::TEXT),
-                detail: Some("Convert current document to HTML".to_string()),
-                command: Some(Command {
-                    title: "Convert to HTML".to_string(),
-                    command: "convert.toHtml".to_string(),
-                    arguments: Some(vec![serde_json::json!(uri)]),
-                }),
-                ..Default::default()
-            },
-            CompletionItem {
-                label: "Convert to Markdown".to_string(),
-                kind: Some(CompletionItemKind::TEXT),
-                detail: Some("Convert current document to Markdown".to_string()),
-                command: Some(Command {
-                    title: "Convert to Markdown".to_string(),
-                    command: "convert.toMarkdown".to_string(),
-                    arguments: Some(vec![serde_json::json!(uri)]),
-                }),
-                ..Default::default()
-            },
-            CompletionItem {
-                label: "Convert to JSON".to_string(),
-                kind: Some(CompletionItemKind::TEXT),
-                detail: Some("Convert current document to JSON".to_string()),
-                command: Some(Command {
-                    title: "Convert to JSON".to_string(),
-                    command: "convert.toJson".to_string(),
-                    arguments: Some(vec![serde_json::json!(uri)]),
-                }),
-                ..Default::default()
-            },
-        ];
+        let uri = &params.text_document_position.text_document.uri;
+        if let Some(result) = self.proxy.forward(uri, "textDocument/completion", &params).await {
+            return result;
+        }
+        let position = params.text_document_position;
+        // Documents not opened yet are offered what their extension's format offers
+        let document = self.document(&position.text_document.uri).unwrap_or_else(|| {
+            let format = Self::uri_to_format(&position.text_document.uri);
+            Document::new(position.text_document.uri.to_string(), String::new(), format.extension().to_string())
+        });
+        let columns = self.columns(&document.content);
+        let mut completions =
+            self.state.providers.completion(&document, columns.incoming(position.position)).await;
+        if !self.registered.read(ClientRecord::supports_snippets) {
+            completions.retain(|item| item.insert_text_format != Some(InsertTextFormat::SNIPPET));
+        }
+        for item in &mut completions {
+            match &mut item.text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => edit.range = columns.outgoing_range(edit.range),
+                Some(CompletionTextEdit::InsertAndReplace(edit)) => {
+                    edit.insert = columns.outgoing_range(edit.insert);
+                    edit.replace = columns.outgoing_range(edit.replace);
+                }
+                None => {}
+            }
+            for edit in item.additional_text_edits.iter_mut().flatten() {
+                edit.range = columns.outgoing_range(edit.range);
+            }
+        }
 
         Ok(Some(CompletionResponse::Array(completions)))
     }
 
     async fn hover(&self, params: HoverParams) -> LspResult<Option<Hover>> {
-        let uri = params.text_document_position_params.text_document.uri.to_string();
-
-        if let Some(doc) = self.state.documents.get(&uri) {
-            let stats = doc.stats();
-            let content = format!(
-                "**Document Statistics**\n\n\
-                - Lines: {}\n\
-                - Words: {}\n\
-                - Characters: {}\n\
-                - Version: {}\n\
-                - Format: {}",
-                stats.lines, stats.words, stats.characters, stats.version, doc.language
-            );
-
-            Ok(Some(Hover {
-                contents: HoverContents::Markup(MarkupContent {
-                    kind: MarkupKind::Markdown,
-                    value: content,
+        let uri = &params.text_document_position_params.text_document.uri;
+        if let Some(result) = self.proxy.forward(uri, "textDocument/hover", &params).await {
+            return result;
+        }
+        let position = params.text_document_position_params;
+        let Some(document) = self.document(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let columns = self.columns(&document.content);
+        let hover = self.state.providers.hover(&document, columns.incoming(position.position)).await;
+        Ok(hover.map(|hover| Hover {
+            range: hover.range.map(|range| columns.outgoing_range(range)),
+            ..hover
+        }))
+    }
+
+    async fn document_symbol(&self, params: DocumentSymbolParams) -> LspResult<Option<DocumentSymbolResponse>> {
+        let uri = &params.text_document.uri;
+        if let Some(result) = self.proxy.forward(uri, "textDocument/documentSymbol", &params).await {
+            return result;
+        }
+        let Some(document) = self.document(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let mut symbols = self.state.providers.document_symbols(&document).await;
+        if let Some(symbols) = &mut symbols {
+            self.columns(&document.content).outgoing_symbols(symbols);
+        }
+        Ok(symbols.map(DocumentSymbolResponse::Nested))
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> LspResult<Option<Vec<TextEdit>>> {
+        let uri = &params.text_document.uri;
+        if let Some(result) = self.proxy.forward(uri, "textDocument/formatting", &params).await {
+            return result;
+        }
+        let Some(document) = self.document(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let mut edits = self.state.providers.formatting(&document, &params.options).await;
+        let columns = self.columns(&document.content);
+        for edit in edits.iter_mut().flatten() {
+            edit.range = columns.outgoing_range(edit.range);
+        }
+        Ok(edits)
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> LspResult<Option<CodeActionResponse>> {
+        let uri = &params.text_document.uri;
+        if let Some(result) = self.proxy.forward(uri, "textDocument/codeAction", &params).await {
+            return result;
+        }
+        let Some(document) = self.document(uri) else {
+            return Ok(None);
+        };
+        let range = self.columns(&document.content).incoming_range(params.range);
+        let mut actions = self.state.providers.code_actions(&document, range).await;
+        // Clients asking for some kinds only get actions of those kinds, or kinds within them
+        if let Some(only) = &params.context.only {
+            actions.retain(|action| match action {
+                CodeActionOrCommand::CodeAction(CodeAction { kind: Some(kind), .. }) => only.iter().any(|wanted| {
+                    kind.as_str() == wanted.as_str() || kind.as_str().starts_with(&format!("{}.", wanted.as_str()))
                 }),
-                range: None,
-            }))
-        } else {
-            Ok(None)
+                _ => false,
+            });
         }
+        Ok(Some(actions))
     }
 
     async fn execute_command(&self, params: ExecuteCommandParams) -> LspResult<Option<Value>> {
         info!("Executing command: {}", params.command);
+        if params.command == DIFF_COMMAND {
+            return self.diff(&params.arguments).map(Some);
+        }
+        if params.command == QUERY_COMMAND {
+            return self.query(&params.arguments).map(Some);
+        }
+        if params.command == INFER_SCHEMA_COMMAND {
+            return self.infer_schema(&params.arguments).map(Some);
+        }
 
         let uri = params
             .arguments
-            .get(0)
+            .first()
             .and_then(|v| v.as_str())
             .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing URI argument"))?;
 
@@ -225,13 +488,9 @@ async fn execute_command(&self, params: ExecuteCommandParams) -> LspResult<Optio
 
         let from_format = Format::from_str(&doc.language).unwrap_or(Format::Markdown);
 
-        let to_format = match params.command.as_str() {
-            "convert.toMarkdown" => Format::Markdown,
-            "convert.toHtml" => Format::Html,
-            "convert.toJson" => Format::Json,
-            _ => {
-                return Err(tower_lsp::jsonrpc::Error::method_not_found());
-            }
+        let Some((_, _, to_format)) = self.conversions().into_iter().find(|(command, _, _)| *command == params.command)
+        else {
+            return Err(tower_lsp::jsonrpc::Error::method_not_found());
         };
 
         let request = ConversionRequest {
@@ -240,7 +499,7 @@ async fn execute_command(&self, params: ExecuteCommandParams) -> LspResult<Optio
             to: to_format,
         };
 
-        match ConversionCore::convert(request) {
+        match self.state.formats.convert(request) {
             Ok(response) => {
                 // Show result to user
                 self.client
@@ -266,83 +525,670 @@ async fn execute_command(&self, params: ExecuteCommandParams) -> LspResult<Optio
         }
     }
 
+    async fn range_formatting(&self, params: DocumentRangeFormattingParams) -> LspResult<Option<Vec<TextEdit>>> {
+        let uri = &params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/rangeFormatting", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> LspResult<Option<SignatureHelp>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/signatureHelp", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn goto_definition(&self, params: GotoDefinitionParams) -> LspResult<Option<GotoDefinitionResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/definition", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn goto_type_definition(
+        &self,
+        params: request::GotoTypeDefinitionParams,
+    ) -> LspResult<Option<request::GotoTypeDefinitionResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/typeDefinition", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn goto_implementation(
+        &self,
+        params: request::GotoImplementationParams,
+    ) -> LspResult<Option<request::GotoImplementationResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/implementation", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> LspResult<Option<Vec<Location>>> {
+        let uri = &params.text_document_position.text_document.uri;
+        self.proxy.forward(uri, "textDocument/references", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn document_highlight(&self, params: DocumentHighlightParams) -> LspResult<Option<Vec<DocumentHighlight>>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/documentHighlight", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn prepare_rename(&self, params: TextDocumentPositionParams) -> LspResult<Option<PrepareRenameResponse>> {
+        let uri = &params.text_document.uri;
+        self.proxy.forward(uri, "textDocument/prepareRename", &params).await.unwrap_or(Ok(None))
+    }
+
+    async fn rename(&self, params: RenameParams) -> LspResult<Option<WorkspaceEdit>> {
+        let uri = &params.text_document_position.text_document.uri;
+        self.proxy.forward(uri, "textDocument/rename", &params).await.unwrap_or(Ok(None))
+    }
+
     async fn diagnostic(
         &self,
         params: DocumentDiagnosticParams,
     ) -> LspResult<DocumentDiagnosticReportResult> {
-        let uri = params.text_document.uri.to_string();
+        // A downstream server publishes its own
+        let items = match self.document(&params.text_document.uri) {
+            Some(_) if self.proxy.routes(params.text_document.uri.as_str()) => vec![],
+            Some(document) => self.diagnostics(&document).await,
+            None => vec![],
+        };
 
-        if let Some(doc) = self.state.documents.get(&uri) {
-            let format = Format::from_str(&doc.language).unwrap_or(Format::Markdown);
-            let diagnostics = match ConversionCore::validate(&doc.content, format) {
-                Ok(issues) => issues
-                    .into_iter()
-                    .map(|message| Diagnostic {
-                        range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-                        severity: Some(DiagnosticSeverity::WARNING),
-                        message,
-                        source: Some("universal-connector".to_string()),
-                        ..Default::default()
-                    })
-                    .collect(),
-                Err(_) => vec![],
+        Ok(DocumentDiagnosticReportResult::Report(
+            DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+                related_documents: None,
+                full_document_diagnostic_report: FullDocumentDiagnosticReport {
+                    result_id: None,
+                    items,
+                },
+            }),
+        ))
+    }
+}
+
+impl UniversalConnectorBackend {
+    /// Send diagnostics for a document
+    ///
+    /// Documents routed to a downstream server get that server's instead.
+    /// The diagnostics are computed and published by a [`jobs::DIAGNOSTICS`]
+    /// job, which drops them if the document has changed by then: the job
+    /// queued by the change publishes those.
+    fn send_diagnostics(&self, uri: &Url) {
+        if self.proxy.routes(uri.as_str()) {
+            return;
+        }
+        let Some(document) = self.document(uri) else {
+            return;
+        };
+        let client = self.client.clone();
+        let providers = Arc::clone(&self.state.providers);
+        let documents = Arc::clone(&self.state.documents);
+        let registered = Arc::clone(&self.registered);
+        let target = uri.clone();
+        let span = info_span!("lsp.publish_diagnostics", diagnostics = tracing::field::Empty);
+        let job = move |_| {
+            async move {
+                let current = |document: &Document| {
+                    documents.get(target.as_str()).is_some_and(|latest| latest.version == document.version)
+                };
+                if !current(&document) {
+                    return Ok(());
+                }
+                let encoding = registered.read(|record| record.position_encoding.unwrap_or_default());
+                let diagnostics = diagnostics(&providers, encoding, &document).await;
+                if !current(&document) {
+                    return Ok(());
+                }
+                Span::current().record("diagnostics", diagnostics.len());
+                client.publish_diagnostics(target, diagnostics, None).await;
+                anyhow::Ok(())
+            }
+            .instrument(span)
+        };
+        if let Err(e) = self.state.jobs.submit(jobs::DIAGNOSTICS, job) {
+            warn!("Diagnostics for {} not sent: {}", uri, e);
+        }
+    }
+
+    /// Diagnostics from the providers, in the client's columns
+    async fn diagnostics(&self, document: &Document) -> Vec<Diagnostic> {
+        let encoding = self.registered.read(|record| record.position_encoding.unwrap_or_default());
+        diagnostics(&self.state.providers, encoding, document).await
+    }
+}
+
+/// Diagnostics from `providers`, in columns counted in `encoding`
+async fn diagnostics(providers: &ProviderRegistry, encoding: PositionEncoding, document: &Document) -> Vec<Diagnostic> {
+    let mut diagnostics = providers.diagnostics(document).await;
+    let columns = Columns { encoding, text: &document.content };
+    for diagnostic in &mut diagnostics {
+        diagnostic.range = columns.outgoing_range(diagnostic.range);
+    }
+    diagnostics
+}
+
+/// Converts the columns of positions in `text` between the client's encoding and the UTF-16 providers use
+struct Columns<'a> {
+    encoding: PositionEncoding,
+    text: &'a str,
+}
+
+impl Columns<'_> {
+    /// Line `number` of the text
+    fn line(&self, number: u32) -> &str {
+        self.text.split('\n').nth(number as usize).unwrap_or_default()
+    }
+
+    /// A position from the client, for the providers
+    fn incoming(&self, position: Position) -> Position {
+        if self.encoding == PositionEncoding::Utf16 {
+            return position;
+        }
+        let character = self.encoding.to_utf16(self.line(position.line), position.character);
+        Position::new(position.line, character)
+    }
+
+    fn incoming_range(&self, range: Range) -> Range {
+        Range::new(self.incoming(range.start), self.incoming(range.end))
+    }
+
+    /// A position from the providers, for the client
+    fn outgoing(&self, position: Position) -> Position {
+        if self.encoding == PositionEncoding::Utf16 {
+            return position;
+        }
+        let character = self.encoding.from_utf16(self.line(position.line), position.character);
+        Position::new(position.line, character)
+    }
+
+    fn outgoing_range(&self, range: Range) -> Range {
+        Range::new(self.outgoing(range.start), self.outgoing(range.end))
+    }
+
+    /// Convert `symbols` and their children for the client
+    fn outgoing_symbols(&self, symbols: &mut [DocumentSymbol]) {
+        for symbol in symbols {
+            symbol.range = self.outgoing_range(symbol.range);
+            symbol.selection_range = self.outgoing_range(symbol.selection_range);
+            if let Some(children) = &mut symbol.children {
+                self.outgoing_symbols(children);
+            }
+        }
+    }
+}
+
+/// A document as an editor holds it
+struct EditorCopy {
+    text: String,
+    /// The version the editor gave it last
+    version: i32,
+    /// Its side of concurrent editing, once that is enabled for the document
+    collab: Option<EditorCollab>,
+}
+
+/// An editor's side of a collaborative document
+///
+/// `client.content` is the editor's copy with the operations of others
+/// applied, which the editor is sent as a workspace edit, one at a time.
+struct EditorCollab {
+    client: CollabClient,
+    /// The text the editor holds once it applies the edit sent to it, while one is
+    pushing: Option<String>,
+}
+
+/// The documents an LSP session's editor has open, kept in step with their collaborators
+///
+/// The editor is a collaborator like any WebSocket client: its edits are
+/// sent against the revision its copy is at, and transformed past the
+/// operations it has not seen yet. Those operations are pushed to it with
+/// `workspace/applyEdit`, for the version of the document they were worked
+/// out against; the change the editor makes applying one is not sent again.
+#[derive(Clone)]
+struct Editors {
+    copies: Arc<Mutex<HashMap<String, EditorCopy>>>,
+    client: Client,
+    state: Arc<ServerState>,
+    registered: Arc<RegisteredClient>,
+    /// Origin of this session's collaborative operations
+    origin: String,
+}
+
+impl Editors {
+    fn columns<'a>(&self, text: &'a str) -> Columns<'a> {
+        let encoding = self.registered.read(|record| record.position_encoding.unwrap_or_default());
+        Columns { encoding, text }
+    }
+
+    /// Note a document the editor opened, returning whether it is collaborative
+    fn open(&self, uri: &str, text: String, version: i32) -> bool {
+        let mut copy = EditorCopy { text, version, collab: None };
+        let collaborative = self.state.collab.is_enabled(uri);
+        if collaborative {
+            self.join(uri, &mut copy);
+        }
+        self.copies.lock().expect("editor copies lock poisoned").insert(uri.to_string(), copy);
+        collaborative
+    }
+
+    fn close(&self, uri: &str) {
+        self.copies.lock().expect("editor copies lock poisoned").remove(uri);
+    }
+
+    /// Apply the editor's `changes` to its copy, returning the content to store, unless the document is collaborative
+    fn change(&self, uri: &str, version: i32, changes: Vec<TextDocumentContentChangeEvent>) -> Result<Option<String>> {
+        let mut copies = self.copies.lock().expect("editor copies lock poisoned");
+        let copy = copies.entry(uri.to_string()).or_insert_with(|| EditorCopy {
+            text: self.state.documents.get(uri).map(|document| document.content).unwrap_or_default(),
+            version,
+            collab: None,
+        });
+        copy.version = version;
+        let collaborative = self.state.collab.is_enabled(uri);
+        if !collaborative {
+            copy.collab = None;
+        } else if copy.collab.is_none() {
+            // Concurrent editing began since the last change: the edit is made to the server's content
+            copy.collab = self.start(uri);
+        }
+
+        // The editor's own edit, and the text it was made to
+        let mut base = copy.text.clone();
+        let mut edit = unchanged(&base);
+        for change in changes {
+            let operation = match change.range {
+                Some(range) => {
+                    let range = self.columns(&copy.text).incoming_range(range);
+                    let (start, end) = ((range.start.line, range.start.character), (range.end.line, range.end.character));
+                    TextOperation::from_range_edit(&copy.text, start, end, &change.text)?
+                }
+                None => TextOperation::from_diff(&copy.text, &change.text),
             };
+            copy.text = operation.apply(&copy.text)?;
+            edit = edit.compose(&operation)?;
+            // Applying what was pushed to it, the editor reaches the content it was worked out for
+            if let Some(collab) = &mut copy.collab {
+                if collab.pushing.as_ref() == Some(&copy.text) {
+                    collab.pushing = None;
+                    base.clone_from(&copy.text);
+                    edit = unchanged(&base);
+                }
+            }
+        }
+        if !collaborative {
+            return Ok(Some(copy.text.clone()));
+        }
 
-            Ok(DocumentDiagnosticReportResult::Report(
-                DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
-                    related_documents: None,
-                    full_document_diagnostic_report: FullDocumentDiagnosticReport {
-                        result_id: None,
-                        items: diagnostics,
-                    },
-                }),
-            ))
+        let Some(collab) = &mut copy.collab else {
+            return Ok(None);
+        };
+        if !edit.is_noop() {
+            // Anything pushed is refused, as the editor's version moved on, so it is sent again below
+            collab.pushing = None;
+            let unseen = TextOperation::from_diff(&base, &collab.client.content);
+            let (edit, _) = TextOperation::transform(&edit, &unseen)?;
+            let sent = collab.client.apply_local(edit).and_then(|next| match next {
+                Some((revision, operation)) => self.state.collab.submit(uri, &self.origin, revision, operation).map(|_| ()),
+                None => Ok(()),
+            });
+            if let Err(e) = sent {
+                self.join(uri, copy);
+                return Err(e);
+            }
+        }
+        self.push(uri, copy);
+        Ok(None)
+    }
+
+    /// Start the editor's side of a collaborative document afresh, from the server's content
+    fn join(&self, uri: &str, copy: &mut EditorCopy) {
+        copy.collab = self.start(uri);
+        self.push(uri, copy);
+    }
+
+    /// The editor's side of a collaborative document, starting from the server's content
+    fn start(&self, uri: &str) -> Option<EditorCollab> {
+        let (revision, content) = self.state.collab.snapshot(uri)?;
+        Some(EditorCollab { client: CollabClient::new(revision, content), pushing: None })
+    }
+
+    /// Send the editor what it lacks of the document, unless an edit it has not applied yet is on its way
+    fn push(&self, uri: &str, copy: &mut EditorCopy) {
+        let Some(collab) = &mut copy.collab else {
+            return;
+        };
+        let Ok(url) = Url::parse(uri) else {
+            return;
+        };
+        if collab.pushing.is_some() || collab.client.content == copy.text {
+            return;
+        }
+        let (range, replacement) = collab::changed_range(&copy.text, &collab.client.content);
+        let columns = self.columns(&copy.text);
+        let position = |offset| {
+            let (line, character) = collab::offset_to_position(&copy.text, offset);
+            columns.outgoing(Position::new(line, character))
+        };
+        let edit = TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier { uri: url, version: Some(copy.version) },
+            edits: vec![OneOf::Left(TextEdit::new(Range::new(position(range.start), position(range.end)), replacement))],
+        };
+        let edit = WorkspaceEdit { document_changes: Some(DocumentChanges::Edits(vec![edit])), ..Default::default() };
+        let pushing = collab.client.content.clone();
+        collab.pushing = Some(pushing.clone());
+        let (editors, uri, version) = (self.clone(), uri.to_string(), copy.version);
+        tokio::spawn(async move {
+            match editors.client.apply_edit(edit).await {
+                Ok(response) if response.applied => return,
+                Ok(_) => info!("Editor refused the collaborative edit to {}", uri),
+                Err(e) => warn!("Collaborative edit to {} not sent to the editor: {}", uri, e),
+            }
+            // Unless the editor changed the document since, which sends the edit again, it waits for the next operation
+            let mut copies = editors.copies.lock().expect("editor copies lock poisoned");
+            if let Some(EditorCopy { version: now, collab: Some(collab), .. }) = copies.get_mut(&uri) {
+                if *now == version && collab.pushing.as_ref() == Some(&pushing) {
+                    collab.pushing = None;
+                }
+            }
+        });
+    }
+
+    /// Take an operation accepted by the server: an acknowledgement of the editor's, or one to push to it
+    fn receive(&self, event: CollabEvent) {
+        let mut copies = self.copies.lock().expect("editor copies lock poisoned");
+        let Some(copy) = copies.get_mut(&event.uri) else {
+            return;
+        };
+        let Some(collab) = &mut copy.collab else {
+            self.join(&event.uri, copy);
+            return;
+        };
+        // Operations up to the snapshot it started from are in its content already
+        if event.revision <= collab.client.revision {
+            return;
+        }
+        let result = if event.revision != collab.client.revision + 1 {
+            Err(anyhow!("Revision {} follows {}", event.revision, collab.client.revision))
+        } else if event.origin == self.origin {
+            collab.client.server_ack().and_then(|next| match next {
+                Some((revision, operation)) => self.state.collab.submit(&event.uri, &self.origin, revision, operation).map(|_| ()),
+                None => Ok(()),
+            })
         } else {
-            Ok(DocumentDiagnosticReportResult::Report(
-                DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
-                    related_documents: None,
-                    full_document_diagnostic_report: FullDocumentDiagnosticReport {
-                        result_id: None,
-                        items: vec![],
-                    },
-                }),
-            ))
+            collab.client.apply_server(event.operation)
+        };
+        match result {
+            Ok(()) => self.push(&event.uri, copy),
+            Err(e) => {
+                warn!("Editor copy of {} out of step, starting afresh: {}", event.uri, e);
+                self.join(&event.uri, copy);
+            }
+        }
+    }
+
+    /// Take the operations accepted by the server until the session ends
+    async fn forward(self, mut events: broadcast::Receiver<CollabEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => self.receive(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let mut copies = self.copies.lock().expect("editor copies lock poisoned");
+                    for (uri, copy) in copies.iter_mut().filter(|(_, copy)| copy.collab.is_some()) {
+                        self.join(uri, copy);
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
         }
     }
 }
 
-impl UniversalConnectorBackend {
-    /// Send diagnostics for a document
-    async fn send_diagnostics(&self, uri: &Url, content: &str) {
-        let format = Self::uri_to_format(uri);
-        if let Ok(issues) = ConversionCore::validate(content, format) {
-            let diagnostics: Vec<Diagnostic> = issues
-                .into_iter()
-                .map(|message| Diagnostic {
-                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-                    severity: Some(DiagnosticSeverity::WARNING),
-                    message,
-                    source: Some("universal-connector".to_string()),
-                    ..Default::default()
-                })
-                .collect();
+/// The operation leaving `text` as it is
+fn unchanged(text: &str) -> TextOperation {
+    let mut operation = TextOperation::new();
+    operation.retain(text.chars().count());
+    operation
+}
 
-            self.client
-                .publish_diagnostics(uri.clone(), diagnostics, None)
-                .await;
+/// Trace contexts of the messages an [`LspHost`] passed to its server, in order
+///
+/// The JSON-RPC stream in between has no room for trace context, so each
+/// dispatch span picks up the context its message was sent in from here.
+#[derive(Clone, Default)]
+struct TraceHandoff(Arc<Mutex<VecDeque<(String, opentelemetry::Context)>>>);
+
+impl TraceHandoff {
+    /// Context for the next dispatch of `method`
+    ///
+    /// Entries ahead of it belong to messages the server rejected without
+    /// dispatching and are dropped.
+    fn take(&self, method: &str) -> Option<opentelemetry::Context> {
+        let mut pending = self.0.lock().expect("trace handoff lock poisoned");
+        while let Some((queued, context)) = pending.pop_front() {
+            if queued == method {
+                return Some(context);
+            }
+        }
+        None
+    }
+}
+
+/// LSP service wrapper recording request latency by method, client activity, and tracing each dispatch
+struct Timed<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+    slow_ops: Arc<SlowOps>,
+    handoff: Option<TraceHandoff>,
+    registered: Arc<RegisteredClient>,
+}
+
+impl<S> Service<Request> for Timed<S>
+where
+    S: Service<Request, Response = Option<Response>, Error = ExitedError>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Response>;
+    type Error = ExitedError;
+    type Future = BoxFuture<'static, Result<Option<Response>, ExitedError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ExitedError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        self.registered.touch();
+        // Notifications have no response to wait for
+        let method = request.id().is_some().then(|| request.method().to_string());
+        let request_id = request.id().map(ToString::to_string);
+        let metrics = Arc::clone(&self.metrics);
+        let slow = Arc::clone(&self.slow_ops);
+        let span = info_span!("lsp.dispatch", rpc.method = request.method());
+        if let Some(context) = self.handoff.as_ref().and_then(|handoff| handoff.take(request.method())) {
+            span.set_parent(context);
+        }
+        let start = Instant::now();
+        let response = span.in_scope(|| self.inner.call(request));
+
+        let timed = async move {
+            let response = response.await;
+            if let Some(method) = method {
+                // Unknown methods share one label so clients cannot add series
+                let unknown = matches!(
+                    &response,
+                    Ok(Some(response)) if response.error().is_some_and(|e| e.code == ErrorCode::MethodNotFound)
+                );
+                let method = if unknown { "unknown" } else { method.as_str() };
+                let elapsed = start.elapsed();
+                metrics
+                    .lsp_request_duration
+                    .with_labels(&[method])
+                    .observe_duration(elapsed);
+                slow.record(Operation::new(OpKind::Lsp, method), elapsed);
+            }
+            response
+        }
+        .instrument(span);
+        // Slow operations within the request carry its JSON-RPC ID
+        match request_id {
+            Some(id) => Box::pin(slow_ops::with_request_id(id, timed)),
+            None => Box::pin(timed),
         }
     }
 }
 
 /// Run the LSP server on stdio
 pub async fn run_lsp_server(state: Arc<ServerState>) -> Result<()> {
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
+    serve_lsp(state, tokio::io::stdin(), tokio::io::stdout(), Transport::LspStdio).await
+}
+
+/// Run the LSP server on any Content-Length framed byte stream, counted as a `transport` connection
+///
+/// # Errors
+///
+/// Fails where `input` or `output` fail.
+pub async fn serve_lsp<I, O>(state: Arc<ServerState>, input: I, output: O, transport: Transport) -> Result<()>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite,
+{
+    serve(state, input, output, transport, None).await
+}
+
+/// Serve, continuing the traces queued in `handoff` for each dispatch
+async fn serve<I, O>(
+    state: Arc<ServerState>,
+    input: I,
+    output: O,
+    transport: Transport,
+    handoff: Option<TraceHandoff>,
+) -> Result<()>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite,
+{
+    let mut connection = state.metrics.connections.open(transport, ConnectionState::Active);
+    let metrics = Arc::clone(&state.metrics);
+    let slow_ops = Arc::clone(&state.slow_ops);
+    // Stdio, TCP and hosted sessions carry no credentials of their own
+    let registered = Arc::new(state.clients.register(transport, &usage::Client::anonymous()));
+    let backend_registered = Arc::clone(&registered);
+    let (service, socket) =
+        LspService::build(|client| UniversalConnectorBackend::new(client, state, backend_registered))
+            .custom_method(METRICS_METHOD, UniversalConnectorBackend::metrics)
+            .finish();
+
+    Server::new(input, output, socket)
+        .serve(Timed {
+            inner: service,
+            metrics,
+            slow_ops,
+            handoff,
+            registered,
+        })
+        .await;
 
-    let (service, socket) = LspService::build(|client| UniversalConnectorBackend::new(client, state))
-        .finish();
+    connection.set_reason(DisconnectReason::Closed);
+    Ok(())
+}
+
+/// Read one Content-Length framed JSON-RPC message, or `None` at end of stream
+///
+/// # Errors
+///
+/// Fails where `reader` does, or a message has no valid Content-Length or
+/// is not JSON.
+pub async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
 
-    Server::new(stdin, stdout, socket).serve(service).await;
+    let length = content_length.ok_or_else(|| anyhow!("Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
 
+/// Write one JSON-RPC message with a Content-Length header
+///
+/// # Errors
+///
+/// Fails where `writer` does.
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Value) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+    writer
+        .write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+        .await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
     Ok(())
 }
+
+/// Language server instance exchanging JSON-RPC messages over channels instead of stdio
+///
+/// Lets the WebSocket server host an LSP session per client. The server
+/// shuts down when the host is dropped.
+pub struct LspHost {
+    input: mpsc::UnboundedSender<Value>,
+    handoff: TraceHandoff,
+}
+
+impl LspHost {
+    /// Start a server whose outgoing messages are sent to `output`
+    pub fn spawn(state: Arc<ServerState>, output: mpsc::UnboundedSender<Value>) -> Self {
+        let (input, mut input_rx) = mpsc::unbounded_channel::<Value>();
+        let (mut server_input, server_stdin) = tokio::io::duplex(PIPE_CAPACITY);
+        let (server_stdout, server_output) = tokio::io::duplex(PIPE_CAPACITY);
+
+        tokio::spawn(async move {
+            while let Some(message) = input_rx.recv().await {
+                if write_message(&mut server_input, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(server_output);
+            loop {
+                match read_message(&mut reader).await {
+                    Ok(Some(message)) => {
+                        if output.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Invalid message from hosted language server: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let handoff = TraceHandoff::default();
+        tokio::spawn(serve(state, server_stdin, server_stdout, Transport::LspPipe, Some(handoff.clone())));
+
+        Self { input, handoff }
+    }
+
+    /// Pass a message from the client to the server
+    ///
+    /// The server's handling of it continues the current span's trace.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the trace handoff's lock.
+    pub fn send(&self, message: Value) {
+        // Queue and send under one lock so contexts stay in message order
+        let mut pending = self.handoff.0.lock().expect("trace handoff lock poisoned");
+        if let Some(method) = message.get("method").and_then(Value::as_str) {
+            pending.push_back((method.to_string(), Span::current().context()));
+        }
+        let _ = self.input.send(message);
+    }
+}
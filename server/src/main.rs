@@ -8,6 +8,7 @@
 //! - LSP Server (stdio) - Main editor integration via Language Server Protocol
 //! - HTTP API (port 8080) - REST endpoints for web integration
 //! - WebSocket (port 8081) - Real-time document updates
+//! - Bridge mode (`BRIDGE_URL` set) - stdio front-end for a remote connector
 //!
 //! # Performance Targets
 //!
@@ -18,100 +19,380 @@
 #![deny(clippy::all)]
 #![warn(clippy::pedantic)]
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::task::JoinSet;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
-use universal_connector_server::{http, lsp, websocket, ServerConfig, ServerState};
+use universal_connector_server::auth::{AuthConfig, AuthService};
+use universal_connector_server::bridge::{self, BridgeConfig};
+use universal_connector_server::config::{self, Flag, LoadedConfig};
+use universal_connector_server::monitoring::slow_ops::OpKind;
+use universal_connector_server::monitoring::statsd::StatsdConfig;
+use universal_connector_server::monitoring::{alerts, rules, MetricsConfig};
+use universal_connector_server::oneshot::{self, ConvertArgs, ValidateArgs};
+use universal_connector_server::{
+    logging, telemetry, LoggingConfig, Server, ServerConfig, ServerState, TracingConfig, TrustedProxies,
+};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing/logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .compact()
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+#[cfg(feature = "counting-allocator")]
+#[global_allocator]
+static ALLOCATOR: universal_connector_server::monitoring::alloc::CountingAllocator =
+    universal_connector_server::monitoring::alloc::CountingAllocator;
 
-    info!("🚀 Universal Language Connector Server starting...");
+/// Command line; settings given as flags override the configuration file and environment
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "Universal Language Connector server")]
+#[allow(clippy::struct_excessive_bools)] // One per switch
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file, instead of the one `CONFIG_FILE` names
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// HTTP API bind address
+    #[arg(long, global = true, value_name = "ADDR")]
+    http_addr: Option<String>,
+    /// WebSocket bind address
+    #[arg(long, global = true, value_name = "ADDR")]
+    ws_addr: Option<String>,
+    /// Do not serve LSP over stdio
+    #[arg(long, global = true)]
+    no_lsp: bool,
+    /// Do not serve the HTTP API
+    #[arg(long, global = true)]
+    no_http: bool,
+    /// Do not serve WebSocket clients
+    #[arg(long, global = true)]
+    no_websocket: bool,
+    /// Default log level
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<String>,
+    /// Refuse a configuration with warnings, as well as one with errors
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Reload the configuration when its file changes, as well as on SIGHUP
+    #[arg(long, global = true)]
+    watch_config: bool,
+}
 
-    // Parse configuration from environment
-    let config = ServerConfig {
-        http_addr: std::env::var("HTTP_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string()),
-        ws_addr: std::env::var("WS_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".to_string()),
-        enable_lsp: std::env::var("ENABLE_LSP").unwrap_or_else(|_| "true".to_string()) == "true",
-        enable_http: std::env::var("ENABLE_HTTP").unwrap_or_else(|_| "true".to_string()) == "true",
-        enable_websocket: std::env::var("ENABLE_WS").unwrap_or_else(|_| "true".to_string()) == "true",
-        jwt_secret: std::env::var("JWT_SECRET").unwrap_or_else(|_| "dev-secret-change-in-production".to_string()),
-        enable_auth: std::env::var("ENABLE_AUTH").unwrap_or_else(|_| "false".to_string()) == "true",
-    };
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Check the configuration and print it as merged, with secrets redacted
+    CheckConfig,
+    /// Print a commented reference configuration file
+    GenerateConfig,
+    /// Print a token signed with the configured secret or private key, for bootstrapping access
+    GenerateToken {
+        /// Subject the token is issued to
+        #[arg(long, default_value = "admin")]
+        subject: String,
+        /// Scope granted; repeat for several
+        #[arg(long = "scope", value_name = "SCOPE", default_value = "*")]
+        scopes: Vec<String>,
+    },
+    /// Convert files between formats, without starting a server
+    Convert(ConvertArgs),
+    /// Validate files, without starting a server; exits with 1 on findings and 2 on errors
+    Validate(ValidateArgs),
+}
 
-    info!("📋 Configuration: {:?}", config);
+impl Cli {
+    /// The settings given as flags
+    fn flags(&self) -> Vec<Flag> {
+        let mut flags = Vec::new();
+        let mut flag = |name: &str, setting: &str, value: &str| {
+            flags.push(Flag { name: name.to_string(), setting: setting.to_string(), value: value.to_string() });
+        };
+        if let Some(addr) = &self.http_addr {
+            flag("--http-addr", "http_addr", addr);
+        }
+        if let Some(addr) = &self.ws_addr {
+            flag("--ws-addr", "ws_addr", addr);
+        }
+        for (off, name, setting) in [
+            (self.no_lsp, "--no-lsp", "enable_lsp"),
+            (self.no_http, "--no-http", "enable_http"),
+            (self.no_websocket, "--no-websocket", "enable_websocket"),
+        ] {
+            if off {
+                flag(name, setting, "false");
+            }
+        }
+        if let Some(level) = &self.log_level {
+            flag("--log-level", "logging.level", level);
+        }
+        flags
+    }
 
-    let state = Arc::new(ServerState::new(config.clone()));
+    /// Load the configuration with the flags on top, returning whether it was layered
+    ///
+    /// A configuration file or any `ULC_` variable layers the settings;
+    /// otherwise the unprefixed variables are read as before.
+    fn load_config(&self) -> Result<(LoadedConfig, bool)> {
+        let layered = self.config.is_some()
+            || std::env::var_os("CONFIG_FILE").is_some()
+            || std::env::vars_os().any(|(name, _)| name.to_string_lossy().starts_with(config::ENV_PREFIX));
+        let loaded = if layered {
+            ServerConfig::load(self.config.as_deref(), &self.flags())?
+        } else {
+            config_from_env()?.with_flags(&self.flags())?
+        };
+        if self.strict && !loaded.warnings.is_empty() {
+            let warnings: Vec<_> = loaded.warnings.iter().map(ToString::to_string).collect();
+            bail!("{}\n(warnings are refused with --strict)", warnings.join("\n"));
+        }
+        Ok((loaded, layered))
+    }
+}
 
-    // Spawn all server components concurrently
-    let mut tasks = JoinSet::new();
+/// Reload the configuration on every SIGHUP and, with `--watch-config`, whenever its file changes
+fn reload_on_change(cli: &Cli, state: &Arc<ServerState>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    // LSP server (stdio) - primary editor integration
-    if config.enable_lsp {
-        info!("📝 Starting LSP server (stdio)...");
-        let lsp_state = Arc::clone(&state);
-        tasks.spawn(async move {
-            lsp::run_lsp_server(lsp_state).await
+        let mut hangups = signal(SignalKind::hangup())?;
+        let (cli, state) = (cli.clone(), Arc::clone(state));
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                reload_config(&cli, &state);
+            }
         });
     }
+    if cli.watch_config {
+        if let Some(path) = cli.config.clone().or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from)) {
+            let interval = Duration::from_secs(env_number("CONFIG_WATCH_INTERVAL_SECS", 2));
+            tokio::spawn(watch_config(cli.clone(), Arc::clone(state), path, interval));
+        } else { warn!("--watch-config needs a configuration file; only SIGHUP reloads") }
+    }
+    Ok(())
+}
 
-    // HTTP REST API server
-    if config.enable_http {
-        info!("🌐 Starting HTTP API server on {}...", config.http_addr);
-        let http_state = Arc::clone(&state);
-        let http_addr = config.http_addr.clone();
-        tasks.spawn(async move {
-            http::run_http_server(http_state, &http_addr).await
-        });
+/// Reload the configuration whenever the modification time or size of `path` changes
+async fn watch_config(cli: Cli, state: Arc<ServerState>, path: PathBuf, interval: Duration) {
+    let stamp = |path: &Path| std::fs::metadata(path).ok().map(|meta| (meta.modified().ok(), meta.len()));
+    let mut last = stamp(&path);
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let current = stamp(&path);
+        if current != last {
+            last = current;
+            info!("Configuration file {} changed", path.display());
+            reload_config(&cli, &state);
+        }
     }
+}
 
-    // WebSocket server for real-time updates
-    if config.enable_websocket {
-        info!("🔌 Starting WebSocket server on {}...", config.ws_addr);
-        let ws_state = Arc::clone(&state);
-        let ws_addr = config.ws_addr.clone();
-        tasks.spawn(async move {
-            websocket::run_websocket_server(ws_state, &ws_addr).await
-        });
+/// Load the configuration again and apply what can change while running
+///
+/// A configuration that fails to load or validate is logged and the running one kept.
+fn reload_config(cli: &Cli, state: &ServerState) {
+    match cli.load_config() {
+        Ok((loaded, _)) => {
+            for key in &loaded.unknown_keys {
+                warn!("Unknown configuration key {}", key);
+            }
+            for warning in &loaded.warnings {
+                warn!("Configuration warning: {}", warning);
+            }
+            state.reload(loaded.config);
+        }
+        Err(e) => {
+            state.metrics.config_reloads.with_labels(&["failed"]).inc();
+            warn!("Configuration not reloaded, keeping the running one: {:#}", e);
+        }
     }
+}
 
-    info!("✅ All servers started successfully");
-    info!("📡 Ready to accept connections");
+/// Read a numeric setting from the environment, falling back to `default`
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
 
-    // Wait for any task to complete (error case) or Ctrl+C
-    tokio::select! {
-        result = tasks.join_next() => {
-            match result {
-                Some(Ok(Ok(()))) => {
-                    info!("Server task completed normally");
-                }
-                Some(Ok(Err(e))) => {
-                    eprintln!("❌ Server error: {}", e);
-                    std::process::exit(1);
-                }
-                Some(Err(e)) => {
-                    eprintln!("❌ Task join error: {}", e);
-                    std::process::exit(1);
-                }
-                None => {
-                    info!("All tasks completed");
-                }
-            }
+/// Read the configuration from unprefixed environment variables, without `CONFIG_FILE` or `ULC_` ones
+fn config_from_env() -> Result<ServerConfig> {
+    let flag = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string()) == "true";
+    let mut config = ServerConfig::default();
+    config.http_addr = std::env::var("HTTP_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+    config.ws_addr = std::env::var("WS_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".to_string());
+    config.enable_lsp = flag("ENABLE_LSP", "true");
+    config.enable_http = flag("ENABLE_HTTP", "true");
+    config.enable_websocket = flag("ENABLE_WS", "true");
+    config.grpc_addr = std::env::var("GRPC_ADDR").unwrap_or_else(|_| "0.0.0.0:50051".to_string());
+    config.enable_grpc = flag("ENABLE_GRPC", "false");
+    config.jwt_secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "dev-secret-change-in-production".to_string());
+    config.enable_auth = flag("ENABLE_AUTH", "false");
+    config.anonymous_read_only = flag("ANONYMOUS_READ_ONLY", "false");
+    config.rate_limit.enabled = flag("ENABLE_RATE_LIMIT", "false");
+
+    let limits = &mut config.ws_connection_limits;
+    limits.max_total = env_number("WS_MAX_CONNECTIONS", limits.max_total);
+    limits.max_per_subject = env_number("WS_MAX_CONNECTIONS_PER_SUBJECT", limits.max_per_subject);
+    limits.max_per_ip = env_number("WS_MAX_CONNECTIONS_PER_IP", limits.max_per_ip);
+    limits.displace_idle = flag("WS_DISPLACE_IDLE", "false");
+    config.trusted_proxies = TrustedProxies::parse(&std::env::var("TRUSTED_PROXIES").unwrap_or_default())?;
+
+    let format_limits = &mut config.format_limits;
+    format_limits.max_input_bytes = env_number("MAX_DOCUMENT_BYTES", format_limits.max_input_bytes);
+    format_limits.max_output_bytes = env_number("MAX_CONVERTED_BYTES", format_limits.max_output_bytes);
+    config.data_dir = std::env::var("DATA_DIR").ok().map(PathBuf::from);
+
+    let thresholds = &mut config.lifecycle_thresholds;
+    thresholds.degrade_after = env_number("HEALTH_DEGRADE_AFTER", thresholds.degrade_after);
+    thresholds.recover_after = env_number("HEALTH_RECOVER_AFTER", thresholds.recover_after);
+    thresholds.unready_after = env_number("HEALTH_UNREADY_AFTER", thresholds.unready_after);
+    thresholds.restart_after = env_number("HEALTH_RESTART_AFTER", thresholds.restart_after);
+    config.lifecycle_webhook = std::env::var("LIFECYCLE_WEBHOOK_URL").ok();
+
+    if let Ok(path) = std::env::var("ALERT_RULES_FILE") {
+        config.alert_rules = rules::load(path.as_ref())?;
+    }
+    config.alert_webhook = std::env::var("ALERT_WEBHOOK_URL").ok();
+    if let Ok(path) = std::env::var("ALERT_SINKS_FILE") {
+        config.alerts = alerts::load(path.as_ref())?;
+    }
+
+    let slow_ops = &mut config.slow_ops;
+    for (kind, name) in [
+        (OpKind::Http, "SLOW_HTTP_MS"),
+        (OpKind::Lsp, "SLOW_LSP_MS"),
+        (OpKind::Conversion, "SLOW_CONVERSION_MS"),
+        (OpKind::Validation, "SLOW_VALIDATION_MS"),
+        (OpKind::Store, "SLOW_STORE_MS"),
+    ] {
+        let default = u64::try_from(slow_ops.thresholds.get(kind).as_millis()).unwrap_or(u64::MAX);
+        slow_ops.thresholds.set(kind, Duration::from_millis(env_number(name, default)));
+    }
+    slow_ops.capacity = env_number("SLOW_OPS_CAPACITY", slow_ops.capacity);
+    slow_ops.max_logs_per_minute = env_number("SLOW_OPS_LOGS_PER_MINUTE", slow_ops.max_logs_per_minute);
+
+    config.usage.per_subject = flag("USAGE_PER_SUBJECT", "true");
+    config.usage.capacity = env_number("USAGE_CAPACITY", config.usage.capacity);
+    config.usage.rollup_file = std::env::var("USAGE_ROLLUP_FILE").ok().map(PathBuf::from);
+
+    if let Ok(path) = std::env::var("METRICS_CONFIG_FILE") {
+        config.metrics = MetricsConfig::load(path.as_ref())?;
+    }
+    config.statsd = StatsdConfig::from_env()?;
+    config.logging = LoggingConfig::from_env()?;
+    config.tracing = TracingConfig::from_env();
+    Ok(config)
+}
+
+/// Print the merged configuration, failing on anything the server would warn of or refuse
+fn check_config(cli: &Cli) -> Result<()> {
+    let (loaded, _) = cli.load_config()?;
+    print!("{}", toml::to_string(&loaded.config.redacted())?);
+    for warning in &loaded.warnings {
+        eprintln!("warning: {warning}");
+    }
+    for key in &loaded.unknown_keys {
+        eprintln!("Unknown configuration key {key}");
+    }
+    for variable in &loaded.unknown_variables {
+        eprintln!("{variable} does not name a configuration setting");
+    }
+    let unknown = loaded.unknown_keys.len() + loaded.unknown_variables.len();
+    if unknown > 0 {
+        bail!("{unknown} unknown configuration settings");
+    }
+    Ok(())
+}
+
+/// Print a token for `subject` signed with the configured secret or private key, to stdout only
+fn generate_token(cli: &Cli, subject: &str, scopes: &[String]) -> Result<()> {
+    let (loaded, _) = cli.load_config()?;
+    let auth = AuthService::new(AuthConfig { enabled: true, ..AuthConfig::from_server_config(&loaded.config) });
+    if !auth.has_signing_key() {
+        bail!("No jwt_secret or jwt_private_key_file is configured to sign the token with");
+    }
+    let token = auth.generate_token(subject.to_string(), scopes.to_vec())?;
+    println!("{}", token.strip_prefix("Bearer ").unwrap_or(&token));
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    match &cli.command {
+        None | Some(Command::Serve) => serve(&cli).await.map(|()| ExitCode::SUCCESS),
+        Some(Command::CheckConfig) => check_config(&cli).map(|()| ExitCode::SUCCESS),
+        Some(Command::GenerateConfig) => {
+            print!("{}", ServerConfig::example_toml());
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::GenerateToken { subject, scopes }) => {
+            generate_token(&cli, subject, scopes).map(|()| ExitCode::SUCCESS)
         }
+        Some(Command::Convert(args)) => Ok(oneshot::convert(args).into()),
+        Some(Command::Validate(args)) => Ok(oneshot::validate(args).into()),
+    }
+}
+
+async fn serve(cli: &Cli) -> Result<()> {
+    let (loaded, layered) = cli.load_config()?;
+
+    // Initialize tracing/logging (stderr, as stdout carries LSP traffic),
+    // exporting spans when an OTLP endpoint is configured
+    let log_handle = logging::init_logging(&loaded.config.logging, &loaded.config.tracing)?;
+    #[cfg(unix)]
+    logging::reload_on_hangup(log_handle.clone())?;
+
+    // Bridge mode: forward stdio LSP traffic to a remote connector instead of serving
+    if let Some(bridge_config) = BridgeConfig::from_env() {
+        info!("🌉 Bridging stdio to {}", bridge_config.url);
+        let result = bridge::run_bridge(bridge_config).await;
+        telemetry::shutdown().await;
+        return result;
+    }
+
+    info!("🚀 Universal Language Connector Server starting...");
+
+    for key in &loaded.unknown_keys {
+        warn!("Unknown configuration key {}", key);
+    }
+    for variable in &loaded.unknown_variables {
+        warn!("{} does not name a configuration setting", variable);
+    }
+    for warning in &loaded.warnings {
+        warn!("Configuration warning: {}", warning);
+    }
+    // Without layering, the unprefixed variables are reported as defaults
+    if layered {
+        for (setting, source) in &loaded.sources {
+            debug!("Configuration {} from {}", setting, source);
+        }
+    }
+    let config = loaded.config;
+
+    info!("📋 Configuration: {:?}", config.redacted());
+
+    let mut state = ServerState::new(config);
+    state.logging = Some(log_handle);
+    let state = Arc::new(state);
+    reload_on_change(cli, &state)?;
+
+    let mut server = Server::run(state).await?;
+    info!("📡 Ready to accept connections");
+
+    let result = tokio::select! {
+        result = server.wait() => result,
         _ = tokio::signal::ctrl_c() => {
             info!("🛑 Received Ctrl+C, shutting down...");
+            server.shutdown();
+            server.wait().await
         }
+    };
+    // The trace exporter is among what the state flushed on the way out
+    if let Some(report) = server.report() {
+        report.log();
     }
-
-    Ok(())
+    result
 }
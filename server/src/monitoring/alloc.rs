@@ -0,0 +1,61 @@
+//! Heap accounting allocator
+//!
+//! Built with the `counting-allocator` feature. The server binary installs
+//! [`CountingAllocator`] as its global allocator; its counts are exported as
+//! `ulc_allocator_*` gauges.
+
+use super::process::AllocStats;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator that counts live bytes and allocations
+pub struct CountingAllocator;
+
+// SAFETY: every call is passed straight to the system allocator
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+/// Bytes and allocations currently live
+///
+/// Both read zero unless [`CountingAllocator`] is the global allocator.
+pub fn stats() -> AllocStats {
+    AllocStats {
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+    }
+}
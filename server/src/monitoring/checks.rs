@@ -0,0 +1,312 @@
+//! Built-in health checks
+//!
+//! Checks for the components every deployment has, registered by
+//! [`ServerState::new`](crate::ServerState::new). The directory checks are
+//! only registered when a data directory is configured.
+
+use super::health::{CheckResult, HealthCheck};
+use super::process;
+use super::Metrics;
+use crate::auth::AuthService;
+use crate::document_store::DocumentStore;
+use futures_util::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Times a write and read of a sentinel document
+pub struct DocumentStoreCheck {
+    store: Arc<DocumentStore>,
+    /// A probe slower than this reports degraded
+    pub degraded_after: Duration,
+}
+
+impl DocumentStoreCheck {
+    pub fn new(store: Arc<DocumentStore>) -> Self {
+        Self {
+            store,
+            degraded_after: Duration::from_millis(50),
+        }
+    }
+}
+
+impl HealthCheck for DocumentStoreCheck {
+    fn name(&self) -> &'static str {
+        "document_store"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let start = Instant::now();
+            if !self.store.probe() {
+                return CheckResult::unhealthy("Sentinel document was not read back");
+            }
+            let elapsed = start.elapsed();
+            if elapsed > self.degraded_after {
+                CheckResult::degraded(format!("Probe took {elapsed:?}"))
+            } else {
+                CheckResult::healthy_with(format!("{} documents", self.store.count()))
+            }
+        })
+    }
+}
+
+/// Writes and removes a file in the persistence directory
+pub struct WritableCheck {
+    dir: PathBuf,
+}
+
+impl WritableCheck {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl HealthCheck for WritableCheck {
+    fn name(&self) -> &'static str {
+        "persistence"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let path = self.dir.join(".health-probe");
+            let written = tokio::fs::write(&path, b"ok").await;
+            let _ = tokio::fs::remove_file(&path).await;
+            match written {
+                Ok(()) => CheckResult::healthy(),
+                Err(e) => CheckResult::unhealthy(format!("Cannot write to {}: {}", self.dir.display(), e)),
+            }
+        })
+    }
+}
+
+/// Free space on the filesystem holding the persistence directory
+pub struct DiskSpaceCheck {
+    dir: PathBuf,
+    /// Less free space than this reports unhealthy, and less than twice
+    /// this reports degraded
+    pub min_free_bytes: u64,
+}
+
+impl DiskSpaceCheck {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            min_free_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl HealthCheck for DiskSpaceCheck {
+    fn name(&self) -> &'static str {
+        "disk_space"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let dir = self.dir.clone();
+            let available = match tokio::task::spawn_blocking(move || fs2::available_space(&dir)).await {
+                Ok(Ok(available)) => available,
+                Ok(Err(e)) => return CheckResult::unhealthy(format!("Cannot stat {}: {}", self.dir.display(), e)),
+                Err(e) => return CheckResult::unhealthy(format!("Disk check failed: {e}")),
+            };
+            let message = format!("{} MiB free", available / (1024 * 1024));
+            if available < self.min_free_bytes {
+                CheckResult::unhealthy(message)
+            } else if available < self.min_free_bytes.saturating_mul(2) {
+                CheckResult::degraded(message)
+            } else {
+                CheckResult::healthy_with(message)
+            }
+        })
+    }
+}
+
+/// Whether the auth service can sign and verify tokens
+pub struct AuthCheck {
+    service: Option<Arc<AuthService>>,
+}
+
+impl AuthCheck {
+    /// Check `service`, or report authentication as disabled when `None`
+    #[must_use]
+    pub fn new(service: Option<Arc<AuthService>>) -> Self {
+        Self { service }
+    }
+}
+
+impl HealthCheck for AuthCheck {
+    fn name(&self) -> &'static str {
+        "auth"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            match &self.service {
+                None => CheckResult::healthy_with("Authentication disabled"),
+                Some(service) if !service.has_verifying_key() => CheckResult::unhealthy("No token verification key loaded"),
+                Some(service) if service.oidc().is_some_and(|provider| !provider.is_loaded()) => {
+                    CheckResult::degraded("Identity provider keys not fetched yet")
+                }
+                Some(_) => CheckResult::healthy(),
+            }
+        })
+    }
+}
+
+/// Scheduling delay of the async runtime
+///
+/// Sleeps briefly and measures how late the timer fires. A busy or blocked
+/// runtime wakes the check late. The worse of this probe and the lag last
+/// seen by the process sampler is reported, so a stall between checks still
+/// degrades health.
+pub struct EventLoopLagCheck {
+    metrics: Arc<Metrics>,
+    /// Lag above this reports degraded
+    pub degraded_after: Duration,
+    /// Lag above this reports unhealthy
+    pub unhealthy_after: Duration,
+}
+
+impl EventLoopLagCheck {
+    #[must_use]
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+            degraded_after: Duration::from_millis(100),
+            unhealthy_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl HealthCheck for EventLoopLagCheck {
+    fn name(&self) -> &'static str {
+        "event_loop"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let sampled = Duration::try_from_secs_f64(self.metrics.process.scheduling_lag.get()).unwrap_or_default();
+            let lag = process::scheduling_lag().await.max(sampled);
+            let message = format!("{lag:?} lag");
+            if lag > self.unhealthy_after {
+                CheckResult::unhealthy(message)
+            } else if lag > self.degraded_after {
+                CheckResult::degraded(message)
+            } else {
+                CheckResult::healthy_with(message)
+            }
+        })
+    }
+}
+
+/// Share of HTTP requests that ended in an error
+pub struct ErrorRateCheck {
+    metrics: Arc<Metrics>,
+}
+
+impl ErrorRateCheck {
+    #[must_use]
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl HealthCheck for ErrorRateCheck {
+    fn name(&self) -> &'static str {
+        "error_rate"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let total_requests = self.metrics.total_requests();
+            let total_errors = self.metrics.errors.get();
+            #[allow(clippy::cast_precision_loss)] // A percentage
+            let error_rate = if total_requests > 0 {
+                (total_errors as f64 / total_requests as f64) * 100.0
+            } else {
+                0.0
+            };
+
+            let message = format!("{error_rate:.2}%");
+            if error_rate > 10.0 {
+                CheckResult::unhealthy(message)
+            } else if error_rate > 5.0 {
+                CheckResult::degraded(message)
+            } else {
+                CheckResult::healthy_with(message)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use crate::monitoring::ServiceStatus;
+
+    #[tokio::test]
+    async fn test_document_store_check() {
+        let store = Arc::new(DocumentStore::new());
+        let mut events = store.subscribe();
+        let result = DocumentStoreCheck::new(Arc::clone(&store)).check().await;
+        assert_eq!(result.status, ServiceStatus::Healthy);
+
+        // The sentinel leaves nothing behind and tells no subscriber
+        assert_eq!(store.count(), 0);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_directory_checks() {
+        let dir = std::env::temp_dir();
+        assert_eq!(WritableCheck::new(&dir).check().await.status, ServiceStatus::Healthy);
+        assert_ne!(DiskSpaceCheck::new(&dir).check().await.message, None);
+
+        let missing = dir.join("ulc-health-missing").join("nested");
+        assert_eq!(WritableCheck::new(&missing).check().await.status, ServiceStatus::Unhealthy);
+        let mut full = DiskSpaceCheck::new(&dir);
+        full.min_free_bytes = u64::MAX;
+        assert_eq!(full.check().await.status, ServiceStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    async fn test_auth_check() {
+        assert_eq!(AuthCheck::new(None).check().await.status, ServiceStatus::Healthy);
+
+        let keyless = AuthService::new(AuthConfig {
+            secret: String::new(),
+            ..AuthConfig::default()
+        });
+        let result = AuthCheck::new(Some(Arc::new(keyless))).check().await;
+        assert_eq!(result.status, ServiceStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    async fn test_event_loop_lag_check_uses_sampled_lag() {
+        let metrics = Arc::new(Metrics::new());
+        let check = EventLoopLagCheck::new(Arc::clone(&metrics));
+        assert_eq!(check.check().await.status, ServiceStatus::Healthy);
+
+        // A stall seen by the sampler degrades health even when the probe is quick
+        metrics.process.scheduling_lag.set(0.25);
+        assert_eq!(check.check().await.status, ServiceStatus::Degraded);
+        metrics.process.scheduling_lag.set(2.0);
+        assert_eq!(check.check().await.status, ServiceStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    async fn test_error_rate_check() {
+        let metrics = Arc::new(Metrics::new());
+        let check = ErrorRateCheck::new(Arc::clone(&metrics));
+        assert_eq!(check.check().await.status, ServiceStatus::Healthy);
+
+        metrics
+            .http_request_duration
+            .with_labels(&["/api/convert", "POST", "5xx"])
+            .observe(0.01);
+        metrics.errors.inc();
+        assert_eq!(check.check().await.status, ServiceStatus::Unhealthy);
+    }
+}
@@ -0,0 +1,328 @@
+//! Connection and session gauges across transports
+//!
+//! Every transport opens an [`OpenConnection`] when a client attaches and
+//! holds it until the client is gone. The guard counts the connection in
+//! `ulc_connections` under its transport and state and, when dropped,
+//! counts the disconnect under the reason last set. A guard dropped without
+//! a reason, as when its task panics or is aborted, counts as `dropped`, so
+//! the gauges stay exact on every path.
+
+use super::registry::{Counter, Family, Gauge, Registry};
+use super::window::WindowSpec;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How a client is attached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Http,
+    WebSocket,
+    Sse,
+    LspStdio,
+    LspTcp,
+    /// Language server hosted in-process, such as one per WebSocket session
+    LspPipe,
+}
+
+impl Transport {
+    /// Every transport, in reporting order
+    pub const ALL: [Self; 6] = [
+        Self::Http,
+        Self::WebSocket,
+        Self::Sse,
+        Self::LspStdio,
+        Self::LspTcp,
+        Self::LspPipe,
+    ];
+
+    /// Name used in metrics
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::WebSocket => "websocket",
+            Self::Sse => "sse",
+            Self::LspStdio => "lsp_stdio",
+            Self::LspTcp => "lsp_tcp",
+            Self::LspPipe => "lsp_pipe",
+        }
+    }
+}
+
+/// Stage of a connection's life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Accepted, protocol not yet agreed
+    Handshaking,
+    Active,
+    /// Shutting down, with work still being released
+    Draining,
+}
+
+impl ConnectionState {
+    /// Name used in metrics
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Handshaking => "handshaking",
+            Self::Active => "active",
+            Self::Draining => "draining",
+        }
+    }
+}
+
+/// Why a connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client or server closed it in an orderly way
+    Closed,
+    /// Lost without a close, on an error, or abandoned by a failed task
+    Dropped,
+    /// Refused before it was established
+    Refused,
+    /// Closed to admit a newer connection for the same subject
+    Displaced,
+    /// Its session was resumed on another connection
+    Superseded,
+}
+
+impl DisconnectReason {
+    /// Name used in metrics
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Dropped => "dropped",
+            Self::Refused => "refused",
+            Self::Displaced => "displaced",
+            Self::Superseded => "superseded",
+        }
+    }
+}
+
+/// Connection gauges and churn counters for every transport
+#[derive(Debug, Clone)]
+pub struct ConnectionMetrics {
+    /// Open connections by transport and state
+    pub open: Family<Gauge>,
+    /// Connections opened by transport
+    pub connects: Family<Counter>,
+    /// Connections ended by transport and reason
+    pub disconnects: Family<Counter>,
+    /// HTTP requests being handled
+    pub http_requests_in_flight: Gauge,
+    /// WebSocket sessions retained for resumption, attached or not
+    pub ws_sessions_retained: Gauge,
+}
+
+impl ConnectionMetrics {
+    /// Register the connection metrics
+    ///
+    /// # Errors
+    ///
+    /// Fails where a metric of the same name is already registered.
+    pub fn register(registry: &Registry) -> Result<Self> {
+        Ok(Self {
+            open: registry.gauge_family("ulc_connections", "Open connections", &["transport", "state"])?,
+            connects: registry.windowed_counter_family(
+                "ulc_connects_total",
+                "Connections opened",
+                &["transport"],
+                WindowSpec::MINUTE,
+            )?,
+            disconnects: registry.windowed_counter_family(
+                "ulc_disconnects_total",
+                "Connections ended",
+                &["transport", "reason"],
+                WindowSpec::MINUTE,
+            )?,
+            http_requests_in_flight: registry.gauge("ulc_http_requests_in_flight", "HTTP requests being handled")?,
+            ws_sessions_retained: registry.gauge(
+                "ulc_ws_sessions_retained",
+                "WebSocket sessions retained for resumption",
+            )?,
+        })
+    }
+
+    /// Count a connection opened over `transport` in `state` until the guard is dropped
+    #[must_use]
+    pub fn open(&self, transport: Transport, state: ConnectionState) -> OpenConnection {
+        self.connects.with_labels(&[transport.as_str()]).inc();
+        let gauge = self.open.with_labels(&[transport.as_str(), state.as_str()]);
+        gauge.inc();
+        OpenConnection {
+            metrics: self.clone(),
+            transport,
+            state,
+            gauge,
+            reason: None,
+        }
+    }
+
+    /// Count an HTTP request in flight until the guard is dropped
+    #[must_use]
+    pub fn request(&self) -> InFlight {
+        self.http_requests_in_flight.inc();
+        InFlight(self.http_requests_in_flight.clone())
+    }
+
+    /// Open connections, connects and disconnects of every transport
+    ///
+    /// `authenticated_subjects` is left for the caller, which knows who is
+    /// connected.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // The gauges count, so are whole and not negative
+    pub fn summary(&self) -> ConnectionsSummary {
+        let mut transports: BTreeMap<String, TransportSummary> = Transport::ALL
+            .iter()
+            .map(|transport| (transport.as_str().to_string(), TransportSummary::default()))
+            .collect();
+        for (labels, gauge) in self.open.children() {
+            let transport = transports.entry(labels[0].clone()).or_default();
+            let open = gauge.get() as u64;
+            transport.open += open;
+            transport.states.insert(labels[1].clone(), open);
+        }
+        for (labels, counter) in self.connects.children() {
+            transports.entry(labels[0].clone()).or_default().connects = counter.get();
+        }
+        for (labels, counter) in self.disconnects.children() {
+            let transport = transports.entry(labels[0].clone()).or_default();
+            transport.disconnects.insert(labels[1].clone(), counter.get());
+        }
+        ConnectionsSummary {
+            transports,
+            http_requests_in_flight: self.http_requests_in_flight.get() as u64,
+            ws_sessions_retained: self.ws_sessions_retained.get() as u64,
+            authenticated_subjects: 0,
+        }
+    }
+}
+
+/// A counted connection, uncounted when dropped
+#[derive(Debug)]
+pub struct OpenConnection {
+    metrics: ConnectionMetrics,
+    transport: Transport,
+    state: ConnectionState,
+    gauge: Gauge,
+    reason: Option<DisconnectReason>,
+}
+
+impl OpenConnection {
+    /// Move the connection to `state`
+    pub fn enter(&mut self, state: ConnectionState) {
+        if state == self.state {
+            return;
+        }
+        self.gauge.dec();
+        self.gauge = self.metrics.open.with_labels(&[self.transport.as_str(), state.as_str()]);
+        self.gauge.inc();
+        self.state = state;
+    }
+
+    /// Reason counted when the connection ends; the last one set wins
+    pub fn set_reason(&mut self, reason: DisconnectReason) {
+        self.reason = Some(reason);
+    }
+
+    /// Current state
+    #[must_use]
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.gauge.dec();
+        let reason = self.reason.unwrap_or(DisconnectReason::Dropped);
+        self.metrics
+            .disconnects
+            .with_labels(&[self.transport.as_str(), reason.as_str()])
+            .inc();
+    }
+}
+
+/// An HTTP request in flight, uncounted when dropped
+#[derive(Debug)]
+pub struct InFlight(Gauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Connections of one transport
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransportSummary {
+    pub open: u64,
+    /// Open connections by state
+    pub states: BTreeMap<String, u64>,
+    pub connects: u64,
+    /// Ended connections by reason
+    pub disconnects: BTreeMap<String, u64>,
+}
+
+/// Everything attached to the server, by transport
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionsSummary {
+    pub transports: BTreeMap<String, TransportSummary>,
+    pub http_requests_in_flight: u64,
+    pub ws_sessions_retained: u64,
+    /// Distinct authenticated subjects with an open WebSocket connection
+    pub authenticated_subjects: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(metrics: &ConnectionMetrics, transport: Transport, state: ConnectionState) -> f64 {
+        metrics.open.with_labels(&[transport.as_str(), state.as_str()]).get()
+    }
+
+    #[test]
+    fn test_guard_tracks_state_and_reason() {
+        let metrics = ConnectionMetrics::register(&Registry::new()).unwrap();
+        let mut connection = metrics.open(Transport::WebSocket, ConnectionState::Handshaking);
+        assert!((open(&metrics, Transport::WebSocket, ConnectionState::Handshaking) - 1.0).abs() < f64::EPSILON);
+
+        connection.enter(ConnectionState::Active);
+        connection.enter(ConnectionState::Active);
+        assert!(open(&metrics, Transport::WebSocket, ConnectionState::Handshaking).abs() < f64::EPSILON);
+        assert!((open(&metrics, Transport::WebSocket, ConnectionState::Active) - 1.0).abs() < f64::EPSILON);
+
+        connection.enter(ConnectionState::Draining);
+        connection.set_reason(DisconnectReason::Closed);
+        drop(connection);
+        let summary = metrics.summary();
+        let websocket = &summary.transports["websocket"];
+        assert_eq!(websocket.open, 0);
+        assert_eq!(websocket.connects, 1);
+        assert_eq!(websocket.disconnects["closed"], 1);
+    }
+
+    #[test]
+    fn test_abandoned_guard_counts_as_dropped() {
+        let metrics = ConnectionMetrics::register(&Registry::new()).unwrap();
+        let handle = std::thread::spawn({
+            let metrics = metrics.clone();
+            move || {
+                let _connection = metrics.open(Transport::LspTcp, ConnectionState::Active);
+                let _request = metrics.request();
+                panic!("transport task failed");
+            }
+        });
+        assert!(handle.join().is_err());
+
+        let summary = metrics.summary();
+        assert_eq!(summary.transports["lsp_tcp"].open, 0);
+        assert_eq!(summary.transports["lsp_tcp"].disconnects["dropped"], 1);
+        assert_eq!(summary.http_requests_in_flight, 0);
+        // Transports nothing connected over are still listed
+        assert_eq!(summary.transports["sse"], TransportSummary::default());
+    }
+}
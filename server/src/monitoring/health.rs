@@ -0,0 +1,528 @@
+//! Health checks
+//!
+//! A [`HealthChecker`] runs the registered [`HealthCheck`]s concurrently,
+//! each under its own timeout, and folds them into one [`HealthStatus`].
+//! Results are cached for a bounded time, and a probe arriving while a
+//! check is running waits for that run instead of starting another, so
+//! frequent probes do not stampede expensive checks.
+//!
+//! The checker also owns the server [`Lifecycle`], which periodic
+//! evaluations move between Ready and Degraded and which the liveness,
+//! readiness and startup probes report.
+
+use super::lifecycle::{Lifecycle, LifecycleState, LifecycleThresholds, Transition};
+use chrono::{DateTime, Utc};
+use futures_util::future::{join_all, BoxFuture};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Health check status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthStatus {
+    pub status: ServiceStatus,
+    pub version: String,
+    pub uptime_seconds: u64,
+    pub checks: HashMap<String, CheckStatus>,
+    pub lifecycle: LifecycleStatus,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Lifecycle state as reported by the probes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LifecycleStatus {
+    pub state: LifecycleState,
+    pub seconds_in_state: u64,
+    pub live: bool,
+    pub ready: bool,
+    pub started: bool,
+}
+
+/// Overall service status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// Individual health check status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckStatus {
+    pub status: ServiceStatus,
+    pub message: Option<String>,
+    pub last_check: DateTime<Utc>,
+    pub duration_ms: u64,
+}
+
+/// Outcome of one run of a [`HealthCheck`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub status: ServiceStatus,
+    pub message: Option<String>,
+    /// Time the check took, measured by the checker
+    pub latency: Duration,
+}
+
+impl CheckResult {
+    fn new(status: ServiceStatus, message: Option<String>) -> Self {
+        Self {
+            status,
+            message,
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// The checked component works
+    #[must_use]
+    pub fn healthy() -> Self {
+        Self::new(ServiceStatus::Healthy, None)
+    }
+
+    /// The checked component works, with a note
+    pub fn healthy_with(message: impl Into<String>) -> Self {
+        Self::new(ServiceStatus::Healthy, Some(message.into()))
+    }
+
+    /// The checked component works but needs attention
+    pub fn degraded(message: impl Into<String>) -> Self {
+        Self::new(ServiceStatus::Degraded, Some(message.into()))
+    }
+
+    /// The checked component does not work
+    pub fn unhealthy(message: impl Into<String>) -> Self {
+        Self::new(ServiceStatus::Unhealthy, Some(message.into()))
+    }
+}
+
+/// A check of one component the server depends on
+pub trait HealthCheck: Send + Sync {
+    /// Name the result is reported under
+    fn name(&self) -> &str;
+
+    /// Run the check
+    ///
+    /// The checker applies the timeout and measures latency, so checks only
+    /// decide the status.
+    fn check(&self) -> BoxFuture<'_, CheckResult>;
+}
+
+/// How a registered check is run
+#[derive(Debug, Clone, Copy)]
+pub struct CheckPolicy {
+    /// A run taking longer than this is reported unhealthy
+    pub timeout: Duration,
+    /// A result younger than this is served from cache
+    pub max_staleness: Duration,
+}
+
+impl Default for CheckPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            max_staleness: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A check with its policy and last result
+struct Registered {
+    check: Box<dyn HealthCheck>,
+    policy: CheckPolicy,
+    /// Held across a run, so concurrent probes share it
+    last: Mutex<Option<(Instant, CheckStatus)>>,
+}
+
+impl Registered {
+    async fn run(&self) -> CheckStatus {
+        let mut last = self.last.lock().await;
+        if let Some((at, status)) = &*last {
+            if at.elapsed() <= self.policy.max_staleness {
+                return status.clone();
+            }
+        }
+
+        let start = Instant::now();
+        let mut result = tokio::time::timeout(self.policy.timeout, self.check.check())
+            .await
+            .unwrap_or_else(|_| CheckResult::unhealthy(format!("Timed out after {:?}", self.policy.timeout)));
+        result.latency = start.elapsed();
+
+        let status = CheckStatus {
+            status: result.status,
+            message: result.message,
+            last_check: Utc::now(),
+            duration_ms: u64::try_from(result.latency.as_millis()).unwrap_or(u64::MAX),
+        };
+        *last = Some((Instant::now(), status.clone()));
+        status
+    }
+}
+
+/// Listener invoked for every lifecycle transition
+type TransitionObserver = Box<dyn Fn(&Transition) + Send + Sync>;
+
+/// Health checker
+pub struct HealthChecker {
+    start_time: DateTime<Utc>,
+    checks: RwLock<Vec<Arc<Registered>>>,
+    lifecycle: std::sync::Mutex<Lifecycle>,
+    observers: RwLock<Vec<TransitionObserver>>,
+}
+
+impl HealthChecker {
+    /// Create new health checker
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_thresholds(LifecycleThresholds::default())
+    }
+
+    /// Create a health checker whose lifecycle uses `thresholds`
+    #[must_use]
+    pub fn with_thresholds(thresholds: LifecycleThresholds) -> Self {
+        Self {
+            start_time: Utc::now(),
+            checks: RwLock::new(Vec::new()),
+            lifecycle: std::sync::Mutex::new(Lifecycle::new(thresholds)),
+            observers: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Register a listener called for every lifecycle transition
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the observer list.
+    pub fn observe(&self, observer: impl Fn(&Transition) + Send + Sync + 'static) {
+        self.observers
+            .write()
+            .expect("observers lock poisoned")
+            .push(Box::new(observer));
+    }
+
+    /// Current lifecycle state
+    pub fn state(&self) -> LifecycleState {
+        self.lifecycle().state()
+    }
+
+    /// Startup finished; the server starts reporting ready
+    pub fn mark_started(&self) {
+        let transition = self.lifecycle().started();
+        self.notify(transition);
+    }
+
+    /// Shutdown requested; the server stops reporting ready
+    pub fn begin_shutdown(&self) {
+        let transition = self.lifecycle().begin_shutdown();
+        self.notify(transition);
+    }
+
+    /// Shutdown finished; the server stops reporting live
+    pub fn mark_stopped(&self) {
+        let transition = self.lifecycle().stopped();
+        self.notify(transition);
+    }
+
+    /// Run the checks and feed the result into the lifecycle
+    pub async fn evaluate(&self) -> HealthStatus {
+        let mut health = self.check().await;
+        let transition = self.lifecycle().observe(health.status);
+        health.lifecycle = self.lifecycle_status();
+        self.notify(transition);
+        health
+    }
+
+    /// Evaluate every `interval` until the server stops
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        while self.state() != LifecycleState::Stopped {
+            ticker.tick().await;
+            self.evaluate().await;
+        }
+    }
+
+    fn lifecycle(&self) -> std::sync::MutexGuard<'_, Lifecycle> {
+        self.lifecycle.lock().expect("lifecycle lock poisoned")
+    }
+
+    fn lifecycle_status(&self) -> LifecycleStatus {
+        let lifecycle = self.lifecycle();
+        LifecycleStatus {
+            state: lifecycle.state(),
+            seconds_in_state: lifecycle.time_in_state().as_secs(),
+            live: lifecycle.is_alive(),
+            ready: lifecycle.is_ready(),
+            started: lifecycle.is_started(),
+        }
+    }
+
+    fn notify(&self, transition: Option<Transition>) {
+        let Some(transition) = transition else {
+            return;
+        };
+        match transition.to {
+            LifecycleState::Degraded | LifecycleState::Stopped => warn!(
+                "Lifecycle {} → {}: {}",
+                transition.from.as_str(),
+                transition.to.as_str(),
+                transition.reason
+            ),
+            _ => info!(
+                "Lifecycle {} → {}: {}",
+                transition.from.as_str(),
+                transition.to.as_str(),
+                transition.reason
+            ),
+        }
+        for observer in self.observers.read().expect("observers lock poisoned").iter() {
+            observer(&transition);
+        }
+    }
+
+    /// Add a check to every later health report
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the check list.
+    pub fn register(&self, check: impl HealthCheck + 'static, policy: CheckPolicy) {
+        self.checks
+            .write()
+            .expect("health checks lock poisoned")
+            .push(Arc::new(Registered {
+                check: Box::new(check),
+                policy,
+                last: Mutex::new(None),
+            }));
+    }
+
+    /// Names of the registered checks
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the check list.
+    pub fn names(&self) -> Vec<String> {
+        self.checks
+            .read()
+            .expect("health checks lock poisoned")
+            .iter()
+            .map(|registered| registered.check.name().to_string())
+            .collect()
+    }
+
+    /// Perform health check
+    ///
+    /// The overall status is the worst of the individual statuses. The
+    /// lifecycle is reported but not changed; see [`evaluate`](Self::evaluate).
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the check list.
+    pub async fn check(&self) -> HealthStatus {
+        let registered = self.checks.read().expect("health checks lock poisoned").clone();
+        let statuses = join_all(registered.iter().map(|registered| registered.run())).await;
+        let checks: HashMap<String, CheckStatus> = registered
+            .iter()
+            .map(|registered| registered.check.name().to_string())
+            .zip(statuses)
+            .collect();
+
+        // Determine overall status
+        let overall_status = if checks.values().any(|c| c.status == ServiceStatus::Unhealthy) {
+            ServiceStatus::Unhealthy
+        } else if checks.values().any(|c| c.status == ServiceStatus::Degraded) {
+            ServiceStatus::Degraded
+        } else {
+            ServiceStatus::Healthy
+        };
+
+        let uptime = Utc::now().signed_duration_since(self.start_time);
+
+        HealthStatus {
+            status: overall_status,
+            version: crate::build_info::VERSION.to_string(),
+            uptime_seconds: u64::try_from(uptime.num_seconds()).unwrap_or_default(),
+            checks,
+            lifecycle: self.lifecycle_status(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+impl Default for HealthChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Returns a fixed result after a delay, counting its runs
+    struct Fixed {
+        name: &'static str,
+        result: CheckResult,
+        delay: Duration,
+        runs: Arc<AtomicUsize>,
+    }
+
+    impl Fixed {
+        fn new(name: &'static str, result: CheckResult) -> Self {
+            Self {
+                name,
+                result,
+                delay: Duration::ZERO,
+                runs: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl HealthCheck for Fixed {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn check(&self) -> BoxFuture<'_, CheckResult> {
+            Box::pin(async move {
+                self.runs.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(self.delay).await;
+                self.result.clone()
+            })
+        }
+    }
+
+    /// Unhealthy while the flag is set
+    struct Toggle(Arc<std::sync::atomic::AtomicBool>);
+
+    impl HealthCheck for Toggle {
+        fn name(&self) -> &'static str {
+            "toggle"
+        }
+
+        fn check(&self) -> BoxFuture<'_, CheckResult> {
+            let failing = self.0.load(Ordering::SeqCst);
+            Box::pin(async move {
+                if failing {
+                    CheckResult::unhealthy("Flag set")
+                } else {
+                    CheckResult::healthy()
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let checker = HealthChecker::new();
+        assert_eq!(checker.check().await.status, ServiceStatus::Healthy);
+
+        checker.register(Fixed::new("store", CheckResult::healthy()), CheckPolicy::default());
+        checker.register(Fixed::new("disk", CheckResult::degraded("Low disk")), CheckPolicy::default());
+        let health = checker.check().await;
+        assert_eq!(health.status, ServiceStatus::Degraded);
+        assert_eq!(health.checks["disk"].message.as_deref(), Some("Low disk"));
+
+        checker.register(Fixed::new("auth", CheckResult::unhealthy("No key")), CheckPolicy::default());
+        assert_eq!(checker.check().await.status, ServiceStatus::Unhealthy);
+        assert_eq!(checker.names(), vec!["store", "disk", "auth"]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_check_times_out() {
+        let checker = HealthChecker::new();
+        let mut slow = Fixed::new("slow", CheckResult::healthy());
+        slow.delay = Duration::from_mins(1);
+        let policy = CheckPolicy {
+            timeout: Duration::from_millis(20),
+            ..CheckPolicy::default()
+        };
+        checker.register(slow, policy);
+
+        let health = checker.check().await;
+        assert_eq!(health.status, ServiceStatus::Unhealthy);
+        assert!(health.checks["slow"].message.as_deref().unwrap().starts_with("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_probes_share_one_run() {
+        let checker = HealthChecker::new();
+        let mut expensive = Fixed::new("expensive", CheckResult::healthy());
+        expensive.delay = Duration::from_millis(50);
+        let runs = Arc::clone(&expensive.runs);
+        checker.register(expensive, CheckPolicy::default());
+
+        let probes = join_all((0..8).map(|_| checker.check())).await;
+        assert!(probes.iter().all(|health| health.status == ServiceStatus::Healthy));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Served from cache until the result is older than max_staleness
+        checker.check().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_evaluations_drive_lifecycle() {
+        use LifecycleState::{Degraded, Draining, Ready, Starting, Stopped};
+        let thresholds = LifecycleThresholds {
+            degrade_after: 2,
+            recover_after: 1,
+            ..LifecycleThresholds::default()
+        };
+        let checker = HealthChecker::with_thresholds(thresholds);
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&seen);
+        checker.observe(move |transition| {
+            recorded.lock().unwrap().push((transition.from, transition.to));
+        });
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        checker.register(
+            Toggle(Arc::clone(&failing)),
+            CheckPolicy {
+                max_staleness: Duration::ZERO,
+                ..CheckPolicy::default()
+            },
+        );
+
+        let health = checker.check().await;
+        assert_eq!(health.lifecycle.state, LifecycleState::Starting);
+        assert!(!health.lifecycle.ready && health.lifecycle.live && !health.lifecycle.started);
+
+        checker.mark_started();
+        failing.store(true, Ordering::SeqCst);
+        checker.evaluate().await;
+        let health = checker.evaluate().await;
+        assert_eq!(health.lifecycle.state, LifecycleState::Degraded);
+        failing.store(false, Ordering::SeqCst);
+        checker.evaluate().await;
+        checker.begin_shutdown();
+        checker.mark_stopped();
+        assert!(!checker.check().await.lifecycle.live);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(Starting, Ready), (Ready, Degraded), (Degraded, Ready), (Ready, Draining), (Draining, Stopped)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_result_is_refreshed() {
+        let checker = HealthChecker::new();
+        let check = Fixed::new("cheap", CheckResult::healthy());
+        let runs = Arc::clone(&check.runs);
+        let policy = CheckPolicy {
+            max_staleness: Duration::ZERO,
+            ..CheckPolicy::default()
+        };
+        checker.register(check, policy);
+
+        checker.check().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        checker.check().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,349 @@
+//! Server lifecycle for liveness, readiness and startup probes
+//!
+//! The server moves through Starting → Ready ⇄ Degraded → Draining →
+//! Stopped. Startup completion and shutdown drive the outer transitions.
+//! Health evaluations move it between Ready and Degraded, with separate
+//! thresholds in each direction so a flapping check does not flip the
+//! state on every evaluation.
+
+use super::health::ServiceStatus;
+use crate::jobs::{self, JobQueue};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Lifecycle state of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LifecycleState {
+    /// Initialising; not yet accepting work
+    Starting,
+    /// Accepting work with all checks passing
+    Ready,
+    /// Accepting work while some checks fail
+    Degraded,
+    /// Shutting down; finishing in-flight work but accepting none
+    Draining,
+    /// Shut down
+    Stopped,
+}
+
+impl LifecycleState {
+    /// Every state, in lifecycle order
+    pub const ALL: [Self; 5] = [Self::Starting, Self::Ready, Self::Degraded, Self::Draining, Self::Stopped];
+
+    /// Label value used in metrics
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Ready => "ready",
+            Self::Degraded => "degraded",
+            Self::Draining => "draining",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+/// Consecutive health evaluations needed to change state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct LifecycleThresholds {
+    /// Failing evaluations that move Ready to Degraded
+    pub degrade_after: u32,
+    /// Healthy evaluations that move Degraded back to Ready
+    pub recover_after: u32,
+    /// Unhealthy evaluations after which a Degraded server stops being ready
+    pub unready_after: u32,
+    /// Unhealthy evaluations after which a Degraded server stops being live
+    pub restart_after: u32,
+}
+
+impl Default for LifecycleThresholds {
+    fn default() -> Self {
+        Self {
+            degrade_after: 3,
+            recover_after: 2,
+            unready_after: 3,
+            restart_after: 10,
+        }
+    }
+}
+
+/// A change of lifecycle state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    pub from: LifecycleState,
+    pub to: LifecycleState,
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Lifecycle state machine
+///
+/// Evaluations count as failing when the overall status is degraded or
+/// unhealthy; the readiness and liveness thresholds count only unhealthy
+/// ones.
+#[derive(Debug)]
+pub struct Lifecycle {
+    state: LifecycleState,
+    since: Instant,
+    thresholds: LifecycleThresholds,
+    /// Consecutive evaluations that were not healthy
+    failing: u32,
+    /// Consecutive evaluations that were unhealthy
+    unhealthy: u32,
+    /// Consecutive evaluations that were healthy
+    passing: u32,
+}
+
+impl Lifecycle {
+    /// Start in [`LifecycleState::Starting`]
+    #[must_use]
+    pub fn new(thresholds: LifecycleThresholds) -> Self {
+        Self {
+            state: LifecycleState::Starting,
+            since: Instant::now(),
+            thresholds,
+            failing: 0,
+            unhealthy: 0,
+            passing: 0,
+        }
+    }
+
+    /// Current state
+    #[must_use]
+    pub fn state(&self) -> LifecycleState {
+        self.state
+    }
+
+    /// Time since the last transition
+    #[must_use]
+    pub fn time_in_state(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    /// Startup finished: Starting → Ready
+    pub fn started(&mut self) -> Option<Transition> {
+        match self.state {
+            LifecycleState::Starting => Some(self.enter(LifecycleState::Ready, "startup complete")),
+            _ => None,
+        }
+    }
+
+    /// Record the overall status of one health evaluation
+    pub fn observe(&mut self, status: ServiceStatus) -> Option<Transition> {
+        match status {
+            ServiceStatus::Healthy => {
+                self.passing += 1;
+                self.failing = 0;
+                self.unhealthy = 0;
+            }
+            ServiceStatus::Degraded => {
+                self.passing = 0;
+                self.failing += 1;
+                self.unhealthy = 0;
+            }
+            ServiceStatus::Unhealthy => {
+                self.passing = 0;
+                self.failing += 1;
+                self.unhealthy += 1;
+            }
+        }
+
+        match self.state {
+            LifecycleState::Ready if self.failing >= self.thresholds.degrade_after => {
+                let reason = format!("{} failing health evaluations", self.failing);
+                Some(self.enter(LifecycleState::Degraded, &reason))
+            }
+            LifecycleState::Degraded if self.passing >= self.thresholds.recover_after => {
+                let reason = format!("{} healthy evaluations", self.passing);
+                Some(self.enter(LifecycleState::Ready, &reason))
+            }
+            _ => None,
+        }
+    }
+
+    /// Shutdown requested: stop accepting work
+    pub fn begin_shutdown(&mut self) -> Option<Transition> {
+        match self.state {
+            LifecycleState::Draining | LifecycleState::Stopped => None,
+            _ => Some(self.enter(LifecycleState::Draining, "shutdown requested")),
+        }
+    }
+
+    /// Shutdown finished
+    pub fn stopped(&mut self) -> Option<Transition> {
+        match self.state {
+            LifecycleState::Stopped => None,
+            _ => Some(self.enter(LifecycleState::Stopped, "shutdown complete")),
+        }
+    }
+
+    /// Whether startup has finished
+    #[must_use]
+    pub fn is_started(&self) -> bool {
+        self.state != LifecycleState::Starting
+    }
+
+    /// Whether the server should receive traffic
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        match self.state {
+            LifecycleState::Ready => true,
+            LifecycleState::Degraded => self.unhealthy < self.thresholds.unready_after,
+            _ => false,
+        }
+    }
+
+    /// Whether the server should be left running
+    ///
+    /// Only a server that stopped, or that has been unhealthy for long
+    /// enough that a restart is the likelier cure, is reported dead. A
+    /// starting or draining server is left to finish.
+    #[must_use]
+    pub fn is_alive(&self) -> bool {
+        match self.state {
+            LifecycleState::Stopped => false,
+            LifecycleState::Degraded => self.unhealthy < self.thresholds.restart_after,
+            _ => true,
+        }
+    }
+
+    fn enter(&mut self, to: LifecycleState, reason: &str) -> Transition {
+        let transition = Transition {
+            from: self.state,
+            to,
+            reason: reason.to_string(),
+            at: Utc::now(),
+        };
+        self.state = to;
+        self.since = Instant::now();
+        self.passing = 0;
+        self.failing = 0;
+        transition
+    }
+}
+
+/// Transition observer that POSTs each transition as JSON to `url`
+///
+/// Deliveries run as [`jobs::WEBHOOK`] jobs and are not retried; a failure,
+/// or a full queue, is logged. Transitions outside a Tokio runtime are not
+/// delivered.
+pub fn webhook(url: String, jobs: Arc<JobQueue>) -> impl Fn(&Transition) + Send + Sync + 'static {
+    let client = reqwest::Client::new();
+    move |transition| {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        let request = client.post(&url).json(transition);
+        let target = url.clone();
+        let submitted = jobs.submit(jobs::WEBHOOK, move |_| async move {
+            match request.send().await.and_then(reqwest::Response::error_for_status) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    warn!("Lifecycle webhook to {} failed: {}", target, e);
+                    Err(e.into())
+                }
+            }
+        });
+        if let Err(e) = submitted {
+            warn!("Lifecycle webhook to {} not delivered: {}", url, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LifecycleState::{Degraded, Draining, Ready, Starting, Stopped};
+
+    fn states(lifecycle: &mut Lifecycle, statuses: &[ServiceStatus]) -> Vec<LifecycleState> {
+        statuses
+            .iter()
+            .map(|status| {
+                lifecycle.observe(*status);
+                lifecycle.state()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_full_lifecycle() {
+        use ServiceStatus::{Degraded as D, Healthy as H};
+        let mut lifecycle = Lifecycle::new(LifecycleThresholds::default());
+        assert_eq!(lifecycle.state(), Starting);
+        assert!(lifecycle.is_alive() && !lifecycle.is_ready() && !lifecycle.is_started());
+
+        // Failing checks during startup do not move the state
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        assert_eq!(lifecycle.state(), Starting);
+
+        let transition = lifecycle.started().unwrap();
+        assert_eq!((transition.from, transition.to), (Starting, Ready));
+        assert!(lifecycle.is_ready() && lifecycle.is_started());
+        assert!(lifecycle.started().is_none());
+
+        assert_eq!(states(&mut lifecycle, &[D, D, D]), vec![Ready, Ready, Degraded]);
+        assert!(lifecycle.is_ready());
+        assert_eq!(states(&mut lifecycle, &[H, H]), vec![Degraded, Ready]);
+
+        let transition = lifecycle.begin_shutdown().unwrap();
+        assert_eq!((transition.from, transition.to), (Ready, Draining));
+        assert!(lifecycle.is_alive() && !lifecycle.is_ready());
+        // Health no longer matters once draining
+        assert_eq!(states(&mut lifecycle, &[H, H, H]), vec![Draining; 3]);
+        assert!(lifecycle.begin_shutdown().is_none());
+
+        assert_eq!(lifecycle.stopped().unwrap().to, Stopped);
+        assert!(!lifecycle.is_alive() && !lifecycle.is_ready());
+        assert!(lifecycle.stopped().is_none());
+    }
+
+    #[test]
+    fn test_flapping_check_hysteresis() {
+        use ServiceStatus::{Healthy as H, Unhealthy as U};
+        let mut lifecycle = Lifecycle::new(LifecycleThresholds::default());
+        lifecycle.started();
+
+        // Alternating results never reach either threshold
+        assert_eq!(states(&mut lifecycle, &[U, H, U, U, H]), vec![Ready; 5]);
+
+        // Three in a row degrade; one healthy blip does not recover
+        assert_eq!(states(&mut lifecycle, &[U, U, U, H, U]), vec![Ready, Ready, Degraded, Degraded, Degraded]);
+        assert_eq!(states(&mut lifecycle, &[H, U, H, H]), vec![Degraded, Degraded, Degraded, Ready]);
+    }
+
+    #[test]
+    fn test_degraded_readiness_and_liveness_thresholds() {
+        let thresholds = LifecycleThresholds {
+            degrade_after: 1,
+            recover_after: 1,
+            unready_after: 2,
+            restart_after: 4,
+        };
+        let mut lifecycle = Lifecycle::new(thresholds);
+        lifecycle.started();
+
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        assert_eq!(lifecycle.state(), Degraded);
+        assert!(lifecycle.is_ready() && lifecycle.is_alive());
+
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        assert!(!lifecycle.is_ready() && lifecycle.is_alive());
+
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        lifecycle.observe(ServiceStatus::Unhealthy);
+        assert!(!lifecycle.is_ready() && !lifecycle.is_alive());
+
+        // Recovery restores both
+        lifecycle.observe(ServiceStatus::Healthy);
+        assert_eq!(lifecycle.state(), Ready);
+        assert!(lifecycle.is_ready() && lifecycle.is_alive());
+    }
+}
@@ -0,0 +1,1018 @@
+//! Monitoring, observability, and distributed tracing
+//!
+//! Provides comprehensive application monitoring with metrics, tracing, and health checks.
+
+#[cfg(feature = "counting-allocator")]
+pub mod alloc;
+pub mod alerts;
+pub mod checks;
+pub mod connections;
+pub mod health;
+pub mod lifecycle;
+pub mod process;
+pub mod prometheus;
+pub mod rates;
+pub mod registry;
+pub mod rules;
+pub mod slow_ops;
+pub mod statsd;
+pub mod store;
+pub mod usage;
+pub mod window;
+
+pub use self::alerts::{AlertMetrics, Alerter, AlertsConfig};
+pub use self::connections::{ConnectionMetrics, ConnectionsSummary};
+pub use self::health::{
+    CheckPolicy, CheckResult, CheckStatus, HealthCheck, HealthChecker, HealthStatus, LifecycleStatus, ServiceStatus,
+};
+pub use self::lifecycle::{LifecycleState, LifecycleThresholds, Transition};
+pub use self::process::{ProcessMetrics, ProcessSnapshot};
+pub use self::rates::Rates;
+pub use self::registry::MetricControl;
+pub use self::slow_ops::{SlowOpConfig, SlowOps};
+pub use self::store::{StoreMetrics, StoreMetricsRecorder};
+pub use self::usage::{Usage, UsageConfig};
+
+use self::rates::RateWindow;
+use self::registry::{Buckets, Counter, Family, FamilySnapshot, Gauge, Histogram, HistogramSnapshot, MetricKind, Registry, SampleValue};
+use self::window::WindowSpec;
+use crate::build_info::BuildInfo;
+use crate::core::{ConversionResponse, Format};
+use crate::formats::stream::Streamed;
+use crate::formats::{FormatObserver, LimitKind, ValidationOutcome};
+use crate::jobs::JobMetrics;
+use crate::scheduler::TaskMetrics;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Application metrics
+///
+/// Every instrument is registered in one [`Registry`], which exporters walk
+/// with [`Registry::gather`]. Cloning shares the instruments.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    started: Instant,
+    rates: Arc<RateWindow>,
+    /// HTTP request handling time by route pattern, method and status class
+    pub http_request_duration: Family<Histogram>,
+    /// WebSocket message handling time by message type
+    pub ws_message_duration: Family<Histogram>,
+    /// LSP request handling time by method
+    pub lsp_request_duration: Family<Histogram>,
+    /// Conversion time by source and target format
+    pub conversion_duration: Family<Histogram>,
+    /// Validation time by format
+    pub validation_duration: Family<Histogram>,
+    /// Errors encountered
+    pub errors: Counter,
+    /// Conversions by source format, target format and outcome
+    pub conversions: Family<Counter>,
+    /// Validation runs by format and outcome
+    pub validations: Family<Counter>,
+    /// Documents refused by a format limit, by limit kind
+    pub format_limit_rejections: Family<Counter>,
+    /// Size of converted documents, in bytes
+    pub conversion_size: Histogram,
+    /// Active connections
+    pub active_connections: Gauge,
+    /// Open WebSocket connections
+    pub ws_connections: Gauge,
+    /// Open WebSocket connections per authenticated subject
+    pub ws_connections_per_subject: Family<Gauge>,
+    /// Open WebSocket connections per client IP
+    pub ws_connections_per_ip: Family<Gauge>,
+    /// WebSocket upgrades refused, by the limit that refused them
+    pub ws_rejections: Family<Counter>,
+    /// WebSocket connections closed to admit a newer one for the same subject
+    pub ws_displaced: Counter,
+    /// 1 for the current lifecycle state, 0 for the others
+    pub lifecycle_state: Family<Gauge>,
+    /// Unix time of the last lifecycle transition
+    pub lifecycle_state_since: Gauge,
+    /// Memory, CPU, descriptors, threads and runtime load
+    pub process: ProcessMetrics,
+    /// Documents, bytes and churn of the document store
+    pub store: StoreMetrics,
+    /// Open connections and churn of every transport
+    pub connections: ConnectionMetrics,
+    /// Alert notifications delivered and failed, by sink
+    pub alerts: AlertMetrics,
+    /// Background jobs queued, running, waited and run, by class
+    pub jobs: JobMetrics,
+    /// Scheduled maintenance runs, skips and last outcome, by task
+    pub tasks: TaskMetrics,
+    /// Configuration reloads, by whether they changed, failed or found nothing new
+    pub config_reloads: Family<Counter>,
+    /// Settings changed by a reload, by setting and whether it was applied or needs a restart
+    pub config_changes: Family<Counter>,
+    /// Always 1, labelled with the build's metadata
+    pub build_info: Family<Gauge>,
+    /// Time since the metrics were created, refreshed by [`Metrics::gather`]
+    pub uptime: Gauge,
+}
+
+impl Metrics {
+    /// Create new metrics instance
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let valid = "built-in metrics are valid";
+        let metrics = Self {
+            http_request_duration: registry
+                .windowed_histogram_family(
+                    "ulc_http_request_duration_seconds",
+                    "HTTP request handling time",
+                    &["route", "method", "status"],
+                    Buckets::latency(),
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            ws_message_duration: registry
+                .histogram_family(
+                    "ulc_ws_message_duration_seconds",
+                    "WebSocket message handling time",
+                    &["method"],
+                    Buckets::latency(),
+                )
+                .expect(valid),
+            lsp_request_duration: registry
+                .histogram_family(
+                    "ulc_lsp_request_duration_seconds",
+                    "LSP request handling time",
+                    &["method"],
+                    Buckets::latency(),
+                )
+                .expect(valid),
+            conversion_duration: registry
+                .histogram_family(
+                    "ulc_conversion_duration_seconds",
+                    "Document conversion time",
+                    &["from", "to"],
+                    Buckets::latency(),
+                )
+                .expect(valid),
+            validation_duration: registry
+                .histogram_family(
+                    "ulc_validation_duration_seconds",
+                    "Document validation time",
+                    &["format"],
+                    Buckets::latency(),
+                )
+                .expect(valid),
+            errors: registry
+                .windowed_counter("ulc_errors_total", "Errors encountered", WindowSpec::MINUTE)
+                .expect(valid),
+            conversions: registry
+                .windowed_counter_family(
+                    "ulc_conversions_total",
+                    "Conversions by source and target format and outcome",
+                    &["from", "to", "outcome"],
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            validations: registry
+                .windowed_counter_family(
+                    "ulc_validations_total",
+                    "Validation runs by format and outcome",
+                    &["format", "outcome"],
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            format_limit_rejections: registry
+                .windowed_counter_family(
+                    "ulc_format_limit_rejections_total",
+                    "Documents refused by a format limit",
+                    &["limit"],
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            conversion_size: registry
+                .histogram("ulc_conversion_size_bytes", "Size of converted documents", Buckets::sizes())
+                .expect(valid),
+            active_connections: registry
+                .gauge("ulc_active_connections", "Active connections")
+                .expect(valid),
+            ws_connections: registry
+                .gauge("ulc_ws_connections", "Open WebSocket connections")
+                .expect(valid),
+            ws_connections_per_subject: registry
+                .gauge_family(
+                    "ulc_ws_connections_per_subject",
+                    "Open WebSocket connections per authenticated subject",
+                    &["subject"],
+                )
+                .expect(valid),
+            ws_connections_per_ip: registry
+                .gauge_family(
+                    "ulc_ws_connections_per_ip",
+                    "Open WebSocket connections per client IP",
+                    &["ip"],
+                )
+                .expect(valid),
+            ws_rejections: registry
+                .windowed_counter_family(
+                    "ulc_ws_rejections_total",
+                    "WebSocket upgrades refused by a connection limit",
+                    &["limit"],
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            ws_displaced: registry
+                .windowed_counter(
+                    "ulc_ws_displaced_total",
+                    "WebSocket connections closed to admit a newer one",
+                    WindowSpec::MINUTE,
+                )
+                .expect(valid),
+            lifecycle_state: registry
+                .gauge_family("ulc_lifecycle_state", "Current lifecycle state", &["state"])
+                .expect(valid),
+            lifecycle_state_since: registry
+                .gauge(
+                    "ulc_lifecycle_state_since_seconds",
+                    "Unix time the current lifecycle state was entered",
+                )
+                .expect(valid),
+            process: ProcessMetrics::register(&registry).expect(valid),
+            store: StoreMetrics::register(&registry).expect(valid),
+            connections: ConnectionMetrics::register(&registry).expect(valid),
+            alerts: AlertMetrics::register(&registry).expect(valid),
+            jobs: JobMetrics::register(&registry).expect(valid),
+            tasks: TaskMetrics::register(&registry).expect(valid),
+            config_reloads: registry
+                .counter_family("ulc_config_reloads_total", "Configuration reloads by outcome", &["outcome"])
+                .expect(valid),
+            config_changes: registry
+                .counter_family(
+                    "ulc_config_changes_total",
+                    "Settings changed by configuration reloads, by outcome",
+                    &["setting", "outcome"],
+                )
+                .expect(valid),
+            build_info: registry
+                .gauge_family("ulc_build_info", "Build metadata of the running binary", &BuildInfo::LABELS)
+                .expect(valid),
+            uptime: registry
+                .gauge("ulc_uptime_seconds", "Time since the server started")
+                .expect(valid),
+            registry: Arc::new(registry),
+            started: Instant::now(),
+            rates: Arc::new(RateWindow::new(RATE_RESOLUTION)),
+        };
+        let build = BuildInfo::current().label_values();
+        metrics.build_info.with_labels(&build.each_ref().map(String::as_str)).set(1.0);
+        metrics
+    }
+
+    /// Report the lifecycle state entered at `since`
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record_lifecycle(&self, state: LifecycleState, since: DateTime<Utc>) {
+        for each in LifecycleState::ALL {
+            let current = if each == state { 1.0 } else { 0.0 };
+            self.lifecycle_state.with_labels(&[each.as_str()]).set(current);
+        }
+        self.lifecycle_state_since.set(since.timestamp_millis() as f64 / 1000.0);
+    }
+
+    /// Registry holding every instrument, for exporters and further metrics
+    #[must_use]
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Time since the metrics were created, which is when the server started
+    #[must_use]
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Every instrument's current value, with uptime brought up to date
+    #[must_use]
+    pub fn gather(&self) -> Vec<FamilySnapshot> {
+        self.uptime.set(self.uptime().as_secs_f64());
+        self.registry.gather()
+    }
+
+    /// Empty every rate window, so rates count from now
+    ///
+    /// Counter values, and so the Prometheus and `StatsD` exports, are untouched.
+    pub fn reset_windows(&self) {
+        self.registry.reset_windows();
+        self.rates.clear();
+    }
+
+    /// Record and export metrics as `config` says, every metric it leaves out in full
+    ///
+    /// Replaces the previous settings. Nothing changes if a name is unknown
+    /// or a sampling rate invalid.
+    ///
+    /// # Errors
+    ///
+    /// Fails where `config` names an unknown metric or gives an invalid
+    /// sampling rate.
+    pub fn apply(&self, config: &MetricsConfig) -> Result<()> {
+        let controls = self.registry.controls();
+        let mut disabled = HashSet::new();
+        for selector in &config.disabled {
+            disabled.extend(select(&controls, selector)?.into_iter().map(|control| control.name.as_str()));
+        }
+        let mut sampling = HashMap::new();
+        for (selector, &every) in &config.sampling {
+            if every == 0 {
+                bail!("Sampling of {selector} must record at least one observation in N");
+            }
+            let histograms: Vec<&str> = select(&controls, selector)?
+                .into_iter()
+                .filter(|control| control.kind == MetricKind::Histogram)
+                .map(|control| control.name.as_str())
+                .collect();
+            if histograms.is_empty() {
+                bail!("Only histograms can be sampled, and {selector} is not one");
+            }
+            sampling.extend(histograms.into_iter().map(|name| (name, every)));
+        }
+
+        for control in &controls {
+            let name = control.name.as_str();
+            if control.kind == MetricKind::Histogram {
+                self.registry.set_sampling(name, sampling.get(name).copied().unwrap_or(1))?;
+            }
+            self.registry.set_enabled(name, !disabled.contains(name))?;
+        }
+        Ok(())
+    }
+
+    /// Whether each metric is recorded, and how often histograms sample
+    #[must_use]
+    pub fn controls(&self) -> Vec<MetricControl> {
+        self.registry.controls()
+    }
+
+    /// Record counter totals for [`MetricsSnapshot::rates`] until the server stops
+    pub async fn run_rate_sampler(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.rates.resolution());
+        loop {
+            ticker.tick().await;
+            self.rates.record(&self.gather(), Instant::now());
+        }
+    }
+
+    /// HTTP requests handled across all routes
+    #[must_use]
+    pub fn total_requests(&self) -> u64 {
+        self.http_request_duration
+            .children()
+            .iter()
+            .map(|(_, histogram)| histogram.snapshot().count)
+            .sum()
+    }
+
+    /// Successful conversions across all format pairs
+    #[must_use]
+    pub fn total_conversions(&self) -> u64 {
+        self.conversions
+            .children()
+            .iter()
+            .filter(|(labels, _)| labels[2] == "succeeded")
+            .map(|(_, counter)| counter.get())
+            .sum()
+    }
+
+    /// Get metrics snapshot
+    ///
+    /// The registry is gathered once, and its size is bounded by the
+    /// per-metric series limit rather than by uptime.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // Sizes and counts, so whole and not negative
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let families = self.gather();
+
+        // Per endpoint, across status classes
+        let mut endpoints: HashMap<String, HistogramSnapshot> = HashMap::new();
+        for (labels, histogram) in self.http_request_duration.children() {
+            let key = format!("{} {}", labels[1], labels[0]);
+            let durations = histogram.snapshot();
+            match endpoints.get_mut(&key) {
+                Some(merged) => merged.merge(&durations),
+                None => {
+                    endpoints.insert(key, durations);
+                }
+            }
+        }
+        let endpoint_stats = endpoints
+            .into_iter()
+            .filter(|(_, durations)| durations.count > 0)
+            .map(|(endpoint, durations)| {
+                let stats = EndpointStats {
+                    count: durations.count,
+                    avg_ms: millis(durations.mean()),
+                    p50_ms: millis(durations.quantile(0.5)),
+                    p95_ms: millis(durations.quantile(0.95)),
+                    p99_ms: millis(durations.quantile(0.99)),
+                };
+                (endpoint, stats)
+            })
+            .collect();
+
+        MetricsSnapshot {
+            total_requests: self.total_requests(),
+            total_errors: self.errors.get(),
+            total_conversions: self.total_conversions(),
+            total_bytes: self.conversion_size.snapshot().sum as u64,
+            active_connections: self.active_connections.get() as u64,
+            endpoint_stats,
+            latency: latency(&families),
+            values: values(&families),
+            rates: with_windowed_rates(self.rates.rates(), &families),
+            websocket: WebSocketStats {
+                connections: self.ws_connections.get() as u64,
+                per_subject: gauges(&self.ws_connections_per_subject),
+                per_ip: gauges(&self.ws_connections_per_ip),
+                rejections: self
+                    .ws_rejections
+                    .children()
+                    .into_iter()
+                    .map(|(labels, counter)| (labels.join(","), counter.get()))
+                    .collect(),
+                displaced: self.ws_displaced.get(),
+            },
+            formats: self.format_stats(),
+            process: self.process.snapshot(),
+            uptime_seconds: self.uptime().as_secs(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Conversion pairs by volume, validation outcomes and limit rejections
+    fn format_stats(&self) -> FormatStats {
+        let mut pairs: HashMap<(String, String), ConversionPairStats> = HashMap::new();
+        for (labels, counter) in self.conversions.children() {
+            let stats = pairs
+                .entry((labels[0].clone(), labels[1].clone()))
+                .or_insert_with(|| ConversionPairStats {
+                    from: labels[0].clone(),
+                    to: labels[1].clone(),
+                    ..ConversionPairStats::default()
+                });
+            stats.attempted += counter.get();
+            if labels[2] == "succeeded" {
+                stats.succeeded += counter.get();
+            } else {
+                stats.failed += counter.get();
+            }
+        }
+        let mut top_conversions: Vec<ConversionPairStats> = pairs.into_values().collect();
+        top_conversions.sort_by(|a, b| {
+            b.attempted
+                .cmp(&a.attempted)
+                .then_with(|| (&a.from, &a.to).cmp(&(&b.from, &b.to)))
+        });
+        top_conversions.truncate(TOP_CONVERSION_PAIRS);
+
+        let mut validations: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        for (labels, counter) in self.validations.children() {
+            validations
+                .entry(labels[0].clone())
+                .or_default()
+                .insert(labels[1].clone(), counter.get());
+        }
+
+        FormatStats {
+            top_conversions,
+            validations,
+            limit_rejections: self
+                .format_limit_rejections
+                .children()
+                .into_iter()
+                .map(|(labels, counter)| (labels.join(","), counter.get()))
+                .collect(),
+        }
+    }
+}
+
+/// Groups of metrics that can be disabled or sampled together, by name prefix
+const METRIC_GROUPS: [(&str, &[&str]); 3] = [
+    ("lsp", &["ulc_lsp_"]),
+    ("websocket", &["ulc_ws_"]),
+    ("formats", &["ulc_conversion", "ulc_validation", "ulc_format_"]),
+];
+
+/// Metrics named by `selector`, a metric name or a group
+fn select<'a>(controls: &'a [MetricControl], selector: &str) -> Result<Vec<&'a MetricControl>> {
+    let matched: Vec<&MetricControl> = match METRIC_GROUPS.iter().find(|(group, _)| *group == selector) {
+        Some((_, prefixes)) => controls
+            .iter()
+            .filter(|control| prefixes.iter().any(|prefix| control.name.starts_with(prefix)))
+            .collect(),
+        None => controls.iter().filter(|control| control.name == selector).collect(),
+    };
+    if matched.is_empty() {
+        bail!("Unknown metric or group: {selector}");
+    }
+    Ok(matched)
+}
+
+/// Metrics left unrecorded, and histograms recording one observation in N
+///
+/// Both take metric names or the groups `lsp`, `websocket` and `formats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct MetricsConfig {
+    /// Neither recorded nor exported
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    /// Observations recorded per one, by histogram or group
+    #[serde(default)]
+    pub sampling: BTreeMap<String, u32>,
+}
+
+impl MetricsConfig {
+    /// Read settings from a YAML or JSON file, checked against the built-in metrics
+    ///
+    /// # Errors
+    ///
+    /// Fails where the file cannot be read or parsed, or as
+    /// [`MetricsConfig::validate`] does.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading metric settings from {}", path.display()))?;
+        let config: Self =
+            serde_yaml::from_str(&text).with_context(|| format!("parsing metric settings in {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("checking metric settings in {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Check every name against the built-in metrics, and every sampling rate
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first unknown name or sampling rate of zero.
+    pub fn validate(&self) -> Result<()> {
+        Metrics::new().apply(self)
+    }
+}
+
+/// Quantile estimates for every populated duration histogram
+fn latency(families: &[FamilySnapshot]) -> BTreeMap<String, Vec<LatencyStats>> {
+    let mut latency = BTreeMap::new();
+    for family in families {
+        if !family.descriptor.name.ends_with("_seconds") {
+            continue;
+        }
+        let stats: Vec<LatencyStats> = family
+            .samples
+            .iter()
+            .filter_map(|sample| match &sample.value {
+                SampleValue::Histogram(durations) if durations.count > 0 => Some(LatencyStats {
+                    labels: family.descriptor.labels.iter().cloned().zip(sample.labels.iter().cloned()).collect(),
+                    count: durations.count,
+                    avg_ms: millis(durations.mean()),
+                    p50_ms: millis(durations.quantile(0.5)),
+                    p95_ms: millis(durations.quantile(0.95)),
+                    p99_ms: millis(durations.quantile(0.99)),
+                }),
+                _ => None,
+            })
+            .collect();
+        if !stats.is_empty() {
+            latency.insert(family.descriptor.name.clone(), stats);
+        }
+    }
+    latency
+}
+
+/// Value of every counter and gauge series
+#[allow(clippy::cast_precision_loss)]
+fn values(families: &[FamilySnapshot]) -> BTreeMap<String, Vec<SeriesValue>> {
+    families
+        .iter()
+        .filter(|family| family.kind != MetricKind::Histogram)
+        .map(|family| {
+            let series = family
+                .samples
+                .iter()
+                .filter_map(|sample| {
+                    let value = match sample.value {
+                        SampleValue::Counter(count) => count as f64,
+                        SampleValue::Gauge(value) => value,
+                        SampleValue::Histogram(_) => return None,
+                    };
+                    Some(SeriesValue {
+                        labels: family.descriptor.labels.iter().cloned().zip(sample.labels.iter().cloned()).collect(),
+                        value,
+                    })
+                })
+                .collect();
+            (family.descriptor.name.clone(), series)
+        })
+        .collect()
+}
+
+impl FormatObserver for Metrics {
+    #[allow(clippy::cast_precision_loss)]
+    fn conversion(&self, from: Format, to: Format, elapsed: Duration, result: &Result<ConversionResponse>) {
+        let (from, to) = (from.extension(), to.extension());
+        self.conversion_duration.with_labels(&[from, to]).observe_duration(elapsed);
+        let outcome = match result {
+            Ok(response) => {
+                self.conversion_size.observe(response.content.len() as f64);
+                "succeeded"
+            }
+            Err(_) => "failed",
+        };
+        self.conversions.with_labels(&[from, to, outcome]).inc();
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn streamed_conversion(&self, from: Format, to: Format, elapsed: Duration, result: &Result<Streamed>) {
+        let (from, to) = (from.extension(), to.extension());
+        self.conversion_duration.with_labels(&[from, to]).observe_duration(elapsed);
+        let outcome = match result {
+            Ok(streamed) => {
+                self.conversion_size.observe(streamed.written as f64);
+                "succeeded"
+            }
+            Err(_) => "failed",
+        };
+        self.conversions.with_labels(&[from, to, outcome]).inc();
+    }
+
+    fn validation(&self, format: Format, elapsed: Duration, outcome: ValidationOutcome) {
+        let format = format.extension();
+        self.validation_duration.with_labels(&[format]).observe_duration(elapsed);
+        self.validations.with_labels(&[format, outcome.as_str()]).inc();
+    }
+
+    fn limit_exceeded(&self, limit: LimitKind) {
+        self.format_limit_rejections.with_labels(&[limit.as_str()]).inc();
+    }
+}
+
+/// Interval between samples for [`MetricsSnapshot::rates`]
+const RATE_RESOLUTION: Duration = Duration::from_secs(10);
+
+/// Sampled rates, with the one-minute rate of families keeping a rate
+/// window read from their windows instead
+fn with_windowed_rates(mut sampled: BTreeMap<String, Rates>, families: &[FamilySnapshot]) -> BTreeMap<String, Rates> {
+    let minute = Duration::from_mins(1);
+    for family in families {
+        let windowed: Option<f64> = family
+            .samples
+            .iter()
+            .map(|sample| sample.window.as_ref().filter(|ring| ring.covers(minute))?.rate(minute))
+            .sum();
+        if let Some(m1) = windowed.filter(|_| !family.samples.is_empty()) {
+            sampled.entry(family.descriptor.name.clone()).or_default().m1 = Some(m1);
+        }
+    }
+    sampled
+}
+
+/// Conversion pairs listed in [`FormatStats::top_conversions`]
+const TOP_CONVERSION_PAIRS: usize = 10;
+
+fn millis(seconds: Option<f64>) -> f64 {
+    seconds.unwrap_or(0.0) * 1000.0
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Values of a single-label gauge family, keyed by label value
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // The gauges count, so are whole and not negative
+fn gauges(family: &Family<Gauge>) -> HashMap<String, u64> {
+    family
+        .children()
+        .into_iter()
+        .map(|(labels, gauge)| (labels.join(","), gauge.get() as u64))
+        .collect()
+}
+
+/// Metrics snapshot for reporting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub total_requests: u64,
+    pub total_errors: u64,
+    pub total_conversions: u64,
+    pub total_bytes: u64,
+    pub active_connections: u64,
+    pub endpoint_stats: HashMap<String, EndpointStats>,
+    /// Approximate quantiles of every duration histogram, by metric name
+    pub latency: BTreeMap<String, Vec<LatencyStats>>,
+    /// Every counter and gauge series, by metric name
+    pub values: BTreeMap<String, Vec<SeriesValue>>,
+    /// Per-second increase of every counter and histogram over the last
+    /// 1, 5 and 15 minutes, by metric name; empty again after a restart
+    pub rates: BTreeMap<String, Rates>,
+    pub websocket: WebSocketStats,
+    pub formats: FormatStats,
+    pub process: ProcessSnapshot,
+    pub uptime_seconds: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// WebSocket connection counts per admission limit
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebSocketStats {
+    pub connections: u64,
+    pub per_subject: HashMap<String, u64>,
+    pub per_ip: HashMap<String, u64>,
+    pub rejections: HashMap<String, u64>,
+    pub displaced: u64,
+}
+
+/// Format usage: busiest conversion pairs and validation outcomes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FormatStats {
+    /// Most attempted conversion pairs, busiest first
+    pub top_conversions: Vec<ConversionPairStats>,
+    /// Validation runs by format, then outcome
+    pub validations: BTreeMap<String, BTreeMap<String, u64>>,
+    pub limit_rejections: HashMap<String, u64>,
+}
+
+/// Conversion counts for one source and target format
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConversionPairStats {
+    pub from: String,
+    pub to: String,
+    pub attempted: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// Per-endpoint statistics, estimated from the latency histogram
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointStats {
+    pub count: u64,
+    pub avg_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Quantile estimates for one label set of a duration histogram
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub labels: BTreeMap<String, String>,
+    pub count: u64,
+    pub avg_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Value of one counter or gauge series
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesValue {
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+/// Distributed tracing span
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub operation: String,
+    pub start_time: DateTime<Utc>,
+    pub duration_ms: Option<u64>,
+    pub tags: HashMap<String, String>,
+    pub logs: Vec<SpanLog>,
+}
+
+/// Span log entry
+#[derive(Debug, Clone)]
+pub struct SpanLog {
+    pub timestamp: DateTime<Utc>,
+    pub level: LogLevel,
+    pub message: String,
+    pub fields: HashMap<String, String>,
+}
+
+/// Log level for span logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Span {
+    /// Create new trace span
+    pub fn new(operation: String) -> Self {
+        Self {
+            trace_id: uuid::Uuid::new_v4().to_string(),
+            span_id: uuid::Uuid::new_v4().to_string(),
+            parent_span_id: None,
+            operation,
+            start_time: Utc::now(),
+            duration_ms: None,
+            tags: HashMap::new(),
+            logs: Vec::new(),
+        }
+    }
+
+    /// Create child span
+    pub fn child(&self, operation: String) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: uuid::Uuid::new_v4().to_string(),
+            parent_span_id: Some(self.span_id.clone()),
+            operation,
+            start_time: Utc::now(),
+            duration_ms: None,
+            tags: HashMap::new(),
+            logs: Vec::new(),
+        }
+    }
+
+    /// Add tag to span
+    pub fn tag(&mut self, key: String, value: String) {
+        self.tags.insert(key, value);
+    }
+
+    /// Add log entry
+    pub fn log(&mut self, level: LogLevel, message: String) {
+        self.logs.push(SpanLog {
+            timestamp: Utc::now(),
+            level,
+            message,
+            fields: HashMap::new(),
+        });
+    }
+
+    /// Finish span and record duration
+    pub fn finish(&mut self) {
+        let duration = Utc::now().signed_duration_since(self.start_time);
+        self.duration_ms = Some(duration.num_milliseconds() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_recording() {
+        let metrics = Metrics::new();
+
+        let convert = metrics.http_request_duration.with_labels(&["/api/convert", "POST", "2xx"]);
+        convert.observe(0.100);
+        convert.observe(0.150);
+        metrics.conversions.with_labels(&["md", "html", "succeeded"]).inc();
+        metrics.conversion_size.observe(1024.0);
+
+        assert_eq!(metrics.total_requests(), 2);
+        assert_eq!(metrics.total_conversions(), 1);
+        assert_eq!(metrics.snapshot().total_bytes, 1024);
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let metrics = Metrics::new();
+        let converted = Ok(ConversionResponse {
+            content: "<p>hi</p>".to_string(),
+            from: Format::Markdown,
+            to: Format::Html,
+            warnings: vec![],
+        });
+        let failed = Err(anyhow::anyhow!("Failed to parse JSON"));
+        let elapsed = Duration::from_millis(2);
+        metrics.conversion(Format::Markdown, Format::Html, elapsed, &converted);
+        metrics.conversion(Format::Markdown, Format::Html, elapsed, &converted);
+        metrics.conversion(Format::Json, Format::Yaml, elapsed, &failed);
+        metrics.validation(Format::Json, elapsed, ValidationOutcome::Warnings);
+        metrics.limit_exceeded(LimitKind::InputSize);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_conversions, 2);
+        assert_eq!(snapshot.total_bytes, 18);
+        let top = &snapshot.formats.top_conversions;
+        assert_eq!((top[0].from.as_str(), top[0].to.as_str(), top[0].attempted), ("md", "html", 2));
+        assert_eq!((top[1].attempted, top[1].succeeded, top[1].failed), (1, 0, 1));
+        assert_eq!(snapshot.formats.validations["json"]["warnings"], 1);
+        assert_eq!(snapshot.formats.limit_rejections.get("input_size"), Some(&1));
+    }
+
+    #[test]
+    fn test_metrics_snapshot() {
+        let metrics = Metrics::new();
+
+        metrics
+            .http_request_duration
+            .with_labels(&["/api/test", "GET", "2xx"])
+            .observe(0.100);
+        metrics
+            .http_request_duration
+            .with_labels(&["/api/test", "GET", "4xx"])
+            .observe(0.200);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_requests, 2);
+        let stats = &snapshot.endpoint_stats["GET /api/test"];
+        assert_eq!(stats.count, 2);
+        assert!((stats.avg_ms - 150.0).abs() < 1e-6);
+        assert!(stats.p50_ms > 64.0 && stats.p50_ms <= 128.0);
+        assert_eq!(snapshot.latency["ulc_http_request_duration_seconds"].len(), 2);
+    }
+
+    #[test]
+    fn test_metrics_registered() {
+        let metrics = Metrics::new();
+        metrics.ws_rejections.with_labels(&["per_ip"]).inc();
+
+        let gathered = metrics.registry().gather();
+        let names: Vec<&str> = gathered.iter().map(|f| f.descriptor.name.as_str()).collect();
+        assert!(names.contains(&"ulc_http_request_duration_seconds"));
+        assert!(names.contains(&"ulc_ws_connections_per_ip"));
+        assert_eq!(metrics.snapshot().websocket.rejections.get("per_ip"), Some(&1));
+    }
+
+    #[test]
+    fn test_metric_groups_disabled_and_sampled() {
+        let metrics = Metrics::new();
+        let config = MetricsConfig {
+            disabled: vec!["lsp".to_string(), "ulc_ws_connections".to_string()],
+            sampling: BTreeMap::from([("formats".to_string(), 4)]),
+        };
+        metrics.apply(&config).unwrap();
+
+        let lsp = metrics.lsp_request_duration.with_labels(&["textDocument/didChange"]);
+        lsp.observe(0.01);
+        assert_eq!(lsp.snapshot().count, 0);
+        let gathered = metrics.gather();
+        let names: Vec<&str> = gathered.iter().map(|f| f.descriptor.name.as_str()).collect();
+        assert!(!names.contains(&"ulc_lsp_request_duration_seconds"));
+        assert!(!names.contains(&"ulc_ws_connections"));
+        assert!(names.contains(&"ulc_ws_connections_per_ip"));
+
+        // Only the group's histograms sample; its counters count everything
+        let controls = metrics.controls();
+        let control = |name: &str| controls.iter().find(|control| control.name == name).unwrap().clone();
+        assert_eq!(control("ulc_conversion_duration_seconds").sample_every, 4);
+        assert_eq!(control("ulc_conversion_size_bytes").sample_every, 4);
+        assert_eq!(control("ulc_conversions_total").sample_every, 1);
+        assert!(!control("ulc_lsp_request_duration_seconds").enabled);
+
+        // A rejected change leaves the settings as they were
+        let unknown = MetricsConfig {
+            disabled: vec!["ulc_http_request_duration_seconds".to_string(), "ulc_nope".to_string()],
+            ..MetricsConfig::default()
+        };
+        assert!(metrics.apply(&unknown).is_err());
+        let counter = MetricsConfig {
+            sampling: BTreeMap::from([("ulc_errors_total".to_string(), 2)]),
+            ..MetricsConfig::default()
+        };
+        assert!(metrics.apply(&counter).is_err());
+        assert_eq!(metrics.controls(), controls);
+
+        // Settings are replaced, not merged
+        metrics.apply(&MetricsConfig::default()).unwrap();
+        assert!(metrics.controls().iter().all(|control| control.enabled && control.sample_every == 1));
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_lifecycle_gauges() {
+        let metrics = Metrics::new();
+        let since = Utc::now();
+        metrics.record_lifecycle(LifecycleState::Draining, since);
+
+        assert!((metrics.lifecycle_state.with_labels(&["draining"]).get() - 1.0).abs() < f64::EPSILON);
+        assert!(metrics.lifecycle_state.with_labels(&["ready"]).get().abs() < f64::EPSILON);
+        assert_eq!(metrics.lifecycle_state.children().len(), LifecycleState::ALL.len());
+        assert!((metrics.lifecycle_state_since.get() - since.timestamp() as f64).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_span_creation() {
+        let span = Span::new("test_operation".to_string());
+        assert_eq!(span.operation, "test_operation");
+        assert!(span.duration_ms.is_none());
+    }
+
+    #[test]
+    fn test_span_child() {
+        let parent = Span::new("parent".to_string());
+        let child = parent.child("child".to_string());
+
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_ne!(child.span_id, parent.span_id);
+        assert_eq!(child.parent_span_id, Some(parent.span_id.clone()));
+    }
+}
@@ -0,0 +1,360 @@
+//! Process and runtime self-metrics
+//!
+//! Memory, CPU time, file descriptors and threads are read from procfs on
+//! Linux and from the Mach task info on macOS. A figure the platform cannot
+//! provide is not registered at all, rather than reported as zero.
+//! Runtime figures come from the Tokio runtime, and scheduling lag from a
+//! timer that measures how late it wakes.
+
+use super::registry::{Gauge, Registry};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Timer armed to measure scheduling lag
+const LAG_PROBE: Duration = Duration::from_millis(10);
+
+/// How late a short timer fires, a measure of how busy the runtime is
+pub async fn scheduling_lag() -> Duration {
+    let start = Instant::now();
+    tokio::time::sleep(LAG_PROBE).await;
+    start.elapsed().saturating_sub(LAG_PROBE)
+}
+
+/// Process figures at one point in time; `None` where the platform has no source
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessStats {
+    pub resident_memory_bytes: Option<u64>,
+    pub virtual_memory_bytes: Option<u64>,
+    /// User and system CPU time since the process started
+    pub cpu_seconds: Option<f64>,
+    pub open_fds: Option<u64>,
+    /// Soft limit on open file descriptors
+    pub max_fds: Option<u64>,
+    pub threads: Option<u64>,
+}
+
+impl ProcessStats {
+    /// Read the current figures
+    #[must_use]
+    pub fn sample() -> Self {
+        let mut stats = Self::default();
+        #[cfg(unix)]
+        unix::sample(&mut stats);
+        #[cfg(target_os = "linux")]
+        linux::sample(&mut stats);
+        #[cfg(target_os = "macos")]
+        macos::sample(&mut stats);
+        stats
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::ProcessStats;
+
+    pub(super) fn sample(stats: &mut ProcessStats) {
+        // SAFETY: both calls only write into the zeroed structs passed to them
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &raw mut usage) } == 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let seconds = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+            stats.cpu_seconds = Some(seconds(usage.ru_utime) + seconds(usage.ru_stime));
+        }
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &raw mut limit) } == 0 && limit.rlim_cur != libc::RLIM_INFINITY {
+            // rlim_t is u64 on Linux but not on every unix
+            #[allow(clippy::useless_conversion)]
+            {
+                stats.max_fds = u64::try_from(limit.rlim_cur).ok();
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::ProcessStats;
+
+    pub(super) fn sample(stats: &mut ProcessStats) {
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            for line in status.lines() {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let kib = || value.trim().trim_end_matches("kB").trim().parse::<u64>().ok().map(|v| v * 1024);
+                match key {
+                    "VmRSS" => stats.resident_memory_bytes = kib(),
+                    "VmSize" => stats.virtual_memory_bytes = kib(),
+                    "Threads" => stats.threads = value.trim().parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+        if let Ok(entries) = std::fs::read_dir("/proc/self/fd") {
+            // The listing holds a descriptor of its own
+            stats.open_fds = Some((entries.count() as u64).saturating_sub(1));
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use super::ProcessStats;
+    use std::mem::size_of;
+
+    pub(super) fn sample(stats: &mut ProcessStats) {
+        // SAFETY: proc_pidinfo writes at most the given size into the buffers passed
+        let pid = unsafe { libc::getpid() };
+        let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+        let size = size_of::<libc::proc_taskinfo>() as libc::c_int;
+        let read = unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDTASKINFO, 0, (&mut info as *mut libc::proc_taskinfo).cast(), size) };
+        if read == size {
+            stats.resident_memory_bytes = Some(info.pti_resident_size);
+            stats.virtual_memory_bytes = Some(info.pti_virtual_size);
+            stats.threads = u64::try_from(info.pti_threadnum).ok();
+        }
+
+        let needed = unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDLISTFDS, 0, std::ptr::null_mut(), 0) };
+        let Ok(needed) = usize::try_from(needed) else {
+            return;
+        };
+        let mut fds: Vec<libc::proc_fdinfo> = Vec::with_capacity(needed / size_of::<libc::proc_fdinfo>() + 1);
+        let capacity = (fds.capacity() * size_of::<libc::proc_fdinfo>()) as libc::c_int;
+        let read = unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDLISTFDS, 0, fds.as_mut_ptr().cast(), capacity) };
+        if let Ok(read) = usize::try_from(read) {
+            stats.open_fds = Some((read / size_of::<libc::proc_fdinfo>()) as u64);
+        }
+    }
+}
+
+/// Tokio runtime figures
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeStats {
+    pub workers: u64,
+    pub alive_tasks: u64,
+    /// Tasks waiting in the shared injection queue
+    pub global_queue_depth: u64,
+}
+
+impl RuntimeStats {
+    /// Read the current runtime's figures, or `None` outside a runtime
+    #[must_use]
+    pub fn sample() -> Option<Self> {
+        let metrics = tokio::runtime::Handle::try_current().ok()?.metrics();
+        Some(Self {
+            workers: metrics.num_workers() as u64,
+            alive_tasks: metrics.num_alive_tasks() as u64,
+            global_queue_depth: metrics.global_queue_depth() as u64,
+        })
+    }
+}
+
+/// Gauges for the process, runtime and, with the `counting-allocator`
+/// feature, the heap
+#[derive(Debug, Clone)]
+pub struct ProcessMetrics {
+    pub resident_memory: Option<Gauge>,
+    pub virtual_memory: Option<Gauge>,
+    pub cpu_seconds: Option<Gauge>,
+    pub open_fds: Option<Gauge>,
+    pub max_fds: Option<Gauge>,
+    pub threads: Option<Gauge>,
+    pub runtime_workers: Gauge,
+    pub runtime_alive_tasks: Gauge,
+    pub runtime_global_queue_depth: Gauge,
+    /// Latest scheduling lag, in seconds
+    pub scheduling_lag: Gauge,
+    #[cfg(feature = "counting-allocator")]
+    pub allocated_bytes: Gauge,
+    #[cfg(feature = "counting-allocator")]
+    pub allocations: Gauge,
+}
+
+impl ProcessMetrics {
+    /// Register the gauges this platform can fill
+    ///
+    /// # Errors
+    ///
+    /// Fails where a metric of the same name is already registered.
+    pub fn register(registry: &Registry) -> Result<Self> {
+        let available = ProcessStats::sample();
+        let optional = |present: bool, name: &str, help: &str| -> Result<Option<Gauge>> {
+            present.then(|| registry.gauge(name, help)).transpose()
+        };
+        Ok(Self {
+            resident_memory: optional(
+                available.resident_memory_bytes.is_some(),
+                "ulc_process_resident_memory_bytes",
+                "Resident memory size",
+            )?,
+            virtual_memory: optional(
+                available.virtual_memory_bytes.is_some(),
+                "ulc_process_virtual_memory_bytes",
+                "Virtual memory size",
+            )?,
+            cpu_seconds: optional(
+                available.cpu_seconds.is_some(),
+                "ulc_process_cpu_seconds",
+                "User and system CPU time used",
+            )?,
+            open_fds: optional(available.open_fds.is_some(), "ulc_process_open_fds", "Open file descriptors")?,
+            max_fds: optional(
+                available.max_fds.is_some(),
+                "ulc_process_max_fds",
+                "Limit on open file descriptors",
+            )?,
+            threads: optional(available.threads.is_some(), "ulc_process_threads", "OS threads")?,
+            runtime_workers: registry.gauge("ulc_runtime_workers", "Tokio worker threads")?,
+            runtime_alive_tasks: registry.gauge("ulc_runtime_alive_tasks", "Tokio tasks not yet finished")?,
+            runtime_global_queue_depth: registry.gauge(
+                "ulc_runtime_global_queue_depth",
+                "Tokio tasks waiting in the global queue",
+            )?,
+            scheduling_lag: registry.gauge(
+                "ulc_runtime_scheduling_lag_seconds",
+                "How late a 10ms timer fired at the last sample",
+            )?,
+            #[cfg(feature = "counting-allocator")]
+            allocated_bytes: registry.gauge("ulc_allocator_allocated_bytes", "Heap bytes allocated and not freed")?,
+            #[cfg(feature = "counting-allocator")]
+            allocations: registry.gauge("ulc_allocator_allocations", "Heap allocations not freed")?,
+        })
+    }
+
+    /// Record one sample of every figure
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record(&self, process: &ProcessStats, runtime: Option<&RuntimeStats>, lag: Duration) {
+        let set = |gauge: &Option<Gauge>, value: Option<f64>| {
+            if let (Some(gauge), Some(value)) = (gauge, value) {
+                gauge.set(value);
+            }
+        };
+        set(&self.resident_memory, process.resident_memory_bytes.map(|v| v as f64));
+        set(&self.virtual_memory, process.virtual_memory_bytes.map(|v| v as f64));
+        set(&self.cpu_seconds, process.cpu_seconds);
+        set(&self.open_fds, process.open_fds.map(|v| v as f64));
+        set(&self.max_fds, process.max_fds.map(|v| v as f64));
+        set(&self.threads, process.threads.map(|v| v as f64));
+        if let Some(runtime) = runtime {
+            self.runtime_workers.set(runtime.workers as f64);
+            self.runtime_alive_tasks.set(runtime.alive_tasks as f64);
+            self.runtime_global_queue_depth.set(runtime.global_queue_depth as f64);
+        }
+        self.scheduling_lag.set(lag.as_secs_f64());
+        #[cfg(feature = "counting-allocator")]
+        {
+            let heap = super::alloc::stats();
+            self.allocated_bytes.set(heap.allocated_bytes as f64);
+            self.allocations.set(heap.allocations as f64);
+        }
+    }
+
+    /// Latest recorded figures
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // The gauges count, so are whole and not negative
+    pub fn snapshot(&self) -> ProcessSnapshot {
+        let get = |gauge: &Option<Gauge>| gauge.as_ref().map(|g| g.get() as u64);
+        ProcessSnapshot {
+            process: ProcessStats {
+                resident_memory_bytes: get(&self.resident_memory),
+                virtual_memory_bytes: get(&self.virtual_memory),
+                cpu_seconds: self.cpu_seconds.as_ref().map(Gauge::get),
+                open_fds: get(&self.open_fds),
+                max_fds: get(&self.max_fds),
+                threads: get(&self.threads),
+            },
+            runtime: RuntimeStats {
+                workers: self.runtime_workers.get() as u64,
+                alive_tasks: self.runtime_alive_tasks.get() as u64,
+                global_queue_depth: self.runtime_global_queue_depth.get() as u64,
+            },
+            scheduling_lag_ms: self.scheduling_lag.get() * 1000.0,
+            #[cfg(feature = "counting-allocator")]
+            allocator: Some(AllocStats {
+                allocated_bytes: self.allocated_bytes.get() as u64,
+                allocations: self.allocations.get() as u64,
+            }),
+            #[cfg(not(feature = "counting-allocator"))]
+            allocator: None,
+        }
+    }
+}
+
+/// Process, runtime and heap figures in the metrics snapshot
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    #[serde(flatten)]
+    pub process: ProcessStats,
+    pub runtime: RuntimeStats,
+    pub scheduling_lag_ms: f64,
+    /// Heap use, with the `counting-allocator` feature
+    pub allocator: Option<AllocStats>,
+}
+
+/// Heap bytes and allocations not yet freed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllocStats {
+    pub allocated_bytes: u64,
+    pub allocations: u64,
+}
+
+/// Record process and runtime figures into `metrics` every `interval`
+pub async fn run_sampler(metrics: ProcessMetrics, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let lag = scheduling_lag().await;
+        metrics.record(&ProcessStats::sample(), RuntimeStats::sample().as_ref(), lag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_sample_reads_this_process() {
+        let stats = ProcessStats::sample();
+        assert!(stats.resident_memory_bytes.unwrap() > 0);
+        assert!(stats.virtual_memory_bytes.unwrap() >= stats.resident_memory_bytes.unwrap());
+        assert!(stats.cpu_seconds.unwrap() >= 0.0);
+        assert!(stats.threads.unwrap() >= 1);
+
+        // Opening a file shows up as one more descriptor
+        let before = stats.open_fds.unwrap();
+        let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+        assert!(ProcessStats::sample().open_fds.unwrap() > before);
+        drop(file);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_record_and_snapshot() {
+        let registry = Registry::new();
+        let metrics = ProcessMetrics::register(&registry).unwrap();
+        let process = ProcessStats::sample();
+        let runtime = RuntimeStats::sample().unwrap();
+        assert_eq!(runtime.workers, 2);
+
+        metrics.record(&process, Some(&runtime), Duration::from_millis(25));
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.runtime.workers, 2);
+        assert!((snapshot.scheduling_lag_ms - 25.0).abs() < 1e-6);
+        assert_eq!(snapshot.process.threads, process.threads);
+        assert_eq!(snapshot.process.open_fds, process.open_fds);
+
+        // Only figures the platform provides are exported
+        let names: Vec<String> = registry.gather().into_iter().map(|f| f.descriptor.name).collect();
+        assert_eq!(names.contains(&"ulc_process_threads".to_string()), process.threads.is_some());
+        assert!(names.contains(&"ulc_runtime_scheduling_lag_seconds".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_scheduling_lag_sees_blocked_runtime() {
+        let lag = tokio::spawn(scheduling_lag());
+        // Block the only worker past the probe's deadline
+        tokio::task::yield_now().await;
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(lag.await.unwrap() >= Duration::from_millis(40));
+    }
+}
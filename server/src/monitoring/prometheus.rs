@@ -0,0 +1,138 @@
+//! Prometheus text exposition format
+//!
+//! Renders gathered metric families in the plain-text format (version
+//! 0.0.4) that Prometheus scrapes. Histograms become cumulative `_bucket`
+//! series with an `le` label, plus `_sum` and `_count`.
+
+use super::registry::{FamilySnapshot, SampleValue};
+use std::fmt::Write;
+
+/// Content type of the exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Render families in the text exposition format
+pub fn encode(families: &[FamilySnapshot]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = &family.descriptor.name;
+        let _ = writeln!(out, "# HELP {} {}", name, escape(&family.descriptor.help, false));
+        let _ = writeln!(out, "# TYPE {} {}", name, family.kind.as_str());
+
+        for sample in &family.samples {
+            let labels: Vec<(&str, &str)> = family
+                .descriptor
+                .labels
+                .iter()
+                .map(String::as_str)
+                .zip(sample.labels.iter().map(String::as_str))
+                .collect();
+            match &sample.value {
+                SampleValue::Counter(value) => line(&mut out, name, &labels, None, &value.to_string()),
+                SampleValue::Gauge(value) => line(&mut out, name, &labels, None, &float(*value)),
+                SampleValue::Histogram(histogram) => {
+                    let bucket = format!("{name}_bucket");
+                    for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+                        line(&mut out, &bucket, &labels, Some(&float(*bound)), &count.to_string());
+                    }
+                    line(&mut out, &bucket, &labels, Some("+Inf"), &histogram.count.to_string());
+                    line(&mut out, &format!("{name}_sum"), &labels, None, &float(histogram.sum));
+                    line(&mut out, &format!("{name}_count"), &labels, None, &histogram.count.to_string());
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Write one sample line, with an optional `le` label appended
+fn line(out: &mut String, name: &str, labels: &[(&str, &str)], le: Option<&str>, value: &str) {
+    out.push_str(name);
+    let le = le.map(|le| ("le", le));
+    let mut labels = labels.iter().copied().chain(le).peekable();
+    if labels.peek().is_some() {
+        out.push('{');
+        for (i, (label, label_value)) in labels.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", label, escape(label_value, true));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// Escape backslashes and newlines, and quotes inside label values
+fn escape(s: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::registry::{Buckets, Registry};
+
+    #[test]
+    fn test_encode_counter_and_gauge() {
+        let registry = Registry::new();
+        registry
+            .counter_family("ulc_ops_total", "Operations\nperformed", &["op"])
+            .unwrap()
+            .with_labels(&["say \"hi\""])
+            .inc_by(3);
+        registry.gauge("ulc_open", "Open things").unwrap().set(2.5);
+
+        let text = encode(&registry.gather());
+        assert_eq!(
+            text,
+            "# HELP ulc_open Open things\n\
+             # TYPE ulc_open gauge\n\
+             ulc_open 2.5\n\
+             # HELP ulc_ops_total Operations\\nperformed\n\
+             # TYPE ulc_ops_total counter\n\
+             ulc_ops_total{op=\"say \\\"hi\\\"\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_histogram_series() {
+        let registry = Registry::new();
+        let family = registry
+            .histogram_family("ulc_latency_seconds", "Latency", &["method"], Buckets::new(vec![0.1, 1.0]))
+            .unwrap();
+        let histogram = family.with_labels(&["get"]);
+        histogram.observe(0.0625);
+        histogram.observe(0.5);
+        histogram.observe(4.0);
+
+        let text = encode(&registry.gather());
+        for expected in [
+            "ulc_latency_seconds_bucket{method=\"get\",le=\"0.1\"} 1",
+            "ulc_latency_seconds_bucket{method=\"get\",le=\"1\"} 2",
+            "ulc_latency_seconds_bucket{method=\"get\",le=\"+Inf\"} 3",
+            "ulc_latency_seconds_sum{method=\"get\"} 4.5625",
+            "ulc_latency_seconds_count{method=\"get\"} 3",
+        ] {
+            assert!(text.lines().any(|line| line == expected), "missing {expected:?} in\n{text}");
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Recent per-second rates of every counter
+//!
+//! A [`RateWindow`] keeps a fixed ring of per-family totals sampled at a
+//! set resolution, enough to cover the longest window, so its size does not
+//! grow with uptime. Rates over 1, 5 and 15 minutes are derived from it.
+//! Histograms contribute their observation counts.
+//!
+//! The ring lives in memory: rates start empty after a restart and only
+//! cover the time since. A total that goes down, as when a series is
+//! removed, is taken to have restarted from zero.
+
+use super::registry::{FamilySnapshot, SampleValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Windows rates are reported over
+const WINDOWS: [Duration; 3] = [
+    Duration::from_mins(1),
+    Duration::from_mins(5),
+    Duration::from_mins(15),
+];
+
+/// Per-second increase of one family over recent windows
+///
+/// A window is `None` until two samples are available.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rates {
+    pub m1: Option<f64>,
+    pub m5: Option<f64>,
+    pub m15: Option<f64>,
+}
+
+/// Ring of counter totals for rate derivation
+#[derive(Debug)]
+pub struct RateWindow {
+    resolution: Duration,
+    capacity: usize,
+    samples: Mutex<VecDeque<(Instant, BTreeMap<String, f64>)>>,
+}
+
+impl RateWindow {
+    /// Ring holding one sample per `resolution` over the longest window
+    #[must_use]
+    pub fn new(resolution: Duration) -> Self {
+        let longest = WINDOWS[WINDOWS.len() - 1];
+        let resolution = resolution.max(Duration::from_millis(1));
+        let capacity = usize::try_from(longest.as_millis() / resolution.as_millis()).unwrap_or(usize::MAX) + 1;
+        Self {
+            resolution,
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Time between samples
+    pub fn resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    /// Record the totals of `families`, gathered at `now`
+    ///
+    /// A sample arriving sooner than the resolution after the last one
+    /// replaces it, so extra calls cannot push history out of the ring.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the samples' lock.
+    pub fn record(&self, families: &[FamilySnapshot], now: Instant) {
+        let totals = totals(families);
+        let mut samples = self.samples.lock().expect("rates lock poisoned");
+        // The one before the last anchors the replaced sample's interval
+        let replace = samples.len() >= 2
+            && samples
+                .get(samples.len() - 2)
+                .is_some_and(|(at, _)| now.saturating_duration_since(*at) < self.resolution);
+        if replace {
+            samples.pop_back();
+        }
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back((now, totals));
+    }
+
+    /// Forget every sample, so rates start empty again
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the samples' lock.
+    pub fn clear(&self) {
+        self.samples.lock().expect("rates lock poisoned").clear();
+    }
+
+    /// Rates of every recorded family, as of the latest sample
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the samples' lock.
+    pub fn rates(&self) -> BTreeMap<String, Rates> {
+        let samples = self.samples.lock().expect("rates lock poisoned");
+        let Some((latest, totals)) = samples.back() else {
+            return BTreeMap::new();
+        };
+        let [m1, m5, m15] = WINDOWS.map(|window| {
+            let start = latest.checked_sub(window);
+            // The latest sample at or before the window start, or the oldest
+            let first = samples
+                .iter()
+                .rposition(|(at, _)| start.is_some_and(|start| *at <= start))
+                .unwrap_or(0);
+            let elapsed = latest.duration_since(samples[first].0).as_secs_f64();
+            let mut increases: BTreeMap<&str, f64> = BTreeMap::new();
+            for pair in samples.range(first..).collect::<Vec<_>>().windows(2) {
+                let (before, after) = (&pair[0].1, &pair[1].1);
+                for (name, value) in after {
+                    let previous = before.get(name).copied().unwrap_or(0.0);
+                    let increase = if *value >= previous { value - previous } else { *value };
+                    *increases.entry(name.as_str()).or_default() += increase;
+                }
+            }
+            (elapsed, increases)
+        });
+        totals
+            .keys()
+            .map(|name| {
+                let rate = |(elapsed, increases): &(f64, BTreeMap<&str, f64>)| {
+                    (*elapsed > 0.0).then(|| increases.get(name.as_str()).copied().unwrap_or(0.0) / elapsed)
+                };
+                let rates = Rates {
+                    m1: rate(&m1),
+                    m5: rate(&m5),
+                    m15: rate(&m15),
+                };
+                (name.clone(), rates)
+            })
+            .collect()
+    }
+}
+
+/// Sum of every counter and histogram count, by family name
+#[allow(clippy::cast_precision_loss)]
+fn totals(families: &[FamilySnapshot]) -> BTreeMap<String, f64> {
+    families
+        .iter()
+        .filter_map(|family| {
+            let mut counted = false;
+            let mut total = 0.0;
+            for sample in &family.samples {
+                match &sample.value {
+                    SampleValue::Counter(count) => total += *count as f64,
+                    SampleValue::Histogram(histogram) => total += histogram.count as f64,
+                    SampleValue::Gauge(_) => continue,
+                }
+                counted = true;
+            }
+            counted.then(|| (family.descriptor.name.clone(), total))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::registry::Registry;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn approx(value: Option<f64>, expected: f64) -> bool {
+        value.is_some_and(|value| (value - expected).abs() < 1e-9)
+    }
+
+    #[test]
+    fn test_rates_over_windows() {
+        let registry = Registry::new();
+        let requests = registry.counter("ulc_requests_total", "Requests").unwrap();
+        registry.gauge("ulc_queue", "Queue").unwrap();
+        let window = RateWindow::new(SECOND * 10);
+        let start = Instant::now();
+
+        // Nothing to derive from a single sample
+        window.record(&registry.gather(), start);
+        assert_eq!(window.rates()["ulc_requests_total"], Rates::default());
+        assert!(!window.rates().contains_key("ulc_queue"));
+
+        // 1/s for 10 minutes, then 5/s for the last minute
+        for tick in 1..=66 {
+            requests.inc_by(if tick > 60 { 50 } else { 10 });
+            window.record(&registry.gather(), start + SECOND * 10 * tick);
+        }
+        let rates = window.rates()["ulc_requests_total"];
+        assert!(approx(rates.m1, 5.0), "{rates:?}");
+        assert!(approx(rates.m5, (240.0 + 300.0) / 300.0), "{rates:?}");
+        // Only 11 minutes recorded: the 15 minute rate covers those
+        assert!(approx(rates.m15, 900.0 / 660.0), "{rates:?}");
+    }
+
+    #[test]
+    fn test_counter_reset_counts_from_zero() {
+        let family = |count: u64| {
+            let registry = Registry::new();
+            registry.counter("ulc_errors_total", "Errors").unwrap().inc_by(count);
+            registry.gather()
+        };
+        let window = RateWindow::new(SECOND * 10);
+        let start = Instant::now();
+        window.record(&family(1000), start);
+        window.record(&family(1060), start + SECOND * 30);
+        // Back to 6: six new errors, not minus a thousand
+        window.record(&family(6), start + SECOND * 60);
+
+        let rates = window.rates()["ulc_errors_total"];
+        assert!(approx(rates.m1, 66.0 / 60.0), "{rates:?}");
+        assert!(rates.m1.unwrap() >= 0.0);
+    }
+
+    #[test]
+    fn test_restart_starts_empty() {
+        // Rates are not persisted: a new window knows nothing of the old one
+        let registry = Registry::new();
+        registry.counter("ulc_errors_total", "Errors").unwrap().inc_by(500);
+        let window = RateWindow::new(SECOND * 10);
+        window.record(&registry.gather(), Instant::now());
+        assert_eq!(window.rates()["ulc_errors_total"], Rates::default());
+    }
+
+    #[test]
+    fn test_size_is_bounded() {
+        let registry = Registry::new();
+        let requests = registry.counter("ulc_requests_total", "Requests").unwrap();
+        let window = RateWindow::new(SECOND * 10);
+        let start = Instant::now();
+        for tick in 0..1000 {
+            requests.inc();
+            window.record(&registry.gather(), start + SECOND * 10 * tick);
+        }
+        // Calls between ticks replace the latest sample
+        for extra in 1..100 {
+            window.record(&registry.gather(), start + SECOND * 9990 + Duration::from_millis(extra));
+        }
+        assert_eq!(window.samples.lock().unwrap().len(), 91);
+        let m15 = window.rates()["ulc_requests_total"].m15.unwrap();
+        assert!((m15 - 0.1).abs() < 1e-3, "{m15}");
+    }
+}
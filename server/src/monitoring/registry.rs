@@ -0,0 +1,1218 @@
+//! Metric instruments and the registry that owns them
+//!
+//! Instruments follow the Prometheus data model: a metric family has a name,
+//! help text and a fixed set of label names, and holds one [`Counter`],
+//! [`Gauge`] or [`Histogram`] per combination of label values. Handles are
+//! cheap to clone and update with plain atomics, so hot paths resolve their
+//! label values once and keep the handle.
+//!
+//! Names, label names and bucket boundaries are validated at registration,
+//! so a malformed metric fails when the server starts rather than when it is
+//! scraped.
+//!
+//! Each family holds at most [`Registry::series_limit`] label sets. Label
+//! values should come from a bounded set — route patterns, enum names — but
+//! if a bug lets raw input through, further label sets collapse into one
+//! whose every value is [`OVERFLOW_LABEL`] instead of growing without bound.
+//!
+//! Counters and histograms registered with a [`WindowSpec`] also keep a
+//! [`RateRing`] per label set, for their recent rate.
+//!
+//! A family can be disabled at runtime with [`Registry::set_enabled`]: its
+//! counters and histograms stop recording, behind one atomic load in the
+//! handle, and it is left out of [`Registry::gather`]. Gauges keep their
+//! value, so one moved up and down while disabled is right when re-enabled.
+//! [`Registry::set_sampling`] makes a histogram record one observation in
+//! N, counted N times.
+
+use super::window::{RateRing, WindowSnapshot, WindowSpec};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Label sets a family holds by default before overflowing
+pub const DEFAULT_SERIES_LIMIT: usize = 1000;
+
+/// Label value of the set that absorbs label sets past the limit
+pub const OVERFLOW_LABEL: &str = "other";
+
+/// Kind of a metric family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    /// Name used in the Prometheus `# TYPE` line
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// Add to an `f64` stored as bits in an atomic
+fn add_f64(cell: &AtomicU64, delta: f64) {
+    let mut current = cell.load(Ordering::Relaxed);
+    loop {
+        let next = (f64::from_bits(current) + delta).to_bits();
+        match cell.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Recording switch shared by every instrument of a family
+#[derive(Debug)]
+pub struct Control {
+    /// Record one observation in this many; 0 while disabled
+    every: AtomicU32,
+    /// Sampling rate, kept while disabled
+    sampling: AtomicU32,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self {
+            every: AtomicU32::new(1),
+            sampling: AtomicU32::new(1),
+        }
+    }
+}
+
+impl Control {
+    /// Observations recorded per one, or 0 when disabled
+    fn every(&self) -> u32 {
+        self.every.load(Ordering::Relaxed)
+    }
+
+    fn enabled(&self) -> bool {
+        self.every() != 0
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        let every = if enabled { self.sampling.load(Ordering::Relaxed) } else { 0 };
+        self.every.store(every, Ordering::Relaxed);
+    }
+
+    fn set_sampling(&self, every: u32) {
+        self.sampling.store(every, Ordering::Relaxed);
+        if self.enabled() {
+            self.every.store(every, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CounterCore {
+    value: AtomicU64,
+    window: Option<RateRing>,
+    control: Arc<Control>,
+}
+
+/// Monotonically increasing count
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<CounterCore>);
+
+impl Counter {
+    fn with_control(window: Option<WindowSpec>, control: &Arc<Control>) -> Self {
+        Self(Arc::new(CounterCore {
+            value: AtomicU64::new(0),
+            window: window.map(|spec| RateRing::new(spec, Instant::now())),
+            control: Arc::clone(control),
+        }))
+    }
+
+    /// Add one
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    /// Add `n`, unless the counter's family is disabled
+    pub fn inc_by(&self, n: u64) {
+        if !self.0.control.enabled() {
+            return;
+        }
+        self.0.value.fetch_add(n, Ordering::Relaxed);
+        if let Some(window) = &self.0.window {
+            window.add(n, Instant::now());
+        }
+    }
+
+    /// Current value
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.value.load(Ordering::Relaxed)
+    }
+
+    /// Per-second increase over the trailing `window`, if the counter keeps a rate window
+    #[must_use]
+    pub fn rate(&self, window: Duration) -> Option<f64> {
+        self.0.window.as_ref()?.rate(window, Instant::now())
+    }
+}
+
+/// Value that can go up and down
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    /// Set the value
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Add `delta`, which may be negative
+    pub fn add(&self, delta: f64) {
+        add_f64(&self.0, delta);
+    }
+
+    /// Add one
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    /// Subtract one
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    /// Current value
+    #[must_use]
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Upper bounds of histogram buckets
+///
+/// An implicit `+Inf` bucket follows the last bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets(Vec<f64>);
+
+impl Buckets {
+    /// Explicit upper bounds, which must be finite and strictly increasing
+    #[must_use]
+    pub fn new(bounds: Vec<f64>) -> Self {
+        Self(bounds)
+    }
+
+    /// `count` bounds starting at `start`, each `factor` times the previous
+    #[must_use]
+    pub fn exponential(start: f64, factor: f64, count: usize) -> Self {
+        Self(
+            std::iter::successors(Some(start), |bound| Some(bound * factor))
+                .take(count)
+                .collect(),
+        )
+    }
+
+    /// `count` bounds starting at `start`, each `width` above the previous
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn linear(start: f64, width: f64, count: usize) -> Self {
+        Self((0..count).map(|i| start + width * i as f64).collect())
+    }
+
+    /// Durations in seconds, from 1 ms to about a minute
+    #[must_use]
+    pub fn latency() -> Self {
+        Self::exponential(0.001, 2.0, 17)
+    }
+
+    /// Sizes in bytes, from 64 B to 16 MiB
+    #[must_use]
+    pub fn sizes() -> Self {
+        Self::exponential(64.0, 4.0, 10)
+    }
+
+    /// The upper bounds
+    #[must_use]
+    pub fn bounds(&self) -> &[f64] {
+        &self.0
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.0.is_empty() {
+            bail!("Histogram needs at least one bucket");
+        }
+        if self.0.iter().any(|bound| !bound.is_finite()) {
+            bail!("Histogram bucket bounds must be finite");
+        }
+        if self.0.windows(2).any(|pair| pair[0] >= pair[1]) {
+            bail!("Histogram bucket bounds must be strictly increasing");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct HistogramCore {
+    bounds: Arc<[f64]>,
+    /// Per-bucket (not cumulative) counts, with the `+Inf` bucket last
+    buckets: Box<[AtomicU64]>,
+    sum: AtomicU64,
+    count: AtomicU64,
+    /// Observations per interval, when the family keeps a rate window
+    window: Option<RateRing>,
+    control: Arc<Control>,
+    /// Observations offered, for sampling
+    offered: AtomicU64,
+}
+
+/// Distribution of observed values over fixed buckets
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<HistogramCore>);
+
+impl Histogram {
+    #[cfg(test)]
+    fn new(bounds: Arc<[f64]>) -> Self {
+        Self::with_control(bounds, None, &Arc::default())
+    }
+
+    fn with_control(bounds: Arc<[f64]>, window: Option<WindowSpec>, control: &Arc<Control>) -> Self {
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self(Arc::new(HistogramCore {
+            bounds,
+            buckets,
+            sum: AtomicU64::new(0f64.to_bits()),
+            count: AtomicU64::new(0),
+            window: window.map(|spec| RateRing::new(spec, Instant::now())),
+            control: Arc::clone(control),
+            offered: AtomicU64::new(0),
+        }))
+    }
+
+    /// Record one value
+    ///
+    /// Nothing is recorded while the family is disabled. When it samples
+    /// one observation in N, the others are skipped and the recorded one
+    /// counts N times.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn observe(&self, value: f64) {
+        let core = &self.0;
+        let every = core.control.every();
+        if every == 0 {
+            return;
+        }
+        let weight = u64::from(every);
+        if every > 1 && !core.offered.fetch_add(1, Ordering::Relaxed).is_multiple_of(weight) {
+            return;
+        }
+        let bucket = core.bounds.partition_point(|bound| *bound < value);
+        core.buckets[bucket].fetch_add(weight, Ordering::Relaxed);
+        add_f64(&core.sum, value * weight as f64);
+        core.count.fetch_add(weight, Ordering::Relaxed);
+        if let Some(window) = &core.window {
+            window.add(weight, Instant::now());
+        }
+    }
+
+    /// Record a duration in seconds
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// Observations per second over the trailing `window`, if the histogram keeps a rate window
+    #[must_use]
+    pub fn rate(&self, window: Duration) -> Option<f64> {
+        self.0.window.as_ref()?.rate(window, Instant::now())
+    }
+
+    /// Current bucket counts, sum and count
+    #[must_use]
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let core = &self.0;
+        let mut cumulative = 0;
+        let counts = core
+            .buckets
+            .iter()
+            .take(core.bounds.len())
+            .map(|bucket| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+        HistogramSnapshot {
+            bounds: core.bounds.to_vec(),
+            counts,
+            sum: f64::from_bits(core.sum.load(Ordering::Relaxed)),
+            // Read after the buckets so `count` is never below the last bucket
+            count: core.count.load(Ordering::Relaxed).max(cumulative),
+        }
+    }
+}
+
+/// Point-in-time view of a [`Histogram`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    /// Bucket upper bounds
+    pub bounds: Vec<f64>,
+    /// Cumulative count of values at or below each bound
+    pub counts: Vec<u64>,
+    /// Sum of all values
+    pub sum: f64,
+    /// Number of values, including those above the last bound
+    pub count: u64,
+}
+
+impl HistogramSnapshot {
+    /// Mean of the observed values
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Add another snapshot of a histogram with the same buckets
+    pub fn merge(&mut self, other: &HistogramSnapshot) {
+        debug_assert_eq!(self.bounds, other.bounds, "merged histograms must share buckets");
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    /// Estimate the `q` quantile (0.0 to 1.0)
+    ///
+    /// Interpolates linearly within the bucket holding the quantile, as
+    /// Prometheus' `histogram_quantile` does. Values above the last bound
+    /// are reported as the last bound.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * self.count as f64;
+        let Some(bucket) = self.counts.iter().position(|count| *count as f64 >= rank) else {
+            return self.bounds.last().copied();
+        };
+        let (lower, below) = match bucket {
+            0 => (0f64.min(self.bounds[0]), 0),
+            _ => (self.bounds[bucket - 1], self.counts[bucket - 1]),
+        };
+        let in_bucket = self.counts[bucket] - below;
+        if in_bucket == 0 {
+            return Some(self.bounds[bucket]);
+        }
+        let fraction = (rank - below as f64) / in_bucket as f64;
+        Some(lower + (self.bounds[bucket] - lower) * fraction)
+    }
+}
+
+/// Value of one sample in a gathered family
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SampleValue {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(HistogramSnapshot),
+}
+
+/// An instrument that can be held in a [`Family`]
+pub trait Metric: Clone + fmt::Debug + Send + Sync + 'static {
+    /// Kind reported for families of this instrument
+    const KIND: MetricKind;
+
+    /// Construct a fresh instrument for a new label set, recording as `control` allows
+    fn create(buckets: &Arc<[f64]>, window: Option<WindowSpec>, control: &Arc<Control>) -> Self;
+
+    /// Current value
+    fn sample(&self) -> SampleValue;
+
+    /// Ring of recent counts, for instruments keeping one
+    fn rate_ring(&self) -> Option<&RateRing> {
+        None
+    }
+}
+
+impl Metric for Counter {
+    const KIND: MetricKind = MetricKind::Counter;
+
+    fn create(_: &Arc<[f64]>, window: Option<WindowSpec>, control: &Arc<Control>) -> Self {
+        Self::with_control(window, control)
+    }
+
+    fn sample(&self) -> SampleValue {
+        SampleValue::Counter(self.get())
+    }
+
+    fn rate_ring(&self) -> Option<&RateRing> {
+        self.0.window.as_ref()
+    }
+}
+
+impl Metric for Gauge {
+    const KIND: MetricKind = MetricKind::Gauge;
+
+    fn create(_: &Arc<[f64]>, _: Option<WindowSpec>, _: &Arc<Control>) -> Self {
+        Self::default()
+    }
+
+    fn sample(&self) -> SampleValue {
+        SampleValue::Gauge(self.get())
+    }
+}
+
+impl Metric for Histogram {
+    const KIND: MetricKind = MetricKind::Histogram;
+
+    fn create(buckets: &Arc<[f64]>, window: Option<WindowSpec>, control: &Arc<Control>) -> Self {
+        Self::with_control(Arc::clone(buckets), window, control)
+    }
+
+    fn sample(&self) -> SampleValue {
+        SampleValue::Histogram(self.snapshot())
+    }
+
+    fn rate_ring(&self) -> Option<&RateRing> {
+        self.0.window.as_ref()
+    }
+}
+
+/// Name, help text and label names of a metric family
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Descriptor {
+    pub name: String,
+    pub help: String,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug)]
+struct FamilyInner<M> {
+    descriptor: Descriptor,
+    buckets: Arc<[f64]>,
+    window: Option<WindowSpec>,
+    children: RwLock<HashMap<Vec<String>, M>>,
+    /// Label sets held before further ones overflow
+    limit: usize,
+    /// Whether the overflow has been logged
+    overflowed: AtomicBool,
+    control: Arc<Control>,
+}
+
+/// A metric with one instrument per set of label values
+#[derive(Debug, Clone)]
+pub struct Family<M>(Arc<FamilyInner<M>>);
+
+impl<M: Metric> Family<M> {
+    /// The family's name, help text and label names
+    #[must_use]
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.0.descriptor
+    }
+
+    /// Instrument for a set of label values, created on first use
+    ///
+    /// Values are given in the order the label names were registered. Once
+    /// the family holds its limit of label sets, new ones share the overflow
+    /// set, and the first overflow is logged.
+    ///
+    /// # Panics
+    ///
+    /// If the number of values differs from the number of label names.
+    pub fn with_labels(&self, values: &[&str]) -> M {
+        let labels = &self.0.descriptor.labels;
+        assert_eq!(
+            values.len(),
+            labels.len(),
+            "metric {} expects labels {:?}",
+            self.0.descriptor.name,
+            labels
+        );
+        let mut key: Vec<String> = values.iter().map(|v| (*v).to_string()).collect();
+        if let Some(metric) = self.0.children.read().expect("metric lock poisoned").get(&key) {
+            return metric.clone();
+        }
+        let mut children = self.0.children.write().expect("metric lock poisoned");
+        let overflow = vec![OVERFLOW_LABEL.to_string(); labels.len()];
+        let held = children.len() - usize::from(children.contains_key(&overflow));
+        if !children.contains_key(&key) && held >= self.0.limit {
+            if !self.0.overflowed.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Metric {} reached its limit of {} label sets; further ones are reported as {:?}",
+                    self.0.descriptor.name, self.0.limit, OVERFLOW_LABEL
+                );
+            }
+            key = overflow;
+        }
+        children
+            .entry(key)
+            .or_insert_with(|| M::create(&self.0.buckets, self.0.window, &self.0.control))
+            .clone()
+    }
+
+    /// Stop reporting a set of label values; returns whether it existed
+    ///
+    /// Handles already resolved for it keep working but are no longer gathered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the family's lock.
+    #[must_use]
+    pub fn remove(&self, values: &[&str]) -> bool {
+        let key: Vec<String> = values.iter().map(|v| (*v).to_string()).collect();
+        self.0
+            .children
+            .write()
+            .expect("metric lock poisoned")
+            .remove(&key)
+            .is_some()
+    }
+
+    /// Rate window each label set keeps, if any
+    #[must_use]
+    pub fn window(&self) -> Option<WindowSpec> {
+        self.0.window
+    }
+
+    /// Every label set with its instrument
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the family's lock.
+    #[must_use]
+    pub fn children(&self) -> Vec<(Vec<String>, M)> {
+        self.0
+            .children
+            .read()
+            .expect("metric lock poisoned")
+            .iter()
+            .map(|(labels, metric)| (labels.clone(), metric.clone()))
+            .collect()
+    }
+}
+
+/// One sample of a gathered family
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Label values, in the order of the family's label names
+    pub labels: Vec<String>,
+    pub value: SampleValue,
+    /// Recent counts, for series keeping a rate window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<WindowSnapshot>,
+}
+
+/// Every sample of a family at one point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FamilySnapshot {
+    #[serde(flatten)]
+    pub descriptor: Descriptor,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+/// Recording settings of a family
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricControl {
+    pub name: String,
+    pub kind: MetricKind,
+    /// Whether the family records and is gathered
+    pub enabled: bool,
+    /// Observations recorded per one, for histograms; 1 records every one
+    pub sample_every: u32,
+}
+
+/// Type-erased family, for gathering
+trait Collect: Send + Sync {
+    fn collect(&self) -> FamilySnapshot;
+
+    fn reset_windows(&self, now: Instant);
+
+    fn kind(&self) -> MetricKind;
+
+    fn control(&self) -> &Control;
+}
+
+impl<M: Metric> Collect for Family<M> {
+    fn collect(&self) -> FamilySnapshot {
+        let now = Instant::now();
+        let mut samples: Vec<Sample> = self
+            .children()
+            .into_iter()
+            .map(|(labels, metric)| Sample {
+                labels,
+                value: metric.sample(),
+                window: metric.rate_ring().map(|ring| ring.snapshot(now)),
+            })
+            .collect();
+        samples.sort_by(|a, b| a.labels.cmp(&b.labels));
+        FamilySnapshot {
+            descriptor: self.0.descriptor.clone(),
+            kind: M::KIND,
+            samples,
+        }
+    }
+
+    fn reset_windows(&self, now: Instant) {
+        for (_, metric) in self.children() {
+            if let Some(ring) = metric.rate_ring() {
+                ring.reset(now);
+            }
+        }
+    }
+
+    fn kind(&self) -> MetricKind {
+        M::KIND
+    }
+
+    fn control(&self) -> &Control {
+        &self.0.control
+    }
+}
+
+fn valid_name(name: &str, allow_colon: bool) -> bool {
+    let mut chars = name.chars();
+    let valid_start = |c: char| c.is_ascii_alphabetic() || c == '_' || (allow_colon && c == ':');
+    chars.next().is_some_and(valid_start) && chars.all(|c| valid_start(c) || c.is_ascii_digit())
+}
+
+/// Owner of every metric family
+pub struct Registry {
+    families: RwLock<BTreeMap<String, Box<dyn Collect>>>,
+    series_limit: usize,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::with_series_limit(DEFAULT_SERIES_LIMIT)
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.families.read().expect("registry lock poisoned").keys().cloned().collect();
+        f.debug_struct("Registry").field("families", &names).finish_non_exhaustive()
+    }
+}
+
+impl Registry {
+    /// Create an empty registry
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty registry whose families hold at most `limit` label
+    /// sets, plus the overflow set
+    #[must_use]
+    pub fn with_series_limit(limit: usize) -> Self {
+        Self {
+            families: RwLock::default(),
+            series_limit: limit,
+        }
+    }
+
+    /// Label sets each family holds before overflowing
+    pub fn series_limit(&self) -> usize {
+        self.series_limit
+    }
+
+    fn register<M: Metric>(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: Option<Buckets>,
+        window: Option<WindowSpec>,
+    ) -> Result<Family<M>> {
+        if !valid_name(name, true) {
+            bail!("Invalid metric name: {name:?}");
+        }
+        for (i, label) in labels.iter().enumerate() {
+            if !valid_name(label, false) || label.starts_with("__") {
+                bail!("Invalid label name {label:?} on metric {name}");
+            }
+            if labels[..i].contains(label) {
+                bail!("Duplicate label name {label:?} on metric {name}");
+            }
+            if M::KIND == MetricKind::Histogram && *label == "le" {
+                bail!("Label name \"le\" is reserved on histogram {name}");
+            }
+        }
+        if let Some(buckets) = &buckets {
+            buckets.validate()?;
+        }
+        if let Some(window) = &window {
+            window.validate()?;
+        }
+
+        let mut families = self.families.write().expect("registry lock poisoned");
+        if families.contains_key(name) {
+            bail!("Metric already registered: {name}");
+        }
+        let family = Family(Arc::new(FamilyInner {
+            descriptor: Descriptor {
+                name: name.to_string(),
+                help: help.to_string(),
+                labels: labels.iter().map(|l| (*l).to_string()).collect(),
+            },
+            buckets: buckets.map(|b| b.0).unwrap_or_default().into(),
+            window,
+            children: RwLock::new(HashMap::new()),
+            limit: self.series_limit,
+            overflowed: AtomicBool::new(false),
+            control: Arc::default(),
+        }));
+        // Unlabeled metrics are reported from the start, even at zero
+        if labels.is_empty() {
+            family.with_labels(&[]);
+        }
+        families.insert(name.to_string(), Box::new(family.clone()));
+        Ok(family)
+    }
+
+    /// Register an unlabeled counter
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken or not a valid metric name.
+    pub fn counter(&self, name: &str, help: &str) -> Result<Counter> {
+        Ok(self.register::<Counter>(name, help, &[], None, None)?.with_labels(&[]))
+    }
+
+    /// Register a counter family with the given label names
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken, or it or a label is not a valid name.
+    pub fn counter_family(&self, name: &str, help: &str, labels: &[&str]) -> Result<Family<Counter>> {
+        self.register(name, help, labels, None, None)
+    }
+
+    /// Register an unlabeled counter keeping a rate window
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken or not a valid metric name.
+    pub fn windowed_counter(&self, name: &str, help: &str, window: WindowSpec) -> Result<Counter> {
+        Ok(self
+            .register::<Counter>(name, help, &[], None, Some(window))?
+            .with_labels(&[]))
+    }
+
+    /// Register a counter family whose every label set keeps a rate window
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken, or it or a label is not a valid name.
+    pub fn windowed_counter_family(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        window: WindowSpec,
+    ) -> Result<Family<Counter>> {
+        self.register(name, help, labels, None, Some(window))
+    }
+
+    /// Register an unlabeled gauge
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken or not a valid metric name.
+    pub fn gauge(&self, name: &str, help: &str) -> Result<Gauge> {
+        Ok(self.register::<Gauge>(name, help, &[], None, None)?.with_labels(&[]))
+    }
+
+    /// Register a gauge family with the given label names
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken, or it or a label is not a valid name.
+    pub fn gauge_family(&self, name: &str, help: &str, labels: &[&str]) -> Result<Family<Gauge>> {
+        self.register(name, help, labels, None, None)
+    }
+
+    /// Register an unlabeled histogram
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken or not a valid metric name, or `buckets` do
+    /// not ascend.
+    pub fn histogram(&self, name: &str, help: &str, buckets: Buckets) -> Result<Histogram> {
+        Ok(self
+            .register::<Histogram>(name, help, &[], Some(buckets), None)?
+            .with_labels(&[]))
+    }
+
+    /// Register a histogram family with the given label names
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken, it or a label is not a valid name, or
+    /// `buckets` do not ascend.
+    pub fn histogram_family(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: Buckets,
+    ) -> Result<Family<Histogram>> {
+        self.register(name, help, labels, Some(buckets), None)
+    }
+
+    /// Register a histogram family whose every label set keeps a rate window of its observations
+    ///
+    /// # Errors
+    ///
+    /// Fails where `name` is taken, it or a label is not a valid name, or
+    /// `buckets` do not ascend.
+    pub fn windowed_histogram_family(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: Buckets,
+        window: WindowSpec,
+    ) -> Result<Family<Histogram>> {
+        self.register(name, help, labels, Some(buckets), Some(window))
+    }
+
+    /// Empty every rate window, which then counts from now
+    ///
+    /// Counter values are untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn reset_windows(&self) {
+        let now = Instant::now();
+        for family in self.families.read().expect("registry lock poisoned").values() {
+            family.reset_windows(now);
+        }
+    }
+
+    /// Current samples of every enabled family, sorted by name
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn gather(&self) -> Vec<FamilySnapshot> {
+        self.families
+            .read()
+            .expect("registry lock poisoned")
+            .values()
+            .filter(|family| family.control().enabled())
+            .map(|family| family.collect())
+            .collect()
+    }
+
+    /// Start or stop recording and gathering a family
+    ///
+    /// # Errors
+    ///
+    /// Fails where no family is called `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let families = self.families.read().expect("registry lock poisoned");
+        let Some(family) = families.get(name) else {
+            bail!("Unknown metric: {name}");
+        };
+        family.control().set_enabled(enabled);
+        Ok(())
+    }
+
+    /// Record one observation in `every` of a histogram family, counting it `every` times
+    ///
+    /// # Errors
+    ///
+    /// Fails where no histogram family is called `name`, or `every` is zero.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn set_sampling(&self, name: &str, every: u32) -> Result<()> {
+        let families = self.families.read().expect("registry lock poisoned");
+        let Some(family) = families.get(name) else {
+            bail!("Unknown metric: {name}");
+        };
+        if family.kind() != MetricKind::Histogram {
+            bail!("Only histograms can be sampled, and {} is a {}", name, family.kind().as_str());
+        }
+        if every == 0 {
+            bail!("Sampling of {name} must record at least one observation in N");
+        }
+        family.control().set_sampling(every);
+        Ok(())
+    }
+
+    /// Recording settings of every family, sorted by name
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the registry's lock.
+    pub fn controls(&self) -> Vec<MetricControl> {
+        self.families
+            .read()
+            .expect("registry lock poisoned")
+            .iter()
+            .map(|(name, family)| {
+                let control = family.control();
+                MetricControl {
+                    name: name.clone(),
+                    kind: family.kind(),
+                    enabled: control.enabled(),
+                    sample_every: control.sampling.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_validates_names() {
+        let registry = Registry::new();
+        assert!(registry.counter("ulc_requests_total", "ok").is_ok());
+        assert!(registry.counter("ulc_requests_total", "duplicate").is_err());
+        assert!(registry.counter("1starts_with_digit", "").is_err());
+        assert!(registry.counter("has-dash", "").is_err());
+        assert!(registry.counter_family("ulc_a_total", "", &["bad-label"]).is_err());
+        assert!(registry.counter_family("ulc_b_total", "", &["__reserved"]).is_err());
+        assert!(registry.counter_family("ulc_c_total", "", &["x", "x"]).is_err());
+        assert!(registry
+            .histogram_family("ulc_d_seconds", "", &["le"], Buckets::latency())
+            .is_err());
+        assert!(registry
+            .histogram("ulc_e_seconds", "", Buckets::new(vec![1.0, 1.0]))
+            .is_err());
+        assert!(registry.histogram("ulc_f_seconds", "", Buckets::new(vec![])).is_err());
+        assert!(registry
+            .histogram("ulc_g_seconds", "", Buckets::new(vec![1.0, f64::INFINITY]))
+            .is_err());
+        assert_eq!(registry.gather().len(), 1);
+    }
+
+    #[test]
+    fn test_family_label_sets() {
+        let registry = Registry::new();
+        let family = registry.counter_family("ulc_ops_total", "Operations", &["op"]).unwrap();
+        let read = family.with_labels(&["read"]);
+        read.inc();
+        family.with_labels(&["read"]).inc_by(2);
+        family.with_labels(&["write"]).inc();
+        assert_eq!(read.get(), 3);
+
+        let gathered = registry.gather();
+        assert_eq!(gathered[0].kind, MetricKind::Counter);
+        assert_eq!(
+            gathered[0].samples,
+            vec![
+                Sample {
+                    labels: vec!["read".to_string()],
+                    value: SampleValue::Counter(3),
+                    window: None,
+                },
+                Sample {
+                    labels: vec!["write".to_string()],
+                    value: SampleValue::Counter(1),
+                    window: None,
+                },
+            ]
+        );
+
+        assert!(family.remove(&["write"]));
+        assert_eq!(registry.gather()[0].samples.len(), 1);
+    }
+
+    #[test]
+    fn test_series_limit_overflows_to_other() {
+        let registry = Registry::with_series_limit(3);
+        let family = registry.counter_family("ulc_paths_total", "Paths", &["path", "method"]).unwrap();
+        for i in 0..50 {
+            family.with_labels(&[&format!("/docs/{i}"), "GET"]).inc();
+        }
+
+        let children = family.children();
+        assert_eq!(children.len(), 4);
+        let overflow = children
+            .iter()
+            .find(|(labels, _)| labels == &["other", "other"])
+            .map(|(_, counter)| counter.get());
+        assert_eq!(overflow, Some(47));
+
+        // Label sets seen before the limit keep their own series
+        family.with_labels(&["/docs/0", "GET"]).inc();
+        assert_eq!(family.with_labels(&["/docs/0", "GET"]).get(), 2);
+
+        // Removing a set makes room again
+        assert!(family.remove(&["/docs/1", "GET"]));
+        family.with_labels(&["/docs/new", "GET"]).inc();
+        assert_eq!(family.with_labels(&["/docs/new", "GET"]).get(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "expects labels")]
+    fn test_wrong_label_count_panics() {
+        let registry = Registry::new();
+        let family = registry.gauge_family("ulc_g", "", &["a", "b"]).unwrap();
+        family.with_labels(&["only_one"]);
+    }
+
+    #[test]
+    fn test_gauge_moves_both_ways() {
+        let gauge = Registry::new().gauge("ulc_open", "").unwrap();
+        gauge.inc();
+        gauge.inc();
+        gauge.dec();
+        gauge.add(0.5);
+        assert!((gauge.get() - 1.5).abs() < f64::EPSILON);
+        gauge.set(7.0);
+        assert!((gauge.get() - 7.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_histogram_buckets_and_quantiles() {
+        let histogram = Registry::new()
+            .histogram("ulc_latency_seconds", "", Buckets::new(vec![1.0, 2.0, 4.0]))
+            .unwrap();
+        for value in [0.5, 1.0, 1.5, 3.0, 10.0] {
+            histogram.observe(value);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.counts, vec![2, 3, 4]);
+        assert_eq!(snapshot.count, 5);
+        assert!((snapshot.sum - 16.0).abs() < f64::EPSILON);
+        assert!((snapshot.mean().unwrap() - 3.2).abs() < f64::EPSILON);
+
+        // The median (rank 2.5) falls halfway through the (1, 2] bucket
+        assert!((snapshot.quantile(0.5).unwrap() - 1.5).abs() < 1e-9);
+        // Values past the last bound report the last bound
+        assert!((snapshot.quantile(0.99).unwrap() - 4.0).abs() < 1e-9);
+        assert!(Histogram::new(Arc::from(vec![1.0])).snapshot().quantile(0.5).is_none());
+    }
+
+    #[test]
+    fn test_windowed_instruments() {
+        let registry = Registry::new();
+        let plain = registry.counter("ulc_plain_total", "").unwrap();
+        let windowed = registry
+            .windowed_counter_family("ulc_windowed_total", "", &["op"], WindowSpec::MINUTE)
+            .unwrap();
+        let requests = registry
+            .windowed_histogram_family("ulc_requests_seconds", "", &[], Buckets::latency(), WindowSpec::MINUTE)
+            .unwrap();
+        assert!(registry
+            .windowed_counter("ulc_bad_total", "", WindowSpec::new(Duration::from_secs(1), 0))
+            .is_err());
+
+        plain.inc();
+        windowed.with_labels(&["read"]).inc_by(5);
+        requests.with_labels(&[]).observe(0.1);
+        assert_eq!(plain.rate(Duration::from_mins(1)), None);
+        assert!(windowed.with_labels(&["read"]).rate(Duration::from_mins(1)).unwrap() > 0.0);
+        assert!(requests.with_labels(&[]).rate(Duration::from_mins(1)).unwrap() > 0.0);
+
+        let gathered = registry.gather();
+        let window = |name: &str| gathered.iter().find(|f| f.descriptor.name == name).unwrap().samples[0].window.clone();
+        assert_eq!(window("ulc_plain_total"), None);
+        assert_eq!(window("ulc_windowed_total").unwrap().counts.iter().sum::<u64>(), 5);
+        assert_eq!(window("ulc_requests_seconds").unwrap().counts.iter().sum::<u64>(), 1);
+
+        // Resetting empties the windows but keeps the values
+        registry.reset_windows();
+        let read = windowed.with_labels(&["read"]);
+        assert!(read.rate(Duration::from_mins(1)).unwrap_or(0.0).abs() < f64::EPSILON);
+        assert_eq!(read.get(), 5);
+    }
+
+    #[test]
+    fn test_disabled_families_skip_recording_and_gathering() {
+        let registry = Registry::new();
+        let requests = registry.counter("ulc_requests_total", "").unwrap();
+        let latency = registry
+            .windowed_histogram_family("ulc_latency_seconds", "", &["op"], Buckets::latency(), WindowSpec::MINUTE)
+            .unwrap();
+        let open = registry.gauge("ulc_open", "").unwrap();
+        let read = latency.with_labels(&["read"]);
+
+        registry.set_enabled("ulc_requests_total", false).unwrap();
+        registry.set_enabled("ulc_latency_seconds", false).unwrap();
+        registry.set_enabled("ulc_open", false).unwrap();
+        requests.inc();
+        read.observe(0.1);
+        // Label sets resolved while disabled share the switch
+        latency.with_labels(&["write"]).observe(0.1);
+        open.inc();
+        assert_eq!(requests.get(), 0);
+        assert_eq!(read.snapshot().count, 0);
+        assert!(read.rate(Duration::from_mins(1)).unwrap_or(0.0).abs() < f64::EPSILON);
+        assert!(registry.gather().is_empty());
+        assert!(registry.set_enabled("ulc_missing", false).is_err());
+
+        // Gauges kept moving, so they are right once re-enabled
+        registry.set_enabled("ulc_open", true).unwrap();
+        open.dec();
+        assert!(open.get().abs() < f64::EPSILON);
+        registry.set_enabled("ulc_requests_total", true).unwrap();
+        requests.inc();
+        assert_eq!(requests.get(), 1);
+        let names: Vec<_> = registry.gather().into_iter().map(|f| f.descriptor.name).collect();
+        assert_eq!(names, ["ulc_open", "ulc_requests_total"]);
+    }
+
+    #[test]
+    fn test_sampled_histogram_scales_counts() {
+        let registry = Registry::new();
+        let histogram = registry
+            .histogram("ulc_latency_seconds", "", Buckets::new(vec![1.0, 2.0]))
+            .unwrap();
+        assert!(registry.set_sampling("ulc_latency_seconds", 0).is_err());
+        registry.counter("ulc_requests_total", "").unwrap();
+        assert!(registry.set_sampling("ulc_requests_total", 10).is_err());
+
+        registry.set_sampling("ulc_latency_seconds", 10).unwrap();
+        for i in 0..1000 {
+            histogram.observe(if i % 4 == 0 { 1.5 } else { 0.5 });
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 1000);
+        // One in ten recorded and counted ten times; half of those recorded are 1.5
+        assert_eq!(snapshot.counts, vec![500, 1000]);
+        assert!((snapshot.sum - 1000.0).abs() < 1e-9);
+
+        // Sampling survives disabling and re-enabling
+        registry.set_enabled("ulc_latency_seconds", false).unwrap();
+        histogram.observe(0.5);
+        registry.set_enabled("ulc_latency_seconds", true).unwrap();
+        let control = registry.controls().into_iter().find(|c| c.name == "ulc_latency_seconds").unwrap();
+        assert_eq!((control.enabled, control.sample_every), (true, 10));
+        registry.set_sampling("ulc_latency_seconds", 1).unwrap();
+        histogram.observe(0.5);
+        assert_eq!(histogram.snapshot().count, 1001);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_default_buckets() {
+        let latency = Buckets::latency();
+        assert!((latency.bounds()[0] - 0.001).abs() < f64::EPSILON);
+        assert!(*latency.bounds().last().unwrap() >= 60.0);
+        assert!(latency.validate().is_ok());
+        assert_eq!(*Buckets::sizes().bounds().last().unwrap(), 16.0 * 1024.0 * 1024.0);
+        assert_eq!(Buckets::linear(0.0, 10.0, 3).bounds(), &[0.0, 10.0, 20.0]);
+    }
+}
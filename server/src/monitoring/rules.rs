@@ -0,0 +1,964 @@
+//! Threshold rules over metrics
+//!
+//! A [`Rule`] watches one value computed from the registry over a trailing
+//! window: a counter's rate, the ratio of two counters' increases, a
+//! histogram quantile or a gauge aggregate. The [`RuleEngine`] evaluates
+//! every rule on an interval from gathered snapshots, keeping the samples
+//! each window needs.
+//!
+//! A rule fires after `for_evaluations` consecutive breaches and resolves
+//! after `resolve_after` consecutive evaluations past `resolve_threshold`,
+//! so a value hovering at the threshold does not flap. Firing rules report
+//! the `rules` health check degraded, and every transition is logged and
+//! passed to the engine's observers, such as [`webhook`].
+//!
+//! A rate over series that keep a rate window at least as long as the
+//! rule's is read from the windows, so it is current to the window's
+//! interval from the first evaluation.
+
+use super::alerts::redact_url;
+use super::health::{CheckResult, HealthCheck};
+use super::registry::{FamilySnapshot, HistogramSnapshot, Sample, SampleValue};
+use super::Metrics;
+use crate::jobs::{self, JobQueue};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Series of one metric, narrowed by label values
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Selector {
+    pub metric: String,
+    /// Label values a series must have; other labels are summed over
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// How a gauge's samples over the window are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Last,
+}
+
+/// Value a rule compares against its threshold
+///
+/// Counters and histogram counts are read as counters, so a counter that
+/// goes down, as after a restart, counts from zero again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Query {
+    /// Per-second increase of the selected counters
+    Rate {
+        #[serde(flatten)]
+        selector: Selector,
+    },
+    /// Increase of `numerator` divided by the increase of `denominator`
+    Ratio { numerator: Selector, denominator: Selector },
+    /// Quantile of the histogram values observed within the window
+    Quantile {
+        #[serde(flatten)]
+        selector: Selector,
+        quantile: f64,
+    },
+    /// Sum of the selected gauges, aggregated over the window
+    Gauge {
+        #[serde(flatten)]
+        selector: Selector,
+        aggregation: Aggregation,
+    },
+}
+
+impl Query {
+    fn selectors(&self) -> Vec<&Selector> {
+        match self {
+            Self::Rate { selector } | Self::Quantile { selector, .. } | Self::Gauge { selector, .. } => vec![selector],
+            Self::Ratio { numerator, denominator } => vec![numerator, denominator],
+        }
+    }
+}
+
+/// Direction in which a value breaches the threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+impl Comparison {
+    fn beyond(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Above => value > threshold,
+            Self::Below => value < threshold,
+        }
+    }
+}
+
+/// What an evaluation without data counts as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoData {
+    /// Leave the rule as it is
+    #[default]
+    Keep,
+    /// Count as within the threshold
+    Ok,
+    /// Count as a breach
+    Alerting,
+}
+
+/// How urgent a rule's alerts are, least first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// A threshold on a value computed from metrics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Name in logs, alerts and the health report
+    pub name: String,
+    pub query: Query,
+    /// Trailing window the value is computed over
+    pub window_secs: u64,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// Value the rule must get back past before resolving; defaults to `threshold`
+    #[serde(default)]
+    pub resolve_threshold: Option<f64>,
+    /// Consecutive breaching evaluations before firing
+    #[serde(default = "one")]
+    pub for_evaluations: u32,
+    /// Consecutive clear evaluations before resolving
+    #[serde(default = "one")]
+    pub resolve_after: u32,
+    #[serde(default)]
+    pub no_data: NoData,
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+fn one() -> u32 {
+    1
+}
+
+impl Rule {
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            bail!("rule without a name");
+        }
+        if self.window_secs == 0 {
+            bail!("rule {}: window_secs must be positive", self.name);
+        }
+        if self.query.selectors().iter().any(|selector| selector.metric.is_empty()) {
+            bail!("rule {}: metric name is empty", self.name);
+        }
+        if let Query::Quantile { quantile, .. } = self.query {
+            if !(0.0..=1.0).contains(&quantile) {
+                bail!("rule {}: quantile {} is not between 0 and 1", self.name, quantile);
+            }
+        }
+        if let Some(resolve) = self.resolve_threshold {
+            if self.comparison.beyond(resolve, self.threshold) {
+                bail!("rule {}: resolve_threshold is past threshold", self.name);
+            }
+        }
+        if self.for_evaluations == 0 || self.resolve_after == 0 {
+            bail!("rule {}: for_evaluations and resolve_after must be positive", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Read rules from a YAML or JSON file holding a list of [`Rule`]s
+///
+/// # Errors
+///
+/// Fails where the file cannot be read or parsed, or as [`validate`] does.
+pub fn load(path: &Path) -> Result<Vec<Rule>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading rules from {}", path.display()))?;
+    let rules: Vec<Rule> = serde_yaml::from_str(&text).with_context(|| format!("parsing rules in {}", path.display()))?;
+    validate(&rules)?;
+    Ok(rules)
+}
+
+/// Check every rule and that names are unique
+///
+/// # Errors
+///
+/// Fails on the first rule that is not valid, or a name given twice.
+pub fn validate(rules: &[Rule]) -> Result<()> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        rule.validate()?;
+        if !names.insert(rule.name.as_str()) {
+            bail!("rule {} is defined twice", rule.name);
+        }
+    }
+    Ok(())
+}
+
+/// Where a rule stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    /// Within the threshold
+    Ok,
+    /// Breaching, but not for long enough to fire
+    Pending,
+    Firing,
+}
+
+/// A rule starting or stopping firing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    /// `Firing`, or `Ok` once resolved
+    pub state: AlertState,
+    /// Value at the transition; `None` when there was no data
+    pub value: Option<f64>,
+    pub threshold: f64,
+    pub severity: Severity,
+    pub at: DateTime<Utc>,
+}
+
+/// A rule's latest evaluation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleStatus {
+    pub rule: String,
+    pub state: AlertState,
+    pub value: Option<f64>,
+    pub threshold: f64,
+    pub severity: Severity,
+}
+
+/// Selected series of one sample, by label values
+type Series = HashMap<Vec<String>, SampleValue>;
+
+/// A rule with the samples its window needs
+struct Tracked {
+    rule: Rule,
+    /// Oldest first; the first is the latest at or before the window start
+    history: VecDeque<(Instant, Vec<Series>)>,
+    /// Rate read from the selected series' rate windows, when they keep them
+    windowed: Option<f64>,
+    state: AlertState,
+    value: Option<f64>,
+    /// Consecutive breaching evaluations, or clear ones while firing
+    streak: u32,
+}
+
+impl Tracked {
+    fn record(&mut self, families: &[FamilySnapshot], now: Instant) {
+        let sample = self.rule.query.selectors().into_iter().map(|s| select(families, s)).collect();
+        self.history.push_back((now, sample));
+        self.windowed = match &self.rule.query {
+            Query::Rate { selector } => windowed_rate(families, selector, self.rule.window()),
+            _ => None,
+        };
+        let start = now.checked_sub(self.rule.window());
+        while let (Some(start), Some((second, _))) = (start, self.history.get(1)) {
+            if *second > start {
+                break;
+            }
+            self.history.pop_front();
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn compute(&self) -> Option<f64> {
+        let (first, _) = self.history.front()?;
+        let (last, latest) = self.history.back()?;
+        let elapsed = last.duration_since(*first).as_secs_f64();
+        let samples = || self.history.iter().map(|(_, sample)| sample);
+        match &self.rule.query {
+            Query::Rate { .. } => self
+                .windowed
+                .or_else(|| (elapsed > 0.0).then(|| increase(samples().map(|s| &s[0])) / elapsed)),
+            Query::Ratio { .. } => {
+                let denominator = increase(samples().map(|s| &s[1]));
+                (elapsed > 0.0 && denominator > 0.0).then(|| increase(samples().map(|s| &s[0])) / denominator)
+            }
+            Query::Quantile { quantile, .. } => {
+                let observed = histogram_increase(&self.history.front()?.1[0], &latest[0])?;
+                observed.quantile(*quantile)
+            }
+            Query::Gauge { aggregation, .. } => {
+                // Gauges have no use for the sample before the window
+                let start = last.checked_sub(self.rule.window());
+                let sums: Vec<f64> = self
+                    .history
+                    .iter()
+                    .filter(|(at, _)| start.is_none_or(|start| *at >= start))
+                    .filter_map(|(_, sample)| gauge_sum(&sample[0]))
+                    .collect();
+                match aggregation {
+                    Aggregation::Avg => (!sums.is_empty()).then(|| sums.iter().sum::<f64>() / sums.len() as f64),
+                    Aggregation::Min => sums.iter().copied().reduce(f64::min),
+                    Aggregation::Max => sums.iter().copied().reduce(f64::max),
+                    Aggregation::Last => gauge_sum(&latest[0]),
+                }
+            }
+        }
+    }
+
+    /// Move the rule on by one evaluation; returns whether it fired or resolved
+    fn step(&mut self, value: Option<f64>) -> Option<AlertState> {
+        self.value = value;
+        let rule = &self.rule;
+        let resolve_threshold = rule.resolve_threshold.unwrap_or(rule.threshold);
+        let breaching = match (value, rule.no_data) {
+            (Some(value), _) if self.state == AlertState::Firing => rule.comparison.beyond(value, resolve_threshold),
+            (Some(value), _) => rule.comparison.beyond(value, rule.threshold),
+            (None, NoData::Keep) => return None,
+            (None, NoData::Ok) => false,
+            (None, NoData::Alerting) => true,
+        };
+
+        match (self.state, breaching) {
+            (AlertState::Firing, true) | (AlertState::Ok, false) => {
+                self.streak = 0;
+                None
+            }
+            (AlertState::Pending, false) => {
+                self.state = AlertState::Ok;
+                self.streak = 0;
+                None
+            }
+            (AlertState::Ok | AlertState::Pending, true) => {
+                self.streak += 1;
+                if self.streak >= rule.for_evaluations {
+                    self.state = AlertState::Firing;
+                    self.streak = 0;
+                    Some(AlertState::Firing)
+                } else {
+                    self.state = AlertState::Pending;
+                    None
+                }
+            }
+            (AlertState::Firing, false) => {
+                self.streak += 1;
+                if self.streak >= rule.resolve_after {
+                    self.state = AlertState::Ok;
+                    self.streak = 0;
+                    Some(AlertState::Ok)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> RuleStatus {
+        RuleStatus {
+            rule: self.rule.name.clone(),
+            state: self.state,
+            value: self.value,
+            threshold: self.rule.threshold,
+            severity: self.rule.severity,
+        }
+    }
+}
+
+/// Samples of `selector`'s metric
+fn matching<'a>(families: &'a [FamilySnapshot], selector: &'a Selector) -> Vec<&'a Sample> {
+    let Some(family) = families.iter().find(|f| f.descriptor.name == selector.metric) else {
+        return Vec::new();
+    };
+    let names = &family.descriptor.labels;
+    if selector.labels.keys().any(|name| !names.contains(name)) {
+        return Vec::new();
+    }
+    family
+        .samples
+        .iter()
+        .filter(|sample| {
+            names
+                .iter()
+                .zip(&sample.labels)
+                .all(|(name, value)| selector.labels.get(name).is_none_or(|wanted| wanted == value))
+        })
+        .collect()
+}
+
+/// Series of `selector`'s metric with its label values
+fn select(families: &[FamilySnapshot], selector: &Selector) -> Series {
+    matching(families, selector)
+        .into_iter()
+        .map(|sample| (sample.labels.clone(), sample.value.clone()))
+        .collect()
+}
+
+/// Rate of `selector`'s series read from their rate windows
+///
+/// `None` unless every selected series keeps a window spanning `window`.
+fn windowed_rate(families: &[FamilySnapshot], selector: &Selector, window: Duration) -> Option<f64> {
+    let samples = matching(families, selector);
+    if samples.is_empty() {
+        return None;
+    }
+    samples
+        .into_iter()
+        .map(|sample| sample.window.as_ref().filter(|ring| ring.covers(window))?.rate(window))
+        .sum()
+}
+
+/// Running total of a counter-like sample
+#[allow(clippy::cast_precision_loss)]
+fn count(value: &SampleValue) -> Option<f64> {
+    match value {
+        SampleValue::Counter(count) => Some(*count as f64),
+        SampleValue::Histogram(histogram) => Some(histogram.count as f64),
+        SampleValue::Gauge(_) => None,
+    }
+}
+
+/// Increase of every series across consecutive samples
+///
+/// A series that goes down was reset, so its new value is all increase; a
+/// series that appears counts from zero.
+fn increase<'a>(samples: impl Iterator<Item = &'a Series>) -> f64 {
+    let mut total = 0.0;
+    let mut previous: Option<&Series> = None;
+    for sample in samples {
+        if let Some(previous) = previous {
+            for (labels, value) in sample {
+                let Some(current) = count(value) else {
+                    continue;
+                };
+                let before = previous.get(labels).and_then(count).unwrap_or(0.0);
+                total += if current >= before { current - before } else { current };
+            }
+        }
+        previous = Some(sample);
+    }
+    total
+}
+
+/// Histogram of the values observed between two samples, merged across series
+fn histogram_increase(first: &Series, last: &Series) -> Option<HistogramSnapshot> {
+    let mut merged: Option<HistogramSnapshot> = None;
+    for (labels, value) in last {
+        let SampleValue::Histogram(current) = value else {
+            continue;
+        };
+        let delta = match first.get(labels) {
+            Some(SampleValue::Histogram(before)) if before.bounds == current.bounds && before.count <= current.count => {
+                HistogramSnapshot {
+                    bounds: current.bounds.clone(),
+                    counts: current.counts.iter().zip(&before.counts).map(|(now, then)| now.saturating_sub(*then)).collect(),
+                    sum: current.sum - before.sum,
+                    count: current.count - before.count,
+                }
+            }
+            // Reset or new series: everything it holds is new
+            _ => current.clone(),
+        };
+        match &mut merged {
+            Some(merged) if merged.bounds == delta.bounds => merged.merge(&delta),
+            Some(_) => {}
+            None => merged = Some(delta),
+        }
+    }
+    merged.filter(|histogram| histogram.count > 0)
+}
+
+/// Sum of the gauge series in a sample, or `None` without any
+fn gauge_sum(sample: &Series) -> Option<f64> {
+    let values: Vec<f64> = sample
+        .values()
+        .filter_map(|value| match value {
+            SampleValue::Gauge(value) => Some(*value),
+            _ => None,
+        })
+        .collect();
+    (!values.is_empty()).then(|| values.iter().sum())
+}
+
+/// Listener invoked for every alert transition
+type AlertObserver = Box<dyn Fn(&AlertEvent) + Send + Sync>;
+
+/// Listener invoked after every evaluation with its transitions and the rules' statuses
+type EvaluationObserver = Box<dyn Fn(&[AlertEvent], &[RuleStatus]) + Send + Sync>;
+
+/// Evaluates rules and tracks which are firing
+pub struct RuleEngine {
+    rules: Mutex<Vec<Tracked>>,
+    observers: RwLock<Vec<AlertObserver>>,
+    evaluation_observers: RwLock<Vec<EvaluationObserver>>,
+}
+
+impl RuleEngine {
+    /// Engine for rules already checked with [`validate`]
+    #[must_use]
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|rule| Tracked {
+                rule,
+                history: VecDeque::new(),
+                windowed: None,
+                state: AlertState::Ok,
+                value: None,
+                streak: 0,
+            })
+            .collect();
+        Self {
+            rules: Mutex::new(rules),
+            observers: RwLock::new(Vec::new()),
+            evaluation_observers: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Register a listener called when a rule fires or resolves
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the observer list.
+    pub fn observe(&self, observer: impl Fn(&AlertEvent) + Send + Sync + 'static) {
+        self.observers
+            .write()
+            .expect("observers lock poisoned")
+            .push(Box::new(observer));
+    }
+
+    /// Register a listener called after every evaluation, with the
+    /// transitions it caused, which may be none, and every rule's status
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the observer list.
+    pub fn observe_evaluations(&self, observer: impl Fn(&[AlertEvent], &[RuleStatus]) + Send + Sync + 'static) {
+        self.evaluation_observers
+            .write()
+            .expect("observers lock poisoned")
+            .push(Box::new(observer));
+    }
+
+    /// Evaluate every rule against `families`, gathered now
+    pub fn evaluate(&self, families: &[FamilySnapshot]) -> Vec<AlertEvent> {
+        self.evaluate_at(families, Instant::now())
+    }
+
+    /// Evaluate every rule against `families`, gathered at `now`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the rules' lock.
+    pub fn evaluate_at(&self, families: &[FamilySnapshot], now: Instant) -> Vec<AlertEvent> {
+        let events: Vec<AlertEvent> = {
+            let mut rules = self.rules.lock().expect("rules lock poisoned");
+            rules
+                .iter_mut()
+                .filter_map(|tracked| {
+                    tracked.record(families, now);
+                    let value = tracked.compute();
+                    tracked.step(value).map(|state| AlertEvent {
+                        rule: tracked.rule.name.clone(),
+                        state,
+                        value,
+                        threshold: tracked.rule.threshold,
+                        severity: tracked.rule.severity,
+                        at: Utc::now(),
+                    })
+                })
+                .collect()
+        };
+        for event in &events {
+            let value = event.value.map_or_else(|| "no data".to_string(), |v| v.to_string());
+            if event.state == AlertState::Firing {
+                warn!(rule = %event.rule, value = %value, threshold = event.threshold, "Alert firing");
+            } else { info!(rule = %event.rule, value = %value, threshold = event.threshold, "Alert resolved") }
+            for observer in self.observers.read().expect("observers lock poisoned").iter() {
+                observer(event);
+            }
+        }
+        let observers = self.evaluation_observers.read().expect("observers lock poisoned");
+        if !observers.is_empty() {
+            let statuses = self.statuses();
+            for observer in observers.iter() {
+                observer(&events, &statuses);
+            }
+        }
+        events
+    }
+
+    /// Evaluate every `interval` from `metrics`
+    pub async fn run(self: Arc<Self>, metrics: Arc<Metrics>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.evaluate(&metrics.gather());
+        }
+    }
+
+    /// Latest evaluation of every rule
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the rules' lock.
+    pub fn statuses(&self) -> Vec<RuleStatus> {
+        self.rules.lock().expect("rules lock poisoned").iter().map(Tracked::status).collect()
+    }
+
+    /// Names of the rules currently firing
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the rules' lock.
+    pub fn firing(&self) -> Vec<String> {
+        self.rules
+            .lock()
+            .expect("rules lock poisoned")
+            .iter()
+            .filter(|tracked| tracked.state == AlertState::Firing)
+            .map(|tracked| tracked.rule.name.clone())
+            .collect()
+    }
+}
+
+/// Reports degraded while any rule is firing
+pub struct RulesCheck {
+    engine: Arc<RuleEngine>,
+}
+
+impl RulesCheck {
+    pub fn new(engine: Arc<RuleEngine>) -> Self {
+        Self { engine }
+    }
+}
+
+impl HealthCheck for RulesCheck {
+    fn name(&self) -> &'static str {
+        "rules"
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckResult> {
+        Box::pin(async move {
+            let firing = self.engine.firing();
+            if firing.is_empty() {
+                CheckResult::healthy()
+            } else {
+                CheckResult::degraded(format!("Firing: {}", firing.join(", ")))
+            }
+        })
+    }
+}
+
+/// Alert observer that POSTs each [`AlertEvent`] as JSON to `url`
+///
+/// Deliveries run as [`jobs::WEBHOOK`] jobs and are not retried; a failure,
+/// or a full queue, is logged. Events outside a Tokio runtime are not
+/// delivered.
+pub fn webhook(url: String, jobs: Arc<JobQueue>) -> impl Fn(&AlertEvent) + Send + Sync + 'static {
+    let client = reqwest::Client::new();
+    move |event| {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        let request = client.post(&url).json(event);
+        let url = redact_url(&url);
+        let target = url.clone();
+        let submitted = jobs.submit(jobs::WEBHOOK, move |_| async move {
+            match request.send().await.and_then(reqwest::Response::error_for_status) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    let e = e.without_url();
+                    warn!("Alert webhook to {} failed: {}", target, e);
+                    Err(e.into())
+                }
+            }
+        });
+        if let Err(e) = submitted {
+            warn!("Alert webhook to {} not delivered: {}", url, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::registry::{Descriptor, MetricKind};
+    use crate::monitoring::window::WindowSnapshot;
+    use crate::monitoring::ServiceStatus;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn family(name: &str, labels: &[&str], kind: MetricKind, samples: Vec<(Vec<&str>, SampleValue)>) -> FamilySnapshot {
+        FamilySnapshot {
+            descriptor: Descriptor {
+                name: name.to_string(),
+                help: String::new(),
+                labels: labels.iter().map(|l| (*l).to_string()).collect(),
+            },
+            kind,
+            samples: samples
+                .into_iter()
+                .map(|(labels, value)| Sample {
+                    labels: labels.into_iter().map(str::to_string).collect(),
+                    value,
+                    window: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Requests by status class
+    fn requests(ok: u64, errors: u64) -> Vec<FamilySnapshot> {
+        vec![family(
+            "ulc_requests_total",
+            &["status"],
+            MetricKind::Counter,
+            vec![
+                (vec!["2xx"], SampleValue::Counter(ok)),
+                (vec!["5xx"], SampleValue::Counter(errors)),
+            ],
+        )]
+    }
+
+    fn error_ratio_rule() -> Rule {
+        serde_yaml::from_str(
+            r"
+name: http_5xx_ratio
+query:
+  kind: ratio
+  numerator: { metric: ulc_requests_total, labels: { status: 5xx } }
+  denominator: { metric: ulc_requests_total }
+window_secs: 300
+comparison: above
+threshold: 0.05
+resolve_threshold: 0.02
+for_evaluations: 2
+",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ratio_fires_and_resolves_with_hysteresis() {
+        let engine = RuleEngine::new(vec![error_ratio_rule()]);
+        let start = Instant::now();
+        let at = |secs: u64| start + SECOND * u32::try_from(secs).unwrap();
+        let states = |engine: &RuleEngine| engine.statuses()[0].state;
+
+        // One sample is not a window
+        assert!(engine.evaluate_at(&requests(0, 0), at(0)).is_empty());
+        assert_eq!(engine.statuses()[0].value, None);
+
+        // 10% errors: pending, then firing on the second breach
+        assert!(engine.evaluate_at(&requests(90, 10), at(60)).is_empty());
+        assert_eq!(states(&engine), AlertState::Pending);
+        let fired = engine.evaluate_at(&requests(180, 20), at(120));
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].state, AlertState::Firing);
+        assert!((fired[0].value.unwrap() - 0.1).abs() < 1e-9);
+        assert!((fired[0].threshold - 0.05).abs() < f64::EPSILON);
+
+        // Falling below the threshold but above the resolve threshold keeps firing
+        assert!(engine.evaluate_at(&requests(1180, 50), at(180)).is_empty());
+        let ratio = engine.statuses()[0].value.unwrap();
+        assert!(ratio > 0.02 && ratio < 0.05, "{ratio}");
+        assert_eq!(states(&engine), AlertState::Firing);
+
+        // Once the bad minutes leave the window, the ratio drops and it resolves
+        let resolved = engine.evaluate_at(&requests(10_000, 50), at(600));
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].state, AlertState::Ok);
+        assert_eq!(resolved[0].value, Some(0.0));
+        assert!(engine.firing().is_empty());
+    }
+
+    #[test]
+    fn test_single_breach_does_not_fire() {
+        let engine = RuleEngine::new(vec![error_ratio_rule()]);
+        let start = Instant::now();
+        engine.evaluate_at(&requests(0, 0), start);
+        engine.evaluate_at(&requests(90, 10), start + SECOND * 60);
+        assert_eq!(engine.statuses()[0].state, AlertState::Pending);
+
+        // A clean minute clears the pending breach
+        engine.evaluate_at(&requests(100_000, 10), start + SECOND * 120);
+        assert_eq!(engine.statuses()[0].state, AlertState::Ok);
+        assert!(engine.firing().is_empty());
+    }
+
+    #[test]
+    fn test_counter_reset_counts_from_zero() {
+        let rule: Rule = serde_yaml::from_str(
+            "{ name: error_rate, query: { kind: rate, metric: ulc_requests_total, labels: { status: 5xx } }, \
+             window_secs: 60, comparison: above, threshold: 1.0 }",
+        )
+        .unwrap();
+        let engine = RuleEngine::new(vec![rule]);
+        let start = Instant::now();
+        engine.evaluate_at(&requests(0, 500), start);
+        engine.evaluate_at(&requests(0, 520), start + SECOND * 10);
+        // The process restarted: 5 errors since, not -515
+        engine.evaluate_at(&requests(0, 5), start + SECOND * 20);
+        let rate = engine.statuses()[0].value.unwrap();
+        assert!((rate - 25.0 / 20.0).abs() < 1e-9, "{rate}");
+        assert_eq!(engine.statuses()[0].state, AlertState::Firing);
+    }
+
+    #[test]
+    fn test_rate_read_from_windows() {
+        let rule: Rule = serde_yaml::from_str(
+            "{ name: error_rate, query: { kind: rate, metric: ulc_requests_total, labels: { status: 5xx } }, \
+             window_secs: 10, comparison: above, threshold: 1.0 }",
+        )
+        .unwrap();
+        let windowed = |counts: Vec<u64>| {
+            let mut families = requests(0, 1000);
+            families[0].samples[1].window = Some(WindowSnapshot {
+                interval_secs: 1.0,
+                counts,
+                current_secs: 0.5,
+                counted_secs: 3600.0,
+            });
+            families
+        };
+        let engine = RuleEngine::new(vec![rule.clone()]);
+        // A single evaluation has a rate: 19 errors over the last 9.5 s
+        engine.evaluate_at(&windowed(vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 18, 500]), Instant::now());
+        let rate = engine.statuses()[0].value.unwrap();
+        assert!((rate - 2.0).abs() < 1e-9, "{rate}");
+        assert_eq!(engine.statuses()[0].state, AlertState::Firing);
+
+        // A window shorter than the rule's is not used
+        let engine = RuleEngine::new(vec![rule]);
+        engine.evaluate_at(&windowed(vec![100; 5]), Instant::now());
+        assert_eq!(engine.statuses()[0].value, None);
+    }
+
+    #[test]
+    fn test_no_data_policies() {
+        let rule = |no_data: &str| -> Rule {
+            serde_yaml::from_str(&format!(
+                "{{ name: queue_{no_data}, query: {{ kind: gauge, metric: ulc_queue, aggregation: max }}, \
+                 window_secs: 60, comparison: above, threshold: 10, no_data: {no_data} }}"
+            ))
+            .unwrap()
+        };
+        let engine = RuleEngine::new(vec![rule("keep"), rule("ok"), rule("alerting")]);
+        let queue = |depth: f64| vec![family("ulc_queue", &[], MetricKind::Gauge, vec![(vec![], SampleValue::Gauge(depth))])];
+        let start = Instant::now();
+
+        engine.evaluate_at(&queue(50.0), start);
+        assert_eq!(engine.firing().len(), 3);
+
+        // The metric disappears; the stale samples age out of the window
+        engine.evaluate_at(&[], start + SECOND * 120);
+        engine.evaluate_at(&[], start + SECOND * 240);
+        let states: Vec<AlertState> = engine.statuses().iter().map(|s| s.state).collect();
+        assert_eq!(states, [AlertState::Firing, AlertState::Ok, AlertState::Firing]);
+        assert!(engine.statuses().iter().all(|s| s.value.is_none()));
+    }
+
+    #[test]
+    fn test_quantile_covers_only_the_window() {
+        let rule: Rule = serde_yaml::from_str(
+            "{ name: slow_conversions, query: { kind: quantile, metric: ulc_conversion_duration_seconds, quantile: 0.99 }, \
+             window_secs: 60, comparison: above, threshold: 10 }",
+        )
+        .unwrap();
+        let engine = RuleEngine::new(vec![rule]);
+        let histogram = |counts: [u64; 3]| {
+            vec![family(
+                "ulc_conversion_duration_seconds",
+                &[],
+                MetricKind::Histogram,
+                vec![(
+                    vec![],
+                    SampleValue::Histogram(HistogramSnapshot {
+                        bounds: vec![1.0, 10.0, 60.0],
+                        counts: counts.to_vec(),
+                        sum: 0.0,
+                        count: counts[2],
+                    }),
+                )],
+            )]
+        };
+        let start = Instant::now();
+
+        // Slow conversions early on
+        engine.evaluate_at(&histogram([0, 0, 100]), start);
+        engine.evaluate_at(&histogram([0, 0, 200]), start + SECOND * 30);
+        assert_eq!(engine.firing(), ["slow_conversions"]);
+
+        // Only fast ones since: the window no longer sees the slow ones
+        engine.evaluate_at(&histogram([1000, 1000, 1200]), start + SECOND * 90);
+        engine.evaluate_at(&histogram([2000, 2000, 2200]), start + SECOND * 150);
+        assert!(engine.statuses()[0].value.unwrap() <= 1.0);
+        assert!(engine.firing().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_firing_rule_degrades_health() {
+        let engine = Arc::new(RuleEngine::new(vec![error_ratio_rule()]));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        engine.observe(move |event| seen.lock().unwrap().push(event.clone()));
+        let check = RulesCheck::new(Arc::clone(&engine));
+        assert_eq!(check.check().await.status, ServiceStatus::Healthy);
+
+        let start = Instant::now();
+        for (minute, errors) in (0u32..).zip([0, 50, 100]) {
+            engine.evaluate_at(&requests(100 * u64::from(minute), errors), start + SECOND * 60 * minute);
+        }
+        let result = check.check().await;
+        assert_eq!(result.status, ServiceStatus::Degraded);
+        assert_eq!(result.message.as_deref(), Some("Firing: http_5xx_ratio"));
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_load_validates_rules() {
+        let dir = std::env::temp_dir().join(format!("ulc-rules-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rules.yaml");
+
+        std::fs::write(&path, serde_yaml::to_string(&vec![error_ratio_rule()]).unwrap()).unwrap();
+        assert_eq!(load(&path).unwrap(), vec![error_ratio_rule()]);
+
+        std::fs::write(&path, serde_yaml::to_string(&vec![error_ratio_rule(), error_ratio_rule()]).unwrap()).unwrap();
+        assert!(load(&path).unwrap_err().to_string().contains("defined twice"));
+
+        let mut inverted = error_ratio_rule();
+        inverted.resolve_threshold = Some(0.5);
+        assert!(validate(&[inverted]).is_err());
+        let mut instant = error_ratio_rule();
+        instant.window_secs = 0;
+        assert!(validate(&[instant]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+//! Slow operation log
+//!
+//! HTTP requests, LSP requests, conversions, validations and store writes
+//! that take longer than their category's threshold are logged at warn
+//! level and kept in a bounded ring, served by `GET /api/admin/slow-ops`.
+//!
+//! Records describe an operation by its size bucket, format, request ID and
+//! options, never by its content. During an incident everything slows down
+//! at once, so each category logs at most a set number of records a minute;
+//! the rest still enter the ring, and the next record logged says how many
+//! were held back.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Length of a log sampling window
+const SAMPLING_WINDOW: Duration = Duration::from_mins(1);
+
+tokio::task_local! {
+    /// ID of the request the current task is serving
+    static REQUEST_ID: String;
+}
+
+/// Run `future` as part of the request `id`
+///
+/// Slow operations recorded while it runs carry the ID, however deep in
+/// the call stack they are timed.
+pub async fn with_request_id<F: Future>(id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(id, future).await
+}
+
+/// ID of the request being served, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Kind of operation timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpKind {
+    Http,
+    Lsp,
+    Conversion,
+    Validation,
+    Store,
+}
+
+impl OpKind {
+    /// Every kind, in sampling slot order
+    pub const ALL: [Self; 5] = [Self::Http, Self::Lsp, Self::Conversion, Self::Validation, Self::Store];
+
+    /// Name used in logs and the ring
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Lsp => "lsp",
+            Self::Conversion => "conversion",
+            Self::Validation => "validation",
+            Self::Store => "store",
+        }
+    }
+
+    fn slot(self) -> usize {
+        self as usize
+    }
+}
+
+/// Duration from which an operation of each kind counts as slow
+///
+/// A zero threshold counts every operation of its kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct SlowOpThresholds {
+    #[serde(with = "crate::config::duration")]
+    pub http: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub lsp: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub conversion: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub validation: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub store: Duration,
+}
+
+impl SlowOpThresholds {
+    /// Threshold for `kind`
+    #[must_use]
+    pub fn get(&self, kind: OpKind) -> Duration {
+        match kind {
+            OpKind::Http => self.http,
+            OpKind::Lsp => self.lsp,
+            OpKind::Conversion => self.conversion,
+            OpKind::Validation => self.validation,
+            OpKind::Store => self.store,
+        }
+    }
+
+    /// Set the threshold for `kind`
+    pub fn set(&mut self, kind: OpKind, threshold: Duration) {
+        match kind {
+            OpKind::Http => self.http = threshold,
+            OpKind::Lsp => self.lsp = threshold,
+            OpKind::Conversion => self.conversion = threshold,
+            OpKind::Validation => self.validation = threshold,
+            OpKind::Store => self.store = threshold,
+        }
+    }
+}
+
+impl Default for SlowOpThresholds {
+    fn default() -> Self {
+        Self {
+            http: Duration::from_secs(1),
+            lsp: Duration::from_secs(1),
+            conversion: Duration::from_millis(500),
+            validation: Duration::from_millis(500),
+            store: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Slow operation detection settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct SlowOpConfig {
+    /// Per-kind thresholds
+    pub thresholds: SlowOpThresholds,
+    /// Slow operations kept for the admin endpoint
+    pub capacity: usize,
+    /// Records logged per kind per minute; zero logs none
+    pub max_logs_per_minute: u32,
+}
+
+impl Default for SlowOpConfig {
+    fn default() -> Self {
+        Self {
+            thresholds: SlowOpThresholds::default(),
+            capacity: 100,
+            max_logs_per_minute: 10,
+        }
+    }
+}
+
+/// An operation being timed, described without its content
+#[derive(Debug, Clone)]
+pub struct Operation {
+    kind: OpKind,
+    name: String,
+    size: Option<&'static str>,
+    format: Option<String>,
+    options: BTreeMap<String, String>,
+}
+
+impl Operation {
+    /// Operation of `kind`, named by its route, method or action
+    pub fn new(kind: OpKind, name: impl Into<String>) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+            size: None,
+            format: None,
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Size of the document handled, recorded as its bucket
+    #[must_use]
+    pub fn size(mut self, len: usize) -> Self {
+        self.size = Some(crate::telemetry::size_bucket(len));
+        self
+    }
+
+    /// Format of the document handled
+    #[must_use]
+    pub fn format(mut self, format: impl Into<String>) -> Self {
+        self.format = Some(format.into());
+        self
+    }
+
+    /// An option the operation ran with
+    #[must_use]
+    pub fn option(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.options.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+/// A slow operation, as logged and kept in the ring
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowOp {
+    pub kind: OpKind,
+    /// Route, LSP method or action
+    pub operation: String,
+    pub duration_ms: f64,
+    pub threshold_ms: f64,
+    /// Size bucket of the document handled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+    pub at: DateTime<Utc>,
+}
+
+/// Log budget of one kind in the current window
+#[derive(Debug, Clone, Copy)]
+struct Sampling {
+    window_start: Option<Instant>,
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Detector and ring of recent slow operations
+#[derive(Debug)]
+pub struct SlowOps {
+    config: RwLock<SlowOpConfig>,
+    ring: Mutex<VecDeque<SlowOp>>,
+    sampling: Mutex<[Sampling; OpKind::ALL.len()]>,
+}
+
+impl SlowOps {
+    /// Detector applying `config`
+    #[must_use]
+    pub fn new(config: SlowOpConfig) -> Self {
+        let idle = Sampling {
+            window_start: None,
+            logged: 0,
+            suppressed: 0,
+        };
+        Self {
+            ring: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            sampling: Mutex::new([idle; OpKind::ALL.len()]),
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Settings in force
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the log's lock.
+    pub fn config(&self) -> SlowOpConfig {
+        self.config.read().expect("slow ops lock poisoned").clone()
+    }
+
+    /// Apply `config` from now on, dropping the oldest records over its capacity
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the log's lock.
+    pub fn set_config(&self, config: SlowOpConfig) {
+        let mut ring = self.ring.lock().expect("slow ops lock poisoned");
+        let excess = ring.len().saturating_sub(config.capacity);
+        ring.drain(..excess);
+        *self.config.write().expect("slow ops lock poisoned") = config;
+    }
+
+    /// Report that `operation` took `elapsed`, returning whether it was slow
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the log's lock.
+    pub fn record(&self, operation: Operation, elapsed: Duration) -> bool {
+        let config = self.config();
+        let threshold = config.thresholds.get(operation.kind);
+        if elapsed < threshold {
+            return false;
+        }
+
+        let slow = SlowOp {
+            kind: operation.kind,
+            operation: operation.name,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            threshold_ms: threshold.as_secs_f64() * 1000.0,
+            size: operation.size.map(str::to_string),
+            format: operation.format,
+            request_id: current_request_id(),
+            options: operation.options,
+            at: Utc::now(),
+        };
+        if let Some(suppressed) = self.sample(slow.kind, Instant::now()) {
+            log(&slow, suppressed);
+        }
+
+        if config.capacity > 0 {
+            let mut ring = self.ring.lock().expect("slow ops lock poisoned");
+            while ring.len() >= config.capacity {
+                ring.pop_front();
+            }
+            ring.push_back(slow);
+        }
+        true
+    }
+
+    /// Slow operations kept, oldest first
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the log's lock.
+    pub fn recent(&self) -> Vec<SlowOp> {
+        self.ring.lock().expect("slow ops lock poisoned").iter().cloned().collect()
+    }
+
+    /// Whether a slow operation of `kind` may be logged now, and if so how
+    /// many were held back before it
+    fn sample(&self, kind: OpKind, now: Instant) -> Option<u64> {
+        let mut sampling = self.sampling.lock().expect("slow ops lock poisoned");
+        let slot = &mut sampling[kind.slot()];
+        let expired = slot
+            .window_start
+            .is_none_or(|start| now.saturating_duration_since(start) >= SAMPLING_WINDOW);
+        if expired {
+            slot.window_start = Some(now);
+            slot.logged = 0;
+        }
+        if slot.logged >= self.config.read().expect("slow ops lock poisoned").max_logs_per_minute {
+            slot.suppressed += 1;
+            return None;
+        }
+        slot.logged += 1;
+        Some(std::mem::take(&mut slot.suppressed))
+    }
+}
+
+impl Default for SlowOps {
+    fn default() -> Self {
+        Self::new(SlowOpConfig::default())
+    }
+}
+
+fn log(slow: &SlowOp, suppressed: u64) {
+    let options = (!slow.options.is_empty()).then(|| {
+        slow.options
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    warn!(
+        op.kind = slow.kind.as_str(),
+        op.name = %slow.operation,
+        duration_ms = slow.duration_ms,
+        threshold_ms = slow.threshold_ms,
+        size = slow.size.as_deref(),
+        format = slow.format.as_deref(),
+        request_id = slow.request_id.as_deref(),
+        options = options.as_deref(),
+        suppressed = (suppressed > 0).then_some(suppressed),
+        "Slow operation"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn slow_ops(max_logs_per_minute: u32, capacity: usize) -> SlowOps {
+        SlowOps::new(SlowOpConfig {
+            capacity,
+            max_logs_per_minute,
+            ..SlowOpConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_threshold_per_kind() {
+        let ops = SlowOps::default();
+        assert!(!ops.record(Operation::new(OpKind::Http, "/api/convert"), MS * 900));
+        assert!(ops.record(Operation::new(OpKind::Conversion, "markdown->html"), MS * 900));
+        assert!(ops.record(Operation::new(OpKind::Store, "upsert"), MS * 150));
+
+        let recent = ops.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].kind, OpKind::Conversion);
+        assert!((recent[0].threshold_ms - 500.0).abs() < 1e-9);
+        assert_eq!(recent[1].operation, "upsert");
+    }
+
+    #[test]
+    fn test_ring_keeps_latest() {
+        let ops = slow_ops(10, 3);
+        for n in 0..5 {
+            ops.record(Operation::new(OpKind::Lsp, format!("method/{n}")), Duration::from_secs(2));
+        }
+        let names: Vec<String> = ops.recent().into_iter().map(|op| op.operation).collect();
+        assert_eq!(names, ["method/2", "method/3", "method/4"]);
+    }
+
+    #[test]
+    fn test_new_config_applies_from_now_on() {
+        let ops = slow_ops(10, 3);
+        for n in 0..3 {
+            ops.record(Operation::new(OpKind::Lsp, format!("method/{n}")), Duration::from_secs(2));
+        }
+        ops.set_config(SlowOpConfig {
+            thresholds: SlowOpThresholds {
+                lsp: Duration::from_secs(5),
+                ..SlowOpThresholds::default()
+            },
+            capacity: 1,
+            ..SlowOpConfig::default()
+        });
+        assert_eq!(ops.recent().len(), 1);
+        assert!(!ops.record(Operation::new(OpKind::Lsp, "method/3"), Duration::from_secs(2)));
+        assert!(ops.record(Operation::new(OpKind::Lsp, "method/4"), Duration::from_secs(6)));
+        let names: Vec<String> = ops.recent().into_iter().map(|op| op.operation).collect();
+        assert_eq!(names, ["method/4"]);
+    }
+
+    #[test]
+    fn test_sampling_per_kind_per_minute() {
+        let ops = slow_ops(2, 100);
+        let start = Instant::now();
+        let logged = |at: Instant, kind: OpKind| ops.sample(kind, at);
+
+        assert_eq!(logged(start, OpKind::Http), Some(0));
+        assert_eq!(logged(start + MS, OpKind::Http), Some(0));
+        assert_eq!(logged(start + MS * 2, OpKind::Http), None);
+        assert_eq!(logged(start + MS * 3, OpKind::Http), None);
+        // Other kinds have their own budget
+        assert_eq!(logged(start + MS * 4, OpKind::Store), Some(0));
+        // A new window logs again, reporting what was held back
+        assert_eq!(logged(start + SAMPLING_WINDOW, OpKind::Http), Some(2));
+        assert_eq!(logged(start + SAMPLING_WINDOW + MS, OpKind::Http), Some(0));
+    }
+
+    #[test]
+    fn test_suppressed_records_still_kept() {
+        let ops = slow_ops(0, 100);
+        for _ in 0..3 {
+            ops.record(Operation::new(OpKind::Validation, "yaml"), Duration::from_secs(1));
+        }
+        assert_eq!(ops.recent().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_scope() {
+        let ops = SlowOps::default();
+        assert_eq!(current_request_id(), None);
+        with_request_id("req-1".to_string(), async {
+            ops.record(Operation::new(OpKind::Store, "remove"), Duration::from_secs(1));
+        })
+        .await;
+        assert_eq!(ops.recent()[0].request_id.as_deref(), Some("req-1"));
+    }
+}
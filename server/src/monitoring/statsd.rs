@@ -0,0 +1,588 @@
+//! `StatsD` and `DogStatsD` push export
+//!
+//! For deployments with a `StatsD` agent rather than a Prometheus scraper.
+//! Every flush interval the registry is gathered and written to the agent
+//! as newline-separated lines packed into datagrams. Counters are sent as
+//! their increase since the previous flush, gauges as their current value,
+//! and histograms as timing samples or a summary, per [`HistogramMode`].
+//! Labels become `DogStatsD` tags, or with tags off are folded into the name.
+//!
+//! Sends never wait: a full socket buffer or an absent agent drops the
+//! datagram and counts it in `ulc_statsd_dropped_packets_total`. Export
+//! reads the same registry as `/metrics`, so both can run at once.
+
+use super::registry::{Counter, FamilySnapshot, HistogramSnapshot, SampleValue};
+use super::Metrics;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+/// Quantiles sent for each histogram in [`HistogramMode::Summary`]
+const SUMMARY_QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+
+/// Where the agent listens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StatsdTarget {
+    /// `host:port` of a UDP listener
+    #[serde(rename = "addr")]
+    Udp(String),
+    /// Path of a Unix datagram socket
+    #[cfg(unix)]
+    #[serde(rename = "socket")]
+    Unix(PathBuf),
+}
+
+/// How histograms are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramMode {
+    /// One sample per bucket observed since the last flush, valued at the
+    /// bucket's upper bound and weighted by a sample rate. Durations in
+    /// seconds are sent as `ms` timers, other histograms as `h`.
+    Timing,
+    /// The count, mean and quantiles of the values observed since the last
+    /// flush, as `.count`, `.avg`, `.p50`, `.p95` and `.p99`
+    Summary,
+}
+
+impl HistogramMode {
+    /// Parse `timing` or `summary`
+    ///
+    /// # Errors
+    ///
+    /// Fails where `value` is neither.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "timing" => Ok(Self::Timing),
+            "summary" => Ok(Self::Summary),
+            _ => Err(anyhow!("Unknown StatsD histogram mode: {value}")),
+        }
+    }
+}
+
+/// `StatsD` export settings
+///
+/// In a configuration file the target is an `addr` or `socket` key beside
+/// the other settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsdConfig {
+    #[serde(flatten)]
+    pub target: StatsdTarget,
+    /// Prepended to every metric name, such as `connector.`
+    #[serde(default)]
+    pub prefix: String,
+    /// Time between flushes
+    #[serde(default = "default_interval", with = "crate::config::duration")]
+    pub interval: Duration,
+    /// Send labels as `DogStatsD` tags; otherwise their values join the name
+    #[serde(default = "default_tags")]
+    pub tags: bool,
+    #[serde(default = "default_histograms")]
+    pub histograms: HistogramMode,
+    /// Largest datagram sent; lines are packed up to this size
+    #[serde(default = "default_max_packet_bytes")]
+    pub max_packet_bytes: usize,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_tags() -> bool {
+    true
+}
+
+fn default_histograms() -> HistogramMode {
+    HistogramMode::Timing
+}
+
+/// Fits an Ethernet MTU with IP and UDP headers
+fn default_max_packet_bytes() -> usize {
+    1432
+}
+
+impl StatsdConfig {
+    /// Export to `target` with default settings
+    #[must_use]
+    pub fn new(target: StatsdTarget) -> Self {
+        Self {
+            target,
+            prefix: String::new(),
+            interval: default_interval(),
+            tags: default_tags(),
+            histograms: default_histograms(),
+            max_packet_bytes: default_max_packet_bytes(),
+        }
+    }
+
+    /// Read `STATSD_ADDR` or `STATSD_SOCKET`, `STATSD_PREFIX`,
+    /// `STATSD_INTERVAL_SECS`, `STATSD_TAGS`, `STATSD_HISTOGRAMS` and
+    /// `STATSD_MAX_PACKET_BYTES`; `None` when no target is set
+    ///
+    /// # Errors
+    ///
+    /// Fails where a variable is set to a value that does not parse as its
+    /// setting.
+    pub fn from_env() -> Result<Option<Self>> {
+        let env = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let target = match (env("STATSD_ADDR"), env("STATSD_SOCKET")) {
+            (Some(_), Some(_)) => return Err(anyhow!("Set only one of STATSD_ADDR and STATSD_SOCKET")),
+            (Some(addr), None) => StatsdTarget::Udp(addr),
+            #[cfg(unix)]
+            (None, Some(path)) => StatsdTarget::Unix(PathBuf::from(path)),
+            #[cfg(not(unix))]
+            (None, Some(_)) => return Err(anyhow!("STATSD_SOCKET needs Unix domain sockets")),
+            (None, None) => return Ok(None),
+        };
+
+        let defaults = Self::new(target);
+        Ok(Some(Self {
+            prefix: env("STATSD_PREFIX").unwrap_or(defaults.prefix),
+            interval: env("STATSD_INTERVAL_SECS")
+                .and_then(|v| v.parse().ok())
+                .map_or(defaults.interval, Duration::from_secs),
+            tags: env("STATSD_TAGS").map_or(defaults.tags, |v| v == "true"),
+            histograms: env("STATSD_HISTOGRAMS").map_or(Ok(defaults.histograms), |v| HistogramMode::parse(&v))?,
+            max_packet_bytes: env("STATSD_MAX_PACKET_BYTES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_packet_bytes),
+            ..defaults
+        }))
+    }
+}
+
+/// Series identity: family name and label values
+type SeriesKey = (String, Vec<String>);
+
+/// Turns gathered families into `StatsD` lines, remembering what was sent
+#[derive(Debug)]
+struct Encoder {
+    prefix: String,
+    tags: bool,
+    histograms: HistogramMode,
+    counters: HashMap<SeriesKey, u64>,
+    histogram_totals: HashMap<SeriesKey, HistogramSnapshot>,
+}
+
+impl Encoder {
+    fn new(config: &StatsdConfig) -> Self {
+        Self {
+            prefix: config.prefix.clone(),
+            tags: config.tags,
+            histograms: config.histograms,
+            counters: HashMap::new(),
+            histogram_totals: HashMap::new(),
+        }
+    }
+
+    /// Lines for everything that changed since the previous call
+    ///
+    /// Series no longer gathered are forgotten, so a series that returns
+    /// is sent in full again.
+    fn encode(&mut self, families: &[FamilySnapshot]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut counters = HashMap::new();
+        let mut histogram_totals = HashMap::new();
+        for family in families {
+            let name = family.descriptor.name.as_str();
+            for sample in &family.samples {
+                let labels: Vec<(&str, &str)> = family
+                    .descriptor
+                    .labels
+                    .iter()
+                    .map(String::as_str)
+                    .zip(sample.labels.iter().map(String::as_str))
+                    .collect();
+                let key = (name.to_string(), sample.labels.clone());
+                match &sample.value {
+                    SampleValue::Counter(total) => {
+                        let increase = match self.counters.get(&key) {
+                            // A total that went down has restarted from zero
+                            Some(previous) if previous <= total => total - previous,
+                            _ => *total,
+                        };
+                        if increase > 0 {
+                            lines.push(self.line(name, None, &labels, &increase.to_string(), "c", None));
+                        }
+                        counters.insert(key, *total);
+                    }
+                    SampleValue::Gauge(value) if value.is_finite() => {
+                        // A signed value would be read as an adjustment
+                        if value.is_sign_negative() && *value != 0.0 {
+                            lines.push(self.line(name, None, &labels, "0", "g", None));
+                        }
+                        lines.push(self.line(name, None, &labels, &value.to_string(), "g", None));
+                    }
+                    SampleValue::Gauge(_) => {}
+                    SampleValue::Histogram(total) => {
+                        let recent = since(total, self.histogram_totals.get(&key));
+                        if recent.count > 0 {
+                            match self.histograms {
+                                HistogramMode::Timing => self.timing(name, &labels, &recent, &mut lines),
+                                HistogramMode::Summary => self.summary(name, &labels, &recent, &mut lines),
+                            }
+                        }
+                        histogram_totals.insert(key, total.clone());
+                    }
+                }
+            }
+        }
+        self.counters = counters;
+        self.histogram_totals = histogram_totals;
+        lines
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn timing(&self, name: &str, labels: &[(&str, &str)], recent: &HistogramSnapshot, lines: &mut Vec<String>) {
+        let (kind, scale) = if name.ends_with("_seconds") { ("ms", 1000.0) } else { ("h", 1.0) };
+        let mut buckets = Vec::with_capacity(recent.bounds.len() + 1);
+        let mut below = 0;
+        for (bound, cumulative) in recent.bounds.iter().zip(&recent.counts) {
+            buckets.push((*bound, cumulative - below));
+            below = *cumulative;
+        }
+        // Values above the last bound are sent as the last bound
+        if let Some(last) = recent.bounds.last() {
+            buckets.push((*last, recent.count - below));
+        }
+        for (bound, count) in buckets.into_iter().filter(|(_, count)| *count > 0) {
+            let value = (bound * scale).to_string();
+            let rate = (count > 1).then(|| 1.0 / count as f64);
+            lines.push(self.line(name, None, labels, &value, kind, rate));
+        }
+    }
+
+    fn summary(&self, name: &str, labels: &[(&str, &str)], recent: &HistogramSnapshot, lines: &mut Vec<String>) {
+        lines.push(self.line(name, Some("count"), labels, &recent.count.to_string(), "c", None));
+        if let Some(mean) = recent.mean() {
+            lines.push(self.line(name, Some("avg"), labels, &mean.to_string(), "g", None));
+        }
+        for (suffix, q) in SUMMARY_QUANTILES {
+            if let Some(value) = recent.quantile(q) {
+                lines.push(self.line(name, Some(suffix), labels, &value.to_string(), "g", None));
+            }
+        }
+    }
+
+    /// One line: `name:value|kind`, then `|@rate` and `|#tags` if any
+    fn line(
+        &self,
+        name: &str,
+        suffix: Option<&str>,
+        labels: &[(&str, &str)],
+        value: &str,
+        kind: &str,
+        rate: Option<f64>,
+    ) -> String {
+        let mut line = format!("{}{}", self.prefix, sanitize(name, false));
+        if !self.tags {
+            for (_, value) in labels {
+                line.push('.');
+                line.push_str(&sanitize(value, true));
+            }
+        }
+        if let Some(suffix) = suffix {
+            line.push('.');
+            line.push_str(suffix);
+        }
+        line.push(':');
+        line.push_str(value);
+        line.push('|');
+        line.push_str(kind);
+        if let Some(rate) = rate {
+            let _ = write!(line, "|@{rate}");
+        }
+        if self.tags && !labels.is_empty() {
+            let tags: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| format!("{}:{}", sanitize(name, false), sanitize(value, false)))
+                .collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+}
+
+/// Replace the characters the line format reserves, and with `in_name`
+/// the dots that would split a folded label value into several segments
+fn sanitize(value: &str, in_name: bool) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' | '\n' | ' ' => '_',
+            '.' if in_name => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Observations in `total` made since `previous`
+fn since(total: &HistogramSnapshot, previous: Option<&HistogramSnapshot>) -> HistogramSnapshot {
+    match previous {
+        Some(previous) if previous.count <= total.count && previous.bounds == total.bounds => HistogramSnapshot {
+            bounds: total.bounds.clone(),
+            counts: total
+                .counts
+                .iter()
+                .zip(&previous.counts)
+                .map(|(now, before)| now.saturating_sub(*before))
+                .collect(),
+            sum: total.sum - previous.sum,
+            count: total.count - previous.count,
+        },
+        _ => total.clone(),
+    }
+}
+
+/// Pack lines into datagrams of at most `max` bytes
+///
+/// A line longer than `max` is sent alone rather than dropped.
+fn pack(lines: Vec<String>, max: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Connected datagram socket to the agent
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Socket {
+    async fn connect(target: &StatsdTarget) -> io::Result<Self> {
+        match target {
+            StatsdTarget::Udp(addr) => {
+                let remote = tokio::net::lookup_host(addr.as_str())
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{addr} did not resolve")))?;
+                let local: SocketAddr = if remote.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(remote).await?;
+                // Readiness is unknown until polled, so a first try_send would block
+                socket.writable().await?;
+                Ok(Self::Udp(socket))
+            }
+            #[cfg(unix)]
+            StatsdTarget::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                socket.writable().await?;
+                Ok(Self::Unix(socket))
+            }
+        }
+    }
+
+    /// Send without waiting for buffer space
+    fn try_send(&self, packet: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(socket) => socket.try_send(packet),
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.try_send(packet),
+        }
+    }
+}
+
+/// Pushes the registry to a `StatsD` agent
+#[derive(Debug)]
+pub struct StatsdExporter {
+    config: StatsdConfig,
+    metrics: Arc<Metrics>,
+    encoder: Encoder,
+    socket: Option<Socket>,
+    failing: bool,
+    packets: Counter,
+    dropped: Counter,
+}
+
+impl StatsdExporter {
+    /// Exporter of `metrics`, registering its own packet counters there
+    ///
+    /// # Errors
+    ///
+    /// Fails where the packet counters are already registered.
+    pub fn new(config: StatsdConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let registry = metrics.registry();
+        let packets = registry.counter("ulc_statsd_packets_total", "Datagrams sent to the StatsD agent")?;
+        let dropped = registry.counter(
+            "ulc_statsd_dropped_packets_total",
+            "Datagrams dropped because the StatsD agent could not take them",
+        )?;
+        Ok(Self {
+            encoder: Encoder::new(&config),
+            config,
+            metrics,
+            socket: None,
+            failing: false,
+            packets,
+            dropped,
+        })
+    }
+
+    /// Send everything that changed since the last flush
+    pub async fn flush(&mut self) {
+        let lines = self.encoder.encode(&self.metrics.gather());
+        for packet in pack(lines, self.config.max_packet_bytes) {
+            self.send(packet.as_bytes()).await;
+        }
+    }
+
+    /// Flush every interval until `stop` completes, then flush once more
+    pub async fn run(mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        let mut ticker = tokio::time::interval(self.config.interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.flush().await,
+                () = &mut stop => break,
+            }
+        }
+        self.flush().await;
+    }
+
+    async fn send(&mut self, packet: &[u8]) {
+        if self.socket.is_none() {
+            match Socket::connect(&self.config.target).await {
+                Ok(socket) => self.socket = Some(socket),
+                Err(e) => return self.drop_packet(&e),
+            }
+        }
+        let Some(socket) = &self.socket else { return };
+        match socket.try_send(packet) {
+            Ok(_) => {
+                self.packets.inc();
+                self.failing = false;
+            }
+            Err(e) => {
+                // Anything but a full buffer may mean the agent restarted
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    self.socket = None;
+                }
+                self.drop_packet(&e);
+            }
+        }
+    }
+
+    fn drop_packet(&mut self, error: &io::Error) {
+        self.dropped.inc();
+        if self.failing {
+            debug!(error = %error, "StatsD datagram dropped");
+        } else {
+            warn!(target = ?self.config.target, error = %error, "StatsD export failing, dropping datagrams");
+            self.failing = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::registry::{Buckets, Registry};
+
+    fn encoder(tags: bool, histograms: HistogramMode) -> Encoder {
+        Encoder::new(&StatsdConfig {
+            prefix: "app.".to_string(),
+            tags,
+            histograms,
+            ..StatsdConfig::new(StatsdTarget::Udp("127.0.0.1:8125".to_string()))
+        })
+    }
+
+    #[test]
+    fn test_counters_sent_as_increases() {
+        let registry = Registry::new();
+        let requests = registry.counter_family("ulc_requests_total", "Requests", &["method"]).unwrap();
+        let mut encoder = encoder(true, HistogramMode::Timing);
+
+        requests.with_labels(&["GET"]).inc_by(5);
+        assert_eq!(encoder.encode(&registry.gather()), ["app.ulc_requests_total:5|c|#method:GET"]);
+        // Unchanged counters are not sent
+        assert!(encoder.encode(&registry.gather()).is_empty());
+        requests.with_labels(&["GET"]).inc_by(2);
+        assert_eq!(encoder.encode(&registry.gather()), ["app.ulc_requests_total:2|c|#method:GET"]);
+    }
+
+    #[test]
+    fn test_gauges_and_folded_labels() {
+        let registry = Registry::new();
+        let open = registry.gauge_family("ulc_open", "Open", &["peer"]).unwrap();
+        open.with_labels(&["10.0.0.1"]).set(3.0);
+        open.with_labels(&["10.0.0.2"]).set(-2.5);
+        let mut lines = encoder(false, HistogramMode::Timing).encode(&registry.gather());
+        lines.sort();
+        assert_eq!(
+            lines,
+            ["app.ulc_open.10_0_0_1:3|g", "app.ulc_open.10_0_0_2:-2.5|g", "app.ulc_open.10_0_0_2:0|g"]
+        );
+    }
+
+    #[test]
+    fn test_histogram_timing_samples() {
+        let registry = Registry::new();
+        let latency = registry
+            .histogram("ulc_latency_seconds", "Latency", Buckets::new(vec![0.1, 1.0]))
+            .unwrap();
+        let mut encoder = encoder(true, HistogramMode::Timing);
+        for value in [0.05, 0.5, 0.7, 5.0] {
+            latency.observe(value);
+        }
+        assert_eq!(
+            encoder.encode(&registry.gather()),
+            ["app.ulc_latency_seconds:100|ms", "app.ulc_latency_seconds:1000|ms|@0.5", "app.ulc_latency_seconds:1000|ms"]
+        );
+        latency.observe(0.01);
+        assert_eq!(encoder.encode(&registry.gather()), ["app.ulc_latency_seconds:100|ms"]);
+    }
+
+    #[test]
+    fn test_histogram_summary() {
+        let registry = Registry::new();
+        let size = registry.histogram("ulc_size_bytes", "Size", Buckets::new(vec![10.0, 100.0])).unwrap();
+        size.observe(4.0);
+        size.observe(6.0);
+        let lines = encoder(true, HistogramMode::Summary).encode(&registry.gather());
+        assert_eq!(lines[0], "app.ulc_size_bytes.count:2|c");
+        assert_eq!(lines[1], "app.ulc_size_bytes.avg:5|g");
+        assert_eq!(lines[2], "app.ulc_size_bytes.p50:5|g");
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn test_pack() {
+        let lines = vec!["a:1|c".to_string(), "b:2|c".to_string(), "c:3|c".to_string()];
+        assert_eq!(pack(lines.clone(), 11), ["a:1|c\nb:2|c", "c:3|c"]);
+        assert_eq!(pack(lines, 3), ["a:1|c", "b:2|c", "c:3|c"]);
+    }
+}
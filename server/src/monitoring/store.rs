@@ -0,0 +1,228 @@
+//! Document store metrics
+//!
+//! The store reports each change through [`StoreMetricsRecorder`] rather
+//! than depending on the registry, and [`StoreMetrics`] turns the reports
+//! into gauges and counters. Gauges move by the size of each change, so
+//! reading them never scans the store. Documents are broken down by format
+//! over a fixed label set, with languages that are not a supported format
+//! counted as `other`.
+
+use super::registry::{Counter, Family, Gauge, Registry};
+use crate::core::Format;
+use anyhow::Result;
+
+/// Format label for languages that are not a supported format
+pub const OTHER_FORMAT: &str = "other";
+
+/// Every value the `format` label takes
+pub const FORMAT_LABELS: [&str; 17] = [
+    "md",
+    "html",
+    "json",
+    "yaml",
+    "xml",
+    "toml",
+    "csv",
+    "tsv",
+    "ini",
+    "json5",
+    "jsonc",
+    "ndjson",
+    "msgpack",
+    "cbor",
+    "env",
+    "plist",
+    OTHER_FORMAT,
+];
+
+/// Format label of a document's language
+#[must_use]
+pub fn format_label(language: &str) -> &'static str {
+    Format::from_str(language).map_or(OTHER_FORMAT, |format| format.extension())
+}
+
+/// What the document store reports about its changes
+///
+/// Calls are made on the writer's thread, so they must be cheap.
+pub trait StoreMetricsRecorder: Send + Sync {
+    /// A document of `language` and `bytes` was stored
+    fn document_added(&self, language: &str, bytes: usize);
+
+    /// A stored document's content went from `old_bytes` to `new_bytes`
+    fn document_resized(&self, old_bytes: usize, new_bytes: usize);
+
+    /// A document of `language` and `bytes` was removed
+    fn document_removed(&self, language: &str, bytes: usize);
+
+    /// Retained version history grew by `added` and shrank by `removed` bytes
+    fn history_changed(&self, added: usize, removed: usize);
+
+    /// A document was removed to make room for others
+    fn document_evicted(&self);
+
+    /// A document was removed because it outlived its time to live
+    fn document_expired(&self);
+
+    /// A conditional write lost to a concurrent one
+    fn write_conflict(&self);
+}
+
+/// Store gauges and churn counters
+#[derive(Debug, Clone)]
+pub struct StoreMetrics {
+    /// Documents held
+    pub documents: Gauge,
+    /// Content bytes held
+    pub bytes: Gauge,
+    /// Documents held by format
+    pub documents_by_format: Family<Gauge>,
+    /// Bytes of retained version history
+    pub history_bytes: Gauge,
+    pub evictions: Counter,
+    pub expirations: Counter,
+    /// Conditional writes refused because the document had changed
+    pub conflicts: Counter,
+}
+
+impl StoreMetrics {
+    /// Register the store metrics, with every format series present at zero
+    ///
+    /// # Errors
+    ///
+    /// Fails where a metric of the same name is already registered.
+    pub fn register(registry: &Registry) -> Result<Self> {
+        let documents_by_format =
+            registry.gauge_family("ulc_store_documents_by_format", "Documents held by format", &["format"])?;
+        for format in FORMAT_LABELS {
+            documents_by_format.with_labels(&[format]);
+        }
+        Ok(Self {
+            documents: registry.gauge("ulc_store_documents", "Documents held")?,
+            bytes: registry.gauge("ulc_store_bytes", "Content bytes held")?,
+            documents_by_format,
+            history_bytes: registry.gauge("ulc_store_history_bytes", "Bytes of retained version history")?,
+            evictions: registry.counter("ulc_store_evictions_total", "Documents evicted to make room")?,
+            expirations: registry.counter("ulc_store_expirations_total", "Documents removed after their time to live")?,
+            conflicts: registry.counter("ulc_store_conflicts_total", "Conditional writes lost to a concurrent write")?,
+        })
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+impl StoreMetricsRecorder for StoreMetrics {
+    fn document_added(&self, language: &str, bytes: usize) {
+        self.documents.inc();
+        self.bytes.add(bytes as f64);
+        self.documents_by_format.with_labels(&[format_label(language)]).inc();
+    }
+
+    fn document_resized(&self, old_bytes: usize, new_bytes: usize) {
+        self.bytes.add(new_bytes as f64 - old_bytes as f64);
+    }
+
+    fn document_removed(&self, language: &str, bytes: usize) {
+        self.documents.dec();
+        self.bytes.add(-(bytes as f64));
+        self.documents_by_format.with_labels(&[format_label(language)]).dec();
+    }
+
+    fn history_changed(&self, added: usize, removed: usize) {
+        self.history_bytes.add(added as f64 - removed as f64);
+    }
+
+    fn document_evicted(&self) {
+        self.evictions.inc();
+    }
+
+    fn document_expired(&self) {
+        self.expirations.inc();
+    }
+
+    fn write_conflict(&self) {
+        self.conflicts.inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_store::DocumentStore;
+    use std::sync::Arc;
+
+    fn by_format(metrics: &StoreMetrics) -> Vec<(&'static str, f64)> {
+        FORMAT_LABELS
+            .into_iter()
+            .map(|format| (format, metrics.documents_by_format.with_labels(&[format]).get()))
+            .collect()
+    }
+
+    #[test]
+    fn test_scripted_store_operations() {
+        let metrics = StoreMetrics::register(&Registry::new()).unwrap();
+        let store = DocumentStore::new();
+        store.upsert("file:///pre.md".to_string(), "# Pre".to_string(), "markdown".to_string());
+        // Documents stored before the recorder is set are counted once
+        store.record_metrics(Arc::new(metrics.clone()));
+        store.record_metrics(Arc::new(metrics.clone()));
+
+        store.upsert("file:///a.yaml".to_string(), "a: 1".to_string(), "yaml".to_string());
+        store.upsert("file:///b.json".to_string(), "{}".to_string(), "json".to_string());
+        store.upsert("file:///c.rs".to_string(), "fn main() {}".to_string(), "rust".to_string());
+        store.upsert("file:///a.yaml".to_string(), "a: 10".to_string(), "yaml".to_string());
+        store.remove("file:///b.json");
+        store.remove("file:///missing.md");
+        assert!(store.probe());
+        metrics.history_changed(100, 0);
+        metrics.history_changed(20, 50);
+        metrics.document_evicted();
+        metrics.document_evicted();
+        metrics.document_expired();
+        for _ in 0..3 {
+            metrics.write_conflict();
+        }
+
+        assert!((metrics.documents.get() - 3.0).abs() < f64::EPSILON);
+        assert!((metrics.bytes.get() - 22.0).abs() < f64::EPSILON);
+        assert_eq!(
+            by_format(&metrics),
+            [
+                ("md", 1.0),
+                ("html", 0.0),
+                ("json", 0.0),
+                ("yaml", 1.0),
+                ("xml", 0.0),
+                ("toml", 0.0),
+                ("csv", 0.0),
+                ("tsv", 0.0),
+                ("ini", 0.0),
+                ("json5", 0.0),
+                ("jsonc", 0.0),
+                ("ndjson", 0.0),
+                ("msgpack", 0.0),
+                ("cbor", 0.0),
+                ("env", 0.0),
+                ("plist", 0.0),
+                ("other", 1.0),
+            ]
+        );
+        assert!((metrics.history_bytes.get() - 70.0).abs() < f64::EPSILON);
+        assert_eq!(metrics.evictions.get(), 2);
+        assert_eq!(metrics.expirations.get(), 1);
+        assert_eq!(metrics.conflicts.get(), 3);
+
+        store.clear();
+        assert!(metrics.documents.get().abs() < f64::EPSILON);
+        assert!(metrics.bytes.get().abs() < f64::EPSILON);
+        assert!(by_format(&metrics).iter().all(|(_, count)| *count == 0.0));
+    }
+
+    #[test]
+    fn test_format_label_is_bounded() {
+        assert_eq!(format_label("markdown"), "md");
+        assert_eq!(format_label("YAML"), "yaml");
+        assert_eq!(format_label("rust"), OTHER_FORMAT);
+        assert!(["plaintext", "json", "htm", "toml", ""]
+            .iter()
+            .all(|language| FORMAT_LABELS.contains(&format_label(language))));
+    }
+}
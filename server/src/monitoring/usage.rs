@@ -0,0 +1,808 @@
+//! Per-client usage accounting
+//!
+//! Requests, conversions, bytes received and WebSocket messages are counted
+//! per authenticated subject in space-saving sketches (Metwally, Agrawal and
+//! El Abbadi), so memory is fixed however many subjects there are. A sketch
+//! monitors at most `capacity` subjects; a subject it is not monitoring
+//! takes over the entry of the least active one and inherits that entry's
+//! activity as its error. Any subject with more than 1/`capacity` of the
+//! activity is always monitored, its counts are never more than it used,
+//! and its counts plus its error are never less.
+//!
+//! One sketch covers each 15-minute slot of the last day, and a query for
+//! the top subjects over a window merges the slots it spans. Traffic
+//! without a subject is counted exactly in a single anonymous bucket, as is
+//! all traffic when subject accounting is turned off.
+//!
+//! With a rollup file configured, each subject's usage for the UTC day is
+//! appended to it as JSON lines when the day ends and at shutdown. A
+//! restart starts the day's counts again, so a day may have several lines
+//! per subject, to be summed.
+
+use crate::auth::Claims;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// Width of a slot
+pub const SLOT: Duration = Duration::from_mins(15);
+
+/// Slots kept: one day
+pub const SLOTS: usize = 96;
+
+/// Usage accounting settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct UsageConfig {
+    /// Count usage per authenticated subject; when off, all traffic is anonymous
+    pub per_subject: bool,
+    /// Subjects each sketch monitors
+    pub capacity: usize,
+    /// JSON lines file daily rollups are appended to
+    pub rollup_file: Option<PathBuf>,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            per_subject: true,
+            capacity: 200,
+            rollup_file: None,
+        }
+    }
+}
+
+/// Who traffic is accounted to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Client {
+    /// Authenticated subject; `None` is anonymous
+    pub subject: Option<String>,
+    /// Client name from the token, if it carries one
+    pub name: Option<String>,
+}
+
+impl Client {
+    #[must_use]
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    /// The subject and client name of a validated token
+    pub fn from_claims(claims: &Claims) -> Self {
+        Self {
+            subject: Some(claims.sub.clone()),
+            name: claims.client_name().map(str::to_string),
+        }
+    }
+}
+
+/// Usage of one subject, or of a bucket of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    /// HTTP requests
+    pub requests: u64,
+    pub conversions: u64,
+    /// Request bodies and WebSocket frames received
+    pub bytes: u64,
+    /// WebSocket messages received
+    pub ws_messages: u64,
+}
+
+impl Counts {
+    /// An HTTP request with a body of `bytes`
+    #[must_use]
+    pub fn request(bytes: u64) -> Self {
+        Self {
+            requests: 1,
+            bytes,
+            ..Self::default()
+        }
+    }
+
+    /// A successful conversion
+    #[must_use]
+    pub fn conversion() -> Self {
+        Self {
+            conversions: 1,
+            ..Self::default()
+        }
+    }
+
+    /// A WebSocket message of `bytes`
+    #[must_use]
+    pub fn ws_message(bytes: u64) -> Self {
+        Self {
+            ws_messages: 1,
+            bytes,
+            ..Self::default()
+        }
+    }
+
+    /// Requests and WebSocket messages, by which subjects are ranked
+    #[must_use]
+    pub fn activity(&self) -> u64 {
+        self.requests + self.ws_messages
+    }
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.conversions += other.conversions;
+        self.bytes += other.bytes;
+        self.ws_messages += other.ws_messages;
+    }
+}
+
+/// A monitored subject
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    name: Option<String>,
+    /// Counted since the subject was last admitted
+    counts: Counts,
+    /// Activity inherited from the entry it displaced
+    error: u64,
+}
+
+impl Entry {
+    /// Upper bound of the subject's activity
+    fn weight(&self) -> u64 {
+        self.counts.activity() + self.error
+    }
+}
+
+/// Space-saving sketch of the most active subjects
+#[derive(Debug, Default)]
+struct Sketch {
+    entries: HashMap<String, Entry>,
+    /// Entries by weight, least active first
+    order: BTreeSet<(u64, String)>,
+}
+
+impl Sketch {
+    fn add(&mut self, capacity: usize, subject: &str, name: Option<&str>, counts: Counts) {
+        if !self.entries.contains_key(subject) && !self.admit(capacity, subject, counts) {
+            return;
+        }
+        let entry = self.entries.get_mut(subject).expect("admitted subjects have an entry");
+        self.order.remove(&(entry.weight(), subject.to_string()));
+        entry.counts += counts;
+        if let Some(name) = name {
+            if entry.name.as_deref() != Some(name) {
+                entry.name = Some(name.to_string());
+            }
+        }
+        self.order.insert((entry.weight(), subject.to_string()));
+    }
+
+    /// Make room for `subject`, displacing the least active entry when full
+    ///
+    /// Usage that adds no activity, such as a conversion by a subject whose
+    /// request was not counted, displaces nothing.
+    fn admit(&mut self, capacity: usize, subject: &str, counts: Counts) -> bool {
+        let error = if self.entries.len() < capacity {
+            0
+        } else if counts.activity() == 0 || capacity == 0 {
+            return false;
+        } else {
+            let (weight, displaced) = self.order.pop_first().expect("a full sketch has entries");
+            self.entries.remove(&displaced);
+            weight
+        };
+        self.entries.insert(
+            subject.to_string(),
+            Entry {
+                error,
+                ..Entry::default()
+            },
+        );
+        true
+    }
+
+    /// Most a subject not monitored can have used
+    fn floor(&self, capacity: usize) -> u64 {
+        if self.entries.len() < capacity {
+            0
+        } else {
+            self.order.first().map_or(0, |(weight, _)| *weight)
+        }
+    }
+}
+
+/// Everything counted over one period
+#[derive(Debug, Default)]
+struct Tally {
+    sketch: Sketch,
+    anonymous: Counts,
+    total: Counts,
+}
+
+impl Tally {
+    fn add(&mut self, capacity: usize, subject: Option<&str>, name: Option<&str>, counts: Counts) {
+        self.total += counts;
+        match subject {
+            Some(subject) => self.sketch.add(capacity, subject, name, counts),
+            None => self.anonymous += counts,
+        }
+    }
+}
+
+/// A subject's usage over a window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientUsage {
+    pub subject: String,
+    /// Client name from the subject's token
+    pub client: Option<String>,
+    /// Counted while the subject was monitored; never more than it used
+    #[serde(flatten)]
+    pub counts: Counts,
+    /// Requests and WebSocket messages the subject may have made beyond those counted
+    pub error: u64,
+}
+
+/// Most active subjects over a window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Window covered, rounded up to whole slots
+    pub window_secs: u64,
+    /// Start of the oldest slot covered
+    pub since: DateTime<Utc>,
+    /// Whether usage is counted per subject
+    pub subject_accounting: bool,
+    /// Most active subjects first
+    pub clients: Vec<ClientUsage>,
+    /// Traffic without a subject
+    pub anonymous: Counts,
+    /// All traffic, including subjects not listed
+    pub total: Counts,
+}
+
+/// One subject's usage on one UTC day, as written to the rollup file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollupRecord {
+    pub date: NaiveDate,
+    /// `None` for anonymous traffic
+    pub subject: Option<String>,
+    pub client: Option<String>,
+    #[serde(flatten)]
+    pub counts: Counts,
+    pub error: u64,
+}
+
+fn rollup_records(date: NaiveDate, tally: Tally) -> Vec<RollupRecord> {
+    let mut records: Vec<_> = tally
+        .sketch
+        .entries
+        .into_iter()
+        .map(|(subject, entry)| RollupRecord {
+            date,
+            subject: Some(subject),
+            client: entry.name,
+            counts: entry.counts,
+            error: entry.error,
+        })
+        .collect();
+    records.sort_by(|a, b| a.subject.cmp(&b.subject));
+    if tally.anonymous != Counts::default() {
+        records.push(RollupRecord {
+            date,
+            subject: None,
+            client: None,
+            counts: tally.anonymous,
+            error: 0,
+        });
+    }
+    records
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Slot numbers and their tallies, oldest first
+    slots: VecDeque<(i64, Tally)>,
+    /// The day being rolled up
+    day: Option<(NaiveDate, Tally)>,
+    /// Rollup records waiting to be written
+    pending: Vec<RollupRecord>,
+}
+
+/// Per-client usage accounting
+#[derive(Debug)]
+pub struct Usage {
+    config: UsageConfig,
+    inner: Mutex<Inner>,
+}
+
+impl Usage {
+    /// Accounting applying `config`
+    #[must_use]
+    pub fn new(config: UsageConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Settings in force
+    pub fn config(&self) -> &UsageConfig {
+        &self.config
+    }
+
+    /// Account `counts` to `client`
+    pub fn record(&self, client: &Client, counts: Counts) {
+        self.record_at(client, counts, Utc::now());
+    }
+
+    /// Account `counts` to `client` at `now`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the usage lock.
+    #[allow(clippy::cast_possible_wrap)] // SLOTS is 96
+    pub fn record_at(&self, client: &Client, counts: Counts, now: DateTime<Utc>) {
+        let (subject, name) = if self.config.per_subject {
+            (client.subject.as_deref(), client.name.as_deref())
+        } else {
+            (None, None)
+        };
+        let capacity = self.config.capacity;
+        let slot = slot_number(now);
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+
+        if inner.slots.back().is_none_or(|(number, _)| *number < slot) {
+            inner.slots.push_back((slot, Tally::default()));
+            while inner.slots.front().is_some_and(|(number, _)| *number <= slot - SLOTS as i64) {
+                inner.slots.pop_front();
+            }
+        }
+        // A clock stepped back counts into the newest slot
+        let (_, tally) = inner.slots.back_mut().expect("a slot was just ensured");
+        tally.add(capacity, subject, name, counts);
+
+        if self.config.rollup_file.is_some() {
+            let date = now.date_naive();
+            if inner.day.as_ref().is_none_or(|(day, _)| *day < date) {
+                if let Some((day, tally)) = inner.day.replace((date, Tally::default())) {
+                    inner.pending.extend(rollup_records(day, tally));
+                }
+            }
+            let (_, tally) = inner.day.as_mut().expect("a day was just ensured");
+            tally.add(capacity, subject, name, counts);
+        }
+    }
+
+    /// The `limit` most active subjects over the trailing `window`
+    pub fn top(&self, window: Duration, limit: usize) -> UsageReport {
+        self.top_at(window, limit, Utc::now())
+    }
+
+    /// The `limit` most active subjects over the `window` trailing `now`
+    ///
+    /// The window is rounded up to whole slots, counting the current slot as
+    /// one, and capped at a day. A subject one slot's sketch was not
+    /// monitoring may have used up to that sketch's least active entry there,
+    /// which is added to its error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the usage lock.
+    #[allow(clippy::cast_possible_wrap)] // at most SLOTS, 96
+    pub fn top_at(&self, window: Duration, limit: usize, now: DateTime<Utc>) -> UsageReport {
+        let slots = usize::try_from(window.as_secs().div_ceil(SLOT.as_secs()))
+            .unwrap_or(SLOTS)
+            .clamp(1, SLOTS);
+        let newest = slot_number(now);
+        let oldest = newest - slots as i64 + 1;
+        let capacity = self.config.capacity;
+
+        let mut anonymous = Counts::default();
+        let mut total = Counts::default();
+        // Per subject: merged usage and the floors of the slots it was monitored in
+        let mut merged: HashMap<String, (ClientUsage, u64)> = HashMap::new();
+        let mut floors = 0;
+        let inner = self.inner.lock().expect("usage lock poisoned");
+        for (_, tally) in inner.slots.iter().filter(|(number, _)| (oldest..=newest).contains(number)) {
+            anonymous += tally.anonymous;
+            total += tally.total;
+            let floor = tally.sketch.floor(capacity);
+            floors += floor;
+            for (subject, entry) in &tally.sketch.entries {
+                let (usage, monitored_floors) = merged.entry(subject.clone()).or_insert_with(|| {
+                    let usage = ClientUsage {
+                        subject: subject.clone(),
+                        client: None,
+                        counts: Counts::default(),
+                        error: 0,
+                    };
+                    (usage, 0)
+                });
+                usage.counts += entry.counts;
+                usage.error += entry.error;
+                if entry.name.is_some() {
+                    usage.client.clone_from(&entry.name);
+                }
+                *monitored_floors += floor;
+            }
+        }
+        drop(inner);
+
+        let mut clients: Vec<_> = merged
+            .into_values()
+            .map(|(mut usage, monitored_floors)| {
+                usage.error += floors - monitored_floors;
+                usage
+            })
+            .collect();
+        clients.sort_by(|a, b| {
+            (b.counts.activity() + b.error)
+                .cmp(&(a.counts.activity() + a.error))
+                .then_with(|| a.subject.cmp(&b.subject))
+        });
+        clients.truncate(limit);
+
+        UsageReport {
+            window_secs: slots as u64 * SLOT.as_secs(),
+            since: slot_start(oldest),
+            subject_accounting: self.config.per_subject,
+            clients,
+            anonymous,
+            total,
+        }
+    }
+
+    /// Rollup records of days ended by `now`, and of the day in progress if `flush`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the usage lock.
+    pub fn take_rollup(&self, now: DateTime<Utc>, flush: bool) -> Vec<RollupRecord> {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        let ended = inner
+            .day
+            .as_ref()
+            .is_some_and(|(day, _)| flush || *day < now.date_naive());
+        if ended {
+            let (day, tally) = inner.day.take().expect("checked above");
+            inner.pending.extend(rollup_records(day, tally));
+        }
+        std::mem::take(&mut inner.pending)
+    }
+
+    /// Append rollups of ended days to the rollup file, as the `usage_rollup` task does every minute
+    ///
+    /// # Errors
+    ///
+    /// Fails where the rollup file cannot be written; the records are then kept
+    /// for the next attempt.
+    pub async fn rollup(&self) -> Result<()> {
+        self.write_rollup(false).await.map(|_| ())
+    }
+
+    /// Append the day in progress to the rollup file, as at shutdown, returning the records written
+    ///
+    /// # Errors
+    ///
+    /// Fails where the rollup file cannot be written.
+    pub async fn flush(&self) -> Result<usize> {
+        self.write_rollup(true).await
+    }
+
+    async fn write_rollup(&self, flush: bool) -> Result<usize> {
+        let Some(path) = &self.config.rollup_file else {
+            return Ok(0);
+        };
+        let records = self.take_rollup(Utc::now(), flush);
+        if records.is_empty() {
+            return Ok(0);
+        }
+        let mut lines = String::new();
+        for record in &records {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push('\n');
+        }
+        let written = async {
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+            file.write_all(lines.as_bytes()).await?;
+            file.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            // Kept for the next attempt
+            let mut inner = self.inner.lock().expect("usage lock poisoned");
+            let newer = std::mem::replace(&mut inner.pending, records);
+            inner.pending.extend(newer);
+            return Err(anyhow!("{}: {}", path.display(), e));
+        }
+        Ok(records.len())
+    }
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self::new(UsageConfig::default())
+    }
+}
+
+fn slot_number(at: DateTime<Utc>) -> i64 {
+    at.timestamp().div_euclid(SLOT.as_secs().cast_signed())
+}
+
+fn slot_start(number: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(number * SLOT.as_secs().cast_signed(), 0)
+        .single()
+        .unwrap_or_default()
+}
+
+/// Parse a window such as `90s`, `15m`, `1h` or `1d`, of at most a day
+///
+/// # Errors
+///
+/// Fails where `window` has no number or unit, or is longer than a day.
+#[allow(clippy::cast_possible_truncation)] // SLOTS is 96
+pub fn parse_window(window: &str) -> Result<Duration> {
+    let split = window.find(|c: char| !c.is_ascii_digit()).unwrap_or(window.len());
+    let (number, unit) = window.split_at(split);
+    let number: u64 = number.parse().map_err(|_| anyhow!("Invalid window: {window}"))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => bail!("Invalid window unit in {window}: use s, m, h or d"),
+    };
+    let window = Duration::from_secs(number.saturating_mul(unit));
+    if window.is_zero() {
+        bail!("Window must be positive");
+    }
+    if window > SLOT * SLOTS as u32 {
+        bail!("Window longer than the day of usage kept: {}", window.as_secs());
+    }
+    Ok(window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random numbers, for synthetic workloads
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        fn unit(&mut self) -> f64 {
+            (self.next() >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    /// Samples subject ranks with Zipf-distributed popularity
+    struct Zipf {
+        cumulative: Vec<f64>,
+    }
+
+    impl Zipf {
+        #[allow(clippy::cast_precision_loss)]
+        fn new(subjects: usize, exponent: f64) -> Self {
+            let mut sum = 0.0;
+            let cumulative = (1..=subjects)
+                .map(|rank| {
+                    sum += 1.0 / (rank as f64).powf(exponent);
+                    sum
+                })
+                .collect();
+            Self { cumulative }
+        }
+
+        fn sample(&self, random: &mut XorShift) -> usize {
+            let target = random.unit() * self.cumulative.last().unwrap();
+            self.cumulative.partition_point(|sum| *sum < target)
+        }
+    }
+
+    fn client(subject: &str) -> Client {
+        Client {
+            subject: Some(subject.to_string()),
+            name: Some(format!("{subject}-editor")),
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_800_000_000 + secs, 0).unwrap()
+    }
+
+    fn config(capacity: usize) -> UsageConfig {
+        UsageConfig {
+            capacity,
+            ..UsageConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_ranking_matches_exact_counts() {
+        for (seed, exponent) in [(1, 1.1), (7, 1.3), (0x9e37_79b9, 1.6)] {
+            let mut random = XorShift(seed);
+            let zipf = Zipf::new(5000, exponent);
+            let usage = Usage::new(config(200));
+            let events = 100_000;
+            let mut exact = vec![0u64; 5000];
+            for _ in 0..events {
+                let rank = zipf.sample(&mut random);
+                exact[rank] += 1;
+                usage.record_at(&client(&format!("subject-{rank}")), Counts::request(10), at(60));
+            }
+            let report = usage.top_at(SLOT, usize::MAX, at(60));
+            assert_eq!(report.total.requests, events);
+            assert!(report.clients.len() <= 200);
+
+            // Every subject reported is bracketed by its counts and its error
+            for reported in &report.clients {
+                let rank: usize = reported.subject["subject-".len()..].parse().unwrap();
+                assert!(reported.counts.requests <= exact[rank], "{reported:?}");
+                assert!(exact[rank] <= reported.counts.requests + reported.error, "{reported:?}");
+                assert_eq!(reported.counts.bytes, reported.counts.requests * 10);
+            }
+            // Every subject with more than 1/capacity of the traffic is reported
+            for (rank, count) in exact.iter().enumerate() {
+                if *count > events / 200 {
+                    let subject = format!("subject-{rank}");
+                    assert!(report.clients.iter().any(|c| c.subject == subject), "{subject} missing");
+                }
+            }
+            // The heaviest hitters come out exactly, in the exact order
+            let mut ranked: Vec<_> = (0..exact.len()).collect();
+            ranked.sort_by(|a, b| exact[*b].cmp(&exact[*a]).then_with(|| a.cmp(b)));
+            for (reported, rank) in report.clients.iter().zip(&ranked).take(10) {
+                assert_eq!(reported.subject, format!("subject-{rank}"));
+                assert_eq!(reported.counts.requests, exact[*rank]);
+                assert_eq!(reported.error, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_windows_merge_slots() {
+        let usage = Usage::new(config(2));
+        let slot = SLOT.as_secs().cast_signed();
+        // Two slots ago, then the last slot, then now
+        usage.record_at(&client("alice"), Counts::request(100), at(0));
+        usage.record_at(&client("alice"), Counts::conversion(), at(0));
+        usage.record_at(&client("bob"), Counts::ws_message(5), at(slot));
+        usage.record_at(&client("bob"), Counts::ws_message(5), at(slot));
+        usage.record_at(&Client::anonymous(), Counts::request(1), at(2 * slot));
+
+        let report = usage.top_at(SLOT, 10, at(2 * slot));
+        assert!(report.clients.is_empty());
+        assert_eq!(report.anonymous.requests, 1);
+        assert_eq!(report.since, at(2 * slot));
+
+        let report = usage.top_at(Duration::from_hours(1), 10, at(2 * slot));
+        assert_eq!(report.window_secs, 3600);
+        let subjects: Vec<_> = report.clients.iter().map(|c| c.subject.as_str()).collect();
+        assert_eq!(subjects, ["bob", "alice"]);
+        assert_eq!(report.clients[1].counts, Counts { requests: 1, conversions: 1, bytes: 100, ws_messages: 0 });
+        assert_eq!(report.clients[1].client.as_deref(), Some("alice-editor"));
+        assert_eq!(report.total.activity(), 4);
+
+        // A day on, everything has aged out
+        let report = usage.top_at(Duration::from_hours(24), 10, at(2 * slot + 86_400));
+        assert!(report.clients.is_empty());
+        assert_eq!(report.total, Counts::default());
+    }
+
+    #[test]
+    fn test_unmonitored_slots_add_error() {
+        let usage = Usage::new(config(1));
+        let slot = SLOT.as_secs().cast_signed();
+        for _ in 0..3 {
+            usage.record_at(&client("alice"), Counts::request(0), at(0));
+        }
+        // Carol displaces Bob in the second slot, inheriting Bob's activity
+        for _ in 0..2 {
+            usage.record_at(&client("bob"), Counts::request(0), at(slot));
+        }
+        usage.record_at(&client("carol"), Counts::request(0), at(slot));
+
+        let report = usage.top_at(SLOT * 2, 10, at(slot));
+        let alice = report.clients.iter().find(|c| c.subject == "alice").unwrap();
+        // Alice may have been among those displaced in the second slot
+        assert_eq!((alice.counts.requests, alice.error), (3, 3));
+        let carol = report.clients.iter().find(|c| c.subject == "carol").unwrap();
+        assert_eq!((carol.counts.requests, carol.error), (1, 2 + 3));
+        // A conversion alone displaces no one
+        usage.record_at(&client("dave"), Counts::conversion(), at(slot));
+        let report = usage.top_at(SLOT, 10, at(slot));
+        assert_eq!(report.clients.len(), 1);
+        assert_eq!(report.total.conversions, 1);
+    }
+
+    #[test]
+    fn test_subject_accounting_disabled() {
+        let usage = Usage::new(UsageConfig {
+            per_subject: false,
+            ..UsageConfig::default()
+        });
+        usage.record_at(&client("alice"), Counts::request(7), at(0));
+        usage.record_at(&Client::anonymous(), Counts::ws_message(3), at(0));
+        let report = usage.top_at(SLOT, 10, at(0));
+        assert!(!report.subject_accounting);
+        assert!(report.clients.is_empty());
+        assert_eq!(report.anonymous, report.total);
+        assert_eq!(report.anonymous.bytes, 10);
+    }
+
+    #[test]
+    fn test_daily_rollup() {
+        let usage = Usage::new(UsageConfig {
+            rollup_file: Some(PathBuf::from("unused")),
+            ..UsageConfig::default()
+        });
+        let day = 86_400;
+        usage.record_at(&client("alice"), Counts::request(1), at(0));
+        usage.record_at(&Client::anonymous(), Counts::request(1), at(0));
+        assert!(usage.take_rollup(at(60), false).is_empty());
+
+        // The first record of the next day closes the previous one
+        usage.record_at(&client("bob"), Counts::request(1), at(day));
+        let records = usage.take_rollup(at(day), false);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].date, at(0).date_naive());
+        assert_eq!(records[0].subject.as_deref(), Some("alice"));
+        assert_eq!(records[0].client.as_deref(), Some("alice-editor"));
+        assert_eq!(records[1].subject, None);
+
+        // The day in progress is only taken on a flush
+        assert!(usage.take_rollup(at(day + 60), false).is_empty());
+        let records = usage.take_rollup(at(day + 60), true);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].subject.as_deref(), Some("bob"));
+
+        let line = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(line["requests"], 1);
+        assert_eq!(line["date"], at(day).date_naive().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_rollup_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("ulc-usage-{}.jsonl", uuid::Uuid::new_v4()));
+        let usage = Usage::new(UsageConfig {
+            rollup_file: Some(path.clone()),
+            ..UsageConfig::default()
+        });
+        usage.record(&client("alice"), Counts::request(1));
+        usage.flush().await.unwrap();
+        usage.record(&client("alice"), Counts::request(1));
+        usage.flush().await.unwrap();
+        usage.flush().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<RollupRecord> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.subject.as_deref() == Some("alice") && r.counts.requests == 1));
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("1h").unwrap(), Duration::from_hours(1));
+        assert_eq!(parse_window("15m").unwrap(), SLOT);
+        assert_eq!(parse_window("1d").unwrap(), Duration::from_hours(24));
+        assert_eq!(parse_window("90s").unwrap(), Duration::from_secs(90));
+        assert!(parse_window("0h").is_err());
+        assert!(parse_window("2d").is_err());
+        assert!(parse_window("1w").is_err());
+        assert!(parse_window("h").is_err());
+        assert!(parse_window("").is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Fixed rings of recent counts for windowed rates
+//!
+//! A counter registered with a [`WindowSpec`] also counts its increments
+//! into a [`RateRing`] of per-interval buckets, so its rate over the last
+//! minute is known to the interval without sampling. Each bucket is one
+//! atomic word holding the interval it belongs to and its count, so an
+//! increment is a single compare-and-swap and a ring's memory is fixed at
+//! eight bytes a bucket.
+//!
+//! Rings live in memory: after a restart, or [`RateRing::reset`], rates
+//! cover only the time since.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Buckets a ring may hold
+pub const MAX_BUCKETS: usize = 3600;
+
+/// Bucket width and count of a ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSpec {
+    pub interval: Duration,
+    pub buckets: usize,
+}
+
+impl WindowSpec {
+    /// The last minute, to the second
+    pub const MINUTE: Self = Self::new(Duration::from_secs(1), 60);
+
+    #[must_use]
+    pub const fn new(interval: Duration, buckets: usize) -> Self {
+        Self { interval, buckets }
+    }
+
+    /// Longest window rates can be computed over
+    #[must_use]
+    pub fn span(&self) -> Duration {
+        self.interval * u32::try_from(self.buckets).unwrap_or(u32::MAX)
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.interval < Duration::from_millis(1) {
+            bail!("Rate window interval must be at least 1 ms");
+        }
+        if self.buckets == 0 || self.buckets > MAX_BUCKETS {
+            bail!("Rate window needs 1 to {MAX_BUCKETS} buckets");
+        }
+        Ok(())
+    }
+}
+
+/// Pack an interval number and a count into one bucket word
+///
+/// Interval numbers are truncated to 32 bits, which is only ambiguous for
+/// buckets more than 2³² intervals stale.
+fn pack(tick: u64, count: u64) -> u64 {
+    (tick << 32) | count.min(u64::from(u32::MAX))
+}
+
+fn unpack(word: u64) -> (u64, u64) {
+    (word >> 32, word & u64::from(u32::MAX))
+}
+
+/// Ring of per-interval counts
+#[derive(Debug)]
+pub struct RateRing {
+    spec: WindowSpec,
+    epoch: Instant,
+    /// Nanoseconds after `epoch` the counts start from
+    start: AtomicU64,
+    buckets: Box<[AtomicU64]>,
+}
+
+impl RateRing {
+    /// Empty ring counting from `now`
+    #[must_use]
+    pub fn new(spec: WindowSpec, now: Instant) -> Self {
+        Self {
+            spec,
+            epoch: now,
+            start: AtomicU64::new(0),
+            // Tagged with an interval that is never current, so every bucket starts empty
+            buckets: (0..spec.buckets).map(|_| AtomicU64::new(pack(u64::from(u32::MAX), 0))).collect(),
+        }
+    }
+
+    pub fn spec(&self) -> WindowSpec {
+        self.spec
+    }
+
+    fn nanos(&self, now: Instant) -> u64 {
+        u64::try_from(now.saturating_duration_since(self.epoch).as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    fn tick(&self, nanos: u64) -> u64 {
+        nanos / u64::try_from(self.spec.interval.as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    /// Count `n` at `now`
+    #[allow(clippy::cast_possible_truncation)] // an index below the bucket count
+    pub fn add(&self, n: u64, now: Instant) {
+        let tick = self.tick(self.nanos(now));
+        let tag = tick & u64::from(u32::MAX);
+        let bucket = &self.buckets[(tick % self.buckets.len() as u64) as usize];
+        let mut current = bucket.load(Ordering::Relaxed);
+        loop {
+            let (held, count) = unpack(current);
+            let next = if held == tag { pack(tag, count.saturating_add(n)) } else { pack(tag, n) };
+            match bucket.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Drop every count and start again from `now`
+    ///
+    /// Increments racing the reset may survive it.
+    pub fn reset(&self, now: Instant) {
+        self.start.store(self.nanos(now), Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.store(pack(u64::from(u32::MAX), 0), Ordering::Relaxed);
+        }
+    }
+
+    /// Counts of every bucket as of `now`, newest first
+    #[allow(clippy::cast_possible_truncation)] // an index below the bucket count
+    pub fn snapshot(&self, now: Instant) -> WindowSnapshot {
+        let nanos = self.nanos(now);
+        let tick = self.tick(nanos);
+        let interval = u64::try_from(self.spec.interval.as_nanos()).unwrap_or(u64::MAX);
+        let len = self.buckets.len() as u64;
+        let counts = (0..len.min(tick + 1))
+            .map(|age| {
+                let tick = tick - age;
+                let (held, count) = unpack(self.buckets[(tick % len) as usize].load(Ordering::Relaxed));
+                if held == tick & u64::from(u32::MAX) {
+                    count
+                } else {
+                    0
+                }
+            })
+            .collect();
+        WindowSnapshot {
+            interval_secs: self.spec.interval.as_secs_f64(),
+            counts,
+            current_secs: Duration::from_nanos(nanos - tick * interval).as_secs_f64(),
+            counted_secs: Duration::from_nanos(nanos.saturating_sub(self.start.load(Ordering::Relaxed))).as_secs_f64(),
+        }
+    }
+
+    /// Per-second rate over the trailing `window` as of `now`
+    pub fn rate(&self, window: Duration, now: Instant) -> Option<f64> {
+        self.snapshot(now).rate(window)
+    }
+}
+
+/// Counts of a [`RateRing`] at one point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowSnapshot {
+    /// Width of a bucket
+    pub interval_secs: f64,
+    /// Count of each bucket, newest (and still filling) first
+    pub counts: Vec<u64>,
+    /// Time spent in the newest bucket so far
+    pub current_secs: f64,
+    /// Time since counting started or was last reset
+    pub counted_secs: f64,
+}
+
+impl WindowSnapshot {
+    /// Longest window [`WindowSnapshot::rate`] covers
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn span(&self) -> Duration {
+        Duration::from_secs_f64(self.interval_secs * self.counts.len() as f64)
+    }
+
+    /// Whether the ring can answer for the trailing `window`
+    ///
+    /// A ring still filling up covers everything counted so far, whatever
+    /// its eventual span.
+    #[must_use]
+    pub fn covers(&self, window: Duration) -> bool {
+        let span = self.span();
+        span >= window || self.counted_secs <= span.as_secs_f64()
+    }
+
+    /// Per-second rate over the trailing `window`, rounded up to whole buckets
+    /// and capped at the ring's span
+    ///
+    /// `None` when no time has been counted.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    pub fn rate(&self, window: Duration) -> Option<f64> {
+        let buckets = ((window.as_secs_f64() / self.interval_secs).ceil() as usize).clamp(1, self.counts.len().max(1));
+        let count: u64 = self.counts.iter().take(buckets).sum();
+        let covered = ((buckets - 1) as f64 * self.interval_secs + self.current_secs).min(self.counted_secs);
+        (covered > 0.0).then(|| count as f64 / covered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Deterministic pseudo-random numbers, for generated increment patterns
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn within(actual: Option<f64>, expected: f64, tolerance: f64) -> bool {
+        actual.is_some_and(|actual| (actual - expected).abs() <= tolerance * expected.max(1.0))
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_steady_rates_within_tolerance() {
+        let mut random = XorShift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..50 {
+            let per_second = 1 + random.below(500);
+            let ring = RateRing::new(WindowSpec::MINUTE, Instant::now());
+            let start = ring.epoch;
+            let seconds = 30 + random.below(200);
+            // Evenly spread over each second, at 10 ms steps
+            for step in 0..seconds * 100 {
+                let at = start + Duration::from_millis(step * 10);
+                ring.add(per_second / 100 + u64::from(step % 100 < per_second % 100), at);
+            }
+            let now = start + SECOND * u32::try_from(seconds).unwrap();
+            for window in [SECOND * 10, SECOND * 60] {
+                let rate = ring.rate(window, now);
+                assert!(within(rate, per_second as f64, 0.02), "{per_second}/s over {window:?}: {rate:?}");
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn test_bursts_straddling_bucket_boundaries() {
+        let mut random = XorShift(42);
+        for _ in 0..200 {
+            let ring = RateRing::new(WindowSpec::new(SECOND, 10), Instant::now());
+            let start = ring.epoch;
+            // A burst either side of a boundary; only what falls inside the window counts
+            let boundary = 5 + random.below(20);
+            let (before, after) = (random.below(1000), random.below(1000));
+            ring.add(before, (start + SECOND * u32::try_from(boundary).unwrap()).checked_sub(Duration::from_millis(1)).unwrap());
+            ring.add(after, start + SECOND * u32::try_from(boundary).unwrap());
+            let now = start + SECOND * u32::try_from(boundary + 9).unwrap() + Duration::from_millis(500);
+
+            // Ten buckets back from `now` start exactly at the boundary
+            let rate = ring.rate(SECOND * 10, now).unwrap();
+            assert!((rate - after as f64 / 9.5).abs() < 1e-9, "{rate}");
+            // A second on, the burst after the boundary has aged out too
+            let rate = ring.rate(SECOND * 10, now + SECOND).unwrap();
+            assert!(rate.abs() < 1e-9, "{rate}");
+        }
+    }
+
+    #[test]
+    fn test_window_clamped_and_limited_to_counted_time() {
+        let ring = RateRing::new(WindowSpec::new(SECOND, 10), Instant::now());
+        let start = ring.epoch;
+        ring.add(30, start + Duration::from_millis(200));
+        // Only 3 s counted: a longer window divides by 3 s, not by the window
+        assert!(within(ring.rate(SECOND * 60, start + SECOND * 3), 10.0, 1e-9));
+        assert_eq!(ring.snapshot(start + SECOND * 3).counts.len(), 4);
+        assert_eq!(ring.rate(SECOND, start), None);
+        // Past the span, counts are gone however long the window
+        assert!(within(ring.rate(SECOND * 60, start + SECOND * 10), 0.0, 1e-9));
+    }
+
+    #[test]
+    fn test_reset_starts_again() {
+        let ring = RateRing::new(WindowSpec::MINUTE, Instant::now());
+        let start = ring.epoch;
+        for second in 0..30 {
+            ring.add(100, start + SECOND * second);
+        }
+        ring.reset(start + SECOND * 30);
+        assert_eq!(ring.rate(SECOND * 60, start + SECOND * 30), None);
+        ring.add(20, start + SECOND * 31);
+        assert!(within(ring.rate(SECOND * 60, start + SECOND * 32), 10.0, 1e-9));
+    }
+
+    #[test]
+    fn test_concurrent_increments_are_not_lost() {
+        let ring = std::sync::Arc::new(RateRing::new(WindowSpec::MINUTE, Instant::now()));
+        let now = ring.epoch + Duration::from_millis(500);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let ring = std::sync::Arc::clone(&ring);
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        ring.add(1, now);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(ring.snapshot(now).counts, vec![80_000]);
+    }
+
+    #[test]
+    fn test_spec_validation() {
+        assert!(WindowSpec::MINUTE.validate().is_ok());
+        assert_eq!(WindowSpec::MINUTE.span(), SECOND * 60);
+        assert!(WindowSpec::new(Duration::ZERO, 60).validate().is_err());
+        assert!(WindowSpec::new(SECOND, 0).validate().is_err());
+        assert!(WindowSpec::new(SECOND, MAX_BUCKETS + 1).validate().is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! Converting and validating files from the command line
+//!
+//! The `convert` and `validate` subcommands run documents through
+//! [`Formats`] directly: no server state is built and nothing is bound.
+//! Inputs are files, glob patterns expanded here as well as by the shell,
+//! or stdin (`-`, or no argument at all). The input format is the one given
+//! with `--from`, else the one the extension names, else the one the
+//! content looks like; `.env` files and `.env.*` variants are dotenv.
+//!
+//! `MessagePack` and CBOR files are binary, and are read and written as their
+//! bytes, which the formats take as base64 text. Property lists are read
+//! the same way when binary, and written as XML.
+//!
+//! Each file is handled on its own, and the exit code is that of the worst
+//! one: 0 when every file converted or validated cleanly, 1 when validation
+//! found problems, and 2 when a file could not be read, parsed or written.
+
+use crate::core::{ConversionOptions, ConversionRequest, Format};
+use crate::formats::datetime::DateTimeStyle;
+use crate::formats::yaml::{self, References, Stream, YamlOptions};
+use crate::formats::{self, cbor, msgpack, ndjson, plist, FormatLimits, FormatRef, Formats, OutputOptions};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Name an input read from stdin is reported by
+const STDIN: &str = "<stdin>";
+
+/// How a file, or a whole run, ended, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Converted, or validated without diagnostics
+    Ok,
+    /// Validated with diagnostics
+    Findings,
+    /// Not read, parsed, converted or written
+    Error,
+}
+
+impl Status {
+    /// Exit code of a run ending this way
+    #[must_use]
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::Findings => 1,
+            Self::Error => 2,
+        }
+    }
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+/// Arguments of `convert`
+#[derive(Debug, Clone, clap::Args)]
+pub struct ConvertArgs {
+    /// Files or glob patterns to convert; stdin when none or `-`
+    #[arg(value_name = "FILE")]
+    pub files: Vec<String>,
+    /// Format of the input, instead of the one its extension or content suggests
+    #[arg(long, value_name = "FORMAT")]
+    pub from: Option<String>,
+    /// Format to convert to
+    #[arg(long, value_name = "FORMAT")]
+    pub to: String,
+    /// File to write to, or directory for several inputs; stdout by default
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+    /// Spaces per level of JSON output, 0 for compact
+    #[arg(long, value_name = "N")]
+    pub indent: Option<usize>,
+    /// Sort the keys of JSON objects
+    #[arg(long)]
+    pub sort_keys: bool,
+    /// Write canonical JSON: compact, with sorted keys
+    #[arg(long)]
+    pub canonical: bool,
+    /// How YAML streams of several documents convert to JSON, and JSON back to them
+    #[arg(long, value_enum, default_value_t = Stream::Single)]
+    pub stream: Stream,
+    /// Whether YAML aliases convert to JSON as copies or references, and JSON references back to aliases
+    #[arg(long, value_enum, default_value_t = References::Expand)]
+    pub references: References,
+    /// How date-times are written: as they were read, as RFC 3339, or as seconds since the Unix epoch
+    #[arg(long, value_enum, default_value_t = DateTimeStyle::Preserve)]
+    pub datetimes: DateTimeStyle,
+}
+
+/// Arguments of `validate`
+#[derive(Debug, Clone, clap::Args)]
+pub struct ValidateArgs {
+    /// Files or glob patterns to validate; stdin when none or `-`
+    #[arg(value_name = "FILE")]
+    pub files: Vec<String>,
+    /// Format of the input, instead of the one its extension or content suggests
+    #[arg(long, value_name = "FORMAT")]
+    pub from: Option<String>,
+    /// How results are reported
+    #[arg(long, value_enum, default_value_t = Report::Text)]
+    pub format: Report,
+}
+
+/// Report format of `validate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Report {
+    /// One line per diagnostic, for people
+    Text,
+    /// A JSON array with one object per file
+    Json,
+    /// GitHub Actions workflow commands, annotating each file
+    Github,
+}
+
+/// Where a document comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    fn name(&self) -> String {
+        match self {
+            Self::Stdin => STDIN.to_string(),
+            Self::File(path) => path.display().to_string(),
+        }
+    }
+
+    fn read(&self) -> Result<String> {
+        match self {
+            Self::Stdin => {
+                let mut content = String::new();
+                std::io::stdin().read_to_string(&mut content).context("reading stdin")?;
+                Ok(content)
+            }
+            Self::File(path) => std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// The input as the formats take it: text, or for a binary format its bytes in base64
+    fn read_as(&self, formats: &Formats, from: Option<&str>) -> Result<String> {
+        let encode: fn(&[u8]) -> String = match self.declared_format(formats, from) {
+            Some(Ok(FormatRef::BuiltIn(Format::Msgpack))) => msgpack::encode_text,
+            Some(Ok(FormatRef::BuiltIn(Format::Cbor))) => cbor::encode_text,
+            Some(Ok(FormatRef::BuiltIn(Format::Plist))) => plist::encode_text,
+            _ => return self.read(),
+        };
+        let mut bytes = Vec::new();
+        self.open()?.read_to_end(&mut bytes).with_context(|| format!("reading {}", self.name()))?;
+        Ok(encode(&bytes))
+    }
+
+    /// The input, to be read a line at a time
+    fn open(&self) -> Result<Box<dyn BufRead>> {
+        match self {
+            Self::Stdin => Ok(Box::new(std::io::stdin().lock())),
+            Self::File(path) => {
+                let file = File::open(path).with_context(|| format!("reading {}", path.display()))?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+        }
+    }
+
+    /// Format given by `from`, else named by the extension, else guessed from `content`
+    fn format(&self, formats: &Formats, from: Option<&str>, content: &str) -> Result<FormatRef> {
+        self.declared_format(formats, from).unwrap_or_else(|| Ok(FormatRef::BuiltIn(formats::detect(content))))
+    }
+
+    /// Format given by `from`, else named by the extension, known before the input is read
+    fn declared_format(&self, formats: &Formats, from: Option<&str>) -> Option<Result<FormatRef>> {
+        if let Some(from) = from {
+            return Some(formats.resolve(from));
+        }
+        let Self::File(path) = self else {
+            return None;
+        };
+        // `.env` and `.env.local` have no extension naming their format
+        let name = path.file_name()?.to_str()?;
+        if name == ".env" || name.starts_with(".env.") {
+            return Some(Ok(FormatRef::BuiltIn(Format::Dotenv)));
+        }
+        formats.resolve(path.extension()?.to_str()?).ok().map(Ok)
+    }
+}
+
+/// The inputs `patterns` name, and the patterns that name none
+fn expand(patterns: &[String]) -> (Vec<Input>, Vec<(String, anyhow::Error)>) {
+    if patterns.is_empty() {
+        return (vec![Input::Stdin], Vec::new());
+    }
+    let (mut inputs, mut failed) = (Vec::new(), Vec::new());
+    for pattern in patterns {
+        if pattern == "-" {
+            inputs.push(Input::Stdin);
+        } else if !pattern.contains(['*', '?', '[']) || Path::new(pattern).exists() {
+            inputs.push(Input::File(PathBuf::from(pattern)));
+        } else {
+            let matched: Result<Vec<PathBuf>> = glob::glob(pattern)
+                .map_err(|e| anyhow!("invalid pattern: {e}"))
+                .and_then(|paths| paths.map(|path| path.map_err(|e| anyhow!("{e}"))).collect());
+            match matched {
+                Ok(paths) if paths.is_empty() => failed.push((pattern.clone(), anyhow!("no files match"))),
+                Ok(paths) => inputs.extend(paths.into_iter().filter(|path| path.is_file()).map(Input::File)),
+                Err(e) => failed.push((pattern.clone(), e)),
+            }
+        }
+    }
+    (inputs, failed)
+}
+
+/// Convert every input, writing each result to stdout or under `--output`
+#[must_use]
+pub fn convert(args: &ConvertArgs) -> Status {
+    let formats = Formats::standalone(FormatLimits::default());
+    let options = OutputOptions { indent: args.indent, sort_keys: args.sort_keys, canonical: args.canonical };
+    if args.stream == Stream::Ndjson && !options.is_default() {
+        return fail("--indent, --sort-keys and --canonical lay out a single JSON document, not NDJSON");
+    }
+    let to = match target(&formats, &args.to, &options) {
+        Ok(to) => to,
+        Err(e) => return fail(&format!("{e:#}")),
+    };
+    let (inputs, failed) = expand(&args.files);
+    let mut status = Status::Ok;
+    for (pattern, e) in failed {
+        status = fail(&format!("{pattern}: {e:#}"));
+    }
+    let several = inputs.len() > 1 || args.files.iter().any(|pattern| pattern.contains(['*', '?', '[']));
+    if several && args.output.as_ref().is_none_or(|output| output.is_file()) {
+        return fail("Several inputs need --output naming a directory");
+    }
+
+    for input in &inputs {
+        let destination = match &args.output {
+            Some(dir) if several => Some(dir.join(output_name(input, &to))),
+            other => other.clone(),
+        };
+        let converted = match convert_streaming(&formats, args, &options, input, &to, destination.as_deref()) {
+            Some(streamed) => streamed,
+            None => convert_one(&formats, args, &options, input, &to).and_then(|output| match to {
+                FormatRef::BuiltIn(Format::Msgpack) => write_bytes(destination.as_deref(), &msgpack::decode_text(&output)?),
+                FormatRef::BuiltIn(Format::Cbor) => write_bytes(destination.as_deref(), &cbor::decode_text(&output)?),
+                _ => write(destination.as_deref(), &output),
+            }),
+        };
+        if let Err(e) = converted {
+            status = fail(&format!("{}: {:#}", input.name(), e));
+        }
+    }
+    status
+}
+
+/// The format converted to, checked against the layout options
+fn target(formats: &Formats, name: &str, options: &OutputOptions) -> Result<FormatRef> {
+    let to = formats.resolve(name)?;
+    if !options.is_default() && !matches!(to, FormatRef::BuiltIn(Format::Json)) {
+        bail!("--indent, --sort-keys and --canonical apply to JSON output only");
+    }
+    Ok(to)
+}
+
+fn convert_one(
+    formats: &Formats,
+    args: &ConvertArgs,
+    options: &OutputOptions,
+    input: &Input,
+    to: &FormatRef,
+) -> Result<String> {
+    let content = input.read_as(formats, args.from.as_deref())?;
+    let from = input.format(formats, args.from.as_deref(), &content)?;
+    let yaml = YamlOptions { stream: args.stream, references: args.references };
+    let datetimes = args.datetimes != DateTimeStyle::default();
+    let output = match (&from, to) {
+        _ if datetimes && yaml != YamlOptions::default() => bail!("--datetimes does not combine with --stream or --references"),
+        (FormatRef::BuiltIn(from), FormatRef::BuiltIn(to)) if datetimes => {
+            let request = ConversionRequest { content, from: *from, to: *to };
+            formats.convert_with(request, &ConversionOptions { datetimes: args.datetimes, ..Default::default() })?.content
+        }
+        _ if datetimes => bail!("--datetimes applies to built-in formats only"),
+        _ if yaml == YamlOptions::default() => formats.convert_any(&content, &from, to)?,
+        (FormatRef::BuiltIn(Format::Yaml), FormatRef::BuiltIn(Format::Json)) => yaml::yaml_to_json_with(&content, &yaml)?,
+        (FormatRef::BuiltIn(Format::Json), FormatRef::BuiltIn(Format::Yaml)) => yaml::json_to_yaml_with(&content, &yaml)?,
+        _ => bail!("--stream and --references apply to YAML to JSON and JSON to YAML only"),
+    };
+    if args.stream == Stream::Ndjson {
+        return Ok(output);
+    }
+    options.apply(&output)
+}
+
+/// Convert NDJSON to a JSON array, or back, a record at a time, where the
+/// input's format is known before reading it and no layout is asked for
+fn convert_streaming(
+    formats: &Formats,
+    args: &ConvertArgs,
+    options: &OutputOptions,
+    input: &Input,
+    to: &FormatRef,
+    destination: Option<&Path>,
+) -> Option<Result<()>> {
+    if args.stream != Stream::Single || args.references != References::Expand || !options.is_default() {
+        return None;
+    }
+    let from = match input.declared_format(formats, args.from.as_deref())? {
+        Ok(from) => from,
+        Err(e) => return Some(Err(e)),
+    };
+    let convert: fn(&mut dyn BufRead, &mut dyn Write) -> Result<usize> = match (&from, to) {
+        (FormatRef::BuiltIn(Format::Ndjson), FormatRef::BuiltIn(Format::Json)) => |reader, writer| ndjson::ndjson_to_array(reader, writer),
+        (FormatRef::BuiltIn(Format::Json), FormatRef::BuiltIn(Format::Ndjson)) => |reader, writer| ndjson::array_to_ndjson(reader, writer),
+        _ => return None,
+    };
+    let streamed = input.open().and_then(|mut reader| {
+        let mut writer: Box<dyn Write> = match destination {
+            Some(path) => {
+                let file = File::create(path).with_context(|| format!("writing {}", path.display()))?;
+                Box::new(BufWriter::new(file))
+            }
+            None => Box::new(std::io::stdout().lock()),
+        };
+        convert(&mut reader, &mut writer)?;
+        Ok(())
+    });
+    Some(streamed)
+}
+
+/// File name of the result of converting `input` to `to`, within the output directory
+fn output_name(input: &Input, to: &FormatRef) -> String {
+    let stem = match input {
+        Input::File(path) => path.file_stem().map_or_else(|| "stdin".into(), |stem| stem.to_string_lossy()),
+        Input::Stdin => "stdin".into(),
+    };
+    let extension = match to {
+        FormatRef::BuiltIn(format) => format.extension(),
+        FormatRef::Plugin(plugin) => plugin.name(),
+    };
+    format!("{stem}.{extension}")
+}
+
+/// Write `output` to `path`, or stdout, ending it with a newline
+fn write(path: Option<&Path>, output: &str) -> Result<()> {
+    let newline = if output.ends_with('\n') { "" } else { "\n" };
+    if let Some(path) = path {
+        std::fs::write(path, format!("{output}{newline}")).with_context(|| format!("writing {}", path.display()))
+    } else {
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "{output}{newline}").and_then(|()| stdout.flush()).context("writing stdout")
+    }
+}
+
+/// Write `bytes` to `path`, or stdout, as they are
+fn write_bytes(path: Option<&Path>, bytes: &[u8]) -> Result<()> {
+    if let Some(path) = path {
+        std::fs::write(path, bytes).with_context(|| format!("writing {}", path.display()))
+    } else {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes).and_then(|()| stdout.flush()).context("writing stdout")
+    }
+}
+
+/// Report `message` on stderr, returning the status of a hard error
+fn fail(message: &str) -> Status {
+    eprintln!("error: {message}");
+    Status::Error
+}
+
+/// The validation of one input
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    /// Format the input was validated as, unless it was not read
+    pub format: Option<String>,
+    pub status: Status,
+    pub diagnostics: Vec<String>,
+    /// Why the input could not be validated
+    pub error: Option<String>,
+}
+
+/// Validate every input, reporting on stdout as `--format` says
+pub fn validate(args: &ValidateArgs) -> Status {
+    let reports = validate_all(args);
+    let status = reports.iter().map(|report| report.status).max().unwrap_or(Status::Ok);
+    let mut stdout = std::io::stdout().lock();
+    let written = match args.format {
+        Report::Text => reports.iter().try_for_each(|report| write_text(&mut stdout, report)),
+        Report::Json => serde_json::to_writer_pretty(&mut stdout, &reports)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(stdout)),
+        Report::Github => reports.iter().try_for_each(|report| write_github(&mut stdout, report)),
+    };
+    match written.and_then(|()| stdout.flush()) {
+        Ok(()) => status,
+        Err(e) => fail(&format!("writing stdout: {e}")),
+    }
+}
+
+/// The reports of every input, in the order given
+pub fn validate_all(args: &ValidateArgs) -> Vec<FileReport> {
+    let formats = Formats::standalone(FormatLimits::default());
+    let (inputs, failed) = expand(&args.files);
+    let mut reports: Vec<FileReport> = failed
+        .into_iter()
+        .map(|(pattern, e)| FileReport {
+            path: pattern,
+            format: None,
+            status: Status::Error,
+            diagnostics: Vec::new(),
+            error: Some(format!("{e:#}")),
+        })
+        .collect();
+    for input in inputs {
+        let mut report =
+            FileReport { path: input.name(), format: None, status: Status::Ok, diagnostics: Vec::new(), error: None };
+        let validated = match input.declared_format(&formats, args.from.as_deref()) {
+            // NDJSON is validated a line at a time, however long the input
+            Some(Ok(FormatRef::BuiltIn(Format::Ndjson))) => {
+                report.format = Some(Format::Ndjson.name().to_string());
+                input.open().and_then(ndjson::validate_reader).map(|diagnostics| {
+                    diagnostics.iter().map(|diagnostic| format!("Invalid NDJSON: {diagnostic}")).collect()
+                })
+            }
+            _ => input.read_as(&formats, args.from.as_deref()).and_then(|content| {
+                let format = input.format(&formats, args.from.as_deref(), &content)?;
+                report.format = Some(format.name().to_string());
+                formats.validate_any(&content, &format)
+            }),
+        };
+        match validated {
+            Ok(diagnostics) if diagnostics.is_empty() => {}
+            Ok(diagnostics) => {
+                report.status = Status::Findings;
+                report.diagnostics = diagnostics;
+            }
+            Err(e) => {
+                report.status = Status::Error;
+                report.error = Some(format!("{e:#}"));
+            }
+        }
+        reports.push(report);
+    }
+    reports
+}
+
+fn write_text(out: &mut impl Write, report: &FileReport) -> std::io::Result<()> {
+    match (&report.error, report.diagnostics.is_empty()) {
+        (Some(error), _) => writeln!(out, "{}: error: {}", report.path, error),
+        (None, true) => writeln!(out, "{}: ok", report.path),
+        (None, false) => report.diagnostics.iter().try_for_each(|diagnostic| writeln!(out, "{}: {}", report.path, diagnostic)),
+    }
+}
+
+/// One `::warning` per diagnostic and an `::error` for a file not validated
+fn write_github(out: &mut impl Write, report: &FileReport) -> std::io::Result<()> {
+    let file = if report.path == STDIN {
+        String::new()
+    } else {
+        format!(" file={}", escape_property(&report.path))
+    };
+    if let Some(error) = &report.error {
+        writeln!(out, "::error{}::{}", file, escape_data(error))?;
+    }
+    for diagnostic in &report.diagnostics {
+        writeln!(out, "::warning{}::{}", file, escape_data(diagnostic))?;
+    }
+    Ok(())
+}
+
+/// Escape the message of a workflow command
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escape a property value of a workflow command
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github_annotations() {
+        let report = FileReport {
+            path: "config/a,b.yaml".to_string(),
+            format: Some("yaml".to_string()),
+            status: Status::Findings,
+            diagnostics: vec!["100% wrong:\nreally".to_string()],
+            error: None,
+        };
+        let mut out = Vec::new();
+        write_github(&mut out, &report).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "::warning file=config/a%2Cb.yaml::100%25 wrong:%0Areally\n");
+
+        let stdin = FileReport { path: STDIN.to_string(), error: Some("unreadable".to_string()), ..report };
+        let mut out = Vec::new();
+        write_github(&mut out, &stdin).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("::error::unreadable\n"));
+    }
+}
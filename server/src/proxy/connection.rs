@@ -0,0 +1,279 @@
+//! JSON-RPC with one downstream language server
+//!
+//! A [`Connection`] sends requests and notifications to the server and
+//! matches its responses to requests by ID. Requests and notifications the
+//! server sends are handed to the receiver returned with the connection,
+//! which ends when the connection does.
+
+use super::DownstreamTransport;
+use crate::config::duration;
+use crate::lsp::{read_message, write_message};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, warn};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Requests awaiting a response, by ID
+type Pending = Arc<Mutex<HashMap<i64, oneshot::Sender<Value>>>>;
+
+/// An open connection to a downstream server
+pub struct Connection {
+    name: String,
+    outgoing: mpsc::UnboundedSender<Value>,
+    pending: Pending,
+    next_id: AtomicI64,
+    closed: watch::Receiver<bool>,
+    /// Reading, writing and stderr tasks, aborted when the connection is dropped
+    tasks: Vec<JoinHandle<()>>,
+    /// The spawned server, killed when the connection is dropped
+    _child: Option<Child>,
+}
+
+impl Connection {
+    /// Connect to the server called `name` over `transport`
+    ///
+    /// Returns the connection and the messages the server sends unprompted.
+    ///
+    /// # Errors
+    ///
+    /// Fails where the command cannot be run or the address reached.
+    pub async fn open(name: &str, transport: &DownstreamTransport) -> Result<(Self, mpsc::UnboundedReceiver<Value>)> {
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (closed_tx, closed) = watch::channel(false);
+        let pending = Pending::default();
+        let io = Io { name: name.to_string(), pending: Arc::clone(&pending), incoming: incoming_tx, closed: closed_tx };
+
+        let (tasks, child) = match transport {
+            DownstreamTransport::Stdio(command) => {
+                let (program, args) = command.split_first().ok_or_else(|| anyhow!("No command to run"))?;
+                let mut child = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| format!("Failed to run {program}"))?;
+                let (Some(stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+                    bail!("Failed to pipe {program}");
+                };
+                let mut tasks = io.framed(stdout, stdin, outgoing_rx);
+                let name = name.to_string();
+                tasks.push(tokio::spawn(async move {
+                    // Servers log to stderr; keep it out of the way unless asked for
+                    let mut lines = BufReader::new(stderr).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        debug!(downstream = %name, "{}", line);
+                    }
+                }));
+                (tasks, Some(child))
+            }
+            DownstreamTransport::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await.with_context(|| format!("Failed to connect to {addr}"))?;
+                let (reader, writer) = stream.into_split();
+                (io.framed(reader, writer, outgoing_rx), None)
+            }
+            DownstreamTransport::WebSocket(url) => {
+                let (socket, _) = tokio_tungstenite::connect_async(url.as_str())
+                    .await
+                    .with_context(|| format!("Failed to connect to {url}"))?;
+                (io.websocket(socket, outgoing_rx), None)
+            }
+        };
+
+        let connection = Self {
+            name: name.to_string(),
+            outgoing,
+            pending,
+            next_id: AtomicI64::new(1),
+            closed,
+            tasks,
+            _child: child,
+        };
+        Ok((connection, incoming))
+    }
+
+    /// Send a request and wait up to `timeout` for its result
+    ///
+    /// # Errors
+    ///
+    /// Fails where the connection is closed, the server answers with an error,
+    /// or not within `timeout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the pending requests' lock.
+    pub async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("pending request lock poisoned").insert(id, tx);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+
+        let response = match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => bail!("{} exited before answering {}", self.name, method),
+            Err(_) => {
+                self.pending.lock().expect("pending request lock poisoned").remove(&id);
+                let _ = self.notify("$/cancelRequest", json!({ "id": id }));
+                bail!("{} did not answer {} within {}", self.name, method, duration::format(timeout));
+            }
+        };
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("no message");
+            bail!("{} failed {}: {}", self.name, method, message);
+        }
+        Ok(response.get("result").cloned().unwrap_or(Value::Null))
+    }
+
+    /// Send a notification
+    ///
+    /// # Errors
+    ///
+    /// Fails where the connection is closed.
+    #[allow(clippy::needless_pass_by_value)] // As `request` takes them
+    pub fn notify(&self, method: &str, params: Value) -> Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    /// Answer the server's request `id`
+    ///
+    /// # Errors
+    ///
+    /// Fails where the connection is closed.
+    #[allow(clippy::needless_pass_by_value)] // As `request` takes them
+    pub fn respond(&self, id: Value, result: Value) -> Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+
+    /// Whether the server has gone away
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Ask the server to shut down and exit, waiting up to `timeout` for it to go
+    pub async fn shutdown(&self, timeout: Duration) {
+        if self.is_closed() {
+            return;
+        }
+        if let Err(e) = self.request("shutdown", Value::Null, timeout).await {
+            debug!("{} did not shut down cleanly: {:#}", self.name, e);
+        }
+        let _ = self.notify("exit", Value::Null);
+        let mut closed = self.closed.clone();
+        let _ = tokio::time::timeout(timeout, closed.wait_for(|closed| *closed)).await;
+    }
+
+    fn send(&self, message: Value) -> Result<()> {
+        self.outgoing.send(message).map_err(|_| anyhow!("{} is not running", self.name))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// What the reading task needs to pass messages on and report the end of the connection
+struct Io {
+    name: String,
+    pending: Pending,
+    incoming: mpsc::UnboundedSender<Value>,
+    closed: watch::Sender<bool>,
+}
+
+impl Io {
+    /// Tasks exchanging Content-Length framed messages over `reader` and `writer`
+    fn framed<R, W>(self, reader: R, mut writer: W, mut outgoing: mpsc::UnboundedReceiver<Value>) -> Vec<JoinHandle<()>>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let writing = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if write_message(&mut writer, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let reading = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                match read_message(&mut reader).await {
+                    Ok(Some(message)) => self.dispatch(message),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Invalid message from {}: {:#}", self.name, e);
+                        break;
+                    }
+                }
+            }
+            self.close();
+        });
+        vec![writing, reading]
+    }
+
+    /// Tasks exchanging one message per text frame over a WebSocket
+    fn websocket(self, socket: Socket, mut outgoing: mpsc::UnboundedReceiver<Value>) -> Vec<JoinHandle<()>> {
+        let (mut sink, mut stream) = socket.split();
+        let writing = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if sink.send(Message::Text(message.to_string())).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let reading = tokio::spawn(async move {
+            while let Some(Ok(frame)) = stream.next().await {
+                match frame {
+                    Message::Text(text) => match serde_json::from_str(&text) {
+                        Ok(message) => self.dispatch(message),
+                        Err(e) => warn!("Invalid message from {}: {}", self.name, e),
+                    },
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            self.close();
+        });
+        vec![writing, reading]
+    }
+
+    /// Pass a response to its request, and anything else to the receiver
+    fn dispatch(&self, message: Value) {
+        if message.get("method").is_some() {
+            let _ = self.incoming.send(message);
+            return;
+        }
+        let waiter = message
+            .get("id")
+            .and_then(Value::as_i64)
+            .and_then(|id| self.pending.lock().expect("pending request lock poisoned").remove(&id));
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(message);
+        } else { debug!("Unexpected response from {}: {}", self.name, message) }
+    }
+
+    /// Fail the requests still waiting and mark the connection closed
+    fn close(self) {
+        self.pending.lock().expect("pending request lock poisoned").clear();
+        self.closed.send_replace(true);
+    }
+}
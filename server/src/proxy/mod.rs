@@ -0,0 +1,841 @@
+//! Fronting other language servers
+//!
+//! A [`DownstreamConfig`] names a language server the connector stands in
+//! front of, such as rust-analyzer: a command spawned over stdio, or a TCP
+//! or WebSocket address. Documents whose language ID is in its `languages`,
+//! or whose URI matches one of its `patterns`, are mirrored to it as they
+//! open, change, save and close, and requests about them are forwarded to
+//! it with the answers passed back. Its diagnostics are published to the
+//! editor, and the capabilities it reports are merged into the connector's
+//! own, so an editor plugin speaks to the connector alone.
+//!
+//! Each LSP session has its own [`Proxy`], and so its own instance of each
+//! downstream server. An instance starts the first time the session needs
+//! it, restarts with a doubling delay when it crashes, and is shut down
+//! after `idle_timeout` without traffic from the editor; documents still
+//! open are opened again in the instance started next. One instance is not
+//! shared between sessions.
+//!
+//! URIs under a [`UriMap`]'s `local` prefix are rewritten on the way to the
+//! downstream and back. Positions pass through unchanged, so downstreams
+//! must count them in UTF-16, as LSP does by default.
+
+pub mod connection;
+
+use self::connection::Connection;
+use crate::config::duration;
+use crate::document_store::{Document, DocumentStore};
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tower_lsp::jsonrpc;
+use tower_lsp::lsp_types::{MessageType, PublishDiagnosticsParams, ServerCapabilities, Url};
+use tower_lsp::Client;
+use tracing::{debug, info, warn};
+
+/// Longest wait before restarting a crashed downstream
+const MAX_RESTART_DELAY: Duration = Duration::from_mins(1);
+
+/// Capabilities of the requests the connector forwards
+const FORWARDED_CAPABILITIES: [&str; 12] = [
+    "hoverProvider",
+    "completionProvider",
+    "signatureHelpProvider",
+    "definitionProvider",
+    "typeDefinitionProvider",
+    "implementationProvider",
+    "referencesProvider",
+    "documentHighlightProvider",
+    "documentSymbolProvider",
+    "documentFormattingProvider",
+    "documentRangeFormattingProvider",
+    "renameProvider",
+];
+
+/// How the connector reaches a downstream server
+///
+/// In a configuration file this is a `command`, `tcp` or `websocket` key
+/// beside the other settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DownstreamTransport {
+    /// Program and arguments of a server speaking LSP on stdin and stdout
+    #[serde(rename = "command")]
+    Stdio(Vec<String>),
+    /// `host:port` of a server speaking LSP over TCP
+    #[serde(rename = "tcp")]
+    Tcp(String),
+    /// `ws://` or `wss://` URL of a server taking one JSON-RPC message per text frame
+    #[serde(rename = "websocket")]
+    WebSocket(String),
+}
+
+/// URI prefixes swapped between the editor and a downstream server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UriMap {
+    /// Prefix of the URIs the editor sends, such as `file:///home/me/project`
+    pub local: String,
+    /// What the downstream sees instead, such as `file:///workspace`
+    pub remote: String,
+}
+
+/// A language server fronted for the documents it matches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DownstreamConfig {
+    /// Name used in logs and errors, such as `rust-analyzer`
+    pub name: String,
+    #[serde(flatten)]
+    pub transport: DownstreamTransport,
+    /// Language IDs of the documents routed to it, as editors send them in `didOpen`
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Globs over URI paths routed to it; one without `/` matches the file name
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// `initializationOptions` sent when it starts
+    #[serde(default)]
+    pub initialization_options: Option<Value>,
+    #[serde(default)]
+    pub uri_map: Option<UriMap>,
+    /// Time without editor traffic after which it is shut down; 0 keeps it running
+    #[serde(default = "default_idle_timeout", with = "crate::config::duration")]
+    pub idle_timeout: Duration,
+    /// Wait before restarting it after a crash, doubled for each crash in a row
+    #[serde(default = "default_restart_delay", with = "crate::config::duration")]
+    pub restart_delay: Duration,
+    /// Crashes in a row after which it is left stopped for the session
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// Longest wait for an answer to a forwarded request
+    #[serde(default = "default_request_timeout", with = "crate::config::duration")]
+    pub request_timeout: Duration,
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_mins(10)
+}
+
+fn default_restart_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_request_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl DownstreamConfig {
+    /// The server `name`, reached over `transport`, with default settings and no documents routed to it
+    pub fn new(name: impl Into<String>, transport: DownstreamTransport) -> Self {
+        Self {
+            name: name.into(),
+            transport,
+            languages: Vec::new(),
+            patterns: Vec::new(),
+            initialization_options: None,
+            uri_map: None,
+            idle_timeout: default_idle_timeout(),
+            restart_delay: default_restart_delay(),
+            max_restarts: default_max_restarts(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+
+    /// Whether the document at `uri`, opened as `language` if it is open, is routed here
+    #[must_use]
+    pub fn matches(&self, uri: &str, language: Option<&str>) -> bool {
+        if language.is_some_and(|language| self.languages.iter().any(|claimed| claimed == language)) {
+            return true;
+        }
+        matches_patterns(&self.patterns, uri)
+    }
+}
+
+/// Whether the path of `uri` matches one of `patterns`, globs where one without `/` matches the file name
+pub(crate) fn matches_patterns(patterns: &[String], uri: &str) -> bool {
+    let Ok(url) = Url::parse(uri) else {
+        return false;
+    };
+    let path = url.path();
+    let file = path.rsplit('/').next().unwrap_or(path);
+    patterns.iter().any(|pattern| {
+        let target = if pattern.contains('/') { path } else { file };
+        glob(pattern.as_bytes(), target.as_bytes())
+    })
+}
+
+/// Whether `text` matches `pattern`, where `*` and `?` stay within a path segment and `**` does not
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&skip| skip == 0 || text[skip - 1] != b'/')
+            .any(|skip| glob(rest, &text[skip..])),
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob(rest, tail)),
+    }
+}
+
+/// Rewrite every URI in `value` under the prefix `from` to be under `to`
+///
+/// Text that merely starts with such a URI, such as a hover message, is left alone.
+fn rewrite(value: &mut Value, from: &str, to: &str) {
+    let swap = |uri: &str| {
+        uri.strip_prefix(from)
+            .filter(|rest| (rest.is_empty() || rest.starts_with('/')) && !rest.contains(char::is_whitespace))
+            .map(|rest| format!("{to}{rest}"))
+    };
+    match value {
+        Value::String(text) => {
+            if let Some(swapped) = swap(text) {
+                *text = swapped;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| rewrite(item, from, to)),
+        Value::Object(map) => {
+            // Workspace edits are keyed by URI
+            let keys: Vec<String> = map.keys().filter(|key| swap(key).is_some()).cloned().collect();
+            for key in keys {
+                if let (Some(swapped), Some(value)) = (swap(&key), map.remove(&key)) {
+                    map.insert(swapped, value);
+                }
+            }
+            map.values_mut().for_each(|value| rewrite(value, from, to));
+        }
+        _ => {}
+    }
+}
+
+/// Merge what downstream servers reported into the connector's own capabilities
+///
+/// Forwarded requests the connector does not answer itself are advertised
+/// if any downstream answers them, and trigger characters are combined.
+/// Completion items are not resolved through the connector.
+pub fn merge_capabilities(capabilities: ServerCapabilities, downstream: &[Value]) -> ServerCapabilities {
+    if downstream.is_empty() {
+        return capabilities;
+    }
+    let Ok(mut merged) = serde_json::to_value(&capabilities) else {
+        return capabilities;
+    };
+    for theirs in downstream {
+        for key in FORWARDED_CAPABILITIES {
+            let Some(theirs) = theirs.get(key).filter(|value| !matches!(value, Value::Null | Value::Bool(false)))
+            else {
+                continue;
+            };
+            match merged.get_mut(key) {
+                None | Some(Value::Null | Value::Bool(false)) => merged[key] = theirs.clone(),
+                Some(ours) => {
+                    for characters in ["triggerCharacters", "retriggerCharacters"] {
+                        let Some(extra) = theirs.get(characters).and_then(Value::as_array) else {
+                            continue;
+                        };
+                        if let Some(Value::Array(list)) = ours.get_mut(characters) {
+                            list.extend(extra.iter().filter(|c| !list.contains(c)).cloned().collect::<Vec<_>>());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Some(completion) = merged.get_mut("completionProvider").and_then(Value::as_object_mut) {
+        completion.remove("resolveProvider");
+    }
+    serde_json::from_value(merged).unwrap_or(capabilities)
+}
+
+/// Capabilities downstream servers reported, kept across sessions
+///
+/// A session advertises them in its `initialize` result; only a downstream
+/// no session has started yet must be started to learn them.
+#[derive(Debug, Default)]
+pub struct KnownCapabilities(DashMap<String, Value>);
+
+impl KnownCapabilities {
+    fn key(config: &DownstreamConfig) -> String {
+        serde_json::to_string(&config.transport).unwrap_or_default()
+    }
+
+    /// What the downstream `config` describes reported when it last started
+    #[must_use]
+    pub fn get(&self, config: &DownstreamConfig) -> Option<Value> {
+        self.0.get(&Self::key(config)).map(|capabilities| capabilities.clone())
+    }
+
+    fn record(&self, config: &DownstreamConfig, capabilities: Value) {
+        self.0.insert(Self::key(config), capabilities);
+    }
+}
+
+/// The downstream servers of one LSP session
+pub struct Proxy {
+    downstreams: Vec<Arc<Downstream>>,
+    session: Arc<Session>,
+}
+
+/// What a session's downstreams share
+struct Session {
+    client: Client,
+    documents: Arc<DocumentStore>,
+    known: Arc<KnownCapabilities>,
+    /// The editor's `initialize` params, passed on to each downstream as it starts
+    initialize: Mutex<Value>,
+    /// Open documents routed to a downstream, by URI, with the downstream's index
+    open: Mutex<HashMap<String, usize>>,
+}
+
+impl Session {
+    fn open_documents(&self, index: usize) -> Vec<String> {
+        let open = self.open.lock().expect("open document lock poisoned");
+        open.iter().filter(|(_, routed)| **routed == index).map(|(uri, _)| uri.clone()).collect()
+    }
+}
+
+/// One downstream server, as one session runs it
+struct Downstream {
+    index: usize,
+    config: DownstreamConfig,
+    session: Arc<Session>,
+    status: tokio::sync::Mutex<Status>,
+    /// Last time the editor's traffic reached it
+    last_used: Mutex<Instant>,
+    /// Crashes since it last answered a request
+    crashes: AtomicU32,
+}
+
+enum Status {
+    Stopped,
+    Running(Arc<Connection>),
+    /// Crashed, and not started again before `until`
+    Crashed {
+        until: Instant,
+    },
+}
+
+impl Proxy {
+    /// A session's proxy to the `downstreams` configured, publishing their diagnostics to `client`
+    pub fn new(
+        client: Client,
+        downstreams: Vec<DownstreamConfig>,
+        documents: Arc<DocumentStore>,
+        known: Arc<KnownCapabilities>,
+    ) -> Self {
+        let session = Arc::new(Session {
+            client,
+            documents,
+            known,
+            initialize: Mutex::new(json!({ "capabilities": {} })),
+            open: Mutex::new(HashMap::new()),
+        });
+        let downstreams = downstreams
+            .into_iter()
+            .enumerate()
+            .map(|(index, config)| {
+                Arc::new(Downstream {
+                    index,
+                    config,
+                    session: Arc::clone(&session),
+                    status: tokio::sync::Mutex::new(Status::Stopped),
+                    last_used: Mutex::new(Instant::now()),
+                    crashes: AtomicU32::new(0),
+                })
+            })
+            .collect();
+        Self { downstreams, session }
+    }
+
+    /// Keep the editor's `initialize` params for the downstreams, returning their capabilities
+    ///
+    /// Downstreams whose capabilities are not known yet are started now.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the session's lock.
+    pub async fn initialize(&self, params: Value) -> Vec<Value> {
+        *self.session.initialize.lock().expect("initialize params lock poisoned") = params;
+        let mut capabilities = Vec::new();
+        for downstream in &self.downstreams {
+            if self.session.known.get(&downstream.config).is_none() {
+                if let Err(e) = downstream.connection().await {
+                    warn!("Downstream {} not started: {:#}", downstream.config.name, e);
+                }
+            }
+            capabilities.extend(self.session.known.get(&downstream.config));
+        }
+        capabilities
+    }
+
+    /// Whether the document at `uri` is routed to a downstream
+    #[must_use]
+    pub fn routes(&self, uri: &str) -> bool {
+        self.route(uri).is_some()
+    }
+
+    /// Open the stored document at `uri` in the downstream it is routed to, if any
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the session's lock.
+    pub async fn did_open(&self, uri: &str, language: &str) {
+        let Some(downstream) = self.downstreams.iter().find(|d| d.config.matches(uri, Some(language))) else {
+            return;
+        };
+        self.session.open.lock().expect("open document lock poisoned").insert(uri.to_string(), downstream.index);
+        match downstream.connection().await {
+            // Starting it opened every document routed to it
+            Ok((_, true)) => {}
+            Ok((connection, false)) => {
+                if let Some(document) = self.session.documents.get(uri) {
+                    downstream.notify(&connection, "textDocument/didOpen", Downstream::opened(&document));
+                }
+            }
+            Err(e) => warn!("Document {} not opened in {}: {:#}", uri, downstream.config.name, e),
+        }
+    }
+
+    /// Send the stored text of the document at `uri` to the downstream it was opened in, if any
+    pub async fn did_change(&self, uri: &str) {
+        let Some(downstream) = self.opened(uri) else {
+            return;
+        };
+        match downstream.connection().await {
+            Ok((_, true)) => {}
+            Ok((connection, false)) => {
+                if let Some(document) = self.session.documents.get(uri) {
+                    let params = json!({
+                        "textDocument": { "uri": uri, "version": document.version },
+                        "contentChanges": [{ "text": document.content }],
+                    });
+                    downstream.notify(&connection, "textDocument/didChange", params);
+                }
+            }
+            Err(e) => warn!("Change to {} not sent to {}: {:#}", uri, downstream.config.name, e),
+        }
+    }
+
+    /// Tell the downstream the document at `uri` was opened in, if running, that it was saved
+    pub async fn did_save(&self, uri: &str) {
+        if let Some(downstream) = self.opened(uri) {
+            if let Some(connection) = downstream.running().await {
+                downstream.notify(&connection, "textDocument/didSave", json!({ "textDocument": { "uri": uri } }));
+            }
+        }
+    }
+
+    /// Close the document at `uri` in the downstream it was opened in, if running
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the session's lock.
+    pub async fn did_close(&self, uri: &str) {
+        let Some(downstream) = self.opened(uri) else {
+            return;
+        };
+        self.session.open.lock().expect("open document lock poisoned").remove(uri);
+        if let Some(connection) = downstream.running().await {
+            downstream.notify(&connection, "textDocument/didClose", json!({ "textDocument": { "uri": uri } }));
+        }
+    }
+
+    /// Forward a request about the document at `uri` to the downstream it is routed to
+    ///
+    /// `None` when no downstream claims the document.
+    pub async fn forward<P, R>(&self, uri: &Url, method: &str, params: &P) -> Option<jsonrpc::Result<R>>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let downstream = self.route(uri.as_str())?;
+        Some(downstream.request(method, params).await.map_err(|e| {
+            warn!("{} for {} not answered: {:#}", method, uri, e);
+            jsonrpc::Error { code: jsonrpc::ErrorCode::InternalError, message: format!("{e:#}").into(), data: None }
+        }))
+    }
+
+    /// Shut every running downstream down
+    pub async fn shutdown(&self) {
+        for downstream in &self.downstreams {
+            downstream.stop().await;
+        }
+    }
+
+    /// The downstream the document at `uri` is routed to: the one it was opened in, else one matching its URI
+    fn route(&self, uri: &str) -> Option<&Arc<Downstream>> {
+        self.opened(uri).or_else(|| self.downstreams.iter().find(|d| d.config.matches(uri, None)))
+    }
+
+    /// The downstream the document at `uri` was opened in, while it is open
+    fn opened(&self, uri: &str) -> Option<&Arc<Downstream>> {
+        let index = self.session.open.lock().expect("open document lock poisoned").get(uri).copied()?;
+        self.downstreams.get(index)
+    }
+}
+
+impl Downstream {
+    /// The running instance, started if need be, and whether it was just started
+    async fn connection(self: &Arc<Self>) -> Result<(Arc<Connection>, bool)> {
+        *self.last_used.lock().expect("last use lock poisoned") = Instant::now();
+        let mut status = self.status.lock().await;
+        match &*status {
+            Status::Running(connection) if !connection.is_closed() => return Ok((Arc::clone(connection), false)),
+            Status::Crashed { .. } if self.gave_up() => {
+                bail!(
+                    "{} crashed {} times in a row and is left stopped",
+                    self.config.name,
+                    self.config.max_restarts + 1
+                )
+            }
+            Status::Crashed { until } if Instant::now() < *until => {
+                let wait = until.saturating_duration_since(Instant::now());
+                bail!("{} crashed and restarts in {}", self.config.name, duration::format(wait));
+            }
+            _ => {}
+        }
+        match self.start().await {
+            Ok(connection) => {
+                *status = Status::Running(Arc::clone(&connection));
+                Ok((connection, true))
+            }
+            Err(e) => {
+                *status = Status::Crashed { until: Instant::now() + self.crashed() };
+                Err(e)
+            }
+        }
+    }
+
+    /// The running instance, if any
+    async fn running(&self) -> Option<Arc<Connection>> {
+        match &*self.status.lock().await {
+            Status::Running(connection) if !connection.is_closed() => Some(Arc::clone(connection)),
+            _ => None,
+        }
+    }
+
+    /// Start an instance, opening the documents routed to it
+    async fn start(self: &Arc<Self>) -> Result<Arc<Connection>> {
+        let (connection, incoming) = Connection::open(&self.config.name, &self.config.transport).await?;
+        let connection = Arc::new(connection);
+
+        let mut params = self.session.initialize.lock().expect("initialize params lock poisoned").clone();
+        if let Value::Object(params) = &mut params {
+            params.insert("processId".to_string(), json!(std::process::id()));
+            params.insert("initializationOptions".to_string(), json!(self.config.initialization_options));
+            // A path, which the URI map cannot rewrite; rootUri says the same
+            params.remove("rootPath");
+        }
+        self.outgoing(&mut params);
+        let result = connection.request("initialize", params, self.config.request_timeout).await?;
+        let capabilities = result.get("capabilities").cloned().unwrap_or_else(|| json!({}));
+        self.session.known.record(&self.config, capabilities);
+        self.notify(&connection, "initialized", json!({}));
+        for uri in self.session.open_documents(self.index) {
+            if let Some(document) = self.session.documents.get(&uri) {
+                self.notify(&connection, "textDocument/didOpen", Self::opened(&document));
+            }
+        }
+
+        tokio::spawn(supervise(Arc::downgrade(self), Arc::downgrade(&connection), incoming));
+        info!("Downstream {} started", self.config.name);
+        Ok(connection)
+    }
+
+    /// Shut the running instance down, if any
+    async fn stop(&self) {
+        let status = std::mem::replace(&mut *self.status.lock().await, Status::Stopped);
+        if let Status::Running(connection) = status {
+            connection.shutdown(self.config.request_timeout).await;
+            info!("Downstream {} stopped", self.config.name);
+        }
+    }
+
+    /// Count a crash, returning the wait before the next start
+    fn crashed(&self) -> Duration {
+        let crashes = self.crashes.fetch_add(1, Ordering::Relaxed) + 1;
+        let doublings = (crashes - 1).min(16);
+        self.config.restart_delay.saturating_mul(1 << doublings).min(MAX_RESTART_DELAY)
+    }
+
+    /// Whether it crashed too often in a row to be started again
+    fn gave_up(&self) -> bool {
+        self.crashes.load(Ordering::Relaxed) > self.config.max_restarts
+    }
+
+    /// Whether `connection` is the running instance
+    fn is_current(status: &Status, connection: &Weak<Connection>) -> bool {
+        matches!(status, Status::Running(running) if std::ptr::eq(Arc::as_ptr(running), connection.as_ptr()))
+    }
+
+    /// Forward a request, rewriting URIs both ways
+    async fn request<P: Serialize, R: DeserializeOwned>(self: &Arc<Self>, method: &str, params: &P) -> Result<R> {
+        let mut params = serde_json::to_value(params)?;
+        self.outgoing(&mut params);
+        let (connection, _) = self.connection().await?;
+        let mut result = connection.request(method, params, self.config.request_timeout).await?;
+        self.crashes.store(0, Ordering::Relaxed);
+        self.incoming(&mut result);
+        serde_json::from_value(result)
+            .with_context(|| format!("{} answered {} with the wrong type", self.config.name, method))
+    }
+
+    /// Send a notification, rewriting its URIs
+    fn notify(&self, connection: &Connection, method: &str, mut params: Value) {
+        self.outgoing(&mut params);
+        if let Err(e) = connection.notify(method, params) {
+            debug!("{} not sent: {:#}", method, e);
+        }
+    }
+
+    /// `didOpen` params for a stored document
+    fn opened(document: &Document) -> Value {
+        json!({
+            "textDocument": {
+                "uri": document.uri,
+                "languageId": document.language,
+                "version": document.version,
+                "text": document.content,
+            }
+        })
+    }
+
+    fn outgoing(&self, value: &mut Value) {
+        if let Some(map) = &self.config.uri_map {
+            rewrite(value, &map.local, &map.remote);
+        }
+    }
+
+    fn incoming(&self, value: &mut Value) {
+        if let Some(map) = &self.config.uri_map {
+            rewrite(value, &map.remote, &map.local);
+        }
+    }
+
+    /// When it will have been idle for `idle_timeout`, unless that is 0
+    fn idle_at(&self) -> Option<Instant> {
+        let last_used = *self.last_used.lock().expect("last use lock poisoned");
+        (!self.config.idle_timeout.is_zero()).then(|| last_used + self.config.idle_timeout)
+    }
+
+    /// Pass on a message the server sent unprompted
+    async fn handle(&self, connection: &Connection, mut message: Value) {
+        self.incoming(&mut message);
+        let method = message.get("method").and_then(Value::as_str).unwrap_or_default().to_string();
+        let client = &self.session.client;
+        if let Some(id) = message.get("id").cloned() {
+            // The editor is not asked; the answers are a minimal client's
+            let result = match method.as_str() {
+                "workspace/configuration" => {
+                    let items = message["params"]["items"].as_array().map_or(0, Vec::len);
+                    Value::Array(vec![Value::Null; items])
+                }
+                _ => Value::Null,
+            };
+            if let Err(e) = connection.respond(id, result) {
+                debug!("{} not answered: {:#}", method, e);
+            }
+            return;
+        }
+        match method.as_str() {
+            "textDocument/publishDiagnostics" => {
+                match serde_json::from_value::<PublishDiagnosticsParams>(message["params"].take()) {
+                    // Its versions count the store's changes, not the editor's
+                    Ok(params) => client.publish_diagnostics(params.uri, params.diagnostics, None).await,
+                    Err(e) => warn!("Invalid diagnostics from {}: {}", self.config.name, e),
+                }
+            }
+            "window/logMessage" | "window/showMessage" => {
+                let Ok(kind) = serde_json::from_value::<MessageType>(message["params"]["type"].take()) else {
+                    return;
+                };
+                let text =
+                    format!("{}: {}", self.config.name, message["params"]["message"].as_str().unwrap_or_default());
+                if method == "window/logMessage" {
+                    client.log_message(kind, text).await;
+                } else {
+                    client.show_message(kind, text).await;
+                }
+            }
+            _ => debug!("{} from {} not passed on", method, self.config.name),
+        }
+    }
+}
+
+/// Pass on what a running instance sends, stop it when idle and restart it when it crashes
+async fn supervise(
+    downstream: Weak<Downstream>,
+    connection: Weak<Connection>,
+    mut incoming: mpsc::UnboundedReceiver<Value>,
+) {
+    loop {
+        // Held only between messages, so dropping the proxy ends the loop
+        let Some(current) = downstream.upgrade() else { return };
+        let idle_at = current.idle_at();
+        drop(current);
+        let idle = async {
+            match idle_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            message = incoming.recv() => {
+                let Some(message) = message else { break };
+                let (Some(downstream), Some(connection)) = (downstream.upgrade(), connection.upgrade()) else {
+                    return;
+                };
+                downstream.handle(&connection, message).await;
+            }
+            () = idle => {
+                let Some(downstream) = downstream.upgrade() else { return };
+                let status = downstream.status.lock().await;
+                let idle = downstream.idle_at().is_some_and(|at| at <= Instant::now());
+                if idle && Downstream::is_current(&status, &connection) {
+                    drop(status);
+                    let idle = duration::format(downstream.config.idle_timeout);
+                    info!("Downstream {} idle for {}", downstream.config.name, idle);
+                    downstream.stop().await;
+                    return;
+                }
+            }
+        }
+    }
+
+    // The connection ended without being stopped
+    let delay = {
+        let Some(downstream) = downstream.upgrade() else { return };
+        let mut status = downstream.status.lock().await;
+        if !Downstream::is_current(&status, &connection) {
+            return;
+        }
+        let delay = downstream.crashed();
+        *status = Status::Crashed { until: Instant::now() + delay };
+        if downstream.gave_up() {
+            warn!(
+                "Downstream {} crashed {} times in a row; not restarting it",
+                downstream.config.name,
+                downstream.config.max_restarts + 1
+            );
+            return;
+        }
+        warn!("Downstream {} exited; restarting it in {}", downstream.config.name, duration::format(delay));
+        delay
+    };
+    restart(downstream, delay).await;
+}
+
+/// Start a crashed downstream again after `delay`, while documents are open in it
+///
+/// Boxed because starting an instance spawns [`supervise`], which restarts it:
+/// the cycle would otherwise keep the compiler from proving the future `Send`.
+fn restart(downstream: Weak<Downstream>, mut delay: Duration) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        loop {
+            tokio::time::sleep(delay).await;
+            let Some(downstream) = downstream.upgrade() else { return };
+            // Otherwise it starts when next needed
+            if downstream.session.open_documents(downstream.index).is_empty() {
+                return;
+            }
+            let Err(e) = downstream.connection().await else { return };
+            warn!("Downstream {} not restarted: {:#}", downstream.config.name, e);
+            delay = match &*downstream.status.lock().await {
+                Status::Crashed { until } if !downstream.gave_up() => until.saturating_duration_since(Instant::now()),
+                _ => return,
+            };
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob(b"*.rs", b"main.rs"));
+        assert!(!glob(b"*.rs", b"main.rsx"));
+        assert!(glob(b"**/*.rs", b"/project/src/main.rs"));
+        assert!(!glob(b"/project/*.rs", b"/project/src/main.rs"));
+        assert!(glob(b"/project/**", b"/project/src/main.rs"));
+        assert!(glob(b"Cargo.to?l", b"Cargo.toml"));
+        assert!(!glob(b"?", b"/"));
+    }
+
+    #[test]
+    fn test_matches_language_or_pattern() {
+        let mut config =
+            DownstreamConfig::new("rust-analyzer", DownstreamTransport::Stdio(vec!["rust-analyzer".into()]));
+        config.languages = vec!["rust".to_string()];
+        config.patterns = vec!["*.rs".to_string(), "**/Cargo.toml".to_string()];
+        assert!(config.matches("file:///project/notes.txt", Some("rust")));
+        assert!(config.matches("file:///project/src/main.rs", None));
+        assert!(config.matches("file:///project/Cargo.toml", Some("toml")));
+        assert!(!config.matches("file:///project/notes.md", Some("markdown")));
+        assert!(!config.matches("not a uri", None));
+    }
+
+    #[test]
+    fn test_rewrite_uris() {
+        let mut edit = json!({
+            "changes": { "file:///home/me/project/a.rs": [{ "newText": "file:///home/me/project" }] },
+            "other": "file:///home/me/project2/b.rs",
+            "message": "file:///home/me/project/a.rs does not compile",
+        });
+        rewrite(&mut edit, "file:///home/me/project", "file:///workspace");
+        assert_eq!(
+            edit,
+            json!({
+                "changes": { "file:///workspace/a.rs": [{ "newText": "file:///workspace" }] },
+                "other": "file:///home/me/project2/b.rs",
+                "message": "file:///home/me/project/a.rs does not compile",
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_capabilities() {
+        let ours = ServerCapabilities {
+            completion_provider: Some(tower_lsp::lsp_types::CompletionOptions {
+                trigger_characters: Some(vec!["#".to_string()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let theirs = json!({
+            "completionProvider": { "triggerCharacters": [".", "#"], "resolveProvider": true },
+            "referencesProvider": true,
+            "codeLensProvider": {},
+        });
+        let merged = serde_json::to_value(merge_capabilities(ours, &[theirs])).unwrap();
+        assert_eq!(merged["completionProvider"], json!({ "triggerCharacters": ["#", "."] }));
+        assert_eq!(merged["referencesProvider"], true);
+        assert!(merged.get("codeLensProvider").is_none());
+    }
+
+    #[test]
+    fn test_transport_in_files() {
+        let config: DownstreamConfig = toml::from_str(
+            r#"
+name = "pyright"
+tcp = "127.0.0.1:9257"
+languages = ["python"]
+restart_delay = "250ms"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.transport, DownstreamTransport::Tcp("127.0.0.1:9257".to_string()));
+        assert_eq!(config.restart_delay, Duration::from_millis(250));
+        assert_eq!(config.idle_timeout, default_idle_timeout());
+    }
+}
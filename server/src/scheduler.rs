@@ -0,0 +1,1088 @@
+//! Recurring maintenance tasks
+//!
+//! Subsystems register named tasks with the [`Scheduler`] instead of running
+//! their own interval loops: the TTL sweep and snapshots of the document
+//! store, the usage rollup, rate limiter eviction, and fetching the keys of
+//! an identity provider. Each runs on a [`Schedule`], either an
+//! interval such as `5m` or a cron spec such as `0 3 * * *` (UTC), and may
+//! have a timeout. The first run of an interval task is delayed by a random
+//! share of its interval, and cron tasks by a fixed jitter, so that servers
+//! started together do not run their maintenance in step.
+//!
+//! A task still running when it is next due is skipped, unless registered
+//! with [`Overlap::Allow`]. At shutdown each in-flight run is waited for or
+//! cancelled, as its [`OnShutdown`] says.
+//!
+//! `GET /api/admin/tasks` lists each task's schedule and last run, and
+//! `POST /api/admin/tasks/{name}/run` runs one at once. Decisions are made
+//! against a [`Clock`], so tests can move time by hand.
+
+use crate::config::duration;
+use crate::monitoring::registry::{Buckets, Counter, Family, Gauge, Histogram, Registry};
+use crate::shutdown::Flushed;
+use anyhow::{bail, Context as _, Result};
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Timelike, Utc};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Removes documents unchanged for longer than `tasks.document_ttl`
+pub const DOCUMENT_TTL_SWEEP: &str = "document_ttl_sweep";
+/// Appends the usage of ended days to the rollup file
+pub const USAGE_ROLLUP: &str = "usage_rollup";
+/// Drops rate limiter buckets that have refilled
+pub const RATE_LIMIT_EVICTION: &str = "rate_limit_eviction";
+/// Writes the documents changed since the last snapshot to the data directory
+pub const DOCUMENT_SNAPSHOT: &str = "document_snapshot";
+/// Fetches the keys of the configured identity provider
+pub const OIDC_KEY_REFRESH: &str = "oidc_key_refresh";
+/// Removes refresh token families whose current token has expired
+pub const REFRESH_TOKEN_PRUNE: &str = "refresh_token_prune";
+/// Removes revoked tokens that have expired from the revocation list
+pub const REVOKED_TOKEN_PRUNE: &str = "revoked_token_prune";
+/// Tasks the server may register, whose schedules `tasks.schedules` can replace
+pub const BUILT_IN: [&str; 7] = [
+    DOCUMENT_TTL_SWEEP,
+    USAGE_ROLLUP,
+    RATE_LIMIT_EVICTION,
+    DOCUMENT_SNAPSHOT,
+    OIDC_KEY_REFRESH,
+    REFRESH_TOKEN_PRUNE,
+    REVOKED_TOKEN_PRUNE,
+];
+
+/// Longest the scheduler sleeps before looking at the clock again
+const MAX_WAIT: Duration = Duration::from_mins(1);
+
+/// How far ahead a cron spec is searched for its next run
+const CRON_HORIZON_DAYS: u64 = 5 * 366;
+
+/// Maintenance settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct TasksConfig {
+    /// Documents unchanged for this long are removed; zero keeps them
+    #[serde(with = "crate::config::duration")]
+    pub document_ttl: Duration,
+    /// Schedules replacing those the tasks are registered with, by task name
+    pub schedules: BTreeMap<String, Schedule>,
+}
+
+impl Default for TasksConfig {
+    fn default() -> Self {
+        Self { document_ttl: Duration::ZERO, schedules: BTreeMap::new() }
+    }
+}
+
+/// When a task runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every interval, which is never zero
+    Every(Duration),
+    /// Whenever the cron spec matches, to the minute, in UTC
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// When the task is next due after `now`, if ever
+    #[must_use]
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Every(interval) => now.checked_add_signed(chrono::Duration::from_std(*interval).ok()?),
+            Self::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    /// Jitter used unless the task sets its own: one interval, or none for cron specs
+    fn default_jitter(&self) -> Duration {
+        match self {
+            Self::Every(interval) => *interval,
+            Self::Cron(_) => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Every(interval) => f.write_str(&duration::format(*interval)),
+            Self::Cron(cron) => f.write_str(&cron.spec),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    /// An interval such as `30s`, or five cron fields
+    fn from_str(text: &str) -> Result<Self> {
+        if text.split_whitespace().count() == 5 {
+            return Ok(Self::Cron(text.parse()?));
+        }
+        let interval = duration::parse(text)?;
+        if interval.is_zero() {
+            bail!("Invalid schedule {text:?}: the interval must be more than 0");
+        }
+        Ok(Self::Every(interval))
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A cron spec: minute, hour, day of month, month and day of week
+///
+/// Each field is `*`, a number, a range `a-b`, any of those with a step
+/// `/n`, or a comma-separated list of them. Sunday is 0 or 7. As in cron,
+/// when both day fields are restricted a day matching either runs the task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    spec: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether both day fields are restricted, so either may match
+    either_day: bool,
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("Invalid cron spec {:?}: expected 5 fields, found {}", spec, fields.len());
+        };
+        let field = |text: &str, name: &str, min: u32, max: u32| {
+            cron_field(text, min, max).with_context(|| format!("Invalid cron spec {spec:?}: bad {name} field"))
+        };
+        let mut weekdays = field(weekday, "day of week", 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let cron = Self {
+            spec: fields.join(" "),
+            minutes: field(minute, "minute", 0, 59)?,
+            hours: field(hour, "hour", 0, 23)?,
+            days: field(day, "day of month", 1, 31)?,
+            months: field(month, "month", 1, 12)?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        };
+        if cron.next_after(DateTime::UNIX_EPOCH).is_none() {
+            bail!("Invalid cron spec {spec:?}: it never matches");
+        }
+        Ok(cron)
+    }
+}
+
+/// Bit set of the values a cron field matches
+fn cron_field(text: &str, min: u32, max: u32) -> Result<u64> {
+    let mut values = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("the step of {part:?} is 0");
+        }
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (low.parse()?, high.parse()?),
+            // "5/15" starts at 5 and runs to the end
+            None if part.contains('/') => (range.parse()?, max),
+            None => {
+                let value = range.parse()?;
+                (value, value)
+            }
+        };
+        if low < min || high > max || low > high {
+            bail!("{part:?} is outside {min}-{max}");
+        }
+        for value in (low..=high).step_by(step) {
+            values |= 1 << value;
+        }
+    }
+    Ok(values)
+}
+
+fn has(values: u64, value: u32) -> bool {
+    values & (1 << value) != 0
+}
+
+impl Cron {
+    /// The first minute after `now` the spec matches, if any within five years
+    #[must_use]
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        let mut at = minute.checked_add_signed(chrono::Duration::minutes(1))?;
+        let horizon = now.checked_add_days(Days::new(CRON_HORIZON_DAYS))?;
+        let midnight = |date: NaiveDate| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN));
+        while at <= horizon {
+            if !has(self.months, at.month()) {
+                let (year, month) = if at.month() == 12 { (at.year() + 1, 1) } else { (at.year(), at.month() + 1) };
+                at = midnight(NaiveDate::from_ymd_opt(year, month, 1)?);
+            } else if !self.matches_day(at.date_naive()) {
+                at = midnight(at.date_naive().checked_add_days(Days::new(1))?);
+            } else if !has(self.hours, at.hour()) {
+                at = at.with_minute(0)?.checked_add_signed(chrono::Duration::hours(1))?;
+            } else if !has(self.minutes, at.minute()) {
+                at = at.checked_add_signed(chrono::Duration::minutes(1))?;
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// Whether a task may start while its previous run is still going
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overlap {
+    /// Skip the run, counting it as skipped
+    #[default]
+    Skip,
+    /// Start another run alongside
+    Allow,
+}
+
+/// What shutdown does with a run in flight
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnShutdown {
+    /// Drop the run
+    #[default]
+    Cancel,
+    /// Let the run finish, within its timeout
+    Wait,
+}
+
+/// A task as registered: its name, schedule and policies
+#[derive(Debug, Clone)]
+pub struct TaskSpec {
+    name: String,
+    schedule: Schedule,
+    timeout: Option<Duration>,
+    overlap: Overlap,
+    on_shutdown: OnShutdown,
+    jitter: Option<Duration>,
+}
+
+impl TaskSpec {
+    /// A task skipping overlapping runs, cancelled at shutdown, with no timeout
+    pub fn new(name: impl Into<String>, schedule: Schedule) -> Self {
+        Self {
+            name: name.into(),
+            schedule,
+            timeout: None,
+            overlap: Overlap::default(),
+            on_shutdown: OnShutdown::default(),
+            jitter: None,
+        }
+    }
+
+    /// Fail runs that take longer than `timeout`
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn overlap(mut self, overlap: Overlap) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    #[must_use]
+    pub fn on_shutdown(mut self, on_shutdown: OnShutdown) -> Self {
+        self.on_shutdown = on_shutdown;
+        self
+    }
+
+    /// Delay of up to `jitter` before the first run, or every run of a cron task
+    #[must_use]
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+}
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskOutcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    Panicked,
+    /// Dropped at shutdown
+    Cancelled,
+}
+
+impl TaskOutcome {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::TimedOut => "timed_out",
+            Self::Panicked => "panicked",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The last run of a task
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub outcome: TaskOutcome,
+    /// Why it did not succeed
+    pub error: Option<String>,
+}
+
+/// A task as `GET /api/admin/tasks` lists it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub name: String,
+    /// Interval or cron spec
+    pub schedule: String,
+    pub timeout_ms: Option<u64>,
+    pub overlap: Overlap,
+    pub on_shutdown: OnShutdown,
+    /// When it is next due; `None` once the scheduler has stopped or the spec has no more matches
+    pub next_run: Option<DateTime<Utc>>,
+    /// Runs in flight
+    pub running: usize,
+    /// Runs finished, however they ended
+    pub runs: u64,
+    /// Runs that did not succeed
+    pub failures: u64,
+    /// Runs skipped because the previous one was still going
+    pub skipped: u64,
+    pub last_run: Option<LastRun>,
+}
+
+/// Why a task could not be run at once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunNowError {
+    Unknown(String),
+    /// The task skips overlapping runs and one is in flight
+    AlreadyRunning(String),
+    ShuttingDown,
+}
+
+impl fmt::Display for RunNowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "No task named {name}"),
+            Self::AlreadyRunning(name) => write!(f, "Task {name} is already running"),
+            Self::ShuttingDown => write!(f, "The scheduler is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for RunNowError {}
+
+/// Where the scheduler reads the time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's UTC clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Runs, skips and last outcome, by task
+#[derive(Debug, Clone)]
+pub struct TaskMetrics {
+    pub runs: Family<Counter>,
+    pub run_duration: Family<Histogram>,
+    pub running: Family<Gauge>,
+    /// Unix time the last run finished
+    pub last_run: Family<Gauge>,
+    pub last_duration: Family<Gauge>,
+    /// 1 if the last run succeeded, 0 if not
+    pub last_success: Family<Gauge>,
+}
+
+impl TaskMetrics {
+    /// Register the task metrics
+    ///
+    /// # Errors
+    ///
+    /// Fails where a metric of the same name is already registered.
+    pub fn register(registry: &Registry) -> Result<Self> {
+        Ok(Self {
+            runs: registry.counter_family(
+                "ulc_task_runs_total",
+                "Scheduled task runs by task and outcome",
+                &["task", "outcome"],
+            )?,
+            run_duration: registry.histogram_family(
+                "ulc_task_run_seconds",
+                "Time scheduled task runs took",
+                &["task"],
+                Buckets::latency(),
+            )?,
+            running: registry.gauge_family("ulc_task_running", "Scheduled task runs in flight", &["task"])?,
+            last_run: registry.gauge_family(
+                "ulc_task_last_run_timestamp_seconds",
+                "Unix time the last run of a scheduled task finished",
+                &["task"],
+            )?,
+            last_duration: registry.gauge_family(
+                "ulc_task_last_duration_seconds",
+                "Time the last run of a scheduled task took",
+                &["task"],
+            )?,
+            last_success: registry.gauge_family(
+                "ulc_task_last_success",
+                "1 if the last run of a scheduled task succeeded",
+                &["task"],
+            )?,
+        })
+    }
+}
+
+type TaskFn = Arc<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+struct InFlight {
+    handle: JoinHandle<()>,
+    started_at: DateTime<Utc>,
+    started: Instant,
+}
+
+struct Task {
+    spec: TaskSpec,
+    run: TaskFn,
+    /// Jitter of this task, fixed at registration
+    offset: Duration,
+    next_run: Option<DateTime<Utc>>,
+    in_flight: HashMap<u64, InFlight>,
+    runs: u64,
+    failures: u64,
+    skipped: u64,
+    last_run: Option<LastRun>,
+}
+
+impl Task {
+    fn status(&self) -> TaskStatus {
+        TaskStatus {
+            name: self.spec.name.clone(),
+            schedule: self.spec.schedule.to_string(),
+            timeout_ms: self.spec.timeout.map(|timeout| u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)),
+            overlap: self.spec.overlap,
+            on_shutdown: self.spec.on_shutdown,
+            next_run: self.next_run,
+            running: self.in_flight.len(),
+            runs: self.runs,
+            failures: self.failures,
+            skipped: self.skipped,
+            last_run: self.last_run.clone(),
+        }
+    }
+
+    /// When the task is due after running at `now`
+    fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let offset = chrono::Duration::from_std(self.offset).ok()?;
+        match &self.spec.schedule {
+            Schedule::Every(_) => self.spec.schedule.next_after(now),
+            Schedule::Cron(cron) => cron.next_after(now.checked_sub_signed(offset)?)?.checked_add_signed(offset),
+        }
+    }
+}
+
+/// Named recurring tasks, run by [`Scheduler::run`]
+pub struct Scheduler {
+    config: TasksConfig,
+    metrics: TaskMetrics,
+    clock: Arc<dyn Clock>,
+    tasks: Mutex<BTreeMap<String, Task>>,
+    /// Signalled when a task is registered, so the loop can wake earlier
+    changed: Notify,
+    stopping: AtomicBool,
+    next_run_id: AtomicU64,
+    /// Seeds the jitter of each task
+    seed: RandomState,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl Scheduler {
+    #[must_use]
+    pub fn new(config: TasksConfig, metrics: TaskMetrics) -> Self {
+        Self {
+            config,
+            metrics,
+            clock: Arc::new(SystemClock),
+            tasks: Mutex::new(BTreeMap::new()),
+            changed: Notify::new(),
+            stopping: AtomicBool::new(false),
+            next_run_id: AtomicU64::new(1),
+            seed: RandomState::new(),
+        }
+    }
+
+    /// Read the time from `clock` instead of the system
+    #[must_use]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Register a task running `run` as `spec` says
+    ///
+    /// A schedule in `tasks.schedules` under the task's name replaces the
+    /// one in `spec`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name is taken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub fn register<F, Fut>(&self, mut spec: TaskSpec, run: F) -> Result<()>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        if let Some(schedule) = self.config.schedules.get(&spec.name) {
+            spec.schedule = schedule.clone();
+        }
+        if spec.schedule == Schedule::Every(Duration::ZERO) {
+            bail!("Task {} runs every 0s", spec.name);
+        }
+        let jitter = spec.jitter.unwrap_or_else(|| spec.schedule.default_jitter());
+        let offset = if jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            let span = u64::try_from(jitter.as_millis()).unwrap_or(u64::MAX);
+            Duration::from_millis(self.seed.hash_one(&spec.name) % span)
+        };
+
+        let mut tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        if tasks.contains_key(&spec.name) {
+            bail!("A task named {} is already registered", spec.name);
+        }
+        let now = self.clock.now();
+        let mut task = Task {
+            spec,
+            run: Arc::new(move || run().boxed()),
+            offset,
+            next_run: None,
+            in_flight: HashMap::new(),
+            runs: 0,
+            failures: 0,
+            skipped: 0,
+            last_run: None,
+        };
+        task.next_run = match &task.spec.schedule {
+            Schedule::Every(_) => now.checked_add_signed(chrono::Duration::from_std(offset)?),
+            Schedule::Cron(_) => task.next_after(now),
+        };
+        tasks.insert(task.spec.name.clone(), task);
+        drop(tasks);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Every task, by name
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub fn list(&self) -> Vec<TaskStatus> {
+        let tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        tasks.values().map(Task::status).collect()
+    }
+
+    /// The task called `name`, if any
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub fn get(&self, name: &str) -> Option<TaskStatus> {
+        let tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        tasks.get(name).map(Task::status)
+    }
+
+    /// Start every task due by now, returning when the next one is due
+    ///
+    /// [`Scheduler::run`] calls this as tasks come due; tests call it after
+    /// moving their clock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub fn tick(self: &Arc<Self>) -> Option<DateTime<Utc>> {
+        if self.stopping.load(Ordering::Acquire) {
+            return None;
+        }
+        let now = self.clock.now();
+        let mut tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        for task in tasks.values_mut() {
+            if task.next_run.is_some_and(|next_run| next_run <= now) {
+                if task.spec.overlap == Overlap::Skip && !task.in_flight.is_empty() {
+                    task.skipped += 1;
+                    self.metrics.runs.with_labels(&[&task.spec.name, "skipped"]).inc();
+                    info!("Task {} skipped: its previous run is still going", task.spec.name);
+                } else {
+                    self.start(task, now);
+                }
+                task.next_run = task.next_after(now);
+            }
+        }
+        tasks.values().filter_map(|task| task.next_run).min()
+    }
+
+    /// Run `name` at once, leaving its schedule as it was
+    ///
+    /// # Errors
+    ///
+    /// [`RunNowError::Unknown`] where no task is called `name`,
+    /// [`RunNowError::AlreadyRunning`] where it skips overlapping runs and one
+    /// is in flight, and [`RunNowError::ShuttingDown`] once shutdown has begun.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub fn run_now(self: &Arc<Self>, name: &str) -> Result<(), RunNowError> {
+        if self.stopping.load(Ordering::Acquire) {
+            return Err(RunNowError::ShuttingDown);
+        }
+        let mut tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        let task = tasks.get_mut(name).ok_or_else(|| RunNowError::Unknown(name.to_string()))?;
+        if task.spec.overlap == Overlap::Skip && !task.in_flight.is_empty() {
+            return Err(RunNowError::AlreadyRunning(name.to_string()));
+        }
+        self.start(task, self.clock.now());
+        Ok(())
+    }
+
+    /// Start a run of `task`, with the lock held so it cannot finish before it is recorded
+    fn start(self: &Arc<Self>, task: &mut Task, now: DateTime<Utc>) {
+        let id = self.next_run_id.fetch_add(1, Ordering::Relaxed);
+        let name = task.spec.name.clone();
+        let timeout = task.spec.timeout;
+        let run = AssertUnwindSafe((task.run)()).catch_unwind();
+        let scheduler = Arc::clone(self);
+        let handle = tokio::spawn(async move {
+            let started = Instant::now();
+            let finished = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, run).await.map_err(|_| timeout),
+                None => Ok(run.await),
+            };
+            let (outcome, error) = match finished {
+                Ok(Ok(Ok(()))) => (TaskOutcome::Succeeded, None),
+                Ok(Ok(Err(e))) => (TaskOutcome::Failed, Some(format!("{e:#}"))),
+                Ok(Err(panic)) => (TaskOutcome::Panicked, Some(panic_message(&*panic))),
+                Err(timeout) => (TaskOutcome::TimedOut, Some(format!("Timed out after {}", duration::format(timeout)))),
+            };
+            scheduler.finish(&name, id, now, started.elapsed(), outcome, error);
+        });
+        self.metrics.running.with_labels(&[&task.spec.name]).inc();
+        task.in_flight.insert(id, InFlight { handle, started_at: now, started: Instant::now() });
+    }
+
+    /// Record how run `id` of `name`, started at `started_at`, ended
+    fn finish(
+        &self,
+        name: &str,
+        id: u64,
+        started_at: DateTime<Utc>,
+        elapsed: Duration,
+        outcome: TaskOutcome,
+        error: Option<String>,
+    ) {
+        if let Some(task) = self.tasks.lock().expect("scheduler lock poisoned").get_mut(name) {
+            // Already gone if shutdown is waiting for the run
+            task.in_flight.remove(&id);
+        }
+        self.record(name, started_at, elapsed, outcome, error);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn record(
+        &self,
+        name: &str,
+        started_at: DateTime<Utc>,
+        elapsed: Duration,
+        outcome: TaskOutcome,
+        error: Option<String>,
+    ) {
+        if let Some(error) = &error {
+            warn!("Task {} {}: {}", name, outcome.as_str(), error);
+        }
+        let succeeded = outcome == TaskOutcome::Succeeded;
+        self.metrics.runs.with_labels(&[name, outcome.as_str()]).inc();
+        self.metrics.run_duration.with_labels(&[name]).observe_duration(elapsed);
+        self.metrics.running.with_labels(&[name]).dec();
+        self.metrics.last_run.with_labels(&[name]).set(self.clock.now().timestamp_millis() as f64 / 1000.0);
+        self.metrics.last_duration.with_labels(&[name]).set(elapsed.as_secs_f64());
+        self.metrics.last_success.with_labels(&[name]).set(if succeeded { 1.0 } else { 0.0 });
+
+        let mut tasks = self.tasks.lock().expect("scheduler lock poisoned");
+        let Some(task) = tasks.get_mut(name) else {
+            return;
+        };
+        task.runs += 1;
+        if !succeeded {
+            task.failures += 1;
+        }
+        let duration_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        task.last_run = Some(LastRun { started_at, duration_ms, outcome, error });
+    }
+
+    /// Start tasks as they come due, until the scheduler is shut down
+    pub async fn run(self: Arc<Self>) {
+        while !self.stopping.load(Ordering::Acquire) {
+            let next = self.tick();
+            let wait = next
+                .and_then(|next| (next - self.clock.now()).to_std().ok())
+                .map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT));
+            tokio::select! {
+                () = tokio::time::sleep(wait) => {}
+                () = self.changed.notified() => {}
+            }
+        }
+    }
+
+    /// Stop starting runs, then wait for or cancel those in flight as each task's policy says
+    ///
+    /// Returns the runs waited for as completed and those cancelled as abandoned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the scheduler's lock.
+    pub async fn shutdown(&self) -> Flushed {
+        self.stopping.store(true, Ordering::Release);
+        self.changed.notify_one();
+        let runs: Vec<(String, OnShutdown, InFlight)> = {
+            let mut tasks = self.tasks.lock().expect("scheduler lock poisoned");
+            tasks
+                .values_mut()
+                .flat_map(|task| {
+                    task.next_run = None;
+                    let (name, policy) = (task.spec.name.clone(), task.spec.on_shutdown);
+                    task.in_flight.drain().map(move |(_, run)| (name.clone(), policy, run))
+                })
+                .collect()
+        };
+        for (_, policy, run) in &runs {
+            if *policy == OnShutdown::Cancel {
+                run.handle.abort();
+            }
+        }
+        let mut flushed = Flushed::default();
+        for (name, _, run) in runs {
+            match run.handle.await {
+                Err(e) if e.is_cancelled() => {
+                    self.record(&name, run.started_at, run.started.elapsed(), TaskOutcome::Cancelled, None);
+                    flushed.abandoned += 1;
+                }
+                _ => flushed.completed += 1,
+            }
+        }
+        flushed
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .map_or_else(|| "Panicked".to_string(), |message| format!("Panicked: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::Metrics;
+    use anyhow::anyhow;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::sync::watch;
+
+    /// A clock moved by hand
+    struct MockClock(Mutex<DateTime<Utc>>);
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self(Mutex::new(Utc.with_ymd_and_hms(2026, 3, 14, 10, 7, 0).unwrap())))
+        }
+
+        fn advance(&self, by: Duration) {
+            let mut now = self.0.lock().unwrap();
+            *now += chrono::Duration::from_std(by).unwrap();
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn scheduler(clock: &Arc<MockClock>) -> (Arc<Scheduler>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let clock: Arc<dyn Clock> = Arc::clone(clock) as Arc<dyn Clock>;
+        let scheduler = Scheduler::new(TasksConfig::default(), metrics.tasks.clone()).with_clock(clock);
+        (Arc::new(scheduler), metrics)
+    }
+
+    fn every(secs: u64) -> Schedule {
+        Schedule::Every(Duration::from_secs(secs))
+    }
+
+    /// Wait until no run of `name` is in flight
+    async fn settle(scheduler: &Scheduler, name: &str) -> TaskStatus {
+        for _ in 0..100 {
+            let status = scheduler.get(name).unwrap();
+            if status.running == 0 {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{name} never finished");
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_schedule_parsing() {
+        assert_eq!("5m".parse::<Schedule>().unwrap(), every(300));
+        assert!("0s".parse::<Schedule>().is_err());
+        let cron: Schedule = "*/15  * * * *".parse().unwrap();
+        assert_eq!(cron.to_string(), "*/15 * * * *");
+        assert_eq!(serde_json::to_value(&cron).unwrap(), "*/15 * * * *");
+        assert_eq!(serde_json::from_value::<Schedule>("10s".into()).unwrap(), every(10));
+
+        for bad in ["* * * *", "60 * * * *", "* 24 * * *", "*/0 * * * *", "5-1 * * * *", "0 0 31 2 *", "a * * * *"] {
+            assert!(bad.parse::<Cron>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_cron_next_run() {
+        let next = |spec: &str, now: &str| spec.parse::<Cron>().unwrap().next_after(at(now)).unwrap();
+        assert_eq!(next("*/15 * * * *", "2026-03-14T10:07:30Z"), at("2026-03-14T10:15:00Z"));
+        assert_eq!(next("*/15 * * * *", "2026-03-14T10:45:00Z"), at("2026-03-14T11:00:00Z"));
+        // 2026-03-14 is a Saturday
+        assert_eq!(next("0 3 * * 1-5", "2026-03-14T10:07:00Z"), at("2026-03-16T03:00:00Z"));
+        assert_eq!(next("30 2 1 * *", "2026-12-05T00:00:00Z"), at("2027-01-01T02:30:00Z"));
+        assert_eq!(next("0 0 29 2 *", "2026-03-01T00:00:00Z"), at("2028-02-29T00:00:00Z"));
+        // Either restricted day field matches; Sunday is 7 as well as 0
+        assert_eq!(next("0 12 20 * 7", "2026-03-14T10:07:00Z"), at("2026-03-15T12:00:00Z"));
+        assert_eq!(next("0 12 16,20 * 0", "2026-03-15T13:00:00Z"), at("2026-03-16T12:00:00Z"));
+    }
+
+    #[tokio::test]
+    async fn test_interval_tasks_follow_the_clock() {
+        let clock = MockClock::new();
+        let (scheduler, metrics) = scheduler(&clock);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&runs);
+        let spec = TaskSpec::new("count", every(10)).jitter(Duration::ZERO);
+        scheduler
+            .register(spec, move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            })
+            .unwrap();
+        assert!(scheduler.register(TaskSpec::new("count", every(5)), || async { Ok(()) }).is_err());
+
+        assert_eq!(scheduler.tick(), Some(clock.now() + chrono::Duration::seconds(10)));
+        settle(&scheduler, "count").await;
+        clock.advance(Duration::from_secs(5));
+        scheduler.tick();
+        settle(&scheduler, "count").await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(5));
+        scheduler.tick();
+        let status = settle(&scheduler, "count").await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(status.runs, 2);
+        assert_eq!(status.last_run.unwrap().outcome, TaskOutcome::Succeeded);
+        assert_eq!(metrics.tasks.runs.with_labels(&["count", "succeeded"]).get(), 2);
+        assert!((metrics.tasks.last_success.with_labels(&["count"]).get() - 1.0).abs() < f64::EPSILON);
+
+        // Missed runs are not made up
+        clock.advance(Duration::from_mins(1));
+        scheduler.tick();
+        settle(&scheduler, "count").await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(scheduler.get("count").unwrap().next_run, Some(clock.now() + chrono::Duration::seconds(10)));
+    }
+
+    #[tokio::test]
+    async fn test_first_run_is_jittered_within_one_interval() {
+        let clock = MockClock::new();
+        let (scheduler, _) = scheduler(&clock);
+        for name in ["a", "b", "c", "d"] {
+            scheduler.register(TaskSpec::new(name, every(60)), || async { Ok(()) }).unwrap();
+        }
+        for status in scheduler.list() {
+            let next_run = status.next_run.unwrap();
+            assert!(next_run >= clock.now() && next_run < clock.now() + chrono::Duration::seconds(60));
+        }
+        let cron = TaskSpec::new("nightly", "0 3 * * *".parse().unwrap()).jitter(Duration::from_mins(10));
+        scheduler.register(cron, || async { Ok(()) }).unwrap();
+        let next_run = scheduler.get("nightly").unwrap().next_run.unwrap();
+        assert!(next_run >= at("2026-03-15T03:00:00Z") && next_run < at("2026-03-15T03:10:00Z"));
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_runs_are_skipped() {
+        let clock = MockClock::new();
+        let (scheduler, metrics) = scheduler(&clock);
+        let (release, released) = watch::channel(false);
+        let blocking = move || {
+            let mut released = released.clone();
+            async move {
+                let _ = released.wait_for(|released| *released).await;
+                Ok(())
+            }
+        };
+        scheduler.register(TaskSpec::new("slow", every(10)).jitter(Duration::ZERO), blocking.clone()).unwrap();
+        let allowed = TaskSpec::new("parallel", every(10)).jitter(Duration::ZERO).overlap(Overlap::Allow);
+        scheduler.register(allowed, blocking).unwrap();
+
+        scheduler.tick();
+        clock.advance(Duration::from_secs(10));
+        scheduler.tick();
+        let slow = scheduler.get("slow").unwrap();
+        assert_eq!((slow.running, slow.skipped), (1, 1));
+        assert_eq!(scheduler.get("parallel").unwrap().running, 2);
+        assert_eq!(metrics.tasks.runs.with_labels(&["slow", "skipped"]).get(), 1);
+        assert_eq!(scheduler.run_now("slow"), Err(RunNowError::AlreadyRunning("slow".to_string())));
+        assert_eq!(scheduler.run_now("missing"), Err(RunNowError::Unknown("missing".to_string())));
+
+        release.send_replace(true);
+        assert_eq!(settle(&scheduler, "slow").await.runs, 1);
+        assert_eq!(settle(&scheduler, "parallel").await.runs, 2);
+        scheduler.run_now("slow").unwrap();
+        assert_eq!(settle(&scheduler, "slow").await.runs, 2);
+    }
+
+    fn corrupt() -> Result<()> {
+        panic!("bad state")
+    }
+
+    #[tokio::test]
+    async fn test_failures_timeouts_and_panics_are_recorded() {
+        let clock = MockClock::new();
+        let (scheduler, metrics) = scheduler(&clock);
+        scheduler.register(TaskSpec::new("failing", every(10)), || async { Err(anyhow!("disk full")) }).unwrap();
+        let hanging = TaskSpec::new("hanging", every(10)).timeout(Duration::from_millis(20));
+        scheduler.register(hanging, std::future::pending).unwrap();
+        scheduler.register(TaskSpec::new("panicking", every(10)), || async { corrupt() }).unwrap();
+
+        for name in ["failing", "hanging", "panicking"] {
+            scheduler.run_now(name).unwrap();
+        }
+        let last = |status: TaskStatus| status.last_run.map(|run| (run.outcome, run.error.unwrap_or_default()));
+        let failing = settle(&scheduler, "failing").await;
+        assert_eq!(failing.failures, 1);
+        assert_eq!(last(failing), Some((TaskOutcome::Failed, "disk full".to_string())));
+        assert_eq!(
+            last(settle(&scheduler, "hanging").await),
+            Some((TaskOutcome::TimedOut, "Timed out after 20ms".to_string()))
+        );
+        assert_eq!(
+            last(settle(&scheduler, "panicking").await),
+            Some((TaskOutcome::Panicked, "Panicked: bad state".to_string()))
+        );
+        assert_eq!(metrics.tasks.runs.with_labels(&["hanging", "timed_out"]).get(), 1);
+        assert!(metrics.tasks.last_success.with_labels(&["failing"]).get().abs() < f64::EPSILON);
+
+        // The panicking task runs again
+        scheduler.run_now("panicking").unwrap();
+        assert_eq!(settle(&scheduler, "panicking").await.runs, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_or_cancels_by_policy() {
+        let clock = MockClock::new();
+        let (scheduler, _) = scheduler(&clock);
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&finished);
+        let waited = TaskSpec::new("flush", every(10)).on_shutdown(OnShutdown::Wait);
+        scheduler
+            .register(waited, move || {
+                let flag = Arc::clone(&flag);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    flag.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .unwrap();
+        scheduler.register(TaskSpec::new("sweep", every(10)), std::future::pending).unwrap();
+        scheduler.run_now("flush").unwrap();
+        scheduler.run_now("sweep").unwrap();
+
+        assert_eq!(scheduler.shutdown().await, Flushed::new(1, 1));
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(scheduler.get("flush").unwrap().last_run.unwrap().outcome, TaskOutcome::Succeeded);
+        let sweep = scheduler.get("sweep").unwrap();
+        assert_eq!(sweep.last_run.unwrap().outcome, TaskOutcome::Cancelled);
+        assert_eq!((sweep.running, sweep.next_run), (0, None));
+
+        clock.advance(Duration::from_mins(1));
+        assert_eq!(scheduler.tick(), None);
+        assert_eq!(scheduler.run_now("sweep"), Err(RunNowError::ShuttingDown));
+    }
+}
@@ -0,0 +1,373 @@
+//! Running every enabled component as one server
+//!
+//! [`Server::run`] binds the HTTP, WebSocket and gRPC listeners before
+//! anything is served, so a bind failure stops startup instead of leaving
+//! the other transports running alone. The transports and background monitoring then
+//! share one shutdown signal: asking the [`ServerHandle`] to stop, or any
+//! transport ending, drains the server in the same order, and then flushes
+//! the state with [`ServerState::shutdown`], whose report the handle keeps.
+//!
+//! Under systemd, listeners passed by socket activation are served instead
+//! of binding, and the service manager hears of readiness and draining; see
+//! [`systemd`].
+
+use crate::config::is_development_secret;
+use crate::monitoring::process;
+use crate::monitoring::statsd::StatsdExporter;
+use crate::shutdown::{Flushed, Phase, ShutdownReport};
+use crate::{http, lsp, systemd, websocket, ServerState, TokenAlgorithm};
+use anyhow::{anyhow, bail, Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{info, warn};
+
+/// Time given to the transports to finish once told to stop
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time given to the state to flush once the transports have stopped
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shutdown signal shared by the components and the handle
+#[derive(Debug, Clone)]
+struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Shutdown {
+    fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+
+    fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Completes once triggered, at once if already
+    fn triggered(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut stop = self.0.subscribe();
+        async move {
+            let _ = stop.wait_for(|stop| *stop).await;
+        }
+    }
+}
+
+/// Entry point running the enabled transports and the background monitoring
+pub struct Server;
+
+impl Server {
+    /// Bind every enabled listener and start serving
+    ///
+    /// Bind to port 0 and read the address back from the handle to serve on
+    /// an ephemeral port. A listener passed by socket activation is served
+    /// as it is, whatever the configured address.
+    ///
+    /// # Errors
+    ///
+    /// Fails without serving anything if authentication is enabled with the
+    /// development `jwt_secret`, a listener cannot bind or the `StatsD`
+    /// exporter cannot start.
+    #[allow(clippy::too_many_lines)] // A step per listener and task
+    pub async fn run(state: Arc<ServerState>) -> Result<ServerHandle> {
+        let config = state.config();
+        // Loading a configuration refuses this too; one built in code may not have been loaded
+        if config.enable_auth && config.jwt_algorithm == TokenAlgorithm::Hs256 && is_development_secret(&config.jwt_secret) {
+            bail!("Authentication is enabled with the development jwt_secret; set it, or ULC_JWT_SECRET, to a secret of your own");
+        }
+        let mut inherited = systemd::Listeners::from_env();
+        let http_listener = if config.enable_http {
+            Some(listen("HTTP API", systemd::HTTP, &config.http_addr, &mut inherited).await?)
+        } else {
+            None
+        };
+        let ws_listener = if config.enable_websocket {
+            Some(listen("WebSocket server", systemd::WEBSOCKET, &config.ws_addr, &mut inherited).await?)
+        } else {
+            None
+        };
+        #[cfg(feature = "grpc")]
+        let grpc_listener = if config.enable_grpc {
+            Some(listen("gRPC server", systemd::GRPC, &config.grpc_addr, &mut inherited).await?)
+        } else {
+            None
+        };
+        if !inherited.is_empty() {
+            warn!(sockets = %inherited.names().join(","), "Passed sockets not served; closing them");
+        }
+        let statsd = match &config.statsd {
+            Some(statsd_config) => {
+                info!("📤 Pushing metrics to StatsD at {:?}", statsd_config.target);
+                Some(StatsdExporter::new(statsd_config.clone(), Arc::clone(&state.metrics))?)
+            }
+            None => None,
+        };
+
+        let shutdown = Shutdown::new();
+        let mut components = JoinSet::new();
+        if config.enable_lsp {
+            info!("📝 Starting LSP server (stdio)...");
+            let (state, stop) = (Arc::clone(&state), shutdown.triggered());
+            components.spawn(async move {
+                let result = tokio::select! {
+                    result = lsp::run_lsp_server(state) => result,
+                    () = stop => Ok(()),
+                };
+                ("LSP server", result)
+            });
+        }
+        let http_addr = match http_listener {
+            Some(listener) => {
+                let addr = listener.local_addr()?;
+                info!("🌐 HTTP API listening on {}", addr);
+                let serving = http::serve_http_until(Arc::clone(&state), listener, shutdown.triggered());
+                components.spawn(async move { ("HTTP API", serving.await) });
+                Some(addr)
+            }
+            None => None,
+        };
+        let ws_addr = match ws_listener {
+            Some(listener) => {
+                let addr = listener.local_addr()?;
+                info!("🔌 WebSocket server listening on {}", addr);
+                let serving = websocket::serve_websocket_until(Arc::clone(&state), listener, shutdown.triggered());
+                components.spawn(async move { ("WebSocket server", serving.await) });
+                Some(addr)
+            }
+            None => None,
+        };
+        #[cfg(feature = "grpc")]
+        let grpc_addr = match grpc_listener {
+            Some(listener) => {
+                let addr = listener.local_addr()?;
+                info!("📡 gRPC server listening on {}", addr);
+                let serving = crate::grpc::serve_grpc_until(Arc::clone(&state), listener, shutdown.triggered());
+                components.spawn(async move { ("gRPC server", serving.await) });
+                Some(addr)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "grpc"))]
+        let grpc_addr = None;
+
+        let mut background = JoinSet::new();
+        background.spawn(Arc::clone(&state.health_checker).run(interval("HEALTH_INTERVAL_SECS", 10)));
+        background.spawn(process::run_sampler(
+            state.metrics.process.clone(),
+            interval("PROCESS_METRICS_INTERVAL_SECS", 10),
+        ));
+        background.spawn(Arc::clone(&state.metrics).run_rate_sampler());
+        background.spawn(Arc::clone(&state.rules).run(Arc::clone(&state.metrics), interval("ALERT_INTERVAL_SECS", 15)));
+        background.spawn(Arc::clone(&state.alerts).run());
+        background.spawn(Arc::clone(&state.audit).run());
+        background.spawn(Arc::clone(&state.scheduler).run());
+        if let Some(interval) = systemd::watchdog_interval() {
+            background.spawn(systemd::run_watchdog(Arc::clone(&state.metrics), interval));
+        }
+        if let Some(exporter) = statsd {
+            let pushing = tokio::spawn(exporter.run(shutdown.triggered()));
+            // Push the final counts, which the hooks before this one have settled
+            state.shutdown_hooks.register("statsd", Phase::Exporters, |_| async move {
+                pushing.await?;
+                Ok(Flushed::new(1, 0))
+            });
+        }
+
+        state.health_checker.mark_started();
+        systemd::notify_ready();
+        info!("✅ All servers started successfully");
+
+        let task = tokio::spawn(supervise(state, components, background, shutdown.clone()));
+        Ok(ServerHandle {
+            http_addr,
+            ws_addr,
+            grpc_addr,
+            shutdown,
+            task: Some(task),
+            report: None,
+        })
+    }
+}
+
+/// A running server
+#[derive(Debug)]
+pub struct ServerHandle {
+    http_addr: Option<SocketAddr>,
+    ws_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    shutdown: Shutdown,
+    task: Option<JoinHandle<(Result<()>, ShutdownReport)>>,
+    report: Option<ShutdownReport>,
+}
+
+impl ServerHandle {
+    /// Address the HTTP API is bound to, unless disabled
+    #[must_use]
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    /// Address the WebSocket server is bound to, unless disabled
+    #[must_use]
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    /// Address the gRPC server is bound to, unless disabled or built without the `grpc` feature
+    #[must_use]
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc_addr
+    }
+
+    /// Ask every component to stop; [`ServerHandle::wait`] returns once they have
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Wait for the server to stop, returning the error of the component that stopped it
+    ///
+    /// Cancelling the wait leaves the server running; once it has returned,
+    /// waiting again returns `Ok` at once.
+    ///
+    /// # Errors
+    ///
+    /// The error of the component that stopped the server, if it failed.
+    pub async fn wait(&mut self) -> Result<()> {
+        let Some(task) = self.task.as_mut() else {
+            return Ok(());
+        };
+        let joined = task.await;
+        self.task = None;
+        let (result, report) = joined.map_err(|e| anyhow!("Server supervisor failed: {e}"))?;
+        self.report = Some(report);
+        result
+    }
+
+    /// What the state flushed on the way out, once [`ServerHandle::wait`] has returned
+    #[must_use]
+    pub fn report(&self) -> Option<&ShutdownReport> {
+        self.report.as_ref()
+    }
+}
+
+/// The listener passed as `socket` by the service manager, or else one bound to `addr`
+async fn listen(name: &str, socket: &str, addr: &str, inherited: &mut systemd::Listeners) -> Result<TcpListener> {
+    match inherited.take(socket) {
+        Some(listener) => {
+            TcpListener::from_std(listener).with_context(|| format!("Failed to serve the {name} on the passed socket"))
+        }
+        None => TcpListener::bind(addr).await.with_context(|| format!("Failed to bind the {name} to {addr}")),
+    }
+}
+
+/// Interval read from the environment variable `name`, in seconds
+fn interval(name: &str, default_secs: u64) -> Duration {
+    let secs = std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
+/// Wait for a stop request or a transport ending, then drain the server
+///
+/// The lifecycle reports draining before the transports stop accepting, so
+/// load balancers see the server leave; jobs and maintenance runs are
+/// settled, documents and usage written and the final `StatsD` push made
+/// before it reports stopped.
+async fn supervise(
+    state: Arc<ServerState>,
+    mut components: JoinSet<(&'static str, Result<()>)>,
+    mut background: JoinSet<()>,
+    shutdown: Shutdown,
+) -> (Result<()>, ShutdownReport) {
+    let first = tokio::select! {
+        Some(joined) = components.join_next() => Some(joined),
+        () = shutdown.triggered() => None,
+    };
+    let mut outcome = match first {
+        None => Ok(()),
+        Some(joined) => {
+            let result = stopped(joined);
+            if result.is_ok() {
+                info!("Server component completed; shutting down");
+            }
+            result
+        }
+    };
+
+    state.health_checker.begin_shutdown();
+    systemd::notify_stopping();
+    shutdown.trigger();
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while let Some(joined) = components.join_next().await {
+            if let Err(e) = stopped(joined) {
+                warn!("{:#}", e);
+                if outcome.is_ok() {
+                    outcome = Err(e);
+                }
+            }
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Server components still running after {:?}; stopping them", DRAIN_TIMEOUT);
+        components.shutdown().await;
+    }
+    background.shutdown().await;
+    let report = state.shutdown(FLUSH_TIMEOUT).await;
+    state.health_checker.mark_stopped();
+    (outcome, report)
+}
+
+/// The outcome of a component that has stopped, naming it in any error
+fn stopped(joined: Result<(&'static str, Result<()>), tokio::task::JoinError>) -> Result<()> {
+    match joined {
+        Ok((name, result)) => result.with_context(|| format!("{name} failed")),
+        Err(e) => Err(anyhow!("Server component panicked: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::LifecycleState;
+    use crate::ServerConfig;
+
+    #[tokio::test]
+    async fn test_refuses_the_development_secret() {
+        let config = ServerConfig { enable_auth: true, http_addr: "127.0.0.1:0".to_string(), ..ServerConfig::default() };
+        let error = Server::run(Arc::new(ServerState::new(config))).await.err().unwrap();
+        assert!(error.to_string().contains("development jwt_secret"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_failure_stops_every_component() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let shutdown = Shutdown::new();
+        let mut components = JoinSet::new();
+        components.spawn(async { ("failing", Err(anyhow!("listener closed"))) });
+        let stop = shutdown.triggered();
+        components.spawn(async move {
+            stop.await;
+            ("healthy", Ok(()))
+        });
+
+        let (outcome, report) = supervise(Arc::clone(&state), components, JoinSet::new(), shutdown).await;
+        assert_eq!(format!("{:#}", outcome.unwrap_err()), "failing failed: listener closed");
+        assert!(report.is_clean());
+        assert_eq!(state.health_checker.state(), LifecycleState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_requested_shutdown_is_ok() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let shutdown = Shutdown::new();
+        let mut components = JoinSet::new();
+        let stop = shutdown.triggered();
+        components.spawn(async move {
+            stop.await;
+            ("healthy", Ok(()))
+        });
+        shutdown.trigger();
+        let (outcome, report) = supervise(state, components, JoinSet::new(), shutdown).await;
+        outcome.unwrap();
+        assert!(report.get("jobs").is_some());
+    }
+}
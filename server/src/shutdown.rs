@@ -0,0 +1,256 @@
+//! Releasing resources when the server stops
+//!
+//! Subsystems holding work or buffered data register a flush hook with the
+//! [`ShutdownHooks`] of the server state instead of [`ServerState::shutdown`]
+//! knowing about each of them. Hooks run one at a time, by [`Phase`] and
+//! then in the order they were registered, against one deadline: background
+//! jobs are settled first, so that what they write is persisted, then the
+//! document store, then in-memory buffers, and exporters last, so that they
+//! carry the counts of everything before them.
+//!
+//! Each hook reports how many of its pending items it completed and how many
+//! it abandoned. The [`ShutdownReport`] collects them per subsystem, with any
+//! error and whether the hook ran out of time, and the binary logs it before
+//! exiting.
+//!
+//! [`ServerState::shutdown`]: crate::ServerState::shutdown
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// When a hook runs relative to the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Stop taking background work and let what is in flight finish
+    Jobs,
+    /// Write stored state to disk
+    Persistence,
+    /// Deliver or write what is buffered in memory
+    Buffers,
+    /// Push final values to external collectors and close them
+    Exporters,
+}
+
+/// What a hook did with the items pending when it ran
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flushed {
+    /// Items finished, written or delivered
+    pub completed: usize,
+    /// Items left undone
+    pub abandoned: usize,
+}
+
+impl Flushed {
+    #[must_use]
+    pub fn new(completed: usize, abandoned: usize) -> Self {
+        Self { completed, abandoned }
+    }
+}
+
+/// How one subsystem's hook ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubsystemReport {
+    pub name: String,
+    pub phase: Phase,
+    pub completed: usize,
+    pub abandoned: usize,
+    /// Whether the deadline passed before the hook finished
+    pub timed_out: bool,
+    /// The error the hook failed with, if it did
+    pub error: Option<String>,
+}
+
+impl SubsystemReport {
+    /// Whether the subsystem finished everything it had
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        !self.timed_out && self.error.is_none() && self.abandoned == 0
+    }
+}
+
+/// What every hook did, in the order they ran
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownReport {
+    pub subsystems: Vec<SubsystemReport>,
+    /// Time taken by the hooks together
+    #[serde(with = "crate::config::duration")]
+    pub elapsed: Duration,
+}
+
+impl ShutdownReport {
+    /// The report of the subsystem called `name`
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&SubsystemReport> {
+        self.subsystems.iter().find(|subsystem| subsystem.name == name)
+    }
+
+    /// Items completed across every subsystem
+    #[must_use]
+    pub fn completed(&self) -> usize {
+        self.subsystems.iter().map(|subsystem| subsystem.completed).sum()
+    }
+
+    /// Items abandoned across every subsystem
+    #[must_use]
+    pub fn abandoned(&self) -> usize {
+        self.subsystems.iter().map(|subsystem| subsystem.abandoned).sum()
+    }
+
+    /// Whether every subsystem finished everything it had
+    pub fn is_clean(&self) -> bool {
+        self.subsystems.iter().all(SubsystemReport::is_clean)
+    }
+
+    /// Log one line per subsystem, at `warn` for those that left work undone
+    pub fn log(&self) {
+        for subsystem in &self.subsystems {
+            let (name, completed, abandoned) = (&subsystem.name, subsystem.completed, subsystem.abandoned);
+            if subsystem.is_clean() {
+                info!(subsystem = %name, completed, "Flushed on shutdown");
+            } else if subsystem.timed_out {
+                warn!(subsystem = %name, completed, abandoned, "Flush on shutdown timed out");
+            } else {
+                let error = subsystem.error.as_deref().unwrap_or("work left undone");
+                warn!(subsystem = %name, completed, abandoned, error, "Flush on shutdown incomplete");
+            }
+        }
+        info!(
+            completed = self.completed(),
+            abandoned = self.abandoned(),
+            "Shutdown flushed in {}",
+            crate::config::duration::format(self.elapsed)
+        );
+    }
+}
+
+/// A registered hook, given the deadline it must finish by
+type Hook = Box<dyn FnOnce(Instant) -> BoxFuture<'static, Result<Flushed>> + Send>;
+
+/// Flush hooks run once, when the server stops
+#[derive(Default)]
+pub struct ShutdownHooks {
+    hooks: Mutex<Vec<(String, Phase, Hook)>>,
+}
+
+impl std::fmt::Debug for ShutdownHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hooks = self.hooks.lock().expect("shutdown hooks lock poisoned");
+        let names: Vec<&str> = hooks.iter().map(|(name, _, _)| name.as_str()).collect();
+        f.debug_struct("ShutdownHooks").field("hooks", &names).finish()
+    }
+}
+
+impl ShutdownHooks {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `hook` for the subsystem `name` in `phase` at shutdown
+    ///
+    /// The hook is given the deadline it should finish by, so it can count
+    /// what it leaves undone. One still running at the deadline is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the hook list.
+    pub fn register<F, Fut>(&self, name: &str, phase: Phase, hook: F)
+    where
+        F: FnOnce(Instant) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Flushed>> + Send + 'static,
+    {
+        let hook: Hook = Box::new(move |deadline| hook(deadline).boxed());
+        self.hooks
+            .lock()
+            .expect("shutdown hooks lock poisoned")
+            .push((name.to_string(), phase, hook));
+    }
+
+    /// Run every hook, by phase, within `timeout` in all
+    ///
+    /// Hooks run once: running them again reports nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the hook list.
+    pub async fn run(&self, timeout: Duration) -> ShutdownReport {
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let mut hooks = std::mem::take(&mut *self.hooks.lock().expect("shutdown hooks lock poisoned"));
+        // Stable, so registration order holds within a phase
+        hooks.sort_by_key(|(_, phase, _)| *phase);
+
+        let mut subsystems = Vec::with_capacity(hooks.len());
+        for (name, phase, hook) in hooks {
+            let mut report =
+                SubsystemReport { name, phase, completed: 0, abandoned: 0, timed_out: false, error: None };
+            match tokio::time::timeout_at(deadline, hook(deadline)).await {
+                Ok(Ok(flushed)) => {
+                    report.completed = flushed.completed;
+                    report.abandoned = flushed.abandoned;
+                }
+                Ok(Err(e)) => report.error = Some(format!("{e:#}")),
+                Err(_) => report.timed_out = true,
+            }
+            subsystems.push(report);
+        }
+        ShutdownReport { subsystems, elapsed: start.elapsed() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_hooks_run_once_by_phase() {
+        let hooks = ShutdownHooks::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (name, phase) in [
+            ("statsd", Phase::Exporters),
+            ("documents", Phase::Persistence),
+            ("jobs", Phase::Jobs),
+            ("usage", Phase::Buffers),
+            ("alerts", Phase::Buffers),
+        ] {
+            let order = Arc::clone(&order);
+            hooks.register(name, phase, move |_| async move {
+                order.lock().unwrap().push(name);
+                Ok(Flushed::new(1, 0))
+            });
+        }
+
+        let report = hooks.run(Duration::from_secs(1)).await;
+        assert_eq!(*order.lock().unwrap(), ["jobs", "documents", "usage", "alerts", "statsd"]);
+        assert_eq!(report.completed(), 5);
+        assert!(report.is_clean());
+        assert!(hooks.run(Duration::from_secs(1)).await.subsystems.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failures_and_timeouts_are_reported() {
+        let hooks = ShutdownHooks::new();
+        hooks.register("partial", Phase::Jobs, |_| async { Ok(Flushed::new(3, 2)) });
+        hooks.register("failing", Phase::Buffers, |_| async { Err(anyhow::anyhow!("disk full")) });
+        hooks.register("hanging", Phase::Buffers, |_| std::future::pending());
+        hooks.register("late", Phase::Exporters, |_| async { Ok(Flushed::new(1, 0)) });
+
+        let report = hooks.run(Duration::from_millis(50)).await;
+        assert_eq!(report.get("partial").unwrap().abandoned, 2);
+        assert_eq!(report.get("failing").unwrap().error.as_deref(), Some("disk full"));
+        assert!(report.get("hanging").unwrap().timed_out);
+        // The deadline is shared, so hooks after an overrun one get no time
+        assert!(report.get("late").unwrap().timed_out || report.get("late").unwrap().completed == 1);
+        assert!(!report.is_clean());
+        assert_eq!(report.abandoned(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+//! Running as a systemd service
+//!
+//! With socket activation systemd binds the listeners itself and passes them
+//! down through `LISTEN_FDS`, `LISTEN_PID` and `LISTEN_FDNAMES`. Sockets
+//! named `http`, `websocket` and `grpc` (the `FileDescriptorName=` of their
+//! socket units) are served in place of binding `http_addr`, `ws_addr` and
+//! `grpc_addr`; the metrics are part of the HTTP API, so they have no socket
+//! of their own.
+//!
+//! The service manager is told through `NOTIFY_SOCKET` when every transport
+//! is serving (`READY=1`) and when draining begins (`STOPPING=1`). With
+//! `WatchdogSec=` set, a `WATCHDOG=1` heartbeat is sent at half the interval
+//! for as long as the event loop lag check is not unhealthy, so a stalled
+//! server is restarted.
+//!
+//! Without those variables, and on platforms other than Linux, all of this
+//! does nothing.
+
+use crate::monitoring::checks::EventLoopLagCheck;
+use crate::monitoring::{HealthCheck, Metrics, ServiceStatus};
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Name of the socket served by the HTTP API
+pub const HTTP: &str = "http";
+/// Name of the socket served by the WebSocket server
+pub const WEBSOCKET: &str = "websocket";
+/// Name of the socket served by the gRPC server
+pub const GRPC: &str = "grpc";
+
+/// Listeners passed down by the service manager, by name
+#[derive(Debug, Default)]
+pub struct Listeners {
+    by_name: BTreeMap<String, TcpListener>,
+}
+
+impl Listeners {
+    /// Take the listeners passed to this process, if any
+    ///
+    /// The variables are removed, so that child processes such as
+    /// downstream language servers do not take the sockets for theirs. A
+    /// socket that is not a TCP listener is logged and closed.
+    #[must_use]
+    pub fn from_env() -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            linux::listeners()
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Self::default()
+        }
+    }
+
+    /// Take the listener passed as `name`
+    pub fn take(&mut self, name: &str) -> Option<TcpListener> {
+        self.by_name.remove(name)
+    }
+
+    /// Names of the listeners not taken
+    pub fn names(&self) -> Vec<&str> {
+        self.by_name.keys().map(String::as_str).collect()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+/// Tell the service manager every transport is serving
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tell the service manager the server is draining
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Send `state` to the service manager, returning whether there was one to send it to
+pub fn notify(state: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        match linux::notify(state) {
+            Ok(sent) => sent,
+            Err(e) => {
+                warn!("Failed to notify the service manager of {}: {}", state, e);
+                false
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = state;
+        false
+    }
+}
+
+/// Interval the service manager expects watchdog heartbeats within, if it asked for any
+#[must_use]
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::watchdog_interval()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Send a heartbeat every half `interval` while the event loop keeps up
+///
+/// A heartbeat is skipped while the event loop lag check reports
+/// unhealthy, so the service manager restarts a server that stays stalled.
+pub async fn run_watchdog(metrics: Arc<Metrics>, interval: Duration) {
+    let check = EventLoopLagCheck::new(metrics);
+    let mut ticks = tokio::time::interval(interval / 2);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let result = check.check().await;
+        if result.status == ServiceStatus::Unhealthy {
+            warn!("Watchdog heartbeat withheld: {}", result.message.as_deref().unwrap_or("event loop lagging"));
+        } else {
+            notify("WATCHDOG=1");
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Listeners;
+    use std::io;
+    use std::os::fd::{FromRawFd, RawFd};
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::time::Duration;
+    use tracing::warn;
+
+    /// First descriptor passed by the service manager
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// Whether `LISTEN_PID` or `WATCHDOG_PID`, when set, names this process
+    fn for_us(variable: &str) -> bool {
+        std::env::var(variable).map_or(true, |pid| pid.parse() == Ok(std::process::id()))
+    }
+
+    pub(super) fn listeners() -> Listeners {
+        let count: Option<RawFd> = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse().ok());
+        let pid_set = std::env::var_os("LISTEN_PID").is_some();
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for variable in ["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+            std::env::remove_var(variable);
+        }
+        let mut listeners = Listeners::default();
+        let Some(count) = count.filter(|_| pid_set && for_us("LISTEN_PID")) else {
+            return listeners;
+        };
+
+        let mut names = names.split(':');
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown").to_string();
+            // SAFETY: the service manager passed descriptors 3 to 3 + LISTEN_FDS
+            // to this process, and nothing else in it owns them
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // Not to be inherited by the processes this one starts
+            // SAFETY: fcntl on a descriptor this process owns
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let checked = if is_listening(fd) {
+                listener.local_addr().and_then(|_| listener.set_nonblocking(true))
+            } else {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "not listening"))
+            };
+            match checked {
+                Ok(()) => {
+                    listeners.by_name.insert(name, listener);
+                }
+                Err(e) => warn!(fd, name = %name, "Passed socket is not a TCP listener; closing it: {}", e),
+            }
+        }
+        listeners
+    }
+
+    /// Whether `fd` is a socket accepting connections
+    fn is_listening(fd: RawFd) -> bool {
+        let mut accepting: libc::c_int = 0;
+        let mut len = libc::socklen_t::try_from(std::mem::size_of::<libc::c_int>()).expect("c_int fits socklen_t");
+        // SAFETY: the option is read into an int of the length given
+        let read = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN, (&raw mut accepting).cast(), &raw mut len)
+        };
+        read == 0 && accepting != 0
+    }
+
+    /// Send `state` to `NOTIFY_SOCKET`, returning whether it is set
+    pub(super) fn notify(state: &str) -> io::Result<bool> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(false);
+        };
+        let path = path.to_string_lossy();
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path.as_ref())?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(true)
+    }
+
+    pub(super) fn watchdog_interval() -> Option<Duration> {
+        let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        (usec > 0 && for_us("WATCHDOG_PID")).then(|| Duration::from_micros(usec))
+    }
+}
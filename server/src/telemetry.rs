@@ -0,0 +1,209 @@
+//! OpenTelemetry trace export
+//!
+//! Spans are created with `tracing` at each layer boundary — HTTP requests,
+//! WebSocket messages, LSP dispatch, store operations, conversion and
+//! validation — and exported over OTLP when an endpoint is configured. Span
+//! attributes describe the work (method, format, size bucket) and never
+//! carry document content.
+//!
+//! Trace context crosses process boundaries as a W3C `traceparent`: in the
+//! HTTP header of that name, and in the `traceparent` field of `Lsp`
+//! WebSocket messages.
+
+use anyhow::Result;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, Sampler, Tracer};
+use opentelemetry_sdk::Resource;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// Name of the trace context HTTP header and `Lsp` message field
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Trace export configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct TracingConfig {
+    /// OTLP gRPC endpoint; traces are not exported when `None`
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` resource attribute
+    pub service_name: String,
+    /// `service.version` resource attribute
+    pub service_version: String,
+    /// Fraction of new traces recorded; continued traces follow the caller's decision
+    pub sampling_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            service_version: crate::build_info::VERSION.to_string(),
+            sampling_ratio: 1.0,
+        }
+    }
+}
+
+impl TracingConfig {
+    /// Read the standard `OTEL_EXPORTER_OTLP_ENDPOINT`, `OTEL_SERVICE_NAME`
+    /// and `OTEL_TRACES_SAMPLER_ARG` variables
+    #[must_use]
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            otlp_endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|v| !v.is_empty()),
+            service_name: std::env::var("OTEL_SERVICE_NAME").unwrap_or(defaults.service_name),
+            service_version: defaults.service_version,
+            sampling_ratio: std::env::var("OTEL_TRACES_SAMPLER_ARG")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map_or(defaults.sampling_ratio, |ratio: f64| ratio.clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Resource attributes identifying this service
+    #[must_use]
+    pub fn resource(&self) -> Resource {
+        Resource::new([
+            KeyValue::new("service.name", self.service_name.clone()),
+            KeyValue::new("service.version", self.service_version.clone()),
+        ])
+    }
+
+    /// Sampler honouring the caller's decision for continued traces
+    #[must_use]
+    pub fn sampler(&self) -> Sampler {
+        Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.sampling_ratio)))
+    }
+}
+
+/// Layer exporting spans over OTLP, or `None` when no endpoint is configured
+///
+/// Spans are exported in batches from the Tokio runtime, so this must be
+/// called inside one. Call [`shutdown`] before exiting to flush them.
+///
+/// # Errors
+///
+/// Fails where the exporter cannot be built for the endpoint.
+pub fn layer<S>(config: &TracingConfig) -> Result<Option<OpenTelemetryLayer<S, Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(
+            sdktrace::config()
+                .with_sampler(config.sampler())
+                .with_resource(config.resource()),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Export spans still buffered and stop the exporter
+pub async fn shutdown() {
+    // Flushing blocks until the exporter is done
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+/// Make `span` continue the trace described by `traceparent`
+///
+/// Missing or malformed values leave the span where it is.
+pub fn set_parent(span: &Span, traceparent: Option<&str>) {
+    let Some(traceparent) = traceparent else {
+        return;
+    };
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let context = TraceContextPropagator::new().extract(&carrier);
+    if context.span().span_context().is_valid() {
+        span.set_parent(context);
+    }
+}
+
+/// `traceparent` continuing the current span's trace, if it is being recorded
+#[must_use]
+pub fn current_traceparent() -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+    carrier.remove(TRACEPARENT)
+}
+
+/// Trace and span IDs of an exported span, as hex
+pub fn span_ids<S>(span: &SpanRef<'_, S>) -> Option<(String, String)>
+where
+    S: for<'span> LookupSpan<'span>,
+{
+    let extensions = span.extensions();
+    let data = extensions.get::<OtelData>()?;
+    // Spans without an active parent get their own trace ID
+    let trace_id = if data.parent_cx.has_active_span() {
+        data.parent_cx.span().span_context().trace_id()
+    } else {
+        data.builder.trace_id?
+    };
+    Some((trace_id.to_string(), data.builder.span_id?.to_string()))
+}
+
+/// Coarse document size for span attributes
+#[must_use]
+pub fn size_bucket(len: usize) -> &'static str {
+    match len {
+        0..=1_023 => "<1KiB",
+        1_024..=65_535 => "1KiB-64KiB",
+        65_536..=1_048_575 => "64KiB-1MiB",
+        _ => ">1MiB",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const REMOTE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_size_bucket() {
+        assert_eq!(size_bucket(0), "<1KiB");
+        assert_eq!(size_bucket(1024), "1KiB-64KiB");
+        assert_eq!(size_bucket(65_536), "64KiB-1MiB");
+        assert_eq!(size_bucket(usize::MAX), ">1MiB");
+    }
+
+    #[test]
+    fn test_traceparent_round_trip() {
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            // Nothing to continue outside a span
+            assert_eq!(current_traceparent(), None);
+
+            let span = tracing::info_span!("continued");
+            set_parent(&span, Some(REMOTE));
+            let traceparent = span.in_scope(current_traceparent).unwrap();
+            assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+            assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+            // Garbage is ignored rather than starting a broken trace
+            let span = tracing::info_span!("fresh");
+            set_parent(&span, Some("not-a-traceparent"));
+            let traceparent = span.in_scope(current_traceparent).unwrap();
+            assert!(!traceparent.contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+        });
+    }
+}
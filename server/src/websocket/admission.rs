@@ -0,0 +1,481 @@
+//! Admission control for WebSocket connections
+//!
+//! Connections are counted against a server-wide cap, a per-subject cap for
+//! authenticated clients and a per-IP cap, and refused at upgrade time once
+//! a cap is reached. With [`ConnectionLimits::displace_idle`] set, a subject
+//! at its cap instead displaces its longest idle connection, which lets a
+//! client reconnect while its previous socket is still half open.
+
+use crate::monitoring::registry::{Family, Gauge};
+use crate::monitoring::Metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+/// Caps on concurrent WebSocket connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ConnectionLimits {
+    /// Connections across all clients
+    pub max_total: usize,
+    /// Connections per authenticated subject
+    pub max_per_subject: usize,
+    /// Connections per client IP
+    pub max_per_ip: usize,
+    /// Displace a subject's longest idle connection instead of refusing a new one
+    pub displace_idle: bool,
+    /// Delay suggested to refused clients via `Retry-After`
+    #[serde(with = "crate::config::duration")]
+    pub retry_after: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_total: 10_000,
+            max_per_subject: 32,
+            max_per_ip: 256,
+            displace_idle: false,
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The limit a refused connection ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Server-wide connection cap
+    Total,
+    /// Per-subject connection cap
+    Subject,
+    /// Per-IP connection cap
+    Ip,
+}
+
+impl Limit {
+    /// Name used in metrics
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Limit::Total => "total",
+            Limit::Subject => "per_subject",
+            Limit::Ip => "per_ip",
+        }
+    }
+
+    /// HTTP status for the refused upgrade
+    ///
+    /// A full server is unavailable to everyone; the per-client caps mean
+    /// this client is making too many connections.
+    #[must_use]
+    pub fn status(self) -> StatusCode {
+        match self {
+            Limit::Total => StatusCode::SERVICE_UNAVAILABLE,
+            Limit::Subject | Limit::Ip => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+/// Who a connection belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Authenticated subject, if the client presented a token
+    pub subject: Option<String>,
+    /// Client address, resolved through trusted proxies
+    pub ip: IpAddr,
+}
+
+/// A refused connection
+#[derive(Debug, Clone, Copy)]
+pub struct Rejection {
+    /// The limit that was reached
+    pub limit: Limit,
+    /// Delay the client should wait before retrying
+    pub retry_after: Duration,
+}
+
+/// An admitted connection, as tracked by [`Admission`]
+#[derive(Debug)]
+struct Connection {
+    id: u64,
+    identity: Identity,
+    /// Milliseconds since the admission epoch at the last client activity
+    last_activity: AtomicU64,
+    /// Set once the connection no longer counts against any limit
+    released: AtomicBool,
+    displaced: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_subject: HashMap<String, Vec<Arc<Connection>>>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Connection counts and the limits they are checked against
+pub struct Admission {
+    limits: RwLock<ConnectionLimits>,
+    metrics: Arc<Metrics>,
+    counts: Mutex<Counts>,
+    next_id: AtomicU64,
+    epoch: Instant,
+}
+
+impl Admission {
+    /// Create admission control reporting its counts to `metrics`
+    #[must_use]
+    pub fn new(limits: ConnectionLimits, metrics: Arc<Metrics>) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            metrics,
+            counts: Mutex::new(Counts::default()),
+            next_id: AtomicU64::new(1),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Limits in force
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the limits' lock.
+    pub fn limits(&self) -> ConnectionLimits {
+        self.limits.read().expect("admission lock poisoned").clone()
+    }
+
+    /// Check connections admitted from now on against `limits`
+    ///
+    /// Open connections are kept, even where they exceed the new limits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the limits' lock.
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        *self.limits.write().expect("admission lock poisoned") = limits;
+    }
+
+    /// Open connections
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the admission lock.
+    pub fn len(&self) -> usize {
+        self.counts.lock().expect("admission lock poisoned").total
+    }
+
+    /// Whether no connection is open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Distinct authenticated subjects with an open connection
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the admission lock.
+    pub fn subjects(&self) -> usize {
+        self.counts.lock().expect("admission lock poisoned").per_subject.len()
+    }
+
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+
+    /// Admit a connection, or report the limit it runs into
+    ///
+    /// The returned guard holds the connection's place until dropped.
+    ///
+    /// # Errors
+    ///
+    /// A [`Rejection`] naming the limit the connection runs into.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the admission lock.
+    pub fn admit(self: &Arc<Self>, identity: Identity) -> Result<ConnectionGuard, Rejection> {
+        let limits = self.limits();
+        let mut counts = self.counts.lock().expect("admission lock poisoned");
+
+        // Under the displacement policy, a subject at its cap frees a slot
+        // before the other limits are checked
+        let mut displaced = None;
+        if let Some(subject) = &identity.subject {
+            let open = counts.per_subject.get(subject).map_or(0, Vec::len);
+            if open >= limits.max_per_subject {
+                if !limits.displace_idle || open == 0 {
+                    return Err(self.reject(Limit::Subject));
+                }
+                let idlest = counts.per_subject[subject]
+                    .iter()
+                    .min_by_key(|c| c.last_activity.load(Ordering::Relaxed))
+                    .map(Arc::clone)
+                    .expect("subject at its cap has connections");
+                self.release(&mut counts, &idlest);
+                displaced = Some(idlest);
+            }
+        }
+
+        let rejected = if counts.total >= limits.max_total {
+            Some(Limit::Total)
+        } else if counts.per_ip.get(&identity.ip).copied().unwrap_or(0) >= limits.max_per_ip {
+            Some(Limit::Ip)
+        } else {
+            None
+        };
+        if let Some(limit) = rejected {
+            // Undo the displacement: the connection was never closed
+            if let Some(connection) = displaced {
+                connection.released.store(false, Ordering::SeqCst);
+                self.track(&mut counts, &connection);
+            }
+            return Err(self.reject(limit));
+        }
+
+        if let Some(connection) = displaced {
+            self.metrics.ws_displaced.inc();
+            connection.displaced.notify_one();
+        }
+
+        let connection = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            identity,
+            last_activity: AtomicU64::new(self.now()),
+            released: AtomicBool::new(false),
+            displaced: Notify::new(),
+        });
+        self.track(&mut counts, &connection);
+        Ok(ConnectionGuard {
+            admission: Arc::clone(self),
+            connection,
+        })
+    }
+
+    fn reject(&self, limit: Limit) -> Rejection {
+        self.metrics.ws_rejections.with_labels(&[limit.as_str()]).inc();
+        Rejection {
+            limit,
+            retry_after: self.limits.read().expect("admission lock poisoned").retry_after,
+        }
+    }
+
+    fn track(&self, counts: &mut Counts, connection: &Arc<Connection>) {
+        let identity = &connection.identity;
+        counts.total += 1;
+        *counts.per_ip.entry(identity.ip).or_insert(0) += 1;
+        if let Some(subject) = &identity.subject {
+            counts.per_subject.entry(subject.clone()).or_default().push(Arc::clone(connection));
+        }
+        self.report(counts, identity);
+    }
+
+    /// Stop counting a connection; releasing twice is a no-op
+    fn release(&self, counts: &mut Counts, connection: &Connection) {
+        if connection.released.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let identity = &connection.identity;
+        counts.total -= 1;
+        if let Some(open) = counts.per_ip.get_mut(&identity.ip) {
+            *open -= 1;
+            if *open == 0 {
+                counts.per_ip.remove(&identity.ip);
+            }
+        }
+        if let Some(subject) = &identity.subject {
+            if let Some(open) = counts.per_subject.get_mut(subject) {
+                open.retain(|c| c.id != connection.id);
+                if open.is_empty() {
+                    counts.per_subject.remove(subject);
+                }
+            }
+        }
+        self.report(counts, identity);
+    }
+
+    /// Copy the counts touched by `identity` to the metrics gauges
+    #[allow(clippy::cast_precision_loss)]
+    fn report(&self, counts: &Counts, identity: &Identity) {
+        fn set(family: &Family<Gauge>, label: &str, open: usize) {
+            if open == 0 {
+                let _ = family.remove(&[label]);
+            } else {
+                family.with_labels(&[label]).set(open as f64);
+            }
+        }
+
+        self.metrics.ws_connections.set(counts.total as f64);
+        let ip = identity.ip.to_string();
+        set(&self.metrics.ws_connections_per_ip, &ip, counts.per_ip.get(&identity.ip).copied().unwrap_or(0));
+        if let Some(subject) = &identity.subject {
+            set(
+                &self.metrics.ws_connections_per_subject,
+                subject,
+                counts.per_subject.get(subject).map_or(0, Vec::len),
+            );
+        }
+    }
+}
+
+/// An admitted connection's place under the limits, released on drop
+pub struct ConnectionGuard {
+    admission: Arc<Admission>,
+    connection: Arc<Connection>,
+}
+
+impl std::fmt::Debug for ConnectionGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionGuard")
+            .field("id", &self.connection.id)
+            .field("identity", &self.connection.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionGuard {
+    /// Record client activity, which keeps the connection from being displaced first
+    pub fn touch(&self) {
+        self.connection
+            .last_activity
+            .store(self.admission.now(), Ordering::Relaxed);
+    }
+
+    /// Wait until a newer connection for the same subject displaces this one
+    pub fn displaced(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let connection = Arc::clone(&self.connection);
+        async move { connection.displaced.notified().await }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.admission.counts.lock().expect("admission lock poisoned");
+        self.admission.release(&mut counts, &self.connection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(limits: ConnectionLimits) -> Arc<Admission> {
+        Arc::new(Admission::new(limits, Arc::new(Metrics::new())))
+    }
+
+    fn identity(subject: Option<&str>, ip: &str) -> Identity {
+        Identity {
+            subject: subject.map(str::to_string),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_rejects_at_each_limit() {
+        let admission = admission(ConnectionLimits {
+            max_total: 3,
+            max_per_subject: 1,
+            max_per_ip: 2,
+            ..ConnectionLimits::default()
+        });
+
+        let _a = admission.admit(identity(Some("alice"), "10.0.0.1")).unwrap();
+        let subject = admission.admit(identity(Some("alice"), "10.0.0.9")).unwrap_err();
+        assert_eq!(subject.limit, Limit::Subject);
+        assert_eq!(subject.limit.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let _b = admission.admit(identity(None, "10.0.0.1")).unwrap();
+        assert_eq!(admission.admit(identity(None, "10.0.0.1")).unwrap_err().limit, Limit::Ip);
+
+        let _c = admission.admit(identity(None, "10.0.0.2")).unwrap();
+        let total = admission.admit(identity(None, "10.0.0.3")).unwrap_err();
+        assert_eq!(total.limit, Limit::Total);
+        assert_eq!(total.limit.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(admission.metrics.snapshot().websocket.rejections.len(), 3);
+    }
+
+    #[test]
+    fn test_dropping_guard_frees_slot() {
+        let admission = admission(ConnectionLimits {
+            max_per_ip: 1,
+            ..ConnectionLimits::default()
+        });
+
+        let guard = admission.admit(identity(None, "10.0.0.1")).unwrap();
+        assert!(admission.admit(identity(None, "10.0.0.1")).is_err());
+        drop(guard);
+        assert!(admission.is_empty());
+        assert!(admission.admit(identity(None, "10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn test_new_limits_apply_to_later_connections() {
+        let admission = admission(ConnectionLimits::default());
+        let _a = admission.admit(identity(None, "10.0.0.1")).unwrap();
+        let _b = admission.admit(identity(None, "10.0.0.1")).unwrap();
+
+        admission.set_limits(ConnectionLimits {
+            max_per_ip: 1,
+            ..ConnectionLimits::default()
+        });
+        assert_eq!(admission.limits().max_per_ip, 1);
+        assert_eq!(admission.len(), 2);
+        assert_eq!(admission.admit(identity(None, "10.0.0.1")).unwrap_err().limit, Limit::Ip);
+        assert!(admission.admit(identity(None, "10.0.0.2")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_displaces_longest_idle_connection() {
+        let admission = admission(ConnectionLimits {
+            max_per_subject: 2,
+            displace_idle: true,
+            ..ConnectionLimits::default()
+        });
+
+        let idle = admission.admit(identity(Some("alice"), "10.0.0.1")).unwrap();
+        let active = admission.admit(identity(Some("alice"), "10.0.0.1")).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        active.touch();
+
+        let newest = admission.admit(identity(Some("alice"), "10.0.0.1")).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), idle.displaced())
+            .await
+            .expect("idle connection should be displaced");
+        assert_eq!(admission.len(), 2);
+
+        // The displaced connection's guard was already released
+        drop(idle);
+        assert_eq!(admission.len(), 2);
+        let ws = admission.metrics.snapshot().websocket;
+        assert_eq!(ws.per_subject.get("alice"), Some(&2));
+        assert_eq!(ws.displaced, 1);
+        assert_eq!(admission.subjects(), 1);
+        drop((active, newest));
+        assert!(admission.is_empty());
+        assert_eq!(admission.subjects(), 0);
+    }
+
+    #[test]
+    fn test_displacement_undone_when_another_limit_rejects() {
+        let admission = admission(ConnectionLimits {
+            max_per_subject: 1,
+            max_per_ip: 1,
+            displace_idle: true,
+            ..ConnectionLimits::default()
+        });
+
+        let _bob = admission.admit(identity(Some("bob"), "10.0.0.2")).unwrap();
+        let _alice = admission.admit(identity(Some("alice"), "10.0.0.1")).unwrap();
+        // Displacing alice's connection frees nothing on 10.0.0.2
+        let rejection = admission.admit(identity(Some("alice"), "10.0.0.2")).unwrap_err();
+        assert_eq!(rejection.limit, Limit::Ip);
+        assert_eq!(admission.len(), 2);
+        assert_eq!(admission.metrics.snapshot().websocket.displaced, 0);
+    }
+}
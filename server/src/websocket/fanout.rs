@@ -0,0 +1,231 @@
+//! Fan-out of store events to WebSocket sessions
+//!
+//! Every store event is wrapped once in a [`SharedEvent`] and published on a
+//! single broadcast channel that all sessions read, so one store write costs
+//! one channel send regardless of the number of connections. Each wire
+//! encoding of an event is computed at most once and shared by every
+//! connection that delivers it.
+
+use super::{document_message, WsMessage};
+use crate::document_store::{DocumentEvent, DocumentStore};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Capacity of the fan-out channel before slow sessions start lagging
+const FANOUT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Wire encoding negotiated for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text frames
+    Json,
+    /// `MessagePack` in binary frames
+    MessagePack,
+}
+
+impl Encoding {
+    /// Encode one message
+    pub(super) fn encode(self, msg: &WsMessage) -> Encoded {
+        match self {
+            Encoding::Json => Encoded::Text(
+                serde_json::to_string(msg)
+                    .expect("WebSocket messages always serialize")
+                    .into(),
+            ),
+            Encoding::MessagePack => Encoded::Binary(
+                rmp_serde::to_vec_named(msg)
+                    .expect("WebSocket messages always serialize")
+                    .into(),
+            ),
+        }
+    }
+
+    /// Combine already encoded messages into one `Batch` frame, in order
+    pub(super) fn batch(self, parts: &[Encoded]) -> Encoded {
+        match self {
+            Encoding::Json => {
+                let mut json = String::from(r#"{"type":"Batch","messages":["#);
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    json.push_str(std::str::from_utf8(part.as_bytes()).expect("JSON frames are UTF-8"));
+                }
+                json.push_str("]}");
+                Encoded::Text(json.into())
+            }
+            Encoding::MessagePack => {
+                // Equivalent to serializing `WsMessage::Batch`, reusing the encoded parts
+                let len = u32::try_from(parts.len()).expect("batch size fits in u32");
+                let mut buf = Vec::new();
+                rmp::encode::write_map_len(&mut buf, 2).expect("writing to a Vec cannot fail");
+                for s in ["type", "Batch", "messages"] {
+                    rmp::encode::write_str(&mut buf, s).expect("writing to a Vec cannot fail");
+                }
+                rmp::encode::write_array_len(&mut buf, len).expect("writing to a Vec cannot fail");
+                for part in parts {
+                    buf.extend_from_slice(part.as_bytes());
+                }
+                Encoded::Binary(buf.into())
+            }
+        }
+    }
+}
+
+/// A message encoded for the wire
+///
+/// The payload is reference counted so one encoding can be handed to every
+/// connection that delivers it.
+#[derive(Debug, Clone)]
+pub enum Encoded {
+    /// JSON text
+    Text(Arc<str>),
+    /// `MessagePack` bytes
+    Binary(Arc<[u8]>),
+}
+
+impl Encoded {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Encoded::Text(text) => text.as_bytes(),
+            Encoded::Binary(bytes) => bytes,
+        }
+    }
+
+    /// Frame to write to the socket
+    ///
+    /// tungstenite takes owned payloads, so this copies the shared bytes;
+    /// the serialization itself is not repeated.
+    #[must_use]
+    pub fn to_message(&self) -> Message {
+        match self {
+            Encoded::Text(text) => Message::Text(text.to_string()),
+            Encoded::Binary(bytes) => Message::Binary(bytes.to_vec()),
+        }
+    }
+}
+
+/// A store event with its wire encodings, computed lazily and shared
+pub struct SharedEvent {
+    event: DocumentEvent,
+    json: OnceLock<Encoded>,
+    msgpack: OnceLock<Encoded>,
+}
+
+impl SharedEvent {
+    fn new(event: DocumentEvent) -> Self {
+        Self {
+            event,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+        }
+    }
+
+    /// The underlying store event
+    pub fn event(&self) -> &DocumentEvent {
+        &self.event
+    }
+
+    /// Client notification for the event, encoded on first use and shared afterwards
+    pub fn encoded(&self, encoding: Encoding) -> Encoded {
+        let slot = match encoding {
+            Encoding::Json => &self.json,
+            Encoding::MessagePack => &self.msgpack,
+        };
+        slot.get_or_init(|| self.encode_uncached(encoding)).clone()
+    }
+
+    /// Encode without the shared cache, as each connection did before events were shared
+    ///
+    /// Kept for the fan-out benchmark's baseline.
+    #[doc(hidden)]
+    pub fn encode_uncached(&self, encoding: Encoding) -> Encoded {
+        encoding.encode(&document_message(&self.event))
+    }
+}
+
+/// Re-broadcasts store events as [`SharedEvent`]s
+pub struct EventFanout {
+    events: broadcast::Sender<Arc<SharedEvent>>,
+}
+
+impl EventFanout {
+    /// Start fanning out a store's events
+    ///
+    /// Events are forwarded synchronously from the store's write path, so a
+    /// session sees a change before any reply to a request sent after it.
+    pub fn new(store: &DocumentStore) -> Self {
+        let (events, _) = broadcast::channel(FANOUT_CHANNEL_CAPACITY);
+        let sender = events.clone();
+        store.observe(move |event| {
+            if sender.receiver_count() > 0 {
+                let _ = sender.send(Arc::new(SharedEvent::new(event.clone())));
+            }
+        });
+        Self { events }
+    }
+
+    /// Receive every subsequent store event
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<SharedEvent>> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pings(encoding: Encoding, n: usize) -> Vec<Encoded> {
+        (0..n).map(|_| encoding.encode(&WsMessage::Ping)).collect()
+    }
+
+    #[test]
+    fn test_batch_decodes_as_batch_message() {
+        let parts = vec![
+            Encoding::Json.encode(&WsMessage::Ping),
+            Encoding::Json.encode(&WsMessage::Error {
+                message: "second".to_string(),
+            }),
+        ];
+        let Encoded::Text(json) = Encoding::Json.batch(&parts) else {
+            panic!("JSON batch should be text");
+        };
+        match serde_json::from_str::<WsMessage>(&json).unwrap() {
+            WsMessage::Batch { messages } => {
+                assert!(matches!(messages.as_slice(), [WsMessage::Ping, WsMessage::Error { .. }]));
+            }
+            other => panic!("Unexpected message: {other:?}"),
+        }
+
+        let Encoded::Binary(bytes) = Encoding::MessagePack.batch(&pings(Encoding::MessagePack, 3)) else {
+            panic!("MessagePack batch should be binary");
+        };
+        match rmp_serde::from_slice::<WsMessage>(&bytes).unwrap() {
+            WsMessage::Batch { messages } => assert_eq!(messages.len(), 3),
+            other => panic!("Unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_shared_event_encodes_once() {
+        let store = DocumentStore::new();
+        let fanout = EventFanout::new(&store);
+        let mut first = fanout.subscribe();
+        let mut second = fanout.subscribe();
+
+        store.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+        let (a, b) = (first.try_recv().unwrap(), second.try_recv().unwrap());
+        assert!(Arc::ptr_eq(&a, &b));
+
+        match (a.encoded(Encoding::Json), b.encoded(Encoding::Json)) {
+            (Encoded::Text(x), Encoded::Text(y)) => assert!(Arc::ptr_eq(&x, &y)),
+            other => panic!("Unexpected encodings: {other:?}"),
+        }
+        assert!(matches!(
+            rmp_serde::from_slice::<WsMessage>(a.encoded(Encoding::MessagePack).as_bytes()).unwrap(),
+            WsMessage::DocumentUpdated { .. }
+        ));
+    }
+}
@@ -0,0 +1,2189 @@
+//! WebSocket server for real-time document updates
+//!
+//! Provides bidirectional communication for live collaboration and updates.
+//!
+//! With authentication enabled, a client presents its token with the
+//! upgrade request: in an `Authorization` or `X-API-Key` header, as a
+//! `bearer.<token>` subprotocol, or in an `access_token` query parameter,
+//! the last two for browsers, which cannot set headers on an upgrade. A
+//! client that presents none must send `Auth` as its first message, before
+//! `Hello`, or is closed with [`CLOSE_UNAUTHORIZED`].
+
+pub mod admission;
+pub mod fanout;
+
+use self::admission::{ConnectionGuard, Identity, Rejection};
+use self::fanout::{Encoded, Encoding, EventFanout, SharedEvent};
+use crate::audit::{AuditEvent, AuditKind};
+use crate::auth::csrf;
+use crate::auth::middleware::{Authentication, Credentials};
+use crate::auth::mtls::{self, ClientCertificate};
+use crate::auth::{api_keys, roles, AuthService, Claims};
+use crate::capabilities::{CapabilityInfo, CapabilityRegistry};
+use crate::clients::ClientInfo;
+use crate::collab::TextOperation;
+use crate::document_store::{DocumentEvent, DocumentEventKind, DocumentStore, UriPattern};
+use crate::lsp::LspHost;
+use crate::monitoring::connections::{ConnectionState, DisconnectReason, Transport};
+use crate::monitoring::registry::Gauge;
+use crate::monitoring::usage::{Client, Counts};
+use crate::monitoring::MetricsSnapshot;
+use crate::telemetry;
+use crate::ServerState;
+use anyhow::Result;
+use dashmap::{DashMap, DashSet};
+use futures_util::{Sink, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::Message};
+use tracing::{error, info, info_span, warn};
+
+/// Maximum subscriptions (document IDs plus patterns) per session
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// Maximum unacknowledged deliveries retained per session before a resync is required
+const MAX_UNACKED_DELIVERIES: usize = 512;
+
+/// How long a detached session is retained for resumption
+const SESSION_TTL: Duration = Duration::from_mins(5);
+
+/// Current WebSocket protocol version
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version still accepted (the one before [`PROTOCOL_VERSION`])
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION - 1;
+
+/// Close code sent when the client's protocol version is no longer supported
+pub const CLOSE_UPGRADE_REQUIRED: u16 = 4001;
+
+/// Close code sent to a connection displaced by a newer one for the same subject
+pub const CLOSE_DISPLACED: u16 = 4002;
+
+/// Close code sent when a client presents no token, or an invalid one, in `Auth`
+pub const CLOSE_UNAUTHORIZED: u16 = 4003;
+
+/// Subprotocol the server selects when a client offers it, as browsers need one selected
+pub const SUBPROTOCOL: &str = "universal-connector";
+
+/// Prefix of the subprotocol carrying a client's token
+pub const TOKEN_SUBPROTOCOL_PREFIX: &str = "bearer.";
+
+/// Types of the messages clients send, as `[policy.messages]` names them
+pub const CLIENT_MESSAGES: &[&str] = &[
+    "Hello", "Subscribe", "Unsubscribe", "OpenSession", "ResumeSession", "Ack", "CollabJoin", "CollabLeave",
+    "CollabOperation", "Lsp", "GetMetrics", "Ping",
+];
+
+/// Largest message accepted from a client
+const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// How often clients should send `Ping` to keep the connection alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a client has to send `Hello` after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for further events to join a batch
+const BATCH_WINDOW: Duration = Duration::from_millis(5);
+
+/// Maximum messages combined into one batch frame
+const MAX_BATCH_SIZE: usize = 64;
+
+/// Optional protocol features a client can ask for in `Hello`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `MessagePack` in binary frames instead of JSON text
+    BinaryEncoding,
+    /// Per-message compression
+    Compression,
+    /// `OpenSession`/`ResumeSession` and acknowledged delivery
+    ResumableSessions,
+    /// Concurrent editing of shared documents
+    Collab,
+    /// Several document notifications combined into one `Batch` frame
+    Batching,
+    /// A hosted language server driven by `Lsp` messages
+    Lsp,
+}
+
+impl Capability {
+    /// All capabilities known to this server
+    pub const ALL: [Capability; 6] = [
+        Capability::BinaryEncoding,
+        Capability::Compression,
+        Capability::ResumableSessions,
+        Capability::Collab,
+        Capability::Batching,
+        Capability::Lsp,
+    ];
+
+    /// Wire name of the capability
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::BinaryEncoding => "binary_encoding",
+            Capability::Compression => "compression",
+            Capability::ResumableSessions => "resumable_sessions",
+            Capability::Collab => "collab",
+            Capability::Batching => "batching",
+            Capability::Lsp => "lsp",
+        }
+    }
+
+    /// Look up a capability by wire name
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str() == name)
+    }
+
+    /// Whether this server implements the capability
+    fn is_supported(self) -> bool {
+        !matches!(self, Capability::Compression)
+    }
+
+    /// First protocol version that includes the capability
+    fn min_version(self) -> u32 {
+        match self {
+            Capability::ResumableSessions | Capability::BinaryEncoding | Capability::Batching | Capability::Lsp => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Limits the server enforces on a connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerLimits {
+    /// Largest message accepted, in bytes
+    pub max_message_size: usize,
+    /// Maximum subscriptions (document IDs plus patterns) per session
+    pub max_subscriptions: usize,
+    /// How often the client should send `Ping`, in seconds
+    pub heartbeat_interval_secs: u64,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_subscriptions: MAX_SUBSCRIPTIONS,
+            heartbeat_interval_secs: HEARTBEAT_INTERVAL.as_secs(),
+        }
+    }
+}
+
+/// Outcome of the connection handshake, sent to the client in `Welcome`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Negotiated {
+    /// Protocol version both sides will speak
+    pub protocol_version: u32,
+    /// Requested capabilities the server agreed to
+    pub capabilities: Vec<Capability>,
+    /// Server limits for this connection
+    pub limits: ServerLimits,
+    /// Crate version of the server, for clients gating features on it
+    #[serde(default)]
+    pub server_version: String,
+    /// Everything the server supports, as listed by its [`CapabilityRegistry`]
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub server_capabilities: serde_json::Value,
+}
+
+impl Negotiated {
+    /// Negotiate from a client's `Hello`
+    ///
+    /// Newer clients are downgraded to [`PROTOCOL_VERSION`]; capabilities that
+    /// are unknown, unsupported or newer than the agreed version are dropped.
+    /// Returns `None` for versions older than [`MIN_PROTOCOL_VERSION`].
+    #[must_use]
+    pub fn negotiate(protocol_version: u32, requested: &[String]) -> Option<Self> {
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        let protocol_version = protocol_version.min(PROTOCOL_VERSION);
+
+        let mut capabilities = Vec::new();
+        for capability in requested.iter().filter_map(|name| Capability::from_name(name)) {
+            if capability.is_supported()
+                && capability.min_version() <= protocol_version
+                && !capabilities.contains(&capability)
+            {
+                capabilities.push(capability);
+            }
+        }
+
+        Some(Self {
+            protocol_version,
+            capabilities,
+            limits: ServerLimits::default(),
+            server_version: crate::build_info::VERSION.to_string(),
+            server_capabilities: serde_json::Value::Null,
+        })
+    }
+
+    /// Check whether a capability was agreed
+    #[must_use]
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Wire encoding for messages after the handshake
+    #[must_use]
+    pub fn encoding(&self) -> Encoding {
+        if self.has(Capability::BinaryEncoding) {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+/// The WebSocket protocol as listed in the [`CapabilityRegistry`]
+#[must_use]
+pub fn capability() -> CapabilityInfo {
+    let mut protocol = CapabilityInfo::new(PROTOCOL_VERSION.to_string())
+        .parameter("min_protocol_version", MIN_PROTOCOL_VERSION)
+        .parameter("limits", ServerLimits::default());
+    for capability in Capability::ALL.into_iter().filter(|capability| capability.is_supported()) {
+        protocol = protocol.child(capability.as_str(), CapabilityInfo::new(capability.min_version().to_string()));
+    }
+    protocol
+}
+
+/// WebSocket message types
+///
+/// Public so clients such as the stdio bridge share the server's schema.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WsMessage {
+    /// First message from a client that presented no token with the upgrade, before `Hello`
+    Auth { token: String },
+    /// First message from the client: protocol version, desired capabilities and who it is
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// Listed with the connection in `GET /api/admin/clients`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<ClientInfo>,
+    },
+    /// Reply to `Hello` with the negotiated protocol
+    Welcome(Negotiated),
+    /// The client broke the negotiated protocol, e.g. by using a capability it did not agree
+    ProtocolViolation { message: String },
+    /// Subscribe to document updates by ID, or by URI glob/prefix pattern
+    ///
+    /// With `ack` set, matching events arrive wrapped in `Reliable` and are
+    /// retained until acknowledged (requires an open session).
+    Subscribe {
+        #[serde(default)]
+        document_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ack: bool,
+    },
+    /// Unsubscribe from document updates by ID, or by the original pattern string
+    Unsubscribe {
+        #[serde(default)]
+        document_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+    },
+    /// Document created or updated notification
+    DocumentUpdated {
+        document_id: String,
+        uri: String,
+        content: String,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    },
+    /// Document removed notification
+    DocumentRemoved { document_id: String, uri: String },
+    /// Make this connection's session resumable after a disconnect
+    OpenSession,
+    /// Reply to `OpenSession`
+    SessionOpened { session_id: String },
+    /// Reattach to a session, acknowledging deliveries up to `last_delivery_id`
+    ResumeSession { session_id: String, last_delivery_id: u64 },
+    /// Reply to `ResumeSession`; unacknowledged deliveries follow with their original IDs
+    SessionResumed { session_id: String, redelivered: usize },
+    /// Message that must be acknowledged; the ID is stable across redelivery
+    Reliable {
+        delivery_id: u64,
+        message: Box<WsMessage>,
+    },
+    /// Cumulative acknowledgement of every delivery up to and including `delivery_id`
+    Ack { delivery_id: u64 },
+    /// Notifications were lost; the client should refetch the state it follows
+    ResyncRequired { reason: String },
+    /// Join concurrent editing for a document (enables it if needed)
+    CollabJoin { uri: String },
+    /// Leave concurrent editing for a document
+    CollabLeave { uri: String },
+    /// Current state of a collaborative document, sent in reply to `CollabJoin`
+    CollabSnapshot {
+        uri: String,
+        revision: u64,
+        content: String,
+    },
+    /// Operation from a client, based on the last server revision it has seen
+    CollabOperation {
+        uri: String,
+        revision: u64,
+        operation: TextOperation,
+    },
+    /// Acknowledgement that the sender's operation became `revision`
+    CollabAck { uri: String, revision: u64 },
+    /// Operation from another client, already transformed onto the server history
+    CollabApplied {
+        uri: String,
+        revision: u64,
+        operation: TextOperation,
+    },
+    /// JSON-RPC message to or from the session's language server
+    Lsp {
+        message: serde_json::Value,
+        /// W3C trace context the server's handling continues
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        traceparent: Option<String>,
+    },
+    /// Several notifications in delivery order (with the `batching` capability)
+    Batch { messages: Vec<WsMessage> },
+    /// Request the server's metrics snapshot, e.g. for a status panel
+    GetMetrics,
+    /// Reply to `GetMetrics`
+    Metrics { snapshot: Box<MetricsSnapshot> },
+    /// Error message
+    Error { message: String },
+    /// Ping/pong for keepalive
+    Ping,
+    Pong,
+}
+
+impl WsMessage {
+    /// Capability a client message depends on, if any
+    fn required_capability(&self) -> Option<Capability> {
+        match self {
+            WsMessage::Subscribe { ack: true, .. }
+            | WsMessage::OpenSession
+            | WsMessage::ResumeSession { .. }
+            | WsMessage::Ack { .. } => Some(Capability::ResumableSessions),
+            WsMessage::CollabJoin { .. } | WsMessage::CollabLeave { .. } | WsMessage::CollabOperation { .. } => {
+                Some(Capability::Collab)
+            }
+            WsMessage::Lsp { .. } => Some(Capability::Lsp),
+            _ => None,
+        }
+    }
+
+    /// Scope a client message needs when authentication is enabled, if any
+    ///
+    /// Following documents needs `read`, and changing them `write`, which
+    /// LSP sessions do as they open and edit documents.
+    fn required_scope(&self) -> Option<&'static str> {
+        match self {
+            WsMessage::Subscribe { .. } | WsMessage::CollabJoin { .. } => Some(roles::READ),
+            WsMessage::CollabOperation { .. } | WsMessage::Lsp { .. } => Some(roles::WRITE),
+            _ => None,
+        }
+    }
+
+    /// Metrics label for a message a client sends the server to act on
+    ///
+    /// `None` for messages only the server sends, so clients cannot add
+    /// label values.
+    fn method(&self) -> Option<&'static str> {
+        Some(match self {
+            WsMessage::Hello { .. } => "Hello",
+            WsMessage::Subscribe { .. } => "Subscribe",
+            WsMessage::Unsubscribe { .. } => "Unsubscribe",
+            WsMessage::OpenSession => "OpenSession",
+            WsMessage::ResumeSession { .. } => "ResumeSession",
+            WsMessage::Ack { .. } => "Ack",
+            WsMessage::CollabJoin { .. } => "CollabJoin",
+            WsMessage::CollabLeave { .. } => "CollabLeave",
+            WsMessage::CollabOperation { .. } => "CollabOperation",
+            WsMessage::Lsp { .. } => "Lsp",
+            WsMessage::GetMetrics => "GetMetrics",
+            WsMessage::Ping => "Ping",
+            _ => return None,
+        })
+    }
+}
+
+/// Document subscriptions held by one session
+///
+/// Each document ID or pattern counts once against [`MAX_SUBSCRIPTIONS`],
+/// regardless of how many documents a pattern matches. Each entry records
+/// whether its events require acknowledgement.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    documents: HashMap<String, bool>,
+    patterns: Vec<(String, UriPattern, bool)>,
+}
+
+impl Subscriptions {
+    fn len(&self) -> usize {
+        self.documents.len() + self.patterns.len()
+    }
+
+    /// Add a subscription; re-subscribing to the same ID or pattern only updates its ack flag
+    pub(crate) fn subscribe(&mut self, document_id: String, pattern: Option<String>, ack: bool) -> Result<(), String> {
+        let existing = match &pattern {
+            Some(raw) => self.patterns.iter_mut().find(|(p, _, _)| p == raw).map(|(_, _, a)| a),
+            None => self.documents.get_mut(&document_id),
+        };
+        if let Some(existing) = existing {
+            *existing = ack;
+            return Ok(());
+        }
+        if self.len() >= MAX_SUBSCRIPTIONS {
+            return Err(format!("Subscription limit of {MAX_SUBSCRIPTIONS} reached"));
+        }
+
+        match pattern {
+            Some(raw) => {
+                let parsed = UriPattern::parse(&raw);
+                self.patterns.push((raw, parsed, ack));
+            }
+            None if document_id.is_empty() => {
+                return Err("Subscribe requires a document_id or pattern".to_string());
+            }
+            None => {
+                self.documents.insert(document_id, ack);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a subscription by document ID or original pattern string
+    pub(crate) fn unsubscribe(&mut self, document_id: &str, pattern: Option<&str>) -> bool {
+        match pattern {
+            Some(raw) => {
+                let before = self.patterns.len();
+                self.patterns.retain(|(p, _, _)| p != raw);
+                self.patterns.len() != before
+            }
+            None => self.documents.remove(document_id).is_some(),
+        }
+    }
+
+    /// Check whether an event should be delivered (at most once, however many subscriptions match)
+    ///
+    /// Returns whether delivery requires acknowledgement, which is the case
+    /// if any matching subscription asked for it.
+    pub(crate) fn matches(&self, event: &DocumentEvent) -> Option<bool> {
+        let by_id = self.documents.get(&event.document.id).copied();
+        self.patterns
+            .iter()
+            .filter(|(_, p, _)| p.matches(&event.document.uri))
+            .map(|(_, _, ack)| *ack)
+            .chain(by_id)
+            .reduce(|a, b| a || b)
+    }
+}
+
+/// Messages delivered in acknowledged mode and not yet acknowledged
+#[derive(Debug)]
+struct DeliveryBuffer {
+    next_id: u64,
+    unacked: VecDeque<(u64, WsMessage)>,
+}
+
+impl Default for DeliveryBuffer {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            unacked: VecDeque::new(),
+        }
+    }
+}
+
+impl DeliveryBuffer {
+    /// Assign the next delivery ID and retain the message until acknowledged
+    ///
+    /// When the buffer is full the retained messages are discarded and a
+    /// `ResyncRequired` signal is returned instead.
+    fn push(&mut self, message: WsMessage) -> WsMessage {
+        if self.unacked.len() >= MAX_UNACKED_DELIVERIES {
+            self.unacked.clear();
+            return WsMessage::ResyncRequired {
+                reason: format!("More than {MAX_UNACKED_DELIVERIES} unacknowledged deliveries"),
+            };
+        }
+
+        let delivery_id = self.next_id;
+        self.next_id += 1;
+        self.unacked.push_back((delivery_id, message.clone()));
+        WsMessage::Reliable {
+            delivery_id,
+            message: Box::new(message),
+        }
+    }
+
+    /// Release every retained message up to and including `delivery_id`
+    fn ack(&mut self, delivery_id: u64) {
+        while self.unacked.front().is_some_and(|(id, _)| *id <= delivery_id) {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Retained messages in delivery order, wrapped for redelivery
+    fn pending(&self) -> Vec<WsMessage> {
+        self.unacked
+            .iter()
+            .map(|(delivery_id, message)| WsMessage::Reliable {
+                delivery_id: *delivery_id,
+                message: Box::new(message.clone()),
+            })
+            .collect()
+    }
+}
+
+/// Output waiting for the connection a session is attached to
+struct Inbox {
+    events: broadcast::Receiver<Arc<SharedEvent>>,
+    lsp: mpsc::UnboundedReceiver<serde_json::Value>,
+}
+
+/// Per-client state that can outlive a single connection
+///
+/// Every connection starts with its own session; `OpenSession` registers it
+/// so it is retained after a disconnect and can be resumed elsewhere.
+struct Session {
+    id: String,
+    /// Subject of the client that began it, the only one that may resume it
+    owner: String,
+    subscriptions: RwLock<Subscriptions>,
+    /// Pending output, held while the session is detached
+    inbox: Arc<tokio::sync::Mutex<Inbox>>,
+    deliveries: Mutex<DeliveryBuffer>,
+    /// Set once the session is registered for resumption
+    resumable: AtomicBool,
+    /// Language server started by the first `Lsp` message
+    lsp: Mutex<Option<LspHost>>,
+    lsp_output: mpsc::UnboundedSender<serde_json::Value>,
+    /// Bumped on each resume so the previously attached connection lets go
+    generation: watch::Sender<u64>,
+    detached_at: Mutex<Option<Instant>>,
+}
+
+impl Session {
+    fn new(events: broadcast::Receiver<Arc<SharedEvent>>, owner: String) -> Self {
+        let (lsp_output, lsp) = mpsc::unbounded_channel();
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            owner,
+            subscriptions: RwLock::new(Subscriptions::default()),
+            inbox: Arc::new(tokio::sync::Mutex::new(Inbox { events, lsp })),
+            deliveries: Mutex::new(DeliveryBuffer::default()),
+            resumable: AtomicBool::new(false),
+            lsp: Mutex::new(None),
+            lsp_output,
+            generation: watch::channel(0).0,
+            detached_at: Mutex::new(None),
+        }
+    }
+
+    /// Start the expiry clock, unless another connection has resumed the session since
+    fn detach(&self, generation: u64) {
+        if *self.generation.borrow() == generation {
+            *self.detached_at.lock().expect("session lock poisoned") = Some(Instant::now());
+        }
+    }
+
+    /// Encode the notification for an event, or `None` if nothing subscribed to it
+    ///
+    /// Plain notifications reuse the event's shared encoding; acknowledged
+    /// ones carry a per-session delivery ID and are encoded here.
+    fn deliver(&self, shared: &SharedEvent, encoding: Encoding) -> Option<Encoded> {
+        let event = shared.event();
+        let requires_ack = self
+            .subscriptions
+            .read()
+            .expect("subscriptions lock poisoned")
+            .matches(event)?;
+
+        if requires_ack {
+            let message = self
+                .deliveries
+                .lock()
+                .expect("deliveries lock poisoned")
+                .push(document_message(event));
+            Some(encoding.encode(&message))
+        } else {
+            Some(shared.encoded(encoding))
+        }
+    }
+
+    /// Encode a message from the language server
+    ///
+    /// In a resumable session it is retained until acknowledged so a
+    /// reconnecting client does not lose responses.
+    fn deliver_lsp(&self, message: serde_json::Value, encoding: Encoding) -> Encoded {
+        let message = WsMessage::Lsp {
+            message,
+            traceparent: None,
+        };
+        if self.resumable.load(Ordering::Acquire) {
+            encoding.encode(&self.deliveries.lock().expect("deliveries lock poisoned").push(message))
+        } else {
+            encoding.encode(&message)
+        }
+    }
+
+    /// Pass a client message to the session's language server, starting it if needed
+    fn send_lsp(&self, state: &Arc<ServerState>, message: serde_json::Value) {
+        self.lsp
+            .lock()
+            .expect("lsp lock poisoned")
+            .get_or_insert_with(|| LspHost::spawn(Arc::clone(state), self.lsp_output.clone()))
+            .send(message);
+    }
+
+    fn ack(&self, delivery_id: u64) {
+        self.deliveries.lock().expect("deliveries lock poisoned").ack(delivery_id);
+    }
+
+    fn pending(&self) -> Vec<WsMessage> {
+        self.deliveries.lock().expect("deliveries lock poisoned").pending()
+    }
+}
+
+/// Resumable WebSocket sessions, shared across connections
+pub struct SessionRegistry {
+    sessions: DashMap<String, Arc<Session>>,
+    fanout: EventFanout,
+    retained: Gauge,
+}
+
+impl SessionRegistry {
+    /// Create an empty registry delivering events from `documents`, reporting its size in `retained`
+    pub fn new(documents: &DocumentStore, retained: Gauge) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            fanout: EventFanout::new(documents),
+            retained,
+        }
+    }
+
+    /// Number of retained sessions, attached or not
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Check whether no sessions are retained
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Receive every subsequent store event through the channel sessions read
+    ///
+    /// Other transports streaming document events use it, so they fall
+    /// behind, and are told to resync, exactly as sessions are.
+    #[must_use]
+    pub fn events(&self) -> broadcast::Receiver<Arc<SharedEvent>> {
+        self.fanout.subscribe()
+    }
+
+    fn contains(&self, session_id: &str) -> bool {
+        self.sessions.contains_key(session_id)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn register(&self, session: &Arc<Session>) {
+        self.prune();
+        session.resumable.store(true, Ordering::Release);
+        self.sessions.insert(session.id.clone(), Arc::clone(session));
+        self.retained.set(self.sessions.len() as f64);
+    }
+
+    /// Take over a session for `subject`, returning it with its new attachment generation
+    fn resume(&self, session_id: &str, subject: &str) -> Result<(Arc<Session>, u64), Unresumable> {
+        self.prune();
+        let session = Arc::clone(self.sessions.get(session_id).ok_or(Unresumable::Unknown)?.value());
+        if session.owner != subject {
+            return Err(Unresumable::NotOwner);
+        }
+        *session.detached_at.lock().expect("session lock poisoned") = None;
+        session.generation.send_modify(|generation| *generation += 1);
+        let generation = *session.generation.borrow();
+        Ok((session, generation))
+    }
+
+    /// Drop sessions detached for longer than [`SESSION_TTL`]
+    #[allow(clippy::cast_precision_loss)]
+    fn prune(&self) {
+        self.sessions.retain(|_, session| {
+            session
+                .detached_at
+                .lock()
+                .expect("session lock poisoned")
+                .is_none_or(|at| at.elapsed() < SESSION_TTL)
+        });
+        self.retained.set(self.sessions.len() as f64);
+    }
+}
+
+/// Why a session cannot be resumed
+enum Unresumable {
+    /// No session has the ID, or it expired
+    Unknown,
+    /// The session belongs to another subject
+    NotOwner,
+}
+
+/// Build the client notification for a store event
+fn document_message(event: &DocumentEvent) -> WsMessage {
+    let doc = &event.document;
+    match event.kind {
+        DocumentEventKind::Created | DocumentEventKind::Updated => WsMessage::DocumentUpdated {
+            document_id: doc.id.clone(),
+            uri: doc.uri.clone(),
+            content: doc.content.clone(),
+            timestamp: doc.modified_at,
+        },
+        DocumentEventKind::Removed => WsMessage::DocumentRemoved {
+            document_id: doc.id.clone(),
+            uri: doc.uri.clone(),
+        },
+    }
+}
+
+/// Send one encoded frame to the client
+async fn send_frame<S>(sink: &mut S, frame: &Encoded) -> Result<()>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    sink.send(frame.to_message()).await?;
+    Ok(())
+}
+
+/// Decode a client message: JSON text frames, or `MessagePack` binary frames
+pub(crate) fn decode_frame(frame: &Message) -> Result<WsMessage, String> {
+    match frame {
+        Message::Text(text) => serde_json::from_str(text).map_err(|e| e.to_string()),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        _ => Err("Unsupported frame type".to_string()),
+    }
+}
+
+/// Wait briefly for more events and combine them with `first` into one frame
+///
+/// Events already queued are taken immediately; the batch closes after
+/// [`BATCH_WINDOW`] or at [`MAX_BATCH_SIZE`] messages. Order is preserved.
+async fn collect_batch(
+    first: Encoded,
+    events: &mut broadcast::Receiver<Arc<SharedEvent>>,
+    session: &Session,
+    encoding: Encoding,
+) -> Encoded {
+    let deadline = tokio::time::Instant::now() + BATCH_WINDOW;
+    let mut parts = vec![first];
+    while parts.len() < MAX_BATCH_SIZE {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Ok(event)) => parts.extend(session.deliver(&event, encoding)),
+            Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                parts.push(encoding.encode(&lagged(skipped)));
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+
+    if parts.len() == 1 {
+        parts.pop().expect("batch has one message")
+    } else {
+        encoding.batch(&parts)
+    }
+}
+
+/// Resync signal for a session that fell behind the event stream
+fn lagged(skipped: u64) -> WsMessage {
+    WsMessage::ResyncRequired {
+        reason: lag_reason(skipped),
+    }
+}
+
+/// Why a subscriber that fell `skipped` events behind must resync
+pub(crate) fn lag_reason(skipped: u64) -> String {
+    format!("{skipped} document events were dropped")
+}
+
+/// Close the connection with a code and reason
+async fn close<S>(sink: &mut S, code: CloseCode, reason: String) -> Result<()>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    sink.send(Message::Close(Some(frame))).await?;
+    Ok(())
+}
+
+/// Wait for the client's `Hello` and reply with the negotiated protocol
+///
+/// Returns the protocol and the client's description of itself, or `None`
+/// once the connection has been closed for a missing handshake or an
+/// unsupported protocol version.
+async fn handshake<S, R>(
+    sink: &mut S,
+    stream: &mut R,
+    registry: &CapabilityRegistry,
+) -> Result<Option<(Negotiated, Option<ClientInfo>)>>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let first = match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<WsMessage>(&text).ok(),
+        Ok(Some(Ok(_))) | Err(_) => None,
+        Ok(Some(Err(e))) => return Err(e.into()),
+        Ok(None) => return Ok(None),
+    };
+
+    let Some(WsMessage::Hello {
+        protocol_version,
+        capabilities,
+        client,
+    }) = first
+    else {
+        close(sink, CloseCode::Protocol, "Expected Hello as the first message".to_string()).await?;
+        return Ok(None);
+    };
+
+    if let Some(negotiated) = Negotiated::negotiate(protocol_version, &capabilities) {
+        let welcome = Negotiated {
+            server_capabilities: registry.to_value(),
+            ..negotiated.clone()
+        };
+        send_frame(sink, &Encoding::Json.encode(&WsMessage::Welcome(welcome))).await?;
+        Ok(Some((negotiated, client)))
+    } else {
+        let reason = format!(
+            "Protocol version {protocol_version} is no longer supported; upgrade to version {PROTOCOL_VERSION}"
+        );
+        close(sink, CloseCode::from(CLOSE_UPGRADE_REQUIRED), reason).await?;
+        Ok(None)
+    }
+}
+
+/// Plain HTTP response refusing an upgrade
+fn refuse(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Wait for the `Auth` message of a client that presented no token with the upgrade
+///
+/// Returns the claims of its token, or `None` once the connection has been
+/// closed for a missing or invalid one.
+async fn authenticate<S, R>(
+    sink: &mut S,
+    stream: &mut R,
+    state: &ServerState,
+    auth: &AuthService,
+    source: std::net::IpAddr,
+) -> Result<Option<Claims>>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let first = match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<WsMessage>(&text).ok(),
+        Ok(Some(Ok(_))) | Err(_) => None,
+        Ok(Some(Err(e))) => return Err(e.into()),
+        Ok(None) => return Ok(None),
+    };
+    let authentication = match first {
+        Some(WsMessage::Auth { token }) => Credentials::token(&token).authenticate(auth),
+        _ => Authentication::Invalid("Expected Auth as the first message".to_string()),
+    };
+    authentication.audit(&state.audit, |kind| audit(kind, source, "Auth"));
+    match authentication.require() {
+        Ok(claims) => Ok(Some(claims.clone())),
+        Err(refusal) => {
+            close(sink, CloseCode::from(CLOSE_UNAUTHORIZED), refusal.to_string()).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Subprotocols a client offers in its upgrade request
+fn offered_protocols(request: &Request) -> impl Iterator<Item = &str> {
+    request
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Token presented with an upgrade request: in a header, a subprotocol or the query
+fn upgrade_token(request: &Request) -> Option<&str> {
+    [header::AUTHORIZATION.as_str(), api_keys::HEADER]
+        .iter()
+        .find_map(|name| request.headers().get(*name))
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| offered_protocols(request).find_map(|protocol| protocol.strip_prefix(TOKEN_SUBPROTOCOL_PREFIX)))
+        .or_else(|| request.uri().query()?.split('&').find_map(|pair| pair.strip_prefix("access_token=")))
+}
+
+/// Identify the client of an upgrade request
+///
+/// A token is optional here, but one that is presented must be valid.
+/// Without one, the client certificate of the connection, if any, decides,
+/// and failing that the client is anonymous until it sends `Auth`, or for
+/// good if anonymous clients are let in. With
+/// CSRF protection on, the token may come in its cookie, and a page of an
+/// origin not allowed is refused, as its browser would send that cookie.
+/// The error is the handshake response tungstenite expects, hence its size.
+#[allow(clippy::result_large_err)]
+fn identify(
+    state: &ServerState,
+    peer: std::net::IpAddr,
+    certificate: Option<&ClientCertificate>,
+    request: &Request,
+) -> Result<(Identity, Client, Claims), ErrorResponse> {
+    let config = state.config();
+    let ip = config.trusted_proxies.resolve(peer, request.headers());
+    let csrf = &config.csrf;
+    let value_of = |name: header::HeaderName| request.headers().get(name).and_then(|value| value.to_str().ok());
+    if csrf.enabled && !csrf.allows_origin(value_of(header::ORIGIN), value_of(header::HOST)) {
+        let detail = format!("Origin not allowed: {}", value_of(header::ORIGIN).unwrap_or_default());
+        state.audit.record(audit(AuditKind::AuthorizationDenied, ip, request.uri().path()).detail(&detail));
+        return Err(refuse(StatusCode::FORBIDDEN, &detail));
+    }
+    let cookies = request.headers().get_all(header::COOKIE).iter().filter_map(|value| value.to_str().ok());
+    let token = upgrade_token(request).or_else(|| csrf.enabled.then(|| csrf::cookie(cookies, &csrf.token_cookie)).flatten());
+    let (client, claims) = match &state.auth_service {
+        Some(auth) => {
+            let authentication = Credentials { token, signer: None, certificate }.authenticate(auth);
+            authentication.audit(&state.audit, |kind| audit(kind, ip, request.uri().path()));
+            match authentication {
+                Authentication::Authenticated(claims) => (Client::from_claims(&claims), claims),
+                // Connected without a token, a client may only send what anonymous clients may, or needs no scope
+                Authentication::Anonymous => (Client::anonymous(), anonymous_claims(state)),
+                Authentication::Invalid(detail) => return Err(refuse(StatusCode::UNAUTHORIZED, &detail)),
+            }
+        }
+        None => (Client::anonymous(), anonymous_claims(state)),
+    };
+    let identity = Identity {
+        subject: client.subject.clone(),
+        ip,
+    };
+    Ok((identity, client, claims))
+}
+
+/// Claims of a client connected without credentials, granting nothing unless anonymous clients are let in
+fn anonymous_claims(state: &ServerState) -> Claims {
+    state.anonymous_claims().unwrap_or_else(|| Claims::new("anonymous".to_string(), Vec::new()))
+}
+
+/// An audit event of `kind` about `resource`, asked for from `source`
+fn audit(kind: AuditKind, source: std::net::IpAddr, resource: &str) -> AuditEvent {
+    AuditEvent::new(kind, "websocket").source(Some(source)).resource(resource)
+}
+
+/// Response for an upgrade refused by a connection limit
+fn rejected(rejection: Rejection) -> ErrorResponse {
+    let mut response = refuse(
+        rejection.limit.status(),
+        &format!("Connection limit reached: {}", rejection.limit.as_str()),
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, rejection.retry_after.as_secs().max(1).into());
+    response
+}
+
+/// Handle a single WebSocket connection
+async fn handle_connection(stream: TcpStream, state: Arc<ServerState>) -> Result<()> {
+    let addr = stream.peer_addr()?;
+    serve_connection(stream, addr, None, state).await
+}
+
+/// Handle a WebSocket connection over `stream`, from the holder of `certificate` if it was authenticated with one
+#[allow(clippy::too_many_lines)] // The connection's whole lifetime
+async fn serve_connection<S>(stream: S, addr: SocketAddr, certificate: Option<ClientCertificate>, state: Arc<ServerState>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!("New WebSocket connection from: {}", addr);
+    let mut connection = state
+        .metrics
+        .connections
+        .open(Transport::WebSocket, ConnectionState::Handshaking);
+
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        ..WebSocketConfig::default()
+    };
+    // Connection limits are enforced on the upgrade request, before any frame is exchanged
+    let mut admitted: Option<ConnectionGuard> = None;
+    let mut client = Client::anonymous();
+    let mut claims = Claims::new("anonymous".to_string(), Vec::new());
+    let mut source = addr.ip();
+    #[allow(clippy::result_large_err)]
+    let admit = |request: &Request, mut response: Response| {
+        let (identity, identified, granted) = identify(&state, addr.ip(), certificate.as_ref(), request)?;
+        client = identified;
+        claims = granted;
+        source = identity.ip;
+        if offered_protocols(request).any(|protocol| protocol == SUBPROTOCOL) {
+            response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, header::HeaderValue::from_static(SUBPROTOCOL));
+        }
+        match state.ws_admission.admit(identity) {
+            Ok(guard) => {
+                admitted = Some(guard);
+                Ok(response)
+            }
+            Err(rejection) => Err(rejected(rejection)),
+        }
+    };
+    let ws_stream = match accept_hdr_async_with_config(stream, admit, Some(config)).await {
+        Ok(ws_stream) => ws_stream,
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            warn!("Refused WebSocket connection from {}: {}", addr, response.status());
+            connection.set_reason(DisconnectReason::Refused);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut admitted = admitted.expect("accepted connections are admitted");
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    // Without credentials on the upgrade, the client authenticates with its first message, unless anonymous clients are let in
+    if let Some(auth) = state.auth_service.as_ref().filter(|_| client.subject.is_none() && state.anonymous_claims().is_none()) {
+        let Some(granted) = authenticate(&mut ws_sender, &mut ws_receiver, &state, auth, source).await? else {
+            info!("WebSocket authentication failed for {}", addr);
+            connection.set_reason(DisconnectReason::Refused);
+            return Ok(());
+        };
+        client = Client::from_claims(&granted);
+        claims = granted;
+        // Counted against its subject's connection cap, now that the subject is known
+        drop(admitted);
+        admitted = match state.ws_admission.admit(Identity { subject: client.subject.clone(), ip: source }) {
+            Ok(guard) => guard,
+            Err(rejection) => {
+                let reason = format!("Connection limit reached: {}", rejection.limit.as_str());
+                close(&mut ws_sender, CloseCode::Again, reason).await?;
+                connection.set_reason(DisconnectReason::Refused);
+                return Ok(());
+            }
+        };
+    }
+    let guard = Arc::new(admitted);
+
+    let Some((negotiated, info)) = handshake(&mut ws_sender, &mut ws_receiver, &state.capabilities).await? else {
+        info!("WebSocket handshake failed for {}", addr);
+        connection.set_reason(DisconnectReason::Refused);
+        return Ok(());
+    };
+    connection.enter(ConnectionState::Active);
+    info!("Negotiated WebSocket protocol with {}: {:?}", addr, negotiated);
+    let registered = Arc::new(state.clients.register(Transport::WebSocket, &client));
+    registered.update(|record| {
+        if let Some(info) = info {
+            record.name = Some(info.name);
+            record.version = info.version;
+        }
+        record.capabilities = serde_json::to_value(&negotiated.capabilities).unwrap_or_default();
+    });
+
+    // Identifies this connection as the origin of collaborative operations
+    let connection_id = uuid::Uuid::new_v4().to_string();
+    let joined: Arc<DashSet<String>> = Arc::new(DashSet::new());
+
+    // Session attached to this connection, with the generation it was attached at
+    let session = Arc::new(Session::new(state.ws_sessions.fanout.subscribe(), claims.sub.clone()));
+    let current = Arc::new(Mutex::new((Arc::clone(&session), 0u64)));
+
+    // Subscribe to broadcast channels
+    let mut collab_rx = state.collab.subscribe();
+
+    // Replies addressed to this client only, and sessions taken over by `ResumeSession`
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<WsMessage>();
+    let (switch_tx, mut switch_rx) = mpsc::unbounded_channel::<Arc<Session>>();
+
+    // Spawn task to forward broadcast messages and replies to this client
+    let encoding = negotiated.encoding();
+    let batching = negotiated.has(Capability::Batching);
+    let send_joined = Arc::clone(&joined);
+    let send_connection_id = connection_id.clone();
+    let mut displaced = Box::pin(guard.displaced());
+    let mut send_task = tokio::spawn(async move {
+        let mut session = session;
+        let mut resumed = false;
+        let mut reason = DisconnectReason::Dropped;
+
+        'attach: loop {
+            let mut generation_rx = session.generation.subscribe();
+            generation_rx.borrow_and_update();
+            // Waits for a previous connection still holding the session to let go
+            let mut inbox = Arc::clone(&session.inbox).lock_owned().await;
+            let Inbox { events, lsp } = &mut *inbox;
+
+            if resumed {
+                let pending = session.pending();
+                let reply = WsMessage::SessionResumed {
+                    session_id: session.id.clone(),
+                    redelivered: pending.len(),
+                };
+                for msg in std::iter::once(reply).chain(pending) {
+                    if send_frame(&mut ws_sender, &encoding.encode(&msg)).await.is_err() {
+                        break 'attach;
+                    }
+                }
+            }
+
+            let switched = loop {
+                // Biased so events published before a request are delivered before its reply
+                let frame = tokio::select! {
+                    biased;
+                    _ = generation_rx.changed() => {
+                        info!("Session {} resumed by another connection", session.id);
+                        reason = DisconnectReason::Superseded;
+                        break None;
+                    }
+                    () = &mut displaced => {
+                        info!("Connection from {} displaced by a newer one", addr);
+                        let message = "Displaced by a newer connection".to_string();
+                        let _ = close(&mut ws_sender, CloseCode::from(CLOSE_DISPLACED), message).await;
+                        reason = DisconnectReason::Displaced;
+                        break None;
+                    }
+                    next = switch_rx.recv() => break next,
+                    event = events.recv() => match event {
+                        Ok(event) => match session.deliver(&event, encoding) {
+                            Some(frame) if batching => collect_batch(frame, events, &session, encoding).await,
+                            Some(frame) => frame,
+                            None => continue,
+                        },
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Document event stream lagged by {} events for {}", skipped, addr);
+                            encoding.encode(&lagged(skipped))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            reason = DisconnectReason::Closed;
+                            break None;
+                        }
+                    },
+                    Some(message) = lsp.recv() => session.deliver_lsp(message, encoding),
+                    event = collab_rx.recv() => encoding.encode(&match event {
+                        Ok(event) if !send_joined.contains(&event.uri) => continue,
+                        Ok(event) if event.origin == send_connection_id => WsMessage::CollabAck {
+                            uri: event.uri,
+                            revision: event.revision,
+                        },
+                        Ok(event) => WsMessage::CollabApplied {
+                            uri: event.uri,
+                            revision: event.revision,
+                            operation: event.operation,
+                        },
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            // Operations were lost; the client must rejoin to resynchronize
+                            warn!("Collaboration stream lagged by {} operations for {}", skipped, addr);
+                            send_joined.clear();
+                            WsMessage::Error {
+                                message: "Collaboration stream lagged; rejoin documents to resynchronize".to_string(),
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            reason = DisconnectReason::Closed;
+                            break None;
+                        }
+                    }),
+                    msg = reply_rx.recv() => if let Some(msg) = msg { encoding.encode(&msg) } else {
+                        reason = DisconnectReason::Closed;
+                        break None;
+                    },
+                };
+
+                if send_frame(&mut ws_sender, &frame).await.is_err() {
+                    break None;
+                }
+            };
+
+            // Release the event stream before attaching to the resumed session
+            drop(inbox);
+            match switched {
+                Some(next) => {
+                    session = next;
+                    resumed = true;
+                }
+                None => break,
+            }
+        }
+        reason
+    });
+
+    // Handle incoming messages from this client
+    let recv_state = Arc::clone(&state);
+    let recv_current = Arc::clone(&current);
+    let recv_guard = Arc::clone(&guard);
+    let recv_registered = Arc::clone(&registered);
+    let mut recv_task = tokio::spawn(async move {
+        let state = recv_state;
+        let mut reason = DisconnectReason::Dropped;
+        while let Some(msg) = ws_receiver.next().await {
+            recv_guard.touch();
+            recv_registered.touch();
+            if let Ok(frame @ (Message::Text(_) | Message::Binary(_))) = &msg {
+                state.usage.record(&client, Counts::ws_message(frame.len() as u64));
+            }
+            match msg {
+                Ok(Message::Binary(_)) if encoding == Encoding::Json => {
+                    let _ = reply_tx.send(WsMessage::ProtocolViolation {
+                        message: format!("Capability not negotiated: {}", Capability::BinaryEncoding.as_str()),
+                    });
+                }
+                Ok(frame @ (Message::Text(_) | Message::Binary(_))) => {
+                    match decode_frame(&frame) {
+                        Ok(WsMessage::Auth { .. }) => {
+                            // Not logged, as it carries a token
+                            let _ = reply_tx.send(WsMessage::ProtocolViolation {
+                                message: "Auth is only accepted as the first message".to_string(),
+                            });
+                        }
+                        Ok(ws_msg) => {
+                            info!("Received WebSocket message: {:?}", ws_msg);
+                            if let Some(capability) = ws_msg.required_capability().filter(|c| !negotiated.has(*c)) {
+                                let _ = reply_tx.send(WsMessage::ProtocolViolation {
+                                    message: format!("Capability not negotiated: {}", capability.as_str()),
+                                });
+                                continue;
+                            }
+                            let required = ws_msg.required_scope().filter(|scope| !claims.has_scope(scope)).or_else(|| {
+                                let policy = state.auth_service.as_ref()?.policy();
+                                policy.missing_for_message(ws_msg.method()?, &claims)
+                            });
+                            if let Some(scope) = required {
+                                let message = format!("Requires the {scope} scope");
+                                let event = audit(AuditKind::AuthorizationDenied, source, ws_msg.method().unwrap_or("unknown"))
+                                    .subject(client.subject.clone())
+                                    .client(client.name.clone())
+                                    .detail(&message);
+                                state.audit.record(event);
+                                let _ = reply_tx.send(WsMessage::Error { message });
+                                continue;
+                            }
+                            let session = Arc::clone(&recv_current.lock().expect("session lock poisoned").0);
+                            let started = Instant::now();
+                            let method = ws_msg.method();
+                            let span = info_span!("ws.message", ws.method = method.unwrap_or("unknown"));
+                            if let WsMessage::Lsp { traceparent, .. } = &ws_msg {
+                                telemetry::set_parent(&span, traceparent.as_deref());
+                            }
+                            let _entered = span.enter();
+
+                            match ws_msg {
+                                WsMessage::Subscribe { document_id, pattern, ack } => {
+                                    // Unacknowledged deliveries only survive a disconnect in an open session
+                                    let result = if ack && !state.ws_sessions.contains(&session.id) {
+                                        Err("Acknowledged subscriptions require an open session".to_string())
+                                    } else {
+                                        session
+                                            .subscriptions
+                                            .write()
+                                            .expect("subscriptions lock poisoned")
+                                            .subscribe(document_id, pattern, ack)
+                                    };
+                                    if let Err(message) = result {
+                                        let _ = reply_tx.send(WsMessage::Error { message });
+                                    }
+                                }
+                                WsMessage::Unsubscribe { document_id, pattern } => {
+                                    let removed = session
+                                        .subscriptions
+                                        .write()
+                                        .expect("subscriptions lock poisoned")
+                                        .unsubscribe(&document_id, pattern.as_deref());
+                                    if !removed {
+                                        info!("Client unsubscribed from unknown subscription: {}", pattern.unwrap_or(document_id));
+                                    }
+                                }
+                                WsMessage::OpenSession => {
+                                    state.ws_sessions.register(&session);
+                                    let _ = reply_tx.send(WsMessage::SessionOpened {
+                                        session_id: session.id.clone(),
+                                    });
+                                }
+                                WsMessage::ResumeSession {
+                                    session_id,
+                                    last_delivery_id,
+                                } => match state.ws_sessions.resume(&session_id, &claims.sub) {
+                                    Ok((resumed, generation)) => {
+                                        resumed.ack(last_delivery_id);
+                                        *recv_current.lock().expect("session lock poisoned") =
+                                            (Arc::clone(&resumed), generation);
+                                        let _ = switch_tx.send(resumed);
+                                    }
+                                    Err(Unresumable::Unknown) => {
+                                        let _ = reply_tx.send(WsMessage::ResyncRequired {
+                                            reason: format!("Unknown or expired session: {session_id}"),
+                                        });
+                                    }
+                                    Err(Unresumable::NotOwner) => {
+                                        warn!("{} refused resuming another client's session from {}", claims.sub, addr);
+                                        let _ = reply_tx.send(WsMessage::Error {
+                                            message: format!("Session {session_id} belongs to another client"),
+                                        });
+                                    }
+                                },
+                                WsMessage::Ack { delivery_id } => {
+                                    session.ack(delivery_id);
+                                }
+                                WsMessage::CollabJoin { uri } => {
+                                    // Mark as joined before taking the snapshot so no
+                                    // operation after the snapshot revision is missed
+                                    joined.insert(uri.clone());
+                                    let (revision, content) = state.collab.enable(&uri);
+                                    let _ = reply_tx.send(WsMessage::CollabSnapshot {
+                                        uri,
+                                        revision,
+                                        content,
+                                    });
+                                }
+                                WsMessage::CollabLeave { uri } => {
+                                    joined.remove(&uri);
+                                }
+                                WsMessage::CollabOperation {
+                                    uri,
+                                    revision,
+                                    operation,
+                                } => {
+                                    if !joined.contains(&uri) {
+                                        let _ = reply_tx.send(WsMessage::Error {
+                                            message: format!("Not joined to collaborative document: {uri}"),
+                                        });
+                                    } else if let Err(e) =
+                                        state.collab.submit(&uri, &connection_id, revision, operation)
+                                    {
+                                        let _ = reply_tx.send(WsMessage::Error {
+                                            message: format!("Rejected operation: {e}"),
+                                        });
+                                    }
+                                    // On success the acknowledgement arrives via the collab stream
+                                }
+                                WsMessage::Lsp { message, .. } => {
+                                    session.send_lsp(&state, message);
+                                }
+                                WsMessage::GetMetrics => {
+                                    let snapshot = Box::new(state.metrics.snapshot());
+                                    let _ = reply_tx.send(WsMessage::Metrics { snapshot });
+                                }
+                                WsMessage::Ping => {
+                                    let _ = reply_tx.send(WsMessage::Pong);
+                                }
+                                WsMessage::Hello { .. } => {
+                                    let _ = reply_tx.send(WsMessage::ProtocolViolation {
+                                        message: "Handshake already completed".to_string(),
+                                    });
+                                }
+                                _ => {
+                                    warn!("Unexpected message type from client");
+                                }
+                            }
+
+                            if let Some(method) = method {
+                                state
+                                    .metrics
+                                    .ws_message_duration
+                                    .with_labels(&[method])
+                                    .observe_duration(started.elapsed());
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to parse WebSocket message: {}", e);
+                            let error_msg = WsMessage::Error {
+                                message: format!("Invalid message format: {}", e),
+                            };
+                            let _ = reply_tx.send(error_msg);
+                        }
+                    }
+                }
+                Ok(Message::Close(_)) => {
+                    info!("Client {} disconnected", addr);
+                    reason = DisconnectReason::Closed;
+                    break;
+                }
+                Ok(Message::Ping(_)) => {
+                    // Handled automatically by tokio-tungstenite
+                    info!("Received ping from {}", addr);
+                }
+                Ok(Message::Pong(_)) => {
+                    // Keepalive response
+                }
+                Err(e) => {
+                    error!("WebSocket error from {}: {}", addr, e);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        reason
+    });
+
+    // Wait for either task to finish; the other is awaited so its hold on
+    // the connection guard is gone before the guard is released below
+    let reason = tokio::select! {
+        reason = (&mut send_task) => {
+            connection.enter(ConnectionState::Draining);
+            recv_task.abort();
+            let _ = recv_task.await;
+            reason
+        }
+        reason = (&mut recv_task) => {
+            connection.enter(ConnectionState::Draining);
+            send_task.abort();
+            let _ = send_task.await;
+            reason
+        }
+    };
+    connection.set_reason(reason.unwrap_or(DisconnectReason::Dropped));
+    drop(guard);
+    drop(registered);
+
+    // An open session stays resumable for a while after the connection drops
+    let (session, generation) = current.lock().expect("session lock poisoned").clone();
+    session.detach(generation);
+    drop(connection);
+
+    info!("WebSocket connection closed: {}", addr);
+    Ok(())
+}
+
+/// Run WebSocket server
+pub async fn run_websocket_server(state: Arc<ServerState>, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("WebSocket server listening on {}", addr);
+
+    serve_websocket(state, listener).await
+}
+
+/// Accept WebSocket connections on an already bound listener
+///
+/// # Errors
+///
+/// As [`serve_websocket_until`] does.
+pub async fn serve_websocket(state: Arc<ServerState>, listener: TcpListener) -> Result<()> {
+    serve_websocket_until(state, listener, std::future::pending()).await
+}
+
+/// Accept WebSocket connections until `stop` completes
+///
+/// Connections already accepted are left to close on their own. With
+/// `[mtls]` configured, connections are TLS and need a client certificate.
+///
+/// # Errors
+///
+/// Fails where the TLS settings of `[mtls]` cannot be loaded, or accepting
+/// connections fails.
+pub async fn serve_websocket_until<F>(state: Arc<ServerState>, listener: TcpListener, stop: F) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let acceptor = state.config().mtls.as_ref().map(|config| mtls::acceptor(config, &["http/1.1"])).transpose()?;
+    tokio::pin!(stop);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut stop => return Ok(()),
+        };
+        match accepted {
+            Ok((stream, addr)) => {
+                let state_clone = Arc::clone(&state);
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    let served = match acceptor {
+                        Some(acceptor) => match mtls::accept(&acceptor, stream).await {
+                            Ok((stream, certificate)) => serve_connection(stream, addr, Some(certificate), state_clone).await,
+                            Err(e) => {
+                                warn!("Refused TLS connection from {}: {:#}", addr, e);
+                                return;
+                            }
+                        },
+                        None => handle_connection(stream, state_clone).await,
+                    };
+                    if let Err(e) = served {
+                        error!("WebSocket connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept WebSocket connection: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::csrf::CsrfConfig;
+    use crate::auth::policy::ScopePolicy;
+    use crate::ServerConfig;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    type TestClient = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    /// Accept any number of connections on an ephemeral port
+    async fn spawn_server(state: Arc<ServerState>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+            }
+        });
+        addr
+    }
+
+    /// Connect and complete the handshake, returning the server's reply
+    async fn connect_with(addr: std::net::SocketAddr, hello: serde_json::Value) -> (TestClient, Option<Message>) {
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+        send(&mut ws, hello).await;
+        let reply = ws.next().await.and_then(Result::ok);
+        (ws, reply)
+    }
+
+    /// Connect with the current protocol version and every supported capability
+    async fn connect(addr: std::net::SocketAddr) -> TestClient {
+        let hello = serde_json::json!({
+            "type": "Hello",
+            "protocol_version": PROTOCOL_VERSION,
+            "capabilities": ["resumable_sessions", "collab"],
+        });
+        match connect_with(addr, hello).await {
+            (ws, Some(Message::Text(text))) => {
+                assert!(matches!(serde_json::from_str::<WsMessage>(&text).unwrap(), WsMessage::Welcome(_)));
+                ws
+            }
+            (_, other) => panic!("Unexpected handshake reply: {other:?}"),
+        }
+    }
+
+    fn names(capabilities: &[&str]) -> Vec<String> {
+        capabilities.iter().map(|c| (*c).to_string()).collect()
+    }
+
+    async fn send(ws: &mut TestClient, msg: serde_json::Value) {
+        ws.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    /// Send a ping and collect everything received before the pong
+    async fn round_trip(ws: &mut TestClient) -> Vec<WsMessage> {
+        ws.send(Message::Text(r#"{"type":"Ping"}"#.to_string())).await.unwrap();
+        let mut received = Vec::new();
+        while let Some(Ok(frame)) = ws.next().await {
+            match decode_frame(&frame).unwrap() {
+                WsMessage::Pong => break,
+                msg => received.push(msg),
+            }
+        }
+        received
+    }
+
+    /// Expand batches into the messages they carry
+    fn unbatch(messages: Vec<WsMessage>) -> Vec<WsMessage> {
+        messages
+            .into_iter()
+            .flat_map(|msg| match msg {
+                WsMessage::Batch { messages } => messages,
+                msg => vec![msg],
+            })
+            .collect()
+    }
+
+    fn contents(messages: Vec<WsMessage>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|msg| match msg {
+                WsMessage::DocumentUpdated { content, .. } => content,
+                other => panic!("Unexpected message: {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ws_message_serialization() {
+        let msg = WsMessage::Subscribe {
+            document_id: "doc-123".to_string(),
+            pattern: None,
+            ack: false,
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        let deserialized: WsMessage = serde_json::from_str(&json).unwrap();
+
+        match deserialized {
+            WsMessage::Subscribe { document_id, .. } => {
+                assert_eq!(document_id, "doc-123");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_collab_operation_message() {
+        let json = r#"{"type":"CollabOperation","uri":"file:///a.txt","revision":3,"operation":[{"retain":1},{"insert":"x"}]}"#;
+        let msg: WsMessage = serde_json::from_str(json).unwrap();
+
+        match msg {
+            WsMessage::CollabOperation { uri, revision, operation } => {
+                assert_eq!(uri, "file:///a.txt");
+                assert_eq!(revision, 3);
+                assert_eq!(operation.apply("a").unwrap(), "ax");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_document_updated_message() {
+        let msg = WsMessage::DocumentUpdated {
+            document_id: "doc-456".to_string(),
+            uri: "file:///doc.md".to_string(),
+            content: "Updated content".to_string(),
+            timestamp: chrono::Utc::now(),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("DocumentUpdated"));
+        assert!(json.contains("doc-456"));
+    }
+
+    #[test]
+    fn test_pattern_unsubscribe_uses_original_string() {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(String::new(), Some("file:///ws/**/*.yaml".to_string()), false).unwrap();
+        assert!(!subs.unsubscribe("", Some("file:///ws/**/*.yml")));
+        assert!(subs.unsubscribe("", Some("file:///ws/**/*.yaml")));
+        assert_eq!(subs.len(), 0);
+    }
+
+    #[test]
+    fn test_subscription_limit() {
+        let mut subs = Subscriptions::default();
+        for i in 0..MAX_SUBSCRIPTIONS {
+            subs.subscribe(String::new(), Some(format!("file:///ws/{i}/**")), false).unwrap();
+        }
+        // Re-subscribing is free, new subscriptions are rejected
+        subs.subscribe(String::new(), Some("file:///ws/0/**".to_string()), false).unwrap();
+        assert!(subs.subscribe("doc-1".to_string(), None, false).is_err());
+    }
+
+    #[test]
+    fn test_delivery_buffer_cumulative_ack() {
+        let mut buffer = DeliveryBuffer::default();
+        for _ in 0..3 {
+            buffer.push(WsMessage::Ping);
+        }
+        buffer.ack(2);
+
+        let pending = buffer.pending();
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(pending[0], WsMessage::Reliable { delivery_id: 3, .. }));
+    }
+
+    #[test]
+    fn test_delivery_buffer_overflow_requires_resync() {
+        let mut buffer = DeliveryBuffer::default();
+        for _ in 0..MAX_UNACKED_DELIVERIES {
+            buffer.push(WsMessage::Ping);
+        }
+        assert!(matches!(buffer.push(WsMessage::Ping), WsMessage::ResyncRequired { .. }));
+        assert!(buffer.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let mut ws = connect(addr).await;
+        state.metrics.errors.inc();
+
+        send(&mut ws, serde_json::json!({ "type": "GetMetrics" })).await;
+        let received = round_trip(&mut ws).await;
+        let [WsMessage::Metrics { snapshot }] = received.as_slice() else {
+            panic!("Unexpected reply: {received:?}");
+        };
+        assert_eq!(snapshot.total_errors, 1);
+        assert!(snapshot.values.contains_key("ulc_errors_total"));
+    }
+
+    #[tokio::test]
+    async fn test_messages_accounted_to_usage() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let mut ws = connect(addr).await;
+
+        send(&mut ws, serde_json::json!({ "type": "GetMetrics" })).await;
+        round_trip(&mut ws).await;
+        // The handshake is not usage; the two messages after it are, anonymously
+        let report = state.usage.top(Duration::from_mins(1), 10);
+        assert!(report.clients.is_empty());
+        assert_eq!(report.anonymous.ws_messages, 2);
+        assert_eq!(report.anonymous.bytes, (r#"{"type":"GetMetrics"}"#.len() + r#"{"type":"Ping"}"#.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_patterns_deliver_once() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let mut ws = connect(addr).await;
+
+        for pattern in ["file:///ws/", "file:///ws/**/*.yaml"] {
+            send(&mut ws, serde_json::json!({ "type": "Subscribe", "pattern": pattern })).await;
+        }
+        assert!(round_trip(&mut ws).await.is_empty());
+
+        // Created after the subscriptions, matched by both patterns
+        state.documents.upsert("file:///ws/k8s/app.yaml".to_string(), "a: 1".to_string(), "yaml".to_string());
+        state.documents.upsert("file:///other/app.yaml".to_string(), "b: 1".to_string(), "yaml".to_string());
+        state.documents.upsert("file:///ws/k8s/app.yaml".to_string(), "a: 2".to_string(), "yaml".to_string());
+
+        let received = round_trip(&mut ws).await;
+        let contents: Vec<String> = received
+            .into_iter()
+            .map(|msg| match msg {
+                WsMessage::DocumentUpdated { uri, content, .. } => {
+                    assert_eq!(uri, "file:///ws/k8s/app.yaml");
+                    content
+                }
+                other => panic!("Unexpected message: {other:?}"),
+            })
+            .collect();
+        assert_eq!(contents, vec!["a: 1", "a: 2"]);
+    }
+
+    #[tokio::test]
+    async fn test_unacked_delivery_redelivered_after_resume() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+
+        let mut ws = connect(addr).await;
+        send(&mut ws, serde_json::json!({ "type": "OpenSession" })).await;
+        send(&mut ws, serde_json::json!({ "type": "Subscribe", "pattern": "file:///ws/", "ack": true })).await;
+        let session_id = match round_trip(&mut ws).await.as_slice() {
+            [WsMessage::SessionOpened { session_id }] => session_id.clone(),
+            other => panic!("Unexpected messages: {other:?}"),
+        };
+
+        state.documents.upsert("file:///ws/a.md".to_string(), "# A".to_string(), "markdown".to_string());
+        let delivery_id = match round_trip(&mut ws).await.as_slice() {
+            [WsMessage::Reliable { delivery_id, .. }] => *delivery_id,
+            other => panic!("Unexpected messages: {other:?}"),
+        };
+
+        // Connection dies before the client acknowledges
+        drop(ws);
+
+        let mut ws = connect(addr).await;
+        let resume = serde_json::json!({ "type": "ResumeSession", "session_id": session_id, "last_delivery_id": 0 });
+        send(&mut ws, resume).await;
+        match round_trip(&mut ws).await.as_slice() {
+            [WsMessage::SessionResumed { redelivered: 1, .. }, WsMessage::Reliable { delivery_id: id, message }] => {
+                assert_eq!(*id, delivery_id);
+                assert!(matches!(**message, WsMessage::DocumentUpdated { .. }));
+            }
+            other => panic!("Unexpected messages: {other:?}"),
+        }
+
+        // Once acknowledged, nothing is redelivered
+        send(&mut ws, serde_json::json!({ "type": "Ack", "delivery_id": delivery_id })).await;
+        round_trip(&mut ws).await;
+        drop(ws);
+
+        let mut ws = connect(addr).await;
+        let resume = serde_json::json!({ "type": "ResumeSession", "session_id": session_id, "last_delivery_id": delivery_id });
+        send(&mut ws, resume).await;
+        let received = round_trip(&mut ws).await;
+        assert!(matches!(received.as_slice(), [WsMessage::SessionResumed { redelivered: 0, .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_only_the_owner_resumes_a_session() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let connect_as = |subject: &str| {
+            let token = state.auth_service.as_ref().unwrap().generate_token(subject.to_string(), vec!["role:viewer".to_string()]);
+            let mut request = format!("ws://{addr}").into_client_request().unwrap();
+            request.headers_mut().insert(header::AUTHORIZATION, token.unwrap().parse().unwrap());
+            async move {
+                let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+                let hello = serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION, "capabilities": ["resumable_sessions"] });
+                send(&mut ws, hello).await;
+                assert!(ws.next().await.is_some());
+                ws
+            }
+        };
+
+        let mut owner = connect_as("alice").await;
+        send(&mut owner, serde_json::json!({ "type": "OpenSession" })).await;
+        let session_id = match round_trip(&mut owner).await.as_slice() {
+            [WsMessage::SessionOpened { session_id }] => session_id.clone(),
+            other => panic!("Unexpected messages: {other:?}"),
+        };
+        drop(owner);
+
+        // Knowing the ID is not enough to take the session over
+        let resume = serde_json::json!({ "type": "ResumeSession", "session_id": session_id, "last_delivery_id": 0 });
+        let mut intruder = connect_as("mallory").await;
+        send(&mut intruder, resume.clone()).await;
+        let received = round_trip(&mut intruder).await;
+        assert!(matches!(received.as_slice(), [WsMessage::Error { message }] if message.contains("belongs to another client")), "{received:?}");
+
+        let mut owner = connect_as("alice").await;
+        send(&mut owner, resume).await;
+        let received = round_trip(&mut owner).await;
+        assert!(matches!(received.as_slice(), [WsMessage::SessionResumed { redelivered: 0, .. }]), "{received:?}");
+    }
+
+    #[test]
+    fn test_negotiate_drops_unsupported_capabilities() {
+        let requested = names(&["binary_encoding", "compression", "collab", "future_thing"]);
+        let negotiated = Negotiated::negotiate(PROTOCOL_VERSION, &requested).unwrap();
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, vec![Capability::BinaryEncoding, Capability::Collab]);
+        assert_eq!(negotiated.limits.max_subscriptions, MAX_SUBSCRIPTIONS);
+    }
+
+    #[test]
+    fn test_negotiate_downgrades_newer_client() {
+        let negotiated = Negotiated::negotiate(PROTOCOL_VERSION + 3, &names(&["resumable_sessions"])).unwrap();
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert!(negotiated.has(Capability::ResumableSessions));
+    }
+
+    #[test]
+    fn test_negotiate_previous_version_drops_newer_capabilities() {
+        let negotiated =
+            Negotiated::negotiate(MIN_PROTOCOL_VERSION, &names(&["resumable_sessions", "collab"])).unwrap();
+        assert_eq!(negotiated.protocol_version, MIN_PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, vec![Capability::Collab]);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_old_version() {
+        assert!(Negotiated::negotiate(MIN_PROTOCOL_VERSION - 1, &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_close_codes() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(state).await;
+
+        let hello = serde_json::json!({ "type": "Hello", "protocol_version": MIN_PROTOCOL_VERSION - 1 });
+        match connect_with(addr, hello).await {
+            (_, Some(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), CLOSE_UPGRADE_REQUIRED),
+            (_, other) => panic!("Unexpected handshake reply: {other:?}"),
+        }
+
+        match connect_with(addr, serde_json::json!({ "type": "Ping" })).await {
+            (_, Some(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Protocol),
+            (_, other) => panic!("Unexpected handshake reply: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unnegotiated_capability_rejected() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+
+        let hello = serde_json::json!({
+            "type": "Hello",
+            "protocol_version": MIN_PROTOCOL_VERSION,
+            "capabilities": ["resumable_sessions"],
+        });
+        let (mut ws, reply) = connect_with(addr, hello).await;
+        match reply {
+            Some(Message::Text(text)) => match serde_json::from_str::<WsMessage>(&text).unwrap() {
+                WsMessage::Welcome(negotiated) => assert!(negotiated.capabilities.is_empty()),
+                other => panic!("Unexpected handshake reply: {other:?}"),
+            },
+            other => panic!("Unexpected handshake reply: {other:?}"),
+        }
+
+        send(&mut ws, serde_json::json!({ "type": "OpenSession" })).await;
+        send(&mut ws, serde_json::json!({ "type": "CollabJoin", "uri": "file:///a.txt" })).await;
+        let received = round_trip(&mut ws).await;
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|msg| matches!(msg, WsMessage::ProtocolViolation { .. })));
+        assert!(state.ws_sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_messages_need_their_scopes() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let subscribe = WsMessage::Subscribe { document_id: "doc".to_string(), pattern: None, ack: false };
+        let join = serde_json::json!({ "type": "CollabJoin", "uri": "file:///a.txt" });
+
+        // Without a scope, only messages needing none are served
+        let unscoped_token = state.auth_service.as_ref().unwrap().generate_token("nobody".to_string(), Vec::new()).unwrap();
+        let (mut unscoped, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+        send(&mut unscoped, serde_json::json!({ "type": "Auth", "token": unscoped_token })).await;
+        send(&mut unscoped, serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION, "capabilities": ["collab"] })).await;
+        assert!(unscoped.next().await.is_some());
+        send(&mut unscoped, serde_json::to_value(&subscribe).unwrap()).await;
+        send(&mut unscoped, join.clone()).await;
+        let received = round_trip(&mut unscoped).await;
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|msg| matches!(msg, WsMessage::Error { message } if message == "Requires the read scope")));
+
+        // A viewer may follow a document, but not edit it
+        let token = state.auth_service.as_ref().unwrap().generate_token("viewer".to_string(), vec!["role:viewer".to_string()]);
+        let mut request = format!("ws://{addr}").into_client_request().unwrap();
+        request.headers_mut().insert(header::AUTHORIZATION, token.unwrap().parse().unwrap());
+        let (mut viewer, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        send(&mut viewer, serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION, "capabilities": ["collab"] })).await;
+        assert!(viewer.next().await.is_some());
+        send(&mut viewer, join).await;
+        let edit = r#"{"type":"CollabOperation","uri":"file:///a.txt","revision":0,"operation":[{"insert":"x"}]}"#;
+        viewer.send(Message::Text(edit.to_string())).await.unwrap();
+        let received = round_trip(&mut viewer).await;
+        assert!(matches!(received.as_slice(), [WsMessage::CollabSnapshot { .. }, WsMessage::Error { message }] if message == "Requires the write scope"), "{received:?}");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_clients_read_only() {
+        for enable_auth in [false, true] {
+            let config = ServerConfig { enable_auth, anonymous_read_only: true, ..ServerConfig::default() };
+            let addr = spawn_server(Arc::new(ServerState::new(config))).await;
+            // No Auth message is expected, so Hello comes first
+            let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+            send(&mut ws, serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION, "capabilities": ["collab", "lsp"] })).await;
+            assert!(matches!(decode_frame(&ws.next().await.unwrap().unwrap()), Ok(WsMessage::Welcome(_))));
+            send(&mut ws, serde_json::json!({ "type": "CollabJoin", "uri": "file:///a.txt" })).await;
+            let edit = r#"{"type":"CollabOperation","uri":"file:///a.txt","revision":0,"operation":[{"insert":"x"}]}"#;
+            ws.send(Message::Text(edit.to_string())).await.unwrap();
+            send(&mut ws, serde_json::json!({ "type": "Lsp", "message": { "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} } })).await;
+            let received = round_trip(&mut ws).await;
+            let refused: Vec<_> = received
+                .iter()
+                .filter_map(|msg| match msg {
+                    WsMessage::Error { message } => Some(message.as_str()),
+                    _ => None,
+                })
+                .collect();
+            assert!(matches!(received.first(), Some(WsMessage::CollabSnapshot { .. })), "{received:?}");
+            assert_eq!(refused, ["Requires the write scope", "Requires the write scope"], "{received:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_presented_with_the_upgrade_or_first() {
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, ..ServerConfig::default() }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let token = state.auth_service.as_ref().unwrap().generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        let token = token.strip_prefix("Bearer ").unwrap();
+        let hello = serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION });
+        let unauthorized = |reply: Option<Message>| match reply {
+            Some(Message::Close(Some(frame))) => {
+                assert_eq!(u16::from(frame.code), CLOSE_UNAUTHORIZED);
+                frame.reason.to_string()
+            }
+            other => panic!("Unexpected reply: {other:?}"),
+        };
+
+        // A client presenting no token must authenticate first
+        assert_eq!(unauthorized(connect_with(addr, hello.clone()).await.1), "Expected Auth as the first message");
+        let forged = serde_json::json!({ "type": "Auth", "token": "forged" });
+        assert!(unauthorized(connect_with(addr, forged).await.1).starts_with("Invalid token: "));
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+        send(&mut ws, serde_json::json!({ "type": "Auth", "token": token })).await;
+        send(&mut ws, hello.clone()).await;
+        assert!(matches!(decode_frame(&ws.next().await.unwrap().unwrap()), Ok(WsMessage::Welcome(_))));
+        send(&mut ws, serde_json::json!({ "type": "Auth", "token": token })).await;
+        send(&mut ws, serde_json::json!({ "type": "Subscribe", "document_id": "doc" })).await;
+        let received = round_trip(&mut ws).await;
+        assert!(matches!(received.as_slice(), [WsMessage::ProtocolViolation { .. }]), "{received:?}");
+
+        // Browsers offer it as a subprotocol, or put it in the query
+        let mut request = format!("ws://{addr}").into_client_request().unwrap();
+        let protocols = format!("{SUBPROTOCOL}, {TOKEN_SUBPROTOCOL_PREFIX}{token}");
+        request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, protocols.parse().unwrap());
+        let (mut ws, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_PROTOCOL], SUBPROTOCOL);
+        send(&mut ws, hello.clone()).await;
+        assert!(matches!(decode_frame(&ws.next().await.unwrap().unwrap()), Ok(WsMessage::Welcome(_))));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/?access_token={token}")).await.unwrap();
+        send(&mut ws, hello).await;
+        assert!(matches!(decode_frame(&ws.next().await.unwrap().unwrap()), Ok(WsMessage::Welcome(_))));
+        let refused = tokio_tungstenite::connect_async(format!("ws://{addr}/?access_token=forged")).await.unwrap_err();
+        assert!(matches!(refused, tokio_tungstenite::tungstenite::Error::Http(response) if response.status() == StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_upgrades_from_other_origins_refused() {
+        let csrf = CsrfConfig { enabled: true, allowed_origins: vec!["https://ide.example.com".to_string()], ..CsrfConfig::default() };
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, csrf, ..ServerConfig::default() }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let token = state.auth_service.as_ref().unwrap().generate_token("alice".to_string(), vec!["read".to_string()]).unwrap();
+        let upgrade = |origin: &str| {
+            let mut request = format!("ws://{addr}").into_client_request().unwrap();
+            request.headers_mut().insert(header::ORIGIN, origin.parse().unwrap());
+            request.headers_mut().insert(header::COOKIE, format!("ulc_token={}", token.strip_prefix("Bearer ").unwrap()).parse().unwrap());
+            tokio_tungstenite::connect_async(request)
+        };
+
+        for origin in [format!("http://{addr}"), "https://ide.example.com".to_string()] {
+            let (mut ws, _) = upgrade(&origin).await.unwrap();
+            // The cookie authenticated it, so Hello comes first
+            send(&mut ws, serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION })).await;
+            assert!(matches!(decode_frame(&ws.next().await.unwrap().unwrap()), Ok(WsMessage::Welcome(_))));
+        }
+        let refused = upgrade("https://evil.example").await.unwrap_err();
+        assert!(matches!(refused, tokio_tungstenite::tungstenite::Error::Http(response) if response.status() == StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn test_policy_adds_message_scopes() {
+        let policy = ScopePolicy::default().message("Subscribe", vec!["follow".to_string()]);
+        let state = Arc::new(ServerState::new(ServerConfig { enable_auth: true, policy, ..ServerConfig::default() }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+        let auth = state.auth_service.as_ref().unwrap();
+        let subscribe = serde_json::json!({ "type": "Subscribe", "document_id": "doc" });
+
+        for (scopes, expected) in [(vec!["read"], Some("Requires the follow scope")), (vec!["read", "follow"], None)] {
+            let token = auth.generate_token("someone".to_string(), scopes.iter().map(ToString::to_string).collect()).unwrap();
+            let mut request = format!("ws://{addr}").into_client_request().unwrap();
+            request.headers_mut().insert(header::AUTHORIZATION, token.parse().unwrap());
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            send(&mut ws, serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION })).await;
+            assert!(ws.next().await.is_some());
+            send(&mut ws, subscribe.clone()).await;
+            let received = round_trip(&mut ws).await;
+            let refused = received.iter().find_map(|msg| match msg {
+                WsMessage::Error { message } => Some(message.as_str()),
+                _ => None,
+            });
+            assert_eq!(refused, expected, "{scopes:?}: {received:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_delivery_preserves_order() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+
+        let hello = serde_json::json!({ "type": "Hello", "protocol_version": PROTOCOL_VERSION, "capabilities": ["batching"] });
+        let (mut ws, _) = connect_with(addr, hello).await;
+        send(&mut ws, serde_json::json!({ "type": "Subscribe", "pattern": "file:///ws/" })).await;
+        round_trip(&mut ws).await;
+
+        let expected: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        for content in &expected {
+            state.documents.upsert("file:///ws/a.md".to_string(), content.clone(), "markdown".to_string());
+        }
+
+        let received = round_trip(&mut ws).await;
+        assert!(received.len() < expected.len(), "events should have been batched");
+        assert_eq!(contents(unbatch(received)), expected);
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_refuses_upgrade() {
+        let state = Arc::new(ServerState::new(ServerConfig {
+            ws_connection_limits: admission::ConnectionLimits {
+                max_per_ip: 1,
+                ..admission::ConnectionLimits::default()
+            },
+            trusted_proxies: crate::TrustedProxies::parse("127.0.0.1").unwrap(),
+            ..ServerConfig::default()
+        }));
+        let addr = spawn_server(Arc::clone(&state)).await;
+
+        let mut first = connect(addr).await;
+        match tokio_tungstenite::connect_async(format!("ws://{addr}")).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+            }
+            other => panic!("Expected the upgrade to be refused: {other:?}"),
+        }
+
+        // A client behind the trusted proxy is counted under its own address
+        let mut request = format!("ws://{addr}").into_client_request().unwrap();
+        request.headers_mut().insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        assert!(tokio_tungstenite::connect_async(request).await.is_ok());
+
+        let ws = state.metrics.snapshot().websocket;
+        assert_eq!(ws.per_ip.get("127.0.0.1"), Some(&1));
+        assert_eq!(ws.rejections.get("per_ip"), Some(&1));
+
+        // Closing the first connection frees its slot
+        first.close(None).await.unwrap();
+        while first.next().await.is_some() {}
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.metrics.snapshot().websocket.per_ip.contains_key("127.0.0.1") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("closed connection should be released");
+        connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn test_message_pack_encoding() {
+        let state = Arc::new(ServerState::new(ServerConfig::default()));
+        let addr = spawn_server(Arc::clone(&state)).await;
+
+        let hello = serde_json::json!({
+            "type": "Hello",
+            "protocol_version": PROTOCOL_VERSION,
+            "capabilities": ["binary_encoding"],
+        });
+        let (mut ws, _) = connect_with(addr, hello).await;
+        let subscribe = WsMessage::Subscribe {
+            document_id: String::new(),
+            pattern: Some("file:///ws/".to_string()),
+            ack: false,
+        };
+        ws.send(Message::Binary(rmp_serde::to_vec_named(&subscribe).unwrap())).await.unwrap();
+        round_trip(&mut ws).await;
+
+        for content in ["one", "two"] {
+            state.documents.upsert("file:///ws/a.md".to_string(), content.to_string(), "markdown".to_string());
+        }
+        ws.send(Message::Binary(rmp_serde::to_vec_named(&WsMessage::Ping).unwrap())).await.unwrap();
+
+        let mut frames = Vec::new();
+        while let Some(Ok(frame)) = ws.next().await {
+            assert!(frame.is_binary(), "expected MessagePack frames, got {frame:?}");
+            match decode_frame(&frame).unwrap() {
+                WsMessage::Pong => break,
+                msg => frames.push(msg),
+            }
+        }
+        assert_eq!(contents(frames), vec!["one", "two"]);
+    }
+}
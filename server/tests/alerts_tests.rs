@@ -0,0 +1,207 @@
+//! Alert sink integration tests
+//!
+//! Rules are evaluated against the server's own metrics through a scripted
+//! firing and flapping sequence, and notifications are delivered over HTTP
+//! to mock sinks, one of which always fails.
+
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{Request, StatusCode};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tower::ServiceExt;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use universal_connector_server::logging::{self, LoggingConfig};
+use universal_connector_server::monitoring::alerts::{AlertsSummary, SinkConfig, Template};
+use universal_connector_server::monitoring::rules::{Rule, Severity};
+use universal_connector_server::{http, ServerConfig, ServerState};
+
+/// Bodies received by the mock sinks, by sink
+type Received = Arc<Mutex<Vec<(String, Value)>>>;
+
+/// Writer collecting everything logged
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self {
+        self.clone()
+    }
+}
+
+async fn receive(
+    State(received): State<Received>,
+    Path((sink, _secret)): Path<(String, String)>,
+    Json(body): Json<Value>,
+) -> StatusCode {
+    received.lock().unwrap().push((sink.clone(), body));
+    if sink == "dead" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Serve the mock sinks, returning their base URL
+async fn mock_sinks(received: Received) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().route("/hooks/:sink/:secret", post(receive)).with_state(received);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    format!("http://{addr}")
+}
+
+fn sink(base: &str, name: &str, template: Template, min_severity: Severity) -> SinkConfig {
+    SinkConfig {
+        name: name.to_string(),
+        url: format!("{base}/hooks/{name}/secret-token"),
+        template,
+        min_severity,
+        routing_key: None,
+    }
+}
+
+/// Fires while errors were counted since the rate windows were last reset
+fn rule(name: &str, severity: &str) -> Rule {
+    serde_yaml::from_str(&format!(
+        "
+name: {name}
+query: {{ kind: rate, metric: ulc_errors_total }}
+window_secs: 60
+comparison: above
+threshold: 1
+no_data: ok
+severity: {severity}
+"
+    ))
+    .unwrap()
+}
+
+/// Messages received by `sink`
+fn bodies(received: &Received, sink: &str) -> Vec<Value> {
+    received
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name == sink)
+        .map(|(_, body)| body.clone())
+        .collect()
+}
+
+/// Wait until the sinks have seen `delivered` and `failed` notifications in all
+async fn settle(state: &ServerState, delivered: u64, failed: u64) -> AlertsSummary {
+    for _ in 0..200 {
+        let summary = state.alerts.summary();
+        let counts = summary.sinks.iter().fold((0, 0), |(d, f), sink| (d + sink.delivered, f + sink.failed));
+        if counts == (delivered, failed) {
+            return summary;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("notifications never settled: {:?}", state.alerts.summary());
+}
+
+#[tokio::test]
+async fn test_firing_and_flapping_notifications() {
+    let captured = Captured::default();
+    let (subscriber, _handle) = logging::subscriber(&LoggingConfig::default(), BoxMakeWriter::new(captured.clone())).unwrap();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let received = Received::default();
+    let base = mock_sinks(Arc::clone(&received)).await;
+    let config = ServerConfig::builder()
+        .alerting(|alerting| {
+            alerting
+                .rule(rule("errors", "critical"))
+                .rule(rule("errors_early", "warning"))
+                .sink(sink(&base, "ops", Template::Generic, Severity::Info))
+                .sink(sink(&base, "chat", Template::Slack, Severity::Critical))
+                .sink(sink(&base, "dead", Template::Generic, Severity::Info))
+                .max_attempts(3)
+                .retry_backoff(Duration::from_millis(5))
+        })
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    tokio::spawn(Arc::clone(&state.alerts).run());
+    let evaluate = || state.rules.evaluate(&state.metrics.gather());
+
+    // Both rules fire in one evaluation: one message per sink
+    state.metrics.errors.inc_by(100);
+    assert_eq!(evaluate().len(), 2);
+    // Still firing: nothing new to say
+    assert!(evaluate().is_empty());
+    settle(&state, 2, 1).await;
+
+    let ops = bodies(&received, "ops");
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0]["status"], "firing");
+    let rules: Vec<_> = ops[0]["alerts"].as_array().unwrap().iter().map(|a| a["rule"].clone()).collect();
+    assert_eq!(rules, ["errors", "errors_early"]);
+    // Only the critical alert passes the chat sink's severity filter
+    let chat = bodies(&received, "chat");
+    let text = chat[0]["text"].as_str().unwrap();
+    assert!(text.starts_with(":rotating_light: 1 firing"), "{text}");
+    assert!(text.contains("[FIRING] errors (critical)"), "{text}");
+    assert!(!text.contains("errors_early"), "{text}");
+
+    // Flapping: resolved, then firing again, each notified once
+    state.metrics.reset_windows();
+    assert_eq!(evaluate().len(), 2);
+    assert!(evaluate().is_empty());
+    state.metrics.errors.inc_by(100);
+    assert_eq!(evaluate().len(), 2);
+    let summary = settle(&state, 6, 3).await;
+
+    let statuses: Vec<_> = bodies(&received, "ops").iter().map(|body| body["status"].clone()).collect();
+    assert_eq!(statuses, ["firing", "resolved", "firing"]);
+    let chat = bodies(&received, "chat");
+    assert_eq!(chat.len(), 3);
+    assert!(chat[1]["text"].as_str().unwrap().contains("[RESOLVED] errors (critical)"));
+
+    // Every failed notification was tried three times
+    assert_eq!(bodies(&received, "dead").len(), 9);
+    let dead = summary.sinks.iter().find(|sink| sink.name == "dead").unwrap();
+    assert_eq!((dead.delivered, dead.failed), (0, 3));
+    assert_eq!(dead.last_error.as_deref(), Some("HTTP 503 after 3 attempts"));
+    assert!(!dead.url.contains("secret"), "{}", dead.url);
+    let failed = state
+        .metrics
+        .alerts
+        .notifications
+        .with_labels(&["dead", "failed"])
+        .get();
+    assert_eq!(failed, 3);
+    assert_eq!(summary.firing.len(), 2);
+
+    // The admin endpoint reports the same, without the secrets
+    let response = http::create_router(Arc::clone(&state))
+        .oneshot(Request::builder().uri("/api/admin/alerts").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let reported: AlertsSummary = serde_json::from_slice(&body).unwrap();
+    assert_eq!(reported.sinks.len(), 3);
+    assert!(!String::from_utf8_lossy(&body).contains("secret"));
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("Alert notification failed"), "{logs}");
+    assert!(!logs.contains("secret"), "{logs}");
+}
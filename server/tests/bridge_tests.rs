@@ -0,0 +1,133 @@
+//! End-to-end tests for the stdio-to-WebSocket LSP bridge
+//!
+//! Editor-side traffic goes through the bridge into a real WebSocket server
+//! hosting the language server, and back.
+
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, BufReader};
+use universal_connector_server::bridge::{run_bridge_with, BridgeConfig};
+use universal_connector_server::lsp::{read_message, write_message};
+use universal_connector_server::{Server, ServerConfig, ServerState};
+
+async fn start_server() -> (Arc<ServerState>, String) {
+    let config = ServerConfig::builder()
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_http()
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    (state, format!("ws://{}", server.ws_addr().unwrap()))
+}
+
+/// Read until the response to request `id`, skipping notifications
+async fn response<R: AsyncBufRead + Unpin>(reader: &mut R, id: i64) -> Value {
+    loop {
+        let message = read_message(reader).await.unwrap().expect("bridge closed stdout");
+        if message["id"] == json!(id) {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_bridge_round_trip() {
+    let (state, url) = start_server().await;
+
+    let (editor, bridge_side) = tokio::io::duplex(64 * 1024);
+    let (bridge_in, bridge_out) = tokio::io::split(bridge_side);
+    let bridge = tokio::spawn(run_bridge_with(BridgeConfig::new(url), bridge_in, bridge_out));
+
+    let (editor_in, mut editor_out) = tokio::io::split(editor);
+    let mut editor_in = BufReader::new(editor_in);
+
+    let initialize = json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } });
+    write_message(&mut editor_out, &initialize).await.unwrap();
+    let initialized = response(&mut editor_in, 1).await;
+    assert_eq!(initialized["result"]["capabilities"]["hoverProvider"], json!(true));
+
+    write_message(&mut editor_out, &json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }))
+        .await
+        .unwrap();
+    let did_open = json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": {
+            "textDocument": {
+                "uri": "file:///bridge/notes.md",
+                "languageId": "markdown",
+                "version": 1,
+                "text": "# Notes\n\nBridged through the connector"
+            }
+        }
+    });
+    write_message(&mut editor_out, &did_open).await.unwrap();
+
+    let hover = json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "textDocument/hover",
+        "params": {
+            "textDocument": { "uri": "file:///bridge/notes.md" },
+            "position": { "line": 0, "character": 2 }
+        }
+    });
+    write_message(&mut editor_out, &hover).await.unwrap();
+    let hovered = response(&mut editor_in, 2).await;
+    let contents = hovered["result"]["contents"]["value"].as_str().unwrap();
+    assert!(contents.contains("Document Statistics"), "unexpected hover: {contents}");
+
+    // The hosted language server shares the connector's document store
+    assert!(state.documents.contains("file:///bridge/notes.md"));
+
+    // Both hops are timed: the WebSocket message and the LSP request it carries
+    let lsp_latency = &state.metrics.lsp_request_duration;
+    assert_eq!(lsp_latency.with_labels(&["initialize"]).snapshot().count, 1);
+    assert_eq!(lsp_latency.with_labels(&["textDocument/hover"]).snapshot().count, 1);
+    assert!(state.metrics.ws_message_duration.with_labels(&["Lsp"]).snapshot().count >= 4);
+
+    // A conversion carried by WebSocket into the LSP backend is counted once
+    let convert = json!({
+        "jsonrpc": "2.0",
+        "id": 3,
+        "method": "workspace/executeCommand",
+        "params": { "command": "convert.toHtml", "arguments": ["file:///bridge/notes.md"] }
+    });
+    write_message(&mut editor_out, &convert).await.unwrap();
+    let converted = response(&mut editor_in, 3).await;
+    assert!(converted["result"]["content"].as_str().unwrap().contains("<h1>Notes</h1>"));
+    let conversions = state.metrics.conversions.children();
+    assert_eq!(conversions.len(), 1);
+    assert_eq!(conversions[0].0, vec!["md", "html", "succeeded"]);
+    assert_eq!(conversions[0].1.get(), 1);
+
+    write_message(&mut editor_out, &json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" }))
+        .await
+        .unwrap();
+    assert_eq!(response(&mut editor_in, 4).await["result"], Value::Null);
+    write_message(&mut editor_out, &json!({ "jsonrpc": "2.0", "method": "exit" }))
+        .await
+        .unwrap();
+
+    bridge.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_bridge_reports_unreachable_connector() {
+    // Nothing listens on this port once the listener is dropped
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    drop(listener);
+
+    let (editor, bridge_side) = tokio::io::duplex(64 * 1024);
+    let (bridge_in, bridge_out) = tokio::io::split(bridge_side);
+    let result = run_bridge_with(BridgeConfig::new(url), bridge_in, bridge_out).await;
+    assert!(result.is_err());
+
+    // The editor is told why the server went away
+    let mut editor_in = BufReader::new(editor);
+    let message = read_message(&mut editor_in).await.unwrap().unwrap();
+    assert_eq!(message["method"], json!("window/showMessage"));
+}
@@ -0,0 +1,61 @@
+//! Build metadata integration tests
+//!
+//! Every surface reporting the server version must report the same build.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use universal_connector_server::http::{self, VersionResponse};
+use universal_connector_server::lsp::LspHost;
+use universal_connector_server::websocket::PROTOCOL_VERSION;
+use universal_connector_server::{BuildInfo, Negotiated, ServerConfig, ServerState};
+
+async fn get(state: &Arc<ServerState>, uri: &str) -> Vec<u8> {
+    let response = http::create_router(Arc::clone(state))
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+}
+
+#[tokio::test]
+async fn test_version_surfaces_agree() {
+    let state = Arc::new(ServerState::new(ServerConfig::default()));
+    let build = BuildInfo::current();
+
+    // GET /api/version
+    let version: VersionResponse = serde_json::from_slice(&get(&state, "/api/version").await).unwrap();
+    assert_eq!(version.build, build);
+
+    // ulc_build_info carries the same values as labels
+    let text = String::from_utf8(get(&state, "/metrics").await).unwrap();
+    let info = text
+        .lines()
+        .find(|line| line.starts_with("ulc_build_info{"))
+        .expect("ulc_build_info exported");
+    assert!(info.ends_with(" 1"), "{info}");
+    for (label, value) in BuildInfo::LABELS.iter().zip(build.label_values()) {
+        assert!(info.contains(&format!("{label}=\"{value}\"")), "{label} missing from {info}");
+    }
+    assert!(text.lines().any(|line| line.starts_with("ulc_uptime_seconds ")));
+
+    // LSP initialize serverInfo
+    let (output, mut messages) = mpsc::unbounded_channel::<Value>();
+    let host = LspHost::spawn(Arc::clone(&state), output);
+    host.send(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } }));
+    let initialized = loop {
+        let message = messages.recv().await.expect("LSP server replies");
+        if message["id"] == json!(1) {
+            break message;
+        }
+    };
+    assert_eq!(initialized["result"]["serverInfo"]["version"], json!(build.version));
+
+    // WebSocket Welcome
+    let negotiated = Negotiated::negotiate(PROTOCOL_VERSION, &[]).unwrap();
+    assert_eq!(negotiated.server_version, build.version);
+}
@@ -0,0 +1,90 @@
+//! Capability registry integration tests
+//!
+//! The HTTP endpoint, the LSP `initialize` result and the WebSocket
+//! `Welcome` are read from one running server and must list the same
+//! capabilities, including those registered after startup.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio_tungstenite::tungstenite::Message;
+use universal_connector_server::lsp::{self, read_message, write_message};
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::websocket::{self, WsMessage};
+use universal_connector_server::{CapabilityInfo, Server, ServerConfig, ServerHandle, ServerState};
+
+async fn start_server() -> (Arc<ServerState>, ServerHandle) {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    (state, server)
+}
+
+async fn over_http(server: &ServerHandle) -> Value {
+    let url = format!("http://{}/api/capabilities", server.http_addr().unwrap());
+    reqwest::get(url).await.unwrap().json().await.unwrap()
+}
+
+async fn over_websocket(server: &ServerHandle) -> Value {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", server.ws_addr().unwrap())).await.unwrap();
+    let hello = json!({ "type": "Hello", "protocol_version": websocket::PROTOCOL_VERSION });
+    ws.send(Message::Text(hello.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(text))) = ws.next().await else {
+        panic!("No Welcome");
+    };
+    match serde_json::from_str(&text).unwrap() {
+        WsMessage::Welcome(negotiated) => negotiated.server_capabilities,
+        other => panic!("Unexpected handshake reply: {other:?}"),
+    }
+}
+
+/// The `initialize` result of a fresh LSP session
+async fn over_lsp(state: &Arc<ServerState>) -> Value {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let (input, output) = tokio::io::split(server);
+    tokio::spawn(lsp::serve_lsp(Arc::clone(state), input, output, Transport::LspTcp));
+    let (client_in, mut client_out) = tokio::io::split(client);
+    let initialize = json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } });
+    write_message(&mut client_out, &initialize).await.unwrap();
+    read_message(&mut BufReader::new(client_in)).await.unwrap().unwrap()["result"].clone()
+}
+
+#[tokio::test]
+async fn test_every_surface_lists_the_registry() {
+    let (state, server) = start_server().await;
+    state
+        .capabilities
+        .register("jobs", CapabilityInfo::new("1").parameter("max_queued", 100))
+        .unwrap();
+
+    let listed = over_http(&server).await;
+    assert_eq!(listed, state.capabilities.to_value());
+    assert_eq!(listed["jobs"]["parameters"]["max_queued"], 100);
+    assert_eq!(listed["websocket"]["version"], websocket::PROTOCOL_VERSION.to_string());
+    assert!(listed["formats"]["children"]["markdown"].is_object());
+
+    let initialized = over_lsp(&state).await;
+    assert_eq!(initialized["capabilities"]["experimental"], listed);
+    assert_eq!(over_websocket(&server).await, listed);
+}
+
+#[tokio::test]
+async fn test_conversions_offered_follow_the_registry() {
+    let (state, _server) = start_server().await;
+    let commands = |initialized: &Value| initialized["capabilities"]["executeCommandProvider"]["commands"].clone();
+    assert_eq!(
+        commands(&over_lsp(&state).await),
+        json!(["convert.toHtml", "convert.toMarkdown", "convert.toJson", "document.diff", "document.query", "schema.infer"])
+    );
+
+    state.capabilities.remove("formats.json").unwrap();
+    let initialized = over_lsp(&state).await;
+    assert_eq!(commands(&initialized), json!(["convert.toHtml", "convert.toMarkdown", "document.diff", "document.query", "schema.infer"]));
+    assert!(initialized["capabilities"]["experimental"]["formats"]["children"].get("json").is_none());
+}
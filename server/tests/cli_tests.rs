@@ -0,0 +1,582 @@
+//! Command-line integration tests
+//!
+//! Each subcommand is run as the built binary, in a clean environment, and
+//! judged by its exit code and output.
+
+use assert_cmd::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use universal_connector_server::auth::{AuthConfig, AuthService, Claims};
+use universal_connector_server::formats::ExtendedFormat;
+use universal_connector_server::ServerConfig;
+
+const BIN: &str = "universal-connector-server";
+
+/// Run the binary with only `env` set
+fn run(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::cargo_bin(BIN)
+        .unwrap()
+        .env_clear()
+        .envs(env.iter().copied())
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+/// A scratch directory holding `name` with `contents`
+fn config_file(name: &str, contents: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("ulc-cli-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    (dir, path)
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// The claims of a token, verified against `secret`
+fn claims(token: &str, secret: &str) -> Claims {
+    let config = AuthConfig { secret: secret.to_string(), enabled: true, ..AuthConfig::default() };
+    AuthService::new(config).validate_token(token).unwrap()
+}
+
+#[test]
+fn test_generate_config() {
+    let output = run(&["generate-config"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), ServerConfig::example_toml());
+    let loaded = ServerConfig::parse(&stdout(&output), ExtendedFormat::Toml).unwrap();
+    assert_eq!(loaded.config, ServerConfig::default());
+}
+
+#[test]
+fn test_check_config_layers_flags_over_env_over_file() {
+    let file = r#"
+http_addr = "0.0.0.0:9000"
+ws_addr = "0.0.0.0:9001"
+jwt_secret = "s3cret"
+
+[logging]
+level = "warn"
+"#;
+    let (dir, path) = config_file("server.toml", file);
+    let output = run(
+        &["check-config", "--config", arg(&path), "--http-addr", "127.0.0.1:7000", "--no-websocket"],
+        &[("ULC_HTTP_ADDR", "127.0.0.1:8000"), ("ULC_LOGGING__LEVEL", "debug")],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let printed = stdout(&output);
+    let config = ServerConfig::parse(&printed, ExtendedFormat::Toml).unwrap().config;
+    assert_eq!(config.http_addr, "127.0.0.1:7000");
+    assert_eq!(config.ws_addr, "0.0.0.0:9001");
+    assert_eq!(config.logging.level, "debug");
+    assert!(!config.enable_websocket);
+    assert_eq!(config.jwt_secret, "<redacted>");
+    assert!(!printed.contains("s3cret"), "{printed}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_check_config_fails_on_problems() {
+    let (dir, path) = config_file("server.toml", "http_adr = \"0.0.0.0:9000\"\n");
+    let output = run(&["check-config", "--config", arg(&path)], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Unknown configuration key http_adr"), "{}", stderr(&output));
+
+    std::fs::write(&path, "[usage]\ncapacity = \"lots\"\n").unwrap();
+    let output = run(&["check-config", "--config", arg(&path)], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("line 2, column 12"), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+
+    let output = run(&["check-config"], &[("ULC_WS_CONNECTION_LIMITS__MAX_TOTAL", "many")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("ULC_WS_CONNECTION_LIMITS__MAX_TOTAL"), "{}", stderr(&output));
+
+    let output = run(&["check-config", "--log-level", "loud"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = run(&["check-config", "--config", arg(&dir.join("missing.toml"))], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // Unknown flags are refused by the parser itself
+    let output = run(&["check-config", "--htp-addr", "0.0.0.0:1"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_check_config_warnings_and_strict() {
+    let weak = "a".repeat(40);
+    let env = [("ULC_ENABLE_AUTH", "true"), ("ULC_JWT_SECRET", weak.as_str())];
+    let output = run(&["check-config"], &env);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("warning: jwt_secret: repeats too few characters"), "{}", stderr(&output));
+
+    let output = run(&["check-config", "--strict"], &env);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("--strict"), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+
+    // Errors stop startup with or without --strict, naming the setting and a fix
+    let output = run(&["check-config"], &[("ULC_ENABLE_AUTH", "true")]);
+    assert_eq!(output.status.code(), Some(1));
+    let error = stderr(&output);
+    assert!(error.contains("jwt_secret: is the development default"), "{error}");
+    assert!(error.contains("openssl rand"), "{error}");
+    assert!(!error.contains("dev-secret-change-in-production"), "{error}");
+
+    let output = run(&["serve", "--http-addr", "0.0.0.0:8080", "--ws-addr", "0.0.0.0:8080"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("ws_addr: uses port 8080"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_generate_token() {
+    let output = run(
+        &["generate-token", "--subject", "ops", "--scope", "admin", "--scope", "documents"],
+        &[("ULC_JWT_SECRET", "s3cret")],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let token = stdout(&output);
+    assert_eq!(token.lines().count(), 1);
+    assert!(!stderr(&output).contains(token.trim()));
+
+    let claims = claims(token.trim(), "s3cret");
+    assert_eq!(claims.sub, "ops");
+    assert_eq!(claims.scopes, ["admin", "documents"]);
+
+    let output = run(&["generate-token"], &[("ULC_JWT_SECRET", "")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).is_empty());
+}
+
+#[tokio::test]
+async fn test_serve() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = format!("127.0.0.1:{port}");
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(BIN))
+        .env_clear()
+        .args(["serve", "--no-lsp", "--no-websocket", "--http-addr", &addr])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut healthy = false;
+    for _ in 0..100 {
+        if let Ok(response) = reqwest::get(format!("http://{addr}/healthz")).await {
+            healthy = response.status().is_success();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(healthy, "the server never answered on {addr}");
+
+    // Serving is the default, and refuses a broken configuration
+    let output = run(&["--config", "/nonexistent/server.toml"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("/nonexistent/server.toml"), "{}", stderr(&output));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_serve_socket_activated() {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::os::unix::process::CommandExt;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let activated = listener.local_addr().unwrap();
+    let configured = format!("127.0.0.1:{}", free_port());
+    let dir = std::env::temp_dir().join(format!("ulc-cli-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let notify_path = dir.join("notify");
+    let notifications = UnixDatagram::bind(&notify_path).unwrap();
+    notifications.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+    // As systemd does: the socket as descriptor 3, and LISTEN_PID naming the
+    // server, which the shell becomes through exec
+    let fd = listener.as_raw_fd();
+    let mut command = std::process::Command::new("sh");
+    command
+        .env_clear()
+        .env("LISTEN_FDS", "1")
+        .env("LISTEN_FDNAMES", "http")
+        .env("NOTIFY_SOCKET", &notify_path)
+        .args(["-c", "LISTEN_PID=$$ exec \"$0\" \"$@\""])
+        .arg(assert_cmd::cargo::cargo_bin(BIN))
+        .args(["serve", "--no-lsp", "--no-websocket", "--http-addr", &configured])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, 3) == -1 || libc::fcntl(3, libc::F_SETFD, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+
+    let mut buffer = [0; 64];
+    let received = notifications.recv(&mut buffer).map(|len| String::from_utf8_lossy(&buffer[..len]).to_string());
+    let healthy = reqwest::get(format!("http://{activated}/healthz")).await.is_ok_and(|r| r.status().is_success());
+    let bound = reqwest::get(format!("http://{configured}/healthz")).await.is_ok();
+    // SAFETY: signalling a child that has not been waited for
+    unsafe { libc::kill(i32::try_from(child.id()).unwrap(), libc::SIGINT) };
+    let stopping = notifications.recv(&mut buffer).map(|len| String::from_utf8_lossy(&buffer[..len]).to_string());
+    let status = child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(received.unwrap(), "READY=1");
+    assert!(healthy, "the server never answered on the passed socket");
+    assert!(!bound, "the server bound {configured} as well");
+    assert_eq!(stopping.unwrap(), "STOPPING=1");
+    assert!(status.success(), "{status}");
+}
+
+/// A port nothing listens on
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Status of validating a markdown `content` of `len` bytes through the server at `addr`
+async fn validate(addr: &str, len: usize) -> Option<u16> {
+    let body = serde_json::json!({ "content": "#".repeat(len), "format": "markdown" });
+    let response = reqwest::Client::new().post(format!("http://{addr}/api/validate")).json(&body).send().await;
+    response.ok().map(|response| response.status().as_u16())
+}
+
+/// Wait up to five seconds for validating `len` bytes to give `status`
+async fn wait_for(addr: &str, len: usize, status: u16) -> bool {
+    for _ in 0..100 {
+        if validate(addr, len).await == Some(status) {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_serve_reloads_configuration() {
+    let addr = format!("127.0.0.1:{}", free_port());
+    let moved = format!("127.0.0.1:{}", free_port());
+    let settings = |http_addr: &str, max_input_bytes: usize| {
+        format!("http_addr = \"{http_addr}\"\n\n[format_limits]\nmax_input_bytes = {max_input_bytes}\n")
+    };
+    let (dir, path) = config_file("server.toml", &settings(&addr, 1_000_000));
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin(BIN))
+        .env_clear()
+        .env("CONFIG_WATCH_INTERVAL_SECS", "1")
+        .args(["serve", "--no-lsp", "--no-websocket", "--watch-config", "--config", arg(&path)])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert!(wait_for(&addr, 64, 200).await, "the server never answered on {addr}");
+
+    // On SIGHUP the new limit applies, while the new address waits for a restart
+    std::fs::write(&path, settings(&moved, 32)).unwrap();
+    let hangup = std::process::Command::new("kill").args(["-HUP", &child.id().to_string()]).status();
+    assert!(hangup.unwrap().success());
+    let limited = wait_for(&addr, 64, 500).await;
+    let metrics = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+    let moved_answers = validate(&moved, 8).await.is_some();
+
+    // The watcher picks up the next edit by itself
+    std::fs::write(&path, settings(&moved, 1_000_000)).unwrap();
+    let unlimited = wait_for(&addr, 64, 200).await;
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+
+    assert!(limited, "the new format limit was never applied");
+    assert!(!moved_answers, "the server moved to {moved} without a restart");
+    let rejected = metrics
+        .lines()
+        .find(|line| line.starts_with("ulc_config_changes_total") && line.contains("\"http_addr\""))
+        .unwrap_or_default();
+    assert!(rejected.contains("rejected"), "{metrics}");
+    assert!(unlimited, "the edited file was never reloaded");
+}
+
+fn oneshot_fixture(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/oneshot").join(name).display().to_string()
+}
+
+#[test]
+fn test_convert_file_to_stdout() {
+    let output = run(&["convert", "--to", "html", &oneshot_fixture("notes.md")], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("<h1>Notes</h1>"), "{}", stdout(&output));
+}
+
+#[test]
+fn test_convert_stdin_with_layout_options() {
+    let convert = |args: &[&str]| {
+        Command::cargo_bin(BIN)
+            .unwrap()
+            .env_clear()
+            .args(["convert", "--from", "json", "--to", "json"])
+            .args(args)
+            .write_stdin(r#"{"b": [1, 2], "a": {"c": null}}"#)
+            .output()
+            .unwrap()
+    };
+    let canonical = convert(&["--canonical"]);
+    assert_eq!(canonical.status.code(), Some(0), "{}", stderr(&canonical));
+    assert_eq!(stdout(&canonical), "{\"a\":{\"c\":null},\"b\":[1,2]}\n");
+
+    let indented = convert(&["--indent", "4", "-"]);
+    assert_eq!(stdout(&indented), "{\n    \"b\": [\n        1,\n        2\n    ],\n    \"a\": {\n        \"c\": null\n    }\n}\n");
+
+    // Layout options only make sense for JSON output
+    let output = run(&["convert", "--to", "html", "--sort-keys", &oneshot_fixture("notes.md")], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("JSON output"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_convert_yaml_streams() {
+    let convert = |args: &[&str], input: &str| {
+        Command::cargo_bin(BIN).unwrap().env_clear().arg("convert").args(args).write_stdin(input.to_string()).output().unwrap()
+    };
+    let manifests = "kind: Service\n---\nkind: Deployment\n";
+
+    let single = convert(&["--from", "yaml", "--to", "json"], manifests);
+    assert_eq!(single.status.code(), Some(2));
+    assert!(stderr(&single).contains("convert it as a stream"), "{}", stderr(&single));
+
+    let ndjson = convert(&["--from", "yaml", "--to", "json", "--stream", "ndjson"], manifests);
+    assert_eq!(stdout(&ndjson), "{\"kind\":\"Service\"}\n{\"kind\":\"Deployment\"}\n");
+    let array = convert(&["--from", "yaml", "--to", "json", "--stream", "array", "--indent", "0"], manifests);
+    assert_eq!(stdout(&array), "[{\"kind\":\"Service\"},{\"kind\":\"Deployment\"}]\n");
+    let back = convert(&["--from", "json", "--to", "yaml", "--stream", "array"], &stdout(&array));
+    assert_eq!(stdout(&back), manifests);
+
+    let html = convert(&["--from", "yaml", "--to", "html", "--stream", "array"], manifests);
+    assert_eq!(html.status.code(), Some(2));
+    assert!(stderr(&html).contains("YAML to JSON"), "{}", stderr(&html));
+}
+
+#[test]
+fn test_convert_yaml_references() {
+    let convert = |args: &[&str], input: &str| {
+        Command::cargo_bin(BIN).unwrap().env_clear().arg("convert").args(args).write_stdin(input.to_string()).output().unwrap()
+    };
+    let yaml = "base: &base\n  retries: 3\njob:\n  <<: *base\n";
+
+    let symbolic = convert(&["--from", "yaml", "--to", "json", "--references", "symbolic", "--indent", "0"], yaml);
+    assert_eq!(stdout(&symbolic), "{\"base\":{\"retries\":3},\"job\":{\"<<\":{\"$ref\":\"#/base\"}}}\n");
+    let back = convert(&["--from", "json", "--to", "yaml", "--references", "symbolic"], &stdout(&symbolic));
+    assert_eq!(stdout(&back), yaml);
+
+    let cycle = convert(&["--from", "yaml", "--to", "json"], "a: &a [*a]\n");
+    assert_eq!(cycle.status.code(), Some(2));
+    assert!(stderr(&cycle).contains("alias *a lies within the node anchored &a"), "{}", stderr(&cycle));
+}
+
+#[test]
+fn test_convert_datetimes() {
+    let convert = |args: &[&str], input: &str| {
+        Command::cargo_bin(BIN).unwrap().env_clear().arg("convert").args(args).write_stdin(input.to_string()).output().unwrap()
+    };
+    let toml = "at = 2024-01-31 13:00:00+01:00\non = 2024-01-31\n";
+    let json = |style: &str| stdout(&convert(&["--from", "toml", "--to", "json", "--indent", "0", "--datetimes", style], toml));
+    assert_eq!(json("rfc3339"), "{\"at\":\"2024-01-31T13:00:00+01:00\",\"on\":\"2024-01-31\"}\n");
+    assert_eq!(json("epoch"), "{\"at\":1706702400,\"on\":\"2024-01-31\"}\n");
+    assert_eq!(json("preserve"), "{\"at\":\"2024-01-31 13:00:00+01:00\",\"on\":\"2024-01-31\"}\n");
+    // As they were read, unless asked otherwise
+    assert_eq!(stdout(&convert(&["--from", "toml", "--to", "json", "--indent", "0"], toml)), json("preserve"));
+
+    let refused = convert(&["--from", "yaml", "--to", "json", "--stream", "array", "--datetimes", "epoch"], "a: 1\n");
+    assert_eq!(refused.status.code(), Some(2));
+    assert!(stderr(&refused).contains("--datetimes does not combine with --stream or --references"), "{}", stderr(&refused));
+}
+
+#[test]
+fn test_ndjson_streams_through_files() {
+    let (dir, log) = config_file("events.jsonl", "{\"id\":1}\n\n{\"id\":2}\n");
+    let array = dir.join("events.json");
+
+    let output = run(&["convert", "--to", "json", "-o", arg(&array), arg(&log)], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(&array).unwrap(), "[\n{\"id\":1},\n{\"id\":2}\n]\n");
+    let output = run(&["convert", "--to", "ndjson", arg(&array)], &[]);
+    assert_eq!(stdout(&output), "{\"id\":1}\n{\"id\":2}\n");
+
+    std::fs::write(&log, "{\"id\":1}\n{broken\n").unwrap();
+    let output = run(&["validate", arg(&log)], &[]);
+    let converted = run(&["convert", "--to", "json", arg(&log)], &[]);
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Invalid NDJSON: line 2"), "{}", stdout(&output));
+    assert_eq!(converted.status.code(), Some(2));
+    assert!(stderr(&converted).contains("line 2"), "{}", stderr(&converted));
+}
+
+#[test]
+fn test_msgpack_files_are_bytes() {
+    let (dir, json) = config_file("service.json", r#"{"name": "api", "port": 8080}"#);
+    let packed = dir.join("service.msgpack");
+
+    let output = run(&["convert", "--to", "msgpack", "-o", arg(&packed), arg(&json)], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = std::fs::read(&packed).unwrap();
+    // A map of two entries, keys sorted, the port as a 16-bit integer
+    assert_eq!(bytes[..6], [0x82, 0xa4, b'n', b'a', b'm', b'e']);
+    assert_eq!(bytes[bytes.len() - 3..], [0xcd, 0x1f, 0x90]);
+
+    let output = run(&["convert", "--to", "yaml", arg(&packed)], &[]);
+    assert_eq!(stdout(&output), "name: api\nport: 8080\n");
+
+    std::fs::write(&packed, &bytes[..bytes.len() - 1]).unwrap();
+    let output = run(&["validate", arg(&packed)], &[]);
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Invalid MessagePack: byte"), "{}", stdout(&output));
+}
+
+#[test]
+fn test_cbor_files_are_bytes() {
+    let (dir, yaml) = config_file("reading.yaml", "sensor: t1\nvalues: [1, 2]\n");
+    let packed = dir.join("reading.cbor");
+
+    let output = run(&["convert", "--to", "cbor", "-o", arg(&packed), arg(&yaml)], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = std::fs::read(&packed).unwrap();
+    // A map of two entries, its last value the array [1, 2]
+    assert_eq!(bytes[0], 0xa2);
+    assert_eq!(bytes[bytes.len() - 3..], [0x82, 0x01, 0x02]);
+
+    let output = run(&["convert", "--to", "json", "--canonical", arg(&packed)], &[]);
+    assert_eq!(stdout(&output), "{\"sensor\":\"t1\",\"values\":[1,2]}\n");
+
+    std::fs::write(&packed, [&bytes[..], &[0x00]].concat()).unwrap();
+    let output = run(&["validate", arg(&packed)], &[]);
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Invalid CBOR: byte"), "{}", stdout(&output));
+}
+
+#[test]
+fn test_plist_files_are_xml() {
+    let (dir, json) = config_file("Info.json", "{\"CFBundleVersion\": 42, \"UIBackgroundModes\": [\"audio\"]}");
+    let plist = dir.join("Info.plist");
+
+    let output = run(&["convert", "--to", "plist", "-o", arg(&plist), arg(&json)], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let xml = std::fs::read_to_string(&plist).unwrap();
+    assert!(xml.contains("<key>CFBundleVersion</key>\n\t<integer>42</integer>"), "{xml}");
+
+    let output = run(&["convert", "--to", "json", "--canonical", arg(&plist)], &[]);
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(stdout(&output), "{\"CFBundleVersion\":42,\"UIBackgroundModes\":[\"audio\"]}\n");
+}
+
+#[test]
+fn test_dotenv_files_by_name() {
+    let (dir, env) = config_file(".env.local", "export PORT=8080\nNAME='my app'\nPORT=9090\n");
+
+    let output = run(&["convert", "--to", "json", "--canonical", arg(&env)], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "{\"NAME\":\"my app\",\"PORT\":\"9090\"}\n");
+
+    let output = run(&["validate", arg(&env)], &[]);
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("duplicate key `PORT`"), "{}", stdout(&output));
+}
+
+#[test]
+fn test_convert_glob_into_directory() {
+    let (dir, _) = config_file("placeholder", "");
+    let out = dir.join("out");
+    let pattern = oneshot_fixture("*.md");
+
+    // Several inputs need a directory to write to
+    let output = run(&["convert", "--to", "html", &pattern], &[]);
+    assert_eq!(output.status.code(), Some(2));
+
+    std::fs::create_dir(&out).unwrap();
+    let output = run(&["convert", "--to", "html", "-o", arg(&out), &pattern], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let html = std::fs::read_to_string(out.join("notes.html")).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(html.contains("<em>emphasis</em>"), "{html}");
+}
+
+#[test]
+fn test_convert_errors_exit_with_2() {
+    let output = run(&["convert", "--to", "html", &oneshot_fixture("invalid.json")], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("invalid.json"), "{}", stderr(&output));
+
+    let output = run(&["convert", "--to", "html", &oneshot_fixture("*.nothing")], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("no files match"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_validate_exit_codes() {
+    let output = run(&["validate", &oneshot_fixture("valid.json"), &oneshot_fixture("notes.md")], &[]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output).matches(": ok").count(), 2, "{}", stdout(&output));
+
+    let output = run(&["validate", &oneshot_fixture("valid.json"), &oneshot_fixture("tabs.yaml")], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("tabs.yaml: Invalid YAML: line 2, column 1: "), "{}", stdout(&output));
+
+    // A file that cannot be read outweighs findings in the others
+    let missing = oneshot_fixture("missing.json");
+    let output = run(&["validate", &oneshot_fixture("tabs.yaml"), &missing], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).contains(&format!("{missing}: error:")), "{}", stdout(&output));
+}
+
+#[test]
+fn test_validate_reports() {
+    let pattern = oneshot_fixture("*.json");
+    let output = run(&["validate", "--format", "json", &pattern], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    let reports: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let statuses: Vec<(&str, &str)> = reports
+        .iter()
+        .map(|report| (report["path"].as_str().unwrap().rsplit('/').next().unwrap(), report["status"].as_str().unwrap()))
+        .collect();
+    assert_eq!(statuses, [("invalid.json", "findings"), ("valid.json", "ok")]);
+
+    let output = run(&["validate", "--format", "github", &oneshot_fixture("tabs.yaml")], &[]);
+    let annotation = stdout(&output);
+    assert!(annotation.starts_with("::warning file="), "{annotation}");
+    assert!(annotation.contains("tabs.yaml::Invalid YAML: line 2, column 1: "), "{annotation}");
+
+    // The format of stdin is detected from its content
+    let output = Command::cargo_bin(BIN)
+        .unwrap()
+        .env_clear()
+        .args(["validate", "--format", "github"])
+        .write_stdin("{\"unterminated\": ")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).starts_with("::warning::Invalid JSON"), "{}", stdout(&output));
+}
@@ -0,0 +1,285 @@
+//! Client library tests
+//!
+//! Every [`UlcClient`] method is called against a real server started with
+//! [`Server::run`] on ephemeral ports, so these double as end-to-end tests
+//! of the HTTP and WebSocket APIs.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use universal_connector_server::client::{ClientError, SocketOptions, Subscription, UlcClient, WsMessage};
+use universal_connector_server::scheduler::{Schedule, TaskSpec};
+use universal_connector_server::{Server, ServerConfig, ServerHandle, ServerState};
+
+const SECRET: &str = "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY";
+
+/// How long a notification may take to arrive
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn start(auth: bool) -> (Arc<ServerState>, ServerHandle, UlcClient) {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .auth(|builder| builder.enabled(auth).secret(SECRET))
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    let client = UlcClient::new(format!("http://{}", server.http_addr().unwrap()))
+        .unwrap()
+        .with_websocket_url(format!("ws://{}", server.ws_addr().unwrap()));
+    (state, server, client)
+}
+
+async fn stop(mut server: ServerHandle) {
+    server.shutdown();
+    server.wait().await.unwrap();
+}
+
+fn token(state: &ServerState, scopes: &[&str]) -> String {
+    let scopes = scopes.iter().map(ToString::to_string).collect();
+    state.auth_service.as_ref().unwrap().generate_token("companion".to_string(), scopes).unwrap()
+}
+
+/// Options reconnecting at once, so tests do not wait out the default delay
+fn quick() -> SocketOptions {
+    SocketOptions { reconnect_delay: Duration::from_millis(10), ..SocketOptions::default() }
+}
+
+#[tokio::test]
+async fn test_convert_and_validate() {
+    let (_state, server, client) = start(false).await;
+
+    let converted = client.convert("# Title", "markdown", "html").await.unwrap();
+    assert!(converted.content.contains("<h1>Title</h1>"), "{}", converted.content);
+    assert_eq!((converted.from.as_str(), converted.to.as_str()), ("markdown", "html"));
+
+    let valid = client.validate(r#"{"a": 1}"#, "json").await.unwrap();
+    assert!(valid.valid && valid.diagnostics.is_empty());
+    let invalid = client.validate("{ broken", "json").await.unwrap();
+    assert!(!invalid.valid);
+    assert!(invalid.diagnostics[0].starts_with("Invalid JSON"), "{:?}", invalid.diagnostics);
+
+    let error = client.convert("text", "nonsense", "html").await.unwrap_err();
+    assert!(matches!(&error, ClientError::BadRequest(message) if message.contains("Invalid 'from' format")), "{error}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_documents() {
+    let (state, server, client) = start(false).await;
+    let document = state.documents.upsert("file:///notes/a.md".into(), "# A".into(), "markdown".into());
+
+    let listed = client.documents().await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].uri, "file:///notes/a.md");
+    let fetched = client.document(&document.id).await.unwrap();
+    assert_eq!(fetched.content, "# A");
+    assert_eq!(client.stats().await.unwrap().document_count, 1);
+
+    client.delete_document(&document.id).await.unwrap();
+    assert!(client.documents().await.unwrap().is_empty());
+    let error = client.document(&document.id).await.unwrap_err();
+    assert!(matches!(error, ClientError::NotFound(_)), "{error}");
+    assert_eq!(error.status(), Some(404));
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_server_information() {
+    let (_state, server, client) = start(false).await;
+
+    let version = client.version().await.unwrap();
+    assert_eq!(version.build.version, env!("CARGO_PKG_VERSION"));
+    assert!(client.capabilities().await.unwrap().get("formats").is_some());
+    assert!(client.health().await.unwrap().lifecycle.ready);
+    assert!(client.ready().await.unwrap());
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_tasks() {
+    let (state, server, client) = start(false).await;
+    let spec = TaskSpec::new("companion_sync", Schedule::Every(Duration::from_secs(3600)));
+    state.scheduler.register(spec, || async { anyhow::Ok(()) }).unwrap();
+
+    let tasks = client.tasks().await.unwrap();
+    assert!(tasks.iter().any(|task| task.name == "companion_sync"), "{tasks:?}");
+    let started = client.run_task("companion_sync").await.unwrap();
+    assert_eq!(started.unwrap().name, "companion_sync");
+    let error = client.run_task("no such task").await.unwrap_err();
+    assert!(matches!(&error, ClientError::NotFound(message) if message.contains("no such task")), "{error}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_tokens() {
+    let (state, server, client) = start(true).await;
+
+    let error = client.tasks().await.unwrap_err();
+    assert!(matches!(error, ClientError::Unauthorized(_)), "{error}");
+    let error = client.clone().with_token(token(&state, &["read"])).tasks().await.unwrap_err();
+    assert!(matches!(error, ClientError::Forbidden(_)), "{error}");
+    assert!(client.clone().with_token(token(&state, &["admin"])).tasks().await.is_ok());
+
+    // The first token is refused, so the request is retried with the next one
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let refreshing = {
+        let (state, fetched) = (Arc::clone(&state), Arc::clone(&fetched));
+        client.clone().with_token_refresh(move || {
+            let token = match fetched.fetch_add(1, Ordering::SeqCst) {
+                0 => "expired".to_string(),
+                _ => token(&state, &["admin"]),
+            };
+            async move { Ok(token) }
+        })
+    };
+    assert!(refreshing.tasks().await.is_ok());
+    assert!(refreshing.tasks().await.is_ok());
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+    // The handshake refuses an invalid token the same way
+    let refreshing = client.clone().with_token("expired").with_token_refresh({
+        let state = Arc::clone(&state);
+        move || {
+            let token = token(&state, &["read"]);
+            async move { Ok(token) }
+        }
+    });
+    let socket = refreshing.websocket().await.unwrap();
+    assert_ne!(refreshing.token().as_deref(), Some("expired"));
+    socket.close().await.unwrap();
+    let error = client.clone().with_token("expired").websocket().await.unwrap_err();
+    assert!(matches!(error, ClientError::Unauthorized(_)), "{error}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_api_keys_until_revoked() {
+    let (state, server, client) = start(true).await;
+    let store = state.auth_service.as_ref().unwrap().api_keys().unwrap();
+    let (record, key) = store.create("ci".into(), "ci-bot".into(), vec!["admin".into()], None).unwrap();
+
+    // Over HTTP and WebSocket alike
+    let keyed = client.clone().with_token(key);
+    assert!(keyed.tasks().await.is_ok());
+    keyed.websocket().await.unwrap().close().await.unwrap();
+    assert!(store.get(&record.id).unwrap().unwrap().last_used_at.is_some());
+
+    store.revoke(&record.id).unwrap();
+    let error = keyed.tasks().await.unwrap_err();
+    assert!(matches!(&error, ClientError::Unauthorized(message) if message.contains("revoked")), "{error}");
+    let error = keyed.websocket().await.unwrap_err();
+    assert!(matches!(error, ClientError::Unauthorized(_)), "{error}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_refresh_tokens_rotate() {
+    let (state, server, client) = start(true).await;
+    let auth = state.auth_service.as_ref().unwrap();
+    let issued = auth.issue_refresh_token("plugin".into(), vec!["admin".into()], Some("vscode".into())).unwrap();
+
+    // A plugin keeps the refresh token returned with each access token
+    let held = Arc::new(std::sync::Mutex::new(issued.refresh_token.clone()));
+    let plugin = {
+        let (client, held) = (client.clone(), Arc::clone(&held));
+        client.clone().with_token_refresh(move || {
+            let (client, held) = (client.clone(), Arc::clone(&held));
+            async move {
+                let current = held.lock().unwrap().clone();
+                let pair = client.refresh(&current).await?;
+                *held.lock().unwrap() = pair.refresh_token;
+                Ok(pair.access_token)
+            }
+        })
+    };
+    assert!(plugin.tasks().await.is_ok());
+    assert_ne!(*held.lock().unwrap(), issued.refresh_token);
+
+    // The spent token, replayed by a thief, revokes the plugin's too
+    let error = client.refresh(&issued.refresh_token).await.unwrap_err();
+    assert!(matches!(&error, ClientError::Unauthorized(message) if message.contains("already used")), "{error}");
+    // The thief's failure makes its IP, here the plugin's too, wait a second before trying again
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let current = held.lock().unwrap().clone();
+    let error = client.refresh(&current).await.unwrap_err();
+    assert!(matches!(&error, ClientError::Unauthorized(message) if message.contains("revoked")), "{error}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_websocket_subscriptions() {
+    let (state, server, client) = start(false).await;
+    let mut socket = client.websocket().await.unwrap();
+    assert!(socket.negotiated().capabilities.contains(&universal_connector_server::Capability::ResumableSessions));
+
+    socket.subscribe_pattern("file:///notes/*", false).await.unwrap();
+    // A round trip, so the subscription is in place before the update
+    socket.send(&WsMessage::GetMetrics).await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::Metrics { .. })));
+
+    let document = state.documents.upsert("file:///notes/b.md".into(), "# B".into(), "markdown".into());
+    state.documents.upsert("file:///elsewhere/c.md".into(), "# C".into(), "markdown".into());
+    match socket.next_within(WAIT).await.unwrap() {
+        Some(WsMessage::DocumentUpdated { document_id, content, .. }) => {
+            assert_eq!((document_id.as_str(), content.as_str()), (document.id.as_str(), "# B"));
+        }
+        other => panic!("expected an update, got {other:?}"),
+    }
+    client.delete_document(&document.id).await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::DocumentRemoved { .. })));
+
+    socket.unfollow(Subscription::Pattern("file:///notes/*".into())).await.unwrap();
+    socket.send(&WsMessage::GetMetrics).await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::Metrics { .. })));
+    state.documents.upsert("file:///notes/d.md".into(), "# D".into(), "markdown".into());
+    assert!(socket.next_within(Duration::from_millis(200)).await.unwrap().is_none());
+
+    // Acknowledged subscriptions need a session
+    assert!(socket.subscribe("anything", true).await.is_err());
+    socket.close().await.unwrap();
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_websocket_resumes_its_session() {
+    let (state, server, client) = start(false).await;
+    let mut socket = client.websocket_with(quick()).await.unwrap();
+    let session_id = socket.open_session().await.unwrap();
+    socket.subscribe_pattern("file:///notes/*", true).await.unwrap();
+    socket.send(&WsMessage::GetMetrics).await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::Metrics { .. })));
+
+    // Sent before the reconnect but never read, so never acknowledged
+    state.documents.upsert("file:///notes/e.md".into(), "# E".into(), "markdown".into());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    socket.reconnect().await.unwrap();
+    assert_eq!(socket.session_id(), Some(session_id.as_str()));
+
+    let redelivered = socket.next_within(WAIT).await.unwrap();
+    assert!(matches!(&redelivered, Some(WsMessage::DocumentUpdated { uri, .. }) if uri == "file:///notes/e.md"));
+    // Delivered once, and the subscription came along with the session
+    state.documents.upsert("file:///notes/f.md".into(), "# F".into(), "markdown".into());
+    let next = socket.next_within(WAIT).await.unwrap();
+    assert!(matches!(&next, Some(WsMessage::DocumentUpdated { uri, .. }) if uri == "file:///notes/f.md"), "{next:?}");
+    socket.close().await.unwrap();
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_websocket_without_a_session_resubscribes() {
+    let (state, server, client) = start(false).await;
+    let mut socket = client.websocket_with(quick()).await.unwrap();
+    socket.subscribe_pattern("file:///notes/*", false).await.unwrap();
+
+    socket.reconnect().await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::ResyncRequired { .. })));
+    socket.send(&WsMessage::GetMetrics).await.unwrap();
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::Metrics { .. })));
+    state.documents.upsert("file:///notes/g.md".into(), "# G".into(), "markdown".into());
+    assert!(matches!(socket.next_within(WAIT).await.unwrap(), Some(WsMessage::DocumentUpdated { .. })));
+    socket.close().await.unwrap();
+    stop(server).await;
+}
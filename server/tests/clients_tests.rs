@@ -0,0 +1,251 @@
+//! Client registry integration tests
+//!
+//! Clients connect over LSP, WebSocket and HTTP and must be listed with what
+//! they said about themselves until they disconnect. LSP clients must get
+//! completions and positions suited to the capabilities they declared.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionTextEdit, Diagnostic, InsertTextFormat, Position, Range, TextEdit,
+};
+use universal_connector_server::clients::ClientRecord;
+use universal_connector_server::document_store::Document;
+use universal_connector_server::lsp::{self, read_message, write_message};
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::{
+    http, websocket, LanguageProvider, ProviderOrder, Server, ServerConfig, ServerHandle, ServerState,
+};
+
+const URI: &str = "file:///project/notes.emoji";
+
+/// Flags `target` on the first line and completes it, once as a snippet
+struct Targets;
+
+#[tower_lsp::async_trait]
+impl LanguageProvider for Targets {
+    fn name(&self) -> &str {
+        "targets"
+    }
+
+    fn languages(&self) -> &[&str] {
+        &["emoji"]
+    }
+
+    async fn diagnostics(&self, document: &Document) -> Vec<Diagnostic> {
+        let first = document.content.lines().next().unwrap_or_default();
+        let Some(start) = first.find("target") else {
+            return Vec::new();
+        };
+        let start = u32::try_from(first[..start].encode_utf16().count()).unwrap();
+        let range = Range::new(Position::new(0, start), Position::new(0, start + 6));
+        vec![Diagnostic::new_simple(range, "target".to_string())]
+    }
+
+    async fn completion(&self, _document: &Document, position: Position) -> Vec<CompletionItem> {
+        let at = Range::new(position, position);
+        let plain = CompletionItem {
+            label: "plain".to_string(),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(at, "plain".to_string()))),
+            ..CompletionItem::default()
+        };
+        let snippet = CompletionItem {
+            label: "snippet".to_string(),
+            insert_text: Some("snippet($1)".to_string()),
+            insert_text_format: Some(InsertTextFormat::SNIPPET),
+            ..CompletionItem::default()
+        };
+        vec![plain, snippet]
+    }
+}
+
+/// Serve HTTP and WebSocket on ephemeral ports
+async fn start_server() -> (Arc<ServerState>, ServerHandle) {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    state.providers.register(Arc::new(Targets), ProviderOrder::BeforeBuiltIn).unwrap();
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    (state, server)
+}
+
+/// The editor's end of an LSP connection
+struct Editor {
+    input: BufReader<ReadHalf<DuplexStream>>,
+    output: WriteHalf<DuplexStream>,
+    next_id: i64,
+    served: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Editor {
+    /// Connect to `state` over an in-memory LSP connection counted as TCP
+    fn connect(state: &Arc<ServerState>) -> Self {
+        let (editor, server) = tokio::io::duplex(64 * 1024);
+        let (input, output) = tokio::io::split(server);
+        let served = tokio::spawn(lsp::serve_lsp(Arc::clone(state), input, output, Transport::LspTcp));
+        let (input, output) = tokio::io::split(editor);
+        Self { input: BufReader::new(input), output, next_id: 1, served }
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        write_message(&mut self.output, &message).await.unwrap();
+    }
+
+    /// Send a request and read until its result, skipping other messages
+    async fn request(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        write_message(&mut self.output, &message).await.unwrap();
+        loop {
+            let message = read_message(&mut self.input).await.unwrap().expect("server closed the connection");
+            if message["id"] == json!(id) {
+                return message["result"].clone();
+            }
+        }
+    }
+
+    /// Open the test document and read its diagnostics
+    async fn open(&mut self, text: &str) -> Vec<Value> {
+        let document = json!({ "uri": URI, "languageId": "emoji", "version": 1, "text": text });
+        self.notify("textDocument/didOpen", json!({ "textDocument": document })).await;
+        loop {
+            let message = read_message(&mut self.input).await.unwrap().expect("server closed the connection");
+            if message["method"] == "textDocument/publishDiagnostics" && message["params"]["uri"] == URI {
+                return message["params"]["diagnostics"].as_array().unwrap().clone();
+            }
+        }
+    }
+
+    async fn disconnect(self) {
+        drop((self.input, self.output));
+        self.served.await.unwrap().unwrap();
+    }
+}
+
+/// `GET /api/admin/clients`
+async fn list_clients(state: &Arc<ServerState>) -> Vec<ClientRecord> {
+    let response = http::create_router(Arc::clone(state))
+        .oneshot(Request::builder().uri("/api/admin/clients").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Wait until `count` clients are registered
+async fn settle(state: &ServerState, count: usize) {
+    for _ in 0..100 {
+        if state.clients.len() == count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} clients registered, never {}", state.clients.len(), count);
+}
+
+#[tokio::test]
+async fn test_clients_listed_until_they_disconnect() {
+    let (state, server) = start_server().await;
+
+    let mut editor = Editor::connect(&state);
+    let initialize = json!({
+        "capabilities": { "general": { "positionEncodings": ["utf-8", "utf-16"] } },
+        "clientInfo": { "name": "test-editor", "version": "1.2" },
+    });
+    let initialized = editor.request("initialize", initialize).await;
+    assert_eq!(initialized["capabilities"]["positionEncoding"], "utf-8");
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", server.ws_addr().unwrap())).await.unwrap();
+    let hello = json!({
+        "type": "Hello",
+        "protocol_version": websocket::PROTOCOL_VERSION,
+        "capabilities": ["collab", "telepathy"],
+        "client": { "name": "test-dashboard" },
+    });
+    ws.send(Message::Text(hello.to_string())).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    let mut http = TcpStream::connect(server.http_addr().unwrap()).await.unwrap();
+    http.write_all(b"GET /healthz HTTP/1.1\r\nhost: localhost\r\n\r\n").await.unwrap();
+    let mut buf = [0; 1024];
+    assert!(http.read(&mut buf).await.unwrap() > 0);
+
+    settle(&state, 3).await;
+    let clients = list_clients(&state).await;
+    let by_transport = |transport: &str| clients.iter().find(|c| c.transport == transport).unwrap().clone();
+    let lsp = by_transport("lsp_tcp");
+    assert_eq!(lsp.name.as_deref(), Some("test-editor"));
+    assert_eq!(lsp.version.as_deref(), Some("1.2"));
+    assert_eq!(lsp.capabilities["general"]["positionEncodings"], json!(["utf-8", "utf-16"]));
+    assert_eq!(serde_json::to_value(lsp.position_encoding).unwrap(), "utf-8");
+    assert_eq!(lsp.subject, None);
+    let ws_client = by_transport("websocket");
+    assert_eq!(ws_client.name.as_deref(), Some("test-dashboard"));
+    assert_eq!(ws_client.version, None);
+    assert_eq!(ws_client.capabilities, json!(["collab"]));
+    assert!(ws_client.connected_at <= ws_client.last_active);
+    let http_client = by_transport("http");
+    assert_eq!(http_client.name, None);
+    assert_ne!(lsp.id, ws_client.id);
+
+    editor.disconnect().await;
+    settle(&state, 2).await;
+    assert!(list_clients(&state).await.iter().all(|c| c.transport != "lsp_tcp"));
+    ws.close(None).await.unwrap();
+    drop(http);
+    settle(&state, 0).await;
+    assert!(list_clients(&state).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_completions_and_positions_follow_client_capabilities() {
+    let (state, _server) = start_server().await;
+    // "😀" is four bytes of UTF-8 and two UTF-16 code units
+    let text = "😀 target";
+
+    let mut plain = Editor::connect(&state);
+    plain.request("initialize", json!({ "capabilities": {} })).await;
+    let diagnostics = plain.open(text).await;
+    assert_eq!(diagnostics[0]["range"]["start"]["character"], 3);
+    let at = json!({ "textDocument": { "uri": URI }, "position": { "line": 0, "character": 3 } });
+    let completions = plain.request("textDocument/completion", at).await;
+    let labels: Vec<_> = completions.as_array().unwrap().iter().map(|item| item["label"].clone()).collect();
+    assert!(labels.contains(&json!("plain")), "{labels:?}");
+    assert!(!labels.contains(&json!("snippet")), "{labels:?}");
+    assert_eq!(completions[0]["textEdit"]["range"]["start"]["character"], 3);
+    plain.disconnect().await;
+
+    let mut capable = Editor::connect(&state);
+    let capabilities = json!({
+        "general": { "positionEncodings": ["utf-8"] },
+        "textDocument": { "completion": { "completionItem": { "snippetSupport": true } } },
+    });
+    capable.request("initialize", json!({ "capabilities": capabilities })).await;
+    let diagnostics = capable.open(text).await;
+    assert_eq!(diagnostics[0]["range"]["start"]["character"], 5);
+    assert_eq!(diagnostics[0]["range"]["end"]["character"], 11);
+    let at = json!({ "textDocument": { "uri": URI }, "position": { "line": 0, "character": 5 } });
+    let completions = capable.request("textDocument/completion", at).await;
+    let labels: Vec<_> = completions.as_array().unwrap().iter().map(|item| item["label"].clone()).collect();
+    assert!(labels.contains(&json!("snippet")), "{labels:?}");
+    // The provider saw UTF-16 column 3 and answered in it; the client gets bytes
+    assert_eq!(completions[0]["textEdit"]["range"]["start"]["character"], 5);
+    capable.disconnect().await;
+    assert!(state.clients.is_empty());
+}
@@ -0,0 +1,218 @@
+//! Concurrent editing integration tests
+//!
+//! An editor speaking LSP and a client of the WebSocket protocol edit one
+//! document at the same time. The editor applies the workspace edits it is
+//! sent as an editor would, and both must end with the server's content.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use universal_connector_server::collab::{self, CollabClient, TextOperation};
+use universal_connector_server::lsp::{self, read_message, write_message};
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::{websocket, Server, ServerConfig, ServerState};
+
+const URI: &str = "file:///project/shared.txt";
+
+/// The editor's end of an LSP connection, holding the document as an editor would
+struct Editor {
+    input: BufReader<ReadHalf<DuplexStream>>,
+    output: WriteHalf<DuplexStream>,
+    text: String,
+    version: i32,
+}
+
+impl Editor {
+    async fn start(state: &Arc<ServerState>, text: &str) -> Self {
+        let (editor, server) = tokio::io::duplex(64 * 1024);
+        let (input, output) = tokio::io::split(server);
+        tokio::spawn(lsp::serve_lsp(Arc::clone(state), input, output, Transport::LspStdio));
+        let (input, output) = tokio::io::split(editor);
+        let mut editor = Self {
+            input: BufReader::new(input),
+            output,
+            text: text.to_string(),
+            version: 1,
+        };
+
+        editor.send(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } })).await;
+        while editor.next().await["id"] != 1 {}
+        editor.notify("initialized", json!({})).await;
+        let document = json!({ "uri": URI, "languageId": "plaintext", "version": 1, "text": text });
+        editor.notify("textDocument/didOpen", json!({ "textDocument": document })).await;
+        editor
+    }
+
+    async fn send(&mut self, message: Value) {
+        write_message(&mut self.output, &message).await.unwrap();
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params })).await;
+    }
+
+    async fn next(&mut self) -> Value {
+        read_message(&mut self.input).await.unwrap().expect("server closed the connection")
+    }
+
+    /// Replace the characters `start..end` of the document, telling the server
+    async fn edit(&mut self, start: usize, end: usize, replacement: &str) {
+        let at = |offset| {
+            let (line, character) = collab::offset_to_position(&self.text, offset);
+            json!({ "line": line, "character": character })
+        };
+        let range = json!({ "start": at(start), "end": at(end) });
+        let chars: Vec<char> = self.text.chars().collect();
+        self.text = chars[..start].iter().chain(replacement.chars().collect::<Vec<_>>().iter()).chain(&chars[end..]).collect();
+        self.version += 1;
+        let document = json!({ "uri": URI, "version": self.version });
+        let change = json!({ "range": range, "text": replacement });
+        self.notify("textDocument/didChange", json!({ "textDocument": document, "contentChanges": [change] })).await;
+    }
+
+    /// Answer a message from the server, applying the workspace edits it asks for
+    async fn handle(&mut self, message: Value) {
+        if message["method"] != "workspace/applyEdit" {
+            return;
+        }
+        let change = &message["params"]["edit"]["documentChanges"][0];
+        let applied = change["textDocument"]["version"] == self.version;
+        if applied {
+            let edit = &change["edits"][0];
+            let offset = |at: &Value| {
+                let (line, character) = (at["line"].as_u64().unwrap(), at["character"].as_u64().unwrap());
+                collab::position_to_offset(&self.text, line as u32, character as u32).unwrap()
+            };
+            let (start, end) = (offset(&edit["range"]["start"]), offset(&edit["range"]["end"]));
+            self.edit(start, end, edit["newText"].as_str().unwrap()).await;
+        }
+        self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": { "applied": applied } })).await;
+    }
+}
+
+/// A client of the WebSocket protocol, joined to the document
+struct Collaborator {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    client: CollabClient,
+}
+
+impl Collaborator {
+    async fn join(addr: SocketAddr) -> Self {
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+        let hello = json!({ "type": "Hello", "protocol_version": websocket::PROTOCOL_VERSION, "capabilities": ["collab"] });
+        ws.send(Message::Text(hello.to_string())).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+        ws.send(Message::Text(json!({ "type": "CollabJoin", "uri": URI }).to_string())).await.unwrap();
+        let Some(Ok(Message::Text(reply))) = ws.next().await else { panic!("no reply to CollabJoin") };
+        let snapshot: Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(snapshot["type"], "CollabSnapshot", "{snapshot}");
+        let client = CollabClient::new(snapshot["revision"].as_u64().unwrap(), snapshot["content"].as_str().unwrap().to_string());
+        Self { ws, client }
+    }
+
+    async fn send(&mut self, sent: Option<(u64, TextOperation)>) {
+        if let Some((revision, operation)) = sent {
+            let message = json!({ "type": "CollabOperation", "uri": URI, "revision": revision, "operation": operation });
+            self.ws.send(Message::Text(message.to_string())).await.unwrap();
+        }
+    }
+
+    /// Replace the characters `start..end` of the document, telling the server
+    async fn edit(&mut self, start: usize, end: usize, replacement: &str) {
+        let mut operation = TextOperation::new();
+        operation.retain(start).insert(replacement).delete(end - start).retain(self.client.content.chars().count() - end);
+        let sent = self.client.apply_local(operation).unwrap();
+        self.send(sent).await;
+    }
+
+    async fn handle(&mut self, message: Message) {
+        let Message::Text(text) = message else { return };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        match message["type"].as_str() {
+            Some("CollabAck") => {
+                let sent = self.client.server_ack().unwrap();
+                self.send(sent).await;
+            }
+            Some("CollabApplied") => {
+                let operation = serde_json::from_value(message["operation"].clone()).unwrap();
+                self.client.apply_server(operation).unwrap();
+            }
+            _ => panic!("unexpected message {message}"),
+        }
+    }
+}
+
+/// Deliver messages to both until neither hears anything more, then check all three agree
+async fn settle(state: &ServerState, editor: &mut Editor, collaborators: &mut [Collaborator]) {
+    let quiet = Duration::from_millis(200);
+    let mut heard = true;
+    while heard {
+        heard = false;
+        for collaborator in collaborators.iter_mut() {
+            while let Ok(Some(message)) = tokio::time::timeout(quiet, collaborator.ws.next()).await {
+                heard = true;
+                collaborator.handle(message.unwrap()).await;
+            }
+        }
+        while let Ok(message) = tokio::time::timeout(quiet, editor.next()).await {
+            heard = true;
+            editor.handle(message).await;
+        }
+    }
+
+    let (_, content) = state.collab.snapshot(URI).unwrap();
+    assert_eq!(editor.text, content);
+    for collaborator in collaborators {
+        assert!(collaborator.client.is_synchronized());
+        assert_eq!(collaborator.client.content, content);
+    }
+}
+
+#[tokio::test]
+async fn test_lsp_and_websocket_edits_converge() {
+    let config = ServerConfig::builder()
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_http()
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    let addr = server.ws_addr().unwrap();
+
+    // The editor edits alone until a collaborator joins, which starts concurrent editing
+    let mut editor = Editor::start(&state, "😀 one\ntwo\n").await;
+    editor.edit(2, 5, "first").await;
+    while state.documents.get(URI).is_none_or(|document| document.content != editor.text) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut collaborators = [Collaborator::join(addr).await, Collaborator::join(addr).await];
+    assert_eq!(collaborators[0].client.content, "😀 first\ntwo\n");
+
+    // Each edits without waiting for the others, over and over
+    for round in 0..5 {
+        editor.edit(0, 0, &format!("lsp{round} ")).await;
+        collaborators[0].edit(0, 0, &format!("ws{round} ")).await;
+        let end = collaborators[1].client.content.chars().count();
+        collaborators[1].edit(end - 1, end, &format!(" ws{round}\n")).await;
+        let at = editor.text.find('\n').map(|byte| editor.text[..byte].chars().count()).unwrap();
+        editor.edit(at - 3, at, "").await;
+    }
+    settle(&state, &mut editor, &mut collaborators).await;
+    assert!(editor.text.contains("lsp4 "), "{}", editor.text);
+    assert!(editor.text.contains("ws4 "), "{}", editor.text);
+    assert!(editor.text.ends_with(" ws4\n"), "{}", editor.text);
+
+    // Edits the editor makes to text it has yet to be sent land where it made them
+    collaborators[0].edit(0, 0, "top\n").await;
+    editor.edit(editor.text.chars().count(), editor.text.chars().count(), "bottom").await;
+    settle(&state, &mut editor, &mut collaborators).await;
+    assert!(editor.text.starts_with("top\n"), "{}", editor.text);
+    assert!(editor.text.ends_with("bottom"), "{}", editor.text);
+}
@@ -0,0 +1,133 @@
+//! Connection tracking integration tests
+//!
+//! Clients connect and disconnect over real sockets, some cleanly and some
+//! abruptly, and every open connection gauge must settle back to zero.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::monitoring::ConnectionsSummary;
+use universal_connector_server::{http, lsp, websocket, Server, ServerConfig, ServerHandle, ServerState};
+
+type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+/// Serve every transport but stdio LSP on ephemeral ports
+async fn start_server() -> (Arc<ServerState>, ServerHandle) {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    (state, server)
+}
+
+/// Wait until `transport` has `open` connections
+async fn settle(state: &ServerState, transport: Transport, open: u64) -> ConnectionsSummary {
+    for _ in 0..100 {
+        let summary = state.metrics.connections.summary();
+        if summary.transports[transport.as_str()].open == open {
+            return summary;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} never reached {} open connections", transport.as_str(), open);
+}
+
+async fn connect_ws(addr: std::net::SocketAddr, protocol_version: u32) -> Client {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}")).await.unwrap();
+    let hello = json!({ "type": "Hello", "protocol_version": protocol_version });
+    ws.send(Message::Text(hello.to_string())).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+    ws
+}
+
+#[tokio::test]
+async fn test_websocket_churn_returns_to_zero() {
+    let (state, server) = start_server().await;
+    let addr = server.ws_addr().unwrap();
+
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        clients.push(connect_ws(addr, websocket::PROTOCOL_VERSION).await);
+    }
+    // Accepted over TCP but never upgraded
+    let stalled = TcpStream::connect(addr).await.unwrap();
+    let summary = settle(&state, Transport::WebSocket, 5).await;
+    assert_eq!(summary.transports["websocket"].states["active"], 4);
+    assert_eq!(summary.transports["websocket"].states["handshaking"], 1);
+
+    // Two leave cleanly, two vanish without a close frame
+    for mut ws in clients.drain(..2) {
+        ws.close(None).await.unwrap();
+    }
+    drop(clients);
+    drop(stalled);
+    // Refused at the protocol handshake
+    connect_ws(addr, 0).await;
+
+    let summary = settle(&state, Transport::WebSocket, 0).await;
+    let ws = &summary.transports["websocket"];
+    assert_eq!(ws.connects, 6);
+    assert_eq!(ws.disconnects["closed"], 2);
+    assert_eq!(ws.disconnects["dropped"], 3);
+    assert_eq!(ws.disconnects["refused"], 1);
+    assert!(ws.states.values().all(|open| *open == 0));
+    assert_eq!(summary.authenticated_subjects, 0);
+}
+
+#[tokio::test]
+async fn test_http_connections_and_summary_endpoint() {
+    let (state, server) = start_server().await;
+    let addr = server.http_addr().unwrap();
+
+    let mut kept_alive = TcpStream::connect(addr).await.unwrap();
+    kept_alive
+        .write_all(b"GET /healthz HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0; 1024];
+    assert!(kept_alive.read(&mut buf).await.unwrap() > 0);
+    // Gone halfway through its request headers
+    let mut abandoned = TcpStream::connect(addr).await.unwrap();
+    abandoned.write_all(b"GET /healthz HTTP/1.1\r\n").await.unwrap();
+    settle(&state, Transport::Http, 2).await;
+
+    drop((kept_alive, abandoned));
+    let summary = settle(&state, Transport::Http, 0).await;
+    assert_eq!(summary.transports["http"].connects, 2);
+    assert_eq!(summary.http_requests_in_flight, 0);
+
+    // LSP over a byte stream counts under the transport it is served on
+    let (client, server) = tokio::io::duplex(1024);
+    let (input, output) = tokio::io::split(server);
+    let serving = tokio::spawn(lsp::serve_lsp(Arc::clone(&state), input, output, Transport::LspTcp));
+    settle(&state, Transport::LspTcp, 1).await;
+    drop(client);
+    serving.await.unwrap().unwrap();
+
+    let response = http::create_router(Arc::clone(&state))
+        .oneshot(Request::builder().uri("/api/admin/connections").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let summary: ConnectionsSummary = serde_json::from_slice(&body).unwrap();
+    assert_eq!(summary.transports["lsp_tcp"].open, 0);
+    assert_eq!(summary.transports["lsp_tcp"].disconnects["closed"], 1);
+    assert_eq!(summary.transports["http"].disconnects["closed"], 2);
+    // The summary request itself was in flight
+    assert_eq!(summary.http_requests_in_flight, 1);
+    assert_eq!(summary.ws_sessions_retained, 0);
+    assert_eq!(summary.transports.len(), Transport::ALL.len());
+}
@@ -0,0 +1,417 @@
+;; kv: `key=value` lines, one pair per line, as a flat JSON object
+;;
+;; Example format plugin for the connector's format plugin ABI 1; see
+;; src/formats/wasm.rs. Compile with `wasm-tools parse kv.wat -o kv.wasm`.
+;;
+;; Results pack the output address in the upper 32 bits and its length in
+;; the lower 32. Fixed results are messages in the data segments below:
+;;   CLEAN      = 68719476737 (address 16, 1 byte)
+;;   MISSING    = 73014444066 (address 17, 34 bytes)
+;;   QUOTES     = 219043332137 (address 51, 41 bytes)
+;;   ESCAPES    = 395136991269 (address 92, 37 bytes)
+;;   NESTED     = 554050781228 (address 129, 44 bytes)
+;;   NOPAIR     = 743029342242 (address 173, 34 bytes)
+;;   UNQUOTABLE = 889058230313 (address 207, 41 bytes)
+(module
+  (memory (export "memory") 1)
+  ;; Bump allocator: the next free byte. Each call gets a fresh instance.
+  (global (mut i32) (i32.const 1024))
+  (data (i32.const 16) "\00")
+  (data (i32.const 17) "\00Every line needs a key=value pair")
+  (data (i32.const 51) "\00Quotes and backslashes are not supported")
+  (data (i32.const 92) "\01Escaped characters are not supported")
+  (data (i32.const 129) "\01Only flat JSON objects convert to key=value")
+  (data (i32.const 173) "\01Every line needs a key=value pair")
+  (data (i32.const 207) "\01Quotes and backslashes are not supported")
+
+  (func (export "ulc_abi_version") (result i32)
+    i32.const 1)
+
+  ;; A buffer of `len` bytes, growing memory when needed
+  (func $alloc (export "ulc_alloc") (param i32) (result i32) (local i32)
+    global.get 0
+    local.set 1
+    global.get 0
+    local.get 0
+    i32.add
+    global.set 0
+    block
+      global.get 0
+      memory.size
+      i32.const 16
+      i32.shl
+      i32.le_u
+      br_if 0
+      global.get 0
+      memory.size
+      i32.const 16
+      i32.shl
+      i32.sub
+      i32.const 16
+      i32.shr_u
+      i32.const 1
+      i32.add
+      memory.grow
+      i32.const -1
+      i32.eq
+      if
+        unreachable
+      end
+    end
+    local.get 1)
+
+  ;; Store a byte at `at`, returning the next address
+  (func $put (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.store8
+    local.get 0
+    i32.const 1
+    i32.add)
+
+  ;; Pack the buffer from `start` to `end`
+  (func $pack (param i32 i32) (result i64)
+    local.get 0
+    i64.extend_i32_u
+    i64.const 32
+    i64.shl
+    local.get 1
+    local.get 0
+    i32.sub
+    i64.extend_i32_u
+    i64.or)
+
+  ;; Locals: output, write position, index, byte, in a pair, any pair written, pair has `=`
+  (func (export "ulc_to_canonical") (param i32 i32) (result i64) (local i32 i32 i32 i32 i32 i32 i32)
+    local.get 1
+    i32.const 5
+    i32.mul
+    i32.const 8
+    i32.add
+    call $alloc
+    local.tee 2
+    i32.const 0
+    call $put
+    i32.const 123
+    call $put
+    local.set 3
+    block
+      loop
+        local.get 4
+        local.get 1
+        i32.ge_u
+        br_if 1
+        local.get 0
+        local.get 4
+        i32.add
+        i32.load8_u
+        local.set 5
+        local.get 4
+        i32.const 1
+        i32.add
+        local.set 4
+        block
+          ;; A newline closes the pair
+          local.get 5
+          i32.const 10
+          i32.eq
+          if
+            local.get 6
+            if
+              local.get 8
+              i32.eqz
+              if
+                i64.const 743029342242
+                return
+              end
+              local.get 3
+              i32.const 34
+              call $put
+              local.set 3
+              i32.const 0
+              local.set 6
+              i32.const 0
+              local.set 8
+            end
+            br 1
+          end
+          ;; Carriage returns, and whitespace before a pair, are dropped
+          local.get 5
+          i32.const 13
+          i32.eq
+          br_if 0
+          local.get 6
+          i32.eqz
+          local.get 5
+          i32.const 32
+          i32.le_u
+          i32.and
+          br_if 0
+          local.get 6
+          i32.eqz
+          if
+            local.get 7
+            if
+              local.get 3
+              i32.const 44
+              call $put
+              local.set 3
+            end
+            local.get 3
+            i32.const 34
+            call $put
+            local.set 3
+            i32.const 1
+            local.set 6
+            i32.const 1
+            local.set 7
+          end
+          ;; Nothing in a pair is escaped, so nothing may need escaping
+          local.get 5
+          i32.const 34
+          i32.eq
+          local.get 5
+          i32.const 92
+          i32.eq
+          i32.or
+          if
+            i64.const 889058230313
+            return
+          end
+          ;; The first `=` ends the key; later ones are part of the value
+          local.get 5
+          i32.const 61
+          i32.eq
+          local.get 8
+          i32.eqz
+          i32.and
+          if
+            local.get 3
+            i32.const 34
+            call $put
+            i32.const 58
+            call $put
+            i32.const 34
+            call $put
+            local.set 3
+            i32.const 1
+            local.set 8
+          else
+            local.get 3
+            local.get 5
+            call $put
+            local.set 3
+          end
+        end
+        br 0
+      end
+    end
+    local.get 6
+    if
+      local.get 8
+      i32.eqz
+      if
+        i64.const 743029342242
+        return
+      end
+      local.get 3
+      i32.const 34
+      call $put
+      local.set 3
+    end
+    local.get 3
+    i32.const 125
+    call $put
+    local.set 3
+    local.get 2
+    local.get 3
+    call $pack)
+
+  ;; Locals: output, write position, index, byte, in a string
+  (func (export "ulc_from_canonical") (param i32 i32) (result i64) (local i32 i32 i32 i32 i32)
+    local.get 1
+    i32.const 1
+    i32.add
+    call $alloc
+    local.tee 2
+    i32.const 0
+    call $put
+    local.set 3
+    block
+      loop
+        local.get 4
+        local.get 1
+        i32.ge_u
+        br_if 1
+        local.get 0
+        local.get 4
+        i32.add
+        i32.load8_u
+        local.set 5
+        local.get 4
+        i32.const 1
+        i32.add
+        local.set 4
+        block
+          local.get 5
+          i32.const 34
+          i32.eq
+          if
+            local.get 6
+            i32.eqz
+            local.set 6
+            br 1
+          end
+          local.get 6
+          if
+            local.get 5
+            i32.const 92
+            i32.eq
+            if
+              i64.const 395136991269
+              return
+            end
+            local.get 3
+            local.get 5
+            call $put
+            local.set 3
+            br 1
+          end
+          local.get 5
+          i32.const 58
+          i32.eq
+          if
+            local.get 3
+            i32.const 61
+            call $put
+            local.set 3
+            br 1
+          end
+          local.get 5
+          i32.const 44
+          i32.eq
+          if
+            local.get 3
+            i32.const 10
+            call $put
+            local.set 3
+            br 1
+          end
+          ;; Arrays, and objects after the outermost, do not flatten
+          local.get 5
+          i32.const 91
+          i32.eq
+          local.get 5
+          i32.const 123
+          i32.eq
+          local.get 3
+          local.get 2
+          i32.const 1
+          i32.add
+          i32.gt_u
+          i32.and
+          i32.or
+          if
+            i64.const 554050781228
+            return
+          end
+          local.get 5
+          i32.const 32
+          i32.le_u
+          br_if 0
+          local.get 5
+          i32.const 123
+          i32.eq
+          br_if 0
+          local.get 5
+          i32.const 125
+          i32.eq
+          br_if 0
+          local.get 3
+          local.get 5
+          call $put
+          local.set 3
+        end
+        br 0
+      end
+    end
+    local.get 2
+    local.get 3
+    call $pack)
+
+  ;; Locals: index, byte, line has text, line has `=`, a line lacks `=`, quotes seen
+  (func (export "ulc_validate") (param i32 i32) (result i64) (local i32 i32 i32 i32 i32 i32)
+    block
+      loop
+        local.get 2
+        local.get 1
+        i32.ge_u
+        br_if 1
+        local.get 0
+        local.get 2
+        i32.add
+        i32.load8_u
+        local.set 3
+        local.get 2
+        i32.const 1
+        i32.add
+        local.set 2
+        local.get 3
+        i32.const 10
+        i32.eq
+        if
+          local.get 4
+          local.get 5
+          i32.eqz
+          i32.and
+          local.get 6
+          i32.or
+          local.set 6
+          i32.const 0
+          local.set 4
+          i32.const 0
+          local.set 5
+        else
+          local.get 3
+          i32.const 32
+          i32.gt_u
+          local.get 4
+          i32.or
+          local.set 4
+          local.get 3
+          i32.const 61
+          i32.eq
+          local.get 5
+          i32.or
+          local.set 5
+          local.get 3
+          i32.const 34
+          i32.eq
+          local.get 3
+          i32.const 92
+          i32.eq
+          i32.or
+          local.get 7
+          i32.or
+          local.set 7
+        end
+        br 0
+      end
+    end
+    local.get 4
+    local.get 5
+    i32.eqz
+    i32.and
+    local.get 6
+    i32.or
+    if
+      i64.const 73014444066
+      return
+    end
+    local.get 7
+    if
+      i64.const 219043332137
+      return
+    end
+    i64.const 68719476737)
+)
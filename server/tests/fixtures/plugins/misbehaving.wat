@@ -0,0 +1,29 @@
+;; misbehaving: implements plugin ABI 1, then breaks every limit
+;;
+;; Test fixture; compile with `wasm-tools parse misbehaving.wat -o misbehaving.wasm`.
+;; Converting to canonical JSON traps, converting from it never finishes,
+;; and validating grows memory to 128 MiB.
+(module
+  (memory (export "memory") 1)
+
+  (func (export "ulc_abi_version") (result i32)
+    i32.const 1)
+
+  (func (export "ulc_alloc") (param i32) (result i32)
+    i32.const 1024)
+
+  (func (export "ulc_to_canonical") (param i32 i32) (result i64)
+    unreachable)
+
+  (func (export "ulc_from_canonical") (param i32 i32) (result i64)
+    loop
+      br 0
+    end
+    unreachable)
+
+  (func (export "ulc_validate") (param i32 i32) (result i64)
+    i32.const 2048
+    memory.grow
+    drop
+    i64.const 4294967296)
+)
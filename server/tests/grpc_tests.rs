@@ -0,0 +1,251 @@
+//! gRPC interface tests
+//!
+//! A generated tonic client calls a real server started with
+//! [`Server::run`] on ephemeral ports.
+
+#![cfg(feature = "grpc")]
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Streaming};
+use universal_connector_server::grpc::proto::documents_client::DocumentsClient;
+use universal_connector_server::grpc::proto::formats_client::FormatsClient;
+use universal_connector_server::grpc::proto::{
+    document_event, subscribe_request, ConvertRequest, DeleteDocumentRequest, DocumentEvent, GetDocumentRequest,
+    ListDocumentsRequest, SubscribeRequest, ValidateRequest,
+};
+use universal_connector_server::{Server, ServerConfig, ServerHandle, ServerState};
+
+const SECRET: &str = "Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY";
+
+/// How long an event may take to arrive
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn start(auth: bool) -> (Arc<ServerState>, ServerHandle, Channel) {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .disable_websocket()
+        .grpc(|grpc| grpc.addr("127.0.0.1:0").enabled(true))
+        .disable_lsp()
+        .auth(|builder| builder.enabled(auth).secret(SECRET))
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    let channel = Channel::from_shared(format!("http://{}", server.grpc_addr().unwrap()))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    (state, server, channel)
+}
+
+async fn stop(mut server: ServerHandle) {
+    server.shutdown();
+    server.wait().await.unwrap();
+}
+
+fn pattern(pattern: &str) -> SubscribeRequest {
+    SubscribeRequest { target: Some(subscribe_request::Target::Pattern(pattern.to_string())), unsubscribe: false }
+}
+
+async fn next(events: &mut Streaming<DocumentEvent>) -> document_event::Event {
+    let event = tokio::time::timeout(WAIT, events.message()).await.expect("event in time");
+    event.unwrap().expect("stream open").event.expect("event set")
+}
+
+#[tokio::test]
+async fn test_convert_and_validate() {
+    let (_state, server, channel) = start(false).await;
+    let mut formats = FormatsClient::new(channel);
+
+    let request = ConvertRequest { content: "# Title".into(), from: "markdown".into(), to: "html".into() };
+    let converted = formats.convert(request).await.unwrap().into_inner();
+    assert!(converted.content.contains("<h1>Title</h1>"), "{}", converted.content);
+    assert_eq!((converted.from.as_str(), converted.to.as_str()), ("markdown", "html"));
+
+    let valid = formats.validate(ValidateRequest { content: r#"{"a": 1}"#.into(), format: "json".into() }).await;
+    let valid = valid.unwrap().into_inner();
+    assert!(valid.valid && valid.diagnostics.is_empty());
+    let invalid = formats.validate(ValidateRequest { content: "{ broken".into(), format: "json".into() }).await;
+    assert!(!invalid.unwrap().into_inner().valid);
+
+    // The same errors as the HTTP API, as status codes
+    let request = ConvertRequest { content: "text".into(), from: "nonsense".into(), to: "html".into() };
+    let status = formats.convert(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert!(status.message().contains("Invalid 'from' format"), "{}", status.message());
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_documents() {
+    let (state, server, channel) = start(false).await;
+    let mut documents = DocumentsClient::new(channel);
+    let document = state.documents.upsert("file:///notes/a.md".into(), "# A".into(), "markdown".into());
+
+    let listed = documents.list_documents(ListDocumentsRequest {}).await.unwrap().into_inner().documents;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].uri, "file:///notes/a.md");
+    let fetched = documents.get_document(GetDocumentRequest { id: document.id.clone() }).await.unwrap().into_inner();
+    assert_eq!((fetched.content.as_str(), fetched.language.as_str()), ("# A", "markdown"));
+    assert!(chrono::DateTime::parse_from_rfc3339(&fetched.modified_at).is_ok(), "{}", fetched.modified_at);
+
+    documents.delete_document(DeleteDocumentRequest { id: document.id.clone() }).await.unwrap();
+    assert!(state.documents.get_by_id(&document.id).is_none());
+    let status = documents.get_document(GetDocumentRequest { id: document.id.to_string() }).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_subscribe() {
+    let (state, server, channel) = start(false).await;
+    let mut documents = DocumentsClient::new(channel);
+    let (requests, outgoing) = mpsc::channel(8);
+    let mut events = documents.subscribe(ReceiverStream::new(outgoing)).await.unwrap().into_inner();
+
+    requests.send(pattern("file:///notes/*")).await.unwrap();
+    // A refused change is reported on the stream, and shows the subscription is in place
+    requests.send(SubscribeRequest::default()).await.unwrap();
+    assert!(matches!(next(&mut events).await, document_event::Event::Error(message) if message.contains("document_id")));
+
+    let document = state.documents.upsert("file:///notes/b.md".into(), "# B".into(), "markdown".into());
+    state.documents.upsert("file:///elsewhere/c.md".into(), "# C".into(), "markdown".into());
+    match next(&mut events).await {
+        document_event::Event::Updated(updated) => {
+            assert_eq!((updated.id.as_str(), updated.content.as_str()), (document.id.as_str(), "# B"));
+        }
+        other => panic!("expected an update, got {other:?}"),
+    }
+    state.documents.remove("file:///notes/b.md");
+    assert!(matches!(next(&mut events).await, document_event::Event::Removed(removed) if removed.document_id == document.id));
+
+    requests.send(SubscribeRequest { unsubscribe: true, ..pattern("file:///notes/*") }).await.unwrap();
+    requests.send(SubscribeRequest::default()).await.unwrap();
+    assert!(matches!(next(&mut events).await, document_event::Event::Error(_)));
+    state.documents.upsert("file:///notes/d.md".into(), "# D".into(), "markdown".into());
+    assert!(tokio::time::timeout(Duration::from_millis(200), events.message()).await.is_err());
+
+    // Closing the request stream ends the subscription
+    drop(requests);
+    let ended = tokio::time::timeout(WAIT, events.message()).await.expect("stream ends in time");
+    assert!(matches!(ended, Ok(None)), "{ended:?}");
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_slow_subscriber_must_resync() {
+    let (state, server, channel) = start(false).await;
+    let mut documents = DocumentsClient::new(channel);
+    let (requests, outgoing) = mpsc::channel(8);
+    let mut events = documents.subscribe(ReceiverStream::new(outgoing)).await.unwrap().into_inner();
+    requests.send(pattern("file:///bulk/*")).await.unwrap();
+    requests.send(SubscribeRequest::default()).await.unwrap();
+    assert!(matches!(next(&mut events).await, document_event::Event::Error(_)));
+
+    // Far more events than the store channel holds, while the client reads none
+    let content = "x".repeat(4096);
+    for i in 0..4096 {
+        state.documents.upsert(format!("file:///bulk/{i}.md"), content.clone(), "markdown".into());
+    }
+    let mut resynced = false;
+    while let Ok(Ok(Some(event))) = tokio::time::timeout(Duration::from_secs(1), events.message()).await {
+        if let Some(document_event::Event::ResyncRequired(resync)) = event.event {
+            assert!(resync.reason.contains("dropped"), "{}", resync.reason);
+            resynced = true;
+            break;
+        }
+    }
+    assert!(resynced, "the slow subscriber was never told to resync");
+    // A stream with events left unread holds its connection open until the drain timeout
+    drop((requests, events));
+    stop(server).await;
+}
+
+#[tokio::test]
+#[allow(clippy::result_large_err)] // Interceptors return tonic's `Status`
+async fn test_authentication() {
+    let (state, server, channel) = start(true).await;
+
+    let status = DocumentsClient::new(channel.clone()).list_documents(ListDocumentsRequest {}).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    assert_eq!(status.message(), "Missing bearer token");
+
+    let mut request = Request::new(ListDocumentsRequest {});
+    request.metadata_mut().insert("authorization", "Bearer expired".parse().unwrap());
+    let status = DocumentsClient::new(channel.clone()).list_documents(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let token = state.auth_service.as_ref().unwrap().generate_token("companion".to_string(), vec!["read".into()]).unwrap();
+    let token: tonic::metadata::MetadataValue<_> = token.parse().unwrap();
+    let mut formats = FormatsClient::with_interceptor(channel.clone(), move |mut request: Request<()>| {
+        request.metadata_mut().insert("authorization", token.clone());
+        Ok(request)
+    });
+    let request = ConvertRequest { content: "# Title".into(), from: "markdown".into(), to: "html".into() };
+    assert!(formats.convert(request).await.is_ok());
+    // Conversions are accounted to the token's subject, as over HTTP
+    let report = state.usage.top(Duration::from_secs(3600), 10);
+    let companion = report.clients.iter().find(|client| client.subject == "companion");
+    assert_eq!(companion.map(|client| client.counts.conversions), Some(1), "{report:?}");
+
+    // A viewer's role grants reading, not deleting
+    let viewer = state.auth_service.as_ref().unwrap().generate_token("viewer".to_string(), vec!["role:viewer".into()]);
+    let viewer: tonic::metadata::MetadataValue<_> = viewer.unwrap().parse().unwrap();
+    let mut request = Request::new(ListDocumentsRequest {});
+    request.metadata_mut().insert("authorization", viewer.clone());
+    assert!(DocumentsClient::new(channel.clone()).list_documents(request).await.is_ok());
+    let mut request = Request::new(DeleteDocumentRequest { id: "missing".into() });
+    request.metadata_mut().insert("authorization", viewer);
+    let status = DocumentsClient::new(channel).delete_document(request).await.unwrap_err();
+    assert_eq!((status.code(), status.message()), (Code::PermissionDenied, "Requires the write scope"));
+    stop(server).await;
+}
+
+#[tokio::test]
+async fn test_shutdown_ends_subscriptions() {
+    let (_state, server, channel) = start(false).await;
+    let mut documents = DocumentsClient::new(channel);
+    let (requests, outgoing) = mpsc::channel(8);
+    let mut events = documents.subscribe(ReceiverStream::new(outgoing)).await.unwrap().into_inner();
+    requests.send(pattern("file:///notes/*")).await.unwrap();
+    requests.send(SubscribeRequest::default()).await.unwrap();
+    assert!(matches!(next(&mut events).await, document_event::Event::Error(_)));
+
+    // Without waiting out the drain timeout for the open stream
+    tokio::time::timeout(WAIT, stop(server)).await.expect("stopped in time");
+    assert!(matches!(events.message().await, Ok(None)));
+}
+
+#[tokio::test]
+async fn test_reflection_lists_the_services() {
+    use tonic_reflection::pb::server_reflection_client::ServerReflectionClient;
+    use tonic_reflection::pb::server_reflection_request::MessageRequest;
+    use tonic_reflection::pb::server_reflection_response::MessageResponse;
+    use tonic_reflection::pb::ServerReflectionRequest;
+
+    // Open without a token, so tools can discover what to call
+    let (_state, server, channel) = start(true).await;
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(MessageRequest::ListServices(String::new())),
+    };
+    let mut responses = ServerReflectionClient::new(channel)
+        .server_reflection_info(tokio_stream::iter([request]))
+        .await
+        .unwrap()
+        .into_inner();
+    let response = responses.message().await.unwrap().unwrap();
+    let Some(MessageResponse::ListServicesResponse(list)) = response.message_response else {
+        panic!("expected a service list, got {response:?}");
+    };
+    let mut services: Vec<_> = list.service.into_iter().map(|service| service.name).collect();
+    services.sort();
+    assert_eq!(services, ["grpc.reflection.v1alpha.ServerReflection", "ulc.v1.Documents", "ulc.v1.Formats"]);
+    drop(responses);
+    stop(server).await;
+}
@@ -0,0 +1,171 @@
+//! Language provider integration tests
+//!
+//! The example `.ulcignore` provider is registered on a running server and
+//! driven by an editor speaking LSP, once over stdio-style framing and once
+//! through the bridge into a WebSocket `Lsp` session. Both must see the same
+//! answers, and Markdown documents must still reach the built-in provider.
+
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{BufReader, DuplexStream, ReadHalf, WriteHalf};
+use universal_connector_server::bridge::{run_bridge_with, BridgeConfig};
+use universal_connector_server::language::ulcignore::UlcIgnoreProvider;
+use universal_connector_server::lsp::{self, read_message, write_message};
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::{ProviderOrder, Server, ServerConfig, ServerHandle, ServerState};
+
+const IGNORE_URI: &str = "file:///project/.ulcignore";
+const NOTES_URI: &str = "file:///project/notes.md";
+const SERVICE_URI: &str = "file:///project/service.yaml";
+
+/// The editor's end of an LSP connection
+struct Editor {
+    input: BufReader<ReadHalf<DuplexStream>>,
+    output: WriteHalf<DuplexStream>,
+    next_id: i64,
+}
+
+impl Editor {
+    fn new(editor: DuplexStream) -> Self {
+        let (input, output) = tokio::io::split(editor);
+        Self {
+            input: BufReader::new(input),
+            output,
+            next_id: 1,
+        }
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        write_message(&mut self.output, &message).await.unwrap();
+    }
+
+    /// Send a request and read until its result, skipping other messages
+    async fn request(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        write_message(&mut self.output, &message).await.unwrap();
+        loop {
+            let message = read_message(&mut self.input).await.unwrap().expect("server closed the connection");
+            if message["id"] == json!(id) {
+                return message["result"].clone();
+            }
+        }
+    }
+
+    /// Read until diagnostics for `uri` are published
+    async fn diagnostics(&mut self, uri: &str) -> Vec<Value> {
+        loop {
+            let message = read_message(&mut self.input).await.unwrap().expect("server closed the connection");
+            if message["method"] == "textDocument/publishDiagnostics" && message["params"]["uri"] == uri {
+                return message["params"]["diagnostics"].as_array().unwrap().clone();
+            }
+        }
+    }
+
+    async fn open(&mut self, uri: &str, language: &str, text: &str) -> Vec<Value> {
+        let document = json!({ "uri": uri, "languageId": language, "version": 1, "text": text });
+        self.notify("textDocument/didOpen", json!({ "textDocument": document })).await;
+        self.diagnostics(uri).await
+    }
+}
+
+async fn start_server() -> (Arc<ServerState>, ServerHandle) {
+    let config = ServerConfig::builder()
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_http()
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    state.providers.register(Arc::new(UlcIgnoreProvider), ProviderOrder::BeforeBuiltIn).unwrap();
+    let server = Server::run(Arc::clone(&state)).await.unwrap();
+    (state, server)
+}
+
+/// Edit a `.ulcignore` file and a Markdown note, checking each feature
+async fn exercise(mut editor: Editor) {
+    let initialized = editor.request("initialize", json!({ "capabilities": {} })).await;
+    let capabilities = &initialized["capabilities"];
+    assert_eq!(capabilities["documentSymbolProvider"], true);
+    assert_eq!(capabilities["documentFormattingProvider"], true);
+    let language = &capabilities["experimental"]["languages"]["children"]["ulcignore"];
+    assert_eq!(language["parameters"]["provider"], "ulcignore");
+    editor.notify("initialized", json!({})).await;
+
+    let diagnostics = editor.open(IGNORE_URI, "ulcignore", "# Build output\n  **/target/**\n!\n**/target/**").await;
+    let lines: Vec<_> = diagnostics.iter().map(|d| d["range"]["start"]["line"].clone()).collect();
+    assert_eq!(lines, [2, 3]);
+    assert!(diagnostics.iter().all(|d| d["source"] == "ulcignore"));
+
+    let at = |line: u32| json!({ "textDocument": { "uri": IGNORE_URI }, "position": { "line": line, "character": 0 } });
+    let hovered = editor.request("textDocument/hover", at(1)).await;
+    assert_eq!(hovered["contents"]["value"], "Documents matching `**/target/**` are ignored");
+    let completions = editor.request("textDocument/completion", at(4)).await;
+    let labels: Vec<_> = completions.as_array().unwrap().iter().map(|item| item["label"].clone()).collect();
+    assert!(labels.contains(&json!("**/*.md")), "{labels:?}");
+    assert!(!labels.contains(&json!("Convert to HTML")), "{labels:?}");
+
+    let document = json!({ "textDocument": { "uri": IGNORE_URI } });
+    let symbols = editor.request("textDocument/documentSymbol", document.clone()).await;
+    let names: Vec<_> = symbols.as_array().unwrap().iter().map(|symbol| symbol["name"].clone()).collect();
+    assert_eq!(names, [json!("**/target/**"), json!("**/target/**")]);
+    let options = json!({ "tabSize": 4, "insertSpaces": true });
+    let formatting = json!({ "textDocument": { "uri": IGNORE_URI }, "options": options });
+    let edits = editor.request("textDocument/formatting", formatting).await;
+    let untidy = json!({ "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 14 } });
+    assert_eq!(edits, json!([{ "range": untidy, "newText": "**/target/**" }]));
+
+    // Markdown is still answered by the built-in format provider
+    editor.open(NOTES_URI, "markdown", "# Notes\n\nKept").await;
+    let at = json!({ "textDocument": { "uri": NOTES_URI }, "position": { "line": 0, "character": 0 } });
+    let hovered = editor.request("textDocument/hover", at.clone()).await;
+    assert!(hovered["contents"]["value"].as_str().unwrap().contains("Document Statistics"));
+    let completions = editor.request("textDocument/completion", at).await;
+    assert_eq!(completions[0]["label"], "Convert to HTML");
+    let symbols = editor.request("textDocument/documentSymbol", json!({ "textDocument": { "uri": NOTES_URI } })).await;
+    assert_eq!(symbols, Value::Null);
+
+    // Data documents are offered a schema drafted from them
+    editor.open(SERVICE_URI, "yaml", "name: api\nport: 80\n").await;
+    let range = json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } });
+    let asked = |uri: &str, only: Value| json!({ "textDocument": { "uri": uri }, "range": range, "context": { "diagnostics": [], "only": only } });
+    let actions = editor.request("textDocument/codeAction", asked(SERVICE_URI, json!(["source"]))).await;
+    assert_eq!(actions[0]["title"], "Generate schema from this document");
+    let command = actions[0]["command"].clone();
+    let inferred = editor.request("workspace/executeCommand", json!({ "command": command["command"], "arguments": command["arguments"] })).await;
+    assert_eq!(inferred["schema"]["required"], json!(["name", "port"]));
+    let queried = editor.request("workspace/executeCommand", json!({ "command": "document.query", "arguments": [SERVICE_URI, "port", "jmespath"] })).await;
+    assert_eq!(queried["result"], 80);
+    assert_eq!(editor.request("textDocument/codeAction", asked(SERVICE_URI, json!(["quickfix"]))).await, json!([]));
+    assert_eq!(editor.request("textDocument/codeAction", asked(NOTES_URI, Value::Null)).await, json!([]));
+
+    assert_eq!(editor.request("shutdown", Value::Null).await, Value::Null);
+    editor.notify("exit", Value::Null).await;
+}
+
+#[tokio::test]
+async fn test_provider_over_stdio_lsp() {
+    let (state, _server) = start_server().await;
+    let (editor, server) = tokio::io::duplex(64 * 1024);
+    let (input, output) = tokio::io::split(server);
+    let served = tokio::spawn(lsp::serve_lsp(Arc::clone(&state), input, output, Transport::LspStdio));
+
+    exercise(Editor::new(editor)).await;
+    served.await.unwrap().unwrap();
+    assert_eq!(state.documents.get(IGNORE_URI).unwrap().language, "ulcignore");
+}
+
+#[tokio::test]
+async fn test_provider_over_websocket() {
+    let (state, server) = start_server().await;
+    let url = format!("ws://{}", server.ws_addr().unwrap());
+    let (editor, bridge_side) = tokio::io::duplex(64 * 1024);
+    let (bridge_in, bridge_out) = tokio::io::split(bridge_side);
+    let bridge = tokio::spawn(run_bridge_with(BridgeConfig::new(url), bridge_in, bridge_out));
+
+    exercise(Editor::new(editor)).await;
+    bridge.await.unwrap().unwrap();
+    assert!(state.documents.contains(IGNORE_URI));
+}
@@ -0,0 +1,41 @@
+//! Metrics snapshot integration tests
+//!
+//! Status panels read the snapshot over LSP where HTTP is out of reach.
+
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use universal_connector_server::lsp::{LspHost, METRICS_METHOD};
+use universal_connector_server::monitoring::MetricsSnapshot;
+use universal_connector_server::{ServerConfig, ServerState};
+
+async fn response(messages: &mut mpsc::UnboundedReceiver<Value>, id: i64) -> Value {
+    loop {
+        let message = messages.recv().await.expect("LSP server replies");
+        if message["id"] == json!(id) {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_lsp_metrics_request() {
+    let state = Arc::new(ServerState::new(ServerConfig::default()));
+    state.metrics.errors.inc();
+    let (output, mut messages) = mpsc::unbounded_channel::<Value>();
+    let host = LspHost::spawn(Arc::clone(&state), output);
+
+    host.send(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } }));
+    response(&mut messages, 1).await;
+    host.send(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+
+    host.send(json!({ "jsonrpc": "2.0", "id": 2, "method": METRICS_METHOD }));
+    let reply = response(&mut messages, 2).await;
+    let snapshot: MetricsSnapshot = serde_json::from_value(reply["result"].clone()).unwrap();
+    assert_eq!(snapshot.total_errors, 1);
+    assert!(snapshot.values.contains_key("ulc_errors_total"));
+    // Sampled rates need samples over time, which a fresh server has not taken;
+    // a counter keeping a rate window has its one-minute rate at once
+    assert!(snapshot.rates.values().all(|rates| rates.m5.is_none() && rates.m15.is_none()));
+    assert!(snapshot.rates["ulc_errors_total"].m1.is_some());
+}
@@ -0,0 +1,327 @@
+//! Downstream language server integration tests
+//!
+//! An editor speaking LSP to the connector opens documents routed to the
+//! mock language server in `tests/support`, reached over stdio, TCP or a
+//! WebSocket. Its answers and diagnostics must come back with URIs mapped,
+//! and crashed or idle instances must be started again with the documents
+//! still open.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use universal_connector_server::lsp::{self, read_message, write_message};
+use universal_connector_server::monitoring::connections::Transport;
+use universal_connector_server::proxy::{DownstreamConfig, DownstreamTransport, UriMap};
+use universal_connector_server::{ServerConfig, ServerState};
+
+const MOCK: &str = env!("CARGO_BIN_EXE_mock-lsp-server");
+const MAIN_URI: &str = "file:///project/src/main.rs";
+const NOTES_URI: &str = "file:///project/notes.md";
+
+/// Longest wait for any one message
+const PATIENCE: Duration = Duration::from_secs(10);
+
+/// The editor's end of an LSP connection
+struct Editor {
+    input: BufReader<ReadHalf<DuplexStream>>,
+    output: WriteHalf<DuplexStream>,
+    next_id: i64,
+    served: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Editor {
+    /// Connect to a new session of a server fronting `downstream`
+    fn connect(downstream: DownstreamConfig) -> Self {
+        let config = ServerConfig::builder().downstream(downstream).build().unwrap();
+        let state = Arc::new(ServerState::new(config));
+        let (editor, server) = tokio::io::duplex(64 * 1024);
+        let (input, output) = tokio::io::split(server);
+        let served = tokio::spawn(lsp::serve_lsp(state, input, output, Transport::LspStdio));
+        let (input, output) = tokio::io::split(editor);
+        Self { input: BufReader::new(input), output, next_id: 1, served }
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) {
+        let mut message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        // tower-lsp refuses `"params": null` where a method takes none
+        if params.is_null() {
+            message.as_object_mut().unwrap().remove("params");
+        }
+        write_message(&mut self.output, &message).await.unwrap();
+    }
+
+    async fn read(&mut self) -> Value {
+        let read = tokio::time::timeout(PATIENCE, read_message(&mut self.input)).await;
+        read.expect("no message in time").unwrap().expect("server closed the connection")
+    }
+
+    /// Send a request and read until its response, skipping other messages
+    async fn call(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        if params.is_null() {
+            message.as_object_mut().unwrap().remove("params");
+        }
+        write_message(&mut self.output, &message).await.unwrap();
+        loop {
+            let message = self.read().await;
+            if message["id"] == json!(id) && message.get("method").is_none() {
+                return message;
+            }
+        }
+    }
+
+    async fn request(&mut self, method: &str, params: Value) -> Value {
+        let response = self.call(method, params).await;
+        assert!(response.get("error").is_none(), "{method} failed: {response}");
+        response["result"].clone()
+    }
+
+    /// Read until diagnostics for `uri` are published
+    async fn diagnostics(&mut self, uri: &str) -> Vec<Value> {
+        loop {
+            let message = self.read().await;
+            if message["method"] == "textDocument/publishDiagnostics" && message["params"]["uri"] == uri {
+                return message["params"]["diagnostics"].as_array().unwrap().clone();
+            }
+        }
+    }
+
+    async fn initialize(&mut self) -> Value {
+        let initialized = self.request("initialize", json!({ "rootUri": "file:///project", "capabilities": {} })).await;
+        self.notify("initialized", json!({})).await;
+        initialized["capabilities"].clone()
+    }
+
+    async fn open(&mut self, uri: &str, language: &str, text: &str) -> Vec<Value> {
+        let document = json!({ "uri": uri, "languageId": language, "version": 1, "text": text });
+        self.notify("textDocument/didOpen", json!({ "textDocument": document })).await;
+        self.diagnostics(uri).await
+    }
+
+    async fn hover(&mut self, uri: &str, line: u32) -> Value {
+        let at = json!({ "textDocument": { "uri": uri }, "position": { "line": line, "character": 0 } });
+        self.call("textDocument/hover", at).await
+    }
+
+    async fn close(mut self) {
+        assert_eq!(self.request("shutdown", Value::Null).await, Value::Null);
+        self.notify("exit", Value::Null).await;
+        // The session ends when the editor hangs up
+        let Self { input, output, served, .. } = self;
+        drop((input, output));
+        served.await.unwrap().unwrap();
+    }
+}
+
+/// A file the mock server logs its messages to
+fn log_file() -> PathBuf {
+    std::env::temp_dir().join(format!("ulc-mock-lsp-{}.log", uuid::Uuid::new_v4()))
+}
+
+/// The process IDs in `log` that received `event`, in order
+fn logged(log: &Path, event: &str) -> Vec<String> {
+    let text = std::fs::read_to_string(log).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(_, logged)| *logged == event)
+        .map(|(pid, _)| pid.to_string())
+        .collect()
+}
+
+/// The mock server over stdio, fronted for Rust documents
+fn mock(log: &Path) -> DownstreamConfig {
+    let command = vec![MOCK.to_string(), "--log".to_string(), log.display().to_string()];
+    let mut config = DownstreamConfig::new("mock", DownstreamTransport::Stdio(command));
+    config.languages = vec!["rust".to_string()];
+    config
+}
+
+#[tokio::test]
+async fn test_proxies_a_stdio_server() {
+    let log = log_file();
+    let mut config = mock(&log);
+    config.uri_map = Some(UriMap { local: "file:///project".to_string(), remote: "file:///workspace".to_string() });
+    let mut editor = Editor::connect(config);
+
+    let capabilities = editor.initialize().await;
+    assert_eq!(capabilities["hoverProvider"], true);
+    assert_eq!(capabilities["referencesProvider"], true);
+    assert_eq!(capabilities["completionProvider"]["triggerCharacters"], json!(["#", "@", "[", "."]));
+    assert!(capabilities["completionProvider"].get("resolveProvider").is_none());
+    assert!(capabilities["executeCommandProvider"]["commands"].as_array().is_some_and(|c| !c.is_empty()));
+
+    let diagnostics = editor.open(MAIN_URI, "rust", "fn main() {}\n// TODO: more").await;
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["source"], "mock");
+    assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 1, "character": 3 }));
+
+    // The downstream sees the remote URI, and the editor its own
+    let hovered = editor.hover(MAIN_URI, 0).await;
+    assert_eq!(hovered["result"]["contents"]["value"], "file:///workspace/src/main.rs line 0: fn main() {}");
+    let at = json!({ "textDocument": { "uri": MAIN_URI }, "position": { "line": 0, "character": 3 } });
+    let completions = editor.request("textDocument/completion", at.clone()).await;
+    assert_eq!(completions, json!([{ "label": "mock_completion" }]));
+    let definition = editor.request("textDocument/definition", at.clone()).await;
+    assert_eq!(definition["uri"], MAIN_URI);
+    let mut references = at.clone();
+    references["context"] = json!({ "includeDeclaration": true });
+    let references = editor.request("textDocument/references", references).await;
+    assert_eq!(references[0]["uri"], MAIN_URI);
+
+    let changes = json!({
+        "textDocument": { "uri": MAIN_URI, "version": 2 },
+        "contentChanges": [{ "text": "// TODO: one\n// TODO: two" }],
+    });
+    editor.notify("textDocument/didChange", changes).await;
+    assert_eq!(editor.diagnostics(MAIN_URI).await.len(), 2);
+
+    // Markdown is still the connector's own
+    editor.open(NOTES_URI, "markdown", "# Notes\n\nKept").await;
+    let hovered = editor.hover(NOTES_URI, 0).await;
+    assert!(hovered["result"]["contents"]["value"].as_str().unwrap().contains("Document Statistics"));
+    let definition = editor.request("textDocument/definition", at.clone()).await;
+    assert_eq!(definition["uri"], MAIN_URI);
+    let at_notes = json!({ "textDocument": { "uri": NOTES_URI }, "position": { "line": 0, "character": 0 } });
+    assert_eq!(editor.request("textDocument/definition", at_notes).await, Value::Null);
+
+    editor.close().await;
+    for event in ["initialize", "initialized", "configured", "textDocument/didOpen", "textDocument/didChange"] {
+        assert_eq!(logged(&log, event).len(), 1, "{event}");
+    }
+    assert_eq!(logged(&log, "shutdown").len(), 1);
+    assert_eq!(logged(&log, "exit").len(), 1);
+    std::fs::remove_file(log).unwrap();
+}
+
+#[tokio::test]
+async fn test_restarts_a_crashed_server() {
+    let log = log_file();
+    let mut config = mock(&log);
+    config.restart_delay = Duration::from_millis(50);
+    let mut editor = Editor::connect(config);
+    editor.initialize().await;
+    assert_eq!(editor.open(MAIN_URI, "rust", "crash\n// TODO").await.len(), 1);
+
+    let crashed = editor.hover(MAIN_URI, 0).await;
+    let error = crashed["error"]["message"].as_str().unwrap();
+    assert!(error.contains("mock exited before answering textDocument/hover"), "{crashed}");
+
+    // Started again with the document still open
+    assert_eq!(editor.diagnostics(MAIN_URI).await.len(), 1);
+    let hovered = editor.hover(MAIN_URI, 1).await;
+    assert_eq!(hovered["result"]["contents"]["value"], format!("{MAIN_URI} line 1: // TODO"));
+
+    let started = logged(&log, "initialize");
+    assert_eq!(started.len(), 2);
+    assert_ne!(started[0], started[1]);
+    assert_eq!(logged(&log, "textDocument/didOpen"), started);
+    editor.close().await;
+    std::fs::remove_file(log).unwrap();
+}
+
+#[tokio::test]
+async fn test_stops_an_idle_server() {
+    let log = log_file();
+    let mut config = mock(&log);
+    config.idle_timeout = Duration::from_millis(200);
+    let mut editor = Editor::connect(config);
+    editor.initialize().await;
+    editor.open(MAIN_URI, "rust", "fn main() {}").await;
+
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    let first = logged(&log, "initialize");
+    assert_eq!(logged(&log, "exit"), first);
+
+    // The next request starts it again, with the document reopened first
+    let hovered = editor.hover(MAIN_URI, 0).await;
+    assert_eq!(hovered["result"]["contents"]["value"], format!("{MAIN_URI} line 0: fn main() {{}}"));
+    let started = logged(&log, "initialize");
+    assert_eq!(started.len(), 2);
+    assert_eq!(logged(&log, "textDocument/didOpen"), started);
+    editor.close().await;
+    std::fs::remove_file(log).unwrap();
+}
+
+/// Start the mock server listening on TCP, returning it and its address
+fn mock_over_tcp() -> (std::process::Child, String) {
+    use std::io::BufRead;
+
+    let mut child = std::process::Command::new(MOCK)
+        .args(["--tcp", "127.0.0.1:0"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut addr = String::new();
+    std::io::BufReader::new(child.stdout.take().unwrap()).read_line(&mut addr).unwrap();
+    (child, addr.trim().to_string())
+}
+
+#[tokio::test]
+async fn test_proxies_a_tcp_server() {
+    let (mut server, addr) = mock_over_tcp();
+    let mut config = DownstreamConfig::new("mock", DownstreamTransport::Tcp(addr));
+    config.patterns = vec!["*.mock".to_string()];
+    let mut editor = Editor::connect(config);
+    editor.initialize().await;
+
+    let uri = "file:///project/example.mock";
+    assert_eq!(editor.open(uri, "plaintext", "TODO").await.len(), 1);
+    let hovered = editor.hover(uri, 0).await;
+    assert_eq!(hovered["result"]["contents"]["value"], format!("{uri} line 0: TODO"));
+    editor.close().await;
+    server.kill().unwrap();
+}
+
+/// Serve one WebSocket connection, one message per text frame, from the mock server over stdio
+async fn mock_over_websocket() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let (mut sink, mut frames) = socket.split();
+        let mut child = tokio::process::Command::new(MOCK)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = read_message(&mut stdout).await {
+                if sink.send(Message::Text(message.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            // The server exited
+            let _ = sink.send(Message::Close(None)).await;
+        });
+        while let Some(Ok(Message::Text(text))) = frames.next().await {
+            write_message(&mut stdin, &serde_json::from_str(&text).unwrap()).await.unwrap();
+        }
+        let _ = child.wait().await;
+    });
+    format!("ws://{addr}")
+}
+
+#[tokio::test]
+async fn test_proxies_a_websocket_server() {
+    let url = mock_over_websocket().await;
+    let mut config = DownstreamConfig::new("mock", DownstreamTransport::WebSocket(url));
+    config.languages = vec!["rust".to_string()];
+    let mut editor = Editor::connect(config);
+    assert_eq!(editor.initialize().await["definitionProvider"], true);
+
+    assert_eq!(editor.open(MAIN_URI, "rust", "// TODO").await.len(), 1);
+    let hovered = editor.hover(MAIN_URI, 0).await;
+    assert_eq!(hovered["result"]["contents"]["value"], format!("{MAIN_URI} line 0: // TODO"));
+    editor.close().await;
+}
@@ -0,0 +1,118 @@
+//! Configuration reload integration tests
+//!
+//! A reloadable and a non-reloadable setting are changed together, and the
+//! running server is judged by what it does afterwards.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use tower::ServiceExt;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use universal_connector_server::logging::{self, LogFormat, LoggingConfig};
+use universal_connector_server::{http, FormatLimits, ServerConfig, ServerState};
+
+/// Writer collecting everything logged
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self {
+        self.clone()
+    }
+}
+
+impl Captured {
+    fn records(&self) -> Vec<Value> {
+        let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        text.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+}
+
+async fn validate(state: &Arc<ServerState>, content: &str) -> StatusCode {
+    let payload = serde_json::json!({ "content": content, "format": "markdown" });
+    let request = Request::builder()
+        .method("POST")
+        .uri("/api/validate")
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+    http::create_router(Arc::clone(state)).oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn test_reload_applies_reloadable_settings_only() {
+    let captured = Captured::default();
+    let mut logging_config = LoggingConfig::default();
+    logging_config.format = LogFormat::Json;
+    let (subscriber, handle) = logging::subscriber(&logging_config, BoxMakeWriter::new(captured.clone())).unwrap();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let running = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:8080"))
+        .logging(|logging| logging.format(LogFormat::Json))
+        .build()
+        .unwrap();
+    let mut state = ServerState::new(running.clone());
+    state.logging = Some(handle.clone());
+    let state = Arc::new(state);
+    let mut updates = state.subscribe_config();
+
+    let document = "# A heading longer than sixteen bytes";
+    assert_eq!(validate(&state, document).await, StatusCode::OK);
+
+    let mut loaded = running.clone();
+    loaded.format_limits = FormatLimits::default();
+    loaded.format_limits.max_input_bytes = 16;
+    loaded.logging.level = "debug".to_string();
+    loaded.http_addr = "127.0.0.1:9090".to_string();
+    let reload = state.reload(loaded.clone());
+    assert_eq!(reload.applied, ["format_limits.max_input_bytes", "logging.level"]);
+    assert_eq!(reload.rejected, ["http_addr"]);
+
+    // The reloadable settings are in force at once
+    assert_eq!(validate(&state, document).await, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(validate(&state, "# Short").await, StatusCode::OK);
+    assert_eq!(handle.filter(), "debug");
+    assert!(updates.has_changed().unwrap());
+    assert_eq!(updates.borrow_and_update().format_limits.max_input_bytes, 16);
+
+    // The bind address keeps its running value
+    assert_eq!(state.config().http_addr, "127.0.0.1:8080");
+    assert_eq!(state.config().format_limits.max_input_bytes, 16);
+
+    let records = captured.records();
+    let reloaded = records.iter().find(|r| r["message"] == "Configuration reloaded").unwrap();
+    assert_eq!(reloaded["changed"], "format_limits.max_input_bytes,logging.level");
+    let refused = records.iter().find(|r| r["settings"].is_string()).unwrap();
+    assert_eq!(refused["level"], "WARN");
+    assert_eq!(refused["settings"], "http_addr");
+
+    let changes = &state.metrics.config_changes;
+    assert_eq!(changes.with_labels(&["format_limits.max_input_bytes", "applied"]).get(), 1);
+    assert_eq!(changes.with_labels(&["http_addr", "rejected"]).get(), 1);
+    assert_eq!(state.metrics.config_reloads.with_labels(&["changed"]).get(), 1);
+
+    // Reloading the same file again still refuses the address, and changes nothing
+    let reload = state.reload(loaded);
+    assert!(reload.applied.is_empty());
+    assert_eq!(reload.rejected, ["http_addr"]);
+    assert!(!updates.has_changed().unwrap());
+
+    let reload = state.reload(state.config().as_ref().clone());
+    assert!(reload.is_empty());
+    assert_eq!(state.metrics.config_reloads.with_labels(&["unchanged"]).get(), 1);
+}
@@ -0,0 +1,176 @@
+//! Whole-server integration tests
+//!
+//! Servers start through [`Server::run`] on ephemeral ports, as an embedder
+//! would start one, and are judged over real sockets.
+
+use futures_util::{SinkExt, StreamExt};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use universal_connector_server::auth::mtls::{CertIdentity, MtlsConfig};
+use universal_connector_server::monitoring::LifecycleState;
+use universal_connector_server::{websocket, Server, ServerConfig, ServerState};
+
+fn ephemeral() -> ServerConfig {
+    ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_serves_every_transport_until_shut_down() {
+    let state = Arc::new(ServerState::new(ephemeral()));
+    let mut server = Server::run(Arc::clone(&state)).await.unwrap();
+    let (http_addr, ws_addr) = (server.http_addr().unwrap(), server.ws_addr().unwrap());
+    assert_ne!(http_addr.port(), 0);
+    assert_ne!(ws_addr.port(), 0);
+    assert_eq!(state.health_checker.state(), LifecycleState::Ready);
+
+    let response = reqwest::get(format!("http://{http_addr}/readyz")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{ws_addr}")).await.unwrap();
+    let hello = json!({ "type": "Hello", "protocol_version": websocket::PROTOCOL_VERSION });
+    ws.send(Message::Text(hello.to_string())).await.unwrap();
+    assert!(matches!(ws.next().await, Some(Ok(Message::Text(_)))));
+
+    server.shutdown();
+    server.wait().await.unwrap();
+    assert_eq!(state.health_checker.state(), LifecycleState::Stopped);
+    assert!(TcpStream::connect(http_addr).await.is_err());
+    assert!(TcpStream::connect(ws_addr).await.is_err());
+    // Waiting again after the server stopped returns at once
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bind_failure_serves_nothing() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_addr = taken.local_addr().unwrap();
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr(taken_addr.to_string()))
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+
+    let error = Server::run(Arc::clone(&state)).await.unwrap_err();
+    assert!(error.to_string().contains(&format!("WebSocket server to {taken_addr}")), "{error}");
+    assert_eq!(state.health_checker.state(), LifecycleState::Starting);
+}
+
+#[tokio::test]
+async fn test_disabled_transports_have_no_address() {
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .disable_websocket()
+        .disable_lsp()
+        .build()
+        .unwrap();
+    let mut server = Server::run(Arc::new(ServerState::new(config))).await.unwrap();
+    assert!(server.http_addr().is_some());
+    assert!(server.ws_addr().is_none());
+    server.shutdown();
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_documents_written_before_shutdown_survive_a_restart() {
+    let dir = std::env::temp_dir().join(format!("ulc-restart-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .data_dir(&dir)
+        .build()
+        .unwrap();
+
+    let state = Arc::new(ServerState::new(config.clone()));
+    let mut server = Server::run(Arc::clone(&state)).await.unwrap();
+    let written = state.documents.upsert("file:///late.md".to_string(), "# Late".to_string(), "markdown".to_string());
+    server.shutdown();
+    server.wait().await.unwrap();
+    let report = server.report().unwrap();
+    assert_eq!(report.get("documents").unwrap().completed, 1);
+    assert!(report.is_clean());
+
+    let restarted = ServerState::new(config);
+    let restored = restarted.documents.get("file:///late.md").unwrap();
+    assert_eq!((restored.id, restored.content), (written.id.clone(), "# Late".to_string()));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_certificates_stand_for_identities() {
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/mtls");
+    let mut mtls = MtlsConfig::new(fixtures.join("server.pem"), fixtures.join("server_key.pem"), vec![fixtures.join("ca.pem")]);
+    mtls.identities.insert("alice".to_string(), CertIdentity { user: None, scopes: vec!["role:viewer".to_string()] });
+    let config = ServerConfig::builder()
+        .http(|http| http.addr("127.0.0.1:0"))
+        .websocket(|ws| ws.addr("127.0.0.1:0"))
+        .disable_lsp()
+        .auth(|auth| auth.enabled(true).secret("Qm9vdHN0cmFwLXRva2VuLWZvci11bGMtc2VydmVyLTIwMjY").mtls(mtls))
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let document = state.documents.upsert("file:///a.md".to_string(), "# A".to_string(), "markdown".to_string());
+    let mut server = Server::run(Arc::clone(&state)).await.unwrap();
+    let (http_port, ws_port) = (server.http_addr().unwrap().port(), server.ws_addr().unwrap().port());
+
+    let pem = |name: &str| std::fs::read(fixtures.join(name)).unwrap();
+    let ca = reqwest::Certificate::from_pem(&pem("ca.pem")).unwrap();
+    let client = |holder: Option<&str>| {
+        let mut builder = reqwest::Client::builder().use_rustls_tls().add_root_certificate(ca.clone());
+        if let Some(holder) = holder {
+            let identity = [pem(&format!("{holder}.pem")), pem(&format!("{holder}_key.pem"))].concat();
+            builder = builder.identity(reqwest::Identity::from_pem(&identity).unwrap());
+        }
+        builder.build().unwrap()
+    };
+    let base = format!("https://localhost:{http_port}");
+
+    // The certificate stands for alice, a viewer
+    let alice = client(Some("alice"));
+    assert_eq!(alice.get(format!("{base}/api/documents")).send().await.unwrap().status(), 200);
+    let refused = alice.delete(format!("{base}/api/documents/{}", document.id)).send().await.unwrap();
+    assert_eq!(refused.status(), 403);
+    assert_eq!(refused.json::<serde_json::Value>().await.unwrap()["error"], "Requires the write scope");
+
+    // Without a certificate from the configured authority there is no connection at all
+    assert!(client(None).get(format!("{base}/healthz")).send().await.is_err());
+    assert!(client(Some("rogue")).get(format!("{base}/healthz")).send().await.is_err());
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(&pem("ca.pem")).unwrap()).unwrap();
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(
+            vec![CertificateDer::from_pem_slice(&pem("alice.pem")).unwrap()],
+            PrivateKeyDer::from_pem_slice(&pem("alice_key.pem")).unwrap(),
+        )
+        .unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", ws_port)).await.unwrap();
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("wss://localhost:{ws_port}"), stream).await.unwrap();
+    let hello = json!({ "type": "Hello", "protocol_version": websocket::PROTOCOL_VERSION, "capabilities": ["collab"] });
+    ws.send(Message::Text(hello.to_string())).await.unwrap();
+    assert!(matches!(ws.next().await, Some(Ok(Message::Text(_)))));
+    ws.send(Message::Text(json!({ "type": "CollabJoin", "uri": "file:///a.md" }).to_string())).await.unwrap();
+    let Some(Ok(Message::Text(reply))) = ws.next().await else { panic!("no reply to CollabJoin") };
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&reply).unwrap()["type"], "CollabSnapshot", "{reply}");
+
+    server.shutdown();
+    server.wait().await.unwrap();
+}
@@ -0,0 +1,118 @@
+//! Slow operation integration tests
+//!
+//! Zero thresholds make every operation slow, so real requests through the
+//! router exercise the log records and the admin endpoint.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tower::ServiceExt;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use universal_connector_server::http::{self, SlowOpsResponse};
+use universal_connector_server::logging::{self, LogFormat, LoggingConfig};
+use universal_connector_server::monitoring::slow_ops::OpKind;
+use universal_connector_server::{ServerConfig, ServerState};
+
+/// Writer collecting everything logged
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self {
+        self.clone()
+    }
+}
+
+fn convert_request() -> Request<Body> {
+    let payload = serde_json::json!({ "content": "# Confidential plans", "from": "markdown", "to": "html" });
+    Request::builder()
+        .method("POST")
+        .uri("/api/convert")
+        .header("content-type", "application/json")
+        .header("x-request-id", "req-slow-1")
+        .body(Body::from(payload.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_slow_operations_logged_and_listed() {
+    let captured = Captured::default();
+    let mut config = LoggingConfig::default();
+    config.format = LogFormat::Json;
+    let (subscriber, _handle) = logging::subscriber(&config, BoxMakeWriter::new(captured.clone())).unwrap();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let config = ServerConfig::builder()
+        .slow_ops(|slow| {
+            OpKind::ALL
+                .into_iter()
+                .fold(slow, |slow, kind| slow.threshold(kind, Duration::ZERO))
+                .capacity(10)
+                .max_logs_per_minute(1)
+        })
+        .build()
+        .unwrap();
+    let state = Arc::new(ServerState::new(config));
+    let app = http::create_router(Arc::clone(&state));
+    for _ in 0..2 {
+        let response = app.clone().oneshot(convert_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // One record per kind this minute, carrying the operation's description
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let records: Vec<Map<String, Value>> = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap().as_object().unwrap().clone())
+        .filter(|record| record["message"] == "Slow operation")
+        .collect();
+    let of_kind = |kind: &str| records.iter().filter(|r| r["op.kind"] == kind).collect::<Vec<_>>();
+    let conversions = of_kind("conversion");
+    assert_eq!(conversions.len(), 1, "{output}");
+    let conversion = conversions[0];
+    assert_eq!(conversion["level"], "WARN");
+    assert_eq!(conversion["op.name"], "convert");
+    assert_eq!(conversion["format"], "md");
+    assert_eq!(conversion["size"], "<1KiB");
+    assert_eq!(conversion["options"], "from=md,to=html");
+    assert_eq!(conversion["request_id"], "req-slow-1");
+    assert!(conversion["duration_ms"].as_f64().unwrap() >= 0.0);
+    assert_eq!(conversion["threshold_ms"], 0.0);
+    let requests = of_kind("http");
+    assert_eq!(requests.len(), 1, "{output}");
+    assert_eq!(requests[0]["op.name"], "POST /api/convert");
+    assert_eq!(requests[0]["request_id"], "req-slow-1");
+    // Document content is never logged
+    assert!(!output.contains("Confidential"));
+
+    // The ring keeps what sampling held back from the log
+    let response = app
+        .oneshot(Request::builder().uri("/api/admin/slow-ops").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let listed: SlowOpsResponse = serde_json::from_slice(&body).unwrap();
+    let conversions: Vec<_> = listed.operations.iter().filter(|op| op.kind == OpKind::Conversion).collect();
+    assert_eq!(conversions.len(), 2);
+    assert_eq!(conversions[0].request_id.as_deref(), Some("req-slow-1"));
+    assert_eq!(conversions[0].options["to"], "html");
+    assert_eq!(conversions[0].size.as_deref(), Some("<1KiB"));
+    assert_eq!(listed.operations.iter().filter(|op| op.kind == OpKind::Http).count(), 2);
+    assert!(!String::from_utf8_lossy(&body).contains("Confidential"));
+}
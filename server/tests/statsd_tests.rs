@@ -0,0 +1,113 @@
+//! StatsD export integration tests
+//!
+//! A mock agent listens on a local socket and checks the lines it receives.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use universal_connector_server::monitoring::statsd::{HistogramMode, StatsdConfig, StatsdExporter, StatsdTarget};
+use universal_connector_server::Metrics;
+
+/// Every line received until the agent has been quiet for a moment
+async fn receive(agent: &UdpSocket) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut buf = vec![0; 65_536];
+    while let Ok(Ok(len)) = tokio::time::timeout(Duration::from_millis(200), agent.recv(&mut buf)).await {
+        let packet = std::str::from_utf8(&buf[..len]).unwrap();
+        lines.extend(packet.lines().map(str::to_string));
+    }
+    lines
+}
+
+#[tokio::test]
+async fn test_dogstatsd_wire_format() {
+    let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let config = StatsdConfig {
+        prefix: "connector.".to_string(),
+        ..StatsdConfig::new(StatsdTarget::Udp(agent.local_addr().unwrap().to_string()))
+    };
+    let mut exporter = StatsdExporter::new(config, Arc::clone(&metrics)).unwrap();
+
+    metrics.errors.inc_by(3);
+    metrics.ws_connections.set(2.0);
+    let conversions = metrics.conversion_duration.with_labels(&["md", "html"]);
+    conversions.observe(0.003);
+    conversions.observe(0.0035);
+    exporter.flush().await;
+    let lines = receive(&agent).await;
+    assert!(lines.contains(&"connector.ulc_errors_total:3|c".to_string()), "{lines:?}");
+    assert!(lines.contains(&"connector.ulc_ws_connections:2|g".to_string()), "{lines:?}");
+    assert!(
+        lines.contains(&"connector.ulc_conversion_duration_seconds:4|ms|@0.5|#from:md,to:html".to_string()),
+        "{lines:?}"
+    );
+    assert!(lines.iter().all(|line| line.starts_with("connector.")));
+
+    // Counters are increases since the last flush; idle ones are not sent
+    metrics.errors.inc();
+    exporter.flush().await;
+    let lines = receive(&agent).await;
+    assert!(lines.contains(&"connector.ulc_errors_total:1|c".to_string()), "{lines:?}");
+    assert!(!lines.iter().any(|line| line.starts_with("connector.ulc_conversion_duration_seconds")));
+    // Gauges are sent every time
+    assert!(lines.contains(&"connector.ulc_ws_connections:2|g".to_string()), "{lines:?}");
+    // The exporter counts its own datagrams, sent in the previous flush
+    assert!(lines.iter().any(|line| line.starts_with("connector.ulc_statsd_packets_total:")));
+}
+
+#[tokio::test]
+async fn test_final_flush_on_stop() {
+    let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let config = StatsdConfig {
+        interval: Duration::from_secs(3600),
+        ..StatsdConfig::new(StatsdTarget::Udp(agent.local_addr().unwrap().to_string()))
+    };
+    let exporter = StatsdExporter::new(config, Arc::clone(&metrics)).unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(exporter.run(async {
+        let _ = stopped.await;
+    }));
+
+    // The first flush happens on start
+    receive(&agent).await;
+    metrics.errors.inc_by(7);
+    stop.send(()).unwrap();
+    running.await.unwrap();
+    let lines = receive(&agent).await;
+    assert!(lines.contains(&"ulc_errors_total:7|c".to_string()), "{lines:?}");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_summary_without_tags() {
+    let path = std::env::temp_dir().join(format!("ulc-statsd-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let agent = tokio::net::UnixDatagram::bind(&path).unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let config = StatsdConfig {
+        tags: false,
+        histograms: HistogramMode::Summary,
+        ..StatsdConfig::new(StatsdTarget::Unix(path.clone()))
+    };
+    let mut exporter = StatsdExporter::new(config, Arc::clone(&metrics)).unwrap();
+
+    let conversions = metrics.conversion_duration.with_labels(&["md", "html"]);
+    conversions.observe(0.003);
+    conversions.observe(0.0035);
+    exporter.flush().await;
+
+    let mut buf = vec![0; 65_536];
+    let mut lines = Vec::new();
+    while let Ok(Ok(len)) = tokio::time::timeout(Duration::from_millis(200), agent.recv(&mut buf)).await {
+        lines.extend(std::str::from_utf8(&buf[..len]).unwrap().lines().map(str::to_string));
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    // Label values join the name; no tags are sent
+    assert!(lines.contains(&"ulc_conversion_duration_seconds.md.html.count:2|c".to_string()), "{lines:?}");
+    assert!(lines.iter().any(|line| line.starts_with("ulc_conversion_duration_seconds.md.html.avg:")));
+    assert!(lines.iter().any(|line| line.starts_with("ulc_conversion_duration_seconds.md.html.p99:")));
+    assert!(lines.iter().all(|line| !line.contains("|#")));
+}
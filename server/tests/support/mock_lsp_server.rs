@@ -0,0 +1,189 @@
+//! A minimal language server for the downstream proxy tests
+//!
+//! Speaks LSP on stdin and stdout, or with `--tcp ADDR` on each connection
+//! accepted there in turn, printing the bound address first. `--log FILE`
+//! appends the process ID and method of every message received.
+//!
+//! Documents get a warning on each line containing `TODO`. Hover describes
+//! the line under the cursor, and exits with status 1 when that line is
+//! `crash`. Definitions and references point at the first line.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+/// How a connection ended
+enum End {
+    Exit,
+    Crash,
+    Closed,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1)).cloned();
+    let log = option("--log");
+
+    if let Some(addr) = option("--tcp") {
+        let listener = TcpListener::bind(addr).expect("binding the listener");
+        println!("{}", listener.local_addr().expect("bound address"));
+        std::io::stdout().flush().expect("printing the address");
+        for stream in listener.incoming() {
+            let stream = stream.expect("accepting a connection");
+            let reader = BufReader::new(stream.try_clone().expect("cloning the stream"));
+            serve(reader, stream, log.as_deref());
+        }
+        return;
+    }
+
+    let end = serve(std::io::stdin().lock(), std::io::stdout().lock(), log.as_deref());
+    std::process::exit(match end {
+        End::Crash => 1,
+        End::Exit | End::Closed => 0,
+    });
+}
+
+fn serve(mut input: impl BufRead, mut output: impl Write, log: Option<&str>) -> End {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    while let Some(message) = read(&mut input) {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        if method.is_empty() {
+            // The answer to workspace/configuration
+            record(log, "configured");
+            continue;
+        }
+        record(log, method);
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."], "resolveProvider": true },
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                },
+                "serverInfo": { "name": "mock" },
+            }),
+            "initialized" => {
+                let items = json!({ "items": [{ "section": "mock" }] });
+                send(&mut output, "workspace/configuration", json!({ "id": "configuration", "params": items }));
+                continue;
+            }
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                let text = match method {
+                    "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+                    _ => params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|c| c["text"].as_str()),
+                };
+                documents.insert(uri.clone(), text.unwrap_or_default().to_string());
+                let diagnostics = todos(&documents[&uri]);
+                let version = &params["textDocument"]["version"];
+                let params = json!({ "uri": uri, "version": version, "diagnostics": diagnostics });
+                send(&mut output, "textDocument/publishDiagnostics", json!({ "params": params }));
+                continue;
+            }
+            "textDocument/didClose" => {
+                documents.remove(params["textDocument"]["uri"].as_str().unwrap_or_default());
+                continue;
+            }
+            "textDocument/hover" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let line = params["position"]["line"].as_u64().unwrap_or_default();
+                let text = documents
+                    .get(uri)
+                    .and_then(|text| text.lines().nth(usize::try_from(line).unwrap_or(usize::MAX)))
+                    .unwrap_or_default();
+                if text == "crash" {
+                    return End::Crash;
+                }
+                json!({ "contents": { "kind": "plaintext", "value": format!("{uri} line {line}: {text}") } })
+            }
+            "textDocument/completion" => json!([{ "label": "mock_completion" }]),
+            "textDocument/definition" => location(params),
+            "textDocument/references" => json!([location(params)]),
+            "shutdown" => Value::Null,
+            "exit" => return End::Exit,
+            _ if message.get("id").is_some() => {
+                let error = json!({ "code": -32601, "message": format!("{method} is not supported") });
+                write(&mut output, &json!({ "jsonrpc": "2.0", "id": message["id"], "error": error }));
+                continue;
+            }
+            _ => continue,
+        };
+        write(&mut output, &json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }));
+    }
+    End::Closed
+}
+
+/// A warning on each line containing `TODO`
+fn todos(text: &str) -> Vec<Value> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(line, content)| {
+            let start = content.find("TODO")?;
+            Some(json!({
+                "range": {
+                    "start": { "line": line, "character": start },
+                    "end": { "line": line, "character": start + 4 },
+                },
+                "severity": 2,
+                "source": "mock",
+                "message": "TODO left in",
+            }))
+        })
+        .collect()
+}
+
+/// The first line of the document asked about
+fn location(params: &Value) -> Value {
+    let start = json!({ "line": 0, "character": 0 });
+    json!({ "uri": params["textDocument"]["uri"], "range": { "start": start, "end": start } })
+}
+
+fn record(log: Option<&str>, event: &str) {
+    let Some(path) = log else { return };
+    let mut file = OpenOptions::new().create(true).append(true).open(path).expect("opening the log");
+    writeln!(file, "{} {}", std::process::id(), event).expect("writing the log");
+}
+
+/// Read one Content-Length framed message, or `None` at end of input
+fn read(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// Send a `method` message with `fields`: its `params`, and its `id` if a request
+fn send(output: &mut impl Write, method: &str, mut fields: Value) {
+    fields["jsonrpc"] = json!("2.0");
+    fields["method"] = json!(method);
+    write(output, &fields);
+}
+
+fn write(output: &mut impl Write, message: &Value) {
+    let body = message.to_string();
+    // A vanished client ends the connection at the next read
+    let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = output.flush();
+}
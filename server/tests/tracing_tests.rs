@@ -0,0 +1,80 @@
+//! Trace export integration tests
+//!
+//! Requests go through the real router with spans exported to memory.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+use opentelemetry_sdk::trace::TracerProvider;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tracing_subscriber::layer::SubscriberExt;
+use universal_connector_server::{http, ServerConfig, ServerState};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const CALLER_SPAN_ID: &str = "00f067aa0ba902b7";
+
+fn attribute(span: &SpanData, key: &str) -> Option<String> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| kv.value.to_string())
+}
+
+#[tokio::test]
+async fn test_convert_request_span_tree() {
+    let exporter = InMemorySpanExporter::default();
+    let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let state = Arc::new(ServerState::new(ServerConfig::default()));
+    let payload = serde_json::json!({ "content": "# Confidential plans", "from": "markdown", "to": "html" });
+    let response = http::create_router(state)
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/convert")
+                .header("content-type", "application/json")
+                .header("traceparent", format!("00-{TRACE_ID}-{CALLER_SPAN_ID}-01"))
+                .body(Body::from(payload.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    provider.force_flush();
+    let spans = exporter.get_finished_spans().unwrap();
+    let span = |name: &str| {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no {name} span in {:?}", spans.iter().map(|s| &s.name).collect::<Vec<_>>()))
+    };
+
+    // The request continues the caller's trace
+    let request = span("http.request");
+    assert_eq!(request.span_context.trace_id().to_string(), TRACE_ID);
+    assert_eq!(request.parent_span_id.to_string(), CALLER_SPAN_ID);
+    assert_eq!(attribute(request, "http.method").as_deref(), Some("POST"));
+    assert_eq!(attribute(request, "http.route").as_deref(), Some("/api/convert"));
+    assert_eq!(attribute(request, "http.status_code").as_deref(), Some("200"));
+
+    // Conversion is its child
+    let convert = span("format.convert");
+    assert_eq!(convert.span_context.trace_id(), request.span_context.trace_id());
+    assert_eq!(convert.parent_span_id, request.span_context.span_id());
+    assert_eq!(attribute(convert, "format.from").as_deref(), Some("md"));
+    assert_eq!(attribute(convert, "format.to").as_deref(), Some("html"));
+    assert_eq!(attribute(convert, "size").as_deref(), Some("<1KiB"));
+
+    // Document content never reaches an attribute
+    assert!(spans
+        .iter()
+        .flat_map(|span| &span.attributes)
+        .all(|kv| !kv.value.to_string().contains("Confidential")));
+}
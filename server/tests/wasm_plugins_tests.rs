@@ -0,0 +1,193 @@
+//! WASM format plugin integration tests
+//!
+//! The fixtures in `tests/fixtures/plugins` are assembled from the `.wat`
+//! sources beside them: `kv` converts `key=value` lines, and `misbehaving`
+//! traps, loops forever or grows its memory, one per ABI function.
+
+#![cfg(feature = "wasm-plugins")]
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower::ServiceExt;
+use universal_connector_server::formats::plugins::{FormatPlugin, PluginConfig};
+use universal_connector_server::formats::wasm::WasmPlugin;
+use universal_connector_server::formats::FormatRef;
+use universal_connector_server::http;
+use universal_connector_server::{ServerConfig, ServerState};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/plugins").join(name)
+}
+
+fn load(name: &str, config: &PluginConfig) -> WasmPlugin {
+    WasmPlugin::load(&fixture(name), config).unwrap()
+}
+
+/// A plugin directory holding copies of the named fixtures
+fn plugin_dir(fixtures: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ulc-plugins-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in fixtures {
+        std::fs::copy(fixture(name), dir.join(name)).unwrap();
+    }
+    dir
+}
+
+fn state_with_plugins(dir: &Path) -> Arc<ServerState> {
+    let config = ServerConfig::builder().plugins(|plugins| plugins.dir(dir)).build().unwrap();
+    Arc::new(ServerState::new(config))
+}
+
+#[test]
+fn test_kv_plugin_converts_both_ways() {
+    let kv = load("kv.wasm", &PluginConfig::default());
+    assert_eq!(kv.name(), "kv");
+
+    let canonical = kv.to_canonical(b"  name=ulc\r\nversion=1\n\nequation=a=b\n").unwrap();
+    let canonical: Value = serde_json::from_slice(&canonical).unwrap();
+    assert_eq!(canonical, json!({ "name": "ulc", "version": "1", "equation": "a=b" }));
+
+    let lines = kv.from_canonical(br#"{"name": "ulc", "stable": true}"#).unwrap();
+    assert_eq!(lines, b"name=ulc\nstable=true");
+
+    assert!(kv.validate(b"name=ulc\n").unwrap().is_empty());
+    assert_eq!(kv.validate(b"name=ulc\nversion\n").unwrap(), ["Every line needs a key=value pair"]);
+}
+
+#[test]
+fn test_kv_plugin_refusals_are_errors() {
+    let kv = load("kv.wasm", &PluginConfig::default());
+    let error = kv.to_canonical(b"name=ulc\nversion").unwrap_err();
+    assert_eq!(error.to_string(), "Plugin kv refused the document: Every line needs a key=value pair");
+    let error = kv.from_canonical(br#"{"nested": {"a": "b"}}"#).unwrap_err();
+    assert!(error.to_string().contains("Only flat JSON objects"), "{error}");
+}
+
+#[test]
+fn test_trap_fails_only_that_call() {
+    let plugin = load("misbehaving.wasm", &PluginConfig::default());
+    let error = plugin.to_canonical(b"anything").unwrap_err();
+    assert!(error.to_string().starts_with("Plugin misbehaving trapped"), "{error}");
+    // A fresh instance each call, so the trap leaves nothing behind
+    let error = plugin.to_canonical(b"anything").unwrap_err();
+    assert!(error.to_string().starts_with("Plugin misbehaving trapped"), "{error}");
+}
+
+#[test]
+fn test_fuel_limit() {
+    let mut config = PluginConfig::default();
+    config.fuel = 10_000;
+    let plugin = load("misbehaving.wasm", &config);
+    let error = plugin.from_canonical(b"{}").unwrap_err();
+    assert_eq!(error.to_string(), "Plugin misbehaving exceeded its limit of 10000 fuel");
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut config = PluginConfig::default();
+    config.max_memory_bytes = 1024 * 1024;
+    let plugin = load("misbehaving.wasm", &config);
+    let error = plugin.validate(b"").unwrap_err();
+    assert!(format!("{error:#}").starts_with("Plugin misbehaving failed"), "{error:#}");
+
+    // Under a larger cap the same call grows, then returns an empty buffer
+    config.max_memory_bytes = 256 * 1024 * 1024;
+    let plugin = load("misbehaving.wasm", &config);
+    assert_eq!(plugin.validate(b"").unwrap_err().to_string(), "Plugin misbehaving returned nothing");
+}
+
+#[test]
+fn test_modules_that_break_the_abi_are_rejected() {
+    let config = PluginConfig::default();
+    let error = WasmPlugin::new("garbage", b"not wasm at all", &config).unwrap_err();
+    assert!(error.to_string().contains("Not a valid wasm module"), "{error}");
+
+    // The binary encoding of `(module (import "wasi_snapshot_preview1" "fd_write" (func)))`
+    let mut importing = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x02\x23\x01".to_vec();
+    importing.push(22);
+    importing.extend_from_slice(b"wasi_snapshot_preview1");
+    importing.push(8);
+    importing.extend_from_slice(b"fd_write\0\0");
+    let error = WasmPlugin::new("importing", &importing, &config).unwrap_err();
+    assert!(error.to_string().contains("wasi_snapshot_preview1::fd_write"), "{error}");
+
+    // `(module)` exports nothing at all
+    let error = WasmPlugin::new("empty", b"\0asm\x01\0\0\0", &config).unwrap_err();
+    assert_eq!(error.to_string(), "Does not export memory");
+}
+
+#[test]
+fn test_plugins_load_at_startup() {
+    let dir = plugin_dir(&["kv.wasm", "misbehaving.wasm"]);
+    std::fs::write(dir.join("broken.wasm"), b"not wasm at all").unwrap();
+    std::fs::write(dir.join("notes.txt"), b"not a plugin").unwrap();
+    let state = state_with_plugins(&dir);
+
+    // The broken module is skipped and the rest still load
+    assert_eq!(state.formats.plugins().names(), ["kv", "misbehaving"]);
+    let kv = state.capabilities.get("formats.kv").unwrap();
+    assert_eq!(kv.parameters["plugin"], true);
+    assert!(!state.capabilities.contains("formats.broken"));
+
+    let from = state.formats.resolve("kv").unwrap();
+    assert!(matches!(from, FormatRef::Plugin(_)));
+    let to = state.formats.resolve("json").unwrap();
+    let converted = state.formats.convert_any("a=1\nb=2", &from, &to).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&converted).unwrap(), json!({ "a": "1", "b": "2" }));
+    let back = state.formats.convert_any(&converted, &to, &from).unwrap();
+    assert_eq!(back, "a=1\nb=2");
+
+    let error = state.formats.convert_any("a=1", &state.formats.resolve("misbehaving").unwrap(), &to).unwrap_err();
+    assert!(error.to_string().contains("trapped"), "{error}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+async fn post(app: axum::Router, uri: &str, payload: Value) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_plugin_formats_over_http() {
+    let dir = plugin_dir(&["kv.wasm", "misbehaving.wasm"]);
+    let state = state_with_plugins(&dir);
+    let app = http::create_router(Arc::clone(&state));
+
+    let payload = json!({ "content": "# Title", "from": "markdown", "to": "kv" });
+    let (status, body) = post(app.clone(), "/api/convert", payload).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["to"], "kv");
+
+    let payload = json!({ "content": "name=ulc", "from": "kv", "to": "json" });
+    let (status, body) = post(app.clone(), "/api/convert", payload).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(serde_json::from_str::<Value>(body["content"].as_str().unwrap()).unwrap(), json!({ "name": "ulc" }));
+
+    let payload = json!({ "content": "name", "from": "kv", "to": "json" });
+    let (status, body) = post(app.clone(), "/api/convert", payload).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.to_string().contains("Every line needs a key=value pair"), "{body}");
+
+    let payload = json!({ "content": "{}", "from": "json", "to": "misbehaving" });
+    let (status, body) = post(app.clone(), "/api/convert", payload).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.to_string().contains("fuel"), "{body}");
+
+    let payload = json!({ "content": "name=ulc\nversion", "format": "kv" });
+    let (status, body) = post(app, "/api/validate", payload).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["valid"], false);
+    assert_eq!(body["diagnostics"], json!(["Every line needs a key=value pair"]));
+    std::fs::remove_dir_all(dir).unwrap();
+}
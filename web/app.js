@@ -196,6 +196,7 @@ function connectWebSocket() {
     ws = new WebSocket(WS_URL);
 
     ws.onopen = () => {
+        ws.send(JSON.stringify({ type: 'Hello', protocol_version: 2, capabilities: [] }));
         wsConnected = true;
         addUpdateEntry('✅ WebSocket connected');
         document.getElementById('active-connections').textContent = '1';
@@ -240,7 +241,11 @@ function handleWebSocketMessage(message) {
         case 'Pong':
             addUpdateEntry('🏓 Pong received');
             break;
+        case 'Welcome':
+            addUpdateEntry(`🤝 Protocol version ${message.protocol_version} negotiated`);
+            break;
         case 'Error':
+        case 'ProtocolViolation':
             addUpdateEntry(`❌ Error: ${message.message}`);
             break;
         default: